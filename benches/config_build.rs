@@ -0,0 +1,139 @@
+//! Configuration construction cost for growing device counts.
+//!
+//! Devices are created outside the measured section; only the builder calls and the arrays they
+//! hand to the framework are timed. Run on macOS with `cargo bench --bench config_build`.
+//!
+//! The `categories` group sets every device category the builder has without a macOS 13 class,
+//! once with no devices and once with eight each. With no devices, each setter passes the shared
+//! empty `NSArray` instead of building one; compare `categories/empty` across that change to see
+//! what the array churn cost.
+
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion};
+use virtualization_rs::virtualization::{
+    entropy_device::VZVirtioEntropyDeviceConfiguration,
+    memory_device::VZVirtioTraditionalMemoryBalloonDeviceConfiguration,
+    network_device::{VZNATNetworkDeviceAttachment, VZVirtioNetworkDeviceConfiguration},
+    socket_device::VZVirtioSocketDeviceConfiguration,
+    storage_device::{VZDiskImageStorageDeviceAttachmentBuilder, VZVirtioBlockDeviceConfiguration},
+    virtual_machine::VZVirtualMachineConfigurationBuilder,
+};
+
+use std::fs::File;
+use std::path::PathBuf;
+
+fn disk_image() -> PathBuf {
+    let path = std::env::temp_dir().join("virtualization-rs-bench.img");
+    File::create(&path)
+        .and_then(|f| f.set_len(1024 * 1024))
+        .unwrap();
+    path
+}
+
+fn block_devices(image: &str, count: usize) -> Vec<VZVirtioBlockDeviceConfiguration> {
+    (0..count)
+        .map(|_| {
+            let attachment = VZDiskImageStorageDeviceAttachmentBuilder::new()
+                .path(image)
+                .read_only(true)
+                .build()
+                .unwrap_or_else(|e| panic!("{}", e));
+            VZVirtioBlockDeviceConfiguration::new(attachment)
+        })
+        .collect()
+}
+
+fn network_devices(count: usize) -> Vec<VZVirtioNetworkDeviceConfiguration> {
+    (0..count)
+        .map(|_| VZVirtioNetworkDeviceConfiguration::new(VZNATNetworkDeviceAttachment::new()))
+        .collect()
+}
+
+fn storage(c: &mut Criterion) {
+    let image = disk_image();
+    let image = image.to_str().unwrap();
+    let mut group = c.benchmark_group("storage_devices");
+    for &count in &[1, 8, 64, 256] {
+        group.bench_with_input(BenchmarkId::from_parameter(count), &count, |b, &count| {
+            b.iter_batched(
+                || block_devices(image, count),
+                |devices| {
+                    VZVirtualMachineConfigurationBuilder::new()
+                        .storage_devices(devices)
+                        .build()
+                },
+                BatchSize::SmallInput,
+            )
+        });
+    }
+    group.finish();
+}
+
+fn network(c: &mut Criterion) {
+    let mut group = c.benchmark_group("network_devices");
+    for &count in &[1, 2, 4, 8, 16] {
+        group.bench_with_input(BenchmarkId::from_parameter(count), &count, |b, &count| {
+            b.iter_batched(
+                || network_devices(count),
+                |devices| {
+                    VZVirtualMachineConfigurationBuilder::new()
+                        .network_devices(devices)
+                        .build()
+                },
+                BatchSize::SmallInput,
+            )
+        });
+    }
+    group.finish();
+}
+
+/// Devices for every category `categories` sets, `count` of each.
+struct Categories {
+    storage: Vec<VZVirtioBlockDeviceConfiguration>,
+    network: Vec<VZVirtioNetworkDeviceConfiguration>,
+    entropy: Vec<VZVirtioEntropyDeviceConfiguration>,
+    memory_balloon: Vec<VZVirtioTraditionalMemoryBalloonDeviceConfiguration>,
+    socket: Vec<VZVirtioSocketDeviceConfiguration>,
+}
+
+fn categories_of(image: &str, count: usize) -> Categories {
+    Categories {
+        storage: block_devices(image, count),
+        network: network_devices(count),
+        entropy: (0..count)
+            .map(|_| VZVirtioEntropyDeviceConfiguration::new())
+            .collect(),
+        memory_balloon: (0..count)
+            .map(|_| VZVirtioTraditionalMemoryBalloonDeviceConfiguration::new())
+            .collect(),
+        socket: (0..count)
+            .map(|_| VZVirtioSocketDeviceConfiguration::new())
+            .collect(),
+    }
+}
+
+fn categories(c: &mut Criterion) {
+    let image = disk_image();
+    let image = image.to_str().unwrap();
+    let mut group = c.benchmark_group("categories");
+    for &(name, count) in &[("empty", 0), ("eight_per_category", 8)] {
+        group.bench_function(name, |b| {
+            b.iter_batched(
+                || categories_of(image, count),
+                |devices| {
+                    VZVirtualMachineConfigurationBuilder::new()
+                        .storage_devices(devices.storage)
+                        .network_devices(devices.network)
+                        .entropy_devices(devices.entropy)
+                        .memory_balloon_devices(devices.memory_balloon)
+                        .socket_devices(devices.socket)
+                        .build()
+                },
+                BatchSize::SmallInput,
+            )
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, storage, network, categories);
+criterion_main!(benches);
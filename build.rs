@@ -0,0 +1,18 @@
+//! Weakly links Virtualization.framework into this package's tests, examples and benches, so they
+//! load on systems without it and report it through `features::is_framework_available`. The
+//! linker drops the framework from binaries that reference none of its symbols.
+//!
+//! Cargo passes link arguments only to the package that prints them, never to its dependents.
+//! The library does not need them: it links no framework symbol and loads the framework at
+//! runtime. An application that wants `otool -L` to list the framework can print the same line
+//! from its own build script.
+
+use std::env;
+
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+    // Both aarch64-apple-darwin and x86_64-apple-darwin; the flag is the same for each.
+    if env::var("CARGO_CFG_TARGET_OS").as_deref() == Ok("macos") {
+        println!("cargo:rustc-link-arg=-Wl,-weak_framework,Virtualization");
+    }
+}
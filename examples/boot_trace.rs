@@ -0,0 +1,79 @@
+//! Records the boot of a Linux guest up to its login prompt and writes the timeline to
+//! `boot_trace.json`, which chrome://tracing and Perfetto open.
+//!
+//! ```sh
+//! cargo run --example boot_trace -- vmlinuz initrd
+//! ```
+
+extern crate virtualization_rs;
+
+use virtualization_rs::base::CancellationToken;
+use virtualization_rs::liveness::Liveness;
+use virtualization_rs::virtualization::boot_loader::VZLinuxBootLoaderBuilder;
+use virtualization_rs::virtualization::entropy_device::VZVirtioEntropyDeviceConfiguration;
+use virtualization_rs::virtualization::serial_port::{
+    ConsoleCapture, VZVirtioConsoleDeviceSerialPortConfiguration,
+};
+use virtualization_rs::virtualization::virtual_machine::{
+    VZVirtualMachine, VZVirtualMachineConfigurationBuilder,
+};
+
+use std::fs::canonicalize;
+use std::sync::mpsc;
+use std::time::Duration;
+
+fn path(arg: Option<String>) -> String {
+    let arg = arg.expect("usage: boot_trace <kernel> <initrd>");
+    canonicalize(arg)
+        .unwrap()
+        .into_os_string()
+        .into_string()
+        .unwrap()
+}
+
+fn main() {
+    let mut args = std::env::args().skip(1);
+    let boot_loader = VZLinuxBootLoaderBuilder::new()
+        .kernel_url(path(args.next()))
+        .initial_ramdisk_url(path(args.next()))
+        .command_line("console=hvc0")
+        .build()
+        .unwrap();
+    let capture = ConsoleCapture::new().unwrap();
+    let conf = VZVirtualMachineConfigurationBuilder::new()
+        .boot_loader(boot_loader)
+        .cpu_count(2)
+        .memory_size_gib(1)
+        .entropy_devices(vec![VZVirtioEntropyDeviceConfiguration::new()])
+        .serial_ports(vec![VZVirtioConsoleDeviceSerialPortConfiguration::new(
+            capture.attachment(),
+        )])
+        .build();
+    if let Err(e) = conf.validate_with_error() {
+        eprintln!("{}", e);
+        return;
+    }
+
+    let vm = VZVirtualMachine::new_with_qos(conf, "boot-trace", None);
+    let timeline = vm.record_timeline();
+    let (tx, rx) = mpsc::channel();
+    let done = tx.clone();
+    vm.start(move |_| done.send(()).unwrap()).unwrap();
+    rx.recv().unwrap();
+
+    let token = CancellationToken::new();
+    match Liveness::console_marker(capture.buffer(), "login:").wait_recorded(
+        Duration::from_secs(120),
+        &token,
+        &timeline,
+    ) {
+        Ok(elapsed) => println!("login prompt after {:?}", elapsed),
+        Err(_) => eprintln!("no login prompt"),
+    }
+    timeline.mark("trace done");
+
+    vm.stop(move |_| tx.send(()).unwrap()).unwrap();
+    rx.recv().unwrap();
+    timeline.write_chrome_trace("boot_trace.json").unwrap();
+    println!("wrote boot_trace.json");
+}
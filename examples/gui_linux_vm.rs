@@ -0,0 +1,277 @@
+//! Runs a Linux guest in a window, after Apple's GUILinuxVirtualMachineSampleApp: EFI boot,
+//! Virtio graphics with a USB keyboard and pointing device, NAT networking, sound and a shared
+//! folder. The virtual machine lives in a bundle directory holding its disk, its EFI variable
+//! store and its machine identifier, created on the first run.
+//!
+//! ```sh
+//! # Install from an ISO, then boot the installed system.
+//! cargo run --example gui_linux_vm --features gui -- --iso debian-12-arm64-netinst.iso
+//! cargo run --example gui_linux_vm --features gui -- --share ~/src
+//! ```
+//!
+//! In the guest, `mount -t virtiofs shared /mnt` mounts the shared folder. The guest's display
+//! follows the window's size from macOS 14 on; before, resize it in the guest with `xrandr`.
+
+extern crate virtualization_rs;
+
+use virtualization_rs::virtualization::{
+    audio_device::{
+        VZVirtioSoundDeviceConfiguration, VZVirtioSoundDeviceInputStreamConfiguration,
+        VZVirtioSoundDeviceOutputStreamConfiguration, VZVirtioSoundDeviceStreamConfiguration,
+    },
+    boot_loader::{
+        VZEFIBootLoaderBuilder, VZEFIVariableStore, VZEFIVariableStoreInitializationOptions,
+    },
+    directory_sharing::{
+        VZSharedDirectory, VZSingleDirectoryShare, VZVirtioFileSystemDeviceConfiguration,
+    },
+    entropy_device::VZVirtioEntropyDeviceConfiguration,
+    error::CompletionOutcome,
+    error_events::ErrorEvent,
+    graphics_device::{VZVirtioGraphicsDeviceConfiguration, VZVirtioGraphicsScanoutConfiguration},
+    keyboard::VZUSBKeyboardConfiguration,
+    memory_device::VZVirtioTraditionalMemoryBalloonDeviceConfiguration,
+    network_device::{
+        VZMACAddress, VZNATNetworkDeviceAttachment, VZVirtioNetworkDeviceConfiguration,
+    },
+    platform::{VZGenericMachineIdentifier, VZGenericPlatformConfiguration},
+    pointing_device::VZUSBScreenCoordinatePointingDeviceConfiguration,
+    storage_device::{
+        VZDiskImageStorageDeviceAttachmentBuilder, VZUSBMassStorageDeviceConfiguration,
+        VZVirtioBlockDeviceConfiguration,
+    },
+    view::{self, VZVirtualMachineView, VZVirtualMachineWindow, WindowObserver},
+    virtual_machine::{VZVirtualMachine, VZVirtualMachineConfigurationBuilder},
+};
+
+use std::error::Error;
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use structopt::StructOpt;
+
+/// The window's initial content size, in points.
+const WIDTH: f64 = 1280.0;
+const HEIGHT: f64 = 720.0;
+
+#[derive(StructOpt, Debug)]
+#[structopt(name = "gui_linux_vm")]
+struct Opt {
+    /// Directory holding the disk, EFI variable store and machine identifier
+    #[structopt(long, parse(from_os_str), default_value = "GUI Linux VM.bundle")]
+    bundle: PathBuf,
+
+    /// Installer image, attached as a USB mass storage device
+    #[structopt(long, parse(from_os_str))]
+    iso: Option<PathBuf>,
+
+    /// Host folder shared with the guest under the tag "shared"
+    #[structopt(long, parse(from_os_str))]
+    share: Option<PathBuf>,
+
+    /// Size of the disk created on the first run, in GiB
+    #[structopt(long, default_value = "64")]
+    disk_size_gib: u64,
+
+    #[structopt(short, long, default_value = "4")]
+    cpu: usize,
+
+    /// Guest memory in MiB
+    #[structopt(short, long, default_value = "4096")]
+    memory_mib: usize,
+
+    /// Also give the guest a microphone, recording from the host's default input
+    #[structopt(long)]
+    microphone: bool,
+}
+
+fn main() {
+    let opt = Opt::from_args();
+    if !VZVirtualMachine::supported() {
+        eprintln!("virtualization is not supported on this host");
+        return;
+    }
+    // Everything below runs on the main thread, which the application's run loop keeps
+    // servicing: the machine's callbacks arrive on the main queue, as the view needs. The window
+    // comes first, as it sizes the display in pixels for the screen it is on.
+    let view = VZVirtualMachineView::new();
+    let window = VZVirtualMachineWindow::new(&view, "GUI Linux VM", WIDTH, HEIGHT);
+    let (width, height, _) = window.display_sizing();
+    let builder = match configure(&opt, width, height) {
+        Ok(builder) => builder,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    let conf = builder.build();
+    if let Err(e) = conf.validate_with_error() {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+
+    let vm = VZVirtualMachine::new_without_queue(conf);
+    view.set_virtual_machine(&vm);
+    view.set_captures_system_keys(true);
+    let _resizing = resize_display(&window, &vm);
+    window.show();
+
+    vm.start(|outcome| match outcome {
+        CompletionOutcome::Success(()) => {}
+        CompletionOutcome::Cancelled => std::process::exit(1),
+        CompletionOutcome::Failed(e) => {
+            eprintln!("failed to start the virtual machine: {}", e.ns_error());
+            std::process::exit(1);
+        }
+    })
+    .expect("a new virtual machine is not started yet");
+    // Like the sample app, quit once the guest stops.
+    let events = vm.error_events();
+    std::thread::spawn(move || {
+        while let Some(event) = events.recv() {
+            match event {
+                ErrorEvent::Error(e) => eprintln!("{} error: {}", e.phase, e.error.ns_error()),
+                ErrorEvent::GuestStopped { reason, .. } => {
+                    eprintln!("guest stopped: {}", reason);
+                    std::process::exit(if reason.is_clean() { 0 } else { 1 });
+                }
+            }
+        }
+    });
+    view::run_application()
+}
+
+fn configure(
+    opt: &Opt,
+    width: i64,
+    height: i64,
+) -> Result<VZVirtualMachineConfigurationBuilder, Box<dyn Error>> {
+    fs::create_dir_all(&opt.bundle)?;
+    let disk = opt.bundle.join("Disk.img");
+    if !disk.exists() {
+        // Sparse: blocks are only allocated as the guest writes them.
+        File::create(&disk)?.set_len(opt.disk_size_gib << 30)?;
+    }
+
+    let mut platform = VZGenericPlatformConfiguration::try_new()?;
+    platform.set_machine_identifier(&machine_identifier(&opt.bundle.join("MachineIdentifier"))?);
+
+    let nvram = path_string(&opt.bundle.join("NVRAM"))?;
+    let variable_store = if Path::new(&nvram).exists() {
+        VZEFIVariableStore::open(nvram)?
+    } else {
+        VZEFIVariableStore::create(nvram, VZEFIVariableStoreInitializationOptions::new())?
+    };
+    let boot_loader = VZEFIBootLoaderBuilder::new()
+        .with_variable_store(variable_store)
+        .try_build()?;
+
+    let main_disk = VZDiskImageStorageDeviceAttachmentBuilder::new()
+        .path(path_string(&disk)?)
+        .read_only(false)
+        .build()?;
+    let mut builder = VZVirtualMachineConfigurationBuilder::new()
+        .boot_loader(boot_loader)
+        .platform(platform)
+        .cpu_count(opt.cpu)
+        .memory_size_mib(opt.memory_mib)
+        .storage_device(VZVirtioBlockDeviceConfiguration::new(main_disk));
+    if let Some(iso) = &opt.iso {
+        let installer = VZDiskImageStorageDeviceAttachmentBuilder::new()
+            .path(path_string(&fs::canonicalize(iso)?)?)
+            .read_only(true)
+            .build()?;
+        builder = builder.storage_device(VZUSBMassStorageDeviceConfiguration::try_new(installer)?);
+    }
+
+    let scanout = VZVirtioGraphicsScanoutConfiguration::try_new(width, height)?;
+    let mut network = VZVirtioNetworkDeviceConfiguration::new(VZNATNetworkDeviceAttachment::new());
+    network.set_mac_address(VZMACAddress::random_locally_administered_address())?;
+    builder = builder
+        .graphics_devices(vec![VZVirtioGraphicsDeviceConfiguration::try_new(vec![
+            scanout,
+        ])?])
+        .keyboards(vec![VZUSBKeyboardConfiguration::try_new()?])
+        .pointing_devices(vec![
+            VZUSBScreenCoordinatePointingDeviceConfiguration::try_new()?,
+        ])
+        .network_devices(vec![network])
+        .entropy_devices(vec![VZVirtioEntropyDeviceConfiguration::new()])
+        .memory_balloon_devices(vec![
+            VZVirtioTraditionalMemoryBalloonDeviceConfiguration::new(),
+        ]);
+
+    if let Some(share) = &opt.share {
+        let directory = VZSharedDirectory::new(&path_string(&fs::canonicalize(share)?)?, false)?;
+        let device = VZVirtioFileSystemDeviceConfiguration::new(
+            "shared",
+            VZSingleDirectoryShare::new(directory),
+        )?;
+        builder = builder.directory_sharing_devices(vec![device]);
+    }
+
+    match sound(opt.microphone) {
+        Ok(sound) => builder = builder.audio_devices(vec![sound]),
+        Err(e) => eprintln!("warning: no sound: {}", e),
+    }
+    Ok(builder)
+}
+
+/// Resizes the guest's display along with the window, once per size in pixels. The view's
+/// `set_automatically_reconfigures_display` does the same; this is the explicit path, which tells
+/// where the guest has to resize its display itself.
+fn resize_display(window: &VZVirtualMachineWindow, vm: &VZVirtualMachine) -> WindowObserver {
+    let displays: Vec<_> = vm
+        .graphics_devices()
+        .iter()
+        .flat_map(|device| device.displays())
+        .collect();
+    let resizable = !displays.is_empty() && displays.iter().all(|display| display.can_resize());
+    if !resizable {
+        eprintln!("this macOS cannot resize the guest's display; use xrandr in the guest");
+    }
+    let mut last = None;
+    window.on_resize(move |(width, height, _)| {
+        if !resizable || last == Some((width, height)) {
+            return;
+        }
+        last = Some((width, height));
+        for display in &displays {
+            if let Err(e) = display.resize(width, height) {
+                eprintln!("{}", e);
+            }
+        }
+    })
+}
+
+/// The identifier saved in `path`, or a new one saved there, so the guest sees the same machine
+/// on every boot.
+fn machine_identifier(path: &Path) -> Result<VZGenericMachineIdentifier, Box<dyn Error>> {
+    if path.exists() {
+        let bytes = fs::read(path)?;
+        return VZGenericMachineIdentifier::from_data_representation(&bytes)
+            .ok_or_else(|| format!("{} is not a machine identifier", path.display()).into());
+    }
+    let identifier = VZGenericMachineIdentifier::try_new()?;
+    fs::write(path, identifier.data_representation())?;
+    Ok(identifier)
+}
+
+fn sound(microphone: bool) -> Result<VZVirtioSoundDeviceConfiguration, Box<dyn Error>> {
+    let mut streams: Vec<Box<dyn VZVirtioSoundDeviceStreamConfiguration>> = vec![Box::new(
+        VZVirtioSoundDeviceOutputStreamConfiguration::host_output()?,
+    )];
+    if microphone {
+        streams.push(Box::new(
+            VZVirtioSoundDeviceInputStreamConfiguration::host_input()?,
+        ));
+    }
+    let mut sound = VZVirtioSoundDeviceConfiguration::try_new()?;
+    sound.set_streams(streams);
+    Ok(sound)
+}
+
+fn path_string(path: &Path) -> Result<String, Box<dyn Error>> {
+    path.to_str()
+        .map(str::to_string)
+        .ok_or_else(|| format!("{} is not UTF-8", path.display()).into())
+}
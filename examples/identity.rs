@@ -0,0 +1,66 @@
+extern crate virtualization_rs;
+
+use std::env;
+use std::io;
+use std::path::PathBuf;
+use virtualization_rs::{
+    identity::{IdentityError, VmIdentity},
+    virtualization::{
+        network_device::{VZNATNetworkDeviceAttachment, VZVirtioNetworkDeviceConfiguration},
+        virtual_machine::VZVirtualMachineConfigurationBuilder,
+    },
+};
+
+/// Loads the identity in the directory given as the only argument, creating it on first use, and
+/// builds two configurations from it: both describe the same machine.
+fn main() {
+    let dir = PathBuf::from(
+        env::args()
+            .nth(1)
+            .unwrap_or_else(|| "vm-identity".to_string()),
+    );
+    let identity = match VmIdentity::load(&dir) {
+        Ok(identity) => identity,
+        Err(IdentityError::Io(e)) if e.kind() == io::ErrorKind::NotFound => {
+            let identity = match VmIdentity::generate(&dir) {
+                Ok(identity) => identity,
+                Err(_) => panic!("cannot create an identity in {}", dir.display()),
+            };
+            if identity.save(&dir).is_err() {
+                panic!("cannot save the identity in {}", dir.display());
+            }
+            println!("created a new identity in {}", dir.display());
+            identity
+        }
+        Err(IdentityError::Corrupted(reason)) => {
+            eprintln!(
+                "{} is corrupted ({}); restore it from a backup",
+                dir.display(),
+                reason
+            );
+            return;
+        }
+        Err(_) => panic!("cannot read the identity in {}", dir.display()),
+    };
+
+    for boot in 1..=2 {
+        let network = VZVirtioNetworkDeviceConfiguration::new(VZNATNetworkDeviceAttachment::new());
+        let builder = VZVirtualMachineConfigurationBuilder::new()
+            .cpu_count(1)
+            .memory_size_mib(512)
+            .network_devices(vec![network]);
+        let conf = match identity.apply(builder) {
+            Ok(builder) => builder.build(),
+            Err(_) => panic!("cannot apply the identity"),
+        };
+        match conf.validate_with_error() {
+            Ok(_) => println!(
+                "boot {}: MAC {}, EFI store {}",
+                boot,
+                identity.mac,
+                identity.efi_store.display()
+            ),
+            Err(e) => eprintln!("{}", e),
+        }
+    }
+}
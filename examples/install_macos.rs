@@ -0,0 +1,55 @@
+//! Installs macOS into a bundle directory with 4 CPUs, 8 GiB of memory and a 64 GiB disk, from a
+//! restore image given or the latest one this host supports. An interrupted run picks up where it
+//! stopped when started again with the same directory.
+//!
+//! ```sh
+//! cargo run --example install_macos --features restore-download -- macOS.bundle [restore.ipsw]
+//! ```
+
+extern crate virtualization_rs;
+
+use virtualization_rs::install_flow::{FlowState, InstallFlow, InstallPlan, Source};
+
+use std::io::{self, Write};
+use std::path::PathBuf;
+
+const BAR_WIDTH: usize = 40;
+
+fn main() {
+    let mut args = std::env::args().skip(1);
+    let bundle_dir = PathBuf::from(
+        args.next()
+            .expect("usage: install_macos <bundle dir> [restore image]"),
+    );
+    let ipsw = args
+        .next()
+        .map_or(Source::Latest, |path| Source::File(path.into()));
+
+    let flow = InstallFlow::new(InstallPlan {
+        ipsw,
+        bundle_dir,
+        cpus: 4,
+        memory: 8 << 30,
+        disk_size: 64 << 30,
+    });
+    let state = flow.state();
+    if !state.is_finished() {
+        eprintln!("{}", state);
+    }
+    let end = flow.run(|state| match state {
+        FlowState::Installing { progress } => {
+            let filled = (progress * BAR_WIDTH as f64) as usize;
+            eprint!(
+                "\r[{}{}] {:3.0}%",
+                "#".repeat(filled.min(BAR_WIDTH)),
+                " ".repeat(BAR_WIDTH - filled.min(BAR_WIDTH)),
+                progress * 100.0
+            );
+            let _ = io::stderr().flush();
+        }
+        state => eprintln!("\r{}", state),
+    });
+    if let FlowState::Failed { .. } = end {
+        std::process::exit(1);
+    }
+}
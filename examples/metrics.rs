@@ -0,0 +1,117 @@
+//! Exports the crate's metrics with the `prometheus` crate: boots a Linux guest, stops it after a
+//! few seconds and prints the registry in the Prometheus text format.
+//!
+//! ```sh
+//! cargo run --example metrics -- vmlinuz initrd
+//! ```
+
+extern crate virtualization_rs;
+
+use virtualization_rs::metrics::{self, Metrics};
+use virtualization_rs::virtualization::boot_loader::VZLinuxBootLoaderBuilder;
+use virtualization_rs::virtualization::entropy_device::VZVirtioEntropyDeviceConfiguration;
+use virtualization_rs::virtualization::virtual_machine::{
+    VZVirtualMachine, VZVirtualMachineConfigurationBuilder,
+};
+
+use std::collections::HashMap;
+use std::fs::canonicalize;
+use std::sync::mpsc;
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+use prometheus::{CounterVec, Encoder, HistogramOpts, HistogramVec, Opts, Registry, TextEncoder};
+
+/// Creates each series on first use, with the label keys of its sample; the keys of a series are
+/// stable, see the `metrics` module documentation.
+struct PrometheusRecorder {
+    registry: Registry,
+    counters: Mutex<HashMap<&'static str, CounterVec>>,
+    histograms: Mutex<HashMap<&'static str, HistogramVec>>,
+}
+
+impl PrometheusRecorder {
+    fn new(registry: Registry) -> PrometheusRecorder {
+        PrometheusRecorder {
+            registry,
+            counters: Mutex::new(HashMap::new()),
+            histograms: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+fn split<'a>(labels: &'a [(&'static str, &'a str)]) -> (Vec<&'static str>, Vec<&'a str>) {
+    labels.iter().cloned().unzip()
+}
+
+impl Metrics for PrometheusRecorder {
+    fn counter(&self, name: &'static str, labels: &[(&'static str, &str)]) {
+        let (keys, values) = split(labels);
+        let mut counters = self.counters.lock().unwrap();
+        let counter = counters.entry(name).or_insert_with(|| {
+            let counter = CounterVec::new(Opts::new(name, name), &keys).unwrap();
+            self.registry.register(Box::new(counter.clone())).unwrap();
+            counter
+        });
+        counter.with_label_values(&values).inc();
+    }
+
+    fn histogram_observe(&self, name: &'static str, value: f64, labels: &[(&'static str, &str)]) {
+        let (keys, values) = split(labels);
+        let mut histograms = self.histograms.lock().unwrap();
+        let histogram = histograms.entry(name).or_insert_with(|| {
+            let histogram = HistogramVec::new(HistogramOpts::new(name, name), &keys).unwrap();
+            self.registry.register(Box::new(histogram.clone())).unwrap();
+            histogram
+        });
+        histogram.with_label_values(&values).observe(value);
+    }
+}
+
+fn path(arg: Option<String>) -> String {
+    let arg = arg.expect("usage: metrics <kernel> <initrd>");
+    canonicalize(arg)
+        .unwrap()
+        .into_os_string()
+        .into_string()
+        .unwrap()
+}
+
+fn main() {
+    let registry = Registry::new();
+    metrics::set_metrics_recorder(Box::new(PrometheusRecorder::new(registry.clone()))).unwrap();
+
+    let mut args = std::env::args().skip(1);
+    let boot_loader = VZLinuxBootLoaderBuilder::new()
+        .kernel_url(path(args.next()))
+        .initial_ramdisk_url(path(args.next()))
+        .command_line("console=hvc0")
+        .build()
+        .unwrap();
+    let conf = VZVirtualMachineConfigurationBuilder::new()
+        .boot_loader(boot_loader)
+        .cpu_count(2)
+        .memory_size_gib(1)
+        .entropy_devices(vec![VZVirtioEntropyDeviceConfiguration::new()])
+        .build();
+    if let Err(e) = conf.validate_with_error() {
+        eprintln!("{}", e);
+        return;
+    }
+
+    let vm = VZVirtualMachine::new_with_qos(conf, "metrics", None);
+    let (tx, rx) = mpsc::channel();
+    let done = tx.clone();
+    vm.start(move |_| done.send(()).unwrap()).unwrap();
+    rx.recv().unwrap();
+    thread::sleep(Duration::from_secs(5));
+    vm.stop(move |_| tx.send(()).unwrap()).unwrap();
+    rx.recv().unwrap();
+
+    let mut text = Vec::new();
+    TextEncoder::new()
+        .encode(&registry.gather(), &mut text)
+        .unwrap();
+    print!("{}", String::from_utf8(text).unwrap());
+}
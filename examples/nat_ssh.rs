@@ -0,0 +1,93 @@
+//! Boots a Linux guest on a NAT attachment and prints how to SSH into it once the host's DHCP
+//! server has leased it an address, then runs until the guest stops.
+//!
+//! ```sh
+//! cargo run --example nat_ssh -- vmlinuz initrd disk.img [user]
+//! ```
+
+extern crate virtualization_rs;
+
+use virtualization_rs::nat::{self, LeaseError};
+use virtualization_rs::virtualization::boot_loader::VZLinuxBootLoaderBuilder;
+use virtualization_rs::virtualization::entropy_device::VZVirtioEntropyDeviceConfiguration;
+use virtualization_rs::virtualization::error_events::ErrorEvent;
+use virtualization_rs::virtualization::network_device::{
+    VZMACAddress, VZNATNetworkDeviceAttachment, VZVirtioNetworkDeviceConfiguration,
+};
+use virtualization_rs::virtualization::storage_device::{
+    VZDiskImageStorageDeviceAttachmentBuilder, VZVirtioBlockDeviceConfiguration,
+};
+use virtualization_rs::virtualization::virtual_machine::{
+    VZVirtualMachine, VZVirtualMachineConfigurationBuilder,
+};
+
+use std::fs::canonicalize;
+use std::time::Duration;
+
+const LEASE_TIMEOUT: Duration = Duration::from_secs(120);
+
+fn path(arg: Option<String>) -> String {
+    let arg = arg.expect("usage: nat_ssh <kernel> <initrd> <disk> [user]");
+    canonicalize(arg)
+        .unwrap()
+        .into_os_string()
+        .into_string()
+        .unwrap()
+}
+
+fn main() {
+    let mut args = std::env::args().skip(1);
+    let boot_loader = VZLinuxBootLoaderBuilder::new()
+        .kernel_url(path(args.next()))
+        .initial_ramdisk_url(path(args.next()))
+        .command_line("console=hvc0 root=/dev/vda")
+        .build()
+        .unwrap();
+    let disk = VZDiskImageStorageDeviceAttachmentBuilder::new()
+        .path(path(args.next()))
+        .read_only(false)
+        .build()
+        .unwrap_or_else(|e| panic!("{}", e));
+    let user = args.next().unwrap_or_else(|| "user".to_string());
+
+    let mac = VZMACAddress::random_locally_administered_address();
+    let mut network = VZVirtioNetworkDeviceConfiguration::new(VZNATNetworkDeviceAttachment::new());
+    network
+        .set_mac_address(VZMACAddress::init_with_string(&mac.string()).unwrap())
+        .unwrap();
+    let conf = VZVirtualMachineConfigurationBuilder::new()
+        .boot_loader(boot_loader)
+        .cpu_count(2)
+        .memory_size_gib(1)
+        .entropy_devices(vec![VZVirtioEntropyDeviceConfiguration::new()])
+        .network_devices(vec![network])
+        .storage_devices(vec![VZVirtioBlockDeviceConfiguration::new(disk)])
+        .build();
+    if let Err(e) = conf.validate_with_error() {
+        eprintln!("{}", e);
+        return;
+    }
+
+    let vm = VZVirtualMachine::new_with_qos(conf, "nat-ssh", None);
+    let events = vm.error_events();
+    vm.start(|_| {}).unwrap();
+
+    println!("waiting for a DHCP lease for {}", mac.string());
+    match nat::guest_ip_for_mac(&mac, LEASE_TIMEOUT) {
+        Ok(ip) => println!("ssh {}@{}", user, ip),
+        Err(LeaseError::Timeout { .. }) => {
+            eprintln!(
+                "no lease after {:?}; does the guest run a DHCP client?",
+                LEASE_TIMEOUT
+            )
+        }
+        Err(e) => eprintln!("{}", e),
+    }
+
+    while let Some(event) = events.recv() {
+        match event {
+            ErrorEvent::Error(e) => eprintln!("{}: {}", e.phase, e.error.ns_error()),
+            ErrorEvent::GuestStopped { .. } => break,
+        }
+    }
+}
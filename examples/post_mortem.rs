@@ -0,0 +1,104 @@
+//! Prints a post-mortem of a Linux guest from its error event stream: boots the guest, waits
+//! until it is down, by shutting itself down, by an error or after `seconds` through a forced
+//! stop, and lists every error with the phase it happened in.
+//!
+//! ```sh
+//! cargo run --example post_mortem -- vmlinuz initrd [seconds]
+//! ```
+
+extern crate virtualization_rs;
+
+use virtualization_rs::virtualization::boot_loader::VZLinuxBootLoaderBuilder;
+use virtualization_rs::virtualization::entropy_device::VZVirtioEntropyDeviceConfiguration;
+use virtualization_rs::virtualization::error_events::{ErrorEvent, Phase};
+use virtualization_rs::virtualization::virtual_machine::{
+    VZVirtualMachine, VZVirtualMachineConfigurationBuilder,
+};
+
+use std::fs::canonicalize;
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
+
+fn path(arg: Option<String>) -> String {
+    let arg = arg.expect("usage: post_mortem <kernel> <initrd> [seconds]");
+    canonicalize(arg)
+        .unwrap()
+        .into_os_string()
+        .into_string()
+        .unwrap()
+}
+
+fn main() {
+    let mut args = std::env::args().skip(1);
+    let boot_loader = VZLinuxBootLoaderBuilder::new()
+        .kernel_url(path(args.next()))
+        .initial_ramdisk_url(path(args.next()))
+        .command_line("console=hvc0")
+        .build()
+        .unwrap();
+    let seconds = args.next().map_or(30, |s| s.parse().unwrap());
+    let conf = VZVirtualMachineConfigurationBuilder::new()
+        .boot_loader(boot_loader)
+        .cpu_count(2)
+        .memory_size_gib(1)
+        .entropy_devices(vec![VZVirtioEntropyDeviceConfiguration::new()])
+        .build();
+    if let Err(e) = conf.validate_with_error() {
+        eprintln!("{}", e);
+        return;
+    }
+
+    let vm = VZVirtualMachine::new_with_qos(conf, "post-mortem", None);
+    let events = vm.error_events();
+    let started = Instant::now();
+    vm.start(|_| {}).unwrap();
+
+    let deadline = started + Duration::from_secs(seconds);
+    let mut received = Vec::new();
+    loop {
+        let left = deadline.saturating_duration_since(Instant::now());
+        match events.recv_timeout(left) {
+            Some(event) => {
+                // A failed start leaves the machine stopped without a terminal event.
+                let done = match &event {
+                    ErrorEvent::Error(e) => e.phase == Phase::Start,
+                    ErrorEvent::GuestStopped { .. } => true,
+                };
+                received.push(event);
+                if done {
+                    break;
+                }
+            }
+            None if events.is_finished() => break,
+            None => {
+                // Out of time: the stop's events are queued by the time it completes.
+                let (tx, rx) = mpsc::channel();
+                let _ = vm.stop(move |_| {
+                    let _ = tx.send(());
+                });
+                let _ = rx.recv();
+                received.extend(std::iter::from_fn(|| events.try_recv()));
+                break;
+            }
+        }
+    }
+
+    println!("post-mortem of {}", vm.display_name());
+    for event in &received {
+        let at = (event.at() - started).as_secs_f64();
+        match event {
+            ErrorEvent::Error(e) => {
+                println!("  +{:>8.3}s  {:<7}  {}", at, e.phase, e.error.ns_error())
+            }
+            ErrorEvent::GuestStopped { reason, .. } => {
+                println!("  +{:>8.3}s  stopped, {}", at, reason.label())
+            }
+        }
+    }
+    if !received.last().is_some_and(ErrorEvent::is_terminal) {
+        println!("  the machine did not report stopping");
+    }
+    if events.dropped() > 0 {
+        println!("  {} events dropped", events.dropped());
+    }
+}
@@ -0,0 +1,61 @@
+//! Boots a Linux kernel and initial ramdisk directly with the `linux-direct-kernel` profile,
+//! with the console on stdin and stdout.
+//!
+//! ```sh
+//! cargo run --example profile_direct_kernel -- vmlinuz initrd [disk.img]
+//! ```
+
+extern crate virtualization_rs;
+
+use virtualization_rs::base::NSFileHandle;
+use virtualization_rs::profile::{self, GuestProfile, LinuxDirectKernel, ProfileInputs};
+use virtualization_rs::virtualization::error_events::ErrorEvent;
+use virtualization_rs::virtualization::serial_port::{
+    VZFileHandleSerialPortAttachmentBuilder, VZVirtioConsoleDeviceSerialPortConfiguration,
+};
+use virtualization_rs::virtualization::virtual_machine::VZVirtualMachine;
+
+fn main() {
+    let mut args = std::env::args().skip(1).map(Into::into);
+    let inputs = ProfileInputs {
+        kernel: args.next(),
+        initrd: args.next(),
+        disk: args.next(),
+        ..ProfileInputs::default()
+    };
+    let profile = LinuxDirectKernel;
+    let mut builder = match profile::configure(&profile, &inputs) {
+        Ok(builder) => builder,
+        Err(errors) => {
+            for error in errors {
+                eprintln!("{}", error);
+            }
+            eprintln!("usage: profile_direct_kernel <kernel> <initrd> [disk]");
+            return;
+        }
+    };
+    if profile.required_devices().serial_console {
+        let attachment = VZFileHandleSerialPortAttachmentBuilder::new()
+            .file_handle_for_reading(NSFileHandle::file_handle_with_standard_input())
+            .file_handle_for_writing(NSFileHandle::file_handle_with_standard_output())
+            .build();
+        builder = builder.serial_ports(vec![VZVirtioConsoleDeviceSerialPortConfiguration::new(
+            attachment,
+        )]);
+    }
+    let conf = builder.cpu_count(2).memory_size_gib(1).build();
+    if let Err(e) = conf.validate_with_error() {
+        eprintln!("{}", e);
+        return;
+    }
+
+    let vm = VZVirtualMachine::new_with_qos(conf, profile.name(), None);
+    let events = vm.error_events();
+    vm.start(|_| {}).unwrap();
+    while let Some(event) = events.recv() {
+        match event {
+            ErrorEvent::Error(e) => eprintln!("{}: {}", e.phase, e.error.ns_error()),
+            ErrorEvent::GuestStopped { .. } => break,
+        }
+    }
+}
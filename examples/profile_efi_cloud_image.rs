@@ -0,0 +1,78 @@
+//! Boots a Linux cloud image through EFI with the `linux-efi-cloud-image` profile, picked by name
+//! as a spec would, with a cloud-init seed letting `user` in with an SSH key. The console is on
+//! stdin and stdout; `nat_ssh` shows how to find the guest's address.
+//!
+//! ```sh
+//! cargo run --example profile_efi_cloud_image --features cloud-init -- \
+//!     jammy-server-cloudimg-arm64.img efi_vars.fd "ssh-ed25519 AAAA... me@host"
+//! ```
+
+extern crate virtualization_rs;
+
+use virtualization_rs::base::NSFileHandle;
+use virtualization_rs::cloudinit::CloudInitSeed;
+use virtualization_rs::profile::{self, ProfileInputs, ProfileKind};
+use virtualization_rs::virtualization::error_events::ErrorEvent;
+use virtualization_rs::virtualization::serial_port::{
+    VZFileHandleSerialPortAttachmentBuilder, VZVirtioConsoleDeviceSerialPortConfiguration,
+};
+use virtualization_rs::virtualization::virtual_machine::VZVirtualMachine;
+
+use std::path::PathBuf;
+
+fn main() {
+    let mut args = std::env::args().skip(1);
+    let (disk, efi_variable_store, key) = match (args.next(), args.next(), args.next()) {
+        (Some(disk), Some(store), Some(key)) => (disk, store, key),
+        _ => {
+            eprintln!("usage: profile_efi_cloud_image <disk> <efi variable store> <ssh key>");
+            return;
+        }
+    };
+    let seed_path = std::env::temp_dir().join("profile-efi-cloud-image-seed.iso");
+    CloudInitSeed::with_ssh_key("cloud", "user", &key)
+        .write_to(&seed_path)
+        .unwrap();
+
+    let kind: ProfileKind = "linux-efi-cloud-image".parse().unwrap();
+    let profile = kind.profile();
+    let inputs = ProfileInputs {
+        disk: Some(PathBuf::from(disk)),
+        efi_variable_store: Some(PathBuf::from(efi_variable_store)),
+        cloud_init_seed: Some(seed_path),
+        ..ProfileInputs::default()
+    };
+    let mut builder = match profile::configure(profile.as_ref(), &inputs) {
+        Ok(builder) => builder,
+        Err(errors) => {
+            for error in errors {
+                eprintln!("{}", error);
+            }
+            return;
+        }
+    };
+    if profile.required_devices().serial_console {
+        let attachment = VZFileHandleSerialPortAttachmentBuilder::new()
+            .file_handle_for_reading(NSFileHandle::file_handle_with_standard_input())
+            .file_handle_for_writing(NSFileHandle::file_handle_with_standard_output())
+            .build();
+        builder = builder.serial_ports(vec![VZVirtioConsoleDeviceSerialPortConfiguration::new(
+            attachment,
+        )]);
+    }
+    let conf = builder.cpu_count(2).memory_size_gib(2).build();
+    if let Err(e) = conf.validate_with_error() {
+        eprintln!("{}", e);
+        return;
+    }
+
+    let vm = VZVirtualMachine::new_with_qos(conf, profile.name(), None);
+    let events = vm.error_events();
+    vm.start(|_| {}).unwrap();
+    while let Some(event) = events.recv() {
+        match event {
+            ErrorEvent::Error(e) => eprintln!("{}: {}", e.phase, e.error.ns_error()),
+            ErrorEvent::GuestStopped { .. } => break,
+        }
+    }
+}
@@ -0,0 +1,37 @@
+//! Composes a macOS guest with the `macos` profile and prints what it picked. Booting it also
+//! needs the guest's Mac platform configuration, which this example leaves out, so it stops
+//! short of starting the machine.
+//!
+//! ```sh
+//! cargo run --example profile_macos --features macos-guest -- disk.img
+//! ```
+
+extern crate virtualization_rs;
+
+use virtualization_rs::profile::{self, GuestProfile, MacOS, ProfileInputs};
+
+use std::path::PathBuf;
+
+fn main() {
+    let inputs = ProfileInputs {
+        disk: std::env::args().nth(1).map(PathBuf::from),
+        ..ProfileInputs::default()
+    };
+    let profile = MacOS;
+    let builder = match profile::configure(&profile, &inputs) {
+        Ok(builder) => builder,
+        Err(errors) => {
+            for error in errors {
+                eprintln!("{}", error);
+            }
+            eprintln!("usage: profile_macos <disk>");
+            return;
+        }
+    };
+    let conf = builder.cpu_count(4).memory_size_gib(4).build();
+    print!("{}", conf.describe());
+    println!("{:?}", profile.required_devices());
+    if let Err(e) = conf.validate_with_error() {
+        println!("without a Mac platform: {}", e);
+    }
+}
@@ -0,0 +1,71 @@
+//! Keeps a Linux guest running with a `respawn::Respawner`: each time it crashes or fails to
+//! start, an identical machine is created and started after a growing delay, until `restarts`
+//! restarts within ten minutes used up the budget or the guest shuts itself down.
+//!
+//! ```sh
+//! cargo run --example respawn -- vmlinuz initrd [restarts]
+//! ```
+
+extern crate virtualization_rs;
+
+use virtualization_rs::respawn::{RespawnEvent, Respawner, RestartPolicy};
+use virtualization_rs::virtualization::boot_loader::VZLinuxBootLoaderBuilder;
+use virtualization_rs::virtualization::entropy_device::VZVirtioEntropyDeviceConfiguration;
+use virtualization_rs::virtualization::virtual_machine::VZVirtualMachineConfigurationBuilder;
+
+use std::fs::canonicalize;
+
+fn path(arg: Option<String>) -> String {
+    let arg = arg.expect("usage: respawn <kernel> <initrd> [restarts]");
+    canonicalize(arg)
+        .unwrap()
+        .into_os_string()
+        .into_string()
+        .unwrap()
+}
+
+fn main() {
+    let mut args = std::env::args().skip(1);
+    let boot_loader = VZLinuxBootLoaderBuilder::new()
+        .kernel_url(path(args.next()))
+        .initial_ramdisk_url(path(args.next()))
+        .command_line("console=hvc0")
+        .build()
+        .unwrap();
+    let restarts = args.next().map_or(3, |s| s.parse().unwrap());
+    let conf = VZVirtualMachineConfigurationBuilder::new()
+        .boot_loader(boot_loader)
+        .cpu_count(2)
+        .memory_size_gib(1)
+        .entropy_devices(vec![VZVirtioEntropyDeviceConfiguration::new()])
+        .build();
+    if let Err(e) = conf.validate_with_error() {
+        eprintln!("{}", e);
+        return;
+    }
+
+    let respawner = Respawner::new(conf, "respawn")
+        .policy(RestartPolicy {
+            max_restarts: restarts,
+            ..RestartPolicy::default()
+        })
+        .on_spawn(|vm, attempt| println!("{} created, attempt {}", vm.display_name(), attempt));
+    for event in respawner.spawn() {
+        match event {
+            RespawnEvent::Spawned { .. } => {}
+            RespawnEvent::StartFailed { error, .. } => {
+                eprintln!("start failed: {}", error.ns_error())
+            }
+            RespawnEvent::Stopped { reason, .. } => println!("stopped: {}", reason),
+            // Only with `diagnose_networking_on_failure`, for machines on a NAT attachment.
+            RespawnEvent::NetworkDiagnosed { .. } => {}
+            RespawnEvent::Restarting { attempt, delay } => {
+                println!("restart {} in {:?}", attempt, delay)
+            }
+            RespawnEvent::GaveUp { restarts } => {
+                eprintln!("gave up after {} restarts", restarts)
+            }
+            RespawnEvent::Finished { .. } => println!("done"),
+        }
+    }
+}
@@ -0,0 +1,116 @@
+//! Downloads a macOS restore image, resuming an earlier attempt if one was interrupted, and checks
+//! that this host can install it on a virtual machine with 4 CPUs and 8 GiB of memory.
+//!
+//! The hardware model and machine identifier of the machine to install are kept in
+//! `<dest>.bundle`: the first run creates the bundle, later runs reuse it and check that this host
+//! still supports its hardware model.
+//!
+//! ```sh
+//! cargo run --example restore_download --features restore-download -- \
+//!     https://updates.cdn-apple.com/.../UniversalMac_13.0_22A380_Restore.ipsw restore.ipsw [sha256]
+//! ```
+
+extern crate virtualization_rs;
+
+use virtualization_rs::mac_bundle::{BundleError, MacVmBundle};
+use virtualization_rs::virtualization::error::CompletionOutcome;
+use virtualization_rs::virtualization::restore_image::{
+    digest_from_hex, Downloader, RestoreImageInfo, VZMacOSRestoreImage,
+};
+
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+
+/// The bundle in `dir`, created from the image's hardware model on the first run.
+fn open_bundle(dir: &Path, image: &VZMacOSRestoreImage) -> Result<MacVmBundle, BundleError> {
+    let bundle = match MacVmBundle::load(dir) {
+        Ok(bundle) => {
+            println!("reusing {}", dir.display());
+            bundle
+        }
+        Err(BundleError::Io(e)) if e.kind() == io::ErrorKind::NotFound => {
+            let requirements = image
+                .most_featureful_supported_configuration()
+                .ok_or(BundleError::HardwareModelUnsupported)?;
+            let bundle = MacVmBundle::create(dir, &requirements.hardware_model())?;
+            bundle.save(dir)?;
+            println!("created {}", dir.display());
+            bundle
+        }
+        Err(e) => return Err(e),
+    };
+    // Both fail if the bundle no longer fits this host.
+    bundle.hardware_model()?;
+    bundle.machine_identifier()?;
+    Ok(bundle)
+}
+
+fn main() {
+    let mut args = std::env::args().skip(1);
+    let usage = "usage: restore_download <url> <dest> [sha256]";
+    let url = args.next().expect(usage);
+    let dest = args.next().expect(usage);
+
+    let mut downloader = Downloader::new().on_progress(|progress| {
+        if let (Some(fraction), Some(expected)) = (progress.fraction(), progress.expected) {
+            eprint!(
+                "\r{:5.1}% of {} MiB",
+                fraction * 100.0,
+                expected / (1024 * 1024)
+            );
+            let _ = io::stderr().flush();
+        }
+    });
+    if let Some(hex) = args.next() {
+        downloader = downloader.sha256(digest_from_hex(&hex).expect("not a SHA-256 digest"));
+    }
+    let handle = match downloader.download(&url, &dest) {
+        Ok(handle) => handle,
+        Err(e) => {
+            eprintln!("{}", e);
+            return;
+        }
+    };
+    let path = match handle.wait() {
+        Ok(path) => path,
+        Err(e) => {
+            eprintln!("\n{}", e);
+            return;
+        }
+    };
+    eprintln!("\ndownloaded {}", path.display());
+
+    let (tx, rx) = mpsc::channel();
+    if let Err(e) = VZMacOSRestoreImage::load_file(&path, move |outcome| tx.send(outcome).unwrap())
+    {
+        eprintln!("{}", e);
+        return;
+    }
+    let image = match rx.recv().unwrap() {
+        CompletionOutcome::Success(image) => image,
+        CompletionOutcome::Cancelled => {
+            eprintln!("loading the restore image was cancelled");
+            return;
+        }
+        CompletionOutcome::Failed(e) => {
+            eprintln!("{}", e.ns_error());
+            return;
+        }
+    };
+    let info = RestoreImageInfo::from_image(&image);
+    println!("macOS {:?} ({})", info.os_version, info.build_version);
+    for violation in info.check_against(4, 8 * 1024 * 1024 * 1024) {
+        println!("{}", violation);
+    }
+
+    let mut bundle_dir = path.into_os_string();
+    bundle_dir.push(".bundle");
+    match open_bundle(&PathBuf::from(bundle_dir), &image) {
+        Ok(bundle) => println!(
+            "auxiliary storage for the installer: {}",
+            bundle.auxiliary_storage.display()
+        ),
+        Err(e) => eprintln!("{}", e),
+    }
+}
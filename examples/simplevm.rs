@@ -1,15 +1,13 @@
 extern crate virtualization_rs;
 
-use block::{Block, ConcreteBlock};
 use libc::sleep;
-use objc::rc::StrongPtr;
 use std::fs::canonicalize;
-use std::sync::{Arc, RwLock};
 use virtualization_rs::{
-    base::{dispatch_async, dispatch_queue_create, Id, NSError, NSFileHandle, NIL},
+    base::{NSFileHandle, QoSClass},
     virtualization::{
         boot_loader::VZLinuxBootLoaderBuilder,
         entropy_device::VZVirtioEntropyDeviceConfiguration,
+        kernel_inspect::KernelCheckError,
         memory_device::VZVirtioTraditionalMemoryBalloonDeviceConfiguration,
         network_device::{
             VZMACAddress, VZNATNetworkDeviceAttachment, VZVirtioNetworkDeviceConfiguration,
@@ -20,11 +18,14 @@ use virtualization_rs::{
         storage_device::{
             VZDiskImageStorageDeviceAttachmentBuilder, VZVirtioBlockDeviceConfiguration,
         },
-        virtual_machine::{VZVirtualMachine, VZVirtualMachineConfigurationBuilder},
+        virtual_machine::{
+            StartOutcome, VZVirtualMachine, VZVirtualMachineConfigurationBuilder,
+        },
     },
 };
 
 use std::path::PathBuf;
+use std::time::{Duration, Instant};
 use structopt::StructOpt;
 
 #[derive(StructOpt, Debug)]
@@ -45,15 +46,28 @@ struct Opt {
     #[structopt(short, long, default_value = "4")]
     cpu: usize,
 
-    #[structopt(short, long, default_value = "2147483648")]
-    memory_size: usize,
+    /// Guest memory in MiB
+    #[structopt(short, long, default_value = "2048")]
+    memory_mib: usize,
+
+    /// Boot the kernel even if its format looks wrong for this host
+    #[structopt(long)]
+    skip_kernel_check: bool,
+
+    /// QoS of the VM's queue: user-interactive, user-initiated, default, utility or background
+    #[structopt(long)]
+    qos: Option<QoSClass>,
+
+    /// Seconds the guest gets to reach the running state before it is stopped
+    #[structopt(long, default_value = "30")]
+    start_deadline: u64,
 }
 
 fn main() {
     let opt = Opt::from_args();
 
     let cpu_count = opt.cpu;
-    let memory_size = opt.memory_size;
+    let memory_mib = opt.memory_mib;
     let command_line = opt.command_line;
     let kernel = opt.kernel;
     let disks: Vec<PathBuf> = opt.disk;
@@ -80,7 +94,31 @@ fn main() {
                 .unwrap(),
         )
         .command_line(command_line)
-        .build();
+        .skip_kernel_check(opt.skip_kernel_check)
+        .build_checked();
+    let boot_loader = match boot_loader {
+        Ok((boot_loader, check)) => {
+            for warning in &check.warnings {
+                eprintln!("warning: {}", warning);
+            }
+            boot_loader
+        }
+        Err(KernelCheckError::Rejected(check)) => {
+            for error in &check.errors {
+                eprintln!("error: {}", error);
+            }
+            eprintln!("pass --skip-kernel-check to boot anyway");
+            return;
+        }
+        Err(KernelCheckError::Io(e)) => {
+            eprintln!("error: {}", e);
+            return;
+        }
+        Err(KernelCheckError::InvalidPath(e)) => {
+            eprintln!("error: {}", e);
+            return;
+        }
+    };
     let file_handle_for_reading = NSFileHandle::file_handle_with_standard_input();
     let file_handle_for_writing = NSFileHandle::file_handle_with_standard_output();
     let attachement = VZFileHandleSerialPortAttachmentBuilder::new()
@@ -106,7 +144,7 @@ fn main() {
         {
             Ok(x) => x,
             Err(err) => {
-                err.dump();
+                eprintln!("{}", err);
                 return;
             }
         };
@@ -116,12 +154,14 @@ fn main() {
 
     let network_attachment = VZNATNetworkDeviceAttachment::new();
     let mut network_device = VZVirtioNetworkDeviceConfiguration::new(network_attachment);
-    network_device.set_mac_address(VZMACAddress::random_locally_administered_address());
+    network_device
+        .set_mac_address(VZMACAddress::random_locally_administered_address())
+        .unwrap();
 
     let conf = VZVirtualMachineConfigurationBuilder::new()
         .boot_loader(boot_loader)
         .cpu_count(cpu_count)
-        .memory_size(memory_size)
+        .memory_size_mib(memory_mib)
         .entropy_devices(vec![entropy])
         .memory_balloon_devices(vec![memory_balloon])
         .network_devices(vec![network_device])
@@ -129,29 +169,38 @@ fn main() {
         .storage_devices(block_devices)
         .build();
 
+    for finding in conf.console_preflight() {
+        eprintln!("{}: {}", finding.severity(), finding);
+    }
+
     match conf.validate_with_error() {
         Ok(_) => {
-            let label = std::ffi::CString::new("second").unwrap();
-            let queue = unsafe { dispatch_queue_create(label.as_ptr(), NIL) };
-            let vm = Arc::new(RwLock::new(VZVirtualMachine::new(conf, queue)));
-            let dispatch_block = ConcreteBlock::new(move || {
-                let completion_handler = ConcreteBlock::new(|err: Id| {
-                    if err != NIL {
-                        let error = unsafe { NSError(StrongPtr::new(err)) };
-                        error.dump();
+            let vm = VZVirtualMachine::new_with_qos(conf, "second", opt.qos);
+            let started = Instant::now();
+            let deadline = Duration::from_secs(opt.start_deadline);
+            let failed = vm.clone();
+            vm.start_with_deadline(deadline, move |outcome| {
+                match &outcome {
+                    StartOutcome::Started => {
+                        println!("guest running after {} ms", started.elapsed().as_millis())
                     }
-                });
-                let completion_handler = completion_handler.copy();
-                let completion_handler: &Block<(Id,), ()> = &completion_handler;
-                vm.write()
-                    .unwrap()
-                    .start_with_completion_handler(completion_handler);
-            });
-            let dispatch_block = dispatch_block.copy();
-            let dispatch_block: &Block<(), ()> = &dispatch_block;
-            unsafe {
-                dispatch_async(queue, dispatch_block);
-            }
+                    StartOutcome::FailedToStart(err) => err.ns_error().dump(),
+                    StartOutcome::Cancelled => println!("start cancelled"),
+                    StartOutcome::DeadlineExceeded { last_state } => eprintln!(
+                        "guest not running after {:?} ({}), stopped",
+                        deadline, last_state
+                    ),
+                }
+                match outcome.on_failure_collect_diagnostics(&failed, "diagnostics") {
+                    Ok(Some(_)) => eprintln!("diagnostics written to ./diagnostics"),
+                    Ok(None) => {}
+                    Err(e) => eprintln!("failed to write diagnostics: {}", e),
+                }
+                if let StartOutcome::DeadlineExceeded { .. } = outcome {
+                    std::process::exit(1);
+                }
+            })
+            .expect("a new virtual machine is not started yet");
             loop {
                 unsafe {
                     sleep(100);
@@ -159,8 +208,7 @@ fn main() {
             }
         }
         Err(e) => {
-            e.dump();
-            return;
+            eprintln!("{}", e);
         }
     }
 }
@@ -0,0 +1,43 @@
+extern crate virtualization_rs;
+
+use std::env;
+use std::path::PathBuf;
+use std::thread;
+use std::time::Duration;
+use virtualization_rs::virtualization::{
+    serial_port::VZVirtioConsoleDeviceSerialPortConfiguration, tcp_console::NoClientPolicy,
+    unix_console::UnixSocketConsole,
+};
+
+/// Creates one console socket per VM name given on the command line, e.g.
+/// `cargo run --example unix_consoles web db`, under `$TMPDIR/virtualization-rs`.
+fn main() {
+    let runtime_dir = env::temp_dir().join("virtualization-rs");
+    std::fs::create_dir_all(&runtime_dir).unwrap();
+
+    let mut consoles = Vec::new();
+    for name in env::args().skip(1) {
+        let path: PathBuf = runtime_dir.join(format!("{}.sock", name));
+        let console =
+            match UnixSocketConsole::bind(&path, NoClientPolicy::Buffer { limit: 64 * 1024 }) {
+                Ok(console) => console,
+                Err(e) => {
+                    eprintln!("{}: {}", name, e);
+                    continue;
+                }
+            };
+        // Goes into the VM's configuration with `.serial_ports(vec![serial])`.
+        let _serial = VZVirtioConsoleDeviceSerialPortConfiguration::new(console.attachment());
+        println!(
+            "{}: socat - UNIX-CONNECT:{}",
+            name,
+            console.path().display()
+        );
+        consoles.push(console);
+    }
+
+    // The sockets are removed when `consoles` is dropped.
+    loop {
+        thread::sleep(Duration::from_secs(1));
+    }
+}
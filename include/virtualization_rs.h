@@ -0,0 +1,169 @@
+/*
+ * C interface of virtualization-rs, built with `make capi`; see src/capi.rs.
+ *
+ * Every function may be called from any thread. Callbacks run one at a time on a serial queue
+ * owned by the machine, and may call back into this interface except to free that machine.
+ * Strings handed out through `err` are freed with vrs_string_free.
+ */
+
+#ifndef VIRTUALIZATION_RS_H
+#define VIRTUALIZATION_RS_H
+
+/* Generated by cbindgen from src/capi.rs; run `make header` instead of editing. */
+
+#include <stdint.h>
+
+/**
+ * Bumped when a call or type of this module changes incompatibly.
+ */
+#define VRS_ABI_VERSION 1
+
+/**
+ * Result of a call, and outcome passed to a callback.
+ */
+typedef enum {
+  VRS_STATUS_OK = 0,
+  /**
+   * A pointer argument was NULL or a string was not UTF-8.
+   */
+  VRS_STATUS_INVALID_ARGUMENT = 1,
+  /**
+   * The machine is already starting, running or stopping, as the call needs it not to be.
+   */
+  VRS_STATUS_WRONG_STATE = 2,
+  /**
+   * The framework failed the operation; the callback's message says why.
+   */
+  VRS_STATUS_FAILED = 3,
+  /**
+   * The operation was cancelled.
+   */
+  VRS_STATUS_CANCELLED = 4,
+  /**
+   * The call panicked.
+   */
+  VRS_STATUS_PANICKED = 5,
+} vrs_status;
+
+/**
+ * A validated configuration.
+ */
+typedef struct vrs_config vrs_config;
+
+/**
+ * A virtual machine, with the queue its callbacks run on.
+ */
+typedef struct vrs_vm vrs_vm;
+
+/**
+ * Paths and kernel command line of a profile, as in [`ProfileInputs`]; NULL for those not
+ * given.
+ */
+typedef struct {
+  const char *kernel;
+  const char *initrd;
+  const char *command_line;
+  const char *efi_variable_store;
+  const char *disk;
+  const char *cloud_init_seed;
+} vrs_profile_inputs;
+
+/**
+ * Called once an operation completed, with `ctx` as given, the outcome, and a message unless it
+ * is [`VrsStatus::Ok`].
+ */
+typedef void (*vrs_callback)(void *ctx, vrs_status status, const char *error);
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+/**
+ * [`VRS_ABI_VERSION`] of the library, to check against the header's `VRS_ABI_VERSION`.
+ */
+uint32_t vrs_abi_version(void);
+
+/**
+ * A validated configuration of the profile named `profile`, e.g. `linux-direct-kernel`, with
+ * `inputs`, `cpu_count` CPUs and `memory_mib` MiB of memory, as [`profile::configure`] composes
+ * it. NULL with the reason in `err` if the framework is missing, an input is refused or the
+ * configuration does not validate. Free it with [`vrs_config_free`].
+ *
+ * # Safety
+ * `profile` and the strings of `inputs` must be NULL or NUL-terminated, `inputs` NULL or valid,
+ * and `err` NULL or valid for writes.
+ */
+vrs_config *vrs_config_new_from_profile(const char *profile,
+                                        const vrs_profile_inputs *inputs,
+                                        uint32_t cpu_count,
+                                        uint64_t memory_mib,
+                                        char **err);
+
+/**
+ * Frees a configuration; machines created from it are not affected. NULL is ignored.
+ *
+ * # Safety
+ * `config` must be NULL or returned by [`vrs_config_new_from_profile`] and not freed yet.
+ */
+void vrs_config_free(vrs_config *config);
+
+/**
+ * A new, stopped virtual machine of `config`, which may create more. NULL with the reason in
+ * `err` if `config` is NULL or the call panicked. Free it with [`vrs_vm_free`].
+ *
+ * # Safety
+ * `config` must be NULL or a live configuration, and `err` NULL or valid for writes.
+ */
+vrs_vm *vrs_vm_create(const vrs_config *config, char **err);
+
+/**
+ * Starts the machine; `callback` runs once the framework accepted or refused the start. Returns
+ * [`VrsStatus::WrongState`] without calling `callback` if the machine is starting, running or
+ * stopping.
+ *
+ * # Safety
+ * `vm` must be NULL or a live machine, and `ctx` usable from the callback queue.
+ */
+vrs_status vrs_vm_start(vrs_vm *vm, vrs_callback callback, void *ctx);
+
+/**
+ * Stops the machine without giving the guest a chance to shut down (macOS 12+); `callback` runs
+ * once it stopped or failed to. Returns [`VrsStatus::WrongState`] without calling `callback` if
+ * a stop is in flight.
+ *
+ * # Safety
+ * As for [`vrs_vm_start`].
+ */
+vrs_status vrs_vm_stop(vrs_vm *vm, vrs_callback callback, void *ctx);
+
+/**
+ * The framework's `VZVirtualMachineState` of the machine, e.g. 0 when stopped and 1 when
+ * running; -1 if `vm` is NULL or the call panicked.
+ *
+ * # Safety
+ * `vm` must be NULL or a live machine.
+ */
+int64_t vrs_vm_state(const vrs_vm *vm);
+
+/**
+ * Frees the machine, waiting for callbacks in flight, so not from one of its callbacks. It
+ * does not stop a running machine first. NULL is ignored.
+ *
+ * # Safety
+ * `vm` must be NULL or returned by [`vrs_vm_create`] and not freed yet.
+ */
+void vrs_vm_free(vrs_vm *vm);
+
+/**
+ * Frees a string this interface handed out. NULL is ignored.
+ *
+ * # Safety
+ * `s` must be NULL or a string from an `err` out-parameter, not freed yet.
+ */
+void vrs_string_free(char *s);
+
+#ifdef __cplusplus
+}  // extern "C"
+#endif  // __cplusplus
+
+#endif  /* VIRTUALIZATION_RS_H */
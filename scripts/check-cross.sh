@@ -0,0 +1,16 @@
+#!/bin/sh
+# Type-checks the crate, its tests and examples for both macOS architectures from any host, e.g. a
+# Linux CI runner: nothing is linked or run, so no macOS SDK is needed. Needs the standard library
+# of each target:
+#
+#     rustup target add aarch64-apple-darwin x86_64-apple-darwin
+set -e
+
+for target in aarch64-apple-darwin x86_64-apple-darwin; do
+    for features in "--no-default-features" "" "--all-features"; do
+        echo "==> cargo check --target $target $features"
+        cargo check --target "$target" $features --lib --tests --examples
+    done
+done
+
+echo "cross checks ok"
@@ -0,0 +1,30 @@
+#!/bin/sh
+# Builds the crate for each supported feature combination and checks that only `gui` builds link
+# AppKit. Run on macOS from the repository root.
+set -e
+
+for features in "--no-default-features" "" "--features gui" "--features macos-guest" \
+    "--features restore-download" "--features cloud-init" "--features isolation" "--features capi" \
+    "--features spec" "--features test-util" "--all-features"; do
+    echo "==> cargo build $features"
+    cargo build $features
+done
+
+# The unit test binary links everything the library's #[link] attributes ask for.
+test_binary() {
+    cargo test --lib --no-run "$@" 2>&1 | sed -n 's/.*Executable unittests src\/lib.rs (\(.*\))/\1/p'
+}
+
+headless=$(test_binary --no-default-features)
+if otool -L "$headless" | grep -q AppKit; then
+    echo "error: --no-default-features build links AppKit" >&2
+    exit 1
+fi
+
+gui=$(test_binary --features gui)
+if ! otool -L "$gui" | grep -q AppKit; then
+    echo "error: gui build does not link AppKit" >&2
+    exit 1
+fi
+
+echo "feature combinations ok"
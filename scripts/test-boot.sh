@@ -0,0 +1,13 @@
+#!/bin/sh
+# Runs the ignored tests of tests/boot.rs, which start a virtual machine: the test binary is
+# signed with the virtualization entitlement first. Needs VIRTUALIZATION_RS_TEST_KERNEL and
+# VIRTUALIZATION_RS_TEST_INITRD. Run on macOS from the repository root.
+set -e
+
+binary=$(cargo test --features test-util --test boot --no-run 2>&1 | sed -n 's/.*Executable tests\/boot.rs (\(.*\))/\1/p')
+if [ -z "$binary" ]; then
+    echo "error: could not build tests/boot.rs" >&2
+    exit 1
+fi
+codesign -f --entitlement virtualization_rs.entitlements -s - "$binary"
+"$binary" --ignored --nocapture
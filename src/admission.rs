@@ -0,0 +1,436 @@
+//! admission module
+//!
+//! Checks whether the host has room for a virtual machine before it starts. A machine whose
+//! memory the host cannot back pushes the host into swapping, and a disk image that grows on a
+//! full volume fails the guest's writes long after the start. [`check`] measures the host and
+//! compares it with the machine's [`Requirements`] under an [`AdmissionPolicy`]:
+//!
+//! | Resource | Measured | Admitted if |
+//! |---|---|---|
+//! | memory | free and inactive pages, from `host_statistics64` | memory size ≤ measured × `memory_overcommit` |
+//! | CPUs | online logical CPUs | CPU count ≤ measured × `cpu_overcommit` |
+//! | disk, per volume | space available to users, from `statfs` | growth of the writable files on it + `min_free_disk_bytes` ≤ measured |
+//!
+//! The growth of a file is the part of its length the volume has not allocated yet, i.e. how
+//! much a sparse disk image can still take; read-only files never grow and volumes holding none
+//! of the writable files are not checked. A resource that cannot be measured rejects the machine.
+//!
+//! The measurements are a snapshot: other processes and machines can take the room between the
+//! check and the start. Callers with their own accounting, e.g. of machines admitted but not
+//! started yet, and tests pass their own [`HostProbe`] to [`check_with`].
+//!
+//! # Examples
+//! ```rust,no_run
+//! # use virtualization_rs::admission::{self, AdmissionPolicy};
+//! # use virtualization_rs::virtualization::virtual_machine::VZVirtualMachineConfiguration;
+//! # fn start(conf: &VZVirtualMachineConfiguration) {
+//! let policy = AdmissionPolicy {
+//!     memory_overcommit: 0.8,
+//!     ..AdmissionPolicy::default()
+//! };
+//! let decision = admission::check(conf, &policy);
+//! if !decision.is_admitted() {
+//!     eprintln!("{}", decision);
+//!     return;
+//! }
+//! # }
+//! ```
+
+use crate::virtualization::virtual_machine::{
+    BackingFile, BackingFileKind, VZVirtualMachineConfiguration,
+};
+
+use std::ffi::CString;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::mem;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+type MachPort = u32;
+type KernReturn = i32;
+
+const KERN_SUCCESS: KernReturn = 0;
+const HOST_VM_INFO64: i32 = 4;
+
+/// `vm_statistics64`, naming the counts read here; the rest of its 38 words is left opaque.
+#[repr(C, align(8))]
+struct VmStatistics64 {
+    free_count: u32,
+    _active_count: u32,
+    inactive_count: u32,
+    _rest: [u32; 35],
+}
+
+const HOST_VM_INFO64_COUNT: u32 = (mem::size_of::<VmStatistics64>() / mem::size_of::<i32>()) as u32;
+
+extern "C" {
+    static mach_task_self_: MachPort;
+    fn mach_host_self() -> MachPort;
+    fn mach_port_deallocate(task: MachPort, name: MachPort) -> KernReturn;
+    fn host_page_size(host: MachPort, page_size: *mut usize) -> KernReturn;
+    fn host_statistics64(
+        host: MachPort,
+        flavor: i32,
+        info: *mut i32,
+        count: *mut u32,
+    ) -> KernReturn;
+}
+
+/// How much of the host a machine may take.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AdmissionPolicy {
+    /// Multiplies the free and inactive memory; below 1 keeps room for the host, above 1 counts
+    /// on the guests not touching all of their memory.
+    pub memory_overcommit: f64,
+    /// Multiplies the logical CPU count; above 1 lets machines share CPUs.
+    pub cpu_overcommit: f64,
+    /// Bytes to keep available on each volume holding writable files, after they grew to their
+    /// full length.
+    pub min_free_disk_bytes: u64,
+}
+
+impl Default for AdmissionPolicy {
+    /// No overcommit and 1 GiB kept free.
+    fn default() -> AdmissionPolicy {
+        AdmissionPolicy {
+            memory_overcommit: 1.0,
+            cpu_overcommit: 1.0,
+            min_free_disk_bytes: 1 << 30,
+        }
+    }
+}
+
+/// What a machine takes from the host, from a configuration, an `isolation::HostSpec` or by hand.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Requirements {
+    pub cpu_count: usize,
+    /// In bytes.
+    pub memory_size: u64,
+    pub files: Vec<BackingFile>,
+}
+
+impl From<&VZVirtualMachineConfiguration> for Requirements {
+    fn from(conf: &VZVirtualMachineConfiguration) -> Requirements {
+        Requirements {
+            cpu_count: conf.cpu_count(),
+            memory_size: conf.memory_size() as u64,
+            files: conf.backing_files(),
+        }
+    }
+}
+
+impl From<&Requirements> for Requirements {
+    fn from(requirements: &Requirements) -> Requirements {
+        requirements.clone()
+    }
+}
+
+/// Memory the host could hand to a machine, in bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct MemoryStats {
+    pub free: u64,
+    /// Pages not used lately, which the host reclaims before swapping.
+    pub inactive: u64,
+}
+
+impl MemoryStats {
+    pub fn available(&self) -> u64 {
+        self.free.saturating_add(self.inactive)
+    }
+}
+
+/// A file and the volume holding it, in bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FileStats {
+    /// Tells volumes apart, e.g. the device number.
+    pub volume: u64,
+    /// Space the volume has available to users.
+    pub volume_available: u64,
+    pub len: u64,
+    /// Space the volume allocated to the file.
+    pub allocated: u64,
+}
+
+impl FileStats {
+    /// How much more space the file takes once all of its length is written.
+    pub fn growth(&self) -> u64 {
+        self.len.saturating_sub(self.allocated)
+    }
+}
+
+/// Where [`check_with`] reads the host's resources from.
+pub trait HostProbe {
+    fn memory(&self) -> io::Result<MemoryStats>;
+    fn logical_cpus(&self) -> io::Result<usize>;
+    fn file(&self, path: &Path) -> io::Result<FileStats>;
+}
+
+/// The host this process runs on, the probe of [`check`].
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Host;
+
+impl HostProbe for Host {
+    fn memory(&self) -> io::Result<MemoryStats> {
+        let kern_error =
+            |call: &str, kr: KernReturn| io::Error::other(format!("{} failed: {}", call, kr));
+        unsafe {
+            let host = mach_host_self();
+            let mut page_size = 0usize;
+            let kr = host_page_size(host, &mut page_size);
+            let mut info: VmStatistics64 = mem::zeroed();
+            let mut count = HOST_VM_INFO64_COUNT;
+            let stats_kr = host_statistics64(
+                host,
+                HOST_VM_INFO64,
+                &mut info as *mut VmStatistics64 as *mut i32,
+                &mut count,
+            );
+            mach_port_deallocate(mach_task_self_, host);
+            if kr != KERN_SUCCESS {
+                return Err(kern_error("host_page_size", kr));
+            }
+            if stats_kr != KERN_SUCCESS {
+                return Err(kern_error("host_statistics64", stats_kr));
+            }
+            let page_size = page_size as u64;
+            Ok(MemoryStats {
+                free: u64::from(info.free_count) * page_size,
+                inactive: u64::from(info.inactive_count) * page_size,
+            })
+        }
+    }
+
+    fn logical_cpus(&self) -> io::Result<usize> {
+        match unsafe { libc::sysconf(libc::_SC_NPROCESSORS_ONLN) } {
+            -1 => Err(io::Error::last_os_error()),
+            count => Ok(count as usize),
+        }
+    }
+
+    fn file(&self, path: &Path) -> io::Result<FileStats> {
+        let metadata = fs::metadata(path)?;
+        let c_path = CString::new(path.as_os_str().as_bytes())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        let mut fs_stats: libc::statfs = unsafe { mem::zeroed() };
+        if unsafe { libc::statfs(c_path.as_ptr(), &mut fs_stats) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(FileStats {
+            volume: metadata.dev(),
+            volume_available: (fs_stats.f_bavail as u64).saturating_mul(fs_stats.f_bsize as u64),
+            len: metadata.len(),
+            // `st_blocks` counts 512-byte blocks whatever the volume's block size.
+            allocated: metadata.blocks().saturating_mul(512),
+        })
+    }
+}
+
+/// A resource the host lacks for a machine, with what was required and measured.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Violation {
+    /// In bytes; `allowed` is `measured` times the memory overcommit.
+    Memory {
+        required: u64,
+        measured: u64,
+        allowed: u64,
+    },
+    /// `allowed` is `measured` times the CPU overcommit.
+    Cpu {
+        required: usize,
+        measured: usize,
+        allowed: usize,
+    },
+    /// The writable files on one volume, in bytes: `required` is their growth plus the minimum to
+    /// keep free, `measured` the space the volume has available.
+    Disk {
+        paths: Vec<PathBuf>,
+        required: u64,
+        measured: u64,
+    },
+    /// Measuring `resource` failed with `error`.
+    Unmeasured { resource: String, error: String },
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Violation::Memory {
+                required,
+                measured,
+                allowed,
+            } => write!(
+                f,
+                "memory: {} bytes required, {} allowed of {} free and inactive",
+                required, allowed, measured
+            ),
+            Violation::Cpu {
+                required,
+                measured,
+                allowed,
+            } => write!(
+                f,
+                "CPUs: {} required, {} allowed of {} logical",
+                required, allowed, measured
+            ),
+            Violation::Disk {
+                paths,
+                required,
+                measured,
+            } => {
+                f.write_str("disk: the volume of")?;
+                for (i, path) in paths.iter().enumerate() {
+                    let separator = if i == 0 { "" } else { "," };
+                    write!(f, "{} '{}'", separator, path.display())?;
+                }
+                write!(f, " needs {} bytes available, has {}", required, measured)
+            }
+            Violation::Unmeasured { resource, error } => {
+                write!(f, "{}: not measured: {}", resource, error)
+            }
+        }
+    }
+}
+
+/// The outcome of [`check`].
+#[derive(Debug, Clone, PartialEq)]
+pub enum AdmissionDecision {
+    Admitted,
+    /// Every resource the host lacks, in the order memory, CPUs, disk.
+    Rejected(Vec<Violation>),
+}
+
+impl AdmissionDecision {
+    pub fn is_admitted(&self) -> bool {
+        matches!(self, AdmissionDecision::Admitted)
+    }
+
+    /// Empty when admitted.
+    pub fn violations(&self) -> &[Violation] {
+        match self {
+            AdmissionDecision::Admitted => &[],
+            AdmissionDecision::Rejected(violations) => violations,
+        }
+    }
+}
+
+impl fmt::Display for AdmissionDecision {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AdmissionDecision::Admitted => f.write_str("admitted"),
+            AdmissionDecision::Rejected(violations) => {
+                f.write_str("not admitted: ")?;
+                for (i, violation) in violations.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str("; ")?;
+                    }
+                    write!(f, "{}", violation)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Checks `requirements` against this host under `policy`.
+pub fn check<R: Into<Requirements>>(
+    requirements: R,
+    policy: &AdmissionPolicy,
+) -> AdmissionDecision {
+    check_with(&Host, requirements, policy)
+}
+
+/// Checks `requirements` against what `probe` measures under `policy`.
+pub fn check_with<P, R>(probe: &P, requirements: R, policy: &AdmissionPolicy) -> AdmissionDecision
+where
+    P: HostProbe + ?Sized,
+    R: Into<Requirements>,
+{
+    let requirements = requirements.into();
+    let mut violations = Vec::new();
+
+    match probe.memory() {
+        Ok(memory) => {
+            let measured = memory.available();
+            let allowed = (measured as f64 * policy.memory_overcommit) as u64;
+            if requirements.memory_size > allowed {
+                violations.push(Violation::Memory {
+                    required: requirements.memory_size,
+                    measured,
+                    allowed,
+                });
+            }
+        }
+        Err(e) => violations.push(unmeasured("memory", e)),
+    }
+
+    match probe.logical_cpus() {
+        Ok(measured) => {
+            let allowed = (measured as f64 * policy.cpu_overcommit) as usize;
+            if requirements.cpu_count > allowed {
+                violations.push(Violation::Cpu {
+                    required: requirements.cpu_count,
+                    measured,
+                    allowed,
+                });
+            }
+        }
+        Err(e) => violations.push(unmeasured("CPUs", e)),
+    }
+
+    // Writable files by volume, in the order their first file appears.
+    let mut volumes: Vec<(FileStats, Vec<PathBuf>, u64)> = Vec::new();
+    for file in requirements.files.iter().filter(|file| !file.read_only) {
+        let stats = match probe.file(&file.path) {
+            Ok(stats) => stats,
+            Err(e) => {
+                let resource = format!("{} '{}'", kind_name(file.kind), file.path.display());
+                violations.push(unmeasured(&resource, e));
+                continue;
+            }
+        };
+        match volumes
+            .iter_mut()
+            .find(|(v, _, _)| v.volume == stats.volume)
+        {
+            Some((_, paths, growth)) => {
+                paths.push(file.path.clone());
+                *growth = growth.saturating_add(stats.growth());
+            }
+            None => volumes.push((stats, vec![file.path.clone()], stats.growth())),
+        }
+    }
+    for (stats, paths, growth) in volumes {
+        let required = growth.saturating_add(policy.min_free_disk_bytes);
+        if required > stats.volume_available {
+            violations.push(Violation::Disk {
+                paths,
+                required,
+                measured: stats.volume_available,
+            });
+        }
+    }
+
+    if violations.is_empty() {
+        AdmissionDecision::Admitted
+    } else {
+        AdmissionDecision::Rejected(violations)
+    }
+}
+
+fn unmeasured(resource: &str, error: io::Error) -> Violation {
+    Violation::Unmeasured {
+        resource: resource.to_string(),
+        error: error.to_string(),
+    }
+}
+
+fn kind_name(kind: BackingFileKind) -> &'static str {
+    match kind {
+        BackingFileKind::DiskImage => "disk image",
+        BackingFileKind::EfiVariableStore => "EFI variable store",
+        BackingFileKind::AuxiliaryStorage => "auxiliary storage",
+    }
+}
@@ -1,27 +1,733 @@
 //! base module
 
+use std::cell::{Cell, RefCell};
+use std::convert::TryFrom;
+use std::ffi::{CStr, CString};
+use std::fmt;
 use std::marker::PhantomData;
+use std::os::unix::io::RawFd;
+use std::path::Path;
+use std::ptr;
 use std::slice;
 use std::str;
+use std::sync::atomic::{AtomicBool, AtomicIsize, AtomicPtr, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex, Once};
+use std::time::Duration;
 
-use block::Block;
+use crate::resource::CloseError;
+use crate::runtime::{
+    alloc, call_with_error, debug_assert_non_nil, from_objc_bool, is_shared, non_nil, owned,
+    retained, to_objc_bool,
+};
+use crate::strict;
+use crate::virtualization::error::vz_error_domain;
+
+use block::{Block, ConcreteBlock};
 use objc::rc::StrongPtr;
-use objc::runtime::{Object, BOOL, NO, YES};
+use objc::runtime::{Object, Sel, BOOL};
 use objc::{class, msg_send, sel, sel_impl};
 
-#[link(name = "Virtualization", kind = "framework")]
-extern "C" {}
-
-#[link(name = "Foundation", kind = "framework")]
+#[cfg_attr(target_vendor = "apple", link(name = "Foundation", kind = "framework"))]
 extern "C" {
     pub fn dispatch_queue_create(label: *const libc::c_char, attr: Id) -> Id;
     pub fn dispatch_sync(queue: Id, block: &Block<(), ()>);
     pub fn dispatch_async(queue: Id, block: &Block<(), ()>);
+    pub fn dispatch_after(when: DispatchTime, queue: Id, block: &Block<(), ()>);
+    pub fn dispatch_time(when: DispatchTime, delta: i64) -> DispatchTime;
+    pub fn dispatch_queue_get_label(queue: Id) -> *const libc::c_char;
+    pub fn dispatch_queue_attr_make_with_qos_class(
+        attr: Id,
+        qos_class: libc::c_uint,
+        relative_priority: libc::c_int,
+    ) -> Id;
+    pub fn dispatch_set_target_queue(object: Id, queue: Id);
+    pub fn dispatch_queue_set_specific(
+        queue: Id,
+        key: *const libc::c_void,
+        context: *mut libc::c_void,
+        destructor: Option<extern "C" fn(*mut libc::c_void)>,
+    );
+    pub fn dispatch_get_specific(key: *const libc::c_void) -> *mut libc::c_void;
+    pub fn dispatch_semaphore_create(value: libc::c_long) -> Id;
+    pub fn dispatch_semaphore_wait(semaphore: Id, timeout: DispatchTime) -> libc::c_long;
+    pub fn dispatch_semaphore_signal(semaphore: Id) -> libc::c_long;
+    pub fn dispatch_source_create(
+        type_: *const DispatchSourceType,
+        handle: libc::uintptr_t,
+        mask: libc::c_ulong,
+        queue: Id,
+    ) -> Id;
+    pub fn dispatch_source_set_event_handler(source: Id, handler: &Block<(), ()>);
+    pub fn dispatch_source_set_cancel_handler(source: Id, handler: &Block<(), ()>);
+    pub fn dispatch_source_cancel(source: Id);
+    pub fn dispatch_source_get_data(source: Id) -> libc::c_ulong;
+    pub fn dispatch_suspend(object: Id);
+    pub fn dispatch_resume(object: Id);
+    static _dispatch_source_type_read: DispatchSourceType;
+    static _dispatch_source_type_write: DispatchSourceType;
+    static _dispatch_main_q: Object;
+    static NSUnderlyingErrorKey: Id;
+}
+
+#[cfg(not(target_arch = "aarch64"))]
+extern "C" {
+    fn sysctlbyname(
+        name: *const libc::c_char,
+        oldp: *mut libc::c_void,
+        oldlenp: *mut libc::size_t,
+        newp: *mut libc::c_void,
+        newlen: libc::size_t,
+    ) -> libc::c_int;
 }
 
 pub type Id = *mut Object;
 pub const NIL: Id = 0 as Id;
 
+pub type DispatchTime = u64;
+pub const DISPATCH_TIME_NOW: DispatchTime = 0;
+pub const DISPATCH_TIME_FOREVER: DispatchTime = !0;
+
+/// `struct dispatch_source_type_s`, whose address `DISPATCH_SOURCE_TYPE_READ` and the other
+/// source types are.
+#[repr(C)]
+pub struct DispatchSourceType {
+    _private: [u8; 0],
+}
+
+/// The processor family of the Mac.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub enum HostArch {
+    AppleSilicon,
+    Intel,
+}
+
+impl fmt::Display for HostArch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HostArch::AppleSilicon => write!(f, "Apple silicon"),
+            HostArch::Intel => write!(f, "Intel"),
+        }
+    }
+}
+
+/// The processor family of the Mac, even when an x86_64 build runs translated by Rosetta.
+#[cfg(target_arch = "aarch64")]
+pub fn host_arch() -> HostArch {
+    HostArch::AppleSilicon
+}
+
+/// The processor family of the Mac, even when an x86_64 build runs translated by Rosetta.
+#[cfg(not(target_arch = "aarch64"))]
+pub fn host_arch() -> HostArch {
+    let mut arm64: libc::c_int = 0;
+    let mut len = std::mem::size_of::<libc::c_int>();
+    let ret = unsafe {
+        sysctlbyname(
+            b"hw.optional.arm64\0".as_ptr() as *const libc::c_char,
+            &mut arm64 as *mut libc::c_int as *mut libc::c_void,
+            &mut len,
+            std::ptr::null_mut(),
+            0,
+        )
+    };
+    // The key does not exist on Intel Macs.
+    if ret == 0 && arm64 == 1 {
+        HostArch::AppleSilicon
+    } else {
+        HostArch::Intel
+    }
+}
+
+/// Quality of service of a dispatch queue; the scheduler favors higher classes under load.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QoSClass {
+    UserInteractive,
+    UserInitiated,
+    Default,
+    Utility,
+    Background,
+}
+
+impl QoSClass {
+    /// The `qos_class_t` value from `<sys/qos.h>`.
+    pub fn raw(self) -> libc::c_uint {
+        match self {
+            QoSClass::UserInteractive => 0x21,
+            QoSClass::UserInitiated => 0x19,
+            QoSClass::Default => 0x15,
+            QoSClass::Utility => 0x11,
+            QoSClass::Background => 0x09,
+        }
+    }
+}
+
+impl str::FromStr for QoSClass {
+    type Err = String;
+
+    /// Accepts `user-interactive`, `user-initiated`, `default`, `utility` and `background`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "user-interactive" => Ok(QoSClass::UserInteractive),
+            "user-initiated" => Ok(QoSClass::UserInitiated),
+            "default" => Ok(QoSClass::Default),
+            "utility" => Ok(QoSClass::Utility),
+            "background" => Ok(QoSClass::Background),
+            _ => Err(format!("unknown QoS class {:?}", s)),
+        }
+    }
+}
+
+/// Key under which every queue checked by [`DispatchQueue::is_current`] stores its own address.
+static QUEUE_IDENTITY_KEY: u8 = 0;
+
+/// A dispatch queue. Clones retain the same queue, which lives until the last of them and of the
+/// framework's references is gone, so one queue can be handed to several virtual machines; see
+/// [`crate::queue_pool`].
+#[derive(Clone)]
+pub struct DispatchQueue(pub StrongPtr);
+
+// Dispatch queues are thread-safe objects.
+unsafe impl Send for DispatchQueue {}
+unsafe impl Sync for DispatchQueue {}
+
+impl DispatchQueue {
+    /// Creates a serial dispatch queue.
+    pub fn new(label: &str) -> DispatchQueue {
+        let label = CString::new(label).unwrap_or_default();
+        let queue = unsafe { DispatchQueue(owned(dispatch_queue_create(label.as_ptr(), NIL))) };
+        debug_assert_non_nil!(queue.0, "dispatch_queue_create");
+        queue
+    }
+
+    /// Creates a serial dispatch queue whose blocks run at `qos`.
+    pub fn new_with_qos(label: &str, qos: QoSClass) -> DispatchQueue {
+        let label = CString::new(label).unwrap_or_default();
+        unsafe {
+            // Attributes are immutable singletons and need no release.
+            let attr = dispatch_queue_attr_make_with_qos_class(NIL, qos.raw(), 0);
+            DispatchQueue(owned(dispatch_queue_create(label.as_ptr(), attr)))
+        }
+    }
+
+    /// Runs the queue's blocks on `target`, e.g. to cap several VM queues under one
+    /// lower-priority queue. Set it before submitting work to the queue.
+    pub fn set_target_queue(&self, target: &DispatchQueue) {
+        unsafe { dispatch_set_target_queue(*self.0, *target.0) }
+    }
+
+    /// The queue bound to the main thread.
+    pub fn main() -> DispatchQueue {
+        unsafe { DispatchQueue(retained(&_dispatch_main_q as *const Object as Id)) }
+    }
+
+    /// Wraps an existing queue, retaining it.
+    ///
+    /// # Safety
+    /// `queue` must be a valid dispatch queue.
+    pub unsafe fn from_raw(queue: Id) -> DispatchQueue {
+        DispatchQueue(retained(queue))
+    }
+
+    /// Submits `f` for asynchronous execution on the queue, which runs it on a thread of its own.
+    pub fn exec_async<F: FnOnce() + Send + 'static>(&self, f: F) {
+        strict::non_nil(*self.0, "DispatchQueue");
+        let f = Cell::new(Some(f));
+        let block = ConcreteBlock::new(move || {
+            if let Some(f) = f.take() {
+                f();
+            }
+        });
+        let block = block.copy();
+        unsafe { dispatch_async(*self.0, &block) }
+    }
+
+    /// Runs `f` on the queue once `delay` has passed.
+    ///
+    /// libdispatch cannot withdraw a submitted block, so cancelling the returned token makes the
+    /// block return without calling `f`; it has no effect once `f` has started.
+    pub fn after<F: FnOnce() + Send + 'static>(&self, delay: Duration, f: F) -> CancellationToken {
+        strict::non_nil(*self.0, "DispatchQueue");
+        let token = CancellationToken::new();
+        let cancelled = token.clone();
+        let f = Cell::new(Some(f));
+        let block = ConcreteBlock::new(move || {
+            if cancelled.is_cancelled() {
+                return;
+            }
+            if let Some(f) = f.take() {
+                f();
+            }
+        });
+        let block = block.copy();
+        let delta = delay.as_nanos().min(i64::MAX as u128) as i64;
+        unsafe {
+            let when = dispatch_time(DISPATCH_TIME_NOW, delta);
+            dispatch_after(when, *self.0, &block);
+        }
+        token
+    }
+
+    /// Runs `f` on the queue and waits for it. Deadlocks if called from the queue itself; see
+    /// [`DispatchQueue::assert_not_current`].
+    pub fn exec_sync<R, F: FnOnce() -> R>(&self, f: F) -> R {
+        strict::non_nil(*self.0, "DispatchQueue");
+        strict::not_on_queue(self);
+        let f = Cell::new(Some(f));
+        let ret = Cell::new(None);
+        let block = ConcreteBlock::new(|| {
+            if let Some(f) = f.take() {
+                ret.set(Some(f()));
+            }
+        });
+        unsafe { dispatch_sync(*self.0, &block) };
+        ret.into_inner().unwrap()
+    }
+
+    /// The label the queue was created with.
+    pub fn label(&self) -> String {
+        unsafe { label_string(dispatch_queue_get_label(*self.0)) }
+    }
+
+    /// The label of the queue the caller is running on, e.g. to assert where a callback runs.
+    pub fn current_label() -> String {
+        // `DISPATCH_CURRENT_QUEUE_LABEL` is a null queue.
+        unsafe { label_string(dispatch_queue_get_label(NIL)) }
+    }
+
+    /// Whether the caller runs on this queue, or on a queue that targets it.
+    ///
+    /// The queue is tagged with `dispatch_queue_set_specific` on first use, and
+    /// `dispatch_get_specific` looks the tag up through the target queue hierarchy, so this also
+    /// holds for queues created with [`DispatchQueue::set_target_queue`] pointing here.
+    pub fn is_current(&self) -> bool {
+        let key = &QUEUE_IDENTITY_KEY as *const u8 as *const libc::c_void;
+        let identity = *self.0 as *mut libc::c_void;
+        unsafe {
+            dispatch_queue_set_specific(*self.0, key, identity, None);
+            dispatch_get_specific(key) == identity
+        }
+    }
+
+    /// Panics if the caller runs on this queue, where waiting for work on the queue would hang
+    /// forever. `what` names the blocking call in the message.
+    #[track_caller]
+    pub fn assert_not_current(&self, what: &str) {
+        if self.is_current() {
+            panic!(
+                "{} was called on dispatch queue \"{}\" and would wait for that same queue; \
+                 call it from another thread or queue",
+                what,
+                self.label()
+            );
+        }
+    }
+
+    /// Whether both are the same queue, e.g. two machines' [`VZVirtualMachine::queue`].
+    ///
+    /// [`VZVirtualMachine::queue`]: crate::virtualization::virtual_machine::VZVirtualMachine::queue
+    pub fn is_same(&self, other: &DispatchQueue) -> bool {
+        *self.0 == *other.0
+    }
+
+    pub fn id(&self) -> Id {
+        *self.0
+    }
+}
+
+unsafe fn label_string(label: *const libc::c_char) -> String {
+    if label.is_null() {
+        String::new()
+    } else {
+        CStr::from_ptr(label).to_string_lossy().into_owned()
+    }
+}
+
+/// A counting dispatch semaphore, to block a thread until a callback fires.
+///
+/// Share it through an `Arc` with the callback that signals it. Waiting on the queue the callback
+/// runs on deadlocks.
+///
+/// # Dropping
+/// The semaphore must outlive its waiters, which the borrow in [`wait`](Self::wait) ensures.
+/// libdispatch also aborts the process when a semaphore is released with a lower value than it
+/// was created with, i.e. with more successful waits than signals. Debug builds assert against
+/// that; release builds signal the difference first to keep the process alive.
+pub struct DispatchSemaphore {
+    p: StrongPtr,
+    initial: isize,
+    /// Signals minus successful waits, plus `initial`: what libdispatch compares on release.
+    value: AtomicIsize,
+}
+
+// Dispatch semaphores are thread-safe objects.
+unsafe impl Send for DispatchSemaphore {}
+unsafe impl Sync for DispatchSemaphore {}
+
+impl DispatchSemaphore {
+    /// A semaphore that lets `initial` waits through before the first signal. Panics if `initial`
+    /// is negative, which libdispatch refuses.
+    pub fn new(initial: isize) -> DispatchSemaphore {
+        assert!(
+            initial >= 0,
+            "DispatchSemaphore::new({}): negative value",
+            initial
+        );
+        let p = unsafe { owned(dispatch_semaphore_create(initial as libc::c_long)) };
+        debug_assert_non_nil!(p, "dispatch_semaphore_create");
+        DispatchSemaphore {
+            p,
+            initial,
+            value: AtomicIsize::new(initial),
+        }
+    }
+
+    /// Increments the semaphore, waking one waiter if there is one. Never blocks, so callbacks
+    /// on any queue can call it.
+    pub fn signal(&self) {
+        self.value.fetch_add(1, Ordering::SeqCst);
+        unsafe { dispatch_semaphore_signal(*self.p) };
+    }
+
+    /// Blocks until the semaphore can be decremented.
+    pub fn wait(&self) {
+        self.wait_until(DISPATCH_TIME_FOREVER);
+    }
+
+    /// Blocks until the semaphore can be decremented or `timeout` passes, and tells which.
+    /// Timeouts beyond what `dispatch_time` holds, about 292 years, wait forever.
+    pub fn wait_timeout(&self, timeout: Duration) -> bool {
+        let when = match i64::try_from(timeout.as_nanos()) {
+            Ok(delta) => unsafe { dispatch_time(DISPATCH_TIME_NOW, delta) },
+            Err(_) => DISPATCH_TIME_FOREVER,
+        };
+        self.wait_until(when)
+    }
+
+    fn wait_until(&self, when: DispatchTime) -> bool {
+        let acquired = unsafe { dispatch_semaphore_wait(*self.p, when) } == 0;
+        if acquired {
+            self.value.fetch_sub(1, Ordering::SeqCst);
+        }
+        acquired
+    }
+}
+
+impl Drop for DispatchSemaphore {
+    fn drop(&mut self) {
+        let value = *self.value.get_mut();
+        debug_assert!(
+            value >= self.initial,
+            "DispatchSemaphore dropped with value {} below its initial value {}: libdispatch \
+             aborts on that; signal once per successful wait",
+            value,
+            self.initial
+        );
+        for _ in value..self.initial {
+            unsafe { dispatch_semaphore_signal(*self.p) };
+        }
+    }
+}
+
+/// The event a [`DispatchSource`] monitors its file descriptor for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DispatchSourceKind {
+    /// Data to read, or end of file; the handler gets an estimate of the bytes available.
+    Read,
+    /// Room to write; the handler gets an estimate of the buffer space available.
+    Write,
+}
+
+/// What a source's cancellation handler shares with its wrapper.
+struct SourceState {
+    cancelled: AtomicBool,
+    /// Whether the cancellation handler ran, after which libdispatch no longer uses the fd.
+    done: Mutex<bool>,
+    done_changed: Condvar,
+    on_cancel: Mutex<Option<Box<dyn FnOnce() + Send>>>,
+}
+
+struct SourceInner {
+    p: StrongPtr,
+    kind: DispatchSourceKind,
+    fd: RawFd,
+    /// `dispatch_suspend` calls not yet balanced by `dispatch_resume`.
+    suspensions: AtomicUsize,
+    state: Arc<SourceState>,
+}
+
+// Dispatch sources are thread-safe objects.
+unsafe impl Send for SourceInner {}
+unsafe impl Sync for SourceInner {}
+
+/// A dispatch source monitoring a file descriptor for reading or writing, whose handler runs on
+/// a dispatch queue instead of a thread blocked in `read` or `write`.
+///
+/// Clones share the source. libdispatch has two rules this wraps:
+/// - A source must not be released while suspended. [`cancel`](Self::cancel) and dropping the
+///   last clone resume it as often as it was suspended.
+/// - The fd must stay open until the source is cancelled and its cancellation handler has run.
+///   Close it in [`on_cancel`](Self::on_cancel), or after
+///   [`wait_cancelled`](Self::wait_cancelled) returned `true`. Debug builds assert that the fd is
+///   still open when the source is cancelled.
+///
+/// The handlers run on the queue's threads, one at a time, so they must be `Send`. A handler
+/// that panics aborts the process, as they are called from C.
+///
+/// # Examples
+/// ```rust
+/// let source = DispatchSource::read(fd, &queue, move |estimated| {
+///     let mut buf = vec![0; estimated.max(1)];
+///     let n = unsafe { libc::read(fd, buf.as_mut_ptr() as *mut libc::c_void, buf.len()) };
+///     // ...
+/// });
+/// source.on_cancel(move || unsafe {
+///     libc::close(fd);
+/// });
+/// ```
+#[derive(Clone)]
+pub struct DispatchSource(Arc<SourceInner>);
+
+impl DispatchSource {
+    /// Calls `handler` on `queue` whenever `fd` has data to read or reached end of file, with an
+    /// estimate of the bytes available, 0 at end of file. The source is running when returned.
+    pub fn read<F: FnMut(usize) + Send + 'static>(
+        fd: RawFd,
+        queue: &DispatchQueue,
+        handler: F,
+    ) -> DispatchSource {
+        DispatchSource::new(DispatchSourceKind::Read, fd, queue, handler)
+    }
+
+    /// Calls `handler` on `queue` whenever `fd` has room to write, with an estimate of the
+    /// buffer space available. The source is running when returned.
+    pub fn write<F: FnMut(usize) + Send + 'static>(
+        fd: RawFd,
+        queue: &DispatchQueue,
+        handler: F,
+    ) -> DispatchSource {
+        DispatchSource::new(DispatchSourceKind::Write, fd, queue, handler)
+    }
+
+    fn new<F: FnMut(usize) + Send + 'static>(
+        kind: DispatchSourceKind,
+        fd: RawFd,
+        queue: &DispatchQueue,
+        handler: F,
+    ) -> DispatchSource {
+        strict::non_nil(*queue.0, "DispatchQueue");
+        let type_ = unsafe {
+            match kind {
+                DispatchSourceKind::Read => &_dispatch_source_type_read as *const _,
+                DispatchSourceKind::Write => &_dispatch_source_type_write as *const _,
+            }
+        };
+        let p = unsafe {
+            owned(dispatch_source_create(
+                type_,
+                fd as libc::uintptr_t,
+                0,
+                *queue.0,
+            ))
+        };
+        debug_assert_non_nil!(p, "dispatch_source_create");
+        let state = Arc::new(SourceState {
+            cancelled: AtomicBool::new(false),
+            done: Mutex::new(false),
+            done_changed: Condvar::new(),
+            on_cancel: Mutex::new(None),
+        });
+
+        // The source retains both blocks and releases them once cancelled, so its own pointer
+        // stays valid for as long as the event handler can run.
+        let source = *p as usize;
+        let handler = RefCell::new(handler);
+        let event = ConcreteBlock::new(move || {
+            let estimated = unsafe { dispatch_source_get_data(source as Id) };
+            (handler.borrow_mut())(estimated as usize);
+        })
+        .copy();
+        let cancelled = state.clone();
+        let cancel = ConcreteBlock::new(move || {
+            let on_cancel = cancelled
+                .on_cancel
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .take();
+            if let Some(on_cancel) = on_cancel {
+                on_cancel();
+            }
+            *cancelled.done.lock().unwrap_or_else(|e| e.into_inner()) = true;
+            cancelled.done_changed.notify_all();
+        })
+        .copy();
+        unsafe {
+            dispatch_source_set_event_handler(*p, &event);
+            dispatch_source_set_cancel_handler(*p, &cancel);
+            // Sources are created inactive.
+            dispatch_resume(*p);
+        }
+        DispatchSource(Arc::new(SourceInner {
+            p,
+            kind,
+            fd,
+            suspensions: AtomicUsize::new(0),
+            state,
+        }))
+    }
+
+    pub fn kind(&self) -> DispatchSourceKind {
+        self.0.kind
+    }
+
+    /// The monitored file descriptor.
+    pub fn fd(&self) -> RawFd {
+        self.0.fd
+    }
+
+    /// Runs `handler` on the queue once the source is cancelled, after the last event handler
+    /// call, e.g. to close the fd. Replaces the handler given before. Has no effect once the
+    /// source is cancelled, which debug builds assert against.
+    pub fn on_cancel<F: FnOnce() + Send + 'static>(&self, handler: F) {
+        debug_assert!(
+            !self.is_cancelled(),
+            "DispatchSource::on_cancel called on a cancelled source"
+        );
+        *self
+            .0
+            .state
+            .on_cancel
+            .lock()
+            .unwrap_or_else(|e| e.into_inner()) = Some(Box::new(handler));
+    }
+
+    /// Stops delivering events until [`resume`](Self::resume). Events that arrive meanwhile are
+    /// coalesced into one call after it. Each call needs its own `resume`.
+    pub fn suspend(&self) {
+        self.0.suspensions.fetch_add(1, Ordering::SeqCst);
+        unsafe { dispatch_suspend(*self.0.p) };
+    }
+
+    /// Balances one [`suspend`](Self::suspend). libdispatch crashes on a resume without one:
+    /// debug builds assert against it, release builds ignore it.
+    pub fn resume(&self) {
+        let balanced = self
+            .0
+            .suspensions
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+            .is_ok();
+        debug_assert!(
+            balanced,
+            "DispatchSource::resume without a matching suspend"
+        );
+        if balanced {
+            unsafe { dispatch_resume(*self.0.p) };
+        }
+    }
+
+    pub fn is_suspended(&self) -> bool {
+        self.0.suspensions.load(Ordering::SeqCst) > 0
+    }
+
+    /// Cancels the source: no event handler call starts after this, and the cancellation handler
+    /// runs on the queue once the last one returned. A suspended source is resumed, so that it
+    /// gets there. Cancelling again does nothing.
+    ///
+    /// Debug builds assert that the fd is still open.
+    pub fn cancel(&self) {
+        self.0.cancel(true);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.state.cancelled.load(Ordering::SeqCst)
+    }
+
+    /// Waits up to `timeout` for the cancellation handler to have run, and tells whether it has;
+    /// from then on the fd may be closed. Called from the source's queue, it always waits out
+    /// `timeout`: the handler cannot run before it returns.
+    pub fn wait_cancelled(&self, timeout: Duration) -> bool {
+        let state = &self.0.state;
+        let done = state.done.lock().unwrap_or_else(|e| e.into_inner());
+        let (done, _) = state
+            .done_changed
+            .wait_timeout_while(done, timeout, |done| !*done)
+            .unwrap_or_else(|e| e.into_inner());
+        *done
+    }
+
+    pub fn id(&self) -> Id {
+        *self.0.p
+    }
+}
+
+impl SourceInner {
+    fn cancel(&self, check_fd: bool) {
+        if self.state.cancelled.load(Ordering::SeqCst) {
+            return;
+        }
+        debug_assert!(
+            !check_fd || unsafe { libc::fcntl(self.fd, libc::F_GETFD) } != -1,
+            "DispatchSource on fd {} cancelled after the fd was closed; cancel it and wait for \
+             its cancellation handler first",
+            self.fd
+        );
+        if self.state.cancelled.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        unsafe { dispatch_source_cancel(*self.p) };
+        for _ in 0..self.suspensions.swap(0, Ordering::SeqCst) {
+            unsafe { dispatch_resume(*self.p) };
+        }
+    }
+}
+
+impl Drop for SourceInner {
+    fn drop(&mut self) {
+        // Releasing a suspended source crashes, and one left running would keep using the fd.
+        self.cancel(!std::thread::panicking());
+    }
+}
+
+/// Where the safe wrappers run a Rust completion closure.
+#[derive(Clone, Default)]
+pub enum CallbackQueue {
+    /// Inline on the queue the framework calls back on, the default.
+    #[default]
+    Framework,
+    /// Re-dispatched asynchronously onto this queue.
+    Queue(DispatchQueue),
+}
+
+impl CallbackQueue {
+    /// Runs `f` where this callback queue says. Anything `f` captures from the framework's
+    /// arguments must already be retained, since the callback may run after the framework's
+    /// autorelease pool has drained.
+    pub(crate) fn deliver<F: FnOnce() + Send + 'static>(&self, f: F) {
+        match self {
+            CallbackQueue::Framework => f(),
+            CallbackQueue::Queue(queue) => queue.exec_async(f),
+        }
+    }
+}
+
+/// A flag shared between a long-running helper and whoever wants to abort it.
+#[derive(Clone, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    pub fn new() -> CancellationToken {
+        CancellationToken::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
 pub type NSInteger = libc::c_long;
 pub type NSUInteger = libc::c_ulong;
 
@@ -32,26 +738,114 @@ pub struct NSArray<T> {
 
 impl<T> NSArray<T> {
     pub fn array_with_objects(objects: Vec<Id>) -> NSArray<T> {
+        NSArray::from_slice(&objects)
+    }
+
+    /// Builds the array straight from a borrowed buffer, so callers can reuse one across arrays.
+    /// An empty buffer gives the shared empty array instead of a new one.
+    pub fn from_slice(objects: &[Id]) -> NSArray<T> {
+        if objects.is_empty() {
+            return NSArray::empty();
+        }
         unsafe {
-            let p = StrongPtr::new(
-                msg_send![class!(NSArray), arrayWithObjects:objects.as_slice().as_ptr() count:objects.len()],
+            // `arrayWithObjects:count:` returns the array autoreleased.
+            let p = retained(
+                msg_send![class!(NSArray), arrayWithObjects:objects.as_ptr() count:objects.len()],
             );
             NSArray {
-                p: p,
+                p,
                 _phantom: PhantomData,
             }
         }
     }
 
+    /// `+[NSArray array]`, created on first use and retained for the life of the process, so
+    /// setters given no devices skip building an array each.
+    pub fn empty() -> NSArray<T> {
+        static CREATE: Once = Once::new();
+        static EMPTY: AtomicPtr<Object> = AtomicPtr::new(ptr::null_mut());
+        CREATE.call_once(|| unsafe {
+            let empty: Id = msg_send![class!(NSArray), array];
+            // Never released, so it outlives the pool `array` autoreleased it into.
+            let empty: Id = msg_send![empty, retain];
+            EMPTY.store(empty, Ordering::Release);
+        });
+        NSArray {
+            p: unsafe { retained(EMPTY.load(Ordering::Acquire)) },
+            _phantom: PhantomData,
+        }
+    }
+
+    /// Number of elements. A wrapper around nil, e.g. from a missing property, counts 0 because
+    /// messages to nil return zero.
     pub fn count(&self) -> usize {
         unsafe { msg_send![*self.p, count] }
     }
 }
 
 impl<T: From<StrongPtr>> NSArray<T> {
+    /// The element at `index`, or `None` when it is out of bounds.
+    pub fn get(&self, index: usize) -> Option<T> {
+        if index < self.count() {
+            Some(unsafe { T::from(retained(msg_send![*self.p, objectAtIndex: index])) })
+        } else {
+            None
+        }
+    }
+
+    pub fn try_first(&self) -> Option<T> {
+        self.get(0)
+    }
+
+    pub fn try_last(&self) -> Option<T> {
+        self.count().checked_sub(1).and_then(|i| self.get(i))
+    }
+
+    /// Panics, rather than raising `NSRangeException`, when `index` is out of bounds.
+    #[deprecated(note = "use `get`, which returns `None` when the index is out of bounds")]
     pub fn object_at_index(&self, index: usize) -> T {
-        debug_assert!(index < self.count());
-        unsafe { T::from(StrongPtr::retain(msg_send![*self.p, objectAtIndex: index])) }
+        match self.get(index) {
+            Some(object) => object,
+            None => panic!(
+                "NSArray index {} out of bounds (count {})",
+                index,
+                self.count()
+            ),
+        }
+    }
+}
+
+impl NSArray<NSString> {
+    /// Creates the strings as well; the array retains them, so nothing else has to be kept alive.
+    pub fn from_strings(items: &[&str]) -> NSArray<NSString> {
+        let strings: Vec<NSString> = items.iter().map(|item| NSString::new(item)).collect();
+        let objects: Vec<Id> = strings.iter().map(|string| *string.0).collect();
+        NSArray::from_slice(&objects)
+    }
+
+    /// Copies the strings out, e.g. of an array the framework returned.
+    pub fn to_strings(&self) -> Vec<String> {
+        (0..self.count())
+            .filter_map(|i| self.get(i))
+            .map(|string| string.as_str().to_string())
+            .collect()
+    }
+}
+
+impl NSArray<NSURL> {
+    /// Creates a file URL for each path, a directory URL for the ones that are directories now.
+    /// Fails, naming the path, on one that cannot be a file URL, e.g. an empty or non-UTF-8 one.
+    pub fn from_file_paths(paths: &[&Path]) -> Result<NSArray<NSURL>, InvalidInput> {
+        let urls = paths
+            .iter()
+            .map(|path| {
+                path.to_str()
+                    .and_then(|s| NSURL::file_url_with_path(s, path.is_dir()))
+                    .ok_or_else(|| InvalidInput::new("file path", &path.to_string_lossy()))
+            })
+            .collect::<Result<Vec<NSURL>, InvalidInput>>()?;
+        let objects: Vec<Id> = urls.iter().map(|url| *url.0).collect();
+        Ok(NSArray::from_slice(&objects))
     }
 }
 
@@ -61,22 +855,37 @@ pub struct NSString(pub StrongPtr);
 impl NSString {
     pub fn new(string: &str) -> NSString {
         unsafe {
-            let alloc: Id = msg_send![class!(NSString), alloc];
-            let p = StrongPtr::new(
-                msg_send![alloc, initWithBytes:string.as_ptr() length:string.len() encoding:UTF8_ENCODING as Id],
+            let i = alloc(class!(NSString));
+            let p = owned(
+                msg_send![i, initWithBytes:string.as_ptr() length:string.len() encoding:UTF8_ENCODING],
             );
+            // `string` is valid UTF-8, so the bytes always decode.
+            debug_assert_non_nil!(p, "-[NSString initWithBytes:length:encoding:]");
             NSString(p)
         }
     }
 
+    /// The length in UTF-8 bytes; 0 for a nil wrapper.
     pub fn len(&self) -> usize {
-        unsafe { msg_send![*self.0, lengthOfBytesUsingEncoding: UTF8_ENCODING] }
+        match non_nil(*self.0, "NSString") {
+            Ok(p) => unsafe { msg_send![p, lengthOfBytesUsingEncoding: UTF8_ENCODING] },
+            Err(_) => 0,
+        }
     }
 
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The string; empty for a nil wrapper, whose `UTF8String` would be a null pointer.
     pub fn as_str(&self) -> &str {
+        let p = match non_nil(*self.0, "NSString") {
+            Ok(p) => p,
+            Err(_) => return "",
+        };
         unsafe {
             let bytes = {
-                let bytes: *const libc::c_char = msg_send![*self.0, UTF8String];
+                let bytes: *const libc::c_char = msg_send![p, UTF8String];
                 bytes as *const u8
             };
             let len = self.len();
@@ -92,39 +901,106 @@ impl From<StrongPtr> for NSString {
     }
 }
 
+/// A value a constructor refused, e.g. a string that is not a URL, named so the error points at
+/// the input rather than at the framework call that would have failed on it later.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InvalidInput {
+    /// What the value was meant to be, e.g. `"kernel URL"`.
+    pub kind: &'static str,
+    pub value: String,
+}
+
+impl InvalidInput {
+    pub fn new(kind: &'static str, value: &str) -> InvalidInput {
+        InvalidInput {
+            kind,
+            value: value.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for InvalidInput {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid {}: {:?}", self.kind, self.value)
+    }
+}
+
+impl std::error::Error for InvalidInput {}
+
 pub struct NSURL(pub StrongPtr);
 
+// Immutable, like `NSError`.
+unsafe impl Send for NSURL {}
+unsafe impl Sync for NSURL {}
+
 impl NSURL {
-    pub fn url_with_string(url: &str) -> NSURL {
+    /// `None` if `url` is not a valid URL string (RFC 2396), e.g. `"http://["`.
+    pub fn url_with_string(url: &str) -> Option<NSURL> {
         unsafe {
             let url_nsstring = NSString::new(url);
-            let p = StrongPtr::retain(msg_send![class!(NSURL), URLWithString: url_nsstring]);
-            NSURL(p)
+            let p = retained(msg_send![class!(NSURL), URLWithString: *url_nsstring.0]);
+            if *p == NIL {
+                return None;
+            }
+            Some(NSURL(p))
         }
     }
 
-    pub fn file_url_with_path(path: &str, is_directory: bool) -> NSURL {
+    /// `None` if `path` is empty, for which `+[NSURL fileURLWithPath:isDirectory:]` raises, or
+    /// contains a NUL byte, which no file path can.
+    pub fn file_url_with_path(path: &str, is_directory: bool) -> Option<NSURL> {
+        if path.is_empty() || path.contains('\0') {
+            return None;
+        }
         unsafe {
             let path_nsstring = NSString::new(path);
-            let is_directory_ = if is_directory { YES } else { NO };
-            let p = StrongPtr::retain(
-                msg_send![class!(NSURL), fileURLWithPath:path_nsstring isDirectory:is_directory_],
+            let p = retained(
+                msg_send![class!(NSURL), fileURLWithPath:*path_nsstring.0 isDirectory:to_objc_bool(is_directory)],
             );
-            NSURL(p)
+            if *p == NIL {
+                return None;
+            }
+            Some(NSURL(p))
         }
     }
 
     pub fn check_resource_is_reachable_and_return_error(&self) -> bool {
         let b: BOOL = unsafe { msg_send![*self.0, checkResourceIsReachableAndReturnError: NIL] };
-        b == YES
+        from_objc_bool(b)
     }
 
     pub fn absolute_url(&self) -> NSURL {
         unsafe {
-            let p = StrongPtr::retain(msg_send![*self.0, absoluteURL]);
+            let p = retained(msg_send![*self.0, absoluteURL]);
+            debug_assert_non_nil!(p, "-[NSURL absoluteURL]");
             NSURL(p)
         }
     }
+
+    /// The path component of a file URL.
+    pub fn path(&self) -> NSString {
+        unsafe { NSString(retained(msg_send![*self.0, path])) }
+    }
+
+    /// The whole URL, e.g. `https://updates.cdn-apple.com/.../Restore.ipsw`.
+    pub fn absolute_string(&self) -> NSString {
+        unsafe { NSString(retained(msg_send![*self.0, absoluteString])) }
+    }
+}
+
+impl From<StrongPtr> for NSURL {
+    fn from(p: StrongPtr) -> Self {
+        NSURL(p)
+    }
+}
+
+/// `NSOperatingSystemVersion`, returned by value from the framework.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NSOperatingSystemVersion {
+    pub major_version: NSInteger,
+    pub minor_version: NSInteger,
+    pub patch_version: NSInteger,
 }
 
 pub struct NSFileHandle(pub StrongPtr);
@@ -132,27 +1008,165 @@ pub struct NSFileHandle(pub StrongPtr);
 impl NSFileHandle {
     pub fn new() -> NSFileHandle {
         unsafe {
-            let p = StrongPtr::new(msg_send![class!(NSFileHandle), new]);
+            let p = owned(msg_send![class!(NSFileHandle), new]);
+            debug_assert_non_nil!(p, "+[NSFileHandle new]");
+            NSFileHandle(p)
+        }
+    }
+
+    /// Wraps `fd`. With `close_on_dealloc` the handle takes ownership of the descriptor.
+    pub fn init_with_file_descriptor(fd: RawFd, close_on_dealloc: bool) -> NSFileHandle {
+        unsafe {
+            let i = alloc(class!(NSFileHandle));
+            let p = owned(
+                msg_send![i, initWithFileDescriptor:fd closeOnDealloc:to_objc_bool(close_on_dealloc)],
+            );
+            debug_assert_non_nil!(p, "-[NSFileHandle initWithFileDescriptor:closeOnDealloc:]");
             NSFileHandle(p)
         }
     }
 
     pub fn file_handle_with_standard_input() -> NSFileHandle {
         unsafe {
-            let p = StrongPtr::retain(msg_send![class!(NSFileHandle), fileHandleWithStandardInput]);
+            let p = retained(msg_send![class!(NSFileHandle), fileHandleWithStandardInput]);
             NSFileHandle(p)
         }
     }
 
     pub fn file_handle_with_standard_output() -> NSFileHandle {
         unsafe {
-            let p = StrongPtr::retain(msg_send![
+            let p = retained(msg_send![
                 class!(NSFileHandle),
                 fileHandleWithStandardOutput
             ]);
             NSFileHandle(p)
         }
     }
+
+    pub fn file_descriptor(&self) -> RawFd {
+        unsafe { msg_send![*self.0, fileDescriptor] }
+    }
+
+    /// Writes all of `data`, blocking until it is written. On a non-blocking descriptor, a full
+    /// pipe fails with `EAGAIN` after a partial write.
+    pub fn write_all(&self, data: &[u8]) -> Result<(), NSError> {
+        let data = NSData::with_bytes(data);
+        unsafe {
+            call_with_error(|error| {
+                let ret: BOOL = msg_send![*self.0, writeData:*data.0 error:error];
+                from_objc_bool(ret)
+            })
+        }
+        .map(drop)
+    }
+
+    /// Reads what one `read(2)` of the descriptor returns, at most `count` bytes: it waits for
+    /// data on a blocking descriptor, and returns what is there without waiting for `count`.
+    ///
+    /// Returns `Ok` with no bytes when a non-blocking descriptor has nothing to read, and fails
+    /// with an error for which [`NSError::is_file_handle_closed`] holds at end of file. Other
+    /// failures are `NSPOSIXErrorDomain` errors with the `errno`.
+    ///
+    /// The framework reads from the handle given to it as `file_handle_for_reading` and writes to
+    /// `file_handle_for_writing`. Read the guest's output from the other end of the writing
+    /// handle's pipe and never from a handle the framework reads, or the two race for the bytes.
+    pub fn read_up_to(&self, count: usize) -> Result<Vec<u8>, NSError> {
+        let mut buf = vec![0u8; count];
+        if count == 0 {
+            return Ok(buf);
+        }
+        let fd = self.file_descriptor();
+        loop {
+            let n = unsafe { libc::read(fd, buf.as_mut_ptr() as *mut libc::c_void, count) };
+            if n > 0 {
+                buf.truncate(n as usize);
+                return Ok(buf);
+            }
+            if n == 0 {
+                return Err(NSError::file_handle_closed());
+            }
+            match std::io::Error::last_os_error().raw_os_error() {
+                Some(libc::EINTR) => continue,
+                Some(libc::EAGAIN) => return Ok(Vec::new()),
+                Some(errno) => return Err(NSError::posix(errno)),
+                None => return Err(NSError::posix(libc::EIO)),
+            }
+        }
+    }
+
+    /// Up to 64 KiB of what is available, waiting for data on a blocking descriptor. Unlike
+    /// `-[NSFileHandle availableData]` it does not raise: end of file and errors return no bytes;
+    /// use [`read_up_to`](Self::read_up_to) to tell them apart.
+    pub fn available_data(&self) -> Vec<u8> {
+        self.read_up_to(64 * 1024).unwrap_or_default()
+    }
+
+    /// Closes the descriptor now rather than when the handle is released, e.g. to send the guest
+    /// end of file; the release then does not close it again. The descriptor is owned if the
+    /// handle was created with `close_on_dealloc`, and borrowed otherwise, as for the standard
+    /// streams: closing it then closes it for its owner too.
+    ///
+    /// Fails with [`CloseError::Attached`], leaving the descriptor open, while anything else
+    /// retains the handle, e.g. a serial port attachment it was given to.
+    pub fn close(self) -> Result<(), CloseError> {
+        if unsafe { is_shared(*self.0) } {
+            return Err(CloseError::Attached("NSFileHandle"));
+        }
+        self.close_unchecked()
+    }
+
+    /// Closes the descriptor even if others retain the handle.
+    pub(crate) fn close_unchecked(&self) -> Result<(), CloseError> {
+        unsafe {
+            call_with_error(|error| {
+                let ret: BOOL = msg_send![*self.0, closeAndReturnError: error];
+                from_objc_bool(ret)
+            })
+        }
+        .map(drop)
+        .map_err(CloseError::Framework)
+    }
+}
+
+impl Default for NSFileHandle {
+    fn default() -> Self {
+        NSFileHandle::new()
+    }
+}
+
+pub struct NSData(pub StrongPtr);
+
+impl NSData {
+    pub fn with_bytes(bytes: &[u8]) -> NSData {
+        unsafe {
+            let p = retained(msg_send![
+                class!(NSData),
+                dataWithBytes: bytes.as_ptr()
+                length: bytes.len()
+            ]);
+            debug_assert_non_nil!(p, "+[NSData dataWithBytes:length:]");
+            NSData(p)
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        unsafe { msg_send![*self.0, length] }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn to_vec(&self) -> Vec<u8> {
+        let len = self.len();
+        if len == 0 {
+            return Vec::new();
+        }
+        unsafe {
+            let bytes: *const u8 = msg_send![*self.0, bytes];
+            slice::from_raw_parts(bytes, len).to_vec()
+        }
+    }
 }
 
 pub struct NSDictionary(pub StrongPtr);
@@ -161,7 +1175,7 @@ impl NSDictionary {
     pub fn all_keys<T>(&self) -> NSArray<T> {
         unsafe {
             NSArray {
-                p: StrongPtr::retain(msg_send![*self.0, allKeys]),
+                p: retained(msg_send![*self.0, allKeys]),
                 _phantom: PhantomData,
             }
         }
@@ -170,55 +1184,186 @@ impl NSDictionary {
     pub fn all_values<T>(&self) -> NSArray<T> {
         unsafe {
             NSArray {
-                p: StrongPtr::retain(msg_send![*self.0, allValues]),
+                p: retained(msg_send![*self.0, allValues]),
                 _phantom: PhantomData,
             }
         }
     }
+
+    /// The value stored under `key`, unretained: it stays valid while the dictionary does.
+    pub fn object_for_key(&self, key: &NSString) -> Option<Id> {
+        let value: Id = unsafe { msg_send![*self.0, objectForKey:*key.0] };
+        if value == NIL {
+            None
+        } else {
+            Some(value)
+        }
+    }
+}
+
+pub const NS_POSIX_ERROR_DOMAIN: &str = "NSPOSIXErrorDomain";
+
+/// Domain of the errors this crate's [`NSFileHandle`] methods report themselves.
+pub const FILE_HANDLE_ERROR_DOMAIN: &str = "virtualization_rs.NSFileHandle";
+
+/// Code of the error [`NSFileHandle::read_up_to`] returns at end of file.
+pub const FILE_HANDLE_CLOSED: NSInteger = 1;
+
+/// Domain of [`NSError::unreported`].
+pub const UNREPORTED_ERROR_DOMAIN: &str = "virtualization_rs.Unreported";
+
+/// A wrapper held nil where its method needed an object, e.g. an [`NSError::nil`]. Debug builds
+/// panic instead.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NilObject(pub &'static str);
+
+impl fmt::Display for NilObject {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} is nil", self.0)
+    }
 }
 
+impl std::error::Error for NilObject {}
+
+#[derive(Clone)]
 pub struct NSError(pub StrongPtr);
 
+// Immutable, and retaining and releasing it is thread-safe, so completion handlers may carry it
+// to whichever queue they run on.
+unsafe impl Send for NSError {}
+unsafe impl Sync for NSError {}
+
 impl NSError {
+    /// A wrapper holding nil, from when failures were told apart by messaging the error; its
+    /// getters return defaults and panic in debug builds.
+    #[deprecated(note = "a call failed if it returned NO or nil; read its error only then")]
     pub fn nil() -> NSError {
         unsafe {
-            let p = StrongPtr::new(NIL);
+            let p = owned(NIL);
             NSError(p)
         }
     }
 
+    /// Creates an error, e.g. to exercise error handling without the framework.
+    pub fn error_with_domain(
+        domain: &str,
+        code: NSInteger,
+        user_info: Option<&NSDictionary>,
+    ) -> NSError {
+        let domain = NSString::new(domain);
+        let user_info = user_info.map_or(NIL, |d| *d.0);
+        unsafe {
+            NSError(retained(
+                msg_send![class!(NSError), errorWithDomain:*domain.0 code:code userInfo:user_info],
+            ))
+        }
+    }
+
+    /// The error a call with an `NSError **` out-parameter is given when it returned `NO` or nil
+    /// without storing one.
+    pub fn unreported() -> NSError {
+        NSError::error_with_domain(UNREPORTED_ERROR_DOMAIN, 0, None)
+    }
+
+    pub fn is_unreported(&self) -> bool {
+        self.domain().as_str() == UNREPORTED_ERROR_DOMAIN
+    }
+
+    fn object(&self) -> Result<Id, NilObject> {
+        non_nil(*self.0, "NSError")
+    }
+
+    /// The code, or [`NilObject`] for a nil wrapper.
+    pub fn try_code(&self) -> Result<isize, NilObject> {
+        self.object().map(|p| unsafe { msg_send![p, code] })
+    }
+
+    /// The code; 0 for a nil wrapper.
     pub fn code(&self) -> isize {
-        unsafe { msg_send![*self.0, code] }
+        self.try_code().unwrap_or(0)
     }
 
-    pub fn localized_description(&self) -> NSString {
-        unsafe { NSString(StrongPtr::retain(msg_send![*self.0, localizedDescription])) }
+    /// The domain; empty for a nil wrapper.
+    pub fn domain(&self) -> NSString {
+        match self.object() {
+            Ok(p) => unsafe { NSString(retained(msg_send![p, domain])) },
+            Err(_) => NSString::new(""),
+        }
     }
 
-    pub fn localized_failure_reason(&self) -> NSString {
-        unsafe {
-            NSString(StrongPtr::retain(msg_send![
-                *self.0,
-                localizedFailureReason
-            ]))
+    /// The description; empty for a nil wrapper.
+    pub fn localized_description(&self) -> NSString {
+        match self.object() {
+            Ok(p) => unsafe { NSString(retained(msg_send![p, localizedDescription])) },
+            Err(_) => NSString::new(""),
         }
     }
 
-    pub fn localized_recovery_suggestion(&self) -> NSString {
-        unsafe {
-            NSString(StrongPtr::retain(msg_send![
-                *self.0,
-                localizedRecoverySuggestion
-            ]))
+    /// A getter that returns nil where the error has no such string.
+    fn optional_string(&self, getter: Sel) -> Option<NSString> {
+        let p = self.object().ok()?;
+        let string: Id = unsafe { msg_send![p, performSelector: getter] };
+        if string.is_null() {
+            None
+        } else {
+            Some(NSString(unsafe { retained(string) }))
         }
     }
 
-    pub fn help_anchor(&self) -> NSString {
-        unsafe { NSString(StrongPtr::retain(msg_send![*self.0, helpAnchor])) }
+    pub fn localized_failure_reason(&self) -> Option<NSString> {
+        self.optional_string(sel!(localizedFailureReason))
     }
 
+    pub fn localized_recovery_suggestion(&self) -> Option<NSString> {
+        self.optional_string(sel!(localizedRecoverySuggestion))
+    }
+
+    pub fn help_anchor(&self) -> Option<NSString> {
+        self.optional_string(sel!(helpAnchor))
+    }
+
+    /// The user info; empty for a nil wrapper.
     pub fn user_info(&self) -> NSDictionary {
-        unsafe { NSDictionary(StrongPtr::retain(msg_send![*self.0, userInfo])) }
+        match self.object() {
+            Ok(p) => unsafe { NSDictionary(retained(msg_send![p, userInfo])) },
+            Err(_) => unsafe { NSDictionary(owned(msg_send![class!(NSDictionary), new])) },
+        }
+    }
+
+    /// The `userInfo` value stored under `key`, unretained: it stays valid while the error does.
+    pub fn user_info_value(&self, key: &str) -> Option<Id> {
+        self.user_info().object_for_key(&NSString::new(key))
+    }
+
+    /// The error that caused this one (`NSUnderlyingErrorKey`).
+    pub fn underlying_error(&self) -> Option<NSError> {
+        // The key is an unretained global; `retained` keeps the wrapper's release balanced.
+        let key = unsafe { NSString(retained(NSUnderlyingErrorKey)) };
+        self.user_info()
+            .object_for_key(&key)
+            .map(|error| unsafe { NSError(retained(error)) })
+    }
+
+    /// An `NSPOSIXErrorDomain` error with `errno` as its code.
+    pub fn posix(errno: libc::c_int) -> NSError {
+        NSError::error_with_domain(NS_POSIX_ERROR_DOMAIN, errno as NSInteger, None)
+    }
+
+    fn file_handle_closed() -> NSError {
+        NSError::error_with_domain(FILE_HANDLE_ERROR_DOMAIN, FILE_HANDLE_CLOSED, None)
+    }
+
+    /// Whether [`NSFileHandle::read_up_to`] failed because it reached end of file.
+    pub fn is_file_handle_closed(&self) -> bool {
+        self.code() == FILE_HANDLE_CLOSED as isize
+            && self.domain().as_str() == FILE_HANDLE_ERROR_DOMAIN
+    }
+
+    /// Whether the error belongs to the framework's `VZErrorDomain`.
+    pub fn is_vz_error(&self) -> bool {
+        let domain = vz_error_domain();
+        let b: BOOL = unsafe { msg_send![*self.domain().0, isEqualToString:*domain.0] };
+        from_objc_bool(b)
     }
 
     pub fn dump(&self) {
@@ -226,27 +1371,50 @@ impl NSError {
         println!("code: {}", code);
         let localized_description = self.localized_description();
         println!("localizedDescription : {}", localized_description.as_str());
-        let localized_failure_reason = self.localized_failure_reason();
-        println!(
-            "localizedFailureReason : {}",
-            localized_failure_reason.as_str()
-        );
-        let localized_recovery_suggestion = self.localized_recovery_suggestion();
-        println!(
-            "localizedRecoverySuggestion : {}",
-            localized_recovery_suggestion.as_str()
-        );
-        let help_anchor = self.help_anchor();
-        println!("helpAnchor : {}", help_anchor.as_str());
+        if let Some(reason) = self.localized_failure_reason() {
+            println!("localizedFailureReason : {}", reason.as_str());
+        }
+        if let Some(suggestion) = self.localized_recovery_suggestion() {
+            println!("localizedRecoverySuggestion : {}", suggestion.as_str());
+        }
+        if let Some(help_anchor) = self.help_anchor() {
+            println!("helpAnchor : {}", help_anchor.as_str());
+        }
         let user_info = self.user_info();
         println!("userInfo :");
         let keys: NSArray<NSString> = user_info.all_keys();
-        let values: NSArray<NSString> = user_info.all_values();
-        let cnt = keys.count();
-        for i in 0..cnt {
-            let k = keys.object_at_index(i);
-            let o = values.object_at_index(i);
-            println!("    key: {}, value: {}", k.as_str(), o.as_str());
+        for key in keys.to_strings() {
+            if let Some(o) = user_info.object_for_key(&NSString::new(&key)) {
+                // Values are not necessarily strings (e.g. an underlying NSError).
+                let o = unsafe { NSString(retained(msg_send![o, description])) };
+                println!("    key: {}, value: {}", key, o.as_str());
+            }
         }
     }
 }
+
+/// `<domain> code <code> — <localized description>`, e.g.
+/// `VZErrorDomain code 2 — Invalid virtual machine configuration.`
+impl fmt::Display for NSError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} code {} — {}",
+            self.domain().as_str(),
+            self.code(),
+            self.localized_description().as_str()
+        )
+    }
+}
+
+impl fmt::Debug for NSError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("NSError")
+            .field("domain", &self.domain().as_str())
+            .field("code", &self.code())
+            .field("description", &self.localized_description().as_str())
+            .finish()
+    }
+}
+
+impl std::error::Error for NSError {}
@@ -0,0 +1,389 @@
+//! C ABI module
+//!
+//! A small C interface for tooling in other languages, e.g. a Go control plane through cgo:
+//! build a configuration from a [guest profile](crate::profile), create a virtual machine from
+//! it, start it, stop it, read its state, and free what was handed out. The declarations are in
+//! `include/virtualization_rs.h`; `make capi` builds the library as a `cdylib` and `staticlib`.
+//!
+//! Only calls whose Rust API is settled are exposed, and they stay as they are within an ABI
+//! version, [`VRS_ABI_VERSION`]; new calls may be added in the same version.
+//!
+//! # Threading
+//!
+//! Every function may be called from any thread. Completion callbacks run one at a time on a
+//! serial queue owned by the virtual machine, never on the caller's thread and never on the
+//! queue the framework uses for the machine, so a callback may call back into this interface,
+//! e.g. to read the state. It must not free the machine it was called for: freeing waits for
+//! the callbacks in flight, its own included, and gives up after
+//! [`DROP_TIMEOUT`](crate::virtualization::virtual_machine::DROP_TIMEOUT).
+//!
+//! # Strings and errors
+//!
+//! Strings passed in are NUL-terminated UTF-8. Strings handed out through an `err` out-parameter
+//! are UTF-8, belong to the caller and are freed with [`vrs_string_free`]; the one passed to a
+//! callback is only valid until the callback returns. `err` may be NULL when the message is not
+//! wanted.
+//!
+//! A panic is caught at the boundary: the call returns NULL or [`VrsStatus::Panicked`] with the
+//! panic message in `err`. The machine it happened on should be freed.
+//!
+//! # Examples
+//! ```c
+//! vrs_profile_inputs inputs = {0};
+//! inputs.kernel = "vmlinuz";
+//! inputs.initrd = "initrd";
+//! char *err = NULL;
+//! vrs_config *config = vrs_config_new_from_profile("linux-direct-kernel", &inputs, 2, 2048, &err);
+//! if (config == NULL) {
+//!     fprintf(stderr, "%s\n", err);
+//!     vrs_string_free(err);
+//!     return 1;
+//! }
+//! vrs_vm *vm = vrs_vm_create(config, &err);
+//! vrs_config_free(config);
+//! vrs_vm_start(vm, on_started, NULL);
+//! ```
+
+use crate::base::DispatchQueue;
+use crate::features;
+use crate::profile::{self, ProfileInputs, ProfileKind};
+use crate::virtualization::error::CompletionOutcome;
+use crate::virtualization::virtual_machine::{VZVirtualMachine, VZVirtualMachineConfiguration};
+
+use std::any::Any;
+use std::ffi::{CStr, CString};
+use std::os::raw::{c_char, c_void};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::PathBuf;
+use std::ptr;
+
+/// Bumped when a call or type of this module changes incompatibly.
+pub const VRS_ABI_VERSION: u32 = 1;
+
+/// Label of the queues of machines created through this interface.
+const QUEUE_LABEL: &str = "virtualization-rs.capi";
+
+/// Result of a call, and outcome passed to a callback.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VrsStatus {
+    Ok = 0,
+    /// A pointer argument was NULL or a string was not UTF-8.
+    InvalidArgument = 1,
+    /// The machine is already starting, running or stopping, as the call needs it not to be.
+    WrongState = 2,
+    /// The framework failed the operation; the callback's message says why.
+    Failed = 3,
+    /// The operation was cancelled.
+    Cancelled = 4,
+    /// The call panicked.
+    Panicked = 5,
+}
+
+/// Paths and kernel command line of a profile, as in [`ProfileInputs`]; NULL for those not
+/// given.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct VrsProfileInputs {
+    pub kernel: *const c_char,
+    pub initrd: *const c_char,
+    pub command_line: *const c_char,
+    pub efi_variable_store: *const c_char,
+    pub disk: *const c_char,
+    pub cloud_init_seed: *const c_char,
+}
+
+/// Called once an operation completed, with `ctx` as given, the outcome, and a message unless it
+/// is [`VrsStatus::Ok`].
+pub type VrsCallback =
+    Option<unsafe extern "C" fn(ctx: *mut c_void, status: VrsStatus, error: *const c_char)>;
+
+/// A validated configuration.
+pub struct VrsConfig(VZVirtualMachineConfiguration);
+
+/// A virtual machine, with the queue its callbacks run on.
+pub struct VrsVm {
+    vm: VZVirtualMachine,
+    _callbacks: DispatchQueue,
+}
+
+/// Runs `f`, turning a panic into `Err` with its message.
+fn catch<T, F: FnOnce() -> Result<T, (VrsStatus, String)>>(f: F) -> Result<T, (VrsStatus, String)> {
+    match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(result) => result,
+        Err(payload) => Err((VrsStatus::Panicked, panic_message(&*payload))),
+    }
+}
+
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+    let message = payload
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("unknown panic");
+    format!("panic: {}", message)
+}
+
+/// `message` as a C string, with NUL bytes replaced.
+fn c_string(message: &str) -> CString {
+    CString::new(message.replace('\0', "\u{fffd}")).unwrap()
+}
+
+/// Hands `message` to the caller through `err`, unless it is NULL.
+///
+/// # Safety
+/// `err` must be NULL or valid for writes.
+unsafe fn set_error(err: *mut *mut c_char, message: &str) {
+    if !err.is_null() {
+        *err = c_string(message).into_raw();
+    }
+}
+
+fn invalid(message: String) -> (VrsStatus, String) {
+    (VrsStatus::InvalidArgument, message)
+}
+
+/// # Safety
+/// `p` must be NULL or a NUL-terminated string that outlives `'a`.
+unsafe fn optional_str<'a>(
+    p: *const c_char,
+    name: &str,
+) -> Result<Option<&'a str>, (VrsStatus, String)> {
+    if p.is_null() {
+        return Ok(None);
+    }
+    CStr::from_ptr(p)
+        .to_str()
+        .map(Some)
+        .map_err(|_| invalid(format!("{} is not UTF-8", name)))
+}
+
+/// # Safety
+/// As for [`optional_str`].
+unsafe fn required_str<'a>(p: *const c_char, name: &str) -> Result<&'a str, (VrsStatus, String)> {
+    optional_str(p, name)?.ok_or_else(|| invalid(format!("{} is NULL", name)))
+}
+
+/// # Safety
+/// As for [`optional_str`].
+unsafe fn optional_path(
+    p: *const c_char,
+    name: &str,
+) -> Result<Option<PathBuf>, (VrsStatus, String)> {
+    Ok(optional_str(p, name)?.map(PathBuf::from))
+}
+
+/// [`VRS_ABI_VERSION`] of the library, to check against the header's `VRS_ABI_VERSION`.
+#[no_mangle]
+pub extern "C" fn vrs_abi_version() -> u32 {
+    VRS_ABI_VERSION
+}
+
+/// A validated configuration of the profile named `profile`, e.g. `linux-direct-kernel`, with
+/// `inputs`, `cpu_count` CPUs and `memory_mib` MiB of memory, as [`profile::configure`] composes
+/// it. NULL with the reason in `err` if the framework is missing, an input is refused or the
+/// configuration does not validate. Free it with [`vrs_config_free`].
+///
+/// # Safety
+/// `profile` and the strings of `inputs` must be NULL or NUL-terminated, `inputs` NULL or valid,
+/// and `err` NULL or valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn vrs_config_new_from_profile(
+    profile: *const c_char,
+    inputs: *const VrsProfileInputs,
+    cpu_count: u32,
+    memory_mib: u64,
+    err: *mut *mut c_char,
+) -> *mut VrsConfig {
+    let result = catch(|| {
+        let kind: ProfileKind = required_str(profile, "profile")?
+            .parse()
+            .map_err(|e: profile::ParseProfileError| invalid(e.to_string()))?;
+        if inputs.is_null() {
+            return Err(invalid("inputs is NULL".to_string()));
+        }
+        let inputs = &*inputs;
+        let inputs = ProfileInputs {
+            kernel: optional_path(inputs.kernel, "kernel")?,
+            initrd: optional_path(inputs.initrd, "initrd")?,
+            command_line: optional_str(inputs.command_line, "command_line")?.map(String::from),
+            efi_variable_store: optional_path(inputs.efi_variable_store, "efi_variable_store")?,
+            disk: optional_path(inputs.disk, "disk")?,
+            cloud_init_seed: optional_path(inputs.cloud_init_seed, "cloud_init_seed")?,
+        };
+        features::require_framework().map_err(|e| (VrsStatus::Failed, e.to_string()))?;
+        let builder = profile::configure(kind.profile().as_ref(), &inputs).map_err(|errors| {
+            let messages: Vec<String> = errors.iter().map(ToString::to_string).collect();
+            (VrsStatus::Failed, messages.join("; "))
+        })?;
+        let conf = builder
+            .cpu_count(cpu_count as usize)
+            .memory_size_mib(memory_mib as usize)
+            .build();
+        conf.validate_with_error()
+            .map_err(|e| (VrsStatus::Failed, e.to_string()))?;
+        Ok(Box::into_raw(Box::new(VrsConfig(conf))))
+    });
+    result.unwrap_or_else(|(_, message)| {
+        set_error(err, &message);
+        ptr::null_mut()
+    })
+}
+
+/// Frees a configuration; machines created from it are not affected. NULL is ignored.
+///
+/// # Safety
+/// `config` must be NULL or returned by [`vrs_config_new_from_profile`] and not freed yet.
+#[no_mangle]
+pub unsafe extern "C" fn vrs_config_free(config: *mut VrsConfig) {
+    if !config.is_null() {
+        drop(Box::from_raw(config));
+    }
+}
+
+/// A new, stopped virtual machine of `config`, which may create more. NULL with the reason in
+/// `err` if `config` is NULL or the call panicked. Free it with [`vrs_vm_free`].
+///
+/// # Safety
+/// `config` must be NULL or a live configuration, and `err` NULL or valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn vrs_vm_create(
+    config: *const VrsConfig,
+    err: *mut *mut c_char,
+) -> *mut VrsVm {
+    let result = catch(|| {
+        if config.is_null() {
+            return Err(invalid("config is NULL".to_string()));
+        }
+        let vm = VZVirtualMachine::new_with_qos((*config).0.clone(), QUEUE_LABEL, None);
+        let callbacks = DispatchQueue::new(QUEUE_LABEL);
+        Ok(Box::into_raw(Box::new(VrsVm {
+            vm: vm.on_queue(&callbacks),
+            _callbacks: callbacks,
+        })))
+    });
+    result.unwrap_or_else(|(_, message)| {
+        set_error(err, &message);
+        ptr::null_mut()
+    })
+}
+
+/// The `ctx` of a callback, which the caller promises is usable from the callback queue.
+struct CallbackContext(*mut c_void);
+
+unsafe impl Send for CallbackContext {}
+
+/// Calls `callback` with the outcome of `outcome`.
+fn complete(callback: VrsCallback, ctx: CallbackContext, outcome: CompletionOutcome) {
+    let callback = match callback {
+        Some(callback) => callback,
+        None => return,
+    };
+    let (status, message) = match outcome {
+        CompletionOutcome::Success(()) => (VrsStatus::Ok, None),
+        CompletionOutcome::Cancelled => (VrsStatus::Cancelled, Some(c_string("cancelled"))),
+        CompletionOutcome::Failed(e) => (VrsStatus::Failed, Some(c_string(&e.0.to_string()))),
+    };
+    let message = message.as_ref().map_or(ptr::null(), |m| m.as_ptr());
+    unsafe { callback(ctx.0, status, message) }
+}
+
+/// Runs `op` on the machine, which calls `callback` once it completed, unless it returns an
+/// error.
+///
+/// # Safety
+/// `vm` must be NULL or a live machine.
+unsafe fn send<F>(vm: *const VrsVm, callback: VrsCallback, ctx: *mut c_void, op: F) -> VrsStatus
+where
+    F: FnOnce(
+        &VZVirtualMachine,
+        Box<dyn FnOnce(CompletionOutcome) + Send>,
+    ) -> Result<(), VrsStatus>,
+{
+    if vm.is_null() {
+        return VrsStatus::InvalidArgument;
+    }
+    let result = catch(|| {
+        let ctx = CallbackContext(ctx);
+        let handler = Box::new(move |outcome| complete(callback, ctx, outcome));
+        op(&(*vm).vm, handler).map_err(|status| (status, String::new()))
+    });
+    match result {
+        Ok(()) => VrsStatus::Ok,
+        Err((status, _)) => status,
+    }
+}
+
+/// Starts the machine; `callback` runs once the framework accepted or refused the start. Returns
+/// [`VrsStatus::WrongState`] without calling `callback` if the machine is starting, running or
+/// stopping.
+///
+/// # Safety
+/// `vm` must be NULL or a live machine, and `ctx` usable from the callback queue.
+#[no_mangle]
+pub unsafe extern "C" fn vrs_vm_start(
+    vm: *mut VrsVm,
+    callback: VrsCallback,
+    ctx: *mut c_void,
+) -> VrsStatus {
+    send(vm, callback, ctx, |vm, handler| {
+        vm.start(handler).map_err(|_| VrsStatus::WrongState)
+    })
+}
+
+/// Stops the machine without giving the guest a chance to shut down (macOS 12+); `callback` runs
+/// once it stopped or failed to. Returns [`VrsStatus::WrongState`] without calling `callback` if
+/// a stop is in flight.
+///
+/// # Safety
+/// As for [`vrs_vm_start`].
+#[no_mangle]
+pub unsafe extern "C" fn vrs_vm_stop(
+    vm: *mut VrsVm,
+    callback: VrsCallback,
+    ctx: *mut c_void,
+) -> VrsStatus {
+    send(vm, callback, ctx, |vm, handler| {
+        vm.stop(handler).map_err(|_| VrsStatus::WrongState)
+    })
+}
+
+/// The framework's `VZVirtualMachineState` of the machine, e.g. 0 when stopped and 1 when
+/// running; -1 if `vm` is NULL or the call panicked.
+///
+/// # Safety
+/// `vm` must be NULL or a live machine.
+#[no_mangle]
+pub unsafe extern "C" fn vrs_vm_state(vm: *const VrsVm) -> i64 {
+    if vm.is_null() {
+        return -1;
+    }
+    let vm = &(*vm).vm;
+    catch(|| Ok(vm.queue().exec_sync(|| vm.state().raw()))).unwrap_or(-1)
+}
+
+/// Frees the machine, waiting for callbacks in flight, so not from one of its callbacks. It
+/// does not stop a running machine first. NULL is ignored.
+///
+/// # Safety
+/// `vm` must be NULL or returned by [`vrs_vm_create`] and not freed yet.
+#[no_mangle]
+pub unsafe extern "C" fn vrs_vm_free(vm: *mut VrsVm) {
+    if !vm.is_null() {
+        let _ = catch(|| {
+            drop(Box::from_raw(vm));
+            Ok(())
+        });
+    }
+}
+
+/// Frees a string this interface handed out. NULL is ignored.
+///
+/// # Safety
+/// `s` must be NULL or a string from an `err` out-parameter, not freed yet.
+#[no_mangle]
+pub unsafe extern "C" fn vrs_string_free(s: *mut c_char) {
+    if !s.is_null() {
+        drop(CString::from_raw(s));
+    }
+}
@@ -0,0 +1,347 @@
+//! cloud-init module
+//!
+//! Generates a NoCloud seed image: a small ISO9660 volume labelled `cidata` holding `user-data`,
+//! `meta-data` and optionally `network-config`, attached to the guest as a read-only disk.
+//!
+//! The primary volume descriptor names the files with d-characters only, as `USER_DATA.;1`. A
+//! Joliet supplementary volume descriptor shares their extents under the real names, which Linux
+//! and macOS use when present.
+//!
+//! # Examples
+//! ```rust
+//! let seed = CloudInitSeed::with_ssh_key("vm1", "ubuntu", "ssh-ed25519 AAAA... me@host");
+//! let (seed_device, seed_path) = match seed.block_device_at_temp_path() {
+//!     Ok(x) => x,
+//!     Err(CloudInitError::Attachment(err)) => {
+//!         eprintln!("{}", err);
+//!         return;
+//!     }
+//!     Err(CloudInitError::Io(err)) => panic!("{}", err),
+//! };
+//! ```
+
+use crate::virtualization::storage_device::{
+    VZDiskImageAttachmentError, VZDiskImageStorageDeviceAttachmentBuilder,
+    VZVirtioBlockDeviceConfiguration,
+};
+
+use std::env;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+const SECTOR_SIZE: usize = 2048;
+const VOLUME_DESCRIPTOR_SECTOR: usize = 16;
+const JOLIET_DESCRIPTOR_SECTOR: usize = 17;
+const TERMINATOR_SECTOR: usize = 18;
+const FIRST_FILE_SECTOR: usize = 25;
+const PATH_TABLE_SIZE: usize = 10;
+/// The escape sequence of Joliet UCS-2 level 3.
+const JOLIET_ESCAPE: &[u8] = b"%/E";
+
+/// Where the path tables and root directory of one volume descriptor are.
+struct Hierarchy {
+    l_path_table: usize,
+    m_path_table: usize,
+    root_directory: usize,
+}
+
+const PRIMARY: Hierarchy = Hierarchy {
+    l_path_table: 19,
+    m_path_table: 20,
+    root_directory: 23,
+};
+
+const JOLIET: Hierarchy = Hierarchy {
+    l_path_table: 21,
+    m_path_table: 22,
+    root_directory: 24,
+};
+
+/// Error returned when writing or attaching a seed image.
+pub enum CloudInitError {
+    Io(io::Error),
+    Attachment(VZDiskImageAttachmentError),
+}
+
+impl From<io::Error> for CloudInitError {
+    fn from(e: io::Error) -> Self {
+        CloudInitError::Io(e)
+    }
+}
+
+impl fmt::Display for CloudInitError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CloudInitError::Io(error) => write!(f, "cannot write the seed image: {}", error),
+            CloudInitError::Attachment(error) => error.fmt(f),
+        }
+    }
+}
+
+impl fmt::Debug for CloudInitError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CloudInitError::Io(error) => f.debug_tuple("Io").field(error).finish(),
+            CloudInitError::Attachment(error) => f.debug_tuple("Attachment").field(error).finish(),
+        }
+    }
+}
+
+impl std::error::Error for CloudInitError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            CloudInitError::Io(error) => Some(error),
+            // Displayed as the attachment error itself, so its cause comes next.
+            CloudInitError::Attachment(error) => error.source(),
+        }
+    }
+}
+
+/// Contents of a NoCloud seed.
+pub struct CloudInitSeed {
+    user_data: String,
+    meta_data: String,
+    network_config: Option<String>,
+}
+
+impl CloudInitSeed {
+    pub fn new<U: Into<String>, M: Into<String>>(user_data: U, meta_data: M) -> Self {
+        CloudInitSeed {
+            user_data: user_data.into(),
+            meta_data: meta_data.into(),
+            network_config: None,
+        }
+    }
+
+    /// A seed that sets the hostname and creates `username` with passwordless sudo, logging in
+    /// with `pubkey`.
+    pub fn with_ssh_key(hostname: &str, username: &str, pubkey: &str) -> Self {
+        let meta_data = format!(
+            "instance-id: {}\nlocal-hostname: {}\n",
+            hostname, hostname
+        );
+        let user_data = format!(
+            "#cloud-config\n\
+             users:\n  \
+               - name: {}\n    \
+                 ssh_authorized_keys:\n      \
+                   - {}\n    \
+                 sudo: ALL=(ALL) NOPASSWD:ALL\n    \
+                 shell: /bin/bash\n",
+            username, pubkey
+        );
+        CloudInitSeed::new(user_data, meta_data)
+    }
+
+    pub fn network_config<T: Into<String>>(mut self, network_config: T) -> Self {
+        self.network_config = Some(network_config.into());
+        self
+    }
+
+    /// Renders the seed as an ISO9660 image.
+    pub fn to_iso(&self) -> Vec<u8> {
+        // Directory records must be sorted by identifier, which both sets of names agree on.
+        let mut files: Vec<(&str, &str, &[u8])> = vec![
+            ("META_DATA.;1", "meta-data", self.meta_data.as_bytes()),
+            ("USER_DATA.;1", "user-data", self.user_data.as_bytes()),
+        ];
+        if let Some(network_config) = &self.network_config {
+            let network_config = network_config.as_bytes();
+            files.insert(1, ("NETWORK_CONFIG.;1", "network-config", network_config));
+        }
+
+        let mut extents = Vec::with_capacity(files.len());
+        let mut next_sector = FIRST_FILE_SECTOR;
+        for (_, _, data) in &files {
+            extents.push(next_sector);
+            next_sector += sectors(data.len()).max(1);
+        }
+        let total_sectors = next_sector;
+        let mut image = vec![0u8; total_sectors * SECTOR_SIZE];
+
+        write_volume_descriptor(
+            sector_mut(&mut image, VOLUME_DESCRIPTOR_SECTOR),
+            total_sectors,
+            &PRIMARY,
+            None,
+        );
+        write_volume_descriptor(
+            sector_mut(&mut image, JOLIET_DESCRIPTOR_SECTOR),
+            total_sectors,
+            &JOLIET,
+            Some(JOLIET_ESCAPE),
+        );
+        write_terminator(sector_mut(&mut image, TERMINATOR_SECTOR));
+
+        for (hierarchy, joliet) in [(&PRIMARY, false), (&JOLIET, true)] {
+            write_path_tables(&mut image, hierarchy);
+            let root = hierarchy.root_directory;
+            let mut records = directory_record(root, SECTOR_SIZE, true, &[0]);
+            records.extend(directory_record(root, SECTOR_SIZE, true, &[1]));
+            for ((primary_name, joliet_name, data), extent) in files.iter().zip(&extents) {
+                let name = if joliet {
+                    ucs2(joliet_name)
+                } else {
+                    primary_name.as_bytes().to_vec()
+                };
+                records.extend(directory_record(*extent, data.len(), false, &name));
+            }
+            sector_mut(&mut image, root)[..records.len()].copy_from_slice(&records);
+        }
+
+        for ((_, _, data), extent) in files.iter().zip(&extents) {
+            let start = extent * SECTOR_SIZE;
+            image[start..start + data.len()].copy_from_slice(data);
+        }
+        image
+    }
+
+    /// Writes the image to `path`.
+    pub fn write_to<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        fs::write(path, self.to_iso())
+    }
+
+    /// Writes the image to `path` and returns a read-only block device for it.
+    pub fn block_device<P: AsRef<Path>>(
+        &self,
+        path: P,
+    ) -> Result<(VZVirtioBlockDeviceConfiguration, PathBuf), CloudInitError> {
+        let path = path.as_ref();
+        self.write_to(path)?;
+        let path = fs::canonicalize(path)?;
+        let path_str = path.to_str().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "seed path is not valid UTF-8")
+        })?;
+        let attachment = VZDiskImageStorageDeviceAttachmentBuilder::new()
+            .path(path_str)
+            .read_only(true)
+            .build()
+            .map_err(CloudInitError::Attachment)?;
+        Ok((VZVirtioBlockDeviceConfiguration::new(attachment), path))
+    }
+
+    /// Like [`CloudInitSeed::block_device`] with a fresh file in the temporary directory. The
+    /// caller removes the returned path when the virtual machine is done with it.
+    pub fn block_device_at_temp_path(
+        &self,
+    ) -> Result<(VZVirtioBlockDeviceConfiguration, PathBuf), CloudInitError> {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        let name = format!(
+            "cidata-{}-{}.iso",
+            process::id(),
+            COUNTER.fetch_add(1, Ordering::SeqCst)
+        );
+        self.block_device(env::temp_dir().join(name))
+    }
+}
+
+fn sectors(len: usize) -> usize {
+    len.div_ceil(SECTOR_SIZE)
+}
+
+fn sector_mut(image: &mut [u8], sector: usize) -> &mut [u8] {
+    &mut image[sector * SECTOR_SIZE..(sector + 1) * SECTOR_SIZE]
+}
+
+fn both_endian_u16(buf: &mut [u8], value: u16) {
+    buf[0..2].copy_from_slice(&value.to_le_bytes());
+    buf[2..4].copy_from_slice(&value.to_be_bytes());
+}
+
+fn both_endian_u32(buf: &mut [u8], value: u32) {
+    buf[0..4].copy_from_slice(&value.to_le_bytes());
+    buf[4..8].copy_from_slice(&value.to_be_bytes());
+}
+
+fn padded(buf: &mut [u8], text: &str) {
+    for (i, b) in buf.iter_mut().enumerate() {
+        *b = *text.as_bytes().get(i).unwrap_or(&b' ');
+    }
+}
+
+/// `text` in big-endian UCS-2, as Joliet names and fields are.
+fn ucs2(text: &str) -> Vec<u8> {
+    text.encode_utf16().flat_map(u16::to_be_bytes).collect()
+}
+
+/// [`padded`] in UCS-2 for Joliet; a field of odd length ends in a zero byte.
+fn padded_ucs2(buf: &mut [u8], text: &str) {
+    let text = ucs2(text);
+    for (i, pair) in buf.chunks_exact_mut(2).enumerate() {
+        let unit = text.get(2 * i..2 * i + 2).unwrap_or(&[0, b' ']);
+        pair.copy_from_slice(unit);
+    }
+}
+
+/// The path tables of a hierarchy with only a root directory.
+fn write_path_tables(image: &mut [u8], hierarchy: &Hierarchy) {
+    let root = hierarchy.root_directory as u32;
+    let l_table = sector_mut(image, hierarchy.l_path_table);
+    l_table[0] = 1;
+    l_table[2..6].copy_from_slice(&root.to_le_bytes());
+    l_table[6..8].copy_from_slice(&1u16.to_le_bytes());
+    let m_table = sector_mut(image, hierarchy.m_path_table);
+    m_table[0] = 1;
+    m_table[2..6].copy_from_slice(&root.to_be_bytes());
+    m_table[6..8].copy_from_slice(&1u16.to_be_bytes());
+}
+
+/// ISO9660 directory record. Dates are left unspecified so the image is reproducible.
+fn directory_record(extent: usize, len: usize, directory: bool, name: &[u8]) -> Vec<u8> {
+    let record_len = 33 + name.len() + (name.len() + 1) % 2;
+    let mut record = vec![0u8; record_len];
+    record[0] = record_len as u8;
+    both_endian_u32(&mut record[2..10], extent as u32);
+    both_endian_u32(&mut record[10..18], len as u32);
+    record[25] = if directory { 2 } else { 0 };
+    both_endian_u16(&mut record[28..32], 1);
+    record[32] = name.len() as u8;
+    record[33..33 + name.len()].copy_from_slice(name);
+    record
+}
+
+/// The primary volume descriptor, or with `escape` the Joliet supplementary one.
+fn write_volume_descriptor(
+    sector: &mut [u8],
+    total_sectors: usize,
+    hierarchy: &Hierarchy,
+    escape: Option<&[u8]>,
+) {
+    let text = match escape {
+        Some(_) => padded_ucs2,
+        None => padded,
+    };
+    sector[0] = if escape.is_some() { 2 } else { 1 };
+    sector[1..6].copy_from_slice(b"CD001");
+    sector[6] = 1;
+    text(&mut sector[8..40], "");
+    text(&mut sector[40..72], "cidata");
+    both_endian_u32(&mut sector[80..88], total_sectors as u32);
+    if let Some(escape) = escape {
+        sector[88..88 + escape.len()].copy_from_slice(escape);
+    }
+    both_endian_u16(&mut sector[120..124], 1);
+    both_endian_u16(&mut sector[124..128], 1);
+    both_endian_u16(&mut sector[128..132], SECTOR_SIZE as u16);
+    both_endian_u32(&mut sector[132..140], PATH_TABLE_SIZE as u32);
+    sector[140..144].copy_from_slice(&(hierarchy.l_path_table as u32).to_le_bytes());
+    sector[148..152].copy_from_slice(&(hierarchy.m_path_table as u32).to_be_bytes());
+    let root = directory_record(hierarchy.root_directory, SECTOR_SIZE, true, &[0]);
+    sector[156..156 + root.len()].copy_from_slice(&root);
+    text(&mut sector[190..813], "");
+    for date in sector[813..881].chunks_mut(17) {
+        date[..16].copy_from_slice(b"0000000000000000");
+        date[16] = 0;
+    }
+    sector[881] = 1;
+}
+
+fn write_terminator(sector: &mut [u8]) {
+    sector[0] = 255;
+    sector[1..6].copy_from_slice(b"CD001");
+    sector[6] = 1;
+}
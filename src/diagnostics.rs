@@ -0,0 +1,824 @@
+//! diagnostics module
+//!
+//! What to attach to an issue when a virtual machine fails to start with an internal error. The
+//! framework writes the actual cause to the unified log, under the `com.apple.Virtualization`
+//! subsystem, where few users think to look. [`collect`] reads the last `window` of that
+//! subsystem with `log show`, the documented command line tool, and combines it with what the
+//! crate knows: the configuration, the host's capabilities, the macOS version and the
+//! entitlements of the running executable. [`DiagnosticsReport::write_bundle`] writes it all to
+//! a directory, ready to attach.
+//!
+//! Log entries are kept if this process logged them or one of the framework's service processes,
+//! whose names start with `com.apple.Virtualization`. The services log under their own pid, so
+//! their entries can include other processes' machines.
+//!
+//! `log show` takes a few seconds. A failed start's completion runs on the VM's queue, which is
+//! blocked that long; nothing else is waiting for it then.
+//!
+//! # Examples
+//! ```rust
+//! let failed = vm.clone();
+//! vm.start_with_deadline(Duration::from_secs(30), move |outcome| {
+//!     match outcome.on_failure_collect_diagnostics(&failed, "diagnostics") {
+//!         Ok(Some(_)) => eprintln!("start failed, attach ./diagnostics to the issue"),
+//!         Ok(None) => {}
+//!         Err(e) => eprintln!("failed to write diagnostics: {}", e),
+//!     }
+//! })?;
+//! ```
+
+use crate::features::{self, HostCapabilities};
+use crate::timeline::json_string;
+use crate::virtualization::virtual_machine::{VZVirtualMachine, VZVirtualMachineConfiguration};
+
+use std::fmt;
+use std::fmt::Write as _;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::process::{self, Command};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// The unified log subsystem of Virtualization.framework.
+pub const SUBSYSTEM: &str = "com.apple.Virtualization";
+
+/// Entitlements [`collect`] checks the running executable for: the one every virtual machine
+/// needs, and the one bridged networking needs.
+pub const ENTITLEMENTS: &[&str] = &[
+    "com.apple.security.virtualization",
+    "com.apple.vm.networking",
+];
+
+/// How much of the log [`StartOutcome::on_failure_collect_diagnostics`] reads.
+///
+/// [`StartOutcome::on_failure_collect_diagnostics`]: crate::virtualization::virtual_machine::StartOutcome::on_failure_collect_diagnostics
+pub const FAILURE_WINDOW: Duration = Duration::from_secs(5 * 60);
+
+/// Deepest nesting of JSON arrays and objects the parser follows; `log show` needs 3.
+const MAX_JSON_DEPTH: usize = 32;
+
+/// How bad a finding of a preflight check is, e.g. a
+/// [`ConsoleFinding`](crate::virtualization::console_preflight::ConsoleFinding) or a
+/// [`NatFinding`](crate::nat::NatFinding). Ordered from the least to the most severe.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    /// Something the check could not tell, or worth knowing when something goes wrong.
+    Note,
+    /// A setup that works badly or not at all in some cases.
+    Warning,
+    /// A setup that cannot work.
+    Error,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Severity::Note => "note",
+            Severity::Warning => "warning",
+            Severity::Error => "error",
+        })
+    }
+}
+
+/// What a report is about.
+pub enum Subject<'a> {
+    Machine(&'a VZVirtualMachine),
+    Configuration(&'a VZVirtualMachineConfiguration),
+}
+
+impl<'a> From<&'a VZVirtualMachine> for Subject<'a> {
+    fn from(vm: &'a VZVirtualMachine) -> Self {
+        Subject::Machine(vm)
+    }
+}
+
+impl<'a> From<&'a VZVirtualMachineConfiguration> for Subject<'a> {
+    fn from(conf: &'a VZVirtualMachineConfiguration) -> Self {
+        Subject::Configuration(conf)
+    }
+}
+
+/// One entry of the unified log.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct LogEntry {
+    /// As `log show` printed it, e.g. `2024-01-15 10:23:45.123456+0100`.
+    pub timestamp: String,
+    /// Name of the process that logged it, e.g. `com.apple.Virtualization.VirtualMachine`.
+    pub process: String,
+    pub pid: Option<u32>,
+    /// `Default`, `Info`, `Debug`, `Error` or `Fault`.
+    pub level: String,
+    /// The category within the subsystem, e.g. `VirtualMachine`.
+    pub category: Option<String>,
+    /// Messages spanning several lines keep their line breaks.
+    pub message: String,
+}
+
+/// `timestamp process[pid] level category: message`, with `-` for a missing pid or category.
+impl fmt::Display for LogEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}[", self.timestamp, self.process)?;
+        match self.pid {
+            Some(pid) => write!(f, "{}", pid)?,
+            None => f.write_str("-")?,
+        }
+        write!(
+            f,
+            "] {} {}: {}",
+            self.level,
+            self.category.as_deref().unwrap_or("-"),
+            self.message
+        )
+    }
+}
+
+/// `log show --style json` output that is not valid JSON, e.g. cut short.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LogParseError {
+    /// Byte offset into the output.
+    pub offset: usize,
+    pub message: &'static str,
+}
+
+impl fmt::Display for LogParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "malformed log show output at byte {}: {}",
+            self.offset, self.message
+        )
+    }
+}
+
+impl std::error::Error for LogParseError {}
+
+/// Parses the output of `log show` in its default style or with `--style json` or
+/// `--style ndjson`, told apart by the first character.
+///
+/// The default style skips the header and the statistics footer, and continues an entry with
+/// every line that does not start a new one. Events other than log messages, e.g. activities,
+/// are left out of the JSON styles.
+pub fn parse_log_show(output: &str) -> Result<Vec<LogEntry>, LogParseError> {
+    let start = output.len() - output.trim_start().len();
+    match output[start..].chars().next() {
+        Some('[') => {
+            let mut parser = JsonParser::new(output, start);
+            let events = parser.value(0)?;
+            parser.end()?;
+            match events {
+                Json::Array(events) => Ok(events.iter().filter_map(entry_from_json).collect()),
+                _ => Err(parser.error("expected an array of events")),
+            }
+        }
+        Some('{') => {
+            let mut entries = Vec::new();
+            let mut offset = 0;
+            for line in output.split_inclusive('\n') {
+                if !line.trim().is_empty() {
+                    let mut parser = JsonParser::new(&output[..offset + line.len()], offset);
+                    let event = parser.value(0)?;
+                    parser.end()?;
+                    entries.extend(entry_from_json(&event));
+                }
+                offset += line.len();
+            }
+            Ok(entries)
+        }
+        _ => Ok(parse_default_style(output)),
+    }
+}
+
+fn parse_default_style(output: &str) -> Vec<LogEntry> {
+    let mut entries: Vec<LogEntry> = Vec::new();
+    for line in output.lines() {
+        if let Some(entry) = parse_default_line(line) {
+            entries.push(entry);
+        } else if line.len() >= 8 && line.bytes().all(|b| b == b'-') {
+            // The statistics footer follows.
+            break;
+        } else if let Some(last) = entries.last_mut() {
+            last.message.push('\n');
+            last.message.push_str(line);
+        }
+    }
+    for entry in &mut entries {
+        let len = entry.message.trim_end().len();
+        entry.message.truncate(len);
+    }
+    entries
+}
+
+fn next_field<'a>(rest: &mut &'a str) -> Option<&'a str> {
+    let s = rest.trim_start_matches(' ');
+    if s.is_empty() {
+        return None;
+    }
+    let end = s.find(' ').unwrap_or(s.len());
+    *rest = &s[end..];
+    Some(&s[..end])
+}
+
+/// `date time thread type activity pid ttl process: (sender) [subsystem:category] message`,
+/// where sender and subsystem are optional.
+fn parse_default_line(line: &str) -> Option<LogEntry> {
+    let mut rest = line;
+    let date = next_field(&mut rest)?;
+    let time = next_field(&mut rest)?;
+    let thread = next_field(&mut rest)?;
+    let level = next_field(&mut rest)?;
+    let _activity = next_field(&mut rest)?;
+    let pid = next_field(&mut rest)?.parse::<u32>().ok()?;
+    let _ttl = next_field(&mut rest)?.parse::<u32>().ok()?;
+    let date_bytes = date.as_bytes();
+    let is_date = date_bytes.len() == 10
+        && date_bytes.iter().enumerate().all(|(i, b)| match i {
+            4 | 7 => *b == b'-',
+            _ => b.is_ascii_digit(),
+        });
+    if !is_date || !time.contains(':') || !thread.starts_with("0x") {
+        return None;
+    }
+
+    let rest = rest.trim_start_matches(' ');
+    let (process, mut rest) = match rest.find(": ") {
+        Some(i) => (&rest[..i], &rest[i + 2..]),
+        None => (rest.trim_end_matches(':'), ""),
+    };
+    if rest.starts_with('(') {
+        if let Some(i) = rest.find(") ") {
+            rest = &rest[i + 2..];
+        }
+    }
+    let mut category = None;
+    if rest.starts_with('[') {
+        if let Some(i) = rest.find(']') {
+            category = rest[1..i]
+                .find(':')
+                .map(|colon| rest[colon + 2..i].to_string());
+            rest = rest[i + 1..].strip_prefix(' ').unwrap_or(&rest[i + 1..]);
+        }
+    }
+    Some(LogEntry {
+        timestamp: format!("{} {}", date, time),
+        process: process.to_string(),
+        pid: Some(pid),
+        level: level.to_string(),
+        category: category.filter(|c| !c.is_empty()),
+        message: rest.to_string(),
+    })
+}
+
+fn entry_from_json(event: &Json) -> Option<LogEntry> {
+    match event.get("eventType").and_then(Json::as_str) {
+        Some("logEvent") | None => {}
+        Some(_) => return None,
+    }
+    let path = event
+        .get("processImagePath")
+        .and_then(Json::as_str)
+        .unwrap_or("");
+    Some(LogEntry {
+        timestamp: event.get("timestamp")?.as_str()?.to_string(),
+        process: path.rsplit('/').next().unwrap_or(path).to_string(),
+        pid: event.get("processID").and_then(Json::as_u32),
+        level: event
+            .get("messageType")
+            .and_then(Json::as_str)
+            .unwrap_or("Default")
+            .to_string(),
+        category: event
+            .get("category")
+            .and_then(Json::as_str)
+            .filter(|c| !c.is_empty())
+            .map(str::to_string),
+        message: event.get("eventMessage")?.as_str()?.to_string(),
+    })
+}
+
+/// A JSON value, as much of JSON as `log show` output needs.
+enum Json {
+    /// `null`, `true` or `false`, which no field read here holds.
+    Literal,
+    Number(f64),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    fn get(&self, key: &str) -> Option<&Json> {
+        match self {
+            Json::Object(members) => members.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    fn as_str(&self) -> Option<&str> {
+        match self {
+            Json::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    fn as_u32(&self) -> Option<u32> {
+        match self {
+            Json::Number(n) if *n >= 0.0 && *n <= u32::MAX as f64 && n.fract() == 0.0 => {
+                Some(*n as u32)
+            }
+            _ => None,
+        }
+    }
+}
+
+struct JsonParser<'a> {
+    text: &'a str,
+    pos: usize,
+}
+
+impl<'a> JsonParser<'a> {
+    fn new(text: &'a str, pos: usize) -> Self {
+        JsonParser { text, pos }
+    }
+
+    fn error(&self, message: &'static str) -> LogParseError {
+        LogParseError {
+            offset: self.pos,
+            message,
+        }
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.text.as_bytes().get(self.pos).copied()
+    }
+
+    fn skip_whitespace(&mut self) {
+        while let Some(b' ') | Some(b'\t') | Some(b'\n') | Some(b'\r') = self.peek() {
+            self.pos += 1;
+        }
+    }
+
+    fn expect(&mut self, byte: u8, message: &'static str) -> Result<(), LogParseError> {
+        self.skip_whitespace();
+        if self.peek() == Some(byte) {
+            self.pos += 1;
+            Ok(())
+        } else {
+            Err(self.error(message))
+        }
+    }
+
+    /// Fails unless only whitespace follows.
+    fn end(&mut self) -> Result<(), LogParseError> {
+        self.skip_whitespace();
+        if self.pos == self.text.len() {
+            Ok(())
+        } else {
+            Err(self.error("unexpected data after the value"))
+        }
+    }
+
+    fn value(&mut self, depth: usize) -> Result<Json, LogParseError> {
+        if depth > MAX_JSON_DEPTH {
+            return Err(self.error("nested too deeply"));
+        }
+        self.skip_whitespace();
+        match self.peek() {
+            Some(b'[') => {
+                self.pos += 1;
+                let mut items = Vec::new();
+                if !self.close(b']') {
+                    loop {
+                        items.push(self.value(depth + 1)?);
+                        if self.close(b']') {
+                            break;
+                        }
+                        self.expect(b',', "expected ',' or ']'")?;
+                    }
+                }
+                Ok(Json::Array(items))
+            }
+            Some(b'{') => {
+                self.pos += 1;
+                let mut members = Vec::new();
+                if !self.close(b'}') {
+                    loop {
+                        self.skip_whitespace();
+                        let key = self.string()?;
+                        self.expect(b':', "expected ':'")?;
+                        members.push((key, self.value(depth + 1)?));
+                        if self.close(b'}') {
+                            break;
+                        }
+                        self.expect(b',', "expected ',' or '}'")?;
+                    }
+                }
+                Ok(Json::Object(members))
+            }
+            Some(b'"') => self.string().map(Json::String),
+            Some(b't') => self.literal("true"),
+            Some(b'f') => self.literal("false"),
+            Some(b'n') => self.literal("null"),
+            Some(b'-') | Some(b'0'..=b'9') => self.number(),
+            Some(_) => Err(self.error("expected a value")),
+            None => Err(self.error("unexpected end of output")),
+        }
+    }
+
+    /// Consumes `byte` if it comes next.
+    fn close(&mut self, byte: u8) -> bool {
+        self.skip_whitespace();
+        let found = self.peek() == Some(byte);
+        if found {
+            self.pos += 1;
+        }
+        found
+    }
+
+    fn literal(&mut self, word: &'static str) -> Result<Json, LogParseError> {
+        if self.text[self.pos..].starts_with(word) {
+            self.pos += word.len();
+            Ok(Json::Literal)
+        } else {
+            Err(self.error("expected a value"))
+        }
+    }
+
+    fn number(&mut self) -> Result<Json, LogParseError> {
+        let start = self.pos;
+        while let Some(b'-') | Some(b'+') | Some(b'.') | Some(b'e') | Some(b'E')
+        | Some(b'0'..=b'9') = self.peek()
+        {
+            self.pos += 1;
+        }
+        self.text[start..self.pos]
+            .parse()
+            .map(Json::Number)
+            .map_err(|_| LogParseError {
+                offset: start,
+                message: "malformed number",
+            })
+    }
+
+    fn hex4(&mut self) -> Result<u32, LogParseError> {
+        let digits = self
+            .text
+            .get(self.pos..self.pos + 4)
+            .ok_or_else(|| self.error("unexpected end of output"))?;
+        let code =
+            u32::from_str_radix(digits, 16).map_err(|_| self.error("malformed \\u escape"))?;
+        self.pos += 4;
+        Ok(code)
+    }
+
+    fn string(&mut self) -> Result<String, LogParseError> {
+        if self.peek() != Some(b'"') {
+            return Err(self.error("expected a string"));
+        }
+        self.pos += 1;
+        let mut out = String::new();
+        loop {
+            // Runs without quotes or escapes end at an ASCII byte, so they are whole characters.
+            let run = self.text[self.pos..]
+                .find(['"', '\\'])
+                .ok_or_else(|| self.error("unterminated string"))?;
+            out.push_str(&self.text[self.pos..self.pos + run]);
+            self.pos += run;
+            let byte = self.text.as_bytes()[self.pos];
+            self.pos += 1;
+            if byte == b'"' {
+                return Ok(out);
+            }
+            let escape = self
+                .peek()
+                .ok_or_else(|| self.error("unterminated string"))?;
+            self.pos += 1;
+            match escape {
+                b'"' => out.push('"'),
+                b'\\' => out.push('\\'),
+                b'/' => out.push('/'),
+                b'b' => out.push('\u{8}'),
+                b'f' => out.push('\u{c}'),
+                b'n' => out.push('\n'),
+                b'r' => out.push('\r'),
+                b't' => out.push('\t'),
+                b'u' => {
+                    let mut code = self.hex4()?;
+                    if (0xd800..0xdc00).contains(&code) && self.text[self.pos..].starts_with("\\u")
+                    {
+                        self.pos += 2;
+                        let low = self.hex4()?;
+                        code = if (0xdc00..0xe000).contains(&low) {
+                            0x10000 + ((code - 0xd800) << 10) + (low - 0xdc00)
+                        } else {
+                            0xfffd
+                        };
+                    }
+                    out.push(std::char::from_u32(code).unwrap_or('\u{fffd}'));
+                }
+                _ => {
+                    self.pos -= 1;
+                    return Err(self.error("unknown escape"));
+                }
+            }
+        }
+    }
+}
+
+/// Whether `name` is granted, for each of [`ENTITLEMENTS`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct EntitlementCheck {
+    pub name: &'static str,
+    pub granted: bool,
+}
+
+/// Checks the entitlements plist `codesign -d --entitlements :-` prints for each of
+/// [`ENTITLEMENTS`]; an entitlement counts as granted only with the value `true`.
+pub fn parse_entitlements(plist: &str) -> Vec<EntitlementCheck> {
+    ENTITLEMENTS
+        .iter()
+        .map(|&name| {
+            let key = format!("<key>{}</key>", name);
+            let granted = plist.find(&key).is_some_and(|i| {
+                let value = plist[i + key.len()..].trim_start();
+                value
+                    .strip_prefix("<true")
+                    .is_some_and(|rest| rest.trim_start().starts_with("/>"))
+            });
+            EntitlementCheck { name, granted }
+        })
+        .collect()
+}
+
+/// The entitlements of the running executable, from `codesign`. An executable that is not
+/// signed has none.
+pub(crate) fn check_entitlements() -> Result<Vec<EntitlementCheck>, String> {
+    let exe = std::env::current_exe().map_err(|e| format!("no executable path: {}", e))?;
+    let output = Command::new("/usr/bin/codesign")
+        .args(["-d", "--entitlements", ":-"])
+        .arg(&exe)
+        .output()
+        .map_err(|e| format!("failed to run codesign: {}", e))?;
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    if output.status.success() {
+        Ok(parse_entitlements(&String::from_utf8_lossy(&output.stdout)))
+    } else if stderr.contains("not signed") {
+        Ok(parse_entitlements(""))
+    } else {
+        Err(format!(
+            "codesign exited with {}: {}",
+            output.status,
+            stderr.trim()
+        ))
+    }
+}
+
+/// The last `window` of [`SUBSYSTEM`], rounded up to whole minutes for `log show --last`.
+fn read_log(window: Duration) -> Result<Vec<LogEntry>, String> {
+    let minutes = (window.saturating_add(Duration::from_secs(59)).as_secs() / 60).max(1);
+    let output = Command::new("/usr/bin/log")
+        .args(["show", "--style", "json", "--info", "--predicate"])
+        .arg(format!("subsystem == \"{}\"", SUBSYSTEM))
+        .arg("--last")
+        .arg(format!("{}m", minutes))
+        .output()
+        .map_err(|e| format!("failed to run log show: {}", e))?;
+    if !output.status.success() {
+        return Err(format!(
+            "log show exited with {}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    parse_log_show(&String::from_utf8_lossy(&output.stdout)).map_err(|e| e.to_string())
+}
+
+/// Everything [`collect`] found; see the [module documentation](self).
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct DiagnosticsReport {
+    /// Seconds since the Unix epoch.
+    pub collected_at: u64,
+    pub pid: u32,
+    /// How far back the log was read, in seconds.
+    pub window: u64,
+    /// e.g. `14.2.1`.
+    pub os_version: String,
+    pub host: HostCapabilities,
+    pub entitlements: Vec<EntitlementCheck>,
+    /// Why `entitlements` is empty, if `codesign` could not tell.
+    pub entitlements_error: Option<String>,
+    /// Display name of the machine, for a report about one.
+    pub machine: Option<String>,
+    /// Lifecycle of the machine, for a report about one.
+    pub lifecycle: Option<String>,
+    /// What the start failed with, for a report from
+    /// [`StartOutcome::on_failure_collect_diagnostics`].
+    ///
+    /// [`StartOutcome::on_failure_collect_diagnostics`]: crate::virtualization::virtual_machine::StartOutcome::on_failure_collect_diagnostics
+    pub failure: Option<String>,
+    /// [`VZVirtualMachineConfiguration::describe`] of the configuration.
+    pub configuration: String,
+    /// Why the configuration fails validation, for a report about a configuration.
+    pub validation_error: Option<String>,
+    pub log: Vec<LogEntry>,
+    /// Why `log` is empty, if `log show` could not be run or parsed.
+    pub log_error: Option<String>,
+}
+
+/// Collects a report about `subject` with the last `window` of the framework's log.
+pub fn collect<'a, S: Into<Subject<'a>>>(subject: S, window: Duration) -> DiagnosticsReport {
+    let pid = process::id();
+    let (machine, lifecycle, configuration, validation_error) = match subject.into() {
+        Subject::Machine(vm) => (
+            Some(vm.display_name()),
+            Some(vm.lifecycle().to_string()),
+            vm.describe_configuration().to_string(),
+            None,
+        ),
+        Subject::Configuration(conf) => (
+            None,
+            None,
+            conf.describe(),
+            conf.validate_with_error().err().map(|e| e.to_string()),
+        ),
+    };
+    let (entitlements, entitlements_error) = match check_entitlements() {
+        Ok(entitlements) => (entitlements, None),
+        Err(e) => (Vec::new(), Some(e)),
+    };
+    let (log, log_error) = match read_log(window) {
+        Ok(mut entries) => {
+            entries.retain(|e| e.pid == Some(pid) || e.process.starts_with(SUBSYSTEM));
+            (entries, None)
+        }
+        Err(e) => (Vec::new(), Some(e)),
+    };
+    DiagnosticsReport {
+        collected_at: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs()),
+        pid,
+        window: window.as_secs(),
+        os_version: features::os_version().to_string(),
+        host: HostCapabilities::detect(),
+        entitlements,
+        entitlements_error,
+        machine,
+        lifecycle,
+        failure: None,
+        configuration,
+        validation_error,
+        log,
+        log_error,
+    }
+}
+
+impl DiagnosticsReport {
+    /// Writes the report to `dir`, created if missing: `report.txt` to read, `report.json` for
+    /// tools, and the configuration and the log on their own in `configuration.txt` and
+    /// `log.txt`. Existing files of those names are replaced.
+    pub fn write_bundle<P: AsRef<Path>>(&self, dir: P) -> io::Result<()> {
+        let dir = dir.as_ref();
+        fs::create_dir_all(dir)?;
+        fs::write(dir.join("report.txt"), self.to_string())?;
+        fs::write(dir.join("report.json"), self.to_json())?;
+        fs::write(dir.join("configuration.txt"), &self.configuration)?;
+        let mut log = String::new();
+        for entry in &self.log {
+            let _ = writeln!(log, "{}", entry);
+        }
+        fs::write(dir.join("log.txt"), log)
+    }
+
+    /// The report as one JSON object, with the fields of the struct.
+    pub fn to_json(&self) -> String {
+        fn opt(out: &mut String, value: Option<&str>) {
+            match value {
+                Some(s) => json_string(out, s),
+                None => out.push_str("null"),
+            }
+        }
+
+        let mut out = String::new();
+        let _ = write!(
+            out,
+            "{{\"collected_at\":{},\"pid\":{},\"window\":{},\"os_version\":",
+            self.collected_at, self.pid, self.window
+        );
+        json_string(&mut out, &self.os_version);
+        out.push_str(",\"host\":{\"arch\":");
+        json_string(&mut out, &format!("{:?}", self.host.arch));
+        let _ = write!(
+            out,
+            ",\"virtualization_supported\":{},\"maximum_cpu_count\":{},\
+             \"maximum_memory_size\":{}}},\"entitlements\":[",
+            self.host.virtualization_supported,
+            self.host.maximum_cpu_count,
+            self.host.maximum_memory_size
+        );
+        for (i, check) in self.entitlements.iter().enumerate() {
+            if i > 0 {
+                out.push(',');
+            }
+            out.push_str("{\"name\":");
+            json_string(&mut out, check.name);
+            let _ = write!(out, ",\"granted\":{}}}", check.granted);
+        }
+        out.push_str("],\"entitlements_error\":");
+        opt(&mut out, self.entitlements_error.as_deref());
+        out.push_str(",\"machine\":");
+        opt(&mut out, self.machine.as_deref());
+        out.push_str(",\"lifecycle\":");
+        opt(&mut out, self.lifecycle.as_deref());
+        out.push_str(",\"failure\":");
+        opt(&mut out, self.failure.as_deref());
+        out.push_str(",\"configuration\":");
+        json_string(&mut out, &self.configuration);
+        out.push_str(",\"validation_error\":");
+        opt(&mut out, self.validation_error.as_deref());
+        out.push_str(",\"log\":[");
+        for (i, entry) in self.log.iter().enumerate() {
+            out.push_str(if i > 0 { ",\n" } else { "\n" });
+            out.push_str("{\"timestamp\":");
+            json_string(&mut out, &entry.timestamp);
+            out.push_str(",\"process\":");
+            json_string(&mut out, &entry.process);
+            out.push_str(",\"pid\":");
+            match entry.pid {
+                Some(pid) => {
+                    let _ = write!(out, "{}", pid);
+                }
+                None => out.push_str("null"),
+            }
+            out.push_str(",\"level\":");
+            json_string(&mut out, &entry.level);
+            out.push_str(",\"category\":");
+            opt(&mut out, entry.category.as_deref());
+            out.push_str(",\"message\":");
+            json_string(&mut out, &entry.message);
+            out.push('}');
+        }
+        out.push_str("],\"log_error\":");
+        opt(&mut out, self.log_error.as_deref());
+        out.push_str("}\n");
+        out
+    }
+}
+
+/// A summary of `key value` lines, then the configuration and the log.
+impl fmt::Display for DiagnosticsReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "collected_at {}", self.collected_at)?;
+        writeln!(f, "pid {}", self.pid)?;
+        writeln!(f, "macos {}", self.os_version)?;
+        writeln!(
+            f,
+            "host {}, virtualization {}, at most {} CPUs and {} bytes of memory",
+            self.host.arch,
+            if self.host.virtualization_supported {
+                "supported"
+            } else {
+                "not supported"
+            },
+            self.host.maximum_cpu_count,
+            self.host.maximum_memory_size
+        )?;
+        for check in &self.entitlements {
+            let granted = if check.granted { "granted" } else { "missing" };
+            writeln!(f, "entitlement {} {}", check.name, granted)?;
+        }
+        if let Some(e) = &self.entitlements_error {
+            writeln!(f, "entitlements unknown: {}", e)?;
+        }
+        if let Some(machine) = &self.machine {
+            writeln!(f, "machine {}", machine)?;
+        }
+        if let Some(lifecycle) = &self.lifecycle {
+            writeln!(f, "lifecycle {}", lifecycle)?;
+        }
+        if let Some(failure) = &self.failure {
+            writeln!(f, "failure {}", failure)?;
+        }
+        if let Some(e) = &self.validation_error {
+            writeln!(f, "validation {}", e)?;
+        }
+        writeln!(f, "\nconfiguration:\n{}", self.configuration)?;
+        match &self.log_error {
+            Some(e) => writeln!(f, "log unavailable: {}", e),
+            None => {
+                writeln!(
+                    f,
+                    "log, last {} s, {} entries:",
+                    self.window,
+                    self.log.len()
+                )?;
+                for entry in &self.log {
+                    writeln!(f, "{}", entry)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
@@ -0,0 +1,549 @@
+//! disk image module
+//!
+//! Host-side maintenance and inspection of raw disk images.
+//!
+//! # Guest discards
+//! The framework exposes no discard or TRIM setting: neither `VZVirtioBlockDeviceConfiguration`
+//! nor `VZDiskImageStorageDeviceAttachment` has a property for it, and no caching or
+//! synchronization mode is documented to change it. Whether blocks the guest frees shrink the
+//! image is up to the host macOS release. An image attached with `read_only` never changes.
+//!
+//! Images therefore only reliably shrink offline: have the guest zero its free space (e.g.
+//! `dd if=/dev/zero of=/fill; rm /fill`), stop the virtual machine and run [`reclaim`] on the
+//! image.
+//!
+//! # Partition tables
+//! [`inspect`] reads the MBR and GPT of an image without attaching it, so a configuration that
+//! cannot boot, e.g. EFI firmware with a blank disk, is reported before the guest sits at a black
+//! screen.
+//!
+//! # Examples
+//! ```rust
+//! let stats = disk_image::reclaim("vm/disk.img")?;
+//! println!("{} bytes returned to the file system", stats.reclaimed_bytes);
+//!
+//! let disk = disk_image::inspect("vm/disk.img")?;
+//! if !disk.has_efi_system_partition {
+//!     eprintln!("warning: vm/disk.img has no EFI system partition");
+//! }
+//! ```
+
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read};
+use std::os::unix::fs::{FileExt, MetadataExt};
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+
+/// `fcntl` command that deallocates a range of a file, from `<sys/fcntl.h>`.
+const F_PUNCHHOLE: libc::c_int = 99;
+
+/// Argument of `F_PUNCHHOLE`.
+#[repr(C)]
+struct FPunchhole {
+    fp_flags: libc::c_uint,
+    reserved: libc::c_uint,
+    fp_offset: libc::off_t,
+    fp_length: libc::off_t,
+}
+
+/// Blocks read per `read` call.
+const BLOCKS_PER_READ: usize = 256;
+
+/// What [`reclaim`] did.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ReclaimStats {
+    /// Length of the image.
+    pub scanned_bytes: u64,
+    /// Bytes in all-zero blocks that were punched out, including ones that were already holes.
+    pub zero_bytes: u64,
+    /// Space the image took on disk before and after.
+    pub allocated_before: u64,
+    pub allocated_after: u64,
+    /// `allocated_before - allocated_after`.
+    pub reclaimed_bytes: u64,
+}
+
+/// Deallocates every run of all-zero file system blocks in the raw image at `path`, so the file
+/// keeps its length and contents but stops taking space for them.
+///
+/// Ranges are aligned to the file system's block size, as `F_PUNCHHOLE` requires; a partial last
+/// block is kept. The image must not be attached to a running virtual machine.
+pub fn reclaim<P: AsRef<Path>>(path: P) -> io::Result<ReclaimStats> {
+    let mut file = OpenOptions::new().read(true).write(true).open(path)?;
+    let metadata = file.metadata()?;
+    let block = metadata.blksize().max(512) as usize;
+    let len = metadata.len();
+    let mut stats = ReclaimStats {
+        scanned_bytes: len,
+        allocated_before: metadata.blocks() * 512,
+        ..ReclaimStats::default()
+    };
+
+    let mut buf = vec![0u8; block * BLOCKS_PER_READ];
+    let mut offset = 0u64;
+    // Start of the current run of zero blocks, if any.
+    let mut run: Option<u64> = None;
+    loop {
+        let n = read_full(&mut file, &mut buf)?;
+        if n == 0 {
+            break;
+        }
+        for chunk in buf[..n].chunks(block) {
+            let whole = chunk.len() == block;
+            if whole && chunk.iter().all(|&b| b == 0) {
+                run.get_or_insert(offset);
+            } else if let Some(start) = run.take() {
+                punch_hole(&file, start, offset - start)?;
+                stats.zero_bytes += offset - start;
+            }
+            offset += chunk.len() as u64;
+        }
+    }
+    if let Some(start) = run {
+        punch_hole(&file, start, offset - start)?;
+        stats.zero_bytes += offset - start;
+    }
+
+    file.sync_all()?;
+    stats.allocated_after = file.metadata()?.blocks() * 512;
+    stats.reclaimed_bytes = stats.allocated_before.saturating_sub(stats.allocated_after);
+    Ok(stats)
+}
+
+/// Fills `buf` unless the end of the file comes first, so chunks stay block aligned.
+fn read_full<R: Read>(reader: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
+
+fn punch_hole<F: AsRawFd>(file: &F, offset: u64, length: u64) -> io::Result<()> {
+    let mut args = FPunchhole {
+        fp_flags: 0,
+        reserved: 0,
+        fp_offset: offset as libc::off_t,
+        fp_length: length as libc::off_t,
+    };
+    let ret = unsafe { libc::fcntl(file.as_raw_fd(), F_PUNCHHOLE, &mut args as *mut FPunchhole) };
+    if ret == -1 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+/// Sector sizes a GPT is looked for with, in order.
+const SECTOR_SIZES: [u64; 2] = [512, 4096];
+
+const MBR_SIGNATURE: [u8; 2] = [0x55, 0xaa];
+const MBR_PARTITIONS_OFFSET: usize = 0x1be;
+const MBR_TYPE_GPT_PROTECTIVE: u8 = 0xee;
+const MBR_TYPE_EFI_SYSTEM: u8 = 0xef;
+
+const GPT_SIGNATURE: &[u8; 8] = b"EFI PART";
+const GPT_HEADER_MIN_LEN: usize = 92;
+const GPT_ENTRY_MIN_LEN: usize = 128;
+/// Largest partition entry array read; the usual one is 16 KiB.
+const GPT_ENTRIES_MAX_LEN: usize = 1 << 20;
+
+/// Bytes read per sample when looking for data in an image without a partition table.
+const SAMPLE_LEN: u64 = 64 * 1024;
+/// Samples spread over the image, besides its first and last bytes.
+const SAMPLES: u64 = 62;
+
+/// A GUID as stored in a GPT: the first three fields little endian.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Guid(pub [u8; 16]);
+
+impl Guid {
+    /// `C12A7328-F81F-11D2-BA4B-00A0C93EC93B`.
+    pub const EFI_SYSTEM_PARTITION: Guid = Guid([
+        0x28, 0x73, 0x2a, 0xc1, 0x1f, 0xf8, 0xd2, 0x11, 0xba, 0x4b, 0x00, 0xa0, 0xc9, 0x3e, 0xc9,
+        0x3b,
+    ]);
+
+    pub fn is_zero(&self) -> bool {
+        self.0 == [0; 16]
+    }
+}
+
+/// Upper-case canonical form, e.g. `C12A7328-F81F-11D2-BA4B-00A0C93EC93B`.
+impl fmt::Display for Guid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let b = &self.0;
+        write!(
+            f,
+            "{:08X}-{:04X}-{:04X}-",
+            u32::from_le_bytes([b[0], b[1], b[2], b[3]]),
+            u16::from_le_bytes([b[4], b[5]]),
+            u16::from_le_bytes([b[6], b[7]])
+        )?;
+        for byte in &b[8..10] {
+            write!(f, "{:02X}", byte)?;
+        }
+        f.write_str("-")?;
+        for byte in &b[10..] {
+            write!(f, "{:02X}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+/// A used entry of a GPT.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GptPartition {
+    /// Position in the partition entry array, from 0.
+    pub index: u32,
+    pub type_guid: Guid,
+    pub unique_guid: Guid,
+    /// The partition name, e.g. `EFI System Partition`; empty if unnamed.
+    pub name: String,
+    pub first_lba: u64,
+    pub last_lba: u64,
+    pub size_bytes: u64,
+}
+
+impl GptPartition {
+    pub fn is_efi_system_partition(&self) -> bool {
+        self.type_guid == Guid::EFI_SYSTEM_PARTITION
+    }
+}
+
+/// The partitioning scheme found on an image.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PartitionTable {
+    /// No MBR signature: unpartitioned, blank or a bare file system.
+    None,
+    /// An MBR without a GPT, with the number of used entries.
+    Mbr { partitions: usize },
+    /// A GPT, valid in its primary or its backup copy.
+    Gpt { sector_size: u64 },
+    /// A protective MBR whose GPT failed validation in both copies; see
+    /// [`DiskInspection::warnings`].
+    InvalidGpt,
+}
+
+/// What [`inspect`] found in an image.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DiskInspection {
+    /// Length of the image.
+    pub size_bytes: u64,
+    /// Space the image takes on disk.
+    pub allocated_bytes: u64,
+    pub table: PartitionTable,
+    /// Used GPT entries, in entry order; empty for other tables.
+    pub partitions: Vec<GptPartition>,
+    /// A GPT entry or MBR partition of EFI system partition type exists.
+    pub has_efi_system_partition: bool,
+    /// No partition table, and nothing but zeros where the image was sampled or no space allocated
+    /// at all. Sampling can miss data, so `false` is certain and `true` is very likely.
+    pub is_blank: bool,
+    /// Damage that did not stop the inspection, e.g. a primary GPT replaced by its backup.
+    pub warnings: Vec<String>,
+}
+
+impl DiskInspection {
+    /// Partitions of the GPT, or used entries of the MBR.
+    pub fn partition_count(&self) -> usize {
+        match self.table {
+            PartitionTable::Mbr { partitions } => partitions,
+            _ => self.partitions.len(),
+        }
+    }
+}
+
+/// Reads the partition table of the raw image at `path`. The image is only read, and may be in
+/// use by a virtual machine, though a guest writing its partition table meanwhile can make the
+/// result inconsistent.
+///
+/// Headers and entry arrays are validated with their CRC32s. Only I/O errors fail; damage is
+/// reported in [`DiskInspection::warnings`].
+pub fn inspect<P: AsRef<Path>>(path: P) -> io::Result<DiskInspection> {
+    let file = File::open(path)?;
+    let metadata = file.metadata()?;
+    let mut inspection = DiskInspection {
+        size_bytes: metadata.len(),
+        allocated_bytes: metadata.blocks() * 512,
+        table: PartitionTable::None,
+        partitions: Vec::new(),
+        has_efi_system_partition: false,
+        is_blank: false,
+        warnings: Vec::new(),
+    };
+
+    let mut mbr = [0u8; 512];
+    if read_at(&file, &mut mbr, 0)? < mbr.len() || mbr[0x1fe..] != MBR_SIGNATURE {
+        inspection.is_blank =
+            inspection.allocated_bytes == 0 || all_zero_samples(&file, inspection.size_bytes)?;
+        return Ok(inspection);
+    }
+    let mbr_types: Vec<u8> = (0..4)
+        .map(|i| mbr[MBR_PARTITIONS_OFFSET + i * 16 + 4])
+        .filter(|&t| t != 0)
+        .collect();
+    if !mbr_types.contains(&MBR_TYPE_GPT_PROTECTIVE) {
+        inspection.table = PartitionTable::Mbr {
+            partitions: mbr_types.len(),
+        };
+        inspection.has_efi_system_partition = mbr_types.contains(&MBR_TYPE_EFI_SYSTEM);
+        return Ok(inspection);
+    }
+
+    inspection.table = PartitionTable::InvalidGpt;
+    for &sector_size in &SECTOR_SIZES {
+        match read_gpt(&file, inspection.size_bytes, sector_size)? {
+            GptRead::Missing => continue,
+            GptRead::Found {
+                partitions,
+                warnings,
+            } => {
+                inspection.table = PartitionTable::Gpt { sector_size };
+                inspection.has_efi_system_partition =
+                    partitions.iter().any(|p| p.is_efi_system_partition());
+                inspection.partitions = partitions;
+                inspection.warnings.extend(warnings);
+                return Ok(inspection);
+            }
+            GptRead::Invalid(warnings) => {
+                inspection.warnings.extend(warnings);
+                return Ok(inspection);
+            }
+        }
+    }
+    inspection
+        .warnings
+        .push("protective MBR without a GPT header".to_string());
+    Ok(inspection)
+}
+
+enum GptRead {
+    /// No GPT signature at this sector size.
+    Missing,
+    Found {
+        partitions: Vec<GptPartition>,
+        warnings: Vec<String>,
+    },
+    /// Both copies failed validation, for the given reasons.
+    Invalid(Vec<String>),
+}
+
+/// Reads the primary GPT, or the backup if the primary is damaged.
+fn read_gpt(file: &File, size: u64, sector_size: u64) -> io::Result<GptRead> {
+    let last_lba = match (size / sector_size).checked_sub(1) {
+        Some(lba) if lba > 1 => lba,
+        _ => return Ok(GptRead::Missing),
+    };
+    let primary = read_sector(file, 1, sector_size)?;
+    if &primary[..8] != GPT_SIGNATURE {
+        return Ok(GptRead::Missing);
+    }
+    let primary_error = match read_gpt_copy(file, &primary, 1, sector_size) {
+        Ok(partitions) => {
+            return Ok(GptRead::Found {
+                partitions,
+                warnings: Vec::new(),
+            })
+        }
+        Err(reason) => format!("primary GPT: {}", reason),
+    };
+    // The primary header says where the backup is, but cannot be trusted once damaged.
+    let backup = read_sector(file, last_lba, sector_size)?;
+    let backup_result = if &backup[..8] == GPT_SIGNATURE {
+        read_gpt_copy(file, &backup, last_lba, sector_size)
+    } else {
+        Err("no header in the last sector".to_string())
+    };
+    Ok(match backup_result {
+        Ok(partitions) => GptRead::Found {
+            partitions,
+            warnings: vec![format!("{}; using the backup GPT", primary_error)],
+        },
+        Err(reason) => GptRead::Invalid(vec![primary_error, format!("backup GPT: {}", reason)]),
+    })
+}
+
+/// Validates the header in `sector`, read from `lba`, and reads its entries.
+fn read_gpt_copy(
+    file: &File,
+    sector: &[u8],
+    lba: u64,
+    sector_size: u64,
+) -> Result<Vec<GptPartition>, String> {
+    let header_len = le_u32(sector, 12) as usize;
+    if header_len < GPT_HEADER_MIN_LEN || header_len > sector.len() {
+        return Err(format!("header size {} is out of range", header_len));
+    }
+    let mut header = sector[..header_len].to_vec();
+    let header_crc = le_u32(&header, 16);
+    header[16..20].copy_from_slice(&[0; 4]);
+    if crc32(&header) != header_crc {
+        return Err("header CRC mismatch".to_string());
+    }
+    if le_u64(&header, 24) != lba {
+        return Err(format!(
+            "header claims to be at LBA {}",
+            le_u64(&header, 24)
+        ));
+    }
+    let entries_lba = le_u64(&header, 72);
+    let count = le_u32(&header, 80) as usize;
+    let entry_len = le_u32(&header, 84) as usize;
+    if entry_len < GPT_ENTRY_MIN_LEN || entry_len & 7 != 0 {
+        return Err(format!("entry size {} is invalid", entry_len));
+    }
+    let entries_len = count
+        .checked_mul(entry_len)
+        .filter(|&len| len <= GPT_ENTRIES_MAX_LEN)
+        .ok_or_else(|| format!("{} entries of {} bytes are too many", count, entry_len))?;
+    let mut entries = vec![0u8; entries_len];
+    let offset = entries_lba
+        .checked_mul(sector_size)
+        .ok_or_else(|| "entry array is out of range".to_string())?;
+    match read_at(file, &mut entries, offset) {
+        Ok(n) if n == entries_len => {}
+        Ok(_) => return Err("entry array is past the end of the image".to_string()),
+        Err(e) => return Err(format!("entry array is unreadable: {}", e)),
+    }
+    if crc32(&entries) != le_u32(&header, 88) {
+        return Err("entry array CRC mismatch".to_string());
+    }
+    Ok(entries
+        .chunks(entry_len)
+        .enumerate()
+        .filter_map(|(index, entry)| {
+            let type_guid = guid_at(entry, 0);
+            if type_guid.is_zero() {
+                return None;
+            }
+            let first_lba = le_u64(entry, 32);
+            let last_lba = le_u64(entry, 40);
+            Some(GptPartition {
+                index: index as u32,
+                type_guid,
+                unique_guid: guid_at(entry, 16),
+                name: utf16_name(&entry[56..128]),
+                first_lba,
+                last_lba,
+                size_bytes: last_lba
+                    .saturating_sub(first_lba)
+                    .saturating_add(1)
+                    .saturating_mul(sector_size),
+            })
+        })
+        .collect())
+}
+
+/// Whether every sample of the image is zero: its first and last bytes and [`SAMPLES`] spots in
+/// between.
+fn all_zero_samples(file: &File, size: u64) -> io::Result<bool> {
+    let mut buf = vec![0u8; SAMPLE_LEN as usize];
+    let span = size.saturating_sub(SAMPLE_LEN);
+    let mut offsets: Vec<u64> = (0..=SAMPLES + 1)
+        .map(|i| span / (SAMPLES + 1) * i)
+        .collect();
+    offsets.push(span);
+    for offset in offsets {
+        let n = read_at(file, &mut buf, offset)?;
+        if buf[..n].iter().any(|&b| b != 0) {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+/// Warnings for EFI firmware booting from disks with the given inspections, e.g. from
+/// `VZEFIBootLoaderBuilder::build_checked`. A single disk without an EFI system partition is
+/// flagged; with several, the firmware may boot from any of them, so only damage is.
+pub fn efi_boot_warnings(disks: &[(&str, &DiskInspection)]) -> Vec<String> {
+    let mut warnings = Vec::new();
+    for (path, disk) in disks {
+        for warning in &disk.warnings {
+            warnings.push(format!("{}: {}", path, warning));
+        }
+    }
+    if let [(path, disk)] = disks {
+        if disk.is_blank {
+            warnings.push(format!(
+                "{}: the only disk is blank; the firmware has nothing to boot",
+                path
+            ));
+        } else if !disk.has_efi_system_partition {
+            warnings.push(format!(
+                "{}: the only disk has no EFI system partition; the firmware has nothing to boot",
+                path
+            ));
+        }
+    }
+    warnings
+}
+
+fn read_sector(file: &File, lba: u64, sector_size: u64) -> io::Result<Vec<u8>> {
+    let mut sector = vec![0u8; sector_size as usize];
+    let n = read_at(file, &mut sector, lba * sector_size)?;
+    sector.truncate(n.max(GPT_SIGNATURE.len()));
+    Ok(sector)
+}
+
+/// Fills `buf` from `offset` unless the end of the file comes first.
+fn read_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match file.read_at(&mut buf[filled..], offset + filled as u64) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
+
+fn le_u32(b: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([b[offset], b[offset + 1], b[offset + 2], b[offset + 3]])
+}
+
+fn le_u64(b: &[u8], offset: usize) -> u64 {
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(&b[offset..offset + 8]);
+    u64::from_le_bytes(bytes)
+}
+
+fn guid_at(b: &[u8], offset: usize) -> Guid {
+    let mut bytes = [0u8; 16];
+    bytes.copy_from_slice(&b[offset..offset + 16]);
+    Guid(bytes)
+}
+
+/// A NUL-padded UTF-16LE partition name.
+fn utf16_name(b: &[u8]) -> String {
+    let units: Vec<u16> = b
+        .chunks(2)
+        .map(|c| u16::from_le_bytes([c[0], c[1]]))
+        .take_while(|&u| u != 0)
+        .collect();
+    String::from_utf16_lossy(&units)
+}
+
+/// CRC-32 (IEEE 802.3), as GPT headers and entry arrays use.
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
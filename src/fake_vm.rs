@@ -0,0 +1,623 @@
+//! fake vm module
+//!
+//! A scripted stand-in for a virtual machine, with the `test-util` feature. [`FakeVm`] implements
+//! [`VirtualMachineHandle`], so code written against the trait, or built on the
+//! [`registry`](crate::registry), [`respawn`](crate::respawn), [`teardown`](crate::teardown) and
+//! [`reconcile`](crate::reconcile) modules, can be unit tested without Virtualization.framework,
+//! the virtualization entitlement or a guest image. It only needs Foundation and libdispatch, so
+//! its tests run on any Mac.
+//!
+//! Unscripted, a fake is a machine whose every operation succeeds right away. A script changes
+//! that:
+//!
+//! - [`FakeVm::reply`] queues how the next call of an operation answers, and
+//!   [`FakeVm::default_reply`] how the calls answer once the queue is used up;
+//! - [`FakeVm::latency`] delays the answers to an operation;
+//! - [`FakeVm::after_start`] queues how a run ends once its start succeeded, e.g. a crash half a
+//!   second in;
+//! - [`FakeVm::crash`] and [`FakeVm::guest_shutdown`] end the current run now.
+//!
+//! Answers, state changes and stops go through the same lifecycle tracking and error event stream
+//! as those of a real machine, on the fake's own queue, so its [`Lifecycle`], [`StopReason`] and
+//! error events come out as a real machine's would. [`FakeVm::calls`] lists the operations it was
+//! sent.
+//!
+//! # Examples
+//! A supervisor restarting machines that crash, tested against fakes that crash twice:
+//! ```rust
+//! let crash = VZError(NSError::posix(libc::EIO));
+//! let mut handle = Respawner::with_factory(move |attempt| {
+//!     let vm = FakeVm::new("web");
+//!     if attempt < 2 {
+//!         vm.after_start(Duration::from_millis(10), FakeEvent::Crash(crash.clone()))
+//!     } else {
+//!         vm
+//!     }
+//! })
+//! .spawn();
+//! ```
+//!
+//! A retry of the application's own, against a machine whose first start fails after 50ms:
+//! ```rust
+//! let vm = FakeVm::new("web")
+//!     .reply(FakeOp::Start, FakeReply::Fail(VZError(NSError::posix(libc::EBUSY))))
+//!     .latency(FakeOp::Start, Duration::from_millis(50));
+//! start_with_one_retry(&vm);
+//! assert_eq!(vm.calls(), [FakeOp::Start, FakeOp::Start]);
+//! assert_eq!(vm.lifecycle(), Lifecycle::Started);
+//! ```
+
+use crate::base::DispatchQueue;
+use crate::reconcile::ObservedDevices;
+use crate::strict;
+use crate::timeline::TimelineSlot;
+use crate::virtualization::error::{CompletionOutcome, VZError, VZErrorCtx};
+use crate::virtualization::error_events::{ErrorEventReceiver, ErrorEventSender, Phase};
+use crate::virtualization::handle::{VirtualMachineHandle, WeakHandle};
+use crate::virtualization::lifecycle::{
+    Lifecycle, LifecycleError, LifecycleTracker, Op, StopReason, StopSignal, Waiter,
+};
+use crate::virtualization::virtual_machine::{VZVirtualMachineState, VmId};
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, Weak};
+use std::time::Duration;
+
+use VZVirtualMachineState::*;
+
+/// An operation a [`FakeVm`] is sent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum FakeOp {
+    /// `start` and `start_or_join`.
+    Start,
+    /// `stop` and `stop_or_join`.
+    Stop,
+    Pause,
+    Resume,
+    /// `request_stop_with_error`.
+    RequestStop,
+}
+
+const OPS: usize = 5;
+
+/// How a [`FakeVm`] answers an operation.
+#[derive(Debug, Clone)]
+pub enum FakeReply {
+    /// Completes successfully. A request to stop is accepted, and the guest shuts down after the
+    /// latency.
+    Succeed,
+    /// Completes with the error, leaving the state as it was; a request to stop returns it.
+    Fail(VZError),
+    /// Completes as cancelled, leaving the state as it was; a request to stop is refused with
+    /// `Ok(false)`.
+    Cancel,
+    /// Never completes, as a framework call that hangs; a request to stop is accepted and the
+    /// guest ignores it.
+    Hang,
+}
+
+// The error is a retained `NSError`, which is immutable.
+unsafe impl Send for FakeReply {}
+unsafe impl Sync for FakeReply {}
+
+impl FakeReply {
+    /// The outcome handed to the completion handler; `None` for a reply that never completes.
+    fn outcome(self) -> Option<CompletionOutcome> {
+        match self {
+            FakeReply::Succeed => Some(CompletionOutcome::Success(())),
+            FakeReply::Fail(error) => Some(CompletionOutcome::Failed(error)),
+            FakeReply::Cancel => Some(CompletionOutcome::Cancelled),
+            FakeReply::Hang => None,
+        }
+    }
+}
+
+/// How a run of a [`FakeVm`] ends on its own.
+#[derive(Debug, Clone)]
+pub enum FakeEvent {
+    /// The guest shuts itself down, as reported to a delegate by `guestDidStopVirtualMachine:`.
+    GuestShutdown,
+    /// The machine stops with the error, as reported to a delegate by
+    /// `virtualMachine:didStopWithError:`.
+    Crash(VZError),
+}
+
+unsafe impl Send for FakeEvent {}
+unsafe impl Sync for FakeEvent {}
+
+#[derive(Default)]
+struct Script {
+    replies: [VecDeque<FakeReply>; OPS],
+    defaults: [Option<FakeReply>; OPS],
+    latencies: [Duration; OPS],
+    /// How the next runs end, and how long after they reached running.
+    endings: VecDeque<(Duration, FakeEvent)>,
+    calls: Vec<FakeOp>,
+    devices: Option<ObservedDevices>,
+}
+
+/// An observation of [`FakeVm::on_first_transition_to`] waiting for its state.
+struct Observer {
+    state: VZVirtualMachineState,
+    f: Box<dyn FnOnce() + Send>,
+    abandoned: Arc<AtomicBool>,
+}
+
+/// What [`FakeVm::on_first_transition_to`] returns; dropping it abandons the observation.
+#[must_use = "the observation ends when the guard is dropped"]
+pub struct FakeObservation(Arc<AtomicBool>);
+
+impl Drop for FakeObservation {
+    fn drop(&mut self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+}
+
+struct Inner {
+    id: VmId,
+    label: String,
+    queue: DispatchQueue,
+    tracker: Arc<LifecycleTracker>,
+    error_events: ErrorEventSender,
+    script: Mutex<Script>,
+    /// Only changed on the queue.
+    state: Mutex<VZVirtualMachineState>,
+    /// Counts the runs that ended, so the endings scheduled for an earlier run are dropped.
+    runs_ended: AtomicU64,
+    observers: Mutex<Vec<Observer>>,
+    /// Completion handlers of the pauses and resumes in flight, by number.
+    pending: Mutex<Vec<(u64, Waiter<CompletionOutcome>)>>,
+    next_pending: AtomicU64,
+}
+
+// Completion handlers are only called on the fake's queue, as the framework calls those of a real
+// machine on its queue.
+unsafe impl Send for Inner {}
+unsafe impl Sync for Inner {}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Whether a run is going on in `state`.
+fn is_running(state: VZVirtualMachineState) -> bool {
+    matches!(
+        state,
+        VZVirtualMachineStateRunning
+            | VZVirtualMachineStatePaused
+            | VZVirtualMachineStatePausing
+            | VZVirtualMachineStateResuming
+    )
+}
+
+/// A scripted virtual machine; see the [module documentation](self). Clones share the machine.
+///
+/// Scripting methods take and return the fake so they chain after [`FakeVm::new`]; called on a
+/// clone, they script the machine all clones share.
+#[derive(Clone)]
+pub struct FakeVm(Arc<Inner>);
+
+impl FakeVm {
+    /// A stopped machine labelled `label`, with a queue of that name. It gets a [`VmId`] as a real
+    /// machine does.
+    pub fn new(label: &str) -> FakeVm {
+        let id = VmId::next();
+        let queue = DispatchQueue::new(label);
+        let tracker = Arc::new(LifecycleTracker::new());
+        // A real machine's observation first reports the current state; changes count from there.
+        tracker.observe(VZVirtualMachineStateStopped);
+        let timeline = Arc::new(TimelineSlot::new(id, format!("{} ({})", id, label)));
+        let error_events = ErrorEventSender::detached(&queue, tracker.clone(), timeline);
+        FakeVm(Arc::new(Inner {
+            id,
+            label: label.to_string(),
+            queue,
+            tracker,
+            error_events,
+            script: Mutex::new(Script::default()),
+            state: Mutex::new(VZVirtualMachineStateStopped),
+            runs_ended: AtomicU64::new(0),
+            observers: Mutex::new(Vec::new()),
+            pending: Mutex::new(Vec::new()),
+            next_pending: AtomicU64::new(0),
+        }))
+    }
+
+    /// Answers the next call of `op` with `reply`, after the replies queued before it.
+    pub fn reply(self, op: FakeOp, reply: FakeReply) -> FakeVm {
+        self.script().replies[op as usize].push_back(reply);
+        self
+    }
+
+    /// Answers the calls of `op` with `reply` once the queued replies are used up, instead of
+    /// [`FakeReply::Succeed`].
+    pub fn default_reply(self, op: FakeOp, reply: FakeReply) -> FakeVm {
+        self.script().defaults[op as usize] = Some(reply);
+        self
+    }
+
+    /// Delays the answers to `op` by `latency`: the completion handler, and the state the
+    /// operation leads to, come that much after the call. For a request to stop, it is how long
+    /// the guest takes to shut down. No delay by default.
+    pub fn latency(self, op: FakeOp, latency: Duration) -> FakeVm {
+        self.script().latencies[op as usize] = latency;
+        self
+    }
+
+    /// Ends the next run that starts successfully with `event`, `delay` after the machine is
+    /// running. Each successful start takes the next ending queued; a run without one goes on
+    /// until it is stopped.
+    pub fn after_start(self, delay: Duration, event: FakeEvent) -> FakeVm {
+        self.script().endings.push_back((delay, event));
+        self
+    }
+
+    /// The devices [`VirtualMachineHandle::observed_devices`] reports. Without, every category
+    /// reads as unavailable.
+    pub fn devices(self, devices: ObservedDevices) -> FakeVm {
+        self.script().devices = Some(devices);
+        self
+    }
+
+    /// Makes the running machine stop with `error` now, as [`FakeEvent::Crash`] does. Does
+    /// nothing if no run is going on.
+    pub fn crash(&self, error: VZError) {
+        self.end_run_now(FakeEvent::Crash(error));
+    }
+
+    /// Makes the guest of the running machine shut down now, as [`FakeEvent::GuestShutdown`]
+    /// does. Does nothing if no run is going on.
+    pub fn guest_shutdown(&self) {
+        self.end_run_now(FakeEvent::GuestShutdown);
+    }
+
+    /// The operations the machine was sent, in order, including those the lifecycle tracking
+    /// refused or joined to one in flight.
+    pub fn calls(&self) -> Vec<FakeOp> {
+        self.script().calls.clone()
+    }
+
+    /// The current state. Unlike [`VirtualMachineHandle::state`], it may be read from any
+    /// thread.
+    pub fn peek_state(&self) -> VZVirtualMachineState {
+        *lock(&self.0.state)
+    }
+
+    fn script(&self) -> MutexGuard<'_, Script> {
+        lock(&self.0.script)
+    }
+
+    /// Records a call of `op`, and returns how to answer it and after how long.
+    fn answer(&self, op: FakeOp) -> (FakeReply, Duration) {
+        let mut script = self.script();
+        script.calls.push(op);
+        let i = op as usize;
+        let reply = script.replies[i]
+            .pop_front()
+            .or_else(|| script.defaults[i].clone())
+            .unwrap_or(FakeReply::Succeed);
+        (reply, script.latencies[i])
+    }
+
+    /// Runs `f` on the queue once `delay` has passed.
+    fn later<F: FnOnce(&FakeVm) + Send + 'static>(&self, delay: Duration, f: F) {
+        let vm = self.clone();
+        self.0.queue.after(delay, move || f(&vm));
+    }
+
+    /// Moves to `state` as a real machine's state observation would report it; on the queue.
+    fn set_state(&self, state: VZVirtualMachineState) {
+        *lock(&self.0.state) = state;
+        self.0.tracker.observe(state);
+        self.notify(state);
+    }
+
+    /// Runs the observations waiting for `state`, and forgets the abandoned ones.
+    fn notify(&self, state: VZVirtualMachineState) {
+        let ready: Vec<Observer> = {
+            let mut observers = lock(&self.0.observers);
+            observers.retain(|o| !o.abandoned.load(Ordering::SeqCst));
+            let (ready, waiting) = observers.drain(..).partition(|o| o.state == state);
+            *observers = waiting;
+            ready
+        };
+        for observer in ready {
+            (observer.f)();
+        }
+    }
+
+    /// Sends a start or stop through the lifecycle tracking, as the safe wrappers of a real
+    /// machine do.
+    fn send_tracked<F>(
+        &self,
+        op: Op,
+        join: bool,
+        completion_handler: F,
+    ) -> Result<(), LifecycleError>
+    where
+        F: FnOnce(CompletionOutcome) + Send + 'static,
+    {
+        let (fake_op, during) = match op {
+            Op::Start => (FakeOp::Start, VZVirtualMachineStateStarting),
+            Op::Stop => (FakeOp::Stop, VZVirtualMachineStateStopping),
+        };
+        let (reply, latency) = self.answer(fake_op);
+        if !self
+            .0
+            .tracker
+            .request(op, join, Box::new(completion_handler))?
+        {
+            return Ok(());
+        }
+        let vm = self.clone();
+        self.0.queue.exec_async(move || {
+            let before = vm.peek_state();
+            vm.set_state(during);
+            vm.later(latency, move |vm| vm.complete_tracked(op, reply, before));
+        });
+        Ok(())
+    }
+
+    /// Completes a start or stop with `reply`, on the queue; `before` is the state it was sent in.
+    fn complete_tracked(&self, op: Op, reply: FakeReply, before: VZVirtualMachineState) {
+        let outcome = match reply.outcome() {
+            Some(outcome) => outcome,
+            None => return,
+        };
+        match (op, outcome.is_success()) {
+            (Op::Start, true) => self.set_state(VZVirtualMachineStateRunning),
+            (Op::Stop, true) => {
+                self.0.runs_ended.fetch_add(1, Ordering::SeqCst);
+                self.set_state(VZVirtualMachineStateStopped);
+            }
+            (_, false) => self.set_state(before),
+        }
+        match (op, &outcome) {
+            (Op::Start, CompletionOutcome::Failed(e)) => {
+                self.0.error_events.error(Phase::Start, e.clone())
+            }
+            (Op::Stop, CompletionOutcome::Failed(e)) => {
+                self.0.error_events.error(Phase::Stop, e.clone())
+            }
+            _ => {}
+        }
+        if op == Op::Stop {
+            self.0.error_events.note_stop(StopSignal::ForceCompleted {
+                stopped: outcome.is_success(),
+            });
+        }
+        let started = op == Op::Start && outcome.is_success();
+        self.0.tracker.complete(op, outcome, self.peek_state());
+        if started {
+            self.schedule_ending();
+        }
+    }
+
+    /// Sends a pause or resume, which the lifecycle tracking does not follow.
+    fn send_untracked<F>(&self, op: FakeOp, completion_handler: F)
+    where
+        F: FnOnce(CompletionOutcome) + Send + 'static,
+    {
+        let (during, after) = match op {
+            FakeOp::Pause => (VZVirtualMachineStatePausing, VZVirtualMachineStatePaused),
+            _ => (VZVirtualMachineStateResuming, VZVirtualMachineStateRunning),
+        };
+        let (reply, latency) = self.answer(op);
+        let number = self.0.next_pending.fetch_add(1, Ordering::SeqCst);
+        lock(&self.0.pending).push((number, Box::new(completion_handler)));
+        let vm = self.clone();
+        self.0.queue.exec_async(move || {
+            let before = vm.peek_state();
+            vm.set_state(during);
+            vm.later(latency, move |vm| {
+                let outcome = match reply.outcome() {
+                    Some(outcome) => outcome,
+                    None => return,
+                };
+                vm.set_state(if outcome.is_success() { after } else { before });
+                let waiter = {
+                    let mut pending = lock(&vm.0.pending);
+                    let i = pending.iter().position(|(n, _)| *n == number);
+                    i.map(|i| pending.remove(i).1)
+                };
+                if let Some(waiter) = waiter {
+                    waiter(outcome);
+                }
+            });
+        });
+    }
+
+    /// Schedules the next scripted ending for the run that just started.
+    fn schedule_ending(&self) {
+        let ending = self.script().endings.pop_front();
+        if let Some((delay, event)) = ending {
+            let run = self.0.runs_ended.load(Ordering::SeqCst);
+            self.later(delay, move |vm| vm.end_run(run, event));
+        }
+    }
+
+    fn end_run_now(&self, event: FakeEvent) {
+        let run = self.0.runs_ended.load(Ordering::SeqCst);
+        let vm = self.clone();
+        self.0.queue.exec_async(move || vm.end_run(run, event));
+    }
+
+    /// Ends the current run with `event`, on the queue, unless it is over: `run` is how many runs
+    /// had ended when the ending was scheduled.
+    fn end_run(&self, run: u64, event: FakeEvent) {
+        if self.0.runs_ended.load(Ordering::SeqCst) != run || !is_running(self.peek_state()) {
+            return;
+        }
+        self.0.runs_ended.fetch_add(1, Ordering::SeqCst);
+        match event {
+            FakeEvent::GuestShutdown => {
+                self.set_state(VZVirtualMachineStateStopped);
+                self.0.error_events.note_stop(StopSignal::GuestStopped);
+            }
+            FakeEvent::Crash(error) => {
+                self.set_state(VZVirtualMachineStateError);
+                self.0.error_events.error(Phase::Runtime, error.clone());
+                self.0
+                    .error_events
+                    .note_stop(StopSignal::StoppedWithError(error));
+            }
+        }
+    }
+}
+
+impl fmt::Debug for FakeVm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FakeVm")
+            .field("id", &self.0.id)
+            .field("label", &self.0.label)
+            .field("lifecycle", &self.0.tracker.lifecycle())
+            .finish()
+    }
+}
+
+/// A reference to a [`FakeVm`] that does not keep it alive.
+#[derive(Clone)]
+pub struct WeakFakeVm(Weak<Inner>);
+
+impl fmt::Debug for WeakFakeVm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("WeakFakeVm")
+            .field("alive", &(self.0.strong_count() > 0))
+            .finish()
+    }
+}
+
+impl WeakHandle for WeakFakeVm {
+    type Handle = FakeVm;
+
+    fn upgrade(&self) -> Option<FakeVm> {
+        self.0.upgrade().map(FakeVm)
+    }
+}
+
+impl VirtualMachineHandle for FakeVm {
+    type Observation = FakeObservation;
+    type Weak = WeakFakeVm;
+
+    fn vm_id(&self) -> VmId {
+        self.0.id
+    }
+
+    fn downgrade(&self) -> WeakFakeVm {
+        WeakFakeVm(Arc::downgrade(&self.0))
+    }
+
+    fn label(&self) -> Option<&str> {
+        Some(&self.0.label)
+    }
+
+    fn queue(&self) -> &DispatchQueue {
+        &self.0.queue
+    }
+
+    fn start<F: FnOnce(CompletionOutcome) + Send + 'static>(
+        &self,
+        completion_handler: F,
+    ) -> Result<(), LifecycleError> {
+        self.send_tracked(Op::Start, false, completion_handler)
+    }
+
+    fn start_or_join<F: FnOnce(CompletionOutcome) + Send + 'static>(
+        &self,
+        completion_handler: F,
+    ) -> Result<(), LifecycleError> {
+        self.send_tracked(Op::Start, true, completion_handler)
+    }
+
+    fn stop<F: FnOnce(CompletionOutcome) + Send + 'static>(
+        &self,
+        completion_handler: F,
+    ) -> Result<(), LifecycleError> {
+        self.send_tracked(Op::Stop, false, completion_handler)
+    }
+
+    fn stop_or_join<F: FnOnce(CompletionOutcome) + Send + 'static>(&self, completion_handler: F) {
+        // Joining never refuses a stop.
+        let _ = self.send_tracked(Op::Stop, true, completion_handler);
+    }
+
+    fn pause<F: FnOnce(CompletionOutcome) + Send + 'static>(&self, completion_handler: F) {
+        self.send_untracked(FakeOp::Pause, completion_handler);
+    }
+
+    fn resume<F: FnOnce(CompletionOutcome) + Send + 'static>(&self, completion_handler: F) {
+        self.send_untracked(FakeOp::Resume, completion_handler);
+    }
+
+    unsafe fn request_stop_with_error(&self) -> Result<bool, VZErrorCtx> {
+        strict::on_vm_queue(&self.0.queue, "FakeVm::request_stop_with_error");
+        let (reply, latency) = self.answer(FakeOp::RequestStop);
+        match reply {
+            FakeReply::Fail(error) => Err(VZErrorCtx::new(
+                "request guest stop of",
+                Some(self.display_name()),
+                error.0,
+            )),
+            FakeReply::Cancel => Ok(false),
+            reply => {
+                self.0.error_events.note_stop(StopSignal::Requested);
+                if let FakeReply::Succeed = reply {
+                    let run = self.0.runs_ended.load(Ordering::SeqCst);
+                    self.later(latency, move |vm| vm.end_run(run, FakeEvent::GuestShutdown));
+                }
+                Ok(true)
+            }
+        }
+    }
+
+    unsafe fn state(&self) -> VZVirtualMachineState {
+        strict::on_vm_queue(&self.0.queue, "FakeVm::state");
+        self.peek_state()
+    }
+
+    fn lifecycle(&self) -> Lifecycle {
+        self.0.tracker.lifecycle()
+    }
+
+    fn last_stop_reason(&self) -> Option<StopReason> {
+        self.0.tracker.last_stop_reason()
+    }
+
+    fn error_events(&self) -> ErrorEventReceiver {
+        self.0.error_events.subscribe()
+    }
+
+    /// Runs `f` once, on the queue, the first time the machine is in `state`, including when it
+    /// already is.
+    fn on_first_transition_to<F>(&self, state: VZVirtualMachineState, f: F) -> FakeObservation
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let abandoned = Arc::new(AtomicBool::new(false));
+        lock(&self.0.observers).push(Observer {
+            state,
+            f: Box::new(f),
+            abandoned: abandoned.clone(),
+        });
+        let vm = self.clone();
+        self.0.queue.exec_async(move || vm.notify(vm.peek_state()));
+        FakeObservation(abandoned)
+    }
+
+    fn observed_devices(&self) -> ObservedDevices {
+        self.script().devices.clone().unwrap_or_else(|| {
+            let unscripted = || "not scripted on the fake".to_string();
+            ObservedDevices {
+                storage_devices: Err(unscripted()),
+                network_devices: Err(unscripted()),
+                entropy_devices: Err(unscripted()),
+                memory_balloon_devices: Err(unscripted()),
+                socket_devices: Err(unscripted()),
+                directory_sharing_devices: Err(unscripted()),
+                console_devices: Err(unscripted()),
+            }
+        })
+    }
+}
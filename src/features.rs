@@ -0,0 +1,375 @@
+//! features module
+//!
+//! What this host can run, for universal binaries that decide at runtime instead of shipping one
+//! build per architecture.
+//!
+//! Classes that only exist on Apple silicon are listed in [`ARCH_RESTRICTED`]; their constructors
+//! check it and return [`UnsupportedOnThisHost`] elsewhere instead of aborting.
+//!
+//! Binaries do not link Virtualization.framework strongly, so they load on systems without it,
+//! such as macOS 10.15. The crate loads it on first use; [`is_framework_available`] tells whether
+//! that worked, and [`require_framework`] turns it into a [`NotAvailable`] error to return before
+//! touching anything else. Past that point the wrappers panic with the same message.
+//!
+//! Classes newer than macOS 11 are looked up through a [`ClassLookup`] each, so a constructor
+//! can fail with [`ClassNotAvailable`] on an older system; the `try_new` variants return it, the
+//! others panic with its message.
+//!
+//! # Examples
+//! ```rust
+//! let caps = HostCapabilities::detect();
+//! let pointing: Vec<Box<dyn VZPointingDeviceConfiguration>> = match VZMacTrackpadConfiguration::new() {
+//!     Ok(trackpad) => vec![Box::new(trackpad)],
+//!     Err(_) => vec![Box::new(VZUSBScreenCoordinatePointingDeviceConfiguration::new())],
+//! };
+//! ```
+//!
+//! ```rust
+//! if let Err(e) = features::require_framework() {
+//!     // virtualization unavailable on this system: ...
+//!     eprintln!("{}", e);
+//!     return;
+//! }
+//! ```
+
+use crate::base::{host_arch, HostArch, Id, NSOperatingSystemVersion};
+use crate::runtime::{from_objc_bool, vz_class};
+
+use std::ffi::CString;
+use std::fmt;
+use std::os::raw::c_void;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Once, OnceLock};
+
+use objc::runtime::{Class, BOOL};
+use objc::{class, msg_send, sel, sel_impl};
+
+/// The framework's binary, loaded at runtime. On current macOS it lives in the dyld shared cache
+/// rather than on disk, which `dlopen` handles.
+pub const FRAMEWORK_PATH: &str =
+    "/System/Library/Frameworks/Virtualization.framework/Virtualization";
+
+/// A class every version of the framework has.
+const PROBE_CLASS: &str = "VZVirtualMachine";
+
+static LOAD: Once = Once::new();
+static LOADED: AtomicBool = AtomicBool::new(false);
+
+/// Virtualization.framework is missing from this system, e.g. before macOS 11, or the crate was
+/// built for another operating system.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NotAvailable;
+
+impl fmt::Display for NotAvailable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "virtualization unavailable on this system: {} cannot be loaded",
+            FRAMEWORK_PATH
+        )
+    }
+}
+
+impl std::error::Error for NotAvailable {}
+
+/// Loads Virtualization.framework if the binary did not, once per process, and reports whether
+/// its classes are registered.
+pub fn is_framework_available() -> bool {
+    LOAD.call_once(|| {
+        let path = CString::new(FRAMEWORK_PATH).unwrap();
+        let handle = unsafe { libc::dlopen(path.as_ptr(), libc::RTLD_LAZY | libc::RTLD_GLOBAL) };
+        // The handle stays open: the framework's classes cannot be unloaded anyway.
+        let loaded = !handle.is_null() && Class::get(PROBE_CLASS).is_some();
+        LOADED.store(loaded, Ordering::Release);
+    });
+    LOADED.load(Ordering::Acquire)
+}
+
+/// Fails with [`NotAvailable`] unless [`is_framework_available`].
+pub fn require_framework() -> Result<(), NotAvailable> {
+    if is_framework_available() {
+        Ok(())
+    } else {
+        Err(NotAvailable)
+    }
+}
+
+/// Guard of every framework class lookup, through `runtime::vz_class!`.
+#[track_caller]
+pub(crate) fn expect_framework() {
+    if let Err(e) = require_framework() {
+        panic!("{}", e);
+    }
+}
+
+/// A framework class missing from the running system, which predates it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClassNotAvailable {
+    pub class: &'static str,
+    /// The first system providing the class, e.g. "macOS 13".
+    pub since: &'static str,
+}
+
+impl fmt::Display for ClassNotAvailable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} is not available on this system, it needs {}",
+            self.class, self.since
+        )
+    }
+}
+
+impl std::error::Error for ClassNotAvailable {}
+
+/// A framework class looked up by name the first time it is needed, for classes that are missing
+/// on some supported systems. The result, found or not, is cached for the life of the process.
+///
+/// # Examples
+/// ```rust
+/// static USB_KEYBOARD: ClassLookup = ClassLookup::new("VZUSBKeyboardConfiguration", "macOS 12");
+///
+/// match USB_KEYBOARD.get() {
+///     Ok(class) => { /* alloc, init... */ }
+///     // VZUSBKeyboardConfiguration is not available on this system, it needs macOS 12
+///     Err(e) => eprintln!("{}", e),
+/// }
+/// ```
+pub struct ClassLookup {
+    name: &'static str,
+    since: &'static str,
+    class: OnceLock<Option<&'static Class>>,
+}
+
+impl ClassLookup {
+    pub const fn new(name: &'static str, since: &'static str) -> ClassLookup {
+        ClassLookup {
+            name,
+            since,
+            class: OnceLock::new(),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// Fails without the framework too, as on macOS 10.15.
+    pub fn get(&self) -> Result<&'static Class, ClassNotAvailable> {
+        let class = self.class.get_or_init(|| {
+            if is_framework_available() {
+                Class::get(self.name)
+            } else {
+                None
+            }
+        });
+        class.ok_or(ClassNotAvailable {
+            class: self.name,
+            since: self.since,
+        })
+    }
+
+    pub fn is_available(&self) -> bool {
+        self.get().is_ok()
+    }
+
+    /// [`get`](Self::get) for constructors that cannot fail, panicking with the
+    /// [`ClassNotAvailable`] message.
+    #[track_caller]
+    pub(crate) fn expect(&self) -> &'static Class {
+        self.get().unwrap_or_else(|e| panic!("{}", e))
+    }
+}
+
+/// The address of the framework's exported `symbol`, or `None` without the framework.
+pub(crate) fn framework_symbol(symbol: &str) -> Option<*const c_void> {
+    if !is_framework_available() {
+        return None;
+    }
+    let symbol = CString::new(symbol).ok()?;
+    let p = unsafe { libc::dlsym(libc::RTLD_DEFAULT, symbol.as_ptr()) };
+    if p.is_null() {
+        None
+    } else {
+        Some(p as *const c_void)
+    }
+}
+
+/// A macOS release, e.g. `14.2.1`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct OsVersion {
+    pub major: u32,
+    pub minor: u32,
+    pub patch: u32,
+}
+
+impl OsVersion {
+    pub const fn new(major: u32, minor: u32, patch: u32) -> OsVersion {
+        OsVersion {
+            major,
+            minor,
+            patch,
+        }
+    }
+}
+
+impl fmt::Display for OsVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)
+    }
+}
+
+/// The version of the running macOS, read once per process from `NSProcessInfo`, which does not
+/// need the framework.
+pub fn os_version() -> OsVersion {
+    static VERSION: OnceLock<OsVersion> = OnceLock::new();
+    *VERSION.get_or_init(|| {
+        let version: NSOperatingSystemVersion = unsafe {
+            let info: Id = msg_send![class!(NSProcessInfo), processInfo];
+            msg_send![info, operatingSystemVersion]
+        };
+        OsVersion::new(
+            version.major_version as u32,
+            version.minor_version as u32,
+            version.patch_version as u32,
+        )
+    })
+}
+
+/// Classes the framework only provides on one architecture, with that architecture.
+///
+/// Sourced from the `#if defined(__arm64__)` sections of the framework headers: every `VZMac...`
+/// class for macOS guests, and the Rosetta directory share with its caching options.
+pub const ARCH_RESTRICTED: &[(&str, HostArch)] = &[
+    ("VZMacGraphicsDeviceConfiguration", HostArch::AppleSilicon),
+    ("VZMacGraphicsDisplayConfiguration", HostArch::AppleSilicon),
+    ("VZMacTrackpadConfiguration", HostArch::AppleSilicon),
+    ("VZMacOSRestoreImage", HostArch::AppleSilicon),
+    ("VZMacHardwareModel", HostArch::AppleSilicon),
+    ("VZMacAuxiliaryStorage", HostArch::AppleSilicon),
+    ("VZMacMachineIdentifier", HostArch::AppleSilicon),
+    ("VZMacOSBootLoader", HostArch::AppleSilicon),
+    ("VZMacOSInstaller", HostArch::AppleSilicon),
+    ("VZMacPlatformConfiguration", HostArch::AppleSilicon),
+    ("VZMacOSVirtualMachineStartOptions", HostArch::AppleSilicon),
+    ("VZMacOSConfigurationRequirements", HostArch::AppleSilicon),
+    ("VZLinuxRosettaDirectoryShare", HostArch::AppleSilicon),
+    ("VZLinuxRosettaCachingOptions", HostArch::AppleSilicon),
+    (
+        "VZLinuxRosettaUnixSocketCachingOptions",
+        HostArch::AppleSilicon,
+    ),
+    (
+        "VZLinuxRosettaAbstractSocketCachingOptions",
+        HostArch::AppleSilicon,
+    ),
+];
+
+/// An architecture-restricted class was used on a host that does not provide it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UnsupportedOnThisHost {
+    pub class: &'static str,
+    pub required: HostArch,
+    pub host: HostArch,
+}
+
+impl fmt::Display for UnsupportedOnThisHost {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} requires an {} host, this is an {} host",
+            self.class, self.required, self.host
+        )
+    }
+}
+
+/// Fails if `class` is restricted to another architecture than `host`'s, or is missing from the
+/// running framework, e.g. in an x86_64 process translated by Rosetta.
+pub(crate) fn check_class(class: &'static str, host: HostArch) -> Result<(), UnsupportedOnThisHost> {
+    let required = match ARCH_RESTRICTED.iter().find(|(name, _)| *name == class) {
+        Some(&(_, required)) => required,
+        None => return Ok(()),
+    };
+    if required == host && is_framework_available() && Class::get(class).is_some() {
+        Ok(())
+    } else {
+        Err(UnsupportedOnThisHost {
+            class,
+            required,
+            host,
+        })
+    }
+}
+
+/// Guard for constructors of [`ARCH_RESTRICTED`] classes.
+pub(crate) fn require(class: &'static str) -> Result<(), UnsupportedOnThisHost> {
+    check_class(class, host_arch())
+}
+
+/// Which kinds of Rosetta caching options a host provides, from [`HostCapabilities::rosetta_caching`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct RosettaCaching {
+    /// `VZLinuxRosettaUnixSocketCachingOptions`, macOS 14 and later.
+    pub unix_socket: bool,
+    /// `VZLinuxRosettaAbstractSocketCachingOptions`, macOS 14 and later.
+    pub abstract_socket: bool,
+}
+
+/// What this host can run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct HostCapabilities {
+    pub arch: HostArch,
+    /// Whether the host supports virtualization at all.
+    pub virtualization_supported: bool,
+    pub maximum_cpu_count: usize,
+    /// In bytes.
+    pub maximum_memory_size: u64,
+}
+
+impl HostCapabilities {
+    /// Without the framework, as on macOS 10.15, the host supports no virtualization at all.
+    pub fn detect() -> HostCapabilities {
+        if !is_framework_available() {
+            return HostCapabilities {
+                arch: host_arch(),
+                virtualization_supported: false,
+                maximum_cpu_count: 0,
+                maximum_memory_size: 0,
+            };
+        }
+        unsafe {
+            let supported: BOOL = msg_send![vz_class!(VZVirtualMachine), isSupported];
+            let maximum_cpu_count: usize = msg_send![
+                vz_class!(VZVirtualMachineConfiguration),
+                maximumAllowedCPUCount
+            ];
+            let maximum_memory_size: u64 = msg_send![
+                vz_class!(VZVirtualMachineConfiguration),
+                maximumAllowedMemorySize
+            ];
+            HostCapabilities {
+                arch: host_arch(),
+                virtualization_supported: from_objc_bool(supported),
+                maximum_cpu_count,
+                maximum_memory_size,
+            }
+        }
+    }
+
+    /// Whether `class` can be used on this host: it is not restricted to another architecture and
+    /// the running framework has it, which rules out classes newer than the host's macOS.
+    pub fn supports_class(&self, class: &'static str) -> bool {
+        check_class(class, self.arch).is_ok()
+            && is_framework_available()
+            && Class::get(class).is_some()
+    }
+
+    /// The Rosetta caching option classes this host provides, all `false` on Intel Macs.
+    pub fn rosetta_caching(&self) -> RosettaCaching {
+        RosettaCaching {
+            unix_socket: self.supports_class("VZLinuxRosettaUnixSocketCachingOptions"),
+            abstract_socket: self.supports_class("VZLinuxRosettaAbstractSocketCachingOptions"),
+        }
+    }
+}
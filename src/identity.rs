@@ -0,0 +1,239 @@
+//! identity module
+//!
+//! Keeps the persistent identity of a virtual machine — generic machine identifier, MAC address
+//! and EFI variable store — together in one directory, so the guest sees the same machine on
+//! every boot.
+//!
+//! # Examples
+//! ```rust
+//! let dir = Path::new("vms/dev");
+//! let identity = match VmIdentity::load(dir) {
+//!     Ok(identity) => identity,
+//!     Err(IdentityError::Io(e)) if e.kind() == io::ErrorKind::NotFound => {
+//!         let identity = VmIdentity::generate(dir)?;
+//!         identity.save(dir)?;
+//!         identity
+//!     }
+//!     Err(e) => return Err(e),
+//! };
+//! let conf = identity
+//!     .apply(VZVirtualMachineConfigurationBuilder::new().network_devices(vec![network]))?
+//!     .cpu_count(2)
+//!     .build();
+//! ```
+
+use crate::virtualization::boot_loader::{
+    VZEFIBootLoaderBuilder, VZEFIVariableStore, VZEFIVariableStoreInitializationOptions,
+};
+use crate::virtualization::error::{FrozenConfigError, VZErrorCtx};
+use crate::virtualization::network_device::VZMACAddress;
+use crate::virtualization::platform::{VZGenericMachineIdentifier, VZGenericPlatformConfiguration};
+use crate::virtualization::virtual_machine::VZVirtualMachineConfigurationBuilder;
+
+use std::fmt::Write as _;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+/// Name of the identity file inside the identity directory.
+const IDENTITY_FILE: &str = "identity";
+/// Name the identity file is written under before it is renamed into place.
+const IDENTITY_TEMP_FILE: &str = "identity.tmp";
+/// Name of the EFI variable store created by [`VmIdentity::generate`].
+const EFI_STORE_FILE: &str = "efi-variables";
+const FORMAT_VERSION: u32 = 1;
+
+/// Why an identity could not be created, read or applied.
+pub enum IdentityError {
+    Io(io::Error),
+    /// The identity file is truncated, fails its checksum or holds invalid values. It is left as
+    /// is; regenerating would give the guest a different machine.
+    Corrupted(String),
+    /// The identity file was written by a newer version of this crate.
+    UnsupportedVersion(u32),
+    /// [`VmIdentity::apply`] found no network device to give the MAC address to.
+    NoNetworkDevice,
+    /// The framework refused to create the EFI variable store.
+    Framework(VZErrorCtx),
+    /// [`VmIdentity::apply`] was given a builder whose first network device is already part of a
+    /// virtual machine.
+    Frozen(FrozenConfigError),
+}
+
+impl From<io::Error> for IdentityError {
+    fn from(e: io::Error) -> Self {
+        IdentityError::Io(e)
+    }
+}
+
+impl From<FrozenConfigError> for IdentityError {
+    fn from(e: FrozenConfigError) -> Self {
+        IdentityError::Frozen(e)
+    }
+}
+
+/// The persistent identity of a virtual machine.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VmIdentity {
+    /// Data representation of a `VZGenericMachineIdentifier`.
+    pub machine_id: Vec<u8>,
+    pub mac: String,
+    pub efi_store: PathBuf,
+}
+
+impl VmIdentity {
+    /// Creates a new machine identifier, a random locally administered MAC address and a fresh
+    /// EFI variable store in `dir`. Nothing is saved; call [`VmIdentity::save`].
+    pub fn generate(dir: &Path) -> Result<VmIdentity, IdentityError> {
+        fs::create_dir_all(dir)?;
+        let efi_store = dir.join(EFI_STORE_FILE);
+        let efi_path = efi_store
+            .to_str()
+            .ok_or_else(|| IdentityError::Corrupted("directory path is not UTF-8".to_string()))?;
+        VZEFIVariableStore::create(efi_path, VZEFIVariableStoreInitializationOptions::new())
+            .map_err(IdentityError::Framework)?;
+        Ok(VmIdentity {
+            machine_id: VZGenericMachineIdentifier::new().data_representation(),
+            mac: VZMACAddress::random_locally_administered_address().string(),
+            efi_store,
+        })
+    }
+
+    /// Reads the identity saved in `dir`.
+    pub fn load(dir: &Path) -> Result<VmIdentity, IdentityError> {
+        VmIdentity::decode(&fs::read_to_string(dir.join(IDENTITY_FILE))?)
+    }
+
+    /// Saves the identity in `dir`. The file is written next to its final name, flushed and
+    /// renamed into place, so a crash leaves either the old or the new identity.
+    pub fn save(&self, dir: &Path) -> Result<(), IdentityError> {
+        let contents = self.encode()?;
+        let temp = dir.join(IDENTITY_TEMP_FILE);
+        let mut file = File::create(&temp)?;
+        file.write_all(contents.as_bytes())?;
+        file.sync_all()?;
+        drop(file);
+        fs::rename(&temp, dir.join(IDENTITY_FILE))?;
+        File::open(dir)?.sync_all()?;
+        Ok(())
+    }
+
+    /// Sets the machine identifier through a generic platform, the MAC address of the first
+    /// network device and an EFI boot loader using the variable store. Add the network devices
+    /// before calling this; a boot loader set earlier is replaced.
+    pub fn apply(
+        &self,
+        mut builder: VZVirtualMachineConfigurationBuilder,
+    ) -> Result<VZVirtualMachineConfigurationBuilder, IdentityError> {
+        let identifier = VZGenericMachineIdentifier::from_data_representation(&self.machine_id)
+            .ok_or_else(|| IdentityError::Corrupted("invalid machine identifier".to_string()))?;
+        let mac = VZMACAddress::init_with_string(&self.mac)
+            .ok_or_else(|| IdentityError::Corrupted(format!("invalid MAC address {}", self.mac)))?;
+        if !builder.set_first_network_device_mac(&mac)? {
+            return Err(IdentityError::NoNetworkDevice);
+        }
+        let efi_path = self.efi_store.to_str().ok_or_else(|| {
+            IdentityError::Corrupted("EFI variable store path is not UTF-8".to_string())
+        })?;
+        if !self.efi_store.is_file() {
+            return Err(IdentityError::Io(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("EFI variable store {} is missing", efi_path),
+            )));
+        }
+
+        let variable_store = VZEFIVariableStore::open(efi_path)
+            .map_err(|e| IdentityError::Corrupted(e.to_string()))?;
+
+        let mut platform = VZGenericPlatformConfiguration::new();
+        platform.set_machine_identifier(&identifier);
+        let boot_loader = VZEFIBootLoaderBuilder::new()
+            .with_variable_store(variable_store)
+            .build();
+        Ok(builder.platform(platform).boot_loader(boot_loader))
+    }
+
+    /// `key value` lines followed by a checksum line over everything before it.
+    fn encode(&self) -> Result<String, IdentityError> {
+        let efi_store = self.efi_store.to_str().ok_or_else(|| {
+            IdentityError::Corrupted("EFI variable store path is not UTF-8".to_string())
+        })?;
+        if efi_store.contains('\n') || self.mac.contains('\n') {
+            return Err(IdentityError::Corrupted(
+                "values must not contain line breaks".to_string(),
+            ));
+        }
+        let mut body = String::new();
+        let _ = writeln!(body, "version {}", FORMAT_VERSION);
+        let _ = writeln!(body, "machine_id {}", hex(&self.machine_id));
+        let _ = writeln!(body, "mac {}", self.mac);
+        let _ = writeln!(body, "efi_store {}", efi_store);
+        let _ = writeln!(body, "checksum {:016x}", fnv1a(body.as_bytes()));
+        Ok(body)
+    }
+
+    fn decode(contents: &str) -> Result<VmIdentity, IdentityError> {
+        let corrupted = |what: &str| IdentityError::Corrupted(what.to_string());
+        let checksum_at = contents
+            .rfind("checksum ")
+            .ok_or_else(|| corrupted("missing checksum, the file is truncated"))?;
+        let (body, checksum_line) = contents.split_at(checksum_at);
+        let checksum = checksum_line["checksum ".len()..].trim_end_matches('\n');
+        if u64::from_str_radix(checksum, 16).ok() != Some(fnv1a(body.as_bytes())) {
+            return Err(corrupted("checksum mismatch"));
+        }
+
+        let mut version = None;
+        let mut machine_id = None;
+        let mut mac = None;
+        let mut efi_store = None;
+        for line in body.lines() {
+            let (key, value) = match line.find(' ') {
+                Some(i) => (&line[..i], &line[i + 1..]),
+                None => return Err(corrupted("malformed line")),
+            };
+            match key {
+                "version" => {
+                    version = Some(value.parse::<u32>().map_err(|_| corrupted("bad version"))?)
+                }
+                "machine_id" => {
+                    machine_id = Some(unhex(value).ok_or_else(|| corrupted("bad machine_id"))?)
+                }
+                "mac" => mac = Some(value.to_string()),
+                "efi_store" => efi_store = Some(PathBuf::from(value)),
+                // Keys added by later versions of the same format.
+                _ => {}
+            }
+        }
+        let version = version.ok_or_else(|| corrupted("missing version"))?;
+        if version > FORMAT_VERSION {
+            return Err(IdentityError::UnsupportedVersion(version));
+        }
+        Ok(VmIdentity {
+            machine_id: machine_id.ok_or_else(|| corrupted("missing machine_id"))?,
+            mac: mac.ok_or_else(|| corrupted("missing mac"))?,
+            efi_store: efi_store.ok_or_else(|| corrupted("missing efi_store"))?,
+        })
+    }
+}
+
+/// 64-bit FNV-1a; detects torn writes and hand edits, not tampering.
+pub(crate) fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, b| {
+        (hash ^ u64::from(*b)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn unhex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
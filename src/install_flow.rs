@@ -0,0 +1,687 @@
+//! install flow module
+//!
+//! Installs macOS into a bundle directory as a state machine of four steps, each run by
+//! [`InstallFlow::advance`]:
+//!
+//! | state | step | on failure |
+//! |---|---|---|
+//! | [`FlowState::FetchingImage`] | the restore image is there, or is downloaded | kept: a partial download resumes |
+//! | [`FlowState::ValidatingRequirements`] | the image can be installed with the planned CPUs and memory | nothing to undo |
+//! | [`FlowState::CreatingBundle`] | the [`MacVmBundle`], its auxiliary storage and the disk image are written | removed |
+//! | [`FlowState::Installing`] | the image is installed on the disk | bundle and disk removed |
+//!
+//! The restore image is never removed. A flow created for a bundle directory that holds the
+//! output of earlier steps resumes after them: an image in place skips the download, and a
+//! complete bundle with its disk skips its creation. The requirements are always checked again,
+//! since nothing records them. A finished installation leaves an `Installed` marker, and its
+//! flow starts [`Done`](FlowState::Done).
+//!
+//! The work of each step is behind [`InstallSteps`]. [`FrameworkSteps`] does it with the
+//! framework; tests substitute steps that need no restore image.
+//!
+//! # Examples
+//! ```rust
+//! let flow = InstallFlow::new(InstallPlan {
+//!     ipsw: Source::Latest,
+//!     bundle_dir: PathBuf::from("vms/macos"),
+//!     cpus: 4,
+//!     memory: 8 << 30,
+//!     disk_size: 64 << 30,
+//! });
+//! match flow.run(|state| eprintln!("{}", state)) {
+//!     FlowState::Done => println!("installed"),
+//!     // creating the bundle failed (cleaned up): ...
+//!     state => eprintln!("{}", state),
+//! }
+//! ```
+
+use crate::base::{CancellationToken, DispatchSemaphore};
+use crate::mac_bundle::MacVmBundle;
+use crate::profile::{self, MacOS, ProfileInputs};
+use crate::virtualization::error::CompletionOutcome;
+use crate::virtualization::graphics_device::{
+    VZMacGraphicsDeviceConfiguration, VZMacGraphicsDisplayConfiguration,
+};
+use crate::virtualization::installer::VZMacOSInstaller;
+use crate::virtualization::platform::{
+    VZMacAuxiliaryStorage, VZMacAuxiliaryStorageInitializationOption,
+    VZMacAuxiliaryStorageInitializationOptions, VZMacPlatformConfiguration,
+};
+use crate::virtualization::restore_image::{
+    RequirementViolation, RestoreImageInfo, VZMacHardwareModel, VZMacOSRestoreImage,
+};
+use crate::virtualization::virtual_machine::VZVirtualMachine;
+
+use std::fmt;
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+/// Name of the downloaded restore image in the bundle directory.
+const IMAGE_FILE: &str = "RestoreImage.ipsw";
+const DISK_FILE: &str = "Disk.img";
+const AUXILIARY_STORAGE_FILE: &str = "AuxiliaryStorage";
+/// Written once the installation finished.
+const INSTALLED_FILE: &str = "Installed";
+
+/// How often blocking steps check for cancellation and report progress.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Where the restore image comes from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Source {
+    /// The latest image this host supports, downloaded into the bundle directory. Needs the
+    /// `restore-download` feature.
+    Latest,
+    File(PathBuf),
+}
+
+/// What to install where.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InstallPlan {
+    pub ipsw: Source,
+    pub bundle_dir: PathBuf,
+    pub cpus: usize,
+    /// In bytes.
+    pub memory: u64,
+    /// Size of the disk image to install on, in bytes.
+    pub disk_size: u64,
+}
+
+impl InstallPlan {
+    /// The restore image: the file given, or `RestoreImage.ipsw` in the bundle directory.
+    pub fn image_path(&self) -> PathBuf {
+        match &self.ipsw {
+            Source::Latest => self.bundle_dir.join(IMAGE_FILE),
+            Source::File(path) => path.clone(),
+        }
+    }
+
+    /// `Disk.img` in the bundle directory.
+    pub fn disk_path(&self) -> PathBuf {
+        self.bundle_dir.join(DISK_FILE)
+    }
+
+    pub fn auxiliary_storage_path(&self) -> PathBuf {
+        self.bundle_dir.join(AUXILIARY_STORAGE_FILE)
+    }
+
+    /// Whether the bundle, its auxiliary storage and the disk image are in place.
+    pub fn has_bundle(&self) -> bool {
+        MacVmBundle::load(&self.bundle_dir).is_ok()
+            && self.auxiliary_storage_path().is_file()
+            && self.disk_path().is_file()
+    }
+
+    /// Whether an installation into the bundle directory finished.
+    pub fn is_installed(&self) -> bool {
+        self.bundle_dir.join(INSTALLED_FILE).is_file()
+    }
+
+    /// The state a new flow for this plan starts in, judged from what the bundle directory holds.
+    pub fn resume_point(&self) -> FlowState {
+        if self.is_installed() {
+            FlowState::Done
+        } else if self.image_path().is_file() {
+            FlowState::ValidatingRequirements
+        } else {
+            FlowState::FetchingImage
+        }
+    }
+}
+
+/// A step of the flow, in order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Step {
+    FetchImage,
+    ValidateRequirements,
+    CreateBundle,
+    Install,
+}
+
+impl fmt::Display for Step {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Step::FetchImage => "fetching the restore image",
+            Step::ValidateRequirements => "validating the requirements",
+            Step::CreateBundle => "creating the bundle",
+            Step::Install => "installing",
+        })
+    }
+}
+
+/// Why a step failed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StepError {
+    /// Through [`InstallFlow::cancel`].
+    Cancelled,
+    /// The image cannot be installed with the planned CPUs and memory, or on this host.
+    Requirements(Vec<RequirementViolation>),
+    Failed(String),
+}
+
+impl fmt::Display for StepError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StepError::Cancelled => write!(f, "cancelled"),
+            StepError::Requirements(violations) => {
+                let violations: Vec<String> = violations.iter().map(ToString::to_string).collect();
+                write!(f, "{}", violations.join("; "))
+            }
+            StepError::Failed(message) => f.write_str(message),
+        }
+    }
+}
+
+impl std::error::Error for StepError {}
+
+fn failed<E: fmt::Display>(context: &str) -> impl FnOnce(E) -> StepError + '_ {
+    move |e| StepError::Failed(format!("{}: {}", context, e))
+}
+
+/// Where a flow is: the step it runs next, or how it ended.
+#[derive(Debug, Clone, PartialEq)]
+pub enum FlowState {
+    FetchingImage,
+    ValidatingRequirements,
+    CreatingBundle,
+    /// With the fraction installed, between 0 and 1.
+    Installing {
+        progress: f64,
+    },
+    Done,
+    /// `cleanup_performed` if what the step wrote was removed, as the table in the
+    /// [module documentation](self) says.
+    Failed {
+        step: Step,
+        error: StepError,
+        cleanup_performed: bool,
+    },
+}
+
+impl FlowState {
+    /// Whether the flow is done or failed.
+    pub fn is_finished(&self) -> bool {
+        matches!(self, FlowState::Done | FlowState::Failed { .. })
+    }
+
+    /// The step this state runs next, if any.
+    pub fn step(&self) -> Option<Step> {
+        match self {
+            FlowState::FetchingImage => Some(Step::FetchImage),
+            FlowState::ValidatingRequirements => Some(Step::ValidateRequirements),
+            FlowState::CreatingBundle => Some(Step::CreateBundle),
+            FlowState::Installing { .. } => Some(Step::Install),
+            FlowState::Done | FlowState::Failed { .. } => None,
+        }
+    }
+}
+
+/// `installing (42%)`, `creating the bundle failed (cleaned up): ...`.
+impl fmt::Display for FlowState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FlowState::Installing { progress } => {
+                write!(f, "installing ({:.0}%)", progress * 100.0)
+            }
+            FlowState::Done => write!(f, "done"),
+            FlowState::Failed {
+                step,
+                error,
+                cleanup_performed,
+            } => write!(
+                f,
+                "{} failed{}: {}",
+                step,
+                if *cleanup_performed {
+                    " (cleaned up)"
+                } else {
+                    ""
+                },
+                error
+            ),
+            state => write!(f, "{}", state.step().unwrap()),
+        }
+    }
+}
+
+/// The work of each step, run on a thread of the flow's own. A step returns once it is done,
+/// and gives up with [`StepError::Cancelled`] once `cancel` is cancelled.
+pub trait InstallSteps: Send {
+    /// Puts the restore image at [`InstallPlan::image_path`].
+    fn fetch_image(
+        &mut self,
+        plan: &InstallPlan,
+        cancel: &CancellationToken,
+    ) -> Result<(), StepError>;
+
+    /// Checks that the image can be installed on this host with the planned CPUs and memory.
+    fn validate_requirements(&mut self, plan: &InstallPlan) -> Result<(), StepError>;
+
+    /// Writes the bundle, its auxiliary storage and an empty disk image of the planned size into
+    /// the bundle directory. Runs after [`validate_requirements`](Self::validate_requirements).
+    fn create_bundle(&mut self, plan: &InstallPlan) -> Result<(), StepError>;
+
+    /// Installs the image on the bundle's disk, calling `progress` with the fraction done.
+    fn install(
+        &mut self,
+        plan: &InstallPlan,
+        progress: &mut dyn FnMut(f64),
+        cancel: &CancellationToken,
+    ) -> Result<(), StepError>;
+}
+
+/// Why [`InstallFlow::advance`] did not start a step.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AdvanceError {
+    /// A step is running.
+    InProgress,
+    /// The installation finished.
+    Done,
+}
+
+impl fmt::Display for AdvanceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AdvanceError::InProgress => write!(f, "a step of the install flow is running"),
+            AdvanceError::Done => write!(f, "the install flow is done"),
+        }
+    }
+}
+
+impl std::error::Error for AdvanceError {}
+
+struct Shared {
+    plan: InstallPlan,
+    steps: Mutex<Box<dyn InstallSteps>>,
+    state: Mutex<FlowState>,
+    /// The token of the step running, if any.
+    running: Mutex<Option<CancellationToken>>,
+}
+
+/// Installs macOS step by step; see the [module documentation](self).
+#[derive(Clone)]
+pub struct InstallFlow {
+    shared: Arc<Shared>,
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+impl InstallFlow {
+    /// A flow running `plan` with [`FrameworkSteps`], starting at its
+    /// [`resume_point`](InstallPlan::resume_point).
+    pub fn new(plan: InstallPlan) -> InstallFlow {
+        InstallFlow::with_steps(plan, Box::new(FrameworkSteps::default()))
+    }
+
+    /// A flow running `plan` with `steps`.
+    pub fn with_steps(plan: InstallPlan, steps: Box<dyn InstallSteps>) -> InstallFlow {
+        InstallFlow {
+            shared: Arc::new(Shared {
+                state: Mutex::new(plan.resume_point()),
+                plan,
+                steps: Mutex::new(steps),
+                running: Mutex::new(None),
+            }),
+        }
+    }
+
+    pub fn plan(&self) -> &InstallPlan {
+        &self.shared.plan
+    }
+
+    pub fn state(&self) -> FlowState {
+        lock(&self.shared.state).clone()
+    }
+
+    /// Runs the next step on a new thread. `on_state` is called there with the progress of an
+    /// installation and, last, with the state the step led to.
+    ///
+    /// After a failure, the flow resumes as a new one would, from what the bundle directory
+    /// holds. Fails without calling `on_state` while a step runs or once the flow is done.
+    pub fn advance<F>(&self, on_state: F) -> Result<(), AdvanceError>
+    where
+        F: FnMut(FlowState) + Send + 'static,
+    {
+        self.spawn(on_state).map(drop)
+    }
+
+    /// Advances until the flow is done or fails, and returns how it ended. `on_state` gets every
+    /// state on the way, on the flow's threads.
+    ///
+    /// Panics if a step is running.
+    pub fn run<F>(&self, on_state: F) -> FlowState
+    where
+        F: FnMut(FlowState) + Send + 'static,
+    {
+        let on_state = Arc::new(Mutex::new(on_state));
+        loop {
+            let on_state = on_state.clone();
+            match self.spawn(move |state| (*lock(&on_state))(state)) {
+                Ok(thread) => thread.join().expect("the install flow thread panicked"),
+                Err(AdvanceError::Done) => return FlowState::Done,
+                Err(AdvanceError::InProgress) => panic!("InstallFlow::run while a step runs"),
+            }
+            let state = self.state();
+            if state.is_finished() {
+                return state;
+            }
+        }
+    }
+
+    fn spawn<F>(&self, mut on_state: F) -> Result<JoinHandle<()>, AdvanceError>
+    where
+        F: FnMut(FlowState) + Send + 'static,
+    {
+        let cancel = CancellationToken::new();
+        {
+            let mut running = lock(&self.shared.running);
+            if running.is_some() {
+                return Err(AdvanceError::InProgress);
+            }
+            let mut state = lock(&self.shared.state);
+            if let FlowState::Failed { .. } = *state {
+                *state = self.shared.plan.resume_point();
+            }
+            if *state == FlowState::Done {
+                return Err(AdvanceError::Done);
+            }
+            *running = Some(cancel.clone());
+        }
+        let shared = self.shared.clone();
+        let thread = thread::Builder::new()
+            .name("install-flow".to_string())
+            .spawn(move || {
+                let state = shared.run_step(&cancel, &mut on_state);
+                *lock(&shared.state) = state.clone();
+                *lock(&shared.running) = None;
+                on_state(state);
+            })
+            .expect("failed to spawn the install flow thread");
+        Ok(thread)
+    }
+
+    /// Cancels the step running, if any: it fails with [`StepError::Cancelled`] and is cleaned
+    /// up as any failure of it.
+    pub fn cancel(&self) {
+        if let Some(cancel) = &*lock(&self.shared.running) {
+            cancel.cancel();
+        }
+    }
+}
+
+impl Shared {
+    /// Runs the step of the current state and returns the state it leads to.
+    fn run_step(
+        &self,
+        cancel: &CancellationToken,
+        on_state: &mut dyn FnMut(FlowState),
+    ) -> FlowState {
+        let plan = &self.plan;
+        let step = match lock(&self.state).step() {
+            Some(step) => step,
+            None => return FlowState::Done,
+        };
+        let mut steps = lock(&self.steps);
+        let result = if cancel.is_cancelled() {
+            Err(StepError::Cancelled)
+        } else {
+            match step {
+                Step::FetchImage => steps.fetch_image(plan, cancel),
+                Step::ValidateRequirements => steps.validate_requirements(plan),
+                Step::CreateBundle => steps.create_bundle(plan),
+                Step::Install => steps
+                    .install(
+                        plan,
+                        &mut |progress| on_state(FlowState::Installing { progress }),
+                        cancel,
+                    )
+                    .and_then(|()| {
+                        File::create(plan.bundle_dir.join(INSTALLED_FILE))
+                            .and_then(|file| file.sync_all())
+                            .map_err(failed("marking the installation finished"))
+                    }),
+            }
+        };
+        match (step, result) {
+            (_, Err(error)) => FlowState::Failed {
+                step,
+                cleanup_performed: clean_up(plan, step),
+                error,
+            },
+            (Step::FetchImage, Ok(())) => FlowState::ValidatingRequirements,
+            (Step::ValidateRequirements, Ok(())) if plan.has_bundle() => {
+                FlowState::Installing { progress: 0.0 }
+            }
+            (Step::ValidateRequirements, Ok(())) => FlowState::CreatingBundle,
+            (Step::CreateBundle, Ok(())) => FlowState::Installing { progress: 0.0 },
+            (Step::Install, Ok(())) => FlowState::Done,
+        }
+    }
+}
+
+/// Undoes what a failed `step` wrote, as far as its contract says; whether it removed anything.
+fn clean_up(plan: &InstallPlan, step: Step) -> bool {
+    match step {
+        // A partial download stays for the next attempt to resume.
+        Step::FetchImage | Step::ValidateRequirements => false,
+        // A half-written or half-installed bundle cannot be resumed; the next attempt creates a
+        // new one.
+        Step::CreateBundle | Step::Install => {
+            let removed = MacVmBundle::remove(&plan.bundle_dir)
+                .and_then(|()| remove_if_exists(&plan.disk_path()))
+                .and_then(|()| remove_if_exists(&plan.bundle_dir.join(INSTALLED_FILE)));
+            removed.is_ok()
+        }
+    }
+}
+
+fn remove_if_exists(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+/// The steps done with the framework. Needs an Apple silicon host.
+#[derive(Default)]
+pub struct FrameworkSteps {
+    /// The data representation of the hardware model the image needs, from the requirements
+    /// check.
+    hardware_model: Option<Vec<u8>>,
+}
+
+fn step_result<T>(outcome: CompletionOutcome<T>) -> Result<T, StepError> {
+    match outcome {
+        CompletionOutcome::Success(value) => Ok(value),
+        CompletionOutcome::Cancelled => Err(StepError::Cancelled),
+        CompletionOutcome::Failed(e) => Err(StepError::Failed(e.0.to_string())),
+    }
+}
+
+/// Waits for `outcome` to be set, calling `tick` with whether `cancel` is cancelled between waits.
+fn wait_for<T>(
+    done: &DispatchSemaphore,
+    outcome: &Mutex<Option<Result<T, StepError>>>,
+    cancel: &CancellationToken,
+    mut tick: impl FnMut(bool),
+) -> Result<T, StepError> {
+    while !done.wait_timeout(POLL_INTERVAL) {
+        tick(cancel.is_cancelled());
+    }
+    lock(outcome)
+        .take()
+        .unwrap_or_else(|| Err(StepError::Failed("no outcome".to_string())))
+}
+
+/// Calls the completion-handler API `start` and waits for its outcome.
+fn complete<T: Send + 'static, S>(start: S) -> Result<T, StepError>
+where
+    S: FnOnce(Box<dyn FnOnce(CompletionOutcome<T>) + Send>) -> Result<(), StepError>,
+{
+    let done = Arc::new(DispatchSemaphore::new(0));
+    let outcome = Arc::new(Mutex::new(None));
+    let (signal, slot) = (done.clone(), outcome.clone());
+    start(Box::new(move |result| {
+        *lock(&slot) = Some(step_result(result));
+        signal.signal();
+    }))?;
+    wait_for(&done, &outcome, &CancellationToken::new(), |_| {})
+}
+
+impl InstallSteps for FrameworkSteps {
+    fn fetch_image(
+        &mut self,
+        plan: &InstallPlan,
+        cancel: &CancellationToken,
+    ) -> Result<(), StepError> {
+        let path = plan.image_path();
+        if path.is_file() {
+            return Ok(());
+        }
+        match &plan.ipsw {
+            Source::File(_) => Err(StepError::Failed(format!(
+                "no restore image at {}",
+                path.display()
+            ))),
+            Source::Latest => fetch_latest(&path, cancel),
+        }
+    }
+
+    fn validate_requirements(&mut self, plan: &InstallPlan) -> Result<(), StepError> {
+        let image = complete(|done| {
+            VZMacOSRestoreImage::load_file(plan.image_path(), done)
+                .map_err(failed("loading the restore image"))
+        })?;
+        let violations = RestoreImageInfo::from_image(&image).check_against(plan.cpus, plan.memory);
+        if !violations.is_empty() {
+            return Err(StepError::Requirements(violations));
+        }
+        let requirements =
+            image
+                .most_featureful_supported_configuration()
+                .ok_or(StepError::Requirements(vec![
+                    RequirementViolation::UnsupportedHost,
+                ]))?;
+        self.hardware_model = Some(requirements.hardware_model().data_representation());
+        Ok(())
+    }
+
+    fn create_bundle(&mut self, plan: &InstallPlan) -> Result<(), StepError> {
+        let model = self
+            .hardware_model
+            .as_ref()
+            .and_then(|data| VZMacHardwareModel::from_data(data))
+            .ok_or_else(|| StepError::Failed("no hardware model to create".to_string()))?;
+        let dir = &plan.bundle_dir;
+        let bundle = MacVmBundle::create(dir, &model).map_err(failed("creating the bundle"))?;
+        bundle.save(dir).map_err(failed("saving the bundle"))?;
+        let options = VZMacAuxiliaryStorageInitializationOptions::new()
+            .with(VZMacAuxiliaryStorageInitializationOption::allow_overwrite());
+        VZMacAuxiliaryStorage::create(&bundle.auxiliary_storage, &model, options)
+            .map_err(failed("creating the auxiliary storage"))?;
+        File::create(plan.disk_path())
+            .and_then(|disk| disk.set_len(plan.disk_size))
+            .map_err(failed("creating the disk image"))
+    }
+
+    fn install(
+        &mut self,
+        plan: &InstallPlan,
+        progress: &mut dyn FnMut(f64),
+        cancel: &CancellationToken,
+    ) -> Result<(), StepError> {
+        let vm = install_machine(plan)?;
+        let installer = VZMacOSInstaller::new(&vm, plan.image_path())
+            .map_err(failed("creating the installer"))?;
+        let done = Arc::new(DispatchSemaphore::new(0));
+        let outcome = Arc::new(Mutex::new(None));
+        let (signal, slot) = (done.clone(), outcome.clone());
+        installer.install(move |result| {
+            *lock(&slot) = Some(step_result(result));
+            signal.signal();
+        });
+        let mut cancelled = false;
+        let result = wait_for(&done, &outcome, cancel, |cancel| {
+            if cancel && !cancelled {
+                cancelled = true;
+                installer.cancel();
+            }
+            progress(installer.fraction_completed());
+        });
+        if result.is_ok() {
+            progress(1.0);
+        }
+        result
+    }
+}
+
+#[cfg(feature = "restore-download")]
+fn fetch_latest(path: &Path, cancel: &CancellationToken) -> Result<(), StepError> {
+    use crate::virtualization::restore_image::Downloader;
+
+    let image = complete(|done| {
+        VZMacOSRestoreImage::fetch_latest_supported(done)
+            .map_err(failed("fetching the latest restore image"))
+    })?;
+    let handle = Downloader::new()
+        .download(&image.url(), path)
+        .map_err(failed("downloading the restore image"))?;
+    while !handle.wait_timeout(POLL_INTERVAL) {
+        if cancel.is_cancelled() {
+            handle.cancel();
+        }
+    }
+    match handle.wait() {
+        Ok(_) => Ok(()),
+        Err(_) if cancel.is_cancelled() => Err(StepError::Cancelled),
+        Err(e) => Err(failed("downloading the restore image")(e)),
+    }
+}
+
+#[cfg(not(feature = "restore-download"))]
+fn fetch_latest(_: &Path, _: &CancellationToken) -> Result<(), StepError> {
+    Err(StepError::Failed(
+        "fetching the latest restore image needs the restore-download feature".to_string(),
+    ))
+}
+
+/// A machine of the bundle's platform with the planned CPUs and memory, booting from the disk.
+fn install_machine(plan: &InstallPlan) -> Result<VZVirtualMachine, StepError> {
+    let bundle = MacVmBundle::load(&plan.bundle_dir).map_err(failed("loading the bundle"))?;
+    let model = bundle
+        .hardware_model()
+        .map_err(failed("loading the bundle"))?;
+    let identifier = bundle
+        .machine_identifier()
+        .map_err(failed("loading the bundle"))?;
+    let storage = VZMacAuxiliaryStorage::open_existing(&bundle.auxiliary_storage)
+        .map_err(failed("opening the auxiliary storage"))?;
+    let platform = VZMacPlatformConfiguration::new(&model, &identifier, &storage)
+        .map_err(failed("creating the platform"))?;
+    let display = VZMacGraphicsDisplayConfiguration::new_with(1920, 1200, 80)
+        .and_then(|display| VZMacGraphicsDeviceConfiguration::new(vec![display]))
+        .map_err(failed("creating the display"))?;
+    let inputs = ProfileInputs {
+        disk: Some(plan.disk_path()),
+        ..ProfileInputs::default()
+    };
+    let conf = profile::configure(&MacOS, &inputs)
+        .map_err(|errors| {
+            let errors: Vec<String> = errors.iter().map(ToString::to_string).collect();
+            StepError::Failed(errors.join("; "))
+        })?
+        .cpu_count(plan.cpus)
+        .memory_size(plan.memory as usize)
+        .platform(platform)
+        .graphics_devices(vec![display])
+        .build();
+    conf.validate_with_error()
+        .map_err(failed("validating the configuration"))?;
+    Ok(VZVirtualMachine::new_with_qos(conf, "install", None))
+}
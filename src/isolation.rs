@@ -0,0 +1,1200 @@
+//! isolation module
+//!
+//! Runs each virtual machine in a child process of its own, so that a crash in the framework or in
+//! a callback takes down that machine only, not the supervisor. The child is the application's own
+//! executable, run again with the hidden subcommand [`HIDDEN_SUBCOMMAND`]; the application hands
+//! control to the crate by calling [`child_main`] first thing in `main`.
+//!
+//! Parent and child share nothing but a socket pair carrying length-prefixed frames: the
+//! [`HostSpec`] once, then commands and their replies. No framework object crosses it. The child
+//! stops its machine and exits when it gets `SIGTERM`, when the machine stops after a start, and
+//! when the parent's end of the socket closes, which includes the parent dying. A child that dies
+//! without reporting makes every call on its [`VmHandle`] fail with [`HostError::HostDied`].
+//!
+//! Each handle gets a [`VmId`] in the parent. The child labels its machine with it, e.g.
+//! `vm-1 (parent vm-3)`, so its timelines and errors can be told apart. The child sends its
+//! warnings over the socket; the parent logs them through the `log` crate, naming the child's
+//! process id.
+//!
+//! # Examples
+//! ```rust
+//! // First thing in `main`: returns unless this process is a VM host.
+//! isolation::child_main();
+//!
+//! let spec = HostSpec::linux("vmlinuz", "initrd", "console=hvc0")
+//!     .disk("disk.img", false)
+//!     .console_log("console.log");
+//! let vm = VmHost::new().spawn(&spec)?;
+//! vm.start()?;
+//! match vm.wait() {
+//!     Ok(exit) => println!("stopped in state {}", exit.state),
+//!     Err(HostError::HostDied { status }) => eprintln!("VM host died: {:?}", status),
+//!     Err(e) => eprintln!("{}", e),
+//! }
+//! ```
+
+use crate::admission::{self, AdmissionDecision, AdmissionPolicy, Requirements, Violation};
+use crate::base::{DispatchQueue, DispatchSemaphore, NSFileHandle, NIL};
+use crate::resource::CloseError;
+use crate::virtualization::boot_loader::VZLinuxBootLoaderBuilder;
+use crate::virtualization::entropy_device::VZVirtioEntropyDeviceConfiguration;
+use crate::virtualization::error::CompletionOutcome;
+use crate::virtualization::network_device::{
+    VZNATNetworkDeviceAttachment, VZVirtioNetworkDeviceConfiguration,
+};
+use crate::virtualization::serial_port::{
+    VZFileHandleSerialPortAttachment, VZVirtioConsoleDeviceSerialPortConfiguration,
+};
+use crate::virtualization::storage_device::{
+    VZDiskImageStorageDeviceAttachmentBuilder, VZVirtioBlockDeviceConfiguration,
+};
+use crate::virtualization::virtual_machine::{
+    BackingFile, BackingFileKind, VZVirtualMachine, VZVirtualMachineConfiguration,
+    VZVirtualMachineConfigurationBuilder, VZVirtualMachineState, VmId,
+};
+
+use std::collections::HashMap;
+use std::env;
+use std::error::Error;
+use std::ffi::{OsStr, OsString};
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::mem;
+use std::net::Shutdown;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::{self, Child, Command, ExitStatus, Stdio};
+use std::ptr;
+use std::sync::atomic::{AtomicBool, AtomicI32, AtomicU64, Ordering};
+use std::sync::{mpsc, Arc, Condvar, Mutex, MutexGuard};
+use std::thread;
+use std::time::Duration;
+
+/// The first argument of a child process started by [`VmHost::spawn`], unless replaced with
+/// [`VmHost::args`]. Argument parsers of the application should not see it: [`child_main`] takes
+/// over the process before.
+pub const HIDDEN_SUBCOMMAND: &str = "__virtualization-rs-vm-host";
+
+/// Tells [`child_main`] the descriptor of its end of the socket.
+const CHILD_ENV: &str = "VIRTUALIZATION_RS_VM_HOST_FD";
+
+/// Tells [`child_main`] the [`VmId`] of its handle in the parent, to label its machine with.
+const PARENT_VM_ENV: &str = "VIRTUALIZATION_RS_VM_HOST_PARENT_VM";
+
+/// Bumped with every change to the frames, so that a child from another build refuses the spec
+/// instead of misreading it.
+const PROTOCOL_VERSION: u32 = 1;
+
+/// Largest frame either side accepts.
+const MAX_FRAME_LEN: usize = 1 << 20;
+
+/// How long a child waits for its machine to stop before exiting anyway.
+const STOP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Serializes socket creation and spawning, so that a child started by another call cannot
+/// inherit this call's socket and keep it open after this child died.
+static SPAWN_LOCK: Mutex<()> = Mutex::new(());
+
+/// What a child process builds: a Linux guest with disk images, an optional NAT network device and
+/// an optional console log. Plain data, so that it can be sent to the child.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HostSpec {
+    kernel: PathBuf,
+    initial_ramdisk: PathBuf,
+    command_line: String,
+    cpu_count: usize,
+    memory_size: usize,
+    disks: Vec<(PathBuf, bool)>,
+    nat_network: bool,
+    console_log: Option<PathBuf>,
+}
+
+impl HostSpec {
+    /// One CPU and 512 MiB of memory, without disks, network or console.
+    pub fn linux<K, I, C>(kernel: K, initial_ramdisk: I, command_line: C) -> HostSpec
+    where
+        K: Into<PathBuf>,
+        I: Into<PathBuf>,
+        C: Into<String>,
+    {
+        HostSpec {
+            kernel: kernel.into(),
+            initial_ramdisk: initial_ramdisk.into(),
+            command_line: command_line.into(),
+            cpu_count: 1,
+            memory_size: 512 * 1024 * 1024,
+            disks: Vec::new(),
+            nat_network: false,
+            console_log: None,
+        }
+    }
+
+    pub fn cpu_count(mut self, cpu_count: usize) -> Self {
+        self.cpu_count = cpu_count;
+        self
+    }
+
+    pub fn memory_size(mut self, memory_size: usize) -> Self {
+        self.memory_size = memory_size;
+        self
+    }
+
+    /// Adds a Virtio block device backed by the raw image at `path`.
+    pub fn disk<P: Into<PathBuf>>(mut self, path: P, read_only: bool) -> Self {
+        self.disks.push((path.into(), read_only));
+        self
+    }
+
+    pub fn nat_network(mut self, nat_network: bool) -> Self {
+        self.nat_network = nat_network;
+        self
+    }
+
+    /// Appends the output of a Virtio console to the file at `path`; the guest gets no input.
+    pub fn console_log<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.console_log = Some(path.into());
+        self
+    }
+
+    fn encode(&self, e: &mut Encoder) {
+        e.path(&self.kernel);
+        e.path(&self.initial_ramdisk);
+        e.str(&self.command_line);
+        e.u64(self.cpu_count as u64);
+        e.u64(self.memory_size as u64);
+        e.u32(self.disks.len() as u32);
+        for (path, read_only) in &self.disks {
+            e.path(path);
+            e.bool(*read_only);
+        }
+        e.bool(self.nat_network);
+        e.bool(self.console_log.is_some());
+        if let Some(path) = &self.console_log {
+            e.path(path);
+        }
+    }
+
+    fn decode(d: &mut Decoder<'_>) -> io::Result<HostSpec> {
+        let mut spec = HostSpec::linux(d.path()?, d.path()?, d.str()?)
+            .cpu_count(d.u64()? as usize)
+            .memory_size(d.u64()? as usize);
+        for _ in 0..d.u32()? {
+            spec = spec.disk(d.path()?, d.bool()?);
+        }
+        spec = spec.nat_network(d.bool()?);
+        if d.bool()? {
+            spec = spec.console_log(d.path()?);
+        }
+        Ok(spec)
+    }
+
+    /// Builds and validates the configuration, in the child.
+    fn build(&self) -> Result<VZVirtualMachineConfiguration, String> {
+        let boot_loader = VZLinuxBootLoaderBuilder::new()
+            .kernel_url(utf8(&self.kernel)?)
+            .initial_ramdisk_url(utf8(&self.initial_ramdisk)?)
+            .command_line(self.command_line.clone())
+            .build()
+            .map_err(|e| e.to_string())?;
+        let mut builder = VZVirtualMachineConfigurationBuilder::new()
+            .boot_loader(boot_loader)
+            .cpu_count(self.cpu_count)
+            .memory_size(self.memory_size)
+            .entropy_devices(vec![VZVirtioEntropyDeviceConfiguration::new()]);
+        for (path, read_only) in &self.disks {
+            let attachment = VZDiskImageStorageDeviceAttachmentBuilder::new()
+                .path(utf8(path)?)
+                .read_only(*read_only)
+                .build()
+                .map_err(|e| e.to_string())?;
+            builder = builder.storage_device(VZVirtioBlockDeviceConfiguration::new(attachment));
+        }
+        if self.nat_network {
+            builder = builder.network_device(VZVirtioNetworkDeviceConfiguration::new(
+                VZNATNetworkDeviceAttachment::new(),
+            ));
+        }
+        if let Some(path) = &self.console_log {
+            let log = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .map_err(|e| format!("failed to open console log '{}': {}", path.display(), e))?;
+            let log = NSFileHandle::init_with_file_descriptor(log.into_raw_fd(), true);
+            let attachment =
+                unsafe { VZFileHandleSerialPortAttachment::from_raw_handles(NIL, *log.0) };
+            builder =
+                builder.serial_ports(vec![VZVirtioConsoleDeviceSerialPortConfiguration::new(
+                    attachment,
+                )]);
+        }
+        let conf = builder.build();
+        conf.validate_with_error().map_err(|e| e.to_string())?;
+        Ok(conf)
+    }
+}
+
+/// The spec's CPUs, memory and disk images; the console log is not counted.
+impl From<&HostSpec> for Requirements {
+    fn from(spec: &HostSpec) -> Requirements {
+        Requirements {
+            cpu_count: spec.cpu_count,
+            memory_size: spec.memory_size as u64,
+            files: spec
+                .disks
+                .iter()
+                .map(|(path, read_only)| BackingFile {
+                    path: path.clone(),
+                    kind: BackingFileKind::DiskImage,
+                    read_only: *read_only,
+                })
+                .collect(),
+        }
+    }
+}
+
+fn utf8(path: &Path) -> Result<String, String> {
+    path.to_str()
+        .map(str::to_string)
+        .ok_or_else(|| format!("path '{}' is not UTF-8", path.display()))
+}
+
+/// A failure the framework reported in the child, e.g. to a start.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RemoteError {
+    pub domain: String,
+    pub code: i64,
+    /// The error as the child displayed it.
+    pub message: String,
+}
+
+impl fmt::Display for RemoteError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+/// Why a [`VmHost`] or [`VmHandle`] call failed.
+#[derive(Debug)]
+pub enum HostError {
+    /// The child process could not be started, or the socket to it failed.
+    Io(io::Error),
+    /// The child could not build the virtual machine from its [`HostSpec`], e.g. for a missing
+    /// kernel; it has exited.
+    Setup(String),
+    /// The child refused the command, e.g. a start while the machine runs.
+    Refused(String),
+    /// The framework failed the command in the child.
+    Failed(RemoteError),
+    /// The framework cancelled the command in the child.
+    Cancelled,
+    /// The child exited after its machine stopped; [`VmHandle::wait`] tells how.
+    Exited,
+    /// The child exited or was killed without reporting, with its exit status if it could be
+    /// collected.
+    HostDied { status: Option<ExitStatus> },
+    /// The child sent a frame this side does not understand.
+    Protocol(String),
+    /// The host lacks room for the machine under the policy of [`VmHost::admit_before_start`];
+    /// the machine was not started.
+    NotAdmitted(Vec<Violation>),
+}
+
+impl fmt::Display for HostError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HostError::Io(e) => write!(f, "VM host I/O failed: {}", e),
+            HostError::Setup(message) => write!(f, "VM host setup failed: {}", message),
+            HostError::Refused(message) => write!(f, "VM host refused the command: {}", message),
+            HostError::Failed(e) => write!(f, "VM host command failed: {}", e),
+            HostError::Cancelled => f.write_str("VM host command was cancelled"),
+            HostError::Exited => f.write_str("VM host has exited"),
+            HostError::HostDied {
+                status: Some(status),
+            } => {
+                write!(f, "VM host died ({})", status)
+            }
+            HostError::HostDied { status: None } => f.write_str("VM host died"),
+            HostError::Protocol(message) => write!(f, "VM host protocol error: {}", message),
+            HostError::NotAdmitted(violations) => write!(
+                f,
+                "VM host did not start the machine: {}",
+                AdmissionDecision::Rejected(violations.clone())
+            ),
+        }
+    }
+}
+
+impl Error for HostError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            HostError::Io(e) => Some(e),
+            HostError::Failed(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl Error for RemoteError {}
+
+impl From<io::Error> for HostError {
+    fn from(e: io::Error) -> HostError {
+        HostError::Io(e)
+    }
+}
+
+/// How a child ended after reporting, from [`VmHandle::wait`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VmExit {
+    /// The state the machine was in when the child exited, usually stopped or error.
+    pub state: VZVirtualMachineState,
+    pub status: ExitStatus,
+}
+
+/// Starts child processes hosting one virtual machine each.
+#[derive(Debug, Clone, Default)]
+pub struct VmHost {
+    program: Option<PathBuf>,
+    args: Option<Vec<OsString>>,
+    admission: Option<AdmissionPolicy>,
+}
+
+impl VmHost {
+    pub fn new() -> VmHost {
+        VmHost::default()
+    }
+
+    /// Runs `program` instead of the current executable. It must call [`child_main`].
+    pub fn program<P: Into<PathBuf>>(mut self, program: P) -> Self {
+        self.program = Some(program.into());
+        self
+    }
+
+    /// Passes `args` instead of [`HIDDEN_SUBCOMMAND`], e.g. for a test harness that needs its own.
+    pub fn args<I, S>(mut self, args: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<OsStr>,
+    {
+        self.args = Some(args.into_iter().map(|a| a.as_ref().to_owned()).collect());
+        self
+    }
+
+    /// Checks the host with [`admission::check`] whenever a machine is started, so that
+    /// [`VmHandle::start`] fails with [`HostError::NotAdmitted`] instead of starting a machine
+    /// the host has no room for. The check runs in the parent, against the spec's requirements.
+    pub fn admit_before_start(mut self, policy: AdmissionPolicy) -> Self {
+        self.admission = Some(policy);
+        self
+    }
+
+    /// Starts a child process, sends it `spec` and returns once it has built the virtual machine,
+    /// which is stopped until [`VmHandle::start`]. Blocks for good if the program never calls
+    /// [`child_main`].
+    ///
+    /// Fails with [`HostError::Setup`] if the child cannot build the machine and with
+    /// [`HostError::HostDied`] if it dies first. The child's standard output and error are the
+    /// parent's.
+    pub fn spawn(&self, spec: &HostSpec) -> Result<VmHandle, HostError> {
+        let program = match &self.program {
+            Some(program) => program.clone(),
+            None => env::current_exe()?,
+        };
+        let args = match &self.args {
+            Some(args) => args.clone(),
+            None => vec![OsString::from(HIDDEN_SUBCOMMAND)],
+        };
+
+        let vm_id = VmId::next();
+        let (mut stream, mut child) = {
+            let _spawning = SPAWN_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+            // Both ends are close-on-exec; only the child clears it on its own end.
+            let (parent_end, child_end) = UnixStream::pair()?;
+            let fd = child_end.as_raw_fd();
+            let mut command = Command::new(program);
+            command
+                .args(args)
+                .env(CHILD_ENV, fd.to_string())
+                .env(PARENT_VM_ENV, vm_id.to_string())
+                .stdin(Stdio::null());
+            unsafe {
+                command.pre_exec(move || {
+                    if libc::fcntl(fd, libc::F_SETFD, 0) == -1 {
+                        return Err(io::Error::last_os_error());
+                    }
+                    Ok(())
+                });
+            }
+            (parent_end, command.spawn()?)
+        };
+
+        let mut hello = Encoder::new();
+        hello.u32(PROTOCOL_VERSION);
+        spec.encode(&mut hello);
+        // A child that died before reading fails the write; its status tells why.
+        let setup = write_frame(&mut stream, &hello.0)
+            .and_then(|()| read_frame(&mut stream))
+            .and_then(|frame| frame.map(|f| ChildMessage::decode(&f)).transpose());
+        match setup {
+            Ok(Some(ChildMessage::Ready)) => {}
+            Ok(Some(ChildMessage::SetupFailed(message))) => {
+                let _ = child.wait();
+                return Err(HostError::Setup(message));
+            }
+            Ok(Some(_)) => {
+                let _ = child.kill();
+                let _ = child.wait();
+                return Err(HostError::Protocol("expected a setup reply".to_string()));
+            }
+            Ok(None) => {
+                return Err(HostError::HostDied {
+                    status: child.wait().ok(),
+                })
+            }
+            Err(e) => {
+                let _ = child.kill();
+                let status = child.wait().ok();
+                return Err(match e.kind() {
+                    io::ErrorKind::InvalidData => HostError::Protocol(e.to_string()),
+                    _ => HostError::HostDied { status },
+                });
+            }
+        }
+
+        let pid = child.id();
+        let shared = Arc::new(Shared::default());
+        let reader = stream.try_clone()?;
+        let reader_shared = shared.clone();
+        thread::Builder::new()
+            .name(format!("vm-host-{}", pid))
+            .spawn(move || read_replies(reader, child, &reader_shared))?;
+        Ok(VmHandle {
+            vm_id,
+            pid,
+            admission: self
+                .admission
+                .map(|policy| (Requirements::from(spec), policy)),
+            stream: Mutex::new(stream),
+            next_id: AtomicU64::new(0),
+            shared,
+            closed: AtomicBool::new(false),
+        })
+    }
+}
+
+/// The parent's end of a child hosting a virtual machine.
+///
+/// Calls block until the child replies and may come from several threads. Dropping the handle
+/// closes the socket, upon which the child stops the machine and exits; it does not wait for that.
+pub struct VmHandle {
+    vm_id: VmId,
+    pid: u32,
+    /// What to check before each start, from [`VmHost::admit_before_start`].
+    admission: Option<(Requirements, AdmissionPolicy)>,
+    stream: Mutex<UnixStream>,
+    next_id: AtomicU64,
+    shared: Arc<Shared>,
+    /// Whether the socket was shut down.
+    closed: AtomicBool,
+}
+
+#[derive(Default)]
+struct Shared {
+    inner: Mutex<SharedInner>,
+    done: Condvar,
+}
+
+#[derive(Default)]
+struct SharedInner {
+    /// Callers waiting for the reply to a request id.
+    pending: HashMap<u64, mpsc::Sender<Reply>>,
+    /// The state the child reported before exiting.
+    exit_state: Option<VZVirtualMachineState>,
+    /// Whether the socket is closed and the child collected.
+    reaped: bool,
+    status: Option<ExitStatus>,
+    protocol_error: Option<String>,
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, SharedInner> {
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Waits until the child is collected.
+    fn reaped(&self) -> MutexGuard<'_, SharedInner> {
+        let mut inner = self.lock();
+        while !inner.reaped {
+            inner = self.done.wait(inner).unwrap_or_else(|e| e.into_inner());
+        }
+        inner
+    }
+}
+
+/// Why calls fail once the child is gone.
+fn gone(inner: &SharedInner) -> HostError {
+    if let Some(message) = &inner.protocol_error {
+        HostError::Protocol(message.clone())
+    } else if inner.exit_state.is_some() {
+        HostError::Exited
+    } else {
+        HostError::HostDied {
+            status: inner.status,
+        }
+    }
+}
+
+impl VmHandle {
+    /// The identity of the machine in this process. The child's machine is labeled with it.
+    pub fn vm_id(&self) -> VmId {
+        self.vm_id
+    }
+
+    /// The process id of the child.
+    pub fn pid(&self) -> u32 {
+        self.pid
+    }
+
+    /// Starts the machine; returns once the framework accepted the start, like
+    /// [`VZVirtualMachine::start`]. Fails with [`HostError::NotAdmitted`] without asking the
+    /// child if the host lacks room under the policy of [`VmHost::admit_before_start`].
+    pub fn start(&self) -> Result<(), HostError> {
+        if let Some((requirements, policy)) = &self.admission {
+            if let AdmissionDecision::Rejected(violations) = admission::check(requirements, policy)
+            {
+                return Err(HostError::NotAdmitted(violations));
+            }
+        }
+        self.call(Request::Start).and_then(Reply::done)
+    }
+
+    /// Stops the machine without giving the guest a chance to shut down, like
+    /// [`VZVirtualMachine::stop`]. The child exits once the machine is stopped.
+    pub fn stop(&self) -> Result<(), HostError> {
+        self.call(Request::Stop).and_then(Reply::done)
+    }
+
+    pub fn state(&self) -> Result<VZVirtualMachineState, HostError> {
+        match self.call(Request::State)? {
+            Reply::State(state) => Ok(state),
+            reply => reply.done().and(Err(HostError::Protocol(
+                "expected a state reply".to_string(),
+            ))),
+        }
+    }
+
+    /// Asks the child to stop its machine and exit, as `kill -TERM` does.
+    pub fn terminate(&self) -> io::Result<()> {
+        if unsafe { libc::kill(self.pid as libc::pid_t, libc::SIGTERM) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// Waits until the child has exited. Succeeds if it reported the machine's final state
+    /// first, and fails with [`HostError::HostDied`] otherwise, e.g. if it was killed.
+    pub fn wait(&self) -> Result<VmExit, HostError> {
+        let inner = self.shared.reaped();
+        match (inner.exit_state, inner.status) {
+            (Some(state), Some(status)) if inner.protocol_error.is_none() => {
+                Ok(VmExit { state, status })
+            }
+            _ => Err(gone(&inner)),
+        }
+    }
+
+    /// Shuts the socket to the child down, so it stops its machine and exits; [`wait`] collects
+    /// it. Dropping the handle does the same, ignoring errors.
+    ///
+    /// [`wait`]: VmHandle::wait
+    pub fn close(self) -> Result<(), CloseError> {
+        self.release()
+    }
+
+    fn release(&self) -> Result<(), CloseError> {
+        if self.closed.swap(true, Ordering::SeqCst) {
+            return Ok(());
+        }
+        // The reader holds a clone of the socket, so only a shutdown ends the child's reads.
+        let stream = self.stream.lock().unwrap_or_else(|e| e.into_inner());
+        stream.shutdown(Shutdown::Write).map_err(CloseError::Io)
+    }
+
+    fn call(&self, request: Request) -> Result<Reply, HostError> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (tx, rx) = mpsc::channel();
+        {
+            let mut inner = self.shared.lock();
+            if inner.reaped || inner.exit_state.is_some() {
+                return Err(gone(&inner));
+            }
+            inner.pending.insert(id, tx);
+        }
+        let mut frame = Encoder::new();
+        frame.u64(id);
+        request.encode(&mut frame);
+        let sent = {
+            let mut stream = self.stream.lock().unwrap_or_else(|e| e.into_inner());
+            write_frame(&mut *stream, &frame.0)
+        };
+        // A failed write means the child is gone: the reader drops `tx` once it collected it.
+        if let Err(e) = sent {
+            if !matches!(
+                e.kind(),
+                io::ErrorKind::BrokenPipe | io::ErrorKind::ConnectionReset
+            ) {
+                self.shared.lock().pending.remove(&id);
+                return Err(HostError::Io(e));
+            }
+        }
+        match rx.recv() {
+            Ok(reply) => Ok(reply),
+            Err(_) => Err(gone(&self.shared.reaped())),
+        }
+    }
+}
+
+impl Drop for VmHandle {
+    fn drop(&mut self) {
+        let _ = self.release();
+    }
+}
+
+/// The reader thread of a [`VmHandle`]: routes replies until the socket closes, then collects
+/// the child and fails the calls still waiting.
+fn read_replies(mut stream: UnixStream, mut child: Child, shared: &Shared) {
+    let mut child_error = None;
+    let protocol_error = loop {
+        let frame = match read_frame(&mut stream) {
+            Ok(Some(frame)) => frame,
+            Ok(None) | Err(_) => break None,
+        };
+        match ChildMessage::decode(&frame) {
+            Ok(ChildMessage::Reply(id, reply)) => {
+                if let Some(tx) = shared.lock().pending.remove(&id) {
+                    let _ = tx.send(reply);
+                }
+            }
+            Ok(ChildMessage::Exited(state)) => shared.lock().exit_state = Some(state),
+            Ok(ChildMessage::Warning(message)) => {
+                log::warn!("VM host {}: {}", child.id(), message)
+            }
+            // The child stops its machine and exits on its own.
+            Ok(ChildMessage::ProtocolError(message)) => child_error = Some(message),
+            Ok(_) => break Some("unexpected setup reply".to_string()),
+            Err(e) => break Some(e.to_string()),
+        }
+    };
+    if protocol_error.is_some() {
+        let _ = child.kill();
+    }
+    let status = child.wait().ok();
+    let mut inner = shared.lock();
+    inner.reaped = true;
+    inner.status = status;
+    inner.protocol_error = protocol_error.or(child_error);
+    inner.pending.clear();
+    shared.done.notify_all();
+}
+
+/// Hands the process over to the crate if it was started by [`VmHost::spawn`], and returns right
+/// away otherwise. Call it first thing in `main`, before parsing arguments or starting threads.
+///
+/// In a child, it builds the virtual machine, serves the parent's commands and exits the process
+/// once the machine stopped, on `SIGTERM`, or when the parent's end of the socket closes.
+pub fn child_main() {
+    let fd = match env::var(CHILD_ENV) {
+        Ok(fd) => fd,
+        Err(_) => return,
+    };
+    let parent_vm = env::var(PARENT_VM_ENV).ok();
+    // Processes this one starts are no VM hosts.
+    env::remove_var(CHILD_ENV);
+    env::remove_var(PARENT_VM_ENV);
+    let code = match fd.parse::<RawFd>() {
+        Ok(fd) => run_child(unsafe { UnixStream::from_raw_fd(fd) }, parent_vm),
+        Err(_) => {
+            log::error!("{}: invalid descriptor {:?}", CHILD_ENV, fd);
+            2
+        }
+    };
+    process::exit(code)
+}
+
+/// The child's end of the socket, shared by the command loop and completion handlers.
+type Writer = Arc<Mutex<UnixStream>>;
+
+fn send(writer: &Writer, message: &ChildMessage) -> io::Result<()> {
+    let mut frame = Encoder::new();
+    message.encode(&mut frame);
+    let mut stream = writer.lock().unwrap_or_else(|e| e.into_inner());
+    write_frame(&mut *stream, &frame.0)
+}
+
+fn run_child(mut stream: UnixStream, parent_vm: Option<String>) -> i32 {
+    let spec = read_frame(&mut stream).and_then(|frame| {
+        let frame = frame.ok_or_else(|| invalid("parent closed before sending a spec"))?;
+        let mut d = Decoder(&frame);
+        match d.u32()? {
+            PROTOCOL_VERSION => HostSpec::decode(&mut d),
+            version => Err(invalid(format!("unsupported protocol version {}", version))),
+        }
+    });
+    let writer: Writer = match stream.try_clone() {
+        Ok(writer) => Arc::new(Mutex::new(writer)),
+        Err(_) => return 1,
+    };
+    let conf = match spec {
+        Ok(spec) => spec.build(),
+        Err(e) => Err(e.to_string()),
+    };
+    let vm = match conf {
+        Ok(conf) => {
+            let queue = DispatchQueue::new("virtualization-rs.vm-host");
+            let label = match parent_vm {
+                Some(parent_vm) => format!("parent {}", parent_vm),
+                None => "parent unknown".to_string(),
+            };
+            VZVirtualMachine::new_labeled(conf, queue.id(), &label)
+        }
+        Err(message) => {
+            let _ = send(&writer, &ChildMessage::SetupFailed(message));
+            return 1;
+        }
+    };
+    if send(&writer, &ChildMessage::Ready).is_err() {
+        return 1;
+    }
+    if let Err(e) = forward_sigterm(&vm, &writer) {
+        let message = format!("{} cannot handle SIGTERM: {}", vm.display_name(), e);
+        let _ = send(&writer, &ChildMessage::Warning(message));
+    }
+
+    loop {
+        let frame = match read_frame(&mut stream) {
+            Ok(Some(frame)) => frame,
+            // The parent is gone, or shut its end down: nobody is left to report to.
+            Ok(None) | Err(_) => {
+                stop_and_wait(&vm);
+                return 0;
+            }
+        };
+        let mut d = Decoder(&frame);
+        let (id, request) = match d.u64().and_then(|id| Ok((id, Request::decode(&mut d)?))) {
+            Ok(request) => request,
+            Err(e) => {
+                let _ = send(&writer, &ChildMessage::ProtocolError(e.to_string()));
+                stop_and_wait(&vm);
+                return 2;
+            }
+        };
+        match request {
+            Request::Start => {
+                let reply_to = writer.clone();
+                let exit_vm = vm.clone();
+                let sent = vm.start(move |outcome| {
+                    let started = outcome.is_success();
+                    let _ = send(&reply_to, &ChildMessage::Reply(id, Reply::from(outcome)));
+                    if started {
+                        exit_when_stopped(&exit_vm, &reply_to);
+                    }
+                });
+                if let Err(e) = sent {
+                    let _ = send(
+                        &writer,
+                        &ChildMessage::Reply(id, Reply::Refused(e.to_string())),
+                    );
+                }
+            }
+            Request::Stop => {
+                let reply_to = writer.clone();
+                let sent = vm.stop(move |outcome| {
+                    let _ = send(&reply_to, &ChildMessage::Reply(id, Reply::from(outcome)));
+                });
+                if let Err(e) = sent {
+                    let _ = send(
+                        &writer,
+                        &ChildMessage::Reply(id, Reply::Refused(e.to_string())),
+                    );
+                }
+            }
+            Request::State => {
+                let state = vm.queue().exec_sync(|| unsafe { vm.state() });
+                let _ = send(&writer, &ChildMessage::Reply(id, Reply::State(state)));
+            }
+        }
+    }
+}
+
+/// Reports the final state and exits the process once the started machine stops or fails. Runs on
+/// the VM's queue.
+fn exit_when_stopped(vm: &VZVirtualMachine, writer: &Writer) {
+    let states = [
+        VZVirtualMachineState::VZVirtualMachineStateStopped,
+        VZVirtualMachineState::VZVirtualMachineStateError,
+    ];
+    for &state in &states {
+        let writer = writer.clone();
+        let guard = vm.on_first_transition_to(state, move || {
+            let _ = send(&writer, &ChildMessage::Exited(state));
+            process::exit(0);
+        });
+        // The process exits with the observation.
+        std::mem::forget(guard);
+    }
+}
+
+/// Stops the machine, if it runs, and waits a bounded time for it. Must not run on the VM's
+/// queue.
+fn stop_and_wait(vm: &VZVirtualMachine) {
+    let stopped = Arc::new(DispatchSemaphore::new(0));
+    let signal = stopped.clone();
+    vm.stop_or_join(move |_| signal.signal());
+    stopped.wait_timeout(STOP_TIMEOUT);
+}
+
+/// Write end of the pipe the `SIGTERM` handler writes to.
+static SIGTERM_PIPE: AtomicI32 = AtomicI32::new(-1);
+
+extern "C" fn on_sigterm(_: libc::c_int) {
+    let fd = SIGTERM_PIPE.load(Ordering::SeqCst);
+    if fd != -1 {
+        unsafe {
+            libc::write(fd, b"t".as_ptr() as *const libc::c_void, 1);
+        }
+    }
+}
+
+/// Turns `SIGTERM` into a stop of the machine, a final report and an exit.
+fn forward_sigterm(vm: &VZVirtualMachine, writer: &Writer) -> io::Result<()> {
+    let mut fds = [0; 2];
+    if unsafe { libc::pipe(fds.as_mut_ptr()) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    let [read_fd, write_fd] = fds;
+    unsafe {
+        libc::fcntl(read_fd, libc::F_SETFD, libc::FD_CLOEXEC);
+        libc::fcntl(write_fd, libc::F_SETFD, libc::FD_CLOEXEC);
+        libc::fcntl(write_fd, libc::F_SETFL, libc::O_NONBLOCK);
+    }
+    let mut read = unsafe { File::from_raw_fd(read_fd) };
+    SIGTERM_PIPE.store(write_fd, Ordering::SeqCst);
+    unsafe {
+        let mut action: libc::sigaction = mem::zeroed();
+        action.sa_sigaction = on_sigterm as extern "C" fn(libc::c_int) as usize;
+        libc::sigemptyset(&mut action.sa_mask);
+        action.sa_flags = libc::SA_RESTART;
+        if libc::sigaction(libc::SIGTERM, &action, ptr::null_mut()) == -1 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    let vm = vm.clone();
+    let writer = writer.clone();
+    thread::Builder::new()
+        .name("vm-host-sigterm".to_string())
+        .spawn(move || {
+            let mut byte = [0u8; 1];
+            if read.read_exact(&mut byte).is_err() {
+                return;
+            }
+            stop_and_wait(&vm);
+            let state = vm.queue().exec_sync(|| unsafe { vm.state() });
+            let _ = send(&writer, &ChildMessage::Exited(state));
+            process::exit(0);
+        })?;
+    Ok(())
+}
+
+/// A command from the parent; each frame carries a request id first.
+enum Request {
+    Start,
+    Stop,
+    State,
+}
+
+impl Request {
+    fn encode(&self, e: &mut Encoder) {
+        e.u8(match self {
+            Request::Start => 1,
+            Request::Stop => 2,
+            Request::State => 3,
+        });
+    }
+
+    fn decode(d: &mut Decoder<'_>) -> io::Result<Request> {
+        match d.u8()? {
+            1 => Ok(Request::Start),
+            2 => Ok(Request::Stop),
+            3 => Ok(Request::State),
+            tag => Err(invalid(format!("unknown request {}", tag))),
+        }
+    }
+}
+
+/// The child's answer to a [`Request`].
+enum Reply {
+    Done,
+    State(VZVirtualMachineState),
+    Refused(String),
+    Failed(RemoteError),
+    Cancelled,
+}
+
+impl Reply {
+    fn done(self) -> Result<(), HostError> {
+        match self {
+            Reply::Done => Ok(()),
+            Reply::State(_) => Err(HostError::Protocol("unexpected state reply".to_string())),
+            Reply::Refused(message) => Err(HostError::Refused(message)),
+            Reply::Failed(e) => Err(HostError::Failed(e)),
+            Reply::Cancelled => Err(HostError::Cancelled),
+        }
+    }
+
+    fn encode(&self, e: &mut Encoder) {
+        match self {
+            Reply::Done => e.u8(1),
+            Reply::State(state) => {
+                e.u8(2);
+                e.i64(state.raw());
+            }
+            Reply::Refused(message) => {
+                e.u8(3);
+                e.str(message);
+            }
+            Reply::Failed(error) => {
+                e.u8(4);
+                e.str(&error.domain);
+                e.i64(error.code);
+                e.str(&error.message);
+            }
+            Reply::Cancelled => e.u8(5),
+        }
+    }
+
+    fn decode(d: &mut Decoder<'_>) -> io::Result<Reply> {
+        match d.u8()? {
+            1 => Ok(Reply::Done),
+            2 => Ok(Reply::State(VZVirtualMachineState::from_raw(d.i64()?))),
+            3 => Ok(Reply::Refused(d.str()?)),
+            4 => Ok(Reply::Failed(RemoteError {
+                domain: d.str()?,
+                code: d.i64()?,
+                message: d.str()?,
+            })),
+            5 => Ok(Reply::Cancelled),
+            tag => Err(invalid(format!("unknown reply {}", tag))),
+        }
+    }
+}
+
+impl From<CompletionOutcome> for Reply {
+    fn from(outcome: CompletionOutcome) -> Reply {
+        match outcome {
+            CompletionOutcome::Success(()) => Reply::Done,
+            CompletionOutcome::Cancelled => Reply::Cancelled,
+            CompletionOutcome::Failed(e) => Reply::Failed(RemoteError {
+                domain: e.0.domain().as_str().to_string(),
+                code: e.0.code() as i64,
+                message: e.0.to_string(),
+            }),
+        }
+    }
+}
+
+/// A frame from the child.
+enum ChildMessage {
+    /// The machine is built; the first frame of a successful setup.
+    Ready,
+    /// The machine cannot be built; the child exits after sending it.
+    SetupFailed(String),
+    Reply(u64, Reply),
+    /// The machine stopped in this state; the child exits after sending it.
+    Exited(VZVirtualMachineState),
+    /// Something the parent logs as a warning.
+    Warning(String),
+    /// The child cannot decode a request; it stops the machine and exits after sending it.
+    ProtocolError(String),
+}
+
+impl ChildMessage {
+    fn encode(&self, e: &mut Encoder) {
+        match self {
+            ChildMessage::Ready => e.u8(1),
+            ChildMessage::SetupFailed(message) => {
+                e.u8(2);
+                e.str(message);
+            }
+            ChildMessage::Reply(id, reply) => {
+                e.u8(3);
+                e.u64(*id);
+                reply.encode(e);
+            }
+            ChildMessage::Exited(state) => {
+                e.u8(4);
+                e.i64(state.raw());
+            }
+            ChildMessage::Warning(message) => {
+                e.u8(5);
+                e.str(message);
+            }
+            ChildMessage::ProtocolError(message) => {
+                e.u8(6);
+                e.str(message);
+            }
+        }
+    }
+
+    fn decode(frame: &[u8]) -> io::Result<ChildMessage> {
+        let mut d = Decoder(frame);
+        match d.u8()? {
+            1 => Ok(ChildMessage::Ready),
+            2 => Ok(ChildMessage::SetupFailed(d.str()?)),
+            3 => Ok(ChildMessage::Reply(d.u64()?, Reply::decode(&mut d)?)),
+            4 => Ok(ChildMessage::Exited(VZVirtualMachineState::from_raw(
+                d.i64()?,
+            ))),
+            5 => Ok(ChildMessage::Warning(d.str()?)),
+            6 => Ok(ChildMessage::ProtocolError(d.str()?)),
+            tag => Err(invalid(format!("unknown message {}", tag))),
+        }
+    }
+}
+
+fn invalid<E: Into<Box<dyn Error + Send + Sync>>>(error: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, error)
+}
+
+/// Writes `payload` after its length as a little-endian `u32`.
+fn write_frame<W: Write>(w: &mut W, payload: &[u8]) -> io::Result<()> {
+    if payload.len() > MAX_FRAME_LEN {
+        return Err(invalid("frame too large"));
+    }
+    let mut frame = Vec::with_capacity(4 + payload.len());
+    frame.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    frame.extend_from_slice(payload);
+    w.write_all(&frame)
+}
+
+/// Reads a frame written by [`write_frame`], or `None` at the end of the stream between frames.
+fn read_frame<R: Read>(r: &mut R) -> io::Result<Option<Vec<u8>>> {
+    let mut len = [0u8; 4];
+    let mut filled = 0;
+    while filled < len.len() {
+        match r.read(&mut len[filled..]) {
+            Ok(0) if filled == 0 => return Ok(None),
+            Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    let len = u32::from_le_bytes(len) as usize;
+    if len > MAX_FRAME_LEN {
+        return Err(invalid("frame too large"));
+    }
+    let mut payload = vec![0u8; len];
+    r.read_exact(&mut payload)?;
+    Ok(Some(payload))
+}
+
+/// Little-endian fields; strings and paths prefixed with their length.
+struct Encoder(Vec<u8>);
+
+impl Encoder {
+    fn new() -> Encoder {
+        Encoder(Vec::new())
+    }
+
+    fn u8(&mut self, v: u8) {
+        self.0.push(v);
+    }
+
+    fn bool(&mut self, v: bool) {
+        self.u8(v as u8);
+    }
+
+    fn u32(&mut self, v: u32) {
+        self.0.extend_from_slice(&v.to_le_bytes());
+    }
+
+    fn u64(&mut self, v: u64) {
+        self.0.extend_from_slice(&v.to_le_bytes());
+    }
+
+    fn i64(&mut self, v: i64) {
+        self.0.extend_from_slice(&v.to_le_bytes());
+    }
+
+    fn bytes(&mut self, v: &[u8]) {
+        self.u32(v.len() as u32);
+        self.0.extend_from_slice(v);
+    }
+
+    fn str(&mut self, v: &str) {
+        self.bytes(v.as_bytes());
+    }
+
+    fn path(&mut self, v: &Path) {
+        self.bytes(v.as_os_str().as_bytes());
+    }
+}
+
+/// Reads what [`Encoder`] wrote, failing with `InvalidData` past the end.
+struct Decoder<'a>(&'a [u8]);
+
+impl<'a> Decoder<'a> {
+    fn take(&mut self, n: usize) -> io::Result<&'a [u8]> {
+        if self.0.len() < n {
+            return Err(invalid("truncated frame"));
+        }
+        let (head, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Ok(head)
+    }
+
+    fn u8(&mut self) -> io::Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn bool(&mut self) -> io::Result<bool> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            v => Err(invalid(format!("invalid bool {}", v))),
+        }
+    }
+
+    fn u32(&mut self) -> io::Result<u32> {
+        let mut v = [0u8; 4];
+        v.copy_from_slice(self.take(4)?);
+        Ok(u32::from_le_bytes(v))
+    }
+
+    fn u64(&mut self) -> io::Result<u64> {
+        let mut v = [0u8; 8];
+        v.copy_from_slice(self.take(8)?);
+        Ok(u64::from_le_bytes(v))
+    }
+
+    fn i64(&mut self) -> io::Result<i64> {
+        Ok(self.u64()? as i64)
+    }
+
+    fn bytes(&mut self) -> io::Result<&'a [u8]> {
+        let len = self.u32()? as usize;
+        self.take(len)
+    }
+
+    fn str(&mut self) -> io::Result<String> {
+        String::from_utf8(self.bytes()?.to_vec()).map_err(invalid)
+    }
+
+    fn path(&mut self) -> io::Result<PathBuf> {
+        Ok(PathBuf::from(OsStr::from_bytes(self.bytes()?)))
+    }
+}
@@ -0,0 +1,505 @@
+//! key-value observing module
+//!
+//! Observes a key path of any key-value observing compliant object with [`observe_keypath`],
+//! handing the callback the changed value decoded by [`FromKvoValue`], e.g. the
+//! `fractionCompleted` of an `NSProgress` with [`observe_fraction_completed`].
+//!
+//! Framework objects whose changes are posted on a dispatch queue, such as the `state` of a
+//! virtual machine, are observed through the crate-internal `observe_on` instead. It adds and
+//! removes the observer on that queue, so they are serialized with the notifications and no
+//! notification can outlive the observation.
+//!
+//! Every observation registers an observer of its own with a context pointer of its own, and
+//! removes exactly that registration, once. The [`KvoGuard`] retains the observed object, so it
+//! cannot be deallocated while observed. Without a queue, notifications arrive on the thread that
+//! made the change:
+//!
+//! - Callbacks of one observation never run concurrently; changes on other threads wait.
+//! - A change the callback makes to the observed key path itself is not delivered to it again.
+//! - Dropping the guard removes the observer, then waits for a callback running on another thread
+//!   to return; no callback starts afterwards. Dropping it from within its own callback does not
+//!   wait.
+
+use crate::base::{DispatchQueue, Id, NSInteger, NSString, NSUInteger, NIL};
+use crate::runtime::{alloc, from_objc_bool, owned, retained};
+
+use std::cell::RefCell;
+use std::os::raw::c_void;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, Once, Weak};
+
+use objc::declare::ClassDecl;
+use objc::rc::StrongPtr;
+use objc::runtime::{Class, Object, Sel, BOOL};
+use objc::{class, msg_send, sel, sel_impl};
+
+#[cfg_attr(target_vendor = "apple", link(name = "Foundation", kind = "framework"))]
+extern "C" {
+    static NSKeyValueChangeNewKey: Id;
+    static NSKeyValueChangeOldKey: Id;
+}
+
+const OBSERVER_CLASS: &str = "VirtualizationRsObserver";
+const HANDLER_IVAR: &str = "handler";
+
+/// One of the `NSKeyValueObservingOptions`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KvoOption(NSUInteger);
+
+impl KvoOption {
+    /// Changes carry the new value; without it, values decode from nil.
+    pub fn new_value() -> Self {
+        Self(0x01)
+    }
+
+    /// Changes carry the value before, for [`KvoChange::old`].
+    pub fn old_value() -> Self {
+        Self(0x02)
+    }
+
+    /// A first notification with the current value is sent from within the registration, so that
+    /// no value is missed.
+    pub fn initial() -> Self {
+        Self(0x04)
+    }
+}
+
+/// The options of an observation.
+#[derive(Debug, Clone, Default)]
+pub struct KvoOptions {
+    options: Vec<KvoOption>,
+}
+
+impl KvoOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with(mut self, option: KvoOption) -> Self {
+        self.options.push(option);
+        self
+    }
+
+    fn into_raw(self) -> NSUInteger {
+        self.options
+            .iter()
+            .fold(NSUInteger::default(), |mut acc, v| {
+                acc |= v.0;
+                acc
+            })
+    }
+}
+
+/// The current value, then each new one.
+fn initial_and_new() -> KvoOptions {
+    KvoOptions::new()
+        .with(KvoOption::initial())
+        .with(KvoOption::new_value())
+}
+
+/// A value decoded from the change dictionary of a notification.
+pub trait FromKvoValue: Sized {
+    /// Decodes one entry of the change dictionary; `value` is nil when the entry is missing or
+    /// `NSNull`. `None` skips the notification, e.g. for an object of another class.
+    ///
+    /// # Safety
+    /// `value` must be nil or a valid object.
+    unsafe fn from_kvo_value(value: Id) -> Option<Self>;
+
+    /// Decodes the change dictionary, by default its new value.
+    ///
+    /// # Safety
+    /// `change` must be a valid change dictionary.
+    unsafe fn from_change(change: Id) -> Option<Self> {
+        Self::from_kvo_value(change_entry(change, NSKeyValueChangeNewKey))
+    }
+}
+
+/// # Safety
+/// `change` must be a valid change dictionary.
+unsafe fn change_entry(change: Id, key: Id) -> Id {
+    let value: Id = msg_send![change, objectForKey: key];
+    let is_null: BOOL = msg_send![value, isKindOfClass: class!(NSNull)];
+    if from_objc_bool(is_null) {
+        NIL
+    } else {
+        value
+    }
+}
+
+/// `value` if it is an `NSNumber`.
+unsafe fn number(value: Id) -> Option<Id> {
+    let is_number: BOOL = msg_send![value, isKindOfClass: class!(NSNumber)];
+    if from_objc_bool(is_number) {
+        Some(value)
+    } else {
+        None
+    }
+}
+
+impl FromKvoValue for isize {
+    unsafe fn from_kvo_value(value: Id) -> Option<Self> {
+        let value: NSInteger = msg_send![number(value)?, integerValue];
+        Some(value as isize)
+    }
+}
+
+impl FromKvoValue for NSInteger {
+    unsafe fn from_kvo_value(value: Id) -> Option<Self> {
+        Some(msg_send![number(value)?, integerValue])
+    }
+}
+
+impl FromKvoValue for f64 {
+    unsafe fn from_kvo_value(value: Id) -> Option<Self> {
+        Some(msg_send![number(value)?, doubleValue])
+    }
+}
+
+impl FromKvoValue for bool {
+    unsafe fn from_kvo_value(value: Id) -> Option<Self> {
+        let value: BOOL = msg_send![number(value)?, boolValue];
+        Some(from_objc_bool(value))
+    }
+}
+
+impl FromKvoValue for NSString {
+    unsafe fn from_kvo_value(value: Id) -> Option<Self> {
+        let is_string: BOOL = msg_send![value, isKindOfClass: class!(NSString)];
+        if from_objc_bool(is_string) {
+            Some(NSString(retained(value)))
+        } else {
+            None
+        }
+    }
+}
+
+/// The value as is, nil for `NSNull`; valid for the duration of the callback only.
+impl FromKvoValue for Id {
+    unsafe fn from_kvo_value(value: Id) -> Option<Self> {
+        Some(value)
+    }
+}
+
+/// `None` for nil and `NSNull`, so that the callback sees a property being cleared.
+impl<T: FromKvoValue> FromKvoValue for Option<T> {
+    unsafe fn from_kvo_value(value: Id) -> Option<Self> {
+        if value.is_null() {
+            Some(None)
+        } else {
+            T::from_kvo_value(value).map(Some)
+        }
+    }
+}
+
+/// Both values of a change, each `None` if missing or not decodable; `old` needs
+/// [`KvoOption::old_value`] and is missing from the initial notification.
+#[derive(Debug, Clone, PartialEq)]
+pub struct KvoChange<T> {
+    pub new: Option<T>,
+    pub old: Option<T>,
+}
+
+impl<T: FromKvoValue> FromKvoValue for KvoChange<T> {
+    unsafe fn from_kvo_value(value: Id) -> Option<Self> {
+        Some(KvoChange {
+            new: T::from_kvo_value(value),
+            old: None,
+        })
+    }
+
+    unsafe fn from_change(change: Id) -> Option<Self> {
+        Some(KvoChange {
+            new: T::from_kvo_value(change_entry(change, NSKeyValueChangeNewKey)),
+            old: T::from_kvo_value(change_entry(change, NSKeyValueChangeOldKey)),
+        })
+    }
+}
+
+/// Called with the change dictionary of every notification; returning `false` ends the
+/// observation.
+type Callback = Box<dyn Fn(Id) -> bool + Send>;
+
+/// Shared by the observer object, which holds one reference in its ivar, and each notification
+/// being delivered, so that a notification racing the removal never sees it freed.
+struct Handler {
+    /// Serializes the callbacks; emptied on removal to drop what the callback captured.
+    callback: Mutex<Option<Callback>>,
+    registration: Weak<Registration>,
+}
+
+thread_local! {
+    /// The handlers whose callbacks run on this thread, innermost last.
+    static DELIVERING: RefCell<Vec<*const Handler>> = const { RefCell::new(Vec::new()) };
+}
+
+fn delivering(handler: *const Handler) -> bool {
+    DELIVERING.with(|d| d.borrow().contains(&handler))
+}
+
+struct Registration {
+    observer: StrongPtr,
+    object: StrongPtr,
+    key_path: NSString,
+    options: NSUInteger,
+    /// Where the observer is added and removed, for objects bound to a queue.
+    queue: Option<DispatchQueue>,
+    handler: Arc<Handler>,
+    removed: AtomicBool,
+}
+
+// The observed object is only sent additions and removals of observers, on its queue if it has
+// one; the observer only reads its ivar. The callbacks are `Send` and serialized by the handler.
+unsafe impl Send for Registration {}
+unsafe impl Sync for Registration {}
+
+impl Registration {
+    /// The context pointer of this registration.
+    fn context(&self) -> *mut c_void {
+        Arc::as_ptr(&self.handler) as *mut c_void
+    }
+
+    /// Must run on the queue, if any.
+    fn add(&self) {
+        unsafe {
+            let _: () = msg_send![
+                *self.object,
+                addObserver: *self.observer
+                forKeyPath: *self.key_path.0
+                options: self.options
+                context: self.context()
+            ];
+        }
+    }
+
+    /// Must run on the queue, if any. Idempotent.
+    fn remove(&self) {
+        if self.removed.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        unsafe {
+            let _: () = msg_send![
+                *self.object,
+                removeObserver: *self.observer
+                forKeyPath: *self.key_path.0
+                context: self.context()
+            ];
+        }
+        // A callback running on this thread is the caller; it cannot be waited for.
+        if !delivering(Arc::as_ptr(&self.handler)) {
+            let callback = self
+                .handler
+                .callback
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .take();
+            drop(callback);
+        }
+    }
+
+    /// Removes the observer on the queue, if any, or right away.
+    fn end(self: Arc<Self>) {
+        match &self.queue {
+            Some(queue) => {
+                let queue = queue.clone();
+                queue.exec_async(move || self.remove());
+            }
+            None => self.remove(),
+        }
+    }
+}
+
+/// Ends the observation when dropped.
+#[must_use = "the observation ends when the guard is dropped"]
+pub struct KvoGuard(Arc<Registration>);
+
+/// The guard of an observation; the name predates [`KvoGuard`].
+pub type ObservationGuard = KvoGuard;
+
+impl KvoGuard {
+    /// Removes the observer now instead of on the queue later. Must run on the queue the
+    /// observation was made with, if any.
+    pub(crate) fn remove_now(self) {
+        self.0.remove();
+    }
+}
+
+impl Drop for KvoGuard {
+    fn drop(&mut self) {
+        self.0.clone().end();
+    }
+}
+
+/// Observes `keypath` of `object` with `options`, calling `callback` with the decoded value of
+/// each notification that decodes; see the [module documentation](self) for the guarantees.
+/// Include [`KvoOption::new_value`] in the options for values to decode from.
+///
+/// # Safety
+/// `object` must be a valid object that is key-value observing compliant for `keypath` and may
+/// be observed from any thread.
+pub unsafe fn observe_keypath<T, F>(
+    object: Id,
+    keypath: &str,
+    options: KvoOptions,
+    callback: F,
+) -> KvoGuard
+where
+    T: FromKvoValue,
+    F: Fn(T) + Send + 'static,
+{
+    register(
+        object,
+        keypath,
+        options.into_raw(),
+        None,
+        Box::new(move |change| {
+            if let Some(value) = T::from_change(change) {
+                callback(value);
+            }
+            true
+        }),
+    )
+}
+
+/// Observes the `fractionCompleted` of `progress`, starting with its current value.
+///
+/// # Safety
+/// `progress` must be a valid `NSProgress`.
+pub unsafe fn observe_fraction_completed<F>(progress: Id, callback: F) -> KvoGuard
+where
+    F: Fn(f64) + Send + 'static,
+{
+    observe_keypath(progress, "fractionCompleted", initial_and_new(), callback)
+}
+
+/// Observes `key_path` of `object`, whose changes are posted on `queue`.
+///
+/// The callback first runs with the current value and then on each change, always on `queue`,
+/// until it returns `false` or the guard is dropped. Values that do not decode are skipped.
+///
+/// # Safety
+/// `object` must be a valid object that posts changes of `key_path` on `queue`.
+pub(crate) unsafe fn observe_on<T, F>(
+    object: Id,
+    key_path: &str,
+    queue: &DispatchQueue,
+    callback: F,
+) -> KvoGuard
+where
+    T: FromKvoValue,
+    F: Fn(T) -> bool + Send + 'static,
+{
+    register(
+        object,
+        key_path,
+        initial_and_new().into_raw(),
+        Some(queue.clone()),
+        Box::new(move |change| match T::from_change(change) {
+            Some(value) => callback(value),
+            None => true,
+        }),
+    )
+}
+
+unsafe fn register(
+    object: Id,
+    key_path: &str,
+    options: NSUInteger,
+    queue: Option<DispatchQueue>,
+    callback: Callback,
+) -> KvoGuard {
+    let observer = owned(msg_send![alloc(observer_class()), init]);
+    let registration = Arc::new_cyclic(|weak| Registration {
+        observer,
+        object: retained(object),
+        key_path: NSString::new(key_path),
+        options,
+        queue,
+        handler: Arc::new(Handler {
+            callback: Mutex::new(Some(callback)),
+            registration: weak.clone(),
+        }),
+        removed: AtomicBool::new(false),
+    });
+    let handler = Arc::into_raw(registration.handler.clone());
+    (**registration.observer).set_ivar(HANDLER_IVAR, handler as *mut c_void);
+
+    match &registration.queue {
+        Some(queue) => {
+            let pending = registration.clone();
+            queue.exec_async(move || pending.add());
+        }
+        None => registration.add(),
+    }
+    KvoGuard(registration)
+}
+
+fn observer_class() -> &'static Class {
+    static REGISTER: Once = Once::new();
+    REGISTER.call_once(|| {
+        let mut decl = ClassDecl::new(OBSERVER_CLASS, class!(NSObject)).unwrap();
+        decl.add_ivar::<*mut c_void>(HANDLER_IVAR);
+        unsafe {
+            decl.add_method(
+                sel!(observeValueForKeyPath:ofObject:change:context:),
+                observe_value as extern "C" fn(&Object, Sel, Id, Id, Id, *mut c_void),
+            );
+            decl.add_method(sel!(dealloc), dealloc as extern "C" fn(&Object, Sel));
+        }
+        decl.register();
+    });
+    Class::get(OBSERVER_CLASS).unwrap()
+}
+
+extern "C" fn observe_value(
+    this: &Object,
+    _cmd: Sel,
+    _key_path: Id,
+    _object: Id,
+    change: Id,
+    context: *mut c_void,
+) {
+    let ptr = unsafe { *this.get_ivar::<*mut c_void>(HANDLER_IVAR) as *const Handler };
+    // Another registration's context: the observer only ever registers its own.
+    if ptr.is_null() || context != ptr as *mut c_void {
+        return;
+    }
+    let handler = unsafe {
+        Arc::increment_strong_count(ptr);
+        Arc::from_raw(ptr)
+    };
+    let registration = match handler.registration.upgrade() {
+        Some(registration) => registration,
+        None => return,
+    };
+    if registration.removed.load(Ordering::SeqCst) || delivering(ptr) {
+        return;
+    }
+    let keep = {
+        let callback = handler.callback.lock().unwrap_or_else(|e| e.into_inner());
+        let callback = match &*callback {
+            // Removed while this notification waited for another callback.
+            Some(_) if registration.removed.load(Ordering::SeqCst) => return,
+            Some(callback) => callback,
+            None => return,
+        };
+        DELIVERING.with(|d| d.borrow_mut().push(ptr));
+        let keep = callback(change);
+        DELIVERING.with(|d| d.borrow_mut().pop());
+        keep
+    };
+    if !keep {
+        // Deferred on a queue, so it does not run inside the notification being delivered.
+        registration.end();
+    }
+}
+
+extern "C" fn dealloc(this: &Object, _cmd: Sel) {
+    unsafe {
+        let handler = *this.get_ivar::<*mut c_void>(HANDLER_IVAR) as *const Handler;
+        if !handler.is_null() {
+            drop(Arc::from_raw(handler));
+        }
+        let _: () = msg_send![super(this, class!(NSObject)), dealloc];
+    }
+}
@@ -10,5 +10,45 @@
 extern crate block;
 extern crate objc;
 
+pub mod admission;
 pub mod base;
+#[cfg(feature = "capi")]
+pub mod capi;
+pub mod diagnostics;
+pub mod disk_image;
+#[cfg(feature = "test-util")]
+pub mod fake_vm;
+pub mod features;
+#[cfg(feature = "cloud-init")]
+pub mod cloudinit;
+pub mod identity;
+#[cfg(feature = "macos-guest")]
+pub mod install_flow;
+#[cfg(feature = "isolation")]
+pub mod isolation;
+pub mod kvo;
+pub mod limits;
+pub mod lint;
+pub mod liveness;
+#[cfg(feature = "macos-guest")]
+pub mod mac_bundle;
+pub mod metrics;
+pub mod nat;
+pub mod presets;
+pub mod profile;
+pub mod queue_pool;
+pub mod queue_watchdog;
+pub mod reconcile;
+pub mod registry;
+pub mod resource;
+pub mod respawn;
+pub mod runtime;
+#[cfg(feature = "spec")]
+pub mod spec;
+pub mod strict;
+pub mod teardown;
+#[cfg(feature = "test-util")]
+#[doc(hidden)]
+pub mod test_support;
+pub mod timeline;
 pub mod virtualization;
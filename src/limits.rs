@@ -0,0 +1,206 @@
+//! limits module
+//!
+//! How many devices of each category a configuration may hold. Going over a limit makes
+//! `validateWithError:` fail with a generic "invalid virtual machine configuration" error that
+//! names neither the category nor the limit, so the builder's
+//! [`validated_build`](crate::virtualization::virtual_machine::VZVirtualMachineConfigurationBuilder::validated_build)
+//! checks the counts against this table first, and UIs can ask [`DeviceLimits::allows_another`]
+//! before offering to add a device.
+//!
+//! | Category | Limit | Source |
+//! |---|---|---|
+//! | `storage_devices` | undocumented | |
+//! | `network_devices` | undocumented | |
+//! | `serial_ports` | undocumented | |
+//! | `entropy_devices` | 1 | validation refuses a second `VZVirtioEntropyDeviceConfiguration` |
+//! | `memory_balloon_devices` | 1 | documentation of `memoryBalloonDevices` |
+//! | `socket_devices` | 1 | documentation of `socketDevices` |
+//! | `directory_sharing_devices` | undocumented, macOS 12 and later | |
+//! | `graphics_devices` | 1, macOS 12 and later | documentation of `graphicsDevices` |
+//! | `keyboards` | undocumented, macOS 12 and later | |
+//! | `pointing_devices` | undocumented, macOS 12 and later | |
+//! | `audio_devices` | undocumented, macOS 12 and later | |
+//! | `console_devices` | undocumented, macOS 13 and later | |
+//!
+//! The limits depend on the macOS release: a category newer than the running system allows no
+//! device at all. [`device_limits`] reads the running release through
+//! [`features::os_version`]; [`DeviceLimits::for_os_version`] gives the table of another.
+//!
+//! # Examples
+//! ```rust
+//! let limits = limits::device_limits();
+//! let balloons = conf.device_count("memory_balloon_devices").unwrap_or(0);
+//! add_balloon_button.set_enabled(limits.allows_another("memory_balloon_devices", balloons));
+//! ```
+
+use crate::features::{self, OsVersion};
+
+use std::fmt;
+
+/// How many devices of a category a configuration may hold.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeviceLimit {
+    AtMost(usize),
+    /// Apple does not document a limit; the framework's validation may still enforce one.
+    Undocumented,
+    /// The category is newer than the macOS release, which allows no device of it.
+    Unavailable {
+        /// The first release providing it, e.g. "macOS 12".
+        since: &'static str,
+    },
+}
+
+impl DeviceLimit {
+    /// Whether a configuration may hold `count` devices of the category.
+    pub fn allows(&self, count: usize) -> bool {
+        match *self {
+            DeviceLimit::AtMost(limit) => count <= limit,
+            DeviceLimit::Undocumented => true,
+            DeviceLimit::Unavailable { .. } => count == 0,
+        }
+    }
+}
+
+impl fmt::Display for DeviceLimit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DeviceLimit::AtMost(limit) => write!(f, "at most {}", limit),
+            DeviceLimit::Undocumented => write!(f, "undocumented"),
+            DeviceLimit::Unavailable { since } => write!(f, "none before {}", since),
+        }
+    }
+}
+
+/// The limit of a category that is unbounded as far as Apple documents, from macOS `major` on.
+fn undocumented_since(version: OsVersion, major: u32, since: &'static str) -> DeviceLimit {
+    if version.major >= major {
+        DeviceLimit::Undocumented
+    } else {
+        DeviceLimit::Unavailable { since }
+    }
+}
+
+/// The limit of a category on a macOS release.
+type LimitOn = fn(OsVersion) -> DeviceLimit;
+
+/// The table of the [module documentation](self): each device array, as
+/// [`describe`](crate::virtualization::virtual_machine::VZVirtualMachineConfiguration::describe)
+/// names it, with its limit on a macOS release.
+const DEVICE_LIMITS: &[(&str, LimitOn)] = &[
+    ("storage_devices", |_| DeviceLimit::Undocumented),
+    ("network_devices", |_| DeviceLimit::Undocumented),
+    ("serial_ports", |_| DeviceLimit::Undocumented),
+    // Not in the documentation; `validateWithError:` refuses a second one.
+    ("entropy_devices", |_| DeviceLimit::AtMost(1)),
+    // VZVirtualMachineConfiguration.memoryBalloonDevices documents at most one.
+    ("memory_balloon_devices", |_| DeviceLimit::AtMost(1)),
+    // VZVirtualMachineConfiguration.socketDevices documents at most one.
+    ("socket_devices", |_| DeviceLimit::AtMost(1)),
+    ("directory_sharing_devices", |v| {
+        undocumented_since(v, 12, "macOS 12")
+    }),
+    // VZVirtualMachineConfiguration.graphicsDevices documents one graphics device at most.
+    ("graphics_devices", |v| {
+        if v.major >= 12 {
+            DeviceLimit::AtMost(1)
+        } else {
+            DeviceLimit::Unavailable { since: "macOS 12" }
+        }
+    }),
+    ("keyboards", |v| undocumented_since(v, 12, "macOS 12")),
+    ("pointing_devices", |v| {
+        undocumented_since(v, 12, "macOS 12")
+    }),
+    ("audio_devices", |v| undocumented_since(v, 12, "macOS 12")),
+    ("console_devices", |v| undocumented_since(v, 13, "macOS 13")),
+];
+
+/// The device limits of a macOS release.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeviceLimits {
+    os_version: OsVersion,
+}
+
+impl DeviceLimits {
+    pub fn for_os_version(os_version: OsVersion) -> DeviceLimits {
+        DeviceLimits { os_version }
+    }
+
+    pub fn os_version(&self) -> OsVersion {
+        self.os_version
+    }
+
+    /// The limit of `category`, e.g. `memory_balloon_devices`; `None` for a name the table does
+    /// not list.
+    pub fn get(&self, category: &str) -> Option<DeviceLimit> {
+        DEVICE_LIMITS
+            .iter()
+            .find(|(name, _)| *name == category)
+            .map(|(_, limit)| limit(self.os_version))
+    }
+
+    /// Every category with its limit, in the order of the table.
+    pub fn iter(&self) -> impl Iterator<Item = (&'static str, DeviceLimit)> + '_ {
+        DEVICE_LIMITS
+            .iter()
+            .map(move |&(name, limit)| (name, limit(self.os_version)))
+    }
+
+    /// Whether a configuration holding `configured` devices of `category` may take one more.
+    pub fn allows_another(&self, category: &str, configured: usize) -> bool {
+        match self.get(category) {
+            Some(limit) => limit.allows(configured.saturating_add(1)),
+            None => true,
+        }
+    }
+
+    /// Fails if `configured` devices of `category` are more than its limit allows. Categories
+    /// the table does not list pass.
+    pub fn check(
+        &self,
+        category: &'static str,
+        configured: usize,
+    ) -> Result<(), DeviceLimitExceeded> {
+        match self.get(category) {
+            Some(limit) if !limit.allows(configured) => Err(DeviceLimitExceeded {
+                category,
+                configured,
+                limit,
+            }),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// The device limits of the running macOS.
+pub fn device_limits() -> DeviceLimits {
+    DeviceLimits::for_os_version(features::os_version())
+}
+
+/// A configuration holds more devices of a category than its limit allows.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeviceLimitExceeded {
+    /// e.g. `memory_balloon_devices`.
+    pub category: &'static str,
+    pub configured: usize,
+    pub limit: DeviceLimit,
+}
+
+impl fmt::Display for DeviceLimitExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.limit {
+            DeviceLimit::Unavailable { since } => write!(
+                f,
+                "{}: {} configured, but this system has none; they need {}",
+                self.category, self.configured, since
+            ),
+            limit => write!(
+                f,
+                "{}: {} configured, the framework allows {}",
+                self.category, self.configured, limit
+            ),
+        }
+    }
+}
+
+impl std::error::Error for DeviceLimitExceeded {}
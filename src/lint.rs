@@ -0,0 +1,217 @@
+//! lint module
+//!
+//! Flags configurations that validate but are likely a mistake, e.g. a Linux guest without an
+//! entropy device, which waits for its random number generator at boot. Each [`Lint`] has a
+//! stable name and a default [`Severity`]; a [`LintConfig`] silences lints or changes their
+//! severity, so a deliberately minimal configuration can acknowledge what it leaves out.
+//!
+//! | lint | default | flags |
+//! |---|---|---|
+//! | `missing-entropy` | warning | no entropy device |
+//! | `missing-balloon` | warning | no memory balloon device |
+//! | `missing-console` | warning | no serial port, console device or graphics device |
+//! | `missing-network` | note | no network device |
+//! | `device-limit` | error | more devices of a category than this macOS allows |
+//! | `duplicate-mac-address` | error | two network devices with one MAC address |
+//!
+//! # Examples
+//! ```rust
+//! let config = LintConfig::new()
+//!     .allow(Lint::MissingBalloon)
+//!     .severity("missing-entropy".parse()?, Severity::Note);
+//! for finding in lint::check(&conf, &config) {
+//!     eprintln!("{}: {}", finding.severity, finding);
+//! }
+//! ```
+
+pub use crate::diagnostics::Severity;
+
+use crate::virtualization::virtual_machine::VZVirtualMachineConfiguration;
+
+use std::cmp::Reverse;
+use std::fmt;
+use std::str::FromStr;
+
+/// Something [`check`] looks for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Lint {
+    MissingEntropy,
+    MissingBalloon,
+    MissingConsole,
+    MissingNetwork,
+    DeviceLimit,
+    DuplicateMacAddress,
+}
+
+const LINTS: &[Lint] = &[
+    Lint::MissingEntropy,
+    Lint::MissingBalloon,
+    Lint::MissingConsole,
+    Lint::MissingNetwork,
+    Lint::DeviceLimit,
+    Lint::DuplicateMacAddress,
+];
+
+impl Lint {
+    pub fn all() -> &'static [Lint] {
+        LINTS
+    }
+
+    /// The stable name configurations and [`FromStr`] use, e.g. `missing-balloon`.
+    pub fn name(self) -> &'static str {
+        match self {
+            Lint::MissingEntropy => "missing-entropy",
+            Lint::MissingBalloon => "missing-balloon",
+            Lint::MissingConsole => "missing-console",
+            Lint::MissingNetwork => "missing-network",
+            Lint::DeviceLimit => "device-limit",
+            Lint::DuplicateMacAddress => "duplicate-mac-address",
+        }
+    }
+
+    /// The severity without a [`LintConfig`] override.
+    pub fn default_severity(self) -> Severity {
+        match self {
+            Lint::MissingNetwork => Severity::Note,
+            Lint::DeviceLimit | Lint::DuplicateMacAddress => Severity::Error,
+            _ => Severity::Warning,
+        }
+    }
+}
+
+impl fmt::Display for Lint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// A name that is not one of [`Lint::all`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseLintError(pub String);
+
+impl fmt::Display for ParseLintError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "unknown lint {:?}", self.0)
+    }
+}
+
+impl std::error::Error for ParseLintError {}
+
+impl FromStr for Lint {
+    type Err = ParseLintError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        LINTS
+            .iter()
+            .copied()
+            .find(|lint| lint.name() == s)
+            .ok_or_else(|| ParseLintError(s.to_string()))
+    }
+}
+
+/// Which lints [`check`] reports, and how severely.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LintConfig {
+    /// `None` silences the lint. The last entry for a lint wins.
+    overrides: Vec<(Lint, Option<Severity>)>,
+}
+
+impl LintConfig {
+    /// Every lint at its default severity.
+    pub fn new() -> LintConfig {
+        LintConfig::default()
+    }
+
+    /// Stops reporting `lint`, for what a configuration leaves out on purpose.
+    pub fn allow(mut self, lint: Lint) -> Self {
+        self.overrides.push((lint, None));
+        self
+    }
+
+    /// Reports `lint` at `severity` instead of its default, e.g. [`Severity::Note`] to keep it
+    /// visible without failing a build that refuses warnings.
+    pub fn severity(mut self, lint: Lint, severity: Severity) -> Self {
+        self.overrides.push((lint, Some(severity)));
+        self
+    }
+
+    /// The severity `lint` is reported at; `None` if it is allowed.
+    pub fn level(&self, lint: Lint) -> Option<Severity> {
+        self.overrides
+            .iter()
+            .rev()
+            .find(|(overridden, _)| *overridden == lint)
+            .map_or(Some(lint.default_severity()), |(_, level)| *level)
+    }
+}
+
+/// A lint [`check`] found.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LintFinding {
+    pub lint: Lint,
+    /// As the [`LintConfig`] set it.
+    pub severity: Severity,
+    pub message: String,
+}
+
+/// The message, with the lint's name to allow it by, e.g.
+/// `no memory balloon device; the host cannot reclaim guest memory [missing-balloon]`.
+impl fmt::Display for LintFinding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} [{}]", self.message, self.lint)
+    }
+}
+
+/// The lints `conf` triggers that `config` does not allow, most severe first.
+pub fn check(conf: &VZVirtualMachineConfiguration, config: &LintConfig) -> Vec<LintFinding> {
+    let count = |category: &str| conf.device_count(category).unwrap_or(0);
+    let mut found: Vec<(Lint, String)> = Vec::new();
+    if count("entropy_devices") == 0 {
+        found.push((
+            Lint::MissingEntropy,
+            "no entropy device; a Linux guest may wait for entropy at boot".to_string(),
+        ));
+    }
+    if count("memory_balloon_devices") == 0 {
+        found.push((
+            Lint::MissingBalloon,
+            "no memory balloon device; the host cannot reclaim guest memory".to_string(),
+        ));
+    }
+    if count("serial_ports") + count("console_devices") + count("graphics_devices") == 0 {
+        found.push((
+            Lint::MissingConsole,
+            "no serial port, console device or graphics device; the guest's output goes nowhere"
+                .to_string(),
+        ));
+    }
+    let macs = conf.network_mac_addresses();
+    if macs.is_empty() {
+        found.push((Lint::MissingNetwork, "no network device".to_string()));
+    }
+    for violation in conf.device_limit_violations() {
+        found.push((Lint::DeviceLimit, violation.to_string()));
+    }
+    // Once per address, at its second device.
+    for (i, mac) in macs.iter().enumerate() {
+        if macs[..i].iter().filter(|m| *m == mac).count() == 1 {
+            found.push((
+                Lint::DuplicateMacAddress,
+                format!("more than one network device has the MAC address {}", mac),
+            ));
+        }
+    }
+
+    let mut findings: Vec<LintFinding> = found
+        .into_iter()
+        .filter_map(|(lint, message)| {
+            config.level(lint).map(|severity| LintFinding {
+                lint,
+                severity,
+                message,
+            })
+        })
+        .collect();
+    findings.sort_by_key(|finding| Reverse(finding.severity));
+    findings
+}
@@ -0,0 +1,257 @@
+//! liveness module
+//!
+//! Answers "did the guest reach userspace?" without SSH, either by waiting for a marker string on
+//! the console or by a one-byte echo over vsock.
+//!
+//! # Examples
+//! ```rust
+//! let capture = ConsoleCapture::new().unwrap();
+//! let serial = VZVirtioConsoleDeviceSerialPortConfiguration::new(capture.attachment());
+//! // ... build and start the virtual machine ...
+//! let token = CancellationToken::new();
+//! let elapsed = Liveness::console_marker(capture.buffer(), "login:")
+//!     .wait(Duration::from_secs(60), &token);
+//! ```
+
+use crate::base::CancellationToken;
+use crate::timeline::{TimelineEventKind, TimelineHandle};
+use crate::virtualization::error::{CompletionOutcome, VZErrorCtx};
+use crate::virtualization::serial_port::ConsoleBuffer;
+use crate::virtualization::socket_device::{VZVirtioSocketConnection, VZVirtioSocketDevice};
+use crate::virtualization::vsock::{Endpoint, VsockPort};
+
+use std::fmt;
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Granularity at which waits check the cancellation token, which has no way to wake them.
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Why a liveness check did not succeed.
+#[derive(Debug)]
+pub enum LivenessError {
+    /// The guest did not answer before the timeout.
+    Timeout,
+    /// The check was cancelled through its token.
+    Cancelled,
+    /// The console or connection was closed before the guest answered.
+    Closed,
+    /// The guest answered the vsock ping with a different byte.
+    UnexpectedReply(u8),
+    /// The framework refused the vsock connection, naming the guest's endpoint.
+    Connect(VZErrorCtx),
+    Io(io::Error),
+}
+
+impl fmt::Display for LivenessError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LivenessError::Timeout => f.write_str("the guest did not answer before the timeout"),
+            LivenessError::Cancelled => f.write_str("the liveness check was cancelled"),
+            LivenessError::Closed => {
+                f.write_str("the console or connection was closed before the guest answered")
+            }
+            LivenessError::UnexpectedReply(byte) => {
+                write!(f, "the guest answered the ping with {:#04x}", byte)
+            }
+            LivenessError::Connect(error) => error.fmt(f),
+            LivenessError::Io(error) => write!(f, "liveness check failed: {}", error),
+        }
+    }
+}
+
+impl std::error::Error for LivenessError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            LivenessError::Timeout
+            | LivenessError::Cancelled
+            | LivenessError::Closed
+            | LivenessError::UnexpectedReply(_) => None,
+            // Displayed as the context error itself, so its cause comes next.
+            LivenessError::Connect(error) => error.source(),
+            LivenessError::Io(error) => Some(error),
+        }
+    }
+}
+
+/// A strategy for detecting that the guest is alive.
+pub enum Liveness {
+    /// Wait for `marker` to appear in the captured console output.
+    ConsoleMarker {
+        buffer: Arc<ConsoleBuffer>,
+        marker: String,
+    },
+    /// Connect to `port` and expect the guest to echo back one byte, e.g. to
+    /// [`vsock::GUEST_SOCAT_LISTENER`](crate::virtualization::vsock::GUEST_SOCAT_LISTENER).
+    VsockPing {
+        device: VZVirtioSocketDevice,
+        port: VsockPort,
+    },
+}
+
+impl Liveness {
+    pub fn console_marker<T: Into<String>>(buffer: Arc<ConsoleBuffer>, marker: T) -> Liveness {
+        Liveness::ConsoleMarker {
+            buffer,
+            marker: marker.into(),
+        }
+    }
+
+    pub fn vsock_ping(device: VZVirtioSocketDevice, port: VsockPort) -> Liveness {
+        Liveness::VsockPing { device, port }
+    }
+
+    /// Blocks until the guest is seen alive and returns how long that took.
+    ///
+    /// Must not be called from the VM's queue when using [`Liveness::VsockPing`].
+    pub fn wait(
+        &self,
+        timeout: Duration,
+        token: &CancellationToken,
+    ) -> Result<Duration, LivenessError> {
+        let started = Instant::now();
+        match self {
+            Liveness::ConsoleMarker { buffer, marker } => {
+                if buffer.wait_for(marker.as_bytes(), timeout, token) {
+                    Ok(started.elapsed())
+                } else if token.is_cancelled() {
+                    Err(LivenessError::Cancelled)
+                } else if buffer.is_closed() {
+                    Err(LivenessError::Closed)
+                } else {
+                    Err(LivenessError::Timeout)
+                }
+            }
+            Liveness::VsockPing { device, port } => {
+                let (tx, rx) = mpsc::channel();
+                let deadline = Deadline::arm(device, timeout, tx.clone());
+                device.connect_to_port(*port, move |result| {
+                    let _ = tx.send(Wake::Connected(result));
+                });
+                let connection = loop {
+                    deadline.check(token)?;
+                    match rx.recv_timeout(POLL_INTERVAL) {
+                        Ok(Wake::Connected(CompletionOutcome::Success(connection))) => {
+                            break connection
+                        }
+                        Ok(Wake::Connected(CompletionOutcome::Cancelled)) => {
+                            return Err(LivenessError::Cancelled)
+                        }
+                        Ok(Wake::Connected(CompletionOutcome::Failed(error))) => {
+                            let error = Endpoint::guest(*port).connect_error(error);
+                            return Err(LivenessError::Connect(error));
+                        }
+                        Ok(Wake::Expired) | Err(mpsc::RecvTimeoutError::Timeout) => continue,
+                        Err(mpsc::RecvTimeoutError::Disconnected) => {
+                            return Err(LivenessError::Closed)
+                        }
+                    }
+                };
+                let reply = ping(connection.file_descriptor(), &deadline, token);
+                let _ = connection.close();
+                reply.map(|_| started.elapsed())
+            }
+        }
+    }
+
+    /// [`wait`](Self::wait), recording a milestone in `timeline` when the guest is seen alive:
+    /// the marker for [`Liveness::ConsoleMarker`], `vsock port <port>` for
+    /// [`Liveness::VsockPing`].
+    pub fn wait_recorded(
+        &self,
+        timeout: Duration,
+        token: &CancellationToken,
+        timeline: &TimelineHandle,
+    ) -> Result<Duration, LivenessError> {
+        let elapsed = self.wait(timeout, token)?;
+        let name = match self {
+            Liveness::ConsoleMarker { marker, .. } => marker.clone(),
+            Liveness::VsockPing { port, .. } => format!("vsock port {}", port),
+        };
+        timeline.record(TimelineEventKind::Milestone { name });
+        Ok(elapsed)
+    }
+}
+
+/// What wakes the vsock connect wait.
+enum Wake {
+    Connected(CompletionOutcome<VZVirtioSocketConnection>),
+    Expired,
+}
+
+/// The timeout of a vsock check, armed on the VM's queue with [`DispatchQueue::after`] and
+/// disarmed when dropped.
+///
+/// [`DispatchQueue::after`]: crate::base::DispatchQueue::after
+struct Deadline {
+    expired: Arc<AtomicBool>,
+    timer: CancellationToken,
+}
+
+impl Deadline {
+    fn arm(device: &VZVirtioSocketDevice, timeout: Duration, wake: mpsc::Sender<Wake>) -> Deadline {
+        let expired = Arc::new(AtomicBool::new(false));
+        let flag = expired.clone();
+        let timer = device.queue().after(timeout, move || {
+            flag.store(true, Ordering::SeqCst);
+            let _ = wake.send(Wake::Expired);
+        });
+        Deadline { expired, timer }
+    }
+
+    fn check(&self, token: &CancellationToken) -> Result<(), LivenessError> {
+        if token.is_cancelled() {
+            Err(LivenessError::Cancelled)
+        } else if self.expired.load(Ordering::SeqCst) {
+            Err(LivenessError::Timeout)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl Drop for Deadline {
+    fn drop(&mut self) {
+        self.timer.cancel();
+    }
+}
+
+fn ping(
+    fd: libc::c_int,
+    deadline: &Deadline,
+    token: &CancellationToken,
+) -> Result<(), LivenessError> {
+    let request = [b'.'];
+    if unsafe { libc::write(fd, request.as_ptr() as *const libc::c_void, 1) } != 1 {
+        return Err(LivenessError::Io(io::Error::last_os_error()));
+    }
+    loop {
+        deadline.check(token)?;
+        let mut pollfd = libc::pollfd {
+            fd,
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        let ready = unsafe { libc::poll(&mut pollfd, 1, POLL_INTERVAL.as_millis() as libc::c_int) };
+        if ready == -1 {
+            let error = io::Error::last_os_error();
+            if error.kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(LivenessError::Io(error));
+        }
+        if ready == 0 {
+            continue;
+        }
+        let mut reply = [0u8];
+        return match unsafe { libc::read(fd, reply.as_mut_ptr() as *mut libc::c_void, 1) } {
+            1 if reply == request => Ok(()),
+            1 => Err(LivenessError::UnexpectedReply(reply[0])),
+            0 => Err(LivenessError::Closed),
+            _ => Err(LivenessError::Io(io::Error::last_os_error())),
+        };
+    }
+}
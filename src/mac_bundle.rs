@@ -0,0 +1,350 @@
+//! mac bundle module
+//!
+//! Keeps what makes a macOS guest the same Mac across runs — hardware model, machine identifier
+//! and auxiliary storage — together in one bundle directory, laid out like the `VM.bundle` of
+//! Apple's sample code:
+//!
+//! | file | contents |
+//! |---|---|
+//! | `HardwareModel` | data representation of the `VZMacHardwareModel` |
+//! | `MachineIdentifier` | data representation of the `VZMacMachineIdentifier` |
+//! | `AuxiliaryStorage` | the guest's auxiliary storage, from [`VZMacAuxiliaryStorage::create`] |
+//! | `Manifest` | format version, length and checksum of both data files, auxiliary storage path |
+//!
+//! The manifest is written last and renamed into place, so a bundle whose save was interrupted
+//! fails to load with [`BundleError::Corrupted`] instead of mixing old and new files.
+//!
+//! [`VZMacAuxiliaryStorage::create`]: crate::virtualization::platform::VZMacAuxiliaryStorage::create
+//!
+//! # Examples
+//! ```rust
+//! let dir = Path::new("vms/macos.bundle");
+//! let bundle = match MacVmBundle::load(dir) {
+//!     Ok(bundle) => bundle,
+//!     Err(BundleError::Io(e)) if e.kind() == io::ErrorKind::NotFound => {
+//!         let requirements = image.most_featureful_supported_configuration().unwrap();
+//!         let bundle = MacVmBundle::create(dir, &requirements.hardware_model())?;
+//!         bundle.save(dir)?;
+//!         bundle
+//!     }
+//!     Err(e) => return Err(e),
+//! };
+//! let hardware_model = bundle.hardware_model()?;
+//! let machine_identifier = bundle.machine_identifier()?;
+//! ```
+
+use crate::features::{self, UnsupportedOnThisHost};
+use crate::identity::fnv1a;
+use crate::virtualization::restore_image::{VZMacHardwareModel, VZMacMachineIdentifier};
+
+use std::error::Error;
+use std::fmt::{self, Write as _};
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+const HARDWARE_MODEL_FILE: &str = "HardwareModel";
+const MACHINE_IDENTIFIER_FILE: &str = "MachineIdentifier";
+/// Default name of the auxiliary storage inside the bundle.
+const AUXILIARY_STORAGE_FILE: &str = "AuxiliaryStorage";
+const MANIFEST_FILE: &str = "Manifest";
+const FORMAT_VERSION: u32 = 1;
+
+/// Why a bundle could not be created, read or used.
+#[derive(Debug)]
+pub enum BundleError {
+    Io(io::Error),
+    /// A file of the bundle is missing, truncated, fails its checksum or holds invalid data. The
+    /// bundle is left as is; recreating it would give the guest a different Mac.
+    Corrupted(String),
+    /// The manifest was written by a newer version of this crate.
+    UnsupportedVersion(u32),
+    /// Mac guests need an Apple silicon host.
+    UnsupportedHost(UnsupportedOnThisHost),
+    /// This host cannot run the saved hardware model, e.g. one of a newer Mac.
+    HardwareModelUnsupported,
+}
+
+impl From<io::Error> for BundleError {
+    fn from(e: io::Error) -> Self {
+        BundleError::Io(e)
+    }
+}
+
+impl fmt::Display for BundleError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BundleError::Io(e) => write!(f, "bundle I/O failed: {}", e),
+            BundleError::Corrupted(reason) => write!(f, "bundle is corrupted: {}", reason),
+            BundleError::UnsupportedVersion(version) => {
+                write!(
+                    f,
+                    "bundle format version {} is newer than this crate",
+                    version
+                )
+            }
+            BundleError::UnsupportedHost(e) => e.fmt(f),
+            BundleError::HardwareModelUnsupported => {
+                write!(f, "this host does not support the bundle's hardware model")
+            }
+        }
+    }
+}
+
+impl Error for BundleError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            BundleError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// The persistent identity of a macOS guest.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MacVmBundle {
+    /// Data representation of a `VZMacHardwareModel`.
+    pub hardware_model: Vec<u8>,
+    /// Data representation of a `VZMacMachineIdentifier`.
+    pub machine_identifier: Vec<u8>,
+    /// The guest's auxiliary storage. A path inside the bundle is saved relative to it, so the
+    /// bundle can be moved.
+    pub auxiliary_storage: PathBuf,
+}
+
+impl MacVmBundle {
+    /// Bundle contents for a new guest of `hardware_model`, e.g. from the configuration
+    /// requirements of a restore image, with a new machine identifier and `AuxiliaryStorage` in
+    /// `dir` as auxiliary storage. Nothing is written; call [`MacVmBundle::save`].
+    pub fn create(
+        dir: &Path,
+        hardware_model: &VZMacHardwareModel,
+    ) -> Result<MacVmBundle, BundleError> {
+        if !hardware_model.is_supported() {
+            return Err(BundleError::HardwareModelUnsupported);
+        }
+        let machine_identifier =
+            VZMacMachineIdentifier::new().map_err(BundleError::UnsupportedHost)?;
+        Ok(MacVmBundle {
+            hardware_model: hardware_model.data_representation(),
+            machine_identifier: machine_identifier.data_representation(),
+            auxiliary_storage: dir.join(AUXILIARY_STORAGE_FILE),
+        })
+    }
+
+    /// Reads the bundle saved in `dir`. Fails with [`BundleError::Io`] of kind `NotFound` if
+    /// `dir` has no manifest, and with [`BundleError::Corrupted`] if a data file does not match
+    /// it.
+    pub fn load(dir: &Path) -> Result<MacVmBundle, BundleError> {
+        let manifest = Manifest::decode(&fs::read_to_string(dir.join(MANIFEST_FILE))?)?;
+        let hardware_model = manifest.hardware_model.read(dir, HARDWARE_MODEL_FILE)?;
+        let machine_identifier = manifest
+            .machine_identifier
+            .read(dir, MACHINE_IDENTIFIER_FILE)?;
+        Ok(MacVmBundle {
+            hardware_model,
+            machine_identifier,
+            auxiliary_storage: dir.join(manifest.auxiliary_storage),
+        })
+    }
+
+    /// Saves the bundle in `dir`, creating it if needed. Each file is written next to its final
+    /// name, flushed and renamed into place, the manifest last.
+    pub fn save(&self, dir: &Path) -> Result<(), BundleError> {
+        let auxiliary_storage = self
+            .auxiliary_storage
+            .strip_prefix(dir)
+            .unwrap_or(&self.auxiliary_storage);
+        let manifest = Manifest {
+            hardware_model: Entry::of(&self.hardware_model),
+            machine_identifier: Entry::of(&self.machine_identifier),
+            auxiliary_storage: auxiliary_storage.to_path_buf(),
+        }
+        .encode()?;
+
+        fs::create_dir_all(dir)?;
+        write_file(dir, HARDWARE_MODEL_FILE, &self.hardware_model)?;
+        write_file(dir, MACHINE_IDENTIFIER_FILE, &self.machine_identifier)?;
+        write_file(dir, MANIFEST_FILE, manifest.as_bytes())?;
+        File::open(dir)?.sync_all()?;
+        Ok(())
+    }
+
+    /// The saved hardware model, checked to be supported by this host.
+    pub fn hardware_model(&self) -> Result<VZMacHardwareModel, BundleError> {
+        features::require("VZMacHardwareModel").map_err(BundleError::UnsupportedHost)?;
+        let model = VZMacHardwareModel::from_data(&self.hardware_model)
+            .ok_or_else(|| BundleError::Corrupted("invalid hardware model".to_string()))?;
+        if !model.is_supported() {
+            return Err(BundleError::HardwareModelUnsupported);
+        }
+        Ok(model)
+    }
+
+    pub fn machine_identifier(&self) -> Result<VZMacMachineIdentifier, BundleError> {
+        features::require("VZMacMachineIdentifier").map_err(BundleError::UnsupportedHost)?;
+        VZMacMachineIdentifier::from_data(&self.machine_identifier)
+            .ok_or_else(|| BundleError::Corrupted("invalid machine identifier".to_string()))
+    }
+
+    /// Deletes the bundle saved in `dir` and the auxiliary storage at its default place there,
+    /// e.g. after an installation on it failed. The manifest goes first, so an interrupted removal
+    /// leaves no bundle that loads. Files already missing are skipped; `dir` itself and anything
+    /// else in it stay.
+    pub fn remove(dir: &Path) -> io::Result<()> {
+        for name in &[
+            MANIFEST_FILE,
+            HARDWARE_MODEL_FILE,
+            MACHINE_IDENTIFIER_FILE,
+            AUXILIARY_STORAGE_FILE,
+        ] {
+            for path in &[dir.join(name), dir.join(format!("{}.tmp", name))] {
+                match fs::remove_file(path) {
+                    Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                    _ => {}
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Writes `name` in `dir` through a temporary file, so a crash leaves the old or the new file.
+fn write_file(dir: &Path, name: &str, contents: &[u8]) -> io::Result<()> {
+    let temp = dir.join(format!("{}.tmp", name));
+    let mut file = File::create(&temp)?;
+    file.write_all(contents)?;
+    file.sync_all()?;
+    drop(file);
+    fs::rename(&temp, dir.join(name))
+}
+
+/// What the manifest records about a data file.
+struct Entry {
+    len: usize,
+    checksum: u64,
+}
+
+impl Entry {
+    fn of(bytes: &[u8]) -> Entry {
+        Entry {
+            len: bytes.len(),
+            checksum: fnv1a(bytes),
+        }
+    }
+
+    fn parse(value: &str) -> Option<Entry> {
+        let mut parts = value.split(' ');
+        let entry = Entry {
+            len: parts.next()?.parse().ok()?,
+            checksum: u64::from_str_radix(parts.next()?, 16).ok()?,
+        };
+        if parts.next().is_some() {
+            return None;
+        }
+        Some(entry)
+    }
+
+    /// Reads `name` from `dir` and checks it against the entry.
+    fn read(&self, dir: &Path, name: &str) -> Result<Vec<u8>, BundleError> {
+        let bytes = match fs::read(dir.join(name)) {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                return Err(BundleError::Corrupted(format!("{} is missing", name)))
+            }
+            Err(e) => return Err(BundleError::Io(e)),
+        };
+        if bytes.len() != self.len || fnv1a(&bytes) != self.checksum {
+            return Err(BundleError::Corrupted(format!(
+                "{} does not match the manifest",
+                name
+            )));
+        }
+        Ok(bytes)
+    }
+}
+
+struct Manifest {
+    hardware_model: Entry,
+    machine_identifier: Entry,
+    auxiliary_storage: PathBuf,
+}
+
+impl Manifest {
+    /// `key value` lines followed by a checksum line over everything before it, as in the
+    /// identity file of [`crate::identity`].
+    fn encode(&self) -> Result<String, BundleError> {
+        let auxiliary_storage = self
+            .auxiliary_storage
+            .to_str()
+            .filter(|path| !path.contains('\n'))
+            .ok_or_else(|| {
+                BundleError::Io(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "auxiliary storage path must be UTF-8 without line breaks",
+                ))
+            })?;
+        let mut body = String::new();
+        let _ = writeln!(body, "version {}", FORMAT_VERSION);
+        for (key, entry) in &[
+            ("hardware_model", &self.hardware_model),
+            ("machine_identifier", &self.machine_identifier),
+        ] {
+            let _ = writeln!(body, "{} {} {:016x}", key, entry.len, entry.checksum);
+        }
+        let _ = writeln!(body, "auxiliary_storage {}", auxiliary_storage);
+        let _ = writeln!(body, "checksum {:016x}", fnv1a(body.as_bytes()));
+        Ok(body)
+    }
+
+    fn decode(contents: &str) -> Result<Manifest, BundleError> {
+        let corrupted = |what: &str| BundleError::Corrupted(what.to_string());
+        let checksum_at = contents
+            .rfind("checksum ")
+            .ok_or_else(|| corrupted("missing manifest checksum, the manifest is truncated"))?;
+        let (body, checksum_line) = contents.split_at(checksum_at);
+        let checksum = checksum_line["checksum ".len()..].trim_end_matches('\n');
+        if u64::from_str_radix(checksum, 16).ok() != Some(fnv1a(body.as_bytes())) {
+            return Err(corrupted("manifest checksum mismatch"));
+        }
+
+        let mut version = None;
+        let mut hardware_model = None;
+        let mut machine_identifier = None;
+        let mut auxiliary_storage = None;
+        for line in body.lines() {
+            let (key, value) = match line.find(' ') {
+                Some(i) => (&line[..i], &line[i + 1..]),
+                None => return Err(corrupted("malformed manifest line")),
+            };
+            match key {
+                "version" => {
+                    version = Some(value.parse::<u32>().map_err(|_| corrupted("bad version"))?)
+                }
+                "hardware_model" => {
+                    hardware_model =
+                        Some(Entry::parse(value).ok_or_else(|| corrupted("bad hardware_model"))?)
+                }
+                "machine_identifier" => {
+                    machine_identifier = Some(
+                        Entry::parse(value).ok_or_else(|| corrupted("bad machine_identifier"))?,
+                    )
+                }
+                "auxiliary_storage" => auxiliary_storage = Some(PathBuf::from(value)),
+                // Keys added by later versions of the same format.
+                _ => {}
+            }
+        }
+        let version = version.ok_or_else(|| corrupted("missing version"))?;
+        if version > FORMAT_VERSION {
+            return Err(BundleError::UnsupportedVersion(version));
+        }
+        Ok(Manifest {
+            hardware_model: hardware_model.ok_or_else(|| corrupted("missing hardware_model"))?,
+            machine_identifier: machine_identifier
+                .ok_or_else(|| corrupted("missing machine_identifier"))?,
+            auxiliary_storage: auxiliary_storage
+                .ok_or_else(|| corrupted("missing auxiliary_storage"))?,
+        })
+    }
+}
@@ -0,0 +1,247 @@
+//! metrics module
+//!
+//! Opt-in counters and timings of configuration validation and virtual machine lifecycle
+//! operations, reported to a process-wide [`Metrics`] recorder set with [`set_metrics_recorder`].
+//! Until one is set, nothing is recorded and instrumented calls only pay for an atomic load.
+//!
+//! # Series
+//! The names, kinds and label keys below are the compatibility surface: they only change with a
+//! major version. Label values may grow, e.g. with states of a new macOS release.
+//!
+//! | name | kind | labels | recorded when |
+//! |---|---|---|---|
+//! | `vz_config_validations_total` | counter | `outcome`, `code` | a configuration is validated |
+//! | `vz_vm_operations_total` | counter | `vm`, `operation`, `outcome` | an operation completes |
+//! | `vz_vm_operation_duration_seconds` | histogram | `vm`, `operation`, `outcome` | an operation completes, from the call |
+//! | `vz_vm_start_to_running_seconds` | histogram | `vm` | the machine is running after a start, from the call |
+//! | `vz_vm_forced_stops_total` | counter | `vm` | a stop is sent to the framework |
+//! | `vz_vm_state_transitions_total` | counter | `vm`, `from`, `to` | the machine's state changes |
+//!
+//! Label values:
+//! - `vm` is the machine's [`VmId`] in decimal, e.g. `3`. There is one value per machine the
+//!   process created, so a recorder exporting to a long-lived store may want to drop it.
+//! - `outcome` of a validation is `valid` or `invalid`; of an operation, `success`, `cancelled`
+//!   or `failed`.
+//! - `code` is the framework's error code in decimal, or `none` for a valid configuration.
+//! - `operation` is `start`, `stop`, `pause`, `resume`, `save_machine_state_to` or
+//!   `restore_machine_state_from`. Starts and stops that join one in flight are not counted again.
+//! - `from` and `to` are state names as [`VZVirtualMachineState`] displays them, e.g. `running`.
+//!
+//! [`VmId`]: crate::virtualization::virtual_machine::VmId
+//!
+//! # Examples
+//! ```rust
+//! struct Stderr;
+//!
+//! impl Metrics for Stderr {
+//!     fn counter(&self, name: &'static str, labels: &[(&'static str, &str)]) {
+//!         eprintln!("{} {:?} +1", name, labels);
+//!     }
+//! }
+//!
+//! set_metrics_recorder(Box::new(Stderr))?;
+//! ```
+
+use crate::virtualization::error::{CompletionOutcome, VZErrorCtx};
+use crate::virtualization::virtual_machine::{VZVirtualMachineState, VmId};
+
+use std::error::Error;
+use std::fmt;
+use std::sync::{Mutex, MutexGuard, OnceLock};
+use std::time::Instant;
+
+pub const CONFIG_VALIDATIONS: &str = "vz_config_validations_total";
+pub const VM_OPERATIONS: &str = "vz_vm_operations_total";
+pub const VM_OPERATION_DURATION: &str = "vz_vm_operation_duration_seconds";
+pub const VM_START_TO_RUNNING: &str = "vz_vm_start_to_running_seconds";
+pub const VM_FORCED_STOPS: &str = "vz_vm_forced_stops_total";
+pub const VM_STATE_TRANSITIONS: &str = "vz_vm_state_transitions_total";
+
+/// Receives the series of the [module documentation](self). Both methods do nothing by default,
+/// so a recorder implements only what it exports.
+///
+/// Methods are called on whichever thread or queue the event happens, often a VM's queue, so they
+/// must not block.
+pub trait Metrics: Send + Sync {
+    /// Adds one to the counter `name` with `labels`.
+    fn counter(&self, _name: &'static str, _labels: &[(&'static str, &str)]) {}
+
+    /// Records `value` in the histogram `name` with `labels`; durations are in seconds.
+    fn histogram_observe(
+        &self,
+        _name: &'static str,
+        _value: f64,
+        _labels: &[(&'static str, &str)],
+    ) {
+    }
+}
+
+/// A recorder that drops everything, as if none was set.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NoopMetrics;
+
+impl Metrics for NoopMetrics {}
+
+static RECORDER: OnceLock<Box<dyn Metrics>> = OnceLock::new();
+
+/// [`set_metrics_recorder`] was called before.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RecorderAlreadySet;
+
+impl fmt::Display for RecorderAlreadySet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("a metrics recorder is already set")
+    }
+}
+
+impl Error for RecorderAlreadySet {}
+
+/// Sends the series of all virtual machines in the process to `recorder` from now on. It can be
+/// set once; later calls fail and leave the first recorder in place.
+pub fn set_metrics_recorder(recorder: Box<dyn Metrics>) -> Result<(), RecorderAlreadySet> {
+    RECORDER.set(recorder).map_err(|_| RecorderAlreadySet)
+}
+
+fn recorder() -> Option<&'static dyn Metrics> {
+    RECORDER.get().map(|r| &**r)
+}
+
+pub(crate) fn counter(name: &'static str, labels: &[(&'static str, &str)]) {
+    if let Some(recorder) = recorder() {
+        recorder.counter(name, labels);
+    }
+}
+
+fn histogram_observe(name: &'static str, value: f64, labels: &[(&'static str, &str)]) {
+    if let Some(recorder) = recorder() {
+        recorder.histogram_observe(name, value, labels);
+    }
+}
+
+/// Counts a validation that returned `result`.
+pub(crate) fn validation(result: &Result<bool, VZErrorCtx>) {
+    if recorder().is_none() {
+        return;
+    }
+    let (outcome, code) = match result {
+        Ok(true) => ("valid", "none".to_string()),
+        Ok(false) => ("invalid", "none".to_string()),
+        Err(e) => ("invalid", e.ns_error().code().to_string()),
+    };
+    counter(CONFIG_VALIDATIONS, &[("outcome", outcome), ("code", &code)]);
+}
+
+pub(crate) fn outcome_label<T>(outcome: &CompletionOutcome<T>) -> &'static str {
+    match outcome {
+        CompletionOutcome::Success(_) => "success",
+        CompletionOutcome::Cancelled => "cancelled",
+        CompletionOutcome::Failed(_) => "failed",
+    }
+}
+
+/// Times an operation sent to the framework, from the call to its completion.
+pub(crate) struct OperationTimer {
+    vm: String,
+    operation: &'static str,
+    started: Instant,
+}
+
+impl OperationTimer {
+    pub(crate) fn finish(self, outcome: &CompletionOutcome) {
+        let labels = [
+            ("vm", self.vm.as_str()),
+            ("operation", self.operation),
+            ("outcome", outcome_label(outcome)),
+        ];
+        counter(VM_OPERATIONS, &labels);
+        histogram_observe(
+            VM_OPERATION_DURATION,
+            self.started.elapsed().as_secs_f64(),
+            &labels,
+        );
+    }
+}
+
+/// Per-machine inputs of the series, shared by all handles to a virtual machine.
+pub(crate) struct VmMetrics {
+    /// The `vm` label.
+    vm: String,
+    inner: Mutex<VmMetricsInner>,
+}
+
+#[derive(Default)]
+struct VmMetricsInner {
+    last_state: Option<VZVirtualMachineState>,
+    /// When the start that has not reached the running state yet was called.
+    start_called: Option<Instant>,
+}
+
+impl VmMetrics {
+    pub(crate) fn new(vm: VmId) -> VmMetrics {
+        VmMetrics {
+            vm: vm.get().to_string(),
+            inner: Mutex::default(),
+        }
+    }
+
+    /// Starts timing `operation`, which is being sent to the framework.
+    pub(crate) fn timer(&self, operation: &'static str) -> OperationTimer {
+        OperationTimer {
+            vm: self.vm.clone(),
+            operation,
+            started: Instant::now(),
+        }
+    }
+
+    pub(crate) fn forced_stop(&self) {
+        counter(VM_FORCED_STOPS, &[("vm", &self.vm)]);
+    }
+
+    fn lock(&self) -> MutexGuard<'_, VmMetricsInner> {
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub(crate) fn start_called(&self, at: Instant) {
+        self.lock().start_called = Some(at);
+    }
+
+    /// Follows the machine's state from the KVO observer. The first notification only reports the
+    /// current state and is not a transition.
+    pub(crate) fn observe(&self, state: VZVirtualMachineState) {
+        let (previous, start_called) = {
+            let mut inner = self.lock();
+            let previous = inner.last_state.replace(state);
+            if previous == Some(state) {
+                return;
+            }
+            let start_called = match state {
+                VZVirtualMachineState::VZVirtualMachineStateRunning
+                | VZVirtualMachineState::VZVirtualMachineStateStopped
+                | VZVirtualMachineState::VZVirtualMachineStateError => inner.start_called.take(),
+                _ => None,
+            };
+            (previous, start_called)
+        };
+        let previous = match previous {
+            Some(previous) => previous,
+            None => return,
+        };
+        if recorder().is_none() {
+            return;
+        }
+        let (from, to) = (previous.to_string(), state.to_string());
+        counter(
+            VM_STATE_TRANSITIONS,
+            &[("vm", &self.vm), ("from", &from), ("to", &to)],
+        );
+        if let (VZVirtualMachineState::VZVirtualMachineStateRunning, Some(at)) =
+            (state, start_called)
+        {
+            histogram_observe(
+                VM_START_TO_RUNNING,
+                at.elapsed().as_secs_f64(),
+                &[("vm", &self.vm)],
+            );
+        }
+    }
+}
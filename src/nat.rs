@@ -0,0 +1,713 @@
+//! nat module
+//!
+//! Finds the address a guest on a `VZNATNetworkDeviceAttachment` got from the host's DHCP server,
+//! `bootpd`, which records its leases in `/var/db/dhcpd_leases`:
+//!
+//! ```text
+//! {
+//!     name=ubuntu
+//!     ip_address=192.168.64.3
+//!     hw_address=1,2:e1:f3:a:4b:c
+//!     identifier=1,2:e1:f3:a:4b:c
+//!     lease=0x65f1c2a0
+//! }
+//! ```
+//!
+//! The hardware address is prefixed with its ARP hardware type, `1` for Ethernet, and drops the
+//! leading zeros of each octet. Only IPv4 leases are read.
+//!
+//! # Host diagnostics
+//! A guest that never gets an address is usually the host's fault: Internet Sharing or `bootpd`
+//! disabled, e.g. by a configuration profile, vmnet's shared network, `192.168.64.0/24` unless
+//! [`VMNET_PLIST_PATH`] moves it, also routed elsewhere, typically by a VPN, or a lease file
+//! `bootpd` cannot keep. [`diagnose`] checks for these without starting a virtual machine, and
+//! whether the executable has the entitlement to start one; each [`NatFinding`] says how to fix
+//! it. The parsers of what it reads, [`parse_routes`], [`disabled_services`] and
+//! [`shared_subnet`], are public for tools that read the same from elsewhere.
+//!
+//! # Examples
+//! ```rust
+//! let mac = VZMACAddress::random_locally_administered_address();
+//! let mut network = VZVirtioNetworkDeviceConfiguration::new(VZNATNetworkDeviceAttachment::new());
+//! network
+//!     .set_mac_address(VZMACAddress::init_with_string(&mac.string()).unwrap())
+//!     .unwrap();
+//! // ... build and start the virtual machine ...
+//! let ip = nat::guest_ip_for_mac(&mac, Duration::from_secs(60))?;
+//! println!("ssh user@{}", ip);
+//! ```
+//!
+//! ```rust
+//! for finding in nat::diagnose() {
+//!     eprintln!("{}: {}\n  {}", finding.severity(), finding, finding.remediation());
+//! }
+//! ```
+
+use crate::diagnostics::{self, EntitlementCheck, Severity};
+use crate::virtualization::network_device::VZMACAddress;
+
+use std::cmp::Reverse;
+use std::fmt;
+use std::fs::{self, File};
+use std::io;
+use std::net::Ipv4Addr;
+use std::path::Path;
+use std::process::{Command, Output};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Where `bootpd` records its leases.
+pub const LEASES_PATH: &str = "/var/db/dhcpd_leases";
+
+/// How often [`guest_ip_for_mac`] reads the lease file again.
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// ARP hardware type of Ethernet, the prefix of hardware addresses in the lease file.
+const HW_TYPE_ETHERNET: &str = "1";
+
+/// The DHCP server's configuration, which Internet Sharing writes when vmnet has it start
+/// `bootpd`.
+pub const BOOTPD_PLIST_PATH: &str = "/etc/bootpd.plist";
+
+/// vmnet's preferences. `Shared_Net_Address` and `Shared_Net_Mask` move its shared network.
+pub const VMNET_PLIST_PATH: &str = "/Library/Preferences/SystemConfiguration/com.apple.vmnet.plist";
+
+/// The launchd services a NAT attachment needs: Internet Sharing, which vmnet asks to set up the
+/// shared network, and the DHCP server it starts.
+pub const NAT_SERVICES: &[&str] = &["com.apple.InternetSharing", "com.apple.bootpd"];
+
+/// vmnet's shared network when [`VMNET_PLIST_PATH`] does not set one.
+pub const DEFAULT_SHARED_SUBNET: Ipv4Subnet = Ipv4Subnet {
+    address: Ipv4Addr::new(192, 168, 64, 0),
+    prefix: 24,
+};
+
+/// The entitlement [`diagnose`] requires; NAT needs no other.
+const VIRTUALIZATION_ENTITLEMENT: &str = "com.apple.security.virtualization";
+
+/// One entry of the lease file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Lease {
+    /// The host name the guest sent, if any.
+    pub name: Option<String>,
+    pub ip_address: Ipv4Addr,
+    /// In the `xx:xx:xx:xx:xx:xx` form of [`VZMACAddress::string`], lowercase.
+    pub hw_address: String,
+    /// When the lease expires, in seconds since the Unix epoch. Renewing a lease moves it, so the
+    /// latest expiry is the most recent lease.
+    pub expires: Option<u64>,
+}
+
+/// Why [`guest_ip_for_mac`] found no address.
+#[derive(Debug)]
+pub enum LeaseError {
+    /// No lease for the address appeared before the timeout. The guest may not have booted far
+    /// enough to ask for one, or may use a static address.
+    Timeout { mac: String },
+    /// The MAC address is not six hexadecimal octets.
+    InvalidMac(String),
+    /// The lease file exists but cannot be read.
+    Io(io::Error),
+}
+
+impl fmt::Display for LeaseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LeaseError::Timeout { mac } => {
+                write!(f, "no DHCP lease for {} before the timeout", mac)
+            }
+            LeaseError::InvalidMac(mac) => write!(f, "invalid MAC address '{}'", mac),
+            LeaseError::Io(e) => write!(f, "failed to read the DHCP lease file: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for LeaseError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            LeaseError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// Normalizes a MAC address with one or two hexadecimal digits per octet, in either case, to the
+/// lowercase `xx:xx:xx:xx:xx:xx` form.
+pub fn normalize_mac(mac: &str) -> Option<String> {
+    let octets = mac
+        .trim()
+        .split(':')
+        .map(|octet| {
+            // `from_str_radix` would also take a sign, e.g. `+a`.
+            if octet.is_empty() || octet.len() > 2 || !octet.bytes().all(|b| b.is_ascii_hexdigit())
+            {
+                return None;
+            }
+            u8::from_str_radix(octet, 16).ok()
+        })
+        .collect::<Option<Vec<u8>>>()?;
+    if octets.len() != 6 {
+        return None;
+    }
+    let octets: Vec<String> = octets.iter().map(|o| format!("{:02x}", o)).collect();
+    Some(octets.join(":"))
+}
+
+/// Parses the contents of a lease file, in file order. Entries without an IPv4 address or an
+/// Ethernet hardware address are skipped, as is an entry cut short by a concurrent write and
+/// anything outside the braces.
+pub fn parse_leases(contents: &str) -> Vec<Lease> {
+    let mut leases = Vec::new();
+    let mut entry: Option<Vec<(&str, &str)>> = None;
+    for line in contents.lines() {
+        let line = line.trim();
+        match line {
+            "{" => entry = Some(Vec::new()),
+            "}" => {
+                if let Some(lease) = entry.take().and_then(|fields| lease_from(&fields)) {
+                    leases.push(lease);
+                }
+            }
+            _ => {
+                if let (Some(fields), Some((key, value))) = (entry.as_mut(), split_field(line)) {
+                    fields.push((key, value));
+                }
+            }
+        }
+    }
+    leases
+}
+
+fn split_field(line: &str) -> Option<(&str, &str)> {
+    let at = line.find('=')?;
+    Some((line[..at].trim(), line[at + 1..].trim()))
+}
+
+fn lease_from(fields: &[(&str, &str)]) -> Option<Lease> {
+    // Later duplicates of a field win, as bootpd would read them.
+    let field = |name: &str| {
+        fields
+            .iter()
+            .rev()
+            .find(|(key, _)| *key == name)
+            .map(|(_, value)| *value)
+    };
+    let ip_address = field("ip_address")?.parse().ok()?;
+    let hw_address = field("hw_address")
+        .and_then(ethernet_address)
+        .or_else(|| field("identifier").and_then(ethernet_address))?;
+    let expires = field("lease").and_then(|lease| {
+        let lease = lease.trim_start_matches("0x").trim_start_matches("0X");
+        u64::from_str_radix(lease, 16).ok()
+    });
+    let name = field("name")
+        .filter(|name| !name.is_empty())
+        .map(String::from);
+    Some(Lease {
+        name,
+        ip_address,
+        hw_address,
+        expires,
+    })
+}
+
+/// The MAC address of a `1,xx:xx:...` hardware address.
+fn ethernet_address(value: &str) -> Option<String> {
+    let at = value.find(',')?;
+    if value[..at].trim() != HW_TYPE_ETHERNET {
+        return None;
+    }
+    normalize_mac(&value[at + 1..])
+}
+
+/// The leases in the file at `path`; none if it does not exist yet, as before the first guest on
+/// a NAT attachment asked for an address.
+pub fn leases_from<P: AsRef<Path>>(path: P) -> io::Result<Vec<Lease>> {
+    match fs::read_to_string(path) {
+        Ok(contents) => Ok(parse_leases(&contents)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(e),
+    }
+}
+
+/// Every lease in [`LEASES_PATH`], for tooling. Empty if the file cannot be read.
+pub fn all_leases() -> Vec<Lease> {
+    leases_from(LEASES_PATH).unwrap_or_default()
+}
+
+/// The most recent of the leases for `mac`, any case and with or without leading zeros: the one
+/// expiring last, or the first in file order, where bootpd puts its newest entries, when they do
+/// not say.
+pub fn latest_lease<'a>(leases: &'a [Lease], mac: &str) -> Option<&'a Lease> {
+    let mac = normalize_mac(mac)?;
+    leases.iter().filter(|lease| lease.hw_address == mac).fold(
+        None,
+        |latest: Option<&Lease>, lease| match latest {
+            Some(latest) if latest.expires >= lease.expires => Some(latest),
+            _ => Some(lease),
+        },
+    )
+}
+
+/// Polls the lease file at `path` until it has a lease for `mac`, and returns the most recent one.
+pub fn wait_for_lease<P: AsRef<Path>>(
+    path: P,
+    mac: &str,
+    timeout: Duration,
+) -> Result<Lease, LeaseError> {
+    let normalized = normalize_mac(mac).ok_or_else(|| LeaseError::InvalidMac(mac.to_string()))?;
+    let deadline = Instant::now() + timeout;
+    loop {
+        let leases = leases_from(path.as_ref()).map_err(LeaseError::Io)?;
+        if let Some(lease) = latest_lease(&leases, &normalized) {
+            return Ok(lease.clone());
+        }
+        let left = deadline.saturating_duration_since(Instant::now());
+        if left == Duration::ZERO {
+            return Err(LeaseError::Timeout { mac: normalized });
+        }
+        thread::sleep(left.min(POLL_INTERVAL));
+    }
+}
+
+/// The IPv4 address the host's DHCP server leased to the guest with `mac`, waiting up to
+/// `timeout` for the guest to ask for one.
+pub fn guest_ip_for_mac(mac: &VZMACAddress, timeout: Duration) -> Result<Ipv4Addr, LeaseError> {
+    wait_for_lease(LEASES_PATH, &mac.string(), timeout).map(|lease| lease.ip_address)
+}
+
+/// An IPv4 network: an address and how many of its leading bits are the network's.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Ipv4Subnet {
+    /// With the host bits clear.
+    pub address: Ipv4Addr,
+    pub prefix: u8,
+}
+
+impl Ipv4Subnet {
+    /// The network of `address` with a `prefix` bit long mask, clearing the host bits. `None` if
+    /// `prefix` is over 32.
+    pub fn new(address: Ipv4Addr, prefix: u8) -> Option<Ipv4Subnet> {
+        if prefix > 32 {
+            return None;
+        }
+        Some(Ipv4Subnet {
+            address: Ipv4Addr::from(u32::from(address) & prefix_mask(prefix)),
+            prefix,
+        })
+    }
+
+    /// The network of `address` with `mask`, e.g. `255.255.255.0`. `None` if the mask's bits
+    /// are not contiguous.
+    pub fn with_mask(address: Ipv4Addr, mask: Ipv4Addr) -> Option<Ipv4Subnet> {
+        let mask = u32::from(mask);
+        let prefix = mask.leading_ones();
+        if mask != prefix_mask(prefix as u8) {
+            return None;
+        }
+        Ipv4Subnet::new(address, prefix as u8)
+    }
+
+    pub fn contains(&self, address: Ipv4Addr) -> bool {
+        u32::from(address) & prefix_mask(self.prefix) == u32::from(self.address)
+    }
+
+    /// Whether an address is in both networks, i.e. one contains the other.
+    pub fn overlaps(&self, other: &Ipv4Subnet) -> bool {
+        let mask = prefix_mask(self.prefix.min(other.prefix));
+        u32::from(self.address) & mask == u32::from(other.address) & mask
+    }
+}
+
+fn prefix_mask(prefix: u8) -> u32 {
+    match prefix {
+        0 => 0,
+        _ => u32::MAX << (32 - u32::from(prefix)),
+    }
+}
+
+/// `192.168.64.0/24`.
+impl fmt::Display for Ipv4Subnet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.address, self.prefix)
+    }
+}
+
+/// An IPv4 route of the host's routing table.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Route {
+    /// `0.0.0.0/0` for the default route.
+    pub destination: Ipv4Subnet,
+    /// An address, `link#<index>` for a directly attached network, or a hardware address.
+    pub gateway: String,
+    /// As `netstat` prints them, e.g. `UGScg`; `H` is a host route, `W` one cloned for a host of
+    /// an attached network.
+    pub flags: String,
+    /// e.g. `en0`, `utun3` or `bridge100`.
+    pub interface: String,
+}
+
+impl Route {
+    fn has_flag(&self, flag: char) -> bool {
+        self.flags.contains(flag)
+    }
+}
+
+/// Parses the IPv4 routes `netstat -rn` prints, in its `Internet:` section or on its own with
+/// `-f inet`. Its destinations drop trailing zero octets and the mask they imply, so `10` is
+/// `10.0.0.0/8` and `192.168.1` is `192.168.1.0/24`, unless a `/prefix` follows. Lines that do
+/// not parse are skipped.
+pub fn parse_routes(netstat: &str) -> Vec<Route> {
+    let mut routes = Vec::new();
+    let mut ipv4 = true;
+    let mut interface_column = None;
+    for line in netstat.lines() {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        match fields.first().copied() {
+            None => continue,
+            Some("Internet:") => ipv4 = true,
+            Some("Internet6:") => ipv4 = false,
+            // Before macOS 13, `Refs` and `Use` columns precede `Netif`.
+            Some("Destination") => {
+                interface_column = fields.iter().position(|&field| field == "Netif")
+            }
+            Some(destination) if ipv4 => {
+                let column = match interface_column {
+                    Some(column) => column,
+                    None => continue,
+                };
+                let route = fields.get(column).and_then(|interface| {
+                    Some(Route {
+                        destination: parse_destination(destination, fields[2])?,
+                        gateway: fields[1].to_string(),
+                        flags: fields[2].to_string(),
+                        interface: interface.to_string(),
+                    })
+                });
+                routes.extend(route);
+            }
+            Some(_) => {}
+        }
+    }
+    routes
+}
+
+fn parse_destination(destination: &str, flags: &str) -> Option<Ipv4Subnet> {
+    if destination == "default" {
+        return Ipv4Subnet::new(Ipv4Addr::UNSPECIFIED, 0);
+    }
+    let (address, prefix) = match destination.find('/') {
+        Some(at) => (
+            &destination[..at],
+            Some(destination[at + 1..].parse().ok()?),
+        ),
+        None => (destination, None),
+    };
+    let mut octets = [0u8; 4];
+    let mut count = 0;
+    for octet in address.split('.') {
+        *octets.get_mut(count)? = octet.parse().ok()?;
+        count += 1;
+    }
+    let prefix = match prefix {
+        Some(prefix) => prefix,
+        None if flags.contains('H') => 32,
+        None => 8 * count as u8,
+    };
+    Ipv4Subnet::new(Ipv4Addr::from(octets), prefix)
+}
+
+/// The routes among `routes` to networks overlapping `shared`, vmnet's shared network, leaving
+/// out those that do not take its traffic away: the default route, loopback, the routes of
+/// vmnet's own bridges (`bridge100` and up), multicast and broadcast routes, and routes cloned
+/// for hosts of a network already listed.
+pub fn overlapping_routes<'a>(shared: &Ipv4Subnet, routes: &'a [Route]) -> Vec<&'a Route> {
+    let multicast = Ipv4Subnet::new(Ipv4Addr::new(224, 0, 0, 0), 4).unwrap();
+    routes
+        .iter()
+        .filter(|route| {
+            route.destination.prefix > 0
+                && route.destination.overlaps(shared)
+                && !route.interface.starts_with("lo")
+                && !is_vmnet_bridge(&route.interface)
+                && !route.destination.overlaps(&multicast)
+                && route.destination.address != Ipv4Addr::BROADCAST
+                && !route.has_flag('W')
+        })
+        .collect()
+}
+
+/// vmnet numbers its bridges from 100; lower ones are the user's, e.g. Thunderbolt Bridge.
+fn is_vmnet_bridge(interface: &str) -> bool {
+    let index = interface
+        .strip_prefix("bridge")
+        .and_then(|index| index.parse::<u32>().ok());
+    matches!(index, Some(index) if index >= 100)
+}
+
+/// The services `launchctl print-disabled system` lists as disabled, as
+/// `"com.apple.bootpd" => disabled`, or `=> true` before macOS 13. Other lists it prints, e.g.
+/// of login items, are skipped.
+pub fn disabled_services(launchctl: &str) -> Vec<String> {
+    let mut disabled = Vec::new();
+    let mut services = false;
+    for line in launchctl.lines().map(str::trim) {
+        if line.ends_with('{') {
+            services = line.starts_with("disabled services");
+            continue;
+        }
+        let at = match line.find("=>") {
+            Some(at) if services => at,
+            _ => continue,
+        };
+        let state = line[at + 2..].trim();
+        if state == "disabled" || state == "true" {
+            disabled.push(line[..at].trim().trim_matches('"').to_string());
+        }
+    }
+    disabled
+}
+
+/// vmnet's shared network from [`VMNET_PLIST_PATH`] as an XML property list, e.g. what
+/// `plutil -convert xml1 -o -` prints: `Shared_Net_Address` with `Shared_Net_Mask`, or a /24
+/// without it. `None` if the plist does not set an address.
+pub fn shared_subnet(vmnet_plist: &str) -> Option<Ipv4Subnet> {
+    let address = plist_string(vmnet_plist, "Shared_Net_Address")?
+        .parse()
+        .ok()?;
+    match plist_string(vmnet_plist, "Shared_Net_Mask") {
+        Some(mask) => Ipv4Subnet::with_mask(address, mask.parse().ok()?),
+        None => Ipv4Subnet::new(address, 24),
+    }
+}
+
+/// The string value of the first `key` of an XML property list.
+fn plist_string<'a>(plist: &'a str, key: &str) -> Option<&'a str> {
+    let key = format!("<key>{}</key>", key);
+    let value = plist[plist.find(&key)? + key.len()..]
+        .trim_start()
+        .strip_prefix("<string>")?;
+    Some(value[..value.find("</string>")?].trim())
+}
+
+/// A problem with the host's NAT networking. `Display` gives the problem,
+/// [`remediation`](Self::remediation) what to do about it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NatFinding {
+    /// A launchd service of [`NAT_SERVICES`] is disabled, so guests get no address.
+    ServiceDisabled { service: &'static str },
+    /// [`BOOTPD_PLIST_PATH`] is not a valid property list, so `bootpd` serves no address.
+    BootpdConfigInvalid { reason: String },
+    /// A route of another interface covers part of vmnet's shared network, so the guests'
+    /// traffic, or the replies to it, leave through that interface.
+    SubnetOverlap { shared: Ipv4Subnet, route: Route },
+    /// The lease file exists but cannot be read: guests get addresses, but
+    /// [`guest_ip_for_mac`] cannot find them.
+    LeaseFileUnreadable { reason: String },
+    /// No guest got an address on this host yet, or the lease file was removed.
+    NoLeaseFile,
+    /// The executable lacks an entitlement every virtual machine needs.
+    MissingEntitlement { name: &'static str },
+    /// A check could not be made: running `probe` failed for `reason`.
+    ProbeFailed { probe: &'static str, reason: String },
+}
+
+impl NatFinding {
+    pub fn severity(&self) -> Severity {
+        match self {
+            NatFinding::LeaseFileUnreadable { .. } => Severity::Warning,
+            NatFinding::NoLeaseFile | NatFinding::ProbeFailed { .. } => Severity::Note,
+            _ => Severity::Error,
+        }
+    }
+
+    /// What to do about it, with the commands to run.
+    pub fn remediation(&self) -> String {
+        match self {
+            NatFinding::ServiceDisabled { service } => format!(
+                "enable it with `sudo launchctl enable system/{}`; if a configuration profile \
+                 disabled it, ask its administrator to allow Internet Sharing",
+                service
+            ),
+            NatFinding::BootpdConfigInvalid { .. } => format!(
+                "move {} away; Internet Sharing writes it again the next time a guest starts",
+                BOOTPD_PLIST_PATH
+            ),
+            NatFinding::SubnetOverlap { route, .. } => format!(
+                "move vmnet's shared network to a free range, e.g. with `sudo defaults write {} \
+                 Shared_Net_Address -string 10.211.64.1` and `Shared_Net_Mask -string \
+                 255.255.255.0`, or have the VPN or network on {} not route {}",
+                VMNET_PLIST_PATH, route.interface, route.destination
+            ),
+            NatFinding::LeaseFileUnreadable { .. } => format!(
+                "restore its permissions with `sudo chmod 644 {}`",
+                LEASES_PATH
+            ),
+            NatFinding::NoLeaseFile => "nothing to do before the first guest on a NAT attachment; \
+                 after one, its DHCP requests never reached bootpd: check that the guest asks for \
+                 an address"
+                .to_string(),
+            NatFinding::MissingEntitlement { name } => format!(
+                "sign the executable with an entitlements file granting {}, e.g. \
+                 `codesign --entitlements virtualization_rs.entitlements -s - <executable>`",
+                name
+            ),
+            NatFinding::ProbeFailed { probe, .. } => format!("run `{}` to check by hand", probe),
+        }
+    }
+}
+
+impl fmt::Display for NatFinding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NatFinding::ServiceDisabled { service } => write!(
+                f,
+                "the launchd service {} is disabled; guests on a NAT attachment get no address",
+                service
+            ),
+            NatFinding::BootpdConfigInvalid { reason } => write!(
+                f,
+                "{} is not a valid property list ({}); bootpd serves no address",
+                BOOTPD_PLIST_PATH, reason
+            ),
+            NatFinding::SubnetOverlap { shared, route } => write!(
+                f,
+                "vmnet's shared network {} overlaps {}, routed through {} via {}",
+                shared, route.destination, route.interface, route.gateway
+            ),
+            NatFinding::LeaseFileUnreadable { reason } => {
+                write!(f, "cannot read {}: {}", LEASES_PATH, reason)
+            }
+            NatFinding::NoLeaseFile => write!(f, "{} does not exist", LEASES_PATH),
+            NatFinding::MissingEntitlement { name } => {
+                write!(f, "the executable lacks the {} entitlement", name)
+            }
+            NatFinding::ProbeFailed { probe, reason } => {
+                write!(f, "could not check with `{}`: {}", probe, reason)
+            }
+        }
+    }
+}
+
+/// The finding about the lease file at `path`, if any.
+pub fn lease_file_finding<P: AsRef<Path>>(path: P) -> Option<NatFinding> {
+    match File::open(path) {
+        Ok(_) => None,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Some(NatFinding::NoLeaseFile),
+        Err(e) => Some(NatFinding::LeaseFileUnreadable {
+            reason: e.to_string(),
+        }),
+    }
+}
+
+/// The findings about `entitlements`, or about why they could not be told.
+pub fn entitlement_findings(
+    entitlements: Result<Vec<EntitlementCheck>, String>,
+) -> Vec<NatFinding> {
+    match entitlements {
+        Ok(checks) => checks
+            .iter()
+            .filter(|check| check.name == VIRTUALIZATION_ENTITLEMENT && !check.granted)
+            .map(|check| NatFinding::MissingEntitlement { name: check.name })
+            .collect(),
+        Err(reason) => vec![NatFinding::ProbeFailed {
+            probe: "codesign -d --entitlements :- <executable>",
+            reason,
+        }],
+    }
+}
+
+/// Runs `program`, failing unless it exits successfully.
+fn run(program: &str, args: &[&str]) -> Result<String, String> {
+    let Output {
+        status,
+        stdout,
+        stderr,
+    } = Command::new(program)
+        .args(args)
+        .output()
+        .map_err(|e| format!("failed to run {}: {}", program, e))?;
+    if status.success() {
+        Ok(String::from_utf8_lossy(&stdout).into_owned())
+    } else {
+        // `plutil -lint` reports on stdout.
+        let message = if stderr.is_empty() { stdout } else { stderr };
+        Err(format!(
+            "exited with {}: {}",
+            status,
+            String::from_utf8_lossy(&message).trim()
+        ))
+    }
+}
+
+/// vmnet's shared network, as configured on this host.
+fn configured_subnet() -> Result<Ipv4Subnet, String> {
+    if !Path::new(VMNET_PLIST_PATH).exists() {
+        return Ok(DEFAULT_SHARED_SUBNET);
+    }
+    let plist = run(
+        "/usr/bin/plutil",
+        &["-convert", "xml1", "-o", "-", VMNET_PLIST_PATH],
+    )?;
+    Ok(shared_subnet(&plist).unwrap_or(DEFAULT_SHARED_SUBNET))
+}
+
+/// Checks the host's NAT networking, without starting a virtual machine; see the
+/// [module documentation](self). Runs `launchctl`, `plutil`, `netstat` and `codesign`, which
+/// takes a fraction of a second. The findings are sorted from the most severe.
+pub fn diagnose() -> Vec<NatFinding> {
+    let mut findings = Vec::new();
+
+    match run("/bin/launchctl", &["print-disabled", "system"]) {
+        Ok(launchctl) => {
+            let disabled = disabled_services(&launchctl);
+            findings.extend(
+                NAT_SERVICES
+                    .iter()
+                    .filter(|&&service| disabled.iter().any(|name| name == service))
+                    .map(|&service| NatFinding::ServiceDisabled { service }),
+            );
+        }
+        Err(reason) => findings.push(NatFinding::ProbeFailed {
+            probe: "launchctl print-disabled system",
+            reason,
+        }),
+    }
+
+    if Path::new(BOOTPD_PLIST_PATH).exists() {
+        if let Err(reason) = run("/usr/bin/plutil", &["-lint", BOOTPD_PLIST_PATH]) {
+            findings.push(NatFinding::BootpdConfigInvalid { reason });
+        }
+    }
+
+    let shared = configured_subnet().unwrap_or_else(|reason| {
+        findings.push(NatFinding::ProbeFailed {
+            probe: "plutil -p /Library/Preferences/SystemConfiguration/com.apple.vmnet.plist",
+            reason,
+        });
+        DEFAULT_SHARED_SUBNET
+    });
+    match run("/usr/sbin/netstat", &["-rn", "-f", "inet"]) {
+        Ok(netstat) => {
+            let routes = parse_routes(&netstat);
+            findings.extend(
+                overlapping_routes(&shared, &routes)
+                    .into_iter()
+                    .map(|route| NatFinding::SubnetOverlap {
+                        shared,
+                        route: route.clone(),
+                    }),
+            );
+        }
+        Err(reason) => findings.push(NatFinding::ProbeFailed {
+            probe: "netstat -rn -f inet",
+            reason,
+        }),
+    }
+
+    findings.extend(lease_file_finding(LEASES_PATH));
+    findings.extend(entitlement_findings(diagnostics::check_entitlements()));
+
+    findings.sort_by_key(|finding| Reverse(finding.severity()));
+    findings
+}
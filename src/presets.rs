@@ -0,0 +1,117 @@
+//! presets module
+//!
+//! A starting configuration for a guest: its CPU count and memory size, and the devices most
+//! guests want. Each device can be left out through its [`DeviceSelection`], so minimal
+//! configurations, e.g. for measurements, start from a preset too; [`lint`](crate::lint) then
+//! flags what they leave out until a [`LintConfig`](crate::lint::LintConfig) acknowledges it.
+//!
+//! # Examples
+//! ```rust
+//! let conf = Preset::new(2, 2048)
+//!     .boot_loader(boot_loader)
+//!     .storage_devices(vec![block_device])
+//!     .build();
+//!
+//! // No entropy or balloon device, on purpose.
+//! let devices = DeviceSelection {
+//!     entropy: false,
+//!     memory_balloon: false,
+//!     ..DeviceSelection::all()
+//! };
+//! let conf = Preset::new(1, 512).devices(devices).boot_loader(boot_loader).build();
+//! let config = LintConfig::new()
+//!     .allow(Lint::MissingEntropy)
+//!     .allow(Lint::MissingBalloon);
+//! assert!(lint::check(&conf, &config).is_empty());
+//! ```
+
+use crate::base::NSFileHandle;
+use crate::profile::DeviceSelection;
+use crate::virtualization::boot_loader::VZBootLoader;
+use crate::virtualization::entropy_device::VZVirtioEntropyDeviceConfiguration;
+use crate::virtualization::memory_device::VZVirtioTraditionalMemoryBalloonDeviceConfiguration;
+use crate::virtualization::network_device::{
+    VZMACAddress, VZNATNetworkDeviceAttachment, VZVirtioNetworkDeviceConfiguration,
+};
+use crate::virtualization::serial_port::{
+    VZFileHandleSerialPortAttachmentBuilder, VZVirtioConsoleDeviceSerialPortConfiguration,
+};
+use crate::virtualization::virtual_machine::VZVirtualMachineConfigurationBuilder;
+
+/// A CPU count, a memory size and a set of devices.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Preset {
+    pub cpu_count: usize,
+    pub memory_mib: usize,
+    /// The entropy, memory balloon, console and network devices to add. The console is on the
+    /// process's standard input and output, `hvc0` in a Linux guest; the network device has a
+    /// random locally administered MAC address. The storage and graphics fields are ignored: the
+    /// caller adds those devices.
+    pub devices: DeviceSelection,
+}
+
+impl Default for Preset {
+    /// 2 CPUs, 2 GiB and every device.
+    fn default() -> Self {
+        Preset::new(2, 2048)
+    }
+}
+
+impl Preset {
+    /// With every device.
+    pub fn new(cpu_count: usize, memory_mib: usize) -> Preset {
+        Preset {
+            cpu_count,
+            memory_mib,
+            devices: DeviceSelection::all(),
+        }
+    }
+
+    pub fn devices(mut self, devices: DeviceSelection) -> Self {
+        self.devices = devices;
+        self
+    }
+
+    /// A configuration builder with the preset's CPU count, memory size and devices. The caller
+    /// adds the boot loader and the storage devices.
+    pub fn builder(&self) -> VZVirtualMachineConfigurationBuilder {
+        let mut builder = VZVirtualMachineConfigurationBuilder::new()
+            .cpu_count(self.cpu_count)
+            .memory_size_mib(self.memory_mib);
+        if self.devices.entropy {
+            builder = builder.entropy_devices(vec![VZVirtioEntropyDeviceConfiguration::new()]);
+        }
+        if self.devices.memory_balloon {
+            builder = builder.memory_balloon_devices(vec![
+                VZVirtioTraditionalMemoryBalloonDeviceConfiguration::new(),
+            ]);
+        }
+        if self.devices.serial_console {
+            let attachment = VZFileHandleSerialPortAttachmentBuilder::new()
+                .file_handle_for_reading(NSFileHandle::file_handle_with_standard_input())
+                .file_handle_for_writing(NSFileHandle::file_handle_with_standard_output())
+                .build();
+            builder =
+                builder.serial_ports(vec![VZVirtioConsoleDeviceSerialPortConfiguration::new(
+                    attachment,
+                )]);
+        }
+        if self.devices.nat_network {
+            let mut network =
+                VZVirtioNetworkDeviceConfiguration::new(VZNATNetworkDeviceAttachment::new());
+            network
+                .set_mac_address(VZMACAddress::random_locally_administered_address())
+                .expect("a new device is not part of a virtual machine");
+            builder = builder.network_devices(vec![network]);
+        }
+        builder
+    }
+
+    /// [`builder`](Self::builder) with `boot_loader`.
+    pub fn boot_loader<T: VZBootLoader>(
+        &self,
+        boot_loader: T,
+    ) -> VZVirtualMachineConfigurationBuilder {
+        self.builder().boot_loader(boot_loader)
+    }
+}
@@ -0,0 +1,557 @@
+//! profile module
+//!
+//! A guest profile turns what the guest is into how to boot it: which boot loader, which kernel
+//! arguments and which devices. A spec names one, e.g. `linux-efi-cloud-image`, and gives the
+//! paths in [`ProfileInputs`]:
+//!
+//! | profile | boot loader | needs | refuses |
+//! |---|---|---|---|
+//! | `linux-direct-kernel` | `VZLinuxBootLoader` | kernel, initial ramdisk | EFI variable store |
+//! | `linux-efi-cloud-image` | `VZEFIBootLoader` | disk, EFI variable store | kernel, initial ramdisk, command line |
+//! | `macos` | `VZMacOSBootLoader` | disk | everything else |
+//!
+//! # Examples
+//! ```rust
+//! let profile: ProfileKind = "linux-efi-cloud-image".parse()?;
+//! let inputs = ProfileInputs {
+//!     disk: Some("jammy-server-cloudimg-arm64.img".into()),
+//!     efi_variable_store: Some("efi_vars.fd".into()),
+//!     cloud_init_seed: Some("seed.iso".into()),
+//!     ..ProfileInputs::default()
+//! };
+//! let conf = match profile::configure(profile.profile().as_ref(), &inputs) {
+//!     Ok(builder) => builder.cpu_count(2).memory_size_gib(2).build(),
+//!     Err(errors) => {
+//!         for error in errors {
+//!             eprintln!("{}", error);
+//!         }
+//!         return;
+//!     }
+//! };
+//! ```
+
+#[cfg(feature = "linux-guest")]
+use crate::virtualization::boot_loader::VZLinuxBootLoaderBuilder;
+#[cfg(feature = "macos-guest")]
+use crate::virtualization::boot_loader::VZMacOSBootLoader;
+use crate::virtualization::boot_loader::{
+    BootLoader, VZEFIBootLoaderBuilder, VZEFIVariableStore, VZEFIVariableStoreInitializationOptions,
+};
+use crate::virtualization::entropy_device::VZVirtioEntropyDeviceConfiguration;
+use crate::virtualization::memory_device::VZVirtioTraditionalMemoryBalloonDeviceConfiguration;
+use crate::virtualization::network_device::{
+    VZNATNetworkDeviceAttachment, VZVirtioNetworkDeviceConfiguration,
+};
+use crate::virtualization::storage_device::{
+    VZDiskImageStorageDeviceAttachmentBuilder, VZVirtioBlockDeviceConfiguration,
+};
+use crate::virtualization::virtual_machine::VZVirtualMachineConfigurationBuilder;
+
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+const KERNEL: &str = "kernel";
+const INITRD: &str = "initial ramdisk";
+const COMMAND_LINE: &str = "kernel command line";
+const EFI_VARIABLE_STORE: &str = "EFI variable store";
+const DISK: &str = "disk";
+const CLOUD_INIT_SEED: &str = "cloud-init seed";
+
+/// What a spec gives a profile to build a guest from. Which fields a profile needs or refuses is
+/// up to its [`GuestProfile::validate_inputs`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default, deny_unknown_fields))]
+pub struct ProfileInputs {
+    pub kernel: Option<PathBuf>,
+    pub initrd: Option<PathBuf>,
+    /// Replaces [`GuestProfile::default_kernel_args`] and whatever the profile adds to them.
+    pub command_line: Option<String>,
+    /// Opened if it exists, created otherwise.
+    pub efi_variable_store: Option<PathBuf>,
+    /// The guest's root disk, attached read-write.
+    pub disk: Option<PathBuf>,
+    /// A NoCloud seed image, attached read-only after the disk.
+    pub cloud_init_seed: Option<PathBuf>,
+}
+
+/// The devices a profile composes, or a [`Preset`](crate::presets::Preset) adds. [`configure`]
+/// adds the storage, entropy, memory balloon and network devices; the console and graphics need
+/// file handles and a view, so the caller adds those.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DeviceSelection {
+    /// A virtio block device for [`ProfileInputs::disk`], when given.
+    pub root_disk: bool,
+    /// A read-only virtio block device for [`ProfileInputs::cloud_init_seed`], when given.
+    pub cloud_init_seed: bool,
+    pub entropy: bool,
+    /// A virtio traditional memory balloon device.
+    pub memory_balloon: bool,
+    /// A virtio network device on NAT.
+    pub nat_network: bool,
+    /// A virtio console, which a Linux guest sees as `hvc0`.
+    pub serial_console: bool,
+    /// A display, keyboard and pointing device, for guests that log in on a screen.
+    pub graphics: bool,
+}
+
+impl DeviceSelection {
+    /// Every device.
+    pub fn all() -> DeviceSelection {
+        DeviceSelection {
+            root_disk: true,
+            cloud_init_seed: true,
+            entropy: true,
+            memory_balloon: true,
+            nat_network: true,
+            serial_console: true,
+            graphics: true,
+        }
+    }
+}
+
+/// An input a profile refused.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProfileError {
+    /// The profile needs an input that was not given.
+    Missing {
+        profile: &'static str,
+        input: &'static str,
+    },
+    /// The profile does not use an input that was given, e.g. a kernel for a guest whose
+    /// firmware boots the kernel on its disk.
+    Forbidden {
+        profile: &'static str,
+        input: &'static str,
+    },
+    /// A path that must name an existing file does not.
+    NotAFile {
+        profile: &'static str,
+        input: &'static str,
+        path: PathBuf,
+    },
+    /// Creating the boot loader or a device from the inputs failed.
+    Refused {
+        profile: &'static str,
+        message: String,
+    },
+}
+
+impl fmt::Display for ProfileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProfileError::Missing { profile, input } => {
+                write!(f, "{} needs the {}", profile, input)
+            }
+            ProfileError::Forbidden { profile, input } => {
+                write!(f, "{} does not use the {}", profile, input)
+            }
+            ProfileError::NotAFile {
+                profile,
+                input,
+                path,
+            } => write!(
+                f,
+                "{}: the {} {} is not a file",
+                profile,
+                input,
+                path.display()
+            ),
+            ProfileError::Refused { profile, message } => write!(f, "{}: {}", profile, message),
+        }
+    }
+}
+
+impl std::error::Error for ProfileError {}
+
+/// How to boot one kind of guest.
+pub trait GuestProfile {
+    /// The name a spec uses, e.g. `linux-efi-cloud-image`.
+    fn name(&self) -> &'static str;
+
+    /// Every problem with `inputs` at once, so a spec can report them together. Empty if the
+    /// profile can boot a guest from them.
+    fn validate_inputs(&self, inputs: &ProfileInputs) -> Vec<ProfileError>;
+
+    /// The boot loader for `inputs`. Fails with the first problem
+    /// [`validate_inputs`](Self::validate_inputs) finds, or if the framework refuses the inputs.
+    fn boot_loader(&self, inputs: &ProfileInputs) -> Result<BootLoader, ProfileError>;
+
+    /// The kernel command line unless [`ProfileInputs::command_line`] replaces it. Empty for
+    /// guests whose own boot loader chooses it.
+    fn default_kernel_args(&self) -> String;
+
+    fn required_devices(&self) -> DeviceSelection;
+}
+
+/// Collects the problems of one profile's inputs.
+struct Check {
+    profile: &'static str,
+    errors: Vec<ProfileError>,
+}
+
+impl Check {
+    fn new(profile: &'static str) -> Check {
+        Check {
+            profile,
+            errors: Vec::new(),
+        }
+    }
+
+    fn required_file(&mut self, input: &'static str, path: &Option<PathBuf>) {
+        match path {
+            Some(path) => self.file(input, path),
+            None => self.errors.push(ProfileError::Missing {
+                profile: self.profile,
+                input,
+            }),
+        }
+    }
+
+    fn optional_file(&mut self, input: &'static str, path: &Option<PathBuf>) {
+        if let Some(path) = path {
+            self.file(input, path);
+        }
+    }
+
+    fn file(&mut self, input: &'static str, path: &Path) {
+        if !path.is_file() {
+            self.errors.push(ProfileError::NotAFile {
+                profile: self.profile,
+                input,
+                path: path.to_path_buf(),
+            });
+        }
+    }
+
+    /// A path that is created if missing, but must be a file if it exists.
+    fn required_creatable(&mut self, input: &'static str, path: &Option<PathBuf>) {
+        match path {
+            Some(path) if path.exists() => self.file(input, path),
+            Some(_) => {}
+            None => self.errors.push(ProfileError::Missing {
+                profile: self.profile,
+                input,
+            }),
+        }
+    }
+
+    fn forbid<T>(&mut self, input: &'static str, value: &Option<T>) {
+        if value.is_some() {
+            self.errors.push(ProfileError::Forbidden {
+                profile: self.profile,
+                input,
+            });
+        }
+    }
+}
+
+fn first_error(profile: &dyn GuestProfile, inputs: &ProfileInputs) -> Result<(), ProfileError> {
+    match profile.validate_inputs(inputs).into_iter().next() {
+        Some(error) => Err(error),
+        None => Ok(()),
+    }
+}
+
+/// A path as the builders take it; validation made sure it names a file.
+fn path_string(path: &Path) -> String {
+    path.to_string_lossy().into_owned()
+}
+
+/// Boots a Linux kernel and initial ramdisk directly, with the console on `hvc0` and, when a
+/// disk is given, the root file system on it.
+#[cfg(feature = "linux-guest")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LinuxDirectKernel;
+
+#[cfg(feature = "linux-guest")]
+impl GuestProfile for LinuxDirectKernel {
+    fn name(&self) -> &'static str {
+        "linux-direct-kernel"
+    }
+
+    fn validate_inputs(&self, inputs: &ProfileInputs) -> Vec<ProfileError> {
+        let mut check = Check::new(self.name());
+        check.required_file(KERNEL, &inputs.kernel);
+        check.required_file(INITRD, &inputs.initrd);
+        check.optional_file(DISK, &inputs.disk);
+        check.optional_file(CLOUD_INIT_SEED, &inputs.cloud_init_seed);
+        check.forbid(EFI_VARIABLE_STORE, &inputs.efi_variable_store);
+        check.errors
+    }
+
+    fn boot_loader(&self, inputs: &ProfileInputs) -> Result<BootLoader, ProfileError> {
+        first_error(self, inputs)?;
+        let command_line = match (&inputs.command_line, &inputs.disk) {
+            (Some(command_line), _) => command_line.clone(),
+            (None, Some(_)) => format!("{} root=/dev/vda", self.default_kernel_args()),
+            (None, None) => self.default_kernel_args(),
+        };
+        let (kernel, initrd) = match (&inputs.kernel, &inputs.initrd) {
+            (Some(kernel), Some(initrd)) => (kernel, initrd),
+            _ => unreachable!("validated"),
+        };
+        VZLinuxBootLoaderBuilder::new()
+            .kernel_url(path_string(kernel))
+            .initial_ramdisk_url(path_string(initrd))
+            .command_line(command_line)
+            .build()
+            .map(BootLoader::Linux)
+            .map_err(|e| ProfileError::Refused {
+                profile: self.name(),
+                message: e.to_string(),
+            })
+    }
+
+    fn default_kernel_args(&self) -> String {
+        "console=hvc0".to_string()
+    }
+
+    fn required_devices(&self) -> DeviceSelection {
+        DeviceSelection {
+            root_disk: true,
+            cloud_init_seed: true,
+            entropy: true,
+            memory_balloon: false,
+            nat_network: true,
+            serial_console: true,
+            graphics: false,
+        }
+    }
+}
+
+/// Boots a Linux cloud image through EFI firmware, which runs the boot loader on the image;
+/// that picks the kernel and its arguments. Configure it with a cloud-init seed.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LinuxEfiCloudImage;
+
+impl GuestProfile for LinuxEfiCloudImage {
+    fn name(&self) -> &'static str {
+        "linux-efi-cloud-image"
+    }
+
+    fn validate_inputs(&self, inputs: &ProfileInputs) -> Vec<ProfileError> {
+        let mut check = Check::new(self.name());
+        check.required_file(DISK, &inputs.disk);
+        check.required_creatable(EFI_VARIABLE_STORE, &inputs.efi_variable_store);
+        check.optional_file(CLOUD_INIT_SEED, &inputs.cloud_init_seed);
+        check.forbid(KERNEL, &inputs.kernel);
+        check.forbid(INITRD, &inputs.initrd);
+        check.forbid(COMMAND_LINE, &inputs.command_line);
+        check.errors
+    }
+
+    fn boot_loader(&self, inputs: &ProfileInputs) -> Result<BootLoader, ProfileError> {
+        first_error(self, inputs)?;
+        let path = match &inputs.efi_variable_store {
+            Some(path) => path,
+            None => unreachable!("validated"),
+        };
+        let refused = |message: String| ProfileError::Refused {
+            profile: self.name(),
+            message,
+        };
+        let store = if path.exists() {
+            VZEFIVariableStore::open(path_string(path)).map_err(|e| refused(e.to_string()))?
+        } else {
+            VZEFIVariableStore::create(
+                path_string(path),
+                VZEFIVariableStoreInitializationOptions::new(),
+            )
+            .map_err(|e| refused(e.to_string()))?
+        };
+        Ok(BootLoader::Efi(
+            VZEFIBootLoaderBuilder::new()
+                .with_variable_store(store)
+                .build(),
+        ))
+    }
+
+    fn default_kernel_args(&self) -> String {
+        String::new()
+    }
+
+    fn required_devices(&self) -> DeviceSelection {
+        DeviceSelection {
+            root_disk: true,
+            cloud_init_seed: true,
+            entropy: true,
+            memory_balloon: false,
+            nat_network: true,
+            serial_console: true,
+            graphics: false,
+        }
+    }
+}
+
+/// Boots an installed macOS guest from its disk. The platform, with the hardware model,
+/// machine identifier and auxiliary storage of the guest, is up to the caller.
+#[cfg(feature = "macos-guest")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MacOS;
+
+#[cfg(feature = "macos-guest")]
+impl GuestProfile for MacOS {
+    fn name(&self) -> &'static str {
+        "macos"
+    }
+
+    fn validate_inputs(&self, inputs: &ProfileInputs) -> Vec<ProfileError> {
+        let mut check = Check::new(self.name());
+        check.required_file(DISK, &inputs.disk);
+        check.forbid(KERNEL, &inputs.kernel);
+        check.forbid(INITRD, &inputs.initrd);
+        check.forbid(COMMAND_LINE, &inputs.command_line);
+        check.forbid(EFI_VARIABLE_STORE, &inputs.efi_variable_store);
+        check.forbid(CLOUD_INIT_SEED, &inputs.cloud_init_seed);
+        check.errors
+    }
+
+    fn boot_loader(&self, inputs: &ProfileInputs) -> Result<BootLoader, ProfileError> {
+        first_error(self, inputs)?;
+        VZMacOSBootLoader::new()
+            .map(BootLoader::MacOS)
+            .map_err(|e| ProfileError::Refused {
+                profile: self.name(),
+                message: e.to_string(),
+            })
+    }
+
+    fn default_kernel_args(&self) -> String {
+        String::new()
+    }
+
+    fn required_devices(&self) -> DeviceSelection {
+        DeviceSelection {
+            root_disk: true,
+            cloud_init_seed: false,
+            entropy: false,
+            memory_balloon: false,
+            nat_network: true,
+            serial_console: false,
+            graphics: true,
+        }
+    }
+}
+
+/// The built-in profiles, by the names specs use.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ProfileKind {
+    #[cfg(feature = "linux-guest")]
+    LinuxDirectKernel,
+    LinuxEfiCloudImage,
+    #[cfg(feature = "macos-guest")]
+    MacOS,
+}
+
+const PROFILE_KINDS: &[ProfileKind] = &[
+    #[cfg(feature = "linux-guest")]
+    ProfileKind::LinuxDirectKernel,
+    ProfileKind::LinuxEfiCloudImage,
+    #[cfg(feature = "macos-guest")]
+    ProfileKind::MacOS,
+];
+
+impl ProfileKind {
+    /// Every built-in profile this build has.
+    pub fn all() -> &'static [ProfileKind] {
+        PROFILE_KINDS
+    }
+
+    pub fn profile(self) -> Box<dyn GuestProfile> {
+        match self {
+            #[cfg(feature = "linux-guest")]
+            ProfileKind::LinuxDirectKernel => Box::new(LinuxDirectKernel),
+            ProfileKind::LinuxEfiCloudImage => Box::new(LinuxEfiCloudImage),
+            #[cfg(feature = "macos-guest")]
+            ProfileKind::MacOS => Box::new(MacOS),
+        }
+    }
+}
+
+impl fmt::Display for ProfileKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.profile().name())
+    }
+}
+
+/// A name that is not one of [`ProfileKind::all`], e.g. `macos` without the `macos-guest`
+/// feature.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseProfileError(pub String);
+
+impl fmt::Display for ParseProfileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "unknown guest profile {:?}", self.0)
+    }
+}
+
+impl std::error::Error for ParseProfileError {}
+
+impl FromStr for ProfileKind {
+    type Err = ParseProfileError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        PROFILE_KINDS
+            .iter()
+            .copied()
+            .find(|kind| kind.profile().name() == s)
+            .ok_or_else(|| ParseProfileError(s.to_string()))
+    }
+}
+
+fn block_device(
+    profile: &dyn GuestProfile,
+    path: &Path,
+    read_only: bool,
+) -> Result<VZVirtioBlockDeviceConfiguration, ProfileError> {
+    VZDiskImageStorageDeviceAttachmentBuilder::new()
+        .path(path_string(path))
+        .read_only(read_only)
+        .build()
+        .map(VZVirtioBlockDeviceConfiguration::new)
+        .map_err(|e| ProfileError::Refused {
+            profile: profile.name(),
+            message: e.to_string(),
+        })
+}
+
+/// Validates `inputs` and starts a configuration with the profile's boot loader and the storage,
+/// entropy, memory balloon and network devices of its [`DeviceSelection`]. The caller adds the CPU count, the
+/// memory size, and the console or graphics devices the selection asks for.
+pub fn configure(
+    profile: &dyn GuestProfile,
+    inputs: &ProfileInputs,
+) -> Result<VZVirtualMachineConfigurationBuilder, Vec<ProfileError>> {
+    let errors = profile.validate_inputs(inputs);
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+    let boot_loader = profile.boot_loader(inputs).map_err(|e| vec![e])?;
+    let devices = profile.required_devices();
+    let mut storage = Vec::new();
+    if let (true, Some(disk)) = (devices.root_disk, &inputs.disk) {
+        storage.push(block_device(profile, disk, false).map_err(|e| vec![e])?);
+    }
+    if let (true, Some(seed)) = (devices.cloud_init_seed, &inputs.cloud_init_seed) {
+        storage.push(block_device(profile, seed, true).map_err(|e| vec![e])?);
+    }
+    let mut builder = VZVirtualMachineConfigurationBuilder::new()
+        .boot_loader(boot_loader)
+        .storage_devices(storage);
+    if devices.entropy {
+        builder = builder.entropy_devices(vec![VZVirtioEntropyDeviceConfiguration::new()]);
+    }
+    if devices.memory_balloon {
+        builder = builder.memory_balloon_devices(vec![
+            VZVirtioTraditionalMemoryBalloonDeviceConfiguration::new(),
+        ]);
+    }
+    if devices.nat_network {
+        builder = builder.network_devices(vec![VZVirtioNetworkDeviceConfiguration::new(
+            VZNATNetworkDeviceAttachment::new(),
+        )]);
+    }
+    Ok(builder)
+}
@@ -0,0 +1,151 @@
+//! queue pool module
+//!
+//! Which dispatch queue each virtual machine runs on. The framework runs a machine's work, its
+//! completion handlers, state observations and delegate callbacks on the queue the machine was
+//! created with, one block at a time, and accepts the same serial queue for several machines.
+//! That is a trade-off, made once per machine by whoever calls [`VZVirtualMachine::new`]:
+//!
+//! | [`QueuePolicy`] | queues | a slow callback delays | suits |
+//! |---|---|---|---|
+//! | [`PerVm`](QueuePolicy::PerVm) | one per machine | its own machine | interactive machines, callbacks that may block |
+//! | [`SharedPool(n)`](QueuePolicy::SharedPool) | `n` | the machines sharing its queue, about one in `n` | many machines with a bound on threads |
+//! | [`SingleShared`](QueuePolicy::SingleShared) | one | every machine | many mostly idle machines with quick callbacks |
+//!
+//! An idle queue costs next to nothing, but each busy one takes a thread of libdispatch's pool, so
+//! a queue per machine is as many threads as machines with work at the same time. Sharing a queue
+//! bounds that, at the price of latency: every callback of every machine on the queue, including
+//! the registry's bulk operations and blocking helpers like `shutdown_sync`, waits for the blocks
+//! before it. One machine's callback that blocks for a second holds up the state changes and
+//! completions of all the others for that second, and one that waits for work on the queue
+//! deadlocks all of them.
+//!
+//! The default is a queue per machine, which is what [`VZVirtualMachine::new_with_qos`] does. A
+//! [`QueuePool`] assigns queues by policy; [`VmRegistry::create`] and [`Respawner::queues`] take
+//! their queues from one, and a pool may be shared by both. Starvation on a shared queue shows
+//! in the [queue watchdog](crate::queue_watchdog)'s counters, summed per queue by
+//! [`queue_watchdog::per_queue`].
+//!
+//! # Examples
+//! ```rust
+//! // Sixty build machines on four queues, round robin.
+//! let registry = VmRegistry::with_queues(QueuePool::new(
+//!     QueuePolicy::SharedPool(4),
+//!     "builders",
+//!     Some(QoSClass::Utility),
+//! ));
+//! for i in 0..60 {
+//!     registry.create(&format!("builder-{}", i), conf.copy())?;
+//! }
+//! ```
+//!
+//! [`VZVirtualMachine::new`]: crate::virtualization::virtual_machine::VZVirtualMachine::new
+//! [`VZVirtualMachine::new_with_qos`]: crate::virtualization::virtual_machine::VZVirtualMachine::new_with_qos
+//! [`VmRegistry::create`]: crate::registry::VmRegistry::create
+//! [`Respawner::queues`]: crate::respawn::Respawner::queues
+//! [`queue_watchdog::per_queue`]: crate::queue_watchdog::per_queue
+
+use crate::base::{DispatchQueue, QoSClass};
+
+use std::sync::{Arc, Mutex};
+
+/// How a [`QueuePool`] assigns queues to machines; see the [module documentation](self).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum QueuePolicy {
+    /// A new queue for each machine.
+    #[default]
+    PerVm,
+    /// At most `n` queues, assigned round robin in the order machines ask.
+    SharedPool(usize),
+    /// One queue for all machines.
+    SingleShared,
+}
+
+/// Assigns serial dispatch queues to machines by a [`QueuePolicy`]. Clones share the queues and
+/// the round robin, so machines created from several places still spread evenly.
+#[derive(Clone)]
+pub struct QueuePool(Arc<Pool>);
+
+struct Pool {
+    policy: QueuePolicy,
+    label: String,
+    qos: Option<QoSClass>,
+    shared: Mutex<Shared>,
+}
+
+/// The shared queues, created as they are first assigned.
+struct Shared {
+    queues: Vec<DispatchQueue>,
+    next: usize,
+}
+
+impl QueuePool {
+    /// A pool creating its queues at `qos`, or the default QoS, labelled `label.<name>` with
+    /// [`PerVm`](QueuePolicy::PerVm), `label.<index>` with
+    /// [`SharedPool`](QueuePolicy::SharedPool) and `label` with
+    /// [`SingleShared`](QueuePolicy::SingleShared).
+    ///
+    /// Panics if `policy` is `SharedPool(0)`.
+    pub fn new(policy: QueuePolicy, label: &str, qos: Option<QoSClass>) -> QueuePool {
+        assert!(
+            policy != QueuePolicy::SharedPool(0),
+            "a shared pool needs at least one queue"
+        );
+        QueuePool(Arc::new(Pool {
+            policy,
+            label: label.to_string(),
+            qos,
+            shared: Mutex::new(Shared {
+                queues: Vec::new(),
+                next: 0,
+            }),
+        }))
+    }
+
+    pub fn policy(&self) -> QueuePolicy {
+        self.0.policy
+    }
+
+    /// The queue for the next machine; `name` labels the queue of a machine of its own.
+    pub fn assign(&self, name: &str) -> DispatchQueue {
+        let pool = &self.0;
+        let size = match pool.policy {
+            QueuePolicy::PerVm => return pool.create(&format!("{}.{}", pool.label, name)),
+            QueuePolicy::SharedPool(size) => size,
+            QueuePolicy::SingleShared => 1,
+        };
+        let mut shared = pool.shared.lock().unwrap_or_else(|e| e.into_inner());
+        let index = shared.next;
+        shared.next = (index + 1) % size;
+        if index == shared.queues.len() {
+            let queue = match pool.policy {
+                QueuePolicy::SharedPool(_) => pool.create(&format!("{}.{}", pool.label, index)),
+                _ => pool.create(&pool.label),
+            };
+            shared.queues.push(queue);
+        }
+        shared.queues[index].clone()
+    }
+
+    /// The shared queues assigned so far, in order; none with [`PerVm`](QueuePolicy::PerVm),
+    /// whose queues belong to their machines.
+    pub fn shared_queues(&self) -> Vec<DispatchQueue> {
+        let shared = self.0.shared.lock().unwrap_or_else(|e| e.into_inner());
+        shared.queues.clone()
+    }
+}
+
+impl Default for QueuePool {
+    /// A queue per machine, labelled `vm.<name>`.
+    fn default() -> Self {
+        QueuePool::new(QueuePolicy::PerVm, "vm", None)
+    }
+}
+
+impl Pool {
+    fn create(&self, label: &str) -> DispatchQueue {
+        match self.qos {
+            Some(qos) => DispatchQueue::new_with_qos(label, qos),
+            None => DispatchQueue::new(label),
+        }
+    }
+}
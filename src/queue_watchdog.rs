@@ -0,0 +1,218 @@
+//! queue watchdog module
+//!
+//! Opt-in timing of the Rust callbacks a virtual machine runs, such as completion handlers and
+//! state callbacks. A callback that blocks keeps the VM's queue from making progress, and one that
+//! waits for work on that queue deadlocks the machine; the watchdog reports every callback that
+//! runs longer than a threshold, so the culprit shows up without `sample` or `spindump`.
+//!
+//! Reports are logged as warnings through the `log` crate, target
+//! `virtualization_rs::queue_watchdog`: one `key=value` line naming the machine, followed by where
+//! the callback was submitted when the `backtrace` feature is enabled. Install a logger, e.g.
+//! `env_logger`, to see them.
+//!
+//! Machines sharing a queue, see [`crate::queue_pool`], also wait for each other's callbacks.
+//! [`per_queue`] sums the counters of the machines on each queue, so a queue starved by one of its
+//! machines stands out.
+//!
+//! # Examples
+//! ```rust
+//! vm.enable_queue_watchdog(Duration::from_millis(100));
+//! vm.start(|_| thread::sleep(Duration::from_secs(1)))?;
+//! // WARN virtualization_rs::queue_watchdog: vm="vm-1 (web)" callback=start queue="vm" elapsed_ms=1001 threshold_ms=100
+//! let stats = vm.queue_watchdog_stats();
+//! assert_eq!(stats.slow_callbacks, 1);
+//! ```
+
+use crate::base::DispatchQueue;
+use crate::virtualization::virtual_machine::VZVirtualMachine;
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Frames printed for a slow callback.
+#[cfg(feature = "backtrace")]
+const MAX_FRAMES: usize = 16;
+
+#[cfg(feature = "backtrace")]
+type Trace = backtrace::Backtrace;
+#[cfg(not(feature = "backtrace"))]
+type Trace = ();
+
+/// Counters of a watchdog, from when it was first enabled.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct QueueWatchdogStats {
+    /// Callbacks that ran while the watchdog was enabled.
+    pub callbacks_timed: u64,
+    /// Those that exceeded the threshold.
+    pub slow_callbacks: u64,
+    pub slowest: Duration,
+}
+
+impl QueueWatchdogStats {
+    fn add(&mut self, other: QueueWatchdogStats) {
+        self.callbacks_timed += other.callbacks_timed;
+        self.slow_callbacks += other.slow_callbacks;
+        self.slowest = self.slowest.max(other.slowest);
+    }
+}
+
+/// The counters of the machines on one queue, from [`per_queue`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QueueWatchdogReport {
+    /// The queue's label.
+    pub queue: String,
+    /// [`VZVirtualMachine::display_name`] of each machine on the queue.
+    pub machines: Vec<String>,
+    /// Their counters summed, with the slowest callback of any of them.
+    pub stats: QueueWatchdogStats,
+}
+
+/// The counters of `machines`, each given once, summed per queue in the order the queues first
+/// appear. The counters of machines whose watchdog was never enabled are zero. On a shared queue,
+/// [`VZVirtualMachine::queue_watchdog_stats`] tells which machine ran the slow callbacks.
+pub fn per_queue<'a, I>(machines: I) -> Vec<QueueWatchdogReport>
+where
+    I: IntoIterator<Item = &'a VZVirtualMachine>,
+{
+    let mut reports: Vec<(DispatchQueue, QueueWatchdogReport)> = Vec::new();
+    for vm in machines {
+        let stats = vm.queue_watchdog_stats();
+        match reports
+            .iter_mut()
+            .find(|(queue, _)| queue.is_same(vm.queue()))
+        {
+            Some((_, report)) => {
+                report.machines.push(vm.display_name());
+                report.stats.add(stats);
+            }
+            None => reports.push((
+                vm.queue().clone(),
+                QueueWatchdogReport {
+                    queue: vm.queue().label(),
+                    machines: vec![vm.display_name()],
+                    stats,
+                },
+            )),
+        }
+    }
+    reports.into_iter().map(|(_, report)| report).collect()
+}
+
+/// Shared by all handles to a virtual machine. Disabled until a threshold is set.
+pub(crate) struct QueueWatchdog {
+    /// The machine, as reports name it.
+    vm: String,
+    /// In nanoseconds; 0 while disabled.
+    threshold: AtomicU64,
+    callbacks_timed: AtomicU64,
+    slow_callbacks: AtomicU64,
+    slowest: AtomicU64,
+}
+
+impl QueueWatchdog {
+    pub(crate) fn new(vm: String) -> QueueWatchdog {
+        QueueWatchdog {
+            vm,
+            threshold: AtomicU64::new(0),
+            callbacks_timed: AtomicU64::new(0),
+            slow_callbacks: AtomicU64::new(0),
+            slowest: AtomicU64::new(0),
+        }
+    }
+
+    pub(crate) fn enable(&self, threshold: Duration) {
+        let nanos = threshold.as_nanos().clamp(1, u64::MAX as u128) as u64;
+        self.threshold.store(nanos, Ordering::SeqCst);
+    }
+
+    pub(crate) fn stats(&self) -> QueueWatchdogStats {
+        QueueWatchdogStats {
+            callbacks_timed: self.callbacks_timed.load(Ordering::SeqCst),
+            slow_callbacks: self.slow_callbacks.load(Ordering::SeqCst),
+            slowest: Duration::from_nanos(self.slowest.load(Ordering::SeqCst)),
+        }
+    }
+
+    fn threshold(&self) -> Option<Duration> {
+        match self.threshold.load(Ordering::SeqCst) {
+            0 => None,
+            nanos => Some(Duration::from_nanos(nanos)),
+        }
+    }
+
+    /// Wraps the user callback `f`, named `name` in reports. The submitting stack is captured
+    /// only if the watchdog is enabled by now; timing starts as soon as it is enabled.
+    pub(crate) fn wrap<A, F>(self: &Arc<Self>, name: &'static str, f: F) -> impl FnOnce(A) + 'static
+    where
+        A: 'static,
+        F: FnOnce(A) + 'static,
+    {
+        let watchdog = self.clone();
+        let trace = watchdog.threshold().and_then(|_| capture());
+        move |arg| {
+            let threshold = match watchdog.threshold() {
+                Some(threshold) => threshold,
+                None => return f(arg),
+            };
+            let started = Instant::now();
+            f(arg);
+            watchdog.record(name, started.elapsed(), threshold, trace);
+        }
+    }
+
+    fn record(&self, name: &str, elapsed: Duration, threshold: Duration, trace: Option<Trace>) {
+        self.callbacks_timed.fetch_add(1, Ordering::SeqCst);
+        let nanos = elapsed.as_nanos().min(u64::MAX as u128) as u64;
+        self.slowest.fetch_max(nanos, Ordering::SeqCst);
+        if elapsed <= threshold {
+            return;
+        }
+        self.slow_callbacks.fetch_add(1, Ordering::SeqCst);
+        let mut report = format!(
+            "vm={:?} callback={} queue={:?} elapsed_ms={} threshold_ms={}",
+            self.vm,
+            name,
+            DispatchQueue::current_label(),
+            elapsed.as_millis(),
+            threshold.as_millis()
+        );
+        if let Some(trace) = trace {
+            report.push_str("\n  submitted at:");
+            for (i, frame) in format_trace(trace).iter().enumerate() {
+                report.push_str(&format!("\n    {}: {}", i, frame));
+            }
+        }
+        log::warn!(target: "virtualization_rs::queue_watchdog", "{}", report);
+    }
+}
+
+#[cfg(feature = "backtrace")]
+fn capture() -> Option<Trace> {
+    // Symbols are only resolved for callbacks that turn out to be slow.
+    Some(backtrace::Backtrace::new_unresolved())
+}
+
+#[cfg(not(feature = "backtrace"))]
+fn capture() -> Option<Trace> {
+    None
+}
+
+#[cfg(feature = "backtrace")]
+fn format_trace(mut trace: Trace) -> Vec<String> {
+    trace.resolve();
+    trace
+        .frames()
+        .iter()
+        .flat_map(|frame| frame.symbols())
+        .filter_map(|symbol| symbol.name().map(|name| name.to_string()))
+        // Frames of the capture itself and of the wrapper.
+        .skip_while(|name| name.starts_with("backtrace::") || name.contains("queue_watchdog"))
+        .take(MAX_FRAMES)
+        .collect()
+}
+
+#[cfg(not(feature = "backtrace"))]
+fn format_trace(_trace: Trace) -> Vec<String> {
+    Vec::new()
+}
@@ -0,0 +1,358 @@
+//! reconcile module
+//!
+//! Compares the devices an orchestrator wants a virtual machine to have with what it has. A
+//! [`ConfigDescription`] lists the desired devices of the categories the caller cares about;
+//! [`ReconcileReport::compare`] reads the machine and reports each of those categories as a
+//! match, a mismatch or unknown. The machine may be any [`VirtualMachineHandle`]; a `FakeVm`
+//! reports the devices it was scripted with.
+//!
+//! The framework only has runtime accessors for some devices, and only on newer macOS releases.
+//! Storage, network and entropy devices are read from the configuration the machine was created
+//! with, which cannot change while it exists; memory balloon targets, socket devices, shares and
+//! consoles from the machine itself. A category whose accessor this macOS lacks is reported as
+//! [`Status::Unknown`] rather than left out.
+//!
+//! MAC addresses compare case-insensitively and regardless of leading zeros, and disk paths after
+//! resolving symbolic links, so `02:AB:0:0:0:1` matches `02:ab:00:00:00:01` and `/tmp/disk.img`
+//! matches `/private/tmp/disk.img`. Order matters: it is the order of the devices on the guest's
+//! PCI bus.
+//!
+//! # Examples
+//! ```rust
+//! let desired = ConfigDescription {
+//!     storage_devices: Some(vec![PathBuf::from("/vms/web/root.img")]),
+//!     network_devices: Some(vec!["02:00:00:00:00:01".to_string()]),
+//!     memory_balloon_devices: Some(vec![2 << 30]),
+//!     ..ConfigDescription::default()
+//! };
+//! let report = ReconcileReport::compare(&desired, &vm);
+//! if report.has_drift() {
+//!     // memory_balloon_devices mismatch: expected [2147483648], actual [4294967296]
+//!     eprint!("{}", report);
+//! }
+//! ```
+
+use crate::nat;
+use crate::virtualization::handle::VirtualMachineHandle;
+use crate::virtualization::virtual_machine::{BackingFileKind, VZVirtualMachine};
+
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use objc::{sel, sel_impl};
+
+/// The devices a virtual machine should have, per category. Categories left `None` are not
+/// compared.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct ConfigDescription {
+    /// The disk images of the storage devices, in order.
+    pub storage_devices: Option<Vec<PathBuf>>,
+    /// The MAC addresses of the network devices, in order.
+    pub network_devices: Option<Vec<String>>,
+    pub entropy_devices: Option<usize>,
+    /// The target memory size of each memory balloon device, in bytes.
+    pub memory_balloon_devices: Option<Vec<u64>>,
+    pub socket_devices: Option<usize>,
+    /// The tags of the directory sharing devices, in order.
+    pub directory_sharing_devices: Option<Vec<String>>,
+    pub console_devices: Option<usize>,
+}
+
+/// What a virtual machine has, per category as in [`ConfigDescription`], or why it could not be
+/// read. [`ObservedDevices::read`] fills it in from a machine; tests and callers with their own
+/// source fill it in directly.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ObservedDevices {
+    pub storage_devices: Result<Vec<PathBuf>, String>,
+    pub network_devices: Result<Vec<String>, String>,
+    pub entropy_devices: Result<usize, String>,
+    pub memory_balloon_devices: Result<Vec<u64>, String>,
+    pub socket_devices: Result<usize, String>,
+    pub directory_sharing_devices: Result<Vec<String>, String>,
+    pub console_devices: Result<usize, String>,
+}
+
+impl ObservedDevices {
+    /// Reads every category from `vm`.
+    ///
+    /// The runtime devices are read on the VM's queue, so this panics if called from that queue.
+    pub fn read(vm: &VZVirtualMachine) -> ObservedDevices {
+        let conf = vm.configuration_copy();
+        let storage_devices = {
+            let paths: Vec<PathBuf> = conf
+                .backing_files()
+                .into_iter()
+                .filter(|file| file.kind == BackingFileKind::DiskImage)
+                .map(|file| file.path)
+                .collect();
+            let count = conf.device_count("storage_devices").unwrap_or(0);
+            if paths.len() == count {
+                Ok(paths)
+            } else {
+                Err(format!(
+                    "{} of {} storage devices have no disk image file",
+                    count - paths.len(),
+                    count
+                ))
+            }
+        };
+        let network_devices = {
+            let macs = conf.network_mac_addresses();
+            let running = vm.network_devices().len();
+            if vm.has_runtime_devices(sel!(networkDevices)) && running != macs.len() {
+                Err(format!(
+                    "{} network devices configured but {} running",
+                    macs.len(),
+                    running
+                ))
+            } else {
+                Ok(macs)
+            }
+        };
+        let entropy_devices = conf
+            .device_count("entropy_devices")
+            .ok_or_else(|| "entropy devices are not available".to_string());
+        let memory_balloon_devices = if vm.has_runtime_devices(sel!(memoryBalloonDevices)) {
+            Ok(vm
+                .memory_balloon_devices()
+                .iter()
+                .map(|device| device.target_virtual_machine_memory_size())
+                .collect())
+        } else {
+            Err(unavailable("memory balloon devices", "macOS 12"))
+        };
+        let directory_sharing_devices = if vm.has_runtime_devices(sel!(directorySharingDevices)) {
+            Ok(vm
+                .directory_sharing_devices()
+                .iter()
+                .map(|device| device.tag())
+                .collect())
+        } else {
+            Err(unavailable("directory sharing devices", "macOS 12"))
+        };
+        let console_devices = if vm.has_runtime_devices(sel!(consoleDevices)) {
+            Ok(vm.console_devices().len())
+        } else {
+            Err(unavailable("console devices", "macOS 13"))
+        };
+        ObservedDevices {
+            storage_devices,
+            network_devices,
+            entropy_devices,
+            memory_balloon_devices,
+            socket_devices: Ok(vm.socket_devices().len()),
+            directory_sharing_devices,
+            console_devices,
+        }
+    }
+}
+
+fn unavailable(devices: &str, release: &str) -> String {
+    format!("reading the running {} needs {}", devices, release)
+}
+
+/// How a category of a virtual machine compares with the desired one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub enum Status {
+    Match,
+    /// Both sides normalized, as lists in brackets for categories with per-device values and as
+    /// counts otherwise.
+    Mismatch {
+        expected: String,
+        actual: String,
+    },
+    /// The category could not be read.
+    Unknown {
+        reason: String,
+    },
+}
+
+/// The comparison of one category, named as in [`ConfigDescription`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct ReconcileEntry {
+    pub category: &'static str,
+    pub status: Status,
+}
+
+/// The comparison of each category a [`ConfigDescription`] sets, in the order of its fields.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct ReconcileReport {
+    pub entries: Vec<ReconcileEntry>,
+}
+
+impl ReconcileReport {
+    /// Reads `vm` and compares it with `desired`; see [`ObservedDevices::read`] for a
+    /// [`VZVirtualMachine`].
+    pub fn compare<V: VirtualMachineHandle>(
+        desired: &ConfigDescription,
+        vm: &V,
+    ) -> ReconcileReport {
+        ReconcileReport::compare_observed(desired, &vm.observed_devices())
+    }
+
+    /// Compares `observed` with `desired`, without a virtual machine.
+    pub fn compare_observed(
+        desired: &ConfigDescription,
+        observed: &ObservedDevices,
+    ) -> ReconcileReport {
+        let mut report = ReconcileReport {
+            entries: Vec::new(),
+        };
+        report.push(
+            "storage_devices",
+            &desired.storage_devices,
+            &observed.storage_devices,
+            |paths| paths.iter().map(|p| canonical(p)).collect(),
+            list,
+        );
+        report.push(
+            "network_devices",
+            &desired.network_devices,
+            &observed.network_devices,
+            |macs| macs.iter().map(|mac| normalize_mac(mac)).collect(),
+            list,
+        );
+        report.push(
+            "entropy_devices",
+            &desired.entropy_devices,
+            &observed.entropy_devices,
+            count,
+            single,
+        );
+        report.push(
+            "memory_balloon_devices",
+            &desired.memory_balloon_devices,
+            &observed.memory_balloon_devices,
+            |targets| targets.iter().map(u64::to_string).collect(),
+            list,
+        );
+        report.push(
+            "socket_devices",
+            &desired.socket_devices,
+            &observed.socket_devices,
+            count,
+            single,
+        );
+        report.push(
+            "directory_sharing_devices",
+            &desired.directory_sharing_devices,
+            &observed.directory_sharing_devices,
+            Vec::clone,
+            list,
+        );
+        report.push(
+            "console_devices",
+            &desired.console_devices,
+            &observed.console_devices,
+            count,
+            single,
+        );
+        report
+    }
+
+    /// Adds the entry of `category` if `desired` is set, comparing both sides by the values
+    /// `normalize` makes of them and showing a mismatch as `render` formats those.
+    fn push<T, F>(
+        &mut self,
+        category: &'static str,
+        desired: &Option<T>,
+        observed: &Result<T, String>,
+        normalize: F,
+        render: fn(&[String]) -> String,
+    ) where
+        F: Fn(&T) -> Vec<String>,
+    {
+        let desired = match desired {
+            Some(desired) => desired,
+            None => return,
+        };
+        let status = match observed {
+            Err(reason) => Status::Unknown {
+                reason: reason.clone(),
+            },
+            Ok(observed) => {
+                let (expected, actual) = (normalize(desired), normalize(observed));
+                if expected == actual {
+                    Status::Match
+                } else {
+                    Status::Mismatch {
+                        expected: render(&expected),
+                        actual: render(&actual),
+                    }
+                }
+            }
+        };
+        self.entries.push(ReconcileEntry { category, status });
+    }
+
+    /// Whether any category differs from the desired one. Unknown categories do not count.
+    pub fn has_drift(&self) -> bool {
+        self.drift().next().is_some()
+    }
+
+    /// The categories that differ from the desired ones.
+    pub fn drift(&self) -> impl Iterator<Item = &ReconcileEntry> {
+        self.entries
+            .iter()
+            .filter(|entry| matches!(entry.status, Status::Mismatch { .. }))
+    }
+
+    /// Whether every compared category matches, none of them unknown.
+    pub fn is_in_sync(&self) -> bool {
+        self.entries
+            .iter()
+            .all(|entry| entry.status == Status::Match)
+    }
+}
+
+/// One line per entry, e.g. `console_devices unknown: reading the running console devices
+/// needs macOS 13`.
+impl fmt::Display for ReconcileReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for entry in &self.entries {
+            match &entry.status {
+                Status::Match => writeln!(f, "{} match", entry.category)?,
+                Status::Mismatch { expected, actual } => writeln!(
+                    f,
+                    "{} mismatch: expected {}, actual {}",
+                    entry.category, expected, actual
+                )?,
+                Status::Unknown { reason } => {
+                    writeln!(f, "{} unknown: {}", entry.category, reason)?
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A count as the single value compared.
+fn count(n: &usize) -> Vec<String> {
+    vec![n.to_string()]
+}
+
+fn single(values: &[String]) -> String {
+    values.concat()
+}
+
+fn list(values: &[String]) -> String {
+    format!("[{}]", values.join(", "))
+}
+
+/// `path` with symbolic links resolved, or as given if it does not exist.
+fn canonical(path: &Path) -> String {
+    fs::canonicalize(path)
+        .unwrap_or_else(|_| path.to_path_buf())
+        .display()
+        .to_string()
+}
+
+/// As [`nat::normalize_mac`] renders it, or lowercased if it is not a MAC address.
+fn normalize_mac(mac: &str) -> String {
+    nat::normalize_mac(mac).unwrap_or_else(|| mac.trim().to_lowercase())
+}
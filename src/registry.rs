@@ -0,0 +1,242 @@
+//! registry module
+//!
+//! Named virtual machines of a process, with bulk operations fanned out across their queues.
+//! Machines are also found by their [`VmId`], as log lines, metrics and errors name them.
+//!
+//! The registry holds [`VZVirtualMachine`]s by default, and any other [`VirtualMachineHandle`],
+//! e.g. a `FakeVm` in tests, as `VmRegistry<FakeVm>`.
+//!
+//! # Lock ordering
+//! The registry lock is only held to look machines up or to change the map. Bulk operations clone
+//! the machines out under the read lock and release it before dispatching onto any VM queue, so
+//! completion handlers running on a VM queue may freely register, remove or look up machines.
+//! They must not call the blocking bulk operations, which wait for every VM queue including their
+//! own. The bulk operations give up on a queue after their timeout, reporting its machine.
+//!
+//! # Queues
+//! Machines built by [`VmRegistry::create`] take their queues from the registry's [`QueuePool`],
+//! a queue each by default. A pool sharing queues bounds the threads the bulk operations occupy,
+//! at the price of each shared queue running its machines' callbacks one after another; see
+//! [`crate::queue_pool`] for the trade-off and [`VmRegistry::queue_watchdog_stats`] to watch it.
+//!
+//! # Examples
+//! ```rust
+//! let registry = Arc::new(VmRegistry::new());
+//! registry.create("web", web_conf)?;
+//! registry.register("db", VZVirtualMachine::new(db_conf, &queue))?;
+//! for (name, state) in registry.states(Duration::from_secs(1)) {
+//!     match state {
+//!         Some(state) => println!("{}: {:?}", name, state),
+//!         None => println!("{}: queue busy", name),
+//!     }
+//! }
+//! let stragglers = registry.stop_all(Duration::from_secs(10));
+//! // From a metric or log line naming `vm-3`:
+//! if let Some((name, vm)) = registry.get_by_id(id) {
+//!     println!("{} is {:?}", name, vm);
+//! }
+//! ```
+
+use crate::queue_pool::QueuePool;
+use crate::queue_watchdog::{self, QueueWatchdogReport};
+use crate::virtualization::handle::VirtualMachineHandle;
+use crate::virtualization::virtual_machine::VZVirtualMachineConfiguration;
+use crate::virtualization::virtual_machine::{VZVirtualMachine, VZVirtualMachineState, VmId};
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::mpsc;
+use std::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::time::{Duration, Instant};
+
+/// A machine is already registered under this name.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DuplicateName(pub String);
+
+/// Virtual machines by name, safe to share between threads and completion handlers.
+pub struct VmRegistry<V = VZVirtualMachine> {
+    machines: RwLock<BTreeMap<String, V>>,
+    queues: QueuePool,
+}
+
+impl<V> Default for VmRegistry<V> {
+    fn default() -> Self {
+        VmRegistry {
+            machines: RwLock::new(BTreeMap::new()),
+            queues: QueuePool::default(),
+        }
+    }
+}
+
+impl<V: VirtualMachineHandle> VmRegistry<V> {
+    pub fn new() -> VmRegistry<V> {
+        VmRegistry::default()
+    }
+
+    /// A registry whose [`VmRegistry::create`] takes queues from `queues`.
+    pub fn with_queues(queues: QueuePool) -> VmRegistry<V> {
+        VmRegistry {
+            machines: RwLock::new(BTreeMap::new()),
+            queues,
+        }
+    }
+
+    pub fn queues(&self) -> &QueuePool {
+        &self.queues
+    }
+
+    fn read(&self) -> RwLockReadGuard<'_, BTreeMap<String, V>> {
+        self.machines.read().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn write(&self) -> RwLockWriteGuard<'_, BTreeMap<String, V>> {
+        self.machines.write().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub fn register(&self, name: &str, vm: V) -> Result<(), DuplicateName> {
+        let mut machines = self.write();
+        if machines.contains_key(name) {
+            return Err(DuplicateName(name.to_string()));
+        }
+        machines.insert(name.to_string(), vm);
+        Ok(())
+    }
+
+    /// The machine registered as `name`. The registry keeps its own reference, so this is a clone
+    /// sharing the same framework object.
+    pub fn get(&self, name: &str) -> Option<V> {
+        self.read().get(name).cloned()
+    }
+
+    /// The machine with `id` and the name it is registered as.
+    pub fn get_by_id(&self, id: VmId) -> Option<(String, V)> {
+        self.read()
+            .iter()
+            .find(|(_, vm)| vm.vm_id() == id)
+            .map(|(name, vm)| (name.clone(), vm.clone()))
+    }
+
+    /// Registered names, sorted.
+    pub fn names(&self) -> Vec<String> {
+        self.read().keys().cloned().collect()
+    }
+
+    pub fn remove(&self, name: &str) -> Option<V> {
+        self.write().remove(name)
+    }
+
+    fn snapshot(&self) -> Vec<(String, V)> {
+        self.read()
+            .iter()
+            .map(|(name, vm)| (name.clone(), vm.clone()))
+            .collect()
+    }
+
+    /// The state of every machine, each read on its own queue concurrently, or `None` for those
+    /// whose queue did not get to it within `timeout`, e.g. because a callback blocks it.
+    ///
+    /// Panics if called from a VM's queue, which could not read its own machine's state.
+    pub fn states(&self, timeout: Duration) -> HashMap<String, Option<VZVirtualMachineState>> {
+        let deadline = Instant::now() + timeout;
+        let machines = self.snapshot();
+        assert_off_queues(&machines, "Registry::states");
+        let mut states: HashMap<String, Option<VZVirtualMachineState>> = machines
+            .iter()
+            .map(|(name, _)| (name.clone(), None))
+            .collect();
+        let (tx, rx) = mpsc::channel();
+        for (name, vm) in machines {
+            let tx = tx.clone();
+            let target = vm.clone();
+            vm.queue().exec_async(move || {
+                let _ = tx.send((name, unsafe { target.state() }));
+            });
+        }
+        drop(tx);
+        let mut pending = states.len();
+        while pending > 0 {
+            let now = Instant::now();
+            if now >= deadline {
+                break;
+            }
+            match rx.recv_timeout(deadline - now) {
+                Ok((name, state)) => {
+                    states.insert(name, Some(state));
+                    pending -= 1;
+                }
+                Err(_) => break,
+            }
+        }
+        states
+    }
+
+    /// Force stops every machine concurrently and returns the names of those that did not confirm
+    /// the stop within `timeout`.
+    ///
+    /// Panics if called from a VM's queue, whose stop it would wait for.
+    pub fn stop_all(&self, timeout: Duration) -> Vec<String> {
+        let deadline = Instant::now() + timeout;
+        let machines = self.snapshot();
+        assert_off_queues(&machines, "Registry::stop_all");
+        let mut pending: Vec<String> = machines.iter().map(|(name, _)| name.clone()).collect();
+        let (tx, rx) = mpsc::channel();
+        for (name, vm) in machines {
+            let tx = tx.clone();
+            let target = vm.clone();
+            vm.stop_or_join(move |outcome| {
+                // Stopping an already stopped machine fails, but it is stopped all the same.
+                let stopped = outcome.is_success()
+                    || matches!(
+                        unsafe { target.state() },
+                        VZVirtualMachineState::VZVirtualMachineStateStopped
+                    );
+                if stopped {
+                    let _ = tx.send(name);
+                }
+            });
+        }
+        drop(tx);
+        while !pending.is_empty() {
+            let now = Instant::now();
+            if now >= deadline {
+                break;
+            }
+            match rx.recv_timeout(deadline - now) {
+                Ok(name) => pending.retain(|n| *n != name),
+                Err(_) => break,
+            }
+        }
+        pending
+    }
+}
+
+impl VmRegistry<VZVirtualMachine> {
+    /// Creates a machine labelled `name` on a queue from the registry's pool and registers it.
+    /// No machine is created if the name is taken.
+    pub fn create(
+        &self,
+        name: &str,
+        conf: VZVirtualMachineConfiguration,
+    ) -> Result<VZVirtualMachine, DuplicateName> {
+        if self.read().contains_key(name) {
+            return Err(DuplicateName(name.to_string()));
+        }
+        // Outside the lock: the framework validates the configuration on creation.
+        let vm = VZVirtualMachine::new_labeled(conf, self.queues.assign(name), name);
+        self.register(name, vm.clone())?;
+        Ok(vm)
+    }
+
+    /// The queue watchdog's counters of the registered machines, summed per queue; see
+    /// [`queue_watchdog::per_queue`].
+    pub fn queue_watchdog_stats(&self) -> Vec<QueueWatchdogReport> {
+        let machines = self.snapshot();
+        queue_watchdog::per_queue(machines.iter().map(|(_, vm)| vm))
+    }
+}
+
+#[track_caller]
+fn assert_off_queues<V: VirtualMachineHandle>(machines: &[(String, V)], what: &str) {
+    for (_, vm) in machines {
+        vm.queue().assert_not_current(what);
+    }
+}
@@ -0,0 +1,122 @@
+//! resource module
+//!
+//! How the crate's types that hold file descriptors and other OS resources release them. Each of
+//! them can be closed explicitly with `close(self)`, which reports what went wrong, and runs the
+//! same logic when dropped, ignoring errors; whichever comes first does the work, exactly once.
+//!
+//! The guest's end of a serial port attachment is referenced by the framework once the
+//! attachment is in a configuration. Closing it then would take the descriptor away from a
+//! running machine, so `close` fails with [`CloseError::Attached`] instead and leaves that end to
+//! be closed when the framework releases the attachment; the host's end is released regardless.
+//!
+//! | type | holds | owned | released by |
+//! |---|---|---|---|
+//! | [`NSFileHandle`] | a descriptor | if created with `close_on_dealloc`; standard streams are borrowed | `close`, or the last release |
+//! | [`VZVirtioSocketConnection`] | a vsock descriptor; duplicates from `dup_stream` and `into_stream` are the stream's | yes, by the framework object | `close`, or the last release |
+//! | [`PtyMaster`] | the pseudo-terminal master; the slave through the port's attachment | yes | `close` or drop, which detach the port first |
+//! | [`ConsoleCapture`] | the guest input pipe's write end; the guest's pipe ends through its attachment | yes | `close` or drop; the guest's ends need the attachment unreferenced |
+//! | [`ConsoleTee`] | as [`ConsoleCapture`], the log file and any transcript | yes | as [`ConsoleCapture`]; the log file and transcript close when the guest's output ends |
+//! | [`ConsoleRecorder`] | the transcript file | yes | `close` or drop |
+//! | [`TcpConsoleBridge`] | the listener, the client, pipes as [`ConsoleCapture`] | yes | `close` or drop, which wait for the listener to close; [`stop`] keeps the guest's ends |
+//! | [`UnixSocketConsole`] | as [`TcpConsoleBridge`], and the socket file | yes | as [`TcpConsoleBridge`], also removing the socket file |
+//! | [`VZDiskImageStorageDeviceAttachment`] | the caller's file, with [`new_from_file`] | yes; the framework holds its own descriptor | `close` or drop |
+//! | [`VZDiskImageStorageDeviceAttachment`] from the builder | a descriptor holding the image lock | yes, by the framework object | the framework's last release, which may be a virtual machine's |
+//! | [`VZDiskBlockDeviceStorageDeviceAttachment`] | the block device, through a file handle | yes, by the framework object | the framework's last release; it cannot be detached |
+//! | `VmHandle` (`isolation`) | the socket to the child | yes | `close` or drop shut it down; the child closes its end and exits |
+//!
+//! [`NSFileHandle`]: crate::base::NSFileHandle
+//! [`VZVirtioSocketConnection`]: crate::virtualization::socket_device::VZVirtioSocketConnection
+//! [`PtyMaster`]: crate::virtualization::console_device::PtyMaster
+//! [`ConsoleCapture`]: crate::virtualization::serial_port::ConsoleCapture
+//! [`ConsoleTee`]: crate::virtualization::console_tee::ConsoleTee
+//! [`ConsoleRecorder`]: crate::virtualization::console_transcript::ConsoleRecorder
+//! [`TcpConsoleBridge`]: crate::virtualization::tcp_console::TcpConsoleBridge
+//! [`stop`]: crate::virtualization::tcp_console::TcpConsoleBridge::stop
+//! [`UnixSocketConsole`]: crate::virtualization::unix_console::UnixSocketConsole
+//! [`VZDiskImageStorageDeviceAttachment`]: crate::virtualization::storage_device::VZDiskImageStorageDeviceAttachment
+//! [`new_from_file`]: crate::virtualization::storage_device::VZDiskImageStorageDeviceAttachment::new_from_file
+//! [`VZDiskBlockDeviceStorageDeviceAttachment`]: crate::virtualization::storage_device::VZDiskBlockDeviceStorageDeviceAttachment
+//!
+//! # Examples
+//! ```rust
+//! let console = TcpConsoleBridge::bind(5555, NoClientPolicy::Drop)?;
+//! // ... the machine is stopped and its configuration dropped:
+//! console.close()?;
+//! let again = TcpConsoleBridge::bind(5555, NoClientPolicy::Drop)?;
+//! ```
+
+use crate::base::NSError;
+
+use std::error::Error;
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::os::unix::io::IntoRawFd;
+
+/// Why a resource did not close cleanly. The resource is released as far as it can be either way.
+#[derive(Debug)]
+pub enum CloseError {
+    /// `close(2)` or a shutdown failed.
+    Io(io::Error),
+    /// `-[NSFileHandle closeAndReturnError:]` failed.
+    Framework(NSError),
+    /// The framework, or another reference, still holds the named resource, so it was left open
+    /// and closes when the last reference is released.
+    Attached(&'static str),
+}
+
+// `NSError` is immutable, and retaining and releasing it is thread-safe.
+unsafe impl Send for CloseError {}
+unsafe impl Sync for CloseError {}
+
+impl fmt::Display for CloseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CloseError::Io(e) => write!(f, "close failed: {}", e),
+            CloseError::Framework(e) => write!(f, "close failed: {}", e),
+            CloseError::Attached(resource) => write!(
+                f,
+                "{} is still referenced, e.g. by a configuration; it closes when released",
+                resource
+            ),
+        }
+    }
+}
+
+impl Error for CloseError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            CloseError::Io(e) => Some(e),
+            CloseError::Framework(e) => Some(e),
+            CloseError::Attached(_) => None,
+        }
+    }
+}
+
+impl From<io::Error> for CloseError {
+    fn from(e: io::Error) -> Self {
+        CloseError::Io(e)
+    }
+}
+
+/// Closes `file`, reporting the error that dropping it would ignore.
+pub(crate) fn close_file(file: File) -> Result<(), CloseError> {
+    if unsafe { libc::close(file.into_raw_fd()) } == -1 {
+        return Err(CloseError::Io(io::Error::last_os_error()));
+    }
+    Ok(())
+}
+
+/// The first error of `results`, after all of them were produced.
+pub(crate) fn first_error<I>(results: I) -> Result<(), CloseError>
+where
+    I: IntoIterator<Item = Result<(), CloseError>>,
+{
+    let mut first = Ok(());
+    for result in results {
+        if first.is_ok() {
+            first = result;
+        }
+    }
+    first
+}
@@ -0,0 +1,662 @@
+//! respawn module
+//!
+//! Crash-loop restarts: a [`Respawner`] creates a virtual machine from a configuration, starts it,
+//! and when it dies unexpectedly creates an identical one on a fresh queue and starts that, until
+//! it stays up or its restart budget runs out.
+//!
+//! What counts as dying unexpectedly is a failed start, a stop with an error and, if the
+//! [`RestartPolicy`] says so, the guest shutting itself down. Stops the host asked for, with
+//! `request_stop_with_error` or `stop`, end the supervision instead; see
+//! [`StopReason`](crate::virtualization::lifecycle::StopReason).
+//!
+//! Restarts are budgeted by [`Backoff`]: at most [`RestartPolicy::max_restarts`] within
+//! [`RestartPolicy::window`], the n-th of them delayed by `initial_delay * 2^(n-1)` up to
+//! `max_delay`. A failure with the budget used up gives up. Restarts older than the window no
+//! longer count, so a machine that stayed up for a window starts over with the shortest delay.
+//!
+//! Each new machine gets a copy of the template configuration, see
+//! [`VZVirtualMachineConfiguration::copy`]. Whatever the application attached to the previous
+//! machine must be attached again: [`Respawner::on_configure`] callbacks may change the copy
+//! before the machine is created, e.g. to point its serial port at a new log file, and
+//! [`Respawner::on_spawn`] callbacks get the machine before it starts, e.g. to register observers
+//! or a [`TeardownGuard`](crate::teardown::TeardownGuard) again.
+//!
+//! Each machine gets a new queue of its own, unless [`Respawner::queues`] gives a
+//! [`QueuePool`](crate::queue_pool::QueuePool) to take them from, e.g. the pool of the
+//! [registry](crate::registry) the machines are registered in.
+//!
+//! The supervision runs on a thread of its own, `vm-respawn`, which reports what happens as
+//! [`RespawnEvent`]s ending with a terminal one.
+//!
+//! A machine on a NAT attachment that keeps failing may be failing for the host's networking.
+//! With [`Respawner::diagnose_networking_on_failure`], each failure is followed by the findings of
+//! [`nat::diagnose`](crate::nat::diagnose).
+//!
+//! Any [`VirtualMachineHandle`] can be supervised: [`Respawner::with_factory`] takes a function
+//! creating the machine of each attempt instead of a configuration, e.g. one returning `FakeVm`s
+//! scripted to crash, to test a restart policy without booting anything.
+//!
+//! # Examples
+//! ```rust
+//! let respawner = Respawner::new(conf, "web")
+//!     .policy(RestartPolicy {
+//!         max_restarts: 3,
+//!         ..RestartPolicy::default()
+//!     })
+//!     .on_spawn(|vm, attempt| println!("{} is attempt {}", vm.display_name(), attempt));
+//! for event in respawner.spawn() {
+//!     match event {
+//!         RespawnEvent::Restarting { delay, .. } => println!("restarting in {:?}", delay),
+//!         RespawnEvent::GaveUp { restarts } => eprintln!("gave up after {} restarts", restarts),
+//!         _ => {}
+//!     }
+//! }
+//! ```
+
+use crate::base::{CancellationToken, DispatchQueue, DispatchSemaphore, QoSClass};
+use crate::nat::{self, NatFinding};
+use crate::queue_pool::QueuePool;
+use crate::virtualization::error::VZError;
+use crate::virtualization::error_events::{ErrorEvent, Phase};
+use crate::virtualization::handle::VirtualMachineHandle;
+use crate::virtualization::lifecycle::{Lifecycle, StopReason};
+use crate::virtualization::virtual_machine::{VZVirtualMachine, VZVirtualMachineConfiguration};
+
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+const RESPAWN_THREAD: &str = "vm-respawn";
+
+/// How many times a [`Respawner`] restarts a machine, and how long it waits before each restart.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RestartPolicy {
+    /// Restarts allowed within `window`. Zero never restarts.
+    pub max_restarts: u32,
+    pub window: Duration,
+    /// Delay of the first restart within the window, doubled for each further one.
+    pub initial_delay: Duration,
+    pub max_delay: Duration,
+    /// Whether the guest shutting itself down is restarted too, as for a service that must stay
+    /// up. Off by default: a guest that shuts down meant to.
+    pub restart_on_guest_shutdown: bool,
+}
+
+impl Default for RestartPolicy {
+    fn default() -> Self {
+        RestartPolicy {
+            max_restarts: 5,
+            window: Duration::from_secs(600),
+            initial_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(60),
+            restart_on_guest_shutdown: false,
+        }
+    }
+}
+
+impl RestartPolicy {
+    /// Whether a machine that stopped for `reason` is restarted.
+    pub fn restarts_after(&self, reason: &StopReason) -> bool {
+        match reason {
+            StopReason::Crashed(_) => true,
+            StopReason::GuestInitiated => self.restart_on_guest_shutdown,
+            StopReason::HostRequested | StopReason::Forced => false,
+        }
+    }
+}
+
+/// What [`Backoff::failed`] decided.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BackoffDecision {
+    /// Restart after `delay`; `attempt` counts the restarts within the window, this one included.
+    Restart { attempt: u32, delay: Duration },
+    /// The budget is used up by the `restarts` within the window.
+    GiveUp { restarts: u32 },
+}
+
+/// The restart budget of a [`RestartPolicy`]: which failures are restarted, and after how long.
+///
+/// Time is passed in, so a sequence of failures can be replayed without waiting for it.
+#[derive(Debug, Clone)]
+pub struct Backoff {
+    policy: RestartPolicy,
+    /// When the restarts within the window were decided, oldest first.
+    restarts: VecDeque<Instant>,
+}
+
+impl Backoff {
+    pub fn new(policy: RestartPolicy) -> Backoff {
+        Backoff {
+            policy,
+            restarts: VecDeque::new(),
+        }
+    }
+
+    /// Decides about a failure at `now`, and counts the restart if there is one.
+    pub fn failed(&mut self, now: Instant) -> BackoffDecision {
+        self.expire(now);
+        let restarts = self.restarts.len() as u32;
+        if restarts >= self.policy.max_restarts {
+            return BackoffDecision::GiveUp { restarts };
+        }
+        self.restarts.push_back(now);
+        BackoffDecision::Restart {
+            attempt: restarts + 1,
+            delay: self.delay(restarts),
+        }
+    }
+
+    /// The restarts that still count against the budget at `now`.
+    pub fn restarts_within_window(&mut self, now: Instant) -> u32 {
+        self.expire(now);
+        self.restarts.len() as u32
+    }
+
+    fn expire(&mut self, now: Instant) {
+        while let Some(&oldest) = self.restarts.front() {
+            if now.saturating_duration_since(oldest) < self.policy.window {
+                break;
+            }
+            self.restarts.pop_front();
+        }
+    }
+
+    /// Delay of a restart with `earlier` restarts before it within the window.
+    fn delay(&self, earlier: u32) -> Duration {
+        let factor = 1u32.checked_shl(earlier).unwrap_or(u32::MAX);
+        self.policy
+            .initial_delay
+            .checked_mul(factor)
+            .map_or(self.policy.max_delay, |delay| {
+                delay.min(self.policy.max_delay)
+            })
+    }
+}
+
+/// What a [`RespawnHandle`] yields.
+#[derive(Debug, Clone)]
+pub enum RespawnEvent<V = VZVirtualMachine> {
+    /// A machine was created and the [`Respawner::on_spawn`] callbacks ran; it is being started.
+    /// `attempt` is 0 for the first machine, then counts the restarts within the window.
+    Spawned { attempt: u32, vm: V },
+    /// The machine failed to start.
+    StartFailed { attempt: u32, error: VZError },
+    /// The machine stopped.
+    Stopped { attempt: u32, reason: StopReason },
+    /// What [`nat::diagnose`] found after the machine failed to start or crashed, with
+    /// [`Respawner::diagnose_networking_on_failure`]. Empty if the host's networking looks fine.
+    NetworkDiagnosed {
+        attempt: u32,
+        findings: Vec<NatFinding>,
+    },
+    /// The next machine is created after `delay`.
+    Restarting { attempt: u32, delay: Duration },
+    /// The last event: the restart budget is used up by the `restarts` within the window.
+    GaveUp { restarts: u32 },
+    /// The last event: the machine stopped for a `reason` that is not restarted, or
+    /// [`RespawnHandle::shutdown`] was called; `reason` is `None` if the last machine failed to
+    /// start or none was running then.
+    Finished { reason: Option<StopReason> },
+}
+
+// The error is a retained `NSError`, which is immutable.
+unsafe impl<V: Send> Send for RespawnEvent<V> {}
+
+impl<V> RespawnEvent<V> {
+    pub fn is_terminal(&self) -> bool {
+        matches!(
+            self,
+            RespawnEvent::GaveUp { .. } | RespawnEvent::Finished { .. }
+        )
+    }
+}
+
+type ConfigureHook = Box<dyn FnMut(&mut VZVirtualMachineConfiguration, u32) + Send>;
+type SpawnHook<V> = Box<dyn FnMut(&V, u32) + Send>;
+type CreateFn<V> = Box<dyn FnMut(u32) -> V + Send>;
+
+/// The configuration the machines of [`Respawner::new`] are created from, and how.
+struct Template {
+    conf: VZVirtualMachineConfiguration,
+    label: String,
+    qos: Option<QoSClass>,
+    queues: Option<QueuePool>,
+    on_configure: Vec<ConfigureHook>,
+}
+
+// The configuration is only used by the supervision thread once the respawner is moved there.
+unsafe impl Send for Template {}
+
+impl Template {
+    /// Creates the machine of `attempt` on a new queue or one from the pool, running the
+    /// callbacks.
+    fn create(&mut self, attempt: u32) -> VZVirtualMachine {
+        let mut conf = self.conf.copy();
+        for hook in &mut self.on_configure {
+            hook(&mut conf, attempt);
+        }
+        match &self.queues {
+            Some(pool) => {
+                VZVirtualMachine::new_labeled(conf, pool.assign(&self.label), &self.label)
+            }
+            None => VZVirtualMachine::new_with_qos(conf, &self.label, self.qos),
+        }
+    }
+}
+
+/// How a [`Respawner`] creates the machine of each attempt.
+enum Factory<V> {
+    /// Only made when `V` is `VZVirtualMachine`, with the identity to hand the machine over.
+    Template(Template, fn(VZVirtualMachine) -> V),
+    Custom(CreateFn<V>),
+}
+
+/// Supervises a virtual machine, creating and starting a new one each time it dies; see the
+/// [module documentation](self).
+pub struct Respawner<V = VZVirtualMachine> {
+    factory: Factory<V>,
+    policy: RestartPolicy,
+    on_spawn: Vec<SpawnHook<V>>,
+    /// A running machine supervised before the first restart.
+    adopted: Option<V>,
+    diagnose_networking: bool,
+}
+
+impl Respawner {
+    /// Supervises machines created from copies of `conf`, each on a new queue named `label` and
+    /// labelled with it.
+    pub fn new(conf: VZVirtualMachineConfiguration, label: &str) -> Respawner {
+        let template = Template {
+            conf,
+            label: label.to_string(),
+            qos: None,
+            queues: None,
+            on_configure: Vec::new(),
+        };
+        Respawner::from_factory(Factory::Template(template, |vm| vm))
+    }
+
+    /// Supervises `vm`, which the application created and started through the safe wrappers, and
+    /// restarts it from [`VZVirtualMachine::configuration_copy`]. The callbacks only run for the
+    /// machines created by the respawner.
+    pub fn adopt(vm: VZVirtualMachine) -> Respawner {
+        let label = vm.label().unwrap_or("vm-respawn").to_string();
+        let mut respawner = Respawner::new(vm.configuration_copy(), &label);
+        respawner.adopted = Some(vm);
+        respawner
+    }
+
+    /// The QoS of the queues of the machines; the default QoS without. Only applies to the
+    /// respawners of [`Respawner::new`] and [`Respawner::adopt`].
+    pub fn qos(mut self, qos: QoSClass) -> Respawner {
+        if let Factory::Template(template, _) = &mut self.factory {
+            template.qos = Some(qos);
+        }
+        self
+    }
+
+    /// Takes the queue of each new machine from `queues`, whose QoS replaces [`Respawner::qos`].
+    /// Only applies to the respawners of [`Respawner::new`] and [`Respawner::adopt`].
+    pub fn queues(mut self, queues: QueuePool) -> Respawner {
+        if let Factory::Template(template, _) = &mut self.factory {
+            template.queues = Some(queues);
+        }
+        self
+    }
+
+    /// Calls `f` with the configuration of each new machine and its attempt, before the machine
+    /// is created from it. Callbacks run in the order they were added, on the `vm-respawn` thread.
+    /// Only applies to the respawners of [`Respawner::new`] and [`Respawner::adopt`].
+    pub fn on_configure<F>(mut self, f: F) -> Respawner
+    where
+        F: FnMut(&mut VZVirtualMachineConfiguration, u32) + Send + 'static,
+    {
+        if let Factory::Template(template, _) = &mut self.factory {
+            template.on_configure.push(Box::new(f));
+        }
+        self
+    }
+}
+
+impl<V: VirtualMachineHandle> Respawner<V> {
+    /// Supervises the machines `create` returns for each attempt, which it is called with on the
+    /// `vm-respawn` thread. Each call must return a new machine that was never started.
+    pub fn with_factory<F>(create: F) -> Respawner<V>
+    where
+        F: FnMut(u32) -> V + Send + 'static,
+    {
+        Respawner::from_factory(Factory::Custom(Box::new(create)))
+    }
+
+    fn from_factory(factory: Factory<V>) -> Respawner<V> {
+        Respawner {
+            factory,
+            policy: RestartPolicy::default(),
+            on_spawn: Vec::new(),
+            adopted: None,
+            diagnose_networking: false,
+        }
+    }
+
+    pub fn policy(mut self, policy: RestartPolicy) -> Respawner<V> {
+        self.policy = policy;
+        self
+    }
+
+    /// Calls `f` with each new machine and its attempt, before it is started. Callbacks run in
+    /// the order they were added, on the `vm-respawn` thread.
+    pub fn on_spawn<F>(mut self, f: F) -> Respawner<V>
+    where
+        F: FnMut(&V, u32) + Send + 'static,
+    {
+        self.on_spawn.push(Box::new(f));
+        self
+    }
+
+    /// Runs [`nat::diagnose`] each time a machine fails to start or crashes, and reports its
+    /// findings as [`RespawnEvent::NetworkDiagnosed`] before deciding about the restart. Off by
+    /// default; meant for machines on a NAT attachment, as the checks take a fraction of a second.
+    pub fn diagnose_networking_on_failure(mut self, diagnose: bool) -> Respawner<V> {
+        self.diagnose_networking = diagnose;
+        self
+    }
+
+    /// Starts the supervision on the `vm-respawn` thread.
+    pub fn spawn(self) -> RespawnHandle<V> {
+        let (events, receiver) = mpsc::channel();
+        let control = Arc::new(Control {
+            shutting_down: AtomicBool::new(false),
+            current: Mutex::new(None),
+            delay: Mutex::new(None),
+        });
+        let supervisor = Supervisor {
+            respawner: self,
+            control: control.clone(),
+            events,
+            queue: DispatchQueue::new(RESPAWN_THREAD),
+        };
+        let thread = thread::Builder::new()
+            .name(RESPAWN_THREAD.to_string())
+            .spawn(move || supervisor.run())
+            .expect("failed to spawn the vm-respawn thread");
+        RespawnHandle {
+            events: receiver,
+            control,
+            thread: Some(thread),
+            finished: false,
+        }
+    }
+}
+
+/// A restart delay in progress.
+struct Delay {
+    elapsed: Arc<DispatchSemaphore>,
+    timer: CancellationToken,
+}
+
+/// Shared by the supervision thread and its handle.
+struct Control<V> {
+    shutting_down: AtomicBool,
+    current: Mutex<Option<V>>,
+    delay: Mutex<Option<Delay>>,
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+impl<V: VirtualMachineHandle> Control<V> {
+    fn is_shutting_down(&self) -> bool {
+        self.shutting_down.load(Ordering::SeqCst)
+    }
+
+    /// Stops supervising: ends a delay in progress and stops the machine, which the supervision
+    /// thread then reports.
+    fn shut_down(&self) {
+        self.shutting_down.store(true, Ordering::SeqCst);
+        if let Some(delay) = lock(&self.delay).as_ref() {
+            delay.timer.cancel();
+            delay.elapsed.signal();
+        }
+        if let Some(vm) = lock(&self.current).as_ref() {
+            if vm.lifecycle() != Lifecycle::Stopped {
+                vm.stop_or_join(|_| {});
+            }
+        }
+    }
+}
+
+/// How a machine's run ended.
+enum Ending {
+    StartFailed(VZError),
+    Stopped(StopReason),
+}
+
+struct Supervisor<V> {
+    respawner: Respawner<V>,
+    control: Arc<Control<V>>,
+    events: Sender<RespawnEvent<V>>,
+    /// Runs the restart delays.
+    queue: DispatchQueue,
+}
+
+impl<V: VirtualMachineHandle> Supervisor<V> {
+    fn send(&self, event: RespawnEvent<V>) {
+        // The handle may be gone; the supervision goes on until it ends.
+        let _ = self.events.send(event);
+    }
+
+    fn run(mut self) {
+        let mut backoff = Backoff::new(self.respawner.policy);
+        let mut attempt = 0;
+        let mut vm = self.respawner.adopted.take();
+        let terminal = loop {
+            let (machine, ending) = match vm.take() {
+                Some(adopted) => {
+                    let ending = self.watch(&adopted, false);
+                    (adopted, ending)
+                }
+                None if self.control.is_shutting_down() => {
+                    break RespawnEvent::Finished { reason: None };
+                }
+                None => {
+                    let machine = self.create(attempt);
+                    let ending = self.watch(&machine, true);
+                    (machine, ending)
+                }
+            };
+            *lock(&self.control.current) = None;
+            drop(machine);
+            let reason = match ending {
+                Ending::StartFailed(error) => {
+                    self.send(RespawnEvent::StartFailed { attempt, error });
+                    None
+                }
+                Ending::Stopped(reason) => {
+                    self.send(RespawnEvent::Stopped {
+                        attempt,
+                        reason: reason.clone(),
+                    });
+                    Some(reason)
+                }
+            };
+            let failed = matches!(reason, None | Some(StopReason::Crashed(_)));
+            if failed && self.respawner.diagnose_networking {
+                self.send(RespawnEvent::NetworkDiagnosed {
+                    attempt,
+                    findings: nat::diagnose(),
+                });
+            }
+            let restart = match &reason {
+                Some(reason) => self.respawner.policy.restarts_after(reason),
+                None => true,
+            };
+            if !restart || self.control.is_shutting_down() {
+                break RespawnEvent::Finished { reason };
+            }
+            match backoff.failed(Instant::now()) {
+                BackoffDecision::GiveUp { restarts } => break RespawnEvent::GaveUp { restarts },
+                BackoffDecision::Restart {
+                    attempt: next,
+                    delay,
+                } => {
+                    self.send(RespawnEvent::Restarting {
+                        attempt: next,
+                        delay,
+                    });
+                    self.wait(delay);
+                    attempt = next;
+                }
+            }
+        };
+        self.send(terminal);
+    }
+
+    /// Creates the machine of `attempt`, running the callbacks.
+    fn create(&mut self, attempt: u32) -> V {
+        let vm = match &mut self.respawner.factory {
+            Factory::Template(template, machine) => machine(template.create(attempt)),
+            Factory::Custom(create) => create(attempt),
+        };
+        for hook in &mut self.respawner.on_spawn {
+            hook(&vm, attempt);
+        }
+        self.send(RespawnEvent::Spawned {
+            attempt,
+            vm: vm.clone(),
+        });
+        vm
+    }
+
+    /// Starts `vm` if `start`, and waits for it to fail to start or to stop.
+    fn watch(&self, vm: &V, start: bool) -> Ending {
+        let events = vm.error_events();
+        *lock(&self.control.current) = Some(vm.clone());
+        // A shutdown before the machine was made current did not stop it.
+        let shutting_down = self.control.is_shutting_down();
+        if start {
+            if shutting_down {
+                return Ending::Stopped(StopReason::Forced);
+            }
+            // A fresh machine is never refused; failures arrive as events.
+            let _ = vm.start(|_| {});
+        } else if vm.lifecycle() == Lifecycle::Stopped {
+            // Stopped before the stream was subscribed to.
+            if let Some(reason) = vm.last_stop_reason() {
+                return Ending::Stopped(reason);
+            }
+        } else if shutting_down {
+            vm.stop_or_join(|_| {});
+        }
+        for event in events {
+            match event {
+                ErrorEvent::Error(e) if e.phase == Phase::Start => {
+                    return Ending::StartFailed(e.error);
+                }
+                ErrorEvent::GuestStopped { reason, .. } => return Ending::Stopped(reason),
+                ErrorEvent::Error(_) => {}
+            }
+        }
+        // The stream only ends early when the machine is gone, which `vm` prevents.
+        Ending::Stopped(vm.last_stop_reason().unwrap_or(StopReason::Forced))
+    }
+
+    /// Waits `delay` with `dispatch_after`, or until the handle shuts the supervision down.
+    fn wait(&self, delay: Duration) {
+        let elapsed = Arc::new(DispatchSemaphore::new(0));
+        let signal = elapsed.clone();
+        let timer = self.queue.after(delay, move || signal.signal());
+        *lock(&self.control.delay) = Some(Delay {
+            elapsed: elapsed.clone(),
+            timer,
+        });
+        // A shutdown before the delay was registered would not have ended it.
+        if !self.control.is_shutting_down() {
+            elapsed.wait();
+        }
+        if let Some(delay) = lock(&self.control.delay).take() {
+            delay.timer.cancel();
+        }
+    }
+}
+
+/// The running supervision of a [`Respawner`]. Iterating it yields its events until the terminal
+/// one. Dropping it shuts the supervision down.
+pub struct RespawnHandle<V: VirtualMachineHandle = VZVirtualMachine> {
+    events: Receiver<RespawnEvent<V>>,
+    control: Arc<Control<V>>,
+    thread: Option<JoinHandle<()>>,
+    finished: bool,
+}
+
+impl<V: VirtualMachineHandle> RespawnHandle<V> {
+    /// Blocks until the next event; `None` after the terminal one.
+    pub fn recv(&mut self) -> Option<RespawnEvent<V>> {
+        if self.finished {
+            return None;
+        }
+        let event = self.events.recv().ok();
+        self.note(event)
+    }
+
+    /// Like [`recv`](Self::recv), giving up after `timeout`.
+    pub fn recv_timeout(&mut self, timeout: Duration) -> Option<RespawnEvent<V>> {
+        if self.finished {
+            return None;
+        }
+        match self.events.recv_timeout(timeout) {
+            Ok(event) => self.note(Some(event)),
+            Err(RecvTimeoutError::Timeout) => None,
+            Err(RecvTimeoutError::Disconnected) => self.note(None),
+        }
+    }
+
+    fn note(&mut self, event: Option<RespawnEvent<V>>) -> Option<RespawnEvent<V>> {
+        self.finished = match &event {
+            Some(event) => event.is_terminal(),
+            None => true,
+        };
+        event
+    }
+
+    /// Whether the terminal event was received.
+    pub fn is_finished(&self) -> bool {
+        self.finished
+    }
+
+    /// The machine being supervised, unless it is between two runs.
+    pub fn current(&self) -> Option<V> {
+        lock(&self.control.current).clone()
+    }
+
+    /// Stops restarting, ends a restart delay in progress and force-stops the running machine.
+    /// The events end with [`RespawnEvent::Finished`].
+    pub fn shutdown(&self) {
+        self.control.shut_down();
+    }
+
+    /// Shuts the supervision down and waits for its thread, dropping the events not received.
+    pub fn join(mut self) {
+        self.shutdown();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl<V: VirtualMachineHandle> Iterator for RespawnHandle<V> {
+    type Item = RespawnEvent<V>;
+
+    fn next(&mut self) -> Option<RespawnEvent<V>> {
+        self.recv()
+    }
+}
+
+impl<V: VirtualMachineHandle> Drop for RespawnHandle<V> {
+    fn drop(&mut self) {
+        self.control.shut_down();
+    }
+}
@@ -0,0 +1,254 @@
+//! runtime module
+//!
+//! The only place in the crate that decides how an Objective-C object is owned, how `BOOL` is
+//! converted and how an `NSError **` out-parameter is read back. Every other module goes through
+//! these helpers instead of calling `StrongPtr::new`/`StrongPtr::retain` or comparing against
+//! `YES`/`NO` directly, and never tells failure from success by messaging a possibly nil error.
+//!
+//! It also hosts [`MainQueuePump`] for programs without an application run loop.
+//!
+//! # Where callbacks arrive
+//! - A machine created with [`VZVirtualMachine::new`] delivers its completion handlers, state
+//!   observations ([`VZVirtualMachine::on_first_transition_to`]) and socket connections on the
+//!   queue passed to it. Nothing needs to be pumped.
+//! - A machine created with [`VZVirtualMachine::new_without_queue`] delivers all of the above on
+//!   the main queue, as do blocks submitted to [`DispatchQueue::main`]. They only run while the
+//!   main thread services the main queue: inside an AppKit run loop, [`MainQueuePump::run_forever`]
+//!   or [`MainQueuePump::pump`].
+//! - `VZVirtualMachineView` must be used on the main thread regardless.
+//!
+//! [`VZVirtualMachine::new`]: crate::virtualization::virtual_machine::VZVirtualMachine::new
+//! [`VZVirtualMachine::new_without_queue`]: crate::virtualization::virtual_machine::VZVirtualMachine::new_without_queue
+//! [`VZVirtualMachine::on_first_transition_to`]: crate::virtualization::virtual_machine::VZVirtualMachine::on_first_transition_to
+//! [`DispatchQueue::main`]: crate::base::DispatchQueue::main
+
+use crate::base::{Id, NSError, NSUInteger, NilObject, NIL};
+
+use std::os::raw::c_void;
+use std::time::{Duration, Instant};
+
+use objc::rc::StrongPtr;
+use objc::runtime::{Class, BOOL, NO, YES};
+use objc::{class, msg_send, sel, sel_impl};
+
+#[cfg_attr(
+    target_vendor = "apple",
+    link(name = "CoreFoundation", kind = "framework")
+)]
+extern "C" {
+    static kCFRunLoopDefaultMode: *const c_void;
+    fn CFRunLoopRunInMode(mode: *const c_void, seconds: f64, return_after_source_handled: u8)
+        -> i32;
+}
+
+#[cfg_attr(target_vendor = "apple", link(name = "Foundation", kind = "framework"))]
+extern "C" {
+    fn dispatch_main() -> !;
+}
+
+/// `kCFRunLoopRunFinished`: the run loop has no sources or timers to wait on.
+const RUN_LOOP_FINISHED: i32 = 1;
+
+/// Services the main dispatch queue from a command-line program, which has no application run
+/// loop to do it.
+pub struct MainQueuePump;
+
+impl MainQueuePump {
+    /// Hands the main thread over to libdispatch. Never returns; exit the process from a callback
+    /// when done.
+    pub fn run_forever() -> ! {
+        unsafe { dispatch_main() }
+    }
+
+    /// Runs main-queue blocks for up to `timeout`, then returns so the caller can do its own work
+    /// in between. Must be called on the main thread.
+    ///
+    /// Returns early only if the run loop has nothing to wait on.
+    pub fn pump(timeout: Duration) {
+        MainQueuePump::pump_until(timeout, || false);
+    }
+
+    /// Runs main-queue blocks until `done` returns `true` or `timeout` passes, checking `done`
+    /// after each block. Returns the last value of `done`. Must be called on the main thread.
+    pub fn pump_until<F: FnMut() -> bool>(timeout: Duration, mut done: F) -> bool {
+        assert!(
+            MainQueuePump::is_main_thread(),
+            "MainQueuePump must be used on the main thread"
+        );
+        let deadline = Instant::now() + timeout;
+        loop {
+            if done() {
+                return true;
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return false;
+            }
+            let seconds = (deadline - now).as_secs_f64();
+            let result = unsafe { CFRunLoopRunInMode(kCFRunLoopDefaultMode, seconds, 1) };
+            if result == RUN_LOOP_FINISHED {
+                return done();
+            }
+        }
+    }
+
+    pub fn is_main_thread() -> bool {
+        let b: BOOL = unsafe { msg_send![class!(NSThread), isMainThread] };
+        from_objc_bool(b)
+    }
+}
+
+/// Asserts in debug builds that `$obj` is not nil, for constructors whose framework call cannot
+/// return nil, so that a regression fails where the object was created instead of in a later
+/// message to it.
+macro_rules! debug_assert_non_nil {
+    ($obj:expr, $what:expr) => {
+        debug_assert!(
+            *$obj != crate::base::NIL,
+            concat!($what, " unexpectedly returned nil")
+        )
+    };
+}
+
+pub(crate) use debug_assert_non_nil;
+
+/// `class!` for the framework's classes: loads the framework first, as binaries do not link it
+/// strongly, and panics with [`NotAvailable`](crate::features::NotAvailable) where it is missing
+/// instead of with objc's "class not found".
+macro_rules! vz_class {
+    ($name:ident) => {{
+        crate::features::expect_framework();
+        objc::class!($name)
+    }};
+}
+
+pub(crate) use vz_class;
+
+/// Sends `alloc` to `class`. The result must be passed to an `init...` method and then to [`owned`].
+pub(crate) unsafe fn alloc(class: &Class) -> Id {
+    msg_send![class, alloc]
+}
+
+/// Takes ownership of an object returned with a +1 retain count (`new`, `alloc`/`init...`, `copy`).
+pub(crate) unsafe fn owned(obj: Id) -> StrongPtr {
+    StrongPtr::new(obj)
+}
+
+/// Retains an object returned with a +0 retain count (autoreleased results and property getters).
+pub(crate) unsafe fn retained(obj: Id) -> StrongPtr {
+    StrongPtr::retain(obj)
+}
+
+/// Whether anything besides the caller's one reference retains `obj`, e.g. a configuration an
+/// attachment was handed to. References held by an autorelease pool count until it drains.
+///
+/// # Safety
+/// `obj` must be a valid, non-nil object.
+pub(crate) unsafe fn is_shared(obj: Id) -> bool {
+    let count: NSUInteger = msg_send![obj, retainCount];
+    count > 1
+}
+
+/// Name of the runtime class of `obj`, e.g. to check what a wrapper holds.
+///
+/// # Safety
+/// `obj` must be a valid, non-nil object.
+pub unsafe fn class_name(obj: Id) -> String {
+    let class: &Class = msg_send![obj, class];
+    class.name().to_string()
+}
+
+/// Runs `f` inside an autorelease pool, so objects autoreleased while it runs are released when
+/// it returns rather than whenever the thread's outermost pool drains.
+pub fn autoreleasepool<R, F: FnOnce() -> R>(f: F) -> R {
+    objc::rc::autoreleasepool(f)
+}
+
+/// Converts a Rust `bool` into the platform `BOOL` (`i8` on x86_64, `bool` on aarch64).
+pub(crate) fn to_objc_bool(b: bool) -> BOOL {
+    if b {
+        YES
+    } else {
+        NO
+    }
+}
+
+/// Converts the platform `BOOL` into a Rust `bool`.
+pub(crate) fn from_objc_bool(b: BOOL) -> bool {
+    b != NO
+}
+
+/// What a method with an `NSError **` out-parameter returns, which says whether it failed: `NO`
+/// (after [`from_objc_bool`]) or nil.
+pub(crate) trait ErrorOutResult {
+    fn failed(&self) -> bool;
+}
+
+impl ErrorOutResult for bool {
+    fn failed(&self) -> bool {
+        !*self
+    }
+}
+
+impl ErrorOutResult for Id {
+    fn failed(&self) -> bool {
+        self.is_null()
+    }
+}
+
+impl ErrorOutResult for StrongPtr {
+    fn failed(&self) -> bool {
+        self.is_null()
+    }
+}
+
+/// Calls `f` with a pointer to a nil-initialized `NSError *` and reads the outcome by the Cocoa
+/// contract: the return value says whether the call failed, and only a failed call's error is
+/// read. An error stored by a call that succeeded is ignored, and a failure that stored none comes
+/// back as [`NSError::unreported`].
+///
+/// The stored error is autoreleased by the framework, so it is retained here rather than adopted;
+/// adopting it was the source of the over-release crashes on the old `NSError::nil()` pattern.
+pub(crate) unsafe fn call_with_error<R, F>(f: F) -> Result<R, NSError>
+where
+    R: ErrorOutResult,
+    F: FnOnce(*mut Id) -> R,
+{
+    let mut error: Id = NIL;
+    let ret = f(&mut error);
+    if !ret.failed() {
+        Ok(ret)
+    } else if error.is_null() {
+        Err(NSError::unreported())
+    } else {
+        Err(NSError(retained(error)))
+    }
+}
+
+/// An object moved into a block for a dispatch queue. Retaining and releasing framework objects
+/// is thread-safe, and the crate only messages each on the queue it belongs to, which
+/// `StrongPtr` and `Id` cannot say.
+pub(crate) struct ForQueue<T: QueueObject>(pub(crate) T);
+
+unsafe impl<T: QueueObject> Send for ForQueue<T> {}
+
+/// What a [`ForQueue`] may carry: framework object pointers, alone or in an `Option` or pair,
+/// but no Rust value that is not `Send` on its own.
+pub(crate) trait QueueObject {}
+
+impl QueueObject for StrongPtr {}
+impl QueueObject for Id {}
+impl<T: QueueObject> QueueObject for Option<T> {}
+impl<A: QueueObject, B: QueueObject> QueueObject for (A, B) {}
+
+/// `obj`, or [`NilObject`] naming `what` if it is nil, which debug builds assert against. For
+/// wrapper methods that need their object: a message to nil returns zero, which passes for a
+/// result.
+pub(crate) fn non_nil(obj: Id, what: &'static str) -> Result<Id, NilObject> {
+    debug_assert!(!obj.is_null(), "{} is nil", what);
+    if obj.is_null() {
+        Err(NilObject(what))
+    } else {
+        Ok(obj)
+    }
+}
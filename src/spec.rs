@@ -0,0 +1,433 @@
+//! spec module
+//!
+//! A virtual machine described in YAML: a [guest profile](crate::profile) with its inputs, the
+//! CPU count and memory size, and extra devices, each under a name. [`VmSpec::build`] turns it
+//! into a configuration builder through [`profile::configure`].
+//!
+//! ```yaml
+//! profile: linux-direct-kernel
+//! cpu_count: 2
+//! memory_mib: 2048
+//! inputs:
+//!   kernel: vm/vmlinuz
+//!   initrd: vm/initrd
+//!   disk: vm/disk.img
+//! devices:
+//!   - name: data
+//!     type: disk
+//!     path: vm/data.img
+//!   - name: uplink
+//!     type: network
+//!     attachment: nat
+//! ```
+//!
+//! # Layering
+//! One shape deployed to several environments is a shared base file with a small overlay per
+//! environment. [`VmSpec::from_layers`] folds files in order with [`VmSpec::merge`], each one
+//! an overlay on the result of the ones before:
+//!
+//! | field | merge |
+//! |---|---|
+//! | `profile`, `cpu_count`, `memory_mib` | replaced when the overlay sets them |
+//! | each field of `inputs` | replaced when the overlay sets it |
+//! | `devices` | merged by `name`, see below |
+//!
+//! A device of the overlay whose name is not in the base is added after the base's devices. One
+//! whose name is, is merged into it field by field, each field replaced when the overlay sets it,
+//! and keeps the base device's place. It may leave out `type`; giving another one than the base's
+//! is a [`DeviceConflict`]. `remove: true` drops the device of that name; a removal of a name the
+//! base does not have is ignored, so an overlay applies to bases with or without the device.
+//!
+//! ```yaml
+//! # prod.yaml, on top of base.yaml
+//! memory_mib: 8192
+//! inputs:
+//!   disk: /Volumes/prod/disk.img
+//! devices:
+//!   - name: data
+//!     remove: true
+//! ```
+//!
+//! # Devices
+//! The profile adds the devices of its [`DeviceSelection`](crate::profile::DeviceSelection),
+//! e.g. the root disk from `inputs.disk`. The spec's devices come after them: disks after the
+//! profile's disks, networks after its network device. Entropy and memory balloon devices of the
+//! spec replace the profile's. Consoles and graphics need file handles and a view, so the caller
+//! adds those to the builder.
+//!
+//! # Examples
+//! ```rust
+//! let spec = VmSpec::from_layers(&["vm/base.yaml".into(), "vm/prod.yaml".into()])?;
+//! let conf = spec.build()?.serial_ports(vec![console]).build();
+//! ```
+
+use crate::profile::{self, ParseProfileError, ProfileError, ProfileInputs, ProfileKind};
+use crate::virtualization::entropy_device::VZVirtioEntropyDeviceConfiguration;
+use crate::virtualization::memory_device::VZVirtioTraditionalMemoryBalloonDeviceConfiguration;
+use crate::virtualization::network_device::{
+    VZMACAddress, VZNATNetworkDeviceAttachment, VZVirtioNetworkDeviceConfiguration,
+};
+use crate::virtualization::storage_device::{
+    VZDiskImageStorageDeviceAttachmentBuilder, VZVirtioBlockDeviceConfiguration,
+};
+use crate::virtualization::virtual_machine::VZVirtualMachineConfigurationBuilder;
+
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+/// A virtual machine, or a layer of one; every field may be left out of a layer.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct VmSpec {
+    /// A [`ProfileKind`] name, e.g. `linux-efi-cloud-image`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub profile: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cpu_count: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub memory_mib: Option<usize>,
+    pub inputs: ProfileInputs,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub devices: Vec<DeviceSpec>,
+}
+
+/// A device of a spec, identified by its name across layers.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DeviceSpec {
+    pub name: String,
+    /// Needed by the time the spec is built; an overlay may leave it out.
+    #[serde(rename = "type", skip_serializing_if = "Option::is_none")]
+    pub kind: Option<DeviceKind>,
+    /// Drops the device of this name from the layers below.
+    #[serde(skip_serializing_if = "is_false")]
+    pub remove: bool,
+    /// The disk image of a `disk`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub path: Option<PathBuf>,
+    /// For a `disk`; read-write if not set.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub read_only: Option<bool>,
+    /// For a `network`; `nat` if not set.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub attachment: Option<NetworkAttachmentKind>,
+    /// For a `network`, e.g. `52:54:00:12:34:56`; random if not set.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mac_address: Option<String>,
+}
+
+fn is_false(value: &bool) -> bool {
+    !*value
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DeviceKind {
+    /// A virtio block device on a disk image.
+    Disk,
+    /// A virtio network device.
+    Network,
+    /// A virtio entropy device.
+    Entropy,
+    /// A virtio traditional memory balloon device.
+    Balloon,
+}
+
+impl fmt::Display for DeviceKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            DeviceKind::Disk => "disk",
+            DeviceKind::Network => "network",
+            DeviceKind::Entropy => "entropy",
+            DeviceKind::Balloon => "balloon",
+        })
+    }
+}
+
+/// What a network device of a spec is attached to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NetworkAttachmentKind {
+    /// The host's NAT.
+    Nat,
+}
+
+/// Two layers give one device name different types.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeviceConflict {
+    pub device: String,
+    pub base: DeviceKind,
+    pub overlay: DeviceKind,
+}
+
+impl fmt::Display for DeviceConflict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "device {:?} is of type {} here but {} in the layers below",
+            self.device, self.overlay, self.base
+        )
+    }
+}
+
+impl std::error::Error for DeviceConflict {}
+
+/// Why a spec could not be loaded or built.
+#[derive(Debug)]
+pub enum SpecError {
+    Io {
+        file: PathBuf,
+        error: io::Error,
+    },
+    /// Not YAML, or not a spec.
+    Parse {
+        file: PathBuf,
+        message: String,
+    },
+    /// One file lists a device name twice.
+    DuplicateDevice {
+        file: PathBuf,
+        device: String,
+    },
+    /// A layer gives a device another type than the layers below it.
+    Conflict {
+        file: PathBuf,
+        conflict: DeviceConflict,
+    },
+    /// No layer names a profile.
+    NoProfile,
+    UnknownProfile(ParseProfileError),
+    /// The profile refused its inputs.
+    Profile(Vec<ProfileError>),
+    /// A device that cannot be built, e.g. a disk without a path.
+    Device {
+        device: String,
+        message: String,
+    },
+}
+
+impl fmt::Display for SpecError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SpecError::Io { file, error } => write!(f, "{}: {}", file.display(), error),
+            SpecError::Parse { file, message } => write!(f, "{}: {}", file.display(), message),
+            SpecError::DuplicateDevice { file, device } => {
+                write!(f, "{}: device {:?} is listed twice", file.display(), device)
+            }
+            SpecError::Conflict { file, conflict } => {
+                write!(f, "{}: {}", file.display(), conflict)
+            }
+            SpecError::NoProfile => f.write_str("the spec names no profile"),
+            SpecError::UnknownProfile(e) => write!(f, "{}", e),
+            SpecError::Profile(errors) => {
+                for (i, error) in errors.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str("; ")?;
+                    }
+                    write!(f, "{}", error)?;
+                }
+                Ok(())
+            }
+            SpecError::Device { device, message } => write!(f, "device {:?}: {}", device, message),
+        }
+    }
+}
+
+impl std::error::Error for SpecError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            SpecError::Io { error, .. } => Some(error),
+            SpecError::Conflict { conflict, .. } => Some(conflict),
+            SpecError::UnknownProfile(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl DeviceSpec {
+    /// `overlay` laid over this device of the same name.
+    fn merge(self, overlay: DeviceSpec) -> Result<DeviceSpec, DeviceConflict> {
+        let kind = match (self.kind, overlay.kind) {
+            (Some(base), Some(kind)) if base != kind => {
+                return Err(DeviceConflict {
+                    device: overlay.name,
+                    base,
+                    overlay: kind,
+                })
+            }
+            (base, kind) => kind.or(base),
+        };
+        Ok(DeviceSpec {
+            name: self.name,
+            kind,
+            remove: false,
+            path: overlay.path.or(self.path),
+            read_only: overlay.read_only.or(self.read_only),
+            attachment: overlay.attachment.or(self.attachment),
+            mac_address: overlay.mac_address.or(self.mac_address),
+        })
+    }
+
+    fn error(&self, message: &str) -> SpecError {
+        SpecError::Device {
+            device: self.name.clone(),
+            message: message.to_string(),
+        }
+    }
+
+    fn disk(&self) -> Result<VZVirtioBlockDeviceConfiguration, SpecError> {
+        let path = self
+            .path
+            .as_ref()
+            .ok_or_else(|| self.error("a disk needs a path"))?;
+        VZDiskImageStorageDeviceAttachmentBuilder::new()
+            .path(path.to_string_lossy().into_owned())
+            .read_only(self.read_only.unwrap_or(false))
+            .build()
+            .map(VZVirtioBlockDeviceConfiguration::new)
+            .map_err(|e| self.error(&e.to_string()))
+    }
+
+    fn network(&self) -> Result<VZVirtioNetworkDeviceConfiguration, SpecError> {
+        let mut device = match self.attachment.unwrap_or(NetworkAttachmentKind::Nat) {
+            NetworkAttachmentKind::Nat => {
+                VZVirtioNetworkDeviceConfiguration::new(VZNATNetworkDeviceAttachment::new())
+            }
+        };
+        if let Some(mac) = &self.mac_address {
+            let mac = VZMACAddress::init_with_string(mac)
+                .ok_or_else(|| self.error(&format!("{:?} is not a MAC address", mac)))?;
+            device
+                .set_mac_address(mac)
+                .map_err(|e| self.error(&e.to_string()))?;
+        }
+        Ok(device)
+    }
+}
+
+impl VmSpec {
+    /// Parses one layer; `file` names it in errors.
+    pub fn from_yaml<P: AsRef<Path>>(text: &str, file: P) -> Result<VmSpec, SpecError> {
+        let file = file.as_ref();
+        // An empty file is an empty layer, not a null document.
+        if text.trim().is_empty() {
+            return Ok(VmSpec::default());
+        }
+        let spec: VmSpec = serde_yaml::from_str(text).map_err(|e| SpecError::Parse {
+            file: file.to_path_buf(),
+            message: e.to_string(),
+        })?;
+        for (i, device) in spec.devices.iter().enumerate() {
+            if spec.devices[..i].iter().any(|d| d.name == device.name) {
+                return Err(SpecError::DuplicateDevice {
+                    file: file.to_path_buf(),
+                    device: device.name.clone(),
+                });
+            }
+        }
+        Ok(spec)
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<VmSpec, SpecError> {
+        let path = path.as_ref();
+        let text = fs::read_to_string(path).map_err(|error| SpecError::Io {
+            file: path.to_path_buf(),
+            error,
+        })?;
+        VmSpec::from_yaml(&text, path)
+    }
+
+    /// Loads `paths` and folds them in order, each an overlay on the ones before. Fails with the
+    /// first file that cannot be read or parsed, or that conflicts with the layers below it.
+    pub fn from_layers(paths: &[PathBuf]) -> Result<VmSpec, SpecError> {
+        let mut spec = VmSpec::default();
+        for path in paths {
+            spec = VmSpec::merge(spec, VmSpec::load(path)?).map_err(|conflict| {
+                SpecError::Conflict {
+                    file: path.clone(),
+                    conflict,
+                }
+            })?;
+        }
+        Ok(spec)
+    }
+
+    /// `overlay` laid over `base`, as the [module documentation](self) describes.
+    pub fn merge(base: VmSpec, overlay: VmSpec) -> Result<VmSpec, DeviceConflict> {
+        let mut devices = base.devices;
+        for device in overlay.devices {
+            let existing = devices.iter().position(|d| d.name == device.name);
+            match (existing, device.remove) {
+                (Some(i), true) => {
+                    devices.remove(i);
+                }
+                (None, true) => {}
+                (Some(i), false) => {
+                    let merged = devices[i].clone().merge(device)?;
+                    devices[i] = merged;
+                }
+                (None, false) => devices.push(device),
+            }
+        }
+        let (inputs, base_inputs) = (overlay.inputs, base.inputs);
+        Ok(VmSpec {
+            profile: overlay.profile.or(base.profile),
+            cpu_count: overlay.cpu_count.or(base.cpu_count),
+            memory_mib: overlay.memory_mib.or(base.memory_mib),
+            inputs: ProfileInputs {
+                kernel: inputs.kernel.or(base_inputs.kernel),
+                initrd: inputs.initrd.or(base_inputs.initrd),
+                command_line: inputs.command_line.or(base_inputs.command_line),
+                efi_variable_store: inputs.efi_variable_store.or(base_inputs.efi_variable_store),
+                disk: inputs.disk.or(base_inputs.disk),
+                cloud_init_seed: inputs.cloud_init_seed.or(base_inputs.cloud_init_seed),
+            },
+            devices,
+        })
+    }
+
+    /// A configuration builder with the profile's boot loader and devices, the CPU count, the
+    /// memory size and the spec's devices. The caller adds consoles and graphics, then builds.
+    pub fn build(&self) -> Result<VZVirtualMachineConfigurationBuilder, SpecError> {
+        let kind: ProfileKind = self
+            .profile
+            .as_deref()
+            .ok_or(SpecError::NoProfile)?
+            .parse()
+            .map_err(SpecError::UnknownProfile)?;
+        let mut builder = profile::configure(kind.profile().as_ref(), &self.inputs)
+            .map_err(SpecError::Profile)?;
+        if let Some(cpu_count) = self.cpu_count {
+            builder = builder.cpu_count(cpu_count);
+        }
+        if let Some(mib) = self.memory_mib {
+            builder = builder.memory_size_mib(mib);
+        }
+        let mut entropy = Vec::new();
+        let mut balloons = Vec::new();
+        for device in &self.devices {
+            match device.kind {
+                Some(DeviceKind::Disk) => builder = builder.storage_device(device.disk()?),
+                Some(DeviceKind::Network) => builder = builder.network_device(device.network()?),
+                Some(DeviceKind::Entropy) => {
+                    entropy.push(VZVirtioEntropyDeviceConfiguration::new())
+                }
+                Some(DeviceKind::Balloon) => {
+                    balloons.push(VZVirtioTraditionalMemoryBalloonDeviceConfiguration::new())
+                }
+                None => return Err(device.error("no layer gives its type")),
+            }
+        }
+        if !entropy.is_empty() {
+            builder = builder.entropy_devices(entropy);
+        }
+        if !balloons.is_empty() {
+            builder = builder.memory_balloon_devices(balloons);
+        }
+        Ok(builder)
+    }
+}
@@ -0,0 +1,231 @@
+//! strict module
+//!
+//! Opt-in checks that turn misuse of the framework into an immediate panic naming the misuse and
+//! the fix, instead of a crash deep inside Virtualization.framework some time later. Strict mode
+//! is off by default. [`strict_mode`] turns it on or off for the whole process; starting the
+//! process with `VIRTUALIZATION_RS_STRICT=1` (or `0`) overrides both the default and
+//! [`strict_mode`], so a staging deployment can enable it without a rebuild.
+//!
+//! When strict mode is off, each check costs one relaxed atomic load. When it is on, the checks
+//! themselves are pointer compares, a queue-specific lookup or a `respondsToSelector:`, cheap
+//! enough to leave on in staging.
+//!
+//! Every panic message starts with [`PANIC_PREFIX`], followed by one of these; the wording is
+//! stable:
+//!
+//! | misuse | checked in | message after the prefix |
+//! |---|---|---|
+//! | a queue-bound call off the VM's queue | `VZVirtualMachine::state`, `request_stop_with_error`, `start_with_completion_handler` | `<call> called off the virtual machine's queue "<label>"; call it from a callback on vm.queue() or inside vm.queue().exec_sync` |
+//! | waiting for a queue from the queue itself | `DispatchQueue::exec_sync` | `DispatchQueue::exec_sync called on queue "<label>" itself, which deadlocks; call it from another thread or queue` |
+//! | changing a device whose configuration created a machine | the device setters returning [`FrozenConfigError`] | `<setter> called on a configuration already used by a virtual machine; build a new configuration instead` |
+//! | a memory size that is not a multiple of 1 MiB | `VZVirtualMachineConfigurationBuilder::memory_size` | `<error>; use memory_size_mib or memory_size_gib`, with [`AlignmentError`]'s `Display` as error |
+//! | caching and synchronization modes that endanger a writable disk image | `VZDiskImageStorageDeviceAttachmentBuilder::build` | `<error>; choose other modes, e.g. from recommended_for_workload`, with [`VZDiskImageAttachmentError`]'s `Display` as error |
+//! | serial ports sharing the file they read from or write to | `VZVirtualMachineConfigurationBuilder::serial_ports` | `<duplicate>; give each port its own file handles`, with [`DuplicateConsoleBacking`]'s `Display` as duplicate |
+//! | a refused start or stop | `VZVirtualMachine::start`, `start_with_deadline`, `stop` | `<operation> of <vm> refused: <reason>; <fix>`, with [`LifecycleError`]'s `Display` as reason |
+//! | a wrapper around nil | the machine, its queue, boot loader, platform and devices when a configuration or machine is built, dispatch queues when work is submitted | `<what> wraps a nil object; it came from a constructor that failed or a pointer that was released` |
+//! | an API the running macOS lacks | `VZVirtualMachine::stop`, `save_machine_state_to`, `restore_machine_state_from` | `<call> needs <macOS version>, and this host's Virtualization.framework lacks -[<class> <selector>]; check the macOS version before calling it` |
+//!
+//! [`FrozenConfigError`]: crate::virtualization::error::FrozenConfigError
+//! [`LifecycleError`]: crate::virtualization::lifecycle::LifecycleError
+//! [`AlignmentError`]: crate::virtualization::error::AlignmentError
+//! [`VZDiskImageAttachmentError`]: crate::virtualization::storage_device::VZDiskImageAttachmentError
+//! [`DuplicateConsoleBacking`]: crate::virtualization::serial_port::DuplicateConsoleBacking
+//!
+//! # Examples
+//! ```rust
+//! virtualization_rs::strict::strict_mode(cfg!(debug_assertions));
+//! // Panics with "virtualization-rs strict mode: VZVirtualMachine::state called off the virtual
+//! // machine's queue ..." instead of racing the framework.
+//! let state = unsafe { vm.state() };
+//! ```
+
+use crate::base::{DispatchQueue, Id, NIL};
+use crate::runtime::{class_name, from_objc_bool};
+use crate::virtualization::error::{AlignmentError, FrozenConfigError};
+use crate::virtualization::lifecycle::LifecycleError;
+use crate::virtualization::serial_port::DuplicateConsoleBacking;
+use crate::virtualization::storage_device::VZDiskImageAttachmentError;
+
+use std::env;
+use std::fmt;
+use std::sync::atomic::{AtomicU8, Ordering};
+
+use objc::runtime::{Sel, BOOL};
+use objc::{msg_send, sel, sel_impl};
+
+/// Overrides [`strict_mode`] when set to `1` or `0`.
+pub const STRICT_ENV: &str = "VIRTUALIZATION_RS_STRICT";
+
+/// Start of every strict mode panic message.
+pub const PANIC_PREFIX: &str = "virtualization-rs strict mode: ";
+
+const UNSET: u8 = 0;
+const OFF: u8 = 1;
+const ON: u8 = 2;
+
+static STATE: AtomicU8 = AtomicU8::new(UNSET);
+
+/// Turns strict mode on or off for the whole process, unless [`STRICT_ENV`] is set.
+pub fn strict_mode(enabled: bool) {
+    let enabled = env_override().unwrap_or(enabled);
+    STATE.store(if enabled { ON } else { OFF }, Ordering::Relaxed);
+}
+
+/// Whether strict mode is on.
+#[inline]
+pub fn is_strict() -> bool {
+    match STATE.load(Ordering::Relaxed) {
+        ON => true,
+        OFF => false,
+        _ => init_from_env(),
+    }
+}
+
+#[cold]
+fn init_from_env() -> bool {
+    let initial = if env_override().unwrap_or(false) {
+        ON
+    } else {
+        OFF
+    };
+    // A concurrent `strict_mode` call wins.
+    let _ = STATE.compare_exchange(UNSET, initial, Ordering::Relaxed, Ordering::Relaxed);
+    STATE.load(Ordering::Relaxed) == ON
+}
+
+fn env_override() -> Option<bool> {
+    match env::var(STRICT_ENV).as_deref() {
+        Ok("1") => Some(true),
+        Ok("0") => Some(false),
+        _ => None,
+    }
+}
+
+#[cold]
+#[track_caller]
+fn fail(message: fmt::Arguments<'_>) -> ! {
+    panic!("{}{}", PANIC_PREFIX, message)
+}
+
+/// Panics unless the caller runs on `queue`, the virtual machine's queue.
+#[inline]
+#[track_caller]
+pub(crate) fn on_vm_queue(queue: &DispatchQueue, call: &str) {
+    if is_strict() && !queue.is_current() {
+        fail(format_args!(
+            "{} called off the virtual machine's queue {:?}; call it from a callback on \
+             vm.queue() or inside vm.queue().exec_sync",
+            call,
+            queue.label()
+        ));
+    }
+}
+
+/// Panics if the caller runs on `queue`, which `exec_sync` would then wait for forever.
+#[inline]
+#[track_caller]
+pub(crate) fn not_on_queue(queue: &DispatchQueue) {
+    if is_strict() && queue.is_current() {
+        fail(format_args!(
+            "DispatchQueue::exec_sync called on queue {:?} itself, which deadlocks; call it \
+             from another thread or queue",
+            queue.label()
+        ));
+    }
+}
+
+/// Panics if `obj`, the object wrapped by `what`, is nil.
+#[inline]
+#[track_caller]
+pub(crate) fn non_nil(obj: Id, what: &str) {
+    if is_strict() && obj == NIL {
+        fail(format_args!(
+            "{} wraps a nil object; it came from a constructor that failed or a pointer that \
+             was released",
+            what
+        ));
+    }
+}
+
+/// Panics with `error`, a setter refused on a frozen configuration.
+#[inline]
+#[track_caller]
+pub(crate) fn frozen(error: &FrozenConfigError) {
+    if is_strict() {
+        fail(format_args!("{}; build a new configuration instead", error));
+    }
+}
+
+/// Panics with `error`, a memory size the builder would otherwise round down.
+#[inline]
+#[track_caller]
+pub(crate) fn misaligned(error: &AlignmentError) {
+    if is_strict() {
+        fail(format_args!(
+            "{}; use memory_size_mib or memory_size_gib",
+            error
+        ));
+    }
+}
+
+/// Panics with `error`, disk image modes the builder would otherwise only warn about.
+#[inline]
+#[track_caller]
+pub(crate) fn dangerous_modes(error: &VZDiskImageAttachmentError) {
+    if is_strict() {
+        fail(format_args!(
+            "{}; choose other modes, e.g. from recommended_for_workload",
+            error
+        ));
+    }
+}
+
+/// Panics with `duplicate`, serial ports the builder would otherwise only warn about.
+#[inline]
+#[track_caller]
+pub(crate) fn duplicate_console_backing(duplicate: &DuplicateConsoleBacking) {
+    if is_strict() {
+        fail(format_args!(
+            "{}; give each port its own file handles",
+            duplicate
+        ));
+    }
+}
+
+/// Panics with `error`, `operation` refused by the lifecycle tracker of the machine `vm`.
+#[inline]
+#[track_caller]
+pub(crate) fn refused(operation: &str, vm: &str, error: &LifecycleError) {
+    if is_strict() {
+        let fix = match error {
+            LifecycleError::AlreadyStarted => "use start_or_join to share the start in flight",
+            LifecycleError::AlreadyStopping => "use stop_or_join to share the stop in flight",
+            LifecycleError::StopInProgress => "start it again from the stop's completion handler",
+        };
+        fail(format_args!(
+            "{} of {} refused: {}; {}",
+            operation, vm, error, fix
+        ));
+    }
+}
+
+/// Panics unless `obj` responds to `selector`, which `call` sends and which appeared in
+/// `requirement`, e.g. `macOS 12`.
+#[inline]
+#[track_caller]
+pub(crate) fn available(obj: Id, selector: Sel, call: &str, requirement: &str) {
+    if !is_strict() {
+        return;
+    }
+    let responds: BOOL = unsafe { msg_send![obj, respondsToSelector: selector] };
+    if !from_objc_bool(responds) {
+        fail(format_args!(
+            "{} needs {}, and this host's Virtualization.framework lacks -[{} {}]; check the \
+             macOS version before calling it",
+            call,
+            requirement,
+            unsafe { class_name(obj) },
+            selector.name()
+        ));
+    }
+}
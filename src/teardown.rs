@@ -0,0 +1,394 @@
+//! teardown module
+//!
+//! Opt-in crash-safe teardown: on SIGINT, SIGTERM or a panic, registered virtual machines are
+//! stopped before the process goes away, so disk images get their final synchronization.
+//!
+//! The signal handler only writes the signal number to a self-pipe. A dedicated `vm-teardown`
+//! thread reads it, stops the machines according to their policies, then restores the previous
+//! signal disposition and re-raises the signal. The panic hook hands off to the same thread and
+//! waits for it before running the previous hook. A machine that does not stop within its policy
+//! is logged as a warning, with its [`VmId`].
+//!
+//! The registry holds machines weakly: a machine is forgotten once it is dropped, or once the run
+//! it was registered for ends, whichever comes first. Register it again before starting it again.
+//! [`registered`] lists the machines still covered.
+//!
+//! Any [`VirtualMachineHandle`] may be registered, so a shutdown sequence can be tested against a
+//! `FakeVm` whose guest ignores the request to stop. [`shutdown`] runs the same sequence on one
+//! machine without a signal or panic.
+//!
+//! [`VmId`]: crate::virtualization::virtual_machine::VmId
+//!
+//! # Examples
+//! ```rust
+//! let vm = VZVirtualMachine::new(conf, queue.id());
+//! let _guard = TeardownGuard::register(&vm, TeardownPolicy::default());
+//! vm.start(|_| {})?;
+//! ```
+
+use crate::base::DispatchSemaphore;
+use crate::virtualization::error_events::ErrorEventReceiver;
+use crate::virtualization::handle::{VirtualMachineHandle, WeakHandle};
+use crate::virtualization::virtual_machine::{VZVirtualMachineState, VmId};
+
+use std::io;
+use std::mem;
+use std::panic;
+use std::sync::atomic::{AtomicI32, AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, Once};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Signals that trigger teardown.
+const SIGNALS: [libc::c_int; 2] = [libc::SIGINT, libc::SIGTERM];
+
+/// Byte written to the self-pipe for a panic instead of a signal number.
+const PANIC_TRIGGER: u8 = 0;
+
+const TEARDOWN_THREAD: &str = "vm-teardown";
+
+/// Granularity at which the teardown thread polls the machine state.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How a registered virtual machine is stopped on teardown.
+#[derive(Debug, Clone, Copy)]
+pub struct TeardownPolicy {
+    /// How long the guest gets to shut down after a stop request before it is force stopped.
+    /// Zero skips the request.
+    pub request_stop_timeout: Duration,
+    /// How long to wait for the machine to reach the stopped state after a force stop.
+    pub force_stop_timeout: Duration,
+}
+
+impl Default for TeardownPolicy {
+    fn default() -> Self {
+        TeardownPolicy {
+            request_stop_timeout: Duration::from_secs(10),
+            force_stop_timeout: Duration::from_secs(5),
+        }
+    }
+}
+
+impl TeardownPolicy {
+    fn total(&self) -> Duration {
+        self.request_stop_timeout + self.force_stop_timeout
+    }
+}
+
+/// A registered machine, whatever its type, held weakly.
+trait Registered: Send + Sync {
+    /// Stops the machine within `policy`; returns whether it stopped, which a dropped machine
+    /// has.
+    fn teardown(&self, policy: &TeardownPolicy) -> bool;
+
+    fn is_dropped(&self) -> bool;
+}
+
+impl<W: WeakHandle> Registered for W {
+    fn teardown(&self, policy: &TeardownPolicy) -> bool {
+        match self.upgrade() {
+            Some(vm) => shutdown(&vm, policy),
+            None => true,
+        }
+    }
+
+    fn is_dropped(&self) -> bool {
+        self.upgrade().is_none()
+    }
+}
+
+#[derive(Clone)]
+struct Entry {
+    id: u64,
+    vm: Arc<dyn Registered>,
+    vm_id: VmId,
+    name: String,
+    /// Ends with the run the machine was registered for, or when the machine is dropped.
+    events: Arc<ErrorEventReceiver>,
+    policy: TeardownPolicy,
+}
+
+impl Entry {
+    /// Whether the machine was dropped or its run ended.
+    fn is_released(&self) -> bool {
+        while self.events.try_recv().is_some() {}
+        self.events.is_finished() || self.vm.is_dropped()
+    }
+}
+
+static REGISTRY: Mutex<Vec<Entry>> = Mutex::new(Vec::new());
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+static INSTALL: Once = Once::new();
+static PIPE_WRITE: AtomicI32 = AtomicI32::new(-1);
+
+/// Number of completed teardown runs, for the panic hook to wait on.
+static RUNS: Mutex<u64> = Mutex::new(0);
+static RUNS_CHANGED: Condvar = Condvar::new();
+
+fn registry() -> MutexGuard<'static, Vec<Entry>> {
+    REGISTRY.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Forgets the machines that were dropped or stopped, and returns the others.
+///
+/// Checked outside the lock: a machine upgraded to check whether it is alive may be torn down
+/// here, if its last clone was dropped meanwhile.
+fn prune() -> Vec<Entry> {
+    let (released, live): (Vec<Entry>, Vec<Entry>) =
+        registry().clone().into_iter().partition(Entry::is_released);
+    if !released.is_empty() {
+        registry().retain(|entry| !released.iter().any(|r| r.id == entry.id));
+    }
+    live
+}
+
+/// The machines registered for teardown that were neither dropped nor stopped, in the order they
+/// were registered.
+pub fn registered() -> Vec<VmId> {
+    prune().iter().map(|entry| entry.vm_id).collect()
+}
+
+/// Keeps a virtual machine registered for teardown until dropped, the machine is dropped or its
+/// run ends.
+///
+/// Machines that are already stopped when teardown triggers are skipped.
+pub struct TeardownGuard {
+    id: u64,
+}
+
+impl TeardownGuard {
+    /// Registers `vm` for its current or next run, installing the signal handlers, panic hook and
+    /// teardown thread on first use. The registry does not keep `vm` alive.
+    pub fn register<V: VirtualMachineHandle>(
+        vm: &V,
+        policy: TeardownPolicy,
+    ) -> io::Result<TeardownGuard> {
+        let mut installed = Ok(());
+        INSTALL.call_once(|| installed = install());
+        installed?;
+        if PIPE_WRITE.load(Ordering::SeqCst) < 0 {
+            return Err(io::Error::other("teardown handlers failed to install"));
+        }
+        let id = NEXT_ID.fetch_add(1, Ordering::SeqCst);
+        registry().push(Entry {
+            id,
+            vm: Arc::new(vm.downgrade()),
+            vm_id: vm.vm_id(),
+            name: vm.display_name(),
+            events: Arc::new(vm.error_events()),
+            policy,
+        });
+        Ok(TeardownGuard { id })
+    }
+}
+
+impl Drop for TeardownGuard {
+    fn drop(&mut self) {
+        registry().retain(|entry| entry.id != self.id);
+    }
+}
+
+fn install() -> io::Result<()> {
+    let mut fds = [0; 2];
+    if unsafe { libc::pipe(fds.as_mut_ptr()) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    let [read_fd, write_fd] = fds;
+    unsafe {
+        libc::fcntl(read_fd, libc::F_SETFD, libc::FD_CLOEXEC);
+        libc::fcntl(write_fd, libc::F_SETFD, libc::FD_CLOEXEC);
+        libc::fcntl(write_fd, libc::F_SETFL, libc::O_NONBLOCK);
+    }
+
+    let mut previous = Vec::with_capacity(SIGNALS.len());
+    for &signal in &SIGNALS {
+        unsafe {
+            let mut action: libc::sigaction = mem::zeroed();
+            action.sa_sigaction = handle_signal as *const () as libc::sighandler_t;
+            libc::sigemptyset(&mut action.sa_mask);
+            action.sa_flags = libc::SA_RESTART;
+            let mut old: libc::sigaction = mem::zeroed();
+            if libc::sigaction(signal, &action, &mut old) == -1 {
+                return Err(io::Error::last_os_error());
+            }
+            previous.push((signal, old));
+        }
+    }
+
+    thread::Builder::new()
+        .name(TEARDOWN_THREAD.to_string())
+        .spawn(move || teardown_thread(read_fd, previous))?;
+
+    let previous_hook = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        teardown_from_panic();
+        previous_hook(info);
+    }));
+
+    PIPE_WRITE.store(write_fd, Ordering::SeqCst);
+    Ok(())
+}
+
+/// Async-signal-safe: only writes to the self-pipe.
+extern "C" fn handle_signal(signal: libc::c_int) {
+    // The pipe is non-blocking, so a full pipe drops the byte instead of hanging the handler;
+    // one pending trigger is enough.
+    let byte = signal as u8;
+    unsafe {
+        libc::write(
+            PIPE_WRITE.load(Ordering::Relaxed),
+            &byte as *const u8 as *const libc::c_void,
+            1,
+        );
+    }
+}
+
+fn teardown_from_panic() {
+    if thread::current().name() == Some(TEARDOWN_THREAD) {
+        return;
+    }
+    let deadline = match prune().iter().map(|e| e.policy.total()).max() {
+        Some(longest) => Instant::now() + longest + POLL_INTERVAL * 10,
+        None => return,
+    };
+    let mut runs = RUNS.lock().unwrap_or_else(|e| e.into_inner());
+    let seen = *runs;
+    let byte = PANIC_TRIGGER;
+    unsafe {
+        libc::write(
+            PIPE_WRITE.load(Ordering::SeqCst),
+            &byte as *const u8 as *const libc::c_void,
+            1,
+        );
+    }
+    while *runs == seen {
+        let now = Instant::now();
+        if now >= deadline {
+            return;
+        }
+        runs = RUNS_CHANGED
+            .wait_timeout(runs, deadline - now)
+            .unwrap_or_else(|e| e.into_inner())
+            .0;
+    }
+}
+
+fn teardown_thread(read_fd: libc::c_int, previous: Vec<(libc::c_int, libc::sigaction)>) {
+    loop {
+        let mut byte = 0u8;
+        let n = unsafe { libc::read(read_fd, &mut byte as *mut u8 as *mut libc::c_void, 1) };
+        if n == -1 && io::Error::last_os_error().kind() == io::ErrorKind::Interrupted {
+            continue;
+        }
+        if n != 1 {
+            return;
+        }
+
+        stop_all();
+        *RUNS.lock().unwrap_or_else(|e| e.into_inner()) += 1;
+        RUNS_CHANGED.notify_all();
+
+        let signal = byte as libc::c_int;
+        if let Some((_, old)) = previous.iter().find(|(s, _)| *s == signal) {
+            unsafe {
+                libc::sigaction(signal, old, std::ptr::null_mut());
+                libc::raise(signal);
+            }
+        }
+    }
+}
+
+/// Stops every registered machine in parallel, reporting those that did not stop.
+fn stop_all() {
+    let workers: Vec<_> = prune()
+        .into_iter()
+        .map(|entry| {
+            thread::spawn(move || {
+                if !entry.vm.teardown(&entry.policy) {
+                    log::warn!(
+                        "vm={:?} did not stop within {}ms",
+                        entry.name,
+                        entry.policy.total().as_millis()
+                    );
+                }
+            })
+        })
+        .collect();
+    for worker in workers {
+        let _ = worker.join();
+    }
+}
+
+/// Stops `vm` as teardown does: asks the guest to stop, force stops the machine if the guest
+/// refused or once `request_stop_timeout` passed, then waits up to `force_stop_timeout` for the stopped state.
+/// Returns whether the machine stopped; one that already was returns right away.
+///
+/// Blocks, so it must not be called on the VM's queue.
+pub fn shutdown<V: VirtualMachineHandle>(vm: &V, policy: &TeardownPolicy) -> bool {
+    if is_stopped(vm, POLL_INTERVAL * 10) {
+        return true;
+    }
+    let requested = policy.request_stop_timeout > Duration::from_secs(0)
+        && on_queue(vm, policy.request_stop_timeout, |vm| unsafe {
+            vm.request_stop_with_error().unwrap_or(false)
+        }) == Some(true);
+    if !requested {
+        vm.stop_or_join(|_| {});
+        return wait_for_stopped(vm, Instant::now() + policy.force_stop_timeout);
+    }
+    // The force stop is queued behind the grace period and withdrawn if the guest makes it.
+    let target = vm.clone();
+    let force_stop = vm
+        .queue()
+        .after(policy.request_stop_timeout, move || target.stop_or_join(|_| {}));
+    let stopped = wait_for_stopped(vm, Instant::now() + policy.total());
+    if stopped {
+        force_stop.cancel();
+    }
+    stopped
+}
+
+fn wait_for_stopped<V: VirtualMachineHandle>(vm: &V, deadline: Instant) -> bool {
+    loop {
+        let now = Instant::now();
+        if now >= deadline {
+            return false;
+        }
+        if is_stopped(vm, deadline - now) {
+            return true;
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+}
+
+fn is_stopped<V: VirtualMachineHandle>(vm: &V, timeout: Duration) -> bool {
+    let state = on_queue(vm, timeout, |vm| unsafe { vm.state() });
+    matches!(
+        state,
+        Some(VZVirtualMachineState::VZVirtualMachineStateStopped)
+            | Some(VZVirtualMachineState::VZVirtualMachineStateError)
+    )
+}
+
+/// Runs `f` on the VM's queue without blocking it, giving up after `timeout` in case the queue is
+/// stuck (e.g. the panicking thread is the one serving it).
+fn on_queue<V, R, F>(vm: &V, timeout: Duration, f: F) -> Option<R>
+where
+    V: VirtualMachineHandle,
+    R: Send + 'static,
+    F: FnOnce(&V) -> R + Send + 'static,
+{
+    let done = Arc::new(DispatchSemaphore::new(0));
+    let result = Arc::new(Mutex::new(None));
+    let (signal, slot) = (done.clone(), result.clone());
+    let target = vm.clone();
+    vm.queue().exec_async(move || {
+        *slot.lock().unwrap_or_else(|e| e.into_inner()) = Some(f(&target));
+        signal.signal();
+    });
+    if !done.wait_timeout(timeout) {
+        return None;
+    }
+    let value = result.lock().unwrap_or_else(|e| e.into_inner()).take();
+    value
+}
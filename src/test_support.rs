@@ -0,0 +1,201 @@
+//! Fixtures for the integration tests under `tests/`, public only so they can reach it; not part of
+//! the crate's API, and built only with the `test-util` feature.
+//!
+//! The tests come in three tiers:
+//!
+//! | test | needs | starts a virtual machine |
+//! |---|---|---|
+//! | `framework_objects` | any Mac | no |
+//! | `validation` | any Mac | no |
+//! | `boot` | the virtualization entitlement and guest images, see [`fixture`] | yes, ignored by default |
+//!
+//! The first two only create framework objects and validate configurations, which works without
+//! the entitlement and on hosts without hardware virtualization, such as CI runners.
+
+use crate::base::{Id, NSError, NIL};
+use crate::runtime::{call_with_error, class_name};
+use crate::virtualization::boot_loader::{
+    VZEFIBootLoader, VZEFIBootLoaderBuilder, VZEFIVariableStore,
+    VZEFIVariableStoreInitializationOption, VZEFIVariableStoreInitializationOptions,
+};
+#[cfg(feature = "linux-guest")]
+use crate::virtualization::boot_loader::{VZLinuxBootLoader, VZLinuxBootLoaderBuilder};
+use crate::virtualization::entropy_device::VZVirtioEntropyDeviceConfiguration;
+use crate::virtualization::platform::VZGenericPlatformConfiguration;
+use crate::virtualization::storage_device::{
+    VZDiskImageStorageDeviceAttachmentBuilder, VZVirtioBlockDeviceConfiguration,
+};
+use crate::virtualization::virtual_machine::{
+    VZVirtualMachineConfiguration, VZVirtualMachineConfigurationBuilder,
+};
+
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// Prefix of the environment variables naming the fixtures of the `boot` tier.
+pub const FIXTURE_ENV_PREFIX: &str = "VIRTUALIZATION_RS_TEST_";
+
+/// A directory under the system temporary directory, removed on drop. The name includes the
+/// process id, so concurrent test binaries do not share it.
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    pub fn new(name: &str) -> TempDir {
+        let path = std::env::temp_dir().join(format!(
+            "virtualization-rs-test-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).unwrap();
+        TempDir(path)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+
+    /// A zero-filled, sparse file of `len` bytes: a blank raw disk image.
+    pub fn disk_image(&self, name: &str, len: u64) -> PathBuf {
+        let path = self.0.join(name);
+        File::create(&path).unwrap().set_len(len).unwrap();
+        path
+    }
+
+    /// A file of garbage: enough for a Linux boot loader to validate, not to boot.
+    pub fn garbage(&self, name: &str) -> PathBuf {
+        let path = self.0.join(name);
+        File::create(&path)
+            .unwrap()
+            .write_all(&[0x5a; 4096])
+            .unwrap();
+        path
+    }
+
+    /// A new EFI variable store, replacing any file of the same name.
+    pub fn efi_variable_store(&self, name: &str) -> VZEFIVariableStore {
+        VZEFIVariableStore::create(
+            path_str(&self.0.join(name)),
+            VZEFIVariableStoreInitializationOptions::new()
+                .with(VZEFIVariableStoreInitializationOption::allow_overwrite()),
+        )
+        .unwrap_or_else(|e| panic!("{}", e))
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+fn path_str(path: &Path) -> String {
+    path.to_str().unwrap().to_string()
+}
+
+/// A Linux boot loader for garbage kernel and initial ramdisk files in `dir`.
+#[cfg(feature = "linux-guest")]
+pub fn garbage_linux_boot_loader(dir: &TempDir) -> VZLinuxBootLoader {
+    VZLinuxBootLoaderBuilder::new()
+        .kernel_url(path_str(&dir.garbage("vmlinuz")))
+        .initial_ramdisk_url(path_str(&dir.garbage("initrd")))
+        .command_line("console=hvc0")
+        .build()
+        .unwrap()
+}
+
+/// An EFI boot loader with a new variable store in `dir`.
+pub fn efi_boot_loader(dir: &TempDir) -> VZEFIBootLoader {
+    VZEFIBootLoaderBuilder::new()
+        .with_variable_store(dir.efi_variable_store("efi-variables"))
+        .build()
+}
+
+/// A configuration builder with one CPU, the smallest memory size the framework allows and an
+/// entropy device, but no boot loader.
+pub fn minimal_builder() -> VZVirtualMachineConfigurationBuilder {
+    VZVirtualMachineConfigurationBuilder::new()
+        .cpu_count(1)
+        .memory_size(VZVirtualMachineConfiguration::minimum_allowed_memory_size())
+        .entropy_devices(vec![VZVirtioEntropyDeviceConfiguration::new()])
+}
+
+/// The smallest Linux configuration that validates, booting garbage files in `dir`.
+#[cfg(feature = "linux-guest")]
+pub fn minimal_linux_config(dir: &TempDir) -> VZVirtualMachineConfiguration {
+    minimal_builder()
+        .boot_loader(garbage_linux_boot_loader(dir))
+        .build()
+}
+
+/// The smallest EFI configuration that validates: the generic platform and a blank 64 MiB disk
+/// image in `dir` on a virtio block device. Needs macOS 13.
+pub fn minimal_efi_config(dir: &TempDir) -> VZVirtualMachineConfiguration {
+    let attachment = VZDiskImageStorageDeviceAttachmentBuilder::new()
+        .path(path_str(&dir.disk_image("disk.img", 64 * 1024 * 1024)))
+        .read_only(false)
+        .build()
+        .unwrap_or_else(|e| panic!("{}", e));
+    minimal_builder()
+        .boot_loader(efi_boot_loader(dir))
+        .platform(VZGenericPlatformConfiguration::new())
+        .storage_devices(vec![VZVirtioBlockDeviceConfiguration::new(attachment)])
+        .build()
+}
+
+/// Reads back a simulated call with an `NSError **` out-parameter as the wrappers read the
+/// framework's: the callee returns `returned` and stores `stored`, unretained as an autoreleased
+/// error would be.
+pub fn bool_call_with_error(returned: bool, stored: Option<&NSError>) -> Result<bool, NSError> {
+    unsafe {
+        call_with_error(|error| {
+            *error = stored.map_or(NIL, |e| *e.0);
+            returned
+        })
+    }
+}
+
+/// As [`bool_call_with_error`], for a callee that returns an object or nil.
+pub fn object_call_with_error(returned: Id, stored: Option<&NSError>) -> Result<Id, NSError> {
+    unsafe {
+        call_with_error(|error| {
+            *error = stored.map_or(NIL, |e| *e.0);
+            returned
+        })
+    }
+}
+
+/// Panics unless `obj` is non-nil; `what` names it in the message.
+pub fn assert_non_nil(obj: Id, what: &str) {
+    assert!(!obj.is_null(), "{} is nil", what);
+}
+
+/// Panics unless `obj` is a non-nil instance of exactly the class `expected`.
+///
+/// # Safety
+/// `obj` must be nil or a valid object.
+pub unsafe fn assert_class(obj: Id, expected: &str) {
+    assert_non_nil(obj, expected);
+    assert_eq!(class_name(obj), expected);
+}
+
+/// The path in `VIRTUALIZATION_RS_TEST_<name>`, or `None` after printing why `test` is skipped
+/// when it is unset or names no file.
+pub fn fixture(test: &str, name: &str) -> Option<PathBuf> {
+    let var = format!("{}{}", FIXTURE_ENV_PREFIX, name);
+    match std::env::var_os(&var).map(PathBuf::from) {
+        Some(path) if path.exists() => Some(path),
+        Some(path) => {
+            println!(
+                "{} skipped: {} is {:?}, which does not exist",
+                test, var, path
+            );
+            None
+        }
+        None => {
+            println!("{} skipped: {} unset", test, var);
+            None
+        }
+    }
+}
@@ -0,0 +1,433 @@
+//! timeline module
+//!
+//! A timestamped record of what happened to a virtual machine, for boot-time analysis: when
+//! operations were requested and completed, when its state changed, when liveness checks saw the
+//! guest's milestones, and marks added by the application. Timestamps come from the monotonic
+//! clock, relative to when recording began.
+//!
+//! The timeline exports to the Chrome trace-event format, which chrome://tracing and Perfetto
+//! open: operations are spans on an "operations" track, each state is a span on a "state" track
+//! until the next one, and milestones and marks are instants on a "milestones" track. The timeline
+//! of a virtual machine is a process named after it, with its [`VmId`] as the process id, so the
+//! traces of several machines can be opened side by side.
+//!
+//! # Examples
+//! ```rust
+//! let timeline = vm.record_timeline();
+//! vm.start(|_| {})?;
+//! Liveness::console_marker(capture.buffer(), "login:").wait_recorded(
+//!     Duration::from_secs(60),
+//!     &token,
+//!     &timeline,
+//! )?;
+//! timeline.mark("cloud-init done");
+//! timeline.write_chrome_trace("boot_trace.json")?;
+//! ```
+
+use crate::metrics;
+use crate::virtualization::error::CompletionOutcome;
+use crate::virtualization::virtual_machine::{VZVirtualMachineState, VmId};
+
+use std::fmt::Write as _;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::ptr;
+use std::sync::atomic::{AtomicPtr, AtomicU64, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant};
+
+/// What happened.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TimelineEventKind {
+    /// An operation was sent to the framework: `start`, `stop`, `pause`, `resume`,
+    /// `save_machine_state_to` or `restore_machine_state_from`.
+    Requested { operation: &'static str },
+    /// The framework called the operation's completion handler; `outcome` is `success`,
+    /// `cancelled` or `failed`.
+    Completed {
+        operation: &'static str,
+        outcome: &'static str,
+    },
+    /// The machine entered `state`.
+    StateChanged { state: VZVirtualMachineState },
+    /// The run ended; `reason` is the label of its
+    /// [`StopReason`](crate::virtualization::lifecycle::StopReason), e.g. `forced`.
+    Stopped { reason: &'static str },
+    /// A liveness check saw the guest alive, named after what it waited for.
+    Milestone { name: String },
+    /// Added by [`TimelineHandle::mark`].
+    Mark { label: String },
+}
+
+/// One event, `at` after recording began.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TimelineEvent {
+    pub at: Duration,
+    pub kind: TimelineEventKind,
+}
+
+struct Node {
+    /// Order of recording, which breaks ties between equal timestamps.
+    seq: u64,
+    event: TimelineEvent,
+    next: *mut Node,
+}
+
+/// An append-only list of events. Appending pushes a node on a lock-free stack, so recording from
+/// the VM's queue never waits for a reader.
+struct Timeline {
+    /// The machine recorded, with its name in traces.
+    vm: Option<(VmId, String)>,
+    origin: Instant,
+    seq: AtomicU64,
+    head: AtomicPtr<Node>,
+}
+
+impl Timeline {
+    fn push(&self, kind: TimelineEventKind) {
+        let seq = self.seq.fetch_add(1, Ordering::Relaxed);
+        let event = TimelineEvent {
+            at: self.origin.elapsed(),
+            kind,
+        };
+        let node = Box::into_raw(Box::new(Node {
+            seq,
+            event,
+            next: ptr::null_mut(),
+        }));
+        let mut head = self.head.load(Ordering::Relaxed);
+        loop {
+            unsafe { (*node).next = head };
+            match self
+                .head
+                .compare_exchange_weak(head, node, Ordering::Release, Ordering::Relaxed)
+            {
+                Ok(_) => return,
+                Err(current) => head = current,
+            }
+        }
+    }
+
+    fn events(&self) -> Vec<TimelineEvent> {
+        let mut nodes = Vec::new();
+        let mut p = self.head.load(Ordering::Acquire);
+        // Nodes are never changed or freed once pushed, until the timeline is dropped.
+        while let Some(node) = unsafe { p.as_ref() } {
+            nodes.push((node.event.at, node.seq, node.event.clone()));
+            p = node.next;
+        }
+        nodes.sort_by_key(|&(at, seq, _)| (at, seq));
+        nodes.into_iter().map(|(_, _, event)| event).collect()
+    }
+}
+
+impl Drop for Timeline {
+    fn drop(&mut self) {
+        let mut p = *self.head.get_mut();
+        while !p.is_null() {
+            let node = unsafe { Box::from_raw(p) };
+            p = node.next;
+        }
+    }
+}
+
+/// A shared handle to a timeline; clones record into the same one.
+#[derive(Clone)]
+pub struct TimelineHandle(Arc<Timeline>);
+
+impl TimelineHandle {
+    /// A timeline not attached to any virtual machine, which only gets marks and milestones.
+    pub fn new() -> TimelineHandle {
+        TimelineHandle::with_vm(None)
+    }
+
+    fn with_vm(vm: Option<(VmId, String)>) -> TimelineHandle {
+        TimelineHandle(Arc::new(Timeline {
+            vm,
+            origin: Instant::now(),
+            seq: AtomicU64::new(0),
+            head: AtomicPtr::new(ptr::null_mut()),
+        }))
+    }
+
+    /// The virtual machine this timeline records; `None` for one made with
+    /// [`TimelineHandle::new`].
+    pub fn vm(&self) -> Option<VmId> {
+        self.0.vm.as_ref().map(|&(id, _)| id)
+    }
+
+    pub(crate) fn record(&self, kind: TimelineEventKind) {
+        self.0.push(kind);
+    }
+
+    /// Adds an application event, e.g. `"cloud-init done"`.
+    pub fn mark<T: Into<String>>(&self, label: T) {
+        self.record(TimelineEventKind::Mark {
+            label: label.into(),
+        });
+    }
+
+    /// The events so far, oldest first.
+    pub fn events(&self) -> Vec<TimelineEvent> {
+        self.0.events()
+    }
+
+    /// The events so far in the Chrome trace-event format, see [`chrome_trace`]. The process is
+    /// the virtual machine, e.g. `vm-3 (web)` with process id 3.
+    pub fn chrome_trace(&self) -> String {
+        match &self.0.vm {
+            Some((id, name)) => trace(&self.events(), id.get(), name),
+            None => chrome_trace(&self.events()),
+        }
+    }
+
+    pub fn write_chrome_trace<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        fs::write(path, self.chrome_trace())
+    }
+}
+
+impl Default for TimelineHandle {
+    fn default() -> Self {
+        TimelineHandle::new()
+    }
+}
+
+/// The timeline of one virtual machine, shared by its clones and created when first asked for.
+pub(crate) struct TimelineSlot {
+    vm: VmId,
+    /// The process name in traces.
+    name: String,
+    timeline: OnceLock<TimelineHandle>,
+}
+
+impl TimelineSlot {
+    pub(crate) fn new(vm: VmId, name: String) -> TimelineSlot {
+        TimelineSlot {
+            vm,
+            name,
+            timeline: OnceLock::new(),
+        }
+    }
+
+    pub(crate) fn attach(&self) -> TimelineHandle {
+        self.timeline
+            .get_or_init(|| TimelineHandle::with_vm(Some((self.vm, self.name.clone()))))
+            .clone()
+    }
+
+    /// Records `kind` if a timeline is attached.
+    pub(crate) fn record(&self, kind: TimelineEventKind) {
+        if let Some(timeline) = self.timeline.get() {
+            timeline.record(kind);
+        }
+    }
+
+    pub(crate) fn record_completed(&self, operation: &'static str, outcome: &CompletionOutcome) {
+        self.record(TimelineEventKind::Completed {
+            operation,
+            outcome: metrics::outcome_label(outcome),
+        });
+    }
+}
+
+/// Process id of the traces of events without a virtual machine.
+const PID: u64 = 1;
+const OPERATIONS_TID: u32 = 1;
+const STATE_TID: u32 = 2;
+const MILESTONES_TID: u32 = 3;
+
+pub(crate) fn json_string(out: &mut String, s: &str) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+/// One trace event, a JSON object.
+struct TraceEvent<'a> {
+    name: &'a str,
+    cat: &'static str,
+    ph: &'static str,
+    ts: Duration,
+    dur: Option<Duration>,
+    pid: u64,
+    tid: u32,
+    arg: Option<(&'static str, &'a str)>,
+}
+
+impl TraceEvent<'_> {
+    fn instant<'a>(
+        name: &'a str,
+        cat: &'static str,
+        at: Duration,
+        pid: u64,
+        tid: u32,
+    ) -> TraceEvent<'a> {
+        TraceEvent {
+            name,
+            cat,
+            ph: "i",
+            ts: at,
+            dur: None,
+            pid,
+            tid,
+            arg: None,
+        }
+    }
+
+    fn to_json(&self) -> String {
+        let mut out = String::from("{\"name\":");
+        json_string(&mut out, self.name);
+        out.push_str(",\"cat\":");
+        json_string(&mut out, self.cat);
+        let _ = write!(
+            out,
+            ",\"ph\":\"{}\",\"ts\":{}",
+            self.ph,
+            self.ts.as_micros()
+        );
+        if let Some(dur) = self.dur {
+            let _ = write!(out, ",\"dur\":{}", dur.as_micros());
+        }
+        if self.ph == "i" {
+            out.push_str(",\"s\":\"t\"");
+        }
+        let _ = write!(out, ",\"pid\":{},\"tid\":{}", self.pid, self.tid);
+        if let Some((key, value)) = self.arg {
+            out.push_str(",\"args\":{");
+            json_string(&mut out, key);
+            out.push(':');
+            json_string(&mut out, value);
+            out.push('}');
+        }
+        out.push('}');
+        out
+    }
+}
+
+/// `events`, oldest first, in the Chrome trace-event JSON format, one event per line.
+///
+/// Timestamps are whole microseconds. A completed operation is a complete (`X`) event from its
+/// request, with its outcome as an argument; an operation without both ends in `events` is an
+/// instant (`i`) event with outcome `pending` or, for a completion alone, its outcome. Each state
+/// lasts until the next state change, or the last event; the end of a run is an instant on the
+/// state track with its reason as an argument. Track names are metadata (`M`) events.
+///
+/// The process is named `virtual machine`, with process id 1; [`TimelineHandle::chrome_trace`]
+/// names it after the machine recorded.
+pub fn chrome_trace(events: &[TimelineEvent]) -> String {
+    trace(events, PID, "virtual machine")
+}
+
+/// `events` as the trace of process `pid`, named `process`.
+fn trace(events: &[TimelineEvent], pid: u64, process: &str) -> String {
+    let end = events.last().map_or(Duration::ZERO, |event| event.at);
+    // When and how each request completed, matched in order per operation.
+    let mut completion: Vec<Option<(Duration, &'static str)>> = vec![None; events.len()];
+    let mut matched = vec![false; events.len()];
+    for (i, event) in events.iter().enumerate() {
+        if let TimelineEventKind::Completed { operation, outcome } = event.kind {
+            let request = (0..i).find(|&j| {
+                completion[j].is_none()
+                    && events[j].kind == TimelineEventKind::Requested { operation }
+            });
+            if let Some(j) = request {
+                completion[j] = Some((event.at, outcome));
+                matched[i] = true;
+            }
+        }
+    }
+
+    let tracks = [
+        (0, "process_name", process),
+        (OPERATIONS_TID, "thread_name", "operations"),
+        (STATE_TID, "thread_name", "state"),
+        (MILESTONES_TID, "thread_name", "milestones"),
+    ];
+    let mut lines: Vec<String> = tracks
+        .iter()
+        .map(|&(tid, name, value)| {
+            TraceEvent {
+                name,
+                cat: "__metadata",
+                ph: "M",
+                ts: Duration::ZERO,
+                dur: None,
+                pid,
+                tid,
+                arg: Some(("name", value)),
+            }
+            .to_json()
+        })
+        .collect();
+    for (i, event) in events.iter().enumerate() {
+        let state;
+        let trace = match &event.kind {
+            TimelineEventKind::Requested { operation } => match completion[i] {
+                Some((done, outcome)) => TraceEvent {
+                    name: operation,
+                    cat: "operation",
+                    ph: "X",
+                    ts: event.at,
+                    dur: Some(done - event.at),
+                    pid,
+                    tid: OPERATIONS_TID,
+                    arg: Some(("outcome", outcome)),
+                },
+                None => TraceEvent {
+                    arg: Some(("outcome", "pending")),
+                    ..TraceEvent::instant(operation, "operation", event.at, pid, OPERATIONS_TID)
+                },
+            },
+            TimelineEventKind::Completed { .. } if matched[i] => continue,
+            TimelineEventKind::Completed { operation, outcome } => TraceEvent {
+                arg: Some(("outcome", outcome)),
+                ..TraceEvent::instant(operation, "operation", event.at, pid, OPERATIONS_TID)
+            },
+            TimelineEventKind::StateChanged { state: entered } => {
+                let until = events[i + 1..]
+                    .iter()
+                    .find(|e| matches!(e.kind, TimelineEventKind::StateChanged { .. }))
+                    .map_or(end, |e| e.at);
+                state = entered.to_string();
+                TraceEvent {
+                    name: &state,
+                    cat: "state",
+                    ph: "X",
+                    ts: event.at,
+                    dur: Some(until - event.at),
+                    pid,
+                    tid: STATE_TID,
+                    arg: None,
+                }
+            }
+            TimelineEventKind::Stopped { reason } => TraceEvent {
+                arg: Some(("reason", reason)),
+                ..TraceEvent::instant("stopped", "stop", event.at, pid, STATE_TID)
+            },
+            TimelineEventKind::Milestone { name } => {
+                TraceEvent::instant(name, "milestone", event.at, pid, MILESTONES_TID)
+            }
+            TimelineEventKind::Mark { label } => {
+                TraceEvent::instant(label, "mark", event.at, pid, MILESTONES_TID)
+            }
+        };
+        lines.push(trace.to_json());
+    }
+    format!(
+        "{{\"traceEvents\":[\n{}\n],\"displayTimeUnit\":\"ms\"}}\n",
+        lines.join(",\n")
+    )
+}
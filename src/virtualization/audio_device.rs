@@ -0,0 +1,129 @@
+//! audio device module
+//!
+//! A Virtio sound device with host-backed streams, macOS 12 and later. An input stream records
+//! from the host's default microphone, which needs the `com.apple.security.device.audio-input`
+//! entitlement in sandboxed apps and the user's permission; output alone needs neither.
+//!
+//! # Examples
+//! ```rust
+//! let mut sound = VZVirtioSoundDeviceConfiguration::try_new()?;
+//! sound.set_streams(vec![VZVirtioSoundDeviceOutputStreamConfiguration::host_output()?]);
+//! let conf = VZVirtualMachineConfigurationBuilder::new()
+//!     .audio_devices(vec![sound])
+//!     // ...
+//!     .build();
+//! ```
+
+use crate::base::{Id, NSArray};
+use crate::features::{ClassLookup, ClassNotAvailable};
+use crate::runtime::owned;
+use crate::virtualization::device::VZDeviceConfiguration;
+
+use std::any::Any;
+
+use objc::rc::StrongPtr;
+use objc::{msg_send, sel, sel_impl};
+
+static VIRTIO_SOUND_DEVICE: ClassLookup =
+    ClassLookup::new("VZVirtioSoundDeviceConfiguration", "macOS 12");
+static INPUT_STREAM: ClassLookup =
+    ClassLookup::new("VZVirtioSoundDeviceInputStreamConfiguration", "macOS 12");
+static OUTPUT_STREAM: ClassLookup =
+    ClassLookup::new("VZVirtioSoundDeviceOutputStreamConfiguration", "macOS 12");
+static HOST_INPUT_SOURCE: ClassLookup =
+    ClassLookup::new("VZHostAudioInputStreamSource", "macOS 12");
+static HOST_OUTPUT_SINK: ClassLookup = ClassLookup::new("VZHostAudioOutputStreamSink", "macOS 12");
+
+/// common configure of audio devices
+pub trait VZAudioDeviceConfiguration: VZDeviceConfiguration {}
+
+impl<T: VZAudioDeviceConfiguration + ?Sized> VZAudioDeviceConfiguration for Box<T> {}
+
+/// A stream of a [`VZVirtioSoundDeviceConfiguration`].
+pub trait VZVirtioSoundDeviceStreamConfiguration {
+    fn id(&self) -> Id;
+}
+
+impl<T: VZVirtioSoundDeviceStreamConfiguration + ?Sized> VZVirtioSoundDeviceStreamConfiguration
+    for Box<T>
+{
+    fn id(&self) -> Id {
+        (**self).id()
+    }
+}
+
+/// A stream the guest records from.
+pub struct VZVirtioSoundDeviceInputStreamConfiguration(StrongPtr);
+
+impl VZVirtioSoundDeviceInputStreamConfiguration {
+    /// A stream fed by the host's default input device.
+    pub fn host_input() -> Result<Self, ClassNotAvailable> {
+        let class = INPUT_STREAM.get()?;
+        let source = HOST_INPUT_SOURCE.get()?;
+        unsafe {
+            let p = owned(msg_send![class, new]);
+            let source = owned(msg_send![source, new]);
+            let _: () = msg_send![*p, setSource: *source];
+            Ok(Self(p))
+        }
+    }
+}
+
+impl VZVirtioSoundDeviceStreamConfiguration for VZVirtioSoundDeviceInputStreamConfiguration {
+    fn id(&self) -> Id {
+        *self.0
+    }
+}
+
+/// A stream the guest plays to.
+pub struct VZVirtioSoundDeviceOutputStreamConfiguration(StrongPtr);
+
+impl VZVirtioSoundDeviceOutputStreamConfiguration {
+    /// A stream played on the host's default output device.
+    pub fn host_output() -> Result<Self, ClassNotAvailable> {
+        let class = OUTPUT_STREAM.get()?;
+        let sink = HOST_OUTPUT_SINK.get()?;
+        unsafe {
+            let p = owned(msg_send![class, new]);
+            let sink = owned(msg_send![sink, new]);
+            let _: () = msg_send![*p, setSink: *sink];
+            Ok(Self(p))
+        }
+    }
+}
+
+impl VZVirtioSoundDeviceStreamConfiguration for VZVirtioSoundDeviceOutputStreamConfiguration {
+    fn id(&self) -> Id {
+        *self.0
+    }
+}
+
+/// A Virtio sound device, without streams until [`set_streams`](Self::set_streams).
+pub struct VZVirtioSoundDeviceConfiguration(StrongPtr);
+
+impl VZVirtioSoundDeviceConfiguration {
+    pub fn try_new() -> Result<Self, ClassNotAvailable> {
+        let class = VIRTIO_SOUND_DEVICE.get()?;
+        Ok(Self(unsafe { owned(msg_send![class, new]) }))
+    }
+
+    pub fn set_streams<T: VZVirtioSoundDeviceStreamConfiguration>(&mut self, streams: Vec<T>) {
+        let streams: Vec<Id> = streams.iter().map(|s| s.id()).collect();
+        let arr: NSArray<T> = NSArray::from_slice(&streams);
+        unsafe {
+            let _: () = msg_send![*self.0, setStreams: *arr.p];
+        }
+    }
+}
+
+impl VZDeviceConfiguration for VZVirtioSoundDeviceConfiguration {
+    fn id(&self) -> Id {
+        *self.0
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+impl VZAudioDeviceConfiguration for VZVirtioSoundDeviceConfiguration {}
@@ -1,14 +1,36 @@
 //! boot loader module
-use crate::base::{Id, NSError, NSString, NSUInteger, NSURL};
+#[cfg(feature = "linux-guest")]
+use crate::base::NSString;
+use crate::base::{Id, InvalidInput, NSError, NSInteger, NSUInteger, NSURL, NIL};
+use crate::disk_image;
+#[cfg(feature = "macos-guest")]
+use crate::features::{self, UnsupportedOnThisHost};
+use crate::features::{ClassLookup, ClassNotAvailable};
+#[cfg(any(feature = "linux-guest", feature = "macos-guest"))]
+use crate::runtime::vz_class;
+use crate::runtime::{alloc, call_with_error, from_objc_bool, owned, retained};
+use crate::virtualization::error::{ResultExt, VZErrorCtx};
+#[cfg(feature = "linux-guest")]
+use crate::virtualization::kernel_inspect::{self, KernelCheck, KernelCheckError};
+
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
 
 use objc::rc::StrongPtr;
-use objc::{class, msg_send, sel, sel_impl};
+use objc::runtime::BOOL;
+use objc::{msg_send, sel, sel_impl};
+
+static EFI_BOOT_LOADER: ClassLookup = ClassLookup::new("VZEFIBootLoader", "macOS 13");
+static EFI_VARIABLE_STORE: ClassLookup = ClassLookup::new("VZEFIVariableStore", "macOS 13");
 
 /// common behaviors for booting
 pub trait VZBootLoader {
     fn id(&self) -> Id;
 }
 
+#[cfg(feature = "linux-guest")]
 /// builder for VZLinuxBootLoader
 /// # Examples
 /// ```rust
@@ -16,24 +38,35 @@ pub trait VZBootLoader {
 ///     .kernel_url(kernel_url)
 ///     .initial_ramdisk_url(initial_ramdisk_url)
 ///     .command_line(command_line)
-///     .build();
+///     .build()?;
 /// ```
 pub struct VZLinuxBootLoaderBuilder<KernelURL, InitialRamdiskURL, CommandLine> {
     kernel_url: KernelURL,
     initial_ramdisk_url: InitialRamdiskURL,
     command_line: CommandLine,
+    skip_kernel_check: bool,
 }
 
+#[cfg(feature = "linux-guest")]
 impl VZLinuxBootLoaderBuilder<(), (), ()> {
     pub fn new() -> Self {
         VZLinuxBootLoaderBuilder {
             kernel_url: (),
             initial_ramdisk_url: (),
             command_line: (),
+            skip_kernel_check: false,
         }
     }
 }
 
+#[cfg(feature = "linux-guest")]
+impl Default for VZLinuxBootLoaderBuilder<(), (), ()> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(feature = "linux-guest")]
 impl<KernelURL, InitialRamdiskURL, CommandLine>
     VZLinuxBootLoaderBuilder<KernelURL, InitialRamdiskURL, CommandLine>
 {
@@ -45,6 +78,7 @@ impl<KernelURL, InitialRamdiskURL, CommandLine>
             kernel_url: kernel_url.into(),
             initial_ramdisk_url: self.initial_ramdisk_url,
             command_line: self.command_line,
+            skip_kernel_check: self.skip_kernel_check,
         }
     }
 
@@ -56,6 +90,7 @@ impl<KernelURL, InitialRamdiskURL, CommandLine>
             kernel_url: self.kernel_url,
             initial_ramdisk_url: initial_ramdisk_url.into(),
             command_line: self.command_line,
+            skip_kernel_check: self.skip_kernel_check,
         }
     }
 
@@ -67,12 +102,23 @@ impl<KernelURL, InitialRamdiskURL, CommandLine>
             kernel_url: self.kernel_url,
             initial_ramdisk_url: self.initial_ramdisk_url,
             command_line: command_line.into(),
+            skip_kernel_check: self.skip_kernel_check,
         }
     }
+
+    /// Makes [`VZLinuxBootLoaderBuilder::build_checked`] skip inspecting the images, e.g. for a
+    /// kernel format the check does not know yet.
+    pub fn skip_kernel_check(mut self, skip: bool) -> Self {
+        self.skip_kernel_check = skip;
+        self
+    }
 }
 
+#[cfg(feature = "linux-guest")]
 impl VZLinuxBootLoaderBuilder<String, String, String> {
-    pub fn build(self) -> VZLinuxBootLoader {
+    /// Fails, naming the path, if the kernel or initial ramdisk path cannot be made into a file
+    /// URL, e.g. because it is empty.
+    pub fn build(self) -> Result<VZLinuxBootLoader, InvalidInput> {
         unsafe {
             VZLinuxBootLoader::new(
                 self.kernel_url.as_str(),
@@ -81,29 +127,54 @@ impl VZLinuxBootLoaderBuilder<String, String, String> {
             )
         }
     }
+
+    /// Like [`VZLinuxBootLoaderBuilder::build`], but first checks that the kernel and initial
+    /// ramdisk can boot on this host. Warnings are returned along with the boot loader.
+    pub fn build_checked(self) -> Result<(VZLinuxBootLoader, KernelCheck), KernelCheckError> {
+        let check = if self.skip_kernel_check {
+            KernelCheck {
+                kernel: kernel_inspect::KernelFormat::Unknown,
+                initrd: None,
+                warnings: vec!["kernel check skipped".to_string()],
+                errors: Vec::new(),
+            }
+        } else {
+            kernel_inspect::check(&self.kernel_url, Some(&self.initial_ramdisk_url))?
+        };
+        if !check.is_ok() {
+            return Err(KernelCheckError::Rejected(check));
+        }
+        Ok((self.build()?, check))
+    }
 }
 
+#[cfg(feature = "linux-guest")]
 ///  bootLoader for Linux kernel
 pub struct VZLinuxBootLoader(StrongPtr);
 
+#[cfg(feature = "linux-guest")]
 impl VZLinuxBootLoader {
     unsafe fn new(
         kernel_url: &str,
         initial_ramdisk_url: &str,
         command_line: &str,
-    ) -> VZLinuxBootLoader {
-        let kernel_url_nsurl = NSURL::file_url_with_path(kernel_url, false).absolute_url();
-        let initial_ramdisk_url_nsurl =
-            NSURL::file_url_with_path(initial_ramdisk_url, false).absolute_url();
+    ) -> Result<VZLinuxBootLoader, InvalidInput> {
+        let kernel_url_nsurl = NSURL::file_url_with_path(kernel_url, false)
+            .ok_or_else(|| InvalidInput::new("kernel path", kernel_url))?
+            .absolute_url();
+        let initial_ramdisk_url_nsurl = NSURL::file_url_with_path(initial_ramdisk_url, false)
+            .ok_or_else(|| InvalidInput::new("initial ramdisk path", initial_ramdisk_url))?
+            .absolute_url();
         let command_line_nsstring = NSString::new(command_line);
-        let p = StrongPtr::new(msg_send![class!(VZLinuxBootLoader), new]);
-        let _: Id = msg_send![*p, setKernelURL: *kernel_url_nsurl.0];
-        let _: Id = msg_send![*p, setInitialRamdiskURL: *initial_ramdisk_url_nsurl.0];
-        let _: Id = msg_send![*p, setCommandLine: *command_line_nsstring.0];
-        VZLinuxBootLoader(p)
+        let p = owned(msg_send![vz_class!(VZLinuxBootLoader), new]);
+        let _: () = msg_send![*p, setKernelURL: *kernel_url_nsurl.0];
+        let _: () = msg_send![*p, setInitialRamdiskURL: *initial_ramdisk_url_nsurl.0];
+        let _: () = msg_send![*p, setCommandLine: *command_line_nsstring.0];
+        Ok(VZLinuxBootLoader(p))
     }
 }
 
+#[cfg(feature = "linux-guest")]
 impl VZBootLoader for VZLinuxBootLoader {
     fn id(&self) -> Id {
         *self.0
@@ -152,7 +223,7 @@ pub struct VZEFIVariableStore(StrongPtr);
 
 impl VZEFIVariableStore {
     /// Creates a new EFI variable store at specified the URL on the filesystem, initialization
-    /// options, and error-return variable.
+    /// options, and error-return variable. Fails with `ENOTSUP` before macOS 13.
     ///
     /// ```
     /// # use virtualization_rs::virtualization::boot_loader::*;
@@ -162,44 +233,185 @@ impl VZEFIVariableStore {
     ///         .with(VZEFIVariableStoreInitializationOption::allow_overwrite()),
     /// ) {
     ///     Ok(v) => v,
-    ///     Err(e) => {
-    ///         e.dump();
-    ///         panic!("Failed to create an EFI variable store")
-    ///     }
+    ///     // failed to create EFI variable store '/path/to/efi/variables': NSCocoaErrorDomain ...
+    ///     Err(e) => panic!("{}", e),
     /// };
     /// ```
     pub fn create<T: Into<String>>(
         file_url: T,
         options: VZEFIVariableStoreInitializationOptions,
-    ) -> Result<Self, NSError> {
-        let file_url = NSURL::url_with_string(file_url.into().as_str());
+    ) -> Result<Self, VZErrorCtx> {
+        let path = file_url.into();
+        let resource = format!("'{}'", path);
+        let file_url = NSURL::file_url_with_path(path.as_str(), false)
+            .ok_or_else(|| NSError::posix(libc::EINVAL))
+            .ctx("create EFI variable store", resource.as_str())?;
+        let class = EFI_VARIABLE_STORE
+            .get()
+            .map_err(|_| NSError::posix(libc::ENOTSUP))
+            .ctx("create EFI variable store", resource.as_str())?;
         let options = options.into_raw();
-        let error = NSError::nil();
-        let i: Id = unsafe { msg_send![class!(VZEFIVariableStore), alloc] };
-        let p = unsafe {
-            StrongPtr::new(msg_send![
-                i,
-                initCreatingVariableStoreAtURL: file_url
-                options: options
-                error: &(*error.0)
-            ])
+        let store = unsafe {
+            call_with_error(|error| {
+                let i = alloc(class);
+                owned(msg_send![
+                    i,
+                    initCreatingVariableStoreAtURL: *file_url.0
+                    options: options
+                    error: error
+                ])
+            })
         };
+        store.map(Self).ctx("create EFI variable store", resource)
+    }
 
-        if error.code() != 0 {
-            Err(error)
-        } else {
-            Ok(Self(p))
+    /// Initialize the variable store from the URL of an existing file. Fails, naming the path, if
+    /// it cannot be made into a file URL, e.g. because it is empty.
+    ///
+    /// # Panics
+    /// Before macOS 13; [`create`](Self::create) fails with `ENOTSUP` instead.
+    #[track_caller]
+    pub fn open<T: Into<String>>(file_url: T) -> Result<Self, InvalidInput> {
+        let path = file_url.into();
+        let file_url = NSURL::file_url_with_path(path.as_str(), false)
+            .ok_or_else(|| InvalidInput::new("EFI variable store path", &path))?;
+        let i = unsafe { alloc(EFI_VARIABLE_STORE.expect()) };
+        Ok(Self(unsafe {
+            owned(msg_send![i, initWithURL: *file_url.0])
+        }))
+    }
+
+    /// Copies `src_path` to `dest_path` and opens the copy, e.g. to give a clone the store of a
+    /// golden image.
+    ///
+    /// Fails if `dest_path` belongs to a live virtual machine. I/O failures are reported as
+    /// `NSPOSIXErrorDomain` errors.
+    pub fn import_from<S: AsRef<Path>, D: AsRef<Path>>(
+        src_path: S,
+        dest_path: D,
+    ) -> Result<Self, VZErrorCtx> {
+        let (src_path, dest_path) = (src_path.as_ref(), dest_path.as_ref());
+        Self::import(src_path, dest_path).ctx(
+            "import EFI variable store",
+            format!("'{}' to '{}'", src_path.display(), dest_path.display()),
+        )
+    }
+
+    fn import(src_path: &Path, dest_path: &Path) -> Result<Self, NSError> {
+        let stores = stores_in_use();
+        if stores.contains(&absolute(dest_path)) {
+            return Err(posix_error(&store_in_use_error()));
+        }
+        copy_synced(src_path, dest_path).map_err(|e| posix_error(&e))?;
+        drop(stores);
+        dest_path
+            .to_str()
+            .and_then(|dest_path| Self::open(dest_path).ok())
+            .ok_or_else(|| posix_error(&io::Error::from_raw_os_error(libc::EINVAL)))
+    }
+
+    /// Copies the backing file to `dest_path` and flushes it to disk.
+    ///
+    /// Refuses with `ErrorKind::Other` while a virtual machine using this store is alive, since
+    /// the framework may be writing to it. The check and the copy happen under the registry lock,
+    /// so a virtual machine created concurrently cannot pick up the store mid-copy.
+    pub fn export_to<P: AsRef<Path>>(&self, dest_path: P) -> io::Result<()> {
+        let path = self.path();
+        let stores = stores_in_use();
+        if stores.contains(&path) {
+            return Err(store_in_use_error());
+        }
+        copy_synced(&path, dest_path.as_ref())?;
+        drop(stores);
+        Ok(())
+    }
+
+    /// Size of the backing file.
+    pub fn size_bytes(&self) -> io::Result<u64> {
+        fs::metadata(self.path()).map(|m| m.len())
+    }
+
+    /// Path of the backing file.
+    pub fn path(&self) -> PathBuf {
+        unsafe { store_path(*self.0) }
+    }
+
+    pub fn id(&self) -> Id {
+        *self.0
+    }
+}
+
+/// Backing files of EFI variable stores attached to live virtual machines, once per machine.
+static STORES_IN_USE: Mutex<Vec<PathBuf>> = Mutex::new(Vec::new());
+
+fn stores_in_use() -> MutexGuard<'static, Vec<PathBuf>> {
+    STORES_IN_USE.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Marks the EFI variable store of a virtual machine as in use until dropped.
+///
+/// Held by [`VZVirtualMachine`](crate::virtualization::virtual_machine::VZVirtualMachine) and
+/// shared between its clones, so the store is released when the last clone is dropped.
+pub(crate) struct VariableStoreLease(PathBuf);
+
+impl VariableStoreLease {
+    /// Registers the store of `boot_loader` if it is an EFI boot loader with a store.
+    ///
+    /// # Safety
+    /// `boot_loader` must be nil or a valid `VZBootLoader`.
+    pub(crate) unsafe fn for_boot_loader(boot_loader: Id) -> Option<Arc<VariableStoreLease>> {
+        let efi_class = EFI_BOOT_LOADER.get().ok()?;
+        if boot_loader == NIL {
+            return None;
         }
+        let is_efi: BOOL = msg_send![boot_loader, isKindOfClass: efi_class];
+        if !from_objc_bool(is_efi) {
+            return None;
+        }
+        let store: Id = msg_send![boot_loader, variableStore];
+        if store == NIL {
+            return None;
+        }
+        let path = store_path(store);
+        stores_in_use().push(path.clone());
+        Some(Arc::new(VariableStoreLease(path)))
     }
+}
 
-    /// Initialize the variable store from the URL of an existing file.
-    pub fn open<T: Into<String>>(file_url: T) -> Self {
-        let file_url = NSURL::url_with_string(file_url.into().as_str());
-        let i: Id = unsafe { msg_send![class!(VZEFIVariableStore), alloc] };
-        Self(unsafe { StrongPtr::new(msg_send![i, initWithURL: file_url]) })
+impl Drop for VariableStoreLease {
+    fn drop(&mut self) {
+        let mut stores = stores_in_use();
+        if let Some(i) = stores.iter().position(|p| *p == self.0) {
+            stores.swap_remove(i);
+        }
     }
 }
 
+unsafe fn store_path(store: Id) -> PathBuf {
+    let url = NSURL(retained(msg_send![store, URL]));
+    absolute(Path::new(url.path().as_str()))
+}
+
+fn absolute(path: &Path) -> PathBuf {
+    fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf())
+}
+
+fn copy_synced(src: &Path, dest: &Path) -> io::Result<()> {
+    fs::copy(src, dest)?;
+    File::open(dest)?.sync_all()
+}
+
+fn store_in_use_error() -> io::Error {
+    io::Error::other(
+        "EFI variable store is attached to a virtual machine that has not been released",
+    )
+}
+
+fn posix_error(e: &io::Error) -> NSError {
+    let code = e.raw_os_error().unwrap_or(libc::EIO);
+    NSError::error_with_domain("NSPOSIXErrorDomain", code as NSInteger, None)
+}
+
 /// Type-safe builder for [`VZEFIBootLoader`].
 #[derive(Default)]
 pub struct VZEFIBootLoaderBuilder {
@@ -216,9 +428,40 @@ impl VZEFIBootLoaderBuilder {
         self
     }
 
+    /// # Panics
+    /// Before macOS 13, which has no EFI boot loader; see [`try_build`](Self::try_build).
+    #[track_caller]
     pub fn build(self) -> VZEFIBootLoader {
+        self.try_build().unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    /// [`build`](Self::build), failing instead of panicking before macOS 13.
+    pub fn try_build(self) -> Result<VZEFIBootLoader, ClassNotAvailable> {
         unsafe { VZEFIBootLoader::new(self.variable_store) }
     }
+
+    /// Like [`VZEFIBootLoaderBuilder::build`], but first inspects the raw disk images the virtual
+    /// machine will boot from, and returns warnings if the firmware is unlikely to find anything
+    /// to boot on them, e.g. a single blank disk. See [`disk_image::efi_boot_warnings`].
+    pub fn build_checked<P: AsRef<Path>>(
+        self,
+        disks: &[P],
+    ) -> io::Result<(VZEFIBootLoader, Vec<String>)> {
+        let inspections = disks
+            .iter()
+            .map(disk_image::inspect)
+            .collect::<io::Result<Vec<_>>>()?;
+        let paths: Vec<_> = disks
+            .iter()
+            .map(|path| path.as_ref().to_string_lossy())
+            .collect();
+        let disks: Vec<_> = paths
+            .iter()
+            .map(|path| path.as_ref())
+            .zip(&inspections)
+            .collect();
+        Ok((self.build(), disk_image::efi_boot_warnings(&disks)))
+    }
 }
 
 /// The boot loader configuration the system uses to boot guest-operating systems that expect an
@@ -226,12 +469,12 @@ impl VZEFIBootLoaderBuilder {
 pub struct VZEFIBootLoader(StrongPtr);
 
 impl VZEFIBootLoader {
-    unsafe fn new(variable_store: Option<VZEFIVariableStore>) -> Self {
-        let p = StrongPtr::new(msg_send![class!(VZEFIBootLoader), new]);
+    unsafe fn new(variable_store: Option<VZEFIVariableStore>) -> Result<Self, ClassNotAvailable> {
+        let p = owned(msg_send![EFI_BOOT_LOADER.get()?, new]);
         if let Some(v) = variable_store {
-            let _: Id = msg_send![*p, setVariableStore: *v.0];
+            let _: () = msg_send![*p, setVariableStore: *v.0];
         }
-        Self(p)
+        Ok(Self(p))
     }
 }
 
@@ -240,3 +483,59 @@ impl VZBootLoader for VZEFIBootLoader {
         *self.0
     }
 }
+
+/// The boot loader of macOS guests, on Apple silicon.
+#[cfg(feature = "macos-guest")]
+pub struct VZMacOSBootLoader(StrongPtr);
+
+#[cfg(feature = "macos-guest")]
+impl VZMacOSBootLoader {
+    pub fn new() -> Result<Self, UnsupportedOnThisHost> {
+        features::require("VZMacOSBootLoader")?;
+        Ok(Self(unsafe {
+            owned(msg_send![vz_class!(VZMacOSBootLoader), new])
+        }))
+    }
+}
+
+#[cfg(feature = "macos-guest")]
+impl VZBootLoader for VZMacOSBootLoader {
+    fn id(&self) -> Id {
+        *self.0
+    }
+}
+
+/// Any of the boot loaders, for code that picks one at run time, e.g. a
+/// [`GuestProfile`](crate::profile::GuestProfile).
+pub enum BootLoader {
+    #[cfg(feature = "linux-guest")]
+    Linux(VZLinuxBootLoader),
+    Efi(VZEFIBootLoader),
+    #[cfg(feature = "macos-guest")]
+    MacOS(VZMacOSBootLoader),
+}
+
+impl BootLoader {
+    /// The framework class, e.g. `VZEFIBootLoader`.
+    pub fn class_name(&self) -> &'static str {
+        match self {
+            #[cfg(feature = "linux-guest")]
+            BootLoader::Linux(_) => "VZLinuxBootLoader",
+            BootLoader::Efi(_) => "VZEFIBootLoader",
+            #[cfg(feature = "macos-guest")]
+            BootLoader::MacOS(_) => "VZMacOSBootLoader",
+        }
+    }
+}
+
+impl VZBootLoader for BootLoader {
+    fn id(&self) -> Id {
+        match self {
+            #[cfg(feature = "linux-guest")]
+            BootLoader::Linux(boot_loader) => boot_loader.id(),
+            BootLoader::Efi(boot_loader) => boot_loader.id(),
+            #[cfg(feature = "macos-guest")]
+            BootLoader::MacOS(boot_loader) => boot_loader.id(),
+        }
+    }
+}
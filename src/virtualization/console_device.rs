@@ -0,0 +1,286 @@
+//! console device module
+//!
+//! The Virtio console devices of a running virtual machine, whose ports can be attached and
+//! detached while the guest runs. A port only needs a host end while someone is using it, e.g.
+//! for a debug shell opened on demand instead of a pipe held from boot.
+//!
+//! Requires macOS 13; before that, [`VZVirtualMachine::console_devices`] is empty.
+//!
+//! # Examples
+//! ```rust
+//! let mut console = attach_console_on_demand(&vm, "debug")?;
+//! console.write_all(b"uname -a\n")?;
+//! // ... read the reply; dropping `console` detaches the port again.
+//! ```
+
+use crate::base::{DispatchQueue, Id, NSError, NSFileHandle, NSInteger, NSString, NSUInteger};
+use crate::resource::{close_file, CloseError};
+use crate::runtime::{from_objc_bool, retained};
+use crate::virtualization::error::{VZError, VZ_ERROR_DOMAIN};
+use crate::virtualization::serial_port::{
+    VZFileHandleSerialPortAttachment, VZSerialPortAttachment,
+};
+use crate::virtualization::virtual_machine::VZVirtualMachine;
+
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::mem::MaybeUninit;
+use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd, RawFd};
+use std::ptr;
+
+use objc::rc::StrongPtr;
+use objc::runtime::BOOL;
+use objc::{msg_send, sel, sel_impl};
+
+/// `VZErrorNotSupported`.
+const NOT_SUPPORTED: NSInteger = 10;
+/// `VZErrorDeviceNotFound`.
+const DEVICE_NOT_FOUND: NSInteger = 30004;
+
+/// A Virtio console device of a running virtual machine.
+pub struct VZVirtioConsoleDevice {
+    p: StrongPtr,
+    queue: DispatchQueue,
+}
+
+impl VZVirtioConsoleDevice {
+    pub(crate) fn from_raw(p: StrongPtr, queue: DispatchQueue) -> VZVirtioConsoleDevice {
+        VZVirtioConsoleDevice { p, queue }
+    }
+
+    /// The configured ports of the device, in port order.
+    ///
+    /// The ports are read on the VM's queue, so this panics if called from that queue.
+    pub fn ports(&self) -> Vec<VZVirtioConsolePort> {
+        self.queue
+            .assert_not_current("VZVirtioConsoleDevice::ports");
+        let p = *self.p;
+        let queue = self.queue.clone();
+        self.queue.exec_sync(move || unsafe {
+            let ports: Id = msg_send![p, ports];
+            let count: u32 = msg_send![ports, maximumPortCount];
+            (0..count as NSUInteger)
+                .filter_map(|i| {
+                    let port: Id = msg_send![ports, objectAtIndexedSubscript: i];
+                    if port.is_null() {
+                        None
+                    } else {
+                        Some(VZVirtioConsolePort::from_raw(retained(port), queue.clone()))
+                    }
+                })
+                .collect()
+        })
+    }
+
+    pub fn id(&self) -> Id {
+        *self.p
+    }
+}
+
+/// A port of a [`VZVirtioConsoleDevice`].
+#[derive(Clone)]
+pub struct VZVirtioConsolePort {
+    p: StrongPtr,
+    queue: DispatchQueue,
+}
+
+impl VZVirtioConsolePort {
+    fn from_raw(p: StrongPtr, queue: DispatchQueue) -> VZVirtioConsolePort {
+        VZVirtioConsolePort { p, queue }
+    }
+
+    /// The name the port was configured with, which the guest sees too. It cannot change, so it
+    /// is read directly.
+    pub fn name(&self) -> Option<String> {
+        unsafe {
+            let name: Id = msg_send![*self.p, name];
+            if name.is_null() {
+                None
+            } else {
+                Some(NSString(retained(name)).as_str().to_string())
+            }
+        }
+    }
+
+    /// Connects the port to `attachment`, or disconnects it with `None`. The guest sees the port
+    /// close and open again.
+    ///
+    /// The change is made on the VM's queue, so this panics if called from that queue. Fails
+    /// with `VZErrorCode::NotSupported` if the framework cannot change the attachment.
+    pub fn set_attachment(
+        &self,
+        attachment: Option<&dyn VZSerialPortAttachment>,
+    ) -> Result<(), VZError> {
+        let supported: BOOL =
+            unsafe { msg_send![*self.p, respondsToSelector: sel!(setAttachment:)] };
+        if !from_objc_bool(supported) {
+            return Err(VZError(NSError::error_with_domain(
+                VZ_ERROR_DOMAIN,
+                NOT_SUPPORTED,
+                None,
+            )));
+        }
+        self.queue
+            .assert_not_current("VZVirtioConsolePort::set_attachment");
+        let attachment = attachment.map_or(ptr::null_mut(), |a| a.id());
+        self.send_attachment(attachment);
+        Ok(())
+    }
+
+    /// Sets the attachment on the VM's queue, directly if already there.
+    fn send_attachment(&self, attachment: Id) {
+        let p = *self.p;
+        let set = move || unsafe {
+            let _: () = msg_send![p, setAttachment: attachment];
+        };
+        if self.queue.is_current() {
+            set()
+        } else {
+            self.queue.exec_sync(set)
+        }
+    }
+
+    pub fn id(&self) -> Id {
+        *self.p
+    }
+}
+
+/// The host end of a pseudo-terminal attached to a console port by
+/// [`attach_console_on_demand`]. Reads return guest output and writes reach the guest.
+///
+/// The master is owned; the slave is owned by the port's attachment. Closing or dropping it
+/// detaches the port, even from the VM's queue.
+pub struct PtyMaster {
+    /// Taken when closed.
+    master: Option<File>,
+    port: VZVirtioConsolePort,
+}
+
+impl PtyMaster {
+    /// The port the pseudo-terminal is attached to.
+    pub fn port(&self) -> &VZVirtioConsolePort {
+        &self.port
+    }
+
+    /// Detaches the port, so the framework releases the slave, and closes the master. Dropping
+    /// does the same, ignoring errors.
+    pub fn close(mut self) -> Result<(), CloseError> {
+        self.release()
+    }
+
+    fn release(&mut self) -> Result<(), CloseError> {
+        let master = match self.master.take() {
+            Some(master) => master,
+            None => return Ok(()),
+        };
+        self.port.send_attachment(ptr::null_mut());
+        close_file(master)
+    }
+
+    fn master(&self) -> &File {
+        // Only `close` and `drop` take it.
+        self.master.as_ref().unwrap()
+    }
+}
+
+impl Read for PtyMaster {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.master().read(buf)
+    }
+}
+
+impl Write for PtyMaster {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.master().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.master().flush()
+    }
+}
+
+impl AsRawFd for PtyMaster {
+    fn as_raw_fd(&self) -> RawFd {
+        self.master().as_raw_fd()
+    }
+}
+
+impl Drop for PtyMaster {
+    fn drop(&mut self) {
+        let _ = self.release();
+    }
+}
+
+/// Attaches a new pseudo-terminal to the console port named `port_name`, for as long as the
+/// returned [`PtyMaster`] lives. The guest end is in raw mode, so bytes pass unchanged.
+///
+/// Fails with `VZErrorCode::DeviceNotFound` if no console device has a port of that name, and
+/// with an `NSPOSIXErrorDomain` error if no pseudo-terminal can be allocated. Panics if called
+/// from the VM's queue.
+pub fn attach_console_on_demand(
+    vm: &VZVirtualMachine,
+    port_name: &str,
+) -> Result<PtyMaster, VZError> {
+    let port = vm
+        .console_devices()
+        .iter()
+        .flat_map(|device| device.ports())
+        .find(|port| port.name().as_deref() == Some(port_name))
+        .ok_or_else(|| {
+            VZError(NSError::error_with_domain(
+                VZ_ERROR_DOMAIN,
+                DEVICE_NOT_FOUND,
+                None,
+            ))
+        })?;
+    let (master, slave) = open_pty().map_err(|e| VZError(posix_error(&e)))?;
+    let slave = NSFileHandle::init_with_file_descriptor(slave.into_raw_fd(), true);
+    // One handle for both directions, so the descriptor is closed once.
+    let attachment =
+        unsafe { VZFileHandleSerialPortAttachment::from_raw_handles(*slave.0, *slave.0) };
+    port.set_attachment(Some(&attachment))?;
+    Ok(PtyMaster {
+        master: Some(master),
+        port,
+    })
+}
+
+/// A pseudo-terminal pair, with the slave in raw mode; both close on exec.
+fn open_pty() -> io::Result<(File, File)> {
+    let (mut master, mut slave) = (0, 0);
+    let ret = unsafe {
+        libc::openpty(
+            &mut master,
+            &mut slave,
+            ptr::null_mut(),
+            ptr::null_mut(),
+            ptr::null_mut(),
+        )
+    };
+    if ret == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    let (master, slave) = unsafe { (File::from_raw_fd(master), File::from_raw_fd(slave)) };
+    for fd in &[master.as_raw_fd(), slave.as_raw_fd()] {
+        if unsafe { libc::fcntl(*fd, libc::F_SETFD, libc::FD_CLOEXEC) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    unsafe {
+        let mut termios = MaybeUninit::<libc::termios>::uninit();
+        if libc::tcgetattr(slave.as_raw_fd(), termios.as_mut_ptr()) == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut termios = termios.assume_init();
+        libc::cfmakeraw(&mut termios);
+        if libc::tcsetattr(slave.as_raw_fd(), libc::TCSANOW, &termios) == -1 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok((master, slave))
+}
+
+fn posix_error(e: &io::Error) -> NSError {
+    let code = e.raw_os_error().unwrap_or(libc::EIO);
+    NSError::error_with_domain("NSPOSIXErrorDomain", code as NSInteger, None)
+}
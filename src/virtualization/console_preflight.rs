@@ -0,0 +1,323 @@
+//! console preflight module
+//!
+//! Checks the `console=` arguments of a Linux kernel command line against the console devices
+//! of the configuration, the most common reason a guest boots without printing anything. The
+//! framework's serial ports are virtio consoles, which a Linux guest names `hvc0`, `hvc1`, ...;
+//! it has no emulated UART (`ttyS0`, `ttyAMA0`).
+//!
+//! # Examples
+//! ```rust
+//! for finding in conf.console_preflight() {
+//!     eprintln!("{}: {}", finding.severity(), finding);
+//! }
+//! ```
+
+pub use crate::diagnostics::Severity;
+
+use std::fmt;
+
+/// How the guest is booted, as far as its kernel command line is concerned.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GuestBoot<'a> {
+    /// The Linux boot loader, with its command line.
+    Linux(&'a str),
+    /// The EFI boot loader: the guest's own bootloader passes the command line.
+    Efi,
+    /// Any other boot loader, e.g. a macOS guest's, which has no kernel command line.
+    Other,
+}
+
+/// The console devices of a configuration that a Linux guest can print to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ConsoleDevices {
+    /// Serial ports, `hvc0` up to `hvc<n - 1>` in the guest.
+    pub virtio_consoles: usize,
+    /// Whether a graphics device gives the guest virtual terminals (`tty0`, `tty1`, ...).
+    pub graphics: bool,
+}
+
+/// What the value of a `console=` argument names.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConsoleTarget {
+    /// A virtio console, `hvc<index>`.
+    Virtio(u32),
+    /// A UART: `ttyS`, `ttyAMA`, `uart8250`, ... No device of the framework provides one.
+    Uart,
+    /// A virtual terminal, `tty<index>`, shown on a graphics device.
+    VirtualTerminal,
+    /// Anything else, e.g. `null` or a line printer.
+    Other,
+}
+
+impl ConsoleTarget {
+    /// Classifies the value of a `console=` argument, e.g. `ttyS0,115200n8`.
+    pub fn parse(value: &str) -> ConsoleTarget {
+        let name = value.split(',').next().unwrap_or("");
+        let base = name.trim_end_matches(|c: char| c.is_ascii_digit());
+        let index = &name[base.len()..];
+        match base {
+            "hvc" => ConsoleTarget::Virtio(index.parse().unwrap_or(0)),
+            "tty" => ConsoleTarget::VirtualTerminal,
+            "ttyS" | "ttyAMA" | "ttyPS" | "ttymxc" | "uart" => ConsoleTarget::Uart,
+            _ => ConsoleTarget::Other,
+        }
+    }
+}
+
+/// A problem with the consoles of a Linux guest. `Display` gives the message with its fix.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConsoleFinding {
+    /// The command line has no `console=` argument, so kernel messages go to the kernel's
+    /// default console, which is not a virtio console.
+    NoConsoleArgument { virtio_consoles: usize },
+    /// `console=<value>` selects a UART.
+    Uart {
+        value: String,
+        virtio_consoles: usize,
+    },
+    /// `console=hvc<index>` selects a virtio console the configuration does not have.
+    MissingVirtioConsole {
+        value: String,
+        index: u32,
+        virtio_consoles: usize,
+    },
+    /// `console=tty<n>` selects a virtual terminal without a graphics device to show it.
+    NoGraphics {
+        value: String,
+        virtio_consoles: usize,
+    },
+    /// `console=<value>` names a device the preflight does not know.
+    Unrecognized { value: String },
+    /// The last `console=` argument, which becomes `/dev/console`, is not shown, while an
+    /// earlier one is.
+    PrimaryNotShown { primary: String, shown: String },
+    /// An EFI boot, whose command line the preflight cannot see.
+    EfiBoot,
+}
+
+impl ConsoleFinding {
+    /// [`Severity::Note`] for what the preflight could not check, [`Severity::Warning`] for a
+    /// console setup that hides the guest's output, or part of it.
+    pub fn severity(&self) -> Severity {
+        match self {
+            ConsoleFinding::Unrecognized { .. } | ConsoleFinding::EfiBoot => Severity::Note,
+            _ => Severity::Warning,
+        }
+    }
+}
+
+/// `hvc0`, `hvc0 and hvc1` or `hvc0 to hvc3`.
+fn hvc_range(count: usize) -> String {
+    match count {
+        1 => "hvc0".to_string(),
+        2 => "hvc0 and hvc1".to_string(),
+        _ => format!("hvc0 to hvc{}", count - 1),
+    }
+}
+
+/// `only a virtio console (hvc0) is configured`, or the same for more consoles.
+fn only_virtio(count: usize) -> String {
+    if count == 1 {
+        "only a virtio console (hvc0) is configured".to_string()
+    } else {
+        format!("only virtio consoles ({}) are configured", hvc_range(count))
+    }
+}
+
+impl fmt::Display for ConsoleFinding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConsoleFinding::NoConsoleArgument { virtio_consoles: 0 } => write!(
+                f,
+                "command line has no console= argument and no serial port is configured; kernel \
+                 messages will go to the default console, which nothing shows — add a serial \
+                 port and console=hvc0"
+            ),
+            ConsoleFinding::NoConsoleArgument { .. } => write!(
+                f,
+                "command line has no console= argument; kernel messages will go to the default \
+                 console, not hvc0 — add console=hvc0"
+            ),
+            ConsoleFinding::Uart {
+                value,
+                virtio_consoles: 0,
+            } => write!(
+                f,
+                "command line selects console={} but no console device is configured — add a \
+                 serial port and change to console=hvc0; pl011/16550 devices are not supported \
+                 by this framework",
+                value
+            ),
+            ConsoleFinding::Uart {
+                value,
+                virtio_consoles,
+            } => write!(
+                f,
+                "command line selects console={} but {} — change to console=hvc0 or add a \
+                 pl011/16550 device (not supported by this framework)",
+                value,
+                only_virtio(*virtio_consoles)
+            ),
+            ConsoleFinding::MissingVirtioConsole {
+                value,
+                index: 0,
+                virtio_consoles: 0,
+            } => write!(
+                f,
+                "command line selects console={} but no serial port is configured — add one, \
+                 which the guest sees as hvc0",
+                value
+            ),
+            ConsoleFinding::MissingVirtioConsole {
+                value,
+                index,
+                virtio_consoles: 0,
+            } => write!(
+                f,
+                "command line selects console={} but no serial port is configured — add a \
+                 serial port and change to console=hvc0, or add serial ports up to hvc{}",
+                value, index
+            ),
+            ConsoleFinding::MissingVirtioConsole {
+                value,
+                index,
+                virtio_consoles,
+            } => write!(
+                f,
+                "command line selects console={} but {} — change to console=hvc0 or add serial \
+                 ports up to hvc{}",
+                value,
+                only_virtio(*virtio_consoles),
+                index
+            ),
+            ConsoleFinding::NoGraphics {
+                value,
+                virtio_consoles: 0,
+            } => write!(
+                f,
+                "command line selects console={}, a virtual terminal, but no graphics device is \
+                 configured — add a graphics device, or a serial port and console=hvc0",
+                value
+            ),
+            ConsoleFinding::NoGraphics { value, .. } => write!(
+                f,
+                "command line selects console={}, a virtual terminal, but no graphics device is \
+                 configured — change to console=hvc0 or add a graphics device",
+                value
+            ),
+            ConsoleFinding::Unrecognized { value } => write!(
+                f,
+                "command line selects console={}, which is not a virtio console, UART or \
+                 virtual terminal; not checked",
+                value
+            ),
+            ConsoleFinding::PrimaryNotShown { primary, shown } => write!(
+                f,
+                "console={} is the last console= argument, so /dev/console (init's output and \
+                 the login prompt) goes there, not to console={} — move console={} last",
+                primary, shown, shown
+            ),
+            ConsoleFinding::EfiBoot => write!(
+                f,
+                "EFI boot: the guest's bootloader passes the kernel command line, which is not \
+                 checked; make sure it selects console=hvc0"
+            ),
+        }
+    }
+}
+
+/// Splits a kernel command line into its arguments as the kernel does: at spaces outside
+/// double quotes, with the quotes removed, up to a `--` that passes the rest to init.
+pub fn split_arguments(command_line: &str) -> Vec<String> {
+    let mut arguments = Vec::new();
+    let mut current = String::new();
+    let mut in_argument = false;
+    let mut in_quotes = false;
+    for c in command_line.chars() {
+        if c.is_whitespace() && !in_quotes {
+            if in_argument {
+                if current == "--" {
+                    return arguments;
+                }
+                arguments.push(std::mem::take(&mut current));
+                in_argument = false;
+            }
+            continue;
+        }
+        in_argument = true;
+        if c == '"' {
+            in_quotes = !in_quotes;
+        } else {
+            current.push(c);
+        }
+    }
+    if in_argument && current != "--" {
+        arguments.push(current);
+    }
+    arguments
+}
+
+/// The values of the `console=` arguments of `command_line`, in order; the last one is the
+/// primary console, `/dev/console`.
+pub fn console_arguments(command_line: &str) -> Vec<String> {
+    split_arguments(command_line)
+        .into_iter()
+        .filter_map(|argument| argument.strip_prefix("console=").map(str::to_string))
+        .collect()
+}
+
+/// Checks the consoles `boot` selects against `devices`. Finds nothing for a boot without a
+/// kernel command line, or a command line whose consoles are all shown.
+pub fn check(boot: GuestBoot<'_>, devices: ConsoleDevices) -> Vec<ConsoleFinding> {
+    let command_line = match boot {
+        GuestBoot::Linux(command_line) => command_line,
+        GuestBoot::Efi => return vec![ConsoleFinding::EfiBoot],
+        GuestBoot::Other => return Vec::new(),
+    };
+    let values = console_arguments(command_line);
+    let virtio_consoles = devices.virtio_consoles;
+    if values.is_empty() {
+        return vec![ConsoleFinding::NoConsoleArgument { virtio_consoles }];
+    }
+    let mut findings = Vec::new();
+    let mut shown = None;
+    for value in &values {
+        let finding = match ConsoleTarget::parse(value) {
+            ConsoleTarget::Virtio(index) if (index as usize) < virtio_consoles => None,
+            ConsoleTarget::Virtio(index) => Some(ConsoleFinding::MissingVirtioConsole {
+                value: value.clone(),
+                index,
+                virtio_consoles,
+            }),
+            ConsoleTarget::VirtualTerminal if devices.graphics => None,
+            ConsoleTarget::VirtualTerminal => Some(ConsoleFinding::NoGraphics {
+                value: value.clone(),
+                virtio_consoles,
+            }),
+            ConsoleTarget::Uart => Some(ConsoleFinding::Uart {
+                value: value.clone(),
+                virtio_consoles,
+            }),
+            ConsoleTarget::Other => Some(ConsoleFinding::Unrecognized {
+                value: value.clone(),
+            }),
+        };
+        match finding {
+            Some(finding) => findings.push(finding),
+            None => shown = Some(value),
+        }
+    }
+    let primary = values.last().expect("no console= arguments");
+    match shown {
+        Some(shown)
+            if shown != primary && ConsoleTarget::parse(primary) != ConsoleTarget::Other =>
+        {
+            findings.push(ConsoleFinding::PrimaryNotShown {
+                primary: primary.clone(),
+                shown: shown.clone(),
+            })
+        }
+        _ => {}
+    }
+    findings
+}
@@ -0,0 +1,358 @@
+//! console tee module
+//!
+//! Reads the guest console once and hands every chunk to a rotating log file, to any number of
+//! live subscribers and, once asked with [`ConsoleTee::record_to`], to a timestamped transcript.
+//!
+//! # Examples
+//! ```rust
+//! let tee = ConsoleTee::new("vm/console.log", 8 * 1024 * 1024, 4)?;
+//! let serial = VZVirtioConsoleDeviceSerialPortConfiguration::new(tee.attachment());
+//! let live = tee.subscribe();
+//! thread::spawn(move || {
+//!     for message in live {
+//!         match message {
+//!             TeeMessage::Data(bytes) => io::stdout().write_all(&bytes).unwrap(),
+//!             TeeMessage::Lagged(n) => eprintln!("[{} bytes skipped]", n),
+//!         }
+//!     }
+//! });
+//! ```
+
+use crate::base::{DispatchQueue, DispatchSource, NSFileHandle};
+use crate::resource::{close_file, first_error, CloseError};
+use crate::virtualization::console_transcript::{ConsoleRecorder, Direction};
+use crate::virtualization::serial_port::{
+    pipe, VZFileHandleSerialPortAttachment, VZFileHandleSerialPortAttachmentBuilder,
+};
+
+use std::ffi::OsString;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Read, Write};
+use std::os::unix::io::{AsRawFd, IntoRawFd};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex, MutexGuard, OnceLock};
+
+/// Chunks a subscriber may have queued before it starts missing output.
+const SUBSCRIBER_QUEUE: usize = 256;
+
+/// A chunk of guest output, shared by every subscriber that receives it.
+pub type Bytes = Arc<[u8]>;
+
+/// What a subscriber receives.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TeeMessage {
+    Data(Bytes),
+    /// The subscriber fell behind and this many bytes were skipped for it. The log file is not
+    /// affected.
+    Lagged(u64),
+}
+
+/// Log file that is renamed to `<path>.1` once it reaches `max_size` bytes, shifting older files
+/// up to `<path>.<max_files>`.
+struct RotatingLog {
+    path: PathBuf,
+    max_size: u64,
+    max_files: usize,
+    file: BufWriter<File>,
+    written: u64,
+}
+
+impl RotatingLog {
+    fn open(path: PathBuf, max_size: u64, max_files: usize) -> io::Result<RotatingLog> {
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let written = file.metadata()?.len();
+        Ok(RotatingLog {
+            path,
+            max_size: max_size.max(1),
+            max_files,
+            file: BufWriter::new(file),
+            written,
+        })
+    }
+
+    /// Splits `bytes` at the size limit, so every rotated file holds exactly `max_size` bytes.
+    fn write(&mut self, mut bytes: &[u8]) -> io::Result<()> {
+        while !bytes.is_empty() {
+            if self.written >= self.max_size {
+                self.rotate()?;
+            }
+            let room = (self.max_size - self.written).min(bytes.len() as u64) as usize;
+            self.file.write_all(&bytes[..room])?;
+            self.written += room as u64;
+            bytes = &bytes[room..];
+        }
+        Ok(())
+    }
+
+    /// Buffered bytes are written to the current file before it is renamed, and nothing is
+    /// written between the rename and opening the new file, so no output is lost or lands in
+    /// the wrong file.
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        if self.max_files == 0 {
+            self.file.get_ref().set_len(0)?;
+        } else {
+            for n in (1..self.max_files).rev() {
+                let from = self.rotated(n);
+                if from.exists() {
+                    fs::rename(&from, self.rotated(n + 1))?;
+                }
+            }
+            fs::rename(&self.path, self.rotated(1))?;
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&self.path)?;
+            self.file = BufWriter::new(file);
+        }
+        self.written = 0;
+        Ok(())
+    }
+
+    fn rotated(&self, n: usize) -> PathBuf {
+        let mut name = OsString::from(self.path.as_os_str());
+        name.push(format!(".{}", n));
+        PathBuf::from(name)
+    }
+}
+
+struct Subscriber {
+    sender: SyncSender<TeeMessage>,
+    /// Bytes skipped since the last message that got through.
+    missed: u64,
+}
+
+impl Subscriber {
+    /// Never blocks. Returns `false` once the receiver is gone.
+    fn send(&mut self, bytes: &Bytes) -> bool {
+        if self.missed > 0 {
+            match self.sender.try_send(TeeMessage::Lagged(self.missed)) {
+                Ok(()) => self.missed = 0,
+                Err(TrySendError::Full(_)) => {
+                    self.missed += bytes.len() as u64;
+                    return true;
+                }
+                Err(TrySendError::Disconnected(_)) => return false,
+            }
+        }
+        match self.sender.try_send(TeeMessage::Data(bytes.clone())) {
+            Ok(()) => true,
+            Err(TrySendError::Full(_)) => {
+                self.missed += bytes.len() as u64;
+                true
+            }
+            Err(TrySendError::Disconnected(_)) => false,
+        }
+    }
+}
+
+struct Shared {
+    log: Mutex<RotatingLog>,
+    /// First log write error since the last `flush` or `rotate_now`.
+    log_error: Mutex<Option<io::Error>>,
+    subscribers: Mutex<Vec<Subscriber>>,
+    recorder: Mutex<Option<ConsoleRecorder>>,
+}
+
+impl Shared {
+    fn log(&self) -> MutexGuard<'_, RotatingLog> {
+        self.log.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn subscribers(&self) -> MutexGuard<'_, Vec<Subscriber>> {
+        self.subscribers.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn recorder(&self) -> MutexGuard<'_, Option<ConsoleRecorder>> {
+        self.recorder.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Flushes the log and the transcript, the log first.
+    fn flush(&self) -> io::Result<()> {
+        let log = self.log().file.flush();
+        let transcript = self
+            .recorder()
+            .as_mut()
+            .map_or(Ok(()), ConsoleRecorder::flush);
+        log.and(transcript)
+    }
+
+    fn record(&self, result: io::Result<()>) {
+        if let Err(e) = result {
+            let mut error = self.log_error.lock().unwrap_or_else(|e| e.into_inner());
+            error.get_or_insert(e);
+        }
+    }
+
+    fn take_error(&self) -> io::Result<()> {
+        match self
+            .log_error
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .take()
+        {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+
+    fn fan_out(&self, chunk: &[u8]) {
+        // The log is written first; a failing disk must not keep subscribers from seeing output.
+        let result = self.log().write(chunk);
+        self.record(result);
+        if let Some(recorder) = self.recorder().as_mut() {
+            let result = recorder.record(Direction::Output, chunk);
+            self.record(result);
+        }
+
+        let mut subscribers = self.subscribers();
+        if subscribers.is_empty() {
+            return;
+        }
+        let bytes: Bytes = Arc::from(chunk);
+        subscribers.retain_mut(|s| s.send(&bytes));
+    }
+}
+
+/// Serial port attachment that writes guest output to a rotating log file and streams it to
+/// subscribers.
+///
+/// A dispatch source drains the guest's pipe on a queue of its own, without a thread blocked in
+/// `read`. Writing the log never waits for subscribers, and a
+/// subscriber that does not keep up gets [`TeeMessage::Lagged`] instead of stalling the guest.
+/// The pipe ends and the log file are owned; see [`close`](Self::close).
+pub struct ConsoleTee {
+    attachment: VZFileHandleSerialPortAttachment,
+    shared: Arc<Shared>,
+    /// Taken when closed.
+    input: Option<File>,
+}
+
+impl ConsoleTee {
+    /// Appends to `path`, rotating it every `max_size` bytes and keeping `max_files` rotated
+    /// files. With `max_files` 0 the log is truncated instead.
+    pub fn new<P: AsRef<Path>>(path: P, max_size: u64, max_files: usize) -> io::Result<ConsoleTee> {
+        let log = RotatingLog::open(path.as_ref().to_path_buf(), max_size, max_files)?;
+        let (guest_input, input) = pipe()?;
+        let (mut output, guest_output) = pipe()?;
+        let attachment = VZFileHandleSerialPortAttachmentBuilder::new()
+            .file_handle_for_reading(NSFileHandle::init_with_file_descriptor(
+                guest_input.into_raw_fd(),
+                true,
+            ))
+            .file_handle_for_writing(NSFileHandle::init_with_file_descriptor(
+                guest_output.into_raw_fd(),
+                true,
+            ))
+            .build();
+
+        let shared = Arc::new(Shared {
+            log: Mutex::new(log),
+            log_error: Mutex::new(None),
+            subscribers: Mutex::new(Vec::new()),
+            recorder: Mutex::new(None),
+        });
+        // The source cancels itself at end of file; until then it holds itself through the
+        // handler, so the guest's output is drained even after `ConsoleTee` is dropped. `output`
+        // is closed along with the handler, which libdispatch releases once cancelled.
+        let this: Arc<OnceLock<DispatchSource>> = Arc::new(OnceLock::new());
+        let reader = shared.clone();
+        let fd = output.as_raw_fd();
+        let source = DispatchSource::read(fd, &DispatchQueue::new("console-tee"), {
+            let this = this.clone();
+            let mut chunk = [0u8; 4096];
+            move |_| match output.read(&mut chunk) {
+                Ok(n) if n > 0 => reader.fan_out(&chunk[..n]),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                _ => {
+                    if let Some(source) = this.get() {
+                        source.cancel();
+                    }
+                }
+            }
+        });
+        let finished = shared.clone();
+        source.on_cancel(move || {
+            let result = finished.flush();
+            finished.record(result);
+            // The transcript is complete; close it rather than wait for the tee to be dropped.
+            drop(finished.recorder().take());
+            // Dropping the senders ends every subscriber's iteration.
+            finished.subscribers().clear();
+        });
+        let _ = this.set(source);
+
+        Ok(ConsoleTee {
+            attachment,
+            shared,
+            input: Some(input),
+        })
+    }
+
+    /// The attachment to hand to a serial port configuration.
+    pub fn attachment(&self) -> VZFileHandleSerialPortAttachment {
+        self.attachment.clone()
+    }
+
+    /// A live feed of guest output from now on. The feed ends when the guest closes its console.
+    pub fn subscribe(&self) -> Receiver<TeeMessage> {
+        let (sender, receiver) = sync_channel(SUBSCRIBER_QUEUE);
+        self.shared
+            .subscribers()
+            .push(Subscriber { sender, missed: 0 });
+        receiver
+    }
+
+    /// Records the guest's output from now on to a transcript at `path`, created or truncated,
+    /// with offsets counted from this call; see
+    /// [`console_transcript`](crate::virtualization::console_transcript). A recording already
+    /// running is closed. Write errors are reported like the log's.
+    pub fn record_to<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let recorder = ConsoleRecorder::create(path)?;
+        *self.shared.recorder() = Some(recorder);
+        Ok(())
+    }
+
+    /// Rotates the log now, regardless of its size. Also reports a log write error that happened
+    /// since the last call to this or [`flush`](Self::flush).
+    pub fn rotate_now(&self) -> io::Result<()> {
+        self.shared.take_error()?;
+        self.shared.log().rotate()
+    }
+
+    /// Writes buffered output to the log file and the transcript. Also reports a write error that
+    /// happened since the last call to this or [`rotate_now`](Self::rotate_now).
+    pub fn flush(&self) -> io::Result<()> {
+        self.shared.take_error()?;
+        self.shared.flush()
+    }
+
+    /// Flushes the log, closes the guest's input, which the guest sees as end of file, and the
+    /// guest's ends of the pipes. The log file is closed and subscribers' feeds end once the
+    /// reader drained the output pipe. Dropping does the same, ignoring errors.
+    ///
+    /// Fails with [`CloseError::Attached`] while a configuration references the attachment: the
+    /// guest's ends, and with them the log file, are then closed when the framework releases it.
+    pub fn close(mut self) -> Result<(), CloseError> {
+        self.release()
+    }
+
+    fn release(&mut self) -> Result<(), CloseError> {
+        let input = match self.input.take() {
+            Some(input) => input,
+            None => return Ok(()),
+        };
+        first_error([
+            self.flush().map_err(CloseError::Io),
+            close_file(input),
+            self.attachment.close_handles("ConsoleTee attachment"),
+        ])
+    }
+}
+
+impl Drop for ConsoleTee {
+    fn drop(&mut self) {
+        let _ = self.release();
+    }
+}
@@ -0,0 +1,593 @@
+//! console transcript module
+//!
+//! Guest console traffic with the time each chunk was seen, to compare what a console said, and
+//! when, across runs: a boot that regressed after a host update shows up as missing or extra
+//! lines and as lines that came later. [`ConsoleTee::record_to`] records a tee's output; a
+//! [`ConsoleRecorder`] records anything else.
+//!
+//! # Format
+//! An 8 byte header, `VZCT`, the version as a little-endian `u16`, then two reserved bytes, is
+//! followed by records, each a little-endian `u32` length of its bytes, a `u64` offset in
+//! microseconds since the recording started, measured on the monotonic clock, a [`Direction`]
+//! byte, then the bytes. Chunks longer than [`MAX_RECORD_LEN`] are split across records.
+//!
+//! A recorder killed mid-write leaves a truncated last record, which
+//! [`ConsoleTranscript::load`] drops, reporting it through
+//! [`truncated`](ConsoleTranscript::truncated); damage anywhere else fails the load.
+//!
+//! # Diffing
+//! [`diff`] splits both transcripts into lines and aligns them with a longest common subsequence
+//! in which lines only pair if their text matches, with runs of digits and of whitespace taken
+//! as equal so kernel timestamps and counters do not break the alignment, and if they came within
+//! [`DiffTolerance::align_within`] of each other. Lines of one transcript only are insertions or
+//! deletions. A paired line whose delay since the previous paired line changed by more than
+//! [`DiffTolerance::report_beyond`] is a timing change, reported once where the time was gained
+//! or lost rather than on every later line.
+//!
+//! # Examples
+//! ```rust
+//! tee.record_to("runs/before.vzct")?;
+//! // ... boot the guest, update the host, boot it again recording to runs/after.vzct
+//! let before = ConsoleTranscript::load("runs/before.vzct")?;
+//! let after = ConsoleTranscript::load("runs/after.vzct")?;
+//! print!("{}", console_transcript::diff(&before, &after, DiffTolerance::default()));
+//! ```
+//!
+//! [`ConsoleTee::record_to`]: crate::virtualization::console_tee::ConsoleTee::record_to
+
+use crate::resource::{close_file, first_error, CloseError};
+
+use std::convert::{TryFrom, TryInto};
+use std::error::Error;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+const MAGIC: &[u8; 4] = b"VZCT";
+/// The version [`ConsoleRecorder`] writes, and the newest [`ConsoleTranscript::load`] reads.
+pub const TRANSCRIPT_VERSION: u16 = 1;
+const HEADER_LEN: usize = 8;
+/// Length, offset and direction.
+const RECORD_HEADER_LEN: usize = 4 + 8 + 1;
+/// The most bytes a record holds. A longer length is damage, not a truncated record.
+pub const MAX_RECORD_LEN: usize = 1 << 20;
+
+/// Pairs of lines [`diff`] aligns between the common start and end of two transcripts; beyond
+/// it, the differing middle is reported as deleted and inserted lines.
+const MAX_ALIGNED_PAIRS: usize = 1 << 24;
+
+/// Which way a chunk went.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Direction {
+    /// Written by the guest.
+    Output,
+    /// Sent to the guest.
+    Input,
+}
+
+impl Direction {
+    fn to_byte(self) -> u8 {
+        match self {
+            Direction::Output => 0,
+            Direction::Input => 1,
+        }
+    }
+
+    fn from_byte(byte: u8) -> Option<Direction> {
+        match byte {
+            0 => Some(Direction::Output),
+            1 => Some(Direction::Input),
+            _ => None,
+        }
+    }
+}
+
+/// A chunk of console traffic.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TranscriptRecord {
+    /// Since the recording started, in whole microseconds.
+    pub offset: Duration,
+    pub direction: Direction,
+    pub bytes: Vec<u8>,
+}
+
+/// Writes a transcript file. Chunks are buffered; [`flush`](Self::flush) or
+/// [`close`](Self::close) to write them out, dropping does the same, ignoring errors.
+pub struct ConsoleRecorder {
+    /// Taken when closed.
+    file: Option<BufWriter<File>>,
+    start: Instant,
+}
+
+impl ConsoleRecorder {
+    /// Creates or truncates `path` and writes the header. Offsets count from now.
+    pub fn create<P: AsRef<Path>>(path: P) -> io::Result<ConsoleRecorder> {
+        let mut file = BufWriter::new(File::create(path)?);
+        file.write_all(MAGIC)?;
+        file.write_all(&TRANSCRIPT_VERSION.to_le_bytes())?;
+        file.write_all(&[0, 0])?;
+        file.flush()?;
+        Ok(ConsoleRecorder {
+            file: Some(file),
+            start: Instant::now(),
+        })
+    }
+
+    /// Records `bytes` as seen now.
+    pub fn record(&mut self, direction: Direction, bytes: &[u8]) -> io::Result<()> {
+        let offset = self.start.elapsed();
+        self.record_at(offset, direction, bytes)
+    }
+
+    /// Records `bytes` as seen `offset` after the start, e.g. to write a transcript of traffic
+    /// timed elsewhere. Empty chunks are skipped.
+    pub fn record_at(
+        &mut self,
+        offset: Duration,
+        direction: Direction,
+        bytes: &[u8],
+    ) -> io::Result<()> {
+        let file = self.file.as_mut().expect("recorder used after close");
+        let offset_us = u64::try_from(offset.as_micros()).unwrap_or(u64::MAX);
+        for chunk in bytes.chunks(MAX_RECORD_LEN) {
+            file.write_all(&(chunk.len() as u32).to_le_bytes())?;
+            file.write_all(&offset_us.to_le_bytes())?;
+            file.write_all(&[direction.to_byte()])?;
+            file.write_all(chunk)?;
+        }
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        match self.file.as_mut() {
+            Some(file) => file.flush(),
+            None => Ok(()),
+        }
+    }
+
+    /// Writes buffered chunks and closes the file.
+    pub fn close(mut self) -> Result<(), CloseError> {
+        self.release()
+    }
+
+    fn release(&mut self) -> Result<(), CloseError> {
+        let mut file = match self.file.take() {
+            Some(file) => file,
+            None => return Ok(()),
+        };
+        let flushed = file.flush().map_err(CloseError::Io);
+        let closed = match file.into_inner() {
+            Ok(file) => close_file(file),
+            Err(e) => Err(CloseError::Io(e.into_error())),
+        };
+        first_error([flushed, closed])
+    }
+}
+
+impl Drop for ConsoleRecorder {
+    fn drop(&mut self) {
+        let _ = self.release();
+    }
+}
+
+/// Why a transcript could not be read.
+#[derive(Debug)]
+pub enum TranscriptError {
+    Io(io::Error),
+    /// The file does not start with a transcript header.
+    NotATranscript,
+    /// Written by a newer version of the format, or a version that never existed.
+    UnsupportedVersion(u16),
+    /// The record at byte `offset` of the file is damaged.
+    Corrupt {
+        offset: usize,
+        reason: &'static str,
+    },
+}
+
+impl fmt::Display for TranscriptError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TranscriptError::Io(e) => write!(f, "cannot read transcript: {}", e),
+            TranscriptError::NotATranscript => write!(f, "not a console transcript"),
+            TranscriptError::UnsupportedVersion(version) => write!(
+                f,
+                "transcript version {} is not supported; the newest is {}",
+                version, TRANSCRIPT_VERSION
+            ),
+            TranscriptError::Corrupt { offset, reason } => {
+                write!(f, "transcript record at byte {}: {}", offset, reason)
+            }
+        }
+    }
+}
+
+impl Error for TranscriptError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            TranscriptError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for TranscriptError {
+    fn from(e: io::Error) -> Self {
+        TranscriptError::Io(e)
+    }
+}
+
+/// A line of console traffic, from a [`ConsoleTranscript`]'s records.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TranscriptLine {
+    /// When its first byte was seen.
+    pub offset: Duration,
+    pub direction: Direction,
+    /// Without the line ending, invalid UTF-8 replaced.
+    pub text: String,
+}
+
+impl fmt::Display for TranscriptLine {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let marker = match self.direction {
+            Direction::Output => "",
+            Direction::Input => "> ",
+        };
+        write!(
+            f,
+            "[{:>5}.{:06}] {}{}",
+            self.offset.as_secs(),
+            self.offset.subsec_micros(),
+            marker,
+            self.text
+        )
+    }
+}
+
+/// The records of a transcript file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConsoleTranscript {
+    version: u16,
+    records: Vec<TranscriptRecord>,
+    truncated: bool,
+}
+
+impl ConsoleTranscript {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<ConsoleTranscript, TranscriptError> {
+        ConsoleTranscript::parse(&fs::read(path)?)
+    }
+
+    /// Reads a transcript from the contents of its file.
+    pub fn parse(bytes: &[u8]) -> Result<ConsoleTranscript, TranscriptError> {
+        if bytes.len() < HEADER_LEN || &bytes[..4] != MAGIC {
+            return Err(TranscriptError::NotATranscript);
+        }
+        let version = u16::from_le_bytes([bytes[4], bytes[5]]);
+        if version == 0 || version > TRANSCRIPT_VERSION {
+            return Err(TranscriptError::UnsupportedVersion(version));
+        }
+        let mut records = Vec::new();
+        let mut truncated = false;
+        let mut at = HEADER_LEN;
+        while at < bytes.len() {
+            let rest = &bytes[at..];
+            if rest.len() < RECORD_HEADER_LEN {
+                truncated = true;
+                break;
+            }
+            let len = u32::from_le_bytes(rest[..4].try_into().unwrap()) as usize;
+            if len > MAX_RECORD_LEN {
+                return Err(TranscriptError::Corrupt {
+                    offset: at,
+                    reason: "length over the record limit",
+                });
+            }
+            let direction = Direction::from_byte(rest[12]).ok_or(TranscriptError::Corrupt {
+                offset: at,
+                reason: "unknown direction",
+            })?;
+            if rest.len() - RECORD_HEADER_LEN < len {
+                truncated = true;
+                break;
+            }
+            let offset_us = u64::from_le_bytes(rest[4..12].try_into().unwrap());
+            records.push(TranscriptRecord {
+                offset: Duration::from_micros(offset_us),
+                direction,
+                bytes: rest[RECORD_HEADER_LEN..RECORD_HEADER_LEN + len].to_vec(),
+            });
+            at += RECORD_HEADER_LEN + len;
+        }
+        Ok(ConsoleTranscript {
+            version,
+            records,
+            truncated,
+        })
+    }
+
+    /// The version of the format the file was written in.
+    pub fn version(&self) -> u16 {
+        self.version
+    }
+
+    pub fn records(&self) -> &[TranscriptRecord] {
+        &self.records
+    }
+
+    pub fn iter(&self) -> std::slice::Iter<'_, TranscriptRecord> {
+        self.records.iter()
+    }
+
+    /// Whether the file ended inside a record, which was dropped.
+    pub fn truncated(&self) -> bool {
+        self.truncated
+    }
+
+    /// The traffic split into lines, each direction on its own, ordered by when they started. A
+    /// line still open at the end, e.g. a login prompt, is the last of its direction.
+    pub fn lines(&self) -> Vec<TranscriptLine> {
+        let mut lines = Vec::new();
+        // The line being assembled in each direction, with its start.
+        let mut open: [Option<(Duration, Vec<u8>)>; 2] = [None, None];
+        for record in &self.records {
+            let slot = &mut open[record.direction.to_byte() as usize];
+            for piece in record.bytes.split_inclusive(|&b| b == b'\n') {
+                let (_, text) = slot.get_or_insert_with(|| (record.offset, Vec::new()));
+                match piece.split_last() {
+                    Some((b'\n', content)) => {
+                        text.extend_from_slice(content);
+                        let (offset, text) = slot.take().unwrap();
+                        lines.push(line(offset, record.direction, &text));
+                    }
+                    _ => text.extend_from_slice(piece),
+                }
+            }
+        }
+        for (direction, slot) in [Direction::Output, Direction::Input].iter().zip(open) {
+            if let Some((offset, text)) = slot {
+                lines.push(line(offset, *direction, &text));
+            }
+        }
+        lines.sort_by_key(|line| line.offset);
+        lines
+    }
+
+    /// The [`lines`](Self::lines) as text, one per line, prefixed with their offset in seconds
+    /// like `dmesg`; input lines are marked with `>`.
+    pub fn render_text(&self) -> String {
+        self.lines()
+            .iter()
+            .map(|line| format!("{}\n", line))
+            .collect()
+    }
+}
+
+impl<'a> IntoIterator for &'a ConsoleTranscript {
+    type Item = &'a TranscriptRecord;
+    type IntoIter = std::slice::Iter<'a, TranscriptRecord>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.records.iter()
+    }
+}
+
+fn line(offset: Duration, direction: Direction, text: &[u8]) -> TranscriptLine {
+    let text = String::from_utf8_lossy(text);
+    TranscriptLine {
+        offset,
+        direction,
+        text: text.trim_end_matches('\r').to_string(),
+    }
+}
+
+/// How far apart [`diff`] lets lines be.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DiffTolerance {
+    /// Lines further apart than this are not paired, however alike.
+    pub align_within: Duration,
+    /// Paired lines are reported once their delay since the previous pair changed by more.
+    pub report_beyond: Duration,
+}
+
+impl Default for DiffTolerance {
+    /// Pairs lines up to a minute apart and reports changes over half a second.
+    fn default() -> Self {
+        DiffTolerance {
+            align_within: Duration::from_secs(60),
+            report_beyond: Duration::from_millis(500),
+        }
+    }
+}
+
+/// A difference between two transcripts, `a` and `b`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DiffEntry {
+    /// A line of `a` that `b` lacks.
+    Deleted(TranscriptLine),
+    /// A line of `b` that `a` lacks.
+    Inserted(TranscriptLine),
+    /// A line of both, whose delay since the previous line of both changed by `delta_us`
+    /// microseconds; positive when `b` is slower.
+    Timing {
+        a: TranscriptLine,
+        b: TranscriptLine,
+        delta_us: i64,
+    },
+}
+
+/// What [`diff`] found, in the order of the transcripts.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TranscriptDiff {
+    entries: Vec<DiffEntry>,
+    aligned: bool,
+}
+
+impl TranscriptDiff {
+    pub fn entries(&self) -> &[DiffEntry] {
+        &self.entries
+    }
+
+    /// Whether the transcripts said the same in the same time, within the tolerance.
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// `false` if the transcripts differed in too many lines to align them, and the differing
+    /// middle is reported as deleted and inserted lines instead.
+    pub fn aligned(&self) -> bool {
+        self.aligned
+    }
+}
+
+impl fmt::Display for TranscriptDiff {
+    /// A line per entry: `-` for deleted lines, `+` for inserted ones and `~` for timing
+    /// changes, with the line as `b` has it and the change in seconds.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for entry in &self.entries {
+            match entry {
+                DiffEntry::Deleted(line) => writeln!(f, "- {}", line)?,
+                DiffEntry::Inserted(line) => writeln!(f, "+ {}", line)?,
+                DiffEntry::Timing { b, delta_us, .. } => {
+                    let sign = if *delta_us < 0 { '-' } else { '+' };
+                    let delta = delta_us.unsigned_abs();
+                    writeln!(
+                        f,
+                        "~ {} ({}{}.{:06} s)",
+                        b,
+                        sign,
+                        delta / 1_000_000,
+                        delta % 1_000_000
+                    )?
+                }
+            }
+        }
+        if !self.aligned {
+            writeln!(
+                f,
+                "(too many differences to align; the middle is not paired)"
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// What lines are compared by: digit runs and whitespace runs each count as one, and leading and
+/// trailing whitespace not at all.
+fn normalized(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let (mut digits, mut space) = (false, false);
+    for c in text.trim().chars() {
+        if c.is_ascii_digit() {
+            if !digits {
+                out.push('0');
+            }
+            digits = true;
+            space = false;
+        } else if c.is_whitespace() {
+            if !space {
+                out.push(' ');
+            }
+            space = true;
+            digits = false;
+        } else {
+            out.push(c);
+            digits = false;
+            space = false;
+        }
+    }
+    out
+}
+
+fn micros(offset: Duration) -> i64 {
+    i64::try_from(offset.as_micros()).unwrap_or(i64::MAX)
+}
+
+/// Aligns the lines of `a` and `b` and reports what changed; see the
+/// [module documentation](self).
+pub fn diff(
+    a: &ConsoleTranscript,
+    b: &ConsoleTranscript,
+    tolerance: DiffTolerance,
+) -> TranscriptDiff {
+    let (a, b) = (a.lines(), b.lines());
+    let keys = |lines: &[TranscriptLine]| -> Vec<(Direction, String)> {
+        lines
+            .iter()
+            .map(|line| (line.direction, normalized(&line.text)))
+            .collect()
+    };
+    let (ka, kb) = (keys(&a), keys(&b));
+    let pairs = |i: usize, j: usize| {
+        ka[i] == kb[j]
+            && (micros(a[i].offset) - micros(b[j].offset)).unsigned_abs()
+                <= micros(tolerance.align_within) as u64
+    };
+
+    // The common start and end pair without a search.
+    let (n, m) = (a.len(), b.len());
+    let mut prefix = 0;
+    while prefix < n.min(m) && pairs(prefix, prefix) {
+        prefix += 1;
+    }
+    let mut suffix = 0;
+    while suffix < (n - prefix).min(m - prefix) && pairs(n - 1 - suffix, m - 1 - suffix) {
+        suffix += 1;
+    }
+    let mut matched: Vec<(usize, usize)> = (0..prefix).map(|i| (i, i)).collect();
+    let (rows, cols) = (n - prefix - suffix, m - prefix - suffix);
+    let aligned = rows.saturating_mul(cols) <= MAX_ALIGNED_PAIRS;
+    if aligned && rows > 0 && cols > 0 {
+        // Longest common subsequence of the middles: `lcs[i][j]` is its length for the lines
+        // from `i` and `j` on.
+        let width = cols + 1;
+        let mut lcs = vec![0u32; (rows + 1) * width];
+        for i in (0..rows).rev() {
+            for j in (0..cols).rev() {
+                lcs[i * width + j] = if pairs(prefix + i, prefix + j) {
+                    lcs[(i + 1) * width + j + 1] + 1
+                } else {
+                    lcs[(i + 1) * width + j].max(lcs[i * width + j + 1])
+                };
+            }
+        }
+        let (mut i, mut j) = (0, 0);
+        while i < rows && j < cols {
+            if pairs(prefix + i, prefix + j) {
+                matched.push((prefix + i, prefix + j));
+                i += 1;
+                j += 1;
+            } else if lcs[(i + 1) * width + j] >= lcs[i * width + j + 1] {
+                i += 1;
+            } else {
+                j += 1;
+            }
+        }
+    }
+    matched.extend((0..suffix).map(|k| (n - suffix + k, m - suffix + k)));
+
+    let mut entries = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    let mut skew = 0;
+    for (pi, pj) in matched.into_iter().chain(Some((n, m))) {
+        entries.extend(a[i..pi].iter().cloned().map(DiffEntry::Deleted));
+        entries.extend(b[j..pj].iter().cloned().map(DiffEntry::Inserted));
+        if pi == n {
+            break;
+        }
+        let paired_skew = micros(b[pj].offset) - micros(a[pi].offset);
+        let delta_us = paired_skew - skew;
+        if delta_us.unsigned_abs() > micros(tolerance.report_beyond) as u64 {
+            entries.push(DiffEntry::Timing {
+                a: a[pi].clone(),
+                b: b[pj].clone(),
+                delta_us,
+            });
+        }
+        skew = paired_skew;
+        i = pi + 1;
+        j = pj + 1;
+    }
+    TranscriptDiff { entries, aligned }
+}
@@ -0,0 +1,108 @@
+//! device module
+//!
+//! Base trait of the per-category device configuration traits, for code that handles devices
+//! generically.
+//!
+//! # Examples
+//! ```rust
+//! let devices: Vec<Box<dyn VZStorageDeviceConfiguration>> = vec![
+//!     Box::new(VZVirtioBlockDeviceConfiguration::new(root)),
+//!     Box::new(VZUSBMassStorageDeviceConfiguration::new(installer)),
+//! ];
+//! for device in &devices {
+//!     let device: &dyn VZDeviceConfiguration = device;
+//!     println!("{}", device.class_name());
+//!     if let Some(block) = device.downcast_ref::<VZVirtioBlockDeviceConfiguration>() {
+//!         // ...
+//!     }
+//! }
+//! ```
+
+use crate::base::Id;
+use crate::strict;
+use crate::virtualization::error::FrozenConfigError;
+
+use std::any::Any;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use objc::runtime::Class;
+use objc::{msg_send, sel, sel_impl};
+
+/// common behaviors of device configurations
+pub trait VZDeviceConfiguration: Any {
+    fn id(&self) -> Id;
+
+    /// The concrete Rust type, for downcasting trait objects.
+    fn as_any(&self) -> &dyn Any;
+
+    /// Name of the runtime class of the underlying object, so subclasses report accurately.
+    fn class_name(&self) -> &str {
+        unsafe {
+            let class: &Class = msg_send![self.id(), class];
+            class.name()
+        }
+    }
+
+    /// The flag set once the device is part of a virtual machine; `None` for devices without
+    /// setters.
+    #[doc(hidden)]
+    fn frozen_flag(&self) -> Option<FrozenFlag> {
+        None
+    }
+}
+
+impl dyn VZDeviceConfiguration {
+    pub fn is<T: VZDeviceConfiguration>(&self) -> bool {
+        self.as_any().is::<T>()
+    }
+
+    pub fn downcast_ref<T: VZDeviceConfiguration>(&self) -> Option<&T> {
+        self.as_any().downcast_ref::<T>()
+    }
+}
+
+/// Boxed devices forward to the device, so `as_any` yields the boxed type rather than the box.
+impl<T: VZDeviceConfiguration + ?Sized> VZDeviceConfiguration for Box<T> {
+    fn id(&self) -> Id {
+        (**self).id()
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        (**self).as_any()
+    }
+
+    fn class_name(&self) -> &str {
+        (**self).class_name()
+    }
+
+    fn frozen_flag(&self) -> Option<FrozenFlag> {
+        (**self).frozen_flag()
+    }
+}
+
+/// Set once a configuration has been used to create a virtual machine, after which the framework
+/// ignores or rejects changes to it. Clones of a configuration share the flag.
+#[derive(Clone, Default)]
+pub struct FrozenFlag(Arc<AtomicBool>);
+
+impl FrozenFlag {
+    pub fn is_frozen(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+
+    pub(crate) fn freeze(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    /// Fails with an error naming `setter` once frozen.
+    pub(crate) fn check(&self, setter: &'static str) -> Result<(), FrozenConfigError> {
+        if self.is_frozen() {
+            let error = FrozenConfigError { setter };
+            strict::frozen(&error);
+            Err(error)
+        } else {
+            Ok(())
+        }
+    }
+}
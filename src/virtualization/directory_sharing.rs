@@ -0,0 +1,476 @@
+//! directory sharing module
+//!
+//! Host directories exposed to the guest over virtiofs, and swapping them while it runs. On Apple
+//! silicon, [`VZLinuxRosettaDirectoryShare`] exposes Rosetta to Linux guests.
+//!
+//! Directory sharing needs macOS 12. Before it the shares panic, and
+//! [`VZVirtioFileSystemDeviceConfiguration::new`] fails with `VZErrorCode::NotSupported`.
+//!
+//! # Examples
+//! ```rust
+//! let share = VZSingleDirectoryShare::new(VZSharedDirectory::new("/Users/me/src", false)?);
+//! let fs = VZVirtioFileSystemDeviceConfiguration::new("src", share)?;
+//! // ... build the configuration with `.directory_sharing_devices(vec![fs])` and start the VM
+//!
+//! let next = VZSingleDirectoryShare::new(VZSharedDirectory::new("/Users/me/other", false)?);
+//! for device in vm.directory_sharing_devices() {
+//!     if device.tag() == "src" {
+//!         device.set_share(&next)?;
+//!     }
+//! }
+//! ```
+
+use crate::base::{DispatchQueue, Id, InvalidInput, NSError, NSInteger, NSString, NIL, NSURL};
+use crate::features::{self, ClassLookup, HostCapabilities};
+use crate::runtime::{
+    alloc, call_with_error, from_objc_bool, owned, retained, to_objc_bool, vz_class,
+};
+use crate::virtualization::device::VZDeviceConfiguration;
+use crate::virtualization::error::{ResultExt, VZError, VZErrorCtx, VZ_ERROR_DOMAIN};
+use crate::virtualization::share_scan::{self, ShareError, SharePolicy, ShareScan};
+
+use std::any::Any;
+use std::io;
+use std::path::PathBuf;
+
+use objc::rc::StrongPtr;
+use objc::runtime::BOOL;
+use objc::{class, msg_send, sel, sel_impl};
+
+/// `VZErrorNotSupported`.
+const NOT_SUPPORTED: NSInteger = 10;
+
+static SHARED_DIRECTORY: ClassLookup = ClassLookup::new("VZSharedDirectory", "macOS 12");
+static SINGLE_DIRECTORY_SHARE: ClassLookup = ClassLookup::new("VZSingleDirectoryShare", "macOS 12");
+static MULTIPLE_DIRECTORY_SHARE: ClassLookup =
+    ClassLookup::new("VZMultipleDirectoryShare", "macOS 12");
+static VIRTIO_FILE_SYSTEM_DEVICE: ClassLookup =
+    ClassLookup::new("VZVirtioFileSystemDeviceConfiguration", "macOS 12");
+
+/// A host directory to share.
+pub struct VZSharedDirectory {
+    p: StrongPtr,
+    path: PathBuf,
+    policy: SharePolicy,
+}
+
+impl VZSharedDirectory {
+    /// Fails, naming the path, if it cannot be made into a file URL, e.g. because it is empty.
+    pub fn new(path: &str, read_only: bool) -> Result<VZSharedDirectory, InvalidInput> {
+        VZSharedDirectory::with_policy(path, read_only, SharePolicy::default())
+    }
+
+    /// Scans `path` with `policy` first and fails instead of sharing a dangerous root, a root
+    /// that is a symbolic link, or a tree with links leading out of it. The scan is best-effort,
+    /// see [`share_scan`](crate::virtualization::share_scan).
+    pub fn new_hardened(
+        path: &str,
+        read_only: bool,
+        policy: SharePolicy,
+    ) -> Result<VZSharedDirectory, ShareError> {
+        share_scan::scan(path, policy)?;
+        VZSharedDirectory::with_policy(path, read_only, policy).map_err(|e| ShareError::Io {
+            path: PathBuf::from(path),
+            error: io::Error::new(io::ErrorKind::InvalidInput, e),
+        })
+    }
+
+    fn with_policy(
+        path: &str,
+        read_only: bool,
+        policy: SharePolicy,
+    ) -> Result<VZSharedDirectory, InvalidInput> {
+        let url = NSURL::file_url_with_path(path, true)
+            .ok_or_else(|| InvalidInput::new("shared directory path", path))?;
+        unsafe {
+            let i = alloc(SHARED_DIRECTORY.expect());
+            let p = owned(msg_send![i, initWithURL:*url.0 readOnly:to_objc_bool(read_only)]);
+            Ok(VZSharedDirectory {
+                p,
+                path: PathBuf::from(path),
+                policy,
+            })
+        }
+    }
+
+    /// Scans the directory again with the policy it was created with, the default one for
+    /// [`VZSharedDirectory::new`], e.g. periodically for a share that stays up. Sharing goes on
+    /// whatever the result.
+    pub fn rescan(&self) -> Result<ShareScan, ShareError> {
+        share_scan::scan(&self.path, self.policy)
+    }
+
+    pub fn id(&self) -> Id {
+        *self.p
+    }
+}
+
+/// common behaviors of directory shares
+pub trait VZDirectoryShare {
+    fn id(&self) -> Id;
+}
+
+/// Shares one directory as the root of the file system.
+pub struct VZSingleDirectoryShare(StrongPtr);
+
+impl VZSingleDirectoryShare {
+    pub fn new(directory: VZSharedDirectory) -> VZSingleDirectoryShare {
+        unsafe {
+            let i = alloc(SINGLE_DIRECTORY_SHARE.expect());
+            let p = owned(msg_send![i, initWithDirectory:*directory.p]);
+            VZSingleDirectoryShare(p)
+        }
+    }
+}
+
+impl VZDirectoryShare for VZSingleDirectoryShare {
+    fn id(&self) -> Id {
+        *self.0
+    }
+}
+
+/// Shares several directories, each under its name at the root of the file system.
+pub struct VZMultipleDirectoryShare(StrongPtr);
+
+impl VZMultipleDirectoryShare {
+    pub fn new(directories: Vec<(String, VZSharedDirectory)>) -> VZMultipleDirectoryShare {
+        let names: Vec<NSString> = directories
+            .iter()
+            .map(|(name, _)| NSString::new(name))
+            .collect();
+        let keys: Vec<Id> = names.iter().map(|name| *name.0).collect();
+        let objects: Vec<Id> = directories.iter().map(|(_, dir)| *dir.p).collect();
+        unsafe {
+            let dictionary: Id = msg_send![
+                class!(NSDictionary),
+                dictionaryWithObjects: objects.as_ptr()
+                forKeys: keys.as_ptr()
+                count: keys.len()
+            ];
+            let i = alloc(MULTIPLE_DIRECTORY_SHARE.expect());
+            let p = owned(msg_send![i, initWithDirectories: dictionary]);
+            VZMultipleDirectoryShare(p)
+        }
+    }
+}
+
+impl VZDirectoryShare for VZMultipleDirectoryShare {
+    fn id(&self) -> Id {
+        *self.0
+    }
+}
+
+/// `VZLinuxRosettaAvailability`: whether the host can share Rosetta with Linux guests.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VZLinuxRosettaAvailability {
+    /// Not on this host: an Intel Mac, or macOS before 13.
+    NotSupported,
+    /// Supported, but Rosetta is not installed on the host.
+    NotInstalled,
+    Installed,
+}
+
+/// The guest-side end of the cache Rosetta keeps of its ahead-of-time translations. The guest
+/// runs the daemon from the share, listening on the same socket: `rosettad daemon <path>` for
+/// [`UnixSocket`](Self::UnixSocket), or on the abstract socket named in
+/// [`AbstractSocket`](Self::AbstractSocket). Without a daemon there, Rosetta translates without
+/// a cache.
+pub enum VZLinuxRosettaCachingOptions {
+    UnixSocket(VZLinuxRosettaUnixSocketCachingOptions),
+    AbstractSocket(VZLinuxRosettaAbstractSocketCachingOptions),
+}
+
+impl VZLinuxRosettaCachingOptions {
+    fn id(&self) -> Id {
+        match self {
+            VZLinuxRosettaCachingOptions::UnixSocket(options) => *options.0,
+            VZLinuxRosettaCachingOptions::AbstractSocket(options) => *options.0,
+        }
+    }
+}
+
+impl From<VZLinuxRosettaUnixSocketCachingOptions> for VZLinuxRosettaCachingOptions {
+    fn from(options: VZLinuxRosettaUnixSocketCachingOptions) -> Self {
+        VZLinuxRosettaCachingOptions::UnixSocket(options)
+    }
+}
+
+impl From<VZLinuxRosettaAbstractSocketCachingOptions> for VZLinuxRosettaCachingOptions {
+    fn from(options: VZLinuxRosettaAbstractSocketCachingOptions) -> Self {
+        VZLinuxRosettaCachingOptions::AbstractSocket(options)
+    }
+}
+
+/// `VZErrorNotSupported`, for classes and selectors the running framework lacks.
+fn not_supported() -> NSError {
+    NSError::error_with_domain(VZ_ERROR_DOMAIN, NOT_SUPPORTED, None)
+}
+
+/// Caches translations through a Unix socket at a path in the guest. macOS 14 and later, Apple
+/// silicon only.
+pub struct VZLinuxRosettaUnixSocketCachingOptions(StrongPtr);
+
+impl VZLinuxRosettaUnixSocketCachingOptions {
+    /// Fails if the framework rejects `path`, e.g. because it is longer than
+    /// [`maximum_path_length`](Self::maximum_path_length), or with `VZErrorCode::NotSupported`
+    /// where the class is missing.
+    pub fn new(path: &str) -> Result<Self, VZErrorCtx> {
+        Self::new_for_host(&HostCapabilities::detect(), path)
+    }
+
+    /// [`new`](Self::new), checking availability against `host` instead of detecting it.
+    pub fn new_for_host(host: &HostCapabilities, path: &str) -> Result<Self, VZErrorCtx> {
+        const OPERATION: &str = "create Rosetta caching options";
+        let resource = format!("socket path '{}'", path);
+        if !host.rosetta_caching().unix_socket {
+            return Err(not_supported()).ctx(OPERATION, resource);
+        }
+        let path = NSString::new(path);
+        unsafe {
+            let i = alloc(vz_class!(VZLinuxRosettaUnixSocketCachingOptions));
+            call_with_error(|error| -> Id { msg_send![i, initWithPath:*path.0 error:error] })
+                .map(|p| VZLinuxRosettaUnixSocketCachingOptions(owned(p)))
+                .ctx(OPERATION, resource)
+        }
+    }
+
+    /// The longest path the framework accepts, in bytes.
+    pub fn maximum_path_length() -> usize {
+        unsafe {
+            msg_send![
+                vz_class!(VZLinuxRosettaUnixSocketCachingOptions),
+                maximumPathLength
+            ]
+        }
+    }
+
+    pub fn path(&self) -> String {
+        unsafe {
+            NSString(retained(msg_send![*self.0, path]))
+                .as_str()
+                .to_string()
+        }
+    }
+}
+
+/// Caches translations through a Linux abstract socket with a name, no file in the guest. macOS 14
+/// and later, Apple silicon only.
+pub struct VZLinuxRosettaAbstractSocketCachingOptions(StrongPtr);
+
+impl VZLinuxRosettaAbstractSocketCachingOptions {
+    /// Fails if the framework rejects `name`, e.g. because it is longer than
+    /// [`maximum_name_length`](Self::maximum_name_length), or with `VZErrorCode::NotSupported`
+    /// where the class is missing.
+    pub fn new(name: &str) -> Result<Self, VZErrorCtx> {
+        Self::new_for_host(&HostCapabilities::detect(), name)
+    }
+
+    /// [`new`](Self::new), checking availability against `host` instead of detecting it.
+    pub fn new_for_host(host: &HostCapabilities, name: &str) -> Result<Self, VZErrorCtx> {
+        const OPERATION: &str = "create Rosetta caching options";
+        let resource = format!("socket name '{}'", name);
+        if !host.rosetta_caching().abstract_socket {
+            return Err(not_supported()).ctx(OPERATION, resource);
+        }
+        let name = NSString::new(name);
+        unsafe {
+            let i = alloc(vz_class!(VZLinuxRosettaAbstractSocketCachingOptions));
+            call_with_error(|error| -> Id { msg_send![i, initWithName:*name.0 error:error] })
+                .map(|p| VZLinuxRosettaAbstractSocketCachingOptions(owned(p)))
+                .ctx(OPERATION, resource)
+        }
+    }
+
+    /// The longest name the framework accepts, in bytes.
+    pub fn maximum_name_length() -> usize {
+        unsafe {
+            msg_send![
+                vz_class!(VZLinuxRosettaAbstractSocketCachingOptions),
+                maximumNameLength
+            ]
+        }
+    }
+
+    pub fn name(&self) -> String {
+        unsafe {
+            NSString(retained(msg_send![*self.0, name]))
+                .as_str()
+                .to_string()
+        }
+    }
+}
+
+/// Shares Rosetta with a Linux guest, which mounts it over virtiofs and registers it with
+/// `binfmt_misc` to run x86_64 binaries. macOS 13 and later, Apple silicon only.
+pub struct VZLinuxRosettaDirectoryShare(StrongPtr);
+
+impl VZLinuxRosettaDirectoryShare {
+    pub fn availability() -> VZLinuxRosettaAvailability {
+        if features::require("VZLinuxRosettaDirectoryShare").is_err() {
+            return VZLinuxRosettaAvailability::NotSupported;
+        }
+        let availability: NSInteger =
+            unsafe { msg_send![vz_class!(VZLinuxRosettaDirectoryShare), availability] };
+        match availability {
+            1 => VZLinuxRosettaAvailability::NotInstalled,
+            2 => VZLinuxRosettaAvailability::Installed,
+            _ => VZLinuxRosettaAvailability::NotSupported,
+        }
+    }
+
+    /// Fails if Rosetta is not installed, or with `VZErrorCode::NotSupported` where the class is
+    /// missing.
+    pub fn new() -> Result<Self, VZErrorCtx> {
+        const OPERATION: &str = "create Rosetta directory share";
+        if features::require("VZLinuxRosettaDirectoryShare").is_err() {
+            return Err(not_supported()).ctx_op(OPERATION);
+        }
+        unsafe {
+            let i = alloc(vz_class!(VZLinuxRosettaDirectoryShare));
+            call_with_error(|error| -> Id { msg_send![i, initWithError: error] })
+                .map(|p| VZLinuxRosettaDirectoryShare(owned(p)))
+                .ctx_op(OPERATION)
+        }
+    }
+
+    /// Sets how Rosetta caches translations, or stops caching with `None`. Fails with
+    /// `VZErrorCode::NotSupported` before macOS 14.
+    pub fn set_options(
+        &self,
+        options: Option<VZLinuxRosettaCachingOptions>,
+    ) -> Result<(), VZError> {
+        let supported: BOOL = unsafe { msg_send![*self.0, respondsToSelector: sel!(setOptions:)] };
+        if !from_objc_bool(supported) {
+            return Err(VZError(not_supported()));
+        }
+        let options = options
+            .as_ref()
+            .map_or(NIL, VZLinuxRosettaCachingOptions::id);
+        unsafe {
+            let _: () = msg_send![*self.0, setOptions: options];
+        }
+        Ok(())
+    }
+
+    /// The caching options, `None` if none are set or before macOS 14.
+    pub fn options(&self) -> Option<VZLinuxRosettaCachingOptions> {
+        unsafe {
+            let supported: BOOL = msg_send![*self.0, respondsToSelector: sel!(options)];
+            if !from_objc_bool(supported) {
+                return None;
+            }
+            let options: Id = msg_send![*self.0, options];
+            if options.is_null() {
+                return None;
+            }
+            let unix: BOOL = msg_send![
+                options,
+                isKindOfClass: vz_class!(VZLinuxRosettaUnixSocketCachingOptions)
+            ];
+            let options = retained(options);
+            if from_objc_bool(unix) {
+                Some(VZLinuxRosettaUnixSocketCachingOptions(options).into())
+            } else {
+                Some(VZLinuxRosettaAbstractSocketCachingOptions(options).into())
+            }
+        }
+    }
+}
+
+impl VZDirectoryShare for VZLinuxRosettaDirectoryShare {
+    fn id(&self) -> Id {
+        *self.0
+    }
+}
+
+/// common configure of directory sharing device
+pub trait VZDirectorySharingDeviceConfiguration: VZDeviceConfiguration {}
+
+impl<T: VZDirectorySharingDeviceConfiguration + ?Sized> VZDirectorySharingDeviceConfiguration
+    for Box<T>
+{
+}
+
+/// configure of a virtiofs device; the guest mounts it by its tag
+pub struct VZVirtioFileSystemDeviceConfiguration(StrongPtr);
+
+impl VZVirtioFileSystemDeviceConfiguration {
+    /// Fails if the framework rejects `tag`, e.g. because it is empty or too long.
+    pub fn new<T: VZDirectoryShare>(tag: &str, share: T) -> Result<Self, VZErrorCtx> {
+        let resource = format!("tag '{}'", tag);
+        let class = VIRTIO_FILE_SYSTEM_DEVICE
+            .get()
+            .map_err(|_| not_supported())
+            .ctx("create directory sharing device", resource.as_str())?;
+        let tag = NSString::new(tag);
+        unsafe {
+            call_with_error(|error| {
+                let ret: BOOL = msg_send![class, validateTag:*tag.0 error:error];
+                from_objc_bool(ret)
+            })
+            .ctx("create directory sharing device", resource)?;
+            let i = alloc(class);
+            let p = owned(msg_send![i, initWithTag:*tag.0]);
+            let _: () = msg_send![*p, setShare: share.id()];
+            Ok(VZVirtioFileSystemDeviceConfiguration(p))
+        }
+    }
+}
+
+impl VZDeviceConfiguration for VZVirtioFileSystemDeviceConfiguration {
+    fn id(&self) -> Id {
+        *self.0
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+impl VZDirectorySharingDeviceConfiguration for VZVirtioFileSystemDeviceConfiguration {}
+
+/// virtiofs device of a running virtual machine, obtained from
+/// [`VZVirtualMachine::directory_sharing_devices`](crate::virtualization::virtual_machine::VZVirtualMachine::directory_sharing_devices).
+pub struct VZVirtioFileSystemDevice {
+    p: StrongPtr,
+    queue: DispatchQueue,
+}
+
+impl VZVirtioFileSystemDevice {
+    pub(crate) fn from_raw(p: StrongPtr, queue: DispatchQueue) -> VZVirtioFileSystemDevice {
+        VZVirtioFileSystemDevice { p, queue }
+    }
+
+    /// The tag the guest mounts the device by. It cannot change, so it is read directly.
+    pub fn tag(&self) -> String {
+        unsafe { NSString(retained(msg_send![*self.p, tag])).as_str().to_string() }
+    }
+
+    /// Points the device at another share. The guest sees the new contents on its next access,
+    /// without remounting.
+    ///
+    /// The change is made on the VM's queue, so this panics if called from that queue. Fails
+    /// with `VZErrorCode::NotSupported` before macOS 13, which cannot change a share at runtime.
+    pub fn set_share<T: VZDirectoryShare>(&self, share: &T) -> Result<(), VZError> {
+        let supported: BOOL = unsafe { msg_send![*self.p, respondsToSelector: sel!(setShare:)] };
+        if !from_objc_bool(supported) {
+            return Err(VZError(NSError::error_with_domain(
+                VZ_ERROR_DOMAIN,
+                NOT_SUPPORTED,
+                None,
+            )));
+        }
+        self.queue
+            .assert_not_current("VZVirtioFileSystemDevice::set_share");
+        let p = *self.p;
+        let share = share.id();
+        self.queue.exec_sync(move || unsafe {
+            let _: () = msg_send![p, setShare: share];
+        });
+        Ok(())
+    }
+
+    pub fn id(&self) -> Id {
+        *self.p
+    }
+}
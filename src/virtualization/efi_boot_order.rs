@@ -0,0 +1,266 @@
+//! EFI boot order module
+//!
+//! Read-only inspection of the `BootOrder` and `Boot####` variables in an EFI variable store, to
+//! warn users whose virtual machine is about to boot the installer again.
+//!
+//! The store is parsed as an EDK2 variable store, optionally wrapped in a firmware volume header,
+//! with plain or authenticated variable headers. Other layouts are reported as
+//! `ErrorKind::InvalidData`.
+//!
+//! An installer image attached through a Virtio block device has the same device path as a system
+//! disk; attach it as USB mass storage so it is classified as removable.
+//!
+//! # Examples
+//! ```rust
+//! if !EfiBootOrder::prefer_disk_over_removable(&store)? {
+//!     println!("your VM will boot the ISO again: remove it or change the boot order in the firmware UI");
+//! }
+//! ```
+
+use crate::virtualization::boot_loader::VZEFIVariableStore;
+
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+
+type Guid = [u8; 16];
+
+/// `gEfiVariableGuid`, the signature of a store with plain variable headers.
+const VARIABLE_STORE_GUID: Guid = [
+    0x16, 0x36, 0xcf, 0xdd, 0x75, 0x32, 0x64, 0x41, 0x98, 0xb6, 0xfe, 0x85, 0x70, 0x7f, 0xfe, 0x7d,
+];
+/// `gEfiAuthenticatedVariableGuid`, the signature of a store with authenticated variable headers.
+const AUTHENTICATED_VARIABLE_STORE_GUID: Guid = [
+    0x78, 0x2c, 0xf3, 0xaa, 0x7b, 0x94, 0x9a, 0x43, 0xa1, 0x80, 0x2e, 0x14, 0x4e, 0xc3, 0x77, 0x92,
+];
+/// `EFI_GLOBAL_VARIABLE`, the vendor of `BootOrder` and `Boot####`.
+const GLOBAL_VARIABLE_GUID: Guid = [
+    0x61, 0xdf, 0xe4, 0x8b, 0xca, 0x93, 0xd2, 0x11, 0xaa, 0x0d, 0x00, 0xe0, 0x98, 0x03, 0x2b, 0x8c,
+];
+
+const FIRMWARE_VOLUME_SIGNATURE: &[u8] = b"_FVH";
+const VARIABLE_STORE_HEADER_SIZE: usize = 28;
+const VARIABLE_HEADER_SIZE: usize = 32;
+const AUTHENTICATED_VARIABLE_HEADER_SIZE: usize = 60;
+const VARIABLE_START_ID: u16 = 0x55aa;
+const VAR_ADDED: u8 = 0x3f;
+const VAR_ADDED_IN_DELETED_TRANSITION: u8 = 0x3e;
+const LOAD_OPTION_ACTIVE: u32 = 0x1;
+
+/// What a boot entry points at, judged from its device path.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BootDevice {
+    /// A Virtio block device or other fixed disk.
+    Disk,
+    /// A USB device or optical media, e.g. an installer.
+    Removable,
+    /// Network boot.
+    Network,
+    /// An application inside the firmware, such as the shell or the setup UI.
+    Firmware,
+    Unknown,
+}
+
+/// A `Boot####` load option.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BootEntry {
+    /// The `####` of the variable name.
+    pub number: u16,
+    pub description: String,
+    pub active: bool,
+    pub device: BootDevice,
+}
+
+/// Boot entries in `BootOrder` order and the one the firmware tries first.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BootEntrySummary {
+    /// The first active entry.
+    pub default: Option<BootEntry>,
+    /// Entries listed in `BootOrder` that exist in the store.
+    pub entries: Vec<BootEntry>,
+}
+
+impl BootEntrySummary {
+    /// Whether the first active disk entry comes before the first active removable one. `true` if
+    /// there is no removable entry.
+    pub fn prefers_disk_over_removable(&self) -> bool {
+        for entry in self.entries.iter().filter(|e| e.active) {
+            match entry.device {
+                BootDevice::Disk => return true,
+                BootDevice::Removable => return false,
+                _ => {}
+            }
+        }
+        true
+    }
+}
+
+/// Reads the boot order of an EFI variable store.
+pub struct EfiBootOrder;
+
+impl EfiBootOrder {
+    pub fn detect_default_boot(store: &VZEFIVariableStore) -> io::Result<BootEntrySummary> {
+        EfiBootOrder::parse(&fs::read(store.path())?)
+    }
+
+    /// Whether the current order boots the system disk before any removable media.
+    pub fn prefer_disk_over_removable(store: &VZEFIVariableStore) -> io::Result<bool> {
+        EfiBootOrder::detect_default_boot(store).map(|s| s.prefers_disk_over_removable())
+    }
+
+    /// Parses the contents of a variable store file.
+    pub fn parse(bytes: &[u8]) -> io::Result<BootEntrySummary> {
+        let variables = global_variables(bytes)?;
+        let order = variables
+            .get("BootOrder")
+            .ok_or_else(|| invalid_data("the store has no BootOrder variable"))?;
+        let entries = order
+            .chunks_exact(2)
+            .map(|n| u16::from_le_bytes([n[0], n[1]]))
+            .filter_map(|number| {
+                let name = format!("Boot{:04X}", number);
+                variables
+                    .get(&name)
+                    .map(|option| parse_load_option(number, option))
+            })
+            .collect::<io::Result<Vec<_>>>()?;
+        let default = entries.iter().find(|e| e.active).cloned();
+        Ok(BootEntrySummary { default, entries })
+    }
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> Option<u16> {
+    bytes
+        .get(offset..offset + 2)
+        .map(|b| u16::from_le_bytes([b[0], b[1]]))
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> Option<u32> {
+    bytes
+        .get(offset..offset + 4)
+        .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+}
+
+fn utf16(bytes: &[u8]) -> String {
+    let units: Vec<u16> = bytes
+        .chunks_exact(2)
+        .map(|u| u16::from_le_bytes([u[0], u[1]]))
+        .take_while(|u| *u != 0)
+        .collect();
+    String::from_utf16_lossy(&units)
+}
+
+/// Offset of the variable store header and whether its variables are authenticated.
+fn find_store(bytes: &[u8]) -> io::Result<(usize, bool)> {
+    let start = if bytes.get(40..44) == Some(FIRMWARE_VOLUME_SIGNATURE) {
+        read_u16(bytes, 48).unwrap_or(0) as usize
+    } else {
+        0
+    };
+    match bytes.get(start..start + 16) {
+        Some(guid) if guid == AUTHENTICATED_VARIABLE_STORE_GUID => Ok((start, true)),
+        Some(guid) if guid == VARIABLE_STORE_GUID => Ok((start, false)),
+        _ => Err(invalid_data("unknown EFI variable store format")),
+    }
+}
+
+/// Live variables of the global vendor, by name. A variable being replaced may appear twice; the
+/// fully added copy wins over the one in deleted transition.
+fn global_variables(bytes: &[u8]) -> io::Result<HashMap<String, Vec<u8>>> {
+    let (store, authenticated) = find_store(bytes)?;
+    let store_size = read_u32(bytes, store + 16).unwrap_or(0) as usize;
+    let end = (store + store_size).min(bytes.len());
+    let (header_size, sizes_at) = if authenticated {
+        (AUTHENTICATED_VARIABLE_HEADER_SIZE, 36)
+    } else {
+        (VARIABLE_HEADER_SIZE, 8)
+    };
+
+    let mut variables: HashMap<String, (u8, Vec<u8>)> = HashMap::new();
+    let mut offset = store + VARIABLE_STORE_HEADER_SIZE;
+    while offset + header_size <= end && read_u16(bytes, offset) == Some(VARIABLE_START_ID) {
+        let state = bytes[offset + 2];
+        let name_size = read_u32(bytes, offset + sizes_at).unwrap_or(0) as usize;
+        let data_size = read_u32(bytes, offset + sizes_at + 4).unwrap_or(0) as usize;
+        let vendor = &bytes[offset + sizes_at + 8..offset + sizes_at + 24];
+        let name_start = offset + header_size;
+        let data_start = name_start + name_size;
+        let data_end = data_start + data_size;
+        if data_end > end {
+            return Err(invalid_data("EFI variable runs past the end of the store"));
+        }
+
+        let live = state == VAR_ADDED || state == VAR_ADDED_IN_DELETED_TRANSITION;
+        if live && vendor == GLOBAL_VARIABLE_GUID {
+            let name = utf16(&bytes[name_start..data_start]);
+            let fully_added = variables
+                .get(&name)
+                .is_some_and(|(seen, _)| *seen == VAR_ADDED);
+            if !fully_added {
+                variables.insert(name, (state, bytes[data_start..data_end].to_vec()));
+            }
+        }
+        offset = (data_end + 3) & !3;
+    }
+    Ok(variables
+        .into_iter()
+        .map(|(name, (_, data))| (name, data))
+        .collect())
+}
+
+/// Parses an `EFI_LOAD_OPTION`.
+fn parse_load_option(number: u16, option: &[u8]) -> io::Result<BootEntry> {
+    let malformed = || invalid_data("malformed EFI load option");
+    let attributes = read_u32(option, 0).ok_or_else(malformed)?;
+    let path_length = read_u16(option, 4).ok_or_else(malformed)? as usize;
+    let description_end = (6..option.len())
+        .step_by(2)
+        .find(|&i| read_u16(option, i) == Some(0))
+        .ok_or_else(malformed)?;
+    let path = option
+        .get(description_end + 2..description_end + 2 + path_length)
+        .ok_or_else(malformed)?;
+    Ok(BootEntry {
+        number,
+        description: utf16(&option[6..description_end]),
+        active: attributes & LOAD_OPTION_ACTIVE != 0,
+        device: classify_device_path(path),
+    })
+}
+
+fn classify_device_path(path: &[u8]) -> BootDevice {
+    let mut fixed = false;
+    let mut offset = 0;
+    while offset + 4 <= path.len() {
+        let (kind, sub_kind) = (path[offset], path[offset + 1]);
+        let length = read_u16(path, offset + 2).unwrap_or(0) as usize;
+        match (kind, sub_kind) {
+            // End of device path.
+            (0x7f, _) => break,
+            // CD-ROM media, USB and USB class nodes.
+            (0x04, 0x02) | (0x03, 0x05) | (0x03, 0x0f) => return BootDevice::Removable,
+            // MAC address, IPv4, IPv6 and URI nodes.
+            (0x03, 0x0b) | (0x03, 0x0c) | (0x03, 0x0d) | (0x03, 0x18) => {
+                return BootDevice::Network
+            }
+            // Firmware file and firmware volume nodes.
+            (0x04, 0x06) | (0x04, 0x07) => return BootDevice::Firmware,
+            // PCI device and hard drive media nodes.
+            (0x01, 0x01) | (0x04, 0x01) => fixed = true,
+            _ => {}
+        }
+        if length < 4 {
+            break;
+        }
+        offset += length;
+    }
+    if fixed {
+        BootDevice::Disk
+    } else {
+        BootDevice::Unknown
+    }
+}
@@ -1,14 +1,18 @@
 //! entropy device module
 
 use crate::base::Id;
+use crate::runtime::{owned, vz_class};
+use crate::virtualization::device::VZDeviceConfiguration;
+
+use std::any::Any;
 
 use objc::rc::StrongPtr;
-use objc::{class, msg_send, sel, sel_impl};
+use objc::{msg_send, sel, sel_impl};
 
 /// common configure of entropy device
-pub trait VZEntropyDeviceConfiguration {
-    fn id(&self) -> Id;
-}
+pub trait VZEntropyDeviceConfiguration: VZDeviceConfiguration {}
+
+impl<T: VZEntropyDeviceConfiguration + ?Sized> VZEntropyDeviceConfiguration for Box<T> {}
 
 /// configure of entropy device
 pub struct VZVirtioEntropyDeviceConfiguration(StrongPtr);
@@ -16,14 +20,29 @@ pub struct VZVirtioEntropyDeviceConfiguration(StrongPtr);
 impl VZVirtioEntropyDeviceConfiguration {
     pub fn new() -> VZVirtioEntropyDeviceConfiguration {
         unsafe {
-            let p = StrongPtr::new(msg_send![class!(VZVirtioEntropyDeviceConfiguration), new]);
+            let p = owned(msg_send![
+                vz_class!(VZVirtioEntropyDeviceConfiguration),
+                new
+            ]);
             VZVirtioEntropyDeviceConfiguration(p)
         }
     }
 }
 
-impl VZEntropyDeviceConfiguration for VZVirtioEntropyDeviceConfiguration {
+impl Default for VZVirtioEntropyDeviceConfiguration {
+    fn default() -> Self {
+        VZVirtioEntropyDeviceConfiguration::new()
+    }
+}
+
+impl VZDeviceConfiguration for VZVirtioEntropyDeviceConfiguration {
     fn id(&self) -> Id {
         *self.0
     }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
 }
+
+impl VZEntropyDeviceConfiguration for VZVirtioEntropyDeviceConfiguration {}
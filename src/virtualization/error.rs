@@ -0,0 +1,405 @@
+//! error module
+
+use crate::base::{Id, NSError, NSInteger, NSString, NIL};
+use crate::features;
+use crate::limits::DeviceLimitExceeded;
+use crate::runtime::retained;
+
+use std::fmt;
+
+/// Error domain of the errors the framework reports.
+pub const VZ_ERROR_DOMAIN: &str = "VZErrorDomain";
+
+/// The `VZErrorDomain` string exported by the framework.
+///
+/// The constant is a global the framework owns. It is retained rather than adopted, so dropping
+/// the wrapper never releases the framework's reference; each call returns the same object.
+/// Without the framework, it is a new string with the same contents.
+pub fn vz_error_domain() -> NSString {
+    match features::framework_symbol("VZErrorDomain") {
+        Some(domain) => unsafe { NSString(retained(*(domain as *const Id))) },
+        None => NSString::new(VZ_ERROR_DOMAIN),
+    }
+}
+
+/// Foundation's domain for Cocoa errors, which includes user cancellation.
+const NS_COCOA_ERROR_DOMAIN: &str = "NSCocoaErrorDomain";
+const NS_USER_CANCELLED_ERROR: NSInteger = 3072;
+
+/// Error codes of `VZErrorDomain`, as listed in `VZError.h`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VZErrorCode {
+    Internal,
+    InvalidVirtualMachineConfiguration,
+    InvalidVirtualMachineState,
+    InvalidVirtualMachineStateTransition,
+    InvalidDiskImage,
+    VirtualMachineLimitExceeded,
+    NetworkError,
+    OutOfDiskSpace,
+    OperationCancelled,
+    NotSupported,
+    Save,
+    Restore,
+    RestoreImageCatalogLoadFailed,
+    InvalidRestoreImageCatalog,
+    NoSupportedRestoreImagesInCatalog,
+    RestoreImageLoadFailed,
+    InvalidRestoreImage,
+    InstallationRequiresUpdate,
+    InstallationFailed,
+    NetworkBlockDeviceNegotiationFailed,
+    NetworkBlockDeviceDisconnected,
+    USBControllerNotFound,
+    DeviceAlreadyAttached,
+    DeviceInitializationFailure,
+    DeviceNotFound,
+    /// A code this crate does not know about yet.
+    Other(NSInteger),
+}
+
+impl VZErrorCode {
+    pub fn from_raw(code: NSInteger) -> VZErrorCode {
+        match code {
+            1 => VZErrorCode::Internal,
+            2 => VZErrorCode::InvalidVirtualMachineConfiguration,
+            3 => VZErrorCode::InvalidVirtualMachineState,
+            4 => VZErrorCode::InvalidVirtualMachineStateTransition,
+            5 => VZErrorCode::InvalidDiskImage,
+            6 => VZErrorCode::VirtualMachineLimitExceeded,
+            7 => VZErrorCode::NetworkError,
+            8 => VZErrorCode::OutOfDiskSpace,
+            9 => VZErrorCode::OperationCancelled,
+            10 => VZErrorCode::NotSupported,
+            11 => VZErrorCode::Save,
+            12 => VZErrorCode::Restore,
+            10001 => VZErrorCode::RestoreImageCatalogLoadFailed,
+            10002 => VZErrorCode::InvalidRestoreImageCatalog,
+            10003 => VZErrorCode::NoSupportedRestoreImagesInCatalog,
+            10004 => VZErrorCode::RestoreImageLoadFailed,
+            10005 => VZErrorCode::InvalidRestoreImage,
+            10006 => VZErrorCode::InstallationRequiresUpdate,
+            10007 => VZErrorCode::InstallationFailed,
+            20001 => VZErrorCode::NetworkBlockDeviceNegotiationFailed,
+            20002 => VZErrorCode::NetworkBlockDeviceDisconnected,
+            30001 => VZErrorCode::USBControllerNotFound,
+            30002 => VZErrorCode::DeviceAlreadyAttached,
+            30003 => VZErrorCode::DeviceInitializationFailure,
+            30004 => VZErrorCode::DeviceNotFound,
+            other => VZErrorCode::Other(other),
+        }
+    }
+}
+
+/// An error reported by the framework.
+#[derive(Debug, Clone)]
+pub struct VZError(pub NSError);
+
+impl VZError {
+    /// Whether the error belongs to `VZErrorDomain`.
+    pub fn is_vz_error(&self) -> bool {
+        self.0.is_vz_error()
+    }
+
+    /// The error that caused this one, if the framework reported it.
+    pub fn underlying_error(&self) -> Option<NSError> {
+        self.0.underlying_error()
+    }
+
+    /// The typed code; `None` for errors from other domains.
+    pub fn code(&self) -> Option<VZErrorCode> {
+        if self.is_vz_error() {
+            Some(VZErrorCode::from_raw(self.0.code() as NSInteger))
+        } else {
+            None
+        }
+    }
+
+    pub fn ns_error(&self) -> &NSError {
+        &self.0
+    }
+}
+
+/// Result delivered to the safe completion-based wrappers.
+#[derive(Clone)]
+pub enum CompletionOutcome<T = ()> {
+    Success(T),
+    /// The operation was cancelled, e.g. through its `NSProgress`. Retrying is pointless.
+    Cancelled,
+    Failed(VZError),
+}
+
+impl CompletionOutcome {
+    /// Maps the `NSError *` argument of a completion handler; nil means success.
+    ///
+    /// # Safety
+    /// `error` must be nil or a valid `NSError`.
+    pub unsafe fn from_error(error: Id) -> CompletionOutcome {
+        if error == NIL {
+            CompletionOutcome::Success(())
+        } else {
+            CompletionOutcome::from_ns_error(NSError(retained(error)))
+        }
+    }
+}
+
+impl<T> CompletionOutcome<T> {
+    /// Classifies a failure. This is the single place deciding what counts as cancellation.
+    pub fn from_ns_error(error: NSError) -> CompletionOutcome<T> {
+        let code = error.code() as NSInteger;
+        let cancelled = if error.is_vz_error() {
+            code == 9
+        } else {
+            error.domain().as_str() == NS_COCOA_ERROR_DOMAIN && code == NS_USER_CANCELLED_ERROR
+        };
+        if cancelled {
+            CompletionOutcome::Cancelled
+        } else {
+            CompletionOutcome::Failed(VZError(error))
+        }
+    }
+
+    pub fn is_success(&self) -> bool {
+        matches!(self, CompletionOutcome::Success(_))
+    }
+}
+
+/// A setter was called on a configuration that a virtual machine was already created from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrozenConfigError {
+    /// The setter that was called, e.g. `VZVirtioNetworkDeviceConfiguration::set_attachment`.
+    pub setter: &'static str,
+}
+
+impl fmt::Display for FrozenConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} called on a configuration already used by a virtual machine",
+            self.setter
+        )
+    }
+}
+
+impl std::error::Error for FrozenConfigError {}
+
+/// A memory size that is not a multiple of the framework's granularity, 1 MiB, with the valid
+/// sizes on either side of it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AlignmentError {
+    /// In bytes, as passed to the setter.
+    pub requested: usize,
+    /// The nearest valid size below `requested`, which the builder uses instead.
+    pub below: usize,
+    /// The nearest valid size above `requested`.
+    pub above: usize,
+}
+
+impl AlignmentError {
+    /// `None` if `requested` is a multiple of `granularity`.
+    pub(crate) fn check(requested: usize, granularity: usize) -> Option<AlignmentError> {
+        let below = requested - requested % granularity;
+        if below == requested {
+            return None;
+        }
+        Some(AlignmentError {
+            requested,
+            below,
+            above: below.saturating_add(granularity),
+        })
+    }
+}
+
+impl fmt::Display for AlignmentError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "memory size {} is not a multiple of 1 MiB; the nearest valid sizes are {} and {}",
+            self.requested, self.below, self.above
+        )
+    }
+}
+
+impl std::error::Error for AlignmentError {}
+
+/// Why [`validated_build`] refused a configuration.
+///
+/// [`validated_build`]: crate::virtualization::virtual_machine::VZVirtualMachineConfigurationBuilder::validated_build
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BuildError {
+    /// `memory_size` rounded the memory size down.
+    Misaligned(AlignmentError),
+    /// A device array holds more devices than the framework allows on this macOS; the first in
+    /// the order of the [`limits`](crate::limits) table.
+    TooManyDevices(DeviceLimitExceeded),
+}
+
+impl fmt::Display for BuildError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BuildError::Misaligned(e) => e.fmt(f),
+            BuildError::TooManyDevices(e) => e.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for BuildError {}
+
+impl From<AlignmentError> for BuildError {
+    fn from(error: AlignmentError) -> Self {
+        BuildError::Misaligned(error)
+    }
+}
+
+impl From<DeviceLimitExceeded> for BuildError {
+    fn from(error: DeviceLimitExceeded) -> Self {
+        BuildError::TooManyDevices(error)
+    }
+}
+
+/// Deepest chain of underlying errors [`VZErrorCtx`] follows.
+const MAX_UNDERLYING_ERRORS: usize = 8;
+
+/// An error of one of the crate's fallible calls, with the operation it was part of and the
+/// resource it was about.
+///
+/// `Display` renders one line with the error itself, e.g. `failed to attach disk image
+/// '/vm/disk.img' (read_only=false): VZErrorDomain code 3 — ...`. `source()` continues with the
+/// errors the framework reported as causing it (`NSUnderlyingErrorKey`), so error reporters
+/// print the whole chain without repeating a line.
+#[derive(Clone)]
+pub struct VZErrorCtx {
+    operation: &'static str,
+    resource: Option<String>,
+    error: NSError,
+    underlying: Option<Box<UnderlyingError>>,
+}
+
+// `NSError` is immutable, and retaining and releasing it is thread-safe; this lets the error
+// cross threads and convert into `anyhow::Error` and similar boxed errors.
+unsafe impl Send for VZErrorCtx {}
+unsafe impl Sync for VZErrorCtx {}
+unsafe impl Send for UnderlyingError {}
+unsafe impl Sync for UnderlyingError {}
+
+impl VZErrorCtx {
+    pub fn new(operation: &'static str, resource: Option<String>, error: NSError) -> VZErrorCtx {
+        VZErrorCtx {
+            operation,
+            resource,
+            underlying: UnderlyingError::chain(&error, MAX_UNDERLYING_ERRORS),
+            error,
+        }
+    }
+
+    /// What the crate was doing, e.g. `create EFI variable store`.
+    pub fn operation(&self) -> &str {
+        self.operation
+    }
+
+    /// The path, device or port involved, as rendered in the message.
+    pub fn resource(&self) -> Option<&str> {
+        self.resource.as_deref()
+    }
+
+    pub fn ns_error(&self) -> &NSError {
+        &self.error
+    }
+
+    pub fn into_ns_error(self) -> NSError {
+        self.error
+    }
+
+    /// The typed code; `None` for errors from other domains.
+    pub fn code(&self) -> Option<VZErrorCode> {
+        VZError(self.error.clone()).code()
+    }
+}
+
+impl fmt::Display for VZErrorCtx {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "failed to {}", self.operation)?;
+        if let Some(resource) = &self.resource {
+            write!(f, " {}", resource)?;
+        }
+        write!(f, ": {}", self.error)
+    }
+}
+
+impl fmt::Debug for VZErrorCtx {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("VZErrorCtx")
+            .field("operation", &self.operation)
+            .field("resource", &self.resource)
+            .field("error", &self.error)
+            .finish()
+    }
+}
+
+impl std::error::Error for VZErrorCtx {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        self.underlying
+            .as_deref()
+            .map(|e| e as &(dyn std::error::Error + 'static))
+    }
+}
+
+/// An error the framework reported as the cause of another, with its own cause.
+#[derive(Clone)]
+struct UnderlyingError {
+    error: NSError,
+    underlying: Option<Box<UnderlyingError>>,
+}
+
+impl UnderlyingError {
+    /// The causes of `error`, at most `depth` deep.
+    fn chain(error: &NSError, depth: usize) -> Option<Box<UnderlyingError>> {
+        if depth == 0 {
+            return None;
+        }
+        let error = error.underlying_error()?;
+        Some(Box::new(UnderlyingError {
+            underlying: UnderlyingError::chain(&error, depth - 1),
+            error,
+        }))
+    }
+}
+
+impl fmt::Display for UnderlyingError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.error.fmt(f)
+    }
+}
+
+impl fmt::Debug for UnderlyingError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.error.fmt(f)
+    }
+}
+
+impl std::error::Error for UnderlyingError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        self.underlying
+            .as_deref()
+            .map(|e| e as &(dyn std::error::Error + 'static))
+    }
+}
+
+/// Attaches context to the `NSError`s of the crate's fallible calls.
+pub(crate) trait ResultExt<T> {
+    /// `resource` is rendered as given, e.g. `'/vm/disk.img' (read_only=false)`.
+    fn ctx<R: Into<String>>(self, operation: &'static str, resource: R) -> Result<T, VZErrorCtx>;
+
+    /// For operations without a resource worth naming.
+    fn ctx_op(self, operation: &'static str) -> Result<T, VZErrorCtx>;
+}
+
+impl<T> ResultExt<T> for Result<T, NSError> {
+    fn ctx<R: Into<String>>(self, operation: &'static str, resource: R) -> Result<T, VZErrorCtx> {
+        self.map_err(|error| VZErrorCtx::new(operation, Some(resource.into()), error))
+    }
+
+    fn ctx_op(self, operation: &'static str) -> Result<T, VZErrorCtx> {
+        self.map_err(|error| VZErrorCtx::new(operation, None, error))
+    }
+}
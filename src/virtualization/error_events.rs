@@ -0,0 +1,438 @@
+//! error events module
+//!
+//! One stream answering "why did my virtual machine die?": the failures of starts and stops sent
+//! through the safe wrappers of
+//! [`VZVirtualMachine`](crate::virtualization::virtual_machine::VZVirtualMachine), the error the
+//! machine stopped with at runtime, and a terminal [`ErrorEvent::GuestStopped`] once the machine
+//! is down.
+//!
+//! Only the crate's safe wrappers and its own `VZVirtualMachineDelegate` feed the stream. Starts
+//! and stops sent with the raw Block-based methods report nothing here, and neither does a
+//! machine whose delegate was replaced through its raw `id`.
+//!
+//! Each receiver queues up to [`QUEUE_CAPACITY`] events. When a receiver falls behind, its oldest
+//! event is dropped to make room and counted in [`ErrorEventReceiver::dropped`]; pushing never
+//! blocks the virtual machine's queue. The terminal event is always delivered, and always last:
+//! the stream of a receiver ends with it, and events after it go to receivers created later.
+//!
+//! # Examples
+//! ```rust
+//! let events = vm.error_events();
+//! vm.start(|_| {})?;
+//! thread::spawn(move || {
+//!     for event in events {
+//!         match event {
+//!             ErrorEvent::Error(e) => eprintln!("{} error: {}", e.phase, e.error.ns_error()),
+//!             ErrorEvent::GuestStopped { reason, .. } => eprintln!("stopped: {}", reason),
+//!         }
+//!     }
+//! });
+//! ```
+
+use crate::base::{DispatchQueue, Id, NSError, NIL};
+use crate::runtime::{alloc, owned, retained, ForQueue};
+use crate::timeline::{TimelineEventKind, TimelineSlot};
+use crate::virtualization::error::VZError;
+use crate::virtualization::lifecycle::{LifecycleTracker, StopReason, StopSignal};
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::os::raw::c_void;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, Once, Weak};
+use std::time::{Duration, Instant};
+
+use objc::declare::ClassDecl;
+use objc::rc::StrongPtr;
+use objc::runtime::{Class, Object, Sel};
+use objc::{class, msg_send, sel, sel_impl};
+
+const DELEGATE_CLASS: &str = "VirtualizationRsVirtualMachineDelegate";
+const EVENTS_IVAR: &str = "events";
+
+/// Events a receiver may have queued before it starts missing the oldest ones.
+pub const QUEUE_CAPACITY: usize = 64;
+
+/// What the virtual machine was doing when the error happened.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+    /// A start failed.
+    Start,
+    /// The running machine stopped with an error.
+    Runtime,
+    /// A stop failed.
+    Stop,
+}
+
+impl fmt::Display for Phase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.pad(match self {
+            Phase::Start => "start",
+            Phase::Runtime => "runtime",
+            Phase::Stop => "stop",
+        })
+    }
+}
+
+/// An error the virtual machine reported.
+#[derive(Debug, Clone)]
+pub struct VmErrorEvent {
+    /// When the crate received it.
+    pub at: Instant,
+    pub phase: Phase,
+    pub error: VZError,
+}
+
+// The error is a retained `NSError`, which is immutable; receivers hand events to other threads.
+unsafe impl Send for VmErrorEvent {}
+unsafe impl Sync for VmErrorEvent {}
+
+/// What a receiver of [`VZVirtualMachine::error_events`] gets.
+///
+/// [`VZVirtualMachine::error_events`]: crate::virtualization::virtual_machine::VZVirtualMachine::error_events
+#[derive(Debug, Clone)]
+pub enum ErrorEvent {
+    Error(VmErrorEvent),
+    /// The machine is down; the last event of a stream. `clean` is true when the guest shut
+    /// itself down, false when the machine stopped with an error or was stopped with
+    /// `VZVirtualMachine::stop`; `reason` tells which, as
+    /// `VZVirtualMachine::last_stop_reason` does.
+    GuestStopped {
+        at: Instant,
+        clean: bool,
+        reason: StopReason,
+    },
+}
+
+impl ErrorEvent {
+    pub fn at(&self) -> Instant {
+        match self {
+            ErrorEvent::Error(event) => event.at,
+            ErrorEvent::GuestStopped { at, .. } => *at,
+        }
+    }
+
+    pub fn is_terminal(&self) -> bool {
+        matches!(self, ErrorEvent::GuestStopped { .. })
+    }
+}
+
+#[derive(Default)]
+struct Stream {
+    events: VecDeque<ErrorEvent>,
+    dropped: u64,
+    /// Nothing more is pushed: the terminal event was, or the machine is gone.
+    closed: bool,
+}
+
+struct Subscription {
+    stream: Mutex<Stream>,
+    ready: Condvar,
+    /// The virtual machine's queue, which pushes the events and so must not wait for them.
+    queue: DispatchQueue,
+}
+
+impl Subscription {
+    fn lock(&self) -> MutexGuard<'_, Stream> {
+        self.stream.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Queues `event`, dropping the oldest one if the queue is full. Returns whether the stream
+    /// is still open.
+    fn push(&self, event: ErrorEvent) -> bool {
+        let mut stream = self.lock();
+        if stream.closed {
+            return false;
+        }
+        if stream.events.len() == QUEUE_CAPACITY {
+            stream.events.pop_front();
+            stream.dropped += 1;
+        }
+        stream.closed = event.is_terminal();
+        stream.events.push_back(event);
+        let open = !stream.closed;
+        drop(stream);
+        self.ready.notify_all();
+        open
+    }
+
+    fn close(&self) {
+        self.lock().closed = true;
+        self.ready.notify_all();
+    }
+}
+
+/// The receiving end of [`VZVirtualMachine::error_events`]; iterating it yields events until the
+/// stream ends.
+///
+/// [`VZVirtualMachine::error_events`]: crate::virtualization::virtual_machine::VZVirtualMachine::error_events
+pub struct ErrorEventReceiver(Arc<Subscription>);
+
+impl ErrorEventReceiver {
+    /// Blocks until the next event. `None` once the stream ended, after the terminal event or
+    /// because the virtual machine was dropped.
+    ///
+    /// Panics if called from the virtual machine's queue, which would never get to push it.
+    #[track_caller]
+    pub fn recv(&self) -> Option<ErrorEvent> {
+        self.0.queue.assert_not_current("ErrorEventReceiver::recv");
+        let stream = self.0.lock();
+        let mut stream = self
+            .0
+            .ready
+            .wait_while(stream, |s| s.events.is_empty() && !s.closed)
+            .unwrap_or_else(|e| e.into_inner());
+        stream.events.pop_front()
+    }
+
+    /// Like [`recv`](Self::recv), giving up after `timeout`; [`is_finished`](Self::is_finished)
+    /// tells a timeout from the end of the stream.
+    #[track_caller]
+    pub fn recv_timeout(&self, timeout: Duration) -> Option<ErrorEvent> {
+        self.0
+            .queue
+            .assert_not_current("ErrorEventReceiver::recv_timeout");
+        let stream = self.0.lock();
+        let (mut stream, _) = self
+            .0
+            .ready
+            .wait_timeout_while(stream, timeout, |s| s.events.is_empty() && !s.closed)
+            .unwrap_or_else(|e| e.into_inner());
+        stream.events.pop_front()
+    }
+
+    /// The next event if one is queued. Never blocks, so it may be called from any queue.
+    pub fn try_recv(&self) -> Option<ErrorEvent> {
+        self.0.lock().events.pop_front()
+    }
+
+    /// Whether the stream ended and every event was received.
+    pub fn is_finished(&self) -> bool {
+        let stream = self.0.lock();
+        stream.closed && stream.events.is_empty()
+    }
+
+    /// How many events were dropped because this receiver fell behind.
+    pub fn dropped(&self) -> u64 {
+        self.0.lock().dropped
+    }
+}
+
+impl Iterator for ErrorEventReceiver {
+    type Item = ErrorEvent;
+
+    fn next(&mut self) -> Option<ErrorEvent> {
+        self.recv()
+    }
+}
+
+struct Hub {
+    subscribers: Mutex<Vec<Arc<Subscription>>>,
+    queue: DispatchQueue,
+    /// Settles why the machine stopped, from what the delegate and the wrappers report.
+    tracker: Arc<LifecycleTracker>,
+    timeline: Arc<TimelineSlot>,
+    /// Set as the virtual machine's delegate, which the machine only holds weakly; `None` for a
+    /// stream without a framework object.
+    delegate: Option<StrongPtr>,
+}
+
+// The delegate is only retained and released here; the framework messages it on the virtual
+// machine's queue.
+unsafe impl Send for Hub {}
+unsafe impl Sync for Hub {}
+
+impl Hub {
+    fn subscribers(&self) -> MutexGuard<'_, Vec<Arc<Subscription>>> {
+        self.subscribers.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl Drop for Hub {
+    fn drop(&mut self) {
+        for subscription in self.subscribers().drain(..) {
+            subscription.close();
+        }
+    }
+}
+
+/// Pushes events to every receiver of a virtual machine's error events.
+///
+/// The crate's wrappers and delegate hold one each; [`VZVirtualMachine::error_event_sender`]
+/// hands one out so tests can drive synthetic events.
+///
+/// [`VZVirtualMachine::error_event_sender`]: crate::virtualization::virtual_machine::VZVirtualMachine::error_event_sender
+#[derive(Clone)]
+pub struct ErrorEventSender(Arc<Hub>);
+
+impl ErrorEventSender {
+    /// Creates the stream of the virtual machine `vm` and installs the delegate feeding it, on
+    /// `queue`. Stops are settled by `tracker` and recorded in `timeline`.
+    ///
+    /// # Safety
+    /// `vm` must be a valid `VZVirtualMachine` created with `queue`.
+    pub(crate) unsafe fn install(
+        vm: Id,
+        queue: &DispatchQueue,
+        tracker: Arc<LifecycleTracker>,
+        timeline: Arc<TimelineSlot>,
+    ) -> ErrorEventSender {
+        let delegate = owned(msg_send![alloc(delegate_class()), init]);
+        let hub = Arc::new(Hub {
+            subscribers: Mutex::new(Vec::new()),
+            queue: queue.clone(),
+            tracker,
+            timeline,
+            delegate: Some(delegate.clone()),
+        });
+        let weak = Box::new(Arc::downgrade(&hub));
+        (**delegate).set_ivar(EVENTS_IVAR, Box::into_raw(weak) as *mut c_void);
+
+        let objects = ForQueue((retained(vm), delegate));
+        queue.exec_async(move || {
+            let (vm, delegate) = objects.0;
+            let _: () = msg_send![*vm, setDelegate: *delegate];
+        });
+        ErrorEventSender(hub)
+    }
+
+    /// Creates a stream fed only through the sender, for a stand-in of a virtual machine whose
+    /// events run on `queue`.
+    #[cfg(feature = "test-util")]
+    pub(crate) fn detached(
+        queue: &DispatchQueue,
+        tracker: Arc<LifecycleTracker>,
+        timeline: Arc<TimelineSlot>,
+    ) -> ErrorEventSender {
+        ErrorEventSender(Arc::new(Hub {
+            subscribers: Mutex::new(Vec::new()),
+            queue: queue.clone(),
+            tracker,
+            timeline,
+            delegate: None,
+        }))
+    }
+
+    /// Unsets the delegate of `vm` if it is still the one [`install`](Self::install) set, so
+    /// the framework stops messaging it before it is released.
+    ///
+    /// # Safety
+    /// `vm` must be the virtual machine the sender was installed on, and this must run on its
+    /// queue.
+    pub(crate) unsafe fn uninstall(&self, vm: Id) {
+        let delegate: Id = msg_send![vm, delegate];
+        if self.0.delegate.as_ref().map(|d| **d) == Some(delegate) {
+            let _: () = msg_send![vm, setDelegate: NIL];
+        }
+    }
+
+    /// A receiver of the events from now on.
+    pub(crate) fn subscribe(&self) -> ErrorEventReceiver {
+        let subscription = Arc::new(Subscription {
+            stream: Mutex::new(Stream::default()),
+            ready: Condvar::new(),
+            queue: self.0.queue.clone(),
+        });
+        self.0.subscribers().push(subscription.clone());
+        ErrorEventReceiver(subscription)
+    }
+
+    /// Pushes `event` to every open receiver.
+    pub fn send(&self, event: ErrorEvent) {
+        self.0
+            .subscribers()
+            .retain(|subscription| subscription.push(event.clone()));
+    }
+
+    /// Pushes an [`ErrorEvent::Error`] stamped now.
+    pub fn error(&self, phase: Phase, error: VZError) {
+        self.send(ErrorEvent::Error(VmErrorEvent {
+            at: Instant::now(),
+            phase,
+            error,
+        }));
+    }
+
+    /// Pushes the terminal [`ErrorEvent::GuestStopped`] stamped now, ending every open stream.
+    pub fn stopped(&self, reason: StopReason) {
+        self.send(ErrorEvent::GuestStopped {
+            at: Instant::now(),
+            clean: reason.is_clean(),
+            reason,
+        });
+    }
+
+    /// Like [`stopped`](Self::stopped), with [`StopReason::GuestInitiated`] when `clean` and
+    /// [`StopReason::Forced`] otherwise.
+    pub fn guest_stopped(&self, clean: bool) {
+        self.stopped(if clean {
+            StopReason::GuestInitiated
+        } else {
+            StopReason::Forced
+        });
+    }
+
+    /// Reports `signal` to the machine's lifecycle tracker. If it settled why the machine
+    /// stopped, records that in the timeline and pushes the terminal event.
+    pub(crate) fn note_stop(&self, signal: StopSignal) {
+        if let Some(reason) = self.0.tracker.note_stop(signal) {
+            self.0.timeline.record(TimelineEventKind::Stopped {
+                reason: reason.label(),
+            });
+            self.stopped(reason);
+        }
+    }
+}
+
+fn delegate_class() -> &'static Class {
+    static REGISTER: Once = Once::new();
+    REGISTER.call_once(|| {
+        let mut decl = ClassDecl::new(DELEGATE_CLASS, class!(NSObject)).unwrap();
+        decl.add_ivar::<*mut c_void>(EVENTS_IVAR);
+        unsafe {
+            decl.add_method(
+                sel!(guestDidStopVirtualMachine:),
+                guest_did_stop as extern "C" fn(&Object, Sel, Id),
+            );
+            decl.add_method(
+                sel!(virtualMachine:didStopWithError:),
+                did_stop_with_error as extern "C" fn(&Object, Sel, Id, Id),
+            );
+            decl.add_method(sel!(dealloc), dealloc as extern "C" fn(&Object, Sel));
+        }
+        decl.register();
+    });
+    Class::get(DELEGATE_CLASS).unwrap()
+}
+
+/// The stream the delegate feeds, unless the virtual machine is gone.
+fn sender(this: &Object) -> Option<ErrorEventSender> {
+    let hub = unsafe { *this.get_ivar::<*mut c_void>(EVENTS_IVAR) as *const Weak<Hub> };
+    if hub.is_null() {
+        return None;
+    }
+    unsafe { &*hub }.upgrade().map(ErrorEventSender)
+}
+
+extern "C" fn guest_did_stop(this: &Object, _cmd: Sel, _vm: Id) {
+    if let Some(sender) = sender(this) {
+        sender.note_stop(StopSignal::GuestStopped);
+    }
+}
+
+extern "C" fn did_stop_with_error(this: &Object, _cmd: Sel, _vm: Id, error: Id) {
+    if let Some(sender) = sender(this) {
+        let error = VZError(NSError(unsafe { retained(error) }));
+        sender.error(Phase::Runtime, error.clone());
+        sender.note_stop(StopSignal::StoppedWithError(error));
+    }
+}
+
+extern "C" fn dealloc(this: &Object, _cmd: Sel) {
+    unsafe {
+        let hub = *this.get_ivar::<*mut c_void>(EVENTS_IVAR) as *mut Weak<Hub>;
+        if !hub.is_null() {
+            drop(Box::from_raw(hub));
+        }
+        let _: () = msg_send![super(this, class!(NSObject)), dealloc];
+    }
+}
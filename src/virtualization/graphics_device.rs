@@ -1,34 +1,132 @@
 //! graphics device module
+//!
+//! A Virtio graphics device has one scanout, whose size is fixed in the configuration: the
+//! framework declares no list of modes for the guest to pick from. From macOS 14 on, the display
+//! of a running machine is resized with [`VZGraphicsDisplay::resize`], which tells the guest of
+//! the new size; before, it fails with `VZErrorCode::NotSupported` and the guest has to change its
+//! own mode, e.g. with `xrandr`. [`VirtioDisplaySizing`] converts the
+//! size of the window showing the display into the pixels of either.
+//!
+//! # Examples
+//! ```rust
+//! let (width, height, _) = VirtioDisplaySizing::for_window((1280.0, 720.0), 2.0);
+//! let scanout = VZVirtioGraphicsScanoutConfiguration::new(width, height);
+//! // ... build the configuration with the device and start the VM
+//! for display in vm.graphics_devices().iter().flat_map(|device| device.displays()) {
+//!     if let Err(e) = display.resize(1920, 1080) {
+//!         eprintln!("{}", e);
+//!     }
+//! }
+//! ```
 
-use crate::base::{Id, NSArray, NSInteger};
+use crate::base::{DispatchQueue, Id, NSArray, NSError, NSInteger, NSUInteger};
+use crate::features::{self, ClassLookup, ClassNotAvailable, UnsupportedOnThisHost};
+use crate::runtime::{alloc, call_with_error, from_objc_bool, owned, retained, vz_class};
+use crate::virtualization::device::VZDeviceConfiguration;
+use crate::virtualization::error::{ResultExt, VZErrorCtx, VZ_ERROR_DOMAIN};
+
+use std::any::Any;
 
 use objc::rc::StrongPtr;
-use objc::{class, msg_send, sel, sel_impl};
+use objc::runtime::BOOL;
+use objc::{msg_send, sel, sel_impl};
 
-/// The base class for a graphics device configuration.
-pub trait VZGraphicsDeviceConfiguration {
-    fn id(&self) -> Id;
+static VIRTIO_GRAPHICS_SCANOUT: ClassLookup =
+    ClassLookup::new("VZVirtioGraphicsScanoutConfiguration", "macOS 13");
+static VIRTIO_GRAPHICS_DEVICE: ClassLookup =
+    ClassLookup::new("VZVirtioGraphicsDeviceConfiguration", "macOS 13");
+
+/// `VZErrorNotSupported`.
+const NOT_SUPPORTED: NSInteger = 10;
+
+/// `CGSize`, passed and returned by value.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+struct CGSize {
+    width: f64,
+    height: f64,
+}
+
+/// Converts the geometry of an AppKit window into the pixels of the display it shows, for
+/// [`VZVirtioGraphicsScanoutConfiguration`], [`VZMacGraphicsDisplayConfiguration::new_with`] and
+/// [`VZGraphicsDisplay::resize`].
+///
+/// AppKit sizes windows in points and draws them at the window's `backingScaleFactor` pixels per
+/// point, 2 on Retina screens. A display sized in points is drawn at half its resolution there,
+/// and one sized with the main screen's factor is wrong once the window moves to another screen,
+/// so both go through here.
+pub struct VirtioDisplaySizing;
+
+impl VirtioDisplaySizing {
+    /// Points per inch in AppKit's coordinate model, as `NSDeviceResolution` reports at a backing
+    /// scale of 1.
+    pub const POINTS_PER_INCH: f64 = 72.0;
+
+    /// The width and height in pixels, and the pixels per inch, of a display filling `points`,
+    /// the width and height of the view showing it, e.g. the window's content size, drawn at
+    /// `backing_scale`, the window's `backingScaleFactor`.
+    ///
+    /// Sizes are rounded to the nearest pixel and are at least one pixel, so a collapsed view
+    /// still gives a valid display. The pixels per inch are
+    /// [`POINTS_PER_INCH`](Self::POINTS_PER_INCH) times the scale, so a guest drawing at that
+    /// density matches the host's.
+    ///
+    /// # Panics
+    /// If `backing_scale` is not positive and finite, or a size is negative or not finite.
+    pub fn for_window(points: (f64, f64), backing_scale: f64) -> (NSInteger, NSInteger, NSInteger) {
+        assert!(
+            backing_scale.is_finite() && backing_scale > 0.0,
+            "backing scale {} is not a positive number",
+            backing_scale
+        );
+        let (width, height) = points;
+        assert!(
+            width.is_finite() && width >= 0.0 && height.is_finite() && height >= 0.0,
+            "window size {}x{} is not a size in points",
+            width,
+            height
+        );
+        let pixels = |points: f64| ((points * backing_scale).round() as NSInteger).max(1);
+        (
+            pixels(width),
+            pixels(height),
+            ((Self::POINTS_PER_INCH * backing_scale).round() as NSInteger).max(1),
+        )
+    }
 }
 
+/// The base class for a graphics device configuration.
+pub trait VZGraphicsDeviceConfiguration: VZDeviceConfiguration {}
+
+impl<T: VZGraphicsDeviceConfiguration + ?Sized> VZGraphicsDeviceConfiguration for Box<T> {}
+
 /// The configuration for a Mac graphics device.
 pub struct VZMacGraphicsDisplayConfiguration(StrongPtr);
 
 impl VZMacGraphicsDisplayConfiguration {
     /// Create a display configuration suitable for showing on the specified screen.
     ///
+    /// `screen` is borrowed: it is only read during the call and neither retained nor released,
+    /// so it may come from another Objective-C binding crate.
+    ///
     /// # Safety
-    /// The type `NSSize` must be a valid struct type of `NSSize` [^1].
+    /// `screen` must be a valid, non-nil `NSScreen`. The type `NSSize` must be a valid struct type
+    /// of `NSSize` [^1].
     ///
     /// [^1]: https://developer.apple.com/documentation/foundation/nssize?language=objc
-    pub unsafe fn new_for<NSSize>(screen: Id, size_in_points: NSSize) -> Self {
-        let i: Id = unsafe { msg_send![class!(VZMacGraphicsDisplayConfiguration), alloc] };
-        Self(unsafe {
-            StrongPtr::new(msg_send![
+    pub unsafe fn new_for<NSSize>(
+        screen: Id,
+        size_in_points: NSSize,
+    ) -> Result<Self, UnsupportedOnThisHost> {
+        features::require("VZMacGraphicsDisplayConfiguration")?;
+        let i = unsafe { alloc(vz_class!(VZMacGraphicsDisplayConfiguration)) };
+        Ok(Self(unsafe {
+            owned(msg_send![
                 i,
                 initWithScreen: screen
                 sizeInPoints: size_in_points
             ])
-        })
+        }))
     }
 
     /// Create a display configuration with the specified pixel dimensions and pixel density.
@@ -36,16 +134,21 @@ impl VZMacGraphicsDisplayConfiguration {
         width_in_pixels: NSInteger,
         height_in_pixels: NSInteger,
         pixels_per_inch: NSInteger,
-    ) -> Self {
-        let i: Id = unsafe { msg_send![class!(VZMacGraphicsDisplayConfiguration), alloc] };
-        Self(unsafe {
-            StrongPtr::new(msg_send![
+    ) -> Result<Self, UnsupportedOnThisHost> {
+        features::require("VZMacGraphicsDisplayConfiguration")?;
+        let i = unsafe { alloc(vz_class!(VZMacGraphicsDisplayConfiguration)) };
+        Ok(Self(unsafe {
+            owned(msg_send![
                 i,
                 initWithWidthInPixels: width_in_pixels
                 heightInPixels: height_in_pixels
                 pixelsPerInch: pixels_per_inch
             ])
-        })
+        }))
+    }
+
+    pub fn id(&self) -> Id {
+        *self.0
     }
 }
 
@@ -54,38 +157,62 @@ pub struct VZMacGraphicsDeviceConfiguration(StrongPtr);
 
 impl VZMacGraphicsDeviceConfiguration {
     /// Creates a new Mac graphics device configuration.
-    pub fn new(displays: Vec<VZMacGraphicsDisplayConfiguration>) -> Self {
+    pub fn new(
+        displays: Vec<VZMacGraphicsDisplayConfiguration>,
+    ) -> Result<Self, UnsupportedOnThisHost> {
+        features::require("VZMacGraphicsDeviceConfiguration")?;
         let displays = displays.iter().map(|x| *x.0).collect();
         let arr: NSArray<VZMacGraphicsDisplayConfiguration> = NSArray::array_with_objects(displays);
         unsafe {
-            let p = StrongPtr::new(msg_send![class!(VZMacGraphicsDeviceConfiguration), new]);
+            let p = owned(msg_send![vz_class!(VZMacGraphicsDeviceConfiguration), new]);
             let _: () = msg_send![*p, setDisplays: *arr.p];
-            Self(p)
+            Ok(Self(p))
         }
     }
 }
 
-impl VZGraphicsDeviceConfiguration for VZMacGraphicsDeviceConfiguration {
+impl VZDeviceConfiguration for VZMacGraphicsDeviceConfiguration {
     fn id(&self) -> Id {
         *self.0
     }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
 }
 
+impl VZGraphicsDeviceConfiguration for VZMacGraphicsDeviceConfiguration {}
+
 /// The configuration for a Virtio graphics device that configures the dimensions of the graphics
 /// device for a Linux VM.
 pub struct VZVirtioGraphicsScanoutConfiguration(StrongPtr);
 
 impl VZVirtioGraphicsScanoutConfiguration {
     /// Creates a Virtio graphics device with the specified dimensions.
+    ///
+    /// # Panics
+    /// Before macOS 13; see [`try_new`](Self::try_new).
+    #[track_caller]
     pub fn new(width_in_pixels: NSInteger, height_in_pixels: NSInteger) -> Self {
-        let i: Id = unsafe { msg_send![class!(VZVirtioGraphicsScanoutConfiguration), alloc] };
-        Self(unsafe {
-            StrongPtr::new(msg_send![
+        Self::try_new(width_in_pixels, height_in_pixels).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    pub fn try_new(
+        width_in_pixels: NSInteger,
+        height_in_pixels: NSInteger,
+    ) -> Result<Self, ClassNotAvailable> {
+        let i = unsafe { alloc(VIRTIO_GRAPHICS_SCANOUT.get()?) };
+        Ok(Self(unsafe {
+            owned(msg_send![
                 i,
                 initWithWidthInPixels: width_in_pixels
                 heightInPixels: height_in_pixels
             ])
-        })
+        }))
+    }
+
+    pub fn id(&self) -> Id {
+        *self.0
     }
 }
 
@@ -93,20 +220,161 @@ impl VZVirtioGraphicsScanoutConfiguration {
 pub struct VZVirtioGraphicsDeviceConfiguration(StrongPtr);
 
 impl VZVirtioGraphicsDeviceConfiguration {
-    /// Creates a new Virtio graphics device.
+    /// The scanouts the framework supports on a device. A scanout is a display, not a mode: the
+    /// modes are the guest's to choose, see the [module documentation](self).
+    pub const MAX_SCANOUTS: usize = 1;
+
+    /// Creates a new Virtio graphics device. Validation fails with more than
+    /// [`MAX_SCANOUTS`](Self::MAX_SCANOUTS) scanouts.
+    ///
+    /// # Panics
+    /// Before macOS 13; see [`try_new`](Self::try_new).
+    #[track_caller]
     pub fn new(scanouts: Vec<VZVirtioGraphicsScanoutConfiguration>) -> Self {
+        Self::try_new(scanouts).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    pub fn try_new(
+        scanouts: Vec<VZVirtioGraphicsScanoutConfiguration>,
+    ) -> Result<Self, ClassNotAvailable> {
+        let class = VIRTIO_GRAPHICS_DEVICE.get()?;
         let scanouts = scanouts.iter().map(|x| *x.0).collect();
         let arr: NSArray<VZMacGraphicsDisplayConfiguration> = NSArray::array_with_objects(scanouts);
         unsafe {
-            let p = StrongPtr::new(msg_send![class!(VZVirtioGraphicsDeviceConfiguration), new]);
+            let p = owned(msg_send![class, new]);
             let _: () = msg_send![*p, setScanouts: *arr.p];
-            Self(p)
+            Ok(Self(p))
         }
     }
 }
 
-impl VZGraphicsDeviceConfiguration for VZVirtioGraphicsDeviceConfiguration {
+impl VZDeviceConfiguration for VZVirtioGraphicsDeviceConfiguration {
     fn id(&self) -> Id {
         *self.0
     }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+impl VZGraphicsDeviceConfiguration for VZVirtioGraphicsDeviceConfiguration {}
+
+/// A graphics device of a running virtual machine, obtained from
+/// [`VZVirtualMachine::graphics_devices`](crate::virtualization::virtual_machine::VZVirtualMachine::graphics_devices).
+/// macOS 14 and later.
+pub struct VZGraphicsDevice {
+    p: StrongPtr,
+    queue: DispatchQueue,
+}
+
+impl VZGraphicsDevice {
+    pub(crate) fn from_raw(p: StrongPtr, queue: DispatchQueue) -> VZGraphicsDevice {
+        VZGraphicsDevice { p, queue }
+    }
+
+    /// The displays of the device, one per scanout or Mac display configured.
+    ///
+    /// The property is read on the VM's queue, directly if called there.
+    pub fn displays(&self) -> Vec<VZGraphicsDisplay> {
+        let p = *self.p;
+        let queue = self.queue.clone();
+        on_queue(&self.queue, move || unsafe {
+            let displays: Id = msg_send![p, displays];
+            let count: NSUInteger = msg_send![displays, count];
+            (0..count)
+                .map(|i| {
+                    let display: Id = msg_send![displays, objectAtIndex: i];
+                    VZGraphicsDisplay {
+                        p: retained(display),
+                        queue: queue.clone(),
+                    }
+                })
+                .collect()
+        })
+    }
+
+    pub fn id(&self) -> Id {
+        *self.p
+    }
+}
+
+/// A display of a running virtual machine's graphics device. macOS 14 and later.
+pub struct VZGraphicsDisplay {
+    p: StrongPtr,
+    queue: DispatchQueue,
+}
+
+impl VZGraphicsDisplay {
+    /// The width and height of the display in pixels.
+    ///
+    /// The property is read on the VM's queue, directly if called there.
+    pub fn size_in_pixels(&self) -> (NSInteger, NSInteger) {
+        let p = *self.p;
+        let size: CGSize = on_queue(&self.queue, move || unsafe { msg_send![p, sizeInPixels] });
+        (size.width as NSInteger, size.height as NSInteger)
+    }
+
+    /// Whether the framework resizes displays at runtime, macOS 14 and later.
+    pub fn can_resize(&self) -> bool {
+        let supported: BOOL = unsafe {
+            msg_send![*self.p, respondsToSelector: sel!(reconfigureWithSizeInPixels:error:)]
+        };
+        from_objc_bool(supported)
+    }
+
+    /// Resizes the display to `width` by `height` pixels, telling the guest of the new size. Size
+    /// the display of a window with [`VirtioDisplaySizing::for_window`].
+    ///
+    /// The change is made on the VM's queue, directly if called there, e.g. from AppKit's
+    /// notifications for a machine of
+    /// [`VZVirtualMachine::new_without_queue`](crate::virtualization::virtual_machine::VZVirtualMachine::new_without_queue).
+    /// Fails with `VZErrorCode::NotSupported` where [`can_resize`](Self::can_resize) is false,
+    /// so callers can leave the resizing to the guest, e.g. `xrandr`.
+    ///
+    /// # Panics
+    /// If `width` or `height` is not positive.
+    pub fn resize(&self, width: NSInteger, height: NSInteger) -> Result<(), VZErrorCtx> {
+        assert!(
+            width > 0 && height > 0,
+            "display size {}x{} is not positive",
+            width,
+            height
+        );
+        let size = format!("{}x{}", width, height);
+        if !self.can_resize() {
+            return Err(VZErrorCtx::new(
+                "resize display to",
+                Some(size),
+                NSError::error_with_domain(VZ_ERROR_DOMAIN, NOT_SUPPORTED, None),
+            ));
+        }
+        let p = *self.p;
+        let size_in_pixels = CGSize {
+            width: width as f64,
+            height: height as f64,
+        };
+        on_queue(&self.queue, move || unsafe {
+            call_with_error(|error| {
+                let ok: BOOL =
+                    msg_send![p, reconfigureWithSizeInPixels: size_in_pixels error: error];
+                from_objc_bool(ok)
+            })
+        })
+        .map(|_| ())
+        .ctx("resize display to", size)
+    }
+
+    pub fn id(&self) -> Id {
+        *self.p
+    }
+}
+
+/// Runs `f` on `queue`, directly if already there.
+fn on_queue<R, F: FnOnce() -> R>(queue: &DispatchQueue, f: F) -> R {
+    if queue.is_current() {
+        f()
+    } else {
+        queue.exec_sync(f)
+    }
 }
@@ -0,0 +1,296 @@
+//! guest disks module
+//!
+//! Predicts the names a Linux guest gives the storage devices of a configuration, e.g. that the
+//! system disk will be `/dev/vda` and the cloud-init seed `/dev/vdb`, before the machine boots.
+//!
+//! The framework puts storage devices on the guest's PCI bus in the order of the array passed to
+//! `storage_devices`, and Linux names devices of each class by its own rules:
+//!
+//! | class | names | predictable |
+//! |---|---|---|
+//! | virtio-blk | `/dev/vda`, `/dev/vdb`, … in array order | always |
+//! | NVMe | `/dev/nvme0n1`, … | only a single controller |
+//! | USB mass storage | `/dev/sda`, … | only a single device |
+//!
+//! Each class is counted on its own, so mixing classes does not shift names: `simplevm`'s
+//! `--disk` images are virtio-blk devices and map to `/dev/vda`, `/dev/vdb`, … in the order given,
+//! even with a USB stick in between.
+//!
+//! # Examples
+//! ```rust
+//! let devices = vec![root_disk, seed_disk];
+//! let map = GuestDiskMap::new(&devices);
+//! if let GuestPath::Predicted(path) = &map.disks()[1].path {
+//!     user_data.push_str(&format!("seed_device: {}\n", path));
+//! }
+//! let conf = VZVirtualMachineConfigurationBuilder::new()
+//!     .storage_devices(devices)
+//!     .build();
+//! ```
+
+use crate::base::{Id, NSString};
+use crate::runtime::{class_name, from_objc_bool, retained};
+use crate::virtualization::storage_device::VZStorageDeviceConfiguration;
+
+use std::fmt;
+
+use objc::runtime::BOOL;
+use objc::{msg_send, sel, sel_impl};
+
+/// Kind of storage device, which decides how the guest names it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub enum DeviceClass {
+    VirtioBlock,
+    Nvme,
+    UsbMassStorage,
+    /// A device this crate has no naming rule for, with its class name.
+    Other(String),
+}
+
+impl DeviceClass {
+    /// The class of a framework storage device configuration class.
+    pub fn from_class_name(name: &str) -> DeviceClass {
+        match name {
+            "VZVirtioBlockDeviceConfiguration" => DeviceClass::VirtioBlock,
+            "VZNVMExpressControllerDeviceConfiguration" => DeviceClass::Nvme,
+            "VZUSBMassStorageDeviceConfiguration" => DeviceClass::UsbMassStorage,
+            other => DeviceClass::Other(other.to_string()),
+        }
+    }
+}
+
+impl fmt::Display for DeviceClass {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DeviceClass::VirtioBlock => f.write_str("virtio-blk"),
+            DeviceClass::Nvme => f.write_str("nvme"),
+            DeviceClass::UsbMassStorage => f.write_str("usb-mass-storage"),
+            DeviceClass::Other(name) => f.write_str(name),
+        }
+    }
+}
+
+/// What backs a storage device on the host.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub enum AttachmentSource {
+    /// Path of a disk image.
+    File(String),
+    /// Any other URL, e.g. of a network block device.
+    Url(String),
+    /// A host block device or other open file.
+    FileHandle,
+    Unknown,
+}
+
+impl fmt::Display for AttachmentSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AttachmentSource::File(path) => f.write_str(path),
+            AttachmentSource::Url(url) => f.write_str(url),
+            AttachmentSource::FileHandle => f.write_str("(file handle)"),
+            AttachmentSource::Unknown => f.write_str("(unknown)"),
+        }
+    }
+}
+
+/// The guest's name for a device, if it can be known before boot.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub enum GuestPath {
+    Predicted(String),
+    /// The name depends on probe timing or on rules this crate does not know; the reason says
+    /// which. Use a filesystem label or UUID instead.
+    Unpredictable(&'static str),
+}
+
+/// One storage device, in array order.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct GuestDisk {
+    /// Position in the array passed to `storage_devices`.
+    pub index: usize,
+    pub class: DeviceClass,
+    pub path: GuestPath,
+    pub source: AttachmentSource,
+}
+
+/// How the guest names the devices of a class, given their position among devices of the class.
+enum Naming {
+    /// `<prefix>a` … `<prefix>z`, then `<prefix>aa`, ….
+    Letters(&'static str),
+    /// `/dev/nvme<N>n1`: one namespace per controller.
+    NvmeController,
+}
+
+struct NamingRule {
+    class: DeviceClass,
+    naming: Naming,
+    /// Whether devices of the class are numbered in bus order. If not, only a lone device of the
+    /// class has a predictable name.
+    ordered: bool,
+}
+
+/// Linux naming rules, one per class.
+const RULES: &[NamingRule] = &[
+    // drivers/block/virtio_blk.c: `virtblk_probe` takes the next index from `vd_index_ida` and
+    // names the disk with `virtblk_name_format("vd", index, ...)`. Probing is synchronous, in PCI
+    // bus order.
+    NamingRule {
+        class: DeviceClass::VirtioBlock,
+        naming: Naming::Letters("/dev/vd"),
+        ordered: true,
+    },
+    // drivers/nvme/host/core.c: controller instances come from `nvme_instance_ida` and
+    // namespaces are named `nvme%dn%d`. drivers/nvme/host/pci.c sets `PROBE_PREFER_ASYNCHRONOUS`,
+    // so controllers may take instances out of bus order.
+    NamingRule {
+        class: DeviceClass::Nvme,
+        naming: Naming::NvmeController,
+        ordered: false,
+    },
+    // drivers/usb/storage/usb.c scans each device from delayed work after `delay_use`, and
+    // drivers/scsi/sd.c names disks `sd%s` from `sd_index_ida` in the order scans finish.
+    NamingRule {
+        class: DeviceClass::UsbMassStorage,
+        naming: Naming::Letters("/dev/sd"),
+        ordered: false,
+    },
+];
+
+/// Predicted guest names of the storage devices of a configuration.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct GuestDiskMap {
+    disks: Vec<GuestDisk>,
+}
+
+impl GuestDiskMap {
+    /// Predicts names for `devices`, the array about to be passed to `storage_devices`.
+    pub fn new<T: VZStorageDeviceConfiguration>(devices: &[T]) -> GuestDiskMap {
+        GuestDiskMap::predict(devices.iter().map(|device| unsafe {
+            let id = device.id();
+            (
+                DeviceClass::from_class_name(&class_name(id)),
+                attachment_source(id),
+            )
+        }))
+    }
+
+    /// Predicts names for devices of the given classes, in array order.
+    pub fn predict<I>(devices: I) -> GuestDiskMap
+    where
+        I: IntoIterator<Item = (DeviceClass, AttachmentSource)>,
+    {
+        let devices: Vec<(DeviceClass, AttachmentSource)> = devices.into_iter().collect();
+        let disks = devices
+            .iter()
+            .enumerate()
+            .map(|(index, (class, source))| {
+                let position = devices[..index].iter().filter(|(c, _)| c == class).count();
+                let count = devices.iter().filter(|(c, _)| c == class).count();
+                GuestDisk {
+                    index,
+                    class: class.clone(),
+                    path: guest_path(class, position, count),
+                    source: source.clone(),
+                }
+            })
+            .collect();
+        GuestDiskMap { disks }
+    }
+
+    pub fn disks(&self) -> &[GuestDisk] {
+        &self.disks
+    }
+
+    /// The predicted name of the device at `index` in the array.
+    pub fn path_of(&self, index: usize) -> Option<&str> {
+        match &self.disks.get(index)?.path {
+            GuestPath::Predicted(path) => Some(path),
+            GuestPath::Unpredictable(_) => None,
+        }
+    }
+
+    pub fn into_vec(self) -> Vec<GuestDisk> {
+        self.disks
+    }
+}
+
+/// One line per device: index, class, guest path and source, separated by spaces.
+impl fmt::Display for GuestDiskMap {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for disk in &self.disks {
+            let path = match &disk.path {
+                GuestPath::Predicted(path) => path.as_str(),
+                GuestPath::Unpredictable(_) => "unpredictable",
+            };
+            writeln!(f, "{} {} {} {}", disk.index, disk.class, path, disk.source)?;
+        }
+        Ok(())
+    }
+}
+
+/// The name of the device at `position` among the `count` devices of `class`.
+fn guest_path(class: &DeviceClass, position: usize, count: usize) -> GuestPath {
+    let rule = match RULES.iter().find(|rule| &rule.class == class) {
+        Some(rule) => rule,
+        None => return GuestPath::Unpredictable("no naming rule for this device class"),
+    };
+    if !rule.ordered && count > 1 {
+        return GuestPath::Unpredictable("devices of this class are probed concurrently");
+    }
+    GuestPath::Predicted(match rule.naming {
+        Naming::Letters(prefix) => format!("{}{}", prefix, disk_letters(position)),
+        Naming::NvmeController => format!("/dev/nvme{}n1", position),
+    })
+}
+
+/// `a` … `z`, `aa` … `zz`, `aaa` …, as `sd_format_disk_name` and `virtblk_name_format` count.
+fn disk_letters(mut index: usize) -> String {
+    let mut letters = Vec::new();
+    loop {
+        letters.push(b'a' + (index % 26) as u8);
+        if index < 26 {
+            break;
+        }
+        index = index / 26 - 1;
+    }
+    letters.reverse();
+    String::from_utf8(letters).unwrap()
+}
+
+/// # Safety
+/// `device` must be a valid storage device configuration.
+unsafe fn attachment_source(device: Id) -> AttachmentSource {
+    let attachment: Id = msg_send![device, attachment];
+    if attachment.is_null() {
+        return AttachmentSource::Unknown;
+    }
+    let has_url: BOOL = msg_send![attachment, respondsToSelector: sel!(URL)];
+    if from_objc_bool(has_url) {
+        let url: Id = msg_send![attachment, URL];
+        if url.is_null() {
+            return AttachmentSource::Unknown;
+        }
+        let is_file: BOOL = msg_send![url, isFileURL];
+        let string: Id = if from_objc_bool(is_file) {
+            msg_send![url, path]
+        } else {
+            msg_send![url, absoluteString]
+        };
+        let string = NSString(retained(string)).as_str().to_string();
+        return if from_objc_bool(is_file) {
+            AttachmentSource::File(string)
+        } else {
+            AttachmentSource::Url(string)
+        };
+    }
+    let has_file_handle: BOOL = msg_send![attachment, respondsToSelector: sel!(fileHandle)];
+    if from_objc_bool(has_file_handle) {
+        AttachmentSource::FileHandle
+    } else {
+        AttachmentSource::Unknown
+    }
+}
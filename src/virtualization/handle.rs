@@ -0,0 +1,219 @@
+//! handle module
+//!
+//! The lifecycle surface of a virtual machine that the crate's supervision modules drive, as a
+//! trait: [`VirtualMachineHandle`]. The [`registry`](crate::registry),
+//! [`respawn`](crate::respawn), [`teardown`](crate::teardown) and
+//! [`reconcile`](crate::reconcile) modules take any handle, so code built on them, or written
+//! against the trait itself, runs against a `FakeVm` in unit tests; see `fake_vm` (feature
+//! `test-util`).
+//!
+//! [`VZVirtualMachine`] implements the trait with its inherent methods, which keep their names and
+//! signatures: code using a concrete machine does not import the trait.
+//!
+//! # Examples
+//! ```rust
+//! /// Stops `vm` gracefully, forcing it down if the guest refuses.
+//! fn stop_gracefully<V: VirtualMachineHandle>(vm: &V) {
+//!     let target = vm.clone();
+//!     vm.queue().exec_async(move || {
+//!         if !matches!(unsafe { target.request_stop_with_error() }, Ok(true)) {
+//!             target.stop_or_join(|_| {});
+//!         }
+//!     });
+//! }
+//! ```
+
+use crate::base::DispatchQueue;
+use crate::kvo::KvoGuard;
+use crate::reconcile::ObservedDevices;
+use crate::virtualization::error::{CompletionOutcome, VZErrorCtx};
+use crate::virtualization::error_events::ErrorEventReceiver;
+use crate::virtualization::lifecycle::{Lifecycle, LifecycleError, StopReason};
+use crate::virtualization::virtual_machine::{
+    VZVirtualMachine, VZVirtualMachineState, VmId, WeakVirtualMachine,
+};
+
+use std::fmt;
+
+/// A virtual machine as the supervision modules see it. Clones are handles to the same machine.
+///
+/// The methods are those of [`VZVirtualMachine`], with the same contracts: completion handlers
+/// run on the machine's queue, `state` and `request_stop_with_error` must be called there, and
+/// starts and stops go through the lifecycle tracking of
+/// [`crate::virtualization::lifecycle`].
+pub trait VirtualMachineHandle: Clone + fmt::Debug + Send + Sync + 'static {
+    /// What [`on_first_transition_to`](Self::on_first_transition_to) returns; dropping it
+    /// abandons the observation.
+    type Observation: Send + Sync;
+
+    /// What [`downgrade`](Self::downgrade) returns.
+    type Weak: WeakHandle<Handle = Self>;
+
+    fn vm_id(&self) -> VmId;
+
+    /// A reference to the machine that does not keep it alive.
+    fn downgrade(&self) -> Self::Weak;
+
+    fn label(&self) -> Option<&str>;
+
+    /// `vm-3 (web)` for a labeled machine, `vm-3` otherwise.
+    fn display_name(&self) -> String {
+        match self.label() {
+            Some(label) => format!("{} ({})", self.vm_id(), label),
+            None => self.vm_id().to_string(),
+        }
+    }
+
+    /// The queue the machine runs its completion handlers and observations on.
+    fn queue(&self) -> &DispatchQueue;
+
+    fn start<F: FnOnce(CompletionOutcome) + Send + 'static>(
+        &self,
+        completion_handler: F,
+    ) -> Result<(), LifecycleError>;
+
+    fn start_or_join<F: FnOnce(CompletionOutcome) + Send + 'static>(
+        &self,
+        completion_handler: F,
+    ) -> Result<(), LifecycleError>;
+
+    fn stop<F: FnOnce(CompletionOutcome) + Send + 'static>(
+        &self,
+        completion_handler: F,
+    ) -> Result<(), LifecycleError>;
+
+    fn stop_or_join<F: FnOnce(CompletionOutcome) + Send + 'static>(&self, completion_handler: F);
+
+    fn pause<F: FnOnce(CompletionOutcome) + Send + 'static>(&self, completion_handler: F);
+
+    fn resume<F: FnOnce(CompletionOutcome) + Send + 'static>(&self, completion_handler: F);
+
+    /// # Safety
+    /// Must be called on the machine's queue.
+    unsafe fn request_stop_with_error(&self) -> Result<bool, VZErrorCtx>;
+
+    /// # Safety
+    /// Must be called on the machine's queue.
+    unsafe fn state(&self) -> VZVirtualMachineState;
+
+    fn lifecycle(&self) -> Lifecycle;
+
+    fn last_stop_reason(&self) -> Option<StopReason>;
+
+    fn error_events(&self) -> ErrorEventReceiver;
+
+    fn on_first_transition_to<F>(&self, state: VZVirtualMachineState, f: F) -> Self::Observation
+    where
+        F: FnOnce() + Send + 'static;
+
+    /// The devices the machine has, for [`ReconcileReport::compare`].
+    ///
+    /// [`ReconcileReport::compare`]: crate::reconcile::ReconcileReport::compare
+    fn observed_devices(&self) -> ObservedDevices;
+}
+
+/// A reference to a machine that does not keep it alive, from
+/// [`VirtualMachineHandle::downgrade`].
+pub trait WeakHandle: Clone + fmt::Debug + Send + Sync + 'static {
+    type Handle: VirtualMachineHandle;
+
+    /// The machine, unless its last handle was dropped.
+    fn upgrade(&self) -> Option<Self::Handle>;
+}
+
+impl VirtualMachineHandle for VZVirtualMachine {
+    type Observation = KvoGuard;
+    type Weak = WeakVirtualMachine;
+
+    fn vm_id(&self) -> VmId {
+        VZVirtualMachine::vm_id(self)
+    }
+
+    fn downgrade(&self) -> WeakVirtualMachine {
+        VZVirtualMachine::downgrade(self)
+    }
+
+    fn label(&self) -> Option<&str> {
+        VZVirtualMachine::label(self)
+    }
+
+    fn display_name(&self) -> String {
+        VZVirtualMachine::display_name(self)
+    }
+
+    fn queue(&self) -> &DispatchQueue {
+        VZVirtualMachine::queue(self)
+    }
+
+    fn start<F: FnOnce(CompletionOutcome) + Send + 'static>(
+        &self,
+        completion_handler: F,
+    ) -> Result<(), LifecycleError> {
+        VZVirtualMachine::start(self, completion_handler)
+    }
+
+    fn start_or_join<F: FnOnce(CompletionOutcome) + Send + 'static>(
+        &self,
+        completion_handler: F,
+    ) -> Result<(), LifecycleError> {
+        VZVirtualMachine::start_or_join(self, completion_handler)
+    }
+
+    fn stop<F: FnOnce(CompletionOutcome) + Send + 'static>(
+        &self,
+        completion_handler: F,
+    ) -> Result<(), LifecycleError> {
+        VZVirtualMachine::stop(self, completion_handler)
+    }
+
+    fn stop_or_join<F: FnOnce(CompletionOutcome) + Send + 'static>(&self, completion_handler: F) {
+        VZVirtualMachine::stop_or_join(self, completion_handler)
+    }
+
+    fn pause<F: FnOnce(CompletionOutcome) + Send + 'static>(&self, completion_handler: F) {
+        VZVirtualMachine::pause(self, completion_handler)
+    }
+
+    fn resume<F: FnOnce(CompletionOutcome) + Send + 'static>(&self, completion_handler: F) {
+        VZVirtualMachine::resume(self, completion_handler)
+    }
+
+    unsafe fn request_stop_with_error(&self) -> Result<bool, VZErrorCtx> {
+        VZVirtualMachine::request_stop_with_error(self)
+    }
+
+    unsafe fn state(&self) -> VZVirtualMachineState {
+        VZVirtualMachine::state(self)
+    }
+
+    fn lifecycle(&self) -> Lifecycle {
+        VZVirtualMachine::lifecycle(self)
+    }
+
+    fn last_stop_reason(&self) -> Option<StopReason> {
+        VZVirtualMachine::last_stop_reason(self)
+    }
+
+    fn error_events(&self) -> ErrorEventReceiver {
+        VZVirtualMachine::error_events(self)
+    }
+
+    fn on_first_transition_to<F>(&self, state: VZVirtualMachineState, f: F) -> KvoGuard
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        VZVirtualMachine::on_first_transition_to(self, state, f)
+    }
+
+    fn observed_devices(&self) -> ObservedDevices {
+        ObservedDevices::read(self)
+    }
+}
+
+impl WeakHandle for WeakVirtualMachine {
+    type Handle = VZVirtualMachine;
+
+    fn upgrade(&self) -> Option<VZVirtualMachine> {
+        WeakVirtualMachine::upgrade(self)
+    }
+}
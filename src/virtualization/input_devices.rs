@@ -0,0 +1,188 @@
+//! input devices module
+//!
+//! Picks keyboards and pointing devices for a guest, so applications do not each re-implement
+//! which guests recognize which devices.
+//!
+//! | guest | keyboards | pointing devices |
+//! |---|---|---|
+//! | Linux, console only | none | none |
+//! | Linux desktop | USB keyboard | USB screen pointer |
+//! | macOS | Mac keyboard (14+) and USB keyboard | Mac trackpad (13+) and USB screen pointer |
+//!
+//! Mac devices are left out when the host lacks their class, or when the guest's version is known
+//! to predate them; the USB devices cover every guest, so input keeps working either way.
+//!
+//! # Examples
+//! ```rust
+//! let input = input_devices::recommended_for(
+//!     GuestOS::MacOS { version_hint: Some(14) },
+//!     &HostCapabilities::detect(),
+//! );
+//! let conf = VZVirtualMachineConfigurationBuilder::new()
+//!     .keyboards(input.keyboards)
+//!     .pointing_devices(input.pointing)
+//!     .build();
+//! ```
+
+use crate::features::HostCapabilities;
+use crate::virtualization::keyboard::{
+    VZKeyboardConfiguration, VZMacKeyboardConfiguration, VZUSBKeyboardConfiguration,
+};
+use crate::virtualization::pointing_device::{
+    VZMacTrackpadConfiguration, VZPointingDeviceConfiguration,
+    VZUSBScreenCoordinatePointingDeviceConfiguration,
+};
+
+/// The guest the devices are for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GuestOS {
+    /// A Linux guest used over a serial console only.
+    LinuxConsoleOnly,
+    /// A Linux guest with a graphical session.
+    LinuxDesktop,
+    /// A macOS guest, with its major version if known, e.g. `Some(14)`.
+    MacOS { version_hint: Option<u32> },
+}
+
+/// An input device this module can recommend.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InputDeviceKind {
+    UsbKeyboard,
+    MacKeyboard,
+    UsbScreenPointer,
+    MacTrackpad,
+}
+
+/// A device for a guest that may be missing from the host or not recognized by the guest.
+struct Rule {
+    kind: InputDeviceKind,
+    /// Class the host must have, if any.
+    class: Option<&'static str>,
+    /// Oldest macOS guest that recognizes the device.
+    min_macos_guest: Option<u32>,
+}
+
+const USB_KEYBOARD: Rule = Rule {
+    kind: InputDeviceKind::UsbKeyboard,
+    class: None,
+    min_macos_guest: None,
+};
+
+const USB_SCREEN_POINTER: Rule = Rule {
+    kind: InputDeviceKind::UsbScreenPointer,
+    class: None,
+    min_macos_guest: None,
+};
+
+/// `VZMacKeyboardConfiguration.h`: "macOS 14 and later guests".
+const MAC_KEYBOARD: Rule = Rule {
+    kind: InputDeviceKind::MacKeyboard,
+    class: Some("VZMacKeyboardConfiguration"),
+    min_macos_guest: Some(14),
+};
+
+/// `VZMacTrackpadConfiguration.h`: "recognized by virtual machines running macOS 13 and later",
+/// alongside a USB screen pointer for older guests.
+const MAC_TRACKPAD: Rule = Rule {
+    kind: InputDeviceKind::MacTrackpad,
+    class: Some("VZMacTrackpadConfiguration"),
+    min_macos_guest: Some(13),
+};
+
+/// Keyboards and pointing devices per guest: each Mac device comes with the USB device that
+/// guests too old for it fall back to.
+fn matrix(guest: GuestOS) -> (&'static [Rule], &'static [Rule]) {
+    match guest {
+        GuestOS::LinuxConsoleOnly => (&[], &[]),
+        GuestOS::LinuxDesktop => (&[USB_KEYBOARD], &[USB_SCREEN_POINTER]),
+        GuestOS::MacOS { .. } => (
+            &[MAC_KEYBOARD, USB_KEYBOARD],
+            &[MAC_TRACKPAD, USB_SCREEN_POINTER],
+        ),
+    }
+}
+
+/// The rules of `rules` that apply, given which classes the host has.
+fn select<F: Fn(&'static str) -> bool>(
+    rules: &[Rule],
+    guest: GuestOS,
+    has_class: &F,
+) -> Vec<InputDeviceKind> {
+    let version = match guest {
+        GuestOS::MacOS { version_hint } => version_hint,
+        _ => None,
+    };
+    rules
+        .iter()
+        .filter(|rule| match rule.class {
+            Some(class) => has_class(class),
+            None => true,
+        })
+        .filter(|rule| match (rule.min_macos_guest, version) {
+            (Some(min), Some(version)) => version >= min,
+            _ => true,
+        })
+        .map(|rule| rule.kind)
+        .collect()
+}
+
+/// The kinds [`recommended_for`] creates, keyboards first, for logging or auditing a choice.
+pub fn recommended_kinds(
+    guest: GuestOS,
+    host: &HostCapabilities,
+) -> (Vec<InputDeviceKind>, Vec<InputDeviceKind>) {
+    let has_class = |class| host.supports_class(class);
+    let (keyboards, pointing) = matrix(guest);
+    (
+        select(keyboards, guest, &has_class),
+        select(pointing, guest, &has_class),
+    )
+}
+
+/// Devices ready for [`VZVirtualMachineConfigurationBuilder::keyboards`] and
+/// [`VZVirtualMachineConfigurationBuilder::pointing_devices`].
+///
+/// [`VZVirtualMachineConfigurationBuilder::keyboards`]: crate::virtualization::virtual_machine::VZVirtualMachineConfigurationBuilder::keyboards
+/// [`VZVirtualMachineConfigurationBuilder::pointing_devices`]: crate::virtualization::virtual_machine::VZVirtualMachineConfigurationBuilder::pointing_devices
+pub struct InputDevices {
+    pub keyboards: Vec<Box<dyn VZKeyboardConfiguration>>,
+    pub pointing: Vec<Box<dyn VZPointingDeviceConfiguration>>,
+}
+
+/// The keyboards and pointing devices `guest` should get on `host`. A Mac device whose class the
+/// host turns out not to provide is skipped.
+pub fn recommended_for(guest: GuestOS, host: &HostCapabilities) -> InputDevices {
+    let (keyboard_kinds, pointing_kinds) = recommended_kinds(guest, host);
+    let mut keyboards: Vec<Box<dyn VZKeyboardConfiguration>> = Vec::new();
+    for kind in keyboard_kinds {
+        match kind {
+            InputDeviceKind::UsbKeyboard => {
+                keyboards.push(Box::new(VZUSBKeyboardConfiguration::new()))
+            }
+            InputDeviceKind::MacKeyboard => {
+                if let Some(keyboard) = VZMacKeyboardConfiguration::new() {
+                    keyboards.push(Box::new(keyboard));
+                }
+            }
+            _ => {}
+        }
+    }
+    let mut pointing: Vec<Box<dyn VZPointingDeviceConfiguration>> = Vec::new();
+    for kind in pointing_kinds {
+        match kind {
+            InputDeviceKind::UsbScreenPointer => pointing.push(Box::new(
+                VZUSBScreenCoordinatePointingDeviceConfiguration::new(),
+            )),
+            InputDeviceKind::MacTrackpad => {
+                if let Ok(trackpad) = VZMacTrackpadConfiguration::new() {
+                    pointing.push(Box::new(trackpad));
+                }
+            }
+            _ => {}
+        }
+    }
+    InputDevices {
+        keyboards,
+        pointing,
+    }
+}
@@ -0,0 +1,116 @@
+//! installer module
+//!
+//! Installs macOS from a restore image on the disk of a virtual machine with a
+//! [`VZMacPlatformConfiguration`](crate::virtualization::platform::VZMacPlatformConfiguration).
+//! The machine must be stopped; the installer starts and stops it itself.
+//!
+//! # Examples
+//! ```rust
+//! let installer = VZMacOSInstaller::new(&vm, "UniversalMac_13.0_22A380_Restore.ipsw")?;
+//! let _guard = installer.observe_progress(|fraction| println!("{:.0}%", fraction * 100.0));
+//! installer.install(|outcome| println!("{:?}", outcome));
+//! ```
+
+use crate::base::{DispatchQueue, Id, NSError, NSURL};
+use crate::features;
+use crate::kvo::{self, KvoGuard};
+use crate::runtime::{alloc, owned, vz_class, ForQueue};
+use crate::virtualization::error::{CompletionOutcome, ResultExt, VZErrorCtx};
+use crate::virtualization::virtual_machine::VZVirtualMachine;
+
+use std::cell::Cell;
+use std::path::Path;
+
+use block::ConcreteBlock;
+use objc::rc::StrongPtr;
+use objc::{msg_send, sel, sel_impl};
+
+/// Installs a restore image on a virtual machine.
+pub struct VZMacOSInstaller {
+    p: StrongPtr,
+    queue: DispatchQueue,
+    /// The installer messages the machine, which must outlive it.
+    _vm: VZVirtualMachine,
+}
+
+impl VZMacOSInstaller {
+    /// An installer of the restore image at `restore_image` on `vm`. Fails with `ENOTSUP` on
+    /// Intel hosts and with `EINVAL` for a path that cannot be a file URL.
+    ///
+    /// Panics if called on the VM's queue, where it creates the installer.
+    pub fn new<P: AsRef<Path>>(
+        vm: &VZVirtualMachine,
+        restore_image: P,
+    ) -> Result<VZMacOSInstaller, VZErrorCtx> {
+        let path = restore_image.as_ref();
+        let resource = format!("'{}'", path.display());
+        VZMacOSInstaller::create(vm, path).ctx("create macOS installer for", resource)
+    }
+
+    fn create(vm: &VZVirtualMachine, path: &Path) -> Result<VZMacOSInstaller, NSError> {
+        features::require("VZMacOSInstaller").map_err(|_| NSError::posix(libc::ENOTSUP))?;
+        let url = path
+            .to_str()
+            .and_then(|path| NSURL::file_url_with_path(path, false))
+            .ok_or_else(|| NSError::posix(libc::EINVAL))?;
+        let queue = vm.queue().clone();
+        queue.assert_not_current("VZMacOSInstaller::new");
+        let vm_id = unsafe { vm.id() };
+        let p = queue.exec_sync(move || unsafe {
+            let i = alloc(vz_class!(VZMacOSInstaller));
+            owned(msg_send![i, initWithVirtualMachine: vm_id restoreImageURL: *url.0])
+        });
+        Ok(VZMacOSInstaller {
+            p,
+            queue,
+            _vm: vm.clone(),
+        })
+    }
+
+    /// Starts the installation; `completion_handler` runs on the VM's queue once it finished,
+    /// failed or was [cancelled](Self::cancel).
+    pub fn install<F: FnOnce(CompletionOutcome) + Send + 'static>(&self, completion_handler: F) {
+        let p = ForQueue(self.p.clone());
+        self.queue.exec_async(move || {
+            let p = p.0;
+            let completion_handler = Cell::new(Some(completion_handler));
+            let block = ConcreteBlock::new(move |error: Id| {
+                let outcome = unsafe { CompletionOutcome::from_error(error) };
+                if let Some(f) = completion_handler.take() {
+                    f(outcome);
+                }
+            });
+            let block = block.copy();
+            unsafe {
+                let _: () = msg_send![*p, installWithCompletionHandler: &*block];
+            }
+        });
+    }
+
+    /// Between 0 and 1.
+    pub fn fraction_completed(&self) -> f64 {
+        unsafe { msg_send![self.progress(), fractionCompleted] }
+    }
+
+    /// Calls `callback` with the fraction completed, on an arbitrary thread, until the guard is
+    /// dropped.
+    pub fn observe_progress<F: Fn(f64) + Send + 'static>(&self, callback: F) -> KvoGuard {
+        unsafe { kvo::observe_fraction_completed(self.progress(), callback) }
+    }
+
+    /// Cancels the installation through its `NSProgress`; the completion handler then gets
+    /// [`CompletionOutcome::Cancelled`].
+    pub fn cancel(&self) {
+        unsafe {
+            let _: () = msg_send![self.progress(), cancel];
+        }
+    }
+
+    fn progress(&self) -> Id {
+        unsafe { msg_send![*self.p, progress] }
+    }
+
+    pub fn id(&self) -> Id {
+        *self.p
+    }
+}
@@ -0,0 +1,261 @@
+//! kernel inspection module
+//!
+//! Identifies Linux kernel and initial ramdisk images from their headers, so an image the host
+//! cannot boot is reported before the framework fails with an internal error at start.
+//!
+//! # Examples
+//! ```rust
+//! let check = kernel_inspect::check("ubuntu/vmlinuz", Some("ubuntu/initrd"))?;
+//! for error in &check.errors {
+//!     eprintln!("error: {}", error);
+//! }
+//! ```
+
+use crate::base::InvalidInput;
+
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+
+/// Enough for every header inspected here.
+const HEADER_LEN: usize = 0x400;
+
+/// Format of a kernel image.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KernelFormat {
+    /// Uncompressed ARM64 `Image`, what the framework boots on Apple silicon.
+    Arm64Image,
+    /// x86 `bzImage`, what the framework boots on Intel.
+    X86BzImage,
+    /// Self-decompressing EFI zboot image (`vmlinuz.efi`).
+    EfiZboot,
+    /// Other PE/COFF executable, with its machine type.
+    PeExecutable { machine: u16 },
+    /// ELF `vmlinux`, with its machine type.
+    Elf { machine: u16 },
+    /// A compressed image that has to be decompressed first.
+    Compressed(Compression),
+    Unknown,
+}
+
+/// Format of an initial ramdisk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InitrdFormat {
+    Cpio,
+    Compressed(Compression),
+    Unknown,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    Gzip,
+    Zstd,
+    Xz,
+    Lz4,
+    Bzip2,
+}
+
+const PE_MACHINE_ARM64: u16 = 0xaa64;
+const ELF_MACHINE_AARCH64: u16 = 0xb7;
+
+/// Outcome of inspecting a kernel and initial ramdisk for the host.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KernelCheck {
+    pub kernel: KernelFormat,
+    pub initrd: Option<InitrdFormat>,
+    /// Problems that may still boot.
+    pub warnings: Vec<String>,
+    /// Problems that cannot boot on this host.
+    pub errors: Vec<String>,
+}
+
+impl KernelCheck {
+    pub fn is_ok(&self) -> bool {
+        self.errors.is_empty()
+    }
+}
+
+/// Error returned by `VZLinuxBootLoaderBuilder::build_checked`.
+#[derive(Debug)]
+pub enum KernelCheckError {
+    /// The kernel or initial ramdisk could not be read.
+    Io(io::Error),
+    /// The images cannot boot on this host; see [`KernelCheck::errors`].
+    Rejected(KernelCheck),
+    /// A path cannot be made into a file URL; only reached with the check skipped.
+    InvalidPath(InvalidInput),
+}
+
+impl From<io::Error> for KernelCheckError {
+    fn from(e: io::Error) -> Self {
+        KernelCheckError::Io(e)
+    }
+}
+
+impl From<InvalidInput> for KernelCheckError {
+    fn from(e: InvalidInput) -> Self {
+        KernelCheckError::InvalidPath(e)
+    }
+}
+
+/// Inspects the images at `kernel` and `initrd` against the host architecture.
+pub fn check<K: AsRef<Path>, I: AsRef<Path>>(
+    kernel: K,
+    initrd: Option<I>,
+) -> io::Result<KernelCheck> {
+    let kernel = detect_kernel(&read_header(kernel.as_ref())?);
+    let initrd = match initrd {
+        Some(path) => Some(detect_initrd(&read_header(path.as_ref())?)),
+        None => None,
+    };
+    Ok(check_formats(kernel, initrd, cfg!(target_arch = "aarch64")))
+}
+
+fn read_header(path: &Path) -> io::Result<Vec<u8>> {
+    let mut header = Vec::with_capacity(HEADER_LEN);
+    File::open(path)?
+        .take(HEADER_LEN as u64)
+        .read_to_end(&mut header)?;
+    Ok(header)
+}
+
+fn at(header: &[u8], offset: usize, magic: &[u8]) -> bool {
+    header.get(offset..offset + magic.len()) == Some(magic)
+}
+
+fn u16_at(header: &[u8], offset: usize) -> Option<u16> {
+    header
+        .get(offset..offset + 2)
+        .map(|b| u16::from_le_bytes([b[0], b[1]]))
+}
+
+fn u32_at(header: &[u8], offset: usize) -> Option<u32> {
+    header
+        .get(offset..offset + 4)
+        .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+}
+
+fn detect_compression(header: &[u8]) -> Option<Compression> {
+    if at(header, 0, &[0x1f, 0x8b]) {
+        Some(Compression::Gzip)
+    } else if at(header, 0, &[0x28, 0xb5, 0x2f, 0xfd]) {
+        Some(Compression::Zstd)
+    } else if at(header, 0, &[0xfd, b'7', b'z', b'X', b'Z', 0x00]) {
+        Some(Compression::Xz)
+    } else if at(header, 0, &[0x02, 0x21, 0x4c, 0x18]) || at(header, 0, &[0x04, 0x22, 0x4d, 0x18])
+    {
+        Some(Compression::Lz4)
+    } else if at(header, 0, b"BZh") {
+        Some(Compression::Bzip2)
+    } else {
+        None
+    }
+}
+
+/// Identifies a kernel image. EFI-stub kernels also start with `MZ`, so the Linux-specific
+/// headers are checked before the generic PE one.
+pub fn detect_kernel(header: &[u8]) -> KernelFormat {
+    if at(header, 0x38, b"ARM\x64") {
+        KernelFormat::Arm64Image
+    } else if at(header, 0x1fe, &[0x55, 0xaa]) && at(header, 0x202, b"HdrS") {
+        KernelFormat::X86BzImage
+    } else if at(header, 0, b"MZ") && at(header, 4, b"zimg") {
+        KernelFormat::EfiZboot
+    } else if at(header, 0, b"MZ") {
+        let machine = u32_at(header, 0x3c)
+            .map(|pe| pe as usize)
+            .filter(|&pe| at(header, pe, b"PE\0\0"))
+            .and_then(|pe| u16_at(header, pe + 4));
+        match machine {
+            Some(machine) => KernelFormat::PeExecutable { machine },
+            None => KernelFormat::Unknown,
+        }
+    } else if at(header, 0, b"\x7fELF") {
+        KernelFormat::Elf {
+            machine: u16_at(header, 0x12).unwrap_or(0),
+        }
+    } else if let Some(compression) = detect_compression(header) {
+        KernelFormat::Compressed(compression)
+    } else {
+        KernelFormat::Unknown
+    }
+}
+
+pub fn detect_initrd(header: &[u8]) -> InitrdFormat {
+    // newc, newc with checksums, and old binary cpio.
+    if at(header, 0, b"070701") || at(header, 0, b"070702") || at(header, 0, &[0xc7, 0x71]) {
+        InitrdFormat::Cpio
+    } else if let Some(compression) = detect_compression(header) {
+        InitrdFormat::Compressed(compression)
+    } else {
+        InitrdFormat::Unknown
+    }
+}
+
+/// Judges the formats for an Apple silicon host when `arm64` is set, an Intel host otherwise.
+pub fn check_formats(
+    kernel: KernelFormat,
+    initrd: Option<InitrdFormat>,
+    arm64: bool,
+) -> KernelCheck {
+    let mut warnings = Vec::new();
+    let mut errors = Vec::new();
+    let host = if arm64 { "an Apple silicon" } else { "an Intel" };
+    match (kernel, arm64) {
+        (KernelFormat::Arm64Image, true) | (KernelFormat::X86BzImage, false) => {}
+        (KernelFormat::Arm64Image, false) => {
+            errors.push(format!("ARM64 kernel Image cannot boot on {} host", host))
+        }
+        (KernelFormat::X86BzImage, true) => {
+            errors.push(format!("x86_64 bzImage cannot boot on {} host", host))
+        }
+        (KernelFormat::Compressed(compression), _) => errors.push(format!(
+            "kernel is {:?}-compressed; the framework needs an uncompressed image, decompress it first",
+            compression
+        )),
+        (KernelFormat::EfiZboot, _) => errors.push(
+            "kernel is a compressed EFI zboot image; boot it with the EFI boot loader or extract the Image"
+                .to_string(),
+        ),
+        (KernelFormat::PeExecutable { machine }, _) => {
+            if (machine == PE_MACHINE_ARM64) != arm64 {
+                errors.push(format!(
+                    "kernel is an EFI executable for another architecture (machine {:#x}) than {} host",
+                    machine, host
+                ));
+            } else {
+                warnings.push(
+                    "kernel is a PE executable without a Linux boot header; it may not boot"
+                        .to_string(),
+                );
+            }
+        }
+        (KernelFormat::Elf { machine }, _) => {
+            if (machine == ELF_MACHINE_AARCH64) != arm64 {
+                errors.push(format!(
+                    "kernel is an ELF vmlinux for another architecture (machine {:#x}) than {} host",
+                    machine, host
+                ));
+            } else {
+                errors.push(
+                    "kernel is an ELF vmlinux; the framework boots the Image/bzImage built next to it"
+                        .to_string(),
+                );
+            }
+        }
+        (KernelFormat::Unknown, _) => {
+            warnings.push("kernel format not recognized; it may not boot".to_string())
+        }
+    }
+    if initrd == Some(InitrdFormat::Unknown) {
+        warnings.push(
+            "initial ramdisk is neither a cpio archive nor a known compressed format".to_string(),
+        );
+    }
+    KernelCheck {
+        kernel,
+        initrd,
+        warnings,
+        errors,
+    }
+}
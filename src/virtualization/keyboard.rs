@@ -1,26 +1,78 @@
 //! keyboard module
 
 use crate::base::Id;
+use crate::features::{ClassLookup, ClassNotAvailable};
+use crate::runtime::owned;
+use crate::virtualization::device::VZDeviceConfiguration;
+
+use std::any::Any;
 
 use objc::rc::StrongPtr;
-use objc::{class, msg_send, sel, sel_impl};
+use objc::{msg_send, sel, sel_impl};
+
+static USB_KEYBOARD: ClassLookup = ClassLookup::new("VZUSBKeyboardConfiguration", "macOS 12");
+static MAC_KEYBOARD: ClassLookup = ClassLookup::new("VZMacKeyboardConfiguration", "macOS 14");
 
 /// The base class for a configuring a keyboard.
-pub trait VZKeyboardConfiguration {
-    fn id(&self) -> Id;
-}
+pub trait VZKeyboardConfiguration: VZDeviceConfiguration {}
+
+impl<T: VZKeyboardConfiguration + ?Sized> VZKeyboardConfiguration for Box<T> {}
 
 /// A device that defines the configuration for a USB keyboard.
 pub struct VZUSBKeyboardConfiguration(StrongPtr);
 
 impl VZUSBKeyboardConfiguration {
+    /// # Panics
+    /// Before macOS 12; see [`try_new`](Self::try_new).
+    #[track_caller]
     pub fn new() -> Self {
-        Self(unsafe { StrongPtr::new(msg_send![class!(VZUSBKeyboardConfiguration), new]) })
+        Self::try_new().unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    pub fn try_new() -> Result<Self, ClassNotAvailable> {
+        let class = USB_KEYBOARD.get()?;
+        Ok(Self(unsafe { owned(msg_send![class, new]) }))
+    }
+}
+
+impl Default for VZUSBKeyboardConfiguration {
+    fn default() -> Self {
+        VZUSBKeyboardConfiguration::new()
+    }
+}
+
+impl VZDeviceConfiguration for VZUSBKeyboardConfiguration {
+    fn id(&self) -> Id {
+        *self.0
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+impl VZKeyboardConfiguration for VZUSBKeyboardConfiguration {}
+
+/// A Mac keyboard, which passes through keys such as the function and globe keys. Recognized by
+/// macOS 14 and later guests; include a [`VZUSBKeyboardConfiguration`] as well for older ones.
+pub struct VZMacKeyboardConfiguration(StrongPtr);
+
+impl VZMacKeyboardConfiguration {
+    /// `None` if the host framework predates the class (macOS 14).
+    pub fn new() -> Option<Self> {
+        let class = MAC_KEYBOARD.get().ok()?;
+        Some(Self(unsafe { owned(msg_send![class, new]) }))
     }
 }
 
-impl VZKeyboardConfiguration for VZUSBKeyboardConfiguration {
+impl VZDeviceConfiguration for VZMacKeyboardConfiguration {
     fn id(&self) -> Id {
         *self.0
     }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
 }
+
+impl VZKeyboardConfiguration for VZMacKeyboardConfiguration {}
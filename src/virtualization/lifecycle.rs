@@ -0,0 +1,488 @@
+//! lifecycle module
+//!
+//! Rust-side bookkeeping of the starts and stops sent through the safe wrappers of
+//! [`VZVirtualMachine`](crate::virtualization::virtual_machine::VZVirtualMachine), so a second
+//! start or stop never reaches the framework while one is in flight.
+//!
+//! # Examples
+//! ```rust,no_run
+//! # use virtualization_rs::virtualization::error::CompletionOutcome;
+//! # use virtualization_rs::virtualization::lifecycle::LifecycleError;
+//! # use virtualization_rs::virtualization::virtual_machine::VZVirtualMachine;
+//! # fn report(_: CompletionOutcome) {}
+//! # fn start(vm: &VZVirtualMachine) -> Result<(), LifecycleError> {
+//! match vm.start(|outcome| report(outcome)) {
+//!     Ok(()) => {}
+//!     Err(LifecycleError::AlreadyStarted) => println!("already starting"),
+//!     Err(e) => eprintln!("{}", e),
+//! }
+//! // From a retry loop: waits for the start in flight instead of sending another one.
+//! vm.start_or_join(|outcome| report(outcome))?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! # Why the machine stopped
+//!
+//! The tracker also records why the last run ended, as a [`StopReason`]. While the machine runs,
+//! causes pile up: the guest was asked to stop, a forced stop was sent. The first sign that the
+//! machine is down settles the reason from what piled up until then, by precedence:
+//!
+//! 1. an error the machine stopped with makes it [`StopReason::Crashed`], whatever else happened;
+//! 2. a forced stop that was sent makes it [`StopReason::Forced`], even when the guest's own stop
+//!    arrives first: the guest may have been shutting down, but the host no longer waited for it;
+//! 3. a request to the guest makes it [`StopReason::HostRequested`];
+//! 4. otherwise the guest stopped on its own, [`StopReason::GuestInitiated`].
+//!
+//! Later signs of the same stop, e.g. the completion of a forced stop after the guest stopped, do
+//! not change it; the next start begins a new run.
+//!
+//! ```rust,no_run
+//! # use virtualization_rs::teardown::{self, TeardownPolicy};
+//! # use virtualization_rs::virtualization::lifecycle::StopReason;
+//! # use virtualization_rs::virtualization::virtual_machine::VZVirtualMachine;
+//! # fn stop(vm: &VZVirtualMachine) {
+//! teardown::shutdown(vm, &TeardownPolicy::default());
+//! match vm.last_stop_reason() {
+//!     Some(StopReason::Forced) => eprintln!("the guest ignored the request to stop"),
+//!     Some(reason) => println!("stopped: {}", reason),
+//!     None => println!("never stopped"),
+//! }
+//! # }
+//! ```
+
+use crate::virtualization::error::{CompletionOutcome, VZError};
+use crate::virtualization::virtual_machine::VZVirtualMachineState;
+
+use std::fmt;
+use std::mem;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::{Mutex, MutexGuard};
+
+/// Where a virtual machine is in its life, as far as the safe wrappers know.
+///
+/// Starts and stops sent through the raw Block-based methods are only picked up once the
+/// machine's state changes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Lifecycle {
+    /// Never started.
+    Created,
+    /// A start is in flight.
+    StartRequested,
+    /// Running or paused.
+    Started,
+    /// A stop is in flight.
+    Stopping,
+    /// Stopped, by a stop, a failed start, the guest or an error. May be started again.
+    Stopped,
+}
+
+const LIFECYCLES: [Lifecycle; 5] = [
+    Lifecycle::Created,
+    Lifecycle::StartRequested,
+    Lifecycle::Started,
+    Lifecycle::Stopping,
+    Lifecycle::Stopped,
+];
+
+impl Lifecycle {
+    fn from_u8(n: u8) -> Lifecycle {
+        LIFECYCLES[n as usize]
+    }
+
+    /// The lifecycle a machine in `state` is in; `None` for states this crate does not know.
+    fn from_state(state: VZVirtualMachineState) -> Option<Lifecycle> {
+        use VZVirtualMachineState::*;
+        match state {
+            VZVirtualMachineStateStopped | VZVirtualMachineStateError => Some(Lifecycle::Stopped),
+            VZVirtualMachineStateStarting | VZVirtualMachineStateRestoring => {
+                Some(Lifecycle::StartRequested)
+            }
+            VZVirtualMachineStateRunning
+            | VZVirtualMachineStatePaused
+            | VZVirtualMachineStatePausing
+            | VZVirtualMachineStateResuming
+            | VZVirtualMachineStateSaving => Some(Lifecycle::Started),
+            VZVirtualMachineStateStopping => Some(Lifecycle::Stopping),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Lifecycle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Lifecycle::Created => "created",
+            Lifecycle::StartRequested => "start requested",
+            Lifecycle::Started => "started",
+            Lifecycle::Stopping => "stopping",
+            Lifecycle::Stopped => "stopped",
+        };
+        f.write_str(name)
+    }
+}
+
+/// Why a start or stop was not sent to the framework. The completion closure is dropped without
+/// being called.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LifecycleError {
+    /// `start` while a start is in flight or the machine is running.
+    AlreadyStarted,
+    /// `stop` while a stop is in flight.
+    AlreadyStopping,
+    /// A start while a stop is in flight.
+    StopInProgress,
+}
+
+impl fmt::Display for LifecycleError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let message = match self {
+            LifecycleError::AlreadyStarted => "the virtual machine is already started",
+            LifecycleError::AlreadyStopping => "the virtual machine is already stopping",
+            LifecycleError::StopInProgress => "the virtual machine is being stopped",
+        };
+        f.write_str(message)
+    }
+}
+
+/// Why the last run of a virtual machine ended. See the [module documentation](self) for how
+/// overlapping causes are settled.
+#[derive(Debug, Clone)]
+pub enum StopReason {
+    /// The guest shut itself down without being asked.
+    GuestInitiated,
+    /// The guest was asked to stop with `VZVirtualMachine::request_stop_with_error`, and did.
+    HostRequested,
+    /// The machine was stopped with `VZVirtualMachine::stop`.
+    Forced,
+    /// The machine stopped with an error.
+    Crashed(VZError),
+}
+
+impl StopReason {
+    /// Whether the guest shut itself down, asked or not.
+    pub fn is_clean(&self) -> bool {
+        matches!(self, StopReason::GuestInitiated | StopReason::HostRequested)
+    }
+
+    /// `guest-initiated`, `host-requested`, `forced` or `crashed`.
+    pub fn label(&self) -> &'static str {
+        match self {
+            StopReason::GuestInitiated => "guest-initiated",
+            StopReason::HostRequested => "host-requested",
+            StopReason::Forced => "forced",
+            StopReason::Crashed(_) => "crashed",
+        }
+    }
+}
+
+impl fmt::Display for StopReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StopReason::Crashed(e) => write!(f, "crashed: {}", e.ns_error()),
+            reason => f.write_str(reason.label()),
+        }
+    }
+}
+
+// The error is a retained `NSError`, which is immutable.
+unsafe impl Send for StopReason {}
+unsafe impl Sync for StopReason {}
+
+/// What the tracker learns about a stop, in the order it happens. The safe wrappers and the
+/// crate's delegate report these; `VZVirtualMachine::stop_signal` replays them for tests.
+#[derive(Debug, Clone)]
+pub enum StopSignal {
+    /// A start was sent, beginning a new run.
+    StartSent,
+    /// The guest accepted a request to stop.
+    Requested,
+    /// A forced stop was sent.
+    ForceSent,
+    /// The forced stop completed; `stopped` is whether it succeeded.
+    ForceCompleted { stopped: bool },
+    /// The framework reported that the guest stopped.
+    GuestStopped,
+    /// The framework reported that the machine stopped with an error.
+    StoppedWithError(VZError),
+}
+
+unsafe impl Send for StopSignal {}
+unsafe impl Sync for StopSignal {}
+
+/// The causes of the stop of the current run, until it is settled.
+#[derive(Default)]
+struct Stops {
+    requested: bool,
+    /// A forced stop was sent and did not fail.
+    forcing: bool,
+    settled: bool,
+    last: Option<StopReason>,
+}
+
+impl Stops {
+    /// Takes `signal` into account, and returns the reason if it settled the stop.
+    fn note(&mut self, signal: StopSignal) -> Option<StopReason> {
+        let error = match signal {
+            StopSignal::StartSent => {
+                *self = Stops {
+                    last: self.last.take(),
+                    ..Stops::default()
+                };
+                return None;
+            }
+            StopSignal::Requested => {
+                self.requested = true;
+                return None;
+            }
+            StopSignal::ForceSent => {
+                self.forcing = true;
+                return None;
+            }
+            StopSignal::ForceCompleted { stopped: false } => {
+                self.forcing = false;
+                return None;
+            }
+            StopSignal::ForceCompleted { stopped: true } | StopSignal::GuestStopped => None,
+            StopSignal::StoppedWithError(e) => Some(e),
+        };
+        if self.settled {
+            return None;
+        }
+        let reason = match error {
+            Some(e) => StopReason::Crashed(e),
+            None if self.forcing => StopReason::Forced,
+            None if self.requested => StopReason::HostRequested,
+            None => StopReason::GuestInitiated,
+        };
+        self.settled = true;
+        self.last = Some(reason.clone());
+        Some(reason)
+    }
+}
+
+/// A completion closure, already wrapped to run on its caller's callback queue.
+pub(crate) type Waiter<T> = Box<dyn FnOnce(T)>;
+
+/// One kind of operation, e.g. starts: all callers that join an operation in flight share its
+/// outcome, and each is called exactly once.
+enum Operation<T> {
+    Idle,
+    Pending(Vec<Waiter<T>>),
+    /// The outcome of the last operation, handed to callers that join after it completed.
+    Done(T),
+}
+
+impl<T: Clone> Operation<T> {
+    fn is_pending(&self) -> bool {
+        matches!(self, Operation::Pending(_))
+    }
+
+    /// Starts a new operation with `waiter` as its first waiter.
+    fn begin(&mut self, waiter: Waiter<T>) {
+        *self = Operation::Pending(vec![waiter]);
+    }
+
+    /// Adds `waiter` to the operation in flight, or hands back the outcome of the last one for
+    /// the caller to deliver outside the lock. Gives `waiter` back if there is neither.
+    fn join(&mut self, waiter: Waiter<T>) -> Result<Option<(Waiter<T>, T)>, Waiter<T>> {
+        match self {
+            Operation::Pending(waiters) => {
+                waiters.push(waiter);
+                Ok(None)
+            }
+            Operation::Done(outcome) => Ok(Some((waiter, outcome.clone()))),
+            Operation::Idle => Err(waiter),
+        }
+    }
+
+    /// Records `outcome` and returns the waiters to call, outside the lock.
+    fn complete(&mut self, outcome: T) -> Vec<Waiter<T>> {
+        match mem::replace(self, Operation::Done(outcome)) {
+            Operation::Pending(waiters) => waiters,
+            _ => Vec::new(),
+        }
+    }
+
+    /// Forgets the outcome of the last operation, so it is not replayed.
+    fn forget(&mut self) {
+        if let Operation::Done(_) = self {
+            *self = Operation::Idle;
+        }
+    }
+}
+
+/// Which operation a request or completion is about.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Op {
+    Start,
+    Stop,
+}
+
+struct Operations {
+    start: Operation<CompletionOutcome>,
+    stop: Operation<CompletionOutcome>,
+    /// The state seen by the last notification of the state observer.
+    last_state: Option<VZVirtualMachineState>,
+    stops: Stops,
+}
+
+impl Operations {
+    fn get(&mut self, op: Op) -> &mut Operation<CompletionOutcome> {
+        match op {
+            Op::Start => &mut self.start,
+            Op::Stop => &mut self.stop,
+        }
+    }
+}
+
+/// Shared by all handles to a virtual machine.
+///
+/// The lifecycle is an atomic so it can be read without the lock, but it is only written with the
+/// lock held. While an operation is in flight, it decides the lifecycle; otherwise the state
+/// observer does.
+pub(crate) struct LifecycleTracker {
+    lifecycle: AtomicU8,
+    operations: Mutex<Operations>,
+}
+
+// Waiters are called on the VM's queue, like the completion closures the safe wrappers hand to
+// the framework, and the outcomes only hold retained framework objects.
+unsafe impl Send for LifecycleTracker {}
+unsafe impl Sync for LifecycleTracker {}
+
+impl LifecycleTracker {
+    pub(crate) fn new() -> LifecycleTracker {
+        LifecycleTracker {
+            lifecycle: AtomicU8::new(Lifecycle::Created as u8),
+            operations: Mutex::new(Operations {
+                start: Operation::Idle,
+                stop: Operation::Idle,
+                last_state: None,
+                stops: Stops::default(),
+            }),
+        }
+    }
+
+    pub(crate) fn lifecycle(&self) -> Lifecycle {
+        Lifecycle::from_u8(self.lifecycle.load(Ordering::SeqCst))
+    }
+
+    fn set(&self, lifecycle: Lifecycle) {
+        self.lifecycle.store(lifecycle as u8, Ordering::SeqCst);
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Operations> {
+        self.operations.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Registers `waiter` for `op`. Returns whether the caller must send `op` to the framework
+    /// and report its outcome with [`LifecycleTracker::complete`].
+    ///
+    /// With `join`, `waiter` shares an operation of the same kind that is in flight, or gets the
+    /// outcome of the last one if the machine is still where it left it.
+    pub(crate) fn request(
+        &self,
+        op: Op,
+        join: bool,
+        waiter: Waiter<CompletionOutcome>,
+    ) -> Result<bool, LifecycleError> {
+        let mut operations = self.lock();
+        let lifecycle = self.lifecycle();
+        let settled = match op {
+            Op::Start => Lifecycle::Started,
+            Op::Stop => Lifecycle::Stopped,
+        };
+        let waiter = if join && (operations.get(op).is_pending() || lifecycle == settled) {
+            match operations.get(op).join(waiter) {
+                Ok(replay) => {
+                    drop(operations);
+                    if let Some((waiter, outcome)) = replay {
+                        waiter(outcome);
+                    }
+                    return Ok(false);
+                }
+                Err(waiter) => waiter,
+            }
+        } else {
+            waiter
+        };
+
+        let next = match (op, lifecycle) {
+            (Op::Start, Lifecycle::StartRequested) | (Op::Start, Lifecycle::Started) => {
+                return Err(LifecycleError::AlreadyStarted)
+            }
+            (Op::Start, Lifecycle::Stopping) => return Err(LifecycleError::StopInProgress),
+            (Op::Stop, Lifecycle::Stopping) => return Err(LifecycleError::AlreadyStopping),
+            (Op::Start, _) => Lifecycle::StartRequested,
+            (Op::Stop, _) => Lifecycle::Stopping,
+        };
+        operations.get(op).begin(waiter);
+        let other = match op {
+            Op::Start => Op::Stop,
+            Op::Stop => Op::Start,
+        };
+        operations.get(other).forget();
+        operations.stops.note(match op {
+            Op::Start => StopSignal::StartSent,
+            Op::Stop => StopSignal::ForceSent,
+        });
+        self.set(next);
+        Ok(true)
+    }
+
+    /// Takes `signal` into account for why the current run ends. Returns the reason if `signal`
+    /// settled it; see the [module documentation](self).
+    pub(crate) fn note_stop(&self, signal: StopSignal) -> Option<StopReason> {
+        self.lock().stops.note(signal)
+    }
+
+    /// Why the last run ended; `None` until a run ended.
+    pub(crate) fn last_stop_reason(&self) -> Option<StopReason> {
+        self.lock().stops.last.clone()
+    }
+
+    /// Reports the outcome of `op` with the machine's `state` at that time, from the VM's queue,
+    /// and calls everyone waiting for it.
+    pub(crate) fn complete(
+        &self,
+        op: Op,
+        outcome: CompletionOutcome,
+        state: VZVirtualMachineState,
+    ) {
+        let waiters = {
+            let mut operations = self.lock();
+            let waiters = operations.get(op).complete(outcome.clone());
+            let other = match op {
+                Op::Start => &operations.stop,
+                Op::Stop => &operations.start,
+            };
+            if !other.is_pending() {
+                if let Some(lifecycle) = Lifecycle::from_state(state) {
+                    self.set(lifecycle);
+                }
+            }
+            waiters
+        };
+        for waiter in waiters {
+            waiter(outcome.clone());
+        }
+    }
+
+    /// Follows changes of the machine's state that no operation in flight accounts for: the guest
+    /// shutting down, an error, or the raw methods. The first notification only reports the
+    /// current state and is not a change.
+    pub(crate) fn observe(&self, state: VZVirtualMachineState) {
+        let mut operations = self.lock();
+        let previous = operations.last_state.replace(state);
+        if previous.is_none() || previous == Some(state) {
+            return;
+        }
+        if operations.start.is_pending() || operations.stop.is_pending() {
+            return;
+        }
+        if let Some(lifecycle) = Lifecycle::from_state(state) {
+            self.set(lifecycle);
+        }
+    }
+}
@@ -1,14 +1,22 @@
 //! memory device module
+//!
+//! The balloon device is configured without parameters. Once the virtual machine runs, its
+//! [`VZVirtioTraditionalMemoryBalloonDevice`] sets how much memory the guest should keep; the
+//! guest hands the rest back to the host.
 
-use crate::base::Id;
+use crate::base::{DispatchQueue, Id};
+use crate::runtime::{owned, vz_class};
+use crate::virtualization::device::VZDeviceConfiguration;
+
+use std::any::Any;
 
 use objc::rc::StrongPtr;
-use objc::{class, msg_send, sel, sel_impl};
+use objc::{msg_send, sel, sel_impl};
 
 /// common configure of memory balloon device
-pub trait VZMemoryBalloonDeviceConfiguration {
-    fn id(&self) -> Id;
-}
+pub trait VZMemoryBalloonDeviceConfiguration: VZDeviceConfiguration {}
+
+impl<T: VZMemoryBalloonDeviceConfiguration + ?Sized> VZMemoryBalloonDeviceConfiguration for Box<T> {}
 
 /// configure of memory balloon device through the Virtio interface
 pub struct VZVirtioTraditionalMemoryBalloonDeviceConfiguration(StrongPtr);
@@ -16,8 +24,8 @@ pub struct VZVirtioTraditionalMemoryBalloonDeviceConfiguration(StrongPtr);
 impl VZVirtioTraditionalMemoryBalloonDeviceConfiguration {
     pub fn new() -> VZVirtioTraditionalMemoryBalloonDeviceConfiguration {
         unsafe {
-            let p = StrongPtr::new(msg_send![
-                class!(VZVirtioTraditionalMemoryBalloonDeviceConfiguration),
+            let p = owned(msg_send![
+                vz_class!(VZVirtioTraditionalMemoryBalloonDeviceConfiguration),
                 new
             ]);
             VZVirtioTraditionalMemoryBalloonDeviceConfiguration(p)
@@ -25,8 +33,65 @@ impl VZVirtioTraditionalMemoryBalloonDeviceConfiguration {
     }
 }
 
-impl VZMemoryBalloonDeviceConfiguration for VZVirtioTraditionalMemoryBalloonDeviceConfiguration {
+impl Default for VZVirtioTraditionalMemoryBalloonDeviceConfiguration {
+    fn default() -> Self {
+        VZVirtioTraditionalMemoryBalloonDeviceConfiguration::new()
+    }
+}
+
+impl VZDeviceConfiguration for VZVirtioTraditionalMemoryBalloonDeviceConfiguration {
     fn id(&self) -> Id {
         *self.0
     }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+impl VZMemoryBalloonDeviceConfiguration for VZVirtioTraditionalMemoryBalloonDeviceConfiguration {}
+
+/// A memory balloon device of a running virtual machine; see
+/// [`VZVirtualMachine::memory_balloon_devices`](crate::virtualization::virtual_machine::VZVirtualMachine::memory_balloon_devices).
+pub struct VZVirtioTraditionalMemoryBalloonDevice {
+    p: StrongPtr,
+    queue: DispatchQueue,
+}
+
+impl VZVirtioTraditionalMemoryBalloonDevice {
+    pub(crate) fn from_raw(p: StrongPtr, queue: DispatchQueue) -> Self {
+        VZVirtioTraditionalMemoryBalloonDevice { p, queue }
+    }
+
+    /// How much memory the guest is asked to keep, in bytes; the configured memory size until
+    /// it is set.
+    ///
+    /// The property is read on the VM's queue, so this panics if called from that queue.
+    pub fn target_virtual_machine_memory_size(&self) -> u64 {
+        self.queue.assert_not_current(
+            "VZVirtioTraditionalMemoryBalloonDevice::target_virtual_machine_memory_size",
+        );
+        let p = *self.p;
+        self.queue
+            .exec_sync(move || unsafe { msg_send![p, targetVirtualMachineMemorySize] })
+    }
+
+    /// Asks the guest to keep `size` bytes, a multiple of
+    /// [`MEMORY_SIZE_GRANULARITY`](crate::virtualization::virtual_machine::MEMORY_SIZE_GRANULARITY)
+    /// which the framework clamps into the allowed memory sizes. The guest reacts at its own pace.
+    ///
+    /// The property is set on the VM's queue, so this panics if called from that queue.
+    pub fn set_target_virtual_machine_memory_size(&self, size: u64) {
+        self.queue.assert_not_current(
+            "VZVirtioTraditionalMemoryBalloonDevice::set_target_virtual_machine_memory_size",
+        );
+        let p = *self.p;
+        self.queue.exec_sync(move || unsafe {
+            let _: () = msg_send![p, setTargetVirtualMachineMemorySize: size];
+        })
+    }
+
+    pub fn id(&self) -> Id {
+        *self.p
+    }
 }
@@ -1,13 +1,44 @@
 //! Virtualization.framework module
 
+pub mod audio_device;
 pub mod boot_loader;
+pub mod console_device;
+#[cfg(feature = "linux-guest")]
+pub mod console_preflight;
+pub mod console_tee;
+pub mod console_transcript;
+pub mod device;
+pub mod directory_sharing;
+pub mod efi_boot_order;
 pub mod entropy_device;
+pub mod error;
+pub mod error_events;
 pub mod graphics_device;
+#[cfg(feature = "linux-guest")]
+pub mod guest_disks;
+pub mod handle;
+pub mod input_devices;
+#[cfg(feature = "macos-guest")]
+pub mod installer;
+#[cfg(feature = "linux-guest")]
+pub mod kernel_inspect;
 pub mod keyboard;
+pub mod lifecycle;
 pub mod memory_device;
 pub mod network_device;
+pub mod platform;
 pub mod pointing_device;
+#[cfg(feature = "macos-guest")]
+pub mod restore_image;
 pub mod serial_port;
+pub mod share_scan;
 pub mod socket_device;
 pub mod storage_device;
+pub mod tcp_console;
+pub mod topology;
+pub mod unix_console;
+pub mod validation;
+#[cfg(feature = "gui")]
+pub mod view;
 pub mod virtual_machine;
+pub mod vsock;
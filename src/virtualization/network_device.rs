@@ -1,9 +1,14 @@
 //! network device module
 
-use crate::base::{Id, NSString};
+use crate::base::{DispatchQueue, Id, NSString, NIL};
+use crate::runtime::{alloc, debug_assert_non_nil, owned, retained, vz_class};
+use crate::virtualization::device::{FrozenFlag, VZDeviceConfiguration};
+use crate::virtualization::error::FrozenConfigError;
+
+use std::any::Any;
 
 use objc::rc::StrongPtr;
-use objc::{class, msg_send, sel, sel_impl};
+use objc::{msg_send, sel, sel_impl};
 
 /// common behaviors for network device attachment
 pub trait VZNetworkDeviceAttachment {
@@ -16,12 +21,18 @@ pub struct VZNATNetworkDeviceAttachment(StrongPtr);
 impl VZNATNetworkDeviceAttachment {
     pub fn new() -> VZNATNetworkDeviceAttachment {
         unsafe {
-            let p = StrongPtr::new(msg_send![class!(VZNATNetworkDeviceAttachment), new]);
+            let p = owned(msg_send![vz_class!(VZNATNetworkDeviceAttachment), new]);
             VZNATNetworkDeviceAttachment(p)
         }
     }
 }
 
+impl Default for VZNATNetworkDeviceAttachment {
+    fn default() -> Self {
+        VZNATNetworkDeviceAttachment::new()
+    }
+}
+
 impl VZNetworkDeviceAttachment for VZNATNetworkDeviceAttachment {
     fn id(&self) -> Id {
         *self.0
@@ -32,13 +43,11 @@ impl VZNetworkDeviceAttachment for VZNATNetworkDeviceAttachment {
 pub trait VZBridgedNetworkInterface {
     fn id(&self) -> Id;
     fn localized_display_name(&self) -> NSString {
-        let _obj = self.id();
-        let p = unsafe { StrongPtr::retain(msg_send![class!(_obj), localizedDisplayName]) };
+        let p = unsafe { retained(msg_send![self.id(), localizedDisplayName]) };
         NSString(p)
     }
     fn identifier(&self) -> NSString {
-        let _obj = self.id();
-        let p = unsafe { StrongPtr::retain(msg_send![class!(_obj), identifier]) };
+        let p = unsafe { retained(msg_send![self.id(), identifier]) };
         NSString(p)
     }
 }
@@ -49,11 +58,25 @@ pub struct VZBridgedNetworkDeviceAttachment(StrongPtr);
 impl VZBridgedNetworkDeviceAttachment {
     pub fn new<T: VZBridgedNetworkInterface>(interface: T) -> VZBridgedNetworkDeviceAttachment {
         unsafe {
-            let obj: Id = msg_send![class!(VZBridgedNetworkDeviceAttachment), alloc];
-            let p = StrongPtr::new(msg_send![obj, initWithInterface:interface.id()]);
+            let obj = alloc(vz_class!(VZBridgedNetworkDeviceAttachment));
+            let p = owned(msg_send![obj, initWithInterface:interface.id()]);
             VZBridgedNetworkDeviceAttachment(p)
         }
     }
+
+    /// Creates the attachment from an interface obtained elsewhere, e.g. from
+    /// `VZBridgedNetworkInterface.networkInterfaces` through another Objective-C binding crate.
+    ///
+    /// The interface is borrowed: the attachment retains it itself, and the caller's reference
+    /// stays the caller's to release.
+    ///
+    /// # Safety
+    /// `interface` must be a valid, non-nil `VZBridgedNetworkInterface`.
+    pub unsafe fn from_raw_interface(interface: Id) -> VZBridgedNetworkDeviceAttachment {
+        let obj = alloc(vz_class!(VZBridgedNetworkDeviceAttachment));
+        let p = owned(msg_send![obj, initWithInterface: interface]);
+        VZBridgedNetworkDeviceAttachment(p)
+    }
 }
 
 impl VZNetworkDeviceAttachment for VZBridgedNetworkDeviceAttachment {
@@ -67,59 +90,141 @@ pub struct VZMACAddress(pub StrongPtr);
 
 impl VZMACAddress {
     pub fn new() -> VZMACAddress {
-        let p = unsafe { StrongPtr::new(msg_send![class!(VZMACAddress), new]) };
+        let p = unsafe { owned(msg_send![vz_class!(VZMACAddress), new]) };
+        debug_assert_non_nil!(p, "+[VZMACAddress new]");
         VZMACAddress(p)
     }
     pub fn random_locally_administered_address() -> VZMACAddress {
+        // A class factory method: the result is autoreleased, not owned.
         let p = unsafe {
-            StrongPtr::new(msg_send![
-                class!(VZMACAddress),
+            retained(msg_send![
+                vz_class!(VZMACAddress),
                 randomLocallyAdministeredAddress
             ])
         };
+        debug_assert_non_nil!(p, "+[VZMACAddress randomLocallyAdministeredAddress]");
         VZMACAddress(p)
     }
 
-    pub fn init_with_string(s: &str) -> VZMACAddress {
+    /// `None` unless `s` is six hexadecimal octets separated by colons.
+    pub fn init_with_string(s: &str) -> Option<VZMACAddress> {
         let string = NSString::new(s);
-        let p =
-            unsafe { StrongPtr::new(msg_send![class!(VZMACAddress), initWithString:*string.0]) };
-        VZMACAddress(p)
+        let p = unsafe {
+            let i = alloc(vz_class!(VZMACAddress));
+            owned(msg_send![i, initWithString:*string.0])
+        };
+        if *p == NIL {
+            return None;
+        }
+        Some(VZMACAddress(p))
+    }
+
+    /// The address in the `xx:xx:xx:xx:xx:xx` form accepted by [`VZMACAddress::init_with_string`].
+    pub fn string(&self) -> String {
+        unsafe { NSString(retained(msg_send![*self.0, string])).as_str().to_string() }
     }
 }
 
-/// common configure of network device
-pub trait VZNetworkDeviceConfiguration {
-    fn id(&self) -> Id;
+impl Default for VZMACAddress {
+    fn default() -> Self {
+        VZMACAddress::new()
+    }
 }
 
+/// common configure of network device
+pub trait VZNetworkDeviceConfiguration: VZDeviceConfiguration {}
+
+impl<T: VZNetworkDeviceConfiguration + ?Sized> VZNetworkDeviceConfiguration for Box<T> {}
+
 /// configure of network device through the Virtio interface
-pub struct VZVirtioNetworkDeviceConfiguration(StrongPtr);
+///
+/// Clones share the underlying object; once any of them is part of a virtual machine the setters
+/// fail with [`FrozenConfigError`].
+#[derive(Clone)]
+pub struct VZVirtioNetworkDeviceConfiguration(StrongPtr, FrozenFlag);
 
 impl VZVirtioNetworkDeviceConfiguration {
     pub fn new<T: VZNetworkDeviceAttachment>(attachment: T) -> VZVirtioNetworkDeviceConfiguration {
         unsafe {
-            let p = StrongPtr::new(msg_send![class!(VZVirtioNetworkDeviceConfiguration), new]);
-            let _: Id = msg_send![*p, setAttachment:attachment.id()];
-            VZVirtioNetworkDeviceConfiguration(p)
+            let p = owned(msg_send![
+                vz_class!(VZVirtioNetworkDeviceConfiguration),
+                new
+            ]);
+            let _: () = msg_send![*p, setAttachment:attachment.id()];
+            VZVirtioNetworkDeviceConfiguration(p, FrozenFlag::default())
         }
     }
 
-    pub fn set_attachment<T: VZNetworkDeviceAttachment>(&mut self, attachment: T) {
+    /// Replaces the attachment.
+    ///
+    /// Takes `&mut self` because configuration objects are plain mutable Objective-C objects with no
+    /// internal synchronization; exclusive access keeps two threads from swapping it concurrently.
+    pub fn set_attachment<T: VZNetworkDeviceAttachment>(
+        &mut self,
+        attachment: T,
+    ) -> Result<(), FrozenConfigError> {
+        self.1
+            .check("VZVirtioNetworkDeviceConfiguration::set_attachment")?;
         unsafe {
-            let _: Id = msg_send![*self.0, setAttachment:attachment.id()];
+            let _: () = msg_send![*self.0, setAttachment:attachment.id()];
         }
+        Ok(())
     }
 
-    pub fn set_mac_address(&mut self, mac: VZMACAddress) {
+    /// Sets the MAC address. Takes `&mut self` for the same reason as [`Self::set_attachment`].
+    pub fn set_mac_address(&mut self, mac: VZMACAddress) -> Result<(), FrozenConfigError> {
+        self.1
+            .check("VZVirtioNetworkDeviceConfiguration::set_mac_address")?;
         unsafe {
-            let _: Id = msg_send![*self.0, setMACAddress:*mac.0];
+            let _: () = msg_send![*self.0, setMACAddress:*mac.0];
         }
+        Ok(())
     }
 }
 
-impl VZNetworkDeviceConfiguration for VZVirtioNetworkDeviceConfiguration {
+impl VZDeviceConfiguration for VZVirtioNetworkDeviceConfiguration {
     fn id(&self) -> Id {
         *self.0
     }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn frozen_flag(&self) -> Option<FrozenFlag> {
+        Some(self.1.clone())
+    }
+}
+
+impl VZNetworkDeviceConfiguration for VZVirtioNetworkDeviceConfiguration {}
+
+/// A network device of a running virtual machine; see
+/// [`VZVirtualMachine::network_devices`](crate::virtualization::virtual_machine::VZVirtualMachine::network_devices).
+pub struct VZNetworkDevice {
+    p: StrongPtr,
+    queue: DispatchQueue,
+}
+
+impl VZNetworkDevice {
+    pub(crate) fn from_raw(p: StrongPtr, queue: DispatchQueue) -> Self {
+        VZNetworkDevice { p, queue }
+    }
+
+    /// Replaces the attachment while the machine runs, e.g. to move the guest to another
+    /// network. The guest sees its link go down and up again.
+    ///
+    /// The property is set on the VM's queue, so this panics if called from that queue.
+    pub fn set_attachment<T: VZNetworkDeviceAttachment>(&self, attachment: T) {
+        self.queue
+            .assert_not_current("VZNetworkDevice::set_attachment");
+        let p = *self.p;
+        let attachment = attachment.id();
+        self.queue.exec_sync(move || unsafe {
+            let _: () = msg_send![p, setAttachment: attachment];
+        })
+    }
+
+    pub fn id(&self) -> Id {
+        *self.p
+    }
 }
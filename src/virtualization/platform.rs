@@ -0,0 +1,347 @@
+//! platform module
+
+use crate::base::{Id, NSData, NIL};
+#[cfg(feature = "macos-guest")]
+use crate::base::{NSError, NSUInteger, NSURL};
+#[cfg(feature = "macos-guest")]
+use crate::features::{self, UnsupportedOnThisHost};
+use crate::features::{ClassLookup, ClassNotAvailable};
+use crate::runtime::{alloc, owned, retained};
+#[cfg(feature = "macos-guest")]
+use crate::runtime::{call_with_error, from_objc_bool, vz_class};
+#[cfg(feature = "macos-guest")]
+use crate::virtualization::error::{ResultExt, VZError, VZErrorCode, VZErrorCtx};
+#[cfg(feature = "macos-guest")]
+use crate::virtualization::restore_image::{VZMacHardwareModel, VZMacMachineIdentifier};
+
+#[cfg(feature = "macos-guest")]
+use std::io;
+#[cfg(feature = "macos-guest")]
+use std::path::{Path, PathBuf};
+
+use objc::rc::StrongPtr;
+#[cfg(feature = "macos-guest")]
+use objc::runtime::BOOL;
+use objc::{msg_send, sel, sel_impl};
+
+static GENERIC_MACHINE_IDENTIFIER: ClassLookup =
+    ClassLookup::new("VZGenericMachineIdentifier", "macOS 13");
+static GENERIC_PLATFORM: ClassLookup =
+    ClassLookup::new("VZGenericPlatformConfiguration", "macOS 12");
+
+/// common behaviors of platform configurations
+pub trait VZPlatformConfiguration {
+    fn id(&self) -> Id;
+}
+
+/// Identifies a virtual machine with the generic platform across boots. Save its
+/// [`data_representation`](Self::data_representation) to give the machine a stable identity.
+pub struct VZGenericMachineIdentifier(StrongPtr);
+
+impl VZGenericMachineIdentifier {
+    /// A new, unique identifier.
+    ///
+    /// # Panics
+    /// Before macOS 13; see [`try_new`](Self::try_new).
+    #[track_caller]
+    pub fn new() -> VZGenericMachineIdentifier {
+        Self::try_new().unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    pub fn try_new() -> Result<VZGenericMachineIdentifier, ClassNotAvailable> {
+        let class = GENERIC_MACHINE_IDENTIFIER.get()?;
+        unsafe { Ok(VZGenericMachineIdentifier(owned(msg_send![class, new]))) }
+    }
+
+    /// Restores an identifier saved earlier; `None` if the bytes are not a valid identifier, or
+    /// before macOS 13.
+    pub fn from_data_representation(bytes: &[u8]) -> Option<VZGenericMachineIdentifier> {
+        let class = GENERIC_MACHINE_IDENTIFIER.get().ok()?;
+        let data = NSData::with_bytes(bytes);
+        unsafe {
+            let i = alloc(class);
+            let p: Id = msg_send![i, initWithDataRepresentation:*data.0];
+            if p == NIL {
+                None
+            } else {
+                Some(VZGenericMachineIdentifier(owned(p)))
+            }
+        }
+    }
+
+    pub fn data_representation(&self) -> Vec<u8> {
+        unsafe {
+            let p: Id = msg_send![*self.0, dataRepresentation];
+            NSData(retained(p)).to_vec()
+        }
+    }
+
+    pub fn id(&self) -> Id {
+        *self.0
+    }
+}
+
+impl Default for VZGenericMachineIdentifier {
+    fn default() -> Self {
+        VZGenericMachineIdentifier::new()
+    }
+}
+
+/// The platform for virtual machines that are not macOS guests.
+pub struct VZGenericPlatformConfiguration(StrongPtr);
+
+impl VZGenericPlatformConfiguration {
+    /// # Panics
+    /// Before macOS 12; see [`try_new`](Self::try_new).
+    #[track_caller]
+    pub fn new() -> VZGenericPlatformConfiguration {
+        Self::try_new().unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    pub fn try_new() -> Result<VZGenericPlatformConfiguration, ClassNotAvailable> {
+        let class = GENERIC_PLATFORM.get()?;
+        unsafe { Ok(VZGenericPlatformConfiguration(owned(msg_send![class, new]))) }
+    }
+
+    pub fn set_machine_identifier(&mut self, identifier: &VZGenericMachineIdentifier) {
+        unsafe {
+            let _: () = msg_send![*self.0, setMachineIdentifier:*identifier.0];
+        }
+    }
+}
+
+impl Default for VZGenericPlatformConfiguration {
+    fn default() -> Self {
+        VZGenericPlatformConfiguration::new()
+    }
+}
+
+impl VZPlatformConfiguration for VZGenericPlatformConfiguration {
+    fn id(&self) -> Id {
+        *self.0
+    }
+}
+
+#[cfg(feature = "macos-guest")]
+pub struct VZMacAuxiliaryStorageInitializationOption(NSUInteger);
+
+#[cfg(feature = "macos-guest")]
+impl VZMacAuxiliaryStorageInitializationOption {
+    /// Lets [`VZMacAuxiliaryStorage::create`] replace a file that already exists at the path.
+    pub fn allow_overwrite() -> Self {
+        Self(1 << 0)
+    }
+}
+
+/// Options for creating auxiliary storage, parallel to
+/// [`VZEFIVariableStoreInitializationOptions`](crate::virtualization::boot_loader::VZEFIVariableStoreInitializationOptions).
+#[cfg(feature = "macos-guest")]
+#[derive(Default)]
+pub struct VZMacAuxiliaryStorageInitializationOptions {
+    options: Vec<VZMacAuxiliaryStorageInitializationOption>,
+}
+
+#[cfg(feature = "macos-guest")]
+impl VZMacAuxiliaryStorageInitializationOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with(mut self, option: VZMacAuxiliaryStorageInitializationOption) -> Self {
+        self.options.push(option);
+        self
+    }
+
+    fn into_raw(self) -> NSUInteger {
+        self.options
+            .iter()
+            .fold(NSUInteger::default(), |acc, v| acc | v.0)
+    }
+}
+
+/// The auxiliary storage of a macOS guest: the NVRAM its boot firmware keeps between boots.
+///
+/// It is created for one [`VZMacHardwareModel`], and the guest must run with that model. A
+/// guest started with storage created for another model fails with an error
+/// [`is_model_mismatch`](Self::is_model_mismatch) recognizes.
+#[cfg(feature = "macos-guest")]
+pub struct VZMacAuxiliaryStorage(StrongPtr);
+
+#[cfg(feature = "macos-guest")]
+impl VZMacAuxiliaryStorage {
+    /// Creates storage for `model` at `path`. Fails if the file exists, unless `options` allow
+    /// overwriting it.
+    ///
+    /// ```no_run
+    /// # use virtualization_rs::virtualization::platform::*;
+    /// # use virtualization_rs::virtualization::restore_image::VZMacHardwareModel;
+    /// # let model = VZMacHardwareModel::from_data(&[]).unwrap();
+    /// let storage = match VZMacAuxiliaryStorage::create(
+    ///     "/vm/AuxiliaryStorage",
+    ///     &model,
+    ///     VZMacAuxiliaryStorageInitializationOptions::new(),
+    /// ) {
+    ///     Ok(storage) => storage,
+    ///     // failed to create auxiliary storage '/vm/AuxiliaryStorage': NSCocoaErrorDomain ...
+    ///     Err(e) => panic!("{}", e),
+    /// };
+    /// ```
+    pub fn create<P: AsRef<Path>>(
+        path: P,
+        model: &VZMacHardwareModel,
+        options: VZMacAuxiliaryStorageInitializationOptions,
+    ) -> Result<Self, VZErrorCtx> {
+        let path = path.as_ref();
+        let resource = format!("'{}'", path.display());
+        let url = file_url(path).ctx("create auxiliary storage", resource.as_str())?;
+        let options = options.into_raw();
+        let storage = unsafe {
+            call_with_error(|error| {
+                let i = alloc(vz_class!(VZMacAuxiliaryStorage));
+                owned(msg_send![
+                    i,
+                    initCreatingStorageAtURL: *url.0
+                    hardwareModel: model.id()
+                    options: options
+                    error: error
+                ])
+            })
+        };
+        storage.map(Self).ctx("create auxiliary storage", resource)
+    }
+
+    /// Opens storage created earlier. Fails with `ENOENT` if there is no file at `path`, and with
+    /// `ENOTSUP` on Intel hosts.
+    ///
+    /// The framework's initializers for existing storage do not report errors and do not read
+    /// the file; a file that is not auxiliary storage fails when the guest starts. `initWithURL:`
+    /// is used where the framework has it (macOS 13), `initWithContentsOfURL:` before.
+    pub fn open_existing<P: AsRef<Path>>(path: P) -> Result<Self, VZErrorCtx> {
+        let path = path.as_ref();
+        let resource = format!("'{}'", path.display());
+        Self::open(path).ctx("open auxiliary storage", resource)
+    }
+
+    fn open(path: &Path) -> Result<Self, NSError> {
+        features::require("VZMacAuxiliaryStorage").map_err(|_| NSError::posix(libc::ENOTSUP))?;
+        let url = file_url(path)?;
+        if !url.check_resource_is_reachable_and_return_error() {
+            return Err(NSError::posix(libc::ENOENT));
+        }
+        unsafe {
+            let i = alloc(vz_class!(VZMacAuxiliaryStorage));
+            let modern: BOOL = msg_send![i, respondsToSelector: sel!(initWithURL:)];
+            let p: Id = if from_objc_bool(modern) {
+                msg_send![i, initWithURL: *url.0]
+            } else {
+                msg_send![i, initWithContentsOfURL: *url.0]
+            };
+            Ok(Self(owned(p)))
+        }
+    }
+
+    /// Deletes the storage at `path` and creates new storage for `model`, if `start_error`
+    /// is the framework refusing to start a guest because the storage was created for another
+    /// hardware model. Returns `Ok(None)`, touching nothing, for any other error.
+    ///
+    /// This is destructive: the guest loses everything its firmware kept in NVRAM, such as
+    /// the startup disk selection. Only call it when `model` is the one the guest was installed
+    /// with; a guest installed for another model does not boot with either storage.
+    pub fn recreate_if_model_mismatch<P: AsRef<Path>>(
+        path: P,
+        model: &VZMacHardwareModel,
+        start_error: &NSError,
+    ) -> Result<Option<Self>, VZErrorCtx> {
+        if !Self::is_model_mismatch(start_error) {
+            return Ok(None);
+        }
+        let options = VZMacAuxiliaryStorageInitializationOptions::new()
+            .with(VZMacAuxiliaryStorageInitializationOption::allow_overwrite());
+        Self::create(path, model, options).map(Some)
+    }
+
+    /// Whether `error`, as returned from starting or validating a virtual machine, reports
+    /// auxiliary storage that does not match the configured hardware model.
+    ///
+    /// `VZError.h` has no dedicated code for this: the framework reports it as
+    /// [`VZErrorCode::InvalidVirtualMachineConfiguration`] with a description naming the
+    /// auxiliary storage, directly or in an underlying error. Both are checked.
+    pub fn is_model_mismatch(error: &NSError) -> bool {
+        let mut error = Some(error.clone());
+        while let Some(e) = error {
+            let vz = VZError(e);
+            if vz.code() == Some(VZErrorCode::InvalidVirtualMachineConfiguration)
+                && mentions_auxiliary_storage(vz.ns_error())
+            {
+                return true;
+            }
+            error = vz.underlying_error();
+        }
+        false
+    }
+
+    /// The file URL of the storage.
+    pub fn url(&self) -> NSURL {
+        unsafe { NSURL(retained(msg_send![*self.0, URL])) }
+    }
+
+    pub fn path(&self) -> PathBuf {
+        PathBuf::from(self.url().path().as_str())
+    }
+
+    /// The size of the file on disk, e.g. to plan the capacity of a directory of bundles.
+    pub fn size_bytes(&self) -> io::Result<u64> {
+        std::fs::metadata(self.path()).map(|m| m.len())
+    }
+
+    pub fn id(&self) -> Id {
+        *self.0
+    }
+}
+
+/// The platform of a macOS guest: the hardware model it was installed for, its machine
+/// identifier and its auxiliary storage, e.g. as saved in a
+/// [`MacVmBundle`](crate::mac_bundle::MacVmBundle).
+#[cfg(feature = "macos-guest")]
+pub struct VZMacPlatformConfiguration(StrongPtr);
+
+#[cfg(feature = "macos-guest")]
+impl VZMacPlatformConfiguration {
+    /// Fails on Intel hosts.
+    pub fn new(
+        hardware_model: &VZMacHardwareModel,
+        machine_identifier: &VZMacMachineIdentifier,
+        auxiliary_storage: &VZMacAuxiliaryStorage,
+    ) -> Result<VZMacPlatformConfiguration, UnsupportedOnThisHost> {
+        features::require("VZMacPlatformConfiguration")?;
+        unsafe {
+            let p = owned(msg_send![vz_class!(VZMacPlatformConfiguration), new]);
+            let _: () = msg_send![*p, setHardwareModel: hardware_model.id()];
+            let _: () = msg_send![*p, setMachineIdentifier: machine_identifier.id()];
+            let _: () = msg_send![*p, setAuxiliaryStorage: auxiliary_storage.id()];
+            Ok(VZMacPlatformConfiguration(p))
+        }
+    }
+}
+
+#[cfg(feature = "macos-guest")]
+impl VZPlatformConfiguration for VZMacPlatformConfiguration {
+    fn id(&self) -> Id {
+        *self.0
+    }
+}
+
+#[cfg(feature = "macos-guest")]
+fn file_url(path: &Path) -> Result<NSURL, NSError> {
+    path.to_str()
+        .and_then(|path| NSURL::file_url_with_path(path, false))
+        .ok_or_else(|| NSError::posix(libc::EINVAL))
+}
+
+#[cfg(feature = "macos-guest")]
+fn mentions_auxiliary_storage(error: &NSError) -> bool {
+    Some(error.localized_description())
+        .into_iter()
+        .chain(error.localized_failure_reason())
+        .any(|s| s.as_str().to_lowercase().contains("auxiliary storage"))
+}
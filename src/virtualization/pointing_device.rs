@@ -1,14 +1,24 @@
 //! pointing device module
 
 use crate::base::Id;
+use crate::features::{self, ClassLookup, ClassNotAvailable, UnsupportedOnThisHost};
+use crate::runtime::{owned, vz_class};
+use crate::virtualization::device::VZDeviceConfiguration;
+
+use std::any::Any;
 
 use objc::rc::StrongPtr;
-use objc::{class, msg_send, sel, sel_impl};
+use objc::{msg_send, sel, sel_impl};
+
+static USB_SCREEN_COORDINATE_POINTING: ClassLookup = ClassLookup::new(
+    "VZUSBScreenCoordinatePointingDeviceConfiguration",
+    "macOS 12",
+);
 
 /// The base class for a pointing device configuration.
-pub trait VZPointingDeviceConfiguration {
-    fn id(&self) -> Id;
-}
+pub trait VZPointingDeviceConfiguration: VZDeviceConfiguration {}
+
+impl<T: VZPointingDeviceConfiguration + ?Sized> VZPointingDeviceConfiguration for Box<T> {}
 
 /// The class that represents the configuration for a Mac trackpad.
 ///
@@ -20,34 +30,59 @@ pub struct VZMacTrackpadConfiguration(StrongPtr);
 
 impl VZMacTrackpadConfiguration {
     /// Creates a new Mac trackpad configuration.
-    pub fn new() -> Self {
-        Self(unsafe { StrongPtr::new(msg_send![class!(VZMacTrackpadConfiguration), new]) })
+    pub fn new() -> Result<Self, UnsupportedOnThisHost> {
+        features::require("VZMacTrackpadConfiguration")?;
+        Ok(Self(unsafe {
+            owned(msg_send![vz_class!(VZMacTrackpadConfiguration), new])
+        }))
     }
 }
 
-impl VZPointingDeviceConfiguration for VZMacTrackpadConfiguration {
+impl VZDeviceConfiguration for VZMacTrackpadConfiguration {
     fn id(&self) -> Id {
         *self.0
     }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
 }
 
+impl VZPointingDeviceConfiguration for VZMacTrackpadConfiguration {}
+
 /// An object that defines the configuration for a USB pointing device that reports absolute coordinates.
 pub struct VZUSBScreenCoordinatePointingDeviceConfiguration(StrongPtr);
 
 impl VZUSBScreenCoordinatePointingDeviceConfiguration {
     /// Creates a new pointing device.
+    ///
+    /// # Panics
+    /// Before macOS 12; see [`try_new`](Self::try_new).
+    #[track_caller]
     pub fn new() -> Self {
-        Self(unsafe {
-            StrongPtr::new(msg_send![
-                class!(VZUSBScreenCoordinatePointingDeviceConfiguration),
-                new
-            ])
-        })
+        Self::try_new().unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    pub fn try_new() -> Result<Self, ClassNotAvailable> {
+        let class = USB_SCREEN_COORDINATE_POINTING.get()?;
+        Ok(Self(unsafe { owned(msg_send![class, new]) }))
+    }
+}
+
+impl Default for VZUSBScreenCoordinatePointingDeviceConfiguration {
+    fn default() -> Self {
+        VZUSBScreenCoordinatePointingDeviceConfiguration::new()
     }
 }
 
-impl VZPointingDeviceConfiguration for VZUSBScreenCoordinatePointingDeviceConfiguration {
+impl VZDeviceConfiguration for VZUSBScreenCoordinatePointingDeviceConfiguration {
     fn id(&self) -> Id {
         *self.0
     }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
 }
+
+impl VZPointingDeviceConfiguration for VZUSBScreenCoordinatePointingDeviceConfiguration {}
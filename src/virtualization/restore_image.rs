@@ -0,0 +1,372 @@
+//! restore image module
+//!
+//! Reads the version and host requirements of a macOS restore image (`.ipsw`) before a
+//! multi-gigabyte install is started.
+//!
+//! # Examples
+//! ```rust
+//! RestoreImageInfo::from_file("UniversalMac_13.0_22A380_Restore.ipsw", |outcome| {
+//!     if let CompletionOutcome::Success(info) = outcome {
+//!         for violation in info.check_against(4, 8 * 1024 * 1024 * 1024) {
+//!             println!("{}", violation);
+//!         }
+//!     }
+//! })?;
+//! ```
+//!
+//! With the `restore-download` feature, [`Downloader`] fetches the image itself, resuming
+//! interrupted downloads and verifying the result.
+
+#[cfg(feature = "restore-download")]
+mod download;
+
+#[cfg(feature = "restore-download")]
+pub use self::download::{
+    digest_from_hex, sha256_file, DownloadError, DownloadHandle, DownloadProgress, Downloader,
+};
+
+use crate::base::{
+    Id, NSData, NSError, NSOperatingSystemVersion, NSString, NSUInteger, NIL, NSURL,
+};
+use crate::features::{self, UnsupportedOnThisHost};
+use crate::runtime::{alloc, from_objc_bool, owned, retained, vz_class};
+use crate::virtualization::error::CompletionOutcome;
+
+use std::cell::Cell;
+use std::fmt;
+use std::path::Path;
+
+use block::{ConcreteBlock, RcBlock};
+use objc::rc::StrongPtr;
+use objc::runtime::BOOL;
+use objc::{msg_send, sel, sel_impl};
+
+/// A Mac hardware model that a restore image can be installed on.
+///
+/// A macOS guest must run with the model it was installed with. Save its
+/// [`data_representation`](Self::data_representation), e.g. with
+/// [`MacVmBundle`](crate::mac_bundle::MacVmBundle), and check
+/// [`is_supported`](Self::is_supported) on the host it is restored on.
+pub struct VZMacHardwareModel(StrongPtr);
+
+impl VZMacHardwareModel {
+    /// Restores a model saved earlier; `None` if the bytes are not a valid model, or on hosts
+    /// without the class.
+    pub fn from_data(bytes: &[u8]) -> Option<VZMacHardwareModel> {
+        features::require("VZMacHardwareModel").ok()?;
+        let data = NSData::with_bytes(bytes);
+        unsafe {
+            let i = alloc(vz_class!(VZMacHardwareModel));
+            let p: Id = msg_send![i, initWithDataRepresentation:*data.0];
+            if p == NIL {
+                None
+            } else {
+                Some(VZMacHardwareModel(owned(p)))
+            }
+        }
+    }
+
+    pub fn data_representation(&self) -> Vec<u8> {
+        unsafe {
+            let p: Id = msg_send![*self.0, dataRepresentation];
+            NSData(retained(p)).to_vec()
+        }
+    }
+
+    /// Whether this host can run virtual machines of this model.
+    pub fn is_supported(&self) -> bool {
+        let b: BOOL = unsafe { msg_send![*self.0, isSupported] };
+        from_objc_bool(b)
+    }
+
+    pub fn id(&self) -> Id {
+        *self.0
+    }
+}
+
+/// Identifies a macOS guest across boots, as [`VZGenericMachineIdentifier`] does other guests.
+/// Save its [`data_representation`](Self::data_representation) with the hardware model.
+///
+/// [`VZGenericMachineIdentifier`]: crate::virtualization::platform::VZGenericMachineIdentifier
+pub struct VZMacMachineIdentifier(StrongPtr);
+
+impl VZMacMachineIdentifier {
+    /// A new, unique identifier. Fails on Intel hosts.
+    pub fn new() -> Result<VZMacMachineIdentifier, UnsupportedOnThisHost> {
+        features::require("VZMacMachineIdentifier")?;
+        unsafe {
+            Ok(VZMacMachineIdentifier(owned(msg_send![
+                vz_class!(VZMacMachineIdentifier),
+                new
+            ])))
+        }
+    }
+
+    /// Restores an identifier saved earlier; `None` if the bytes are not a valid identifier, or
+    /// on hosts without the class.
+    pub fn from_data(bytes: &[u8]) -> Option<VZMacMachineIdentifier> {
+        features::require("VZMacMachineIdentifier").ok()?;
+        let data = NSData::with_bytes(bytes);
+        unsafe {
+            let i = alloc(vz_class!(VZMacMachineIdentifier));
+            let p: Id = msg_send![i, initWithDataRepresentation:*data.0];
+            if p == NIL {
+                None
+            } else {
+                Some(VZMacMachineIdentifier(owned(p)))
+            }
+        }
+    }
+
+    pub fn data_representation(&self) -> Vec<u8> {
+        unsafe {
+            let p: Id = msg_send![*self.0, dataRepresentation];
+            NSData(retained(p)).to_vec()
+        }
+    }
+
+    pub fn id(&self) -> Id {
+        *self.0
+    }
+}
+
+/// The hardware model and minimum resources a restore image needs.
+pub struct VZMacOSConfigurationRequirements(StrongPtr);
+
+impl VZMacOSConfigurationRequirements {
+    pub fn hardware_model(&self) -> VZMacHardwareModel {
+        unsafe { VZMacHardwareModel(retained(msg_send![*self.0, hardwareModel])) }
+    }
+
+    pub fn minimum_supported_cpu_count(&self) -> usize {
+        let count: NSUInteger = unsafe { msg_send![*self.0, minimumSupportedCPUCount] };
+        count as usize
+    }
+
+    /// In bytes.
+    pub fn minimum_supported_memory_size(&self) -> u64 {
+        unsafe { msg_send![*self.0, minimumSupportedMemorySize] }
+    }
+}
+
+/// A macOS restore image.
+pub struct VZMacOSRestoreImage(StrongPtr);
+
+// Immutable once loaded; only its properties are read.
+unsafe impl Send for VZMacOSRestoreImage {}
+unsafe impl Sync for VZMacOSRestoreImage {}
+
+impl VZMacOSRestoreImage {
+    /// Loads the restore image at `path`. `completion_handler` runs on an arbitrary queue.
+    ///
+    /// Fails without calling `completion_handler` on Intel hosts. A path that cannot be a file
+    /// URL, e.g. an empty one, fails through `completion_handler` with `EINVAL` right away.
+    pub fn load_file<P, F>(path: P, completion_handler: F) -> Result<(), UnsupportedOnThisHost>
+    where
+        P: AsRef<Path>,
+        F: FnOnce(CompletionOutcome<VZMacOSRestoreImage>) + Send + 'static,
+    {
+        features::require("VZMacOSRestoreImage")?;
+        let url = match NSURL::file_url_with_path(&path.as_ref().to_string_lossy(), false) {
+            Some(url) => url,
+            None => {
+                completion_handler(CompletionOutcome::from_ns_error(NSError::posix(
+                    libc::EINVAL,
+                )));
+                return Ok(());
+            }
+        };
+        let block = image_block(completion_handler);
+        unsafe {
+            let _: () = msg_send![
+                vz_class!(VZMacOSRestoreImage),
+                loadFileURL: *url.0
+                completionHandler: &*block
+            ];
+        }
+        Ok(())
+    }
+
+    /// Asks Apple for the latest restore image this host supports. Nothing is downloaded; the
+    /// image's [`url`](Self::url) says where from. `completion_handler` runs on an arbitrary
+    /// queue.
+    ///
+    /// Fails without calling `completion_handler` on Intel hosts.
+    pub fn fetch_latest_supported<F>(completion_handler: F) -> Result<(), UnsupportedOnThisHost>
+    where
+        F: FnOnce(CompletionOutcome<VZMacOSRestoreImage>) + Send + 'static,
+    {
+        features::require("VZMacOSRestoreImage")?;
+        let block = image_block(completion_handler);
+        unsafe {
+            let _: () = msg_send![
+                vz_class!(VZMacOSRestoreImage),
+                fetchLatestSupportedWithCompletionHandler: &*block
+            ];
+        }
+        Ok(())
+    }
+
+    /// Where the image is: a file URL for a loaded image, the download URL for a fetched one.
+    pub fn url(&self) -> String {
+        unsafe { NSURL(retained(msg_send![*self.0, URL])) }
+            .absolute_string()
+            .as_str()
+            .to_string()
+    }
+
+    /// The build of the operating system, e.g. `22A380`.
+    pub fn build_version(&self) -> NSString {
+        unsafe { NSString(retained(msg_send![*self.0, buildVersion])) }
+    }
+
+    pub fn operating_system_version(&self) -> NSOperatingSystemVersion {
+        unsafe { msg_send![*self.0, operatingSystemVersion] }
+    }
+
+    /// The requirements of the most capable configuration this host supports; `None` if the
+    /// image cannot be installed on this host.
+    pub fn most_featureful_supported_configuration(
+        &self,
+    ) -> Option<VZMacOSConfigurationRequirements> {
+        let p: Id = unsafe { msg_send![*self.0, mostFeaturefulSupportedConfiguration] };
+        if p == NIL {
+            None
+        } else {
+            Some(VZMacOSConfigurationRequirements(unsafe { retained(p) }))
+        }
+    }
+
+    pub fn id(&self) -> Id {
+        *self.0
+    }
+}
+
+/// The completion block of the class methods that hand out a restore image.
+fn image_block<F>(completion_handler: F) -> RcBlock<(Id, Id), ()>
+where
+    F: FnOnce(CompletionOutcome<VZMacOSRestoreImage>) + Send + 'static,
+{
+    let completion_handler = Cell::new(Some(completion_handler));
+    let block = ConcreteBlock::new(move |image: Id, error: Id| {
+        let outcome = if error == NIL {
+            CompletionOutcome::Success(VZMacOSRestoreImage(unsafe { retained(image) }))
+        } else {
+            CompletionOutcome::from_ns_error(NSError(unsafe { retained(error) }))
+        };
+        if let Some(f) = completion_handler.take() {
+            f(outcome);
+        }
+    });
+    block.copy()
+}
+
+/// What a restore image is and what it needs from this host.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RestoreImageInfo {
+    pub build_version: String,
+    /// Major, minor and patch version.
+    pub os_version: (u64, u64, u64),
+    /// Whether the image has a configuration whose hardware model this host supports.
+    pub supported: bool,
+    /// 0 if unsupported.
+    pub minimum_cpu_count: usize,
+    /// In bytes; 0 if unsupported.
+    pub minimum_memory_size: u64,
+}
+
+/// A reason a planned virtual machine cannot run a restore image.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RequirementViolation {
+    /// The image cannot be installed on this host.
+    UnsupportedHost,
+    CpuCount { required: usize, requested: usize },
+    MemorySize { required: u64, requested: u64 },
+}
+
+impl fmt::Display for RequirementViolation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RequirementViolation::UnsupportedHost => {
+                write!(f, "the restore image cannot be installed on this host")
+            }
+            RequirementViolation::CpuCount { required, requested } => write!(
+                f,
+                "{} CPUs requested, the restore image needs at least {}",
+                requested, required
+            ),
+            RequirementViolation::MemorySize { required, requested } => write!(
+                f,
+                "{} bytes of memory requested, the restore image needs at least {}",
+                requested, required
+            ),
+        }
+    }
+}
+
+impl RestoreImageInfo {
+    /// Loads the restore image at `path` and summarizes it. `completion_handler` runs on an
+    /// arbitrary queue.
+    ///
+    /// Fails without calling `completion_handler` on Intel hosts.
+    pub fn from_file<P, F>(path: P, completion_handler: F) -> Result<(), UnsupportedOnThisHost>
+    where
+        P: AsRef<Path>,
+        F: FnOnce(CompletionOutcome<RestoreImageInfo>) + Send + 'static,
+    {
+        VZMacOSRestoreImage::load_file(path, move |outcome| {
+            completion_handler(match outcome {
+                CompletionOutcome::Success(image) => {
+                    CompletionOutcome::Success(RestoreImageInfo::from_image(&image))
+                }
+                CompletionOutcome::Cancelled => CompletionOutcome::Cancelled,
+                CompletionOutcome::Failed(e) => CompletionOutcome::Failed(e),
+            })
+        })
+    }
+
+    pub fn from_image(image: &VZMacOSRestoreImage) -> RestoreImageInfo {
+        let version = image.operating_system_version();
+        let requirements = image.most_featureful_supported_configuration();
+        let supported = requirements
+            .as_ref()
+            .is_some_and(|r| r.hardware_model().is_supported());
+        RestoreImageInfo {
+            build_version: image.build_version().as_str().to_string(),
+            os_version: (
+                version.major_version as u64,
+                version.minor_version as u64,
+                version.patch_version as u64,
+            ),
+            supported,
+            minimum_cpu_count: requirements
+                .as_ref()
+                .map_or(0, |r| r.minimum_supported_cpu_count()),
+            minimum_memory_size: requirements
+                .as_ref()
+                .map_or(0, |r| r.minimum_supported_memory_size()),
+        }
+    }
+
+    /// Everything that keeps a virtual machine with `cpu_count` CPUs and `memory_size` bytes of
+    /// memory from installing this image; empty if it can.
+    pub fn check_against(&self, cpu_count: usize, memory_size: u64) -> Vec<RequirementViolation> {
+        if !self.supported {
+            return vec![RequirementViolation::UnsupportedHost];
+        }
+        let mut violations = Vec::new();
+        if cpu_count < self.minimum_cpu_count {
+            violations.push(RequirementViolation::CpuCount {
+                required: self.minimum_cpu_count,
+                requested: cpu_count,
+            });
+        }
+        if memory_size < self.minimum_memory_size {
+            violations.push(RequirementViolation::MemorySize {
+                required: self.minimum_memory_size,
+                requested: memory_size,
+            });
+        }
+        violations
+    }
+}
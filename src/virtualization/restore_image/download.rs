@@ -0,0 +1,672 @@
+//! Downloads restore images with `NSURLSession`, resuming interrupted downloads and verifying
+//! what arrives before it is put in place.
+//!
+//! A download of `dest` writes to `dest.download` and, once verified, renames it to `dest`. When
+//! a transfer fails or is cancelled and the server allows it, the session's resume data is saved
+//! as `dest.resumedata`, and the next download of `dest` continues from there.
+
+use crate::base::{Id, NSData, NSError, NIL, NSURL};
+use crate::runtime::{alloc, owned, retained};
+
+use std::error::Error;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::os::raw::c_void;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex, Once};
+use std::time::Duration;
+
+use block::ConcreteBlock;
+use objc::declare::ClassDecl;
+use objc::rc::StrongPtr;
+use objc::runtime::{Class, Object, Sel};
+use objc::{class, msg_send, sel, sel_impl};
+
+const DELEGATE_CLASS: &str = "VirtualizationRsDownloadDelegate";
+const SHARED_IVAR: &str = "shared";
+
+const NS_URL_ERROR_DOMAIN: &str = "NSURLErrorDomain";
+/// `NSURLErrorCancelled`.
+const NS_URL_ERROR_CANCELLED: isize = -999;
+/// `NSURLSessionDownloadTaskResumeData`, the `userInfo` key of a failed download's resume data.
+const RESUME_DATA_KEY: &str = "NSURLSessionDownloadTaskResumeData";
+
+/// Checks a downloaded file before it is put in place; the message ends up in
+/// [`DownloadError::Rejected`].
+type VerifyHook = Box<dyn Fn(&Path) -> Result<(), String> + Send + Sync>;
+type ProgressCallback = Box<dyn Fn(DownloadProgress) + Send + Sync>;
+
+/// How far a download got.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct DownloadProgress {
+    /// Bytes in the partial file, including those of an earlier, resumed attempt.
+    pub written: u64,
+    /// `None` until the server announced the size.
+    pub expected: Option<u64>,
+}
+
+impl DownloadProgress {
+    /// Between 0 and 1; `None` while the size is unknown.
+    pub fn fraction(&self) -> Option<f64> {
+        self.expected
+            .filter(|&expected| expected > 0)
+            .map(|expected| (self.written as f64 / expected as f64).min(1.0))
+    }
+}
+
+/// Why a download did not produce a file.
+#[derive(Debug)]
+pub enum DownloadError {
+    InvalidUrl(String),
+    /// Cancelled through [`DownloadHandle::cancel`]; resume data was saved if the server allows
+    /// resuming.
+    Cancelled,
+    /// The transfer failed. If `resumable`, resume data was saved and downloading again continues
+    /// where this attempt stopped.
+    Transfer {
+        error: NSError,
+        resumable: bool,
+    },
+    Io {
+        path: PathBuf,
+        error: io::Error,
+    },
+    SizeMismatch {
+        expected: u64,
+        actual: u64,
+    },
+    ChecksumMismatch {
+        expected: [u8; 32],
+        actual: [u8; 32],
+    },
+    /// The caller's verification hook refused the file.
+    Rejected(String),
+}
+
+// `NSError` is immutable, and retaining and releasing it is thread-safe; the error is created on
+// the session's delegate queue and handed to the thread waiting for the download.
+unsafe impl Send for DownloadError {}
+unsafe impl Sync for DownloadError {}
+
+impl fmt::Display for DownloadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DownloadError::InvalidUrl(url) => write!(f, "invalid download URL '{}'", url),
+            DownloadError::Cancelled => write!(f, "download cancelled"),
+            DownloadError::Transfer { error, resumable } => write!(
+                f,
+                "download failed{}: {}",
+                if *resumable { " (resumable)" } else { "" },
+                error
+            ),
+            DownloadError::Io { path, error } => write!(f, "{}: {}", path.display(), error),
+            DownloadError::SizeMismatch { expected, actual } => {
+                write!(f, "downloaded {} bytes, expected {}", actual, expected)
+            }
+            DownloadError::ChecksumMismatch { expected, actual } => write!(
+                f,
+                "SHA-256 of the download is {}, expected {}",
+                hex(actual),
+                hex(expected)
+            ),
+            DownloadError::Rejected(reason) => write!(f, "download rejected: {}", reason),
+        }
+    }
+}
+
+impl Error for DownloadError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            DownloadError::Transfer { error, .. } => Some(error),
+            DownloadError::Io { error, .. } => Some(error),
+            _ => None,
+        }
+    }
+}
+
+fn io_error(path: &Path) -> impl FnOnce(io::Error) -> DownloadError + '_ {
+    move |error| DownloadError::Io {
+        path: path.to_path_buf(),
+        error,
+    }
+}
+
+/// `path` with `suffix` appended to its file name.
+fn sibling(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(suffix);
+    path.with_file_name(name)
+}
+
+/// Downloads restore images, and other large files, with the system's URL loading.
+///
+/// # Examples
+/// ```rust
+/// let handle = Downloader::new()
+///     .expected_size(13_579_246_801)
+///     .sha256(digest_from_hex(EXPECTED).unwrap())
+///     .on_progress(|p| eprint!("\r{:.1}%", p.fraction().unwrap_or(0.0) * 100.0))
+///     .download(url, "UniversalMac_13.0_22A380_Restore.ipsw")?;
+/// let path = handle.wait()?;
+/// ```
+#[derive(Default)]
+pub struct Downloader {
+    expected_size: Option<u64>,
+    sha256: Option<[u8; 32]>,
+    verify: Option<VerifyHook>,
+    on_progress: Option<ProgressCallback>,
+}
+
+impl Downloader {
+    pub fn new() -> Downloader {
+        Downloader::default()
+    }
+
+    /// Refuses a download of any other size.
+    pub fn expected_size(mut self, bytes: u64) -> Downloader {
+        self.expected_size = Some(bytes);
+        self
+    }
+
+    /// Refuses a download with any other SHA-256 digest, computed once the transfer is complete.
+    pub fn sha256(mut self, digest: [u8; 32]) -> Downloader {
+        self.sha256 = Some(digest);
+        self
+    }
+
+    /// Runs `verify` on the complete download, after the size and checksum checks, and refuses
+    /// the download if it fails.
+    pub fn verify_with<F>(mut self, verify: F) -> Downloader
+    where
+        F: Fn(&Path) -> Result<(), String> + Send + Sync + 'static,
+    {
+        self.verify = Some(Box::new(verify));
+        self
+    }
+
+    /// Calls `on_progress` on the session's delegate queue as data arrives.
+    pub fn on_progress<F>(mut self, on_progress: F) -> Downloader
+    where
+        F: Fn(DownloadProgress) + Send + Sync + 'static,
+    {
+        self.on_progress = Some(Box::new(on_progress));
+        self
+    }
+
+    /// Starts downloading `url` to `dest`, resuming from `dest.resumedata` if an earlier attempt
+    /// left it.
+    pub fn download<P: AsRef<Path>>(
+        self,
+        url: &str,
+        dest: P,
+    ) -> Result<DownloadHandle, DownloadError> {
+        let dest = dest.as_ref().to_path_buf();
+        let ns_url = NSURL::url_with_string(url)
+            .ok_or_else(|| DownloadError::InvalidUrl(url.to_string()))?;
+        let resume_path = sibling(&dest, ".resumedata");
+        let resume_data = match fs::read(&resume_path) {
+            Ok(bytes) => Some(NSData::with_bytes(&bytes)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+            Err(e) => return Err(io_error(&resume_path)(e)),
+        };
+
+        let shared = Arc::new(Shared {
+            partial: sibling(&dest, ".download"),
+            resume: resume_path,
+            dest,
+            downloader: self,
+            state: Mutex::new(State::default()),
+            finished: Condvar::new(),
+        });
+        unsafe {
+            let delegate = owned(msg_send![alloc(delegate_class()), init]);
+            let ivar = Box::into_raw(Box::new(shared.clone()));
+            (**delegate).set_ivar(SHARED_IVAR, ivar as *mut c_void);
+
+            let configuration: Id = msg_send![
+                class!(NSURLSessionConfiguration),
+                defaultSessionConfiguration
+            ];
+            // The session keeps the delegate until it is invalidated, after its only task.
+            let session = retained(msg_send![
+                class!(NSURLSession),
+                sessionWithConfiguration: configuration
+                delegate: *delegate
+                delegateQueue: NIL
+            ]);
+            let task = match &resume_data {
+                Some(data) => retained(msg_send![*session, downloadTaskWithResumeData: *data.0]),
+                None => retained(msg_send![*session, downloadTaskWithURL: *ns_url.0]),
+            };
+            let _: () = msg_send![*task, resume];
+            let _: () = msg_send![*session, finishTasksAndInvalidate];
+            Ok(DownloadHandle { task, shared })
+        }
+    }
+}
+
+#[derive(Default)]
+struct State {
+    progress: DownloadProgress,
+    resumed_at: Option<u64>,
+    /// Moving the session's temporary file to the partial file failed.
+    move_error: Option<io::Error>,
+    /// `cancelByProducingResumeData:` was called and has not handed over the resume data yet.
+    cancelling: bool,
+    /// The cancellation, held back while `cancelling`.
+    pending: Option<Result<PathBuf, DownloadError>>,
+    outcome: Option<Result<PathBuf, DownloadError>>,
+}
+
+struct Shared {
+    dest: PathBuf,
+    partial: PathBuf,
+    resume: PathBuf,
+    downloader: Downloader,
+    state: Mutex<State>,
+    finished: Condvar,
+}
+
+impl Shared {
+    fn lock(&self) -> std::sync::MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn set_progress(&self, progress: DownloadProgress) {
+        self.lock().progress = progress;
+        if let Some(on_progress) = &self.downloader.on_progress {
+            on_progress(progress);
+        }
+    }
+
+    /// Saves resume data for the next attempt; whether it was saved.
+    fn save_resume_data(&self, data: Id) -> bool {
+        if data == NIL {
+            return false;
+        }
+        let data = NSData(unsafe { retained(data) });
+        fs::write(&self.resume, data.to_vec()).is_ok()
+    }
+
+    fn publish(&self, state: &mut State, outcome: Result<PathBuf, DownloadError>) {
+        // A cancellation is reported once its resume data is saved.
+        if state.cancelling && matches!(outcome, Err(DownloadError::Cancelled)) {
+            state.pending = Some(outcome);
+        } else {
+            state.outcome = Some(outcome);
+            self.finished.notify_all();
+        }
+    }
+
+    /// The session finished the task, successfully if `error` is nil.
+    fn complete(&self, error: Id) {
+        let outcome = if error == NIL {
+            let move_error = self.lock().move_error.take();
+            match move_error {
+                Some(e) => Err(io_error(&self.partial)(e)),
+                None => self.finish(),
+            }
+        } else {
+            let error = NSError(unsafe { retained(error) });
+            let resume_data = error.user_info_value(RESUME_DATA_KEY).unwrap_or(NIL);
+            let resumable = self.save_resume_data(resume_data);
+            if error.domain().as_str() == NS_URL_ERROR_DOMAIN
+                && error.code() == NS_URL_ERROR_CANCELLED
+            {
+                Err(DownloadError::Cancelled)
+            } else {
+                if !resumable {
+                    // Resume data that led here is stale; the next attempt starts over.
+                    let _ = fs::remove_file(&self.resume);
+                }
+                Err(DownloadError::Transfer { error, resumable })
+            }
+        };
+        let mut state = self.lock();
+        self.publish(&mut state, outcome);
+    }
+
+    /// Verifies the partial file and puts it in place.
+    fn finish(&self) -> Result<PathBuf, DownloadError> {
+        let _ = fs::remove_file(&self.resume);
+        if let Err(e) = self.verify() {
+            let _ = fs::remove_file(&self.partial);
+            return Err(e);
+        }
+        fs::rename(&self.partial, &self.dest).map_err(io_error(&self.dest))?;
+        Ok(self.dest.clone())
+    }
+
+    fn verify(&self) -> Result<(), DownloadError> {
+        let downloader = &self.downloader;
+        if let Some(expected) = downloader.expected_size {
+            let actual = fs::metadata(&self.partial)
+                .map_err(io_error(&self.partial))?
+                .len();
+            if actual != expected {
+                return Err(DownloadError::SizeMismatch { expected, actual });
+            }
+        }
+        if let Some(expected) = downloader.sha256 {
+            let actual = sha256_file(&self.partial).map_err(io_error(&self.partial))?;
+            if actual != expected {
+                return Err(DownloadError::ChecksumMismatch { expected, actual });
+            }
+        }
+        if let Some(verify) = &downloader.verify {
+            verify(&self.partial).map_err(DownloadError::Rejected)?;
+        }
+        Ok(())
+    }
+}
+
+/// A download in progress. Dropping the handle does not stop it.
+pub struct DownloadHandle {
+    task: StrongPtr,
+    shared: Arc<Shared>,
+}
+
+// `NSURLSessionTask` is thread-safe, and the shared state is behind a mutex.
+unsafe impl Send for DownloadHandle {}
+unsafe impl Sync for DownloadHandle {}
+
+impl DownloadHandle {
+    pub fn progress(&self) -> DownloadProgress {
+        self.shared.lock().progress
+    }
+
+    /// The offset the download continued from, once the session resumed an earlier attempt.
+    pub fn resumed_at(&self) -> Option<u64> {
+        self.shared.lock().resumed_at
+    }
+
+    /// Stops the download, saving resume data if the server allows resuming. The download then
+    /// ends with [`DownloadError::Cancelled`], once the resume data is saved.
+    pub fn cancel(&self) {
+        {
+            let mut state = self.shared.lock();
+            if state.outcome.is_some() || state.cancelling {
+                return;
+            }
+            state.cancelling = true;
+        }
+        let shared = self.shared.clone();
+        let block = ConcreteBlock::new(move |data: Id| {
+            shared.save_resume_data(data);
+            let mut state = shared.lock();
+            state.cancelling = false;
+            if let Some(outcome) = state.pending.take() {
+                shared.publish(&mut state, outcome);
+            }
+        });
+        let block = block.copy();
+        unsafe {
+            let _: () = msg_send![*self.task, cancelByProducingResumeData: &*block];
+        }
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.shared.lock().outcome.is_some()
+    }
+
+    /// Blocks until the download ends and returns where the file is.
+    pub fn wait(self) -> Result<PathBuf, DownloadError> {
+        let state = self.shared.lock();
+        let mut state = self
+            .shared
+            .finished
+            .wait_while(state, |state| state.outcome.is_none())
+            .unwrap_or_else(|e| e.into_inner());
+        state.outcome.take().unwrap()
+    }
+
+    /// Blocks until the download ends or `timeout` passes; whether it ended.
+    pub fn wait_timeout(&self, timeout: Duration) -> bool {
+        let state = self.shared.lock();
+        let (state, _) = self
+            .shared
+            .finished
+            .wait_timeout_while(state, timeout, |state| state.outcome.is_none())
+            .unwrap_or_else(|e| e.into_inner());
+        state.outcome.is_some()
+    }
+}
+
+fn delegate_class() -> &'static Class {
+    static REGISTER: Once = Once::new();
+    REGISTER.call_once(|| {
+        let mut decl = ClassDecl::new(DELEGATE_CLASS, class!(NSObject)).unwrap();
+        decl.add_ivar::<*mut c_void>(SHARED_IVAR);
+        unsafe {
+            decl.add_method(
+                sel!(URLSession:downloadTask:didWriteData:totalBytesWritten:totalBytesExpectedToWrite:),
+                did_write_data as extern "C" fn(&Object, Sel, Id, Id, i64, i64, i64),
+            );
+            decl.add_method(
+                sel!(URLSession:downloadTask:didResumeAtOffset:expectedTotalBytes:),
+                did_resume as extern "C" fn(&Object, Sel, Id, Id, i64, i64),
+            );
+            decl.add_method(
+                sel!(URLSession:downloadTask:didFinishDownloadingToURL:),
+                did_finish_downloading as extern "C" fn(&Object, Sel, Id, Id, Id),
+            );
+            decl.add_method(
+                sel!(URLSession:task:didCompleteWithError:),
+                did_complete as extern "C" fn(&Object, Sel, Id, Id, Id),
+            );
+            decl.add_method(sel!(dealloc), dealloc as extern "C" fn(&Object, Sel));
+        }
+        decl.register();
+    });
+    Class::get(DELEGATE_CLASS).unwrap()
+}
+
+fn shared(this: &Object) -> &Arc<Shared> {
+    unsafe { &*(*this.get_ivar::<*mut c_void>(SHARED_IVAR) as *const Arc<Shared>) }
+}
+
+/// `NSURLSessionTransferSizeUnknown` is -1.
+fn known_size(bytes: i64) -> Option<u64> {
+    if bytes < 0 {
+        None
+    } else {
+        Some(bytes as u64)
+    }
+}
+
+extern "C" fn did_write_data(
+    this: &Object,
+    _cmd: Sel,
+    _session: Id,
+    _task: Id,
+    _written: i64,
+    total_written: i64,
+    total_expected: i64,
+) {
+    shared(this).set_progress(DownloadProgress {
+        written: total_written.max(0) as u64,
+        expected: known_size(total_expected),
+    });
+}
+
+extern "C" fn did_resume(
+    this: &Object,
+    _cmd: Sel,
+    _session: Id,
+    _task: Id,
+    offset: i64,
+    total_expected: i64,
+) {
+    let shared = shared(this);
+    shared.lock().resumed_at = Some(offset.max(0) as u64);
+    shared.set_progress(DownloadProgress {
+        written: offset.max(0) as u64,
+        expected: known_size(total_expected),
+    });
+}
+
+/// The session deletes `location` when this returns, so it is moved right away.
+extern "C" fn did_finish_downloading(
+    this: &Object,
+    _cmd: Sel,
+    _session: Id,
+    _task: Id,
+    location: Id,
+) {
+    let shared = shared(this);
+    let location = NSURL(unsafe { retained(location) });
+    let location = PathBuf::from(location.path().as_str());
+    let moved = fs::rename(&location, &shared.partial).or_else(|_| {
+        // Across volumes, e.g. when the destination is on an external disk.
+        fs::copy(&location, &shared.partial).map(|_| ())
+    });
+    shared.lock().move_error = moved.err();
+}
+
+extern "C" fn did_complete(this: &Object, _cmd: Sel, _session: Id, _task: Id, error: Id) {
+    shared(this).complete(error);
+}
+
+extern "C" fn dealloc(this: &Object, _cmd: Sel) {
+    unsafe {
+        let shared = *this.get_ivar::<*mut c_void>(SHARED_IVAR) as *mut Arc<Shared>;
+        if !shared.is_null() {
+            drop(Box::from_raw(shared));
+        }
+        let _: () = msg_send![super(this, class!(NSObject)), dealloc];
+    }
+}
+
+/// The 32-byte digest written as 64 hexadecimal digits, e.g. from a download page; `None` if
+/// `hex` is anything else.
+pub fn digest_from_hex(hex: &str) -> Option<[u8; 32]> {
+    let hex = hex.trim();
+    if hex.len() != 64 || !hex.is_ascii() {
+        return None;
+    }
+    let mut digest = [0; 32];
+    for (i, byte) in digest.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[2 * i..2 * i + 2], 16).ok()?;
+    }
+    Some(digest)
+}
+
+fn hex(digest: &[u8; 32]) -> String {
+    digest.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// The SHA-256 digest of the file at `path`.
+pub fn sha256_file<P: AsRef<Path>>(path: P) -> io::Result<[u8; 32]> {
+    let mut file = File::open(path)?;
+    let mut sha = Sha256::new();
+    let mut buf = vec![0; 1 << 20];
+    loop {
+        match file.read(&mut buf) {
+            Ok(0) => return Ok(sha.finish()),
+            Ok(n) => sha.update(&buf[..n]),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+const K: [u32; 64] = [
+    0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+    0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+    0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+    0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+    0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+    0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+    0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+    0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2,
+];
+
+/// FIPS 180-4 SHA-256, enough to check a download without another dependency.
+struct Sha256 {
+    h: [u32; 8],
+    block: [u8; 64],
+    filled: usize,
+    len: u64,
+}
+
+impl Sha256 {
+    fn new() -> Sha256 {
+        Sha256 {
+            h: [
+                0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab,
+                0x5be0cd19,
+            ],
+            block: [0; 64],
+            filled: 0,
+            len: 0,
+        }
+    }
+
+    fn update(&mut self, mut data: &[u8]) {
+        self.len += data.len() as u64;
+        while !data.is_empty() {
+            let n = (64 - self.filled).min(data.len());
+            self.block[self.filled..self.filled + n].copy_from_slice(&data[..n]);
+            self.filled += n;
+            data = &data[n..];
+            if self.filled == 64 {
+                self.compress();
+                self.filled = 0;
+            }
+        }
+    }
+
+    fn finish(mut self) -> [u8; 32] {
+        let bits = self.len.wrapping_mul(8);
+        self.update(&[0x80]);
+        while self.filled != 56 {
+            self.update(&[0]);
+        }
+        self.update(&bits.to_be_bytes());
+        let mut digest = [0; 32];
+        for (chunk, word) in digest.chunks_mut(4).zip(self.h.iter()) {
+            chunk.copy_from_slice(&word.to_be_bytes());
+        }
+        digest
+    }
+
+    fn compress(&mut self) {
+        let mut w = [0u32; 64];
+        for (i, chunk) in self.block.chunks(4).enumerate() {
+            w[i] = u32::from_be_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+        }
+        for i in 16..64 {
+            let s0 = w[i - 15].rotate_right(7) ^ w[i - 15].rotate_right(18) ^ (w[i - 15] >> 3);
+            let s1 = w[i - 2].rotate_right(17) ^ w[i - 2].rotate_right(19) ^ (w[i - 2] >> 10);
+            w[i] = w[i - 16]
+                .wrapping_add(s0)
+                .wrapping_add(w[i - 7])
+                .wrapping_add(s1);
+        }
+        let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut h] = self.h;
+        for i in 0..64 {
+            let s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+            let ch = (e & f) ^ (!e & g);
+            let t1 = h
+                .wrapping_add(s1)
+                .wrapping_add(ch)
+                .wrapping_add(K[i])
+                .wrapping_add(w[i]);
+            let s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+            let maj = (a & b) ^ (a & c) ^ (b & c);
+            let t2 = s0.wrapping_add(maj);
+            h = g;
+            g = f;
+            f = e;
+            e = d.wrapping_add(t1);
+            d = c;
+            c = b;
+            b = a;
+            a = t1.wrapping_add(t2);
+        }
+        for (state, value) in self.h.iter_mut().zip([a, b, c, d, e, f, g, h].iter()) {
+            *state = state.wrapping_add(*value);
+        }
+    }
+}
@@ -1,9 +1,24 @@
 //! serial port module
 
-use crate::base::{Id, NSFileHandle};
+use crate::base::{CancellationToken, Id, NSFileHandle, NIL};
+use crate::resource::{close_file, first_error, CloseError};
+use crate::runtime::{alloc, from_objc_bool, is_shared, owned, retained, vz_class};
+use crate::virtualization::device::VZDeviceConfiguration;
+
+use std::any::Any;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::mem::ManuallyDrop;
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::{FromRawFd, IntoRawFd, RawFd};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
 
 use objc::rc::StrongPtr;
-use objc::{class, msg_send, sel, sel_impl};
+use objc::runtime::BOOL;
+use objc::{msg_send, sel, sel_impl};
 
 /// common configure for serial port attachment
 pub trait VZSerialPortAttachment {
@@ -32,13 +47,19 @@ impl VZFileHandleSerialPortAttachmentBuilder<(), ()> {
     }
 }
 
+impl Default for VZFileHandleSerialPortAttachmentBuilder<(), ()> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl<R, W> VZFileHandleSerialPortAttachmentBuilder<R, W> {
     pub fn file_handle_for_reading(
         self,
         file_handle_for_reading: NSFileHandle,
     ) -> VZFileHandleSerialPortAttachmentBuilder<NSFileHandle, W> {
         VZFileHandleSerialPortAttachmentBuilder {
-            file_handle_for_reading: file_handle_for_reading,
+            file_handle_for_reading,
             file_handle_for_writing: self.file_handle_for_writing,
         }
     }
@@ -49,7 +70,7 @@ impl<R, W> VZFileHandleSerialPortAttachmentBuilder<R, W> {
     ) -> VZFileHandleSerialPortAttachmentBuilder<R, NSFileHandle> {
         VZFileHandleSerialPortAttachmentBuilder {
             file_handle_for_reading: self.file_handle_for_reading,
-            file_handle_for_writing: file_handle_for_writing,
+            file_handle_for_writing,
         }
     }
 }
@@ -66,6 +87,7 @@ impl VZFileHandleSerialPortAttachmentBuilder<NSFileHandle, NSFileHandle> {
 }
 
 /// thie struct configure a serial port
+#[derive(Clone)]
 pub struct VZFileHandleSerialPortAttachment(StrongPtr);
 
 impl VZFileHandleSerialPortAttachment {
@@ -73,12 +95,72 @@ impl VZFileHandleSerialPortAttachment {
         file_handle_for_reading: NSFileHandle,
         file_handle_for_writing: NSFileHandle,
     ) -> VZFileHandleSerialPortAttachment {
-        let i: Id = msg_send![class!(VZFileHandleSerialPortAttachment), new];
-        let p = StrongPtr::new(
-            msg_send![i, initWithFileHandleForReading:*file_handle_for_reading.0 fileHandleForWriting:*file_handle_for_writing.0],
-        );
+        VZFileHandleSerialPortAttachment::from_raw_handles(
+            *file_handle_for_reading.0,
+            *file_handle_for_writing.0,
+        )
+    }
+
+    /// Creates the attachment from file handles made elsewhere, e.g. by another Objective-C
+    /// binding crate.
+    ///
+    /// The handles are borrowed: the attachment retains them itself, and the caller's references
+    /// stay the caller's to release.
+    ///
+    /// # Safety
+    /// `read` and `write` must each be nil or a valid `NSFileHandle`. A nil `read` gives the guest
+    /// no input, a nil `write` discards its output.
+    pub unsafe fn from_raw_handles(read: Id, write: Id) -> VZFileHandleSerialPortAttachment {
+        let i = alloc(vz_class!(VZFileHandleSerialPortAttachment));
+        let p = owned(msg_send![i, initWithFileHandleForReading:read fileHandleForWriting:write]);
         VZFileHandleSerialPortAttachment(p)
     }
+
+    /// The handle the guest's input is read from; `None` if the guest gets no input.
+    pub fn file_handle_for_reading(&self) -> Option<NSFileHandle> {
+        let p: Id = unsafe { msg_send![*self.0, fileHandleForReading] };
+        if p == NIL {
+            None
+        } else {
+            Some(NSFileHandle(unsafe { retained(p) }))
+        }
+    }
+
+    /// The handle the guest's output is written to; `None` if it is discarded.
+    pub fn file_handle_for_writing(&self) -> Option<NSFileHandle> {
+        let p: Id = unsafe { msg_send![*self.0, fileHandleForWriting] };
+        if p == NIL {
+            None
+        } else {
+            Some(NSFileHandle(unsafe { retained(p) }))
+        }
+    }
+
+    /// The descriptors behind [`file_handle_for_reading`] and [`file_handle_for_writing`], from
+    /// each handle's `fileDescriptor`; `None` where the handle is nil.
+    ///
+    /// [`file_handle_for_reading`]: Self::file_handle_for_reading
+    /// [`file_handle_for_writing`]: Self::file_handle_for_writing
+    pub fn backing_fds(&self) -> (Option<RawFd>, Option<RawFd>) {
+        (
+            self.file_handle_for_reading().map(|h| h.file_descriptor()),
+            self.file_handle_for_writing().map(|h| h.file_descriptor()),
+        )
+    }
+
+    /// Closes both handles now, for an attachment whose handles the crate created. Fails with
+    /// [`CloseError::Attached`], closing nothing, if anything besides `self` references the
+    /// attachment: the framework may be using them.
+    pub(crate) fn close_handles(&self, resource: &'static str) -> Result<(), CloseError> {
+        if unsafe { is_shared(*self.0) } {
+            return Err(CloseError::Attached(resource));
+        }
+        let handles = [
+            self.file_handle_for_reading(),
+            self.file_handle_for_writing(),
+        ];
+        first_error(handles.iter().flatten().map(NSFileHandle::close_unchecked))
+    }
 }
 
 impl VZSerialPortAttachment for VZFileHandleSerialPortAttachment {
@@ -87,11 +169,161 @@ impl VZSerialPortAttachment for VZFileHandleSerialPortAttachment {
     }
 }
 
-/// configure of serial port
-pub trait VZSerialPortConfiguration {
-    fn id(&self) -> Id;
+pub(crate) fn pipe() -> io::Result<(File, File)> {
+    let mut fds = [0; 2];
+    if unsafe { libc::pipe(fds.as_mut_ptr()) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    unsafe { Ok((File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1]))) }
+}
+
+#[derive(Default)]
+struct CaptureState {
+    data: Vec<u8>,
+    closed: bool,
+}
+
+/// Guest console output collected by a [`ConsoleCapture`].
+#[derive(Default)]
+pub struct ConsoleBuffer {
+    state: Mutex<CaptureState>,
+    changed: Condvar,
+}
+
+impl ConsoleBuffer {
+    /// Everything the guest has written so far.
+    pub fn contents(&self) -> Vec<u8> {
+        self.state.lock().unwrap().data.clone()
+    }
+
+    /// Whether the guest side of the pipe has been closed.
+    pub fn is_closed(&self) -> bool {
+        self.state.lock().unwrap().closed
+    }
+
+    /// Blocks until `marker` appears in the output. Returns `false` on timeout, cancellation or when
+    /// the console is closed without the marker appearing.
+    pub fn wait_for(&self, marker: &[u8], timeout: Duration, token: &CancellationToken) -> bool {
+        let deadline = Instant::now() + timeout;
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if marker.is_empty() || state.data.windows(marker.len()).any(|w| w == marker) {
+                return true;
+            }
+            let now = Instant::now();
+            if state.closed || token.is_cancelled() || now >= deadline {
+                return false;
+            }
+            // Wake up periodically so cancellation is noticed without a notification.
+            let slice = (deadline - now).min(Duration::from_millis(50));
+            state = self.changed.wait_timeout(state, slice).unwrap().0;
+        }
+    }
+
+    fn append(&self, bytes: &[u8]) {
+        self.state.lock().unwrap().data.extend_from_slice(bytes);
+        self.changed.notify_all();
+    }
+
+    fn close(&self) {
+        self.state.lock().unwrap().closed = true;
+        self.changed.notify_all();
+    }
+}
+
+/// Serial port attachment that collects guest output in memory.
+///
+/// The guest writes into a pipe drained by a background thread; guest input is a pipe whose write
+/// end is held by this struct, so the guest never sees end-of-file on its console. The pipe ends
+/// are owned; see [`close`](Self::close).
+pub struct ConsoleCapture {
+    attachment: VZFileHandleSerialPortAttachment,
+    buffer: Arc<ConsoleBuffer>,
+    /// Taken when closed.
+    input: Option<File>,
+}
+
+impl ConsoleCapture {
+    pub fn new() -> io::Result<ConsoleCapture> {
+        let (guest_input, input) = pipe()?;
+        let (mut output, guest_output) = pipe()?;
+        let attachment = VZFileHandleSerialPortAttachmentBuilder::new()
+            .file_handle_for_reading(NSFileHandle::init_with_file_descriptor(
+                guest_input.into_raw_fd(),
+                true,
+            ))
+            .file_handle_for_writing(NSFileHandle::init_with_file_descriptor(
+                guest_output.into_raw_fd(),
+                true,
+            ))
+            .build();
+
+        let buffer = Arc::new(ConsoleBuffer::default());
+        let reader = buffer.clone();
+        thread::Builder::new()
+            .name("console-capture".into())
+            .spawn(move || {
+                let mut chunk = [0u8; 4096];
+                loop {
+                    match output.read(&mut chunk) {
+                        Ok(0) => break,
+                        Ok(n) => reader.append(&chunk[..n]),
+                        Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                        Err(_) => break,
+                    }
+                }
+                reader.close();
+            })?;
+
+        Ok(ConsoleCapture {
+            attachment,
+            buffer,
+            input: Some(input),
+        })
+    }
+
+    /// The attachment to hand to a serial port configuration.
+    pub fn attachment(&self) -> VZFileHandleSerialPortAttachment {
+        self.attachment.clone()
+    }
+
+    pub fn buffer(&self) -> Arc<ConsoleBuffer> {
+        self.buffer.clone()
+    }
+
+    /// Closes the guest's input, which the guest sees as end of file, and the guest's ends of the
+    /// pipes, after which the buffer is closed once the reader drained it. Dropping does the same,
+    /// ignoring errors.
+    ///
+    /// Fails with [`CloseError::Attached`] while a configuration references the attachment: the
+    /// guest's ends are then closed when the framework releases it.
+    pub fn close(mut self) -> Result<(), CloseError> {
+        self.release()
+    }
+
+    fn release(&mut self) -> Result<(), CloseError> {
+        let input = match self.input.take() {
+            Some(input) => input,
+            None => return Ok(()),
+        };
+        first_error([
+            close_file(input),
+            self.attachment.close_handles("ConsoleCapture attachment"),
+        ])
+    }
 }
 
+impl Drop for ConsoleCapture {
+    fn drop(&mut self) {
+        let _ = self.release();
+    }
+}
+
+/// configure of serial port
+pub trait VZSerialPortConfiguration: VZDeviceConfiguration {}
+
+impl<T: VZSerialPortConfiguration + ?Sized> VZSerialPortConfiguration for Box<T> {}
+
 /// configure of serial port through the Virtio interface
 pub struct VZVirtioConsoleDeviceSerialPortConfiguration(StrongPtr);
 
@@ -100,18 +332,222 @@ impl VZVirtioConsoleDeviceSerialPortConfiguration {
         attachement: T,
     ) -> VZVirtioConsoleDeviceSerialPortConfiguration {
         unsafe {
-            let p = StrongPtr::new(msg_send![
-                class!(VZVirtioConsoleDeviceSerialPortConfiguration),
+            let p = owned(msg_send![
+                vz_class!(VZVirtioConsoleDeviceSerialPortConfiguration),
                 new
             ]);
-            let _: Id = msg_send![*p, setAttachment: attachement.id()];
+            let _: () = msg_send![*p, setAttachment: attachement.id()];
             VZVirtioConsoleDeviceSerialPortConfiguration(p)
         }
     }
 }
 
-impl VZSerialPortConfiguration for VZVirtioConsoleDeviceSerialPortConfiguration {
+impl VZDeviceConfiguration for VZVirtioConsoleDeviceSerialPortConfiguration {
     fn id(&self) -> Id {
         *self.0
     }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+impl VZSerialPortConfiguration for VZVirtioConsoleDeviceSerialPortConfiguration {}
+
+/// The descriptors behind the attachment of the serial port configuration `port`; `None` for
+/// either side unless it is a [`VZFileHandleSerialPortAttachment`].
+///
+/// # Safety
+/// `port` must be a valid `VZSerialPortConfiguration`.
+pub(crate) unsafe fn port_backing_fds(port: Id) -> (Option<RawFd>, Option<RawFd>) {
+    let attachment: Id = msg_send![port, attachment];
+    if attachment == NIL {
+        return (None, None);
+    }
+    let is_file_handle: BOOL = msg_send![
+        attachment,
+        isKindOfClass: vz_class!(VZFileHandleSerialPortAttachment)
+    ];
+    if !from_objc_bool(is_file_handle) {
+        return (None, None);
+    }
+    VZFileHandleSerialPortAttachment(retained(attachment)).backing_fds()
+}
+
+/// The side of a console two serial ports share a file for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BackingDirection {
+    /// The guest's input: each byte read reaches only one of the ports.
+    Input,
+    /// The guest's output: the ports' output interleaves.
+    Output,
+}
+
+/// Serial ports whose attachments read from, or write to, the same file.
+///
+/// Files are compared by device and inode, so a descriptor duplicated with `dup` counts as the
+/// file it was duplicated from. `/dev/null` is never a duplicate.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DuplicateConsoleBacking {
+    /// Indices of the ports in the configuration's array, in order; at least two.
+    pub ports: Vec<usize>,
+    pub direction: BackingDirection,
+}
+
+impl fmt::Display for DuplicateConsoleBacking {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (last, rest) = self.ports.split_last().expect("no ports");
+        let rest: Vec<String> = rest.iter().map(usize::to_string).collect();
+        write!(f, "serial ports {} and {} ", rest.join(", "), last)?;
+        match self.direction {
+            BackingDirection::Input => f.write_str("read the guest's input from the same file"),
+            BackingDirection::Output => f.write_str("write the guest's output to the same file"),
+        }
+    }
+}
+
+impl std::error::Error for DuplicateConsoleBacking {}
+
+/// Device and inode of the file `fd` refers to, shared by every descriptor duplicated from it.
+fn file_identity(fd: RawFd) -> Option<(u64, u64)> {
+    // Borrows `fd`: the handle owning it closes it, not this.
+    let file = ManuallyDrop::new(unsafe { File::from_raw_fd(fd) });
+    let metadata = file.metadata().ok()?;
+    Some((metadata.dev(), metadata.ino()))
+}
+
+/// Finds the ports that share a file with another port, given the [`backing_fds`] of each port
+/// in order. Descriptors that are `None` or not open are skipped.
+///
+/// [`backing_fds`]: VZFileHandleSerialPortAttachment::backing_fds
+pub fn find_duplicate_backings(
+    backings: &[(Option<RawFd>, Option<RawFd>)],
+) -> Vec<DuplicateConsoleBacking> {
+    let null = fs::metadata("/dev/null")
+        .ok()
+        .map(|metadata| (metadata.dev(), metadata.ino()));
+    let mut duplicates = Vec::new();
+    for &direction in &[BackingDirection::Input, BackingDirection::Output] {
+        let mut files: Vec<((u64, u64), Vec<usize>)> = Vec::new();
+        for (index, &(read, write)) in backings.iter().enumerate() {
+            let fd = match direction {
+                BackingDirection::Input => read,
+                BackingDirection::Output => write,
+            };
+            let identity = match fd.and_then(file_identity) {
+                Some(identity) if Some(identity) != null => identity,
+                _ => continue,
+            };
+            match files.iter_mut().find(|(file, _)| *file == identity) {
+                Some((_, ports)) => ports.push(index),
+                None => files.push((identity, vec![index])),
+            }
+        }
+        duplicates.extend(
+            files
+                .into_iter()
+                .filter(|(_, ports)| ports.len() > 1)
+                .map(|(_, ports)| DuplicateConsoleBacking { ports, direction }),
+        );
+    }
+    duplicates
+}
+
+/// Most serial ports a Linux guest gives a console device to (`MAX_NR_HVC_CONSOLES`). The
+/// framework accepts more, but later ones get no `hvc` device.
+pub const MAX_SERIAL_PORTS: usize = 16;
+
+/// Why a port could not be added to a [`SerialPortSet`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SerialPortSetError {
+    DuplicateName(String),
+    TooManyPorts { limit: usize },
+}
+
+impl fmt::Display for SerialPortSetError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SerialPortSetError::DuplicateName(name) => {
+                write!(f, "a serial port named {:?} was already added", name)
+            }
+            SerialPortSetError::TooManyPorts { limit } => {
+                write!(f, "at most {} serial ports are supported", limit)
+            }
+        }
+    }
+}
+
+/// Named Virtio console ports in guest device order.
+///
+/// The framework creates one Virtio console device per port, in the order of the configuration's
+/// array, and a Linux guest numbers them in that order: the first port added is `hvc0`, the
+/// second `hvc1`, and so on.
+///
+/// # Examples
+/// ```rust
+/// let mut ports = SerialPortSet::new();
+/// ports.add("shell", shell.attachment())?.add("logs", logs.attachment())?;
+/// let map = ports.guest_device_map(); // [("shell", "/dev/hvc0"), ("logs", "/dev/hvc1")]
+/// let conf = VZVirtualMachineConfigurationBuilder::new()
+///     .serial_ports(ports.into_ports())
+///     .build();
+/// ```
+#[derive(Default)]
+pub struct SerialPortSet {
+    ports: Vec<(String, VZVirtioConsoleDeviceSerialPortConfiguration)>,
+}
+
+impl SerialPortSet {
+    pub fn new() -> SerialPortSet {
+        SerialPortSet::default()
+    }
+
+    /// Adds a port after the ones added so far.
+    pub fn add<T: VZSerialPortAttachment>(
+        &mut self,
+        name: &str,
+        attachment: T,
+    ) -> Result<&mut Self, SerialPortSetError> {
+        if self.ports.iter().any(|(existing, _)| existing == name) {
+            return Err(SerialPortSetError::DuplicateName(name.to_string()));
+        }
+        if self.ports.len() == MAX_SERIAL_PORTS {
+            return Err(SerialPortSetError::TooManyPorts {
+                limit: MAX_SERIAL_PORTS,
+            });
+        }
+        self.ports.push((
+            name.to_string(),
+            VZVirtioConsoleDeviceSerialPortConfiguration::new(attachment),
+        ));
+        Ok(self)
+    }
+
+    pub fn len(&self) -> usize {
+        self.ports.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ports.is_empty()
+    }
+
+    /// Index of the guest console device the port named `name` becomes, e.g. 1 for `hvc1`.
+    pub fn guest_index(&self, name: &str) -> Option<usize> {
+        self.ports.iter().position(|(existing, _)| existing == name)
+    }
+
+    /// Each port's name with the guest device path it appears as, in order, for provisioning
+    /// metadata such as cloud-init or agent configuration.
+    pub fn guest_device_map(&self) -> Vec<(String, String)> {
+        self.ports
+            .iter()
+            .enumerate()
+            .map(|(i, (name, _))| (name.clone(), format!("/dev/hvc{}", i)))
+            .collect()
+    }
+
+    /// The ports for [`VZVirtualMachineConfigurationBuilder::serial_ports`](crate::virtualization::virtual_machine::VZVirtualMachineConfigurationBuilder::serial_ports).
+    pub fn into_ports(self) -> Vec<VZVirtioConsoleDeviceSerialPortConfiguration> {
+        self.ports.into_iter().map(|(_, port)| port).collect()
+    }
 }
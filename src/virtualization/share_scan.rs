@@ -0,0 +1,289 @@
+//! share scan module
+//!
+//! Checks a directory before it is shared with a guest: that it is not a root whose sharing
+//! exposes the whole machine or every user's files, and that no symbolic link inside it leads
+//! outside of it, e.g. a link to `~/.ssh` that silently widens the share.
+//!
+//! # Caveat
+//! A scan is a snapshot. A link created or retargeted after it, by the host or by a guest with
+//! write access, is not seen until the next [`scan`], and hard links cannot be told apart from
+//! the files they link. This narrows what a share exposes by mistake; it is not a sandbox for a
+//! guest that tries to escape.
+//!
+//! # Examples
+//! ```rust
+//! let policy = SharePolicy::new(SymlinkPolicy::RefuseIfEscaping);
+//! let dir = VZSharedDirectory::new_hardened("/Users/me/src", false, policy)?;
+//! // ... later, for a share that stays up for days:
+//! let report = dir.rescan()?;
+//! println!("{} links checked", report.links_checked);
+//! ```
+
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+
+/// Roots refused unless [`SharePolicy::allow_dangerous_roots`] is set, compared with the
+/// resolved path. The user's home directory, from `HOME`, is refused as well.
+pub const DANGEROUS_ROOTS: &[&str] = &[
+    "/",
+    "/Users",
+    "/System",
+    "/Library",
+    "/Applications",
+    "/Volumes",
+    "/private",
+    "/private/etc",
+    "/private/var",
+];
+
+/// What to do about symbolic links that lead outside the share.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SymlinkPolicy {
+    /// Refuse the share, naming the links.
+    RefuseIfEscaping,
+    /// Share anyway, and report the links in the [`ShareScan`] and as `log` warnings.
+    WarnOnly,
+}
+
+/// How a share is checked before it is created.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SharePolicy {
+    pub symlinks: SymlinkPolicy,
+    /// Allows sharing the roots in [`DANGEROUS_ROOTS`] and the home directory.
+    pub allow_dangerous_roots: bool,
+}
+
+impl SharePolicy {
+    pub fn new(symlinks: SymlinkPolicy) -> SharePolicy {
+        SharePolicy {
+            symlinks,
+            allow_dangerous_roots: false,
+        }
+    }
+
+    pub fn allow_dangerous_roots(mut self, allow: bool) -> SharePolicy {
+        self.allow_dangerous_roots = allow;
+        self
+    }
+}
+
+impl Default for SharePolicy {
+    fn default() -> Self {
+        SharePolicy::new(SymlinkPolicy::RefuseIfEscaping)
+    }
+}
+
+/// A symbolic link whose target is outside the share.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EscapingLink {
+    pub link: PathBuf,
+    /// The resolved target; for a broken link, where it would lead once the target exists.
+    pub target: PathBuf,
+    pub broken: bool,
+}
+
+impl fmt::Display for EscapingLink {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} leads to {}{}",
+            self.link.display(),
+            self.target.display(),
+            if self.broken { " (broken)" } else { "" }
+        )
+    }
+}
+
+/// What a scan found.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ShareScan {
+    /// The share root with every symbolic link resolved.
+    pub root: PathBuf,
+    pub links_checked: usize,
+    /// Links leading outside `root`, only non-empty under [`SymlinkPolicy::WarnOnly`].
+    pub escaping: Vec<EscapingLink>,
+}
+
+/// Why a directory was not shared.
+#[derive(Debug)]
+pub enum ShareError {
+    Io {
+        path: PathBuf,
+        error: io::Error,
+    },
+    /// The root is a directory whose sharing exposes too much; see [`DANGEROUS_ROOTS`].
+    DangerousRoot(PathBuf),
+    /// The root itself is a symbolic link, to `target`.
+    RootIsSymlink {
+        root: PathBuf,
+        target: PathBuf,
+    },
+    /// Links inside the share lead outside of it.
+    EscapingLinks(Vec<EscapingLink>),
+}
+
+impl fmt::Display for ShareError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ShareError::Io { path, error } => {
+                write!(f, "failed to scan {}: {}", path.display(), error)
+            }
+            ShareError::DangerousRoot(root) => {
+                write!(
+                    f,
+                    "refusing to share {}: it exposes too much",
+                    root.display()
+                )
+            }
+            ShareError::RootIsSymlink { root, target } => write!(
+                f,
+                "refusing to share {}: it is a symbolic link to {}",
+                root.display(),
+                target.display()
+            ),
+            ShareError::EscapingLinks(links) => {
+                write!(f, "refusing to share: {}", links[0])?;
+                if links.len() > 1 {
+                    write!(
+                        f,
+                        " and {} more links lead outside the share",
+                        links.len() - 1
+                    )?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl Error for ShareError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ShareError::Io { error, .. } => Some(error),
+            _ => None,
+        }
+    }
+}
+
+fn io_error(path: &Path) -> impl FnOnce(io::Error) -> ShareError + '_ {
+    move |error| ShareError::Io {
+        path: path.to_path_buf(),
+        error,
+    }
+}
+
+/// `path` with `.` and `..` components resolved without touching the file system; `..` at the
+/// root stays at the root.
+fn normalize(path: &Path) -> PathBuf {
+    let mut out = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::ParentDir => {
+                out.pop();
+            }
+            Component::CurDir => {}
+            other => out.push(other.as_os_str()),
+        }
+    }
+    out
+}
+
+/// Where the link at `link`, in the resolved directory `dir`, leads: resolved if it exists, and
+/// resolved up to its first missing component otherwise, e.g. for a broken link or a loop.
+fn resolve_link(dir: &Path, link: &Path) -> io::Result<(PathBuf, bool)> {
+    let target = normalize(&dir.join(fs::read_link(link)?));
+    match fs::canonicalize(&target) {
+        Ok(resolved) => Ok((resolved, false)),
+        Err(_) => {
+            // A missing component may itself be reached through links; resolve what exists.
+            let mut existing = target.as_path();
+            let mut rest = Vec::new();
+            while let Some(parent) = existing.parent() {
+                rest.push(existing.file_name().unwrap_or_default().to_owned());
+                existing = parent;
+                if let Ok(mut resolved) = fs::canonicalize(existing) {
+                    for name in rest.iter().rev() {
+                        resolved.push(name);
+                    }
+                    return Ok((normalize(&resolved), true));
+                }
+            }
+            Ok((target, true))
+        }
+    }
+}
+
+fn is_dangerous(root: &Path) -> bool {
+    let home = std::env::var_os("HOME")
+        .map(PathBuf::from)
+        .and_then(|home| fs::canonicalize(home).ok());
+    DANGEROUS_ROOTS
+        .iter()
+        .any(|dangerous| root == Path::new(dangerous))
+        || home.as_deref() == Some(root)
+}
+
+/// Checks the directory at `path` against `policy`. Links are not followed while walking the
+/// tree, only resolved, so a link back into the share cannot make the walk loop.
+pub fn scan<P: AsRef<Path>>(path: P, policy: SharePolicy) -> Result<ShareScan, ShareError> {
+    let path = path.as_ref();
+    let metadata = fs::symlink_metadata(path).map_err(io_error(path))?;
+    let root = fs::canonicalize(path).map_err(io_error(path))?;
+    if metadata.file_type().is_symlink() {
+        let error = ShareError::RootIsSymlink {
+            root: path.to_path_buf(),
+            target: root.clone(),
+        };
+        match policy.symlinks {
+            SymlinkPolicy::RefuseIfEscaping => return Err(error),
+            SymlinkPolicy::WarnOnly => log::warn!("{}", error),
+        }
+    }
+    if !policy.allow_dangerous_roots && is_dangerous(&root) {
+        return Err(ShareError::DangerousRoot(root));
+    }
+
+    let mut report = ShareScan {
+        root: root.clone(),
+        links_checked: 0,
+        escaping: Vec::new(),
+    };
+    let mut pending = vec![root.clone()];
+    while let Some(dir) = pending.pop() {
+        for entry in fs::read_dir(&dir).map_err(io_error(&dir))? {
+            let entry = entry.map_err(io_error(&dir))?;
+            let path = entry.path();
+            let file_type = entry.file_type().map_err(io_error(&path))?;
+            if file_type.is_dir() {
+                pending.push(path);
+            } else if file_type.is_symlink() {
+                report.links_checked += 1;
+                let (target, broken) = resolve_link(&dir, &path).map_err(io_error(&path))?;
+                if !target.starts_with(&root) {
+                    report.escaping.push(EscapingLink {
+                        link: path,
+                        target,
+                        broken,
+                    });
+                }
+            }
+        }
+    }
+    report.escaping.sort_by(|a, b| a.link.cmp(&b.link));
+
+    if report.escaping.is_empty() {
+        return Ok(report);
+    }
+    match policy.symlinks {
+        SymlinkPolicy::RefuseIfEscaping => Err(ShareError::EscapingLinks(report.escaping)),
+        SymlinkPolicy::WarnOnly => {
+            for link in &report.escaping {
+                log::warn!("shared directory {}: {}", root.display(), link);
+            }
+            Ok(report)
+        }
+    }
+}
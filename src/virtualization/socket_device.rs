@@ -1,8 +1,214 @@
 //! socket device module
 
-use crate::base::Id;
+use crate::base::{CallbackQueue, DispatchQueue, Id, NSError, NIL};
+use crate::resource::CloseError;
+use crate::runtime::{owned, retained, vz_class, ForQueue};
+use crate::virtualization::error::CompletionOutcome;
+use crate::virtualization::device::VZDeviceConfiguration;
+use crate::virtualization::vsock::VsockPort;
+
+use std::any::Any;
+use std::cell::Cell;
+use std::io;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+
+use block::ConcreteBlock;
+use objc::rc::StrongPtr;
+use objc::{msg_send, sel, sel_impl};
 
 /// common configure of socket device
-pub trait VZSocketDeviceConfiguration {
-    fn id(&self) -> Id;
+pub trait VZSocketDeviceConfiguration: VZDeviceConfiguration {}
+
+impl<T: VZSocketDeviceConfiguration + ?Sized> VZSocketDeviceConfiguration for Box<T> {}
+
+/// configure of socket device through the Virtio interface
+pub struct VZVirtioSocketDeviceConfiguration(StrongPtr);
+
+impl VZVirtioSocketDeviceConfiguration {
+    pub fn new() -> VZVirtioSocketDeviceConfiguration {
+        unsafe {
+            let p = owned(msg_send![vz_class!(VZVirtioSocketDeviceConfiguration), new]);
+            VZVirtioSocketDeviceConfiguration(p)
+        }
+    }
+}
+
+impl Default for VZVirtioSocketDeviceConfiguration {
+    fn default() -> Self {
+        VZVirtioSocketDeviceConfiguration::new()
+    }
+}
+
+impl VZDeviceConfiguration for VZVirtioSocketDeviceConfiguration {
+    fn id(&self) -> Id {
+        *self.0
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+impl VZSocketDeviceConfiguration for VZVirtioSocketDeviceConfiguration {}
+
+/// Virtio socket device of a virtual machine, obtained from
+/// [`VZVirtualMachine::socket_devices`](crate::virtualization::virtual_machine::VZVirtualMachine::socket_devices).
+pub struct VZVirtioSocketDevice {
+    p: StrongPtr,
+    queue: DispatchQueue,
+    callbacks: CallbackQueue,
+}
+
+impl VZVirtioSocketDevice {
+    pub(crate) fn from_raw(
+        p: StrongPtr,
+        queue: DispatchQueue,
+        callbacks: CallbackQueue,
+    ) -> VZVirtioSocketDevice {
+        VZVirtioSocketDevice {
+            p,
+            queue,
+            callbacks,
+        }
+    }
+
+    /// A handle to the same device whose completion closures run on `queue`.
+    pub fn on_queue(&self, queue: &DispatchQueue) -> VZVirtioSocketDevice {
+        VZVirtioSocketDevice {
+            p: self.p.clone(),
+            queue: self.queue.clone(),
+            callbacks: CallbackQueue::Queue(queue.clone()),
+        }
+    }
+
+    /// The VM's queue, onto which requests to the device are dispatched.
+    pub(crate) fn queue(&self) -> &DispatchQueue {
+        &self.queue
+    }
+
+    /// Connects to a port the guest listens on. A failure does not name the port;
+    /// [`Endpoint::connect_error`](crate::virtualization::vsock::Endpoint::connect_error) adds it.
+    ///
+    /// The request is dispatched onto the VM's queue; `completion_handler` runs there too unless
+    /// another queue was chosen with [`VZVirtioSocketDevice::on_queue`].
+    pub fn connect_to_port<F>(&self, port: VsockPort, completion_handler: F)
+    where
+        F: FnOnce(CompletionOutcome<VZVirtioSocketConnection>) + Send + 'static,
+    {
+        let p = ForQueue(self.p.clone());
+        let callbacks = self.callbacks.clone();
+        let port = port.get();
+        self.queue.exec_async(move || {
+            let p = p.0;
+            let completion_handler = Cell::new(Some(completion_handler));
+            let block = ConcreteBlock::new(move |connection: Id, error: Id| {
+                let outcome = if error != NIL {
+                    CompletionOutcome::from_ns_error(NSError(unsafe { retained(error) }))
+                } else {
+                    CompletionOutcome::Success(VZVirtioSocketConnection(unsafe {
+                        retained(connection)
+                    }))
+                };
+                if let Some(f) = completion_handler.take() {
+                    callbacks.deliver(move || f(outcome));
+                }
+            });
+            let block = block.copy();
+            unsafe {
+                let _: () = msg_send![*p, connectToPort:port completionHandler:&*block];
+            }
+        });
+    }
+
+    pub fn id(&self) -> Id {
+        *self.p
+    }
+}
+
+/// A connection to a port of the guest.
+///
+/// The connection owns its file descriptor: the framework closes it on `close` or when the
+/// connection object is released, whichever comes first, and expects nobody else to close it.
+/// Wrapping the descriptor in a Rust stream would close it a second time, or leave the stream
+/// with a descriptor the framework closed, or reused for something else, when the connection
+/// goes away. Instead:
+///
+/// | call | descriptor | lifetime |
+/// |---|---|---|
+/// | [`as_raw_fd`](AsRawFd::as_raw_fd) | the connection's, borrowed | until the connection is closed or released |
+/// | [`dup_stream`](Self::dup_stream) | a duplicate | independent of the connection |
+/// | [`into_stream`](Self::into_stream) | a duplicate; the connection's is closed | the stream's alone |
+/// | [`close`](Self::close) | the connection's, closed now | duplicates stay open |
+///
+/// A vsock connection ends when its last descriptor is closed, so a duplicate keeps it open after
+/// the connection object is gone. The streams are `UnixStream`s only as carriers of `read`,
+/// `write` and `shutdown`; their address methods fail on a vsock descriptor.
+pub struct VZVirtioSocketConnection(StrongPtr);
+
+// Only its descriptor and ports are read, which do not change, and closing it is thread-safe.
+unsafe impl Send for VZVirtioSocketConnection {}
+
+impl VZVirtioSocketConnection {
+    /// Wraps a connection made elsewhere, e.g. by another Objective-C binding crate.
+    ///
+    /// The connection is borrowed: the wrapper retains it, and the caller's reference stays the
+    /// caller's to release.
+    ///
+    /// # Safety
+    /// `connection` must be a valid, non-nil `VZVirtioSocketConnection`, or an object answering
+    /// `fileDescriptor`, `sourcePort`, `destinationPort` and `close` the same way.
+    pub unsafe fn from_raw_connection(connection: Id) -> VZVirtioSocketConnection {
+        VZVirtioSocketConnection(retained(connection))
+    }
+
+    /// The connection's descriptor, borrowed; the same as [`AsRawFd::as_raw_fd`]. Do not close
+    /// it, and do not use it after the connection is closed or released.
+    pub fn file_descriptor(&self) -> RawFd {
+        unsafe { msg_send![*self.0, fileDescriptor] }
+    }
+
+    pub fn source_port(&self) -> VsockPort {
+        VsockPort::from_raw(unsafe { msg_send![*self.0, sourcePort] })
+    }
+
+    pub fn destination_port(&self) -> VsockPort {
+        VsockPort::from_raw(unsafe { msg_send![*self.0, destinationPort] })
+    }
+
+    /// A stream on a duplicate of the descriptor, close-on-exec. The connection and the stream
+    /// can be closed or dropped in any order; the guest sees the connection end once both are.
+    pub fn dup_stream(&self) -> io::Result<UnixStream> {
+        let fd = unsafe { libc::fcntl(self.file_descriptor(), libc::F_DUPFD_CLOEXEC, 0) };
+        if fd == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(unsafe { UnixStream::from_raw_fd(fd) })
+    }
+
+    /// Hands the connection over to a stream, which is then its only descriptor.
+    ///
+    /// The framework cannot give up its descriptor, so this duplicates it as
+    /// [`dup_stream`](Self::dup_stream) does and closes the connection's. If duplicating fails
+    /// the connection is released, which closes it.
+    pub fn into_stream(self) -> io::Result<UnixStream> {
+        let stream = self.dup_stream()?;
+        let _ = self.close();
+        Ok(stream)
+    }
+
+    /// Closes the connection's descriptor now rather than when the connection is released.
+    /// Streams from [`dup_stream`](Self::dup_stream) stay open. The framework reports no errors.
+    pub fn close(self) -> Result<(), CloseError> {
+        unsafe {
+            let _: () = msg_send![*self.0, close];
+        }
+        Ok(())
+    }
+}
+
+impl AsRawFd for VZVirtioSocketConnection {
+    fn as_raw_fd(&self) -> RawFd {
+        self.file_descriptor()
+    }
 }
@@ -1,37 +1,96 @@
 //! storage device module
 
-use crate::base::{Id, NSError, NSInteger, NSURL};
+use crate::base::{Id, NSError, NSFileHandle, NSInteger, NSURL};
+use crate::features::{ClassLookup, ClassNotAvailable};
+use crate::resource::{close_file, CloseError};
+use crate::runtime::{alloc, call_with_error, owned, retained, to_objc_bool, vz_class};
+use crate::strict;
+use crate::virtualization::device::VZDeviceConfiguration;
+use crate::virtualization::error::{ResultExt, VZErrorCtx};
 
-use objc::runtime::BOOL;
+use std::any::Any;
+use std::fmt;
+use std::fs::{self, File};
+use std::io;
+use std::os::raw::c_void;
+use std::os::unix::io::{AsRawFd, IntoRawFd};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard, Once};
+
+use objc::declare::ClassDecl;
+use objc::rc::StrongPtr;
+use objc::runtime::{Class, Object, Sel, BOOL};
 use objc::{class, msg_send, sel, sel_impl};
-use objc::{rc::StrongPtr, runtime::NO, runtime::YES};
+
+static DISK_BLOCK_DEVICE_ATTACHMENT: ClassLookup =
+    ClassLookup::new("VZDiskBlockDeviceStorageDeviceAttachment", "macOS 14");
+static USB_MASS_STORAGE: ClassLookup =
+    ClassLookup::new("VZUSBMassStorageDeviceConfiguration", "macOS 13");
 
 /// common configure of storage device attachment
 pub trait VZStorageDeviceAttachment {
     fn id(&self) -> Id;
 }
 
+// Raw values, checked against the macOS 14 SDK headers. The synchronization integers differ
+// between the two enums, which is why they are distinct types.
+//
+// | mode      | VZDiskImageCachingMode | VZDiskImageSynchronizationMode | VZDiskSynchronizationMode |
+// |-----------|------------------------|--------------------------------|---------------------------|
+// | automatic | 0                      |                                |                           |
+// | uncached  | 1                      |                                |                           |
+// | cached    | 2                      |                                |                           |
+// | full      |                        | 1                              | 0                         |
+// | fsync     |                        | 2                              |                           |
+// | none      |                        | 3                              | 1                         |
+const DISK_IMAGE_CACHING_AUTOMATIC: NSInteger = 0;
+const DISK_IMAGE_CACHING_UNCACHED: NSInteger = 1;
+const DISK_IMAGE_CACHING_CACHED: NSInteger = 2;
+const DISK_IMAGE_SYNCHRONIZATION_FULL: NSInteger = 1;
+const DISK_IMAGE_SYNCHRONIZATION_FSYNC: NSInteger = 2;
+const DISK_IMAGE_SYNCHRONIZATION_NONE: NSInteger = 3;
+const DISK_SYNCHRONIZATION_FULL: NSInteger = 0;
+const DISK_SYNCHRONIZATION_NONE: NSInteger = 1;
+
 /// An integer that describes the disk image caching mode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct VZDiskImageCachingMode(NSInteger);
 
 impl VZDiskImageCachingMode {
     /// Allows the virtualization framework to automatically determine whether to enable data caching.
     pub fn automatic() -> Self {
-        Self(0)
+        Self(DISK_IMAGE_CACHING_AUTOMATIC)
     }
 
     /// Disables data caching.
     pub fn uncached() -> Self {
-        Self(1)
+        Self(DISK_IMAGE_CACHING_UNCACHED)
     }
 
     /// Enables data caching.
     pub fn cached() -> Self {
-        Self(2)
+        Self(DISK_IMAGE_CACHING_CACHED)
+    }
+
+    fn raw(&self) -> NSInteger {
+        self.0
+    }
+
+    fn name(&self) -> &'static str {
+        match self.0 {
+            DISK_IMAGE_CACHING_AUTOMATIC => "automatic",
+            DISK_IMAGE_CACHING_UNCACHED => "uncached",
+            DISK_IMAGE_CACHING_CACHED => "cached",
+            _ => "unknown",
+        }
     }
 }
 
 /// An integer that describes the disk image synchronization mode.
+///
+/// Only for [`VZDiskImageStorageDeviceAttachment`]; other attachments take
+/// [`VZDiskSynchronizationMode`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct VZDiskImageSynchronizationMode(NSInteger);
 
 impl VZDiskImageSynchronizationMode {
@@ -42,7 +101,7 @@ impl VZDiskImageSynchronizationMode {
     /// ensures the data moves from the disk's internal cache to permanent storage. This ensures
     /// there's no loss of already synchronized data in the case of panic or loss of power.
     pub fn full() -> Self {
-        Self(1)
+        Self(DISK_IMAGE_SYNCHRONIZATION_FULL)
     }
 
     /// Synchronizes data to the drive using the system's best-effort synchronization mode.
@@ -53,7 +112,7 @@ impl VZDiskImageSynchronizationMode {
     ///
     /// This is a best-effort mode with the same guarantees as the `fsync(_:)` system call.
     pub fn fsync() -> Self {
-        Self(2)
+        Self(DISK_IMAGE_SYNCHRONIZATION_FSYNC)
     }
 
     /// Disables data synchronization with the permanent storage.
@@ -68,7 +127,189 @@ impl VZDiskImageSynchronizationMode {
     /// Using this mode may result in improved performance since no synchronization with the
     /// underlying storage is necessary.
     pub fn none() -> Self {
-        Self(3)
+        Self(DISK_IMAGE_SYNCHRONIZATION_NONE)
+    }
+
+    fn raw(&self) -> NSInteger {
+        self.0
+    }
+
+    fn name(&self) -> &'static str {
+        match self.0 {
+            DISK_IMAGE_SYNCHRONIZATION_FULL => "full",
+            DISK_IMAGE_SYNCHRONIZATION_FSYNC => "fsync",
+            DISK_IMAGE_SYNCHRONIZATION_NONE => "none",
+            _ => "unknown",
+        }
+    }
+}
+
+/// An integer that describes the synchronization mode of block device and network block device
+/// attachments.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VZDiskSynchronizationMode(NSInteger);
+
+impl VZDiskSynchronizationMode {
+    /// Synchronizes data to the permanent storage and out of the disk's internal cache.
+    pub fn full() -> Self {
+        Self(DISK_SYNCHRONIZATION_FULL)
+    }
+
+    /// Disables data synchronization with the permanent storage.
+    pub fn none() -> Self {
+        Self(DISK_SYNCHRONIZATION_NONE)
+    }
+
+    fn raw(&self) -> NSInteger {
+        self.0
+    }
+}
+
+/// How well a caching mode and a synchronization mode of a disk image work together, from
+/// [`ModeCombination::assess`]. The framework accepts every combination without comment.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ModeCombination {
+    Recommended,
+    /// Safe to use, with a trade-off worth knowing about.
+    Acceptable {
+        note: &'static str,
+    },
+    /// Risks losing or corrupting the image, or costs performance for nothing.
+    Dangerous {
+        reason: &'static str,
+    },
+}
+
+impl ModeCombination {
+    pub fn assess(
+        caching_mode: VZDiskImageCachingMode,
+        synchronization_mode: VZDiskImageSynchronizationMode,
+    ) -> ModeCombination {
+        MODE_MATRIX
+            .iter()
+            .find(|(caching, synchronization, _)| {
+                *caching == caching_mode.raw() && *synchronization == synchronization_mode.raw()
+            })
+            .map_or(
+                ModeCombination::Acceptable {
+                    note: "a mode this crate has no assessment for",
+                },
+                |(_, _, assessment)| *assessment,
+            )
+    }
+}
+
+// Every caching and synchronization mode pair, with why it is assessed as it is. Based on
+// Apple's documentation of the modes as of macOS 14: update a cell and its rationale together
+// when the guidance changes.
+const MODE_MATRIX: &[(NSInteger, NSInteger, ModeCombination)] = &[
+    // The framework's defaults: it picks caching for the image's storage, and a guest's flush
+    // reaches permanent storage.
+    (
+        DISK_IMAGE_CACHING_AUTOMATIC,
+        DISK_IMAGE_SYNCHRONIZATION_FULL,
+        ModeCombination::Recommended,
+    ),
+    // fsync(2) leaves data in the drive's cache, which a host power loss can drop.
+    (
+        DISK_IMAGE_CACHING_AUTOMATIC,
+        DISK_IMAGE_SYNCHRONIZATION_FSYNC,
+        ModeCombination::Acceptable {
+            note: "a host power loss can drop writes the drive has cached",
+        },
+    ),
+    // Automatic caching picks cached for most images, with the same risk as cached + none.
+    (
+        DISK_IMAGE_CACHING_AUTOMATIC,
+        DISK_IMAGE_SYNCHRONIZATION_NONE,
+        ModeCombination::Dangerous {
+            reason: "guest flushes are ignored while the host may cache writes, so a host crash \
+                     can silently corrupt the image",
+        },
+    ),
+    // Durable, but every guest read goes to the drive; right for storage the host must not
+    // cache, e.g. an image other hosts access too.
+    (
+        DISK_IMAGE_CACHING_UNCACHED,
+        DISK_IMAGE_SYNCHRONIZATION_FULL,
+        ModeCombination::Acceptable {
+            note: "reads bypass the host's cache, which is slower unless the storage is shared",
+        },
+    ),
+    // Uncached I/O is slow, and fsync(2) still leaves data in the drive's cache.
+    (
+        DISK_IMAGE_CACHING_UNCACHED,
+        DISK_IMAGE_SYNCHRONIZATION_FSYNC,
+        ModeCombination::Acceptable {
+            note: "slower than cached, and a host power loss can drop writes the drive has cached",
+        },
+    ),
+    // Pays for uncached I/O and gets no durability for it.
+    (
+        DISK_IMAGE_CACHING_UNCACHED,
+        DISK_IMAGE_SYNCHRONIZATION_NONE,
+        ModeCombination::Dangerous {
+            reason: "uncached I/O is slow and guest flushes are ignored, giving neither \
+                     performance nor safety",
+        },
+    ),
+    // The host's cache speeds up reads, and a guest's flush still reaches permanent storage.
+    (
+        DISK_IMAGE_CACHING_CACHED,
+        DISK_IMAGE_SYNCHRONIZATION_FULL,
+        ModeCombination::Recommended,
+    ),
+    // Fast; a host power loss can drop what the drive has cached.
+    (
+        DISK_IMAGE_CACHING_CACHED,
+        DISK_IMAGE_SYNCHRONIZATION_FSYNC,
+        ModeCombination::Acceptable {
+            note: "a host power loss can drop writes the drive has cached",
+        },
+    ),
+    // Writes can sit in the host's cache indefinitely, whatever the guest flushes.
+    (
+        DISK_IMAGE_CACHING_CACHED,
+        DISK_IMAGE_SYNCHRONIZATION_NONE,
+        ModeCombination::Dangerous {
+            reason: "guest flushes are ignored while the host caches writes, so a host crash can \
+                     silently corrupt the image",
+        },
+    ),
+];
+
+/// What a disk image is used for, for [`recommended_for_workload`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Workload {
+    /// An image thrown away after one run, e.g. a CI job's scratch disk.
+    Ephemeral,
+    /// An image that keeps a guest's data across runs.
+    Persistent,
+    /// A base image many guests attach read-only.
+    SharedBaseImage,
+}
+
+/// The caching and synchronization modes suggested for `workload`. None of them is
+/// [`ModeCombination::Dangerous`]; synchronization mode `none` is never suggested, since a
+/// host crash during an ephemeral run still leaves a corrupt image behind, and `fsync` costs
+/// little more.
+pub fn recommended_for_workload(
+    workload: Workload,
+) -> (VZDiskImageCachingMode, VZDiskImageSynchronizationMode) {
+    match workload {
+        Workload::Ephemeral => (
+            VZDiskImageCachingMode::cached(),
+            VZDiskImageSynchronizationMode::fsync(),
+        ),
+        Workload::Persistent => (
+            VZDiskImageCachingMode::automatic(),
+            VZDiskImageSynchronizationMode::full(),
+        ),
+        // The host's cache serves the blocks all guests read; nothing is written.
+        Workload::SharedBaseImage => (
+            VZDiskImageCachingMode::cached(),
+            VZDiskImageSynchronizationMode::full(),
+        ),
     }
 }
 
@@ -81,11 +322,32 @@ impl VZDiskImageSynchronizationMode {
 /// {
 ///     Ok(x) => x,
 ///     Err(err) => {
-///         err.dump();
+///         eprintln!("{}", err);
 ///         return;
 ///     }
 /// };
 /// ```
+///
+/// # Locking
+/// `build` takes an advisory `flock` lock on the image without waiting for it: a shared one when
+/// read-only, so any number of machines can boot from one base image, and an exclusive one when
+/// writable. A second writable attachment of the image, or a read-only one next to a writable
+/// one, fails with [`VZDiskImageAttachmentError::ImageBusy`] instead of corrupting it, whether it
+/// is built by this process or another.
+///
+/// The lock belongs to the framework's attachment object, not to the wrapper: device
+/// configurations, and virtual machines made from them, keep it held after the wrapper is
+/// gone, and it is released when the last of them is released. The lock is advisory and only
+/// keeps out other `flock` users, such as other processes using this crate; see
+/// [`no_lock`](VZDiskImageStorageDeviceAttachmentBuilder::no_lock) to opt out.
+///
+/// # Modes
+/// With caching and synchronization modes set, `build` assesses them with
+/// [`ModeCombination::assess`]. A [`ModeCombination::Dangerous`] pair for a writable image is
+/// attached with a `log` warning, panics in strict mode, and fails with
+/// [`VZDiskImageAttachmentError::DangerousModes`] after
+/// [`refuse_dangerous_modes`](VZDiskImageStorageDeviceAttachmentBuilder::refuse_dangerous_modes).
+/// Read-only images are never written, so any pair is fine for them.
 pub struct VZDiskImageStorageDeviceAttachmentBuilder<
     Path,
     ReadOnly,
@@ -96,6 +358,8 @@ pub struct VZDiskImageStorageDeviceAttachmentBuilder<
     read_only: ReadOnly,
     caching_mode: CachingMode,
     synchronization_mode: SynchronizationMode,
+    lock: bool,
+    refuse_dangerous_modes: bool,
 }
 
 impl VZDiskImageStorageDeviceAttachmentBuilder<(), bool, (), ()> {
@@ -105,10 +369,18 @@ impl VZDiskImageStorageDeviceAttachmentBuilder<(), bool, (), ()> {
             read_only: true,
             caching_mode: (),
             synchronization_mode: (),
+            lock: true,
+            refuse_dangerous_modes: false,
         }
     }
 }
 
+impl Default for VZDiskImageStorageDeviceAttachmentBuilder<(), bool, (), ()> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl<Path, ReadOnly, CachingMode, SynchronizationMode>
     VZDiskImageStorageDeviceAttachmentBuilder<Path, ReadOnly, CachingMode, SynchronizationMode>
 {
@@ -122,6 +394,31 @@ impl<Path, ReadOnly, CachingMode, SynchronizationMode>
             read_only: self.read_only,
             caching_mode: self.caching_mode,
             synchronization_mode: self.synchronization_mode,
+            lock: self.lock,
+            refuse_dangerous_modes: self.refuse_dangerous_modes,
+        }
+    }
+
+    /// Uses a file URL made elsewhere, e.g. by another Objective-C binding crate, instead of a
+    /// path.
+    ///
+    /// The builder retains the URL; the caller's reference stays the caller's to release.
+    ///
+    /// # Safety
+    /// `url` must be a valid, non-nil `NSURL`. It should be a file URL; the framework rejects
+    /// other URLs when the attachment is built.
+    pub unsafe fn url_raw(
+        self,
+        url: Id,
+    ) -> VZDiskImageStorageDeviceAttachmentBuilder<NSURL, ReadOnly, CachingMode, SynchronizationMode>
+    {
+        VZDiskImageStorageDeviceAttachmentBuilder {
+            path: NSURL(retained(url)),
+            read_only: self.read_only,
+            caching_mode: self.caching_mode,
+            synchronization_mode: self.synchronization_mode,
+            lock: self.lock,
+            refuse_dangerous_modes: self.refuse_dangerous_modes,
         }
     }
 
@@ -135,6 +432,8 @@ impl<Path, ReadOnly, CachingMode, SynchronizationMode>
             read_only,
             caching_mode: self.caching_mode,
             synchronization_mode: self.synchronization_mode,
+            lock: self.lock,
+            refuse_dangerous_modes: self.refuse_dangerous_modes,
         }
     }
 
@@ -152,6 +451,8 @@ impl<Path, ReadOnly, CachingMode, SynchronizationMode>
             read_only: self.read_only,
             caching_mode,
             synchronization_mode: self.synchronization_mode,
+            lock: self.lock,
+            refuse_dangerous_modes: self.refuse_dangerous_modes,
         }
     }
 
@@ -169,14 +470,46 @@ impl<Path, ReadOnly, CachingMode, SynchronizationMode>
             read_only: self.read_only,
             caching_mode: self.caching_mode,
             synchronization_mode,
+            lock: self.lock,
+            refuse_dangerous_modes: self.refuse_dangerous_modes,
         }
     }
+
+    /// Attaches the image without an advisory lock, as before locking existed.
+    ///
+    /// For images coordinated some other way, e.g. by a lock the caller takes with `flock`
+    /// itself, which would otherwise make `build` fail with
+    /// [`VZDiskImageAttachmentError::ImageBusy`].
+    pub fn no_lock(mut self) -> Self {
+        self.lock = false;
+        self
+    }
+
+    /// Makes `build` fail with [`VZDiskImageAttachmentError::DangerousModes`] instead of
+    /// warning when the modes are [`ModeCombination::Dangerous`] for a writable image.
+    pub fn refuse_dangerous_modes(mut self) -> Self {
+        self.refuse_dangerous_modes = true;
+        self
+    }
 }
 
 impl VZDiskImageStorageDeviceAttachmentBuilder<String, bool, (), ()> {
-    pub fn build(self) -> Result<VZDiskImageStorageDeviceAttachment, NSError> {
-        let read_only = if self.read_only { YES } else { NO };
-        unsafe { VZDiskImageStorageDeviceAttachment::new(self.path.as_str(), read_only) }
+    pub fn build(self) -> Result<VZDiskImageStorageDeviceAttachment, VZDiskImageAttachmentError> {
+        let read_only = to_objc_bool(self.read_only);
+        let attachment = disk_image_url(&self.path)
+            .and_then(|url| unsafe { VZDiskImageStorageDeviceAttachment::new(&url, read_only) })
+            .ctx(ATTACH_DISK_IMAGE, disk_image(&self.path, self.read_only))?;
+        lock_image(attachment, &self.path, self.read_only, self.lock)
+    }
+}
+
+impl VZDiskImageStorageDeviceAttachmentBuilder<NSURL, bool, (), ()> {
+    pub fn build(self) -> Result<VZDiskImageStorageDeviceAttachment, VZDiskImageAttachmentError> {
+        let path = self.path.path();
+        let read_only = to_objc_bool(self.read_only);
+        let attachment = unsafe { VZDiskImageStorageDeviceAttachment::new(&self.path, read_only) }
+            .ctx(ATTACH_DISK_IMAGE, disk_image(path.as_str(), self.read_only))?;
+        lock_image(attachment, path.as_str(), self.read_only, self.lock)
     }
 }
 
@@ -188,64 +521,490 @@ impl
         VZDiskImageSynchronizationMode,
     >
 {
-    pub fn build(self) -> Result<VZDiskImageStorageDeviceAttachment, NSError> {
-        let read_only = if self.read_only { YES } else { NO };
-        unsafe {
+    pub fn build(self) -> Result<VZDiskImageStorageDeviceAttachment, VZDiskImageAttachmentError> {
+        check_modes(
+            &self.path,
+            self.read_only,
+            self.caching_mode,
+            self.synchronization_mode,
+            self.refuse_dangerous_modes,
+        )?;
+        let read_only = to_objc_bool(self.read_only);
+        let attachment = disk_image_url(&self.path)
+            .and_then(|url| unsafe {
+                VZDiskImageStorageDeviceAttachment::new_with_mode(
+                    &url,
+                    read_only,
+                    self.caching_mode.raw(),
+                    self.synchronization_mode.raw(),
+                )
+            })
+            .ctx(ATTACH_DISK_IMAGE, disk_image(&self.path, self.read_only))?;
+        lock_image(attachment, &self.path, self.read_only, self.lock)
+    }
+}
+
+impl
+    VZDiskImageStorageDeviceAttachmentBuilder<
+        NSURL,
+        bool,
+        VZDiskImageCachingMode,
+        VZDiskImageSynchronizationMode,
+    >
+{
+    pub fn build(self) -> Result<VZDiskImageStorageDeviceAttachment, VZDiskImageAttachmentError> {
+        let path = self.path.path();
+        check_modes(
+            path.as_str(),
+            self.read_only,
+            self.caching_mode,
+            self.synchronization_mode,
+            self.refuse_dangerous_modes,
+        )?;
+        let read_only = to_objc_bool(self.read_only);
+        let attachment = unsafe {
             VZDiskImageStorageDeviceAttachment::new_with_mode(
-                self.path.as_str(),
+                &self.path,
                 read_only,
-                self.caching_mode.0,
-                self.synchronization_mode.0,
+                self.caching_mode.raw(),
+                self.synchronization_mode.raw(),
             )
         }
+        .ctx(ATTACH_DISK_IMAGE, disk_image(path.as_str(), self.read_only))?;
+        lock_image(attachment, path.as_str(), self.read_only, self.lock)
+    }
+}
+
+const ATTACH_DISK_IMAGE: &str = "attach disk image";
+
+/// The resource of a disk image attachment error.
+fn disk_image(path: &str, read_only: bool) -> String {
+    format!("'{}' (read_only={})", path, read_only)
+}
+
+/// Assesses the modes of an attachment about to be built; see the builder's "Modes" section.
+fn check_modes(
+    path: &str,
+    read_only: bool,
+    caching_mode: VZDiskImageCachingMode,
+    synchronization_mode: VZDiskImageSynchronizationMode,
+    refuse: bool,
+) -> Result<(), VZDiskImageAttachmentError> {
+    if read_only {
+        return Ok(());
+    }
+    let reason = match ModeCombination::assess(caching_mode, synchronization_mode) {
+        ModeCombination::Dangerous { reason } => reason,
+        _ => return Ok(()),
+    };
+    let error = VZDiskImageAttachmentError::DangerousModes {
+        path: path.to_string(),
+        caching_mode,
+        synchronization_mode,
+        reason,
+    };
+    if refuse {
+        return Err(error);
+    }
+    strict::dangerous_modes(&error);
+    log::warn!("{}; attaching anyway", error);
+    Ok(())
+}
+
+/// The file URL of `path`, or `EINVAL` if it cannot be one, e.g. because it is empty.
+fn disk_image_url(path: &str) -> Result<NSURL, NSError> {
+    NSURL::file_url_with_path(path, false).ok_or_else(|| NSError::posix(libc::EINVAL))
+}
+
+/// Error returned by [`VZDiskImageStorageDeviceAttachmentBuilder`]'s `build`.
+pub enum VZDiskImageAttachmentError {
+    /// A conflicting advisory lock is held on the image: an exclusive one when attaching
+    /// read-only, any when attaching writable.
+    ImageBusy {
+        /// The image path as given to the builder.
+        path: String,
+        /// Who holds the lock, as far as this process can tell without asking the kernel for
+        /// the owners of open files.
+        holder_hint: String,
+    },
+    /// The framework rejected the attachment, or the image could not be opened to lock it.
+    Framework(VZErrorCtx),
+    /// The modes are [`ModeCombination::Dangerous`] for a writable image, and the builder was
+    /// told to [`refuse_dangerous_modes`](VZDiskImageStorageDeviceAttachmentBuilder::refuse_dangerous_modes).
+    DangerousModes {
+        /// The image path as given to the builder.
+        path: String,
+        caching_mode: VZDiskImageCachingMode,
+        synchronization_mode: VZDiskImageSynchronizationMode,
+        reason: &'static str,
+    },
+}
+
+impl From<VZErrorCtx> for VZDiskImageAttachmentError {
+    fn from(error: VZErrorCtx) -> Self {
+        VZDiskImageAttachmentError::Framework(error)
+    }
+}
+
+impl fmt::Display for VZDiskImageAttachmentError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VZDiskImageAttachmentError::ImageBusy { path, holder_hint } => {
+                write!(f, "disk image '{}' is busy: {}", path, holder_hint)
+            }
+            VZDiskImageAttachmentError::Framework(error) => error.fmt(f),
+            VZDiskImageAttachmentError::DangerousModes {
+                path,
+                caching_mode,
+                synchronization_mode,
+                reason,
+            } => write!(
+                f,
+                "disk image '{}' has dangerous modes, caching {} with synchronization {}: {}",
+                path,
+                caching_mode.name(),
+                synchronization_mode.name(),
+                reason
+            ),
+        }
+    }
+}
+
+impl fmt::Debug for VZDiskImageAttachmentError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VZDiskImageAttachmentError::ImageBusy { path, holder_hint } => f
+                .debug_struct("ImageBusy")
+                .field("path", path)
+                .field("holder_hint", holder_hint)
+                .finish(),
+            VZDiskImageAttachmentError::Framework(error) => {
+                f.debug_tuple("Framework").field(error).finish()
+            }
+            VZDiskImageAttachmentError::DangerousModes {
+                path,
+                caching_mode,
+                synchronization_mode,
+                reason,
+            } => f
+                .debug_struct("DangerousModes")
+                .field("path", path)
+                .field("caching_mode", caching_mode)
+                .field("synchronization_mode", synchronization_mode)
+                .field("reason", reason)
+                .finish(),
+        }
+    }
+}
+
+impl std::error::Error for VZDiskImageAttachmentError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            VZDiskImageAttachmentError::ImageBusy { .. }
+            | VZDiskImageAttachmentError::DangerousModes { .. } => None,
+            // Displayed as the context error itself, so its cause comes next.
+            VZDiskImageAttachmentError::Framework(error) => error.source(),
+        }
+    }
+}
+
+/// Images locked by attachments built in this process, with whether the lock is exclusive. Only
+/// used to name the holder of a conflicting lock; the kernel enforces the locks themselves.
+static LOCKED_IMAGES: Mutex<Vec<(PathBuf, bool)>> = Mutex::new(Vec::new());
+
+fn locked_images() -> MutexGuard<'static, Vec<(PathBuf, bool)>> {
+    LOCKED_IMAGES.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// An advisory `flock` lock on a disk image, released when dropped.
+///
+/// `flock` locks belong to an open file description, so this descriptor conflicts with others
+/// opened on the image, in this process too, but not with the descriptor the framework opens
+/// on it, which the framework does not lock.
+struct ImageLock {
+    _file: File,
+    path: PathBuf,
+    exclusive: bool,
+}
+
+impl ImageLock {
+    fn acquire(path: &str, read_only: bool) -> Result<ImageLock, VZDiskImageAttachmentError> {
+        let file = File::open(path).map_err(|e| lock_failed(path, read_only, e))?;
+        let key = fs::canonicalize(path).unwrap_or_else(|_| PathBuf::from(path));
+        let exclusive = !read_only;
+        let operation = if exclusive {
+            libc::LOCK_EX
+        } else {
+            libc::LOCK_SH
+        };
+        // Held across `flock`, so the hint sees the attachments of this process as they were
+        // when the lock was refused.
+        let mut images = locked_images();
+        if unsafe { libc::flock(file.as_raw_fd(), operation | libc::LOCK_NB) } == -1 {
+            let error = io::Error::last_os_error();
+            if error.raw_os_error() != Some(libc::EWOULDBLOCK) {
+                return Err(lock_failed(path, read_only, error));
+            }
+            return Err(VZDiskImageAttachmentError::ImageBusy {
+                path: path.to_string(),
+                holder_hint: holder_hint(&images, &key),
+            });
+        }
+        images.push((key.clone(), exclusive));
+        Ok(ImageLock {
+            _file: file,
+            path: key,
+            exclusive,
+        })
+    }
+}
+
+impl Drop for ImageLock {
+    fn drop(&mut self) {
+        let mut images = locked_images();
+        let lock = (self.path.clone(), self.exclusive);
+        if let Some(i) = images.iter().position(|l| *l == lock) {
+            images.swap_remove(i);
+        }
+        // `_file` is closed after this, releasing the lock.
+    }
+}
+
+/// The image could be attached but not opened or locked, reported like the framework's errors.
+fn lock_failed(path: &str, read_only: bool, error: io::Error) -> VZDiskImageAttachmentError {
+    let errno = error.raw_os_error().unwrap_or(libc::EIO);
+    let error = NSError::posix(errno);
+    VZErrorCtx::new(ATTACH_DISK_IMAGE, Some(disk_image(path, read_only)), error).into()
+}
+
+/// Names the holder of a lock on `path` that conflicts with a new one.
+fn holder_hint(images: &[(PathBuf, bool)], path: &Path) -> String {
+    let writers = images.iter().filter(|(p, e)| p == path && *e).count();
+    let readers = images.iter().filter(|(p, e)| p == path && !*e).count();
+    if writers > 0 {
+        "a writable attachment in this process holds this image".to_string()
+    } else if readers == 1 {
+        "a read-only attachment in this process holds this image".to_string()
+    } else if readers > 1 {
+        format!(
+            "{} read-only attachments in this process hold this image",
+            readers
+        )
+    } else {
+        "another process or attachment holds this image".to_string()
+    }
+}
+
+const LOCK_HOLDER_CLASS: &str = "VirtualizationRsDiskImageLock";
+const LOCK_IVAR: &str = "lock";
+
+/// `OBJC_ASSOCIATION_RETAIN_NONATOMIC`.
+const ASSOCIATION_RETAIN: usize = 1;
+
+/// The key the lock holder is associated with; only its address matters.
+static LOCK_KEY: u8 = 0;
+
+extern "C" {
+    fn objc_setAssociatedObject(object: Id, key: *const c_void, value: Id, policy: usize);
+}
+
+/// Locks the image of `attachment` unless the builder opted out.
+///
+/// The lock is associated with the framework's attachment object rather than kept in the
+/// wrapper: device configurations retain that object and drop the wrapper, so the lock lasts
+/// exactly as long as the last configuration or virtual machine using the attachment.
+fn lock_image(
+    attachment: VZDiskImageStorageDeviceAttachment,
+    path: &str,
+    read_only: bool,
+    lock: bool,
+) -> Result<VZDiskImageStorageDeviceAttachment, VZDiskImageAttachmentError> {
+    if !lock {
+        return Ok(attachment);
+    }
+    let lock = ImageLock::acquire(path, read_only)?;
+    unsafe {
+        let holder = owned(msg_send![alloc(lock_holder_class()), init]);
+        (**holder).set_ivar(LOCK_IVAR, Box::into_raw(Box::new(lock)) as *mut c_void);
+        objc_setAssociatedObject(
+            *attachment.0,
+            &LOCK_KEY as *const u8 as *const c_void,
+            *holder,
+            ASSOCIATION_RETAIN,
+        );
+    }
+    Ok(attachment)
+}
+
+fn lock_holder_class() -> &'static Class {
+    static REGISTER: Once = Once::new();
+    REGISTER.call_once(|| {
+        let mut decl = ClassDecl::new(LOCK_HOLDER_CLASS, class!(NSObject)).unwrap();
+        decl.add_ivar::<*mut c_void>(LOCK_IVAR);
+        unsafe {
+            decl.add_method(
+                sel!(dealloc),
+                dealloc_lock_holder as extern "C" fn(&Object, Sel),
+            );
+        }
+        decl.register();
+    });
+    Class::get(LOCK_HOLDER_CLASS).unwrap()
+}
+
+extern "C" fn dealloc_lock_holder(this: &Object, _cmd: Sel) {
+    unsafe {
+        let lock = *this.get_ivar::<*mut c_void>(LOCK_IVAR) as *mut ImageLock;
+        if !lock.is_null() {
+            drop(Box::from_raw(lock));
+        }
+        let _: () = msg_send![super(this, class!(NSObject)), dealloc];
+    }
+}
+
+/// Error returned by [`VZDiskImageStorageDeviceAttachment::new_from_file`].
+pub enum VZDiskImageFileAttachmentError {
+    /// The requested `read_only` flag does not match the access mode the file was opened with.
+    AccessModeMismatch { read_only: bool },
+    /// The access mode of the descriptor could not be read.
+    Io(io::Error),
+    /// The framework rejected the attachment.
+    Framework(VZErrorCtx),
+}
+
+impl fmt::Display for VZDiskImageFileAttachmentError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VZDiskImageFileAttachmentError::AccessModeMismatch { read_only: true } => {
+                write!(f, "a read-only disk image attachment needs a readable file")
+            }
+            VZDiskImageFileAttachmentError::AccessModeMismatch { read_only: false } => write!(
+                f,
+                "a writable disk image attachment needs a file opened for reading and writing"
+            ),
+            VZDiskImageFileAttachmentError::Io(error) => write!(
+                f,
+                "cannot read the access mode of the disk image file: {}",
+                error
+            ),
+            VZDiskImageFileAttachmentError::Framework(error) => error.fmt(f),
+        }
+    }
+}
+
+impl fmt::Debug for VZDiskImageFileAttachmentError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VZDiskImageFileAttachmentError::AccessModeMismatch { read_only } => f
+                .debug_struct("AccessModeMismatch")
+                .field("read_only", read_only)
+                .finish(),
+            VZDiskImageFileAttachmentError::Io(error) => f.debug_tuple("Io").field(error).finish(),
+            VZDiskImageFileAttachmentError::Framework(error) => {
+                f.debug_tuple("Framework").field(error).finish()
+            }
+        }
+    }
+}
+
+impl std::error::Error for VZDiskImageFileAttachmentError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            VZDiskImageFileAttachmentError::AccessModeMismatch { .. } => None,
+            VZDiskImageFileAttachmentError::Io(error) => Some(error),
+            // Displayed as the context error itself, so its cause comes next.
+            VZDiskImageFileAttachmentError::Framework(error) => error.source(),
+        }
     }
 }
 
 /// configure of disk image storage device attachment
-pub struct VZDiskImageStorageDeviceAttachment(StrongPtr);
+pub struct VZDiskImageStorageDeviceAttachment(StrongPtr, Option<File>);
 
 impl VZDiskImageStorageDeviceAttachment {
+    /// Initialize the attachment from an already-open file.
+    ///
+    /// The framework only accepts URLs, so the image is opened through `/dev/fd/N`. Opening
+    /// `/dev/fd/N` duplicates the descriptor onto the same open file description, so advisory locks
+    /// (`flock`) taken on `file` by the caller also cover the framework's descriptor. `file` is kept
+    /// for the lifetime of this wrapper and closed exactly once when it is closed or dropped; the
+    /// framework's own descriptor stays valid after that.
+    ///
+    /// `file` must be readable, and also writable unless `read_only` is set.
+    pub fn new_from_file(
+        file: File,
+        read_only: bool,
+        caching_mode: VZDiskImageCachingMode,
+        synchronization_mode: VZDiskImageSynchronizationMode,
+    ) -> Result<VZDiskImageStorageDeviceAttachment, VZDiskImageFileAttachmentError> {
+        let fd = file.as_raw_fd();
+        let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+        if flags == -1 {
+            return Err(VZDiskImageFileAttachmentError::Io(io::Error::last_os_error()));
+        }
+        let access_mode = flags & libc::O_ACCMODE;
+        let readable = access_mode != libc::O_WRONLY;
+        let writable = access_mode != libc::O_RDONLY;
+        if !readable || (!read_only && !writable) {
+            return Err(VZDiskImageFileAttachmentError::AccessModeMismatch { read_only });
+        }
+
+        let path = format!("/dev/fd/{}", fd);
+        let attachment = disk_image_url(&path)
+            .and_then(|url| unsafe {
+                VZDiskImageStorageDeviceAttachment::new_with_mode(
+                    &url,
+                    to_objc_bool(read_only),
+                    caching_mode.raw(),
+                    synchronization_mode.raw(),
+                )
+            })
+            .ctx(ATTACH_DISK_IMAGE, disk_image(&path, read_only));
+        match attachment {
+            Ok(attachment) => Ok(VZDiskImageStorageDeviceAttachment(attachment.0, Some(file))),
+            Err(error) => Err(VZDiskImageFileAttachmentError::Framework(error)),
+        }
+    }
+
+    /// Closes the file passed to [`new_from_file`], if any, reporting the error that dropping
+    /// would ignore. The framework's descriptor is unaffected, and so is the image lock taken by
+    /// the builder, which goes with the framework's attachment object.
+    ///
+    /// [`new_from_file`]: VZDiskImageStorageDeviceAttachment::new_from_file
+    pub fn close(mut self) -> Result<(), CloseError> {
+        self.1.take().map_or(Ok(()), close_file)
+    }
+
     unsafe fn new(
-        path: &str,
+        url: &NSURL,
         read_only: BOOL,
     ) -> Result<VZDiskImageStorageDeviceAttachment, NSError> {
-        let i: Id = msg_send![class!(VZDiskImageStorageDeviceAttachment), alloc];
-        let path_nsurl = NSURL::file_url_with_path(path, false);
-        let error = NSError::nil();
-        let p = StrongPtr::new(
-            msg_send![i, initWithURL:*path_nsurl.0 readOnly:read_only error:&(*error.0)],
-        );
-        if error.code() != 0 {
-            Err(error)
-        } else {
-            Ok(VZDiskImageStorageDeviceAttachment(p))
-        }
+        call_with_error(|error| {
+            let i = alloc(vz_class!(VZDiskImageStorageDeviceAttachment));
+            owned(msg_send![i, initWithURL:*url.0 readOnly:read_only error:error])
+        })
+        .map(|p| VZDiskImageStorageDeviceAttachment(p, None))
     }
 
     /// Initialize the attachment from a local file URL.
     unsafe fn new_with_mode(
-        path: &str,
+        url: &NSURL,
         read_only: BOOL,
         caching_mode: NSInteger,
         synchronization_mode: NSInteger,
     ) -> Result<VZDiskImageStorageDeviceAttachment, NSError> {
-        let i: Id = msg_send![class!(VZDiskImageStorageDeviceAttachment), alloc];
-        let path_nsurl = NSURL::file_url_with_path(path, false);
-        let error = NSError::nil();
-        let p = StrongPtr::new(msg_send![
-            i,
-            initWithURL: *path_nsurl.0
-            readOnly: read_only
-            cachingMode: caching_mode
-            synchronizationMode: synchronization_mode
-            error: &(*error.0)
-        ]);
-
-        if error.code() != 0 {
-            Err(error)
-        } else {
-            Ok(VZDiskImageStorageDeviceAttachment(p))
-        }
+        call_with_error(|error| {
+            let i = alloc(vz_class!(VZDiskImageStorageDeviceAttachment));
+            owned(msg_send![
+                i,
+                initWithURL: *url.0
+                readOnly: read_only
+                cachingMode: caching_mode
+                synchronizationMode: synchronization_mode
+                error: error
+            ])
+        })
+        .map(|p| VZDiskImageStorageDeviceAttachment(p, None))
     }
 }
 
@@ -255,46 +1014,115 @@ impl VZStorageDeviceAttachment for VZDiskImageStorageDeviceAttachment {
     }
 }
 
-/// configure of storage device
-pub trait VZStorageDeviceConfiguration {
-    fn id(&self) -> Id;
+/// Attachment of a host block device, such as `/dev/disk4`, opened by the caller. Available on
+/// macOS 14 and later.
+pub struct VZDiskBlockDeviceStorageDeviceAttachment(StrongPtr);
+
+impl VZDiskBlockDeviceStorageDeviceAttachment {
+    /// The attachment owns `file` and closes it when the framework releases the attachment.
+    /// Fails with `ENOTSUP`, closing `file`, before macOS 14.
+    pub fn new(
+        file: File,
+        read_only: bool,
+        synchronization_mode: VZDiskSynchronizationMode,
+    ) -> Result<VZDiskBlockDeviceStorageDeviceAttachment, VZErrorCtx> {
+        let class = DISK_BLOCK_DEVICE_ATTACHMENT
+            .get()
+            .map_err(|_| NSError::posix(libc::ENOTSUP))
+            .ctx(
+                "attach block device",
+                format!("fd {} (read_only={})", file.as_raw_fd(), read_only),
+            )?;
+        let fd = file.into_raw_fd();
+        let file_handle = NSFileHandle::init_with_file_descriptor(fd, true);
+        let attachment = unsafe {
+            call_with_error(|error| {
+                let i = alloc(class);
+                owned(msg_send![
+                    i,
+                    initWithFileHandle: *file_handle.0
+                    readOnly: to_objc_bool(read_only)
+                    synchronizationMode: synchronization_mode.raw()
+                    error: error
+                ])
+            })
+        };
+        attachment
+            .map(VZDiskBlockDeviceStorageDeviceAttachment)
+            .ctx(
+                "attach block device",
+                format!("fd {} (read_only={})", fd, read_only),
+            )
+    }
+}
+
+impl VZStorageDeviceAttachment for VZDiskBlockDeviceStorageDeviceAttachment {
+    fn id(&self) -> Id {
+        *self.0
+    }
 }
 
+/// configure of storage device
+pub trait VZStorageDeviceConfiguration: VZDeviceConfiguration {}
+
+impl<T: VZStorageDeviceConfiguration + ?Sized> VZStorageDeviceConfiguration for Box<T> {}
+
 /// configure of storage device through the Virtio interface
 pub struct VZVirtioBlockDeviceConfiguration(StrongPtr);
 
 impl VZVirtioBlockDeviceConfiguration {
     pub fn new<T: VZStorageDeviceAttachment>(attachment: T) -> VZVirtioBlockDeviceConfiguration {
         unsafe {
-            let i: Id = msg_send![class!(VZVirtioBlockDeviceConfiguration), alloc];
-            let p = StrongPtr::new(msg_send![i, initWithAttachment:attachment.id()]);
+            let i = alloc(vz_class!(VZVirtioBlockDeviceConfiguration));
+            let p = owned(msg_send![i, initWithAttachment:attachment.id()]);
             VZVirtioBlockDeviceConfiguration(p)
         }
     }
 }
 
-impl VZStorageDeviceConfiguration for VZVirtioBlockDeviceConfiguration {
+impl VZDeviceConfiguration for VZVirtioBlockDeviceConfiguration {
     fn id(&self) -> Id {
         *self.0
     }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
 }
 
+impl VZStorageDeviceConfiguration for VZVirtioBlockDeviceConfiguration {}
+
 /// The configuration object that represents a USB Mass storage device.
 pub struct VZUSBMassStorageDeviceConfiguration(StrongPtr);
 
 impl VZUSBMassStorageDeviceConfiguration {
     /// Creates a new storage device configuration with the specified attachment.
+    ///
+    /// # Panics
+    /// Before macOS 13; see [`try_new`](Self::try_new).
+    #[track_caller]
     pub fn new<T: VZStorageDeviceAttachment>(attachment: T) -> Self {
+        Self::try_new(attachment).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    pub fn try_new<T: VZStorageDeviceAttachment>(attachment: T) -> Result<Self, ClassNotAvailable> {
+        let class = USB_MASS_STORAGE.get()?;
         unsafe {
-            let i: Id = msg_send![class!(VZUSBMassStorageDeviceConfiguration), alloc];
-            let p = StrongPtr::new(msg_send![i, initWithAttachment:attachment.id()]);
-            Self(p)
+            let i = alloc(class);
+            let p = owned(msg_send![i, initWithAttachment:attachment.id()]);
+            Ok(Self(p))
         }
     }
 }
 
-impl VZStorageDeviceConfiguration for VZUSBMassStorageDeviceConfiguration {
+impl VZDeviceConfiguration for VZUSBMassStorageDeviceConfiguration {
     fn id(&self) -> Id {
         *self.0
     }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
 }
+
+impl VZStorageDeviceConfiguration for VZUSBMassStorageDeviceConfiguration {}
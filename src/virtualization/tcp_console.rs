@@ -0,0 +1,381 @@
+//! TCP console module
+//!
+//! Exposes a guest serial console on a localhost TCP port, e.g. for `nc localhost 5555`.
+//!
+//! # Examples
+//! ```rust
+//! let bridge = TcpConsoleBridge::bind(5555, NoClientPolicy::Buffer { limit: 64 * 1024 })?;
+//! let serial = VZVirtioConsoleDeviceSerialPortConfiguration::new(bridge.attachment());
+//! println!("console on {}", bridge.local_addr());
+//! ```
+
+use crate::base::NSFileHandle;
+use crate::resource::{close_file, first_error, CloseError};
+use crate::virtualization::serial_port::{
+    pipe, VZFileHandleSerialPortAttachment, VZFileHandleSerialPortAttachmentBuilder,
+};
+
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::net::{Ipv4Addr, Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::os::unix::io::IntoRawFd;
+use std::os::unix::net::UnixStream;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread::{self, JoinHandle};
+
+const BUSY_MESSAGE: &[u8] = b"console busy: another client is attached\r\n";
+
+/// What happens to guest output while no client is connected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NoClientPolicy {
+    /// Discard it.
+    Drop,
+    /// Keep the most recent `limit` bytes and send them to the next client.
+    Buffer { limit: usize },
+}
+
+/// A connected console client.
+pub(crate) trait ConsoleStream: Read + Write + Send + Sized + 'static {
+    fn try_clone(&self) -> io::Result<Self>;
+    fn shutdown(&self, how: Shutdown) -> io::Result<()>;
+}
+
+impl ConsoleStream for TcpStream {
+    fn try_clone(&self) -> io::Result<Self> {
+        TcpStream::try_clone(self)
+    }
+
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        TcpStream::shutdown(self, how)
+    }
+}
+
+impl ConsoleStream for UnixStream {
+    fn try_clone(&self) -> io::Result<Self> {
+        UnixStream::try_clone(self)
+    }
+
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        UnixStream::shutdown(self, how)
+    }
+}
+
+struct Client<S> {
+    id: u64,
+    stream: S,
+}
+
+struct Shared<S> {
+    policy: NoClientPolicy,
+    stopped: AtomicBool,
+    accepted: AtomicU64,
+    refused: AtomicU64,
+    /// Lock order: `client` before `backlog`.
+    client: Mutex<Option<Client<S>>>,
+    backlog: Mutex<VecDeque<u8>>,
+    /// Taken when the bridge is closed.
+    guest_input: Mutex<Option<File>>,
+}
+
+impl<S: ConsoleStream> Shared<S> {
+    fn client(&self) -> MutexGuard<'_, Option<Client<S>>> {
+        self.client.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn backlog(&self) -> MutexGuard<'_, VecDeque<u8>> {
+        self.backlog.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn detach(&self, id: u64) {
+        let mut client = self.client();
+        if client.as_ref().map(|c| c.id) == Some(id) {
+            if let Some(c) = client.take() {
+                let _ = c.stream.shutdown(Shutdown::Both);
+            }
+        }
+    }
+}
+
+/// The guest side of a socket console: pipes to the guest, one client at a time and the
+/// [`NoClientPolicy`]. Shared by [`TcpConsoleBridge`] and the unix socket console.
+pub(crate) struct ConsoleBridge<S> {
+    attachment: VZFileHandleSerialPortAttachment,
+    shared: Arc<Shared<S>>,
+    /// The thread owning the listener; detached if it cannot be woken.
+    acceptor: Mutex<Option<JoinHandle<()>>>,
+    closed: AtomicBool,
+}
+
+impl<S: ConsoleStream> ConsoleBridge<S> {
+    /// Serves clients from `incoming` on threads named after `name`.
+    pub(crate) fn start<I>(name: &str, policy: NoClientPolicy, incoming: I) -> io::Result<Self>
+    where
+        I: Iterator<Item = io::Result<S>> + Send + 'static,
+    {
+        let (guest_input, input) = pipe()?;
+        let (output, guest_output) = pipe()?;
+        let attachment = VZFileHandleSerialPortAttachmentBuilder::new()
+            .file_handle_for_reading(NSFileHandle::init_with_file_descriptor(
+                guest_input.into_raw_fd(),
+                true,
+            ))
+            .file_handle_for_writing(NSFileHandle::init_with_file_descriptor(
+                guest_output.into_raw_fd(),
+                true,
+            ))
+            .build();
+
+        let shared = Arc::new(Shared {
+            policy,
+            stopped: AtomicBool::new(false),
+            accepted: AtomicU64::new(0),
+            refused: AtomicU64::new(0),
+            client: Mutex::new(None),
+            backlog: Mutex::new(VecDeque::new()),
+            guest_input: Mutex::new(Some(input)),
+        });
+
+        let state = shared.clone();
+        thread::Builder::new()
+            .name(format!("{}-output", name))
+            .spawn(move || forward_guest_output(output, &state))?;
+        let state = shared.clone();
+        let input_name = format!("{}-input", name);
+        let acceptor = thread::Builder::new()
+            .name(format!("{}-accept", name))
+            .spawn(move || accept_clients(incoming, &state, input_name))?;
+
+        Ok(ConsoleBridge {
+            attachment,
+            shared,
+            acceptor: Mutex::new(Some(acceptor)),
+            closed: AtomicBool::new(false),
+        })
+    }
+
+    pub(crate) fn attachment(&self) -> VZFileHandleSerialPortAttachment {
+        self.attachment.clone()
+    }
+
+    pub(crate) fn connections_accepted(&self) -> u64 {
+        self.shared.accepted.load(Ordering::SeqCst)
+    }
+
+    pub(crate) fn connections_refused(&self) -> u64 {
+        self.shared.refused.load(Ordering::SeqCst)
+    }
+
+    pub(crate) fn is_client_attached(&self) -> bool {
+        self.shared.client().is_some()
+    }
+
+    /// Stops accepting and disconnects the client. `wake` must unblock the acceptor, e.g. by
+    /// connecting to the listener, and return whether it did; the acceptor then notices the flag
+    /// and exits. Returns `false` if the bridge was already stopped.
+    pub(crate) fn stop<W: FnOnce() -> bool>(&self, wake: W) -> bool {
+        if self.shared.stopped.swap(true, Ordering::SeqCst) {
+            return false;
+        }
+        if !wake() {
+            // Left blocked in `accept`; it cannot be waited for.
+            self.acceptor().take();
+        }
+        if let Some(client) = self.shared.client().take() {
+            let _ = client.stream.shutdown(Shutdown::Both);
+        }
+        true
+    }
+
+    /// After [`stop`](Self::stop): waits for the acceptor to drop the listener, and closes the
+    /// guest's input and, unless something else references the attachment, the guest's ends of
+    /// the pipes. Only the first call does anything.
+    pub(crate) fn close(&self, resource: &'static str) -> Result<(), CloseError> {
+        if self.closed.swap(true, Ordering::SeqCst) {
+            return Ok(());
+        }
+        if let Some(acceptor) = self.acceptor().take() {
+            let _ = acceptor.join();
+        }
+        let input = self
+            .shared
+            .guest_input
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .take();
+        first_error([
+            input.map_or(Ok(()), close_file),
+            self.attachment.close_handles(resource),
+        ])
+    }
+
+    fn acceptor(&self) -> MutexGuard<'_, Option<JoinHandle<()>>> {
+        self.acceptor.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Serial port attachment bridged to a single TCP client on localhost.
+///
+/// The guest keeps running across client disconnects; output produced while nobody is connected
+/// is handled according to the [`NoClientPolicy`]. A second concurrent client is sent a short
+/// message and disconnected. The listener, the client's socket and the pipe ends are owned; see
+/// [`close`](Self::close).
+pub struct TcpConsoleBridge {
+    bridge: ConsoleBridge<TcpStream>,
+    local_addr: SocketAddr,
+}
+
+impl TcpConsoleBridge {
+    /// Listens on `127.0.0.1:port`; port 0 picks a free port, see
+    /// [`TcpConsoleBridge::local_addr`].
+    pub fn bind(port: u16, policy: NoClientPolicy) -> io::Result<TcpConsoleBridge> {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, port))?;
+        let local_addr = listener.local_addr()?;
+        let incoming = std::iter::from_fn(move || Some(listener.accept().map(|(s, _)| s)));
+        Ok(TcpConsoleBridge {
+            bridge: ConsoleBridge::start("tcp-console", policy, incoming)?,
+            local_addr,
+        })
+    }
+
+    /// The attachment to hand to a serial port configuration.
+    pub fn attachment(&self) -> VZFileHandleSerialPortAttachment {
+        self.bridge.attachment()
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Number of clients that were attached so far.
+    pub fn connections_accepted(&self) -> u64 {
+        self.bridge.connections_accepted()
+    }
+
+    /// Number of clients turned away because another one was attached.
+    pub fn connections_refused(&self) -> u64 {
+        self.bridge.connections_refused()
+    }
+
+    pub fn is_client_attached(&self) -> bool {
+        self.bridge.is_client_attached()
+    }
+
+    /// Stops listening and disconnects the client. The guest side stays open, so the guest does
+    /// not see its console go away.
+    pub fn stop(&self) {
+        let local_addr = self.local_addr;
+        self.bridge.stop(|| TcpStream::connect(local_addr).is_ok());
+    }
+
+    /// Stops, waits until the port is free to bind again, and closes the pipes to the guest.
+    /// Dropping does the same, ignoring errors.
+    ///
+    /// Fails with [`CloseError::Attached`] while a configuration references the attachment: the
+    /// guest's ends are then closed when the framework releases it.
+    pub fn close(self) -> Result<(), CloseError> {
+        self.release()
+    }
+
+    fn release(&self) -> Result<(), CloseError> {
+        self.stop();
+        self.bridge.close("TcpConsoleBridge attachment")
+    }
+}
+
+impl Drop for TcpConsoleBridge {
+    fn drop(&mut self) {
+        let _ = self.release();
+    }
+}
+
+fn forward_guest_output<S: ConsoleStream>(mut output: File, shared: &Shared<S>) {
+    let mut chunk = [0u8; 4096];
+    loop {
+        let n = match output.read(&mut chunk) {
+            Ok(0) => return,
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(_) => return,
+        };
+        let bytes = &chunk[..n];
+        let mut client = shared.client();
+        if let Some(c) = client.as_mut() {
+            if c.stream.write_all(bytes).is_ok() {
+                continue;
+            }
+            let _ = c.stream.shutdown(Shutdown::Both);
+            *client = None;
+        }
+        if let NoClientPolicy::Buffer { limit } = shared.policy {
+            let mut backlog = shared.backlog();
+            backlog.extend(bytes);
+            let excess = backlog.len().saturating_sub(limit);
+            backlog.drain(..excess);
+        }
+    }
+}
+
+fn accept_clients<S, I>(incoming: I, shared: &Arc<Shared<S>>, input_name: String)
+where
+    S: ConsoleStream,
+    I: Iterator<Item = io::Result<S>>,
+{
+    for (id, stream) in incoming.enumerate() {
+        if shared.stopped.load(Ordering::SeqCst) {
+            return;
+        }
+        let mut stream = match stream {
+            Ok(stream) => stream,
+            Err(_) => continue,
+        };
+        let mut client = shared.client();
+        if client.is_some() {
+            drop(client);
+            shared.refused.fetch_add(1, Ordering::SeqCst);
+            let _ = stream.write_all(BUSY_MESSAGE);
+            let _ = stream.shutdown(Shutdown::Both);
+            continue;
+        }
+        let reader = match stream.try_clone() {
+            Ok(reader) => reader,
+            Err(_) => continue,
+        };
+        let backlog: Vec<u8> = shared.backlog().drain(..).collect();
+        if stream.write_all(&backlog).is_err() {
+            continue;
+        }
+        let id = id as u64;
+        *client = Some(Client { id, stream });
+        drop(client);
+        shared.accepted.fetch_add(1, Ordering::SeqCst);
+
+        let state = shared.clone();
+        let spawned = thread::Builder::new()
+            .name(input_name.clone())
+            .spawn(move || forward_client_input(reader, id, &state));
+        if spawned.is_err() {
+            shared.detach(id);
+        }
+    }
+}
+
+fn forward_client_input<S: ConsoleStream>(mut reader: S, id: u64, shared: &Shared<S>) {
+    let mut chunk = [0u8; 4096];
+    loop {
+        match reader.read(&mut chunk) {
+            Ok(0) => break,
+            Ok(n) => {
+                let mut input = shared.guest_input.lock().unwrap_or_else(|e| e.into_inner());
+                let written = input.as_mut().map(|input| input.write_all(&chunk[..n]));
+                if !matches!(written, Some(Ok(()))) {
+                    break;
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(_) => break,
+        }
+    }
+    shared.detach(id);
+}
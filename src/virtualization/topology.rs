@@ -0,0 +1,182 @@
+//! topology module
+//!
+//! Keeps the order of storage and network devices stable across runs. The framework puts devices
+//! on the guest's PCI bus in array order, and Linux derives disk and interface names from it, so a
+//! configuration rebuilt with its devices added in another order renames them in the guest.
+//!
+//! Devices added with a key come first, sorted by key; devices added without one follow in the
+//! order they were added. Keys are compared byte-wise, and a key added again replaces the device
+//! it was given before. The resulting order is recorded in a [`TopologyManifest`], which can be
+//! stored and compared with the one of the next run.
+//!
+//! # Examples
+//! ```rust
+//! let (conf, manifest) = VZVirtualMachineConfigurationBuilder::new()
+//!     .storage_device_keyed("system", root_disk)
+//!     .storage_device_keyed("cidata", seed_disk)
+//!     .network_device_keyed("primary", nat)
+//!     .build_with_manifest();
+//! // storage 0 cidata VZVirtioBlockDeviceConfiguration file:///path/to/seed.img
+//! // storage 1 system VZVirtioBlockDeviceConfiguration file:///path/to/root.img
+//! // network 0 primary VZVirtioNetworkDeviceConfiguration 02:00:00:00:00:01
+//! print!("{}", manifest);
+//! ```
+
+use crate::base::{Id, NSString};
+use crate::runtime::{from_objc_bool, retained};
+use crate::virtualization::device::VZDeviceConfiguration;
+
+use std::fmt;
+
+use objc::runtime::BOOL;
+use objc::{msg_send, sel, sel_impl};
+
+/// Devices of one category in the order they go to the framework, with their keys.
+pub(crate) struct KeyedDevices<T> {
+    /// Keyed devices first, sorted; `None` for the rest.
+    keys: Vec<Option<String>>,
+    devices: Vec<T>,
+}
+
+impl<T> KeyedDevices<T> {
+    pub(crate) fn new() -> KeyedDevices<T> {
+        KeyedDevices {
+            keys: Vec::new(),
+            devices: Vec::new(),
+        }
+    }
+
+    /// Replaces all devices, keyed or not, with unkeyed `devices`.
+    pub(crate) fn reset(&mut self, devices: Vec<T>) {
+        self.keys = devices.iter().map(|_| None).collect();
+        self.devices = devices;
+    }
+
+    /// Adds `device` after all others.
+    pub(crate) fn push(&mut self, device: T) {
+        self.keys.push(None);
+        self.devices.push(device);
+    }
+
+    /// Adds `device` at the place of `key` among the keyed devices, or replaces the device of
+    /// `key`.
+    pub(crate) fn insert(&mut self, key: String, device: T) {
+        let keyed = self.keys.iter().take_while(|k| k.is_some()).count();
+        match self.keys[..keyed].binary_search_by(|k| k.as_deref().unwrap().cmp(&key)) {
+            Ok(index) => self.devices[index] = device,
+            Err(index) => {
+                self.keys.insert(index, Some(key));
+                self.devices.insert(index, device);
+            }
+        }
+    }
+
+    pub(crate) fn devices(&self) -> &[T] {
+        &self.devices
+    }
+}
+
+impl<T: VZDeviceConfiguration> KeyedDevices<T> {
+    fn entries(&self) -> Vec<TopologyEntry> {
+        self.keys
+            .iter()
+            .zip(&self.devices)
+            .enumerate()
+            .map(|(index, (key, device))| TopologyEntry {
+                index,
+                key: key.clone(),
+                class: device.class_name().to_string(),
+                identity: unsafe { identity(device.id()) },
+            })
+            .collect()
+    }
+}
+
+/// One device, at its position in the array of its category.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct TopologyEntry {
+    pub index: usize,
+    /// `None` for a device added without a key.
+    pub key: Option<String>,
+    /// Name of the device's framework class.
+    pub class: String,
+    /// The MAC address of a network device, or the URL of a storage device's image, if any.
+    pub identity: Option<String>,
+}
+
+/// The order of the storage and network devices of a configuration; see the
+/// [module documentation](self).
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct TopologyManifest {
+    pub storage: Vec<TopologyEntry>,
+    pub network: Vec<TopologyEntry>,
+}
+
+impl TopologyManifest {
+    pub(crate) fn new<S, N>(storage: &KeyedDevices<S>, network: &KeyedDevices<N>) -> Self
+    where
+        S: VZDeviceConfiguration,
+        N: VZDeviceConfiguration,
+    {
+        TopologyManifest {
+            storage: storage.entries(),
+            network: network.entries(),
+        }
+    }
+}
+
+/// One line per device: category, index, key, class and identity, separated by spaces, with `-`
+/// for a missing key or identity.
+impl fmt::Display for TopologyManifest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let categories = [("storage", &self.storage), ("network", &self.network)];
+        for (category, entries) in categories.iter() {
+            for entry in entries.iter() {
+                writeln!(
+                    f,
+                    "{} {} {} {} {}",
+                    category,
+                    entry.index,
+                    entry.key.as_deref().unwrap_or("-"),
+                    entry.class,
+                    entry.identity.as_deref().unwrap_or("-")
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// # Safety
+/// `device` must be a valid device configuration.
+unsafe fn identity(device: Id) -> Option<String> {
+    let has_mac: BOOL = msg_send![device, respondsToSelector: sel!(MACAddress)];
+    if from_objc_bool(has_mac) {
+        let mac: Id = msg_send![device, MACAddress];
+        if mac.is_null() {
+            return None;
+        }
+        let string: Id = msg_send![mac, string];
+        return Some(NSString(retained(string)).as_str().to_string());
+    }
+    let has_attachment: BOOL = msg_send![device, respondsToSelector: sel!(attachment)];
+    if !from_objc_bool(has_attachment) {
+        return None;
+    }
+    let attachment: Id = msg_send![device, attachment];
+    if attachment.is_null() {
+        return None;
+    }
+    let has_url: BOOL = msg_send![attachment, respondsToSelector: sel!(URL)];
+    if !from_objc_bool(has_url) {
+        return None;
+    }
+    let url: Id = msg_send![attachment, URL];
+    if url.is_null() {
+        return None;
+    }
+    let string: Id = msg_send![url, absoluteString];
+    Some(NSString(retained(string)).as_str().to_string())
+}
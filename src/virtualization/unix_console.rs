@@ -0,0 +1,165 @@
+//! unix console module
+//!
+//! Exposes a guest serial console on a unix domain socket, e.g. for
+//! `socat - UNIX-CONNECT:/run/vms/web.sock`.
+//!
+//! # Examples
+//! ```rust
+//! let console = UnixSocketConsole::bind("/run/vms/web.sock", NoClientPolicy::Buffer { limit: 64 * 1024 })?;
+//! let serial = VZVirtioConsoleDeviceSerialPortConfiguration::new(console.attachment());
+//! ```
+
+use crate::resource::CloseError;
+use crate::virtualization::serial_port::VZFileHandleSerialPortAttachment;
+use crate::virtualization::tcp_console::{ConsoleBridge, NoClientPolicy};
+
+use std::fs;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{FileTypeExt, MetadataExt, PermissionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+
+/// Longest socket path `bind` accepts: `sun_path` is 104 bytes on macOS, including the
+/// terminating NUL.
+pub const MAX_SOCKET_PATH_LEN: usize = 103;
+
+/// Permissions of the socket file unless given: only the owner may connect.
+pub const DEFAULT_SOCKET_MODE: u32 = 0o600;
+
+/// Serial port attachment bridged to a single client of a unix domain socket.
+///
+/// Clients are served as by [`TcpConsoleBridge`](crate::virtualization::tcp_console::TcpConsoleBridge):
+/// one at a time, and guest output produced while nobody is connected is handled according to
+/// the [`NoClientPolicy`]. The listener, the client's socket and the pipe ends are owned, and the
+/// socket file is removed when this is closed or dropped; see [`close`](Self::close).
+pub struct UnixSocketConsole {
+    bridge: ConsoleBridge<UnixStream>,
+    path: PathBuf,
+    /// Device and inode of the socket file, so a file that replaced it is not removed.
+    file_id: (u64, u64),
+}
+
+impl UnixSocketConsole {
+    /// Listens on `path` with [`DEFAULT_SOCKET_MODE`].
+    pub fn bind<P: AsRef<Path>>(path: P, policy: NoClientPolicy) -> io::Result<UnixSocketConsole> {
+        UnixSocketConsole::bind_with_mode(path, policy, DEFAULT_SOCKET_MODE)
+    }
+
+    /// Listens on `path`, whose permissions are set to `mode` before any client is served.
+    ///
+    /// A socket file left behind by a process that is gone is replaced. Fails with
+    /// `ErrorKind::AddrInUse` if something still accepts connections on `path`, and with
+    /// `ErrorKind::InvalidInput` if `path` is longer than [`MAX_SOCKET_PATH_LEN`] bytes.
+    pub fn bind_with_mode<P: AsRef<Path>>(
+        path: P,
+        policy: NoClientPolicy,
+        mode: u32,
+    ) -> io::Result<UnixSocketConsole> {
+        let path = path.as_ref().to_path_buf();
+        let len = path.as_os_str().as_bytes().len();
+        if len > MAX_SOCKET_PATH_LEN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "socket path {} is {} bytes, the limit is {}",
+                    path.display(),
+                    len,
+                    MAX_SOCKET_PATH_LEN
+                ),
+            ));
+        }
+        remove_stale_socket(&path)?;
+
+        let listener = UnixListener::bind(&path)?;
+        fs::set_permissions(&path, fs::Permissions::from_mode(mode))?;
+        let metadata = fs::symlink_metadata(&path)?;
+        let incoming = std::iter::from_fn(move || Some(listener.accept().map(|(s, _)| s)));
+        Ok(UnixSocketConsole {
+            bridge: ConsoleBridge::start("unix-console", policy, incoming)?,
+            path,
+            file_id: (metadata.dev(), metadata.ino()),
+        })
+    }
+
+    /// The attachment to hand to a serial port configuration.
+    pub fn attachment(&self) -> VZFileHandleSerialPortAttachment {
+        self.bridge.attachment()
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Number of clients that were attached so far.
+    pub fn connections_accepted(&self) -> u64 {
+        self.bridge.connections_accepted()
+    }
+
+    /// Number of clients turned away because another one was attached.
+    pub fn connections_refused(&self) -> u64 {
+        self.bridge.connections_refused()
+    }
+
+    pub fn is_client_attached(&self) -> bool {
+        self.bridge.is_client_attached()
+    }
+
+    /// Stops listening, disconnects the client and removes the socket file. The guest side stays
+    /// open, so the guest does not see its console go away.
+    pub fn stop(&self) {
+        let path = &self.path;
+        let stopped = self.bridge.stop(|| UnixStream::connect(path).is_ok());
+        if !stopped {
+            return;
+        }
+        if let Ok(metadata) = fs::symlink_metadata(path) {
+            if (metadata.dev(), metadata.ino()) == self.file_id {
+                let _ = fs::remove_file(path);
+            }
+        }
+    }
+
+    /// Stops, removing the socket file, waits until the path is free to bind again, and closes
+    /// the pipes to the guest. Dropping does the same, ignoring errors.
+    ///
+    /// Fails with [`CloseError::Attached`] while a configuration references the attachment: the
+    /// guest's ends are then closed when the framework releases it.
+    pub fn close(self) -> Result<(), CloseError> {
+        self.release()
+    }
+
+    fn release(&self) -> Result<(), CloseError> {
+        self.stop();
+        self.bridge.close("UnixSocketConsole attachment")
+    }
+}
+
+impl Drop for UnixSocketConsole {
+    fn drop(&mut self) {
+        let _ = self.release();
+    }
+}
+
+/// Removes `path` if it is a socket nobody accepts connections on.
+fn remove_stale_socket(path: &Path) -> io::Result<()> {
+    let metadata = match fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    if !metadata.file_type().is_socket() {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("{} exists and is not a socket", path.display()),
+        ));
+    }
+    match UnixStream::connect(path) {
+        Ok(_) => Err(io::Error::new(
+            io::ErrorKind::AddrInUse,
+            format!("a process is listening on {}", path.display()),
+        )),
+        Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => fs::remove_file(path),
+        Err(e) => Err(e),
+    }
+}
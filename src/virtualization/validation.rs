@@ -0,0 +1,208 @@
+//! validation module
+//!
+//! Finds the device a configuration fails validation on. `validateWithError:` judges the whole
+//! configuration, and its error rarely names a device, so
+//! [`validated_build_verbose`](crate::virtualization::virtual_machine::VZVirtualMachineConfigurationBuilder::validated_build_verbose)
+//! revalidates a copy without devices, then adds them back one at a time, category by category
+//! in the order [`describe`](crate::virtualization::virtual_machine::VZVirtualMachineConfiguration::describe)
+//! lists them. The first device whose addition makes it fail is the [`Culprit`].
+//!
+//! That costs one validation per device, so it only runs after the configuration as built
+//! failed a single validation.
+//!
+//! # Examples
+//! ```rust
+//! match builder.validated_build_verbose() {
+//!     Ok(conf) => { /* ... */ }
+//!     // serial_ports[1] (VZVirtioConsoleDeviceSerialPortConfiguration) fails validation, after
+//!     // 6 validations: ...
+//!     Err(e) => eprintln!("{}", e),
+//! }
+//! ```
+
+use crate::limits::DeviceLimitExceeded;
+use crate::virtualization::error::{AlignmentError, BuildError, VZErrorCtx};
+
+use std::fmt;
+
+/// What a configuration was found to fail validation on.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Culprit {
+    /// The device at `index` of the `category` array, e.g. `storage_devices`: the first whose
+    /// addition made a valid configuration fail.
+    Device {
+        category: &'static str,
+        index: usize,
+        /// The framework class of the device.
+        class: String,
+    },
+    /// The configuration fails without devices and with the first of each, so the boot loader,
+    /// platform, CPU count or memory size is to blame. So is a broken first device of a category
+    /// the configuration cannot do without.
+    Base,
+    /// A copy validated with every device; the failure did not reproduce.
+    NotReproduced,
+}
+
+impl fmt::Display for Culprit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Culprit::Device {
+                category,
+                index,
+                class,
+            } => write!(f, "{}[{}] ({})", category, index, class),
+            Culprit::Base => write!(f, "the configuration without devices"),
+            Culprit::NotReproduced => write!(f, "the configuration"),
+        }
+    }
+}
+
+/// A configuration that failed validation, with what it failed on.
+#[derive(Debug, Clone)]
+pub struct ValidationFailure {
+    pub culprit: Culprit,
+    /// The framework's error when the culprit was added, otherwise the error of the
+    /// configuration as built.
+    pub error: VZErrorCtx,
+    /// Validations run to find the culprit, the first one included.
+    pub validations: usize,
+}
+
+impl fmt::Display for ValidationFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} fails validation, after {} validations: {}",
+            self.culprit, self.validations, self.error
+        )
+    }
+}
+
+impl std::error::Error for ValidationFailure {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.error)
+    }
+}
+
+/// Why [`validated_build_verbose`] failed.
+///
+/// [`validated_build_verbose`]: crate::virtualization::virtual_machine::VZVirtualMachineConfigurationBuilder::validated_build_verbose
+#[derive(Debug, Clone)]
+pub enum VerboseBuildError {
+    /// As with `validated_build`.
+    Misaligned(AlignmentError),
+    /// As with `validated_build`.
+    TooManyDevices(DeviceLimitExceeded),
+    Invalid(ValidationFailure),
+}
+
+impl fmt::Display for VerboseBuildError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VerboseBuildError::Misaligned(e) => e.fmt(f),
+            VerboseBuildError::TooManyDevices(e) => e.fmt(f),
+            VerboseBuildError::Invalid(e) => e.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for VerboseBuildError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            VerboseBuildError::Misaligned(_) | VerboseBuildError::TooManyDevices(_) => None,
+            VerboseBuildError::Invalid(e) => e.source(),
+        }
+    }
+}
+
+impl From<AlignmentError> for VerboseBuildError {
+    fn from(error: AlignmentError) -> Self {
+        VerboseBuildError::Misaligned(error)
+    }
+}
+
+impl From<BuildError> for VerboseBuildError {
+    fn from(error: BuildError) -> Self {
+        match error {
+            BuildError::Misaligned(e) => VerboseBuildError::Misaligned(e),
+            BuildError::TooManyDevices(e) => VerboseBuildError::TooManyDevices(e),
+        }
+    }
+}
+
+impl From<ValidationFailure> for VerboseBuildError {
+    fn from(error: ValidationFailure) -> Self {
+        VerboseBuildError::Invalid(error)
+    }
+}
+
+/// Where [`locate`] found a failure, by position.
+#[doc(hidden)]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Located<E> {
+    Device {
+        category: usize,
+        index: usize,
+        error: E,
+    },
+    /// With the error of the configuration without devices.
+    Base(E),
+    NotReproduced,
+}
+
+/// The search behind [`validated_build_verbose`], apart from the framework: `counts` holds the
+/// number of devices of each category, and `validate` validates the configuration with the
+/// first `lengths[c]` devices of each category `c`. Returns where it failed and how many times
+/// `validate` ran.
+///
+/// Without devices at all the configuration may fail because a category must not be empty, so
+/// then the first device of each category is tried alone, and then those of all categories
+/// together, before the base is blamed. The walk starts from the first of those that validates.
+///
+/// Public for the integration tests.
+///
+/// [`validated_build_verbose`]: crate::virtualization::virtual_machine::VZVirtualMachineConfigurationBuilder::validated_build_verbose
+#[doc(hidden)]
+pub fn locate<E, F>(counts: &[usize], mut validate: F) -> (Located<E>, usize)
+where
+    F: FnMut(&[usize]) -> Result<(), E>,
+{
+    let mut validations = 0;
+    let mut check = |lengths: &[usize]| {
+        validations += 1;
+        validate(lengths)
+    };
+    let mut lengths = vec![0; counts.len()];
+    if let Err(error) = check(&lengths) {
+        let mut seeds: Vec<Vec<usize>> = (0..counts.len())
+            .filter(|&c| counts[c] > 0)
+            .map(|c| {
+                let mut seed = vec![0; counts.len()];
+                seed[c] = 1;
+                seed
+            })
+            .collect();
+        if seeds.len() > 1 {
+            seeds.push(counts.iter().map(|&n| n.min(1)).collect());
+        }
+        match seeds.into_iter().find(|seed| check(seed).is_ok()) {
+            Some(seed) => lengths = seed,
+            None => return (Located::Base(error), validations),
+        }
+    }
+    for category in 0..counts.len() {
+        while lengths[category] < counts[category] {
+            lengths[category] += 1;
+            if let Err(error) = check(&lengths) {
+                let located = Located::Device {
+                    category,
+                    index: lengths[category] - 1,
+                    error,
+                };
+                return (located, validations);
+            }
+        }
+    }
+    (Located::NotReproduced, validations)
+}
@@ -0,0 +1,812 @@
+//! virtual machine view module
+//!
+//! Only compiled with the `gui` feature; this is the only module that links AppKit.
+//!
+//! The view is where keyboard and pointer input reaches the guest, so injected input takes the
+//! same path as a user's: AppKit events sent to the view's responder methods (`keyDown:`,
+//! `mouseDown:`, `mouseMoved:`, ...) on the main thread. The events are built with `NSEvent`'s
+//! constructors, and go through `CGEvent` only to set the middle button's number, which those
+//! constructors cannot. The guest reads key codes, not characters, so what a key types depends on
+//! the guest's keyboard layout; [`keystroke_for`] assumes US.
+//!
+//! Programs without an AppKit setup of their own can show the view in a
+//! [`VZVirtualMachineWindow`] and hand the main thread to [`run_application`]. The window reports
+//! its size in display pixels, see [`VZVirtualMachineWindow::on_resize`], for guests whose display
+//! the view does not resize itself.
+//!
+//! # Examples
+//! ```rust
+//! let view = VZVirtualMachineView::new();
+//! view.set_virtual_machine(&vm);
+//! view.type_text("uname -a\n", Duration::from_millis(20))?;
+//! view.inject_mouse_event(100.0, 100.0, MouseButtons::LEFT)?;
+//! view.inject_mouse_event(100.0, 100.0, MouseButtons::NONE)?;
+//! ```
+
+use crate::base::{DispatchQueue, Id, NSInteger, NSString, NSUInteger, NIL};
+use crate::runtime::{
+    alloc, from_objc_bool, owned, retained, to_objc_bool, vz_class, MainQueuePump,
+};
+use crate::virtualization::graphics_device::VirtioDisplaySizing;
+use crate::virtualization::virtual_machine::VZVirtualMachine;
+
+use std::cell::{Cell, RefCell};
+use std::error::Error;
+use std::fmt;
+use std::os::raw::c_void;
+use std::rc::Rc;
+use std::sync::Once;
+use std::thread;
+use std::time::Duration;
+
+use block::ConcreteBlock;
+use objc::declare::ClassDecl;
+use objc::rc::StrongPtr;
+use objc::runtime::{Class, Object, Sel, BOOL, NO, YES};
+use objc::{class, msg_send, sel, sel_impl};
+
+#[cfg_attr(target_vendor = "apple", link(name = "AppKit", kind = "framework"))]
+extern "C" {}
+
+#[cfg_attr(
+    target_vendor = "apple",
+    link(name = "CoreGraphics", kind = "framework")
+)]
+extern "C" {
+    fn CGEventCreateCopy(event: *const c_void) -> *mut c_void;
+    fn CGEventSetIntegerValueField(event: *mut c_void, field: u32, value: i64);
+    fn CFRelease(cf: *const c_void);
+}
+
+// `NSEventType` raw values, checked against the macOS 14 SDK headers.
+const EVENT_LEFT_MOUSE_DOWN: NSUInteger = 1;
+const EVENT_LEFT_MOUSE_UP: NSUInteger = 2;
+const EVENT_RIGHT_MOUSE_DOWN: NSUInteger = 3;
+const EVENT_RIGHT_MOUSE_UP: NSUInteger = 4;
+const EVENT_MOUSE_MOVED: NSUInteger = 5;
+const EVENT_LEFT_MOUSE_DRAGGED: NSUInteger = 6;
+const EVENT_RIGHT_MOUSE_DRAGGED: NSUInteger = 7;
+const EVENT_KEY_DOWN: NSUInteger = 10;
+const EVENT_KEY_UP: NSUInteger = 11;
+const EVENT_FLAGS_CHANGED: NSUInteger = 12;
+const EVENT_OTHER_MOUSE_DOWN: NSUInteger = 25;
+const EVENT_OTHER_MOUSE_UP: NSUInteger = 26;
+const EVENT_OTHER_MOUSE_DRAGGED: NSUInteger = 27;
+
+// `NSEventModifierFlags`.
+const MODIFIER_CAPS_LOCK: NSUInteger = 1 << 16;
+const MODIFIER_SHIFT: NSUInteger = 1 << 17;
+const MODIFIER_CONTROL: NSUInteger = 1 << 18;
+const MODIFIER_OPTION: NSUInteger = 1 << 19;
+const MODIFIER_COMMAND: NSUInteger = 1 << 20;
+const MODIFIER_FUNCTION: NSUInteger = 1 << 23;
+
+/// `kCGMouseEventButtonNumber`.
+const CG_MOUSE_EVENT_BUTTON_NUMBER: u32 = 3;
+
+/// Virtual key codes (`kVK_*` in `HIToolbox/Events.h`) of keys that type no character. Keys
+/// that do are found with [`keystroke_for`].
+pub mod keycode {
+    pub const RETURN: u16 = 0x24;
+    pub const TAB: u16 = 0x30;
+    pub const SPACE: u16 = 0x31;
+    pub const DELETE: u16 = 0x33;
+    pub const ESCAPE: u16 = 0x35;
+    pub const RIGHT_COMMAND: u16 = 0x36;
+    pub const COMMAND: u16 = 0x37;
+    pub const SHIFT: u16 = 0x38;
+    pub const CAPS_LOCK: u16 = 0x39;
+    pub const OPTION: u16 = 0x3A;
+    pub const CONTROL: u16 = 0x3B;
+    pub const RIGHT_SHIFT: u16 = 0x3C;
+    pub const RIGHT_OPTION: u16 = 0x3D;
+    pub const RIGHT_CONTROL: u16 = 0x3E;
+    pub const FUNCTION: u16 = 0x3F;
+    pub const LEFT_ARROW: u16 = 0x7B;
+    pub const RIGHT_ARROW: u16 = 0x7C;
+    pub const DOWN_ARROW: u16 = 0x7D;
+    pub const UP_ARROW: u16 = 0x7E;
+}
+
+/// The ANSI keys of the US layout: key code, character typed alone, character typed with Shift.
+/// Return, Tab and Space type the same character either way.
+const US_LAYOUT: &[(u16, char, char)] = &[
+    (0x00, 'a', 'A'),
+    (0x01, 's', 'S'),
+    (0x02, 'd', 'D'),
+    (0x03, 'f', 'F'),
+    (0x04, 'h', 'H'),
+    (0x05, 'g', 'G'),
+    (0x06, 'z', 'Z'),
+    (0x07, 'x', 'X'),
+    (0x08, 'c', 'C'),
+    (0x09, 'v', 'V'),
+    (0x0B, 'b', 'B'),
+    (0x0C, 'q', 'Q'),
+    (0x0D, 'w', 'W'),
+    (0x0E, 'e', 'E'),
+    (0x0F, 'r', 'R'),
+    (0x10, 'y', 'Y'),
+    (0x11, 't', 'T'),
+    (0x12, '1', '!'),
+    (0x13, '2', '@'),
+    (0x14, '3', '#'),
+    (0x15, '4', '$'),
+    (0x16, '6', '^'),
+    (0x17, '5', '%'),
+    (0x18, '=', '+'),
+    (0x19, '9', '('),
+    (0x1A, '7', '&'),
+    (0x1B, '-', '_'),
+    (0x1C, '8', '*'),
+    (0x1D, '0', ')'),
+    (0x1E, ']', '}'),
+    (0x1F, 'o', 'O'),
+    (0x20, 'u', 'U'),
+    (0x21, '[', '{'),
+    (0x22, 'i', 'I'),
+    (0x23, 'p', 'P'),
+    (0x25, 'l', 'L'),
+    (0x26, 'j', 'J'),
+    (0x27, '\'', '"'),
+    (0x28, 'k', 'K'),
+    (0x29, ';', ':'),
+    (0x2A, '\\', '|'),
+    (0x2B, ',', '<'),
+    (0x2C, '/', '?'),
+    (0x2D, 'n', 'N'),
+    (0x2E, 'm', 'M'),
+    (0x2F, '.', '>'),
+    (0x32, '`', '~'),
+    (keycode::RETURN, '\n', '\n'),
+    (keycode::TAB, '\t', '\t'),
+    (keycode::SPACE, ' ', ' '),
+];
+
+/// A key press that types a character.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyStroke {
+    pub keycode: u16,
+    /// Whether Shift is held down for it.
+    pub shift: bool,
+}
+
+/// The key that types `c` on a US keyboard layout, or `None` for characters it has no key for,
+/// which is everything outside printable ASCII, newline and tab.
+pub fn keystroke_for(c: char) -> Option<KeyStroke> {
+    US_LAYOUT.iter().find_map(|&(keycode, plain, shifted)| {
+        if c == plain {
+            Some(KeyStroke {
+                keycode,
+                shift: false,
+            })
+        } else if c == shifted {
+            Some(KeyStroke {
+                keycode,
+                shift: true,
+            })
+        } else {
+            None
+        }
+    })
+}
+
+/// The character a key types, sent along with its key code for the benefit of AppKit; the guest
+/// only reads the key code.
+fn character_for(keycode: u16, shift: bool) -> Option<char> {
+    US_LAYOUT
+        .iter()
+        .find(|&&(code, _, _)| code == keycode)
+        .map(|&(_, plain, shifted)| if shift { shifted } else { plain })
+}
+
+/// The modifier flag a modifier key sets, or `None` for other keys.
+fn modifier_flag(keycode: u16) -> Option<NSUInteger> {
+    match keycode {
+        keycode::COMMAND | keycode::RIGHT_COMMAND => Some(MODIFIER_COMMAND),
+        keycode::SHIFT | keycode::RIGHT_SHIFT => Some(MODIFIER_SHIFT),
+        keycode::OPTION | keycode::RIGHT_OPTION => Some(MODIFIER_OPTION),
+        keycode::CONTROL | keycode::RIGHT_CONTROL => Some(MODIFIER_CONTROL),
+        keycode::CAPS_LOCK => Some(MODIFIER_CAPS_LOCK),
+        keycode::FUNCTION => Some(MODIFIER_FUNCTION),
+        _ => None,
+    }
+}
+
+/// Mouse buttons held down during a pointer event.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MouseButtons {
+    pub left: bool,
+    pub right: bool,
+    pub middle: bool,
+}
+
+impl MouseButtons {
+    pub const NONE: MouseButtons = MouseButtons {
+        left: false,
+        right: false,
+        middle: false,
+    };
+    pub const LEFT: MouseButtons = MouseButtons {
+        left: true,
+        ..MouseButtons::NONE
+    };
+    pub const RIGHT: MouseButtons = MouseButtons {
+        right: true,
+        ..MouseButtons::NONE
+    };
+    pub const MIDDLE: MouseButtons = MouseButtons {
+        middle: true,
+        ..MouseButtons::NONE
+    };
+}
+
+/// An event type with the responder method that handles it.
+type EventKind = (NSUInteger, &'static str);
+
+const MOUSE_MOVED: EventKind = (EVENT_MOUSE_MOVED, "mouseMoved:");
+
+/// The events of one mouse button.
+struct Button {
+    held: fn(MouseButtons) -> bool,
+    down: EventKind,
+    up: EventKind,
+    dragged: EventKind,
+    /// `buttonNumber`; AppKit only sets 0 and 1 itself.
+    number: i64,
+}
+
+const BUTTONS: [Button; 3] = [
+    Button {
+        held: |b| b.left,
+        down: (EVENT_LEFT_MOUSE_DOWN, "mouseDown:"),
+        up: (EVENT_LEFT_MOUSE_UP, "mouseUp:"),
+        dragged: (EVENT_LEFT_MOUSE_DRAGGED, "mouseDragged:"),
+        number: 0,
+    },
+    Button {
+        held: |b| b.right,
+        down: (EVENT_RIGHT_MOUSE_DOWN, "rightMouseDown:"),
+        up: (EVENT_RIGHT_MOUSE_UP, "rightMouseUp:"),
+        dragged: (EVENT_RIGHT_MOUSE_DRAGGED, "rightMouseDragged:"),
+        number: 1,
+    },
+    Button {
+        held: |b| b.middle,
+        down: (EVENT_OTHER_MOUSE_DOWN, "otherMouseDown:"),
+        up: (EVENT_OTHER_MOUSE_UP, "otherMouseUp:"),
+        dragged: (EVENT_OTHER_MOUSE_DRAGGED, "otherMouseDragged:"),
+        number: 2,
+    },
+];
+
+/// `NSPoint`, passed and returned by value.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+struct NSPoint {
+    x: f64,
+    y: f64,
+}
+
+/// `NSSize`, passed by value inside [`NSRect`].
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+struct NSSize {
+    width: f64,
+    height: f64,
+}
+
+/// `NSRect`, passed by value.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+struct NSRect {
+    origin: NSPoint,
+    size: NSSize,
+}
+
+// `NSWindowStyleMask`, `NSBackingStoreType` and `NSApplicationActivationPolicy` raw values,
+// checked against the macOS 14 SDK headers.
+const WINDOW_STYLE_TITLED: NSUInteger = 1;
+const WINDOW_STYLE_CLOSABLE: NSUInteger = 1 << 1;
+const WINDOW_STYLE_MINIATURIZABLE: NSUInteger = 1 << 2;
+const WINDOW_STYLE_RESIZABLE: NSUInteger = 1 << 3;
+const BACKING_STORE_BUFFERED: NSUInteger = 2;
+const ACTIVATION_POLICY_REGULAR: NSInteger = 0;
+
+/// The window notifications after which the display pixels of a window may differ: its content
+/// was resized, or it moved to a screen of another backing scale.
+const RESIZE_NOTIFICATIONS: &[&str] = &[
+    "NSWindowDidResizeNotification",
+    "NSWindowDidChangeBackingPropertiesNotification",
+];
+
+const APP_DELEGATE_CLASS: &str = "VirtualizationRsApplicationDelegate";
+
+/// Error returned when injecting input into a view.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InjectError {
+    /// The view has no virtual machine to send the input to.
+    NoVirtualMachine,
+    /// [`VZVirtualMachineView::type_text`] found a character [`keystroke_for`] has no key for.
+    /// Nothing was typed.
+    UnmappedCharacter(char),
+    /// AppKit returned nil instead of the event.
+    EventCreationFailed,
+}
+
+impl fmt::Display for InjectError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InjectError::NoVirtualMachine => {
+                write!(f, "the view has no virtual machine to send input to")
+            }
+            InjectError::UnmappedCharacter(c) => {
+                write!(f, "{:?} has no key on the US keyboard layout", c)
+            }
+            InjectError::EventCreationFailed => write!(f, "AppKit did not create the input event"),
+        }
+    }
+}
+
+impl Error for InjectError {}
+
+/// A view that presents the display of a virtual machine and forwards keyboard and mouse input to
+/// it. Must be created and used on the main thread.
+pub struct VZVirtualMachineView {
+    p: StrongPtr,
+    /// Modifier flags held down by injected key events, sent along with every injected event.
+    modifiers: Cell<NSUInteger>,
+    /// Buttons held down by the last injected pointer event.
+    buttons: Cell<MouseButtons>,
+}
+
+impl VZVirtualMachineView {
+    pub fn new() -> VZVirtualMachineView {
+        unsafe {
+            let i = alloc(vz_class!(VZVirtualMachineView));
+            VZVirtualMachineView {
+                p: owned(msg_send![i, init]),
+                modifiers: Cell::new(0),
+                buttons: Cell::new(MouseButtons::NONE),
+            }
+        }
+    }
+
+    pub fn set_virtual_machine(&self, vm: &VZVirtualMachine) {
+        unsafe {
+            let _: () = msg_send![*self.p, setVirtualMachine: vm.id()];
+        }
+    }
+
+    /// Whether system hot keys (e.g. Command-Tab) are sent to the guest instead of the host.
+    pub fn set_captures_system_keys(&self, captures_system_keys: bool) {
+        unsafe {
+            let _: () =
+                msg_send![*self.p, setCapturesSystemKeys: to_objc_bool(captures_system_keys)];
+        }
+    }
+
+    pub fn captures_system_keys(&self) -> bool {
+        let b: BOOL = unsafe { msg_send![*self.p, capturesSystemKeys] };
+        from_objc_bool(b)
+    }
+
+    /// Whether the guest's display is resized along with the view, macOS 14 and later. Returns
+    /// whether the framework has the setting; where it does not, nothing changes.
+    pub fn set_automatically_reconfigures_display(&self, enabled: bool) -> bool {
+        unsafe {
+            let selector = sel!(setAutomaticallyReconfiguresDisplay:);
+            let supported: BOOL = msg_send![*self.p, respondsToSelector: selector];
+            if !from_objc_bool(supported) {
+                return false;
+            }
+            let _: () =
+                msg_send![*self.p, setAutomaticallyReconfiguresDisplay: to_objc_bool(enabled)];
+        }
+        true
+    }
+
+    /// Presses (`down`) or releases a key, given its virtual key code: see [`keycode`] and
+    /// [`keystroke_for`].
+    ///
+    /// Modifier keys are sent as `flagsChanged:` and stay held for later events until released;
+    /// Caps Lock toggles on each press. Off the main thread, the event is delivered through the
+    /// main queue and the call waits for it, so the main queue must be serviced meanwhile.
+    pub fn inject_key_event(&self, keycode: u16, down: bool) -> Result<(), InjectError> {
+        on_main(|| unsafe {
+            self.check_attached()?;
+            let held = self.modifiers.get();
+            let (kind, characters) = match modifier_flag(keycode) {
+                Some(flag) => {
+                    let held = match (flag, down) {
+                        (MODIFIER_CAPS_LOCK, true) => held ^ flag,
+                        (MODIFIER_CAPS_LOCK, false) => held,
+                        (_, true) => held | flag,
+                        (_, false) => held & !flag,
+                    };
+                    self.modifiers.set(held);
+                    ((EVENT_FLAGS_CHANGED, "flagsChanged:"), String::new())
+                }
+                None => {
+                    let shift = held & MODIFIER_SHIFT != 0;
+                    let characters = character_for(keycode, shift)
+                        .map(String::from)
+                        .unwrap_or_default();
+                    let kind = if down {
+                        (EVENT_KEY_DOWN, "keyDown:")
+                    } else {
+                        (EVENT_KEY_UP, "keyUp:")
+                    };
+                    (kind, characters)
+                }
+            };
+            let event = self.key_event(kind.0, keycode, &characters)?;
+            self.send(kind.1, &event);
+            Ok(())
+        })
+    }
+
+    /// Moves the pointer to (`x`, `y`) in the view's coordinates, in points, with `buttons` held
+    /// down from then on.
+    ///
+    /// Sends a move, or a drag while buttons were held, followed by a press or release for each
+    /// button that changed since the last call. Delivered like
+    /// [`inject_key_event`](VZVirtualMachineView::inject_key_event).
+    pub fn inject_mouse_event(
+        &self,
+        x: f64,
+        y: f64,
+        buttons: MouseButtons,
+    ) -> Result<(), InjectError> {
+        on_main(|| unsafe {
+            self.check_attached()?;
+            let location: NSPoint = msg_send![*self.p, convertPoint: NSPoint { x, y } toView: NIL];
+            let held = self.buttons.get();
+            match BUTTONS.iter().find(|b| (b.held)(held)) {
+                Some(button) => self.send_mouse(button.dragged, location, button.number, 0, 1.0)?,
+                None => self.send_mouse(MOUSE_MOVED, location, 0, 0, 0.0)?,
+            }
+            for button in &BUTTONS {
+                match ((button.held)(held), (button.held)(buttons)) {
+                    (false, true) => {
+                        self.send_mouse(button.down, location, button.number, 1, 1.0)?
+                    }
+                    (true, false) => self.send_mouse(button.up, location, button.number, 1, 0.0)?,
+                    _ => {}
+                }
+            }
+            self.buttons.set(buttons);
+            Ok(())
+        })
+    }
+
+    /// Types `text` key by key, holding Shift where the US layout needs it, waiting
+    /// `inter_key_delay` between keys.
+    ///
+    /// Only printable ASCII, newline and tab can be typed, and the guest must use a US layout to
+    /// see the same characters; other characters fail with
+    /// [`InjectError::UnmappedCharacter`] before any key is sent. Modifiers held with
+    /// [`inject_key_event`](VZVirtualMachineView::inject_key_event) apply to the typed keys too.
+    /// On the main thread the main queue is serviced while waiting, so the display keeps
+    /// updating.
+    pub fn type_text(&self, text: &str, inter_key_delay: Duration) -> Result<(), InjectError> {
+        let strokes = text
+            .chars()
+            .map(|c| keystroke_for(c).ok_or(InjectError::UnmappedCharacter(c)))
+            .collect::<Result<Vec<_>, _>>()?;
+        for (i, stroke) in strokes.iter().enumerate() {
+            if i > 0 {
+                pause(inter_key_delay);
+            }
+            if stroke.shift {
+                self.inject_key_event(keycode::SHIFT, true)?;
+            }
+            self.inject_key_event(stroke.keycode, true)?;
+            self.inject_key_event(stroke.keycode, false)?;
+            if stroke.shift {
+                self.inject_key_event(keycode::SHIFT, false)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn id(&self) -> Id {
+        *self.p
+    }
+
+    unsafe fn check_attached(&self) -> Result<(), InjectError> {
+        let vm: Id = msg_send![*self.p, virtualMachine];
+        if vm == NIL {
+            return Err(InjectError::NoVirtualMachine);
+        }
+        Ok(())
+    }
+
+    unsafe fn key_event(
+        &self,
+        kind: NSUInteger,
+        keycode: u16,
+        characters: &str,
+    ) -> Result<StrongPtr, InjectError> {
+        let characters = NSString::new(characters);
+        let event: Id = msg_send![
+            class!(NSEvent),
+            keyEventWithType: kind
+            location: NSPoint::default()
+            modifierFlags: self.modifiers.get()
+            timestamp: uptime()
+            windowNumber: self.window_number()
+            context: NIL
+            characters: *characters.0
+            charactersIgnoringModifiers: *characters.0
+            isARepeat: NO
+            keyCode: keycode
+        ];
+        if event == NIL {
+            return Err(InjectError::EventCreationFailed);
+        }
+        Ok(retained(event))
+    }
+
+    /// Sends a mouse event of `kind` for the button numbered `number`. Presses and releases
+    /// count one click, moves and drags none; only releases and moves have no pressure.
+    unsafe fn send_mouse(
+        &self,
+        kind: EventKind,
+        location: NSPoint,
+        number: i64,
+        clicks: isize,
+        pressure: f32,
+    ) -> Result<(), InjectError> {
+        let event: Id = msg_send![
+            class!(NSEvent),
+            mouseEventWithType: kind.0
+            location: location
+            modifierFlags: self.modifiers.get()
+            timestamp: uptime()
+            windowNumber: self.window_number()
+            context: NIL
+            eventNumber: 0isize
+            clickCount: clicks
+            pressure: pressure
+        ];
+        if event == NIL {
+            return Err(InjectError::EventCreationFailed);
+        }
+        let event = if number > 1 {
+            with_button_number(event, number)?
+        } else {
+            retained(event)
+        };
+        self.send(kind.1, &event);
+        Ok(())
+    }
+
+    unsafe fn window_number(&self) -> isize {
+        let window: Id = msg_send![*self.p, window];
+        if window == NIL {
+            return 0;
+        }
+        msg_send![window, windowNumber]
+    }
+
+    unsafe fn send(&self, selector: &str, event: &StrongPtr) {
+        let _: Id =
+            msg_send![*self.p, performSelector: Sel::register(selector) withObject: **event];
+    }
+}
+
+impl Default for VZVirtualMachineView {
+    fn default() -> Self {
+        VZVirtualMachineView::new()
+    }
+}
+
+/// A titled, closable and resizable window showing a [`VZVirtualMachineView`], for programs
+/// without windows of their own. Must be created and used on the main thread.
+pub struct VZVirtualMachineWindow(StrongPtr);
+
+impl VZVirtualMachineWindow {
+    /// A window with `view` as its content, `width` by `height` points, centered on the main
+    /// screen and hidden until [`show`](Self::show). The view gets the keyboard focus.
+    pub fn new(view: &VZVirtualMachineView, title: &str, width: f64, height: f64) -> Self {
+        let frame = NSRect {
+            origin: NSPoint::default(),
+            size: NSSize { width, height },
+        };
+        let style = WINDOW_STYLE_TITLED
+            | WINDOW_STYLE_CLOSABLE
+            | WINDOW_STYLE_MINIATURIZABLE
+            | WINDOW_STYLE_RESIZABLE;
+        let title = NSString::new(title);
+        unsafe {
+            // AppKit expects the application object before the first window.
+            let _: Id = msg_send![class!(NSApplication), sharedApplication];
+            let i = alloc(class!(NSWindow));
+            let p = owned(msg_send![
+                i,
+                initWithContentRect: frame
+                styleMask: style
+                backing: BACKING_STORE_BUFFERED
+                defer: NO
+            ]);
+            // Closing must not release the window under this wrapper.
+            let _: () = msg_send![*p, setReleasedWhenClosed: NO];
+            let _: () = msg_send![*p, setTitle: *title.0];
+            let _: () = msg_send![*p, setContentView: *view.p];
+            let _: BOOL = msg_send![*p, makeFirstResponder: *view.p];
+            let _: () = msg_send![*p, center];
+            VZVirtualMachineWindow(p)
+        }
+    }
+
+    /// Shows the window in front of the others and makes it the key window.
+    pub fn show(&self) {
+        unsafe {
+            let _: () = msg_send![*self.0, makeKeyAndOrderFront: NIL];
+        }
+    }
+
+    /// The width and height of the window's content, which the view fills, in points.
+    pub fn content_size(&self) -> (f64, f64) {
+        unsafe {
+            let view: Id = msg_send![*self.0, contentView];
+            let frame: NSRect = msg_send![view, frame];
+            (frame.size.width, frame.size.height)
+        }
+    }
+
+    /// The pixels per point the window is drawn at: 2 on Retina screens, 1 on others.
+    pub fn backing_scale_factor(&self) -> f64 {
+        unsafe { msg_send![*self.0, backingScaleFactor] }
+    }
+
+    /// The width and height in pixels, and the pixels per inch, of a display filling the view;
+    /// see [`VirtioDisplaySizing::for_window`].
+    pub fn display_sizing(&self) -> (NSInteger, NSInteger, NSInteger) {
+        VirtioDisplaySizing::for_window(self.content_size(), self.backing_scale_factor())
+    }
+
+    /// Calls `f` with the window's [`display_sizing`](Self::display_sizing) on the main thread
+    /// each time it may have changed: when the window is resized, which a live resize does many
+    /// times, or moves to a screen of another backing scale. Stops when the returned
+    /// [`WindowObserver`] is dropped.
+    pub fn on_resize<F>(&self, f: F) -> WindowObserver
+    where
+        F: FnMut((NSInteger, NSInteger, NSInteger)) + 'static,
+    {
+        let f = Rc::new(RefCell::new(f));
+        let window = self.0.clone();
+        let observers = RESIZE_NOTIFICATIONS
+            .iter()
+            .map(|name| {
+                let (f, window) = (f.clone(), window.clone());
+                let block = ConcreteBlock::new(move |_notification: Id| {
+                    let sizing = VZVirtualMachineWindow(window.clone()).display_sizing();
+                    // A resize from within `f` notifies again; that call is skipped.
+                    if let Ok(mut f) = f.try_borrow_mut() {
+                        f(sizing);
+                    }
+                });
+                let block = block.copy();
+                let name = NSString::new(name);
+                unsafe {
+                    let center: Id = msg_send![class!(NSNotificationCenter), defaultCenter];
+                    let observer: Id = msg_send![
+                        center,
+                        addObserverForName: *name.0
+                        object: *self.0
+                        queue: NIL
+                        usingBlock: &*block
+                    ];
+                    retained(observer)
+                }
+            })
+            .collect();
+        WindowObserver { observers }
+    }
+
+    pub fn id(&self) -> Id {
+        *self.0
+    }
+}
+
+/// Observes a window for [`VZVirtualMachineWindow::on_resize`] until dropped, which must happen
+/// on the main thread.
+pub struct WindowObserver {
+    observers: Vec<StrongPtr>,
+}
+
+impl Drop for WindowObserver {
+    fn drop(&mut self) {
+        unsafe {
+            let center: Id = msg_send![class!(NSNotificationCenter), defaultCenter];
+            for observer in &self.observers {
+                let _: () = msg_send![center, removeObserver: **observer];
+            }
+        }
+    }
+}
+
+/// Runs the application on the main thread as a regular app, with a Dock icon and a place in
+/// the app switcher, until it terminates, which it does once its last window is closed. Never
+/// returns: AppKit exits the process.
+///
+/// The main queue is serviced meanwhile, so machines created with
+/// [`VZVirtualMachine::new_without_queue`] work.
+pub fn run_application() -> ! {
+    unsafe {
+        let app: Id = msg_send![class!(NSApplication), sharedApplication];
+        let _: BOOL = msg_send![app, setActivationPolicy: ACTIVATION_POLICY_REGULAR];
+        // `delegate` is a weak property: this reference keeps the delegate alive, and is never
+        // released as `run` does not return.
+        let delegate = owned(msg_send![app_delegate_class(), new]);
+        let _: () = msg_send![app, setDelegate: *delegate];
+        let _: () = msg_send![app, activateIgnoringOtherApps: YES];
+        let _: () = msg_send![app, run];
+    }
+    std::process::exit(0)
+}
+
+fn app_delegate_class() -> &'static Class {
+    static REGISTER: Once = Once::new();
+    REGISTER.call_once(|| {
+        let mut decl = ClassDecl::new(APP_DELEGATE_CLASS, class!(NSObject)).unwrap();
+        unsafe {
+            decl.add_method(
+                sel!(applicationShouldTerminateAfterLastWindowClosed:),
+                terminate_after_last_window_closed as extern "C" fn(&Object, Sel, Id) -> BOOL,
+            );
+        }
+        decl.register();
+    });
+    Class::get(APP_DELEGATE_CLASS).unwrap()
+}
+
+extern "C" fn terminate_after_last_window_closed(_this: &Object, _cmd: Sel, _app: Id) -> BOOL {
+    YES
+}
+
+/// A copy of the mouse event `event` with another button number, which only a `CGEvent` can
+/// set.
+unsafe fn with_button_number(event: Id, number: i64) -> Result<StrongPtr, InjectError> {
+    let cg_event: *const c_void = msg_send![event, CGEvent];
+    if cg_event.is_null() {
+        return Err(InjectError::EventCreationFailed);
+    }
+    let copy = CGEventCreateCopy(cg_event);
+    if copy.is_null() {
+        return Err(InjectError::EventCreationFailed);
+    }
+    CGEventSetIntegerValueField(copy, CG_MOUSE_EVENT_BUTTON_NUMBER, number);
+    let event: Id = msg_send![class!(NSEvent), eventWithCGEvent: copy];
+    CFRelease(copy);
+    if event == NIL {
+        return Err(InjectError::EventCreationFailed);
+    }
+    Ok(retained(event))
+}
+
+/// Seconds since boot, the clock of event timestamps.
+unsafe fn uptime() -> f64 {
+    let info: Id = msg_send![class!(NSProcessInfo), processInfo];
+    msg_send![info, systemUptime]
+}
+
+/// Runs `f` on the main thread: right away on it, otherwise through the main queue, waiting.
+fn on_main<R, F: FnOnce() -> R>(f: F) -> R {
+    if MainQueuePump::is_main_thread() {
+        f()
+    } else {
+        DispatchQueue::main().exec_sync(f)
+    }
+}
+
+/// Waits between keys, servicing the main queue when on the main thread.
+fn pause(delay: Duration) {
+    if MainQueuePump::is_main_thread() {
+        MainQueuePump::pump(delay);
+    } else {
+        thread::sleep(delay);
+    }
+}
@@ -1,23 +1,72 @@
 //! virtual machine module
 
+#[cfg(feature = "linux-guest")]
 use crate::{
-    base::{Id, NSArray, NSError},
-    virtualization::boot_loader::VZBootLoader,
+    base::NSString,
+    virtualization::console_preflight::{self, ConsoleDevices, ConsoleFinding, GuestBoot},
+};
+use crate::{
+    base::{
+        CallbackQueue, CancellationToken, DispatchQueue, DispatchSemaphore, Id, InvalidInput,
+        NSArray, NSInteger, NSUInteger, QoSClass, NSURL,
+    },
+    diagnostics::{self, DiagnosticsReport},
+    features::{self, NotAvailable},
+    kvo::{self, KvoGuard},
+    limits::{self, DeviceLimitExceeded},
+    metrics::{self, VmMetrics},
+    queue_watchdog::{QueueWatchdog, QueueWatchdogStats},
+    runtime::{
+        alloc, call_with_error, class_name, debug_assert_non_nil, from_objc_bool, owned, retained,
+        to_objc_bool, vz_class, ForQueue,
+    },
+    strict,
+    timeline::{TimelineEventKind, TimelineHandle, TimelineSlot},
+    virtualization::audio_device::VZAudioDeviceConfiguration,
+    virtualization::boot_loader::{VZBootLoader, VariableStoreLease},
+    virtualization::console_device::VZVirtioConsoleDevice,
+    virtualization::device::{FrozenFlag, VZDeviceConfiguration},
+    virtualization::directory_sharing::{
+        VZDirectorySharingDeviceConfiguration, VZVirtioFileSystemDevice,
+    },
     virtualization::entropy_device::VZEntropyDeviceConfiguration,
-    virtualization::graphics_device::VZGraphicsDeviceConfiguration,
+    virtualization::error::{
+        AlignmentError, BuildError, CompletionOutcome, FrozenConfigError, ResultExt, VZError,
+        VZErrorCtx,
+    },
+    virtualization::error_events::{ErrorEventReceiver, ErrorEventSender, Phase},
+    virtualization::graphics_device::{VZGraphicsDevice, VZGraphicsDeviceConfiguration},
     virtualization::keyboard::VZKeyboardConfiguration,
-    virtualization::memory_device::VZMemoryBalloonDeviceConfiguration,
-    virtualization::network_device::VZNetworkDeviceConfiguration,
+    virtualization::lifecycle::{
+        Lifecycle, LifecycleError, LifecycleTracker, Op, StopReason, StopSignal,
+    },
+    virtualization::memory_device::{
+        VZMemoryBalloonDeviceConfiguration, VZVirtioTraditionalMemoryBalloonDevice,
+    },
+    virtualization::network_device::{VZMACAddress, VZNetworkDevice, VZNetworkDeviceConfiguration},
+    virtualization::platform::VZPlatformConfiguration,
     virtualization::pointing_device::VZPointingDeviceConfiguration,
-    virtualization::serial_port::VZSerialPortConfiguration,
-    virtualization::socket_device::VZSocketDeviceConfiguration,
+    virtualization::serial_port::{self, DuplicateConsoleBacking, VZSerialPortConfiguration},
+    virtualization::socket_device::{VZSocketDeviceConfiguration, VZVirtioSocketDevice},
     virtualization::storage_device::VZStorageDeviceConfiguration,
+    virtualization::topology::{KeyedDevices, TopologyManifest},
+    virtualization::validation::{self, Culprit, Located, ValidationFailure, VerboseBuildError},
 };
 
-use block::Block;
-use objc::runtime::BOOL;
-use objc::{class, msg_send, sel, sel_impl};
-use objc::{rc::StrongPtr, runtime::YES};
+use std::cell::Cell;
+use std::fmt;
+use std::io;
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, Weak};
+use std::time::{Duration, Instant};
+
+use block::{Block, ConcreteBlock};
+use objc::rc::StrongPtr;
+use objc::runtime::{Sel, BOOL};
+use objc::{msg_send, sel, sel_impl};
 
 /// builder for VZVirtualMachineConfiguration
 /// # Examples
@@ -25,7 +74,7 @@ use objc::{rc::StrongPtr, runtime::YES};
 /// let conf = VZVirtualMachineConfigurationBuilder::new()
 ///     .boot_loader(boot_loader)
 ///     .cpu_count(cpu_count)
-///     .memory_size(memory_size)
+///     .memory_size_mib(memory_size_mib)
 ///     .entropy_devices(vec![entropy])
 ///     .memory_balloon_devices(vec![memory_balloon])
 ///     .network_devices(vec![network_device])
@@ -35,27 +84,97 @@ use objc::{rc::StrongPtr, runtime::YES};
 /// ```
 pub struct VZVirtualMachineConfigurationBuilder {
     conf: VZVirtualMachineConfiguration,
+    storage: KeyedDevices<Box<dyn VZStorageDeviceConfiguration>>,
+    network: KeyedDevices<Box<dyn VZNetworkDeviceConfiguration>>,
+    /// The last memory size passed to `memory_size`, if it was rounded down.
+    misaligned_memory_size: Option<AlignmentError>,
+    /// Serial ports passed to `serial_ports` that share a file.
+    duplicate_console_backings: Vec<DuplicateConsoleBacking>,
 }
 
 impl VZVirtualMachineConfigurationBuilder {
+    /// Panics without the framework, e.g. on macOS 10.15; see [`try_new`](Self::try_new).
     pub fn new() -> Self {
         VZVirtualMachineConfigurationBuilder {
             conf: VZVirtualMachineConfiguration::new(),
+            storage: KeyedDevices::new(),
+            network: KeyedDevices::new(),
+            misaligned_memory_size: None,
+            duplicate_console_backings: Vec::new(),
         }
     }
 
+    /// Like [`new`](Self::new), but fails with [`NotAvailable`] without the framework, for
+    /// binaries that degrade on systems without it.
+    pub fn try_new() -> Result<Self, NotAvailable> {
+        features::require_framework()?;
+        Ok(Self::new())
+    }
+
     pub fn boot_loader<T: VZBootLoader>(mut self, boot_loader: T) -> Self {
         self.conf.set_boot_loader(boot_loader);
         self
     }
 
     pub fn cpu_count(mut self, cpu_count: usize) -> Self {
-        self.conf.set_cpu_count(cpu_count);
+        self.conf.apply_cpu_count(cpu_count);
         self
     }
 
+    /// In bytes. The framework takes multiples of [`MEMORY_SIZE_GRANULARITY`]; any other size
+    /// is rounded down with a `log` warning, and makes [`validated_build`] fail. The allowed
+    /// bounds pass through as they are. In strict mode, any other size panics.
+    ///
+    /// Prefer [`memory_size_mib`] and [`memory_size_gib`], which cannot be misaligned.
+    ///
+    /// [`validated_build`]: VZVirtualMachineConfigurationBuilder::validated_build
+    /// [`memory_size_mib`]: VZVirtualMachineConfigurationBuilder::memory_size_mib
+    /// [`memory_size_gib`]: VZVirtualMachineConfigurationBuilder::memory_size_gib
     pub fn memory_size(mut self, memory_size: usize) -> Self {
-        self.conf.set_memory_size(memory_size);
+        let bounds = [
+            VZVirtualMachineConfiguration::minimum_allowed_memory_size(),
+            VZVirtualMachineConfiguration::maximum_allowed_memory_size(),
+        ];
+        self.misaligned_memory_size = if bounds.contains(&memory_size) {
+            None
+        } else {
+            AlignmentError::check(memory_size, MEMORY_SIZE_GRANULARITY)
+        };
+        match &self.misaligned_memory_size {
+            Some(error) => {
+                strict::misaligned(error);
+                log::warn!("{}; rounding down to {}", error, error.below);
+                self.conf.apply_memory_size(error.below);
+            }
+            None => self.conf.apply_memory_size(memory_size),
+        }
+        self
+    }
+
+    /// Memory size in MiB.
+    pub fn memory_size_mib(self, mib: usize) -> Self {
+        // Saturates to the largest multiple of the granularity, so this stays aligned.
+        let largest = usize::MAX - usize::MAX % MEMORY_SIZE_GRANULARITY;
+        self.memory_size(mib.checked_mul(MEMORY_SIZE_GRANULARITY).unwrap_or(largest))
+    }
+
+    /// Memory size in GiB.
+    pub fn memory_size_gib(self, gib: usize) -> Self {
+        self.memory_size_mib(gib.saturating_mul(1024))
+    }
+
+    /// macOS 12 and later.
+    pub fn audio_devices<T: VZAudioDeviceConfiguration>(mut self, audio_devices: Vec<T>) -> Self {
+        self.conf.set_audio_devices(audio_devices);
+        self
+    }
+
+    pub fn directory_sharing_devices<T: VZDirectorySharingDeviceConfiguration>(
+        mut self,
+        directory_sharing_devices: Vec<T>,
+    ) -> Self {
+        self.conf
+            .set_directory_sharing_devices(directory_sharing_devices);
         self
     }
 
@@ -88,11 +207,44 @@ impl VZVirtualMachineConfigurationBuilder {
         self
     }
 
+    /// Replaces all network devices added so far, keyed or not. See
+    /// [`topology`](crate::virtualization::topology) for how devices are ordered.
     pub fn network_devices<T: VZNetworkDeviceConfiguration>(
         mut self,
         network_devices: Vec<T>,
     ) -> Self {
-        self.conf.set_network_devices(network_devices);
+        self.network.reset(
+            network_devices
+                .into_iter()
+                .map(|d| Box::new(d) as Box<dyn VZNetworkDeviceConfiguration>)
+                .collect(),
+        );
+        self.conf.set_network_devices(self.network.devices());
+        self
+    }
+
+    /// Adds a network device after all others.
+    pub fn network_device<T: VZNetworkDeviceConfiguration>(mut self, network_device: T) -> Self {
+        self.network.push(Box::new(network_device));
+        self.conf.set_network_devices(self.network.devices());
+        self
+    }
+
+    /// Adds a network device at the place of `key`, ahead of unkeyed devices, or replaces the
+    /// device added with `key` before.
+    pub fn network_device_keyed<T: VZNetworkDeviceConfiguration>(
+        mut self,
+        key: &str,
+        network_device: T,
+    ) -> Self {
+        self.network
+            .insert(key.to_string(), Box::new(network_device));
+        self.conf.set_network_devices(self.network.devices());
+        self
+    }
+
+    pub fn platform<T: VZPlatformConfiguration>(mut self, platform: T) -> Self {
+        self.conf.set_platform(platform);
         self
     }
 
@@ -104,11 +256,32 @@ impl VZVirtualMachineConfigurationBuilder {
         self
     }
 
+    /// Ports whose attachments read from or write to the same file as another port, even through
+    /// a duplicated descriptor, get a `log` warning, and are listed by
+    /// [`duplicate_console_backings`]. In strict mode, they panic.
+    ///
+    /// [`duplicate_console_backings`]: VZVirtualMachineConfigurationBuilder::duplicate_console_backings
     pub fn serial_ports<T: VZSerialPortConfiguration>(mut self, serial_ports: Vec<T>) -> Self {
+        let backings: Vec<_> = serial_ports
+            .iter()
+            .map(|port| unsafe { serial_port::port_backing_fds(port.id()) })
+            .collect();
+        self.duplicate_console_backings = serial_port::find_duplicate_backings(&backings);
+        for duplicate in &self.duplicate_console_backings {
+            strict::duplicate_console_backing(duplicate);
+            log::warn!("{}; attaching anyway", duplicate);
+        }
         self.conf.set_serial_ports(serial_ports);
         self
     }
 
+    /// The serial ports of the last [`serial_ports`] call that share a file with another port.
+    ///
+    /// [`serial_ports`]: VZVirtualMachineConfigurationBuilder::serial_ports
+    pub fn duplicate_console_backings(&self) -> &[DuplicateConsoleBacking] {
+        &self.duplicate_console_backings
+    }
+
     pub fn socket_devices<T: VZSocketDeviceConfiguration>(
         mut self,
         socket_devices: Vec<T>,
@@ -117,69 +290,376 @@ impl VZVirtualMachineConfigurationBuilder {
         self
     }
 
+    /// Replaces all storage devices added so far, keyed or not. See
+    /// [`topology`](crate::virtualization::topology) for how devices are ordered.
     pub fn storage_devices<T: VZStorageDeviceConfiguration>(
         mut self,
         storage_devices: Vec<T>,
     ) -> Self {
-        self.conf.set_storage_devices(storage_devices);
+        self.storage.reset(
+            storage_devices
+                .into_iter()
+                .map(|d| Box::new(d) as Box<dyn VZStorageDeviceConfiguration>)
+                .collect(),
+        );
+        self.conf.set_storage_devices(self.storage.devices());
         self
     }
 
+    /// Adds a storage device after all others.
+    pub fn storage_device<T: VZStorageDeviceConfiguration>(mut self, storage_device: T) -> Self {
+        self.storage.push(Box::new(storage_device));
+        self.conf.set_storage_devices(self.storage.devices());
+        self
+    }
+
+    /// Adds a storage device at the place of `key`, ahead of unkeyed devices, or replaces the
+    /// device added with `key` before.
+    pub fn storage_device_keyed<T: VZStorageDeviceConfiguration>(
+        mut self,
+        key: &str,
+        storage_device: T,
+    ) -> Self {
+        self.storage
+            .insert(key.to_string(), Box::new(storage_device));
+        self.conf.set_storage_devices(self.storage.devices());
+        self
+    }
+
+    /// Sets the MAC address of the first network device added so far; `false` if there is none.
+    /// Fails like the device's own setter once the configuration or the device is part of a
+    /// virtual machine.
+    pub(crate) fn set_first_network_device_mac(
+        &mut self,
+        mac: &VZMACAddress,
+    ) -> Result<bool, FrozenConfigError> {
+        const SETTER: &str = "VZVirtualMachineConfigurationBuilder::set_first_network_device_mac";
+        self.conf.frozen.check(SETTER)?;
+        let device = match self.network.devices().first() {
+            Some(device) => device,
+            None => return Ok(false),
+        };
+        if let Some(flag) = device.frozen_flag() {
+            flag.check(SETTER)?;
+        }
+        unsafe {
+            let _: () = msg_send![device.id(), setMACAddress:*mac.0];
+        }
+        Ok(true)
+    }
+
     pub fn build(self) -> VZVirtualMachineConfiguration {
         self.conf
     }
+
+    /// Like [`build`], but fails instead of building with a memory size that `memory_size`
+    /// rounded down, or with more devices of a category than the framework allows on this
+    /// macOS; see [`limits`](crate::limits). The framework's own checks still need
+    /// [`VZVirtualMachineConfiguration::validate_with_error`].
+    ///
+    /// [`build`]: VZVirtualMachineConfigurationBuilder::build
+    pub fn validated_build(self) -> Result<VZVirtualMachineConfiguration, BuildError> {
+        if let Some(error) = self.misaligned_memory_size {
+            return Err(error.into());
+        }
+        if let Some(error) = self.conf.device_limit_violations().into_iter().next() {
+            return Err(error.into());
+        }
+        Ok(self.conf)
+    }
+
+    /// Like [`validated_build`], and also validates the configuration. If that fails, it finds
+    /// the device the failure comes from by revalidating a copy with one device added at a time;
+    /// see [`validation`](crate::virtualization::validation).
+    ///
+    /// [`validated_build`]: VZVirtualMachineConfigurationBuilder::validated_build
+    pub fn validated_build_verbose(
+        self,
+    ) -> Result<VZVirtualMachineConfiguration, VerboseBuildError> {
+        let conf = self.validated_build()?;
+        match conf.validate_with_error() {
+            Ok(_) => Ok(conf),
+            Err(error) => Err(conf.locate_failure(error).into()),
+        }
+    }
+
+    /// Builds the configuration along with the order its storage and network devices ended up in.
+    pub fn build_with_manifest(self) -> (VZVirtualMachineConfiguration, TopologyManifest) {
+        let manifest = TopologyManifest::new(&self.storage, &self.network);
+        (self.conf, manifest)
+    }
+}
+
+impl Default for VZVirtualMachineConfigurationBuilder {
+    fn default() -> Self {
+        VZVirtualMachineConfigurationBuilder::new()
+    }
 }
 
+/// Memory sizes the framework accepts are multiples of this, 1 MiB.
+pub const MEMORY_SIZE_GRANULARITY: usize = 1 << 20;
+
+/// `requested`, in bytes, rounded down to a multiple of [`MEMORY_SIZE_GRANULARITY`] and clamped
+/// into the bounds the framework allows on this host. The bounds themselves are kept as they are,
+/// like the builder keeps them, so a suggested size suggests itself.
+pub fn suggested_memory_size(requested: usize) -> usize {
+    let max = VZVirtualMachineConfiguration::maximum_allowed_memory_size();
+    if requested >= max {
+        return max;
+    }
+    let aligned = requested - requested % MEMORY_SIZE_GRANULARITY;
+    aligned.max(VZVirtualMachineConfiguration::minimum_allowed_memory_size())
+}
+
+/// Device arrays of a configuration as `describe` names them, with their getters; the later
+/// ones are missing from older macOS releases.
+const DEVICE_ARRAYS: &[(&str, &str)] = &[
+    ("storage_devices", "storageDevices"),
+    ("network_devices", "networkDevices"),
+    ("serial_ports", "serialPorts"),
+    ("entropy_devices", "entropyDevices"),
+    ("memory_balloon_devices", "memoryBalloonDevices"),
+    ("socket_devices", "socketDevices"),
+    ("directory_sharing_devices", "directorySharingDevices"),
+    ("graphics_devices", "graphicsDevices"),
+    ("keyboards", "keyboards"),
+    ("pointing_devices", "pointingDevices"),
+    ("audio_devices", "audioDevices"),
+    ("console_devices", "consoleDevices"),
+];
+
 /// configure of virtual machine
-pub struct VZVirtualMachineConfiguration(StrongPtr);
+///
+/// Creating a virtual machine freezes the configuration and the devices in it, shared with all
+/// clones; see [`FrozenFlag`].
+#[derive(Clone)]
+pub struct VZVirtualMachineConfiguration {
+    p: StrongPtr,
+    frozen: FrozenFlag,
+    /// The flags of the devices in each device array, named as in [`DEVICE_ARRAYS`].
+    device_flags: Vec<(&'static str, FrozenFlag)>,
+    /// Device pointers of the array being built, reused by every `set_*_devices` call.
+    scratch: Vec<Id>,
+    /// Values passed to the setters, to tell what the framework changed.
+    requested_cpu_count: usize,
+    requested_memory_size: usize,
+}
+
+/// A resource as requested and as the framework holds it after validation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ResourceValue {
+    pub requested: usize,
+    pub effective: usize,
+    pub was_clamped: bool,
+}
+
+impl ResourceValue {
+    fn new(requested: usize, effective: usize) -> ResourceValue {
+        ResourceValue {
+            requested,
+            effective,
+            was_clamped: requested != effective,
+        }
+    }
+}
+
+/// What a virtual machine built from a configuration actually gets; see
+/// [`VZVirtualMachineConfiguration::effective_resources`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EffectiveResources {
+    pub cpu_count: ResourceValue,
+    /// In bytes.
+    pub memory_size: ResourceValue,
+}
+
+/// What a [`BackingFile`] holds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum BackingFileKind {
+    DiskImage,
+    EfiVariableStore,
+    AuxiliaryStorage,
+}
+
+/// A file a virtual machine built from a configuration reads or writes while it runs; see
+/// [`VZVirtualMachineConfiguration::backing_files`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BackingFile {
+    pub path: PathBuf,
+    pub kind: BackingFileKind,
+    /// Whether the machine only reads the file; the EFI variable store and auxiliary storage are
+    /// always written.
+    pub read_only: bool,
+}
 
 impl VZVirtualMachineConfiguration {
     fn new() -> VZVirtualMachineConfiguration {
         unsafe {
-            let obj = StrongPtr::new(msg_send![class!(VZVirtualMachineConfiguration), new]);
-            VZVirtualMachineConfiguration(obj)
+            let p = owned(msg_send![vz_class!(VZVirtualMachineConfiguration), new]);
+            let requested_cpu_count: NSUInteger = msg_send![*p, CPUCount];
+            let requested_memory_size: u64 = msg_send![*p, memorySize];
+            VZVirtualMachineConfiguration {
+                p,
+                frozen: FrozenFlag::default(),
+                device_flags: Vec::new(),
+                scratch: Vec::new(),
+                requested_cpu_count: requested_cpu_count as usize,
+                requested_memory_size: requested_memory_size as usize,
+            }
+        }
+    }
+
+    /// A copy through `NSCopying`, to specialize a configuration built elsewhere, e.g. with
+    /// another disk. Unlike `clone`, which shares the object, changes to the copy leave `self` as
+    /// it is.
+    ///
+    /// The copy is not frozen, even if `self` is. Its device arrays are copied but the devices in
+    /// them are shared, and stay frozen if a virtual machine was created with them; replace them
+    /// rather than changing them.
+    pub fn copy(&self) -> VZVirtualMachineConfiguration {
+        unsafe {
+            // `copy` returns the copy retained.
+            let p = owned(msg_send![*self.p, copy]);
+            debug_assert_non_nil!(p, "-[VZVirtualMachineConfiguration copy]");
+            VZVirtualMachineConfiguration {
+                p,
+                frozen: FrozenFlag::default(),
+                device_flags: self.device_flags.clone(),
+                scratch: Vec::new(),
+                requested_cpu_count: self.requested_cpu_count,
+                requested_memory_size: self.requested_memory_size,
+            }
+        }
+    }
+
+    /// Whether a virtual machine was created from this configuration.
+    pub fn is_frozen(&self) -> bool {
+        self.frozen.is_frozen()
+    }
+
+    fn freeze(&self) {
+        self.frozen.freeze();
+        for (_, device) in &self.device_flags {
+            device.freeze();
         }
     }
 
+    /// Builds the array for a `set...Devices:` call and replaces the flags remembered for the
+    /// `category` array with those of `devices`, so only devices still in the configuration are
+    /// frozen along with it.
+    fn device_array<T: VZDeviceConfiguration>(
+        &mut self,
+        category: &'static str,
+        devices: &[T],
+    ) -> NSArray<T> {
+        self.device_flags.retain(|(array, _)| *array != category);
+        self.device_flags.extend(
+            devices
+                .iter()
+                .filter_map(|d| d.frozen_flag())
+                .map(|flag| (category, flag)),
+        );
+        self.scratch.clear();
+        self.scratch.extend(devices.iter().map(|d| {
+            let id = d.id();
+            strict::non_nil(id, "a device configuration");
+            id
+        }));
+        NSArray::from_slice(&self.scratch)
+    }
+
     fn set_boot_loader<T: VZBootLoader>(&mut self, boot_loader: T) {
+        strict::non_nil(boot_loader.id(), "the boot loader");
+        unsafe {
+            let _: () = msg_send![*self.p, setBootLoader: boot_loader.id()];
+        }
+    }
+
+    fn set_platform<T: VZPlatformConfiguration>(&mut self, platform: T) {
+        strict::non_nil(platform.id(), "the platform configuration");
+        unsafe {
+            let _: () = msg_send![*self.p, setPlatform: platform.id()];
+        }
+    }
+
+    /// Sets the CPU count of a configuration built elsewhere, e.g. a [`copy`](Self::copy). The
+    /// framework checks it against the host in [`validate_with_error`](Self::validate_with_error).
+    pub fn set_cpu_count(&mut self, cnt: usize) -> Result<(), FrozenConfigError> {
+        self.frozen
+            .check("VZVirtualMachineConfiguration::set_cpu_count")?;
+        self.apply_cpu_count(cnt);
+        Ok(())
+    }
+
+    /// Sets the memory size of a configuration built elsewhere, in bytes. Unlike the builder's
+    /// [`memory_size`](VZVirtualMachineConfigurationBuilder::memory_size), it does not round: a
+    /// size that is not a multiple of [`MEMORY_SIZE_GRANULARITY`] fails validation.
+    pub fn set_memory_size(&mut self, size: usize) -> Result<(), FrozenConfigError> {
+        self.frozen
+            .check("VZVirtualMachineConfiguration::set_memory_size")?;
+        self.apply_memory_size(size);
+        Ok(())
+    }
+
+    /// Replaces all storage devices of a configuration built elsewhere.
+    pub fn replace_storage_devices(
+        &mut self,
+        devices: Vec<Box<dyn VZStorageDeviceConfiguration>>,
+    ) -> Result<(), FrozenConfigError> {
+        self.frozen
+            .check("VZVirtualMachineConfiguration::replace_storage_devices")?;
+        self.set_storage_devices(&devices);
+        Ok(())
+    }
+
+    fn apply_cpu_count(&mut self, cnt: usize) {
+        self.requested_cpu_count = cnt;
         unsafe {
-            let _: () = msg_send![*self.0, setBootLoader: boot_loader.id()];
+            let _: () = msg_send![*self.p, setCPUCount: cnt];
         }
     }
 
-    fn set_cpu_count(&mut self, cnt: usize) {
+    fn apply_memory_size(&mut self, size: usize) {
+        self.requested_memory_size = size;
         unsafe {
-            let _: () = msg_send![*self.0, setCPUCount: cnt];
+            let _: () = msg_send![*self.p, setMemorySize: size];
         }
     }
 
-    fn set_memory_size(&mut self, size: usize) {
+    fn set_audio_devices<T: VZAudioDeviceConfiguration>(&mut self, devices: Vec<T>) {
+        let arr = self.device_array("audio_devices", &devices);
         unsafe {
-            let _: () = msg_send![*self.0, setMemorySize: size];
+            let _: () = msg_send![*self.p, setAudioDevices:*arr.p];
+        }
+    }
+
+    fn set_directory_sharing_devices<T: VZDirectorySharingDeviceConfiguration>(
+        &mut self,
+        devices: Vec<T>,
+    ) {
+        let arr = self.device_array("directory_sharing_devices", &devices);
+        unsafe {
+            let _: () = msg_send![*self.p, setDirectorySharingDevices:*arr.p];
         }
     }
 
     fn set_entropy_devices<T: VZEntropyDeviceConfiguration>(&mut self, devices: Vec<T>) {
-        let device_ids = devices.iter().map(|x| x.id()).collect();
-        let arr: NSArray<T> = NSArray::array_with_objects(device_ids);
+        let arr = self.device_array("entropy_devices", &devices);
         unsafe {
-            let _: () = msg_send![*self.0, setEntropyDevices:*arr.p];
+            let _: () = msg_send![*self.p, setEntropyDevices:*arr.p];
         }
     }
 
     fn set_graphics_devices<T: VZGraphicsDeviceConfiguration>(&mut self, devices: Vec<T>) {
-        let device_ids = devices.iter().map(|x| x.id()).collect();
-        let arr: NSArray<T> = NSArray::array_with_objects(device_ids);
+        let arr = self.device_array("graphics_devices", &devices);
         unsafe {
-            let _: () = msg_send![*self.0, setGraphicsDevices:*arr.p];
+            let _: () = msg_send![*self.p, setGraphicsDevices:*arr.p];
         }
     }
 
     fn set_keyboards<T: VZKeyboardConfiguration>(&mut self, devices: Vec<T>) {
-        let device_ids = devices.iter().map(|x| x.id()).collect();
-        let arr: NSArray<T> = NSArray::array_with_objects(device_ids);
+        let arr = self.device_array("keyboards", &devices);
         unsafe {
-            let _: () = msg_send![*self.0, setKeyboards:*arr.p];
+            let _: () = msg_send![*self.p, setKeyboards:*arr.p];
         }
     }
 
@@ -187,72 +667,778 @@ impl VZVirtualMachineConfiguration {
         &mut self,
         devices: Vec<T>,
     ) {
-        let device_ids = devices.iter().map(|x| x.id()).collect();
-        let arr: NSArray<T> = NSArray::array_with_objects(device_ids);
+        let arr = self.device_array("memory_balloon_devices", &devices);
         unsafe {
-            let _: () = msg_send![*self.0, setMemoryBalloonDevices:*arr.p];
+            let _: () = msg_send![*self.p, setMemoryBalloonDevices:*arr.p];
         }
     }
 
-    fn set_network_devices<T: VZNetworkDeviceConfiguration>(&mut self, devices: Vec<T>) {
-        let device_ids = devices.iter().map(|x| x.id()).collect();
-        let arr: NSArray<T> = NSArray::array_with_objects(device_ids);
+    fn set_network_devices<T: VZNetworkDeviceConfiguration>(&mut self, devices: &[T]) {
+        let arr = self.device_array("network_devices", devices);
         unsafe {
-            let _: () = msg_send![*self.0, setNetworkDevices:*arr.p];
+            let _: () = msg_send![*self.p, setNetworkDevices:*arr.p];
         }
     }
 
     fn set_pointing_devices<T: VZPointingDeviceConfiguration>(&mut self, devices: Vec<T>) {
-        let device_ids = devices.iter().map(|x| x.id()).collect();
-        let arr: NSArray<T> = NSArray::array_with_objects(device_ids);
+        let arr = self.device_array("pointing_devices", &devices);
         unsafe {
-            let _: () = msg_send![*self.0, setPointingDevices:*arr.p];
+            let _: () = msg_send![*self.p, setPointingDevices:*arr.p];
         }
     }
 
     fn set_serial_ports<T: VZSerialPortConfiguration>(&mut self, devices: Vec<T>) {
-        let device_ids = devices.iter().map(|x| x.id()).collect();
-        let arr: NSArray<T> = NSArray::array_with_objects(device_ids);
+        let arr = self.device_array("serial_ports", &devices);
         unsafe {
-            let _: () = msg_send![*self.0, setSerialPorts:*arr.p];
+            let _: () = msg_send![*self.p, setSerialPorts:*arr.p];
         }
     }
 
     fn set_socket_devices<T: VZSocketDeviceConfiguration>(&mut self, devices: Vec<T>) {
-        let device_ids = devices.iter().map(|x| x.id()).collect();
-        let arr: NSArray<T> = NSArray::array_with_objects(device_ids);
+        let arr = self.device_array("socket_devices", &devices);
         unsafe {
-            let _: () = msg_send![*self.0, setSocketDevices:*arr.p];
+            let _: () = msg_send![*self.p, setSocketDevices:*arr.p];
         }
     }
 
-    fn set_storage_devices<T: VZStorageDeviceConfiguration>(&mut self, devices: Vec<T>) {
-        let device_ids = devices.iter().map(|x| x.id()).collect();
-        let arr: NSArray<T> = NSArray::array_with_objects(device_ids);
+    fn set_storage_devices<T: VZStorageDeviceConfiguration>(&mut self, devices: &[T]) {
+        let arr = self.device_array("storage_devices", devices);
         unsafe {
-            let _: () = msg_send![*self.0, setStorageDevices:*arr.p];
+            let _: () = msg_send![*self.p, setStorageDevices:*arr.p];
         }
     }
 
-    pub fn validate_with_error(&self) -> Result<BOOL, NSError> {
+    pub fn validate_with_error(&self) -> Result<bool, VZErrorCtx> {
+        let result = unsafe {
+            call_with_error(|error| {
+                let ret: BOOL = msg_send![*self.p, validateWithError: error];
+                from_objc_bool(ret)
+            })
+        }
+        .ctx_op("validate virtual machine configuration");
+        metrics::validation(&result);
+        result
+    }
+
+    pub fn cpu_count(&self) -> usize {
+        let count: NSUInteger = unsafe { msg_send![*self.p, CPUCount] };
+        count as usize
+    }
+
+    /// Smallest memory size a virtual machine can have, in bytes.
+    pub fn minimum_allowed_memory_size() -> usize {
         unsafe {
-            let error = NSError(StrongPtr::new(0 as Id));
-            let obj: BOOL = msg_send![*self.0, validateWithError: &(*error.0)];
-            if error.code() != 0 {
-                Err(error)
-            } else {
-                Ok(obj)
+            let size: u64 = msg_send![
+                vz_class!(VZVirtualMachineConfiguration),
+                minimumAllowedMemorySize
+            ];
+            size as usize
+        }
+    }
+
+    /// Largest memory size a virtual machine can have on this host, in bytes.
+    pub fn maximum_allowed_memory_size() -> usize {
+        unsafe {
+            let size: u64 = msg_send![
+                vz_class!(VZVirtualMachineConfiguration),
+                maximumAllowedMemorySize
+            ];
+            size as usize
+        }
+    }
+
+    /// In bytes.
+    pub fn memory_size(&self) -> usize {
+        let size: u64 = unsafe { msg_send![*self.p, memorySize] };
+        size as usize
+    }
+
+    /// One line per setting: the CPU count, the memory size in bytes, the classes of the boot
+    /// loader and platform, then each device array that is not empty with its device classes,
+    /// in order, e.g. `storage_devices VZVirtioBlockDeviceConfiguration`.
+    pub fn describe(&self) -> String {
+        let mut out = format!(
+            "cpu_count {}\nmemory_size {}\n",
+            self.cpu_count(),
+            self.memory_size()
+        );
+        unsafe {
+            let boot_loader: Id = msg_send![*self.p, bootLoader];
+            let platform: Id = msg_send![*self.p, platform];
+            for (name, obj) in [("boot_loader", boot_loader), ("platform", platform)].iter() {
+                let class = if obj.is_null() {
+                    "-".to_string()
+                } else {
+                    class_name(*obj)
+                };
+                out.push_str(&format!("{} {}\n", name, class));
+            }
+            for (name, selector) in DEVICE_ARRAYS {
+                let sel = Sel::register(selector);
+                let responds: BOOL = msg_send![*self.p, respondsToSelector: sel];
+                if !from_objc_bool(responds) {
+                    continue;
+                }
+                let devices: Id = msg_send![*self.p, performSelector: sel];
+                let count: NSUInteger = msg_send![devices, count];
+                if count == 0 {
+                    continue;
+                }
+                out.push_str(name);
+                for i in 0..count {
+                    let device: Id = msg_send![devices, objectAtIndex: i];
+                    out.push(' ');
+                    out.push_str(&class_name(device));
+                }
+                out.push('\n');
+            }
+        }
+        out
+    }
+
+    /// The number of devices in the array `describe` names `category`, e.g. `entropy_devices`;
+    /// `None` for a name it does not list, or an array this macOS does not have.
+    pub fn device_count(&self, category: &str) -> Option<usize> {
+        let (_, getter) = DEVICE_ARRAYS.iter().find(|(name, _)| *name == category)?;
+        unsafe {
+            let getter = Sel::register(getter);
+            if !responds_to(*self.p, getter) {
+                return None;
+            }
+            let devices: Id = msg_send![*self.p, performSelector: getter];
+            let count: NSUInteger = msg_send![devices, count];
+            Some(count as usize)
+        }
+    }
+
+    /// The device arrays holding more devices than [`limits::device_limits`] allows, in the
+    /// order of its table.
+    pub fn device_limit_violations(&self) -> Vec<DeviceLimitExceeded> {
+        let limits = limits::device_limits();
+        DEVICE_ARRAYS
+            .iter()
+            .filter_map(|(name, _)| {
+                let configured = self.device_count(name).unwrap_or(0);
+                limits.check(name, configured).err()
+            })
+            .collect()
+    }
+
+    /// The MAC addresses of the network devices in order, as [`VZMACAddress::string`] renders
+    /// them.
+    pub fn network_mac_addresses(&self) -> Vec<String> {
+        unsafe {
+            let devices: Id = msg_send![*self.p, networkDevices];
+            let count: NSUInteger = msg_send![devices, count];
+            (0..count)
+                .map(|i| {
+                    let device: Id = msg_send![devices, objectAtIndex: i];
+                    let mac: Id = msg_send![device, MACAddress];
+                    VZMACAddress(retained(mac)).string()
+                })
+                .collect()
+        }
+    }
+
+    /// Validates the configuration and reads the CPU count and memory size back, so callers can
+    /// record what the virtual machine gets if the framework adjusted the requested values.
+    /// Values outside the allowed bounds fail validation instead.
+    pub fn effective_resources(&self) -> Result<EffectiveResources, VZErrorCtx> {
+        self.validate_with_error()?;
+        Ok(EffectiveResources {
+            cpu_count: ResourceValue::new(self.requested_cpu_count, self.cpu_count()),
+            memory_size: ResourceValue::new(self.requested_memory_size, self.memory_size()),
+        })
+    }
+
+    /// The files behind the configuration: the disk images of the storage devices in order, then
+    /// the EFI variable store of the boot loader and the auxiliary storage of a Mac platform.
+    /// Attachments without a file URL, e.g. network block devices, are left out.
+    pub fn backing_files(&self) -> Vec<BackingFile> {
+        let mut files = Vec::new();
+        unsafe {
+            let devices: Id = msg_send![*self.p, storageDevices];
+            let count: NSUInteger = msg_send![devices, count];
+            for i in 0..count {
+                let device: Id = msg_send![devices, objectAtIndex: i];
+                let attachment: Id = msg_send![device, attachment];
+                if let Some(path) = file_url_path(attachment) {
+                    let read_only = responds_to(attachment, sel!(isReadOnly)) && {
+                        let read_only: BOOL = msg_send![attachment, isReadOnly];
+                        from_objc_bool(read_only)
+                    };
+                    files.push(BackingFile {
+                        path,
+                        kind: BackingFileKind::DiskImage,
+                        read_only,
+                    });
+                }
+            }
+            let owners = [
+                (
+                    sel!(bootLoader),
+                    sel!(variableStore),
+                    BackingFileKind::EfiVariableStore,
+                ),
+                (
+                    sel!(platform),
+                    sel!(auxiliaryStorage),
+                    BackingFileKind::AuxiliaryStorage,
+                ),
+            ];
+            for &(owner, getter, kind) in owners.iter() {
+                let owner: Id = msg_send![*self.p, performSelector: owner];
+                if !responds_to(owner, getter) {
+                    continue;
+                }
+                let file: Id = msg_send![owner, performSelector: getter];
+                if let Some(path) = file_url_path(file) {
+                    files.push(BackingFile {
+                        path,
+                        kind,
+                        read_only: false,
+                    });
+                }
+            }
+        }
+        files
+    }
+
+    /// Checks the `console=` arguments of a Linux boot loader's command line against the
+    /// serial ports and graphics devices; see [`console_preflight`].
+    #[cfg(feature = "linux-guest")]
+    pub fn console_preflight(&self) -> Vec<ConsoleFinding> {
+        let devices = ConsoleDevices {
+            virtio_consoles: self.device_count("serial_ports").unwrap_or(0),
+            graphics: self.device_count("graphics_devices").unwrap_or(0) > 0,
+        };
+        unsafe {
+            let boot_loader: Id = msg_send![*self.p, bootLoader];
+            if boot_loader.is_null() {
+                return console_preflight::check(GuestBoot::Other, devices);
+            }
+            match class_name(boot_loader).as_str() {
+                "VZLinuxBootLoader" => {
+                    let command_line = NSString(retained(msg_send![boot_loader, commandLine]));
+                    console_preflight::check(GuestBoot::Linux(command_line.as_str()), devices)
+                }
+                "VZEFIBootLoader" => console_preflight::check(GuestBoot::Efi, devices),
+                _ => console_preflight::check(GuestBoot::Other, devices),
+            }
+        }
+    }
+}
+
+/// A device array of a configuration being revalidated by
+/// [`VZVirtualMachineConfiguration::locate_failure`].
+struct DeviceCategory {
+    name: &'static str,
+    setter: Sel,
+    /// The array of the configuration as built.
+    devices: StrongPtr,
+    count: usize,
+    /// How many of `devices` the copy holds.
+    applied: usize,
+}
+
+impl VZVirtualMachineConfiguration {
+    /// Finds what the configuration fails validation on, having failed with `error`. Works on
+    /// a copy, on which each step only sets the arrays that changed.
+    fn locate_failure(&self, error: VZErrorCtx) -> ValidationFailure {
+        unsafe {
+            let copy = owned(msg_send![*self.p, copy]);
+            let mut categories = Vec::new();
+            for (name, getter) in DEVICE_ARRAYS {
+                let getter = Sel::register(getter);
+                if !responds_to(*self.p, getter) {
+                    continue;
+                }
+                let devices = retained(msg_send![*self.p, performSelector: getter]);
+                let count: NSUInteger = msg_send![*devices, count];
+                categories.push(DeviceCategory {
+                    name,
+                    setter: Sel::register(&setter_name(getter.name())),
+                    devices,
+                    count: count as usize,
+                    applied: count as usize,
+                });
+            }
+            let counts: Vec<usize> = categories.iter().map(|c| c.count).collect();
+            let (located, validations) = validation::locate(&counts, |lengths| {
+                for (category, &length) in categories.iter_mut().zip(lengths) {
+                    if category.applied != length {
+                        let prefix: Vec<Id> = (0..length)
+                            .map(|i| msg_send![*category.devices, objectAtIndex: i as NSUInteger])
+                            .collect();
+                        let arr: NSArray<Id> = NSArray::from_slice(&prefix);
+                        let _: () =
+                            msg_send![*copy, performSelector: category.setter withObject: *arr.p];
+                        category.applied = length;
+                    }
+                }
+                call_with_error(|error| {
+                    let ret: BOOL = msg_send![*copy, validateWithError: error];
+                    from_objc_bool(ret)
+                })
+                .map(drop)
+            });
+            let (culprit, error) = match located {
+                Located::Device {
+                    category,
+                    index,
+                    error,
+                } => {
+                    let category = &categories[category];
+                    let device: Id =
+                        msg_send![*category.devices, objectAtIndex: index as NSUInteger];
+                    let resource = format!("{}[{}]", category.name, index);
+                    let error = VZErrorCtx::new(
+                        "validate virtual machine configuration",
+                        Some(resource),
+                        error,
+                    );
+                    let culprit = Culprit::Device {
+                        category: category.name,
+                        index,
+                        class: class_name(device),
+                    };
+                    (culprit, error)
+                }
+                Located::Base(_) => (Culprit::Base, error),
+                Located::NotReproduced => (Culprit::NotReproduced, error),
+            };
+            ValidationFailure {
+                culprit,
+                error,
+                validations: validations + 1,
             }
         }
     }
 }
 
+/// `setStorageDevices:` for `storageDevices`.
+fn setter_name(getter: &str) -> String {
+    let mut chars = getter.chars();
+    match chars.next() {
+        Some(first) => format!("set{}{}:", first.to_ascii_uppercase(), chars.as_str()),
+        None => String::new(),
+    }
+}
+
+/// # Safety
+/// `obj` must be nil or a valid object.
+unsafe fn responds_to(obj: Id, sel: Sel) -> bool {
+    if obj.is_null() {
+        return false;
+    }
+    let responds: BOOL = msg_send![obj, respondsToSelector: sel];
+    from_objc_bool(responds)
+}
+
+/// The path of `obj`'s `URL` if it has one and it is a file URL.
+///
+/// # Safety
+/// `obj` must be nil or a valid object.
+unsafe fn file_url_path(obj: Id) -> Option<PathBuf> {
+    if !responds_to(obj, sel!(URL)) {
+        return None;
+    }
+    let url: Id = msg_send![obj, URL];
+    if url.is_null() {
+        return None;
+    }
+    let is_file: BOOL = msg_send![url, isFileURL];
+    if !from_objc_bool(is_file) {
+        return None;
+    }
+    let path = NSURL(retained(url)).path();
+    Some(PathBuf::from(path.as_str()))
+}
+
+/// The identity the crate gives each virtual machine it creates, to tell machines apart in log
+/// lines, metrics, timelines and errors. Unique within the process and increasing in creation
+/// order, starting at 1; clones of a machine share it. Displays as `vm-3`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct VmId(u64);
+
+impl VmId {
+    pub(crate) fn next() -> VmId {
+        static NEXT: AtomicU64 = AtomicU64::new(1);
+        VmId(NEXT.fetch_add(1, Ordering::Relaxed))
+    }
+
+    pub fn get(self) -> u64 {
+        self.0
+    }
+}
+
+impl fmt::Display for VmId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "vm-{}", self.0)
+    }
+}
+
+/// `vm-3 (web)` for a labeled machine, `vm-3` otherwise.
+fn display_name(id: VmId, label: Option<&str>) -> String {
+    match label {
+        Some(label) => format!("{} ({})", id, label),
+        None => id.to_string(),
+    }
+}
+
+/// The queue a virtual machine is created on, from a [`DispatchQueue`] given away to the machine,
+/// a `&DispatchQueue` other machines may share, which retains it, or a raw queue, as before. See
+/// [`crate::queue_pool`] for what sharing a queue costs.
+pub struct VmQueue(QueueArg);
+
+enum QueueArg {
+    Wrapped(DispatchQueue),
+    /// Retained when the machine is created.
+    Raw(Id),
+}
+
+impl From<DispatchQueue> for VmQueue {
+    fn from(queue: DispatchQueue) -> Self {
+        VmQueue(QueueArg::Wrapped(queue))
+    }
+}
+
+impl From<&DispatchQueue> for VmQueue {
+    fn from(queue: &DispatchQueue) -> Self {
+        VmQueue(QueueArg::Wrapped(queue.clone()))
+    }
+}
+
+impl From<Id> for VmQueue {
+    /// `queue` must be a dispatch queue or nil; nil fails in strict mode.
+    fn from(queue: Id) -> Self {
+        VmQueue(QueueArg::Raw(queue))
+    }
+}
+
 /// virtual machine
+///
+/// Methods take `&self`: they only send messages to the framework object, which serializes access
+/// on the VM's dispatch queue itself. Share it between callbacks with an `Arc` rather than cloning.
+///
+/// `Debug` prints its [`VmId`], label, framework object and [`Lifecycle`].
+///
+/// # Dropping
+/// Clones share one teardown, run when the last clone is dropped or earlier by
+/// [`VZVirtualMachine::shutdown_sync`]:
+///
+/// 1. On the VM's queue, the crate's state observer is removed and the delegate is unset, so the
+///    framework stops messaging objects that are about to go.
+/// 2. Completion handlers already sent to the framework get up to [`DROP_TIMEOUT`] to run.
+/// 3. The framework object and the queue are released, then the configuration the machine was
+///    created from with its attachments, e.g. serial port file handles, and the EFI variable
+///    store lease.
+///
+/// The fields are declared in that order. When the last clone is dropped on the VM's queue, e.g.
+/// from a completion handler, step 2 is skipped since the handlers could only run after it.
+///
+/// The framework object is only ever released on the VM's queue: dropped on another thread, its
+/// last reference goes to a block queued there, which runs once the blocks before it did. A
+/// machine on the main queue is released when the main thread next runs it.
+#[derive(Clone)]
+pub struct VZVirtualMachine {
+    shutdown: Arc<Shutdown>,
+    id: VmId,
+    label: Option<Arc<str>>,
+    p: VmObject,
+    queue: DispatchQueue,
+    callbacks: CallbackQueue,
+    lifecycle: Arc<LifecycleTracker>,
+    metrics: Arc<VmMetrics>,
+    timeline: Arc<TimelineSlot>,
+    watchdog: Arc<QueueWatchdog>,
+    error_events: ErrorEventSender,
+    /// [`VZVirtualMachineConfiguration::describe`] of the configuration it was created from.
+    configuration: Arc<str>,
+    /// The configuration it was created from, for [`VZVirtualMachine::configuration_copy`].
+    source: Arc<SourceConfiguration>,
+    /// Keeps the EFI variable store registered as in use until the last clone is dropped.
+    _efi_store: Option<Arc<VariableStoreLease>>,
+}
+
+/// How long dropping the last clone of a virtual machine waits for its queue and its completion
+/// handlers in flight; see [dropping](VZVirtualMachine#dropping).
+pub const DROP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Why [`VZVirtualMachine::shutdown_sync`] did not finish in time. The teardown is not retried;
+/// what is left happens as the queue gets to it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ShutdownError {
+    /// The VM's queue did not run the detaching block in time, e.g. the main queue of a process
+    /// whose main thread is blocked. The block stays queued.
+    QueueBlocked,
+    /// This many completion handlers had not run yet.
+    InFlight(usize),
+}
+
+impl fmt::Display for ShutdownError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ShutdownError::QueueBlocked => write!(
+                f,
+                "the virtual machine's queue did not run the shutdown in time"
+            ),
+            ShutdownError::InFlight(n) => {
+                write!(f, "{} completion handlers were still in flight", n)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ShutdownError {}
+
+/// Completion handlers sent to the framework that have not run yet.
+#[derive(Default)]
+struct InFlight {
+    count: Mutex<usize>,
+    changed: Condvar,
+}
+
+impl InFlight {
+    fn lock(&self) -> MutexGuard<'_, usize> {
+        self.count.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Counts a handler until the returned token is dropped.
+    fn begin(self: &Arc<Self>) -> InFlightToken {
+        *self.lock() += 1;
+        InFlightToken(self.clone())
+    }
+
+    /// Waits until no handler is in flight or `deadline` passes, and returns how many are left.
+    fn wait(&self, deadline: Instant) -> usize {
+        let mut count = self.lock();
+        while *count > 0 {
+            let now = Instant::now();
+            if now >= deadline {
+                break;
+            }
+            count = self
+                .changed
+                .wait_timeout(count, deadline - now)
+                .unwrap_or_else(|e| e.into_inner())
+                .0;
+        }
+        *count
+    }
+}
+
+/// Dropped once its completion handler ran, or with the block if the framework never calls it.
+struct InFlightToken(Arc<InFlight>);
+
+impl Drop for InFlightToken {
+    fn drop(&mut self) {
+        *self.0.lock() -= 1;
+        self.0.changed.notify_all();
+    }
+}
+
+/// The teardown the clones of a virtual machine share; see
+/// [dropping](VZVirtualMachine#dropping).
+struct Shutdown {
+    p: VmObject,
+    queue: DispatchQueue,
+    error_events: ErrorEventSender,
+    /// Feeds state changes to the lifecycle tracker, metrics and timeline until the teardown.
+    observation: Mutex<Option<KvoGuard>>,
+    in_flight: Arc<InFlight>,
+    done: AtomicBool,
+}
+
+// The framework object is only messaged from its queue.
+unsafe impl Send for Shutdown {}
+unsafe impl Sync for Shutdown {}
+
+impl Shutdown {
+    /// Runs the teardown once; later calls return `Ok(())` right away.
+    fn run(&self, timeout: Duration) -> Result<(), ShutdownError> {
+        if self.done.swap(true, Ordering::SeqCst) {
+            return Ok(());
+        }
+        let deadline = Instant::now() + timeout;
+        let observation = self
+            .observation
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .take();
+        let p = self.p.clone();
+        let error_events = self.error_events.clone();
+        let detach = move || {
+            if let Some(observation) = observation {
+                observation.remove_now();
+            }
+            unsafe { error_events.uninstall(*p) };
+        };
+        if self.queue.is_current() {
+            detach();
+            // Handlers in flight run on this queue, after the caller returns.
+            return match *self.in_flight.lock() {
+                0 => Ok(()),
+                n => Err(ShutdownError::InFlight(n)),
+            };
+        }
+        let detached = Arc::new(DispatchSemaphore::new(0));
+        let signal = detached.clone();
+        self.queue.exec_async(move || {
+            detach();
+            signal.signal();
+        });
+        if !detached.wait_timeout(deadline.saturating_duration_since(Instant::now())) {
+            return Err(ShutdownError::QueueBlocked);
+        }
+        match self.in_flight.wait(deadline) {
+            0 => Ok(()),
+            n => Err(ShutdownError::InFlight(n)),
+        }
+    }
+}
+
+impl Drop for Shutdown {
+    fn drop(&mut self) {
+        let _ = self.run(DROP_TIMEOUT);
+    }
+}
+
+/// The framework object of a virtual machine, shared by its clones, the teardown and the blocks
+/// sent to its queue. The last reference releases it on the VM's queue, since the framework tears
+/// the object down on the thread of that release and part of that state is confined to the queue.
 #[derive(Clone)]
-pub struct VZVirtualMachine(StrongPtr);
+struct VmObject(Arc<QueueRelease>);
+
+struct QueueRelease {
+    p: Option<StrongPtr>,
+    queue: DispatchQueue,
+}
+
+// The framework object is messaged and released on its queue only.
+unsafe impl Send for QueueRelease {}
+unsafe impl Sync for QueueRelease {}
+
+impl VmObject {
+    fn new(p: StrongPtr, queue: DispatchQueue) -> VmObject {
+        VmObject(Arc::new(QueueRelease { p: Some(p), queue }))
+    }
+}
+
+impl Deref for VmObject {
+    type Target = Id;
+
+    fn deref(&self) -> &Id {
+        self.0.p.as_ref().unwrap()
+    }
+}
+
+impl Drop for QueueRelease {
+    fn drop(&mut self) {
+        let p = self.p.take();
+        if self.queue.is_current() {
+            drop(p);
+        } else {
+            // The block owns the last reference; the queue runs it after the blocks before it.
+            let p = ForQueue(p);
+            self.queue.exec_async(move || drop(p));
+        }
+    }
+}
+
+/// The frozen configuration a virtual machine was created from.
+struct SourceConfiguration(VZVirtualMachineConfiguration);
+
+// Frozen, it is only ever copied, which reads it.
+unsafe impl Send for SourceConfiguration {}
+unsafe impl Sync for SourceConfiguration {}
+
+// The safe methods only message the framework object from its queue; the rest are `unsafe` and
+// leave that to the caller.
+unsafe impl Send for VZVirtualMachine {}
+unsafe impl Sync for VZVirtualMachine {}
+
+impl fmt::Debug for VZVirtualMachine {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("VZVirtualMachine")
+            .field("id", &self.id)
+            .field("label", &self.label)
+            .field("ptr", &*self.p)
+            .field("lifecycle", &self.lifecycle())
+            .finish()
+    }
+}
+
+/// A reference to a virtual machine that does not keep it alive, from
+/// [`VZVirtualMachine::downgrade`]. Once the last clone is dropped, and with it the machine as
+/// described under [dropping](VZVirtualMachine#dropping), [`upgrade`](Self::upgrade) returns
+/// `None`.
+#[derive(Clone)]
+pub struct WeakVirtualMachine {
+    shutdown: Weak<Shutdown>,
+    id: VmId,
+    label: Option<Arc<str>>,
+    callbacks: CallbackQueue,
+    lifecycle: Weak<LifecycleTracker>,
+    metrics: Weak<VmMetrics>,
+    timeline: Weak<TimelineSlot>,
+    watchdog: Weak<QueueWatchdog>,
+    configuration: Arc<str>,
+    source: Weak<SourceConfiguration>,
+    efi_store: Option<Weak<VariableStoreLease>>,
+}
+
+// Only upgraded, which gives a `VZVirtualMachine`.
+unsafe impl Send for WeakVirtualMachine {}
+unsafe impl Sync for WeakVirtualMachine {}
+
+impl WeakVirtualMachine {
+    /// The machine, unless its last clone was dropped.
+    pub fn upgrade(&self) -> Option<VZVirtualMachine> {
+        let shutdown = self.shutdown.upgrade()?;
+        let efi_store = match &self.efi_store {
+            Some(lease) => Some(lease.upgrade()?),
+            None => None,
+        };
+        Some(VZVirtualMachine {
+            id: self.id,
+            label: self.label.clone(),
+            p: shutdown.p.clone(),
+            queue: shutdown.queue.clone(),
+            callbacks: self.callbacks.clone(),
+            lifecycle: self.lifecycle.upgrade()?,
+            metrics: self.metrics.upgrade()?,
+            timeline: self.timeline.upgrade()?,
+            watchdog: self.watchdog.upgrade()?,
+            error_events: shutdown.error_events.clone(),
+            configuration: self.configuration.clone(),
+            source: self.source.upgrade()?,
+            _efi_store: efi_store,
+            shutdown,
+        })
+    }
+
+    pub fn vm_id(&self) -> VmId {
+        self.id
+    }
+}
+
+impl fmt::Debug for WeakVirtualMachine {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("WeakVirtualMachine")
+            .field("id", &self.id)
+            .field("label", &self.label)
+            .field("alive", &(self.shutdown.strong_count() > 0))
+            .finish()
+    }
+}
 
 /// state of virtual machine
-#[derive(Debug)]
+///
+/// New states appear in new macOS releases, so matches need a wildcard arm. A state this crate
+/// does not know yet is reported as `Unknown` with its raw value.
+#[non_exhaustive]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum VZVirtualMachineState {
     /// Initial state before the virtual machine is started.
     VZVirtualMachineStateStopped,
@@ -275,65 +1461,1239 @@ pub enum VZVirtualMachineState {
     /// The virtual machine is being resumed. This is the intermediate state between VZVirtualMachineStatePaused and VZVirtualMachineStateRunning. */
     VZVirtualMachineStateResuming,
 
-    /// Other
-    Other,
+    /// The virtual machine is being stopped. This is the intermediate state between VZVirtualMachineStateRunning and VZVirtualMachineStateStopped. (macOS 12)
+    VZVirtualMachineStateStopping,
+
+    /// The virtual machine is being saved to a file. (macOS 14)
+    VZVirtualMachineStateSaving,
+
+    /// The virtual machine is being restored from a file. (macOS 14)
+    VZVirtualMachineStateRestoring,
+
+    /// A state this crate does not know, with its raw value. Never equal to a known state.
+    Unknown(NSInteger),
+}
+
+/// Raw values from `VZVirtualMachine.h`, with the names used by `Display` and `FromStr`.
+const STATE_TABLE: &[(NSInteger, VZVirtualMachineState, &str)] = &[
+    (0, VZVirtualMachineState::VZVirtualMachineStateStopped, "stopped"),
+    (1, VZVirtualMachineState::VZVirtualMachineStateRunning, "running"),
+    (2, VZVirtualMachineState::VZVirtualMachineStatePaused, "paused"),
+    (3, VZVirtualMachineState::VZVirtualMachineStateError, "error"),
+    (4, VZVirtualMachineState::VZVirtualMachineStateStarting, "starting"),
+    (5, VZVirtualMachineState::VZVirtualMachineStatePausing, "pausing"),
+    (6, VZVirtualMachineState::VZVirtualMachineStateResuming, "resuming"),
+    (7, VZVirtualMachineState::VZVirtualMachineStateStopping, "stopping"),
+    (8, VZVirtualMachineState::VZVirtualMachineStateSaving, "saving"),
+    (9, VZVirtualMachineState::VZVirtualMachineStateRestoring, "restoring"),
+];
+
+impl VZVirtualMachineState {
+    pub fn from_raw(n: NSInteger) -> VZVirtualMachineState {
+        STATE_TABLE
+            .iter()
+            .find(|(raw, _, _)| *raw == n)
+            .map_or(VZVirtualMachineState::Unknown(n), |&(_, state, _)| state)
+    }
+
+    /// The value the framework uses for this state.
+    pub fn raw(&self) -> NSInteger {
+        match *self {
+            VZVirtualMachineState::Unknown(n) => n,
+            state => STATE_TABLE
+                .iter()
+                .find(|(_, known, _)| *known == state)
+                .map(|&(raw, _, _)| raw)
+                .unwrap_or(-1),
+        }
+    }
+}
+
+impl fmt::Display for VZVirtualMachineState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let VZVirtualMachineState::Unknown(n) = *self {
+            return write!(f, "unknown({})", n);
+        }
+        match STATE_TABLE.iter().find(|(_, known, _)| known == self) {
+            Some((_, _, name)) => f.write_str(name),
+            None => write!(f, "unknown({})", self.raw()),
+        }
+    }
+}
+
+/// A string that is not a state name produced by `Display`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseStateError(pub String);
+
+impl fmt::Display for ParseStateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "unknown virtual machine state name {:?}", self.0)
+    }
+}
+
+impl FromStr for VZVirtualMachineState {
+    type Err = ParseStateError;
+
+    /// Accepts the names produced by `Display`, including `unknown(N)`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(&(_, state, _)) = STATE_TABLE.iter().find(|(_, _, name)| *name == s) {
+            return Ok(state);
+        }
+        s.strip_prefix("unknown(")
+            .and_then(|rest| rest.strip_suffix(')'))
+            .and_then(|n| n.parse::<NSInteger>().ok())
+            .map(VZVirtualMachineState::from_raw)
+            .ok_or_else(|| ParseStateError(s.to_string()))
+    }
+}
+
+/// How [`VZVirtualMachine::start_with_mode`] starts a machine.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum StartMode {
+    /// As [`VZVirtualMachine::start`] does.
+    Normal,
+    /// A macOS guest into macOS Recovery (macOS 13+).
+    MacRecovery,
+    /// A macOS guest into DFU mode, to restore its firmware (macOS 14+).
+    MacDFU,
+}
+
+/// Boot loader class of the guests the `Mac*` start modes apply to.
+const MAC_BOOT_LOADER: &str = "VZMacOSBootLoader";
+
+/// Class of the start options the `Mac*` start modes set.
+const MAC_START_OPTIONS: &str = "VZMacOSVirtualMachineStartOptions";
+
+impl StartMode {
+    /// The setter of `VZMacOSVirtualMachineStartOptions` the mode turns on, and the macOS
+    /// release that has it.
+    fn option(self) -> Option<(&'static str, &'static str)> {
+        match self {
+            StartMode::Normal => None,
+            StartMode::MacRecovery => Some(("setStartUpFromMacOSRecovery:", "macOS 13")),
+            StartMode::MacDFU => Some(("setForceDFU:", "macOS 14")),
+        }
+    }
+}
+
+impl fmt::Display for StartMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            StartMode::Normal => "normal start",
+            StartMode::MacRecovery => "start into macOS Recovery",
+            StartMode::MacDFU => "start into DFU mode",
+        })
+    }
+}
+
+/// Why [`VZVirtualMachine::start_with_mode`] sent nothing to the framework. The completion
+/// closure is dropped without being called.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StartModeError {
+    /// The mode only applies to macOS guests, and the machine boots with another boot loader:
+    /// its class, or `-` without one.
+    WrongGuest {
+        mode: StartMode,
+        boot_loader: String,
+    },
+    /// This host's framework lacks the start option the mode needs.
+    Unavailable {
+        mode: StartMode,
+        option: &'static str,
+        requirement: &'static str,
+    },
+    Lifecycle(LifecycleError),
+}
+
+impl fmt::Display for StartModeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StartModeError::WrongGuest { mode, boot_loader } => write!(
+                f,
+                "{} needs a macOS guest booted by {}, this machine boots with {}",
+                mode, MAC_BOOT_LOADER, boot_loader
+            ),
+            StartModeError::Unavailable {
+                mode,
+                option,
+                requirement,
+            } => write!(
+                f,
+                "{} needs {} of {}, which this host lacks before {}",
+                mode, option, MAC_START_OPTIONS, requirement
+            ),
+            StartModeError::Lifecycle(error) => error.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for StartModeError {}
+
+impl From<LifecycleError> for StartModeError {
+    fn from(error: LifecycleError) -> Self {
+        StartModeError::Lifecycle(error)
+    }
+}
+
+/// The start options for `mode`, which turns on `setter`, or why this host cannot have them.
+fn mac_start_options(
+    mode: StartMode,
+    setter: &'static str,
+    requirement: &'static str,
+) -> Result<StrongPtr, StartModeError> {
+    let unavailable = StartModeError::Unavailable {
+        mode,
+        option: setter,
+        requirement,
+    };
+    if !features::HostCapabilities::detect().supports_class(MAC_START_OPTIONS) {
+        return Err(unavailable);
+    }
+    unsafe {
+        let options = owned(msg_send![vz_class!(VZMacOSVirtualMachineStartOptions), new]);
+        let sel = Sel::register(setter);
+        if !responds_to(*options, sel) {
+            return Err(unavailable);
+        }
+        match mode {
+            StartMode::MacRecovery => {
+                let _: () = msg_send![*options, setStartUpFromMacOSRecovery: to_objc_bool(true)];
+            }
+            StartMode::MacDFU => {
+                let _: () = msg_send![*options, setForceDFU: to_objc_bool(true)];
+            }
+            StartMode::Normal => {}
+        }
+        Ok(options)
+    }
+}
+
+/// How [`VZVirtualMachine::start_with_deadline`] ended.
+pub enum StartOutcome {
+    /// The start was accepted and the machine reached the running state.
+    Started,
+    FailedToStart(VZError),
+    /// The start was cancelled.
+    Cancelled,
+    /// The machine was not running in time and has been force stopped. `last_state` is the last
+    /// state seen before the deadline.
+    DeadlineExceeded {
+        last_state: VZVirtualMachineState,
+    },
+}
+
+impl StartOutcome {
+    /// Unless the machine started or the start was cancelled, collects diagnostics about `vm`
+    /// with the last [`FAILURE_WINDOW`] of the framework's log, records what the start failed
+    /// with, and writes the report to `dir` with [`DiagnosticsReport::write_bundle`].
+    ///
+    /// Blocks while `log show` runs, a few seconds.
+    ///
+    /// [`FAILURE_WINDOW`]: diagnostics::FAILURE_WINDOW
+    pub fn on_failure_collect_diagnostics<P: AsRef<Path>>(
+        &self,
+        vm: &VZVirtualMachine,
+        dir: P,
+    ) -> io::Result<Option<DiagnosticsReport>> {
+        let failure = match self {
+            StartOutcome::Started | StartOutcome::Cancelled => return Ok(None),
+            StartOutcome::FailedToStart(e) => e.ns_error().to_string(),
+            StartOutcome::DeadlineExceeded { last_state } => {
+                format!("not running by the deadline, last state {}", last_state)
+            }
+        };
+        let mut report = diagnostics::collect(vm, diagnostics::FAILURE_WINDOW);
+        report.failure = Some(failure);
+        report.write_bundle(dir)?;
+        Ok(Some(report))
+    }
+}
+
+/// Inputs to the start race, from whichever queue they arrive on.
+enum StartEvent {
+    Outcome(CompletionOutcome),
+    State(VZVirtualMachineState),
+    Deadline,
+}
+
+/// The single owner of a start race: the first event that settles it wins and later ones are
+/// ignored, e.g. a completion handler firing after the deadline.
+enum StartPhase {
+    Waiting {
+        accepted: bool,
+        last_state: VZVirtualMachineState,
+    },
+    Settled,
+}
+
+impl StartPhase {
+    fn on(&mut self, event: StartEvent) -> Option<StartOutcome> {
+        let (accepted, last_state) = match self {
+            StartPhase::Waiting {
+                accepted,
+                last_state,
+            } => (accepted, last_state),
+            StartPhase::Settled => return None,
+        };
+        let outcome = match event {
+            StartEvent::Outcome(CompletionOutcome::Success(())) => {
+                *accepted = true;
+                running(*last_state)
+            }
+            StartEvent::Outcome(CompletionOutcome::Cancelled) => Some(StartOutcome::Cancelled),
+            StartEvent::Outcome(CompletionOutcome::Failed(e)) => {
+                Some(StartOutcome::FailedToStart(e))
+            }
+            StartEvent::State(state) => {
+                *last_state = state;
+                if *accepted {
+                    running(state)
+                } else {
+                    None
+                }
+            }
+            StartEvent::Deadline => Some(StartOutcome::DeadlineExceeded {
+                last_state: *last_state,
+            }),
+        };
+        if outcome.is_some() {
+            *self = StartPhase::Settled;
+        }
+        outcome
+    }
+}
+
+fn running(state: VZVirtualMachineState) -> Option<StartOutcome> {
+    if state == VZVirtualMachineState::VZVirtualMachineStateRunning {
+        Some(StartOutcome::Started)
+    } else {
+        None
+    }
+}
+
+struct StartRaceInner {
+    phase: StartPhase,
+    completion: Option<Box<dyn FnOnce(StartOutcome) + Send>>,
+    observation: Option<KvoGuard>,
+    timer: Option<CancellationToken>,
+}
+
+struct StartRace {
+    vm: VZVirtualMachine,
+    inner: Mutex<StartRaceInner>,
+}
+
+impl StartRace {
+    fn lock(&self) -> MutexGuard<'_, StartRaceInner> {
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Settles the race without reporting, for a start that was never sent.
+    fn abandon(&self) {
+        let (observation, timer) = {
+            let mut inner = self.lock();
+            inner.phase = StartPhase::Settled;
+            inner.completion = None;
+            (inner.observation.take(), inner.timer.take())
+        };
+        if let Some(timer) = timer {
+            timer.cancel();
+        }
+        drop(observation);
+    }
+
+    /// Feeds `event` to the state machine and, if it settles the race, releases the observation
+    /// and timer and reports. Returns whether the race is still open.
+    fn on(&self, event: StartEvent) -> bool {
+        let (outcome, completion, observation, timer) = {
+            let mut inner = self.lock();
+            match inner.phase.on(event) {
+                Some(outcome) => (
+                    outcome,
+                    inner.completion.take(),
+                    inner.observation.take(),
+                    inner.timer.take(),
+                ),
+                None => return !matches!(inner.phase, StartPhase::Settled),
+            }
+        };
+        if let Some(timer) = timer {
+            timer.cancel();
+        }
+        drop(observation);
+        let completion = match completion {
+            Some(completion) => completion,
+            None => return false,
+        };
+        let callbacks = self.vm.callbacks.clone();
+        if let StartOutcome::DeadlineExceeded { .. } = outcome {
+            // Report once the machine is down, whether or not the stop itself succeeded.
+            self.vm
+                .stop_or_join(move |_| callbacks.deliver(move || completion(outcome)));
+        } else {
+            callbacks.deliver(move || completion(outcome));
+        }
+        false
+    }
 }
 
 impl VZVirtualMachine {
-    pub fn new(conf: VZVirtualMachineConfiguration, queue: Id) -> VZVirtualMachine {
+    /// Creates the virtual machine on `queue`, which may be shared with other machines: a
+    /// [`DispatchQueue`], a `&DispatchQueue` or a raw queue; see [`VmQueue`].
+    pub fn new<Q: Into<VmQueue>>(
+        conf: VZVirtualMachineConfiguration,
+        queue: Q,
+    ) -> VZVirtualMachine {
+        VZVirtualMachine::new_on(conf, queue.into(), None)
+    }
+
+    /// Like [`VZVirtualMachine::new`], with `label` naming the machine next to its [`VmId`] in
+    /// log lines, timelines and errors, e.g. `vm-3 (web)`.
+    pub fn new_labeled<Q: Into<VmQueue>>(
+        conf: VZVirtualMachineConfiguration,
+        queue: Q,
+        label: &str,
+    ) -> VZVirtualMachine {
+        VZVirtualMachine::new_on(conf, queue.into(), Some(label))
+    }
+
+    fn new_on(
+        conf: VZVirtualMachineConfiguration,
+        VmQueue(queue): VmQueue,
+        label: Option<&str>,
+    ) -> VZVirtualMachine {
+        let queue = match queue {
+            QueueArg::Wrapped(queue) => queue,
+            QueueArg::Raw(queue) => unsafe { DispatchQueue::from_raw(queue) },
+        };
+        strict::non_nil(queue.id(), "the queue passed to VZVirtualMachine::new");
         unsafe {
-            let i: Id = msg_send![class!(VZVirtualMachine), alloc];
-            let p = StrongPtr::new(msg_send![i, initWithConfiguration:*conf.0 queue:queue]);
-            VZVirtualMachine(p)
+            conf.freeze();
+            let i = alloc(vz_class!(VZVirtualMachine));
+            let p = owned(msg_send![i, initWithConfiguration:*conf.p queue:queue.id()]);
+            VZVirtualMachine::from_parts(
+                VmObject::new(p, queue.clone()),
+                queue,
+                VariableStoreLease::for_boot_loader(msg_send![*conf.p, bootLoader]),
+                label,
+                conf,
+            )
         }
     }
 
     pub fn new_without_queue(conf: VZVirtualMachineConfiguration) -> VZVirtualMachine {
         unsafe {
-            let i: Id = msg_send![class!(VZVirtualMachine), alloc];
-            let p = StrongPtr::new(msg_send![i, initWithConfiguration:*conf.0]);
-            VZVirtualMachine(p)
+            conf.freeze();
+            let i = alloc(vz_class!(VZVirtualMachine));
+            let p = owned(msg_send![i, initWithConfiguration:*conf.p]);
+            VZVirtualMachine::from_parts(
+                VmObject::new(p, DispatchQueue::main()),
+                DispatchQueue::main(),
+                VariableStoreLease::for_boot_loader(msg_send![*conf.p, bootLoader]),
+                None,
+                conf,
+            )
+        }
+    }
+
+    fn from_parts(
+        p: VmObject,
+        queue: DispatchQueue,
+        efi_store: Option<Arc<VariableStoreLease>>,
+        label: Option<&str>,
+        source: VZVirtualMachineConfiguration,
+    ) -> VZVirtualMachine {
+        let id = VmId::next();
+        let label: Option<Arc<str>> = label.map(Arc::from);
+        let name = display_name(id, label.as_deref());
+        let lifecycle = Arc::new(LifecycleTracker::new());
+        let tracker = lifecycle.clone();
+        let metrics = Arc::new(VmMetrics::new(id));
+        let vm_metrics = metrics.clone();
+        let timeline = Arc::new(TimelineSlot::new(id, name.clone()));
+        let vm_timeline = timeline.clone();
+        let observation = unsafe {
+            kvo::observe_on(*p, "state", &queue, move |state: NSInteger| {
+                let state = VZVirtualMachineState::from_raw(state);
+                tracker.observe(state);
+                vm_metrics.observe(state);
+                vm_timeline.record(TimelineEventKind::StateChanged { state });
+                true
+            })
+        };
+        let error_events =
+            unsafe { ErrorEventSender::install(*p, &queue, lifecycle.clone(), timeline.clone()) };
+        let shutdown = Arc::new(Shutdown {
+            p: p.clone(),
+            queue: queue.clone(),
+            error_events: error_events.clone(),
+            observation: Mutex::new(Some(observation)),
+            in_flight: Arc::new(InFlight::default()),
+            done: AtomicBool::new(false),
+        });
+        VZVirtualMachine {
+            shutdown,
+            id,
+            label,
+            p,
+            queue,
+            callbacks: CallbackQueue::Framework,
+            lifecycle,
+            metrics,
+            timeline,
+            watchdog: Arc::new(QueueWatchdog::new(name)),
+            error_events,
+            configuration: Arc::from(source.describe()),
+            source: Arc::new(SourceConfiguration(source)),
+            _efi_store: efi_store,
+        }
+    }
+
+    /// Creates the virtual machine on a new serial queue named `label`, running at `qos` or the
+    /// default QoS, e.g. `UserInitiated` for an interactive machine and `Utility` for batch ones.
+    /// `label` also labels the machine, as with [`VZVirtualMachine::new_labeled`].
+    pub fn new_with_qos(
+        conf: VZVirtualMachineConfiguration,
+        label: &str,
+        qos: Option<QoSClass>,
+    ) -> VZVirtualMachine {
+        let queue = match qos {
+            Some(qos) => DispatchQueue::new_with_qos(label, qos),
+            None => DispatchQueue::new(label),
+        };
+        VZVirtualMachine::new_labeled(conf, queue, label)
+    }
+
+    /// A reference to this machine that does not keep it alive, e.g. for a registry that should
+    /// forget machines nobody else holds.
+    pub fn downgrade(&self) -> WeakVirtualMachine {
+        WeakVirtualMachine {
+            shutdown: Arc::downgrade(&self.shutdown),
+            id: self.id,
+            label: self.label.clone(),
+            callbacks: self.callbacks.clone(),
+            lifecycle: Arc::downgrade(&self.lifecycle),
+            metrics: Arc::downgrade(&self.metrics),
+            timeline: Arc::downgrade(&self.timeline),
+            watchdog: Arc::downgrade(&self.watchdog),
+            configuration: self.configuration.clone(),
+            source: Arc::downgrade(&self.source),
+            efi_store: self._efi_store.as_ref().map(Arc::downgrade),
+        }
+    }
+
+    /// The identity the crate gave this machine, shared by its clones. Not to be confused with
+    /// [`VZVirtualMachine::id`], the framework object.
+    pub fn vm_id(&self) -> VmId {
+        self.id
+    }
+
+    /// The label given with [`VZVirtualMachine::new_labeled`] or
+    /// [`VZVirtualMachine::new_with_qos`].
+    pub fn label(&self) -> Option<&str> {
+        self.label.as_deref()
+    }
+
+    /// [`VZVirtualMachineConfiguration::describe`] of the configuration the machine was created
+    /// from.
+    pub fn describe_configuration(&self) -> &str {
+        &self.configuration
+    }
+
+    /// An unfrozen copy of the configuration the machine was created from, to create an identical
+    /// machine from, e.g. after this one crashed; see [`VZVirtualMachineConfiguration::copy`] for
+    /// what the copy shares. The framework keeps its own copy of the configuration, so this one
+    /// may be used while the machine runs or after it stopped.
+    pub fn configuration_copy(&self) -> VZVirtualMachineConfiguration {
+        self.source.0.copy()
+    }
+
+    /// Runs the teardown the last clone would run on drop now, waiting up to `timeout` for the
+    /// VM's queue and the completion handlers in flight; see
+    /// [dropping](VZVirtualMachine#dropping). Only the first call of all clones tears down;
+    /// later calls and the final drop return right away.
+    ///
+    /// Afterwards the machine no longer tracks its state or reports error events, so stop it
+    /// first. The framework object and the queue are still released with the last clone.
+    pub fn shutdown_sync(&self, timeout: Duration) -> Result<(), ShutdownError> {
+        self.shutdown.run(timeout)
+    }
+
+    /// The id and label as log lines and errors name the machine, e.g. `vm-3 (web)` or `vm-4`.
+    pub fn display_name(&self) -> String {
+        display_name(self.id, self.label())
+    }
+
+    /// A handle to the same virtual machine whose completion closures, including those of the
+    /// socket devices it hands out, run on `queue` instead of the VM's queue.
+    pub fn on_queue(&self, queue: &DispatchQueue) -> VZVirtualMachine {
+        VZVirtualMachine {
+            callbacks: CallbackQueue::Queue(queue.clone()),
+            ..self.clone()
         }
     }
 
-    pub fn start_with_completion_handler(&mut self, completion_handler: &Block<(Id,), ()>) {
+    /// Sends `startWithCompletionHandler:` as is. Unlike [`VZVirtualMachine::start`] this is not
+    /// guarded against a start in flight and only shows up in [`VZVirtualMachine::lifecycle`]
+    /// once the state changes; it must be called on the VM's queue.
+    pub fn start_with_completion_handler(&self, completion_handler: &Block<(Id,), ()>) {
+        strict::on_vm_queue(
+            &self.queue,
+            "VZVirtualMachine::start_with_completion_handler",
+        );
         unsafe {
-            let _: Id = msg_send![*self.0, startWithCompletionHandler: completion_handler];
+            let _: () = msg_send![*self.p, startWithCompletionHandler: completion_handler];
         }
     }
 
-    pub unsafe fn request_stop_with_error(&mut self) -> Result<bool, NSError> {
-        let error = NSError(StrongPtr::new(0 as Id));
-        let ret: BOOL = msg_send![*self.0, requestStopWithError:*error.0];
-        if error.code() != 0 {
-            Err(error)
-        } else {
-            Ok(ret)
+    /// Starts the virtual machine. The call is dispatched onto the VM's queue and
+    /// `completion_handler` runs there, or on the queue chosen with [`VZVirtualMachine::on_queue`].
+    ///
+    /// Fails with [`LifecycleError::AlreadyStarted`] while a start is in flight or the machine is
+    /// running, and with [`LifecycleError::StopInProgress`] during a stop; nothing is sent to the
+    /// framework then and `completion_handler` is dropped.
+    pub fn start<F: FnOnce(CompletionOutcome) + Send + 'static>(
+        &self,
+        completion_handler: F,
+    ) -> Result<(), LifecycleError> {
+        self.send_tracked(Op::Start, false, completion_handler, |vm, block| unsafe {
+            let _: () = msg_send![vm, startWithCompletionHandler: block];
+        })
+    }
+
+    /// Like [`VZVirtualMachine::start`], but a start in flight is joined instead of refused:
+    /// `completion_handler` gets the same outcome as the call that sent it. If the machine is
+    /// running since the last start, it gets that start's outcome right away.
+    pub fn start_or_join<F: FnOnce(CompletionOutcome) + Send + 'static>(
+        &self,
+        completion_handler: F,
+    ) -> Result<(), LifecycleError> {
+        self.send_tracked(Op::Start, true, completion_handler, |vm, block| unsafe {
+            let _: () = msg_send![vm, startWithCompletionHandler: block];
+        })
+    }
+
+    /// Starts the virtual machine the way `mode` says. [`StartMode::Normal`] is
+    /// [`VZVirtualMachine::start`]; the `Mac*` modes send `startWithOptions:completionHandler:`
+    /// with the matching `VZMacOSVirtualMachineStartOptions`.
+    ///
+    /// Fails without touching the framework if a `Mac*` mode is asked of a machine that does not
+    /// boot with `VZMacOSBootLoader`, if the host's framework lacks the option the mode needs,
+    /// or as [`VZVirtualMachine::start`] does.
+    pub fn start_with_mode<F: FnOnce(CompletionOutcome) + Send + 'static>(
+        &self,
+        mode: StartMode,
+        completion_handler: F,
+    ) -> Result<(), StartModeError> {
+        let (setter, requirement) = match mode.option() {
+            Some(option) => option,
+            None => return Ok(self.start(completion_handler)?),
+        };
+        let boot_loader = self.boot_loader_class();
+        if boot_loader != MAC_BOOT_LOADER {
+            return Err(StartModeError::WrongGuest {
+                mode,
+                boot_loader: boot_loader.to_string(),
+            });
+        }
+        let options = ForQueue(mac_start_options(mode, setter, requirement)?);
+        self.send_tracked(
+            Op::Start,
+            false,
+            completion_handler,
+            move |vm, block| unsafe {
+                let options = options.0;
+                let _: () = msg_send![vm, startWithOptions:*options completionHandler:block];
+            },
+        )?;
+        Ok(())
+    }
+
+    /// Class of the boot loader the machine was created with, as
+    /// [`VZVirtualMachine::describe_configuration`] names it: `-` without one.
+    fn boot_loader_class(&self) -> &str {
+        self.configuration
+            .lines()
+            .find_map(|line| line.strip_prefix("boot_loader "))
+            .unwrap_or("-")
+    }
+
+    /// Starts the virtual machine and reports [`StartOutcome::Started`] only once the start was
+    /// accepted and the machine reached the running state, both within `deadline`. Otherwise the
+    /// machine is force stopped with [`VZVirtualMachine::stop`] before `completion` gets
+    /// [`StartOutcome::DeadlineExceeded`].
+    ///
+    /// `completion` runs once, on the VM's queue or the one chosen with
+    /// [`VZVirtualMachine::on_queue`]. Events arriving after the outcome is decided are ignored.
+    ///
+    /// Fails as [`VZVirtualMachine::start`] does, without calling `completion`.
+    pub fn start_with_deadline<F>(
+        &self,
+        deadline: Duration,
+        completion: F,
+    ) -> Result<(), LifecycleError>
+    where
+        F: FnOnce(StartOutcome) + Send + 'static,
+    {
+        let race = Arc::new(StartRace {
+            vm: self.clone(),
+            inner: Mutex::new(StartRaceInner {
+                phase: StartPhase::Waiting {
+                    accepted: false,
+                    last_state: VZVirtualMachineState::VZVirtualMachineStateStopped,
+                },
+                completion: Some(Box::new(
+                    self.watchdog.wrap("start_with_deadline", completion),
+                )),
+                observation: None,
+                timer: None,
+            }),
+        });
+
+        let observer = race.clone();
+        let observation = unsafe {
+            kvo::observe_on(*self.p, "state", &self.queue, move |state: NSInteger| {
+                let state = VZVirtualMachineState::from_raw(state);
+                observer.on(StartEvent::State(state))
+            })
+        };
+        let timer_race = race.clone();
+        let timer = self.queue.after(deadline, move || {
+            timer_race.on(StartEvent::Deadline);
+        });
+        {
+            let mut inner = race.lock();
+            if let StartPhase::Settled = inner.phase {
+                // A zero deadline can fire before this point; keeping them would leak the race.
+                timer.cancel();
+                drop(observation);
+            } else {
+                inner.observation = Some(observation);
+                inner.timer = Some(timer);
+            }
+        }
+
+        let starter = race.clone();
+        let result = self.start(move |outcome| {
+            starter.on(StartEvent::Outcome(outcome));
+        });
+        if result.is_err() {
+            race.abandon();
+        }
+        result
+    }
+
+    /// Stops the virtual machine without giving the guest a chance to shut down (macOS 12+).
+    ///
+    /// Fails with [`LifecycleError::AlreadyStopping`] while a stop is in flight; nothing is sent
+    /// to the framework then and `completion_handler` is dropped.
+    pub fn stop<F: FnOnce(CompletionOutcome) + Send + 'static>(
+        &self,
+        completion_handler: F,
+    ) -> Result<(), LifecycleError> {
+        self.require_stop("VZVirtualMachine::stop");
+        self.send_tracked(Op::Stop, false, completion_handler, |vm, block| unsafe {
+            let _: () = msg_send![vm, stopWithCompletionHandler: block];
+        })
+    }
+
+    /// Like [`VZVirtualMachine::stop`], but a stop in flight is joined instead of refused. If the
+    /// machine is stopped since the last stop, `completion_handler` gets that stop's outcome right
+    /// away.
+    pub fn stop_or_join<F: FnOnce(CompletionOutcome) + Send + 'static>(
+        &self,
+        completion_handler: F,
+    ) {
+        self.require_stop("VZVirtualMachine::stop_or_join");
+        // Joining never refuses a stop.
+        let _ = self.send_tracked(Op::Stop, true, completion_handler, |vm, block| unsafe {
+            let _: () = msg_send![vm, stopWithCompletionHandler: block];
+        });
+    }
+
+    #[track_caller]
+    fn require_stop(&self, call: &str) {
+        strict::available(*self.p, sel!(stopWithCompletionHandler:), call, "macOS 12");
+    }
+
+    /// Where the machine is in its life, as far as the safe wrappers know.
+    pub fn lifecycle(&self) -> Lifecycle {
+        self.lifecycle.lifecycle()
+    }
+
+    /// The errors of this machine from now on, in one stream: failed starts and stops sent
+    /// through the safe wrappers, the error the machine stopped with at runtime, and a terminal
+    /// event once it is down, after which the stream ends. Each call returns a new receiver, fed
+    /// by every clone of the machine. See [`crate::virtualization::error_events`].
+    pub fn error_events(&self) -> ErrorEventReceiver {
+        self.error_events.subscribe()
+    }
+
+    /// The sender feeding [`VZVirtualMachine::error_events`], for tests pushing synthetic events.
+    #[doc(hidden)]
+    pub fn error_event_sender(&self) -> ErrorEventSender {
+        self.error_events.clone()
+    }
+
+    /// Why the last run ended: the guest shut down on its own or when asked, the machine was
+    /// stopped, or it crashed. `None` until a run ended. When several of these overlap, e.g. a
+    /// forced stop after the guest did not answer a request in time, the precedence described in
+    /// [`crate::virtualization::lifecycle`] picks one.
+    pub fn last_stop_reason(&self) -> Option<StopReason> {
+        self.lifecycle.last_stop_reason()
+    }
+
+    /// Reports `signal` as if the wrappers or the delegate had, for tests scripting how a stop
+    /// unfolds. A signal that settles the stop is recorded and ends the error event streams, as
+    /// a real one would.
+    #[doc(hidden)]
+    pub fn stop_signal(&self, signal: StopSignal) {
+        self.error_events.note_stop(signal);
+    }
+
+    pub fn pause<F: FnOnce(CompletionOutcome) + Send + 'static>(&self, completion_handler: F) {
+        self.send_with_completion("pause", completion_handler, |vm, block| unsafe {
+            let _: () = msg_send![vm, pauseWithCompletionHandler: block];
+        });
+    }
+
+    pub fn resume<F: FnOnce(CompletionOutcome) + Send + 'static>(&self, completion_handler: F) {
+        self.send_with_completion("resume", completion_handler, |vm, block| unsafe {
+            let _: () = msg_send![vm, resumeWithCompletionHandler: block];
+        });
+    }
+
+    /// Saves the state of a paused virtual machine to `path` (macOS 14+).
+    ///
+    /// Fails without calling `completion_handler` if `path` cannot be a file URL, e.g. because
+    /// it is empty.
+    pub fn save_machine_state_to<F: FnOnce(CompletionOutcome) + Send + 'static>(
+        &self,
+        path: &str,
+        completion_handler: F,
+    ) -> Result<(), InvalidInput> {
+        let url = NSURL::file_url_with_path(path, false)
+            .ok_or_else(|| InvalidInput::new("machine state path", path))?;
+        strict::available(
+            *self.p,
+            sel!(saveMachineStateToURL:completionHandler:),
+            "VZVirtualMachine::save_machine_state_to",
+            "macOS 14",
+        );
+        self.send_with_completion(
+            "save_machine_state_to",
+            completion_handler,
+            move |vm, block| unsafe {
+                let _: () = msg_send![vm, saveMachineStateToURL:*url.0 completionHandler:block];
+            },
+        );
+        Ok(())
+    }
+
+    /// Restores a stopped virtual machine from a state saved at `path` (macOS 14+).
+    ///
+    /// Fails without calling `completion_handler` if `path` cannot be a file URL.
+    pub fn restore_machine_state_from<F: FnOnce(CompletionOutcome) + Send + 'static>(
+        &self,
+        path: &str,
+        completion_handler: F,
+    ) -> Result<(), InvalidInput> {
+        let url = NSURL::file_url_with_path(path, false)
+            .ok_or_else(|| InvalidInput::new("machine state path", path))?;
+        strict::available(
+            *self.p,
+            sel!(restoreMachineStateFromURL:completionHandler:),
+            "VZVirtualMachine::restore_machine_state_from",
+            "macOS 14",
+        );
+        self.send_with_completion(
+            "restore_machine_state_from",
+            completion_handler,
+            move |vm, block| unsafe {
+                let _: () =
+                    msg_send![vm, restoreMachineStateFromURL:*url.0 completionHandler:block];
+            },
+        );
+        Ok(())
+    }
+
+    fn send_with_completion<F, S>(&self, name: &'static str, completion_handler: F, send: S)
+    where
+        F: FnOnce(CompletionOutcome) + Send + 'static,
+        S: FnOnce(Id, &Block<(Id,), ()>) + Send + 'static,
+    {
+        let callbacks = self.callbacks.clone();
+        let completion_handler = self.watchdog.wrap(name, completion_handler);
+        let timer = self.metrics.timer(name);
+        let timeline = self.record_requested(name);
+        self.send(
+            move |_, outcome| {
+                timer.finish(&outcome);
+                timeline.record_completed(name, &outcome);
+                callbacks.deliver(move || completion_handler(outcome))
+            },
+            send,
+        );
+    }
+
+    /// Sends `op` unless the lifecycle tracker refuses it or joins it to one in flight.
+    fn send_tracked<F, S>(
+        &self,
+        op: Op,
+        join: bool,
+        completion_handler: F,
+        send: S,
+    ) -> Result<(), LifecycleError>
+    where
+        F: FnOnce(CompletionOutcome) + Send + 'static,
+        S: FnOnce(Id, &Block<(Id,), ()>) + Send + 'static,
+    {
+        let callbacks = self.callbacks.clone();
+        let name = match op {
+            Op::Start => "start",
+            Op::Stop => "stop",
+        };
+        let completion_handler = self.watchdog.wrap(name, completion_handler);
+        let waiter =
+            Box::new(move |outcome| callbacks.deliver(move || completion_handler(outcome)));
+        match self.lifecycle.request(op, join, waiter) {
+            Ok(true) => {}
+            Ok(false) => return Ok(()),
+            Err(error) => {
+                strict::refused(name, &self.display_name(), &error);
+                return Err(error);
+            }
         }
+        let timer = self.metrics.timer(name);
+        match op {
+            Op::Start => self.metrics.start_called(Instant::now()),
+            Op::Stop => self.metrics.forced_stop(),
+        }
+        let timeline = self.record_requested(name);
+        let tracker = self.lifecycle.clone();
+        let error_events = self.error_events.clone();
+        self.send(
+            move |vm, outcome| {
+                timer.finish(&outcome);
+                timeline.record_completed(name, &outcome);
+                match (op, &outcome) {
+                    (Op::Start, CompletionOutcome::Failed(e)) => {
+                        error_events.error(Phase::Start, e.clone())
+                    }
+                    (Op::Stop, CompletionOutcome::Failed(e)) => {
+                        error_events.error(Phase::Stop, e.clone())
+                    }
+                    _ => {}
+                }
+                if op == Op::Stop {
+                    error_events.note_stop(StopSignal::ForceCompleted {
+                        stopped: matches!(outcome, CompletionOutcome::Success(())),
+                    });
+                }
+                let state = unsafe { VZVirtualMachineState::from_raw(msg_send![vm, state]) };
+                tracker.complete(op, outcome, state);
+            },
+            send,
+        );
+        Ok(())
+    }
+
+    /// Records that `operation` is being sent, and returns the slot to record its completion in.
+    fn record_requested(&self, operation: &'static str) -> Arc<TimelineSlot> {
+        self.timeline
+            .record(TimelineEventKind::Requested { operation });
+        self.timeline.clone()
+    }
+
+    /// Sends a message taking a completion handler on the VM's queue. `on_complete` runs on the
+    /// queue the framework calls back on, with the virtual machine.
+    fn send<C, S>(&self, on_complete: C, send: S)
+    where
+        C: FnOnce(Id, CompletionOutcome) + Send + 'static,
+        S: FnOnce(Id, &Block<(Id,), ()>) + Send + 'static,
+    {
+        strict::non_nil(*self.p, "VZVirtualMachine");
+        let p = self.p.clone();
+        let token = self.shutdown.in_flight.begin();
+        self.queue.exec_async(move || {
+            let on_complete = Cell::new(Some(on_complete));
+            let token = Cell::new(Some(token));
+            let vm = p.clone();
+            let block = ConcreteBlock::new(move |error: Id| {
+                // Retains the error, so it survives a hop to another queue.
+                let outcome = unsafe { CompletionOutcome::from_error(error) };
+                if let Some(f) = on_complete.take() {
+                    f(*vm, outcome);
+                }
+                drop(token.take());
+            });
+            let block = block.copy();
+            send(*p, &block);
+        });
     }
 
+    /// Asks the guest to shut down. Once the guest accepted, its stop counts as
+    /// [`StopReason::HostRequested`] unless the machine is forced down or crashes first.
+    ///
+    /// # Safety
+    /// Must be called on the VM's queue, e.g. in a closure passed to `vm.queue().exec_sync`.
+    pub unsafe fn request_stop_with_error(&self) -> Result<bool, VZErrorCtx> {
+        strict::on_vm_queue(&self.queue, "VZVirtualMachine::request_stop_with_error");
+        let result = call_with_error(|error| {
+            let ret: BOOL = msg_send![*self.p, requestStopWithError: error];
+            from_objc_bool(ret)
+        });
+        if result.is_ok() {
+            self.error_events.note_stop(StopSignal::Requested);
+        }
+        result.ctx("request guest stop of", self.display_name())
+    }
+
+    /// `false` without the framework too; see [`features::is_framework_available`].
     pub fn supported() -> bool {
+        if !features::is_framework_available() {
+            return false;
+        }
         unsafe {
-            let b: BOOL = msg_send![class!(VZVirtualMachine), isSupported];
-            b == YES
+            let b: BOOL = msg_send![vz_class!(VZVirtualMachine), isSupported];
+            from_objc_bool(b)
         }
     }
 
+    /// # Safety
+    /// Must be called on the VM's queue, as for
+    /// [`request_stop_with_error`](VZVirtualMachine::request_stop_with_error).
     pub unsafe fn state(&self) -> VZVirtualMachineState {
-        let n: isize = msg_send![*self.0, state];
-        match n {
-            0 => VZVirtualMachineState::VZVirtualMachineStateStopped,
-            1 => VZVirtualMachineState::VZVirtualMachineStateRunning,
-            2 => VZVirtualMachineState::VZVirtualMachineStatePaused,
-            3 => VZVirtualMachineState::VZVirtualMachineStateError,
-            4 => VZVirtualMachineState::VZVirtualMachineStateStarting,
-            5 => VZVirtualMachineState::VZVirtualMachineStatePausing,
-            6 => VZVirtualMachineState::VZVirtualMachineStateResuming,
-            _ => VZVirtualMachineState::Other,
+        strict::on_vm_queue(&self.queue, "VZVirtualMachine::state");
+        VZVirtualMachineState::from_raw(msg_send![*self.p, state])
+    }
+
+    /// Runs `f` once, the first time the virtual machine is seen in `state`, including when it is
+    /// already in `state` at registration. `f` runs on the VM's queue.
+    ///
+    /// Unlike the completion handler of [`VZVirtualMachine::start`], which fires once the start is
+    /// accepted, this waits for the state itself. The observation removes itself after `f` ran;
+    /// dropping the guard earlier abandons it.
+    pub fn on_first_transition_to<F>(&self, state: VZVirtualMachineState, f: F) -> KvoGuard
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let claimed = AtomicBool::new(false);
+        let f = self.watchdog.wrap("on_first_transition_to", move |()| f());
+        let f = Cell::new(Some(f));
+        unsafe {
+            kvo::observe_on(*self.p, "state", &self.queue, move |new: NSInteger| {
+                if VZVirtualMachineState::from_raw(new) != state {
+                    return true;
+                }
+                if !claimed.swap(true, Ordering::SeqCst) {
+                    if let Some(f) = f.take() {
+                        f(());
+                    }
+                }
+                false
+            })
+        }
+    }
+
+    /// Records the events of this virtual machine from now on: its operations, the completion of
+    /// each, and its state changes. All clones record into the same timeline, which is created by
+    /// the first call; later calls return handles to it. See [`crate::timeline`].
+    pub fn record_timeline(&self) -> TimelineHandle {
+        self.timeline.attach()
+    }
+
+    /// Times every Rust callback this virtual machine runs from now on, including completion
+    /// handlers and [`VZVirtualMachine::on_first_transition_to`] callbacks, and reports each one
+    /// that takes longer than `threshold` on standard error. Applies to all clones; calling it
+    /// again changes the threshold. See [`crate::queue_watchdog`].
+    pub fn enable_queue_watchdog(&self, threshold: Duration) {
+        self.watchdog.enable(threshold);
+    }
+
+    pub fn queue_watchdog_stats(&self) -> QueueWatchdogStats {
+        self.watchdog.stats()
+    }
+
+    /// Panics if called from the VM's queue, or a queue targeting it. Blocking helpers call this
+    /// first, so a callback that uses them fails with a message instead of deadlocking the VM.
+    #[track_caller]
+    pub fn assert_not_on_vm_queue(&self) {
+        self.queue
+            .assert_not_current("a blocking virtualization-rs call");
+    }
+
+    /// The queue the virtual machine was created with; the main queue for
+    /// [`VZVirtualMachine::new_without_queue`].
+    pub fn queue(&self) -> &DispatchQueue {
+        &self.queue
+    }
+
+    /// The Virtio socket devices of the virtual machine.
+    ///
+    /// The property is read on the VM's queue, so this panics if called from that queue.
+    pub fn socket_devices(&self) -> Vec<VZVirtioSocketDevice> {
+        self.queue
+            .assert_not_current("VZVirtualMachine::socket_devices");
+        let p = *self.p;
+        let queue = self.queue.clone();
+        let callbacks = self.callbacks.clone();
+        self.queue.exec_sync(move || unsafe {
+            let devices: Id = msg_send![p, socketDevices];
+            let count: NSUInteger = msg_send![devices, count];
+            (0..count)
+                .map(|i| {
+                    let device: Id = msg_send![devices, objectAtIndex: i];
+                    VZVirtioSocketDevice::from_raw(
+                        retained(device),
+                        queue.clone(),
+                        callbacks.clone(),
+                    )
+                })
+                .collect()
+        })
+    }
+
+    /// The directory sharing devices of the virtual machine; empty before macOS 12.
+    ///
+    /// The property is read on the VM's queue, so this panics if called from that queue.
+    pub fn directory_sharing_devices(&self) -> Vec<VZVirtioFileSystemDevice> {
+        self.queue
+            .assert_not_current("VZVirtualMachine::directory_sharing_devices");
+        let p = *self.p;
+        let queue = self.queue.clone();
+        self.queue.exec_sync(move || unsafe {
+            let supported: BOOL =
+                msg_send![p, respondsToSelector: sel!(directorySharingDevices)];
+            if !from_objc_bool(supported) {
+                return Vec::new();
+            }
+            let devices: Id = msg_send![p, directorySharingDevices];
+            let count: NSUInteger = msg_send![devices, count];
+            (0..count)
+                .map(|i| {
+                    let device: Id = msg_send![devices, objectAtIndex: i];
+                    VZVirtioFileSystemDevice::from_raw(retained(device), queue.clone())
+                })
+                .collect()
+        })
+    }
+
+    /// The Virtio console devices of the virtual machine; empty before macOS 13.
+    ///
+    /// The property is read on the VM's queue, so this panics if called from that queue.
+    pub fn console_devices(&self) -> Vec<VZVirtioConsoleDevice> {
+        self.queue
+            .assert_not_current("VZVirtualMachine::console_devices");
+        let p = *self.p;
+        let queue = self.queue.clone();
+        self.queue.exec_sync(move || unsafe {
+            let supported: BOOL = msg_send![p, respondsToSelector: sel!(consoleDevices)];
+            if !from_objc_bool(supported) {
+                return Vec::new();
+            }
+            let devices: Id = msg_send![p, consoleDevices];
+            let count: NSUInteger = msg_send![devices, count];
+            (0..count)
+                .map(|i| {
+                    let device: Id = msg_send![devices, objectAtIndex: i];
+                    VZVirtioConsoleDevice::from_raw(retained(device), queue.clone())
+                })
+                .collect()
+        })
+    }
+
+    /// The graphics devices of the virtual machine; empty before macOS 14.
+    ///
+    /// The property is read on the VM's queue, directly if called there: the displays of a
+    /// machine on the main queue are resized from AppKit's notifications on the main thread.
+    pub fn graphics_devices(&self) -> Vec<VZGraphicsDevice> {
+        let p = *self.p;
+        let queue = self.queue.clone();
+        let read = move || unsafe {
+            let supported: BOOL = msg_send![p, respondsToSelector: sel!(graphicsDevices)];
+            if !from_objc_bool(supported) {
+                return Vec::new();
+            }
+            let devices: Id = msg_send![p, graphicsDevices];
+            let count: NSUInteger = msg_send![devices, count];
+            (0..count)
+                .map(|i| {
+                    let device: Id = msg_send![devices, objectAtIndex: i];
+                    VZGraphicsDevice::from_raw(retained(device), queue.clone())
+                })
+                .collect()
+        };
+        if self.queue.is_current() {
+            read()
+        } else {
+            self.queue.exec_sync(read)
         }
     }
 
+    /// The memory balloon devices of the virtual machine; empty before macOS 12.
+    ///
+    /// The property is read on the VM's queue, so this panics if called from that queue.
+    pub fn memory_balloon_devices(&self) -> Vec<VZVirtioTraditionalMemoryBalloonDevice> {
+        self.queue
+            .assert_not_current("VZVirtualMachine::memory_balloon_devices");
+        let p = *self.p;
+        let queue = self.queue.clone();
+        self.queue.exec_sync(move || unsafe {
+            let supported: BOOL = msg_send![p, respondsToSelector: sel!(memoryBalloonDevices)];
+            if !from_objc_bool(supported) {
+                return Vec::new();
+            }
+            let devices: Id = msg_send![p, memoryBalloonDevices];
+            let count: NSUInteger = msg_send![devices, count];
+            (0..count)
+                .map(|i| {
+                    let device: Id = msg_send![devices, objectAtIndex: i];
+                    VZVirtioTraditionalMemoryBalloonDevice::from_raw(
+                        retained(device),
+                        queue.clone(),
+                    )
+                })
+                .collect()
+        })
+    }
+
+    /// The network devices of the virtual machine; empty before macOS 14.
+    ///
+    /// The property is read on the VM's queue, so this panics if called from that queue.
+    pub fn network_devices(&self) -> Vec<VZNetworkDevice> {
+        self.queue
+            .assert_not_current("VZVirtualMachine::network_devices");
+        let p = *self.p;
+        let queue = self.queue.clone();
+        self.queue.exec_sync(move || unsafe {
+            let supported: BOOL = msg_send![p, respondsToSelector: sel!(networkDevices)];
+            if !from_objc_bool(supported) {
+                return Vec::new();
+            }
+            let devices: Id = msg_send![p, networkDevices];
+            let count: NSUInteger = msg_send![devices, count];
+            (0..count)
+                .map(|i| {
+                    let device: Id = msg_send![devices, objectAtIndex: i];
+                    VZNetworkDevice::from_raw(retained(device), queue.clone())
+                })
+                .collect()
+        })
+    }
+
+    /// Whether this macOS has the runtime device accessor read by `getter`, e.g.
+    /// `consoleDevices`, so an empty array from it means the machine has none of the devices.
+    pub(crate) fn has_runtime_devices(&self, getter: Sel) -> bool {
+        unsafe { responds_to(*self.p, getter) }
+    }
+
+    /// # Safety
+    /// The framework object may only be messaged on the VM's queue, and must not be released
+    /// through the returned pointer. Setting its delegate ends [`error_events`].
+    ///
+    /// [`error_events`]: VZVirtualMachine::error_events
     pub unsafe fn id(&self) -> Id {
-        *self.0
+        *self.p
     }
 }
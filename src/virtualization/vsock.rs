@@ -0,0 +1,212 @@
+//! vsock module
+//!
+//! Addresses of the virtio socket device. The framework only takes ports: from the host the guest
+//! is always context id (CID) [`GUEST_CID`], and the guest reaches the host at [`HOST_CID`].
+//! [`VsockPort`] is a checked port, and [`Endpoint`] the pair as shown in errors and logs, e.g.
+//! `vsock 3:1024`.
+//!
+//! The guest side is not this crate's to run; [`GUEST_SOCAT_LISTENER`] and the systemd units are
+//! templates tooling can print with [`guest_instructions`], for a guest echoing on a port as
+//! [`Liveness::vsock_ping`](crate::liveness::Liveness::vsock_ping) expects.
+//!
+//! # Examples
+//! ```rust
+//! let port = VsockPort::new(1024)?;
+//! println!("{}", vsock::guest_instructions(vsock::GUEST_SOCAT_LISTENER, port));
+//! device.connect_to_port(port, move |outcome| match outcome {
+//!     CompletionOutcome::Success(connection) => { /* ... */ }
+//!     // failed to connect to vsock 3:1024: ...
+//!     CompletionOutcome::Failed(e) => eprintln!("{}", Endpoint::guest(port).connect_error(e)),
+//!     CompletionOutcome::Cancelled => {}
+//! });
+//! ```
+
+use crate::virtualization::error::{VZError, VZErrorCtx};
+
+use std::convert::TryFrom;
+use std::fmt;
+use std::str::FromStr;
+
+/// The host's CID, as the guest addresses it.
+pub const HOST_CID: u32 = 2;
+
+/// The guest's CID, as the host addresses it. Every guest has it: each machine has its own device.
+pub const GUEST_CID: u32 = 3;
+
+/// `VMADDR_CID_ANY`: a guest listener bound to it accepts connections to any of its CIDs.
+pub const VMADDR_CID_ANY: u32 = u32::MAX;
+
+/// `VMADDR_PORT_ANY`, which asks the kernel for a free port and cannot be connected to.
+pub const VMADDR_PORT_ANY: u32 = u32::MAX;
+
+/// Ports below this one are reserved in Linux guests: only root can listen on them.
+pub const FIRST_UNRESERVED_PORT: u32 = 1024;
+
+/// Placeholder for the port in the guest-side templates.
+pub const PORT_PLACEHOLDER: &str = "{port}";
+
+/// A guest listener echoing back what it receives, e.g. started from an initrd.
+pub const GUEST_SOCAT_LISTENER: &str = "socat VSOCK-LISTEN:{port},reuseaddr,fork EXEC:/bin/cat";
+
+/// A socket unit for the same listener under systemd, e.g. `vsock-echo.socket`, with
+/// [`GUEST_SYSTEMD_SERVICE`] as `vsock-echo@.service`.
+pub const GUEST_SYSTEMD_SOCKET: &str = "\
+[Socket]
+ListenStream=vsock:4294967295:{port}
+Accept=yes
+
+[Install]
+WantedBy=sockets.target
+";
+
+/// The service run for each connection accepted by [`GUEST_SYSTEMD_SOCKET`].
+pub const GUEST_SYSTEMD_SERVICE: &str = "\
+[Service]
+ExecStart=/bin/cat
+StandardInput=socket
+";
+
+/// `template` with [`PORT_PLACEHOLDER`] replaced by `port`.
+pub fn guest_instructions(template: &str, port: VsockPort) -> String {
+    template.replace(PORT_PLACEHOLDER, &port.to_string())
+}
+
+/// A port refused by [`VsockPort::new`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VsockPortError {
+    /// Port 0 is not a port.
+    Zero,
+    /// [`VMADDR_PORT_ANY`].
+    Any,
+}
+
+impl fmt::Display for VsockPortError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VsockPortError::Zero => write!(f, "invalid vsock port 0"),
+            VsockPortError::Any => write!(
+                f,
+                "invalid vsock port {}: VMADDR_PORT_ANY cannot be connected to",
+                VMADDR_PORT_ANY
+            ),
+        }
+    }
+}
+
+impl std::error::Error for VsockPortError {}
+
+/// A vsock port of the guest or the host.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct VsockPort(u32);
+
+impl VsockPort {
+    /// Fails for 0 and [`VMADDR_PORT_ANY`]. Reserved ports are accepted; see
+    /// [`is_reserved`](Self::is_reserved).
+    pub fn new(port: u32) -> Result<VsockPort, VsockPortError> {
+        match port {
+            0 => Err(VsockPortError::Zero),
+            VMADDR_PORT_ANY => Err(VsockPortError::Any),
+            port => Ok(VsockPort(port)),
+        }
+    }
+
+    /// A port the framework reported, taken as is.
+    pub(crate) fn from_raw(port: u32) -> VsockPort {
+        VsockPort(port)
+    }
+
+    pub fn get(self) -> u32 {
+        self.0
+    }
+
+    /// Whether the port is below [`FIRST_UNRESERVED_PORT`], so a guest listener needs root.
+    pub fn is_reserved(self) -> bool {
+        self.0 < FIRST_UNRESERVED_PORT
+    }
+}
+
+impl TryFrom<u32> for VsockPort {
+    type Error = VsockPortError;
+
+    fn try_from(port: u32) -> Result<VsockPort, VsockPortError> {
+        VsockPort::new(port)
+    }
+}
+
+impl From<VsockPort> for u32 {
+    fn from(port: VsockPort) -> u32 {
+        port.0
+    }
+}
+
+/// A port number given as text, e.g. from the command line or the environment.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParseVsockPortError {
+    NotANumber(String),
+    Invalid(VsockPortError),
+}
+
+impl fmt::Display for ParseVsockPortError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseVsockPortError::NotANumber(s) => write!(f, "invalid vsock port: {:?}", s),
+            ParseVsockPortError::Invalid(e) => e.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for ParseVsockPortError {}
+
+impl FromStr for VsockPort {
+    type Err = ParseVsockPortError;
+
+    fn from_str(s: &str) -> Result<VsockPort, ParseVsockPortError> {
+        let port = s
+            .parse()
+            .map_err(|_| ParseVsockPortError::NotANumber(s.to_string()))?;
+        VsockPort::new(port).map_err(ParseVsockPortError::Invalid)
+    }
+}
+
+impl fmt::Display for VsockPort {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+/// One end of a vsock connection, rendered as `vsock <cid>:<port>`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Endpoint {
+    pub cid: u32,
+    pub port: VsockPort,
+}
+
+impl Endpoint {
+    /// `port` of the guest, where the host connects to.
+    pub fn guest(port: VsockPort) -> Endpoint {
+        Endpoint {
+            cid: GUEST_CID,
+            port,
+        }
+    }
+
+    /// `port` of the host, where the guest connects to.
+    pub fn host(port: VsockPort) -> Endpoint {
+        Endpoint {
+            cid: HOST_CID,
+            port,
+        }
+    }
+
+    /// `error`, the failure of a connection to this endpoint, naming it: `failed to connect to
+    /// vsock 3:1024: ...`.
+    pub fn connect_error(&self, error: VZError) -> VZErrorCtx {
+        VZErrorCtx::new("connect to", Some(self.to_string()), error.0)
+    }
+}
+
+impl fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "vsock {}:{}", self.cid, self.port)
+    }
+}
@@ -0,0 +1,440 @@
+//! Admission control: the decision against injected host measurements, violation by violation with
+//! the measured and required numbers, and the measurements of the real host.
+
+#![cfg(target_os = "macos")]
+
+extern crate virtualization_rs;
+
+use virtualization_rs::admission::{
+    self, AdmissionDecision, AdmissionPolicy, FileStats, Host, HostProbe, MemoryStats,
+    Requirements, Violation,
+};
+use virtualization_rs::runtime::autoreleasepool;
+use virtualization_rs::virtualization::storage_device::{
+    VZDiskImageStorageDeviceAttachmentBuilder, VZVirtioBlockDeviceConfiguration,
+};
+use virtualization_rs::virtualization::virtual_machine::{
+    BackingFile, BackingFileKind, VZVirtualMachineConfigurationBuilder,
+};
+
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+
+const GIB: u64 = 1 << 30;
+
+/// Measurements handed out as given; files it does not know are missing.
+struct FakeHost {
+    memory: Option<MemoryStats>,
+    logical_cpus: usize,
+    files: HashMap<PathBuf, FileStats>,
+}
+
+impl FakeHost {
+    /// 8 GiB free and inactive, 4 CPUs, no files.
+    fn new() -> FakeHost {
+        FakeHost {
+            memory: Some(MemoryStats {
+                free: 6 * GIB,
+                inactive: 2 * GIB,
+            }),
+            logical_cpus: 4,
+            files: HashMap::new(),
+        }
+    }
+
+    /// A file of `len` bytes with `allocated` of them on a volume with `available` bytes.
+    fn file(mut self, path: &str, volume: u64, available: u64, len: u64, allocated: u64) -> Self {
+        self.files.insert(
+            PathBuf::from(path),
+            FileStats {
+                volume,
+                volume_available: available,
+                len,
+                allocated,
+            },
+        );
+        self
+    }
+}
+
+impl HostProbe for FakeHost {
+    fn memory(&self) -> io::Result<MemoryStats> {
+        self.memory
+            .ok_or_else(|| io::Error::other("host_statistics64 failed: 5"))
+    }
+
+    fn logical_cpus(&self) -> io::Result<usize> {
+        Ok(self.logical_cpus)
+    }
+
+    fn file(&self, path: &Path) -> io::Result<FileStats> {
+        self.files
+            .get(path)
+            .copied()
+            .ok_or_else(|| io::Error::from_raw_os_error(2))
+    }
+}
+
+fn disk(path: &str, read_only: bool) -> BackingFile {
+    BackingFile {
+        path: PathBuf::from(path),
+        kind: BackingFileKind::DiskImage,
+        read_only,
+    }
+}
+
+fn requirements(cpu_count: usize, memory_size: u64, files: Vec<BackingFile>) -> Requirements {
+    Requirements {
+        cpu_count,
+        memory_size,
+        files,
+    }
+}
+
+fn no_min_free() -> AdmissionPolicy {
+    AdmissionPolicy {
+        min_free_disk_bytes: 0,
+        ..AdmissionPolicy::default()
+    }
+}
+
+#[test]
+fn admits_what_fits() {
+    let host = FakeHost::new().file("/a/disk.img", 1, 20 * GIB, 16 * GIB, GIB);
+    let decision = admission::check_with(
+        &host,
+        requirements(4, 8 * GIB, vec![disk("/a/disk.img", false)]),
+        &AdmissionPolicy::default(),
+    );
+    assert_eq!(decision, AdmissionDecision::Admitted);
+    assert!(decision.is_admitted());
+    assert!(decision.violations().is_empty());
+    assert_eq!(decision.to_string(), "admitted");
+}
+
+#[test]
+fn memory_counts_free_and_inactive_times_the_overcommit() {
+    let host = FakeHost::new();
+    let cases = [
+        (8 * GIB, 1.0, None),
+        (8 * GIB + 1, 1.0, Some(8 * GIB)),
+        (6 * GIB, 0.5, Some(4 * GIB)),
+        (12 * GIB, 1.5, None),
+    ];
+    for &(memory_size, memory_overcommit, allowed) in &cases {
+        let policy = AdmissionPolicy {
+            memory_overcommit,
+            ..AdmissionPolicy::default()
+        };
+        let decision = admission::check_with(&host, requirements(1, memory_size, vec![]), &policy);
+        let expected = allowed.map(|allowed| Violation::Memory {
+            required: memory_size,
+            measured: 8 * GIB,
+            allowed,
+        });
+        assert_eq!(
+            decision.violations(),
+            expected.as_slice(),
+            "{} bytes at {}",
+            memory_size,
+            memory_overcommit
+        );
+    }
+}
+
+#[test]
+fn cpus_count_logical_cpus_times_the_overcommit() {
+    let host = FakeHost::new();
+    let cases = [
+        (4, 1.0, None),
+        (5, 1.0, Some(4)),
+        (8, 2.0, None),
+        (3, 0.5, Some(2)),
+    ];
+    for &(cpu_count, cpu_overcommit, allowed) in &cases {
+        let policy = AdmissionPolicy {
+            cpu_overcommit,
+            ..AdmissionPolicy::default()
+        };
+        let decision = admission::check_with(&host, requirements(cpu_count, GIB, vec![]), &policy);
+        let expected = allowed.map(|allowed| Violation::Cpu {
+            required: cpu_count,
+            measured: 4,
+            allowed,
+        });
+        assert_eq!(
+            decision.violations(),
+            expected.as_slice(),
+            "{} CPUs at {}",
+            cpu_count,
+            cpu_overcommit
+        );
+    }
+}
+
+#[test]
+fn disk_sums_the_growth_of_writable_files_per_volume() {
+    let host = FakeHost::new()
+        // Volume 1: 3 + 2 GiB to grow, 4 GiB available.
+        .file("/a/root.img", 1, 4 * GIB, 4 * GIB, GIB)
+        .file("/a/data.img", 1, 4 * GIB, 2 * GIB, 0)
+        // Read-only, so its growth is not counted.
+        .file("/a/base.img", 1, 4 * GIB, 64 * GIB, 0)
+        // Volume 2: fully allocated, 1 GiB available.
+        .file("/b/nvram", 2, GIB, GIB, GIB);
+    let files = vec![
+        disk("/a/root.img", false),
+        disk("/a/base.img", true),
+        disk("/a/data.img", false),
+        BackingFile {
+            path: PathBuf::from("/b/nvram"),
+            kind: BackingFileKind::EfiVariableStore,
+            read_only: false,
+        },
+    ];
+
+    let decision =
+        admission::check_with(&host, requirements(1, GIB, files.clone()), &no_min_free());
+    assert_eq!(
+        decision.violations(),
+        &[Violation::Disk {
+            paths: vec![PathBuf::from("/a/root.img"), PathBuf::from("/a/data.img")],
+            required: 5 * GIB,
+            measured: 4 * GIB,
+        }]
+    );
+
+    // The minimum to keep free applies to every volume holding writable files.
+    let policy = AdmissionPolicy {
+        min_free_disk_bytes: 2 * GIB,
+        ..AdmissionPolicy::default()
+    };
+    let decision = admission::check_with(&host, requirements(1, GIB, files), &policy);
+    assert_eq!(
+        decision.violations(),
+        &[
+            Violation::Disk {
+                paths: vec![PathBuf::from("/a/root.img"), PathBuf::from("/a/data.img")],
+                required: 7 * GIB,
+                measured: 4 * GIB,
+            },
+            Violation::Disk {
+                paths: vec![PathBuf::from("/b/nvram")],
+                required: 2 * GIB,
+                measured: GIB,
+            },
+        ]
+    );
+}
+
+#[test]
+fn volumes_of_read_only_files_are_not_checked() {
+    let host = FakeHost::new().file("/full/base.img", 1, 0, 8 * GIB, 0);
+    let decision = admission::check_with(
+        &host,
+        requirements(1, GIB, vec![disk("/full/base.img", true)]),
+        &AdmissionPolicy::default(),
+    );
+    assert!(decision.is_admitted(), "{}", decision);
+}
+
+#[test]
+fn unmeasured_resources_reject() {
+    let host = FakeHost {
+        memory: None,
+        ..FakeHost::new()
+    };
+    let decision = admission::check_with(
+        &host,
+        requirements(1, GIB, vec![disk("/missing/disk.img", false)]),
+        &AdmissionPolicy::default(),
+    );
+    let missing = io::Error::from_raw_os_error(2).to_string();
+    assert_eq!(
+        decision.violations(),
+        &[
+            Violation::Unmeasured {
+                resource: "memory".to_string(),
+                error: "host_statistics64 failed: 5".to_string(),
+            },
+            Violation::Unmeasured {
+                resource: "disk image '/missing/disk.img'".to_string(),
+                error: missing.clone(),
+            },
+        ]
+    );
+    assert_eq!(
+        decision.to_string(),
+        format!(
+            "not admitted: memory: not measured: host_statistics64 failed: 5; \
+             disk image '/missing/disk.img': not measured: {}",
+            missing
+        )
+    );
+}
+
+#[test]
+fn violations_show_required_and_measured_numbers() {
+    let host =
+        FakeHost::new()
+            .file("/a/root.img", 1, 100, 300, 100)
+            .file("/a/data.img", 1, 100, 50, 0);
+    let decision = admission::check_with(
+        &host,
+        requirements(
+            6,
+            9 * GIB,
+            vec![disk("/a/root.img", false), disk("/a/data.img", false)],
+        ),
+        &AdmissionPolicy {
+            cpu_overcommit: 1.25,
+            ..no_min_free()
+        },
+    );
+    assert_eq!(
+        decision.to_string(),
+        format!(
+            "not admitted: memory: {} bytes required, {} allowed of {} free and inactive; \
+             CPUs: 6 required, 5 allowed of 4 logical; \
+             disk: the volume of '/a/root.img', '/a/data.img' needs 250 bytes available, has 100",
+            9 * GIB,
+            8 * GIB,
+            8 * GIB
+        )
+    );
+}
+
+/// A sparse file of `len` bytes in the temporary directory, removed on drop.
+struct SparseFile(PathBuf);
+
+impl SparseFile {
+    fn new(name: &str, len: u64) -> SparseFile {
+        let path = std::env::temp_dir().join(format!(
+            "virtualization-rs-admission-{}-{}.img",
+            name,
+            std::process::id()
+        ));
+        File::create(&path).unwrap().set_len(len).unwrap();
+        SparseFile(path)
+    }
+}
+
+impl Drop for SparseFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.0);
+    }
+}
+
+#[test]
+fn measures_the_real_host() {
+    let memory = Host.memory().unwrap();
+    assert!(memory.available() > 0, "{:?}", memory);
+    let cpus = Host.logical_cpus().unwrap();
+    assert!(cpus >= 1);
+
+    let image = SparseFile::new("measure", GIB);
+    let stats = Host.file(&image.0).unwrap();
+    assert_eq!(stats.len, GIB);
+    assert!(stats.growth() > GIB / 2, "{:?}", stats);
+    assert!(stats.volume_available > 0, "{:?}", stats);
+
+    let small = requirements(1, 1 << 20, vec![]);
+    let policy = no_min_free();
+    assert!(admission::check(&small, &policy).is_admitted());
+
+    let huge = requirements(cpus + 1, u64::MAX, vec![]);
+    match admission::check(&huge, &policy).violations() {
+        [Violation::Memory {
+            required, measured, ..
+        }, Violation::Cpu {
+            required: cpu_count,
+            measured: logical_cpus,
+            ..
+        }] => {
+            assert_eq!(*required, u64::MAX);
+            assert!(*measured > 0);
+            assert_eq!((*cpu_count, *logical_cpus), (cpus + 1, cpus));
+        }
+        other => panic!("expected memory and CPU violations, got {:?}", other),
+    }
+
+    // The sparse image can grow by about 1 GiB; demanding all of the volume on top cannot fit.
+    let policy = AdmissionPolicy {
+        min_free_disk_bytes: stats.volume_available,
+        ..AdmissionPolicy::default()
+    };
+    let decision = admission::check(
+        requirements(1, 1 << 20, vec![disk(image.0.to_str().unwrap(), false)]),
+        &policy,
+    );
+    match decision.violations() {
+        [Violation::Disk { paths, .. }] => {
+            assert_eq!(paths.as_slice(), std::slice::from_ref(&image.0))
+        }
+        other => panic!("expected a disk violation, got {:?}", other),
+    }
+}
+
+#[test]
+fn configurations_list_their_backing_files() {
+    let root = SparseFile::new("root", 1 << 20);
+    let base = SparseFile::new("base", 1 << 20);
+    let conf = autoreleasepool(|| {
+        let attach = |file: &SparseFile, read_only| {
+            let attachment = VZDiskImageStorageDeviceAttachmentBuilder::new()
+                .path(file.0.to_str().unwrap())
+                .read_only(read_only)
+                .build()
+                .unwrap();
+            VZVirtioBlockDeviceConfiguration::new(attachment)
+        };
+        VZVirtualMachineConfigurationBuilder::new()
+            .cpu_count(2)
+            .memory_size_mib(512)
+            .storage_device(attach(&root, false))
+            .storage_device(attach(&base, true))
+            .build()
+    });
+    let requirements = Requirements::from(&conf);
+    assert_eq!(requirements.cpu_count, 2);
+    assert_eq!(requirements.memory_size, 512 << 20);
+    let paths: Vec<(PathBuf, bool)> = requirements
+        .files
+        .iter()
+        .map(|file| {
+            assert_eq!(file.kind, BackingFileKind::DiskImage);
+            // The framework may resolve symlinks in the temporary directory.
+            (fs::canonicalize(&file.path).unwrap(), file.read_only)
+        })
+        .collect();
+    assert_eq!(
+        paths,
+        vec![
+            (fs::canonicalize(&root.0).unwrap(), false),
+            (fs::canonicalize(&base.0).unwrap(), true),
+        ]
+    );
+}
+
+#[cfg(feature = "isolation")]
+#[test]
+fn specs_list_their_disks() {
+    use virtualization_rs::isolation::HostSpec;
+
+    let spec = HostSpec::linux("vmlinuz", "initrd", "console=hvc0")
+        .cpu_count(2)
+        .memory_size(1 << 30)
+        .disk("root.img", false)
+        .disk("base.img", true)
+        .console_log("console.log");
+    assert_eq!(
+        Requirements::from(&spec),
+        requirements(
+            2,
+            GIB,
+            vec![disk("root.img", false), disk("base.img", true)]
+        )
+    );
+}
@@ -0,0 +1,256 @@
+//! Auxiliary storage: created in a scratch directory for a real hardware model, reopened, and
+//! refused over an existing file unless overwriting is allowed; start errors are told apart by
+//! whether they report storage of another model.
+//!
+//! The cases with a real hardware model need a restore image in `VIRTUALIZATION_RS_TEST_IPSW`
+//! and print why they are skipped otherwise.
+
+#![cfg(target_os = "macos")]
+
+extern crate libc;
+extern crate objc;
+extern crate virtualization_rs;
+
+use virtualization_rs::base::{Id, NSDictionary, NSError, NSInteger, NSString};
+use virtualization_rs::features::HostCapabilities;
+use virtualization_rs::virtualization::error::{CompletionOutcome, VZErrorCtx, VZ_ERROR_DOMAIN};
+use virtualization_rs::virtualization::platform::{
+    VZMacAuxiliaryStorage, VZMacAuxiliaryStorageInitializationOption,
+    VZMacAuxiliaryStorageInitializationOptions,
+};
+use virtualization_rs::virtualization::restore_image::{VZMacHardwareModel, VZMacOSRestoreImage};
+
+use std::fs;
+use std::path::PathBuf;
+use std::sync::mpsc;
+
+use objc::rc::StrongPtr;
+use objc::{class, msg_send, sel, sel_impl};
+
+const INVALID_CONFIGURATION: NSInteger = 2;
+
+struct Scratch(PathBuf);
+
+impl Scratch {
+    fn new(name: &str) -> Scratch {
+        let path = std::env::temp_dir().join(format!(
+            "virtualization-rs-aux-storage-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).unwrap();
+        Scratch(path)
+    }
+
+    fn storage(&self) -> PathBuf {
+        self.0.join("AuxiliaryStorage")
+    }
+}
+
+impl Drop for Scratch {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+/// The hardware model of the restore image in `VIRTUALIZATION_RS_TEST_IPSW`.
+fn hardware_model(test: &str) -> Option<VZMacHardwareModel> {
+    let ipsw = match std::env::var_os("VIRTUALIZATION_RS_TEST_IPSW") {
+        Some(path) => PathBuf::from(path),
+        None => {
+            println!("{} skipped: VIRTUALIZATION_RS_TEST_IPSW unset", test);
+            return None;
+        }
+    };
+    let (tx, rx) = mpsc::channel();
+    if let Err(e) = VZMacOSRestoreImage::load_file(&ipsw, move |outcome| {
+        let _ = tx.send(outcome);
+    }) {
+        println!("{} skipped: {}", test, e);
+        return None;
+    }
+    let image = match rx.recv().unwrap() {
+        CompletionOutcome::Success(image) => image,
+        CompletionOutcome::Cancelled => panic!("loading the restore image was cancelled"),
+        CompletionOutcome::Failed(e) => panic!("{}", e.ns_error()),
+    };
+    let requirements = image
+        .most_featureful_supported_configuration()
+        .expect("the restore image is not supported on this host");
+    Some(requirements.hardware_model())
+}
+
+/// A `VZErrorDomain` error with `description`, caused by `underlying` if given.
+fn vz_error(code: NSInteger, description: &str, underlying: Option<&NSError>) -> NSError {
+    let description = NSString::new(description);
+    let mut keys = vec![NSString::new("NSLocalizedDescription")];
+    let mut objects = vec![*description.0];
+    if let Some(underlying) = underlying {
+        keys.push(NSString::new("NSUnderlyingError"));
+        objects.push(*underlying.0);
+    }
+    let keys: Vec<Id> = keys.iter().map(|key| *key.0).collect();
+    let user_info = unsafe {
+        let p: Id = msg_send![
+            class!(NSDictionary),
+            dictionaryWithObjects: objects.as_ptr()
+            forKeys: keys.as_ptr()
+            count: keys.len()
+        ];
+        NSDictionary(StrongPtr::retain(p))
+    };
+    NSError::error_with_domain(VZ_ERROR_DOMAIN, code, Some(&user_info))
+}
+
+fn assert_errno(error: &VZErrorCtx, errno: i32) {
+    assert_eq!(error.ns_error().domain().as_str(), "NSPOSIXErrorDomain");
+    assert_eq!(error.ns_error().code(), errno as isize);
+}
+
+#[test]
+fn tells_model_mismatches_apart() {
+    let mismatch = vz_error(
+        INVALID_CONFIGURATION,
+        "The auxiliary storage is not compatible with the hardware model.",
+        None,
+    );
+    assert!(VZMacAuxiliaryStorage::is_model_mismatch(&mismatch));
+
+    let wrapped = vz_error(
+        INVALID_CONFIGURATION,
+        "Invalid virtual machine configuration.",
+        Some(&mismatch),
+    );
+    assert!(VZMacAuxiliaryStorage::is_model_mismatch(&wrapped));
+
+    for other in &[
+        vz_error(
+            INVALID_CONFIGURATION,
+            "The storage device attachment is invalid.",
+            None,
+        ),
+        vz_error(1, "Auxiliary storage is corrupted.", None),
+        NSError::error_with_domain(VZ_ERROR_DOMAIN, INVALID_CONFIGURATION, None),
+        NSError::posix(libc::ENOENT),
+    ] {
+        assert!(!VZMacAuxiliaryStorage::is_model_mismatch(other));
+    }
+}
+
+#[test]
+fn opening_a_missing_file_fails() {
+    let scratch = Scratch::new("missing");
+    let error = VZMacAuxiliaryStorage::open_existing(scratch.storage())
+        .err()
+        .unwrap();
+    let errno = if HostCapabilities::detect().supports_class("VZMacAuxiliaryStorage") {
+        libc::ENOENT
+    } else {
+        libc::ENOTSUP
+    };
+    assert_errno(&error, errno);
+    assert!(error.to_string().starts_with(&format!(
+        "failed to open auxiliary storage '{}': ",
+        scratch.storage().display()
+    )));
+}
+
+#[test]
+fn creates_and_reopens() {
+    let model = match hardware_model("creates_and_reopens") {
+        Some(model) => model,
+        None => return,
+    };
+    let scratch = Scratch::new("create");
+    let created = VZMacAuxiliaryStorage::create(
+        scratch.storage(),
+        &model,
+        VZMacAuxiliaryStorageInitializationOptions::new(),
+    )
+    .unwrap();
+    assert_eq!(created.path(), scratch.storage());
+    assert_eq!(
+        created.url().path().as_str(),
+        scratch.storage().to_str().unwrap()
+    );
+    let size = created.size_bytes().unwrap();
+    assert!(size > 0);
+    assert_eq!(size, fs::metadata(scratch.storage()).unwrap().len());
+
+    let reopened = VZMacAuxiliaryStorage::open_existing(scratch.storage()).unwrap();
+    assert_eq!(reopened.path(), scratch.storage());
+    assert_eq!(reopened.size_bytes().unwrap(), size);
+}
+
+#[test]
+fn overwrites_only_when_allowed() {
+    let model = match hardware_model("overwrites_only_when_allowed") {
+        Some(model) => model,
+        None => return,
+    };
+    let scratch = Scratch::new("overwrite");
+    fs::write(scratch.storage(), b"not auxiliary storage").unwrap();
+
+    let error = VZMacAuxiliaryStorage::create(
+        scratch.storage(),
+        &model,
+        VZMacAuxiliaryStorageInitializationOptions::new(),
+    )
+    .err()
+    .expect("created storage over an existing file");
+    assert!(error
+        .to_string()
+        .starts_with("failed to create auxiliary storage '"));
+    assert_eq!(
+        fs::read(scratch.storage()).unwrap(),
+        b"not auxiliary storage"
+    );
+
+    let storage = VZMacAuxiliaryStorage::create(
+        scratch.storage(),
+        &model,
+        VZMacAuxiliaryStorageInitializationOptions::new()
+            .with(VZMacAuxiliaryStorageInitializationOption::allow_overwrite()),
+    )
+    .unwrap();
+    assert_ne!(
+        fs::read(scratch.storage()).unwrap(),
+        b"not auxiliary storage"
+    );
+    assert!(storage.size_bytes().unwrap() > 0);
+}
+
+#[test]
+fn recreates_only_on_a_model_mismatch() {
+    let model = match hardware_model("recreates_only_on_a_model_mismatch") {
+        Some(model) => model,
+        None => return,
+    };
+    let scratch = Scratch::new("recreate");
+    fs::write(scratch.storage(), b"storage of another model").unwrap();
+
+    let other = vz_error(1, "Internal error.", None);
+    let kept = VZMacAuxiliaryStorage::recreate_if_model_mismatch(scratch.storage(), &model, &other)
+        .unwrap();
+    assert!(kept.is_none());
+    assert_eq!(
+        fs::read(scratch.storage()).unwrap(),
+        b"storage of another model"
+    );
+
+    let mismatch = vz_error(
+        INVALID_CONFIGURATION,
+        "The auxiliary storage is not compatible with the hardware model.",
+        None,
+    );
+    let recreated =
+        VZMacAuxiliaryStorage::recreate_if_model_mismatch(scratch.storage(), &model, &mismatch)
+            .unwrap()
+            .expect("a mismatch recreates the storage");
+    assert_eq!(recreated.path(), scratch.storage());
+    assert_ne!(
+        fs::read(scratch.storage()).unwrap(),
+        b"storage of another model"
+    );
+}
@@ -0,0 +1,157 @@
+//! Boots a real Linux guest and stops it again. Ignored by default: starting a virtual machine
+//! needs hardware virtualization, a test binary signed with the `com.apple.security.virtualization`
+//! entitlement, and guest images named by environment variables:
+//!
+//! - `VIRTUALIZATION_RS_TEST_KERNEL`: an uncompressed kernel for the host's architecture
+//! - `VIRTUALIZATION_RS_TEST_INITRD`: its initial ramdisk
+//! - `VIRTUALIZATION_RS_TEST_DISK` (optional): a raw disk image attached read-only
+//!
+//! `make test-boot` signs the test binary with `virtualization_rs.entitlements` and runs it with
+//! `--ignored`.
+
+#![cfg(target_os = "macos")]
+
+extern crate virtualization_rs;
+
+use virtualization_rs::test_support::{self, fixture};
+use virtualization_rs::virtualization::boot_loader::VZLinuxBootLoaderBuilder;
+use virtualization_rs::virtualization::error::CompletionOutcome;
+use virtualization_rs::virtualization::storage_device::{
+    VZDiskImageStorageDeviceAttachmentBuilder, VZVirtioBlockDeviceConfiguration,
+};
+use virtualization_rs::virtualization::virtual_machine::{
+    StartOutcome, VZVirtualMachine, VZVirtualMachineConfiguration,
+};
+
+use std::path::Path;
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::Duration;
+
+const START_DEADLINE: Duration = Duration::from_secs(60);
+
+fn path_str(path: &Path) -> String {
+    path.to_str().unwrap().to_string()
+}
+
+/// The minimal configuration with the guest images, or `None` if `test` is skipped.
+fn guest_config(test: &str) -> Option<VZVirtualMachineConfiguration> {
+    if !VZVirtualMachine::supported() {
+        println!(
+            "{} skipped: virtualization is not supported on this host",
+            test
+        );
+        return None;
+    }
+    let kernel = fixture(test, "KERNEL")?;
+    let initrd = fixture(test, "INITRD")?;
+    let boot_loader = VZLinuxBootLoaderBuilder::new()
+        .kernel_url(path_str(&kernel))
+        .initial_ramdisk_url(path_str(&initrd))
+        .command_line("console=hvc0")
+        .build()
+        .unwrap();
+    let mut builder = test_support::minimal_builder()
+        .memory_size_mib(512)
+        .boot_loader(boot_loader);
+    if std::env::var_os("VIRTUALIZATION_RS_TEST_DISK").is_some() {
+        let disk = fixture(test, "DISK")?;
+        let attachment = VZDiskImageStorageDeviceAttachmentBuilder::new()
+            .path(path_str(&disk))
+            .read_only(true)
+            .build()
+            .unwrap_or_else(|e| panic!("{}", e));
+        builder = builder.storage_devices(vec![VZVirtioBlockDeviceConfiguration::new(attachment)]);
+    }
+    Some(builder.build())
+}
+
+#[test]
+#[ignore]
+fn linux_guest_starts_and_stops() {
+    let conf = match guest_config("linux_guest_starts_and_stops") {
+        Some(conf) => conf,
+        None => return,
+    };
+    conf.validate_with_error()
+        .unwrap_or_else(|e| panic!("{}", e));
+    let vm = VZVirtualMachine::new_with_qos(conf, "boot-test", None);
+
+    let (tx, rx) = mpsc::channel();
+    vm.start_with_deadline(START_DEADLINE, move |outcome| {
+        let _ = tx.send(match outcome {
+            StartOutcome::Started => Ok(()),
+            StartOutcome::FailedToStart(error) => Err(format!(
+                "{:?}; is the test binary signed with the virtualization entitlement?",
+                error
+            )),
+            StartOutcome::Cancelled => Err("start cancelled".to_string()),
+            StartOutcome::DeadlineExceeded { last_state } => Err(format!(
+                "not running after {:?}: {}",
+                START_DEADLINE, last_state
+            )),
+        });
+    })
+    .unwrap();
+    if let Err(error) = rx.recv().unwrap() {
+        panic!("the guest did not start: {}", error);
+    }
+
+    let (tx, rx) = mpsc::channel();
+    vm.stop(move |outcome| {
+        let _ = tx.send(match outcome {
+            CompletionOutcome::Success(()) => Ok(()),
+            CompletionOutcome::Cancelled => Err("stop cancelled".to_string()),
+            CompletionOutcome::Failed(error) => Err(format!("{:?}", error)),
+        });
+    })
+    .unwrap();
+    if let Err(error) = rx.recv_timeout(Duration::from_secs(30)).unwrap() {
+        panic!("the guest did not stop: {}", error);
+    }
+}
+
+#[test]
+#[ignore]
+fn linux_guest_starts_and_stops_through_an_arc() {
+    let conf = match guest_config("linux_guest_starts_and_stops_through_an_arc") {
+        Some(conf) => conf,
+        None => return,
+    };
+    let vm = Arc::new(VZVirtualMachine::new_with_qos(conf, "boot-test-arc", None));
+
+    // One closure starts the machine from another thread; the other, run once it started, stops
+    // it. Both hold the same machine.
+    let (tx, rx) = mpsc::channel();
+    let stopper = vm.clone();
+    let stop = move |tx: mpsc::Sender<Result<(), String>>| {
+        let result = stopper.stop(move |outcome| {
+            let _ = tx.send(match outcome {
+                CompletionOutcome::Success(()) => Ok(()),
+                CompletionOutcome::Cancelled => Err("stop cancelled".to_string()),
+                CompletionOutcome::Failed(error) => Err(format!("stop failed: {:?}", error)),
+            });
+        });
+        result.unwrap();
+    };
+    let starter = vm.clone();
+    thread::spawn(move || {
+        starter
+            .start(move |outcome| match outcome {
+                CompletionOutcome::Success(()) => stop(tx),
+                CompletionOutcome::Cancelled => {
+                    let _ = tx.send(Err("start cancelled".to_string()));
+                }
+                CompletionOutcome::Failed(error) => {
+                    let _ = tx.send(Err(format!("start failed: {:?}", error)));
+                }
+            })
+            .unwrap();
+    })
+    .join()
+    .unwrap();
+
+    if let Err(error) = rx.recv_timeout(START_DEADLINE).unwrap() {
+        panic!("{}", error);
+    }
+}
@@ -0,0 +1,242 @@
+//! The C interface: the header agrees with the Rust declarations, compiled with the system C
+//! compiler, and the calls behave as documented from a caller that only has raw pointers.
+//!
+//! The machine created here has a garbage kernel, so its start fails; nothing boots.
+
+#![cfg(target_os = "macos")]
+
+extern crate virtualization_rs;
+
+use virtualization_rs::base::DispatchSemaphore;
+use virtualization_rs::capi::{self, VrsCallback, VrsConfig, VrsProfileInputs, VrsStatus, VrsVm};
+use virtualization_rs::test_support::TempDir;
+
+use std::collections::HashMap;
+use std::ffi::{CStr, CString};
+use std::mem;
+use std::os::raw::{c_char, c_void};
+use std::path::Path;
+use std::process::Command;
+use std::ptr;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Compiles `tests/capi/<name>.c` against `include/` with `args`, into `output`.
+fn cc(name: &str, args: &[&str], output: &Path) {
+    let root = env!("CARGO_MANIFEST_DIR");
+    let status = Command::new("cc")
+        .args(["-std=c99", "-Wall", "-Wextra", "-Werror", "-I"])
+        .arg(Path::new(root).join("include"))
+        .args(args)
+        .arg(
+            Path::new(root)
+                .join("tests/capi")
+                .join(format!("{}.c", name)),
+        )
+        .arg("-o")
+        .arg(output)
+        .status()
+        .expect("running cc");
+    assert!(status.success(), "cc failed on tests/capi/{}.c", name);
+}
+
+#[test]
+fn the_header_lays_out_types_as_rust_does() {
+    let dir = TempDir::new("capi-layout");
+    let binary = dir.path().join("layout");
+    cc("layout", &[], &binary);
+    let output = Command::new(&binary).output().unwrap();
+    assert!(output.status.success());
+    let printed: HashMap<String, usize> = String::from_utf8(output.stdout)
+        .unwrap()
+        .lines()
+        .map(|line| {
+            let (name, value) = line.rsplit_once(' ').unwrap();
+            (name.to_string(), value.parse().unwrap())
+        })
+        .collect();
+
+    let pointer = mem::size_of::<*const c_char>();
+    let mut expected: HashMap<String, usize> = vec![
+        ("VRS_ABI_VERSION", capi::VRS_ABI_VERSION as usize),
+        ("sizeof(vrs_status)", mem::size_of::<VrsStatus>()),
+        (
+            "sizeof(vrs_profile_inputs)",
+            mem::size_of::<VrsProfileInputs>(),
+        ),
+        ("sizeof(vrs_callback)", mem::size_of::<VrsCallback>()),
+        ("VRS_STATUS_OK", VrsStatus::Ok as usize),
+        (
+            "VRS_STATUS_INVALID_ARGUMENT",
+            VrsStatus::InvalidArgument as usize,
+        ),
+        ("VRS_STATUS_WRONG_STATE", VrsStatus::WrongState as usize),
+        ("VRS_STATUS_FAILED", VrsStatus::Failed as usize),
+        ("VRS_STATUS_CANCELLED", VrsStatus::Cancelled as usize),
+        ("VRS_STATUS_PANICKED", VrsStatus::Panicked as usize),
+    ]
+    .into_iter()
+    .map(|(name, value)| (name.to_string(), value))
+    .collect();
+    let fields = [
+        "kernel",
+        "initrd",
+        "command_line",
+        "efi_variable_store",
+        "disk",
+        "cloud_init_seed",
+    ];
+    for (i, field) in fields.iter().enumerate() {
+        expected.insert(format!("vrs_profile_inputs.{}", field), i * pointer);
+    }
+    assert_eq!(printed, expected);
+}
+
+#[test]
+fn the_header_declares_the_rust_signatures() {
+    // The C side assigns each function to a pointer of the type listed in prototypes.c ...
+    let dir = TempDir::new("capi-prototypes");
+    cc("prototypes", &["-c"], &dir.path().join("prototypes.o"));
+    // ... and these are the same types on the Rust side.
+    let _: extern "C" fn() -> u32 = capi::vrs_abi_version;
+    let _: unsafe extern "C" fn(
+        *const c_char,
+        *const VrsProfileInputs,
+        u32,
+        u64,
+        *mut *mut c_char,
+    ) -> *mut VrsConfig = capi::vrs_config_new_from_profile;
+    let _: unsafe extern "C" fn(*mut VrsConfig) = capi::vrs_config_free;
+    let _: unsafe extern "C" fn(*const VrsConfig, *mut *mut c_char) -> *mut VrsVm =
+        capi::vrs_vm_create;
+    let _: unsafe extern "C" fn(*mut VrsVm, VrsCallback, *mut c_void) -> VrsStatus =
+        capi::vrs_vm_start;
+    let _: unsafe extern "C" fn(*mut VrsVm, VrsCallback, *mut c_void) -> VrsStatus =
+        capi::vrs_vm_stop;
+    let _: unsafe extern "C" fn(*const VrsVm) -> i64 = capi::vrs_vm_state;
+    let _: unsafe extern "C" fn(*mut VrsVm) = capi::vrs_vm_free;
+    let _: unsafe extern "C" fn(*mut c_char) = capi::vrs_string_free;
+}
+
+fn no_inputs() -> VrsProfileInputs {
+    VrsProfileInputs {
+        kernel: ptr::null(),
+        initrd: ptr::null(),
+        command_line: ptr::null(),
+        efi_variable_store: ptr::null(),
+        disk: ptr::null(),
+        cloud_init_seed: ptr::null(),
+    }
+}
+
+/// Takes the string in `err`, freeing it.
+unsafe fn take_error(err: &mut *mut c_char) -> String {
+    assert!(!err.is_null(), "no error message");
+    let message = CStr::from_ptr(*err).to_str().unwrap().to_string();
+    capi::vrs_string_free(*err);
+    *err = ptr::null_mut();
+    message
+}
+
+#[test]
+fn refused_arguments_come_back_as_errors() {
+    let profile = CString::new("windows").unwrap();
+    let inputs = no_inputs();
+    let mut err = ptr::null_mut();
+    unsafe {
+        let config = capi::vrs_config_new_from_profile(profile.as_ptr(), &inputs, 1, 512, &mut err);
+        assert!(config.is_null());
+        assert_eq!(take_error(&mut err), "unknown guest profile \"windows\"");
+
+        let profile = CString::new("linux-direct-kernel").unwrap();
+        let config =
+            capi::vrs_config_new_from_profile(profile.as_ptr(), ptr::null(), 1, 512, &mut err);
+        assert!(config.is_null());
+        assert_eq!(take_error(&mut err), "inputs is NULL");
+
+        // The kernel is missing, which the profile refuses.
+        let config = capi::vrs_config_new_from_profile(profile.as_ptr(), &inputs, 1, 512, &mut err);
+        assert!(config.is_null());
+        assert!(take_error(&mut err).contains("kernel"));
+
+        // Without an out-parameter the message is dropped.
+        let config =
+            capi::vrs_config_new_from_profile(profile.as_ptr(), &inputs, 1, 512, ptr::null_mut());
+        assert!(config.is_null());
+
+        assert!(capi::vrs_vm_create(ptr::null(), &mut err).is_null());
+        assert_eq!(take_error(&mut err), "config is NULL");
+        assert_eq!(
+            capi::vrs_vm_start(ptr::null_mut(), None, ptr::null_mut()),
+            VrsStatus::InvalidArgument
+        );
+        assert_eq!(
+            capi::vrs_vm_stop(ptr::null_mut(), None, ptr::null_mut()),
+            VrsStatus::InvalidArgument
+        );
+        assert_eq!(capi::vrs_vm_state(ptr::null()), -1);
+        capi::vrs_config_free(ptr::null_mut());
+        capi::vrs_vm_free(ptr::null_mut());
+        capi::vrs_string_free(ptr::null_mut());
+    }
+    assert_eq!(capi::vrs_abi_version(), capi::VRS_ABI_VERSION);
+}
+
+/// What a callback was called with, and a semaphore it signals.
+struct Completion {
+    done: DispatchSemaphore,
+    outcome: Mutex<Option<(VrsStatus, Option<String>)>>,
+}
+
+unsafe extern "C" fn record(ctx: *mut c_void, status: VrsStatus, error: *const c_char) {
+    let completion = &*(ctx as *const Completion);
+    let message = if error.is_null() {
+        None
+    } else {
+        Some(CStr::from_ptr(error).to_str().unwrap().to_string())
+    };
+    *completion.outcome.lock().unwrap() = Some((status, message));
+    completion.done.signal();
+}
+
+#[test]
+fn a_machine_goes_through_create_start_and_free() {
+    let dir = TempDir::new("capi-machine");
+    let kernel = CString::new(dir.garbage("vmlinuz").to_str().unwrap()).unwrap();
+    let initrd = CString::new(dir.garbage("initrd").to_str().unwrap()).unwrap();
+    let profile = CString::new("linux-direct-kernel").unwrap();
+    let inputs = VrsProfileInputs {
+        kernel: kernel.as_ptr(),
+        initrd: initrd.as_ptr(),
+        ..no_inputs()
+    };
+    let completion = Completion {
+        done: DispatchSemaphore::new(0),
+        outcome: Mutex::new(None),
+    };
+    let ctx = &completion as *const Completion as *mut c_void;
+    let mut err = ptr::null_mut();
+    unsafe {
+        let config = capi::vrs_config_new_from_profile(profile.as_ptr(), &inputs, 1, 512, &mut err);
+        assert!(!config.is_null(), "{}", take_error(&mut err));
+        let vm = capi::vrs_vm_create(config, &mut err);
+        capi::vrs_config_free(config);
+        assert!(!vm.is_null(), "{}", take_error(&mut err));
+        assert_eq!(capi::vrs_vm_state(vm), 0);
+
+        assert_eq!(capi::vrs_vm_start(vm, Some(record), ctx), VrsStatus::Ok);
+        // The first start is in flight, so the second is refused without its callback.
+        assert_eq!(
+            capi::vrs_vm_start(vm, Some(record), ctx),
+            VrsStatus::WrongState
+        );
+        assert!(completion.done.wait_timeout(Duration::from_secs(30)));
+        // The garbage kernel does not boot.
+        let (status, message) = completion.outcome.lock().unwrap().take().unwrap();
+        assert_eq!(status, VrsStatus::Failed);
+        assert!(!message.unwrap().is_empty());
+        assert!(!completion.done.wait_timeout(Duration::from_millis(100)));
+
+        capi::vrs_vm_free(vm);
+    }
+}
@@ -0,0 +1,29 @@
+/* Prints the layout the header gives the interface's types, for tests/capi.rs to compare with
+ * Rust's. */
+#include <stddef.h>
+#include <stdio.h>
+
+#include "virtualization_rs.h"
+
+#define FIELD(name) \
+    printf("vrs_profile_inputs.%s %zu\n", #name, offsetof(vrs_profile_inputs, name))
+
+int main(void) {
+    printf("VRS_ABI_VERSION %d\n", VRS_ABI_VERSION);
+    printf("sizeof(vrs_status) %zu\n", sizeof(vrs_status));
+    printf("sizeof(vrs_profile_inputs) %zu\n", sizeof(vrs_profile_inputs));
+    printf("sizeof(vrs_callback) %zu\n", sizeof(vrs_callback));
+    FIELD(kernel);
+    FIELD(initrd);
+    FIELD(command_line);
+    FIELD(efi_variable_store);
+    FIELD(disk);
+    FIELD(cloud_init_seed);
+    printf("VRS_STATUS_OK %d\n", VRS_STATUS_OK);
+    printf("VRS_STATUS_INVALID_ARGUMENT %d\n", VRS_STATUS_INVALID_ARGUMENT);
+    printf("VRS_STATUS_WRONG_STATE %d\n", VRS_STATUS_WRONG_STATE);
+    printf("VRS_STATUS_FAILED %d\n", VRS_STATUS_FAILED);
+    printf("VRS_STATUS_CANCELLED %d\n", VRS_STATUS_CANCELLED);
+    printf("VRS_STATUS_PANICKED %d\n", VRS_STATUS_PANICKED);
+    return 0;
+}
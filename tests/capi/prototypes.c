@@ -0,0 +1,14 @@
+/* Compiled, not run, by tests/capi.rs: the header declares every call with these types, which
+ * tests/capi.rs pins on the Rust side. */
+#include "virtualization_rs.h"
+
+uint32_t (*const abi_version)(void) = vrs_abi_version;
+vrs_config *(*const config_new_from_profile)(const char *, const vrs_profile_inputs *, uint32_t,
+                                             uint64_t, char **) = vrs_config_new_from_profile;
+void (*const config_free)(vrs_config *) = vrs_config_free;
+vrs_vm *(*const vm_create)(const vrs_config *, char **) = vrs_vm_create;
+vrs_status (*const vm_start)(vrs_vm *, vrs_callback, void *) = vrs_vm_start;
+vrs_status (*const vm_stop)(vrs_vm *, vrs_callback, void *) = vrs_vm_stop;
+int64_t (*const vm_state)(const vrs_vm *) = vrs_vm_state;
+void (*const vm_free)(vrs_vm *) = vrs_vm_free;
+void (*const string_free)(char *) = vrs_string_free;
@@ -0,0 +1,134 @@
+//! Closing the types that own descriptors: each descriptor is closed exactly once, a later
+//! release does not close a descriptor that reused the number, the guest's ends are refused while
+//! a configuration holds the attachment, and a closed console bridge's address can be bound again
+//! right away.
+
+#![cfg(target_os = "macos")]
+
+extern crate virtualization_rs;
+
+use virtualization_rs::base::NSFileHandle;
+use virtualization_rs::resource::CloseError;
+use virtualization_rs::virtualization::serial_port::{
+    ConsoleCapture, VZVirtioConsoleDeviceSerialPortConfiguration,
+};
+use virtualization_rs::virtualization::tcp_console::{NoClientPolicy, TcpConsoleBridge};
+use virtualization_rs::virtualization::unix_console::UnixSocketConsole;
+
+use std::fs::File;
+use std::net::TcpListener;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::UnixListener;
+use std::sync::{Mutex, MutexGuard};
+
+/// The tests reuse descriptor numbers on purpose, so none may open descriptors meanwhile.
+fn serial() -> MutexGuard<'static, ()> {
+    static LOCK: Mutex<()> = Mutex::new(());
+    LOCK.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+fn is_open(fd: RawFd) -> bool {
+    unsafe { libc::fcntl(fd, libc::F_GETFD) != -1 }
+}
+
+/// `/dev/null` duplicated onto a descriptor number that was just closed, standing in for a
+/// descriptor some other code opened in the meantime.
+struct Canary(RawFd);
+
+impl Canary {
+    fn onto(fd: RawFd) -> Canary {
+        assert!(!is_open(fd), "{} is still open", fd);
+        let null = File::open("/dev/null").unwrap();
+        assert_eq!(unsafe { libc::dup2(null.as_raw_fd(), fd) }, fd);
+        Canary(fd)
+    }
+}
+
+impl Drop for Canary {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.0) };
+    }
+}
+
+#[test]
+fn file_handle_is_refused_while_retained() {
+    let _serial = serial();
+    let mut fds: [RawFd; 2] = [0; 2];
+    assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+    let read = NSFileHandle::init_with_file_descriptor(fds[0], true);
+    let write = NSFileHandle::init_with_file_descriptor(fds[1], true);
+    let other = NSFileHandle(read.0.clone());
+
+    assert!(matches!(read.close(), Err(CloseError::Attached(_))));
+    assert!(is_open(fds[0]));
+    other.close().unwrap();
+    assert!(!is_open(fds[0]));
+    write.close().unwrap();
+    assert!(!is_open(fds[1]));
+}
+
+#[test]
+fn console_capture_closes_guest_ends_once() {
+    let _serial = serial();
+    let capture = ConsoleCapture::new().unwrap();
+    let attachment = capture.attachment();
+    let reading = attachment.file_handle_for_reading().unwrap();
+    let writing = attachment.file_handle_for_writing().unwrap();
+    drop(attachment);
+    let (read_fd, write_fd) = (reading.file_descriptor(), writing.file_descriptor());
+
+    capture.close().unwrap();
+    let canaries = [Canary::onto(read_fd), Canary::onto(write_fd)];
+    // The last releases of the handles must not close the canaries.
+    drop(reading);
+    drop(writing);
+    assert!(canaries.iter().all(|canary| is_open(canary.0)));
+}
+
+#[test]
+fn console_capture_is_refused_while_configured() {
+    let _serial = serial();
+    let capture = ConsoleCapture::new().unwrap();
+    let fd = capture
+        .attachment()
+        .file_handle_for_writing()
+        .unwrap()
+        .file_descriptor();
+    let config = VZVirtioConsoleDeviceSerialPortConfiguration::new(capture.attachment());
+
+    match capture.close() {
+        Err(CloseError::Attached(resource)) => assert!(resource.contains("ConsoleCapture")),
+        other => panic!("expected Attached, got {:?}", other),
+    }
+    assert!(is_open(fd));
+    drop(config);
+
+    let capture = ConsoleCapture::new().unwrap();
+    drop(VZVirtioConsoleDeviceSerialPortConfiguration::new(
+        capture.attachment(),
+    ));
+    capture.close().unwrap();
+}
+
+#[test]
+fn tcp_console_port_is_free_after_close() {
+    let _serial = serial();
+    let console = TcpConsoleBridge::bind(0, NoClientPolicy::Drop).unwrap();
+    let addr = console.local_addr();
+    console.close().unwrap();
+    TcpListener::bind(addr).unwrap();
+}
+
+#[test]
+fn unix_console_path_is_free_after_close() {
+    let _serial = serial();
+    let path = std::env::temp_dir().join(format!(
+        "virtualization-rs-close-{}.sock",
+        std::process::id()
+    ));
+    let console = UnixSocketConsole::bind(&path, NoClientPolicy::Drop).unwrap();
+    console.close().unwrap();
+    assert!(!path.exists());
+    drop(UnixListener::bind(&path).unwrap());
+    std::fs::remove_file(&path).unwrap();
+}
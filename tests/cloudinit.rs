@@ -0,0 +1,163 @@
+//! NoCloud seed images: a rendered seed matches a captured image byte for byte, the primary
+//! volume descriptor names its files with d-characters only, and the Joliet descriptor names the
+//! same extents as cloud-init expects them.
+
+#![cfg(target_os = "macos")]
+
+extern crate virtualization_rs;
+
+use virtualization_rs::cloudinit::CloudInitSeed;
+
+const SECTOR_SIZE: usize = 2048;
+const FIXTURE: &str = include_str!("data/cidata_seed.hex");
+
+fn seed() -> CloudInitSeed {
+    CloudInitSeed::new("#cloud-config\n", "instance-id: vm1\n").network_config("version: 2\n")
+}
+
+/// The image a fixture of `len` bytes describes, its rows laid over zeros.
+fn image_of(fixture: &str, len: usize) -> Vec<u8> {
+    let mut image = vec![0u8; len];
+    for line in fixture.lines().filter(|line| !line.starts_with('#')) {
+        let (offset, bytes) = line.split_once(": ").unwrap();
+        let offset = usize::from_str_radix(offset, 16).unwrap();
+        for (i, byte) in bytes.split(' ').enumerate() {
+            image[offset + i] = u8::from_str_radix(byte, 16).unwrap();
+        }
+    }
+    image
+}
+
+fn sector(image: &[u8], sector: usize) -> &[u8] {
+    &image[sector * SECTOR_SIZE..(sector + 1) * SECTOR_SIZE]
+}
+
+fn le_u32(bytes: &[u8]) -> usize {
+    u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize
+}
+
+/// The identifiers, extents and sizes of the files in the root directory a volume descriptor
+/// names.
+fn files(image: &[u8], descriptor: usize) -> Vec<(Vec<u8>, usize, usize)> {
+    let root = &sector(image, descriptor)[156..190];
+    let mut directory = sector(image, le_u32(&root[2..6]));
+    let mut files = Vec::new();
+    while directory[0] != 0 {
+        let record = &directory[..directory[0] as usize];
+        let name = &record[33..33 + record[32] as usize];
+        if record[25] & 2 == 0 {
+            files.push((
+                name.to_vec(),
+                le_u32(&record[2..6]),
+                le_u32(&record[10..14]),
+            ));
+        }
+        directory = &directory[record.len()..];
+    }
+    files
+}
+
+fn ucs2(text: &str) -> Vec<u8> {
+    text.encode_utf16().flat_map(u16::to_be_bytes).collect()
+}
+
+#[test]
+fn a_seed_matches_the_captured_image() {
+    let image = seed().to_iso();
+    assert_eq!(image.len(), 28 * SECTOR_SIZE);
+    let expected = image_of(FIXTURE, image.len());
+    // Compared per sector so a mismatch names where it is.
+    for (i, (actual, expected)) in image
+        .chunks(SECTOR_SIZE)
+        .zip(expected.chunks(SECTOR_SIZE))
+        .enumerate()
+    {
+        for (offset, (a, e)) in actual.iter().zip(expected).enumerate() {
+            assert_eq!(a, e, "sector {} offset {}", i, offset);
+        }
+    }
+}
+
+#[test]
+fn the_volume_descriptors_are_primary_joliet_and_terminator() {
+    let image = seed().to_iso();
+    let primary = sector(&image, 16);
+    assert_eq!(&primary[..7], b"\x01CD001\x01");
+    assert_eq!(&primary[40..48], b"cidata  ");
+    assert_eq!(&primary[80..88], &[28, 0, 0, 0, 0, 0, 0, 28]);
+    assert!(primary[88..120].iter().all(|&b| b == 0));
+
+    let joliet = sector(&image, 17);
+    assert_eq!(&joliet[..7], b"\x02CD001\x01");
+    assert_eq!(&joliet[40..52], &ucs2("cidata")[..]);
+    assert_eq!(&joliet[88..91], b"%/E");
+
+    assert_eq!(&sector(&image, 18)[..7], b"\xffCD001\x01");
+}
+
+#[test]
+fn the_path_tables_name_each_root() {
+    let image = seed().to_iso();
+    for descriptor in [16, 17] {
+        let volume = sector(&image, descriptor);
+        let root = le_u32(&volume[158..162]);
+        assert_eq!(&volume[132..140], &[10, 0, 0, 0, 0, 0, 0, 10]);
+        let l_table = sector(&image, le_u32(&volume[140..144]));
+        let mut expected = vec![1, 0];
+        expected.extend((root as u32).to_le_bytes());
+        expected.extend([1, 0, 0, 0]);
+        assert_eq!(&l_table[..10], &expected[..]);
+        let m_sector = u32::from_be_bytes([volume[148], volume[149], volume[150], volume[151]]);
+        let m_table = sector(&image, m_sector as usize);
+        let mut expected = vec![1, 0];
+        expected.extend((root as u32).to_be_bytes());
+        expected.extend([0, 1, 0, 0]);
+        assert_eq!(&m_table[..10], &expected[..]);
+    }
+}
+
+#[test]
+fn primary_names_are_d_characters_and_joliet_names_are_real() {
+    let image = seed().to_iso();
+    let primary = files(&image, 16);
+    let names: Vec<_> = primary.iter().map(|(name, _, _)| &name[..]).collect();
+    assert_eq!(
+        names,
+        [&b"META_DATA.;1"[..], b"NETWORK_CONFIG.;1", b"USER_DATA.;1"]
+    );
+    for name in names {
+        let (stem, version) = name.split_at(name.len() - 3);
+        assert_eq!(version, b".;1");
+        assert!(stem
+            .iter()
+            .all(|b| b.is_ascii_uppercase() || b.is_ascii_digit() || *b == b'_'));
+    }
+
+    let joliet = files(&image, 17);
+    let expected: Vec<_> = ["meta-data", "network-config", "user-data"]
+        .iter()
+        .map(|name| ucs2(name))
+        .collect();
+    let names: Vec<_> = joliet.iter().map(|(name, _, _)| name.clone()).collect();
+    assert_eq!(names, expected);
+
+    // Both sets of names share the extents, which hold the contents.
+    let contents: [&[u8]; 3] = [b"instance-id: vm1\n", b"version: 2\n", b"#cloud-config\n"];
+    for (((_, extent, size), (_, joliet_extent, joliet_size)), content) in
+        primary.iter().zip(&joliet).zip(contents)
+    {
+        assert_eq!((extent, size), (joliet_extent, joliet_size));
+        assert_eq!(&image[extent * SECTOR_SIZE..][..*size], content);
+    }
+}
+
+#[test]
+fn network_config_is_optional() {
+    let image = CloudInitSeed::new("#cloud-config\n", "instance-id: vm1\n").to_iso();
+    let names: Vec<_> = files(&image, 16)
+        .into_iter()
+        .map(|(name, _, _)| name)
+        .collect();
+    assert_eq!(names, [b"META_DATA.;1".to_vec(), b"USER_DATA.;1".to_vec()]);
+    assert_eq!(files(&image, 17).len(), 2);
+}
@@ -0,0 +1,111 @@
+//! Replacing the devices of a configuration reuses the configuration's pointer buffer: once it
+//! has grown to the device count, building the array for another set of devices makes no Rust
+//! heap allocation, however many devices there are.
+//!
+//! A dedicated binary, since it installs a counting global allocator. Only allocations of the
+//! test's own thread are counted, so the harness's threads do not disturb it.
+
+#![cfg(target_os = "macos")]
+
+extern crate virtualization_rs;
+
+use virtualization_rs::test_support::{self, TempDir};
+use virtualization_rs::virtualization::storage_device::{
+    VZDiskImageStorageDeviceAttachmentBuilder, VZStorageDeviceConfiguration,
+    VZVirtioBlockDeviceConfiguration,
+};
+use virtualization_rs::virtualization::virtual_machine::VZVirtualMachineConfiguration;
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+use std::path::Path;
+
+struct Counting;
+
+thread_local! {
+    static COUNTING: Cell<bool> = const { Cell::new(false) };
+    static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+}
+
+fn count() {
+    if COUNTING.with(Cell::get) {
+        ALLOCATIONS.with(|allocations| allocations.set(allocations.get() + 1));
+    }
+}
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        count();
+        System.alloc(layout)
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        count();
+        System.alloc_zeroed(layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        count();
+        System.realloc(ptr, layout, new_size)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+/// The allocations `f` makes on this thread.
+fn allocations<F: FnOnce()>(f: F) -> usize {
+    ALLOCATIONS.with(|allocations| allocations.set(0));
+    COUNTING.with(|counting| counting.set(true));
+    f();
+    COUNTING.with(|counting| counting.set(false));
+    ALLOCATIONS.with(Cell::get)
+}
+
+fn devices(image: &Path, count: usize) -> Vec<Box<dyn VZStorageDeviceConfiguration>> {
+    (0..count)
+        .map(|_| {
+            let attachment = VZDiskImageStorageDeviceAttachmentBuilder::new()
+                .path(image.to_str().unwrap())
+                .read_only(true)
+                .build()
+                .unwrap_or_else(|e| panic!("{}", e));
+            Box::new(VZVirtioBlockDeviceConfiguration::new(attachment))
+                as Box<dyn VZStorageDeviceConfiguration>
+        })
+        .collect()
+}
+
+fn replace(
+    conf: &mut VZVirtualMachineConfiguration,
+    devices: Vec<Box<dyn VZStorageDeviceConfiguration>>,
+) {
+    conf.replace_storage_devices(devices).unwrap();
+}
+
+#[test]
+fn replacing_devices_reuses_the_buffer() {
+    let dir = TempDir::new("config-alloc");
+    let image = dir.disk_image("disk.img", 1024 * 1024);
+    let mut conf = test_support::minimal_linux_config(&dir);
+    // Devices are created before counting; only the setter is measured.
+    let mut batches: Vec<_> = (0..8).map(|_| devices(&image, 64)).collect();
+    let larger = devices(&image, 256);
+    let smaller = devices(&image, 8);
+
+    // Grows the buffer to 64 pointers.
+    replace(&mut conf, batches.pop().unwrap());
+    for batch in batches {
+        assert_eq!(allocations(|| replace(&mut conf, batch)), 0);
+    }
+    // A larger set grows the buffer once, not once per device.
+    assert!(allocations(|| replace(&mut conf, larger)) <= 1);
+    assert_eq!(allocations(|| replace(&mut conf, smaller)), 0);
+    assert!(conf
+        .validate_with_error()
+        .unwrap_or_else(|e| panic!("{}", e)));
+}
@@ -0,0 +1,187 @@
+//! Serial ports that read from or write to the same file are flagged by the builder, whether they
+//! share an attachment or reach the file through duplicated descriptors. Ports on distinct files,
+//! or discarding output to `/dev/null`, are not.
+
+#![cfg(target_os = "macos")]
+
+extern crate virtualization_rs;
+
+use virtualization_rs::base::{NSFileHandle, NIL};
+use virtualization_rs::strict::{strict_mode, PANIC_PREFIX};
+use virtualization_rs::test_support::TempDir;
+use virtualization_rs::virtualization::serial_port::{
+    find_duplicate_backings, BackingDirection, DuplicateConsoleBacking,
+    VZFileHandleSerialPortAttachment, VZFileHandleSerialPortAttachmentBuilder,
+    VZVirtioConsoleDeviceSerialPortConfiguration,
+};
+use virtualization_rs::virtualization::virtual_machine::VZVirtualMachineConfigurationBuilder;
+
+use std::fs::{File, OpenOptions};
+use std::os::unix::io::{AsRawFd, IntoRawFd};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+use std::sync::{Mutex, MutexGuard};
+
+/// Strict mode is process-wide: the builder calls take turns with the test turning it on.
+fn lock() -> MutexGuard<'static, ()> {
+    static LOCK: Mutex<()> = Mutex::new(());
+    LOCK.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// A handle owning a new descriptor for `file`.
+fn handle(file: &File) -> NSFileHandle {
+    NSFileHandle::init_with_file_descriptor(file.try_clone().unwrap().into_raw_fd(), true)
+}
+
+fn open(path: &Path) -> File {
+    OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)
+        .unwrap()
+}
+
+fn attachment(read: &File, write: &File) -> VZFileHandleSerialPortAttachment {
+    VZFileHandleSerialPortAttachmentBuilder::new()
+        .file_handle_for_reading(handle(read))
+        .file_handle_for_writing(handle(write))
+        .build()
+}
+
+fn duplicates(attachments: Vec<VZFileHandleSerialPortAttachment>) -> Vec<DuplicateConsoleBacking> {
+    let ports = attachments
+        .into_iter()
+        .map(VZVirtioConsoleDeviceSerialPortConfiguration::new)
+        .collect();
+    let _lock = lock();
+    VZVirtualMachineConfigurationBuilder::new()
+        .serial_ports(ports)
+        .duplicate_console_backings()
+        .to_vec()
+}
+
+fn both_directions(ports: Vec<usize>) -> Vec<DuplicateConsoleBacking> {
+    vec![
+        DuplicateConsoleBacking {
+            ports: ports.clone(),
+            direction: BackingDirection::Input,
+        },
+        DuplicateConsoleBacking {
+            ports,
+            direction: BackingDirection::Output,
+        },
+    ]
+}
+
+#[test]
+fn backing_fds_are_the_handles_descriptors() {
+    let dir = TempDir::new("console-backing-fds");
+    let input = open(&dir.path().join("input"));
+    let read = handle(&input);
+    let fd = read.file_descriptor();
+    let attachment = unsafe { VZFileHandleSerialPortAttachment::from_raw_handles(*read.0, NIL) };
+    assert_eq!(attachment.backing_fds(), (Some(fd), None));
+}
+
+#[test]
+fn the_same_attachment_on_two_ports_is_flagged() {
+    let dir = TempDir::new("console-backing-same");
+    let input = open(&dir.path().join("input"));
+    let output = open(&dir.path().join("output"));
+    let shared = attachment(&input, &output);
+    let found = duplicates(vec![shared.clone(), attachment(&output, &input), shared]);
+    assert_eq!(found, both_directions(vec![0, 2]));
+    assert_eq!(
+        found[0].to_string(),
+        "serial ports 0 and 2 read the guest's input from the same file"
+    );
+}
+
+#[test]
+fn duplicated_descriptors_are_flagged() {
+    let dir = TempDir::new("console-backing-dup");
+    let input = open(&dir.path().join("input"));
+    let output = open(&dir.path().join("output"));
+    let first = attachment(&input, &output);
+    let second = attachment(&input, &output);
+    let (first_read, first_write) = first.backing_fds();
+    let (second_read, second_write) = second.backing_fds();
+    assert_ne!(first_read, second_read);
+    assert_ne!(first_write, second_write);
+    assert_eq!(duplicates(vec![first, second]), both_directions(vec![0, 1]));
+}
+
+#[test]
+fn distinct_files_are_not_flagged() {
+    let dir = TempDir::new("console-backing-distinct");
+    let files: Vec<File> = (0..4)
+        .map(|i| open(&dir.path().join(i.to_string())))
+        .collect();
+    let found = duplicates(vec![
+        attachment(&files[0], &files[1]),
+        attachment(&files[2], &files[3]),
+    ]);
+    assert!(found.is_empty(), "{:?}", found);
+
+    // One port reading and writing one file, like a terminal, shares it with nothing.
+    let found = duplicates(vec![attachment(&files[0], &files[0])]);
+    assert!(found.is_empty(), "{:?}", found);
+}
+
+#[test]
+fn discarding_to_dev_null_is_not_flagged() {
+    let dir = TempDir::new("console-backing-null");
+    let null = open(Path::new("/dev/null"));
+    let found = duplicates(vec![
+        attachment(&open(&dir.path().join("a")), &null),
+        attachment(&open(&dir.path().join("b")), &null),
+    ]);
+    assert!(found.is_empty(), "{:?}", found);
+}
+
+#[test]
+fn missing_descriptors_are_skipped() {
+    let dir = TempDir::new("console-backing-missing");
+    let file = open(&dir.path().join("file"));
+    let fd = file.as_raw_fd();
+    assert!(find_duplicate_backings(&[(None, None), (None, None)]).is_empty());
+    assert_eq!(
+        find_duplicate_backings(&[(Some(fd), None), (None, None), (Some(fd), Some(fd))]),
+        vec![DuplicateConsoleBacking {
+            ports: vec![0, 2],
+            direction: BackingDirection::Input,
+        }]
+    );
+}
+
+#[test]
+fn strict_mode_panics_on_a_duplicate() {
+    let dir = TempDir::new("console-backing-strict");
+    let input = open(&dir.path().join("input"));
+    let output = open(&dir.path().join("output"));
+    let shared = attachment(&input, &output);
+    let ports = vec![
+        VZVirtioConsoleDeviceSerialPortConfiguration::new(shared.clone()),
+        VZVirtioConsoleDeviceSerialPortConfiguration::new(shared),
+    ];
+    let builder = VZVirtualMachineConfigurationBuilder::new();
+    let _lock = lock();
+    strict_mode(true);
+    let result = panic::catch_unwind(AssertUnwindSafe(move || builder.serial_ports(ports)));
+    strict_mode(false);
+    let payload = result.err().expect("strict mode accepted a duplicate");
+    let message = payload
+        .downcast_ref::<String>()
+        .cloned()
+        .unwrap_or_default();
+    assert_eq!(
+        message,
+        format!(
+            "{}serial ports 0 and 1 read the guest's input from the same file; give each port \
+             its own file handles",
+            PANIC_PREFIX
+        )
+    );
+}
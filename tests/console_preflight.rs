@@ -0,0 +1,220 @@
+//! The console preflight over command lines as distributions and guides write them, against the
+//! console devices of a configuration, then on configurations themselves.
+
+#![cfg(target_os = "macos")]
+
+extern crate virtualization_rs;
+
+use virtualization_rs::features::HostCapabilities;
+use virtualization_rs::test_support::{self, TempDir};
+use virtualization_rs::virtualization::console_preflight::{
+    check, console_arguments, split_arguments, ConsoleDevices, ConsoleFinding, ConsoleTarget,
+    GuestBoot, Severity,
+};
+use virtualization_rs::virtualization::serial_port::{
+    ConsoleCapture, VZVirtioConsoleDeviceSerialPortConfiguration,
+};
+
+const ONE_PORT: ConsoleDevices = ConsoleDevices {
+    virtio_consoles: 1,
+    graphics: false,
+};
+const NO_DEVICES: ConsoleDevices = ConsoleDevices {
+    virtio_consoles: 0,
+    graphics: false,
+};
+const PORT_AND_GRAPHICS: ConsoleDevices = ConsoleDevices {
+    virtio_consoles: 1,
+    graphics: true,
+};
+
+fn uart(value: &str, virtio_consoles: usize) -> ConsoleFinding {
+    ConsoleFinding::Uart {
+        value: value.to_string(),
+        virtio_consoles,
+    }
+}
+
+fn missing(value: &str, index: u32, virtio_consoles: usize) -> ConsoleFinding {
+    ConsoleFinding::MissingVirtioConsole {
+        value: value.to_string(),
+        index,
+        virtio_consoles,
+    }
+}
+
+fn no_graphics(value: &str, virtio_consoles: usize) -> ConsoleFinding {
+    ConsoleFinding::NoGraphics {
+        value: value.to_string(),
+        virtio_consoles,
+    }
+}
+
+fn primary_not_shown(primary: &str, shown: &str) -> ConsoleFinding {
+    ConsoleFinding::PrimaryNotShown {
+        primary: primary.to_string(),
+        shown: shown.to_string(),
+    }
+}
+
+#[test]
+fn command_lines() {
+    let cases: Vec<(&str, ConsoleDevices, Vec<ConsoleFinding>)> = vec![
+        // The simplevm example's default.
+        ("console=hvc0", ONE_PORT, vec![]),
+        (
+            "root=/dev/vda1 ro console=hvc0 quiet",
+            PORT_AND_GRAPHICS,
+            vec![],
+        ),
+        // Ubuntu cloud images.
+        (
+            "BOOT_IMAGE=/boot/vmlinuz root=LABEL=cloudimg-rootfs ro console=tty1 console=ttyS0",
+            ONE_PORT,
+            vec![no_graphics("tty1", 1), uart("ttyS0", 1)],
+        ),
+        // Debian on arm64 boards and QEMU's virt machine.
+        (
+            "console=ttyAMA0,115200 root=/dev/vda2 rw",
+            ONE_PORT,
+            vec![uart("ttyAMA0,115200", 1)],
+        ),
+        // Fedora's default, which leaves the console to the kernel.
+        (
+            "BOOT_IMAGE=(hd0,gpt2)/vmlinuz-6.5.6-300.fc39.aarch64 root=UUID=8a1f rhgb quiet",
+            ONE_PORT,
+            vec![ConsoleFinding::NoConsoleArgument { virtio_consoles: 1 }],
+        ),
+        // The last console= is /dev/console, so the login prompt goes to the UART.
+        (
+            "console=hvc0 console=ttyS0,115200n8",
+            ONE_PORT,
+            vec![
+                uart("ttyS0,115200n8", 1),
+                primary_not_shown("ttyS0,115200n8", "hvc0"),
+            ],
+        ),
+        // Google Compute Engine images with hvc0 added last: only kernel messages are lost.
+        (
+            "console=ttyS0,38400n8 earlyprintk=ttyS0 console=hvc0",
+            ONE_PORT,
+            vec![uart("ttyS0,38400n8", 1)],
+        ),
+        // Alpine's virt flavor shows both.
+        ("console=tty0 console=hvc0", PORT_AND_GRAPHICS, vec![]),
+        ("console=hvc1", ONE_PORT, vec![missing("hvc1", 1, 1)]),
+        (
+            "root=/dev/vda console=hvc0",
+            NO_DEVICES,
+            vec![missing("hvc0", 0, 0)],
+        ),
+        // Arguments after -- go to init.
+        (
+            "init=/bin/sh -- console=hvc0",
+            ONE_PORT,
+            vec![ConsoleFinding::NoConsoleArgument { virtio_consoles: 1 }],
+        ),
+        // Quoted values may contain spaces.
+        (
+            "console=hvc0 dyndbg=\"file drivers/tty/* +p\" \"console=ttyS0\"",
+            ONE_PORT,
+            vec![uart("ttyS0", 1), primary_not_shown("ttyS0", "hvc0")],
+        ),
+        (
+            "console=null",
+            ONE_PORT,
+            vec![ConsoleFinding::Unrecognized {
+                value: "null".to_string(),
+            }],
+        ),
+        ("console=ttyS0", NO_DEVICES, vec![uart("ttyS0", 0)]),
+    ];
+    for (command_line, devices, expected) in cases {
+        assert_eq!(
+            check(GuestBoot::Linux(command_line), devices),
+            expected,
+            "{:?} with {:?}",
+            command_line,
+            devices
+        );
+    }
+}
+
+#[test]
+fn boots_without_our_command_line() {
+    assert_eq!(
+        check(GuestBoot::Efi, ONE_PORT),
+        vec![ConsoleFinding::EfiBoot]
+    );
+    assert_eq!(ConsoleFinding::EfiBoot.severity(), Severity::Note);
+    assert!(check(GuestBoot::Other, NO_DEVICES).is_empty());
+}
+
+#[test]
+fn arguments_are_split_as_the_kernel_does() {
+    assert_eq!(
+        split_arguments("  a=1   b=\"x y\"  \"c=z w\" -- d=2"),
+        vec!["a=1", "b=x y", "c=z w"]
+    );
+    assert_eq!(
+        console_arguments("console=tty0 consoleblank=0 console=hvc0,115200"),
+        vec!["tty0", "hvc0,115200"]
+    );
+    assert_eq!(ConsoleTarget::parse("hvc3"), ConsoleTarget::Virtio(3));
+    assert_eq!(ConsoleTarget::parse("ttyAMA0,115200"), ConsoleTarget::Uart);
+    assert_eq!(
+        ConsoleTarget::parse("uart8250,mmio,0x3f8"),
+        ConsoleTarget::Uart
+    );
+    assert_eq!(ConsoleTarget::parse("tty1"), ConsoleTarget::VirtualTerminal);
+    assert_eq!(ConsoleTarget::parse("lp0"), ConsoleTarget::Other);
+}
+
+#[test]
+fn messages_name_the_fix() {
+    assert_eq!(
+        uart("ttyS0", 1).to_string(),
+        "command line selects console=ttyS0 but only a virtio console (hvc0) is configured — \
+         change to console=hvc0 or add a pl011/16550 device (not supported by this framework)"
+    );
+    assert_eq!(
+        missing("hvc3", 3, 2).to_string(),
+        "command line selects console=hvc3 but only virtio consoles (hvc0 and hvc1) are \
+         configured — change to console=hvc0 or add serial ports up to hvc3"
+    );
+    assert_eq!(
+        primary_not_shown("ttyS0", "hvc0").to_string(),
+        "console=ttyS0 is the last console= argument, so /dev/console (init's output and the \
+         login prompt) goes there, not to console=hvc0 — move console=hvc0 last"
+    );
+    assert_eq!(uart("ttyS0", 1).severity(), Severity::Warning);
+}
+
+#[test]
+fn configurations() {
+    let dir = TempDir::new("console-preflight");
+    // The fixture boots with console=hvc0 and has no serial port.
+    assert_eq!(
+        test_support::minimal_linux_config(&dir).console_preflight(),
+        vec![missing("hvc0", 0, 0)]
+    );
+
+    let capture = ConsoleCapture::new().unwrap();
+    let conf = test_support::minimal_builder()
+        .boot_loader(test_support::garbage_linux_boot_loader(&dir))
+        .serial_ports(vec![VZVirtioConsoleDeviceSerialPortConfiguration::new(
+            capture.attachment(),
+        )])
+        .build();
+    assert!(conf.console_preflight().is_empty());
+
+    if HostCapabilities::detect().supports_class("VZEFIBootLoader") {
+        assert_eq!(
+            test_support::minimal_builder()
+                .boot_loader(test_support::efi_boot_loader(&dir))
+                .build()
+                .console_preflight(),
+            vec![ConsoleFinding::EfiBoot]
+        );
+    }
+}
@@ -0,0 +1,73 @@
+//! `ConsoleTee` drains the guest's output with a dispatch source: output written to the
+//! attachment reaches the log, the subscribers and a transcript, and closing the tee ends their
+//! feeds.
+
+#![cfg(target_os = "macos")]
+
+extern crate virtualization_rs;
+
+use virtualization_rs::test_support::TempDir;
+use virtualization_rs::virtualization::console_tee::{ConsoleTee, TeeMessage};
+use virtualization_rs::virtualization::console_transcript::{ConsoleTranscript, Direction};
+
+use std::fs;
+use std::sync::mpsc::RecvTimeoutError;
+use std::time::Duration;
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+#[test]
+fn guest_output_reaches_the_log_and_subscribers() {
+    let dir = TempDir::new("console-tee");
+    let log = dir.path().join("console.log");
+    let tee = ConsoleTee::new(&log, 1024, 2).unwrap();
+    let live = tee.subscribe();
+
+    let guest = tee.attachment().file_handle_for_writing().unwrap();
+    guest.write_all(b"booting\n").unwrap();
+    match live.recv_timeout(TIMEOUT) {
+        Ok(TeeMessage::Data(bytes)) => assert_eq!(&bytes[..], b"booting\n"),
+        other => panic!("unexpected {:?}", other),
+    }
+    guest.write_all(b"login: ").unwrap();
+    match live.recv_timeout(TIMEOUT) {
+        Ok(TeeMessage::Data(bytes)) => assert_eq!(&bytes[..], b"login: "),
+        other => panic!("unexpected {:?}", other),
+    }
+    drop(guest);
+
+    tee.close().unwrap();
+    // The source sees end of file once the guest's end is closed, and ends the feed.
+    assert_eq!(
+        live.recv_timeout(TIMEOUT).err(),
+        Some(RecvTimeoutError::Disconnected)
+    );
+    assert_eq!(fs::read(&log).unwrap(), b"booting\nlogin: ");
+}
+
+#[test]
+fn output_is_recorded_to_a_transcript() {
+    let dir = TempDir::new("console-tee-transcript");
+    let tee = ConsoleTee::new(dir.path().join("console.log"), 1024, 2).unwrap();
+    let transcript = dir.path().join("console.vzct");
+    tee.record_to(&transcript).unwrap();
+    let live = tee.subscribe();
+
+    let guest = tee.attachment().file_handle_for_writing().unwrap();
+    guest.write_all(b"booting\nlogin: ").unwrap();
+    drop(guest);
+    tee.close().unwrap();
+    while live.recv_timeout(TIMEOUT).is_ok() {}
+
+    let transcript = ConsoleTranscript::load(&transcript).unwrap();
+    assert!(!transcript.truncated());
+    let lines: Vec<_> = transcript
+        .lines()
+        .into_iter()
+        .map(|line| line.text)
+        .collect();
+    assert_eq!(lines, ["booting", "login: "]);
+    assert!(transcript
+        .iter()
+        .all(|record| record.direction == Direction::Output));
+}
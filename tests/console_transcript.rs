@@ -0,0 +1,244 @@
+//! Console transcripts: a recording reads back as written, damage is told from a recorder killed
+//! mid-write, and the diff of a crafted regression against its baseline reports the missing
+//! line and the slow mount, and nothing else.
+
+#![cfg(target_os = "macos")]
+
+extern crate virtualization_rs;
+
+use virtualization_rs::test_support::TempDir;
+use virtualization_rs::virtualization::console_transcript::{
+    self, ConsoleRecorder, ConsoleTranscript, DiffEntry, DiffTolerance, Direction, TranscriptError,
+    TranscriptRecord, MAX_RECORD_LEN, TRANSCRIPT_VERSION,
+};
+
+use std::time::Duration;
+
+const BASELINE: &[u8] = include_bytes!("data/console_boot_baseline.vzct");
+/// The baseline without its `renamed from eth0` line, with the root filesystem mounted two
+/// seconds later and everything after it late by as much.
+const REGRESSED: &[u8] = include_bytes!("data/console_boot_regressed.vzct");
+/// The baseline with the last record cut short.
+const TRUNCATED: &[u8] = include_bytes!("data/console_boot_truncated.vzct");
+const BASELINE_TEXT: &str = include_str!("data/console_boot_baseline.txt");
+
+fn header(version: u16) -> Vec<u8> {
+    let mut bytes = b"VZCT".to_vec();
+    bytes.extend_from_slice(&version.to_le_bytes());
+    bytes.extend_from_slice(&[0, 0]);
+    bytes
+}
+
+fn record(bytes: &mut Vec<u8>, len: u32, offset_us: u64, direction: u8, data: &[u8]) {
+    bytes.extend_from_slice(&len.to_le_bytes());
+    bytes.extend_from_slice(&offset_us.to_le_bytes());
+    bytes.push(direction);
+    bytes.extend_from_slice(data);
+}
+
+#[test]
+fn baseline_renders_as_text() {
+    let baseline = ConsoleTranscript::parse(BASELINE).unwrap();
+    assert_eq!(baseline.version(), TRANSCRIPT_VERSION);
+    assert_eq!(baseline.records().len(), 10);
+    assert!(!baseline.truncated());
+    assert_eq!(baseline.render_text(), BASELINE_TEXT);
+}
+
+#[test]
+fn lines_keep_each_direction_apart() {
+    let baseline = ConsoleTranscript::parse(BASELINE).unwrap();
+    let lines = baseline.lines();
+    // The echoed user name completes the prompt; what was typed is a line of its own.
+    let login = lines
+        .iter()
+        .position(|line| line.text == "debian login: root");
+    let typed = lines
+        .iter()
+        .position(|line| line.direction == Direction::Input)
+        .unwrap();
+    assert_eq!(lines[typed].text, "root");
+    assert_eq!(lines[typed].offset, Duration::from_secs(5));
+    assert!(login.unwrap() < typed);
+    // The password prompt is still open at the end.
+    assert_eq!(lines.last().unwrap().text, "Password: ");
+}
+
+#[test]
+fn a_truncated_record_is_dropped() {
+    let truncated = ConsoleTranscript::parse(TRUNCATED).unwrap();
+    let baseline = ConsoleTranscript::parse(BASELINE).unwrap();
+    assert!(truncated.truncated());
+    assert_eq!(truncated.records(), &baseline.records()[..9]);
+
+    // Only the last record may be short: a header cut short is still a transcript.
+    let mut bytes = header(TRANSCRIPT_VERSION);
+    bytes.extend_from_slice(&[3, 0, 0]);
+    let cut = ConsoleTranscript::parse(&bytes).unwrap();
+    assert!(cut.truncated());
+    assert!(cut.records().is_empty());
+}
+
+#[test]
+fn damage_fails_the_load() {
+    match ConsoleTranscript::parse(b"[    0.000000] Booting Linux") {
+        Err(TranscriptError::NotATranscript) => {}
+        other => panic!("unexpected {:?}", other),
+    }
+    match ConsoleTranscript::parse(&header(TRANSCRIPT_VERSION + 1)) {
+        Err(TranscriptError::UnsupportedVersion(version)) => {
+            assert_eq!(version, TRANSCRIPT_VERSION + 1)
+        }
+        other => panic!("unexpected {:?}", other),
+    }
+
+    let mut unknown_direction = header(TRANSCRIPT_VERSION);
+    record(&mut unknown_direction, 2, 0, 0, b"ok");
+    record(&mut unknown_direction, 2, 10, 7, b"ok");
+    match ConsoleTranscript::parse(&unknown_direction) {
+        Err(TranscriptError::Corrupt { offset, .. }) => assert_eq!(offset, 8 + 13 + 2),
+        other => panic!("unexpected {:?}", other),
+    }
+
+    // A length beyond the limit is damage even at the end of the file.
+    let mut too_long = header(TRANSCRIPT_VERSION);
+    record(&mut too_long, MAX_RECORD_LEN as u32 + 1, 0, 0, b"");
+    match ConsoleTranscript::parse(&too_long) {
+        Err(TranscriptError::Corrupt { offset, .. }) => assert_eq!(offset, 8),
+        other => panic!("unexpected {:?}", other),
+    }
+}
+
+#[test]
+fn recordings_read_back() {
+    let dir = TempDir::new("console-transcript");
+    let path = dir.path().join("run.vzct");
+    let long = vec![b'x'; MAX_RECORD_LEN + 5];
+
+    let mut recorder = ConsoleRecorder::create(&path).unwrap();
+    recorder
+        .record_at(Duration::from_millis(120), Direction::Output, b"login: ")
+        .unwrap();
+    recorder
+        .record_at(Duration::from_secs(2), Direction::Input, b"")
+        .unwrap();
+    recorder
+        .record_at(Duration::from_secs(3), Direction::Input, b"root\n")
+        .unwrap();
+    recorder
+        .record_at(Duration::from_secs(4), Direction::Output, &long)
+        .unwrap();
+    recorder.close().unwrap();
+
+    let transcript = ConsoleTranscript::load(&path).unwrap();
+    assert!(!transcript.truncated());
+    assert_eq!(
+        transcript.records()[..2],
+        [
+            TranscriptRecord {
+                offset: Duration::from_millis(120),
+                direction: Direction::Output,
+                bytes: b"login: ".to_vec(),
+            },
+            TranscriptRecord {
+                offset: Duration::from_secs(3),
+                direction: Direction::Input,
+                bytes: b"root\n".to_vec(),
+            },
+        ]
+    );
+    // The long chunk is split, every part seen at the same time.
+    let parts: Vec<_> = transcript.iter().skip(2).collect();
+    assert_eq!(parts.len(), 2);
+    assert_eq!(parts[0].bytes.len(), MAX_RECORD_LEN);
+    assert_eq!(parts[1].bytes.len(), 5);
+    assert!(parts
+        .iter()
+        .all(|part| part.offset == Duration::from_secs(4)));
+}
+
+#[test]
+fn a_transcript_does_not_differ_from_itself() {
+    let baseline = ConsoleTranscript::parse(BASELINE).unwrap();
+    let diff = console_transcript::diff(&baseline, &baseline, DiffTolerance::default());
+    assert!(diff.is_empty());
+    assert!(diff.aligned());
+    assert_eq!(diff.to_string(), "");
+}
+
+#[test]
+fn a_regressed_boot_shows_the_missing_line_and_the_slow_mount() {
+    let baseline = ConsoleTranscript::parse(BASELINE).unwrap();
+    let regressed = ConsoleTranscript::parse(REGRESSED).unwrap();
+    let diff = console_transcript::diff(&baseline, &regressed, DiffTolerance::default());
+    assert!(diff.aligned());
+
+    // Kernel timestamps differ on every line, and everything after the mount is two seconds
+    // late: neither is reported.
+    match diff.entries() {
+        [DiffEntry::Deleted(renamed), DiffEntry::Timing { a, b, delta_us }] => {
+            assert!(renamed
+                .text
+                .ends_with("virtio_net virtio1 enp0s1: renamed from eth0"));
+            assert!(a.text.contains("EXT4-fs (vda1): mounted filesystem"));
+            assert_eq!(a.offset, Duration::from_micros(1_204_000));
+            assert_eq!(b.offset, Duration::from_micros(3_210_000));
+            assert_eq!(*delta_us, 2_001_250);
+        }
+        other => panic!("unexpected {:#?}", other),
+    }
+    assert_eq!(
+        diff.to_string(),
+        "- [    0.652000] [    0.531877] virtio_net virtio1 enp0s1: renamed from eth0\n\
+         ~ [    3.210000] [    3.089967] EXT4-fs (vda1): mounted filesystem with ordered data mode \
+         (+2.001250 s)\n"
+    );
+
+    // The other way round, the line is an insertion and the mount got faster.
+    let reverse = console_transcript::diff(&regressed, &baseline, DiffTolerance::default());
+    match reverse.entries() {
+        [DiffEntry::Inserted(renamed), DiffEntry::Timing { delta_us, .. }] => {
+            assert!(renamed.text.ends_with("renamed from eth0"));
+            assert_eq!(*delta_us, -2_001_250);
+        }
+        other => panic!("unexpected {:#?}", other),
+    }
+}
+
+#[test]
+fn tolerances_bound_pairing_and_reporting() {
+    let baseline = ConsoleTranscript::parse(BASELINE).unwrap();
+    let regressed = ConsoleTranscript::parse(REGRESSED).unwrap();
+
+    // Reporting only changes over three seconds leaves the missing line.
+    let lenient = DiffTolerance {
+        report_beyond: Duration::from_secs(3),
+        ..DiffTolerance::default()
+    };
+    match console_transcript::diff(&baseline, &regressed, lenient).entries() {
+        [DiffEntry::Deleted(_)] => {}
+        other => panic!("unexpected {:#?}", other),
+    }
+
+    // Lines a second apart do not pair: everything after the mount is deleted and inserted.
+    let strict = DiffTolerance {
+        align_within: Duration::from_secs(1),
+        ..DiffTolerance::default()
+    };
+    let diff = console_transcript::diff(&baseline, &regressed, strict);
+    let deleted = diff
+        .entries()
+        .iter()
+        .filter(|entry| matches!(entry, DiffEntry::Deleted(_)))
+        .count();
+    let inserted = diff
+        .entries()
+        .iter()
+        .filter(|entry| matches!(entry, DiffEntry::Inserted(_)))
+        .count();
+    assert_eq!((deleted, inserted), (9, 8));
+    assert!(!diff
+        .entries()
+        .iter()
+        .any(|entry| matches!(entry, DiffEntry::Timing { .. })));
+}
@@ -0,0 +1,234 @@
+//! Device-count limits: the table on each macOS release, each violation it knows planted in a
+//! configuration and refused by `validated_build` with its category, and configurations at the
+//! limits passing it and the framework's validation alike.
+
+#![cfg(target_os = "macos")]
+
+extern crate virtualization_rs;
+
+use virtualization_rs::features::{self, HostCapabilities, OsVersion};
+use virtualization_rs::limits::{self, DeviceLimit, DeviceLimitExceeded, DeviceLimits};
+use virtualization_rs::test_support::{self, TempDir};
+use virtualization_rs::virtualization::entropy_device::VZVirtioEntropyDeviceConfiguration;
+use virtualization_rs::virtualization::error::BuildError;
+use virtualization_rs::virtualization::graphics_device::{
+    VZVirtioGraphicsDeviceConfiguration, VZVirtioGraphicsScanoutConfiguration,
+};
+use virtualization_rs::virtualization::memory_device::VZVirtioTraditionalMemoryBalloonDeviceConfiguration;
+use virtualization_rs::virtualization::socket_device::VZVirtioSocketDeviceConfiguration;
+use virtualization_rs::virtualization::virtual_machine::VZVirtualMachineConfigurationBuilder;
+
+const BIG_SUR: OsVersion = OsVersion::new(11, 7, 10);
+const MONTEREY: OsVersion = OsVersion::new(12, 0, 0);
+const VENTURA: OsVersion = OsVersion::new(13, 6, 1);
+
+fn graphics_device() -> VZVirtioGraphicsDeviceConfiguration {
+    VZVirtioGraphicsDeviceConfiguration::new(vec![VZVirtioGraphicsScanoutConfiguration::new(
+        1024, 768,
+    )])
+}
+
+fn has_virtio_graphics() -> bool {
+    HostCapabilities::detect().supports_class("VZVirtioGraphicsDeviceConfiguration")
+}
+
+/// The minimal builder, which has an entropy device, booting garbage files in `dir`.
+fn builder(dir: &TempDir) -> VZVirtualMachineConfigurationBuilder {
+    test_support::minimal_builder().boot_loader(test_support::garbage_linux_boot_loader(dir))
+}
+
+fn too_many(builder: VZVirtualMachineConfigurationBuilder) -> DeviceLimitExceeded {
+    match builder.validated_build() {
+        Err(BuildError::TooManyDevices(exceeded)) => exceeded,
+        Err(other) => panic!("expected too many devices, got {}", other),
+        Ok(conf) => panic!("built with too many devices:\n{}", conf.describe()),
+    }
+}
+
+#[test]
+fn the_table_follows_the_release() {
+    let big_sur = DeviceLimits::for_os_version(BIG_SUR);
+    assert_eq!(big_sur.os_version(), BIG_SUR);
+    assert_eq!(
+        big_sur.get("graphics_devices"),
+        Some(DeviceLimit::Unavailable { since: "macOS 12" })
+    );
+    assert_eq!(
+        big_sur.get("keyboards"),
+        Some(DeviceLimit::Unavailable { since: "macOS 12" })
+    );
+    assert_eq!(
+        big_sur.get("memory_balloon_devices"),
+        Some(DeviceLimit::AtMost(1))
+    );
+    assert_eq!(
+        big_sur.get("storage_devices"),
+        Some(DeviceLimit::Undocumented)
+    );
+
+    let monterey = DeviceLimits::for_os_version(MONTEREY);
+    assert_eq!(
+        monterey.get("graphics_devices"),
+        Some(DeviceLimit::AtMost(1))
+    );
+    assert_eq!(monterey.get("keyboards"), Some(DeviceLimit::Undocumented));
+    assert_eq!(
+        monterey.get("console_devices"),
+        Some(DeviceLimit::Unavailable { since: "macOS 13" })
+    );
+    assert_eq!(
+        DeviceLimits::for_os_version(VENTURA).get("console_devices"),
+        Some(DeviceLimit::Undocumented)
+    );
+    assert_eq!(monterey.get("usb_controllers"), None);
+
+    // Every device array of a configuration, in the order `describe` lists them.
+    let categories: Vec<&str> = monterey.iter().map(|(category, _)| category).collect();
+    assert_eq!(
+        categories,
+        [
+            "storage_devices",
+            "network_devices",
+            "serial_ports",
+            "entropy_devices",
+            "memory_balloon_devices",
+            "socket_devices",
+            "directory_sharing_devices",
+            "graphics_devices",
+            "keyboards",
+            "pointing_devices",
+            "audio_devices",
+            "console_devices",
+        ]
+    );
+    assert_eq!(limits::device_limits().os_version(), features::os_version());
+}
+
+#[test]
+fn each_limit_is_checked_at_and_past_it() {
+    let ventura = DeviceLimits::for_os_version(VENTURA);
+    for category in [
+        "entropy_devices",
+        "memory_balloon_devices",
+        "socket_devices",
+        "graphics_devices",
+    ] {
+        assert_eq!(ventura.check(category, 1), Ok(()), "{}", category);
+        assert!(ventura.allows_another(category, 0), "{}", category);
+        assert!(!ventura.allows_another(category, 1), "{}", category);
+        assert_eq!(
+            ventura.check(category, 2),
+            Err(DeviceLimitExceeded {
+                category,
+                configured: 2,
+                limit: DeviceLimit::AtMost(1),
+            })
+        );
+    }
+    assert_eq!(ventura.check("storage_devices", 64), Ok(()));
+    assert!(ventura.allows_another("storage_devices", 64));
+    assert!(ventura.allows_another("usb_controllers", 8));
+
+    let big_sur = DeviceLimits::for_os_version(BIG_SUR);
+    assert_eq!(big_sur.check("audio_devices", 0), Ok(()));
+    assert!(!big_sur.allows_another("audio_devices", 0));
+    assert_eq!(
+        big_sur.check("audio_devices", 1).unwrap_err().to_string(),
+        "audio_devices: 1 configured, but this system has none; they need macOS 12"
+    );
+}
+
+#[test]
+fn a_second_entropy_device_is_refused() {
+    let dir = TempDir::new("limits-entropy");
+    let exceeded = too_many(builder(&dir).entropy_devices(vec![
+        VZVirtioEntropyDeviceConfiguration::new(),
+        VZVirtioEntropyDeviceConfiguration::new(),
+    ]));
+    assert_eq!(
+        exceeded.to_string(),
+        "entropy_devices: 2 configured, the framework allows at most 1"
+    );
+}
+
+#[test]
+fn a_second_memory_balloon_is_refused() {
+    let dir = TempDir::new("limits-balloon");
+    let exceeded = too_many(builder(&dir).memory_balloon_devices(vec![
+        VZVirtioTraditionalMemoryBalloonDeviceConfiguration::new(),
+        VZVirtioTraditionalMemoryBalloonDeviceConfiguration::new(),
+    ]));
+    assert_eq!(exceeded.category, "memory_balloon_devices");
+    assert_eq!(exceeded.configured, 2);
+}
+
+#[test]
+fn a_second_socket_device_is_refused() {
+    let dir = TempDir::new("limits-socket");
+    let exceeded = too_many(builder(&dir).socket_devices(vec![
+        VZVirtioSocketDeviceConfiguration::new(),
+        VZVirtioSocketDeviceConfiguration::new(),
+        VZVirtioSocketDeviceConfiguration::new(),
+    ]));
+    assert_eq!(
+        exceeded.to_string(),
+        "socket_devices: 3 configured, the framework allows at most 1"
+    );
+}
+
+#[test]
+fn a_second_graphics_device_is_refused() {
+    if !has_virtio_graphics() {
+        return;
+    }
+    let dir = TempDir::new("limits-graphics");
+    let exceeded =
+        too_many(builder(&dir).graphics_devices(vec![graphics_device(), graphics_device()]));
+    assert_eq!(exceeded.category, "graphics_devices");
+}
+
+#[test]
+fn the_first_violation_is_reported_and_all_are_listed() {
+    let dir = TempDir::new("limits-several");
+    let over_two_limits = || {
+        builder(&dir)
+            .socket_devices(vec![
+                VZVirtioSocketDeviceConfiguration::new(),
+                VZVirtioSocketDeviceConfiguration::new(),
+            ])
+            .memory_balloon_devices(vec![
+                VZVirtioTraditionalMemoryBalloonDeviceConfiguration::new(),
+                VZVirtioTraditionalMemoryBalloonDeviceConfiguration::new(),
+            ])
+    };
+    let violations = over_two_limits().build().device_limit_violations();
+    let categories: Vec<&str> = violations.iter().map(|v| v.category).collect();
+    assert_eq!(categories, ["memory_balloon_devices", "socket_devices"]);
+    assert_eq!(
+        too_many(over_two_limits()).category,
+        "memory_balloon_devices"
+    );
+}
+
+#[test]
+fn configurations_at_the_limits_build_and_validate() {
+    let dir = TempDir::new("limits-at");
+    let builder = builder(&dir)
+        .memory_balloon_devices(vec![
+            VZVirtioTraditionalMemoryBalloonDeviceConfiguration::new(),
+        ])
+        .socket_devices(vec![VZVirtioSocketDeviceConfiguration::new()]);
+    let builder = if has_virtio_graphics() {
+        builder.graphics_devices(vec![graphics_device()])
+    } else {
+        builder
+    };
+    let conf = builder
+        .validated_build()
+        .unwrap_or_else(|e| panic!("{}", e));
+    assert!(conf.device_limit_violations().is_empty());
+    assert_eq!(conf.device_count("entropy_devices"), Some(1));
+    assert!(conf
+        .validate_with_error()
+        .unwrap_or_else(|e| panic!("{}", e)));
+}
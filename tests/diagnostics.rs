@@ -0,0 +1,277 @@
+//! Diagnostics: `log show` output parses the same in its default and JSON styles, from captured
+//! fixtures, and a report about a configuration or a failed start lands in a bundle directory.
+
+#![cfg(target_os = "macos")]
+
+extern crate serde_json;
+extern crate virtualization_rs;
+
+use virtualization_rs::diagnostics::{
+    self, parse_entitlements, parse_log_show, EntitlementCheck, LogEntry,
+};
+use virtualization_rs::virtualization::boot_loader::VZLinuxBootLoaderBuilder;
+use virtualization_rs::virtualization::virtual_machine::{
+    StartOutcome, VZVirtualMachine, VZVirtualMachineConfiguration,
+    VZVirtualMachineConfigurationBuilder, VZVirtualMachineState,
+};
+
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+const DEFAULT_STYLE: &str = include_str!("data/log_show_default.txt");
+const JSON_STYLE: &str = include_str!("data/log_show.json");
+const NDJSON_STYLE: &str = include_str!("data/log_show.ndjson");
+const ENTITLEMENTS: &str = include_str!("data/entitlements.plist");
+
+const SERVICE: &str = "com.apple.Virtualization.VirtualMachine";
+
+/// A scratch directory with kernel and initrd files that validate but do not boot.
+struct Scratch(PathBuf);
+
+impl Scratch {
+    fn new(name: &str) -> Scratch {
+        let path = std::env::temp_dir().join(format!(
+            "virtualization-rs-diagnostics-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).unwrap();
+        for name in &["vmlinuz", "initrd"] {
+            File::create(path.join(name))
+                .unwrap()
+                .write_all(&[0x5a; 4096])
+                .unwrap();
+        }
+        Scratch(path)
+    }
+
+    fn conf(&self) -> VZVirtualMachineConfiguration {
+        let boot_loader = VZLinuxBootLoaderBuilder::new()
+            .kernel_url(self.0.join("vmlinuz").to_str().unwrap())
+            .initial_ramdisk_url(self.0.join("initrd").to_str().unwrap())
+            .command_line("console=hvc0")
+            .build()
+            .unwrap();
+        VZVirtualMachineConfigurationBuilder::new()
+            .boot_loader(boot_loader)
+            .cpu_count(1)
+            .memory_size_mib(512)
+            .build()
+    }
+}
+
+impl Drop for Scratch {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+fn entry(
+    timestamp: &str,
+    process: &str,
+    pid: u32,
+    level: &str,
+    category: Option<&str>,
+    message: &str,
+) -> LogEntry {
+    LogEntry {
+        timestamp: timestamp.to_string(),
+        process: process.to_string(),
+        pid: Some(pid),
+        level: level.to_string(),
+        category: category.map(str::to_string),
+        message: message.to_string(),
+    }
+}
+
+fn assert_bundle(dir: &Path) -> serde_json::Value {
+    for name in &["report.txt", "report.json", "configuration.txt", "log.txt"] {
+        assert!(dir.join(name).is_file(), "{} missing", name);
+    }
+    serde_json::from_str(&fs::read_to_string(dir.join("report.json")).unwrap())
+        .expect("report.json is not valid JSON")
+}
+
+#[test]
+fn parses_the_default_style() {
+    let entries = parse_log_show(DEFAULT_STYLE).unwrap();
+    assert_eq!(
+        entries,
+        [
+            entry(
+                "2024-01-15 10:23:45.123456+0100",
+                "simplevm",
+                4242,
+                "Default",
+                Some("VirtualMachine"),
+                "Starting virtual machine"
+            ),
+            entry(
+                "2024-01-15 10:23:45.234567+0100",
+                SERVICE,
+                4243,
+                "Error",
+                Some("Memory"),
+                "Failed to map guest memory:\n    size 0x40000000\n    error 12"
+            ),
+            entry(
+                "2024-01-15 10:23:45.345678+0100",
+                "simplevm",
+                4242,
+                "Fault",
+                None,
+                "Internal error"
+            ),
+            entry(
+                "2024-01-15 10:23:45.456789+0100",
+                "simplevm",
+                4242,
+                "Default",
+                None,
+                "Stopped"
+            ),
+        ]
+    );
+}
+
+#[test]
+fn parses_the_json_styles() {
+    let expected = [
+        entry(
+            "2024-01-15 10:23:45.123456+0100",
+            "simplevm",
+            4242,
+            "Default",
+            Some("VirtualMachine"),
+            "Starting virtual machine",
+        ),
+        entry(
+            "2024-01-15 10:23:45.234567+0100",
+            SERVICE,
+            4243,
+            "Error",
+            Some("Memory"),
+            "Failed to map guest memory:\n\tsize 0x40000000 \u{2014} caf\u{e9} \u{1f4a5}",
+        ),
+        entry(
+            "2024-01-15 10:23:45.345678+0100",
+            "simplevm",
+            4242,
+            "Fault",
+            None,
+            "Internal error \"VZErrorInternal\"",
+        ),
+    ];
+    assert_eq!(parse_log_show(JSON_STYLE).unwrap(), expected);
+
+    let ndjson = parse_log_show(NDJSON_STYLE).unwrap();
+    assert_eq!(ndjson.len(), 2);
+    assert_eq!(ndjson[0], expected[0]);
+    assert_eq!(ndjson[1].process, SERVICE);
+    assert_eq!(ndjson[1].message, "Failed to map guest memory");
+
+    assert_eq!(parse_log_show("[]").unwrap(), []);
+    assert_eq!(parse_log_show("").unwrap(), []);
+}
+
+#[test]
+fn rejects_truncated_json() {
+    let cut = &JSON_STYLE[..JSON_STYLE.len() / 2];
+    let error = parse_log_show(cut).unwrap_err();
+    assert!(error.offset <= cut.len());
+    assert!(error
+        .to_string()
+        .starts_with("malformed log show output at byte "));
+    assert!(parse_log_show("[{\"eventMessage\": \"\\x\"}]").is_err());
+    assert!(parse_log_show("[1] 2").is_err());
+}
+
+#[test]
+fn reads_entitlements() {
+    assert_eq!(
+        parse_entitlements(ENTITLEMENTS),
+        [
+            EntitlementCheck {
+                name: "com.apple.security.virtualization",
+                granted: true,
+            },
+            EntitlementCheck {
+                name: "com.apple.vm.networking",
+                granted: false,
+            },
+        ]
+    );
+    assert!(parse_entitlements("").iter().all(|check| !check.granted));
+}
+
+#[test]
+fn reports_on_a_configuration() {
+    let scratch = Scratch::new("configuration");
+    let conf = scratch.conf();
+    let report = diagnostics::collect(&conf, Duration::from_secs(60));
+    assert_eq!(report.pid, std::process::id());
+    assert_eq!(report.window, 60);
+    assert!(report
+        .configuration
+        .starts_with("cpu_count 1\nmemory_size 536870912\n"));
+    assert!(report
+        .configuration
+        .contains("boot_loader VZLinuxBootLoader\n"));
+    assert!(report.machine.is_none());
+    assert!(report.failure.is_none());
+    assert!(report.validation_error.is_none());
+    if let Some(e) = &report.log_error {
+        println!("log show unavailable: {}", e);
+    }
+    assert!(report
+        .log
+        .iter()
+        .all(|e| e.pid == Some(report.pid) || e.process.starts_with(diagnostics::SUBSYSTEM)));
+
+    let dir = scratch.0.join("bundle");
+    report.write_bundle(&dir).unwrap();
+    let json = assert_bundle(&dir);
+    assert_eq!(json["pid"], report.pid);
+    assert_eq!(json["configuration"], report.configuration.as_str());
+    assert_eq!(
+        fs::read_to_string(dir.join("configuration.txt")).unwrap(),
+        report.configuration
+    );
+}
+
+#[test]
+fn collects_on_a_failed_start_only() {
+    let scratch = Scratch::new("failure");
+    let vm = VZVirtualMachine::new_with_qos(scratch.conf(), "diagnostics", None);
+    let dir = scratch.0.join("bundle");
+
+    for outcome in &[StartOutcome::Started, StartOutcome::Cancelled] {
+        assert!(outcome
+            .on_failure_collect_diagnostics(&vm, &dir)
+            .unwrap()
+            .is_none());
+    }
+    assert!(!dir.exists());
+
+    let outcome = StartOutcome::DeadlineExceeded {
+        last_state: VZVirtualMachineState::VZVirtualMachineStateStarting,
+    };
+    let report = outcome
+        .on_failure_collect_diagnostics(&vm, &dir)
+        .unwrap()
+        .expect("a deadline exceeded start is a failure");
+    assert_eq!(report.machine, Some(vm.display_name()));
+    assert_eq!(report.configuration, vm.describe_configuration());
+    assert_eq!(
+        report.failure.as_deref(),
+        Some("not running by the deadline, last state starting")
+    );
+    let json = assert_bundle(&dir);
+    assert_eq!(json["machine"], vm.display_name().as_str());
+    assert!(fs::read_to_string(dir.join("report.txt"))
+        .unwrap()
+        .contains("failure not running by the deadline"));
+}
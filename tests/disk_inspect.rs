@@ -0,0 +1,312 @@
+//! Partition tables of crafted images must be read back as written, damage must fall back to the
+//! backup GPT, and EFI boot must be warned about a single disk without an EFI system partition.
+
+#![cfg(target_os = "macos")]
+
+extern crate virtualization_rs;
+
+use virtualization_rs::disk_image::{self, DiskInspection, Guid, PartitionTable};
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+const SECTOR: u64 = 512;
+/// Sectors of the test images: 4 MiB.
+const SECTORS: u64 = 8192;
+const ENTRIES: u32 = 128;
+const ENTRY_LEN: u32 = 128;
+/// Sectors of the partition entry array.
+const ENTRY_SECTORS: u64 = (ENTRIES * ENTRY_LEN) as u64 / SECTOR;
+
+/// `0FC63DAF-8483-4772-8E79-3D69D8477DE4`, a Linux file system.
+const LINUX_DATA: Guid = Guid([
+    0xaf, 0x3d, 0xc6, 0x0f, 0x83, 0x84, 0x72, 0x47, 0x8e, 0x79, 0x3d, 0x69, 0xd8, 0x47, 0x7d, 0xe4,
+]);
+
+/// A scratch directory, removed on drop.
+struct Scratch(PathBuf);
+
+impl Scratch {
+    fn new(name: &str) -> Scratch {
+        let path = std::env::temp_dir().join(format!(
+            "virtualization-rs-inspect-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).unwrap();
+        Scratch(path)
+    }
+
+    fn path(&self, name: &str) -> PathBuf {
+        self.0.join(name)
+    }
+}
+
+impl Drop for Scratch {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+struct Partition {
+    type_guid: Guid,
+    name: &'static str,
+    first_lba: u64,
+    last_lba: u64,
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+fn write_at(path: &Path, offset: u64, data: &[u8]) {
+    let mut file = OpenOptions::new().write(true).open(path).unwrap();
+    file.seek(SeekFrom::Start(offset)).unwrap();
+    file.write_all(data).unwrap();
+}
+
+/// A sparse image of [`SECTORS`] sectors.
+fn blank_image(path: &Path) {
+    File::create(path)
+        .unwrap()
+        .set_len(SECTORS * SECTOR)
+        .unwrap();
+}
+
+fn mbr(types: &[u8]) -> Vec<u8> {
+    let mut mbr = vec![0u8; SECTOR as usize];
+    for (i, &t) in types.iter().enumerate() {
+        let entry = 0x1be + i * 16;
+        mbr[entry + 4] = t;
+        mbr[entry + 8..entry + 12].copy_from_slice(&1u32.to_le_bytes());
+        mbr[entry + 12..entry + 16].copy_from_slice(&((SECTORS - 1) as u32).to_le_bytes());
+    }
+    mbr[0x1fe] = 0x55;
+    mbr[0x1ff] = 0xaa;
+    mbr
+}
+
+fn gpt_header(my_lba: u64, alternate_lba: u64, entries_lba: u64, entries_crc: u32) -> Vec<u8> {
+    let mut header = vec![0u8; 92];
+    header[..8].copy_from_slice(b"EFI PART");
+    header[8..12].copy_from_slice(&0x0001_0000u32.to_le_bytes());
+    header[12..16].copy_from_slice(&92u32.to_le_bytes());
+    header[24..32].copy_from_slice(&my_lba.to_le_bytes());
+    header[32..40].copy_from_slice(&alternate_lba.to_le_bytes());
+    header[40..48].copy_from_slice(&(2 + ENTRY_SECTORS).to_le_bytes());
+    header[48..56].copy_from_slice(&(SECTORS - 2 - ENTRY_SECTORS).to_le_bytes());
+    header[56..72].copy_from_slice(&[0x42; 16]);
+    header[72..80].copy_from_slice(&entries_lba.to_le_bytes());
+    header[80..84].copy_from_slice(&ENTRIES.to_le_bytes());
+    header[84..88].copy_from_slice(&ENTRY_LEN.to_le_bytes());
+    header[88..92].copy_from_slice(&entries_crc.to_le_bytes());
+    let crc = crc32(&header);
+    header[16..20].copy_from_slice(&crc.to_le_bytes());
+    header
+}
+
+/// A GPT image with both copies of the table.
+fn gpt_image(path: &Path, partitions: &[Partition]) {
+    blank_image(path);
+    let mut entries = vec![0u8; (ENTRIES * ENTRY_LEN) as usize];
+    for (i, p) in partitions.iter().enumerate() {
+        let entry = &mut entries[i * ENTRY_LEN as usize..];
+        entry[..16].copy_from_slice(&p.type_guid.0);
+        entry[16..32].copy_from_slice(&[i as u8 + 1; 16]);
+        entry[32..40].copy_from_slice(&p.first_lba.to_le_bytes());
+        entry[40..48].copy_from_slice(&p.last_lba.to_le_bytes());
+        for (j, unit) in p.name.encode_utf16().enumerate() {
+            entry[56 + j * 2..58 + j * 2].copy_from_slice(&unit.to_le_bytes());
+        }
+    }
+    let entries_crc = crc32(&entries);
+    let last = SECTORS - 1;
+    let backup_entries = last - ENTRY_SECTORS;
+    write_at(path, 0, &mbr(&[0xee]));
+    write_at(path, SECTOR, &gpt_header(1, last, 2, entries_crc));
+    write_at(path, 2 * SECTOR, &entries);
+    write_at(path, backup_entries * SECTOR, &entries);
+    write_at(
+        path,
+        last * SECTOR,
+        &gpt_header(last, 1, backup_entries, entries_crc),
+    );
+}
+
+fn efi_and_root() -> Vec<Partition> {
+    vec![
+        Partition {
+            type_guid: Guid::EFI_SYSTEM_PARTITION,
+            name: "EFI System Partition",
+            first_lba: 2048,
+            last_lba: 4095,
+        },
+        Partition {
+            type_guid: LINUX_DATA,
+            name: "root",
+            first_lba: 4096,
+            last_lba: 8000,
+        },
+    ]
+}
+
+fn warnings(path: &Path, disk: &DiskInspection) -> Vec<String> {
+    disk_image::efi_boot_warnings(&[(path.to_str().unwrap(), disk)])
+}
+
+#[test]
+fn guid_display_is_canonical() {
+    assert_eq!(
+        Guid::EFI_SYSTEM_PARTITION.to_string(),
+        "C12A7328-F81F-11D2-BA4B-00A0C93EC93B"
+    );
+    assert_eq!(
+        LINUX_DATA.to_string(),
+        "0FC63DAF-8483-4772-8E79-3D69D8477DE4"
+    );
+}
+
+#[test]
+fn reads_gpt_partitions() {
+    let scratch = Scratch::new("gpt");
+    let path = scratch.path("disk.img");
+    gpt_image(&path, &efi_and_root());
+    let disk = disk_image::inspect(&path).unwrap();
+    assert_eq!(disk.table, PartitionTable::Gpt { sector_size: 512 });
+    assert_eq!(disk.partition_count(), 2);
+    assert!(disk.has_efi_system_partition);
+    assert!(!disk.is_blank);
+    assert!(disk.warnings.is_empty(), "{:?}", disk.warnings);
+
+    let esp = &disk.partitions[0];
+    assert!(esp.is_efi_system_partition());
+    assert_eq!(esp.name, "EFI System Partition");
+    assert_eq!(esp.size_bytes, 2048 * SECTOR);
+    let root = &disk.partitions[1];
+    assert_eq!(root.index, 1);
+    assert_eq!(root.type_guid, LINUX_DATA);
+    assert_eq!(root.name, "root");
+    assert_eq!(root.size_bytes, 3905 * SECTOR);
+    assert!(warnings(&path, &disk).is_empty());
+}
+
+#[test]
+fn damaged_primary_falls_back_to_backup() {
+    let scratch = Scratch::new("backup");
+    let path = scratch.path("disk.img");
+    gpt_image(&path, &efi_and_root());
+    // A flipped byte in the name of the first entry breaks the CRC of the primary entry array.
+    write_at(&path, 2 * SECTOR + 60, &[0xff]);
+    let disk = disk_image::inspect(&path).unwrap();
+    assert_eq!(disk.table, PartitionTable::Gpt { sector_size: 512 });
+    assert_eq!(disk.partitions[0].name, "EFI System Partition");
+    assert_eq!(disk.warnings.len(), 1);
+    assert!(
+        disk.warnings[0].contains("entry array CRC mismatch"),
+        "{:?}",
+        disk.warnings
+    );
+    assert!(disk.warnings[0].contains("backup"), "{:?}", disk.warnings);
+}
+
+#[test]
+fn damaged_headers_make_an_invalid_gpt() {
+    let scratch = Scratch::new("invalid");
+    let path = scratch.path("disk.img");
+    gpt_image(&path, &efi_and_root());
+    write_at(&path, SECTOR + 40, &[0xff]);
+    write_at(&path, (SECTORS - 1) * SECTOR + 40, &[0xff]);
+    let disk = disk_image::inspect(&path).unwrap();
+    assert_eq!(disk.table, PartitionTable::InvalidGpt);
+    assert!(!disk.has_efi_system_partition);
+    assert_eq!(disk.warnings.len(), 2, "{:?}", disk.warnings);
+    assert!(disk
+        .warnings
+        .iter()
+        .all(|w| w.contains("header CRC mismatch")));
+}
+
+#[test]
+fn sparse_image_is_blank() {
+    let scratch = Scratch::new("blank");
+    let path = scratch.path("disk.img");
+    blank_image(&path);
+    let disk = disk_image::inspect(&path).unwrap();
+    assert_eq!(disk.table, PartitionTable::None);
+    assert_eq!(disk.partition_count(), 0);
+    assert!(disk.is_blank);
+    let warnings = warnings(&path, &disk);
+    assert_eq!(warnings.len(), 1);
+    assert!(warnings[0].contains("blank"), "{:?}", warnings);
+}
+
+#[test]
+fn data_without_a_table_is_not_blank() {
+    let scratch = Scratch::new("data");
+    let path = scratch.path("disk.img");
+    blank_image(&path);
+    write_at(&path, 1024, b"a bare file system");
+    let disk = disk_image::inspect(&path).unwrap();
+    assert_eq!(disk.table, PartitionTable::None);
+    assert!(!disk.is_blank);
+    let warnings = warnings(&path, &disk);
+    assert_eq!(warnings.len(), 1);
+    assert!(
+        warnings[0].contains("no EFI system partition"),
+        "{:?}",
+        warnings
+    );
+}
+
+#[test]
+fn reads_mbr_partitions() {
+    let scratch = Scratch::new("mbr");
+    let path = scratch.path("disk.img");
+    blank_image(&path);
+    write_at(&path, 0, &mbr(&[0x83, 0x82]));
+    let disk = disk_image::inspect(&path).unwrap();
+    assert_eq!(disk.table, PartitionTable::Mbr { partitions: 2 });
+    assert_eq!(disk.partition_count(), 2);
+    assert!(!disk.has_efi_system_partition);
+
+    write_at(&path, 0, &mbr(&[0xef, 0x83]));
+    let disk = disk_image::inspect(&path).unwrap();
+    assert!(disk.has_efi_system_partition);
+}
+
+#[test]
+fn only_single_disks_need_an_esp() {
+    let scratch = Scratch::new("multi");
+    let data = scratch.path("data.img");
+    gpt_image(
+        &data,
+        &[Partition {
+            type_guid: LINUX_DATA,
+            name: "data",
+            first_lba: 2048,
+            last_lba: 8000,
+        }],
+    );
+    let disk = disk_image::inspect(&data).unwrap();
+    assert!(!disk.has_efi_system_partition);
+    assert_eq!(warnings(&data, &disk).len(), 1);
+
+    let boot = scratch.path("boot.img");
+    gpt_image(&boot, &efi_and_root());
+    let other = disk_image::inspect(&boot).unwrap();
+    let disks = [("data.img", &disk), ("boot.img", &other)];
+    assert!(disk_image::efi_boot_warnings(&disks).is_empty());
+}
@@ -0,0 +1,263 @@
+//! Disk image attachments lock their image: read-only ones share it, writable ones exclude
+//! everything else, in this process and across processes, for as long as the framework's
+//! attachment object lives.
+//!
+//! The other process is this test binary again, running `hold_lock_child` alone.
+
+#![cfg(target_os = "macos")]
+
+extern crate virtualization_rs;
+
+use virtualization_rs::runtime::autoreleasepool;
+use virtualization_rs::virtualization::storage_device::{
+    VZDiskImageAttachmentError, VZDiskImageStorageDeviceAttachment,
+    VZDiskImageStorageDeviceAttachmentBuilder, VZVirtioBlockDeviceConfiguration,
+};
+use virtualization_rs::virtualization::virtual_machine::VZVirtualMachineConfigurationBuilder;
+
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Lines, Read};
+use std::path::PathBuf;
+use std::process::{Child, ChildStdout, Command, Stdio};
+
+const CHILD_IMAGE: &str = "VIRTUALIZATION_RS_LOCK_CHILD_IMAGE";
+const CHILD_READ_ONLY: &str = "VIRTUALIZATION_RS_LOCK_CHILD_READ_ONLY";
+const CHILD_READY: &str = "virtualization-rs: image locked";
+
+const OTHER_HOLDER: &str = "another process or attachment holds this image";
+const WRITER_HERE: &str = "a writable attachment in this process holds this image";
+
+struct Scratch(PathBuf);
+
+impl Scratch {
+    fn new(name: &str) -> Scratch {
+        let path = std::env::temp_dir().join(format!(
+            "virtualization-rs-disk-lock-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).unwrap();
+        File::create(path.join("disk.img"))
+            .unwrap()
+            .set_len(1024 * 1024)
+            .unwrap();
+        Scratch(path)
+    }
+
+    fn image(&self) -> String {
+        self.0.join("disk.img").to_str().unwrap().to_string()
+    }
+
+    fn attach(
+        &self,
+        read_only: bool,
+    ) -> Result<VZDiskImageStorageDeviceAttachment, VZDiskImageAttachmentError> {
+        autoreleasepool(|| {
+            VZDiskImageStorageDeviceAttachmentBuilder::new()
+                .path(self.image())
+                .read_only(read_only)
+                .build()
+        })
+    }
+
+    fn assert_busy(&self, read_only: bool, hint: &str) {
+        match self.attach(read_only) {
+            Err(VZDiskImageAttachmentError::ImageBusy { path, holder_hint }) => {
+                assert_eq!(path, self.image());
+                assert_eq!(holder_hint, hint);
+            }
+            Err(e) => panic!("expected ImageBusy, got {:?}", e),
+            Ok(_) => panic!("attached a busy image (read_only={})", read_only),
+        }
+    }
+}
+
+impl Drop for Scratch {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Another process holding an attachment of an image until released.
+struct Holder {
+    child: Child,
+    _stdout: Lines<BufReader<ChildStdout>>,
+}
+
+impl Holder {
+    fn spawn(image: &str, read_only: bool) -> Holder {
+        let mut child = Command::new(std::env::current_exe().unwrap())
+            .args(["hold_lock_child", "--exact", "--ignored", "--nocapture"])
+            .env(CHILD_IMAGE, image)
+            .env(CHILD_READ_ONLY, if read_only { "1" } else { "0" })
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+        let mut stdout = BufReader::new(child.stdout.take().unwrap()).lines();
+        loop {
+            match stdout.next() {
+                Some(Ok(line)) if line == CHILD_READY => break,
+                Some(Ok(_)) => {}
+                _ => panic!(
+                    "the child exited before locking the image: {:?}",
+                    child.wait()
+                ),
+            }
+        }
+        Holder {
+            child,
+            _stdout: stdout,
+        }
+    }
+
+    /// Lets the child drop its attachment and exit.
+    fn release(mut self) {
+        drop(self.child.stdin.take());
+        assert!(self.child.wait().unwrap().success());
+    }
+}
+
+impl Drop for Holder {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+/// Run by [`Holder`] in a child process; does nothing when run directly.
+#[test]
+#[ignore]
+fn hold_lock_child() {
+    let image = match std::env::var(CHILD_IMAGE) {
+        Ok(image) => image,
+        Err(_) => return,
+    };
+    let read_only = std::env::var(CHILD_READ_ONLY).ok().as_deref() == Some("1");
+    let attachment = autoreleasepool(|| {
+        VZDiskImageStorageDeviceAttachmentBuilder::new()
+            .path(image)
+            .read_only(read_only)
+            .build()
+            .unwrap()
+    });
+    println!("{}", CHILD_READY);
+    // Holds the attachment until the parent closes stdin, or dies.
+    io::stdin().read_to_end(&mut Vec::new()).unwrap();
+    drop(attachment);
+}
+
+#[test]
+fn writable_attachments_exclude_everything_else() {
+    let scratch = Scratch::new("writable");
+    let writer = scratch.attach(false).unwrap();
+    scratch.assert_busy(false, WRITER_HERE);
+    scratch.assert_busy(true, WRITER_HERE);
+
+    autoreleasepool(|| drop(writer));
+    drop(scratch.attach(false).unwrap());
+    drop(scratch.attach(true).unwrap());
+}
+
+#[test]
+fn read_only_attachments_share_the_image() {
+    let scratch = Scratch::new("read-only");
+    let first = scratch.attach(true).unwrap();
+    scratch.assert_busy(
+        false,
+        "a read-only attachment in this process holds this image",
+    );
+    let others = vec![scratch.attach(true).unwrap(), scratch.attach(true).unwrap()];
+    scratch.assert_busy(
+        false,
+        "3 read-only attachments in this process hold this image",
+    );
+
+    autoreleasepool(|| drop(first));
+    scratch.assert_busy(
+        false,
+        "2 read-only attachments in this process hold this image",
+    );
+    autoreleasepool(|| drop(others));
+    drop(scratch.attach(false).unwrap());
+}
+
+#[test]
+fn the_lock_follows_the_framework_object() {
+    let scratch = Scratch::new("lifetime");
+    let conf = autoreleasepool(|| {
+        let device = VZVirtioBlockDeviceConfiguration::new(scratch.attach(false).unwrap());
+        VZVirtualMachineConfigurationBuilder::new()
+            .storage_device(device)
+            .build()
+    });
+    // The wrapper and the device configuration are gone; the configuration keeps the lock.
+    scratch.assert_busy(false, WRITER_HERE);
+
+    let clone = conf.clone();
+    autoreleasepool(|| drop(conf));
+    scratch.assert_busy(true, WRITER_HERE);
+    autoreleasepool(|| drop(clone));
+    drop(scratch.attach(false).unwrap());
+}
+
+#[test]
+fn no_lock_neither_takes_nor_checks_the_lock() {
+    let scratch = Scratch::new("no-lock");
+    let writer = scratch.attach(false).unwrap();
+    let unlocked = autoreleasepool(|| {
+        VZDiskImageStorageDeviceAttachmentBuilder::new()
+            .path(scratch.image())
+            .read_only(false)
+            .no_lock()
+            .build()
+            .unwrap()
+    });
+    autoreleasepool(|| drop(writer));
+    drop(scratch.attach(false).unwrap());
+    drop(unlocked);
+}
+
+#[test]
+fn another_process_holds_the_image() {
+    let scratch = Scratch::new("other-process");
+    let holder = Holder::spawn(&scratch.image(), false);
+    scratch.assert_busy(false, OTHER_HOLDER);
+    scratch.assert_busy(true, OTHER_HOLDER);
+    holder.release();
+    drop(scratch.attach(false).unwrap());
+
+    let holder = Holder::spawn(&scratch.image(), true);
+    let reader = scratch.attach(true).unwrap();
+    scratch.assert_busy(
+        false,
+        "a read-only attachment in this process holds this image",
+    );
+    autoreleasepool(|| drop(reader));
+    scratch.assert_busy(false, OTHER_HOLDER);
+    holder.release();
+    drop(scratch.attach(false).unwrap());
+}
+
+#[test]
+fn a_killed_holder_releases_the_image() {
+    let scratch = Scratch::new("killed");
+    let holder = Holder::spawn(&scratch.image(), false);
+    scratch.assert_busy(false, OTHER_HOLDER);
+    drop(holder);
+    drop(scratch.attach(false).unwrap());
+}
+
+#[test]
+fn busy_errors_name_the_image_and_holder() {
+    let scratch = Scratch::new("message");
+    let writer = scratch.attach(false).unwrap();
+    let error = scratch.attach(false).err().unwrap();
+    assert_eq!(
+        error.to_string(),
+        format!("disk image '{}' is busy: {}", scratch.image(), WRITER_HERE)
+    );
+    assert!(std::error::Error::source(&error).is_none());
+    drop(writer);
+}
@@ -0,0 +1,333 @@
+//! Disk image caching and synchronization modes: every pair of the matrix is assessed as
+//! documented, workload suggestions are never dangerous, and the builder warns about, refuses
+//! or panics on dangerous pairs for writable images only. Warnings are captured with a logger.
+
+#![cfg(target_os = "macos")]
+
+extern crate log;
+extern crate virtualization_rs;
+
+use log::{Level, LevelFilter, Log, Metadata, Record};
+
+use virtualization_rs::runtime::autoreleasepool;
+use virtualization_rs::strict::{strict_mode, PANIC_PREFIX};
+use virtualization_rs::virtualization::storage_device::{
+    recommended_for_workload, ModeCombination, VZDiskImageAttachmentError, VZDiskImageCachingMode,
+    VZDiskImageStorageDeviceAttachment, VZDiskImageStorageDeviceAttachmentBuilder,
+    VZDiskImageSynchronizationMode, Workload,
+};
+
+use std::fs::{self, File};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::PathBuf;
+use std::sync::{Mutex, MutexGuard};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    Recommended,
+    Acceptable,
+    Dangerous,
+}
+
+fn kind(assessment: ModeCombination) -> Kind {
+    match assessment {
+        ModeCombination::Recommended => Kind::Recommended,
+        ModeCombination::Acceptable { note } => {
+            assert!(!note.is_empty());
+            Kind::Acceptable
+        }
+        ModeCombination::Dangerous { reason } => {
+            assert!(!reason.is_empty());
+            Kind::Dangerous
+        }
+    }
+}
+
+fn matrix() -> Vec<(VZDiskImageCachingMode, VZDiskImageSynchronizationMode, Kind)> {
+    use VZDiskImageCachingMode as C;
+    use VZDiskImageSynchronizationMode as S;
+    vec![
+        (C::automatic(), S::full(), Kind::Recommended),
+        (C::automatic(), S::fsync(), Kind::Acceptable),
+        (C::automatic(), S::none(), Kind::Dangerous),
+        (C::uncached(), S::full(), Kind::Acceptable),
+        (C::uncached(), S::fsync(), Kind::Acceptable),
+        (C::uncached(), S::none(), Kind::Dangerous),
+        (C::cached(), S::full(), Kind::Recommended),
+        (C::cached(), S::fsync(), Kind::Acceptable),
+        (C::cached(), S::none(), Kind::Dangerous),
+    ]
+}
+
+/// Strict mode and the logger are process-wide, so the tests building attachments take turns.
+fn serial() -> MutexGuard<'static, ()> {
+    static LOCK: Mutex<()> = Mutex::new(());
+    LOCK.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Keeps the crate's warnings for [`warnings`].
+struct Capture;
+
+static WARNINGS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+impl Log for Capture {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= Level::Warn && metadata.target().starts_with("virtualization_rs")
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            WARNINGS
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .push(record.args().to_string());
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+/// The warnings logged since the last call, installing the logger on the first one.
+fn warnings() -> Vec<String> {
+    static LOGGER: Capture = Capture;
+    if log::set_logger(&LOGGER).is_ok() {
+        log::set_max_level(LevelFilter::Warn);
+    }
+    std::mem::take(&mut *WARNINGS.lock().unwrap_or_else(|e| e.into_inner()))
+}
+
+struct Scratch(PathBuf);
+
+impl Scratch {
+    fn new(name: &str) -> Scratch {
+        let path = std::env::temp_dir().join(format!(
+            "virtualization-rs-disk-modes-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).unwrap();
+        File::create(path.join("disk.img"))
+            .unwrap()
+            .set_len(1024 * 1024)
+            .unwrap();
+        Scratch(path)
+    }
+
+    fn image(&self) -> String {
+        self.0.join("disk.img").to_str().unwrap().to_string()
+    }
+
+    fn attach(
+        &self,
+        read_only: bool,
+        caching_mode: VZDiskImageCachingMode,
+        synchronization_mode: VZDiskImageSynchronizationMode,
+        refuse: bool,
+    ) -> Result<VZDiskImageStorageDeviceAttachment, VZDiskImageAttachmentError> {
+        autoreleasepool(|| {
+            let builder = VZDiskImageStorageDeviceAttachmentBuilder::new()
+                .path(self.image())
+                .read_only(read_only)
+                .caching_mode(caching_mode)
+                .synchronization_mode(synchronization_mode);
+            if refuse {
+                builder.refuse_dangerous_modes().build()
+            } else {
+                builder.build()
+            }
+        })
+    }
+}
+
+impl Drop for Scratch {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+#[test]
+fn assesses_every_cell() {
+    for (caching, synchronization, expected) in matrix() {
+        assert_eq!(
+            kind(ModeCombination::assess(caching, synchronization)),
+            expected,
+            "{:?} with {:?}",
+            caching,
+            synchronization
+        );
+    }
+}
+
+#[test]
+fn workload_suggestions_are_not_dangerous() {
+    use VZDiskImageCachingMode as C;
+    use VZDiskImageSynchronizationMode as S;
+    let expected = [
+        (Workload::Ephemeral, C::cached(), S::fsync()),
+        (Workload::Persistent, C::automatic(), S::full()),
+        (Workload::SharedBaseImage, C::cached(), S::full()),
+    ];
+    for &(workload, caching, synchronization) in &expected {
+        assert_eq!(
+            recommended_for_workload(workload),
+            (caching, synchronization)
+        );
+        assert_ne!(
+            kind(ModeCombination::assess(caching, synchronization)),
+            Kind::Dangerous,
+            "{:?}",
+            workload
+        );
+    }
+}
+
+#[test]
+fn refuses_dangerous_cells_when_asked() {
+    let _serial = serial();
+    let scratch = Scratch::new("refuse");
+    warnings();
+    for (caching, synchronization, expected) in matrix() {
+        let result = scratch.attach(false, caching, synchronization, true);
+        match (expected, result) {
+            (Kind::Dangerous, Err(VZDiskImageAttachmentError::DangerousModes { path, .. })) => {
+                assert_eq!(path, scratch.image());
+            }
+            (Kind::Dangerous, other) => panic!(
+                "{:?} with {:?}: expected DangerousModes, got {:?}",
+                caching,
+                synchronization,
+                other.err()
+            ),
+            // Dropped right away, releasing the image lock for the next cell.
+            (_, result) => drop(result.unwrap()),
+        }
+    }
+    assert_eq!(warnings(), Vec::<String>::new());
+}
+
+#[test]
+fn warns_about_dangerous_cells_of_writable_images_only() {
+    let _serial = serial();
+    let scratch = Scratch::new("warn");
+    warnings();
+    for (caching, synchronization, expected) in matrix() {
+        // The warning names the error refusing would have returned.
+        let refused = scratch
+            .attach(false, caching, synchronization, true)
+            .err()
+            .map(|error| format!("{}; attaching anyway", error));
+        drop(
+            scratch
+                .attach(false, caching, synchronization, false)
+                .unwrap(),
+        );
+        drop(
+            scratch
+                .attach(true, caching, synchronization, false)
+                .unwrap(),
+        );
+        let expected: Vec<String> = match expected {
+            Kind::Dangerous => vec![refused.expect("dangerous modes were not refused")],
+            _ => Vec::new(),
+        };
+        assert_eq!(
+            warnings(),
+            expected,
+            "{:?} with {:?}",
+            caching,
+            synchronization
+        );
+    }
+}
+
+#[test]
+fn refused_attachments_leave_the_image_unlocked() {
+    let _serial = serial();
+    let scratch = Scratch::new("unlocked");
+    let error = scratch
+        .attach(
+            false,
+            VZDiskImageCachingMode::cached(),
+            VZDiskImageSynchronizationMode::none(),
+            true,
+        )
+        .err()
+        .unwrap();
+    assert!(
+        error.to_string().starts_with(&format!(
+            "disk image '{}' has dangerous modes, caching cached with synchronization none: ",
+            scratch.image()
+        )),
+        "{}",
+        error
+    );
+    assert!(std::error::Error::source(&error).is_none());
+    drop(
+        scratch
+            .attach(
+                false,
+                VZDiskImageCachingMode::automatic(),
+                VZDiskImageSynchronizationMode::full(),
+                true,
+            )
+            .unwrap(),
+    );
+}
+
+#[test]
+fn read_only_images_take_any_modes() {
+    let _serial = serial();
+    let scratch = Scratch::new("read-only");
+    for (caching, synchronization, _) in matrix() {
+        drop(
+            scratch
+                .attach(true, caching, synchronization, true)
+                .unwrap(),
+        );
+    }
+}
+
+#[test]
+fn dangerous_cells_warn_by_default_and_panic_in_strict_mode() {
+    let _serial = serial();
+    let scratch = Scratch::new("strict");
+    let dangerous = || {
+        scratch.attach(
+            false,
+            VZDiskImageCachingMode::uncached(),
+            VZDiskImageSynchronizationMode::none(),
+            false,
+        )
+    };
+    warnings();
+    drop(dangerous().unwrap());
+    let logged = warnings();
+    assert_eq!(logged.len(), 1, "{:?}", logged);
+    assert!(
+        logged[0].starts_with(&format!(
+            "disk image '{}' has dangerous modes, caching uncached with synchronization none: ",
+            scratch.image()
+        )),
+        "{}",
+        logged[0]
+    );
+    assert!(logged[0].ends_with("; attaching anyway"), "{}", logged[0]);
+
+    strict_mode(true);
+    let result = panic::catch_unwind(AssertUnwindSafe(dangerous));
+    strict_mode(false);
+    // The panic replaces the warning.
+    assert_eq!(warnings(), Vec::<String>::new());
+    let payload = result.err().expect("strict mode attached dangerous modes");
+    let message = payload
+        .downcast_ref::<String>()
+        .cloned()
+        .unwrap_or_default();
+    assert!(message.starts_with(PANIC_PREFIX), "{}", message);
+    assert!(
+        message.ends_with("; choose other modes, e.g. from recommended_for_workload"),
+        "{}",
+        message
+    );
+}
@@ -0,0 +1,143 @@
+//! Reclaiming a raw image punches out its whole all-zero blocks and nothing else: the image keeps
+//! its length and every byte, a partial last block and blocks holding a single byte stay, holes
+//! already there count as zero without being reclaimed again, and the stats add up.
+
+#![cfg(target_os = "macos")]
+
+extern crate virtualization_rs;
+
+use virtualization_rs::disk_image::{self, ReclaimStats};
+use virtualization_rs::test_support::TempDir;
+
+use std::fs::{self, File};
+use std::io::{self, Seek, SeekFrom, Write};
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+
+/// The block size reclaim works in for files in `dir`.
+fn block_size(dir: &TempDir) -> u64 {
+    let probe = dir.path().join("probe");
+    File::create(&probe).unwrap();
+    let block = fs::metadata(&probe).unwrap().blksize().max(512);
+    fs::remove_file(&probe).unwrap();
+    block
+}
+
+fn allocated(path: &Path) -> u64 {
+    fs::metadata(path).unwrap().blocks() * 512
+}
+
+/// Writes `blocks` of `block` bytes, each all zeros or filled with the given byte, followed by
+/// `tail`. Every byte is written, so every block starts out allocated.
+fn image(path: &Path, block: u64, blocks: &[u8], tail: &[u8]) -> Vec<u8> {
+    let mut contents = Vec::new();
+    for &fill in blocks {
+        contents.extend(vec![fill; block as usize]);
+    }
+    contents.extend_from_slice(tail);
+    fs::write(path, &contents).unwrap();
+    contents
+}
+
+fn assert_consistent(stats: &ReclaimStats, path: &Path) {
+    assert_eq!(stats.allocated_after, allocated(path));
+    assert!(stats.allocated_after <= stats.allocated_before);
+    assert_eq!(
+        stats.reclaimed_bytes,
+        stats.allocated_before - stats.allocated_after
+    );
+}
+
+#[test]
+fn zero_runs_are_punched_out() {
+    let dir = TempDir::new("reclaim-runs");
+    let block = block_size(&dir);
+    let path = dir.path().join("disk.img");
+    // Two runs of zero blocks, one in the middle and one at the end before a partial block.
+    let contents = image(&path, block, &[1, 0, 0, 2, 0], &[0; 100]);
+    let before = allocated(&path);
+
+    let stats = disk_image::reclaim(&path).unwrap();
+    assert_eq!(stats.scanned_bytes, contents.len() as u64);
+    assert_eq!(stats.zero_bytes, 3 * block);
+    assert_eq!(stats.allocated_before, before);
+    assert_consistent(&stats, &path);
+    assert!(
+        stats.reclaimed_bytes >= 2 * block,
+        "reclaimed {} of {} bytes in zero blocks",
+        stats.reclaimed_bytes,
+        stats.zero_bytes
+    );
+    assert_eq!(fs::read(&path).unwrap(), contents);
+}
+
+#[test]
+fn a_single_byte_keeps_its_block() {
+    let dir = TempDir::new("reclaim-single-byte");
+    let block = block_size(&dir);
+    let path = dir.path().join("disk.img");
+    let mut contents = image(&path, block, &[0, 0, 0, 0], &[]);
+    // One byte in the second block and the last byte of the image.
+    for offset in [block + block / 2, 4 * block - 1] {
+        let mut file = fs::OpenOptions::new().write(true).open(&path).unwrap();
+        file.seek(SeekFrom::Start(offset)).unwrap();
+        file.write_all(&[0x5a]).unwrap();
+        contents[offset as usize] = 0x5a;
+    }
+
+    let stats = disk_image::reclaim(&path).unwrap();
+    assert_eq!(stats.zero_bytes, 2 * block);
+    assert_consistent(&stats, &path);
+    assert_eq!(fs::read(&path).unwrap(), contents);
+}
+
+#[test]
+fn holes_are_counted_but_not_reclaimed_again() {
+    let dir = TempDir::new("reclaim-again");
+    let block = block_size(&dir);
+    let path = dir.path().join("disk.img");
+    let contents = image(&path, block, &[0, 3, 0, 0], &[]);
+
+    let first = disk_image::reclaim(&path).unwrap();
+    let second = disk_image::reclaim(&path).unwrap();
+    assert_eq!(second.zero_bytes, first.zero_bytes);
+    assert_eq!(second.zero_bytes, 3 * block);
+    assert_eq!(second.allocated_before, first.allocated_after);
+    assert_eq!(second.reclaimed_bytes, 0);
+    assert_consistent(&second, &path);
+    assert_eq!(fs::read(&path).unwrap(), contents);
+}
+
+#[test]
+fn a_sparse_image_has_nothing_to_reclaim() {
+    let dir = TempDir::new("reclaim-sparse");
+    let block = block_size(&dir);
+    let path = dir.path().join("disk.img");
+    // Length set without writing, plus a partial block.
+    File::create(&path)
+        .unwrap()
+        .set_len(64 * block + 7)
+        .unwrap();
+
+    let stats = disk_image::reclaim(&path).unwrap();
+    assert_eq!(stats.scanned_bytes, 64 * block + 7);
+    assert_eq!(stats.zero_bytes, 64 * block);
+    assert_eq!(stats.reclaimed_bytes, 0);
+    assert_consistent(&stats, &path);
+    assert_eq!(fs::metadata(&path).unwrap().len(), 64 * block + 7);
+}
+
+#[test]
+fn an_empty_image_is_scanned_as_such() {
+    let dir = TempDir::new("reclaim-empty");
+    let path = dir.path().join("disk.img");
+    File::create(&path).unwrap();
+    assert_eq!(disk_image::reclaim(&path).unwrap(), ReclaimStats::default());
+}
+
+#[test]
+fn a_missing_image_is_an_error() {
+    let dir = TempDir::new("reclaim-missing");
+    let error = disk_image::reclaim(dir.path().join("missing.img")).unwrap_err();
+    assert_eq!(error.kind(), io::ErrorKind::NotFound);
+}
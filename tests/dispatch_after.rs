@@ -0,0 +1,101 @@
+//! `DispatchQueue::after` and the token it hands out: the closure runs on the queue once the
+//! delay has passed unless its token was cancelled first, cancelling later or cancelling another
+//! closure's token changes nothing, and the token may be cancelled from another thread.
+
+#![cfg(target_os = "macos")]
+
+extern crate virtualization_rs;
+
+use virtualization_rs::base::{CancellationToken, DispatchQueue};
+
+use std::sync::mpsc::{channel, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const DELAY: Duration = Duration::from_millis(100);
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+fn assert_send<T: Send + Sync + 'static>(_: &T) {}
+
+#[test]
+fn runs_on_the_queue_after_the_delay() {
+    let queue = DispatchQueue::new("after-runs");
+    let (sender, ran) = channel();
+    let target = queue.clone();
+    let scheduled = Instant::now();
+    let token = queue.after(DELAY, move || {
+        let _ = sender.send((scheduled.elapsed(), target.is_current()));
+    });
+    let (elapsed, on_queue) = ran.recv_timeout(TIMEOUT).unwrap();
+    assert!(elapsed >= DELAY, "{:?}", elapsed);
+    assert!(elapsed < TIMEOUT, "{:?}", elapsed);
+    assert!(on_queue);
+    assert!(!token.is_cancelled());
+}
+
+#[test]
+fn a_cancelled_token_keeps_the_closure_from_running() {
+    let queue = DispatchQueue::new("after-cancelled");
+    let (sender, ran) = channel::<()>();
+    let token = queue.after(DELAY, move || {
+        let _ = sender.send(());
+    });
+    token.cancel();
+    assert!(token.is_cancelled());
+    // The closure is dropped unrun once the block comes due, which closes the channel.
+    assert_eq!(
+        ran.recv_timeout(TIMEOUT),
+        Err(RecvTimeoutError::Disconnected)
+    );
+}
+
+#[test]
+fn cancelling_after_the_run_changes_nothing() {
+    let queue = DispatchQueue::new("after-stale");
+    let (sender, ran) = channel();
+    let token = queue.after(Duration::from_millis(0), move || {
+        let _ = sender.send(());
+    });
+    assert_eq!(ran.recv_timeout(TIMEOUT), Ok(()));
+    token.cancel();
+    assert_eq!(ran.recv_timeout(DELAY), Err(RecvTimeoutError::Disconnected));
+}
+
+#[test]
+fn a_token_only_cancels_its_own_closure() {
+    let queue = DispatchQueue::new("after-foreign");
+    let (sender, ran) = channel();
+    let first = sender.clone();
+    let cancelled = queue.after(DELAY, move || {
+        let _ = first.send("cancelled");
+    });
+    let kept = queue.after(DELAY, move || {
+        let _ = sender.send("kept");
+    });
+    cancelled.cancel();
+    // An unrelated token cancelled as well does not reach either closure.
+    CancellationToken::new().cancel();
+    assert_eq!(ran.recv_timeout(TIMEOUT), Ok("kept"));
+    assert_eq!(
+        ran.recv_timeout(TIMEOUT),
+        Err(RecvTimeoutError::Disconnected)
+    );
+    assert!(!kept.is_cancelled());
+}
+
+#[test]
+fn the_token_is_cancelled_from_another_thread() {
+    let queue = DispatchQueue::new("after-threads");
+    let (sender, ran) = channel::<()>();
+    let token = queue.after(DELAY * 5, move || {
+        let _ = sender.send(());
+    });
+    assert_send(&token);
+    let canceller = token.clone();
+    thread::spawn(move || canceller.cancel()).join().unwrap();
+    assert!(token.is_cancelled());
+    assert_eq!(
+        ran.recv_timeout(TIMEOUT),
+        Err(RecvTimeoutError::Disconnected)
+    );
+}
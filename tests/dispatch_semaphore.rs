@@ -0,0 +1,63 @@
+//! `DispatchSemaphore` as the blocking bridges use it: a callback on another thread signals, the
+//! caller waits with or without a timeout.
+
+#![cfg(target_os = "macos")]
+
+extern crate virtualization_rs;
+
+use virtualization_rs::base::DispatchSemaphore;
+
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+#[test]
+fn signal_before_wait_does_not_block() {
+    let semaphore = DispatchSemaphore::new(0);
+    semaphore.signal();
+    let started = Instant::now();
+    semaphore.wait();
+    assert!(started.elapsed() < Duration::from_secs(1));
+    semaphore.signal();
+    assert!(semaphore.wait_timeout(Duration::from_secs(0)));
+}
+
+#[test]
+fn initial_value_lets_waits_through() {
+    let semaphore = DispatchSemaphore::new(2);
+    assert!(semaphore.wait_timeout(Duration::from_millis(10)));
+    assert!(semaphore.wait_timeout(Duration::from_millis(10)));
+    assert!(!semaphore.wait_timeout(Duration::from_millis(10)));
+    // Back to the initial value before it is dropped.
+    semaphore.signal();
+    semaphore.signal();
+}
+
+#[test]
+fn wait_timeout_expires() {
+    let semaphore = DispatchSemaphore::new(0);
+    let started = Instant::now();
+    assert!(!semaphore.wait_timeout(Duration::from_millis(200)));
+    assert!(started.elapsed() >= Duration::from_millis(200));
+}
+
+#[test]
+fn signal_from_another_thread_wakes_the_waiter() {
+    let semaphore = Arc::new(DispatchSemaphore::new(0));
+    let signaller = {
+        let semaphore = semaphore.clone();
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(100));
+            semaphore.signal();
+        })
+    };
+    assert!(semaphore.wait_timeout(Duration::from_secs(10)));
+    signaller.join().unwrap();
+}
+
+#[test]
+fn huge_timeouts_wait_forever() {
+    let semaphore = DispatchSemaphore::new(0);
+    semaphore.signal();
+    assert!(semaphore.wait_timeout(Duration::from_secs(u64::MAX)));
+}
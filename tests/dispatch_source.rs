@@ -0,0 +1,203 @@
+//! `DispatchSource` on a pipe with scripted writes: events reach the handler on the queue,
+//! suspension holds them back until resumed, and cancellation runs the cancellation handler
+//! before the fd is closed, suspended or not.
+
+#![cfg(target_os = "macos")]
+
+extern crate libc;
+extern crate virtualization_rs;
+
+use virtualization_rs::base::{DispatchQueue, DispatchSource, DispatchSourceKind};
+
+use std::fs::File;
+use std::io::Write;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError};
+use std::time::Duration;
+
+const EVENT_TIMEOUT: Duration = Duration::from_secs(5);
+const QUIET: Duration = Duration::from_millis(200);
+
+fn pipe() -> (File, File) {
+    let mut fds = [0; 2];
+    assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+    unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) }
+}
+
+/// Reads what is available on `fd`, without taking ownership of it.
+fn read_available(fd: RawFd, estimated: usize) -> Vec<u8> {
+    let mut buf = vec![0u8; estimated.max(1)];
+    let n = unsafe { libc::read(fd, buf.as_mut_ptr() as *mut libc::c_void, buf.len()) };
+    buf.truncate(n.max(0) as usize);
+    buf
+}
+
+/// A read source on `fd` sending each chunk it reads, with the estimate it got.
+fn reader(fd: RawFd, queue: &DispatchQueue) -> (DispatchSource, Receiver<(usize, Vec<u8>)>) {
+    let (sender, receiver) = channel();
+    let source = DispatchSource::read(fd, queue, move |estimated| {
+        let _ = sender.send((estimated, read_available(fd, estimated)));
+    });
+    (source, receiver)
+}
+
+fn is_open(fd: RawFd) -> bool {
+    unsafe { libc::fcntl(fd, libc::F_GETFD) != -1 }
+}
+
+#[test]
+fn scripted_writes_reach_the_handler() {
+    let (read, mut write) = pipe();
+    let (source, events) = reader(read.as_raw_fd(), &DispatchQueue::new("source-read"));
+    assert_eq!(source.kind(), DispatchSourceKind::Read);
+    assert_eq!(source.fd(), read.as_raw_fd());
+
+    for chunk in [&b"first"[..], b"second", b"third line\n"].iter() {
+        write.write_all(chunk).unwrap();
+        let (estimated, data) = events.recv_timeout(EVENT_TIMEOUT).unwrap();
+        assert_eq!(estimated, chunk.len());
+        assert_eq!(&data[..], *chunk);
+    }
+
+    // End of file is an event with nothing to read.
+    drop(write);
+    assert_eq!(events.recv_timeout(EVENT_TIMEOUT).unwrap(), (0, Vec::new()));
+    source.cancel();
+    assert!(source.wait_cancelled(EVENT_TIMEOUT));
+}
+
+#[test]
+fn suspended_sources_hold_events_until_resumed() {
+    let (read, mut write) = pipe();
+    let (source, events) = reader(read.as_raw_fd(), &DispatchQueue::new("source-suspend"));
+
+    source.suspend();
+    source.suspend();
+    assert!(source.is_suspended());
+    write.write_all(b"held").unwrap();
+    write.write_all(b" back").unwrap();
+    assert_eq!(
+        events.recv_timeout(QUIET).err(),
+        Some(RecvTimeoutError::Timeout)
+    );
+
+    source.resume();
+    assert_eq!(
+        events.recv_timeout(QUIET).err(),
+        Some(RecvTimeoutError::Timeout)
+    );
+    source.resume();
+    assert!(!source.is_suspended());
+    // Both writes are coalesced into one event.
+    let (_, data) = events.recv_timeout(EVENT_TIMEOUT).unwrap();
+    assert_eq!(data, b"held back");
+
+    source.cancel();
+    assert!(source.wait_cancelled(EVENT_TIMEOUT));
+}
+
+#[test]
+fn the_cancellation_handler_closes_the_fd_after_the_last_event() {
+    let (read, mut write) = pipe();
+    let (source, events) = reader(read.as_raw_fd(), &DispatchQueue::new("source-cancel"));
+    let (cancelled_sender, cancelled) = channel();
+    source.on_cancel(move || {
+        let _ = cancelled_sender.send(is_open(read.as_raw_fd()));
+        drop(read);
+    });
+
+    write.write_all(b"before").unwrap();
+    assert_eq!(events.recv_timeout(EVENT_TIMEOUT).unwrap().1, b"before");
+
+    source.cancel();
+    assert!(source.is_cancelled());
+    assert!(source.wait_cancelled(EVENT_TIMEOUT));
+    // The fd was still open when the handler ran, and closed by it.
+    assert_eq!(cancelled.recv_timeout(EVENT_TIMEOUT), Ok(true));
+
+    // Cancelling again does nothing, and the event handler is released once cancelled.
+    source.cancel();
+    assert_eq!(
+        events.recv_timeout(QUIET).err(),
+        Some(RecvTimeoutError::Disconnected)
+    );
+}
+
+#[test]
+fn cancelling_a_suspended_source_resumes_it() {
+    let (read, _write) = pipe();
+    let (source, _events) = reader(read.as_raw_fd(), &DispatchQueue::new("source-suspended"));
+    let (sender, cancelled) = channel();
+    source.on_cancel(move || {
+        let _ = sender.send(());
+    });
+    source.suspend();
+    source.suspend();
+    source.cancel();
+    assert!(!source.is_suspended());
+    assert!(source.wait_cancelled(EVENT_TIMEOUT));
+    assert_eq!(cancelled.recv_timeout(EVENT_TIMEOUT), Ok(()));
+}
+
+#[test]
+fn dropping_a_suspended_source_cancels_it() {
+    let (read, _write) = pipe();
+    let (source, events) = reader(read.as_raw_fd(), &DispatchQueue::new("source-drop"));
+    let (sender, cancelled) = channel();
+    source.on_cancel(move || {
+        let _ = sender.send(());
+    });
+    source.suspend();
+    let clone = source.clone();
+    drop(source);
+    assert!(clone.is_suspended());
+    drop(clone);
+    assert_eq!(cancelled.recv_timeout(EVENT_TIMEOUT), Ok(()));
+    // The event handler, and the sender it held, are released once cancelled.
+    assert_eq!(
+        events.recv_timeout(EVENT_TIMEOUT).err(),
+        Some(RecvTimeoutError::Disconnected)
+    );
+    drop(read);
+}
+
+#[test]
+fn write_sources_report_buffer_space() {
+    let (_read, write) = pipe();
+    let fd = write.as_raw_fd();
+    let (sender, space) = channel();
+    let source = DispatchSource::write(fd, &DispatchQueue::new("source-write"), move |estimated| {
+        let _ = sender.send(estimated);
+    });
+    assert_eq!(source.kind(), DispatchSourceKind::Write);
+    assert!(space.recv_timeout(EVENT_TIMEOUT).unwrap() > 0);
+    source.cancel();
+    assert!(source.wait_cancelled(EVENT_TIMEOUT));
+}
+
+#[test]
+#[cfg_attr(
+    debug_assertions,
+    should_panic(expected = "DispatchSource::resume without a matching suspend")
+)]
+fn resuming_a_running_source() {
+    let (read, _write) = pipe();
+    let (source, _events) = reader(read.as_raw_fd(), &DispatchQueue::new("source-resume"));
+    source.resume();
+    assert!(!source.is_suspended());
+    source.cancel();
+}
+
+#[test]
+#[cfg(debug_assertions)]
+#[should_panic(expected = "cancelled after the fd was closed")]
+fn cancelling_after_close() {
+    let (read, _write) = pipe();
+    // A high number, which the tests running alongside do not reuse once it is closed.
+    let fd = unsafe { libc::fcntl(read.as_raw_fd(), libc::F_DUPFD, 900) };
+    assert!(fd >= 900);
+    let (source, _events) = reader(fd, &DispatchQueue::new("source-closed"));
+    unsafe { libc::close(fd) };
+    assert!(!is_open(fd));
+    source.cancel();
+}
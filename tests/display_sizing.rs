@@ -0,0 +1,129 @@
+//! `VirtioDisplaySizing::for_window` over every whole and half point size up to 8K at the backing
+//! scales AppKit uses: the pixels round to the nearest, never fall below one, scale back to the
+//! points within half a pixel, and the density follows the scale.
+
+#![cfg(target_os = "macos")]
+
+extern crate virtualization_rs;
+
+use virtualization_rs::virtualization::graphics_device::VirtioDisplaySizing;
+
+/// The backing scales of macOS screens, and the fractional ones of scaled display modes.
+const SCALES: &[f64] = &[1.0, 1.5, 2.0, 3.0];
+
+/// Every size from 0 to 8192 points in half points.
+fn sizes() -> impl Iterator<Item = f64> {
+    (0..=16384).map(|halves| f64::from(halves) / 2.0)
+}
+
+#[test]
+fn common_windows() {
+    assert_eq!(
+        VirtioDisplaySizing::for_window((1280.0, 720.0), 1.0),
+        (1280, 720, 72)
+    );
+    assert_eq!(
+        VirtioDisplaySizing::for_window((1280.0, 720.0), 2.0),
+        (2560, 1440, 144)
+    );
+    assert_eq!(
+        VirtioDisplaySizing::for_window((1440.0, 900.0), 2.0),
+        (2880, 1800, 144)
+    );
+    assert_eq!(
+        VirtioDisplaySizing::for_window((1024.0, 768.0), 1.5),
+        (1536, 1152, 108)
+    );
+    assert_eq!(
+        VirtioDisplaySizing::for_window((800.5, 600.5), 3.0),
+        (2402, 1802, 216)
+    );
+}
+
+#[test]
+fn pixels_are_the_points_scaled_and_rounded() {
+    for &scale in SCALES {
+        for points in sizes() {
+            let (width, height, _) = VirtioDisplaySizing::for_window((points, points), scale);
+            assert_eq!(width, height);
+            assert!(width >= 1, "{} points at {}", points, scale);
+            if points * scale >= 0.5 {
+                // Within half a pixel of the exact size.
+                let exact = points * scale;
+                assert!(
+                    (width as f64 - exact).abs() <= 0.5,
+                    "{} points at {} gave {} pixels",
+                    points,
+                    scale,
+                    width
+                );
+            } else {
+                assert_eq!(width, 1, "{} points at {}", points, scale);
+            }
+        }
+    }
+}
+
+#[test]
+fn pixels_grow_with_the_window() {
+    for &scale in SCALES {
+        let mut previous = 0;
+        for points in sizes() {
+            let (width, _, _) = VirtioDisplaySizing::for_window((points, 1.0), scale);
+            assert!(width >= previous, "{} points at {}", points, scale);
+            previous = width;
+        }
+    }
+}
+
+#[test]
+fn width_and_height_are_independent() {
+    for &scale in SCALES {
+        for points in sizes().step_by(97) {
+            let (width, _, _) = VirtioDisplaySizing::for_window((points, 1.0), scale);
+            let (_, height, _) = VirtioDisplaySizing::for_window((1.0, points), scale);
+            let both = VirtioDisplaySizing::for_window((points, points), scale);
+            assert_eq!((both.0, both.1), (width, height));
+        }
+    }
+}
+
+#[test]
+fn density_follows_the_scale() {
+    for &scale in SCALES {
+        let (_, _, ppi) = VirtioDisplaySizing::for_window((1.0, 1.0), scale);
+        assert_eq!(
+            ppi as f64,
+            (VirtioDisplaySizing::POINTS_PER_INCH * scale).round()
+        );
+    }
+    // Tiny scales still report a density.
+    assert_eq!(
+        VirtioDisplaySizing::for_window((1.0, 1.0), 0.001),
+        (1, 1, 1)
+    );
+}
+
+#[test]
+#[should_panic(expected = "backing scale")]
+fn zero_scale_panics() {
+    VirtioDisplaySizing::for_window((1280.0, 720.0), 0.0);
+}
+
+#[test]
+#[should_panic(expected = "backing scale")]
+fn nan_scale_panics() {
+    VirtioDisplaySizing::for_window((1280.0, 720.0), f64::NAN);
+}
+
+#[test]
+#[should_panic(expected = "not a size in points")]
+fn negative_size_panics() {
+    VirtioDisplaySizing::for_window((-1.0, 720.0), 2.0);
+}
+
+#[test]
+#[should_panic(expected = "not a size in points")]
+fn infinite_size_panics() {
+    VirtioDisplaySizing::for_window((1280.0, f64::INFINITY), 2.0);
+}
@@ -0,0 +1,226 @@
+//! Teardown of a virtual machine with observers: whatever order the machine, its clones and the
+//! guards handed out are dropped in, and from whichever queue, nothing the framework still
+//! messages is released first and nothing waits forever.
+//!
+//! None of these start a machine for real; the failed starts only put completion handlers in
+//! flight. The crashes this guards against are use-after-free, so a passing run is best
+//! confirmed under AddressSanitizer or the zombie allocator, manually:
+//!
+//! ```sh
+//! RUSTFLAGS=-Zsanitizer=address cargo +nightly test --target aarch64-apple-darwin \
+//!     --features linux-guest --test drop_order
+//! NSZombieEnabled=YES MallocScribble=1 cargo test --features linux-guest --test drop_order
+//! ```
+//!
+//! Either reports a message sent to a freed observer, delegate or queue with its stack.
+
+#![cfg(target_os = "macos")]
+
+extern crate virtualization_rs;
+
+use objc::rc::WeakPtr;
+use virtualization_rs::base::DispatchSemaphore;
+use virtualization_rs::test_support::{self, TempDir};
+use virtualization_rs::virtualization::virtual_machine::{
+    ShutdownError, VZVirtualMachine, VZVirtualMachineState, DROP_TIMEOUT,
+};
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Barrier};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const MACHINES: usize = 500;
+
+fn vm(dir: &TempDir) -> VZVirtualMachine {
+    VZVirtualMachine::new_with_qos(test_support::minimal_linux_config(dir), "drop-order", None)
+}
+
+/// Drops `vm`, which must be the last clone, from its own queue and waits for that.
+fn drop_on_queue(vm: VZVirtualMachine) {
+    let done = Arc::new(DispatchSemaphore::new(0));
+    let signal = done.clone();
+    let queue = vm.queue().clone();
+    queue.exec_async(move || {
+        drop(vm);
+        signal.signal();
+    });
+    assert!(
+        done.wait_timeout(DROP_TIMEOUT),
+        "dropping on the VM's queue did not return"
+    );
+}
+
+/// A weak reference to the framework object of `vm`.
+fn weak(vm: &VZVirtualMachine) -> WeakPtr {
+    unsafe { WeakPtr::new(vm.id()) }
+}
+
+/// Whether the object of `weak` is deallocated within [`DROP_TIMEOUT`]. What the framework
+/// autoreleased on the VM's queue may keep it alive until that thread drains its pool.
+fn deallocated(weak: &WeakPtr) -> bool {
+    let deadline = Instant::now() + DROP_TIMEOUT;
+    while !weak.load().is_null() {
+        if Instant::now() >= deadline {
+            return false;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    true
+}
+
+#[test]
+fn machines_with_observers_are_torn_down_in_any_order() {
+    let dir = TempDir::new("drop-order");
+    let started = Instant::now();
+    for i in 0..MACHINES {
+        let vm = vm(&dir);
+        let guard =
+            vm.on_first_transition_to(VZVirtualMachineState::VZVirtualMachineStateRunning, || {});
+        let events = vm.error_events();
+        let timeline = vm.record_timeline();
+        let clone = vm.clone();
+        match i % 5 {
+            0 => {
+                drop(vm);
+                drop(clone);
+                drop(guard);
+            }
+            1 => {
+                drop(guard);
+                drop(clone);
+                drop(vm);
+            }
+            2 => {
+                drop(clone);
+                drop(guard);
+                drop_on_queue(vm);
+            }
+            3 => {
+                assert_eq!(vm.shutdown_sync(DROP_TIMEOUT), Ok(()));
+                drop(vm);
+                drop(guard);
+                drop(clone);
+            }
+            _ => {
+                // The last clone goes on another thread while this one still holds the guard.
+                thread::spawn(move || drop(clone));
+                drop(vm);
+                drop(guard);
+            }
+        }
+        drop(timeline);
+        // The stream ends once the last clone is gone.
+        assert!(events.recv_timeout(DROP_TIMEOUT).is_none(), "machine {}", i);
+        assert!(events.is_finished(), "machine {}", i);
+    }
+    // Nothing was in flight, so no teardown waited for its timeout.
+    assert!(started.elapsed() < DROP_TIMEOUT * 2);
+}
+
+#[test]
+fn shutdown_runs_once() {
+    let dir = TempDir::new("drop-order-once");
+    let vm = vm(&dir);
+    let clone = vm.clone();
+    assert_eq!(vm.shutdown_sync(Duration::from_secs(5)), Ok(()));
+    assert_eq!(clone.shutdown_sync(Duration::from_secs(0)), Ok(()));
+    drop(clone);
+    let dropping = Instant::now();
+    drop(vm);
+    assert!(dropping.elapsed() < Duration::from_secs(1));
+}
+
+#[test]
+fn shutdown_waits_for_completion_handlers_in_flight() {
+    let dir = TempDir::new("drop-order-in-flight");
+    let vm = vm(&dir);
+    let ran = Arc::new(AtomicBool::new(false));
+    let handler_ran = ran.clone();
+    // The garbage kernel does not boot, so the handler runs with an error.
+    vm.start(move |_| {
+        thread::sleep(Duration::from_millis(200));
+        handler_ran.store(true, Ordering::SeqCst);
+    })
+    .unwrap();
+    assert_eq!(vm.shutdown_sync(Duration::from_secs(30)), Ok(()));
+    assert!(ran.load(Ordering::SeqCst));
+}
+
+#[test]
+fn shutdown_on_the_queue_reports_what_it_cannot_wait_for() {
+    let dir = TempDir::new("drop-order-on-queue");
+    let vm = Arc::new(vm(&dir));
+    let result = Arc::new(std::sync::Mutex::new(None));
+    let done = Arc::new(DispatchSemaphore::new(0));
+    let (target, slot, signal) = (vm.clone(), result.clone(), done.clone());
+    vm.queue().exec_async(move || {
+        // Queued behind this block, the start's handler is in flight for as long as it runs.
+        target.start(|_| {}).unwrap();
+        *slot.lock().unwrap() = Some(target.shutdown_sync(Duration::from_secs(5)));
+        signal.signal();
+    });
+    assert!(done.wait_timeout(Duration::from_secs(10)));
+    let result = result.lock().unwrap().take();
+    assert_eq!(result, Some(Err(ShutdownError::InFlight(1))));
+}
+
+#[test]
+fn the_last_reference_is_released_on_the_queue() {
+    let dir = TempDir::new("drop-order-release");
+    let vm = vm(&dir);
+    let weak = weak(&vm);
+    assert_eq!(vm.shutdown_sync(DROP_TIMEOUT), Ok(()));
+    // With the queue busy, a release on it has to wait.
+    let blocked = Arc::new(DispatchSemaphore::new(0));
+    let unblock = blocked.clone();
+    let queue = vm.queue().clone();
+    queue.exec_async(move || blocked.wait());
+    thread::spawn(move || drop(vm)).join().unwrap();
+    assert!(!weak.load().is_null(), "released off the queue");
+    unblock.signal();
+    assert!(deallocated(&weak));
+}
+
+#[test]
+fn last_references_dropped_on_spawned_threads() {
+    const THREADS: usize = 8;
+    let dir = TempDir::new("drop-order-threads");
+    for i in 0..MACHINES / 10 {
+        let vm = vm(&dir);
+        let weak = weak(&vm);
+        // Every thread drops a clone at once, so any of them may hold the last one.
+        let barrier = Arc::new(Barrier::new(THREADS));
+        let threads: Vec<_> = (0..THREADS)
+            .map(|_| {
+                let (clone, barrier) = (vm.clone(), barrier.clone());
+                thread::spawn(move || {
+                    barrier.wait();
+                    drop(clone);
+                })
+            })
+            .collect();
+        drop(vm);
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert!(deallocated(&weak), "machine {}", i);
+    }
+}
+
+#[test]
+fn a_machine_dropped_on_another_thread_while_it_starts() {
+    let dir = TempDir::new("drop-order-starting");
+    for i in 0..10 {
+        let vm = vm(&dir);
+        let weak = weak(&vm);
+        let done = Arc::new(DispatchSemaphore::new(0));
+        let signal = done.clone();
+        // The garbage kernel does not boot, so the handler runs with an error while the spawned
+        // thread drops the last clone, which waits for it.
+        vm.start(move |_| signal.signal()).unwrap();
+        thread::spawn(move || drop(vm)).join().unwrap();
+        assert!(done.wait_timeout(DROP_TIMEOUT), "machine {}", i);
+        assert!(deallocated(&weak), "machine {}", i);
+    }
+}
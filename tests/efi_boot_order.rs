@@ -0,0 +1,185 @@
+//! Boot order inspection over a small EFI variable store fixture: the fixture boots its USB
+//! installer first, a store rewritten with another `BootOrder` or an inactive installer entry reads
+//! back with the disk first, and a store with a corrupt header or a truncated variable is
+//! reported as invalid data.
+//!
+//! The fixture is in `tests/data/efi_variable_store.hex`. Reading it through a
+//! `VZEFIVariableStore` needs macOS 13; the parser itself runs on any Mac.
+
+#![cfg(target_os = "macos")]
+
+extern crate virtualization_rs;
+
+use virtualization_rs::features::HostCapabilities;
+use virtualization_rs::test_support::TempDir;
+use virtualization_rs::virtualization::boot_loader::VZEFIVariableStore;
+use virtualization_rs::virtualization::efi_boot_order::{
+    BootDevice, BootEntrySummary, EfiBootOrder,
+};
+
+use std::fs;
+use std::io;
+
+const FIXTURE: &str = include_str!("data/efi_variable_store.hex");
+const FIXTURE_LEN: usize = 4096;
+/// The offset of the store's signature, after the firmware volume header.
+const STORE_SIGNATURE: usize = 72;
+
+/// The fixture's bytes, its rows laid over erased flash.
+fn fixture() -> Vec<u8> {
+    let mut image = vec![0xffu8; FIXTURE_LEN];
+    for line in FIXTURE.lines().filter(|line| !line.starts_with('#')) {
+        let (offset, bytes) = line.split_once(": ").unwrap();
+        let offset = usize::from_str_radix(offset, 16).unwrap();
+        for (i, byte) in bytes.split(' ').enumerate() {
+            image[offset + i] = u8::from_str_radix(byte, 16).unwrap();
+        }
+    }
+    image
+}
+
+fn utf16(text: &str) -> Vec<u8> {
+    text.encode_utf16()
+        .chain(Some(0))
+        .flat_map(u16::to_le_bytes)
+        .collect()
+}
+
+/// The offset of the data of the last variable named `name`, the live copy in the fixture.
+fn data_of(store: &[u8], name: &str) -> usize {
+    let name = utf16(name);
+    let start = store
+        .windows(name.len())
+        .rposition(|window| window == &name[..])
+        .unwrap();
+    start + name.len()
+}
+
+/// Writes `store` to a file and reads its boot order back, through a `VZEFIVariableStore` where
+/// the framework has one.
+fn round_trip(dir: &TempDir, store: &[u8]) -> io::Result<BootEntrySummary> {
+    let path = dir.path().join("efi-variables");
+    fs::write(&path, store)?;
+    if !HostCapabilities::detect().supports_class("VZEFIVariableStore") {
+        return EfiBootOrder::parse(&fs::read(&path)?);
+    }
+    let store = VZEFIVariableStore::open(path.to_str().unwrap()).unwrap();
+    EfiBootOrder::detect_default_boot(&store)
+}
+
+fn numbers(summary: &BootEntrySummary) -> Vec<u16> {
+    summary.entries.iter().map(|entry| entry.number).collect()
+}
+
+fn assert_invalid_data(result: io::Result<BootEntrySummary>, message: &str) {
+    let error = result.unwrap_err();
+    assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    assert_eq!(error.to_string(), message);
+}
+
+#[test]
+fn the_fixture_boots_the_installer_first() {
+    let summary = EfiBootOrder::parse(&fixture()).unwrap();
+    // The superseded `BootOrder` listing the disk first is ignored.
+    assert_eq!(numbers(&summary), [1, 0, 2]);
+    let entries: Vec<_> = summary
+        .entries
+        .iter()
+        .map(|entry| (entry.description.as_str(), entry.active, entry.device))
+        .collect();
+    assert_eq!(
+        entries,
+        [
+            ("UEFI USB Device", true, BootDevice::Removable),
+            ("UEFI Misc Device", true, BootDevice::Disk),
+            ("EFI Internal Shell", true, BootDevice::Firmware),
+        ]
+    );
+    assert_eq!(summary.default.as_ref().map(|entry| entry.number), Some(1));
+    assert!(!summary.prefers_disk_over_removable());
+}
+
+#[test]
+fn a_rewritten_boot_order_reads_back() {
+    let dir = TempDir::new("efi-boot-order-rewritten");
+    let mut store = fixture();
+    let order = data_of(&store, "BootOrder");
+    store[order..order + 6].copy_from_slice(&[0, 0, 1, 0, 2, 0]);
+    let summary = round_trip(&dir, &store).unwrap();
+    assert_eq!(numbers(&summary), [0, 1, 2]);
+    assert_eq!(summary.default.as_ref().map(|entry| entry.number), Some(0));
+    assert!(summary.prefers_disk_over_removable());
+
+    // An order naming a missing entry skips it.
+    store[order..order + 2].copy_from_slice(&[9, 0]);
+    let summary = round_trip(&dir, &store).unwrap();
+    assert_eq!(numbers(&summary), [1, 2]);
+}
+
+#[test]
+fn an_inactive_installer_is_passed_over() {
+    let dir = TempDir::new("efi-boot-order-inactive");
+    let mut store = fixture();
+    let installer = data_of(&store, "Boot0001");
+    store[installer..installer + 4].copy_from_slice(&0u32.to_le_bytes());
+    let summary = round_trip(&dir, &store).unwrap();
+    assert_eq!(numbers(&summary), [1, 0, 2]);
+    assert!(!summary.entries[0].active);
+    assert_eq!(summary.default.as_ref().map(|entry| entry.number), Some(0));
+    assert!(summary.prefers_disk_over_removable());
+}
+
+#[test]
+fn a_store_without_boot_order_is_invalid_data() {
+    let dir = TempDir::new("efi-boot-order-missing");
+    let mut store = fixture();
+    let name = utf16("BootOrder");
+    // Renames the live `BootOrder`, keeping its size.
+    let order = data_of(&store, "BootOrder") - name.len();
+    store[order] = b'b';
+    assert_invalid_data(
+        round_trip(&dir, &store),
+        "the store has no BootOrder variable",
+    );
+}
+
+#[test]
+fn a_corrupt_header_is_invalid_data() {
+    let dir = TempDir::new("efi-boot-order-corrupt");
+    let mut store = fixture();
+    store[STORE_SIGNATURE] ^= 0xff;
+    assert_invalid_data(
+        round_trip(&dir, &store),
+        "unknown EFI variable store format",
+    );
+
+    // Without the firmware volume, the store is looked for at the start of the file.
+    let mut store = fixture();
+    store[40..44].copy_from_slice(b"_XXX");
+    assert_invalid_data(
+        round_trip(&dir, &store),
+        "unknown EFI variable store format",
+    );
+}
+
+#[test]
+fn a_truncated_variable_is_invalid_data() {
+    let dir = TempDir::new("efi-boot-order-truncated");
+    let store = fixture();
+    // Cut in the middle of the shell's device path.
+    let shell = data_of(&store, "Boot0002");
+    assert_invalid_data(
+        round_trip(&dir, &store[..shell + 40]),
+        "EFI variable runs past the end of the store",
+    );
+
+    // A data size larger than the store, as a torn write would leave it.
+    let mut store = fixture();
+    let installer = data_of(&store, "Boot0001") - utf16("Boot0001").len();
+    // `DataSize` of the authenticated header starts 20 bytes before the name.
+    store[installer - 20..installer - 16].copy_from_slice(&(FIXTURE_LEN as u32).to_le_bytes());
+    assert_invalid_data(
+        round_trip(&dir, &store),
+        "EFI variable runs past the end of the store",
+    );
+}
@@ -0,0 +1,128 @@
+//! EFI variable stores are exported and imported by copying their backing file, which is refused
+//! while a virtual machine holding the store is alive, and allowed again once its last clone is
+//! dropped. Runs on any Mac with macOS 13; no virtual machine is started.
+
+#![cfg(target_os = "macos")]
+
+extern crate virtualization_rs;
+
+use virtualization_rs::base::DispatchQueue;
+use virtualization_rs::features::HostCapabilities;
+use virtualization_rs::test_support::{self, TempDir};
+use virtualization_rs::virtualization::boot_loader::{VZEFIBootLoaderBuilder, VZEFIVariableStore};
+use virtualization_rs::virtualization::platform::VZGenericPlatformConfiguration;
+use virtualization_rs::virtualization::storage_device::{
+    VZDiskImageStorageDeviceAttachmentBuilder, VZVirtioBlockDeviceConfiguration,
+};
+use virtualization_rs::virtualization::virtual_machine::VZVirtualMachine;
+
+use std::fs;
+use std::io;
+
+const IN_USE: &str =
+    "EFI variable store is attached to a virtual machine that has not been released";
+
+fn skipped(test: &str) -> bool {
+    let skip = !HostCapabilities::detect().supports_class("VZEFIBootLoader");
+    if skip {
+        println!("{} skipped: no VZEFIBootLoader before macOS 13", test);
+    }
+    skip
+}
+
+fn open(dir: &TempDir, name: &str) -> VZEFIVariableStore {
+    VZEFIVariableStore::open(dir.path().join(name).to_str().unwrap()).unwrap()
+}
+
+fn assert_in_use(result: io::Result<()>) {
+    let error = result.unwrap_err();
+    assert_eq!(error.kind(), io::ErrorKind::Other);
+    assert_eq!(error.to_string(), IN_USE);
+}
+
+#[test]
+fn a_released_store_exports_as_a_copy() {
+    if skipped("a_released_store_exports_as_a_copy") {
+        return;
+    }
+    let dir = TempDir::new("efi-store-export");
+    let store = dir.efi_variable_store("golden");
+    let exported = dir.path().join("exported");
+    store.export_to(&exported).unwrap();
+    let original = fs::read(store.path()).unwrap();
+    assert_eq!(fs::read(&exported).unwrap(), original);
+    assert_eq!(store.size_bytes().unwrap(), original.len() as u64);
+    assert!(!original.is_empty());
+}
+
+#[test]
+fn a_store_held_by_a_machine_is_refused_until_its_last_clone_is_dropped() {
+    if skipped("a_store_held_by_a_machine_is_refused_until_its_last_clone_is_dropped") {
+        return;
+    }
+    let dir = TempDir::new("efi-store-held");
+    let golden = dir.efi_variable_store("golden");
+    let queue = DispatchQueue::new("efi-store-held");
+    let vm = VZVirtualMachine::new(test_support::minimal_efi_config(&dir), &queue);
+    let clone = vm.clone();
+
+    // The store `minimal_efi_config` attaches.
+    let store = open(&dir, "efi-variables");
+    assert_in_use(store.export_to(dir.path().join("exported")));
+    let error = VZEFIVariableStore::import_from(golden.path(), store.path())
+        .map(|store| store.id())
+        .unwrap_err();
+    assert_eq!(error.operation(), "import EFI variable store");
+    // Only the holder's store is refused.
+    golden.export_to(dir.path().join("golden-copy")).unwrap();
+
+    drop(vm);
+    assert_in_use(store.export_to(dir.path().join("exported")));
+    drop(clone);
+    store.export_to(dir.path().join("exported")).unwrap();
+}
+
+#[test]
+fn an_imported_store_configures_a_clone() {
+    if skipped("an_imported_store_configures_a_clone") {
+        return;
+    }
+    let dir = TempDir::new("efi-store-import");
+    let golden = dir.efi_variable_store("golden");
+    let imported =
+        VZEFIVariableStore::import_from(golden.path(), dir.path().join("clone")).unwrap();
+    assert_eq!(
+        imported.path(),
+        fs::canonicalize(dir.path().join("clone")).unwrap()
+    );
+    assert_eq!(
+        fs::read(imported.path()).unwrap(),
+        fs::read(golden.path()).unwrap()
+    );
+
+    let disk = VZDiskImageStorageDeviceAttachmentBuilder::new()
+        .path(
+            dir.disk_image("disk.img", 64 * 1024 * 1024)
+                .to_str()
+                .unwrap(),
+        )
+        .read_only(false)
+        .build()
+        .unwrap_or_else(|e| panic!("{}", e));
+    let conf = test_support::minimal_builder()
+        .boot_loader(
+            VZEFIBootLoaderBuilder::new()
+                .with_variable_store(imported)
+                .build(),
+        )
+        .platform(VZGenericPlatformConfiguration::new())
+        .storage_devices(vec![VZVirtioBlockDeviceConfiguration::new(disk)])
+        .build();
+    assert!(conf
+        .validate_with_error()
+        .unwrap_or_else(|e| panic!("{}\n{}", e, conf.describe())));
+    // The clone holds the imported store, not the golden one.
+    let _vm = VZVirtualMachine::new(conf, DispatchQueue::new("efi-store-import"));
+    assert_in_use(open(&dir, "clone").export_to(dir.path().join("exported")));
+    golden.export_to(dir.path().join("golden-copy")).unwrap();
+}
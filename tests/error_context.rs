@@ -0,0 +1,121 @@
+//! Errors of fallible calls must name the operation and resource in one line, and chain to the
+//! errors the framework reported as their cause.
+
+#![cfg(target_os = "macos")]
+
+extern crate virtualization_rs;
+
+use virtualization_rs::virtualization::boot_loader::{
+    VZEFIVariableStore, VZEFIVariableStoreInitializationOptions, VZLinuxBootLoaderBuilder,
+};
+use virtualization_rs::virtualization::error::VZErrorCtx;
+use virtualization_rs::virtualization::storage_device::{
+    VZDiskImageAttachmentError, VZDiskImageStorageDeviceAttachmentBuilder,
+};
+use virtualization_rs::virtualization::virtual_machine::VZVirtualMachineConfigurationBuilder;
+
+use std::error::Error;
+
+const MISSING_DIR: &str = "/nonexistent-virtualization-rs";
+
+/// Messages of `error` and its sources, outermost first.
+fn chain(error: &VZErrorCtx) -> Vec<String> {
+    let mut messages = vec![error.to_string()];
+    let mut source = error.source();
+    while let Some(e) = source {
+        messages.push(e.to_string());
+        source = e.source();
+    }
+    messages
+}
+
+fn assert_one_line(error: &VZErrorCtx) {
+    for message in chain(error) {
+        assert!(!message.contains('\n'), "{:?}", message);
+    }
+}
+
+#[test]
+fn missing_kernel_file() {
+    let kernel = format!("{}/vmlinuz", MISSING_DIR);
+    let boot_loader = VZLinuxBootLoaderBuilder::new()
+        .kernel_url(kernel)
+        .initial_ramdisk_url(format!("{}/initrd", MISSING_DIR))
+        .command_line("console=hvc0")
+        .build()
+        .unwrap();
+    let conf = VZVirtualMachineConfigurationBuilder::new()
+        .boot_loader(boot_loader)
+        .cpu_count(1)
+        .memory_size(512 * 1024 * 1024)
+        .build();
+    let error = conf.validate_with_error().unwrap_err();
+    assert_eq!(error.operation(), "validate virtual machine configuration");
+    assert_eq!(error.resource(), None);
+    assert!(
+        error.to_string().starts_with(
+            "failed to validate virtual machine configuration: VZErrorDomain code 2 — "
+        ),
+        "{}",
+        error
+    );
+    assert_one_line(&error);
+}
+
+#[test]
+fn invalid_disk_path() {
+    let path = format!("{}/disk.img", MISSING_DIR);
+    let error = VZDiskImageStorageDeviceAttachmentBuilder::new()
+        .path(path.clone())
+        .read_only(false)
+        .build()
+        .err()
+        .expect("attached a missing disk image");
+    let error = match error {
+        VZDiskImageAttachmentError::Framework(error) => error,
+        other => panic!("expected a framework error, got {:?}", other),
+    };
+    assert_eq!(error.operation(), "attach disk image");
+    let prefix = format!("failed to attach disk image '{}' (read_only=false): ", path);
+    assert!(error.to_string().starts_with(&prefix), "{}", error);
+    assert!(error.to_string().contains(" code "), "{}", error);
+    assert_one_line(&error);
+}
+
+#[test]
+fn bad_efi_store_path() {
+    let path = format!("{}/efi-variables", MISSING_DIR);
+    let error =
+        VZEFIVariableStore::create(path.clone(), VZEFIVariableStoreInitializationOptions::new())
+            .err()
+            .expect("created a store in a missing directory");
+    assert_eq!(error.operation(), "create EFI variable store");
+    assert_eq!(error.resource(), Some(format!("'{}'", path).as_str()));
+    let prefix = format!("failed to create EFI variable store '{}': ", path);
+    assert!(error.to_string().starts_with(&prefix), "{}", error);
+    assert_one_line(&error);
+}
+
+#[test]
+fn converts_into_boxed_errors() {
+    fn boxed() -> Result<(), Box<dyn Error + Send + Sync>> {
+        VZDiskImageStorageDeviceAttachmentBuilder::new()
+            .path(format!("{}/disk.img", MISSING_DIR))
+            .read_only(true)
+            .build()?;
+        Ok(())
+    }
+    let error = boxed().unwrap_err();
+    assert!(error
+        .to_string()
+        .starts_with("failed to attach disk image "));
+    let error = match *error.downcast::<VZDiskImageAttachmentError>().unwrap() {
+        VZDiskImageAttachmentError::Framework(error) => error,
+        other => panic!("expected a framework error, got {:?}", other),
+    };
+    // Each source is the cause of the previous error, never the same line again.
+    let messages = chain(&error);
+    for pair in messages.windows(2) {
+        assert!(!pair[0].ends_with(&pair[1]), "{:?}", messages);
+    }
+}
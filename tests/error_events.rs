@@ -0,0 +1,183 @@
+//! The error event stream of a virtual machine: events arrive in the order they were pushed, the
+//! terminal event comes last and ends the stream, and a receiver that falls behind loses its
+//! oldest events, counted, but never the terminal one.
+
+#![cfg(target_os = "macos")]
+
+extern crate virtualization_rs;
+
+use virtualization_rs::base::NSError;
+use virtualization_rs::virtualization::boot_loader::VZLinuxBootLoaderBuilder;
+use virtualization_rs::virtualization::error::VZError;
+use virtualization_rs::virtualization::error_events::{ErrorEvent, Phase, QUEUE_CAPACITY};
+use virtualization_rs::virtualization::virtual_machine::{
+    VZVirtualMachine, VZVirtualMachineConfigurationBuilder,
+};
+
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// A virtual machine whose kernel and initrd validate but do not boot.
+struct Scratch(PathBuf);
+
+impl Scratch {
+    fn new(name: &str) -> Scratch {
+        let path = std::env::temp_dir().join(format!(
+            "virtualization-rs-error-events-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).unwrap();
+        for name in &["vmlinuz", "initrd"] {
+            File::create(path.join(name))
+                .unwrap()
+                .write_all(&[0x5a; 4096])
+                .unwrap();
+        }
+        Scratch(path)
+    }
+
+    fn vm(&self) -> VZVirtualMachine {
+        let boot_loader = VZLinuxBootLoaderBuilder::new()
+            .kernel_url(self.0.join("vmlinuz").to_str().unwrap())
+            .initial_ramdisk_url(self.0.join("initrd").to_str().unwrap())
+            .command_line("console=hvc0")
+            .build()
+            .unwrap();
+        let conf = VZVirtualMachineConfigurationBuilder::new()
+            .boot_loader(boot_loader)
+            .cpu_count(1)
+            .memory_size(512 * 1024 * 1024)
+            .build();
+        VZVirtualMachine::new_with_qos(conf, "error-events", None)
+    }
+}
+
+impl Drop for Scratch {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+/// A synthetic error told apart by its code.
+fn error(code: i32) -> VZError {
+    VZError(NSError::posix(code))
+}
+
+/// Phase and code of an error event, `None` for the terminal event.
+fn describe(event: &ErrorEvent) -> Option<(Phase, isize)> {
+    match event {
+        ErrorEvent::Error(e) => Some((e.phase, e.error.ns_error().code())),
+        ErrorEvent::GuestStopped { .. } => None,
+    }
+}
+
+#[test]
+fn events_arrive_in_order_and_end_with_the_terminal_event() {
+    let scratch = Scratch::new("order");
+    let vm = scratch.vm();
+    let mut events = vm.error_events();
+    let sender = vm.error_event_sender();
+    sender.error(Phase::Start, error(1));
+    sender.error(Phase::Stop, error(2));
+    sender.error(Phase::Runtime, error(3));
+    sender.guest_stopped(false);
+    // After the terminal event: not for this receiver.
+    sender.error(Phase::Start, error(4));
+
+    let received: Vec<ErrorEvent> = events.by_ref().collect();
+    let described: Vec<_> = received.iter().map(describe).collect();
+    assert_eq!(
+        described,
+        [
+            Some((Phase::Start, 1)),
+            Some((Phase::Stop, 2)),
+            Some((Phase::Runtime, 3)),
+            None
+        ]
+    );
+    match received.last() {
+        Some(ErrorEvent::GuestStopped { clean, .. }) => assert!(!clean),
+        other => panic!("expected the terminal event last, got {:?}", other),
+    }
+    assert!(received.windows(2).all(|w| w[0].at() <= w[1].at()));
+    assert!(events.is_finished());
+    assert!(events.recv().is_none());
+    assert_eq!(events.dropped(), 0);
+}
+
+#[test]
+fn receivers_see_events_from_their_creation_on() {
+    let scratch = Scratch::new("receivers");
+    let vm = scratch.vm();
+    let sender = vm.error_event_sender();
+    let first = vm.error_events();
+    sender.error(Phase::Start, error(1));
+    let second = vm.clone().error_events();
+    sender.guest_stopped(true);
+    let third = vm.error_events();
+    sender.error(Phase::Start, error(2));
+
+    assert_eq!(
+        first.try_recv().as_ref().and_then(describe),
+        Some((Phase::Start, 1))
+    );
+    assert!(first.try_recv().unwrap().is_terminal());
+    assert!(second.try_recv().unwrap().is_terminal());
+    assert!(second.is_finished());
+    assert_eq!(
+        third.try_recv().as_ref().and_then(describe),
+        Some((Phase::Start, 2))
+    );
+    assert!(!third.is_finished());
+}
+
+#[test]
+fn a_slow_receiver_drops_the_oldest_events_but_never_the_terminal_one() {
+    let scratch = Scratch::new("drop");
+    let vm = scratch.vm();
+    let mut events = vm.error_events();
+    let sender = vm.error_event_sender();
+    let pushed = QUEUE_CAPACITY + 10;
+    for code in 1..=pushed {
+        sender.error(Phase::Runtime, error(code as i32));
+    }
+    assert_eq!(events.dropped(), 10);
+    sender.guest_stopped(true);
+    assert_eq!(events.dropped(), 11);
+
+    let received: Vec<ErrorEvent> = events.by_ref().collect();
+    assert_eq!(received.len(), QUEUE_CAPACITY);
+    assert_eq!(describe(&received[0]), Some((Phase::Runtime, 12)));
+    match received.last() {
+        Some(ErrorEvent::GuestStopped { clean, .. }) => assert!(clean),
+        other => panic!("expected the terminal event last, got {:?}", other),
+    }
+}
+
+#[test]
+fn the_stream_ends_when_the_machine_is_dropped() {
+    let scratch = Scratch::new("dropped");
+    let vm = scratch.vm();
+    let events = vm.error_events();
+    vm.error_event_sender().error(Phase::Start, error(1));
+    drop(vm);
+    assert!(events.recv().is_some());
+    assert!(events.recv().is_none());
+    assert!(events.is_finished());
+}
+
+#[test]
+fn a_failed_start_is_reported() {
+    let scratch = Scratch::new("start");
+    let vm = scratch.vm();
+    let events = vm.error_events();
+    vm.start(|_| {}).unwrap();
+    match events.recv_timeout(Duration::from_secs(30)) {
+        Some(ErrorEvent::Error(e)) => assert_eq!(e.phase, Phase::Start),
+        other => panic!("expected a start error, got {:?}", other),
+    }
+}
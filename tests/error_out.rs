@@ -0,0 +1,181 @@
+//! Calls with an `NSError **` out-parameter are read by the Cocoa contract: the return value says
+//! whether the call failed, and only then is the error read. Each outcome is driven through a
+//! simulated callee with synthetic errors, then the reachable outcomes of the wrappers' own calls
+//! are checked.
+//!
+//! Wrappers holding nil panic in debug builds and return their documented defaults otherwise.
+
+#![cfg(target_os = "macos")]
+
+extern crate virtualization_rs;
+
+use virtualization_rs::base::{NSError, NSString, NilObject, UNREPORTED_ERROR_DOMAIN};
+use virtualization_rs::features::HostCapabilities;
+use virtualization_rs::test_support::{self, TempDir};
+use virtualization_rs::virtualization::boot_loader::{
+    VZEFIVariableStore, VZEFIVariableStoreInitializationOption,
+    VZEFIVariableStoreInitializationOptions,
+};
+use virtualization_rs::virtualization::directory_sharing::{
+    VZSharedDirectory, VZSingleDirectoryShare, VZVirtioFileSystemDeviceConfiguration,
+};
+use virtualization_rs::virtualization::storage_device::{
+    VZDiskImageAttachmentError, VZDiskImageStorageDeviceAttachmentBuilder,
+};
+
+use std::ptr;
+
+fn synthetic() -> NSError {
+    NSError::error_with_domain("virtualization_rs.test", 42, None)
+}
+
+fn assert_synthetic(error: &NSError) {
+    assert_eq!(error.domain().as_str(), "virtualization_rs.test");
+    assert_eq!(error.code(), 42);
+    assert!(!error.is_unreported());
+}
+
+#[test]
+fn a_bool_call_is_read_by_its_return_value() {
+    let error = synthetic();
+    assert_eq!(
+        test_support::bool_call_with_error(true, None).ok(),
+        Some(true)
+    );
+    // An error left behind by a call that succeeded is not a failure.
+    assert_eq!(
+        test_support::bool_call_with_error(true, Some(&error)).ok(),
+        Some(true)
+    );
+    assert_synthetic(&test_support::bool_call_with_error(false, Some(&error)).unwrap_err());
+
+    let unreported = test_support::bool_call_with_error(false, None).unwrap_err();
+    assert!(unreported.is_unreported());
+    assert_eq!(unreported.domain().as_str(), UNREPORTED_ERROR_DOMAIN);
+}
+
+#[test]
+fn an_object_call_is_read_by_its_return_value() {
+    let error = synthetic();
+    let object = NSString::new("result");
+    assert_eq!(
+        test_support::object_call_with_error(*object.0, None).ok(),
+        Some(*object.0)
+    );
+    assert_eq!(
+        test_support::object_call_with_error(*object.0, Some(&error)).ok(),
+        Some(*object.0)
+    );
+    assert_synthetic(
+        &test_support::object_call_with_error(ptr::null_mut(), Some(&error)).unwrap_err(),
+    );
+    assert!(test_support::object_call_with_error(ptr::null_mut(), None)
+        .unwrap_err()
+        .is_unreported());
+}
+
+#[test]
+fn the_stored_error_outlives_the_call() {
+    let read = {
+        let error = synthetic();
+        test_support::bool_call_with_error(false, Some(&error)).unwrap_err()
+    };
+    assert_synthetic(&read);
+}
+
+#[test]
+fn missing_strings_of_an_error_are_none() {
+    let error = synthetic();
+    assert!(error.localized_failure_reason().is_none());
+    assert!(error.localized_recovery_suggestion().is_none());
+    assert!(error.help_anchor().is_none());
+    assert!(!error.localized_description().as_str().is_empty());
+}
+
+#[test]
+#[allow(deprecated)]
+#[cfg_attr(debug_assertions, should_panic(expected = "NSError is nil"))]
+fn a_nil_error_has_no_code() {
+    let error = NSError::nil();
+    assert_eq!(error.try_code(), Err(NilObject("NSError")));
+    assert_eq!(error.code(), 0);
+    assert_eq!(error.domain().as_str(), "");
+    assert!(!error.is_vz_error());
+}
+
+#[test]
+fn disk_image_attachments() {
+    let dir = TempDir::new("error-out-disk");
+    let image = dir.disk_image("disk.img", 1 << 20);
+    assert!(VZDiskImageStorageDeviceAttachmentBuilder::new()
+        .path(image.to_str().unwrap())
+        .read_only(true)
+        .build()
+        .is_ok());
+
+    let missing = dir.path().join("missing.img");
+    match VZDiskImageStorageDeviceAttachmentBuilder::new()
+        .path(missing.to_str().unwrap())
+        .read_only(true)
+        .build()
+    {
+        Err(VZDiskImageAttachmentError::Framework(e)) => {
+            assert!(!e.ns_error().is_unreported(), "{}", e)
+        }
+        Err(e) => panic!("unexpected {}", e),
+        Ok(_) => panic!("attached a missing image"),
+    }
+}
+
+#[test]
+fn efi_variable_stores() {
+    if !HostCapabilities::detect().supports_class("VZEFIVariableStore") {
+        println!("efi_variable_stores skipped: no VZEFIVariableStore before macOS 13");
+        return;
+    }
+    let dir = TempDir::new("error-out-efi");
+    let options = || {
+        VZEFIVariableStoreInitializationOptions::new()
+            .with(VZEFIVariableStoreInitializationOption::allow_overwrite())
+    };
+    let path = dir.path().join("efi_vars.fd");
+    assert!(VZEFIVariableStore::create(path.to_str().unwrap(), options()).is_ok());
+
+    let nowhere = dir.path().join("missing").join("efi_vars.fd");
+    let error = VZEFIVariableStore::create(nowhere.to_str().unwrap(), options())
+        .err()
+        .expect("created a store in a missing directory");
+    assert!(!error.ns_error().is_unreported(), "{}", error);
+}
+
+#[test]
+fn configuration_validation() {
+    let dir = TempDir::new("error-out-validation");
+    assert_eq!(
+        test_support::minimal_linux_config(&dir)
+            .validate_with_error()
+            .map_err(|e| e.to_string()),
+        Ok(true)
+    );
+    let error = test_support::minimal_builder()
+        .build()
+        .validate_with_error()
+        .unwrap_err();
+    assert!(!error.ns_error().is_unreported(), "{}", error);
+    assert!(error.ns_error().is_vz_error(), "{}", error);
+}
+
+#[test]
+fn directory_sharing_tags() {
+    let dir = TempDir::new("error-out-tag");
+    let share = || {
+        VZSingleDirectoryShare::new(
+            VZSharedDirectory::new(dir.path().to_str().unwrap(), true).unwrap(),
+        )
+    };
+    assert!(VZVirtioFileSystemDeviceConfiguration::new("share", share()).is_ok());
+    let error = VZVirtioFileSystemDeviceConfiguration::new("", share())
+        .err()
+        .expect("accepted an empty tag");
+    assert!(!error.ns_error().is_unreported(), "{}", error);
+}
@@ -0,0 +1,317 @@
+//! `FakeVm` against its script: unscripted operations succeed, replies are used in order then the
+//! default, latencies delay the answers, scripted and injected endings settle the same stop
+//! reasons and error events a real machine reports, and reconciliation reads the scripted devices.
+
+#![cfg(target_os = "macos")]
+
+extern crate libc;
+extern crate virtualization_rs;
+
+use virtualization_rs::base::NSError;
+use virtualization_rs::fake_vm::{FakeEvent, FakeOp, FakeReply, FakeVm};
+use virtualization_rs::reconcile::{ConfigDescription, ObservedDevices, ReconcileReport, Status};
+use virtualization_rs::virtualization::error::{CompletionOutcome, VZError};
+use virtualization_rs::virtualization::error_events::{ErrorEvent, ErrorEventReceiver, Phase};
+use virtualization_rs::virtualization::handle::VirtualMachineHandle;
+use virtualization_rs::virtualization::lifecycle::{Lifecycle, LifecycleError, StopReason};
+use virtualization_rs::virtualization::virtual_machine::VZVirtualMachineState::*;
+
+use std::sync::mpsc::{channel, RecvTimeoutError, Sender};
+use std::time::{Duration, Instant};
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+const QUIET: Duration = Duration::from_millis(100);
+
+fn eio() -> VZError {
+    VZError(NSError::posix(libc::EIO))
+}
+
+fn label(outcome: &CompletionOutcome) -> &'static str {
+    match outcome {
+        CompletionOutcome::Success(_) => "success",
+        CompletionOutcome::Cancelled => "cancelled",
+        CompletionOutcome::Failed(_) => "failed",
+    }
+}
+
+/// A completion handler sending the label of its outcome.
+fn report(outcomes: &Sender<&'static str>) -> impl FnOnce(CompletionOutcome) + 'static {
+    let outcomes = outcomes.clone();
+    move |outcome| {
+        let _ = outcomes.send(label(&outcome));
+    }
+}
+
+/// Starts `vm` and waits for the start to complete, returning its outcome.
+fn start(vm: &FakeVm) -> &'static str {
+    let (sender, outcome) = channel();
+    vm.start(report(&sender)).unwrap();
+    outcome.recv_timeout(TIMEOUT).unwrap()
+}
+
+/// The reason of the terminal event of `events`, and the phases of the errors before it.
+fn until_stopped(events: &ErrorEventReceiver) -> (Vec<Phase>, StopReason) {
+    let mut phases = Vec::new();
+    loop {
+        match events.recv_timeout(TIMEOUT) {
+            Some(ErrorEvent::Error(e)) => phases.push(e.phase),
+            Some(ErrorEvent::GuestStopped { clean, reason, .. }) => {
+                assert_eq!(clean, reason.is_clean(), "{}", reason);
+                return (phases, reason);
+            }
+            None => panic!("no terminal event; errors {:?}", phases),
+        }
+    }
+}
+
+#[test]
+fn unscripted_operations_succeed() {
+    let vm = FakeVm::new("fake-unscripted");
+    assert_eq!(vm.lifecycle(), Lifecycle::Created);
+    assert_eq!(vm.label(), Some("fake-unscripted"));
+    assert_eq!(
+        vm.display_name(),
+        format!("{} (fake-unscripted)", vm.vm_id())
+    );
+
+    assert_eq!(start(&vm), "success");
+    assert_eq!(vm.lifecycle(), Lifecycle::Started);
+    assert_eq!(vm.peek_state(), VZVirtualMachineStateRunning);
+    assert_eq!(vm.start(|_| {}), Err(LifecycleError::AlreadyStarted));
+
+    let (sender, outcomes) = channel();
+    vm.pause(report(&sender));
+    assert_eq!(outcomes.recv_timeout(TIMEOUT), Ok("success"));
+    assert_eq!(vm.peek_state(), VZVirtualMachineStatePaused);
+    vm.resume(report(&sender));
+    assert_eq!(outcomes.recv_timeout(TIMEOUT), Ok("success"));
+    assert_eq!(
+        vm.queue().exec_sync(|| unsafe { vm.state() }),
+        VZVirtualMachineStateRunning
+    );
+
+    let events = vm.error_events();
+    vm.stop(report(&sender)).unwrap();
+    assert_eq!(outcomes.recv_timeout(TIMEOUT), Ok("success"));
+    assert_eq!(vm.lifecycle(), Lifecycle::Stopped);
+    let (phases, reason) = until_stopped(&events);
+    assert!(phases.is_empty(), "{:?}", phases);
+    assert!(matches!(reason, StopReason::Forced), "{}", reason);
+    assert_eq!(
+        vm.calls(),
+        [
+            FakeOp::Start,
+            FakeOp::Start,
+            FakeOp::Pause,
+            FakeOp::Resume,
+            FakeOp::Stop
+        ]
+    );
+}
+
+#[test]
+fn replies_are_used_in_order_then_the_default() {
+    let vm = FakeVm::new("fake-replies")
+        .reply(FakeOp::Start, FakeReply::Fail(eio()))
+        .reply(FakeOp::Start, FakeReply::Cancel)
+        .default_reply(FakeOp::Stop, FakeReply::Fail(eio()));
+    let events = vm.error_events();
+
+    assert_eq!(start(&vm), "failed");
+    assert_eq!(vm.lifecycle(), Lifecycle::Stopped);
+    match events.recv_timeout(TIMEOUT) {
+        Some(ErrorEvent::Error(e)) => assert_eq!(e.phase, Phase::Start),
+        other => panic!("expected a start error, got {:?}", other),
+    }
+    assert_eq!(start(&vm), "cancelled");
+    assert_eq!(start(&vm), "success");
+
+    // A failed stop leaves the machine running, every time.
+    for _ in 0..2 {
+        let (sender, outcome) = channel();
+        vm.stop(report(&sender)).unwrap();
+        assert_eq!(outcome.recv_timeout(TIMEOUT), Ok("failed"));
+        assert_eq!(vm.lifecycle(), Lifecycle::Started);
+        assert_eq!(vm.peek_state(), VZVirtualMachineStateRunning);
+        match events.recv_timeout(TIMEOUT) {
+            Some(ErrorEvent::Error(e)) => assert_eq!(e.phase, Phase::Stop),
+            other => panic!("expected a stop error, got {:?}", other),
+        }
+    }
+    assert!(vm.last_stop_reason().is_none());
+}
+
+#[test]
+fn latency_delays_the_answer_and_joins_share_it() {
+    let latency = Duration::from_millis(200);
+    let vm = FakeVm::new("fake-latency").latency(FakeOp::Start, latency);
+    let (sender, outcomes) = channel();
+    let asked = Instant::now();
+    vm.start(report(&sender)).unwrap();
+    assert_eq!(vm.lifecycle(), Lifecycle::StartRequested);
+    vm.start_or_join(report(&sender)).unwrap();
+    assert_eq!(
+        outcomes.recv_timeout(QUIET).err(),
+        Some(RecvTimeoutError::Timeout)
+    );
+    assert_eq!(outcomes.recv_timeout(TIMEOUT), Ok("success"));
+    assert_eq!(outcomes.recv_timeout(TIMEOUT), Ok("success"));
+    assert!(asked.elapsed() >= latency);
+    assert_eq!(vm.calls(), [FakeOp::Start, FakeOp::Start]);
+}
+
+#[test]
+fn a_scripted_crash_ends_its_run_only() {
+    let vm =
+        FakeVm::new("fake-crash").after_start(Duration::from_millis(20), FakeEvent::Crash(eio()));
+    let (sender, crashed) = channel();
+    let _observation = vm.on_first_transition_to(VZVirtualMachineStateError, move || {
+        let _ = sender.send(());
+    });
+    let events = vm.error_events();
+    assert_eq!(start(&vm), "success");
+    let (phases, reason) = until_stopped(&events);
+    assert_eq!(phases, [Phase::Runtime]);
+    assert!(matches!(reason, StopReason::Crashed(_)), "{}", reason);
+    assert!(matches!(
+        vm.last_stop_reason(),
+        Some(StopReason::Crashed(_))
+    ));
+    assert_eq!(vm.lifecycle(), Lifecycle::Stopped);
+    assert_eq!(crashed.recv_timeout(TIMEOUT), Ok(()));
+
+    // The next run has no ending scripted and keeps running.
+    let events = vm.error_events();
+    assert_eq!(start(&vm), "success");
+    assert!(events.recv_timeout(QUIET).is_none());
+    assert_eq!(vm.lifecycle(), Lifecycle::Started);
+}
+
+#[test]
+fn injected_endings_settle_the_stop_reason() {
+    let vm = FakeVm::new("fake-endings").latency(FakeOp::RequestStop, Duration::from_millis(20));
+
+    // Asked to stop, the guest shuts down after the latency.
+    assert_eq!(start(&vm), "success");
+    let events = vm.error_events();
+    let accepted = vm
+        .queue()
+        .exec_sync(|| unsafe { vm.request_stop_with_error() }.unwrap());
+    assert!(accepted);
+    assert!(matches!(
+        until_stopped(&events).1,
+        StopReason::HostRequested
+    ));
+
+    assert_eq!(start(&vm), "success");
+    let events = vm.error_events();
+    vm.guest_shutdown();
+    assert!(matches!(
+        until_stopped(&events).1,
+        StopReason::GuestInitiated
+    ));
+
+    assert_eq!(start(&vm), "success");
+    let events = vm.error_events();
+    vm.crash(eio());
+    assert!(matches!(until_stopped(&events).1, StopReason::Crashed(_)));
+    // Nothing is running to crash.
+    vm.crash(eio());
+    assert!(vm.error_events().recv_timeout(QUIET).is_none());
+}
+
+#[test]
+fn a_guest_ignoring_the_request_is_forced_down() {
+    let vm = FakeVm::new("fake-ignore")
+        .reply(FakeOp::RequestStop, FakeReply::Hang)
+        .reply(FakeOp::RequestStop, FakeReply::Fail(eio()));
+    assert_eq!(start(&vm), "success");
+    let events = vm.error_events();
+    assert_eq!(
+        vm.queue()
+            .exec_sync(|| unsafe { vm.request_stop_with_error() }.ok()),
+        Some(true)
+    );
+    assert!(events.recv_timeout(QUIET).is_none());
+    assert_eq!(vm.lifecycle(), Lifecycle::Started);
+
+    let error = vm
+        .queue()
+        .exec_sync(|| unsafe { vm.request_stop_with_error() }.unwrap_err());
+    assert!(error.to_string().contains(&vm.display_name()), "{}", error);
+
+    vm.stop_or_join(|_| {});
+    assert!(matches!(until_stopped(&events).1, StopReason::Forced));
+}
+
+#[test]
+fn transitions_are_observed_once() {
+    let vm = FakeVm::new("fake-observe");
+    let (sender, running) = channel();
+    let first = sender.clone();
+    let _once = vm.on_first_transition_to(VZVirtualMachineStateRunning, move || {
+        let _ = first.send("registered before");
+    });
+    let abandoned = sender.clone();
+    drop(
+        vm.on_first_transition_to(VZVirtualMachineStateRunning, move || {
+            let _ = abandoned.send("abandoned");
+        }),
+    );
+
+    assert_eq!(start(&vm), "success");
+    assert_eq!(running.recv_timeout(TIMEOUT), Ok("registered before"));
+    // Already running counts.
+    let _now = vm.on_first_transition_to(VZVirtualMachineStateRunning, move || {
+        let _ = sender.send("registered while running");
+    });
+    assert_eq!(
+        running.recv_timeout(TIMEOUT),
+        Ok("registered while running")
+    );
+
+    let (stopped_sender, stopped) = channel();
+    vm.stop_or_join(report(&stopped_sender));
+    assert_eq!(stopped.recv_timeout(TIMEOUT), Ok("success"));
+    assert_eq!(start(&vm), "success");
+    assert_eq!(
+        running.recv_timeout(QUIET).err(),
+        Some(RecvTimeoutError::Timeout)
+    );
+}
+
+/// A machine without any device, whose categories can all be read.
+fn no_devices() -> ObservedDevices {
+    ObservedDevices {
+        storage_devices: Ok(Vec::new()),
+        network_devices: Ok(Vec::new()),
+        entropy_devices: Ok(0),
+        memory_balloon_devices: Ok(Vec::new()),
+        socket_devices: Ok(0),
+        directory_sharing_devices: Ok(Vec::new()),
+        console_devices: Ok(0),
+    }
+}
+
+#[test]
+fn reconcile_reads_the_scripted_devices() {
+    let desired = ConfigDescription {
+        entropy_devices: Some(1),
+        socket_devices: Some(1),
+        ..ConfigDescription::default()
+    };
+    let unscripted = ReconcileReport::compare(&desired, &FakeVm::new("fake-unscripted-devices"));
+    assert!(unscripted
+        .entries
+        .iter()
+        .all(|entry| matches!(entry.status, Status::Unknown { .. })));
+
+    let vm = FakeVm::new("fake-devices").devices(ObservedDevices {
+        entropy_devices: Ok(1),
+        socket_devices: Ok(0),
+        ..no_devices()
+    });
+    let report = ReconcileReport::compare(&desired, &vm);
+    let drift: Vec<&str> = report.drift().map(|entry| entry.category).collect();
+    assert_eq!(drift, ["socket_devices"]);
+}
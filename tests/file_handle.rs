@@ -0,0 +1,67 @@
+//! `NSFileHandle` reads and writes over a pipe pair: partial reads return what is there, an empty
+//! non-blocking pipe reads as no bytes, end of file is a distinct error, and a bad descriptor fails
+//! with its errno.
+
+#![cfg(target_os = "macos")]
+
+extern crate virtualization_rs;
+
+use virtualization_rs::base::NSFileHandle;
+
+use std::os::unix::io::RawFd;
+
+/// `(read, write)` handles owning both ends of a new pipe.
+fn pipe() -> (NSFileHandle, NSFileHandle) {
+    let mut fds: [RawFd; 2] = [0; 2];
+    assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+    (
+        NSFileHandle::init_with_file_descriptor(fds[0], true),
+        NSFileHandle::init_with_file_descriptor(fds[1], true),
+    )
+}
+
+#[test]
+fn partial_reads() {
+    let (read, write) = pipe();
+    write.write_all(b"login: ").unwrap();
+    assert_eq!(read.read_up_to(4).unwrap(), b"logi");
+    assert_eq!(read.read_up_to(64).unwrap(), b"n: ");
+    write.write_all(b"root\n").unwrap();
+    assert_eq!(read.available_data(), b"root\n");
+}
+
+#[test]
+fn end_of_file_is_an_error() {
+    let (read, write) = pipe();
+    write.write_all(b"bye").unwrap();
+    write.close().unwrap();
+    assert_eq!(read.read_up_to(64).unwrap(), b"bye");
+    let error = read.read_up_to(64).unwrap_err();
+    assert!(error.is_file_handle_closed(), "{}", error);
+    assert!(read.available_data().is_empty());
+}
+
+#[test]
+fn empty_non_blocking_pipe_reads_nothing() {
+    let (read, write) = pipe();
+    let fd = read.file_descriptor();
+    unsafe {
+        let flags = libc::fcntl(fd, libc::F_GETFL);
+        assert_eq!(libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK), 0);
+    }
+    assert_eq!(read.read_up_to(64).unwrap(), b"");
+    write.write_all(b"x").unwrap();
+    assert_eq!(read.read_up_to(64).unwrap(), b"x");
+}
+
+/// The descriptors of a pipe opened for the other direction fail like closed ones, with `EBADF`,
+/// and unlike closed ones cannot be reused by another test meanwhile.
+#[test]
+fn bad_descriptor_fails() {
+    let (read, write) = pipe();
+    let error = write.read_up_to(64).unwrap_err();
+    assert!(!error.is_file_handle_closed());
+    assert_eq!(error.domain().as_str(), "NSPOSIXErrorDomain");
+    assert_eq!(error.code(), libc::EBADF as isize);
+    assert!(read.write_all(b"x").is_err());
+}
@@ -0,0 +1,134 @@
+//! The framework is loaded at runtime and weakly linked into this package's binaries, so that
+//! binaries degrade on systems without it instead of failing at dyld time. These tests run on
+//! systems that have it.
+
+#![cfg(target_os = "macos")]
+
+extern crate virtualization_rs;
+
+use virtualization_rs::features::{
+    self, ClassLookup, ClassNotAvailable, HostCapabilities, NotAvailable,
+};
+use virtualization_rs::virtualization::boot_loader::VZEFIBootLoaderBuilder;
+use virtualization_rs::virtualization::error::{vz_error_domain, VZ_ERROR_DOMAIN};
+use virtualization_rs::virtualization::graphics_device::VZVirtioGraphicsScanoutConfiguration;
+use virtualization_rs::virtualization::keyboard::VZUSBKeyboardConfiguration;
+use virtualization_rs::virtualization::platform::{
+    VZGenericMachineIdentifier, VZGenericPlatformConfiguration,
+};
+use virtualization_rs::virtualization::virtual_machine::{
+    VZVirtualMachine, VZVirtualMachineConfigurationBuilder,
+};
+
+use std::process::Command;
+
+#[test]
+fn framework_loads() {
+    assert!(features::is_framework_available());
+    assert_eq!(features::require_framework(), Ok(()));
+    assert!(VZVirtualMachineConfigurationBuilder::try_new().is_ok());
+    assert_eq!(vz_error_domain().as_str(), VZ_ERROR_DOMAIN);
+    let caps = HostCapabilities::detect();
+    assert_eq!(caps.virtualization_supported, VZVirtualMachine::supported());
+    assert!(caps.maximum_cpu_count > 0);
+}
+
+#[test]
+fn not_available_names_the_framework() {
+    let message = NotAvailable.to_string();
+    assert!(
+        message.starts_with("virtualization unavailable on this system: "),
+        "{}",
+        message
+    );
+    assert!(message.contains(features::FRAMEWORK_PATH), "{}", message);
+}
+
+static MISSING: ClassLookup = ClassLookup::new("VZDoesNotExist", "macOS 99");
+
+#[test]
+fn a_missing_class_fails_with_its_requirement() {
+    let expected = ClassNotAvailable {
+        class: "VZDoesNotExist",
+        since: "macOS 99",
+    };
+    assert_eq!(MISSING.get().err(), Some(expected));
+    // Cached, and the same answer every time.
+    assert_eq!(MISSING.get().err(), Some(expected));
+    assert!(!MISSING.is_available());
+    assert_eq!(MISSING.name(), "VZDoesNotExist");
+    assert_eq!(
+        expected.to_string(),
+        "VZDoesNotExist is not available on this system, it needs macOS 99"
+    );
+}
+
+#[test]
+fn a_present_class_is_found_once() {
+    static MACHINE: ClassLookup = ClassLookup::new("VZVirtualMachine", "macOS 11");
+    let class = MACHINE.get().unwrap();
+    assert_eq!(class.name(), "VZVirtualMachine");
+    assert!(std::ptr::eq(class, MACHINE.get().unwrap()));
+}
+
+/// The fallible constructors succeed exactly where the host has the class.
+#[test]
+fn try_new_follows_the_host() {
+    let caps = HostCapabilities::detect();
+    let cases = [
+        (
+            "VZUSBKeyboardConfiguration",
+            VZUSBKeyboardConfiguration::try_new().err(),
+        ),
+        (
+            "VZGenericPlatformConfiguration",
+            VZGenericPlatformConfiguration::try_new().err(),
+        ),
+        (
+            "VZGenericMachineIdentifier",
+            VZGenericMachineIdentifier::try_new().err(),
+        ),
+        (
+            "VZVirtioGraphicsScanoutConfiguration",
+            VZVirtioGraphicsScanoutConfiguration::try_new(1920, 1080).err(),
+        ),
+        (
+            "VZEFIBootLoader",
+            VZEFIBootLoaderBuilder::new().try_build().err(),
+        ),
+    ];
+    for (class, error) in cases.iter() {
+        match error {
+            None => assert!(caps.supports_class(class), "{}", class),
+            Some(e) => {
+                assert!(!caps.supports_class(class), "{}", class);
+                assert_eq!(e.class, *class);
+            }
+        }
+    }
+}
+
+/// Nothing links the framework strongly: `build.rs` links it weakly, and the linker may drop even
+/// that while no symbol of it is referenced.
+#[test]
+fn test_binary_does_not_link_the_framework_strongly() {
+    let exe = std::env::current_exe().unwrap();
+    let output = Command::new("otool").arg("-l").arg(&exe).output();
+    let output = match output {
+        Ok(output) if output.status.success() => output,
+        _ => {
+            println!("test_binary_does_not_link_the_framework_strongly skipped: otool unavailable");
+            return;
+        }
+    };
+    let load_commands = String::from_utf8_lossy(&output.stdout);
+    let mut command = "";
+    for line in load_commands.lines() {
+        let line = line.trim();
+        if let Some(cmd) = line.strip_prefix("cmd ") {
+            command = cmd;
+        } else if line.starts_with("name ") && line.contains("/Virtualization.framework/") {
+            assert_eq!(command, "LC_LOAD_WEAK_DYLIB", "{}", line);
+        }
+    }
+}
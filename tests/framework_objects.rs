@@ -0,0 +1,454 @@
+//! Exercises every public constructor against the installed Virtualization.framework, without
+//! booting a virtual machine: each object must be non-nil, of the expected class, and survive
+//! being created and released repeatedly inside autorelease pools. Needs neither the
+//! virtualization entitlement nor hardware virtualization, so it runs on any Mac.
+
+#![cfg(target_os = "macos")]
+
+extern crate virtualization_rs;
+
+use virtualization_rs::base::{Id, NSFileHandle, NSString, NSURL};
+use virtualization_rs::runtime::{autoreleasepool, class_name};
+use virtualization_rs::test_support::{self, assert_class, assert_non_nil, TempDir};
+use virtualization_rs::virtualization::boot_loader::{
+    VZBootLoader, VZEFIBootLoaderBuilder, VZEFIVariableStore,
+    VZEFIVariableStoreInitializationOption, VZEFIVariableStoreInitializationOptions,
+};
+use virtualization_rs::virtualization::device::VZDeviceConfiguration;
+use virtualization_rs::virtualization::directory_sharing::{
+    VZDirectoryShare, VZMultipleDirectoryShare, VZSharedDirectory, VZSingleDirectoryShare,
+    VZVirtioFileSystemDeviceConfiguration,
+};
+use virtualization_rs::virtualization::entropy_device::VZVirtioEntropyDeviceConfiguration;
+use virtualization_rs::virtualization::graphics_device::{
+    VZVirtioGraphicsDeviceConfiguration, VZVirtioGraphicsScanoutConfiguration,
+};
+use virtualization_rs::virtualization::keyboard::VZUSBKeyboardConfiguration;
+use virtualization_rs::virtualization::memory_device::VZVirtioTraditionalMemoryBalloonDeviceConfiguration;
+use virtualization_rs::virtualization::network_device::{
+    VZMACAddress, VZNATNetworkDeviceAttachment, VZNetworkDeviceAttachment,
+    VZVirtioNetworkDeviceConfiguration,
+};
+use virtualization_rs::virtualization::platform::{
+    VZGenericMachineIdentifier, VZGenericPlatformConfiguration, VZPlatformConfiguration,
+};
+use virtualization_rs::virtualization::pointing_device::VZUSBScreenCoordinatePointingDeviceConfiguration;
+use virtualization_rs::virtualization::serial_port::{
+    VZFileHandleSerialPortAttachmentBuilder, VZSerialPortAttachment,
+    VZVirtioConsoleDeviceSerialPortConfiguration,
+};
+use virtualization_rs::virtualization::socket_device::VZVirtioSocketDeviceConfiguration;
+use virtualization_rs::virtualization::storage_device::{
+    VZDiskImageCachingMode, VZDiskImageFileAttachmentError, VZDiskImageStorageDeviceAttachment,
+    VZDiskImageStorageDeviceAttachmentBuilder, VZDiskImageSynchronizationMode,
+    VZStorageDeviceAttachment, VZUSBMassStorageDeviceConfiguration,
+    VZVirtioBlockDeviceConfiguration,
+};
+use virtualization_rs::virtualization::virtual_machine::{VZVirtualMachine, VZVirtualMachineState};
+
+use std::fs::{File, OpenOptions};
+use std::path::Path;
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::Duration;
+
+/// Rounds per constructor; over-releases usually crash within a few pool drains.
+const ROUNDS: usize = 100;
+
+/// Builds an object `ROUNDS` times, each in its own pool, checking its class every time.
+fn check<T, F, I>(expected: &str, make: F, id: I)
+where
+    F: Fn() -> T,
+    I: Fn(&T) -> Id,
+{
+    for round in 0..ROUNDS {
+        autoreleasepool(|| {
+            let object = make();
+            let p = id(&object);
+            assert_non_nil(p, &format!("{} in round {}", expected, round));
+            unsafe { assert_class(p, expected) };
+            drop(object);
+        });
+    }
+}
+
+fn device<T: VZDeviceConfiguration>(device: &T) -> Id {
+    device.id()
+}
+
+#[test]
+fn foundation_objects() {
+    check(
+        "NSConcreteFileHandle",
+        NSFileHandle::file_handle_with_standard_input,
+        |h| *h.0,
+    );
+    check(
+        "NSConcreteFileHandle",
+        || NSFileHandle::init_with_file_descriptor(libc_dup(0), true),
+        |h| *h.0,
+    );
+    check(
+        "NSURL",
+        || NSURL::file_url_with_path("/tmp", true).unwrap(),
+        |u| *u.0,
+    );
+    check(
+        "NSURL",
+        || NSURL::url_with_string("https://example.com").unwrap(),
+        |u| *u.0,
+    );
+    check_any_string();
+}
+
+/// `NSString`'s concrete class depends on the content, so only the cluster is checked.
+fn check_any_string() {
+    for _ in 0..ROUNDS {
+        autoreleasepool(|| {
+            let s = NSString::new("virtualization-rs");
+            assert_non_nil(*s.0, "NSString");
+            assert!(unsafe { class_name(*s.0) }.contains("String"));
+            assert_eq!(s.as_str(), "virtualization-rs");
+        });
+    }
+}
+
+fn libc_dup(fd: i32) -> i32 {
+    extern "C" {
+        fn dup(fd: i32) -> i32;
+    }
+    let new = unsafe { dup(fd) };
+    assert!(new >= 0);
+    new
+}
+
+#[test]
+fn simple_devices() {
+    check(
+        "VZVirtioEntropyDeviceConfiguration",
+        VZVirtioEntropyDeviceConfiguration::new,
+        device,
+    );
+    check(
+        "VZVirtioTraditionalMemoryBalloonDeviceConfiguration",
+        VZVirtioTraditionalMemoryBalloonDeviceConfiguration::new,
+        device,
+    );
+    check(
+        "VZVirtioSocketDeviceConfiguration",
+        VZVirtioSocketDeviceConfiguration::new,
+        device,
+    );
+    check(
+        "VZUSBKeyboardConfiguration",
+        VZUSBKeyboardConfiguration::new,
+        device,
+    );
+    check(
+        "VZUSBScreenCoordinatePointingDeviceConfiguration",
+        VZUSBScreenCoordinatePointingDeviceConfiguration::new,
+        device,
+    );
+    check(
+        "VZVirtioGraphicsScanoutConfiguration",
+        || VZVirtioGraphicsScanoutConfiguration::new(1024, 768),
+        |s| s.id(),
+    );
+    check(
+        "VZVirtioGraphicsDeviceConfiguration",
+        || {
+            VZVirtioGraphicsDeviceConfiguration::new(vec![
+                VZVirtioGraphicsScanoutConfiguration::new(1024, 768),
+            ])
+        },
+        device,
+    );
+}
+
+#[test]
+fn network() {
+    check("VZMACAddress", VZMACAddress::new, |m| *m.0);
+    check(
+        "VZMACAddress",
+        VZMACAddress::random_locally_administered_address,
+        |m| *m.0,
+    );
+    check(
+        "VZMACAddress",
+        || VZMACAddress::init_with_string("02:00:00:00:00:01").unwrap(),
+        |m| *m.0,
+    );
+    check(
+        "VZNATNetworkDeviceAttachment",
+        VZNATNetworkDeviceAttachment::new,
+        |a| a.id(),
+    );
+    check(
+        "VZVirtioNetworkDeviceConfiguration",
+        || VZVirtioNetworkDeviceConfiguration::new(VZNATNetworkDeviceAttachment::new()),
+        device,
+    );
+}
+
+#[test]
+fn serial_ports() {
+    let attachment = || {
+        VZFileHandleSerialPortAttachmentBuilder::new()
+            .file_handle_for_reading(NSFileHandle::file_handle_with_standard_input())
+            .file_handle_for_writing(NSFileHandle::new())
+            .build()
+    };
+    check("VZFileHandleSerialPortAttachment", attachment, |a| a.id());
+    check(
+        "VZVirtioConsoleDeviceSerialPortConfiguration",
+        || VZVirtioConsoleDeviceSerialPortConfiguration::new(attachment()),
+        device,
+    );
+}
+
+#[test]
+fn storage() {
+    let dir = TempDir::new("storage");
+    let image = dir.disk_image("disk.img", 1024 * 1024);
+    let image = image.to_str().unwrap().to_string();
+    check(
+        "VZDiskImageStorageDeviceAttachment",
+        || {
+            VZDiskImageStorageDeviceAttachmentBuilder::new()
+                .path(image.clone())
+                .build()
+                .unwrap_or_else(|e| panic!("{}", e))
+        },
+        |a| a.id(),
+    );
+    check(
+        "VZDiskImageStorageDeviceAttachment",
+        || {
+            VZDiskImageStorageDeviceAttachmentBuilder::new()
+                .path(image.clone())
+                .read_only(false)
+                .caching_mode(VZDiskImageCachingMode::automatic())
+                .synchronization_mode(VZDiskImageSynchronizationMode::full())
+                .build()
+                .unwrap_or_else(|e| panic!("{}", e))
+        },
+        |a| a.id(),
+    );
+    let attachment = || {
+        VZDiskImageStorageDeviceAttachmentBuilder::new()
+            .path(image.clone())
+            .build()
+            .unwrap_or_else(|e| panic!("{}", e))
+    };
+    check(
+        "VZVirtioBlockDeviceConfiguration",
+        || VZVirtioBlockDeviceConfiguration::new(attachment()),
+        device,
+    );
+    check(
+        "VZUSBMassStorageDeviceConfiguration",
+        || VZUSBMassStorageDeviceConfiguration::new(attachment()),
+        device,
+    );
+}
+
+fn attach_file(
+    file: File,
+    read_only: bool,
+) -> Result<VZDiskImageStorageDeviceAttachment, VZDiskImageFileAttachmentError> {
+    VZDiskImageStorageDeviceAttachment::new_from_file(
+        file,
+        read_only,
+        VZDiskImageCachingMode::automatic(),
+        VZDiskImageSynchronizationMode::full(),
+    )
+}
+
+fn open(path: &Path, read: bool, write: bool) -> File {
+    OpenOptions::new()
+        .read(read)
+        .write(write)
+        .open(path)
+        .unwrap()
+}
+
+#[test]
+fn storage_from_file() {
+    let dir = TempDir::new("storage-from-file");
+    let image = dir.disk_image("disk.img", 1024 * 1024);
+    check(
+        "VZDiskImageStorageDeviceAttachment",
+        || attach_file(open(&image, true, false), true).unwrap(),
+        |a| a.id(),
+    );
+    check(
+        "VZDiskImageStorageDeviceAttachment",
+        || attach_file(open(&image, true, true), false).unwrap(),
+        |a| a.id(),
+    );
+}
+
+#[test]
+fn storage_from_file_checks_the_access_mode() {
+    let dir = TempDir::new("storage-access-mode");
+    let image = dir.disk_image("disk.img", 1024 * 1024);
+    // Writable attachments need a writable file.
+    match attach_file(open(&image, true, false), false) {
+        Err(VZDiskImageFileAttachmentError::AccessModeMismatch { read_only: false }) => {}
+        other => panic!("unexpected {:?}", other.map(|a| a.id())),
+    }
+    // Every attachment needs a readable file.
+    for &read_only in &[true, false] {
+        match attach_file(open(&image, false, true), read_only) {
+            Err(VZDiskImageFileAttachmentError::AccessModeMismatch { read_only: r }) => {
+                assert_eq!(r, read_only)
+            }
+            other => panic!("unexpected {:?}", other.map(|a| a.id())),
+        }
+    }
+    let error = attach_file(open(&image, true, false), false)
+        .map(|a| a.id())
+        .unwrap_err();
+    assert_eq!(
+        error.to_string(),
+        "a writable disk image attachment needs a file opened for reading and writing"
+    );
+}
+
+#[test]
+fn efi() {
+    let dir = TempDir::new("efi");
+    let store = dir.path().join("efi-variables");
+    let store = store.to_str().unwrap().to_string();
+    check(
+        "VZEFIVariableStore",
+        || {
+            VZEFIVariableStore::create(
+                store.clone(),
+                VZEFIVariableStoreInitializationOptions::new()
+                    .with(VZEFIVariableStoreInitializationOption::allow_overwrite()),
+            )
+            .unwrap_or_else(|e| panic!("{}", e))
+        },
+        |s| s.id(),
+    );
+    check(
+        "VZEFIVariableStore",
+        || VZEFIVariableStore::open(store.clone()).unwrap(),
+        |s| s.id(),
+    );
+    check(
+        "VZEFIBootLoader",
+        || {
+            VZEFIBootLoaderBuilder::new()
+                .with_variable_store(VZEFIVariableStore::open(store.clone()).unwrap())
+                .build()
+        },
+        |b| b.id(),
+    );
+}
+
+#[test]
+fn linux_boot_loader() {
+    let dir = TempDir::new("linux");
+    check(
+        "VZLinuxBootLoader",
+        || test_support::garbage_linux_boot_loader(&dir),
+        |b| b.id(),
+    );
+}
+
+#[test]
+fn virtual_machine_shared_through_an_arc() {
+    let dir = TempDir::new("shared-vm");
+    let vm = Arc::new(VZVirtualMachine::new_with_qos(
+        test_support::minimal_linux_config(&dir),
+        "shared-vm",
+        None,
+    ));
+    let object = unsafe { vm.id() } as usize;
+    let (tx, rx) = mpsc::channel();
+    let threads: Vec<_> = (0..2)
+        .map(|_| {
+            let vm = vm.clone();
+            let tx = tx.clone();
+            // Each thread hands its reference on to a callback on the machine's queue.
+            thread::spawn(move || {
+                let target = vm.clone();
+                vm.queue().exec_async(move || {
+                    let seen = (unsafe { target.id() } as usize, target.vm_id());
+                    let _ = tx.send((seen, unsafe { target.state() }));
+                });
+            })
+        })
+        .collect();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+    for _ in 0..2 {
+        let (seen, state) = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(seen, (object, vm.vm_id()));
+        assert_eq!(state, VZVirtualMachineState::VZVirtualMachineStateStopped);
+    }
+}
+
+#[test]
+fn directory_sharing() {
+    let dir = TempDir::new("share");
+    let path = dir.path().to_str().unwrap().to_string();
+    check(
+        "VZSharedDirectory",
+        || VZSharedDirectory::new(&path, true).unwrap(),
+        |d| d.id(),
+    );
+    check(
+        "VZSingleDirectoryShare",
+        || VZSingleDirectoryShare::new(VZSharedDirectory::new(&path, true).unwrap()),
+        |s| s.id(),
+    );
+    check(
+        "VZMultipleDirectoryShare",
+        || {
+            VZMultipleDirectoryShare::new(vec![
+                (
+                    "a".to_string(),
+                    VZSharedDirectory::new(&path, true).unwrap(),
+                ),
+                (
+                    "b".to_string(),
+                    VZSharedDirectory::new(&path, false).unwrap(),
+                ),
+            ])
+        },
+        |s| s.id(),
+    );
+    check(
+        "VZVirtioFileSystemDeviceConfiguration",
+        || {
+            let share = VZSingleDirectoryShare::new(VZSharedDirectory::new(&path, true).unwrap());
+            VZVirtioFileSystemDeviceConfiguration::new("share", share)
+                .unwrap_or_else(|e| panic!("{}", e))
+        },
+        device,
+    );
+}
+
+#[test]
+fn platform() {
+    check(
+        "VZGenericMachineIdentifier",
+        VZGenericMachineIdentifier::new,
+        |m| m.id(),
+    );
+    let bytes = VZGenericMachineIdentifier::new().data_representation();
+    check(
+        "VZGenericMachineIdentifier",
+        || VZGenericMachineIdentifier::from_data_representation(&bytes).unwrap(),
+        |m| m.id(),
+    );
+    check(
+        "VZGenericPlatformConfiguration",
+        VZGenericPlatformConfiguration::new,
+        |p| p.id(),
+    );
+}
@@ -0,0 +1,134 @@
+//! Architecture guards: every class a constructor guards is in `features::ARCH_RESTRICTED`, each
+//! one is refused for a host of the other architecture and allowed on its own where the running
+//! framework has it, and a guarded constructor fails with `UnsupportedOnThisHost` rather than
+//! aborting. The other architecture is mocked through `HostCapabilities::arch`.
+
+#![cfg(target_os = "macos")]
+
+extern crate objc;
+extern crate virtualization_rs;
+
+use virtualization_rs::base::{host_arch, HostArch};
+use virtualization_rs::features::{self, HostCapabilities, ARCH_RESTRICTED};
+use virtualization_rs::virtualization::pointing_device::VZMacTrackpadConfiguration;
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use objc::runtime::Class;
+
+const GUARD: &str = "features::require(\"";
+
+fn other(arch: HostArch) -> HostArch {
+    match arch {
+        HostArch::AppleSilicon => HostArch::Intel,
+        HostArch::Intel => HostArch::AppleSilicon,
+    }
+}
+
+fn mocked(arch: HostArch) -> HostCapabilities {
+    HostCapabilities {
+        arch,
+        ..HostCapabilities::detect()
+    }
+}
+
+fn sources(dir: &Path, files: &mut Vec<PathBuf>) {
+    for entry in fs::read_dir(dir).unwrap() {
+        let path = entry.unwrap().path();
+        if path.is_dir() {
+            sources(&path, files);
+        } else if path.extension().is_some_and(|extension| extension == "rs") {
+            files.push(path);
+        }
+    }
+}
+
+/// The classes named by `features::require` calls in the crate's sources, with where.
+fn guarded_classes() -> Vec<(String, PathBuf)> {
+    let mut files = Vec::new();
+    sources(
+        &Path::new(env!("CARGO_MANIFEST_DIR")).join("src"),
+        &mut files,
+    );
+    let mut classes = Vec::new();
+    for file in files {
+        let source = fs::read_to_string(&file).unwrap();
+        for (at, _) in source.match_indices(GUARD) {
+            let rest = &source[at + GUARD.len()..];
+            let class = &rest[..rest.find('"').unwrap()];
+            classes.push((class.to_string(), file.clone()));
+        }
+    }
+    classes
+}
+
+#[test]
+fn every_guarded_class_is_in_the_table() {
+    let guarded = guarded_classes();
+    assert!(!guarded.is_empty());
+    for (class, file) in guarded {
+        assert!(
+            ARCH_RESTRICTED.iter().any(|(name, _)| *name == class),
+            "{} guards {}, which is not in ARCH_RESTRICTED",
+            file.display(),
+            class
+        );
+    }
+}
+
+#[test]
+fn restricted_classes_follow_the_mocked_architecture() {
+    features::is_framework_available();
+    for &(class, required) in ARCH_RESTRICTED {
+        assert!(
+            !mocked(other(required)).supports_class(class),
+            "{} on an {} host",
+            class,
+            other(required)
+        );
+        // Allowed on its own architecture wherever the framework has it, which it does not on
+        // the other one, or before the macOS that added it.
+        assert_eq!(
+            mocked(required).supports_class(class),
+            Class::get(class).is_some(),
+            "{} on an {} host",
+            class,
+            required
+        );
+    }
+}
+
+#[test]
+fn unrestricted_classes_are_supported_on_both_architectures() {
+    for arch in [HostArch::AppleSilicon, HostArch::Intel] {
+        assert!(mocked(arch).supports_class("VZVirtioBlockDeviceConfiguration"));
+        assert!(mocked(arch).supports_class("VZLinuxBootLoader"));
+    }
+}
+
+#[test]
+fn the_detected_architecture_is_the_hosts() {
+    assert_eq!(HostCapabilities::detect().arch, host_arch());
+    // Known at compile time; only x86_64 builds ask the kernel, as they may run translated.
+    if cfg!(target_arch = "aarch64") {
+        assert_eq!(host_arch(), HostArch::AppleSilicon);
+    }
+}
+
+#[test]
+fn a_guarded_constructor_fails_instead_of_aborting() {
+    let supported = HostCapabilities::detect().supports_class("VZMacTrackpadConfiguration");
+    match VZMacTrackpadConfiguration::new() {
+        Ok(_) => assert!(supported),
+        Err(error) => {
+            assert!(!supported);
+            assert_eq!(error.class, "VZMacTrackpadConfiguration");
+            assert_eq!(error.required, HostArch::AppleSilicon);
+            assert_eq!(error.host, host_arch());
+            assert!(error
+                .to_string()
+                .starts_with("VZMacTrackpadConfiguration requires an "));
+        }
+    }
+}
@@ -0,0 +1,397 @@
+//! The install flow moves through its steps in order, resumes after the steps whose output the
+//! bundle directory already holds, cleans up after a failure as each step promises, and passes
+//! cancellation on to the step running.
+//!
+//! The steps are stand-ins that write placeholder files; nothing is downloaded or installed.
+
+#![cfg(target_os = "macos")]
+
+extern crate virtualization_rs;
+
+use virtualization_rs::base::{CancellationToken, DispatchSemaphore};
+use virtualization_rs::install_flow::{
+    AdvanceError, FlowState, InstallFlow, InstallPlan, InstallSteps, Source, Step, StepError,
+};
+use virtualization_rs::mac_bundle::MacVmBundle;
+use virtualization_rs::test_support::TempDir;
+use virtualization_rs::virtualization::restore_image::RequirementViolation;
+
+use std::fs::{self, File};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// What the stand-in steps do besides succeeding.
+#[derive(Default, Clone, Copy)]
+struct Faults {
+    /// Fetching writes part of the image, then fails.
+    fetch: bool,
+    /// The image needs more CPUs than planned.
+    requirements: bool,
+    /// Creating the bundle writes it, then fails.
+    create: bool,
+    /// Installing waits to be cancelled.
+    install_until_cancelled: bool,
+}
+
+#[derive(Default)]
+struct Log {
+    steps: Vec<Step>,
+    progress: Vec<f64>,
+}
+
+struct FakeSteps {
+    faults: Faults,
+    log: Arc<Mutex<Log>>,
+    /// Signalled once the installation started.
+    installing: Arc<DispatchSemaphore>,
+}
+
+impl FakeSteps {
+    fn new(faults: Faults) -> (Box<FakeSteps>, Arc<Mutex<Log>>, Arc<DispatchSemaphore>) {
+        let log = Arc::new(Mutex::new(Log::default()));
+        let installing = Arc::new(DispatchSemaphore::new(0));
+        let steps = FakeSteps {
+            faults,
+            log: log.clone(),
+            installing: installing.clone(),
+        };
+        (Box::new(steps), log, installing)
+    }
+
+    fn enter(&self, step: Step) {
+        self.log.lock().unwrap().steps.push(step);
+    }
+}
+
+fn failure(step: Step) -> StepError {
+    StepError::Failed(format!("{} failed on purpose", step))
+}
+
+impl InstallSteps for FakeSteps {
+    fn fetch_image(
+        &mut self,
+        plan: &InstallPlan,
+        _cancel: &CancellationToken,
+    ) -> Result<(), StepError> {
+        self.enter(Step::FetchImage);
+        if self.faults.fetch {
+            fs::write(plan.image_path().with_extension("download"), b"ipsw").unwrap();
+            return Err(failure(Step::FetchImage));
+        }
+        fs::write(plan.image_path(), b"ipsw").unwrap();
+        Ok(())
+    }
+
+    fn validate_requirements(&mut self, plan: &InstallPlan) -> Result<(), StepError> {
+        self.enter(Step::ValidateRequirements);
+        if self.faults.requirements {
+            return Err(StepError::Requirements(vec![
+                RequirementViolation::CpuCount {
+                    required: plan.cpus + 1,
+                    requested: plan.cpus,
+                },
+            ]));
+        }
+        Ok(())
+    }
+
+    fn create_bundle(&mut self, plan: &InstallPlan) -> Result<(), StepError> {
+        self.enter(Step::CreateBundle);
+        write_bundle(plan);
+        if self.faults.create {
+            return Err(failure(Step::CreateBundle));
+        }
+        Ok(())
+    }
+
+    fn install(
+        &mut self,
+        _plan: &InstallPlan,
+        progress: &mut dyn FnMut(f64),
+        cancel: &CancellationToken,
+    ) -> Result<(), StepError> {
+        self.enter(Step::Install);
+        self.installing.signal();
+        progress(0.5);
+        if self.faults.install_until_cancelled {
+            while !cancel.is_cancelled() {
+                thread::sleep(Duration::from_millis(10));
+            }
+            return Err(StepError::Cancelled);
+        }
+        progress(1.0);
+        Ok(())
+    }
+}
+
+/// A bundle of placeholder data, its auxiliary storage and disk image, as creating it leaves them.
+fn write_bundle(plan: &InstallPlan) {
+    MacVmBundle {
+        hardware_model: b"hardware model".to_vec(),
+        machine_identifier: b"machine identifier".to_vec(),
+        auxiliary_storage: plan.auxiliary_storage_path(),
+    }
+    .save(&plan.bundle_dir)
+    .unwrap();
+    fs::write(plan.auxiliary_storage_path(), b"storage").unwrap();
+    File::create(plan.disk_path())
+        .unwrap()
+        .set_len(plan.disk_size)
+        .unwrap();
+}
+
+fn plan(dir: &TempDir) -> InstallPlan {
+    InstallPlan {
+        ipsw: Source::Latest,
+        bundle_dir: dir.path().join("macOS.bundle"),
+        cpus: 4,
+        memory: 8 << 30,
+        disk_size: 1 << 20,
+    }
+}
+
+/// Runs `flow` to its end, collecting every state reported on the way.
+fn run(flow: &InstallFlow) -> (FlowState, Vec<FlowState>) {
+    let states = Arc::new(Mutex::new(Vec::new()));
+    let seen = states.clone();
+    let end = flow.run(move |state| seen.lock().unwrap().push(state));
+    let states = states.lock().unwrap().clone();
+    (end, states)
+}
+
+fn exists(path: &Path) -> bool {
+    fs::symlink_metadata(path).is_ok()
+}
+
+#[test]
+fn a_new_bundle_goes_through_every_step() {
+    let dir = TempDir::new("install-flow-full");
+    let plan = plan(&dir);
+    fs::create_dir_all(&plan.bundle_dir).unwrap();
+    let (steps, log, _) = FakeSteps::new(Faults::default());
+    let flow = InstallFlow::with_steps(plan.clone(), steps);
+    assert_eq!(flow.state(), FlowState::FetchingImage);
+
+    let (end, states) = run(&flow);
+    assert_eq!(end, FlowState::Done);
+    assert_eq!(
+        states,
+        vec![
+            FlowState::ValidatingRequirements,
+            FlowState::CreatingBundle,
+            FlowState::Installing { progress: 0.0 },
+            FlowState::Installing { progress: 0.5 },
+            FlowState::Installing { progress: 1.0 },
+            FlowState::Done,
+        ]
+    );
+    assert_eq!(
+        log.lock().unwrap().steps,
+        vec![
+            Step::FetchImage,
+            Step::ValidateRequirements,
+            Step::CreateBundle,
+            Step::Install
+        ]
+    );
+    assert!(plan.is_installed());
+
+    // A finished installation is not redone, by this flow or a new one.
+    assert_eq!(flow.advance(|_| {}), Err(AdvanceError::Done));
+    let (steps, log, _) = FakeSteps::new(Faults::default());
+    let again = InstallFlow::with_steps(plan, steps);
+    assert_eq!(again.state(), FlowState::Done);
+    assert_eq!(run(&again).0, FlowState::Done);
+    assert!(log.lock().unwrap().steps.is_empty());
+}
+
+#[test]
+fn an_image_in_place_is_not_fetched() {
+    let dir = TempDir::new("install-flow-image");
+    let mut plan = plan(&dir);
+    plan.ipsw = Source::File(dir.garbage("Restore.ipsw"));
+    let (steps, log, _) = FakeSteps::new(Faults::default());
+    let flow = InstallFlow::with_steps(plan, steps);
+    assert_eq!(flow.state(), FlowState::ValidatingRequirements);
+
+    assert_eq!(run(&flow).0, FlowState::Done);
+    assert_eq!(
+        log.lock().unwrap().steps,
+        vec![
+            Step::ValidateRequirements,
+            Step::CreateBundle,
+            Step::Install
+        ]
+    );
+}
+
+#[test]
+fn a_complete_bundle_is_not_created_again() {
+    let dir = TempDir::new("install-flow-bundle");
+    let plan = plan(&dir);
+    fs::create_dir_all(&plan.bundle_dir).unwrap();
+    fs::write(plan.image_path(), b"ipsw").unwrap();
+    write_bundle(&plan);
+    assert!(plan.has_bundle());
+    let (steps, log, _) = FakeSteps::new(Faults::default());
+    let flow = InstallFlow::with_steps(plan, steps);
+
+    assert_eq!(run(&flow).0, FlowState::Done);
+    // The requirements are checked again, since nothing records them.
+    assert_eq!(
+        log.lock().unwrap().steps,
+        vec![Step::ValidateRequirements, Step::Install]
+    );
+}
+
+#[test]
+fn a_failed_fetch_keeps_the_partial_download() {
+    let dir = TempDir::new("install-flow-fetch");
+    let plan = plan(&dir);
+    fs::create_dir_all(&plan.bundle_dir).unwrap();
+    let (steps, _, _) = FakeSteps::new(Faults {
+        fetch: true,
+        ..Faults::default()
+    });
+    let flow = InstallFlow::with_steps(plan.clone(), steps);
+
+    assert_eq!(
+        run(&flow).0,
+        FlowState::Failed {
+            step: Step::FetchImage,
+            error: failure(Step::FetchImage),
+            cleanup_performed: false,
+        }
+    );
+    assert!(exists(&plan.image_path().with_extension("download")));
+}
+
+#[test]
+fn unmet_requirements_stop_before_the_bundle_is_created() {
+    let dir = TempDir::new("install-flow-requirements");
+    let plan = plan(&dir);
+    fs::create_dir_all(&plan.bundle_dir).unwrap();
+    let (steps, _, _) = FakeSteps::new(Faults {
+        requirements: true,
+        ..Faults::default()
+    });
+    let flow = InstallFlow::with_steps(plan.clone(), steps);
+
+    let end = run(&flow).0;
+    match &end {
+        FlowState::Failed {
+            step: Step::ValidateRequirements,
+            error: StepError::Requirements(violations),
+            cleanup_performed: false,
+        } => assert_eq!(violations.len(), 1),
+        state => panic!("unexpected {:?}", state),
+    }
+    assert!(end.to_string().contains("needs at least 5"), "{}", end);
+    assert!(!exists(&plan.disk_path()));
+    assert!(exists(&plan.image_path()));
+}
+
+#[test]
+fn a_failed_bundle_creation_is_removed_but_the_image_kept() {
+    let dir = TempDir::new("install-flow-create");
+    let plan = plan(&dir);
+    fs::create_dir_all(&plan.bundle_dir).unwrap();
+    let (steps, _, _) = FakeSteps::new(Faults {
+        create: true,
+        ..Faults::default()
+    });
+    let flow = InstallFlow::with_steps(plan.clone(), steps);
+
+    let end = run(&flow).0;
+    assert_eq!(
+        end,
+        FlowState::Failed {
+            step: Step::CreateBundle,
+            error: failure(Step::CreateBundle),
+            cleanup_performed: true,
+        }
+    );
+    assert!(end
+        .to_string()
+        .starts_with("creating the bundle failed (cleaned up): "));
+    assert!(MacVmBundle::load(&plan.bundle_dir).is_err());
+    assert!(!exists(&plan.auxiliary_storage_path()));
+    assert!(!exists(&plan.disk_path()));
+    assert!(exists(&plan.image_path()));
+
+    // The next advance resumes after the fetch.
+    let states = Arc::new(Mutex::new(Vec::new()));
+    let seen = states.clone();
+    let done = Arc::new(DispatchSemaphore::new(0));
+    let signal = done.clone();
+    flow.advance(move |state| {
+        seen.lock().unwrap().push(state);
+        signal.signal();
+    })
+    .unwrap();
+    assert!(done.wait_timeout(Duration::from_secs(10)));
+    assert_eq!(*states.lock().unwrap(), vec![FlowState::CreatingBundle]);
+}
+
+#[test]
+fn cancelling_an_installation_removes_the_bundle() {
+    let dir = TempDir::new("install-flow-cancel");
+    let plan = plan(&dir);
+    fs::create_dir_all(&plan.bundle_dir).unwrap();
+    fs::write(plan.image_path(), b"ipsw").unwrap();
+    write_bundle(&plan);
+    let (steps, log, installing) = FakeSteps::new(Faults {
+        install_until_cancelled: true,
+        ..Faults::default()
+    });
+    let flow = InstallFlow::with_steps(plan.clone(), steps);
+    assert_eq!(run_one(&flow), FlowState::Installing { progress: 0.0 });
+
+    let end = Arc::new(Mutex::new(None));
+    let done = Arc::new(DispatchSemaphore::new(0));
+    let (slot, signal) = (end.clone(), done.clone());
+    let sink = log.clone();
+    flow.advance(move |state| match state {
+        FlowState::Installing { progress } => sink.lock().unwrap().progress.push(progress),
+        state => {
+            *slot.lock().unwrap() = Some(state);
+            signal.signal();
+        }
+    })
+    .unwrap();
+    assert!(installing.wait_timeout(Duration::from_secs(10)));
+    // Only one step runs at a time.
+    assert_eq!(flow.advance(|_| {}), Err(AdvanceError::InProgress));
+    flow.cancel();
+
+    assert!(done.wait_timeout(Duration::from_secs(10)));
+    assert_eq!(
+        end.lock().unwrap().take(),
+        Some(FlowState::Failed {
+            step: Step::Install,
+            error: StepError::Cancelled,
+            cleanup_performed: true,
+        })
+    );
+    assert_eq!(log.lock().unwrap().progress, vec![0.5]);
+    assert!(!plan.has_bundle());
+    assert!(!plan.is_installed());
+    assert!(exists(&plan.image_path()));
+}
+
+/// Advances `flow` by one step and returns the state it led to.
+fn run_one(flow: &InstallFlow) -> FlowState {
+    let end = Arc::new(Mutex::new(None));
+    let done = Arc::new(DispatchSemaphore::new(0));
+    let (slot, signal) = (end.clone(), done.clone());
+    flow.advance(move |state| {
+        *slot.lock().unwrap() = Some(state);
+        signal.signal();
+    })
+    .unwrap();
+    assert!(done.wait_timeout(Duration::from_secs(10)));
+    let state = end.lock().unwrap().take();
+    state.unwrap()
+}
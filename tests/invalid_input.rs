@@ -0,0 +1,103 @@
+//! Constructors given input the framework would turn into nil fail right away, naming the input,
+//! instead of handing nil on to a later framework call.
+
+#![cfg(target_os = "macos")]
+
+extern crate virtualization_rs;
+
+use virtualization_rs::base::{InvalidInput, NSURL};
+use virtualization_rs::virtualization::boot_loader::{
+    VZEFIVariableStore, VZEFIVariableStoreInitializationOptions, VZLinuxBootLoaderBuilder,
+};
+use virtualization_rs::virtualization::directory_sharing::VZSharedDirectory;
+use virtualization_rs::virtualization::kernel_inspect::KernelCheckError;
+use virtualization_rs::virtualization::network_device::VZMACAddress;
+use virtualization_rs::virtualization::storage_device::{
+    VZDiskImageAttachmentError, VZDiskImageStorageDeviceAttachmentBuilder,
+};
+
+#[test]
+fn urls() {
+    assert!(NSURL::url_with_string("http://[").is_none());
+    assert!(NSURL::url_with_string("https://example.com/restore.ipsw").is_some());
+    assert!(NSURL::file_url_with_path("", false).is_none());
+    assert!(NSURL::file_url_with_path("/tmp/a\0b", false).is_none());
+    assert!(NSURL::file_url_with_path("/tmp", true).is_some());
+}
+
+#[test]
+fn mac_addresses() {
+    for bad in &["", "garbage", "02:00:00:00:00", "02:00:00:00:00:zz"] {
+        assert!(VZMACAddress::init_with_string(bad).is_none(), "{:?}", bad);
+    }
+    let mac = VZMACAddress::init_with_string("02:00:00:00:00:01").unwrap();
+    assert_eq!(mac.string(), "02:00:00:00:00:01");
+}
+
+#[test]
+fn linux_boot_loader_names_the_path() {
+    let error = VZLinuxBootLoaderBuilder::new()
+        .kernel_url("")
+        .initial_ramdisk_url("/tmp/initrd")
+        .command_line("console=hvc0")
+        .build()
+        .err()
+        .unwrap();
+    assert_eq!(error, InvalidInput::new("kernel path", ""));
+    assert_eq!(error.to_string(), "invalid kernel path: \"\"");
+
+    let error = VZLinuxBootLoaderBuilder::new()
+        .kernel_url("/tmp/vmlinuz")
+        .initial_ramdisk_url("/tmp/a\0b")
+        .command_line("console=hvc0")
+        .build()
+        .err()
+        .unwrap();
+    assert_eq!(error.kind, "initial ramdisk path");
+    assert_eq!(error.value, "/tmp/a\0b");
+
+    let checked = VZLinuxBootLoaderBuilder::new()
+        .kernel_url("")
+        .initial_ramdisk_url("")
+        .command_line("console=hvc0")
+        .skip_kernel_check(true)
+        .build_checked();
+    match checked {
+        Err(KernelCheckError::InvalidPath(error)) => assert_eq!(error.kind, "kernel path"),
+        other => panic!("expected InvalidPath, got {:?}", other.err()),
+    }
+}
+
+#[test]
+fn efi_variable_store_names_the_path() {
+    let error = VZEFIVariableStore::open("").err().unwrap();
+    assert_eq!(error, InvalidInput::new("EFI variable store path", ""));
+
+    let error = VZEFIVariableStore::create("", VZEFIVariableStoreInitializationOptions::new())
+        .err()
+        .unwrap();
+    assert_eq!(error.resource(), Some("''"));
+    assert_eq!(error.ns_error().code(), libc::EINVAL as isize);
+}
+
+#[test]
+fn disk_image_names_the_path() {
+    let error = VZDiskImageStorageDeviceAttachmentBuilder::new()
+        .path("")
+        .read_only(true)
+        .build()
+        .err()
+        .unwrap();
+    let error = match error {
+        VZDiskImageAttachmentError::Framework(error) => error,
+        other => panic!("expected a framework error, got {:?}", other),
+    };
+    assert_eq!(error.resource(), Some("'' (read_only=true)"));
+    assert_eq!(error.ns_error().code(), libc::EINVAL as isize);
+}
+
+#[test]
+fn shared_directory_names_the_path() {
+    let error = VZSharedDirectory::new("", true).err().unwrap();
+    assert_eq!(error, InvalidInput::new("shared directory path", ""));
+}
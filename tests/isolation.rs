@@ -0,0 +1,247 @@
+//! A virtual machine hosted in a child process must report its failures as typed errors, and a
+//! child that dies, however it dies, must turn every call on its handle into `HostDied`.
+//!
+//! Runs without the test harness: like an application, `main` calls `child_main` first, so that
+//! the children `VmHost` starts from this binary become VM hosts. A real boot runs only with
+//! `VIRTUALIZATION_RS_TEST_KERNEL` and `VIRTUALIZATION_RS_TEST_INITRD` set.
+
+extern crate virtualization_rs;
+
+use virtualization_rs::isolation;
+
+#[cfg(target_os = "macos")]
+mod tests {
+    use virtualization_rs::admission::{AdmissionPolicy, Violation};
+    use virtualization_rs::isolation::{HostError, HostSpec, VmHandle, VmHost};
+    use virtualization_rs::virtualization::virtual_machine::VZVirtualMachineState;
+
+    use std::fs::{self, File};
+    use std::io::Write;
+    use std::os::unix::process::ExitStatusExt;
+    use std::path::PathBuf;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    /// A scratch directory, removed on drop.
+    struct Scratch(PathBuf);
+
+    impl Scratch {
+        fn new(name: &str) -> Scratch {
+            let path = std::env::temp_dir().join(format!(
+                "virtualization-rs-isolation-{}-{}",
+                name,
+                std::process::id()
+            ));
+            let _ = fs::remove_dir_all(&path);
+            fs::create_dir_all(&path).unwrap();
+            Scratch(path)
+        }
+
+        /// A file of garbage: enough for the configuration to validate, not to boot.
+        fn file(&self, name: &str) -> PathBuf {
+            let path = self.0.join(name);
+            File::create(&path)
+                .unwrap()
+                .write_all(&[0x5a; 4096])
+                .unwrap();
+            path
+        }
+    }
+
+    impl Drop for Scratch {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn spawn(scratch: &Scratch) -> VmHandle {
+        let spec = HostSpec::linux(
+            scratch.file("vmlinuz"),
+            scratch.file("initrd"),
+            "console=hvc0",
+        );
+        VmHost::new()
+            .spawn(&spec)
+            .unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    fn assert_killed(result: Result<impl std::fmt::Debug, HostError>) {
+        match result {
+            Err(HostError::HostDied {
+                status: Some(status),
+            }) => assert_eq!(status.signal(), Some(libc::SIGKILL), "{}", status),
+            other => panic!("expected HostDied, got {:?}", other),
+        }
+    }
+
+    fn kill(vm: &VmHandle) {
+        assert_eq!(
+            unsafe { libc::kill(vm.pid() as libc::pid_t, libc::SIGKILL) },
+            0
+        );
+    }
+
+    pub fn setup_failure_is_typed() {
+        let spec = HostSpec::linux(
+            "/nonexistent-virtualization-rs/vmlinuz",
+            "/nonexistent-virtualization-rs/initrd",
+            "console=hvc0",
+        );
+        match VmHost::new().spawn(&spec) {
+            Err(HostError::Setup(message)) => assert!(
+                message.starts_with("failed to validate virtual machine configuration: "),
+                "{}",
+                message
+            ),
+            Err(e) => panic!("expected a setup error, got {}", e),
+            Ok(_) => panic!("built a machine without a kernel"),
+        }
+    }
+
+    pub fn new_machine_is_stopped() {
+        let scratch = Scratch::new("stopped");
+        let vm = spawn(&scratch);
+        assert_eq!(
+            vm.state().unwrap(),
+            VZVirtualMachineState::VZVirtualMachineStateStopped
+        );
+    }
+
+    pub fn unadmitted_machine_stays_stopped() {
+        let scratch = Scratch::new("unadmitted");
+        let spec = HostSpec::linux(
+            scratch.file("vmlinuz"),
+            scratch.file("initrd"),
+            "console=hvc0",
+        );
+        let policy = AdmissionPolicy {
+            memory_overcommit: 0.0,
+            ..AdmissionPolicy::default()
+        };
+        let vm = VmHost::new()
+            .admit_before_start(policy)
+            .spawn(&spec)
+            .unwrap_or_else(|e| panic!("{}", e));
+        match vm.start() {
+            Err(HostError::NotAdmitted(violations)) => match violations.as_slice() {
+                [Violation::Memory {
+                    required, allowed, ..
+                }] => {
+                    assert_eq!(*required, 512 * 1024 * 1024);
+                    assert_eq!(*allowed, 0);
+                }
+                other => panic!("expected a memory violation, got {:?}", other),
+            },
+            other => panic!("expected NotAdmitted, got {:?}", other),
+        }
+        assert_eq!(
+            vm.state().unwrap(),
+            VZVirtualMachineState::VZVirtualMachineStateStopped
+        );
+    }
+
+    pub fn killed_child_fails_every_call() {
+        let scratch = Scratch::new("killed");
+        let vm = spawn(&scratch);
+        kill(&vm);
+        assert_killed(vm.wait());
+        assert_killed(vm.state());
+        assert_killed(vm.start());
+        assert_killed(vm.stop());
+    }
+
+    pub fn calls_in_flight_fail_when_the_child_dies() {
+        let scratch = Scratch::new("in-flight");
+        let vm = spawn(&scratch);
+        thread::scope(|s| {
+            let waiter = s.spawn(|| vm.wait());
+            thread::sleep(Duration::from_millis(200));
+            kill(&vm);
+            assert_killed(waiter.join().unwrap());
+        });
+    }
+
+    pub fn sigterm_stops_and_reports() {
+        let scratch = Scratch::new("sigterm");
+        let vm = spawn(&scratch);
+        vm.terminate().unwrap();
+        let exit = vm.wait().unwrap_or_else(|e| panic!("{}", e));
+        assert_eq!(
+            exit.state,
+            VZVirtualMachineState::VZVirtualMachineStateStopped
+        );
+        assert!(exit.status.success(), "{}", exit.status);
+        assert!(matches!(vm.state(), Err(HostError::Exited)));
+    }
+
+    pub fn dropped_handle_ends_the_child() {
+        let scratch = Scratch::new("dropped");
+        let pid = spawn(&scratch).pid() as libc::pid_t;
+        let deadline = Instant::now() + Duration::from_secs(15);
+        // The reader thread collects the child, after which the pid is gone.
+        while unsafe { libc::kill(pid, 0) } == 0 {
+            assert!(Instant::now() < deadline, "child {} still running", pid);
+            thread::sleep(Duration::from_millis(50));
+        }
+    }
+
+    /// Kills the child while a real guest boots.
+    pub fn killed_mid_boot() {
+        let (kernel, initrd) = match (
+            std::env::var_os("VIRTUALIZATION_RS_TEST_KERNEL"),
+            std::env::var_os("VIRTUALIZATION_RS_TEST_INITRD"),
+        ) {
+            (Some(kernel), Some(initrd)) => (kernel, initrd),
+            _ => {
+                println!("killed_mid_boot skipped: no test kernel");
+                return;
+            }
+        };
+        let spec = HostSpec::linux(kernel, initrd, "console=hvc0").memory_size(1 << 30);
+        let vm = VmHost::new()
+            .spawn(&spec)
+            .unwrap_or_else(|e| panic!("{}", e));
+        vm.start().unwrap_or_else(|e| panic!("{}", e));
+        thread::sleep(Duration::from_millis(500));
+        kill(&vm);
+        assert_killed(vm.wait());
+        assert_killed(vm.state());
+    }
+}
+
+fn main() {
+    isolation::child_main();
+
+    #[cfg(target_os = "macos")]
+    {
+        let tests: &[(&str, fn())] = &[
+            ("setup_failure_is_typed", tests::setup_failure_is_typed),
+            ("new_machine_is_stopped", tests::new_machine_is_stopped),
+            (
+                "unadmitted_machine_stays_stopped",
+                tests::unadmitted_machine_stays_stopped,
+            ),
+            (
+                "killed_child_fails_every_call",
+                tests::killed_child_fails_every_call,
+            ),
+            (
+                "calls_in_flight_fail_when_the_child_dies",
+                tests::calls_in_flight_fail_when_the_child_dies,
+            ),
+            (
+                "sigterm_stops_and_reports",
+                tests::sigterm_stops_and_reports,
+            ),
+            (
+                "dropped_handle_ends_the_child",
+                tests::dropped_handle_ends_the_child,
+            ),
+            ("killed_mid_boot", tests::killed_mid_boot),
+        ];
+        for (name, test) in tests {
+            test();
+            println!("test {} ... ok", name);
+        }
+    }
+}
@@ -0,0 +1,447 @@
+//! Key-value observing of any object: values decode by type from the change dictionaries, the
+//! options decide about initial and old values, and the guard ends the observation exactly once,
+//! also when dropped from its own callback or while other threads keep changing the value.
+//!
+//! The observed object is an instance of a class declared here, with properties that post their
+//! changes manually from their setters.
+
+#![cfg(target_os = "macos")]
+
+extern crate objc;
+extern crate virtualization_rs;
+
+use virtualization_rs::base::{Id, NSString, NIL};
+use virtualization_rs::kvo::{
+    observe_fraction_completed, observe_keypath, FromKvoValue, KvoChange, KvoGuard, KvoOption,
+    KvoOptions,
+};
+use virtualization_rs::runtime::autoreleasepool;
+
+use std::convert::identity;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, Once};
+use std::thread;
+
+use objc::declare::ClassDecl;
+use objc::rc::{StrongPtr, WeakPtr};
+use objc::runtime::{Class, Object, Sel, BOOL, NO, YES};
+use objc::{class, msg_send, sel, sel_impl};
+
+const MODEL_CLASS: &str = "VirtualizationRsTestKvoModel";
+
+fn model_class() -> &'static Class {
+    static REGISTER: Once = Once::new();
+    REGISTER.call_once(|| {
+        let mut decl = ClassDecl::new(MODEL_CLASS, class!(NSObject)).unwrap();
+        decl.add_ivar::<isize>("_count");
+        decl.add_ivar::<f64>("_ratio");
+        decl.add_ivar::<BOOL>("_flag");
+        decl.add_ivar::<Id>("_name");
+        unsafe {
+            decl.add_class_method(
+                sel!(automaticallyNotifiesObserversForKey:),
+                notifies_automatically as extern "C" fn(&Class, Sel, Id) -> BOOL,
+            );
+            decl.add_method(sel!(count), count as extern "C" fn(&Object, Sel) -> isize);
+            decl.add_method(
+                sel!(setCount:),
+                set_count as extern "C" fn(&mut Object, Sel, isize),
+            );
+            decl.add_method(sel!(ratio), ratio as extern "C" fn(&Object, Sel) -> f64);
+            decl.add_method(
+                sel!(setRatio:),
+                set_ratio as extern "C" fn(&mut Object, Sel, f64),
+            );
+            decl.add_method(sel!(flag), flag as extern "C" fn(&Object, Sel) -> BOOL);
+            decl.add_method(
+                sel!(setFlag:),
+                set_flag as extern "C" fn(&mut Object, Sel, BOOL),
+            );
+            decl.add_method(sel!(name), name as extern "C" fn(&Object, Sel) -> Id);
+            decl.add_method(
+                sel!(setName:),
+                set_name as extern "C" fn(&mut Object, Sel, Id),
+            );
+            decl.add_method(sel!(dealloc), dealloc as extern "C" fn(&mut Object, Sel));
+        }
+        decl.register();
+    });
+    Class::get(MODEL_CLASS).unwrap()
+}
+
+extern "C" fn notifies_automatically(_class: &Class, _cmd: Sel, _key: Id) -> BOOL {
+    NO
+}
+
+/// Sets `ivar` of `this` to `value`, posting the change of `key`.
+fn change<T: objc::Encode>(this: &mut Object, key: &str, ivar: &str, value: T) {
+    let key = NSString::new(key);
+    unsafe {
+        let _: () = msg_send![this, willChangeValueForKey: *key.0];
+        this.set_ivar(ivar, value);
+        let _: () = msg_send![this, didChangeValueForKey: *key.0];
+    }
+}
+
+extern "C" fn count(this: &Object, _cmd: Sel) -> isize {
+    unsafe { *this.get_ivar("_count") }
+}
+
+extern "C" fn set_count(this: &mut Object, _cmd: Sel, value: isize) {
+    change(this, "count", "_count", value);
+}
+
+extern "C" fn ratio(this: &Object, _cmd: Sel) -> f64 {
+    unsafe { *this.get_ivar("_ratio") }
+}
+
+extern "C" fn set_ratio(this: &mut Object, _cmd: Sel, value: f64) {
+    change(this, "ratio", "_ratio", value);
+}
+
+extern "C" fn flag(this: &Object, _cmd: Sel) -> BOOL {
+    unsafe { *this.get_ivar("_flag") }
+}
+
+extern "C" fn set_flag(this: &mut Object, _cmd: Sel, value: BOOL) {
+    change(this, "flag", "_flag", value);
+}
+
+extern "C" fn name(this: &Object, _cmd: Sel) -> Id {
+    unsafe { *this.get_ivar("_name") }
+}
+
+extern "C" fn set_name(this: &mut Object, _cmd: Sel, value: Id) {
+    unsafe {
+        let old: Id = *this.get_ivar("_name");
+        let _: Id = msg_send![value, retain];
+        change(this, "name", "_name", value);
+        let _: () = msg_send![old, release];
+    }
+}
+
+extern "C" fn dealloc(this: &mut Object, _cmd: Sel) {
+    unsafe {
+        let name: Id = *this.get_ivar("_name");
+        let _: () = msg_send![name, release];
+        let _: () = msg_send![super(this, class!(NSObject)), dealloc];
+    }
+}
+
+/// An instance of the model class, changed from several threads by the tests.
+struct Model(StrongPtr);
+
+unsafe impl Send for Model {}
+unsafe impl Sync for Model {}
+
+impl Model {
+    fn new() -> Model {
+        unsafe { Model(StrongPtr::new(msg_send![model_class(), new])) }
+    }
+
+    fn id(&self) -> Id {
+        *self.0
+    }
+
+    fn set_count(&self, value: isize) {
+        unsafe { msg_send![*self.0, setCount: value] }
+    }
+
+    fn set_ratio(&self, value: f64) {
+        unsafe { msg_send![*self.0, setRatio: value] }
+    }
+
+    fn set_flag(&self, value: bool) {
+        let value = if value { YES } else { NO };
+        unsafe { msg_send![*self.0, setFlag: value] }
+    }
+
+    fn set_name(&self, value: Option<&str>) {
+        let value = value.map(NSString::new);
+        let value = value.as_ref().map_or(NIL, |s| *s.0);
+        unsafe { msg_send![*self.0, setName: value] }
+    }
+
+    fn set_name_null(&self) {
+        unsafe {
+            let null: Id = msg_send![class!(NSNull), null];
+            msg_send![*self.0, setName: null]
+        }
+    }
+
+    /// Observes `keypath`, recording the decoded values as `map` turns them into plain data.
+    fn record<T, U>(
+        &self,
+        keypath: &str,
+        options: KvoOptions,
+        map: fn(T) -> U,
+    ) -> (Arc<Mutex<Vec<U>>>, KvoGuard)
+    where
+        T: FromKvoValue + 'static,
+        U: Send + 'static,
+    {
+        let values = Arc::new(Mutex::new(Vec::new()));
+        let recorded = values.clone();
+        let guard = unsafe {
+            observe_keypath(self.id(), keypath, options, move |value: T| {
+                recorded.lock().unwrap().push(map(value));
+            })
+        };
+        (values, guard)
+    }
+}
+
+fn initial_and_new() -> KvoOptions {
+    KvoOptions::new()
+        .with(KvoOption::initial())
+        .with(KvoOption::new_value())
+}
+
+fn string(value: NSString) -> String {
+    value.as_str().to_string()
+}
+
+fn taken<T>(values: &Mutex<Vec<T>>) -> Vec<T> {
+    std::mem::take(&mut *values.lock().unwrap())
+}
+
+#[test]
+fn decodes_numbers_bools_and_strings() {
+    let model = Model::new();
+    let (counts, _counts) = model.record::<isize, _>("count", initial_and_new(), identity);
+    let (ratios, _ratios) = model.record::<f64, _>("ratio", initial_and_new(), identity);
+    let (flags, _flags) = model.record::<bool, _>("flag", initial_and_new(), identity);
+    let (names, _names) = model.record("name", initial_and_new(), string);
+
+    model.set_count(-3);
+    model.set_count(7);
+    model.set_ratio(0.25);
+    model.set_flag(true);
+    model.set_name(Some("first"));
+    model.set_name(Some("second"));
+
+    assert_eq!(taken(&counts), vec![0, -3, 7]);
+    assert_eq!(taken(&ratios), vec![0.0, 0.25]);
+    assert_eq!(taken(&flags), vec![false, true]);
+    // The initial nil name does not decode as a string.
+    assert_eq!(taken(&names), vec!["first", "second"]);
+}
+
+#[test]
+fn initial_value_only_when_asked() {
+    let model = Model::new();
+    model.set_count(5);
+    let (with_initial, _with) = model.record::<isize, _>("count", initial_and_new(), identity);
+    let (without, _without) = model.record::<isize, _>(
+        "count",
+        KvoOptions::new().with(KvoOption::new_value()),
+        identity,
+    );
+    model.set_count(6);
+    assert_eq!(taken(&with_initial), vec![5, 6]);
+    assert_eq!(taken(&without), vec![6]);
+}
+
+#[test]
+fn old_values_when_asked() {
+    let model = Model::new();
+    let options = initial_and_new().with(KvoOption::old_value());
+    let (changes, _guard) = model.record::<KvoChange<isize>, _>("count", options, identity);
+    model.set_count(1);
+    model.set_count(2);
+    let change = |new, old| KvoChange { new, old };
+    assert_eq!(
+        taken(&changes),
+        vec![
+            change(Some(0), None),
+            change(Some(1), Some(0)),
+            change(Some(2), Some(1))
+        ]
+    );
+}
+
+#[test]
+fn nil_and_null_clear_optional_values() {
+    let model = Model::new();
+    let (names, _names) = model.record("name", initial_and_new(), string);
+    let (optional, _optional) =
+        model.record("name", initial_and_new(), |name: Option<NSString>| {
+            name.map(string)
+        });
+    let (nils, _nils) = model.record("name", initial_and_new(), |id: Id| id.is_null());
+    // A string property observed as a number never decodes.
+    let (numbers, _numbers) = model.record::<isize, _>("name", initial_and_new(), identity);
+
+    model.set_name(Some("set"));
+    model.set_name(None);
+    model.set_name(Some("again"));
+    model.set_name_null();
+
+    assert_eq!(taken(&names), vec!["set", "again"]);
+    assert_eq!(
+        taken(&optional),
+        vec![
+            None,
+            Some("set".to_string()),
+            None,
+            Some("again".to_string()),
+            None
+        ]
+    );
+    assert_eq!(taken(&nils), vec![true, false, true, false, true]);
+    assert!(taken(&numbers).is_empty());
+}
+
+#[test]
+fn dropping_the_guard_ends_the_observation() {
+    let model = Model::new();
+    let (counts, guard) = model.record::<isize, _>("count", initial_and_new(), identity);
+    model.set_count(1);
+    drop(guard);
+    model.set_count(2);
+    assert_eq!(taken(&counts), vec![0, 1]);
+    // The callback and what it captured are gone.
+    assert_eq!(Arc::strong_count(&counts), 1);
+}
+
+#[test]
+fn the_guard_keeps_the_object_alive() {
+    // The pool drains what the notifications autoreleased, which could keep the model alive.
+    let (weak, counts) = autoreleasepool(|| {
+        let model = Model::new();
+        let (counts, guard) = model.record::<isize, _>("count", initial_and_new(), identity);
+        let weak = unsafe { WeakPtr::new(model.id()) };
+        drop(model);
+
+        let alive = weak.load();
+        assert!(!alive.is_null());
+        unsafe {
+            let _: () = msg_send![*alive, setCount: 4isize];
+        }
+        drop(guard);
+        (weak, counts)
+    });
+    assert!(weak.load().is_null());
+    assert_eq!(taken(&counts), vec![0, 4]);
+}
+
+#[test]
+fn the_callback_can_drop_its_guard() {
+    let model = Model::new();
+    let slot: Arc<Mutex<Option<KvoGuard>>> = Arc::new(Mutex::new(None));
+    let counts = Arc::new(Mutex::new(Vec::new()));
+    let (callback_slot, recorded) = (slot.clone(), counts.clone());
+    let guard = unsafe {
+        observe_keypath(
+            model.id(),
+            "count",
+            KvoOptions::new().with(KvoOption::new_value()),
+            move |count: isize| {
+                recorded.lock().unwrap().push(count);
+                if count == 2 {
+                    let guard = callback_slot.lock().unwrap().take();
+                    drop(guard);
+                }
+            },
+        )
+    };
+    *slot.lock().unwrap() = Some(guard);
+    for count in 1..=4 {
+        model.set_count(count);
+    }
+    assert_eq!(taken(&counts), vec![1, 2]);
+    assert!(slot.lock().unwrap().is_none());
+}
+
+#[test]
+fn changes_from_the_callback_are_not_redelivered() {
+    let model = Arc::new(Model::new());
+    let counts = Arc::new(Mutex::new(Vec::new()));
+    let (inner, recorded) = (model.clone(), counts.clone());
+    let _guard = unsafe {
+        observe_keypath(
+            model.id(),
+            "count",
+            KvoOptions::new().with(KvoOption::new_value()),
+            move |count: isize| {
+                recorded.lock().unwrap().push(count);
+                inner.set_count(count * 10);
+            },
+        )
+    };
+    model.set_count(1);
+    model.set_count(2);
+    assert_eq!(taken(&counts), vec![1, 2]);
+    let count: isize = unsafe { msg_send![model.id(), count] };
+    assert_eq!(count, 20);
+}
+
+#[test]
+fn callbacks_are_serialized_and_end_with_the_guard() {
+    let model = Model::new();
+    let running = Arc::new(AtomicBool::new(false));
+    let delivered = Arc::new(AtomicUsize::new(0));
+    let (callback_running, callback_delivered) = (running.clone(), delivered.clone());
+    let guard = unsafe {
+        observe_keypath(
+            model.id(),
+            "count",
+            KvoOptions::new().with(KvoOption::new_value()),
+            move |_: isize| {
+                assert!(
+                    !callback_running.swap(true, Ordering::SeqCst),
+                    "callbacks overlapped"
+                );
+                thread::yield_now();
+                callback_delivered.fetch_add(1, Ordering::SeqCst);
+                callback_running.store(false, Ordering::SeqCst);
+            },
+        )
+    };
+    let stop = AtomicBool::new(false);
+    thread::scope(|s| {
+        for t in 0..4 {
+            let (model, stop) = (&model, &stop);
+            s.spawn(move || {
+                let mut count = t * 1_000_000;
+                while !stop.load(Ordering::SeqCst) {
+                    count += 1;
+                    model.set_count(count);
+                }
+            });
+        }
+        while delivered.load(Ordering::SeqCst) < 1000 {
+            thread::yield_now();
+        }
+        drop(guard);
+        // No callback runs or starts once the guard is gone.
+        let after_drop = delivered.load(Ordering::SeqCst);
+        assert!(!running.load(Ordering::SeqCst));
+        for _ in 0..1000 {
+            thread::yield_now();
+        }
+        assert_eq!(delivered.load(Ordering::SeqCst), after_drop);
+        stop.store(true, Ordering::SeqCst);
+    });
+}
+
+#[test]
+fn observes_the_fraction_completed_of_a_progress() {
+    let progress = unsafe {
+        StrongPtr::retain(msg_send![class!(NSProgress), progressWithTotalUnitCount: 4i64])
+    };
+    let fractions = Arc::new(Mutex::new(Vec::new()));
+    let recorded = fractions.clone();
+    let guard = unsafe {
+        observe_fraction_completed(*progress, move |fraction| {
+            recorded.lock().unwrap().push(fraction);
+        })
+    };
+    for done in 1..=4i64 {
+        unsafe {
+            let _: () = msg_send![*progress, setCompletedUnitCount: done];
+        }
+    }
+    drop(guard);
+    assert_eq!(taken(&fractions), vec![0.0, 0.25, 0.5, 0.75, 1.0]);
+}
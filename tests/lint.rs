@@ -0,0 +1,247 @@
+//! Presets with each device left out, and the lint pass over them: lints have stable names and
+//! default severities, a `LintConfig` silences or regrades them by name, and a deliberately
+//! minimal configuration lints clean once it acknowledges what it leaves out.
+
+#![cfg(target_os = "macos")]
+
+extern crate virtualization_rs;
+
+use virtualization_rs::lint::{self, Lint, LintConfig, ParseLintError, Severity};
+use virtualization_rs::presets::Preset;
+use virtualization_rs::profile::DeviceSelection;
+use virtualization_rs::test_support::{self, TempDir};
+use virtualization_rs::virtualization::memory_device::VZVirtioTraditionalMemoryBalloonDeviceConfiguration;
+use virtualization_rs::virtualization::network_device::{
+    VZMACAddress, VZNATNetworkDeviceAttachment, VZVirtioNetworkDeviceConfiguration,
+};
+use virtualization_rs::virtualization::virtual_machine::VZVirtualMachineConfiguration;
+
+fn build(dir: &TempDir, preset: Preset) -> VZVirtualMachineConfiguration {
+    let conf = preset
+        .boot_loader(test_support::garbage_linux_boot_loader(dir))
+        .build();
+    assert!(conf
+        .validate_with_error()
+        .unwrap_or_else(|e| panic!("{}", e)));
+    conf
+}
+
+/// The lints and severities `check` reports, in its order.
+fn lints(conf: &VZVirtualMachineConfiguration, config: &LintConfig) -> Vec<(Lint, Severity)> {
+    lint::check(conf, config)
+        .into_iter()
+        .map(|finding| (finding.lint, finding.severity))
+        .collect()
+}
+
+fn benchmark_devices() -> DeviceSelection {
+    DeviceSelection {
+        entropy: false,
+        memory_balloon: false,
+        ..DeviceSelection::all()
+    }
+}
+
+fn network_device(mac: &str) -> VZVirtioNetworkDeviceConfiguration {
+    let mut device = VZVirtioNetworkDeviceConfiguration::new(VZNATNetworkDeviceAttachment::new());
+    device
+        .set_mac_address(VZMACAddress::init_with_string(mac).unwrap())
+        .unwrap();
+    device
+}
+
+#[test]
+fn lint_names_are_stable() {
+    let names: Vec<_> = Lint::all().iter().map(|lint| lint.name()).collect();
+    assert_eq!(
+        names,
+        [
+            "missing-entropy",
+            "missing-balloon",
+            "missing-console",
+            "missing-network",
+            "device-limit",
+            "duplicate-mac-address",
+        ]
+    );
+    for &lint in Lint::all() {
+        assert_eq!(lint.to_string().parse::<Lint>(), Ok(lint));
+    }
+    assert_eq!(
+        "missing_balloon".parse::<Lint>(),
+        Err(ParseLintError("missing_balloon".to_string()))
+    );
+    assert_eq!(
+        ParseLintError("x".to_string()).to_string(),
+        "unknown lint \"x\""
+    );
+}
+
+#[test]
+fn a_config_silences_and_regrades_lints() {
+    let config = LintConfig::new();
+    for &lint in Lint::all() {
+        assert_eq!(config.level(lint), Some(lint.default_severity()));
+    }
+    assert_eq!(Lint::MissingBalloon.default_severity(), Severity::Warning);
+    assert_eq!(Lint::MissingNetwork.default_severity(), Severity::Note);
+    assert_eq!(
+        Lint::DuplicateMacAddress.default_severity(),
+        Severity::Error
+    );
+
+    let config = LintConfig::new()
+        .allow(Lint::MissingBalloon)
+        .severity(Lint::MissingEntropy, Severity::Note)
+        .severity(Lint::MissingNetwork, Severity::Error);
+    assert_eq!(config.level(Lint::MissingBalloon), None);
+    assert_eq!(config.level(Lint::MissingEntropy), Some(Severity::Note));
+    assert_eq!(config.level(Lint::MissingNetwork), Some(Severity::Error));
+    assert_eq!(config.level(Lint::MissingConsole), Some(Severity::Warning));
+
+    // The last word on a lint wins.
+    let config = config
+        .severity(Lint::MissingBalloon, Severity::Note)
+        .allow(Lint::MissingNetwork);
+    assert_eq!(config.level(Lint::MissingBalloon), Some(Severity::Note));
+    assert_eq!(config.level(Lint::MissingNetwork), None);
+}
+
+#[test]
+fn a_full_preset_lints_clean() {
+    let dir = TempDir::new("lint-full");
+    let conf = build(&dir, Preset::default());
+    assert_eq!(conf.cpu_count(), 2);
+    assert_eq!(conf.memory_size(), 2 << 30);
+    for (category, count) in [
+        ("entropy_devices", 1),
+        ("memory_balloon_devices", 1),
+        ("serial_ports", 1),
+        ("network_devices", 1),
+        ("storage_devices", 0),
+    ] {
+        assert_eq!(conf.device_count(category), Some(count), "{}", category);
+    }
+    assert_eq!(lint::check(&conf, &LintConfig::new()), []);
+}
+
+#[test]
+fn a_minimal_benchmark_lints_clean_once_acknowledged() {
+    let dir = TempDir::new("lint-benchmark");
+    let conf = build(&dir, Preset::new(1, 512).devices(benchmark_devices()));
+    assert_eq!(conf.device_count("entropy_devices"), Some(0));
+    assert_eq!(conf.device_count("memory_balloon_devices"), Some(0));
+    assert_eq!(conf.device_count("serial_ports"), Some(1));
+    assert_eq!(conf.device_count("network_devices"), Some(1));
+
+    let findings = lint::check(&conf, &LintConfig::new());
+    assert_eq!(
+        findings
+            .iter()
+            .map(|finding| finding.to_string())
+            .collect::<Vec<_>>(),
+        [
+            "no entropy device; a Linux guest may wait for entropy at boot [missing-entropy]",
+            "no memory balloon device; the host cannot reclaim guest memory [missing-balloon]",
+        ]
+    );
+    assert!(findings
+        .iter()
+        .all(|finding| finding.severity == Severity::Warning));
+
+    let acknowledged = LintConfig::new()
+        .allow("missing-entropy".parse().unwrap())
+        .allow("missing-balloon".parse().unwrap());
+    assert_eq!(lint::check(&conf, &acknowledged), []);
+}
+
+#[test]
+fn findings_come_most_severe_first() {
+    let dir = TempDir::new("lint-none");
+    let conf = build(
+        &dir,
+        Preset::new(1, 512).devices(DeviceSelection::default()),
+    );
+    assert_eq!(
+        lints(&conf, &LintConfig::new()),
+        [
+            (Lint::MissingEntropy, Severity::Warning),
+            (Lint::MissingBalloon, Severity::Warning),
+            (Lint::MissingConsole, Severity::Warning),
+            (Lint::MissingNetwork, Severity::Note),
+        ]
+    );
+    let config = LintConfig::new()
+        .severity(Lint::MissingNetwork, Severity::Error)
+        .severity(Lint::MissingEntropy, Severity::Note)
+        .allow(Lint::MissingBalloon);
+    assert_eq!(
+        lints(&conf, &config),
+        [
+            (Lint::MissingNetwork, Severity::Error),
+            (Lint::MissingConsole, Severity::Warning),
+            (Lint::MissingEntropy, Severity::Note),
+        ]
+    );
+}
+
+#[test]
+fn each_left_out_device_triggers_its_lint_alone() {
+    let dir = TempDir::new("lint-each");
+    for lint in [
+        Lint::MissingEntropy,
+        Lint::MissingBalloon,
+        Lint::MissingConsole,
+        Lint::MissingNetwork,
+    ] {
+        let mut devices = DeviceSelection::all();
+        match lint {
+            Lint::MissingEntropy => devices.entropy = false,
+            Lint::MissingBalloon => devices.memory_balloon = false,
+            Lint::MissingConsole => devices.serial_console = false,
+            _ => devices.nat_network = false,
+        }
+        let conf = build(&dir, Preset::default().devices(devices));
+        assert_eq!(
+            lints(&conf, &LintConfig::new()),
+            [(lint, lint.default_severity())]
+        );
+        assert_eq!(lint::check(&conf, &LintConfig::new().allow(lint)), []);
+    }
+}
+
+#[test]
+fn duplicate_mac_addresses_and_device_limits_are_errors() {
+    let dir = TempDir::new("lint-errors");
+    let conf = Preset::new(1, 512)
+        .boot_loader(test_support::garbage_linux_boot_loader(&dir))
+        .network_devices(vec![
+            network_device("52:54:00:00:00:01"),
+            network_device("52:54:00:00:00:02"),
+            network_device("52:54:00:00:00:01"),
+            network_device("52:54:00:00:00:01"),
+        ])
+        .memory_balloon_devices(vec![
+            VZVirtioTraditionalMemoryBalloonDeviceConfiguration::new(),
+            VZVirtioTraditionalMemoryBalloonDeviceConfiguration::new(),
+        ])
+        .build();
+    let findings = lint::check(&conf, &LintConfig::new());
+    let errors: Vec<_> = findings
+        .iter()
+        .map(|finding| (finding.lint, finding.severity))
+        .collect();
+    assert_eq!(
+        errors,
+        [
+            (Lint::DeviceLimit, Severity::Error),
+            (Lint::DuplicateMacAddress, Severity::Error),
+        ]
+    );
+    assert!(findings[0].message.starts_with("memory_balloon_devices: "));
+    // Once per address, however many devices share it.
+    assert_eq!(
+        findings[1].to_string(),
+        "more than one network device has the MAC address 52:54:00:00:00:01 [duplicate-mac-address]"
+    );
+}
@@ -0,0 +1,111 @@
+//! Console-marker liveness checks against buffers no guest writes to: the marker is found once
+//! written, and otherwise the check times out, is cancelled through its token, including by a
+//! supervisor watching a `FakeVm` crash, or sees the console closed.
+
+#![cfg(target_os = "macos")]
+
+extern crate libc;
+extern crate virtualization_rs;
+
+use virtualization_rs::base::{CancellationToken, NSError};
+use virtualization_rs::fake_vm::FakeVm;
+use virtualization_rs::liveness::{Liveness, LivenessError};
+use virtualization_rs::virtualization::error::{CompletionOutcome, VZError};
+use virtualization_rs::virtualization::handle::VirtualMachineHandle;
+use virtualization_rs::virtualization::serial_port::{ConsoleBuffer, ConsoleCapture};
+use virtualization_rs::virtualization::virtual_machine::VZVirtualMachineState::*;
+
+use std::sync::mpsc::channel;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+const SHORT: Duration = Duration::from_millis(200);
+
+/// Writes `bytes` where the guest's console output goes.
+fn guest_writes(capture: &ConsoleCapture, bytes: &[u8]) {
+    let fd = capture.attachment().backing_fds().1.unwrap();
+    let written = unsafe { libc::write(fd, bytes.as_ptr() as *const libc::c_void, bytes.len()) };
+    assert_eq!(written, bytes.len() as isize);
+}
+
+#[test]
+fn the_marker_is_found_once_written() {
+    let capture = ConsoleCapture::new().unwrap();
+    let liveness = Liveness::console_marker(capture.buffer(), "login:");
+    guest_writes(&capture, b"Welcome\nhost login: ");
+    let elapsed = liveness.wait(TIMEOUT, &CancellationToken::new()).unwrap();
+    assert!(elapsed < TIMEOUT);
+}
+
+#[test]
+fn a_silent_console_times_out() {
+    let liveness = Liveness::console_marker(Arc::new(ConsoleBuffer::default()), "login:");
+    let started = Instant::now();
+    let error = liveness.wait(SHORT, &CancellationToken::new()).unwrap_err();
+    assert!(matches!(error, LivenessError::Timeout), "{:?}", error);
+    assert!(started.elapsed() >= SHORT);
+    assert_eq!(
+        error.to_string(),
+        "the guest did not answer before the timeout"
+    );
+}
+
+#[test]
+fn a_cancelled_token_ends_the_wait() {
+    let liveness = Liveness::console_marker(Arc::new(ConsoleBuffer::default()), "login:");
+    let token = CancellationToken::new();
+    token.cancel();
+    let error = liveness.wait(TIMEOUT, &token).unwrap_err();
+    assert!(matches!(error, LivenessError::Cancelled), "{:?}", error);
+
+    let token = CancellationToken::new();
+    let canceller = token.clone();
+    thread::spawn(move || {
+        thread::sleep(SHORT);
+        canceller.cancel();
+    });
+    let started = Instant::now();
+    let error = liveness.wait(TIMEOUT, &token).unwrap_err();
+    assert!(matches!(error, LivenessError::Cancelled), "{:?}", error);
+    assert!(started.elapsed() < TIMEOUT);
+}
+
+#[test]
+fn a_supervisor_cancels_when_the_machine_crashes() {
+    let vm = FakeVm::new("liveness-crash");
+    let token = CancellationToken::new();
+    let canceller = token.clone();
+    let _observation = vm.on_first_transition_to(VZVirtualMachineStateError, move || {
+        canceller.cancel();
+    });
+    let (sender, started) = channel();
+    vm.start(move |outcome| {
+        let _ = sender.send(matches!(outcome, CompletionOutcome::Success(_)));
+    })
+    .unwrap();
+    assert!(started.recv_timeout(TIMEOUT).unwrap());
+
+    let crasher = vm.clone();
+    thread::spawn(move || {
+        thread::sleep(SHORT);
+        crasher.crash(VZError(NSError::posix(libc::EIO)));
+    });
+    let liveness = Liveness::console_marker(Arc::new(ConsoleBuffer::default()), "login:");
+    let error = liveness.wait(TIMEOUT, &token).unwrap_err();
+    assert!(matches!(error, LivenessError::Cancelled), "{:?}", error);
+    assert_eq!(vm.peek_state(), VZVirtualMachineStateError);
+}
+
+#[test]
+fn a_closed_console_is_reported() {
+    let capture = ConsoleCapture::new().unwrap();
+    let liveness = Liveness::console_marker(capture.buffer(), "login:");
+    guest_writes(&capture, b"kernel panic");
+    capture.close().unwrap();
+    let error = liveness
+        .wait(TIMEOUT, &CancellationToken::new())
+        .unwrap_err();
+    assert!(matches!(error, LivenessError::Closed), "{:?}", error);
+}
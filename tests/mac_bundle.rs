@@ -0,0 +1,157 @@
+//! macOS guest bundles round-trip their hardware model and machine identifier byte for byte,
+//! survive being moved, and refuse to load when a file no longer matches the manifest.
+//!
+//! The case with a real hardware model needs a restore image in `VIRTUALIZATION_RS_TEST_IPSW`
+//! and prints why it is skipped otherwise.
+
+#![cfg(target_os = "macos")]
+
+extern crate virtualization_rs;
+
+use virtualization_rs::mac_bundle::{BundleError, MacVmBundle};
+use virtualization_rs::virtualization::error::CompletionOutcome;
+use virtualization_rs::virtualization::restore_image::VZMacOSRestoreImage;
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+
+struct Scratch(PathBuf);
+
+impl Scratch {
+    fn new(name: &str) -> Scratch {
+        let path = std::env::temp_dir().join(format!(
+            "virtualization-rs-mac-bundle-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&path);
+        Scratch(path)
+    }
+
+    fn bundle(&self) -> PathBuf {
+        self.0.join("macOS.bundle")
+    }
+}
+
+impl Drop for Scratch {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Bundle contents the framework never sees, for the file format alone.
+fn synthetic(dir: &Path) -> MacVmBundle {
+    MacVmBundle {
+        hardware_model: (0..=255).collect(),
+        machine_identifier: b"machine identifier\n\0".to_vec(),
+        auxiliary_storage: dir.join("AuxiliaryStorage"),
+    }
+}
+
+fn assert_corrupted(dir: &Path) {
+    match MacVmBundle::load(dir) {
+        Err(BundleError::Corrupted(_)) => {}
+        other => panic!("expected Corrupted, got {:?}", other),
+    }
+}
+
+#[test]
+fn round_trips_and_moves() {
+    let scratch = Scratch::new("round-trip");
+    let dir = scratch.bundle();
+    let bundle = synthetic(&dir);
+    bundle.save(&dir).unwrap();
+    assert_eq!(MacVmBundle::load(&dir).unwrap(), bundle);
+
+    let moved = scratch.0.join("moved.bundle");
+    fs::rename(&dir, &moved).unwrap();
+    let loaded = MacVmBundle::load(&moved).unwrap();
+    assert_eq!(loaded.auxiliary_storage, moved.join("AuxiliaryStorage"));
+    assert_eq!(loaded.hardware_model, bundle.hardware_model);
+    assert_eq!(loaded.machine_identifier, bundle.machine_identifier);
+}
+
+#[test]
+fn missing_bundle_is_not_found() {
+    let scratch = Scratch::new("missing");
+    match MacVmBundle::load(&scratch.bundle()) {
+        Err(BundleError::Io(e)) => assert_eq!(e.kind(), io::ErrorKind::NotFound),
+        other => panic!("expected NotFound, got {:?}", other),
+    }
+}
+
+#[test]
+fn detects_corruption() {
+    let scratch = Scratch::new("corruption");
+    let dir = scratch.bundle();
+    let bundle = synthetic(&dir);
+
+    bundle.save(&dir).unwrap();
+    let model = dir.join("HardwareModel");
+    let mut bytes = fs::read(&model).unwrap();
+    bytes[7] ^= 1;
+    fs::write(&model, &bytes).unwrap();
+    assert_corrupted(&dir);
+
+    bundle.save(&dir).unwrap();
+    fs::remove_file(dir.join("MachineIdentifier")).unwrap();
+    assert_corrupted(&dir);
+
+    bundle.save(&dir).unwrap();
+    let manifest = dir.join("Manifest");
+    let contents = fs::read_to_string(&manifest).unwrap();
+    fs::write(&manifest, &contents[..contents.len() / 2]).unwrap();
+    assert_corrupted(&dir);
+
+    fs::write(&manifest, contents.replacen("version 1", "version 2", 1)).unwrap();
+    assert_corrupted(&dir);
+
+    bundle.save(&dir).unwrap();
+    assert_eq!(MacVmBundle::load(&dir).unwrap(), bundle);
+}
+
+#[test]
+fn real_hardware_model_round_trips() {
+    let ipsw = match std::env::var_os("VIRTUALIZATION_RS_TEST_IPSW") {
+        Some(path) => PathBuf::from(path),
+        None => {
+            println!("real_hardware_model_round_trips skipped: VIRTUALIZATION_RS_TEST_IPSW unset");
+            return;
+        }
+    };
+    let (tx, rx) = mpsc::channel();
+    if let Err(e) = VZMacOSRestoreImage::load_file(&ipsw, move |outcome| {
+        let _ = tx.send(outcome);
+    }) {
+        println!("real_hardware_model_round_trips skipped: {}", e);
+        return;
+    }
+    let image = match rx.recv().unwrap() {
+        CompletionOutcome::Success(image) => image,
+        CompletionOutcome::Cancelled => panic!("loading the restore image was cancelled"),
+        CompletionOutcome::Failed(e) => panic!("{}", e.ns_error()),
+    };
+    let requirements = image
+        .most_featureful_supported_configuration()
+        .expect("the restore image is not supported on this host");
+
+    let scratch = Scratch::new("real");
+    let dir = scratch.bundle();
+    let created = MacVmBundle::create(&dir, &requirements.hardware_model()).unwrap();
+    created.save(&dir).unwrap();
+    let loaded = MacVmBundle::load(&dir).unwrap();
+    assert_eq!(loaded, created);
+
+    let model = loaded.hardware_model().unwrap();
+    assert!(model.is_supported());
+    assert_eq!(
+        model.data_representation(),
+        requirements.hardware_model().data_representation()
+    );
+    assert_eq!(
+        loaded.machine_identifier().unwrap().data_representation(),
+        created.machine_identifier
+    );
+}
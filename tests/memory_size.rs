@@ -0,0 +1,116 @@
+//! Memory sizes: the builder takes multiples of 1 MiB as they are, rounds anything else down and
+//! reports it from `validated_build`, and `suggested_memory_size` lands inside the bounds the
+//! framework allows.
+
+#![cfg(target_os = "macos")]
+
+extern crate virtualization_rs;
+
+use virtualization_rs::virtualization::error::{AlignmentError, BuildError};
+use virtualization_rs::virtualization::virtual_machine::{
+    suggested_memory_size, VZVirtualMachineConfiguration, VZVirtualMachineConfigurationBuilder,
+    MEMORY_SIZE_GRANULARITY,
+};
+
+const MIB: usize = MEMORY_SIZE_GRANULARITY;
+
+fn min() -> usize {
+    VZVirtualMachineConfiguration::minimum_allowed_memory_size()
+}
+
+fn max() -> usize {
+    VZVirtualMachineConfiguration::maximum_allowed_memory_size()
+}
+
+#[test]
+fn aligned_sizes_pass_through_untouched() {
+    let conf = VZVirtualMachineConfigurationBuilder::new()
+        .memory_size(768 * MIB)
+        .validated_build()
+        .unwrap();
+    assert_eq!(conf.memory_size(), 768 * MIB);
+
+    let mib = VZVirtualMachineConfigurationBuilder::new().memory_size_mib(768);
+    assert_eq!(mib.validated_build().unwrap().memory_size(), 768 * MIB);
+    let gib = VZVirtualMachineConfigurationBuilder::new().memory_size_gib(2);
+    assert_eq!(gib.validated_build().unwrap().memory_size(), 2048 * MIB);
+}
+
+#[test]
+fn misaligned_sizes_round_down() {
+    let requested = 768 * MIB + 1;
+    let conf = VZVirtualMachineConfigurationBuilder::new()
+        .memory_size(requested)
+        .build();
+    assert_eq!(conf.memory_size(), 768 * MIB);
+
+    let error = VZVirtualMachineConfigurationBuilder::new()
+        .memory_size(requested)
+        .validated_build()
+        .err()
+        .expect("a misaligned size must fail validated_build");
+    assert_eq!(
+        error,
+        BuildError::Misaligned(AlignmentError {
+            requested,
+            below: 768 * MIB,
+            above: 769 * MIB,
+        })
+    );
+    assert_eq!(
+        error.to_string(),
+        "memory size 805306369 is not a multiple of 1 MiB; the nearest valid sizes are \
+         805306368 and 806354944"
+    );
+
+    // Only the last size counts.
+    let conf = VZVirtualMachineConfigurationBuilder::new()
+        .memory_size(requested)
+        .memory_size_mib(512)
+        .validated_build()
+        .unwrap();
+    assert_eq!(conf.memory_size(), 512 * MIB);
+}
+
+#[test]
+fn suggested_sizes_are_aligned_and_clamped_at_both_bounds() {
+    assert!(min() <= max());
+    assert_eq!(suggested_memory_size(0), min());
+    assert_eq!(suggested_memory_size(1), min());
+    assert_eq!(suggested_memory_size(usize::MAX), max());
+    assert_eq!(suggested_memory_size(max() + MIB), max());
+
+    let inside = 1024 * MIB;
+    if min() <= inside && inside + MIB <= max() {
+        assert_eq!(suggested_memory_size(inside), inside);
+        assert_eq!(suggested_memory_size(inside + MIB - 1), inside);
+    }
+}
+
+#[test]
+fn the_exact_maximum_passes_through() {
+    let conf = VZVirtualMachineConfigurationBuilder::new()
+        .memory_size(max())
+        .validated_build()
+        .unwrap();
+    assert_eq!(conf.memory_size(), max());
+    let conf = VZVirtualMachineConfigurationBuilder::new()
+        .memory_size(min())
+        .validated_build()
+        .unwrap();
+    assert_eq!(conf.memory_size(), min());
+}
+
+#[test]
+fn sizes_in_mib_and_gib_stay_aligned_when_they_overflow() {
+    let builders = [
+        VZVirtualMachineConfigurationBuilder::new().memory_size_mib(usize::MAX),
+        VZVirtualMachineConfigurationBuilder::new().memory_size_gib(usize::MAX / 1024 + 1),
+    ];
+    for builder in builders {
+        let conf = builder
+            .validated_build()
+            .expect("an overflowing size in MiB or GiB must stay aligned");
+        assert_eq!(conf.memory_size() % MIB, 0);
+    }
+}
@@ -0,0 +1,258 @@
+//! A scripted lifecycle must emit the documented series, with the documented label keys, to the
+//! process-wide recorder.
+
+#![cfg(target_os = "macos")]
+
+extern crate virtualization_rs;
+
+use virtualization_rs::metrics::{self, Metrics, NoopMetrics, RecorderAlreadySet};
+use virtualization_rs::virtualization::boot_loader::VZLinuxBootLoaderBuilder;
+use virtualization_rs::virtualization::error::CompletionOutcome;
+use virtualization_rs::virtualization::virtual_machine::{
+    VZVirtualMachine, VZVirtualMachineConfiguration, VZVirtualMachineConfigurationBuilder,
+    VZVirtualMachineState,
+};
+
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::mpsc;
+use std::sync::{Mutex, Once};
+use std::time::Duration;
+
+#[derive(Debug, Clone, PartialEq)]
+enum Sample {
+    Counter(&'static str, Vec<(String, String)>),
+    Histogram(&'static str, f64, Vec<(String, String)>),
+}
+
+static SAMPLES: Mutex<Vec<Sample>> = Mutex::new(Vec::new());
+static INSTALL: Once = Once::new();
+
+struct TestRecorder;
+
+fn owned(labels: &[(&'static str, &str)]) -> Vec<(String, String)> {
+    labels
+        .iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect()
+}
+
+impl Metrics for TestRecorder {
+    fn counter(&self, name: &'static str, labels: &[(&'static str, &str)]) {
+        SAMPLES
+            .lock()
+            .unwrap()
+            .push(Sample::Counter(name, owned(labels)));
+    }
+
+    fn histogram_observe(&self, name: &'static str, value: f64, labels: &[(&'static str, &str)]) {
+        SAMPLES
+            .lock()
+            .unwrap()
+            .push(Sample::Histogram(name, value, owned(labels)));
+    }
+}
+
+fn install() {
+    INSTALL.call_once(|| metrics::set_metrics_recorder(Box::new(TestRecorder)).unwrap());
+}
+
+/// Takes the samples recorded so far.
+fn drain() -> Vec<Sample> {
+    std::mem::take(&mut *SAMPLES.lock().unwrap())
+}
+
+fn labels(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+    pairs
+        .iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect()
+}
+
+fn outcome_label(outcome: &CompletionOutcome) -> &'static str {
+    match outcome {
+        CompletionOutcome::Success(()) => "success",
+        CompletionOutcome::Cancelled => "cancelled",
+        CompletionOutcome::Failed(_) => "failed",
+    }
+}
+
+fn counted(samples: &[Sample], name: &str, pairs: &[(&str, &str)]) -> usize {
+    let expected = labels(pairs);
+    samples
+        .iter()
+        .filter(|s| matches!(s, Sample::Counter(n, l) if *n == name && *l == expected))
+        .count()
+}
+
+fn observed(samples: &[Sample], name: &str, pairs: &[(&str, &str)]) -> Vec<f64> {
+    let expected = labels(pairs);
+    samples
+        .iter()
+        .filter_map(|s| match s {
+            Sample::Histogram(n, v, l) if *n == name && *l == expected => Some(*v),
+            _ => None,
+        })
+        .collect()
+}
+
+/// A scratch directory with kernel and initrd files that validate but do not boot.
+struct Scratch(PathBuf);
+
+impl Scratch {
+    fn new() -> Scratch {
+        let path =
+            std::env::temp_dir().join(format!("virtualization-rs-metrics-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).unwrap();
+        for name in &["vmlinuz", "initrd"] {
+            File::create(path.join(name))
+                .unwrap()
+                .write_all(&[0x5a; 4096])
+                .unwrap();
+        }
+        Scratch(path)
+    }
+
+    fn config(&self) -> VZVirtualMachineConfiguration {
+        config(
+            self.0.join("vmlinuz").to_str().unwrap(),
+            self.0.join("initrd").to_str().unwrap(),
+        )
+    }
+}
+
+impl Drop for Scratch {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+fn config(kernel: &str, initrd: &str) -> VZVirtualMachineConfiguration {
+    let boot_loader = VZLinuxBootLoaderBuilder::new()
+        .kernel_url(kernel)
+        .initial_ramdisk_url(initrd)
+        .command_line("console=hvc0")
+        .build()
+        .unwrap();
+    VZVirtualMachineConfigurationBuilder::new()
+        .boot_loader(boot_loader)
+        .cpu_count(1)
+        .memory_size(512 * 1024 * 1024)
+        .build()
+}
+
+/// Runs `op` with a completion sender and waits for the outcome.
+fn complete<F>(op: F) -> CompletionOutcome
+where
+    F: FnOnce(mpsc::Sender<CompletionOutcome>),
+{
+    let (tx, rx) = mpsc::channel();
+    op(tx);
+    rx.recv_timeout(Duration::from_secs(30))
+        .expect("no completion")
+}
+
+#[test]
+fn scripted_lifecycle_emits_documented_series() {
+    install();
+    let scratch = Scratch::new();
+
+    let missing = config(
+        "/nonexistent-virtualization-rs/vmlinuz",
+        "/nonexistent-virtualization-rs/initrd",
+    );
+    assert!(missing.validate_with_error().is_err());
+    let conf = scratch.config();
+    assert!(conf.validate_with_error().unwrap());
+
+    let vm = VZVirtualMachine::new_with_qos(conf, "metrics-test", None);
+    let id = vm.vm_id().get().to_string();
+    let id = id.as_str();
+    let started = complete(|tx| {
+        vm.start(move |outcome| tx.send(outcome).unwrap()).unwrap();
+    });
+    let stopped = complete(|tx| {
+        vm.stop(move |outcome| tx.send(outcome).unwrap()).unwrap();
+    });
+    let paused = complete(|tx| vm.pause(move |outcome| tx.send(outcome).unwrap()));
+    // Let the last state changes reach the observer.
+    vm.queue().exec_sync(|| ());
+
+    let samples = drain();
+    let validations = metrics::CONFIG_VALIDATIONS;
+    assert_eq!(
+        counted(
+            &samples,
+            validations,
+            &[("outcome", "invalid"), ("code", "2")]
+        ),
+        1,
+        "{:#?}",
+        samples
+    );
+    assert_eq!(
+        counted(
+            &samples,
+            validations,
+            &[("outcome", "valid"), ("code", "none")]
+        ),
+        1,
+        "{:#?}",
+        samples
+    );
+
+    for (operation, outcome) in &[("start", &started), ("stop", &stopped), ("pause", &paused)] {
+        let pairs = [
+            ("vm", id),
+            ("operation", *operation),
+            ("outcome", outcome_label(outcome)),
+        ];
+        assert_eq!(
+            counted(&samples, metrics::VM_OPERATIONS, &pairs),
+            1,
+            "{}: {:#?}",
+            operation,
+            samples
+        );
+        let durations = observed(&samples, metrics::VM_OPERATION_DURATION, &pairs);
+        assert_eq!(durations.len(), 1, "{}: {:#?}", operation, samples);
+        assert!(durations[0] >= 0.0 && durations[0] < 30.0);
+    }
+    assert_eq!(
+        counted(&samples, metrics::VM_FORCED_STOPS, &[("vm", id)]),
+        1
+    );
+
+    let mut reached_running = false;
+    for sample in &samples {
+        if let Sample::Counter(name, labels) = sample {
+            if *name != metrics::VM_STATE_TRANSITIONS {
+                continue;
+            }
+            let keys: Vec<&str> = labels.iter().map(|(k, _)| k.as_str()).collect();
+            assert_eq!(keys, ["vm", "from", "to"]);
+            assert_eq!(labels[0].1, id);
+            let from: VZVirtualMachineState = labels[1].1.parse().unwrap();
+            let to: VZVirtualMachineState = labels[2].1.parse().unwrap();
+            assert_ne!(from, to);
+            reached_running |= to == VZVirtualMachineState::VZVirtualMachineStateRunning;
+        }
+    }
+    assert_eq!(
+        observed(&samples, metrics::VM_START_TO_RUNNING, &[("vm", id)]).len(),
+        reached_running as usize,
+        "{:#?}",
+        samples
+    );
+}
+
+#[test]
+fn recorder_is_set_once() {
+    install();
+    assert_eq!(
+        metrics::set_metrics_recorder(Box::new(NoopMetrics)),
+        Err(RecorderAlreadySet)
+    );
+}
@@ -0,0 +1,360 @@
+//! Parsing of bootpd's lease file, against a fixture with the quirks of real ones, and polling it
+//! for a guest's lease. The parsers of the host diagnostics against captured `netstat`,
+//! `launchctl` and vmnet output, and the diagnostics on this host.
+
+#![cfg(target_os = "macos")]
+
+extern crate virtualization_rs;
+
+use virtualization_rs::diagnostics::{EntitlementCheck, Severity};
+use virtualization_rs::nat::{
+    self, disabled_services, entitlement_findings, latest_lease, lease_file_finding, leases_from,
+    normalize_mac, overlapping_routes, parse_leases, parse_routes, shared_subnet, wait_for_lease,
+    Ipv4Subnet, Lease, LeaseError, NatFinding, DEFAULT_SHARED_SUBNET,
+};
+
+use std::fs;
+use std::net::Ipv4Addr;
+use std::path::PathBuf;
+use std::thread;
+use std::time::{Duration, Instant};
+
+const LEASES: &str = include_str!("data/dhcpd_leases");
+/// macOS 14 with a VPN on `utun3` routing half of vmnet's shared network.
+const NETSTAT: &str = include_str!("data/netstat_rn.txt");
+/// macOS 12, with `Refs` and `Use` columns.
+const NETSTAT_LEGACY: &str = include_str!("data/netstat_rn_legacy.txt");
+const LAUNCHCTL: &str = include_str!("data/launchctl_print_disabled.txt");
+const LAUNCHCTL_LEGACY: &str = include_str!("data/launchctl_print_disabled_legacy.txt");
+/// vmnet moved to `10.211.64.0/22`.
+const VMNET_PLIST: &str = include_str!("data/com.apple.vmnet.plist");
+
+fn scratch(name: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!(
+        "virtualization-rs-nat-{}-{}",
+        name,
+        std::process::id()
+    ));
+    let _ = fs::remove_file(&path);
+    path
+}
+
+#[test]
+fn parses_complete_ethernet_ipv4_entries() {
+    let leases = parse_leases(LEASES);
+    let ips: Vec<Ipv4Addr> = leases.iter().map(|lease| lease.ip_address).collect();
+    // Skipped: no address, no hardware address, token ring, IPv6, and the entry cut short.
+    assert_eq!(
+        ips,
+        [
+            Ipv4Addr::new(192, 168, 64, 3),
+            Ipv4Addr::new(192, 168, 64, 2),
+            Ipv4Addr::new(192, 168, 64, 9),
+            Ipv4Addr::new(192, 168, 64, 4),
+            Ipv4Addr::new(192, 168, 64, 7),
+        ]
+    );
+    assert_eq!(
+        leases[0],
+        Lease {
+            name: Some("ubuntu".to_string()),
+            ip_address: Ipv4Addr::new(192, 168, 64, 3),
+            hw_address: "02:e1:f3:0a:4b:0c".to_string(),
+            expires: Some(0x65f1c2a0),
+        }
+    );
+}
+
+#[test]
+fn quirky_fields() {
+    let leases = parse_leases(LEASES);
+    // Upper case digits are normalized.
+    assert_eq!(leases[1].hw_address, "02:e1:f3:0a:4b:0d");
+    // An empty name is no name.
+    assert_eq!(leases[3].name, None);
+    // Without hw_address the identifier names the hardware, and a value may contain '='.
+    assert_eq!(leases[4].hw_address, "a6:00:00:00:00:05");
+    assert_eq!(leases[4].expires, None);
+}
+
+#[test]
+fn most_recent_lease_wins() {
+    let leases = parse_leases(LEASES);
+    for mac in &["02:e1:f3:0a:4b:0c", "2:E1:F3:A:4B:C"] {
+        let lease = latest_lease(&leases, mac).unwrap();
+        assert_eq!(lease.ip_address, Ipv4Addr::new(192, 168, 64, 3));
+    }
+    assert!(latest_lease(&leases, "a6:00:00:00:00:06").is_none());
+    assert!(latest_lease(&leases, "not a mac").is_none());
+
+    // Without expiry times, the first entry in the file is the most recent.
+    let undated = "{\nip_address=10.0.0.1\nhw_address=1,a:b:c:d:e:f\n}\n\
+                   {\nip_address=10.0.0.2\nhw_address=1,a:b:c:d:e:f\n}\n";
+    let leases = parse_leases(undated);
+    let lease = latest_lease(&leases, "0a:0b:0c:0d:0e:0f").unwrap();
+    assert_eq!(lease.ip_address, Ipv4Addr::new(10, 0, 0, 1));
+}
+
+#[test]
+fn normalizes_mac_addresses() {
+    assert_eq!(
+        normalize_mac("2:E1:f3:A:4b:c").as_deref(),
+        Some("02:e1:f3:0a:4b:0c")
+    );
+    assert_eq!(normalize_mac("02:e1:f3:0a:4b"), None);
+    assert_eq!(normalize_mac("02:e1:f3:0a:4b:0c:00"), None);
+    assert_eq!(normalize_mac("002:e1:f3:0a:4b:0c"), None);
+    assert_eq!(normalize_mac("02:e1::0a:4b:0c"), None);
+    assert_eq!(normalize_mac("+2:e1:f3:0a:4b:0c"), None);
+}
+
+#[test]
+fn missing_file_has_no_leases() {
+    assert!(leases_from(scratch("missing")).unwrap().is_empty());
+}
+
+#[test]
+fn waits_for_the_lease_to_appear() {
+    let path = scratch("appears");
+    let writer = {
+        let path = path.clone();
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(700));
+            fs::write(&path, LEASES).unwrap();
+        })
+    };
+    let lease = wait_for_lease(&path, "2:e1:f3:a:4b:d", Duration::from_secs(10)).unwrap();
+    writer.join().unwrap();
+    let _ = fs::remove_file(&path);
+    assert_eq!(lease.ip_address, Ipv4Addr::new(192, 168, 64, 2));
+    assert_eq!(lease.name.as_deref(), Some("debian"));
+}
+
+#[test]
+fn times_out_without_a_lease() {
+    let path = scratch("timeout");
+    fs::write(&path, LEASES).unwrap();
+    let started = Instant::now();
+    let error = wait_for_lease(&path, "a6:00:00:00:00:06", Duration::from_millis(600)).unwrap_err();
+    let _ = fs::remove_file(&path);
+    assert!(started.elapsed() >= Duration::from_millis(600));
+    match error {
+        LeaseError::Timeout { mac } => assert_eq!(mac, "a6:00:00:00:00:06"),
+        e => panic!("{}", e),
+    }
+}
+
+#[test]
+fn rejects_invalid_mac_addresses() {
+    match wait_for_lease(scratch("invalid"), "02:e1", Duration::from_secs(1)) {
+        Err(LeaseError::InvalidMac(mac)) => assert_eq!(mac, "02:e1"),
+        other => panic!("{:?}", other),
+    }
+}
+
+fn subnet(a: u8, b: u8, c: u8, d: u8, prefix: u8) -> Ipv4Subnet {
+    Ipv4Subnet::new(Ipv4Addr::new(a, b, c, d), prefix).unwrap()
+}
+
+#[test]
+fn subnets() {
+    assert_eq!(subnet(192, 168, 64, 7, 24), DEFAULT_SHARED_SUBNET);
+    assert_eq!(DEFAULT_SHARED_SUBNET.to_string(), "192.168.64.0/24");
+    assert!(Ipv4Subnet::new(Ipv4Addr::LOCALHOST, 33).is_none());
+    assert_eq!(
+        Ipv4Subnet::with_mask(
+            Ipv4Addr::new(10, 211, 64, 1),
+            Ipv4Addr::new(255, 255, 252, 0)
+        ),
+        Some(subnet(10, 211, 64, 0, 22))
+    );
+    assert!(
+        Ipv4Subnet::with_mask(Ipv4Addr::new(10, 0, 0, 1), Ipv4Addr::new(255, 0, 255, 0)).is_none()
+    );
+
+    assert!(DEFAULT_SHARED_SUBNET.contains(Ipv4Addr::new(192, 168, 64, 255)));
+    assert!(!DEFAULT_SHARED_SUBNET.contains(Ipv4Addr::new(192, 168, 65, 0)));
+    // Either contains the other.
+    assert!(DEFAULT_SHARED_SUBNET.overlaps(&subnet(192, 168, 0, 0, 16)));
+    assert!(DEFAULT_SHARED_SUBNET.overlaps(&subnet(192, 168, 64, 128, 25)));
+    assert!(!DEFAULT_SHARED_SUBNET.overlaps(&subnet(192, 168, 65, 0, 24)));
+    assert!(DEFAULT_SHARED_SUBNET.overlaps(&subnet(0, 0, 0, 0, 0)));
+}
+
+#[test]
+fn parses_routes_of_both_netstat_formats() {
+    let routes = parse_routes(NETSTAT);
+    // The `Internet6:` section is skipped.
+    assert_eq!(routes.len(), 18);
+    assert_eq!(routes[0].destination, subnet(0, 0, 0, 0, 0));
+    assert_eq!(routes[0].gateway, "192.168.1.1");
+    assert_eq!(routes[0].interface, "en0");
+    // Trailing zero octets are dropped along with the mask they imply.
+    assert_eq!(routes[2].destination, subnet(10, 0, 0, 0, 8));
+    assert_eq!(routes[3].destination, subnet(127, 0, 0, 0, 8));
+    assert_eq!(routes[5].destination, subnet(169, 254, 0, 0, 16));
+    assert_eq!(routes[6].destination, subnet(172, 16, 40, 0, 22));
+    // Host routes, with or without a prefix.
+    assert_eq!(routes[4].destination, subnet(127, 0, 0, 1, 32));
+    assert_eq!(routes[9].destination, subnet(192, 168, 1, 1, 32));
+    assert_eq!(routes[9].flags, "UHLWIir");
+    assert_eq!(routes[11].interface, "bridge100");
+
+    let legacy = parse_routes(NETSTAT_LEGACY);
+    let destinations: Vec<String> = legacy
+        .iter()
+        .map(|route| format!("{} {}", route.destination, route.interface))
+        .collect();
+    // Destinations that are not addresses are skipped.
+    assert_eq!(
+        destinations,
+        [
+            "0.0.0.0/0 en0",
+            "10.0.0.0/8 en0",
+            "10.0.0.1/32 en0",
+            "127.0.0.0/8 lo0",
+            "127.0.0.1/32 lo0",
+            "192.168.0.0/16 utun2",
+        ]
+    );
+    // Without a header there is no telling the interface.
+    assert!(parse_routes("default 10.0.0.1 UGSc en0").is_empty());
+}
+
+#[test]
+fn finds_routes_overlapping_the_shared_network() {
+    let routes = parse_routes(NETSTAT);
+    // Not the default routes, vmnet's bridge, the cloned hosts on it or multicast.
+    let overlapping = overlapping_routes(&DEFAULT_SHARED_SUBNET, &routes);
+    assert_eq!(overlapping.len(), 1);
+    assert_eq!(overlapping[0].destination, subnet(192, 168, 64, 128, 25));
+    assert_eq!(overlapping[0].interface, "utun3");
+
+    let moved = shared_subnet(VMNET_PLIST).unwrap();
+    let overlapping = overlapping_routes(&moved, &routes);
+    assert_eq!(overlapping.len(), 1);
+    assert_eq!(overlapping[0].destination, subnet(10, 0, 0, 0, 8));
+
+    let legacy = parse_routes(NETSTAT_LEGACY);
+    let overlapping = overlapping_routes(&DEFAULT_SHARED_SUBNET, &legacy);
+    assert_eq!(overlapping.len(), 1);
+    assert_eq!(overlapping[0].interface, "utun2");
+    assert!(overlapping_routes(&subnet(172, 31, 0, 0, 24), &legacy).is_empty());
+}
+
+#[test]
+fn reads_the_disabled_services() {
+    assert_eq!(
+        disabled_services(LAUNCHCTL),
+        [
+            "com.apple.ftpd",
+            "com.apple.mdmclient.daemon.runatboot",
+            "com.apple.bootpd",
+            "com.openssh.sshd",
+        ]
+    );
+    assert_eq!(
+        disabled_services(LAUNCHCTL_LEGACY),
+        [
+            "com.apple.ftpd",
+            "com.apple.InternetSharing",
+            "com.openssh.sshd"
+        ]
+    );
+}
+
+#[test]
+fn reads_the_shared_network_of_vmnet() {
+    assert_eq!(shared_subnet(VMNET_PLIST), Some(subnet(10, 211, 64, 0, 22)));
+    // Without a mask, a /24.
+    let address_only = "<dict><key>Shared_Net_Address</key> <string>172.30.1.1</string></dict>";
+    assert_eq!(shared_subnet(address_only), Some(subnet(172, 30, 1, 0, 24)));
+    assert_eq!(shared_subnet("<dict></dict>"), None);
+    let bad_mask = VMNET_PLIST.replace("255.255.252.0", "255.0.255.0");
+    assert_eq!(shared_subnet(&bad_mask), None);
+}
+
+#[test]
+fn lease_file_findings() {
+    assert_eq!(
+        lease_file_finding(scratch("no-lease-file")),
+        Some(NatFinding::NoLeaseFile)
+    );
+    let path = scratch("lease-file");
+    fs::write(&path, LEASES).unwrap();
+    let finding = lease_file_finding(&path);
+    let _ = fs::remove_file(&path);
+    assert_eq!(finding, None);
+}
+
+#[test]
+fn entitlement_findings_need_only_virtualization() {
+    let checks = |virtualization, networking| {
+        Ok(vec![
+            EntitlementCheck {
+                name: "com.apple.security.virtualization",
+                granted: virtualization,
+            },
+            EntitlementCheck {
+                name: "com.apple.vm.networking",
+                granted: networking,
+            },
+        ])
+    };
+    assert!(entitlement_findings(checks(true, false)).is_empty());
+    assert_eq!(
+        entitlement_findings(checks(false, true)),
+        [NatFinding::MissingEntitlement {
+            name: "com.apple.security.virtualization"
+        }]
+    );
+    match &entitlement_findings(Err("codesign exited with 1".to_string()))[..] {
+        [finding @ NatFinding::ProbeFailed { .. }] => {
+            assert_eq!(finding.severity(), Severity::Note)
+        }
+        other => panic!("{:?}", other),
+    }
+}
+
+#[test]
+fn findings_say_what_to_do() {
+    let route = parse_routes(NETSTAT)
+        .into_iter()
+        .find(|route| route.interface == "utun3" && route.destination.prefix == 25)
+        .unwrap();
+    let overlap = NatFinding::SubnetOverlap {
+        shared: DEFAULT_SHARED_SUBNET,
+        route,
+    };
+    assert_eq!(overlap.severity(), Severity::Error);
+    assert_eq!(
+        overlap.to_string(),
+        "vmnet's shared network 192.168.64.0/24 overlaps 192.168.64.128/25, routed through utun3 \
+         via link#19"
+    );
+    assert!(overlap.remediation().contains("Shared_Net_Address"));
+    assert!(overlap.remediation().contains("192.168.64.128/25"));
+
+    let disabled = NatFinding::ServiceDisabled {
+        service: "com.apple.bootpd",
+    };
+    assert_eq!(disabled.severity(), Severity::Error);
+    assert!(disabled
+        .remediation()
+        .contains("sudo launchctl enable system/com.apple.bootpd"));
+    assert_eq!(NatFinding::NoLeaseFile.severity(), Severity::Note);
+}
+
+#[test]
+fn diagnoses_this_host() {
+    let started = Instant::now();
+    let findings = nat::diagnose();
+    println!("{:?} in {:?}", findings, started.elapsed());
+    for finding in &findings {
+        assert!(!finding.to_string().is_empty());
+        assert!(!finding.remediation().is_empty());
+    }
+    assert!(findings
+        .windows(2)
+        .all(|pair| pair[0].severity() >= pair[1].severity()));
+}
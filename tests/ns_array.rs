@@ -0,0 +1,177 @@
+//! Arrays of strings and file URLs built from Rust values: they round-trip unicode and spaces,
+//! hold on to their elements after the caller's pools drain, and refuse paths that cannot be URLs.
+//! Empty arrays are one shared object, which survives being handed out and released repeatedly.
+//! Access out of bounds, or through a wrapper around nil, gives `None` instead of raising.
+
+#![cfg(target_os = "macos")]
+
+extern crate virtualization_rs;
+
+use virtualization_rs::base::{NSArray, NSString, NSURL};
+use virtualization_rs::runtime::autoreleasepool;
+use virtualization_rs::test_support::{self, TempDir};
+use virtualization_rs::virtualization::network_device::VZVirtioNetworkDeviceConfiguration;
+use virtualization_rs::virtualization::socket_device::VZVirtioSocketDeviceConfiguration;
+use virtualization_rs::virtualization::storage_device::VZVirtioBlockDeviceConfiguration;
+
+use objc::rc::StrongPtr;
+use objc::{msg_send, sel, sel_impl};
+
+use std::ffi::OsStr;
+use std::fs;
+use std::marker::PhantomData;
+use std::os::unix::ffi::OsStrExt;
+use std::panic;
+use std::path::{Path, PathBuf};
+use std::ptr;
+
+const STRINGS: &[&str] = &["eth0", "", "Wi‑Fi (en0)", "日本語", "🦀 crab"];
+
+#[test]
+fn strings_round_trip() {
+    let array = NSArray::<NSString>::from_strings(STRINGS);
+    assert_eq!(array.count(), STRINGS.len());
+    assert_eq!(array.to_strings(), STRINGS);
+    let empty = NSArray::<NSString>::from_strings(&[]);
+    assert!(empty.to_strings().is_empty());
+}
+
+fn as_str(string: Option<NSString>) -> Option<String> {
+    string.map(|string| string.as_str().to_string())
+}
+
+#[test]
+fn access_out_of_bounds_is_none() {
+    let array = NSArray::<NSString>::from_strings(STRINGS);
+    assert_eq!(as_str(array.get(3)).as_deref(), Some(STRINGS[3]));
+    assert!(array.get(STRINGS.len()).is_none());
+    assert!(array.get(usize::MAX).is_none());
+    assert_eq!(as_str(array.try_first()).as_deref(), Some(STRINGS[0]));
+    assert_eq!(
+        as_str(array.try_last()).as_deref(),
+        Some(STRINGS[STRINGS.len() - 1])
+    );
+
+    let empty = NSArray::<NSString>::empty();
+    assert!(empty.get(0).is_none());
+    assert!(empty.try_first().is_none());
+    assert!(empty.try_last().is_none());
+}
+
+#[test]
+#[allow(deprecated)]
+fn object_at_index_panics_out_of_bounds() {
+    let array = NSArray::<NSString>::from_strings(&["only"]);
+    assert_eq!(array.object_at_index(0).as_str(), "only");
+    let panic = panic::catch_unwind(|| array.object_at_index(1).as_str().to_string()).unwrap_err();
+    assert_eq!(
+        panic.downcast_ref::<String>().map(String::as_str),
+        Some("NSArray index 1 out of bounds (count 1)")
+    );
+}
+
+#[test]
+fn a_wrapper_around_nil_is_empty() {
+    let nil = NSArray::<NSString> {
+        p: unsafe { StrongPtr::new(ptr::null_mut()) },
+        _phantom: PhantomData,
+    };
+    assert_eq!(nil.count(), 0);
+    assert!(nil.get(0).is_none());
+    assert!(nil.try_first().is_none());
+    assert!(nil.try_last().is_none());
+    assert!(nil.to_strings().is_empty());
+}
+
+#[test]
+fn file_paths_round_trip() {
+    let dir = std::env::temp_dir().join(format!(
+        "virtualization-rs-array {} ünïcode",
+        std::process::id()
+    ));
+    fs::create_dir_all(&dir).unwrap();
+    let file = dir.join("disk image.img");
+    fs::write(&file, b"").unwrap();
+    let paths: Vec<&Path> = vec![&dir, &file];
+
+    let array = NSArray::<NSURL>::from_file_paths(&paths).unwrap();
+    let round_trip: Vec<PathBuf> = (0..array.count())
+        .map(|i| PathBuf::from(array.get(i).unwrap().path().as_str()))
+        .collect();
+    assert_eq!(round_trip, paths);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn invalid_file_paths_are_named() {
+    let error = NSArray::<NSURL>::from_file_paths(&[Path::new("/tmp"), Path::new("")])
+        .err()
+        .unwrap();
+    assert_eq!((error.kind, error.value.as_str()), ("file path", ""));
+
+    let non_utf8 = Path::new(OsStr::from_bytes(b"/tmp/\xff"));
+    assert!(NSArray::<NSURL>::from_file_paths(&[non_utf8]).is_err());
+}
+
+#[test]
+fn elements_outlive_the_pool() {
+    let array = autoreleasepool(|| {
+        let array = NSArray::<NSString>::from_strings(STRINGS);
+        // Anything else created here is autoreleased and goes with the pool.
+        drop(NSArray::<NSString>::from_strings(&["scratch"]));
+        array
+    });
+    for _ in 0..100 {
+        autoreleasepool(|| assert_eq!(array.to_strings(), STRINGS));
+    }
+    drop(array);
+}
+
+#[test]
+fn empty_arrays_share_one_object() {
+    let first = NSArray::<NSString>::from_slice(&[]);
+    let second = autoreleasepool(|| NSArray::<NSString>::array_with_objects(Vec::new()));
+    assert_eq!(*first.p, *second.p);
+    assert_eq!(second.count(), 0);
+    // Released as often as it was handed out, and still alive for the next caller.
+    drop(first);
+    drop(second);
+    for _ in 0..10_000 {
+        autoreleasepool(|| drop(NSArray::<NSString>::from_slice(&[])));
+    }
+    assert_eq!(NSArray::<NSString>::empty().count(), 0);
+}
+
+#[test]
+fn arrays_release_their_elements() {
+    let element = NSString::new("element");
+    let retain_count = || -> usize { unsafe { msg_send![*element.0, retainCount] } };
+    let before = retain_count();
+    for _ in 0..10_000 {
+        autoreleasepool(|| {
+            let array = NSArray::<NSString>::from_slice(&[*element.0, *element.0]);
+            assert_eq!(array.count(), 2);
+        });
+    }
+    assert_eq!(retain_count(), before);
+}
+
+#[test]
+fn repeated_configurations_with_empty_categories() {
+    let dir = TempDir::new("ns-array-repeated");
+    for i in 0..1_000 {
+        let conf = autoreleasepool(|| {
+            test_support::minimal_builder()
+                .boot_loader(test_support::garbage_linux_boot_loader(&dir))
+                .storage_devices(Vec::<VZVirtioBlockDeviceConfiguration>::new())
+                .network_devices(Vec::<VZVirtioNetworkDeviceConfiguration>::new())
+                .socket_devices(Vec::<VZVirtioSocketDeviceConfiguration>::new())
+                .build()
+        });
+        if i % 100 == 0 {
+            assert!(conf
+                .validate_with_error()
+                .unwrap_or_else(|e| panic!("{}", e)));
+        }
+    }
+}
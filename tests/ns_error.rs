@@ -0,0 +1,83 @@
+//! The framework's `VZErrorDomain` constant comes back as the same object however often it is
+//! read and released, and errors built with `NSUnderlyingErrorKey` are walked down their chain
+//! to the very objects they were built from.
+
+#![cfg(target_os = "macos")]
+
+extern crate libc;
+extern crate objc;
+extern crate virtualization_rs;
+
+use virtualization_rs::base::{NSDictionary, NSError, NSString};
+use virtualization_rs::runtime::autoreleasepool;
+use virtualization_rs::virtualization::error::{vz_error_domain, VZError, VZ_ERROR_DOMAIN};
+
+use objc::rc::StrongPtr;
+use objc::{class, msg_send, sel, sel_impl};
+
+/// A `userInfo` holding `underlying` under `NSUnderlyingErrorKey`.
+fn caused_by(underlying: &NSError) -> NSDictionary {
+    let key = NSString::new("NSUnderlyingError");
+    unsafe {
+        NSDictionary(StrongPtr::retain(msg_send![
+            class!(NSDictionary),
+            dictionaryWithObject:*underlying.0
+            forKey:*key.0
+        ]))
+    }
+}
+
+#[test]
+fn the_domain_constant_is_one_object() {
+    let first = vz_error_domain();
+    let second = vz_error_domain();
+    assert_eq!(*first.0, *second.0);
+    assert_eq!(first.as_str(), VZ_ERROR_DOMAIN);
+    let domain = *first.0;
+    drop(first);
+    drop(second);
+    // Released as often as it was read, and never by the framework's own reference.
+    for _ in 0..10_000 {
+        autoreleasepool(|| drop(vz_error_domain()));
+    }
+    let again = vz_error_domain();
+    assert_eq!(*again.0, domain);
+    assert_eq!(again.as_str(), VZ_ERROR_DOMAIN);
+}
+
+#[test]
+fn errors_of_the_domain_are_vz_errors() {
+    // An equal string, not the constant itself.
+    let error = NSError::error_with_domain(VZ_ERROR_DOMAIN, 1, None);
+    assert!(error.is_vz_error());
+    assert!(VZError(error).is_vz_error());
+    assert!(!NSError::posix(libc::EIO).is_vz_error());
+}
+
+#[test]
+fn an_underlying_error_chain_is_walked_two_levels_deep() {
+    let root = NSError::error_with_domain("RootDomain", 1, None);
+    let middle = NSError::error_with_domain(VZ_ERROR_DOMAIN, 2, Some(&caused_by(&root)));
+    let top = NSError::error_with_domain("TopDomain", 3, Some(&caused_by(&middle)));
+
+    let first = top.underlying_error().unwrap();
+    assert_eq!(*first.0, *middle.0);
+    assert_eq!(first.code(), 2);
+    assert!(first.is_vz_error());
+    assert_eq!(top.user_info_value("NSUnderlyingError"), Some(*middle.0));
+
+    let second = first.underlying_error().unwrap();
+    assert_eq!(*second.0, *root.0);
+    assert_eq!(second.domain().as_str(), "RootDomain");
+    assert!(second.underlying_error().is_none());
+    assert!(second.user_info_value("NSUnderlyingError").is_none());
+
+    // The chain holds on to its errors after the caller's wrappers are gone.
+    drop((root, middle, first, second));
+    let second = top.underlying_error().unwrap().underlying_error().unwrap();
+    assert_eq!(second.code(), 1);
+    assert_eq!(
+        VZError(top).underlying_error().map(|error| error.code()),
+        Some(2)
+    );
+}
@@ -0,0 +1,318 @@
+//! Each built-in guest profile against a table of inputs: what it refuses, what it composes from
+//! the rest, and that the composition validates.
+
+#![cfg(target_os = "macos")]
+
+extern crate virtualization_rs;
+
+use virtualization_rs::features::HostCapabilities;
+use virtualization_rs::profile::{
+    self, DeviceSelection, GuestProfile, LinuxDirectKernel, LinuxEfiCloudImage, ProfileError,
+    ProfileInputs, ProfileKind,
+};
+use virtualization_rs::test_support::TempDir;
+use virtualization_rs::virtualization::virtual_machine::VZVirtualMachineConfiguration;
+
+use std::path::{Path, PathBuf};
+
+/// Files every case can point at.
+struct Files {
+    dir: TempDir,
+    kernel: PathBuf,
+    initrd: PathBuf,
+    disk: PathBuf,
+    seed: PathBuf,
+    missing: PathBuf,
+}
+
+impl Files {
+    fn new(name: &str) -> Files {
+        let dir = TempDir::new(name);
+        Files {
+            kernel: dir.garbage("vmlinuz"),
+            initrd: dir.garbage("initrd"),
+            disk: dir.disk_image("disk.img", 64 * 1024 * 1024),
+            seed: dir.disk_image("seed.iso", 1024 * 1024),
+            missing: dir.path().join("missing"),
+            dir,
+        }
+    }
+
+    fn efi_variable_store(&self) -> PathBuf {
+        self.dir.path().join("efi_vars.fd")
+    }
+}
+
+fn missing(profile: &'static str, input: &'static str) -> ProfileError {
+    ProfileError::Missing { profile, input }
+}
+
+fn forbidden(profile: &'static str, input: &'static str) -> ProfileError {
+    ProfileError::Forbidden { profile, input }
+}
+
+fn not_a_file(profile: &'static str, input: &'static str, path: &Path) -> ProfileError {
+    ProfileError::NotAFile {
+        profile,
+        input,
+        path: path.to_path_buf(),
+    }
+}
+
+/// Runs `cases` of (description, inputs, expected errors) against `profile`. Inputs without
+/// errors must also compose into a configuration that validates.
+fn check_table(profile: &dyn GuestProfile, cases: Vec<(&str, ProfileInputs, Vec<ProfileError>)>) {
+    for (description, inputs, expected) in cases {
+        assert_eq!(
+            profile.validate_inputs(&inputs),
+            expected,
+            "{}: {}",
+            profile.name(),
+            description
+        );
+        match profile.boot_loader(&inputs) {
+            Ok(_) => assert!(expected.is_empty(), "{}: {}", profile.name(), description),
+            Err(error) => assert_eq!(Some(&error), expected.first(), "{}", description),
+        }
+        if expected.is_empty() {
+            assert_validates(profile, &inputs, description);
+        }
+    }
+}
+
+fn assert_validates(profile: &dyn GuestProfile, inputs: &ProfileInputs, description: &str) {
+    let conf = profile::configure(profile, inputs)
+        .unwrap_or_else(|errors| panic!("{}: {:?}", description, errors))
+        .cpu_count(1)
+        .memory_size(VZVirtualMachineConfiguration::minimum_allowed_memory_size())
+        .build();
+    assert!(
+        conf.validate_with_error().unwrap_or_else(|e| panic!(
+            "{}: {}\n{}",
+            description,
+            e,
+            conf.describe()
+        )),
+        "{}",
+        description
+    );
+}
+
+#[test]
+fn linux_direct_kernel() {
+    let files = Files::new("profile-direct");
+    let name = "linux-direct-kernel";
+    let base = ProfileInputs {
+        kernel: Some(files.kernel.clone()),
+        initrd: Some(files.initrd.clone()),
+        ..ProfileInputs::default()
+    };
+    check_table(
+        &LinuxDirectKernel,
+        vec![
+            ("kernel and initrd", base.clone(), vec![]),
+            (
+                "with disk, seed and command line",
+                ProfileInputs {
+                    disk: Some(files.disk.clone()),
+                    cloud_init_seed: Some(files.seed.clone()),
+                    command_line: Some("console=hvc0 quiet".to_string()),
+                    ..base.clone()
+                },
+                vec![],
+            ),
+            (
+                "nothing",
+                ProfileInputs::default(),
+                vec![missing(name, "kernel"), missing(name, "initial ramdisk")],
+            ),
+            (
+                "kernel that does not exist",
+                ProfileInputs {
+                    kernel: Some(files.missing.clone()),
+                    ..base.clone()
+                },
+                vec![not_a_file(name, "kernel", &files.missing)],
+            ),
+            (
+                "directory as disk",
+                ProfileInputs {
+                    disk: Some(files.dir.path().to_path_buf()),
+                    ..base.clone()
+                },
+                vec![not_a_file(name, "disk", files.dir.path())],
+            ),
+            (
+                "EFI variable store",
+                ProfileInputs {
+                    efi_variable_store: Some(files.efi_variable_store()),
+                    ..base.clone()
+                },
+                vec![forbidden(name, "EFI variable store")],
+            ),
+        ],
+    );
+    assert_eq!(LinuxDirectKernel.default_kernel_args(), "console=hvc0");
+    assert_eq!(
+        LinuxDirectKernel.required_devices(),
+        DeviceSelection {
+            root_disk: true,
+            cloud_init_seed: true,
+            entropy: true,
+            memory_balloon: false,
+            nat_network: true,
+            serial_console: true,
+            graphics: false,
+        }
+    );
+}
+
+#[test]
+fn linux_efi_cloud_image() {
+    if !HostCapabilities::detect().supports_class("VZEFIBootLoader") {
+        println!("linux_efi_cloud_image skipped: no VZEFIBootLoader before macOS 13");
+        return;
+    }
+    let files = Files::new("profile-efi");
+    let name = "linux-efi-cloud-image";
+    let base = ProfileInputs {
+        disk: Some(files.disk.clone()),
+        efi_variable_store: Some(files.efi_variable_store()),
+        ..ProfileInputs::default()
+    };
+    check_table(
+        &LinuxEfiCloudImage,
+        vec![
+            // Creates the variable store, which the next case opens.
+            ("disk and new variable store", base.clone(), vec![]),
+            (
+                "existing variable store and seed",
+                ProfileInputs {
+                    cloud_init_seed: Some(files.seed.clone()),
+                    ..base.clone()
+                },
+                vec![],
+            ),
+            (
+                "nothing",
+                ProfileInputs::default(),
+                vec![missing(name, "disk"), missing(name, "EFI variable store")],
+            ),
+            (
+                "kernel, initrd and command line",
+                ProfileInputs {
+                    kernel: Some(files.kernel.clone()),
+                    initrd: Some(files.initrd.clone()),
+                    command_line: Some("console=hvc0".to_string()),
+                    ..base.clone()
+                },
+                vec![
+                    forbidden(name, "kernel"),
+                    forbidden(name, "initial ramdisk"),
+                    forbidden(name, "kernel command line"),
+                ],
+            ),
+            (
+                "directory as variable store",
+                ProfileInputs {
+                    efi_variable_store: Some(files.dir.path().to_path_buf()),
+                    ..base.clone()
+                },
+                vec![not_a_file(name, "EFI variable store", files.dir.path())],
+            ),
+            (
+                "seed that does not exist",
+                ProfileInputs {
+                    cloud_init_seed: Some(files.missing.clone()),
+                    ..base.clone()
+                },
+                vec![not_a_file(name, "cloud-init seed", &files.missing)],
+            ),
+        ],
+    );
+    assert!(files.efi_variable_store().is_file());
+    assert_eq!(LinuxEfiCloudImage.default_kernel_args(), "");
+}
+
+#[cfg(feature = "macos-guest")]
+#[test]
+fn macos() {
+    use virtualization_rs::profile::MacOS;
+
+    let files = Files::new("profile-macos");
+    let name = "macos";
+    let errors = MacOS.validate_inputs(&ProfileInputs {
+        kernel: Some(files.kernel.clone()),
+        initrd: Some(files.initrd.clone()),
+        command_line: Some("console=hvc0".to_string()),
+        efi_variable_store: Some(files.efi_variable_store()),
+        cloud_init_seed: Some(files.seed.clone()),
+        ..ProfileInputs::default()
+    });
+    assert_eq!(
+        errors,
+        vec![
+            missing(name, "disk"),
+            forbidden(name, "kernel"),
+            forbidden(name, "initial ramdisk"),
+            forbidden(name, "kernel command line"),
+            forbidden(name, "EFI variable store"),
+            forbidden(name, "cloud-init seed"),
+        ]
+    );
+    let inputs = ProfileInputs {
+        disk: Some(files.disk.clone()),
+        ..ProfileInputs::default()
+    };
+    assert!(MacOS.validate_inputs(&inputs).is_empty());
+    // Without a Mac platform the configuration cannot validate; the boot loader only exists on
+    // Apple silicon.
+    let supported = HostCapabilities::detect().supports_class("VZMacOSBootLoader");
+    match MacOS.boot_loader(&inputs) {
+        Ok(boot_loader) => {
+            assert!(supported);
+            assert_eq!(boot_loader.class_name(), "VZMacOSBootLoader");
+        }
+        Err(ProfileError::Refused { profile, .. }) => {
+            assert!(!supported);
+            assert_eq!(profile, name);
+        }
+        Err(error) => panic!("{}", error),
+    }
+    assert!(MacOS.required_devices().graphics);
+}
+
+#[test]
+fn kinds_parse_from_their_names() {
+    for &kind in ProfileKind::all() {
+        let name = kind.to_string();
+        assert_eq!(name.parse::<ProfileKind>(), Ok(kind));
+        assert_eq!(kind.profile().name(), name);
+    }
+    assert_eq!(
+        "linux-efi-cloud-image".parse(),
+        Ok(ProfileKind::LinuxEfiCloudImage)
+    );
+    let error = "ubuntu".parse::<ProfileKind>().unwrap_err();
+    assert_eq!(error.to_string(), "unknown guest profile \"ubuntu\"");
+}
+
+#[test]
+fn errors_name_profile_and_input() {
+    let messages: Vec<String> = [
+        missing("linux-direct-kernel", "kernel"),
+        forbidden("linux-efi-cloud-image", "kernel"),
+        not_a_file("linux-direct-kernel", "disk", Path::new("/nonexistent")),
+    ]
+    .iter()
+    .map(ToString::to_string)
+    .collect();
+    assert_eq!(
+        messages,
+        [
+            "linux-direct-kernel needs the kernel",
+            "linux-efi-cloud-image does not use the kernel",
+            "linux-direct-kernel: the disk /nonexistent is not a file",
+        ]
+    );
+}
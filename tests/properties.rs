@@ -0,0 +1,442 @@
+//! Properties of the pure layers around the framework: memory sizes, the validation search, keyed
+//! device order, guest profile input checks, MAC and vsock parsing, and reconciliation. Only the
+//! topology and memory size properties create framework objects, and only configurations.
+//!
+//! Generators stay small and shrink towards the simplest case: few devices, short names, sizes at
+//! the bounds. A failure prints the minimal input; rerun it with `PROPTEST_CASES` raised to look
+//! for more.
+
+#![cfg(target_os = "macos")]
+
+extern crate proptest;
+extern crate virtualization_rs;
+
+use proptest::collection::{btree_set, vec};
+use proptest::prelude::*;
+use proptest::sample::subsequence;
+
+use virtualization_rs::nat::normalize_mac;
+use virtualization_rs::profile::{ProfileError, ProfileInputs, ProfileKind};
+use virtualization_rs::reconcile::{ConfigDescription, ObservedDevices, ReconcileReport, Status};
+use virtualization_rs::test_support::TempDir;
+use virtualization_rs::virtualization::network_device::{
+    VZMACAddress, VZNATNetworkDeviceAttachment, VZVirtioNetworkDeviceConfiguration,
+};
+use virtualization_rs::virtualization::validation::{self, Located};
+use virtualization_rs::virtualization::virtual_machine::{
+    suggested_memory_size, VZVirtualMachineConfiguration, VZVirtualMachineConfigurationBuilder,
+    MEMORY_SIZE_GRANULARITY,
+};
+use virtualization_rs::virtualization::vsock::VsockPort;
+
+use std::path::PathBuf;
+
+const MIB: usize = MEMORY_SIZE_GRANULARITY;
+
+/// Sizes at, next to and between the allowed bounds, and at the ends of `usize`.
+fn memory_size() -> impl Strategy<Value = usize> {
+    let min = VZVirtualMachineConfiguration::minimum_allowed_memory_size();
+    let max = VZVirtualMachineConfiguration::maximum_allowed_memory_size();
+    prop_oneof![
+        prop::sample::select(vec![
+            0,
+            1,
+            min - 1,
+            min,
+            min + 1,
+            max - 1,
+            max,
+            max + 1,
+            usize::MAX - 1,
+            usize::MAX,
+        ]),
+        min..=max,
+        any::<usize>(),
+    ]
+}
+
+/// Whether the builder takes `size` as it is: a multiple of the granularity or a bound.
+fn kept(size: usize) -> bool {
+    size.is_multiple_of(MIB)
+        || size == VZVirtualMachineConfiguration::minimum_allowed_memory_size()
+        || size == VZVirtualMachineConfiguration::maximum_allowed_memory_size()
+}
+
+fn mib() -> impl Strategy<Value = usize> {
+    prop_oneof![0..1usize << 20, any::<usize>(), Just(usize::MAX)]
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(64))]
+
+    #[test]
+    fn any_memory_size_builds(size in memory_size()) {
+        let conf = VZVirtualMachineConfigurationBuilder::new().memory_size(size).build();
+        if kept(size) {
+            prop_assert_eq!(conf.memory_size(), size);
+        } else {
+            prop_assert_eq!(conf.memory_size(), size - size % MIB);
+        }
+    }
+
+    #[test]
+    fn misaligned_sizes_are_reported(size in memory_size()) {
+        let result = VZVirtualMachineConfigurationBuilder::new()
+            .memory_size(size)
+            .validated_build();
+        prop_assert_eq!(result.is_ok(), kept(size));
+    }
+
+    #[test]
+    fn sizes_in_mib_are_never_misaligned(mib in mib()) {
+        let result = VZVirtualMachineConfigurationBuilder::new()
+            .memory_size_mib(mib)
+            .validated_build();
+        prop_assert!(result.is_ok(), "{:?}", result.err());
+        let result = VZVirtualMachineConfigurationBuilder::new()
+            .memory_size_gib(mib)
+            .validated_build();
+        prop_assert!(result.is_ok(), "{:?}", result.err());
+    }
+
+    #[test]
+    fn suggested_sizes_are_allowed(size in memory_size()) {
+        let min = VZVirtualMachineConfiguration::minimum_allowed_memory_size();
+        let max = VZVirtualMachineConfiguration::maximum_allowed_memory_size();
+        let suggested = suggested_memory_size(size);
+        prop_assert!(min <= suggested && suggested <= max);
+        prop_assert!(kept(suggested));
+        prop_assert_eq!(suggested_memory_size(suggested), suggested);
+    }
+}
+
+/// Device counts per category, with a device that makes validation fail planted in one of them.
+fn planted() -> impl Strategy<Value = (Vec<usize>, usize, usize)> {
+    vec(0..4usize, 1..6)
+        .prop_filter("a category with devices", |counts| {
+            counts.iter().any(|&n| n > 0)
+        })
+        .prop_flat_map(|counts| {
+            let categories: Vec<usize> = (0..counts.len()).filter(|&c| counts[c] > 0).collect();
+            (Just(counts), prop::sample::select(categories))
+        })
+        .prop_flat_map(|(counts, category)| {
+            let n = counts[category];
+            (Just(counts), Just(category), 0..n)
+        })
+}
+
+proptest! {
+    #[test]
+    fn the_search_finds_a_planted_device((counts, category, index) in planted()) {
+        let (located, validations) = validation::locate(&counts, |lengths| {
+            if lengths[category] > index { Err(()) } else { Ok(()) }
+        });
+        let total: usize = counts.iter().sum();
+        prop_assert_eq!(located, Located::Device { category, index, error: () });
+        prop_assert!(validations <= 1 + total);
+    }
+
+    #[test]
+    fn a_required_category_is_seeded(
+        (counts, category, index) in planted(),
+        required in 0..6usize,
+    ) {
+        // Validation also fails while the required category is empty.
+        let required = required % counts.len();
+        prop_assume!(counts[required] > 0 && !(required == category && index == 0));
+        let (located, _) = validation::locate(&counts, |lengths| {
+            if lengths[required] == 0 || lengths[category] > index { Err(()) } else { Ok(()) }
+        });
+        prop_assert_eq!(located, Located::Device { category, index, error: () });
+    }
+
+    #[test]
+    fn the_search_stops_for_any_validator(
+        counts in vec(0..4usize, 0..6),
+        failing in vec(any::<bool>(), 64),
+    ) {
+        let total: usize = counts.iter().sum();
+        let mut calls = 0;
+        let (located, validations) = validation::locate(&counts, |_| {
+            calls += 1;
+            if failing[calls % failing.len()] { Err(calls) } else { Ok(()) }
+        });
+        prop_assert_eq!(validations, calls);
+        prop_assert!(validations <= 1 + counts.len() + 1 + total);
+        if let Located::Device { category, index, .. } = located {
+            prop_assert!(index < counts[category]);
+        }
+    }
+}
+
+/// Distinct keys, including empty and non-ASCII ones, each with a MAC address.
+fn keyed_devices() -> impl Strategy<Value = Vec<(String, u8)>> {
+    btree_set("\\PC{0,6}", 1..6).prop_flat_map(|keys| {
+        let n = keys.len();
+        (Just(keys), vec(any::<u8>(), n))
+            .prop_map(|(keys, octets)| keys.into_iter().zip(octets).collect())
+    })
+}
+
+fn network_device(octet: u8) -> VZVirtioNetworkDeviceConfiguration {
+    let mut device = VZVirtioNetworkDeviceConfiguration::new(VZNATNetworkDeviceAttachment::new());
+    let mac = VZMACAddress::init_with_string(&format!("02:00:00:00:00:{:02x}", octet)).unwrap();
+    device.set_mac_address(mac).unwrap();
+    device
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(32))]
+
+    #[test]
+    fn keyed_devices_come_out_in_key_order(
+        devices in keyed_devices(),
+        unkeyed in vec(any::<u8>(), 0..3),
+        seed in any::<u64>(),
+    ) {
+        let build = |order: &[(String, u8)]| {
+            let mut builder = VZVirtualMachineConfigurationBuilder::new();
+            for (i, (key, octet)) in order.iter().enumerate() {
+                builder = builder.network_device_keyed(key, network_device(*octet));
+                if let Some(octet) = unkeyed.get(i) {
+                    builder = builder.network_device(network_device(*octet));
+                }
+            }
+            for octet in unkeyed.iter().skip(order.len()) {
+                builder = builder.network_device(network_device(*octet));
+            }
+            builder.build_with_manifest().1
+        };
+        let mut shuffled = devices.clone();
+        let len = shuffled.len();
+        for i in 0..len {
+            shuffled.swap(i, (seed as usize).wrapping_add(i * 7) % len);
+        }
+        let manifest = build(&devices);
+        prop_assert_eq!(&manifest, &build(&shuffled));
+        let keys: Vec<Option<&str>> =
+            manifest.network.iter().map(|entry| entry.key.as_deref()).collect();
+        let mut sorted: Vec<&str> = devices.iter().map(|(key, _)| key.as_str()).collect();
+        sorted.sort_unstable();
+        let expected: Vec<Option<&str>> = sorted
+            .into_iter()
+            .map(Some)
+            .chain(unkeyed.iter().map(|_| None))
+            .collect();
+        prop_assert_eq!(keys, expected);
+    }
+}
+
+/// What a generated path names, inside a test directory.
+#[derive(Debug, Clone, Copy)]
+enum Planted {
+    File,
+    Directory,
+    Missing,
+}
+
+fn input() -> impl Strategy<Value = Option<(Planted, String)>> {
+    let planted = prop_oneof![
+        Just(Planted::File),
+        Just(Planted::Directory),
+        Just(Planted::Missing)
+    ];
+    prop::option::of((planted, "\\PC{1,8}"))
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(64))]
+
+    #[test]
+    fn profiles_flag_every_planted_problem(
+        kind in prop::sample::select(ProfileKind::all()),
+        paths in vec(input(), 5),
+        command_line in prop::option::of("\\PC{0,16}"),
+    ) {
+        let dir = TempDir::new("properties-profile");
+        let mut resolved = Vec::new();
+        for (i, path) in paths.iter().enumerate() {
+            resolved.push(path.as_ref().map(|(planted, name)| {
+                // Distinct per input, so one input's file is not another's directory.
+                let path = dir.path().join(format!("{}-{}", i, name.replace('/', "_")));
+                match planted {
+                    Planted::File => std::fs::write(&path, b"").unwrap(),
+                    Planted::Directory => std::fs::create_dir(&path).unwrap(),
+                    Planted::Missing => {}
+                }
+                path
+            }));
+        }
+        let inputs = ProfileInputs {
+            kernel: resolved[0].clone(),
+            initrd: resolved[1].clone(),
+            command_line,
+            efi_variable_store: resolved[2].clone(),
+            disk: resolved[3].clone(),
+            cloud_init_seed: resolved[4].clone(),
+        };
+        let profile = kind.profile();
+        let errors = profile.validate_inputs(&inputs);
+        for error in &errors {
+            match error {
+                ProfileError::NotAFile { path, .. } => prop_assert!(!path.is_file()),
+                ProfileError::Missing { .. } | ProfileError::Forbidden { .. } => {}
+                ProfileError::Refused { .. } => prop_assert!(false, "validation refused: {}", error),
+            }
+        }
+        // Every path given that names a directory is flagged, needed or not.
+        let directories = paths
+            .iter()
+            .filter(|path| matches!(path, Some((Planted::Directory, _))))
+            .count();
+        prop_assert!(errors.len() >= directories, "{:?}", errors);
+        if !errors.is_empty() {
+            prop_assert_eq!(profile.boot_loader(&inputs).err(), errors.into_iter().next());
+        }
+    }
+}
+
+/// A MAC address as people write it: octets with or without a leading zero, in either case.
+fn written_mac() -> impl Strategy<Value = ([u8; 6], String)> {
+    (any::<[u8; 6]>(), vec(any::<(bool, bool)>(), 6)).prop_map(|(octets, styles)| {
+        let written: Vec<String> = octets
+            .iter()
+            .zip(styles)
+            .map(|(octet, (pad, upper))| {
+                let octet = if pad || *octet > 0xf {
+                    format!("{:02x}", octet)
+                } else {
+                    format!("{:x}", octet)
+                };
+                if upper {
+                    octet.to_uppercase()
+                } else {
+                    octet
+                }
+            })
+            .collect();
+        (octets, written.join(":"))
+    })
+}
+
+proptest! {
+    #[test]
+    fn written_macs_normalize_to_their_octets((octets, written) in written_mac()) {
+        let expected: Vec<String> = octets.iter().map(|o| format!("{:02x}", o)).collect();
+        let normalized = normalize_mac(&written);
+        prop_assert_eq!(normalized.clone(), Some(expected.join(":")));
+        prop_assert_eq!(normalize_mac(normalized.as_deref().unwrap()), normalized.clone());
+    }
+
+    #[test]
+    fn only_hex_octets_are_macs(mac in "\\PC{0,20}") {
+        if let Some(normalized) = normalize_mac(&mac) {
+            let octets: Vec<&str> = mac.trim().split(':').collect();
+            prop_assert_eq!(octets.len(), 6);
+            for octet in octets {
+                prop_assert!(!octet.is_empty() && octet.len() <= 2, "{:?}", mac);
+                prop_assert!(octet.bytes().all(|b| b.is_ascii_hexdigit()), "{:?}", mac);
+            }
+            prop_assert_eq!(normalize_mac(&normalized), Some(normalized.clone()));
+        }
+    }
+
+    #[test]
+    fn vsock_ports_round_trip(port in 1..u32::MAX) {
+        let port = VsockPort::new(port).unwrap();
+        prop_assert_eq!(port.to_string().parse::<VsockPort>(), Ok(port));
+    }
+}
+
+fn description() -> impl Strategy<Value = ConfigDescription> {
+    (
+        prop::option::of(vec("/nonexistent/\\PC{1,8}".prop_map(PathBuf::from), 0..3)),
+        prop::option::of(vec(written_mac().prop_map(|(_, mac)| mac), 0..3)),
+        prop::option::of(0..3usize),
+        prop::option::of(vec(any::<u64>(), 0..2)),
+        prop::option::of(0..3usize),
+        prop::option::of(vec("\\PC{0,8}", 0..3)),
+        prop::option::of(0..3usize),
+    )
+        .prop_map(
+            |(storage, network, entropy, balloons, sockets, shares, consoles)| ConfigDescription {
+                storage_devices: storage,
+                network_devices: network,
+                entropy_devices: entropy,
+                memory_balloon_devices: balloons,
+                socket_devices: sockets,
+                directory_sharing_devices: shares,
+                console_devices: consoles,
+            },
+        )
+}
+
+/// What a machine described by `desired` reports, with MAC addresses in uppercase; empty or
+/// unknown for the categories it leaves out.
+fn observed_as(desired: &ConfigDescription) -> ObservedDevices {
+    ObservedDevices {
+        storage_devices: Ok(desired.storage_devices.clone().unwrap_or_default()),
+        network_devices: Ok(desired
+            .network_devices
+            .iter()
+            .flatten()
+            .map(|mac| mac.to_uppercase())
+            .collect()),
+        entropy_devices: Ok(desired.entropy_devices.unwrap_or(0)),
+        memory_balloon_devices: Ok(desired.memory_balloon_devices.clone().unwrap_or_default()),
+        socket_devices: Ok(desired.socket_devices.unwrap_or(0)),
+        directory_sharing_devices: Ok(desired
+            .directory_sharing_devices
+            .clone()
+            .unwrap_or_default()),
+        console_devices: Err("not read".to_string()),
+    }
+}
+
+proptest! {
+    #[test]
+    fn a_machine_as_described_has_no_drift(desired in description()) {
+        let report = ReconcileReport::compare_observed(&desired, &observed_as(&desired));
+        prop_assert!(!report.has_drift(), "{}", report);
+        for entry in &report.entries {
+            match &entry.status {
+                Status::Unknown { .. } => prop_assert_eq!(entry.category, "console_devices"),
+                status => prop_assert_eq!(status, &Status::Match),
+            }
+        }
+    }
+
+    #[test]
+    fn a_missing_device_is_drift(mut desired in description(), dropped in 0..4usize) {
+        // The device is added to the description, then left out of what the machine reports.
+        match dropped {
+            0 => desired.storage_devices.get_or_insert_with(Vec::new).push("/nonexistent/x".into()),
+            1 => desired.network_devices.get_or_insert_with(Vec::new).push("02:00:00:00:00:01".into()),
+            2 => desired.memory_balloon_devices.get_or_insert_with(Vec::new).push(1 << 30),
+            _ => desired.directory_sharing_devices.get_or_insert_with(Vec::new).push("x".into()),
+        }
+        let mut observed = observed_as(&desired);
+        match dropped {
+            0 => drop(observed.storage_devices.as_mut().unwrap().pop()),
+            1 => drop(observed.network_devices.as_mut().unwrap().pop()),
+            2 => drop(observed.memory_balloon_devices.as_mut().unwrap().pop()),
+            _ => drop(observed.directory_sharing_devices.as_mut().unwrap().pop()),
+        }
+        let report = ReconcileReport::compare_observed(&desired, &observed);
+        prop_assert_eq!(report.drift().count(), 1, "{}", report);
+    }
+
+    #[test]
+    fn reordered_macs_are_drift(macs in subsequence((0..=255u8).collect::<Vec<_>>(), 2..4)) {
+        let macs: Vec<String> = macs.iter().map(|o| format!("02:00:00:00:00:{:02x}", o)).collect();
+        let desired = ConfigDescription {
+            network_devices: Some(macs.clone()),
+            ..ConfigDescription::default()
+        };
+        let mut observed = observed_as(&desired);
+        observed.network_devices.as_mut().unwrap().reverse();
+        let report = ReconcileReport::compare_observed(&desired, &observed);
+        prop_assert!(report.has_drift());
+    }
+}
@@ -0,0 +1,52 @@
+//! A blocking helper called from the queue it waits for must panic instead of hanging.
+
+#![cfg(target_os = "macos")]
+
+extern crate virtualization_rs;
+
+use virtualization_rs::base::DispatchQueue;
+
+use std::panic;
+use std::sync::mpsc;
+use std::time::Duration;
+
+#[test]
+fn is_current_only_on_the_queue() {
+    let queue = DispatchQueue::new("queue-guard");
+    let other = DispatchQueue::new("queue-guard-other");
+    assert!(!queue.is_current());
+    assert!(queue.exec_sync(|| queue.is_current()));
+    assert!(!other.exec_sync(|| queue.is_current()));
+}
+
+#[test]
+fn is_current_follows_target_queues() {
+    let queue = DispatchQueue::new("queue-guard-target");
+    let child = DispatchQueue::new("queue-guard-child");
+    child.set_target_queue(&queue);
+    assert!(child.exec_sync(|| queue.is_current()));
+}
+
+#[test]
+fn blocking_call_on_own_queue_panics() {
+    let queue = DispatchQueue::new("queue-guard-deadlock");
+    let (tx, rx) = mpsc::channel();
+    let target = queue.clone();
+    queue.exec_async(move || {
+        // Without the guard, the nested `exec_sync` would never return and `recv` would time out.
+        let result = panic::catch_unwind(panic::AssertUnwindSafe(|| {
+            target.assert_not_current("exec_sync");
+            target.exec_sync(|| ())
+        }));
+        let message = result
+            .err()
+            .and_then(|e| e.downcast_ref::<String>().cloned())
+            .unwrap_or_default();
+        let _ = tx.send(message);
+    });
+    let message = rx
+        .recv_timeout(Duration::from_secs(5))
+        .expect("queue deadlocked");
+    assert!(message.contains("exec_sync"), "{}", message);
+    assert!(message.contains("queue-guard-deadlock"), "{}", message);
+}
@@ -0,0 +1,220 @@
+//! Queue policies: a pool hands out queues per machine, round robin over a fixed number or one for
+//! all, the registry creates its machines on them, and the watchdog's counters add up per queue.
+//! The ignored test measures how long machines on one queue wait for each other's slow callbacks.
+
+#![cfg(target_os = "macos")]
+
+extern crate virtualization_rs;
+
+use virtualization_rs::base::{DispatchQueue, QoSClass};
+use virtualization_rs::queue_pool::{QueuePolicy, QueuePool};
+use virtualization_rs::queue_watchdog::{self, QueueWatchdogStats};
+use virtualization_rs::registry::{DuplicateName, VmRegistry};
+use virtualization_rs::test_support::{minimal_linux_config, TempDir};
+use virtualization_rs::virtualization::virtual_machine::VZVirtualMachine;
+
+use std::thread;
+use std::time::{Duration, Instant};
+
+fn names(count: usize) -> Vec<String> {
+    (0..count).map(|i| format!("vm{}", i)).collect()
+}
+
+/// How many of `queues` are each of the distinct queues, in the order they first appear.
+fn distribution(queues: &[DispatchQueue]) -> Vec<(String, usize)> {
+    let mut counts: Vec<(DispatchQueue, usize)> = Vec::new();
+    for queue in queues {
+        match counts.iter_mut().find(|(seen, _)| seen.is_same(queue)) {
+            Some((_, count)) => *count += 1,
+            None => counts.push((queue.clone(), 1)),
+        }
+    }
+    counts
+        .into_iter()
+        .map(|(queue, count)| (queue.label(), count))
+        .collect()
+}
+
+#[test]
+fn a_shared_pool_assigns_round_robin() {
+    let pool = QueuePool::new(QueuePolicy::SharedPool(3), "builders", None);
+    let queues: Vec<_> = names(10).iter().map(|name| pool.assign(name)).collect();
+    assert_eq!(
+        distribution(&queues),
+        [
+            ("builders.0".to_string(), 4),
+            ("builders.1".to_string(), 3),
+            ("builders.2".to_string(), 3),
+        ]
+    );
+    // The machines on a queue are those `n` apart.
+    assert!(queues[0].is_same(&queues[3]));
+    assert!(!queues[0].is_same(&queues[1]));
+    assert_eq!(pool.shared_queues().len(), 3);
+}
+
+#[test]
+fn queues_are_created_as_first_assigned() {
+    let pool = QueuePool::new(QueuePolicy::SharedPool(8), "lazy", None);
+    assert!(pool.shared_queues().is_empty());
+    pool.assign("a");
+    pool.assign("b");
+    assert_eq!(
+        pool.shared_queues()
+            .iter()
+            .map(DispatchQueue::label)
+            .collect::<Vec<_>>(),
+        ["lazy.0", "lazy.1"]
+    );
+}
+
+#[test]
+fn single_shared_assigns_one_queue() {
+    let pool = QueuePool::new(
+        QueuePolicy::SingleShared,
+        "idle",
+        Some(QoSClass::Background),
+    );
+    let queues: Vec<_> = names(5).iter().map(|name| pool.assign(name)).collect();
+    assert_eq!(distribution(&queues), [("idle".to_string(), 5)]);
+}
+
+#[test]
+fn per_vm_assigns_a_queue_each() {
+    let pool = QueuePool::default();
+    assert_eq!(pool.policy(), QueuePolicy::PerVm);
+    let queues: Vec<_> = names(4).iter().map(|name| pool.assign(name)).collect();
+    assert_eq!(
+        distribution(&queues),
+        [
+            ("vm.vm0".to_string(), 1),
+            ("vm.vm1".to_string(), 1),
+            ("vm.vm2".to_string(), 1),
+            ("vm.vm3".to_string(), 1),
+        ]
+    );
+    assert!(pool.shared_queues().is_empty());
+}
+
+#[test]
+fn clones_share_the_round_robin() {
+    let pool = QueuePool::new(QueuePolicy::SharedPool(2), "shared", None);
+    let clone = pool.clone();
+    let first = pool.assign("a");
+    let second = clone.assign("b");
+    let third = clone.assign("c");
+    assert!(!first.is_same(&second));
+    assert!(first.is_same(&third));
+}
+
+#[test]
+fn clones_are_the_same_queue() {
+    let queue = DispatchQueue::new("cloned");
+    let clone = queue.clone();
+    assert!(queue.is_same(&clone));
+    assert_eq!(queue.id(), clone.id());
+    drop(queue);
+    // The clone keeps the queue alive.
+    assert_eq!(clone.exec_sync(|| 7), 7);
+    assert!(!clone.is_same(&DispatchQueue::new("cloned")));
+}
+
+#[test]
+#[should_panic(expected = "at least one queue")]
+fn an_empty_shared_pool_panics() {
+    QueuePool::new(QueuePolicy::SharedPool(0), "empty", None);
+}
+
+#[test]
+fn machines_share_a_queue_given_by_reference() {
+    let dir = TempDir::new("queue-pool-reference");
+    let queue = DispatchQueue::new("by-reference");
+    let a = VZVirtualMachine::new(minimal_linux_config(&dir), &queue);
+    let b = VZVirtualMachine::new(minimal_linux_config(&dir), &queue);
+    let c = VZVirtualMachine::new(minimal_linux_config(&dir), queue.id());
+    assert!(a.queue().is_same(&queue));
+    assert!(b.queue().is_same(a.queue()));
+    assert!(c.queue().is_same(a.queue()));
+    // The machines keep the queue alive.
+    drop(queue);
+    assert_eq!(a.queue().exec_sync(|| 1), 1);
+}
+
+#[test]
+fn the_registry_creates_machines_on_its_pool() {
+    let dir = TempDir::new("queue-pool-registry");
+    let registry =
+        VmRegistry::with_queues(QueuePool::new(QueuePolicy::SharedPool(2), "registry", None));
+    for name in names(5) {
+        let vm = registry.create(&name, minimal_linux_config(&dir)).unwrap();
+        assert_eq!(vm.label(), Some(name.as_str()));
+    }
+    assert!(matches!(
+        registry.create("vm0", minimal_linux_config(&dir)),
+        Err(DuplicateName(name)) if name == "vm0"
+    ));
+    assert_eq!(registry.names().len(), 5);
+    assert_eq!(registry.queues().shared_queues().len(), 2);
+
+    let reports = registry.queue_watchdog_stats();
+    let queues: Vec<_> = reports.iter().map(|report| report.queue.as_str()).collect();
+    assert_eq!(queues, ["registry.0", "registry.1"]);
+    // Registry order is by name, and names were assigned queues in the same order.
+    let machines: Vec<_> = reports.iter().map(|report| report.machines.len()).collect();
+    assert_eq!(machines, [3, 2]);
+    assert!(reports[0].machines[0].ends_with("(vm0)"));
+    assert!(reports[1].machines[0].ends_with("(vm1)"));
+    // None of the machines has run a callback.
+    assert!(reports
+        .iter()
+        .all(|report| report.stats == QueueWatchdogStats::default()));
+}
+
+#[test]
+fn machines_on_their_own_queues_are_reported_apart() {
+    let dir = TempDir::new("queue-pool-per-vm");
+    let machines: Vec<_> = names(3)
+        .iter()
+        .map(|name| VZVirtualMachine::new_with_qos(minimal_linux_config(&dir), name, None))
+        .collect();
+    let reports = queue_watchdog::per_queue(&machines);
+    assert_eq!(reports.len(), 3);
+    for (report, vm) in reports.iter().zip(&machines) {
+        assert_eq!(report.machines, [vm.display_name()]);
+        assert_eq!(report.stats, vm.queue_watchdog_stats());
+    }
+}
+
+/// The time `registry.states()` takes while a callback of `slow` ms is queued on each machine's
+/// queue.
+fn states_latency(policy: QueuePolicy, machines: usize, slow: u64) -> Duration {
+    let dir = TempDir::new("queue-pool-latency");
+    let registry = VmRegistry::with_queues(QueuePool::new(policy, "latency", None));
+    for name in names(machines) {
+        registry.create(&name, minimal_linux_config(&dir)).unwrap();
+    }
+    for name in registry.names() {
+        let vm = registry.get(&name).unwrap();
+        vm.queue()
+            .exec_async(move || thread::sleep(Duration::from_millis(slow)));
+    }
+    let started = Instant::now();
+    assert_eq!(registry.states(Duration::from_secs(5)).len(), machines);
+    started.elapsed()
+}
+
+#[test]
+#[ignore = "timing dependent; run with --ignored on an otherwise idle Mac"]
+fn a_single_shared_queue_serializes_slow_callbacks() {
+    const MACHINES: usize = 16;
+    const SLOW_MS: u64 = 20;
+    let per_vm = states_latency(QueuePolicy::PerVm, MACHINES, SLOW_MS);
+    let shared = states_latency(QueuePolicy::SingleShared, MACHINES, SLOW_MS);
+    eprintln!(
+        "{} machines with a {} ms callback each: states() took {:?} on a queue each, {:?} on one",
+        MACHINES, SLOW_MS, per_vm, shared
+    );
+    // On their own queues the slow callbacks overlap; on one they run back to back.
+    assert!(per_vm < Duration::from_millis(SLOW_MS * 4));
+    assert!(shared >= Duration::from_millis(SLOW_MS * MACHINES as u64));
+}
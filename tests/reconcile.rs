@@ -0,0 +1,205 @@
+//! Reconciliation of desired devices against observed ones: per-category statuses, tolerant
+//! matching of MAC addresses and disk paths, and the report as text.
+
+#![cfg(target_os = "macos")]
+
+extern crate virtualization_rs;
+
+use virtualization_rs::reconcile::{
+    ConfigDescription, ObservedDevices, ReconcileEntry, ReconcileReport, Status,
+};
+use virtualization_rs::test_support::TempDir;
+
+use std::os::unix::fs::symlink;
+use std::path::PathBuf;
+
+/// A machine with one disk, one NIC, one entropy device, a 2 GiB balloon target, one socket
+/// device, one share and no consoles, on a macOS without console accessors.
+fn observed() -> ObservedDevices {
+    ObservedDevices {
+        storage_devices: Ok(vec![PathBuf::from("/nonexistent/root.img")]),
+        network_devices: Ok(vec!["02:ab:00:00:00:01".to_string()]),
+        entropy_devices: Ok(1),
+        memory_balloon_devices: Ok(vec![2 << 30]),
+        socket_devices: Ok(1),
+        directory_sharing_devices: Ok(vec!["shared".to_string()]),
+        console_devices: Err("reading the running console devices needs macOS 13".to_string()),
+    }
+}
+
+fn mismatch(expected: &str, actual: &str) -> Status {
+    Status::Mismatch {
+        expected: expected.to_string(),
+        actual: actual.to_string(),
+    }
+}
+
+#[test]
+fn each_category_compares_on_its_own() {
+    let table: Vec<(&str, ConfigDescription, Status)> = vec![
+        (
+            "storage_devices",
+            ConfigDescription {
+                storage_devices: Some(vec![PathBuf::from("/nonexistent/root.img")]),
+                ..ConfigDescription::default()
+            },
+            Status::Match,
+        ),
+        (
+            "storage_devices",
+            ConfigDescription {
+                storage_devices: Some(vec![PathBuf::from("/nonexistent/other.img")]),
+                ..ConfigDescription::default()
+            },
+            mismatch("[/nonexistent/other.img]", "[/nonexistent/root.img]"),
+        ),
+        (
+            "network_devices",
+            ConfigDescription {
+                network_devices: Some(vec!["02:AB:0:0:0:1".to_string()]),
+                ..ConfigDescription::default()
+            },
+            Status::Match,
+        ),
+        (
+            "network_devices",
+            ConfigDescription {
+                network_devices: Some(vec![
+                    "02:ab:00:00:00:01".to_string(),
+                    "02:ab:00:00:00:02".to_string(),
+                ]),
+                ..ConfigDescription::default()
+            },
+            mismatch(
+                "[02:ab:00:00:00:01, 02:ab:00:00:00:02]",
+                "[02:ab:00:00:00:01]",
+            ),
+        ),
+        (
+            "entropy_devices",
+            ConfigDescription {
+                entropy_devices: Some(0),
+                ..ConfigDescription::default()
+            },
+            mismatch("0", "1"),
+        ),
+        (
+            "memory_balloon_devices",
+            ConfigDescription {
+                memory_balloon_devices: Some(vec![2 << 30]),
+                ..ConfigDescription::default()
+            },
+            Status::Match,
+        ),
+        (
+            "memory_balloon_devices",
+            ConfigDescription {
+                memory_balloon_devices: Some(vec![1 << 30]),
+                ..ConfigDescription::default()
+            },
+            mismatch("[1073741824]", "[2147483648]"),
+        ),
+        (
+            "socket_devices",
+            ConfigDescription {
+                socket_devices: Some(1),
+                ..ConfigDescription::default()
+            },
+            Status::Match,
+        ),
+        (
+            "directory_sharing_devices",
+            ConfigDescription {
+                directory_sharing_devices: Some(vec!["Shared".to_string()]),
+                ..ConfigDescription::default()
+            },
+            mismatch("[Shared]", "[shared]"),
+        ),
+        (
+            "console_devices",
+            ConfigDescription {
+                console_devices: Some(0),
+                ..ConfigDescription::default()
+            },
+            Status::Unknown {
+                reason: "reading the running console devices needs macOS 13".to_string(),
+            },
+        ),
+    ];
+    let observed = observed();
+    for (category, desired, status) in table {
+        let report = ReconcileReport::compare_observed(&desired, &observed);
+        assert_eq!(
+            report.entries,
+            vec![ReconcileEntry {
+                category,
+                status: status.clone()
+            }],
+            "{:?}",
+            desired
+        );
+        assert_eq!(
+            report.has_drift(),
+            matches!(status, Status::Mismatch { .. })
+        );
+        assert_eq!(report.is_in_sync(), status == Status::Match);
+    }
+}
+
+#[test]
+fn nothing_desired_reports_nothing() {
+    let report = ReconcileReport::compare_observed(&ConfigDescription::default(), &observed());
+    assert!(report.entries.is_empty());
+    assert!(report.is_in_sync());
+}
+
+#[test]
+fn macs_that_are_not_addresses_compare_lowercased() {
+    let desired = ConfigDescription {
+        network_devices: Some(vec!["Not-A-MAC".to_string()]),
+        ..ConfigDescription::default()
+    };
+    let observed = ObservedDevices {
+        network_devices: Ok(vec!["not-a-mac".to_string()]),
+        ..observed()
+    };
+    let report = ReconcileReport::compare_observed(&desired, &observed);
+    assert_eq!(report.entries[0].status, Status::Match);
+}
+
+#[test]
+fn disk_paths_compare_after_resolving_links() {
+    let dir = TempDir::new("reconcile-links");
+    let disk = dir.disk_image("root.img", 1 << 20);
+    let link = dir.path().join("link.img");
+    symlink(&disk, &link).unwrap();
+    let desired = ConfigDescription {
+        storage_devices: Some(vec![link]),
+        ..ConfigDescription::default()
+    };
+    let observed = ObservedDevices {
+        storage_devices: Ok(vec![disk]),
+        ..observed()
+    };
+    let report = ReconcileReport::compare_observed(&desired, &observed);
+    assert_eq!(report.entries[0].status, Status::Match);
+}
+
+#[test]
+fn the_report_lists_categories_in_order() {
+    let desired = ConfigDescription {
+        storage_devices: Some(vec![PathBuf::from("/nonexistent/root.img")]),
+        entropy_devices: Some(2),
+        console_devices: Some(1),
+        ..ConfigDescription::default()
+    };
+    let report = ReconcileReport::compare_observed(&desired, &observed());
+    assert_eq!(
+        report.to_string(),
+        "storage_devices match\n\
+         entropy_devices mismatch: expected 2, actual 1\n\
+         console_devices unknown: reading the running console devices needs macOS 13\n"
+    );
+    let drift: Vec<&str> = report.drift().map(|entry| entry.category).collect();
+    assert_eq!(drift, vec!["entropy_devices"]);
+}
@@ -0,0 +1,169 @@
+//! The registry over fake machines: names are unique, machines are found by name and by id, and
+//! bulk operations report every machine's state, or that its queue did not answer in time, and
+//! the stops that do not complete in time, also while other threads change the registry.
+
+#![cfg(target_os = "macos")]
+
+extern crate virtualization_rs;
+
+use virtualization_rs::base::NSError;
+use virtualization_rs::fake_vm::{FakeOp, FakeReply, FakeVm};
+use virtualization_rs::registry::{DuplicateName, VmRegistry};
+use virtualization_rs::virtualization::error::VZError;
+use virtualization_rs::virtualization::handle::VirtualMachineHandle;
+use virtualization_rs::virtualization::virtual_machine::VZVirtualMachineState::*;
+
+use std::sync::mpsc::channel;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+fn started(vm: FakeVm) -> FakeVm {
+    let (sender, started) = channel();
+    vm.start(move |outcome| {
+        let _ = sender.send(outcome.is_success());
+    })
+    .unwrap();
+    assert_eq!(started.recv_timeout(TIMEOUT), Ok(true));
+    vm
+}
+
+#[test]
+fn names_are_unique() {
+    let registry = VmRegistry::new();
+    registry.register("web", FakeVm::new("web")).unwrap();
+    assert_eq!(
+        registry.register("web", FakeVm::new("web")),
+        Err(DuplicateName("web".to_string()))
+    );
+    registry.register("db", FakeVm::new("db")).unwrap();
+    assert_eq!(registry.names(), ["db", "web"]);
+}
+
+#[test]
+fn registry_finds_machines_by_id() {
+    let registry = VmRegistry::new();
+    let web = FakeVm::new("web");
+    let db = FakeVm::new("db");
+    registry.register("web", web.clone()).unwrap();
+    registry.register("db", db.clone()).unwrap();
+
+    assert_eq!(registry.get("web").unwrap().vm_id(), web.vm_id());
+    let (name, found) = registry.get_by_id(db.vm_id()).unwrap();
+    assert_eq!(name, "db");
+    assert_eq!(found.vm_id(), db.vm_id());
+    assert_eq!(registry.remove("web").unwrap().vm_id(), web.vm_id());
+    assert!(registry.get_by_id(web.vm_id()).is_none());
+    assert!(registry.get("web").is_none());
+}
+
+#[test]
+fn states_reads_every_machine() {
+    let registry = VmRegistry::new();
+    registry
+        .register("web", started(FakeVm::new("web")))
+        .unwrap();
+    registry.register("db", FakeVm::new("db")).unwrap();
+    let states = registry.states(TIMEOUT);
+    assert_eq!(states.len(), 2);
+    assert_eq!(states["web"], Some(VZVirtualMachineStateRunning));
+    assert_eq!(states["db"], Some(VZVirtualMachineStateStopped));
+}
+
+#[test]
+fn states_reports_machines_whose_queue_is_blocked() {
+    let registry = VmRegistry::new();
+    let stuck = FakeVm::new("stuck");
+    registry.register("stuck", stuck.clone()).unwrap();
+    registry.register("idle", FakeVm::new("idle")).unwrap();
+    let (release, released) = channel::<()>();
+    stuck.queue().exec_async(move || {
+        let _ = released.recv();
+    });
+
+    let timeout = Duration::from_millis(200);
+    let asked = Instant::now();
+    let states = registry.states(timeout);
+    assert!(asked.elapsed() >= timeout);
+    assert!(asked.elapsed() < TIMEOUT);
+    assert_eq!(states["stuck"], None);
+    assert_eq!(states["idle"], Some(VZVirtualMachineStateStopped));
+
+    drop(release);
+    assert_eq!(
+        registry.states(TIMEOUT)["stuck"],
+        Some(VZVirtualMachineStateStopped)
+    );
+}
+
+#[test]
+fn concurrent_register_remove_and_states() {
+    const THREADS: usize = 4;
+    const ROUNDS: usize = 50;
+    let registry = Arc::new(VmRegistry::new());
+    registry.register("stable", FakeVm::new("stable")).unwrap();
+
+    let churners: Vec<_> = (0..THREADS)
+        .map(|t| {
+            let registry = registry.clone();
+            thread::spawn(move || {
+                for round in 0..ROUNDS {
+                    let name = format!("vm-{}-{}", t, round);
+                    registry.register(&name, FakeVm::new(&name)).unwrap();
+                    if round % 2 == 1 {
+                        assert!(registry.remove(&name).is_some());
+                    }
+                }
+            })
+        })
+        .collect();
+    let readers: Vec<_> = (0..THREADS)
+        .map(|_| {
+            let registry = registry.clone();
+            thread::spawn(move || {
+                for _ in 0..ROUNDS {
+                    let states = registry.states(TIMEOUT);
+                    assert_eq!(states["stable"], Some(VZVirtualMachineStateStopped));
+                    for (name, state) in states {
+                        assert!(name == "stable" || name.starts_with("vm-"), "{}", name);
+                        assert_eq!(state, Some(VZVirtualMachineStateStopped), "{}", name);
+                    }
+                }
+            })
+        })
+        .collect();
+    for worker in churners.into_iter().chain(readers) {
+        worker.join().unwrap();
+    }
+
+    // Odd rounds removed what they registered.
+    let kept = THREADS * ROUNDS / 2;
+    assert_eq!(registry.names().len(), kept + 1);
+    assert_eq!(registry.states(TIMEOUT).len(), kept + 1);
+}
+
+#[test]
+fn stop_all_returns_the_stragglers() {
+    let registry = VmRegistry::new();
+    let web = started(FakeVm::new("web"));
+    let db = started(FakeVm::new("db").reply(FakeOp::Stop, FakeReply::Hang));
+    registry.register("web", web.clone()).unwrap();
+    registry.register("db", db.clone()).unwrap();
+    // Never started, its stop fails, but it is stopped all the same.
+    registry
+        .register(
+            "cache",
+            FakeVm::new("cache").reply(FakeOp::Stop, FakeReply::Fail(VZError(NSError::posix(5)))),
+        )
+        .unwrap();
+
+    let timeout = Duration::from_millis(200);
+    let asked = Instant::now();
+    assert_eq!(registry.stop_all(timeout), ["db"]);
+    assert!(asked.elapsed() >= timeout);
+    assert_eq!(web.peek_state(), VZVirtualMachineStateStopped);
+    assert_eq!(db.peek_state(), VZVirtualMachineStateStopping);
+    assert_eq!(db.calls(), [FakeOp::Start, FakeOp::Stop]);
+}
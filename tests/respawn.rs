@@ -0,0 +1,407 @@
+//! Crash-loop restarts: the backoff replayed over scripted failures, and a respawner supervising
+//! fake machines that never boot, crash or shut down, until its budget runs out, the stop is not
+//! restarted or it is shut down, and diagnosing the host's networking after each failure. One
+//! supervision runs real machines, for the hooks of `Respawner::new`.
+
+#![cfg(target_os = "macos")]
+
+extern crate virtualization_rs;
+
+use virtualization_rs::base::NSError;
+use virtualization_rs::fake_vm::{FakeEvent, FakeOp, FakeReply, FakeVm};
+use virtualization_rs::respawn::{
+    Backoff, BackoffDecision, RespawnEvent, RespawnHandle, Respawner, RestartPolicy,
+};
+use virtualization_rs::test_support::{self, TempDir};
+use virtualization_rs::virtualization::error::VZError;
+use virtualization_rs::virtualization::handle::VirtualMachineHandle;
+use virtualization_rs::virtualization::lifecycle::{Lifecycle, StopReason};
+use virtualization_rs::virtualization::virtual_machine::{
+    VZVirtualMachine, VZVirtualMachineState, VmId,
+};
+
+use std::sync::mpsc::channel;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+const SECOND: Duration = Duration::from_secs(1);
+
+fn policy(max_restarts: u32) -> RestartPolicy {
+    RestartPolicy {
+        max_restarts,
+        window: Duration::from_secs(60),
+        initial_delay: SECOND,
+        max_delay: Duration::from_secs(5),
+        restart_on_guest_shutdown: false,
+    }
+}
+
+fn restart(attempt: u32, delay_secs: u64) -> BackoffDecision {
+    BackoffDecision::Restart {
+        attempt,
+        delay: Duration::from_secs(delay_secs),
+    }
+}
+
+/// The decisions for failures at `seconds` after the start.
+fn replay(policy: RestartPolicy, seconds: &[u64]) -> Vec<BackoffDecision> {
+    let start = Instant::now();
+    let mut backoff = Backoff::new(policy);
+    seconds
+        .iter()
+        .map(|&s| backoff.failed(start + Duration::from_secs(s)))
+        .collect()
+}
+
+#[test]
+fn an_immediate_crash_loop_doubles_the_delay_then_gives_up() {
+    assert_eq!(
+        replay(policy(4), &[0, 1, 3, 7, 15]),
+        [
+            restart(1, 1),
+            restart(2, 2),
+            restart(3, 4),
+            // Capped at `max_delay`.
+            restart(4, 5),
+            BackoffDecision::GiveUp { restarts: 4 },
+        ]
+    );
+}
+
+#[test]
+fn a_crash_after_a_stable_window_starts_over() {
+    assert_eq!(
+        replay(policy(2), &[0, 1, 200]),
+        [restart(1, 1), restart(2, 2), restart(1, 1)]
+    );
+}
+
+#[test]
+fn restarts_leave_the_budget_as_the_window_slides() {
+    // Restarts at 0 and 30 use the budget; at 61 the first no longer counts, at 70 the second
+    // still does.
+    assert_eq!(
+        replay(policy(2), &[0, 30, 40, 61, 70]),
+        [
+            restart(1, 1),
+            restart(2, 2),
+            BackoffDecision::GiveUp { restarts: 2 },
+            restart(2, 2),
+            BackoffDecision::GiveUp { restarts: 2 },
+        ]
+    );
+    let start = Instant::now();
+    let mut backoff = Backoff::new(policy(2));
+    backoff.failed(start);
+    assert_eq!(backoff.restarts_within_window(start + SECOND), 1);
+    assert_eq!(backoff.restarts_within_window(start + 60 * SECOND), 0);
+}
+
+#[test]
+fn no_budget_never_restarts() {
+    assert_eq!(
+        replay(policy(0), &[0]),
+        [BackoffDecision::GiveUp { restarts: 0 }]
+    );
+}
+
+#[test]
+fn only_unexpected_stops_are_restarted() {
+    let crashed = StopReason::Crashed(VZError(NSError::posix(5)));
+    let default = RestartPolicy::default();
+    assert!(default.restarts_after(&crashed));
+    assert!(!default.restarts_after(&StopReason::GuestInitiated));
+    assert!(!default.restarts_after(&StopReason::HostRequested));
+    assert!(!default.restarts_after(&StopReason::Forced));
+    let service = RestartPolicy {
+        restart_on_guest_shutdown: true,
+        ..default
+    };
+    assert!(service.restarts_after(&StopReason::GuestInitiated));
+    assert!(!service.restarts_after(&StopReason::Forced));
+}
+
+#[test]
+fn configuration_copy_is_unfrozen_and_identical() {
+    let dir = TempDir::new("respawn-copy");
+    let conf = test_support::minimal_linux_config(&dir);
+    let described = conf.describe();
+    let vm = VZVirtualMachine::new_with_qos(conf, "respawn-copy", None);
+    let mut copy = vm.configuration_copy();
+    assert!(!copy.is_frozen());
+    assert_eq!(copy.describe(), described);
+    assert_eq!(vm.describe_configuration(), described);
+    copy.set_cpu_count(2).unwrap();
+    // The machine's own configuration is untouched.
+    assert_eq!(vm.configuration_copy().describe(), described);
+}
+
+/// The events up to the terminal one, failing the test if one takes longer than a minute.
+fn collect<V: VirtualMachineHandle>(handle: &mut RespawnHandle<V>) -> Vec<RespawnEvent<V>> {
+    let mut events = Vec::new();
+    while !handle.is_finished() {
+        let event = handle
+            .recv_timeout(Duration::from_secs(60))
+            .unwrap_or_else(|| panic!("no terminal event; got {:?}", events));
+        events.push(event);
+    }
+    events
+}
+
+fn eio() -> VZError {
+    VZError(NSError::posix(5))
+}
+
+/// Fake machines whose every start fails.
+fn never_boots(policy: RestartPolicy) -> Respawner<FakeVm> {
+    Respawner::with_factory(|_| {
+        FakeVm::new("respawn").default_reply(FakeOp::Start, FakeReply::Fail(eio()))
+    })
+    .policy(policy)
+}
+
+/// The restarts announced among `events`.
+fn restarts<V>(events: &[RespawnEvent<V>]) -> Vec<(u32, Duration)> {
+    events
+        .iter()
+        .filter_map(|event| match event {
+            RespawnEvent::Restarting { attempt, delay } => Some((*attempt, *delay)),
+            _ => None,
+        })
+        .collect()
+}
+
+#[test]
+fn a_machine_that_never_boots_exhausts_the_budget() {
+    let spawned = Arc::new(Mutex::new(Vec::new()));
+    let s = spawned.clone();
+    let mut handle = never_boots(RestartPolicy {
+        initial_delay: Duration::from_millis(10),
+        ..policy(2)
+    })
+    .on_spawn(move |vm, attempt| s.lock().unwrap().push((attempt, vm.vm_id())))
+    .spawn();
+    let events = collect(&mut handle);
+
+    let spawned = spawned.lock().unwrap().clone();
+    assert_eq!(
+        spawned.iter().map(|&(a, _)| a).collect::<Vec<_>>(),
+        [0, 1, 2]
+    );
+    // Every restart is a new machine.
+    assert!(spawned.windows(2).all(|w| w[0].1 != w[1].1));
+    assert_eq!(
+        restarts(&events),
+        [
+            (1, Duration::from_millis(10)),
+            (2, Duration::from_millis(20))
+        ]
+    );
+    let failures = events
+        .iter()
+        .filter(|event| matches!(event, RespawnEvent::StartFailed { .. }))
+        .count();
+    assert_eq!(failures, 3, "{:?}", events);
+    match events.last() {
+        Some(RespawnEvent::GaveUp { restarts }) => assert_eq!(*restarts, 2),
+        other => panic!("expected to give up, got {:?}", other),
+    }
+    assert!(handle.recv().is_none());
+}
+
+#[test]
+fn a_crashed_machine_is_replaced() {
+    // The first machine crashes soon after booting, the second keeps running.
+    let mut handle = Respawner::with_factory(|attempt| {
+        let vm = FakeVm::new("respawn-crash");
+        if attempt == 0 {
+            vm.after_start(Duration::from_millis(20), FakeEvent::Crash(eio()))
+        } else {
+            vm
+        }
+    })
+    .policy(RestartPolicy {
+        initial_delay: Duration::from_millis(10),
+        ..policy(2)
+    })
+    .spawn();
+    let mut events = Vec::new();
+    while events.len() < 4 {
+        events.push(
+            handle
+                .recv_timeout(Duration::from_secs(60))
+                .unwrap_or_else(|| panic!("no replacement; got {:?}", events)),
+        );
+    }
+    assert!(
+        matches!(
+            events[..],
+            [
+                RespawnEvent::Spawned { attempt: 0, .. },
+                RespawnEvent::Stopped {
+                    attempt: 0,
+                    reason: StopReason::Crashed(_)
+                },
+                RespawnEvent::Restarting { attempt: 1, .. },
+                RespawnEvent::Spawned { attempt: 1, .. },
+            ]
+        ),
+        "{:?}",
+        events
+    );
+    let ids: Vec<VmId> = events
+        .iter()
+        .filter_map(|event| match event {
+            RespawnEvent::Spawned { vm, .. } => Some(vm.vm_id()),
+            _ => None,
+        })
+        .collect();
+    assert_ne!(ids[0], ids[1]);
+    let replacement = handle.current().unwrap();
+    assert_eq!(replacement.vm_id(), ids[1]);
+    let (sender, running) = channel();
+    let _observation = replacement.on_first_transition_to(
+        VZVirtualMachineState::VZVirtualMachineStateRunning,
+        move || {
+            let _ = sender.send(());
+        },
+    );
+    assert_eq!(running.recv_timeout(Duration::from_secs(60)), Ok(()));
+
+    // A machine that is running is stopped by the shutdown, which ends the supervision.
+    handle.shutdown();
+    let events = collect(&mut handle);
+    assert!(
+        matches!(
+            events.last(),
+            Some(RespawnEvent::Finished {
+                reason: Some(StopReason::Forced)
+            })
+        ),
+        "{:?}",
+        events
+    );
+    assert_eq!(replacement.lifecycle(), Lifecycle::Stopped);
+    handle.join();
+}
+
+#[test]
+fn a_guest_shutdown_is_not_restarted() {
+    let mut handle = Respawner::with_factory(|_| {
+        FakeVm::new("respawn-shutdown")
+            .after_start(Duration::from_millis(20), FakeEvent::GuestShutdown)
+    })
+    .policy(policy(2))
+    .spawn();
+    let events = collect(&mut handle);
+    assert!(restarts(&events).is_empty(), "{:?}", events);
+    assert!(
+        matches!(
+            events.last(),
+            Some(RespawnEvent::Finished {
+                reason: Some(StopReason::GuestInitiated)
+            })
+        ),
+        "{:?}",
+        events
+    );
+    handle.join();
+}
+
+#[test]
+fn failures_are_followed_by_a_network_diagnosis() {
+    let mut handle = never_boots(RestartPolicy {
+        initial_delay: Duration::from_millis(10),
+        ..policy(1)
+    })
+    .diagnose_networking_on_failure(true)
+    .spawn();
+    let events = collect(&mut handle);
+    let diagnosed: Vec<u32> = events
+        .iter()
+        .filter_map(|event| match event {
+            RespawnEvent::NetworkDiagnosed { attempt, .. } => Some(*attempt),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(diagnosed, [0, 1], "{:?}", events);
+    // Each right after its failure, before the restart is decided.
+    let after_failure = events.windows(2).all(|pair| match pair {
+        [RespawnEvent::StartFailed { attempt, .. }, next] => matches!(
+            next,
+            RespawnEvent::NetworkDiagnosed { attempt: a, .. } if a == attempt
+        ),
+        _ => true,
+    });
+    assert!(after_failure, "{:?}", events);
+    handle.join();
+
+    // A guest shutting down did not fail.
+    let mut handle = Respawner::with_factory(|_| {
+        FakeVm::new("respawn-diagnose-shutdown")
+            .after_start(Duration::from_millis(20), FakeEvent::GuestShutdown)
+    })
+    .diagnose_networking_on_failure(true)
+    .spawn();
+    let events = collect(&mut handle);
+    assert!(
+        !events
+            .iter()
+            .any(|event| matches!(event, RespawnEvent::NetworkDiagnosed { .. })),
+        "{:?}",
+        events
+    );
+    handle.join();
+}
+
+#[test]
+fn shutdown_ends_a_restart_delay() {
+    let mut handle = never_boots(RestartPolicy {
+        initial_delay: Duration::from_secs(3600),
+        max_delay: Duration::from_secs(3600),
+        ..policy(2)
+    })
+    .spawn();
+    loop {
+        match handle.recv_timeout(Duration::from_secs(60)) {
+            Some(RespawnEvent::Restarting { .. }) => break,
+            Some(event) => assert!(!event.is_terminal(), "{:?}", event),
+            None => panic!("no restart"),
+        }
+    }
+    let asked = Instant::now();
+    handle.shutdown();
+    match handle.recv_timeout(Duration::from_secs(60)) {
+        Some(RespawnEvent::Finished { reason }) => assert!(reason.is_none()),
+        other => panic!("expected to finish, got {:?}", other),
+    }
+    assert!(asked.elapsed() < Duration::from_secs(60));
+    assert!(handle.current().is_none());
+    handle.join();
+}
+
+#[test]
+fn real_machines_are_configured_and_labeled() {
+    let dir = TempDir::new("respawn-hooks");
+    let configured = Arc::new(Mutex::new(Vec::new()));
+    let c = configured.clone();
+    // The kernel is garbage: the start fails, or the machine crashes right away.
+    let mut handle = Respawner::new(test_support::minimal_linux_config(&dir), "respawn")
+        .policy(policy(0))
+        .on_configure(move |conf, attempt| {
+            assert!(!conf.is_frozen());
+            c.lock().unwrap().push(attempt);
+        })
+        .on_spawn(|vm, attempt| {
+            assert_eq!(attempt, 0);
+            assert_eq!(vm.label(), Some("respawn"));
+        })
+        .spawn();
+    let events = collect(&mut handle);
+    assert_eq!(*configured.lock().unwrap(), [0]);
+    assert!(
+        matches!(events.last(), Some(RespawnEvent::GaveUp { restarts: 0 })),
+        "{:?}",
+        events
+    );
+}
@@ -0,0 +1,334 @@
+//! Downloads from a small HTTP server in the test: a complete download must be verified and put
+//! in place, a transfer cut off by the server or cancelled must leave resume data and continue
+//! from it with a range request, and a size or checksum mismatch must be reported without
+//! leaving the file behind.
+
+#![cfg(target_os = "macos")]
+
+extern crate virtualization_rs;
+
+use virtualization_rs::virtualization::restore_image::{
+    digest_from_hex, sha256_file, DownloadError, DownloadHandle, Downloader,
+};
+
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+const SIZE: usize = 4 << 20;
+const CUT: usize = 1 << 20;
+
+/// How the server answers a request for the whole file.
+#[derive(Clone, Copy, PartialEq)]
+enum Behaviour {
+    Complete,
+    /// Closes the connection after `CUT` bytes, once.
+    CutOnce,
+    /// Sends `CUT` bytes, then stalls until the client goes away.
+    StallOnce,
+}
+
+struct Server {
+    url: String,
+    body: Arc<Vec<u8>>,
+    /// The `Range` header of each request, if any.
+    ranges: Arc<Mutex<Vec<Option<String>>>>,
+}
+
+impl Server {
+    fn start(behaviour: Behaviour) -> Server {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/restore.ipsw", listener.local_addr().unwrap());
+        let body: Arc<Vec<u8>> = Arc::new((0..SIZE).map(|i| (i % 251) as u8).collect());
+        let ranges = Arc::new(Mutex::new(Vec::new()));
+        let interrupted = Arc::new(AtomicBool::new(false));
+        {
+            let body = body.clone();
+            let ranges = ranges.clone();
+            thread::spawn(move || {
+                for stream in listener.incoming() {
+                    let stream = stream.unwrap();
+                    let body = body.clone();
+                    let ranges = ranges.clone();
+                    let interrupted = interrupted.clone();
+                    thread::spawn(move || {
+                        let range = read_request(&stream);
+                        ranges.lock().unwrap().push(range.clone());
+                        let start = range
+                            .as_deref()
+                            .and_then(|r| r.strip_prefix("bytes="))
+                            .and_then(|r| r.trim_end_matches('-').parse().ok());
+                        let cut = behaviour != Behaviour::Complete
+                            && start.is_none()
+                            && !interrupted.swap(true, Ordering::SeqCst);
+                        respond(
+                            stream,
+                            &body,
+                            start,
+                            if cut { Some(behaviour) } else { None },
+                        );
+                    });
+                }
+            });
+        }
+        Server { url, body, ranges }
+    }
+
+    fn ranges(&self) -> Vec<Option<String>> {
+        self.ranges.lock().unwrap().clone()
+    }
+}
+
+/// Reads the request head and returns its `Range` header.
+fn read_request(stream: &TcpStream) -> Option<String> {
+    let mut reader = BufReader::new(stream);
+    let mut range = None;
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line).unwrap() == 0 || line == "\r\n" {
+            return range;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            if name.eq_ignore_ascii_case("range") {
+                range = Some(value.trim().to_string());
+            }
+        }
+    }
+}
+
+fn respond(mut stream: TcpStream, body: &[u8], start: Option<usize>, cut: Option<Behaviour>) {
+    let validators = "ETag: \"restore-1\"\r\nLast-Modified: Mon, 24 Oct 2022 17:00:00 GMT\r\n\
+                      Accept-Ranges: bytes\r\nContent-Type: application/octet-stream\r\n";
+    let head = match start {
+        Some(start) => format!(
+            "HTTP/1.1 206 Partial Content\r\n{}Content-Range: bytes {}-{}/{}\r\n\
+             Content-Length: {}\r\nConnection: close\r\n\r\n",
+            validators,
+            start,
+            body.len() - 1,
+            body.len(),
+            body.len() - start
+        ),
+        None => format!(
+            "HTTP/1.1 200 OK\r\n{}Content-Length: {}\r\nConnection: close\r\n\r\n",
+            validators,
+            body.len()
+        ),
+    };
+    if stream.write_all(head.as_bytes()).is_err() {
+        return;
+    }
+    let body = &body[start.unwrap_or(0)..];
+    match cut {
+        None => {
+            let _ = stream.write_all(body);
+        }
+        Some(behaviour) => {
+            let _ = stream.write_all(&body[..CUT]);
+            let _ = stream.flush();
+            if behaviour == Behaviour::StallOnce {
+                thread::sleep(Duration::from_secs(30));
+            } else {
+                // Let the client read what was sent before the connection drops.
+                thread::sleep(Duration::from_millis(500));
+            }
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+    }
+}
+
+/// A scratch directory for the downloaded file and its siblings.
+struct Dest(PathBuf);
+
+impl Dest {
+    fn new(name: &str) -> Dest {
+        let dir = std::env::temp_dir().join(format!(
+            "virtualization-rs-download-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        Dest(dir)
+    }
+
+    fn path(&self) -> PathBuf {
+        self.0.join("restore.ipsw")
+    }
+
+    fn sibling(&self, suffix: &str) -> PathBuf {
+        self.0.join(format!("restore.ipsw{}", suffix))
+    }
+}
+
+impl Drop for Dest {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+#[test]
+fn digests_are_sha256() {
+    let dest = Dest::new("digest");
+    fs::write(dest.path(), b"abc").unwrap();
+    assert_eq!(
+        sha256_file(dest.path()).unwrap(),
+        digest_from_hex("ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad")
+            .unwrap()
+    );
+    assert!(digest_from_hex("ba7816bf").is_none());
+    assert!(digest_from_hex(&"zz".repeat(32)).is_none());
+}
+
+#[test]
+fn complete_download_is_verified() {
+    let server = Server::start(Behaviour::Complete);
+    let dest = Dest::new("complete");
+    fs::write(dest.sibling(".expected"), server.body.as_slice()).unwrap();
+    let expected = sha256_file(dest.sibling(".expected")).unwrap();
+
+    let last = Arc::new(Mutex::new(None));
+    let seen = last.clone();
+    let handle = Downloader::new()
+        .expected_size(SIZE as u64)
+        .sha256(expected)
+        .verify_with(|path| {
+            if path.extension().is_some_and(|e| e == "download") {
+                Ok(())
+            } else {
+                Err(format!("verified {}", path.display()))
+            }
+        })
+        .on_progress(move |progress| *seen.lock().unwrap() = Some(progress))
+        .download(&server.url, dest.path())
+        .unwrap();
+    assert_eq!(handle.wait().unwrap(), dest.path());
+    assert_eq!(fs::read(dest.path()).unwrap(), *server.body);
+    assert!(!dest.sibling(".download").exists());
+    assert!(!dest.sibling(".resumedata").exists());
+    let last = last.lock().unwrap().unwrap();
+    assert_eq!(last.written, SIZE as u64);
+    assert_eq!(last.fraction(), Some(1.0));
+    assert_eq!(server.ranges(), vec![None]);
+}
+
+#[test]
+fn interrupted_download_resumes() {
+    let server = Server::start(Behaviour::CutOnce);
+    let dest = Dest::new("interrupted");
+    match Downloader::new()
+        .download(&server.url, dest.path())
+        .unwrap()
+        .wait()
+    {
+        Err(DownloadError::Transfer { resumable, .. }) => assert!(resumable),
+        other => panic!("expected a resumable transfer error, got {:?}", other),
+    }
+    assert!(dest.sibling(".resumedata").exists());
+    assert!(!dest.path().exists());
+
+    let resumed = Mutex::new(None);
+    let handle = Downloader::new()
+        .download(&server.url, dest.path())
+        .unwrap();
+    assert_eq!(handle_wait(handle, &resumed).unwrap(), dest.path());
+    let offset = resumed.lock().unwrap().unwrap();
+    assert!(offset > 0 && offset <= CUT as u64, "resumed at {}", offset);
+    assert_eq!(fs::read(dest.path()).unwrap(), *server.body);
+    assert!(!dest.sibling(".resumedata").exists());
+    let ranges = server.ranges();
+    assert_eq!(ranges.len(), 2);
+    assert_eq!(ranges[1].as_deref(), Some(&*format!("bytes={}-", offset)));
+}
+
+/// Waits for `handle`, keeping the offset it resumed at in `resumed`.
+fn handle_wait(
+    handle: DownloadHandle,
+    resumed: &Mutex<Option<u64>>,
+) -> Result<PathBuf, DownloadError> {
+    while !handle.wait_timeout(Duration::from_millis(50)) {}
+    *resumed.lock().unwrap() = handle.resumed_at();
+    handle.wait()
+}
+
+#[test]
+fn cancelled_download_resumes() {
+    let server = Server::start(Behaviour::StallOnce);
+    let dest = Dest::new("cancelled");
+    let handle = Downloader::new()
+        .download(&server.url, dest.path())
+        .unwrap();
+    while handle.progress().written < CUT as u64 {
+        assert!(!handle.wait_timeout(Duration::from_millis(20)));
+    }
+    assert_eq!(handle.progress().expected, Some(SIZE as u64));
+    handle.cancel();
+    assert!(matches!(handle.wait(), Err(DownloadError::Cancelled)));
+    assert!(dest.sibling(".resumedata").exists());
+
+    let resumed = Mutex::new(None);
+    let handle = Downloader::new()
+        .download(&server.url, dest.path())
+        .unwrap();
+    assert_eq!(handle_wait(handle, &resumed).unwrap(), dest.path());
+    assert_eq!(*resumed.lock().unwrap(), Some(CUT as u64));
+    assert_eq!(fs::read(dest.path()).unwrap(), *server.body);
+}
+
+#[test]
+fn mismatches_are_reported() {
+    let server = Server::start(Behaviour::Complete);
+    let dest = Dest::new("mismatch");
+    let wrong = [0x5a; 32];
+    match Downloader::new()
+        .sha256(wrong)
+        .download(&server.url, dest.path())
+        .unwrap()
+        .wait()
+    {
+        Err(DownloadError::ChecksumMismatch { expected, actual }) => {
+            assert_eq!(expected, wrong);
+            assert_ne!(actual, wrong);
+        }
+        other => panic!("expected a checksum mismatch, got {:?}", other),
+    }
+    assert!(!dest.path().exists());
+    assert!(!dest.sibling(".download").exists());
+
+    match Downloader::new()
+        .expected_size(SIZE as u64 + 1)
+        .download(&server.url, dest.path())
+        .unwrap()
+        .wait()
+    {
+        Err(DownloadError::SizeMismatch { expected, actual }) => {
+            assert_eq!((expected, actual), (SIZE as u64 + 1, SIZE as u64))
+        }
+        other => panic!("expected a size mismatch, got {:?}", other),
+    }
+
+    match Downloader::new()
+        .verify_with(|_| Err("not a restore image".to_string()))
+        .download(&server.url, dest.path())
+        .unwrap()
+        .wait()
+    {
+        Err(DownloadError::Rejected(reason)) => assert_eq!(reason, "not a restore image"),
+        other => panic!("expected a rejection, got {:?}", other),
+    }
+    assert!(!dest.path().exists());
+}
+
+#[test]
+fn invalid_urls_are_refused() {
+    let dest = Dest::new("invalid");
+    assert!(matches!(
+        Downloader::new().download("http://[", dest.path()),
+        Err(DownloadError::InvalidUrl(_))
+    ));
+}
@@ -0,0 +1,114 @@
+//! Rosetta caching options must validate their socket path or name in the framework and fail with
+//! an error, not an exception, and report `NotSupported` on hosts without them.
+//!
+//! Cases that need the option classes or an installed Rosetta print why they are skipped.
+
+#![cfg(target_os = "macos")]
+
+extern crate virtualization_rs;
+
+use virtualization_rs::base::HostArch;
+use virtualization_rs::features::HostCapabilities;
+use virtualization_rs::virtualization::directory_sharing::{
+    VZLinuxRosettaAbstractSocketCachingOptions, VZLinuxRosettaAvailability,
+    VZLinuxRosettaCachingOptions, VZLinuxRosettaDirectoryShare,
+    VZLinuxRosettaUnixSocketCachingOptions,
+};
+use virtualization_rs::virtualization::error::VZErrorCode;
+
+const SOCKET_PATH: &str = "/run/rosettad/rosetta.sock";
+
+fn caching_supported(test: &str) -> bool {
+    let caching = HostCapabilities::detect().rosetta_caching();
+    if !(caching.unix_socket && caching.abstract_socket) {
+        println!("{} skipped: no Rosetta caching options on this host", test);
+        return false;
+    }
+    true
+}
+
+#[test]
+fn unix_socket_path_round_trips() {
+    if !caching_supported("unix_socket_path_round_trips") {
+        return;
+    }
+    let options = VZLinuxRosettaUnixSocketCachingOptions::new(SOCKET_PATH).unwrap();
+    assert_eq!(options.path(), SOCKET_PATH);
+    let options = VZLinuxRosettaAbstractSocketCachingOptions::new("rosetta").unwrap();
+    assert_eq!(options.name(), "rosetta");
+}
+
+#[test]
+fn overlong_socket_path_fails() {
+    if !caching_supported("overlong_socket_path_fails") {
+        return;
+    }
+    let path = format!(
+        "/{}",
+        "a".repeat(VZLinuxRosettaUnixSocketCachingOptions::maximum_path_length())
+    );
+    let error = VZLinuxRosettaUnixSocketCachingOptions::new(&path)
+        .err()
+        .expect("accepted an overlong socket path");
+    assert_eq!(error.operation(), "create Rosetta caching options");
+    assert_eq!(
+        error.resource(),
+        Some(format!("socket path '{}'", path).as_str())
+    );
+
+    let name = "a".repeat(VZLinuxRosettaAbstractSocketCachingOptions::maximum_name_length() + 1);
+    assert!(VZLinuxRosettaAbstractSocketCachingOptions::new(&name).is_err());
+}
+
+#[test]
+fn options_round_trip_on_share() {
+    if !caching_supported("options_round_trip_on_share") {
+        return;
+    }
+    if VZLinuxRosettaDirectoryShare::availability() != VZLinuxRosettaAvailability::Installed {
+        println!("options_round_trip_on_share skipped: Rosetta is not installed");
+        return;
+    }
+    let share = VZLinuxRosettaDirectoryShare::new().unwrap();
+    assert!(share.options().is_none());
+
+    let options = VZLinuxRosettaUnixSocketCachingOptions::new(SOCKET_PATH).unwrap();
+    assert!(share.set_options(Some(options.into())).is_ok());
+    match share.options() {
+        Some(VZLinuxRosettaCachingOptions::UnixSocket(options)) => {
+            assert_eq!(options.path(), SOCKET_PATH)
+        }
+        _ => panic!("expected Unix socket options"),
+    }
+
+    let options = VZLinuxRosettaAbstractSocketCachingOptions::new("rosetta").unwrap();
+    assert!(share.set_options(Some(options.into())).is_ok());
+    match share.options() {
+        Some(VZLinuxRosettaCachingOptions::AbstractSocket(options)) => {
+            assert_eq!(options.name(), "rosetta")
+        }
+        _ => panic!("expected abstract socket options"),
+    }
+
+    assert!(share.set_options(None).is_ok());
+    assert!(share.options().is_none());
+}
+
+#[test]
+fn unsupported_host_fails_with_not_supported() {
+    let intel = HostCapabilities {
+        arch: HostArch::Intel,
+        ..HostCapabilities::detect()
+    };
+    assert!(!intel.rosetta_caching().unix_socket);
+    assert!(!intel.rosetta_caching().abstract_socket);
+
+    let error = VZLinuxRosettaUnixSocketCachingOptions::new_for_host(&intel, SOCKET_PATH)
+        .err()
+        .expect("created caching options on an Intel host");
+    assert_eq!(error.code(), Some(VZErrorCode::NotSupported));
+    let error = VZLinuxRosettaAbstractSocketCachingOptions::new_for_host(&intel, "rosetta")
+        .err()
+        .expect("created caching options on an Intel host");
+    assert_eq!(error.code(), Some(VZErrorCode::NotSupported));
+}
@@ -0,0 +1,158 @@
+//! Named serial ports keep the order they were added in, which is the guest's `hvc` numbering,
+//! refuse a name used twice and a port past `MAX_SERIAL_PORTS` without changing the set, and a
+//! configuration with two of them validates with the ports in that order.
+
+#![cfg(target_os = "macos")]
+
+extern crate objc;
+extern crate virtualization_rs;
+
+use virtualization_rs::base::{Id, NSFileHandle};
+use virtualization_rs::test_support::{self, TempDir};
+use virtualization_rs::virtualization::device::VZDeviceConfiguration;
+use virtualization_rs::virtualization::serial_port::{
+    SerialPortSet, SerialPortSetError, VZFileHandleSerialPortAttachment,
+    VZFileHandleSerialPortAttachmentBuilder, VZSerialPortAttachment, MAX_SERIAL_PORTS,
+};
+
+use std::fs::{File, OpenOptions};
+use std::os::unix::io::IntoRawFd;
+
+use objc::{msg_send, sel, sel_impl};
+
+/// An attachment reading from and writing to a file of its own, so no two share a backing.
+fn attachment(dir: &TempDir, name: &str) -> VZFileHandleSerialPortAttachment {
+    let open = |suffix: &str| -> File {
+        OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(dir.path().join(format!("{}.{}", name, suffix)))
+            .unwrap()
+    };
+    let handle = |file: File| NSFileHandle::init_with_file_descriptor(file.into_raw_fd(), true);
+    VZFileHandleSerialPortAttachmentBuilder::new()
+        .file_handle_for_reading(handle(open("in")))
+        .file_handle_for_writing(handle(open("out")))
+        .build()
+}
+
+/// Adds ports named `names`, returning their attachments' objects in the same order.
+fn add_all(dir: &TempDir, ports: &mut SerialPortSet, names: &[&str]) -> Vec<Id> {
+    names
+        .iter()
+        .map(|name| {
+            let attachment = attachment(dir, name);
+            let id = attachment.id();
+            ports.add(name, attachment).unwrap();
+            id
+        })
+        .collect()
+}
+
+fn device_map(names: &[&str]) -> Vec<(String, String)> {
+    names
+        .iter()
+        .enumerate()
+        .map(|(i, name)| (name.to_string(), format!("/dev/hvc{}", i)))
+        .collect()
+}
+
+#[test]
+fn ports_keep_the_order_they_were_added_in() {
+    let dir = TempDir::new("serial-port-set-order");
+    let mut ports = SerialPortSet::new();
+    assert!(ports.is_empty());
+    assert!(ports.guest_device_map().is_empty());
+
+    add_all(&dir, &mut ports, &["shell", "logs"]);
+    assert_eq!(ports.guest_device_map(), device_map(&["shell", "logs"]));
+
+    // Later ports never renumber earlier ones.
+    let names = ["shell", "logs", "agent", "a", "z"];
+    add_all(&dir, &mut ports, &names[2..]);
+    assert_eq!(ports.len(), names.len());
+    assert_eq!(ports.guest_device_map(), device_map(&names));
+    for (i, name) in names.iter().enumerate() {
+        assert_eq!(ports.guest_index(name), Some(i));
+    }
+    assert_eq!(ports.guest_index("missing"), None);
+}
+
+#[test]
+fn the_ports_are_handed_over_in_guest_order() {
+    let dir = TempDir::new("serial-port-set-into-ports");
+    let mut ports = SerialPortSet::new();
+    let attachments = add_all(&dir, &mut ports, &["shell", "logs", "agent"]);
+    let ports = ports.into_ports();
+    assert_eq!(ports.len(), attachments.len());
+    for (port, attachment) in ports.iter().zip(attachments) {
+        let attached: Id = unsafe { msg_send![port.id(), attachment] };
+        assert_eq!(attached, attachment);
+    }
+}
+
+#[test]
+fn a_name_is_added_once() {
+    let dir = TempDir::new("serial-port-set-duplicate");
+    let mut ports = SerialPortSet::new();
+    add_all(&dir, &mut ports, &["shell", "logs"]);
+    let error = ports
+        .add("shell", attachment(&dir, "shell-again"))
+        .err()
+        .unwrap();
+    assert_eq!(
+        error,
+        SerialPortSetError::DuplicateName("shell".to_string())
+    );
+    assert_eq!(
+        error.to_string(),
+        "a serial port named \"shell\" was already added"
+    );
+    assert_eq!(ports.guest_device_map(), device_map(&["shell", "logs"]));
+}
+
+#[test]
+fn the_port_past_the_limit_is_refused() {
+    let dir = TempDir::new("serial-port-set-limit");
+    let names: Vec<String> = (0..MAX_SERIAL_PORTS)
+        .map(|i| format!("port{}", i))
+        .collect();
+    let names: Vec<&str> = names.iter().map(String::as_str).collect();
+    let mut ports = SerialPortSet::new();
+    add_all(&dir, &mut ports, &names);
+    assert_eq!(ports.guest_index("port15"), Some(15));
+
+    let error = ports
+        .add("one-more", attachment(&dir, "one-more"))
+        .err()
+        .unwrap();
+    assert_eq!(error, SerialPortSetError::TooManyPorts { limit: 16 });
+    assert_eq!(error.to_string(), "at most 16 serial ports are supported");
+    assert_eq!(ports.len(), MAX_SERIAL_PORTS);
+    assert_eq!(ports.guest_index("one-more"), None);
+    assert_eq!(ports.guest_device_map(), device_map(&names));
+}
+
+#[test]
+fn a_configuration_with_two_ports_validates() {
+    let dir = TempDir::new("serial-port-set-config");
+    let mut ports = SerialPortSet::new();
+    add_all(&dir, &mut ports, &["shell", "logs"]);
+    assert_eq!(
+        ports.guest_device_map(),
+        [
+            ("shell".to_string(), "/dev/hvc0".to_string()),
+            ("logs".to_string(), "/dev/hvc1".to_string()),
+        ]
+    );
+    let conf = test_support::minimal_builder()
+        .boot_loader(test_support::garbage_linux_boot_loader(&dir))
+        .serial_ports(ports.into_ports())
+        .build();
+    assert_eq!(conf.device_count("serial_ports"), Some(2));
+    assert!(conf
+        .validate_with_error()
+        .unwrap_or_else(|e| panic!("{}", e)));
+}
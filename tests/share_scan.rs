@@ -0,0 +1,158 @@
+//! The share scan must refuse trees with links leading out of them, whether the link is absolute,
+//! relative or broken, accept links that stay inside, and refuse symbolic-link and dangerous
+//! roots.
+
+#![cfg(target_os = "macos")]
+
+extern crate virtualization_rs;
+
+use virtualization_rs::virtualization::directory_sharing::VZSharedDirectory;
+use virtualization_rs::virtualization::share_scan::{self, ShareError, SharePolicy, SymlinkPolicy};
+
+use std::fs;
+use std::os::unix::fs::symlink;
+use std::path::{Path, PathBuf};
+
+/// A scratch directory holding a `share` to scan and an `outside` next to it.
+struct Tree(PathBuf);
+
+impl Tree {
+    fn new(name: &str) -> Tree {
+        let path = std::env::temp_dir().join(format!(
+            "virtualization-rs-share-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(path.join("share/sub")).unwrap();
+        fs::create_dir_all(path.join("outside")).unwrap();
+        fs::write(path.join("share/sub/file"), b"inside").unwrap();
+        fs::write(path.join("outside/secret"), b"outside").unwrap();
+        // Links that stay inside, relative and absolute.
+        symlink("sub/file", path.join("share/relative")).unwrap();
+        symlink(path.join("share/sub"), path.join("share/absolute")).unwrap();
+        symlink("../sub", path.join("share/sub/parent")).unwrap();
+        Tree(path)
+    }
+
+    fn share(&self) -> PathBuf {
+        self.0.join("share")
+    }
+
+    fn link(&self, target: &Path, at: &str) -> PathBuf {
+        let link = self.share().join(at);
+        symlink(target, &link).unwrap();
+        fs::canonicalize(link.parent().unwrap())
+            .unwrap()
+            .join(link.file_name().unwrap())
+    }
+}
+
+impl Drop for Tree {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+fn refuse() -> SharePolicy {
+    SharePolicy::new(SymlinkPolicy::RefuseIfEscaping)
+}
+
+fn escaping(result: Result<share_scan::ShareScan, ShareError>) -> Vec<(PathBuf, bool)> {
+    match result {
+        Err(ShareError::EscapingLinks(links)) => {
+            links.into_iter().map(|l| (l.link, l.broken)).collect()
+        }
+        Err(e) => panic!("expected escaping links, got {}", e),
+        Ok(scan) => panic!("accepted {:?}", scan),
+    }
+}
+
+#[test]
+fn links_inside_are_accepted() {
+    let tree = Tree::new("inside");
+    tree.link(Path::new("sub/missing"), "broken-inside");
+    let scan = share_scan::scan(tree.share(), refuse()).unwrap();
+    assert_eq!(scan.root, fs::canonicalize(tree.share()).unwrap());
+    assert_eq!(scan.links_checked, 4);
+    assert!(scan.escaping.is_empty());
+}
+
+#[test]
+fn escaping_links_are_refused() {
+    let tree = Tree::new("escaping");
+    let absolute = tree.link(&tree.0.join("outside/secret"), "sub/ssh");
+    let relative = tree.link(Path::new("../../outside"), "sub/up");
+    assert_eq!(
+        escaping(share_scan::scan(tree.share(), refuse())),
+        vec![(absolute, false), (relative, false)]
+    );
+}
+
+#[test]
+fn broken_escaping_links_are_refused() {
+    let tree = Tree::new("broken");
+    let link = tree.link(&tree.0.join("outside/not-yet"), "later");
+    assert_eq!(
+        escaping(share_scan::scan(tree.share(), refuse())),
+        vec![(link, true)]
+    );
+}
+
+#[test]
+fn link_loops_do_not_hang() {
+    let tree = Tree::new("loop");
+    tree.link(Path::new("b"), "a");
+    tree.link(Path::new("a"), "b");
+    tree.link(Path::new("."), "self");
+    let scan = share_scan::scan(tree.share(), refuse()).unwrap();
+    assert_eq!(scan.links_checked, 6);
+}
+
+#[test]
+fn warn_only_reports_and_shares() {
+    let tree = Tree::new("warn");
+    let link = tree.link(&tree.0.join("outside"), "out");
+    let scan = share_scan::scan(tree.share(), SharePolicy::new(SymlinkPolicy::WarnOnly)).unwrap();
+    assert_eq!(scan.escaping.len(), 1);
+    assert_eq!(scan.escaping[0].link, link);
+    assert_eq!(
+        scan.escaping[0].target,
+        fs::canonicalize(tree.0.join("outside")).unwrap()
+    );
+}
+
+#[test]
+fn symlink_root_is_refused() {
+    let tree = Tree::new("root-link");
+    let root = tree.0.join("link-to-outside");
+    symlink(tree.0.join("outside"), &root).unwrap();
+    match share_scan::scan(&root, refuse()) {
+        Err(ShareError::RootIsSymlink { target, .. }) => {
+            assert_eq!(target, fs::canonicalize(tree.0.join("outside")).unwrap())
+        }
+        other => panic!("expected RootIsSymlink, got {:?}", other),
+    }
+    assert!(share_scan::scan(&root, SharePolicy::new(SymlinkPolicy::WarnOnly)).is_ok());
+}
+
+#[test]
+fn dangerous_roots_are_refused() {
+    for root in &["/", "/Users"] {
+        match share_scan::scan(root, refuse()) {
+            Err(ShareError::DangerousRoot(path)) => assert_eq!(path, Path::new(root)),
+            other => panic!("expected DangerousRoot for {}, got {:?}", root, other),
+        }
+    }
+}
+
+#[test]
+fn hardened_share_rescans() {
+    let tree = Tree::new("rescan");
+    let share = tree.share();
+    let dir = VZSharedDirectory::new_hardened(share.to_str().unwrap(), true, refuse()).unwrap();
+    assert!(dir.rescan().is_ok());
+    let link = tree.link(&tree.0.join("outside"), "added-later");
+    assert_eq!(escaping(dir.rescan()), vec![(link, false)]);
+    assert!(VZSharedDirectory::new_hardened(share.to_str().unwrap(), true, refuse()).is_err());
+}
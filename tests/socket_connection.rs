@@ -0,0 +1,282 @@
+//! Vsock connection descriptors: borrowed through `as_raw_fd`, duplicated by `dup_stream`,
+//! handed over by `into_stream` and closed by `close`, with a stream outliving the connection
+//! object without `EBADF` or lost data.
+//!
+//! The connection is a stand-in object holding one end of a socket pair, closing it on `close`
+//! or `dealloc` as the framework's connection does. `streams_outlive_a_real_guests_connection`
+//! is ignored: it needs `VIRTUALIZATION_RS_TEST_KERNEL` and `VIRTUALIZATION_RS_TEST_INITRD` for
+//! a guest that echoes on vsock port `VIRTUALIZATION_RS_TEST_VSOCK_PORT`, e.g. with
+//! `socat VSOCK-LISTEN:1234,fork EXEC:cat` started from its initrd.
+
+#![cfg(target_os = "macos")]
+
+extern crate libc;
+extern crate objc;
+extern crate virtualization_rs;
+
+use virtualization_rs::base::Id;
+use virtualization_rs::virtualization::boot_loader::VZLinuxBootLoaderBuilder;
+use virtualization_rs::virtualization::error::CompletionOutcome;
+use virtualization_rs::virtualization::socket_device::{
+    VZVirtioSocketConnection, VZVirtioSocketDeviceConfiguration,
+};
+use virtualization_rs::virtualization::virtual_machine::{
+    StartOutcome, VZVirtualMachine, VZVirtualMachineConfigurationBuilder,
+};
+use virtualization_rs::virtualization::vsock::VsockPort;
+
+use std::io::{self, Read, Write};
+use std::net::Shutdown;
+use std::os::unix::io::{AsRawFd, IntoRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Mutex, MutexGuard, Once};
+use std::thread;
+use std::time::Duration;
+
+use objc::declare::ClassDecl;
+use objc::runtime::{Class, Object, Sel};
+use objc::{class, msg_send, sel, sel_impl};
+
+const STAND_IN_CLASS: &str = "VirtualizationRsTestSocketConnection";
+const FD_IVAR: &str = "fd";
+const SOURCE_PORT: u32 = 1024;
+const DESTINATION_PORT: u32 = 5000;
+
+/// How often a stand-in closed its descriptor.
+static CLOSES: AtomicUsize = AtomicUsize::new(0);
+
+/// The tests check whether descriptor numbers are open, so none may open descriptors meanwhile.
+fn serial() -> MutexGuard<'static, ()> {
+    static LOCK: Mutex<()> = Mutex::new(());
+    LOCK.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+fn is_open(fd: RawFd) -> bool {
+    unsafe { libc::fcntl(fd, libc::F_GETFD) != -1 }
+}
+
+fn stand_in_class() -> &'static Class {
+    static REGISTER: Once = Once::new();
+    REGISTER.call_once(|| {
+        let mut decl = ClassDecl::new(STAND_IN_CLASS, class!(NSObject)).unwrap();
+        decl.add_ivar::<RawFd>(FD_IVAR);
+        unsafe {
+            decl.add_method(
+                sel!(fileDescriptor),
+                file_descriptor as extern "C" fn(&Object, Sel) -> RawFd,
+            );
+            decl.add_method(
+                sel!(sourcePort),
+                source_port as extern "C" fn(&Object, Sel) -> u32,
+            );
+            decl.add_method(
+                sel!(destinationPort),
+                destination_port as extern "C" fn(&Object, Sel) -> u32,
+            );
+            decl.add_method(sel!(close), close as extern "C" fn(&mut Object, Sel));
+            decl.add_method(sel!(dealloc), dealloc as extern "C" fn(&mut Object, Sel));
+        }
+        decl.register();
+    });
+    Class::get(STAND_IN_CLASS).unwrap()
+}
+
+extern "C" fn file_descriptor(this: &Object, _cmd: Sel) -> RawFd {
+    unsafe { *this.get_ivar::<RawFd>(FD_IVAR) }
+}
+
+extern "C" fn source_port(_this: &Object, _cmd: Sel) -> u32 {
+    SOURCE_PORT
+}
+
+extern "C" fn destination_port(_this: &Object, _cmd: Sel) -> u32 {
+    DESTINATION_PORT
+}
+
+extern "C" fn close(this: &mut Object, _cmd: Sel) {
+    unsafe {
+        let fd = *this.get_ivar::<RawFd>(FD_IVAR);
+        if fd != -1 {
+            libc::close(fd);
+            this.set_ivar::<RawFd>(FD_IVAR, -1);
+            CLOSES.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+}
+
+extern "C" fn dealloc(this: &mut Object, cmd: Sel) {
+    close(this, cmd);
+    unsafe {
+        let _: () = msg_send![super(this, class!(NSObject)), dealloc];
+    }
+}
+
+/// A stand-in connection owning one end of a socket pair, its descriptor and the other end.
+fn connection() -> (VZVirtioSocketConnection, RawFd, UnixStream) {
+    let (ours, peer) = UnixStream::pair().unwrap();
+    let fd = ours.into_raw_fd();
+    unsafe {
+        let obj: Id = msg_send![stand_in_class(), new];
+        (*obj).set_ivar::<RawFd>(FD_IVAR, fd);
+        let connection = VZVirtioSocketConnection::from_raw_connection(obj);
+        let _: () = msg_send![obj, release];
+        (connection, fd, peer)
+    }
+}
+
+/// Sends a megabyte each way through `stream` and `peer`, then checks that `peer` sees the end of
+/// the stream once `stream` shuts down.
+fn assert_round_trip(stream: &mut UnixStream, peer: &mut UnixStream) {
+    let payload: Vec<u8> = (0..1 << 20).map(|i| (i % 251) as u8).collect();
+    let mut writer = stream.try_clone().unwrap();
+    let sent = payload.clone();
+    let writing = thread::spawn(move || writer.write_all(&sent));
+    let mut received = vec![0; payload.len()];
+    peer.read_exact(&mut received).unwrap();
+    writing.join().unwrap().unwrap();
+    assert!(received == payload, "the peer received other bytes");
+
+    let mut echo = peer.try_clone().unwrap();
+    let sent = payload.clone();
+    let writing = thread::spawn(move || echo.write_all(&sent));
+    stream.read_exact(&mut received).unwrap();
+    writing.join().unwrap().unwrap();
+    assert!(received == payload, "the stream received other bytes");
+
+    stream.shutdown(Shutdown::Write).unwrap();
+    assert_eq!(peer.read(&mut [0; 1]).unwrap(), 0);
+}
+
+#[test]
+fn as_raw_fd_borrows_the_connections_descriptor() {
+    let _serial = serial();
+    let closes = CLOSES.load(Ordering::SeqCst);
+    let (connection, fd, _peer) = connection();
+    assert_eq!(connection.as_raw_fd(), fd);
+    assert_eq!(connection.file_descriptor(), fd);
+    assert_eq!(connection.source_port().get(), SOURCE_PORT);
+    assert_eq!(connection.destination_port().get(), DESTINATION_PORT);
+
+    drop(connection);
+    assert!(!is_open(fd));
+    assert_eq!(CLOSES.load(Ordering::SeqCst), closes + 1);
+}
+
+#[test]
+fn dup_stream_outlives_the_connection() {
+    let _serial = serial();
+    let (connection, fd, mut peer) = connection();
+    let mut stream = connection.dup_stream().unwrap();
+    assert_ne!(stream.as_raw_fd(), fd);
+    let flags = unsafe { libc::fcntl(stream.as_raw_fd(), libc::F_GETFD) };
+    assert_eq!(flags & libc::FD_CLOEXEC, libc::FD_CLOEXEC);
+
+    drop(connection);
+    assert!(!is_open(fd));
+    assert!(is_open(stream.as_raw_fd()));
+    assert_round_trip(&mut stream, &mut peer);
+}
+
+#[test]
+fn dropping_a_dup_stream_keeps_the_connection() {
+    let _serial = serial();
+    let (connection, fd, mut peer) = connection();
+    drop(connection.dup_stream().unwrap());
+    assert!(is_open(fd));
+
+    let mut stream = connection.into_stream().unwrap();
+    assert_round_trip(&mut stream, &mut peer);
+}
+
+#[test]
+fn into_stream_hands_the_connection_over() {
+    let _serial = serial();
+    let closes = CLOSES.load(Ordering::SeqCst);
+    let (connection, fd, mut peer) = connection();
+    let mut stream = connection.into_stream().unwrap();
+    // The connection's descriptor is closed once, before the stream is used.
+    assert!(!is_open(fd));
+    assert_eq!(CLOSES.load(Ordering::SeqCst), closes + 1);
+    assert_round_trip(&mut stream, &mut peer);
+}
+
+#[test]
+fn close_leaves_duplicates_open() {
+    let _serial = serial();
+    let (connection, fd, mut peer) = connection();
+    let mut stream = connection.dup_stream().unwrap();
+    connection.close().unwrap();
+    assert!(!is_open(fd));
+    assert_round_trip(&mut stream, &mut peer);
+}
+
+fn connect(vm: &VZVirtualMachine, port: VsockPort, into: bool) -> io::Result<UnixStream> {
+    let device = vm.socket_devices().remove(0);
+    for _ in 0..60 {
+        let (tx, rx) = mpsc::channel();
+        device.connect_to_port(port, move |outcome| {
+            let stream = match outcome {
+                // The connection object is released at the end of this closure either way.
+                CompletionOutcome::Success(connection) if into => Some(connection.into_stream()),
+                CompletionOutcome::Success(connection) => Some(connection.dup_stream()),
+                _ => None,
+            };
+            let _ = tx.send(stream);
+        });
+        if let Some(stream) = rx.recv().unwrap() {
+            return stream;
+        }
+        // The guest is not listening yet.
+        thread::sleep(Duration::from_secs(1));
+    }
+    panic!("nothing listens on vsock port {} of the guest", port);
+}
+
+#[test]
+#[ignore]
+fn streams_outlive_a_real_guests_connection() {
+    let var = |name: &str| std::env::var(format!("VIRTUALIZATION_RS_TEST_{}", name));
+    let (kernel, initrd, port) = match (var("KERNEL"), var("INITRD"), var("VSOCK_PORT")) {
+        (Ok(kernel), Ok(initrd), Ok(port)) => (kernel, initrd, port.parse().unwrap()),
+        _ => {
+            println!(
+                "streams_outlive_a_real_guests_connection skipped: VIRTUALIZATION_RS_TEST_KERNEL, \
+                 _INITRD or _VSOCK_PORT unset"
+            );
+            return;
+        }
+    };
+    let boot_loader = VZLinuxBootLoaderBuilder::new()
+        .kernel_url(kernel)
+        .initial_ramdisk_url(initrd)
+        .command_line("console=hvc0")
+        .build()
+        .unwrap();
+    let conf = VZVirtualMachineConfigurationBuilder::new()
+        .boot_loader(boot_loader)
+        .cpu_count(1)
+        .memory_size_mib(512)
+        .socket_devices(vec![VZVirtioSocketDeviceConfiguration::new()])
+        .build();
+    conf.validate_with_error().unwrap();
+    let vm = VZVirtualMachine::new_with_qos(conf, "socket-connection", None);
+    let (tx, rx) = mpsc::channel();
+    vm.start_with_deadline(Duration::from_secs(30), move |outcome| {
+        let _ = tx.send(matches!(outcome, StartOutcome::Started));
+    })
+    .unwrap();
+    assert!(rx.recv().unwrap(), "the guest did not start");
+
+    let payload: Vec<u8> = (0..1 << 20).map(|i| (i % 251) as u8).collect();
+    for &into in &[false, true] {
+        let mut stream = connect(&vm, port, into).unwrap();
+        let mut writer = stream.try_clone().unwrap();
+        let sent = payload.clone();
+        let writing = thread::spawn(move || writer.write_all(&sent));
+        let mut echoed = vec![0; payload.len()];
+        stream.read_exact(&mut echoed).unwrap();
+        writing.join().unwrap().unwrap();
+        assert!(echoed == payload, "the guest echoed other bytes");
+    }
+}
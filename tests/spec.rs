@@ -0,0 +1,402 @@
+//! Layered specs: each field merges as the `spec` module documents it, devices are matched by
+//! name and dropped by `remove: true`, three files fold in order, a type conflict or a name listed
+//! twice is reported with the file and the device, and a folded spec builds a configuration.
+
+#![cfg(target_os = "macos")]
+
+extern crate virtualization_rs;
+
+use virtualization_rs::profile::ProfileInputs;
+use virtualization_rs::spec::{
+    DeviceConflict, DeviceKind, DeviceSpec, NetworkAttachmentKind, SpecError, VmSpec,
+};
+use virtualization_rs::test_support::TempDir;
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+fn parse(text: &str) -> VmSpec {
+    VmSpec::from_yaml(text, "test.yaml").unwrap_or_else(|e| panic!("{}", e))
+}
+
+fn merge(base: &str, overlay: &str) -> Result<VmSpec, DeviceConflict> {
+    VmSpec::merge(parse(base), parse(overlay))
+}
+
+fn device(name: &str, kind: DeviceKind) -> DeviceSpec {
+    DeviceSpec {
+        name: name.to_string(),
+        kind: Some(kind),
+        ..DeviceSpec::default()
+    }
+}
+
+fn disk(name: &str, path: &str) -> DeviceSpec {
+    DeviceSpec {
+        path: Some(path.into()),
+        ..device(name, DeviceKind::Disk)
+    }
+}
+
+fn names(spec: &VmSpec) -> Vec<&str> {
+    spec.devices.iter().map(|d| d.name.as_str()).collect()
+}
+
+fn write(dir: &TempDir, name: &str, text: &str) -> PathBuf {
+    let path = dir.path().join(name);
+    fs::write(&path, text).unwrap();
+    path
+}
+
+const BASE: &str = "
+profile: linux-direct-kernel
+cpu_count: 2
+memory_mib: 2048
+inputs:
+  kernel: vm/vmlinuz
+  initrd: vm/initrd
+  disk: vm/disk.img
+devices:
+  - name: data
+    type: disk
+    path: vm/data.img
+  - name: uplink
+    type: network
+  - name: rng
+    type: entropy
+";
+
+#[test]
+fn scalars_are_replaced_when_the_overlay_sets_them() {
+    // (overlay, profile, cpu count, memory)
+    let cases: &[(&str, &str, usize, usize)] = &[
+        ("", "linux-direct-kernel", 2, 2048),
+        ("memory_mib: 8192", "linux-direct-kernel", 2, 8192),
+        ("cpu_count: 8", "linux-direct-kernel", 8, 2048),
+        (
+            "profile: linux-efi-cloud-image\ncpu_count: 4\nmemory_mib: 4096",
+            "linux-efi-cloud-image",
+            4,
+            4096,
+        ),
+    ];
+    for &(overlay, profile, cpu_count, memory_mib) in cases {
+        let spec = merge(BASE, overlay).unwrap();
+        assert_eq!(spec.profile.as_deref(), Some(profile), "{:?}", overlay);
+        assert_eq!(spec.cpu_count, Some(cpu_count), "{:?}", overlay);
+        assert_eq!(spec.memory_mib, Some(memory_mib), "{:?}", overlay);
+        assert_eq!(names(&spec), ["data", "uplink", "rng"], "{:?}", overlay);
+    }
+}
+
+#[test]
+fn inputs_are_replaced_one_by_one() {
+    let spec = merge(
+        BASE,
+        "inputs:\n  disk: prod/disk.img\n  command_line: quiet",
+    )
+    .unwrap();
+    assert_eq!(
+        spec.inputs,
+        ProfileInputs {
+            kernel: Some("vm/vmlinuz".into()),
+            initrd: Some("vm/initrd".into()),
+            command_line: Some("quiet".to_string()),
+            disk: Some("prod/disk.img".into()),
+            ..ProfileInputs::default()
+        }
+    );
+}
+
+#[test]
+fn devices_merge_by_name() {
+    // (overlay, devices after the merge)
+    let cases: Vec<(&str, Vec<DeviceSpec>)> = vec![
+        // A field replaced, in place; the type may be left out.
+        (
+            "devices:\n  - name: data\n    path: prod/data.img\n    read_only: true",
+            vec![
+                DeviceSpec {
+                    read_only: Some(true),
+                    ..disk("data", "prod/data.img")
+                },
+                device("uplink", DeviceKind::Network),
+                device("rng", DeviceKind::Entropy),
+            ],
+        ),
+        // A new name is added after the base's devices.
+        (
+            "devices:\n  - name: scratch\n    type: disk\n    path: scratch.img",
+            vec![
+                disk("data", "vm/data.img"),
+                device("uplink", DeviceKind::Network),
+                device("rng", DeviceKind::Entropy),
+                disk("scratch", "scratch.img"),
+            ],
+        ),
+        // The same type given again is no conflict.
+        (
+            "devices:\n  - name: uplink\n    type: network\n    attachment: nat\n    mac_address: 52:54:00:12:34:56",
+            vec![
+                disk("data", "vm/data.img"),
+                DeviceSpec {
+                    attachment: Some(NetworkAttachmentKind::Nat),
+                    mac_address: Some("52:54:00:12:34:56".to_string()),
+                    ..device("uplink", DeviceKind::Network)
+                },
+                device("rng", DeviceKind::Entropy),
+            ],
+        ),
+        // Tombstones drop their device, and are ignored for a name the base does not have.
+        (
+            "devices:\n  - name: uplink\n    remove: true\n  - name: missing\n    remove: true",
+            vec![
+                disk("data", "vm/data.img"),
+                device("rng", DeviceKind::Entropy),
+            ],
+        ),
+        // A tombstone needs no type, and ignores the other fields.
+        (
+            "devices:\n  - name: data\n    type: network\n    remove: true",
+            vec![
+                device("uplink", DeviceKind::Network),
+                device("rng", DeviceKind::Entropy),
+            ],
+        ),
+    ];
+    for (overlay, devices) in cases {
+        assert_eq!(
+            merge(BASE, overlay).unwrap().devices,
+            devices,
+            "{}",
+            overlay
+        );
+    }
+}
+
+#[test]
+fn a_device_changing_type_is_a_conflict() {
+    let conflict = merge(BASE, "devices:\n  - name: data\n    type: network").unwrap_err();
+    assert_eq!(
+        conflict,
+        DeviceConflict {
+            device: "data".to_string(),
+            base: DeviceKind::Disk,
+            overlay: DeviceKind::Network,
+        }
+    );
+    assert_eq!(
+        conflict.to_string(),
+        "device \"data\" is of type network here but disk in the layers below"
+    );
+}
+
+#[test]
+fn a_removed_device_can_come_back_with_another_type() {
+    let removed = merge(BASE, "devices:\n  - name: data\n    remove: true").unwrap();
+    let spec = VmSpec::merge(
+        removed,
+        parse("devices:\n  - name: data\n    type: balloon"),
+    )
+    .unwrap();
+    assert_eq!(names(&spec), ["uplink", "rng", "data"]);
+    assert_eq!(spec.devices[2], device("data", DeviceKind::Balloon));
+}
+
+#[test]
+fn three_layers_fold_in_order() {
+    let dir = TempDir::new("spec-layers");
+    let base = write(&dir, "base.yaml", BASE);
+    let staging = write(
+        &dir,
+        "staging.yaml",
+        "memory_mib: 4096\ndevices:\n  - name: data\n    path: staging/data.img\n  - name: debug\n    type: disk\n    path: debug.img\n",
+    );
+    // Six lines on top of the two below.
+    let prod = write(
+        &dir,
+        "prod.yaml",
+        "memory_mib: 8192\ninputs:\n  disk: prod/disk.img\ndevices:\n  - name: debug\n    remove: true\n",
+    );
+    assert_eq!(fs::read_to_string(&prod).unwrap().lines().count(), 6);
+
+    let spec = VmSpec::from_layers(&[base.clone(), staging.clone(), prod.clone()]).unwrap();
+    assert_eq!(spec.profile.as_deref(), Some("linux-direct-kernel"));
+    assert_eq!(spec.cpu_count, Some(2));
+    assert_eq!(spec.memory_mib, Some(8192));
+    assert_eq!(spec.inputs.kernel, Some("vm/vmlinuz".into()));
+    assert_eq!(spec.inputs.disk, Some("prod/disk.img".into()));
+    assert_eq!(
+        spec.devices,
+        [
+            disk("data", "staging/data.img"),
+            device("uplink", DeviceKind::Network),
+            device("rng", DeviceKind::Entropy),
+        ]
+    );
+
+    // Order matters: staging over prod keeps staging's memory and its debug disk.
+    let spec = VmSpec::from_layers(&[base.clone(), prod, staging]).unwrap();
+    assert_eq!(spec.memory_mib, Some(4096));
+    assert_eq!(names(&spec), ["data", "uplink", "rng", "debug"]);
+
+    // One layer folds to itself.
+    let single = VmSpec::from_layers(&[base]).unwrap();
+    assert_eq!(single, parse(BASE));
+    assert_eq!(VmSpec::from_layers(&[]).unwrap(), VmSpec::default());
+}
+
+#[test]
+fn a_conflict_names_the_file_and_the_device() {
+    let dir = TempDir::new("spec-conflict");
+    let base = write(&dir, "base.yaml", BASE);
+    let bad = write(
+        &dir,
+        "bad.yaml",
+        "devices:\n  - name: rng\n    type: balloon\n",
+    );
+    let error = VmSpec::from_layers(&[base, bad.clone()]).unwrap_err();
+    match &error {
+        SpecError::Conflict { file, conflict } => {
+            assert_eq!(file, &bad);
+            assert_eq!(conflict.device, "rng");
+        }
+        other => panic!("{:?}", other),
+    }
+    assert_eq!(
+        error.to_string(),
+        format!(
+            "{}: device \"rng\" is of type balloon here but entropy in the layers below",
+            bad.display()
+        )
+    );
+}
+
+#[test]
+fn a_name_listed_twice_in_one_file_is_refused() {
+    let error = VmSpec::from_yaml(
+        "devices:\n  - name: data\n    type: disk\n  - name: data\n    remove: true\n",
+        "twice.yaml",
+    )
+    .unwrap_err();
+    match &error {
+        SpecError::DuplicateDevice { file, device } => {
+            assert_eq!(file, Path::new("twice.yaml"));
+            assert_eq!(device, "data");
+        }
+        other => panic!("{:?}", other),
+    }
+    assert_eq!(
+        error.to_string(),
+        "twice.yaml: device \"data\" is listed twice"
+    );
+}
+
+#[test]
+fn unreadable_and_malformed_layers_name_their_file() {
+    let dir = TempDir::new("spec-malformed");
+    let missing = dir.path().join("missing.yaml");
+    match VmSpec::from_layers(std::slice::from_ref(&missing)).unwrap_err() {
+        SpecError::Io { file, .. } => assert_eq!(file, missing),
+        other => panic!("{:?}", other),
+    }
+    for (name, text) in [
+        ("unknown-field.yaml", "memory: 2048\n"),
+        (
+            "unknown-type.yaml",
+            "devices:\n  - name: gpu\n    type: gpu\n",
+        ),
+        ("not-a-map.yaml", "- profile\n"),
+    ] {
+        let path = write(&dir, name, text);
+        match VmSpec::from_layers(std::slice::from_ref(&path)).unwrap_err() {
+            SpecError::Parse { file, .. } => assert_eq!(file, path),
+            other => panic!("{}: {:?}", name, other),
+        }
+    }
+    // An empty file is an empty layer.
+    let empty = write(&dir, "empty.yaml", "\n");
+    assert_eq!(VmSpec::from_layers(&[empty]).unwrap(), VmSpec::default());
+}
+
+#[test]
+fn a_folded_spec_builds_a_configuration() {
+    let dir = TempDir::new("spec-build");
+    let kernel = dir.garbage("vmlinuz");
+    let initrd = dir.garbage("initrd");
+    let root = dir.disk_image("disk.img", 1024 * 1024);
+    let data = dir.disk_image("data.img", 1024 * 1024);
+    let base = write(
+        &dir,
+        "base.yaml",
+        &format!(
+            "profile: linux-direct-kernel\ncpu_count: 1\nmemory_mib: 512\ninputs:\n  kernel: {}\n  initrd: {}\n  disk: {}\ndevices:\n  - name: data\n    type: disk\n    path: {}\n  - name: uplink\n    type: network\n    mac_address: 52:54:00:12:34:56\n  - name: balloon\n    type: balloon\n",
+            kernel.display(),
+            initrd.display(),
+            root.display(),
+            data.display()
+        ),
+    );
+    let overlay = write(
+        &dir,
+        "overlay.yaml",
+        "memory_mib: 1024\ndevices:\n  - name: data\n    read_only: true\n",
+    );
+    let spec = VmSpec::from_layers(&[base, overlay]).unwrap();
+    let conf = spec.build().unwrap_or_else(|e| panic!("{}", e)).build();
+    assert_eq!(conf.cpu_count(), 1);
+    assert_eq!(conf.memory_size(), 1024 * 1024 * 1024);
+    // The profile's root disk and NAT network, then the spec's.
+    assert_eq!(conf.device_count("storage_devices"), Some(2));
+    assert_eq!(conf.device_count("network_devices"), Some(2));
+    assert_eq!(conf.device_count("memory_balloon_devices"), Some(1));
+    assert_eq!(conf.device_count("entropy_devices"), Some(1));
+    assert!(conf
+        .network_mac_addresses()
+        .contains(&"52:54:00:12:34:56".to_string()));
+    assert!(conf
+        .validate_with_error()
+        .unwrap_or_else(|e| panic!("{}", e)));
+}
+
+#[test]
+fn an_incomplete_spec_does_not_build() {
+    let error = |text: &str| parse(text).build().err().unwrap().to_string();
+    assert_eq!(error("cpu_count: 1"), "the spec names no profile");
+    assert_eq!(
+        error("profile: windows"),
+        "unknown guest profile \"windows\""
+    );
+    assert_eq!(
+        error("profile: linux-direct-kernel"),
+        "linux-direct-kernel needs the kernel; linux-direct-kernel needs the initial ramdisk"
+    );
+
+    let dir = TempDir::new("spec-incomplete");
+    let inputs = format!(
+        "profile: linux-direct-kernel\ninputs:\n  kernel: {}\n  initrd: {}\n",
+        dir.garbage("vmlinuz").display(),
+        dir.garbage("initrd").display()
+    );
+    assert_eq!(
+        error(&format!(
+            "{}devices:\n  - name: data\n    path: data.img\n",
+            inputs
+        )),
+        "device \"data\": no layer gives its type"
+    );
+    assert_eq!(
+        error(&format!(
+            "{}devices:\n  - name: data\n    type: disk\n",
+            inputs
+        )),
+        "device \"data\": a disk needs a path"
+    );
+    assert_eq!(
+        error(&format!(
+            "{}devices:\n  - name: uplink\n    type: network\n    mac_address: nope\n",
+            inputs
+        )),
+        "device \"uplink\": \"nope\" is not a MAC address"
+    );
+}
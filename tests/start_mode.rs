@@ -0,0 +1,81 @@
+//! The `Mac*` start modes are refused for machines that are not macOS guests before anything is
+//! sent to the framework, so these run on any Mac without the virtualization entitlement.
+
+#![cfg(target_os = "macos")]
+
+extern crate virtualization_rs;
+
+use virtualization_rs::base::DispatchQueue;
+use virtualization_rs::features::HostCapabilities;
+use virtualization_rs::test_support::{self, TempDir};
+use virtualization_rs::virtualization::lifecycle::Lifecycle;
+use virtualization_rs::virtualization::virtual_machine::{
+    StartMode, StartModeError, VZVirtualMachine, VZVirtualMachineConfiguration,
+};
+
+const MAC_MODES: [StartMode; 2] = [StartMode::MacRecovery, StartMode::MacDFU];
+
+fn assert_refused(conf: VZVirtualMachineConfiguration, boot_loader: &str) {
+    let queue = DispatchQueue::new("start-mode-test");
+    let vm = VZVirtualMachine::new(conf, queue.id());
+    for &mode in &MAC_MODES {
+        let error = vm
+            .start_with_mode(mode, |_| panic!("completion called"))
+            .unwrap_err();
+        assert_eq!(
+            error,
+            StartModeError::WrongGuest {
+                mode,
+                boot_loader: boot_loader.to_string(),
+            }
+        );
+        assert_eq!(vm.lifecycle(), Lifecycle::Created);
+    }
+}
+
+#[test]
+fn linux_guest_refuses_mac_modes() {
+    let dir = TempDir::new("start-mode-linux");
+    assert_refused(
+        test_support::minimal_linux_config(&dir),
+        "VZLinuxBootLoader",
+    );
+}
+
+#[test]
+fn efi_guest_refuses_mac_modes() {
+    if !HostCapabilities::detect().supports_class("VZEFIBootLoader") {
+        println!("efi_guest_refuses_mac_modes skipped: no VZEFIBootLoader before macOS 13");
+        return;
+    }
+    let dir = TempDir::new("start-mode-efi");
+    assert_refused(test_support::minimal_efi_config(&dir), "VZEFIBootLoader");
+}
+
+#[test]
+fn machine_without_boot_loader_refuses_mac_modes() {
+    assert_refused(test_support::minimal_builder().build(), "-");
+}
+
+#[test]
+fn errors_name_the_incompatibility() {
+    let error = StartModeError::WrongGuest {
+        mode: StartMode::MacRecovery,
+        boot_loader: "VZLinuxBootLoader".to_string(),
+    };
+    assert_eq!(
+        error.to_string(),
+        "start into macOS Recovery needs a macOS guest booted by VZMacOSBootLoader, this machine \
+         boots with VZLinuxBootLoader"
+    );
+    let error = StartModeError::Unavailable {
+        mode: StartMode::MacDFU,
+        option: "setForceDFU:",
+        requirement: "macOS 14",
+    };
+    assert_eq!(
+        error.to_string(),
+        "start into DFU mode needs setForceDFU: of VZMacOSVirtualMachineStartOptions, which this \
+         host lacks before macOS 14"
+    );
+}
@@ -0,0 +1,192 @@
+//! Why a virtual machine stopped, settled from scripted sequences of what the wrappers and the
+//! delegate report: the precedence of overlapping causes, one terminal event per run, and the
+//! same reason in `last_stop_reason`, the error event stream and the timeline.
+
+#![cfg(target_os = "macos")]
+
+extern crate virtualization_rs;
+
+use virtualization_rs::base::NSError;
+use virtualization_rs::test_support::{self, TempDir};
+use virtualization_rs::timeline::TimelineEventKind;
+use virtualization_rs::virtualization::error::VZError;
+use virtualization_rs::virtualization::error_events::ErrorEvent;
+use virtualization_rs::virtualization::lifecycle::{StopReason, StopSignal};
+use virtualization_rs::virtualization::virtual_machine::VZVirtualMachine;
+
+use StopSignal::*;
+
+fn crash(code: i32) -> StopSignal {
+    StoppedWithError(VZError(NSError::posix(code)))
+}
+
+/// What one script left behind: the reason, and the reasons of the terminal events and of the
+/// timeline's stops, as labels.
+struct Outcome {
+    reason: Option<StopReason>,
+    terminal: Vec<&'static str>,
+    timeline: Vec<&'static str>,
+}
+
+fn run(dir: &TempDir, script: Vec<StopSignal>) -> Outcome {
+    let vm = VZVirtualMachine::new_with_qos(
+        test_support::minimal_linux_config(dir),
+        "stop-reason",
+        None,
+    );
+    let timeline = vm.record_timeline();
+    let events = vm.error_events();
+    for signal in script {
+        vm.stop_signal(signal);
+    }
+    let terminal = std::iter::from_fn(|| events.try_recv())
+        .filter_map(|event| match event {
+            ErrorEvent::GuestStopped { clean, reason, .. } => {
+                assert_eq!(clean, reason.is_clean(), "{}", reason);
+                Some(reason.label())
+            }
+            ErrorEvent::Error(_) => None,
+        })
+        .collect();
+    let timeline = timeline
+        .events()
+        .into_iter()
+        .filter_map(|event| match event.kind {
+            TimelineEventKind::Stopped { reason } => Some(reason),
+            _ => None,
+        })
+        .collect();
+    Outcome {
+        reason: vm.last_stop_reason(),
+        terminal,
+        timeline,
+    }
+}
+
+#[test]
+fn overlapping_causes_settle_by_precedence() {
+    let dir = TempDir::new("stop-reason");
+    let cases = vec![
+        (
+            "the guest shuts down",
+            vec![GuestStopped],
+            "guest-initiated",
+        ),
+        (
+            "the guest complies with a request",
+            vec![Requested, GuestStopped],
+            "host-requested",
+        ),
+        (
+            "a forced stop",
+            vec![ForceSent, ForceCompleted { stopped: true }],
+            "forced",
+        ),
+        (
+            "a request, then a forced stop after the timeout",
+            vec![Requested, ForceSent, ForceCompleted { stopped: true }],
+            "forced",
+        ),
+        (
+            "the guest stops on its own while being forced",
+            vec![ForceSent, GuestStopped, ForceCompleted { stopped: true }],
+            "forced",
+        ),
+        (
+            "a request answered while the forced stop is in flight",
+            vec![Requested, ForceSent, GuestStopped],
+            "forced",
+        ),
+        (
+            "a forced stop that failed, then the requested stop",
+            vec![
+                Requested,
+                ForceSent,
+                ForceCompleted { stopped: false },
+                GuestStopped,
+            ],
+            "host-requested",
+        ),
+        (
+            "a crash while being forced",
+            vec![Requested, ForceSent, crash(5)],
+            "crashed",
+        ),
+        (
+            "a forced stop completing after the guest stopped",
+            vec![GuestStopped, ForceSent, ForceCompleted { stopped: true }],
+            "guest-initiated",
+        ),
+        (
+            "an error after the stop settled",
+            vec![Requested, GuestStopped, crash(5)],
+            "host-requested",
+        ),
+    ];
+    for (description, script, expected) in cases {
+        let outcome = run(&dir, script);
+        let reason = outcome
+            .reason
+            .unwrap_or_else(|| panic!("{}: no reason", description));
+        assert_eq!(reason.label(), expected, "{}", description);
+        assert_eq!(outcome.terminal, [expected], "{}", description);
+        assert_eq!(outcome.timeline, [expected], "{}", description);
+    }
+}
+
+#[test]
+fn a_crash_keeps_its_error() {
+    let dir = TempDir::new("stop-reason-crash");
+    match run(&dir, vec![crash(5)]).reason {
+        Some(StopReason::Crashed(e)) => assert_eq!(e.ns_error().code(), 5),
+        other => panic!("expected a crash, got {:?}", other),
+    }
+}
+
+#[test]
+fn causes_without_a_stop_settle_nothing() {
+    let dir = TempDir::new("stop-reason-none");
+    let outcome = run(
+        &dir,
+        vec![Requested, ForceSent, ForceCompleted { stopped: false }],
+    );
+    assert!(outcome.reason.is_none());
+    assert!(outcome.terminal.is_empty());
+    assert!(outcome.timeline.is_empty());
+}
+
+#[test]
+fn a_start_begins_a_new_run() {
+    let dir = TempDir::new("stop-reason-runs");
+    let outcome = run(
+        &dir,
+        vec![
+            ForceSent,
+            ForceCompleted { stopped: true },
+            StartSent,
+            // The new run ends on its own.
+            GuestStopped,
+        ],
+    );
+    assert_eq!(outcome.reason.map(|r| r.label()), Some("guest-initiated"));
+    // The stream ended with the first run; the timeline records both.
+    assert_eq!(outcome.terminal, ["forced"]);
+    assert_eq!(outcome.timeline, ["forced", "guest-initiated"]);
+}
+
+#[test]
+fn the_reason_survives_the_next_start_until_the_next_stop() {
+    let dir = TempDir::new("stop-reason-last");
+    let outcome = run(&dir, vec![Requested, GuestStopped, StartSent, Requested]);
+    assert_eq!(outcome.reason.map(|r| r.label()), Some("host-requested"));
+}
+
+#[test]
+fn reasons_display_their_label() {
+    assert_eq!(StopReason::GuestInitiated.to_string(), "guest-initiated");
+    assert_eq!(StopReason::Forced.to_string(), "forced");
+    assert!(StopReason::HostRequested.is_clean());
+    assert!(!StopReason::Forced.is_clean());
+    let crashed = StopReason::Crashed(VZError(NSError::posix(5)));
+    assert!(crashed.to_string().starts_with("crashed: "), "{}", crashed);
+}
@@ -0,0 +1,249 @@
+//! Strict mode: each misuse it checks panics with its documented message, and with strict mode
+//! off the same calls fail or go through as before.
+
+#![cfg(target_os = "macos")]
+
+extern crate objc;
+extern crate virtualization_rs;
+
+use virtualization_rs::base::{DispatchQueue, NIL};
+use virtualization_rs::strict::{strict_mode, PANIC_PREFIX};
+use virtualization_rs::virtualization::boot_loader::VZLinuxBootLoaderBuilder;
+use virtualization_rs::virtualization::lifecycle::LifecycleError;
+use virtualization_rs::virtualization::network_device::{
+    VZMACAddress, VZNATNetworkDeviceAttachment, VZVirtioNetworkDeviceConfiguration,
+};
+use virtualization_rs::virtualization::virtual_machine::{
+    VZVirtualMachine, VZVirtualMachineConfigurationBuilder,
+};
+
+use std::any::Any;
+use std::fs::{self, File};
+use std::io::Write;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::PathBuf;
+use std::sync::{Mutex, MutexGuard};
+
+use objc::runtime::Class;
+use objc::{sel, sel_impl};
+
+/// Strict mode is process-wide, so the tests take turns, each with the mode it needs.
+struct Strict {
+    _lock: MutexGuard<'static, ()>,
+}
+
+impl Strict {
+    fn set(enabled: bool) -> Strict {
+        static LOCK: Mutex<()> = Mutex::new(());
+        let lock = LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        strict_mode(enabled);
+        Strict { _lock: lock }
+    }
+}
+
+impl Drop for Strict {
+    fn drop(&mut self) {
+        strict_mode(false);
+    }
+}
+
+/// A scratch directory with kernel and initrd files that validate but do not boot.
+struct Scratch(PathBuf);
+
+impl Scratch {
+    fn new(name: &str) -> Scratch {
+        let path = std::env::temp_dir().join(format!(
+            "virtualization-rs-strict-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).unwrap();
+        for name in &["vmlinuz", "initrd"] {
+            File::create(path.join(name))
+                .unwrap()
+                .write_all(&[0x5a; 4096])
+                .unwrap();
+        }
+        Scratch(path)
+    }
+
+    fn builder(&self) -> VZVirtualMachineConfigurationBuilder {
+        let boot_loader = VZLinuxBootLoaderBuilder::new()
+            .kernel_url(self.0.join("vmlinuz").to_str().unwrap())
+            .initial_ramdisk_url(self.0.join("initrd").to_str().unwrap())
+            .command_line("console=hvc0")
+            .build()
+            .unwrap();
+        VZVirtualMachineConfigurationBuilder::new()
+            .boot_loader(boot_loader)
+            .cpu_count(1)
+            .memory_size(512 * 1024 * 1024)
+    }
+
+    fn vm(&self) -> VZVirtualMachine {
+        VZVirtualMachine::new_with_qos(self.builder().build(), "strict-test", None)
+    }
+}
+
+impl Drop for Scratch {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+/// The message `f` panicked with, without the prefix every strict mode message starts with.
+fn strict_panic<R, F: FnOnce() -> R>(f: F) -> String {
+    let payload = match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(_) => panic!("expected a strict mode panic"),
+        Err(payload) => payload,
+    };
+    let message = message(&*payload);
+    match message.strip_prefix(PANIC_PREFIX) {
+        Some(rest) => rest.to_string(),
+        None => panic!("not a strict mode panic: {}", message),
+    }
+}
+
+fn message(payload: &(dyn Any + Send)) -> String {
+    if let Some(s) = payload.downcast_ref::<String>() {
+        s.clone()
+    } else if let Some(s) = payload.downcast_ref::<&str>() {
+        s.to_string()
+    } else {
+        String::new()
+    }
+}
+
+#[test]
+fn state_off_the_vm_queue() {
+    let _strict = Strict::set(true);
+    let scratch = Scratch::new("state");
+    let vm = scratch.vm();
+    assert_eq!(
+        strict_panic(|| unsafe { vm.state() }),
+        "VZVirtualMachine::state called off the virtual machine's queue \"strict-test\"; call it \
+         from a callback on vm.queue() or inside vm.queue().exec_sync"
+    );
+    let target = vm.clone();
+    vm.queue().exec_sync(move || unsafe { target.state() });
+}
+
+#[test]
+fn exec_sync_on_its_own_queue() {
+    let _strict = Strict::set(true);
+    let queue = DispatchQueue::new("strict-sync");
+    let inner = queue.clone();
+    // The panic must not unwind out of the block into libdispatch.
+    let message = queue.exec_sync(move || strict_panic(|| inner.exec_sync(|| ())));
+    assert_eq!(
+        message,
+        "DispatchQueue::exec_sync called on queue \"strict-sync\" itself, which deadlocks; call \
+         it from another thread or queue"
+    );
+}
+
+#[test]
+fn frozen_configuration() {
+    let scratch = Scratch::new("frozen");
+    let network = VZVirtioNetworkDeviceConfiguration::new(VZNATNetworkDeviceAttachment::new());
+    let mut kept = network.clone();
+    let _vm = VZVirtualMachine::new_with_qos(
+        scratch.builder().network_device(network).build(),
+        "strict-test",
+        None,
+    );
+    let mac = || VZMACAddress::random_locally_administered_address();
+
+    let strict = Strict::set(false);
+    assert!(kept.set_mac_address(mac()).is_err());
+    drop(strict);
+
+    let _strict = Strict::set(true);
+    assert_eq!(
+        strict_panic(|| kept.set_mac_address(mac())),
+        "VZVirtioNetworkDeviceConfiguration::set_mac_address called on a configuration already \
+         used by a virtual machine; build a new configuration instead"
+    );
+}
+
+#[test]
+fn double_start() {
+    let scratch = Scratch::new("start");
+    let vm = scratch.vm();
+    let name = vm.display_name();
+    // The first start stays in flight: its completion waits for the queue, blocked here.
+    let (hold, release) = std::sync::mpsc::channel::<()>();
+    vm.queue().exec_async(move || {
+        let _ = release.recv();
+    });
+    vm.start(|_| {}).unwrap();
+
+    let strict = Strict::set(false);
+    assert!(matches!(
+        vm.start(|_| {}),
+        Err(LifecycleError::AlreadyStarted)
+    ));
+    drop(strict);
+
+    let _strict = Strict::set(true);
+    assert_eq!(
+        strict_panic(|| vm.start(|_| {})),
+        format!(
+            "start of {} refused: the virtual machine is already started; use start_or_join to \
+             share the start in flight",
+            name
+        )
+    );
+    drop(hold);
+}
+
+#[test]
+fn misaligned_memory_size() {
+    let scratch = Scratch::new("memory");
+    let misaligned = 512 * 1024 * 1024 + 4096;
+
+    let strict = Strict::set(false);
+    let conf = scratch.builder().memory_size(misaligned).build();
+    assert_eq!(conf.memory_size(), 512 * 1024 * 1024);
+    drop(strict);
+
+    let _strict = Strict::set(true);
+    assert_eq!(
+        strict_panic(|| scratch.builder().memory_size(misaligned)),
+        "memory size 536875008 is not a multiple of 1 MiB; the nearest valid sizes are \
+         536870912 and 537919488; use memory_size_mib or memory_size_gib"
+    );
+}
+
+#[test]
+fn nil_queue() {
+    let _strict = Strict::set(true);
+    let scratch = Scratch::new("nil");
+    let conf = scratch.builder().build();
+    assert_eq!(
+        strict_panic(|| VZVirtualMachine::new(conf, NIL)),
+        "the queue passed to VZVirtualMachine::new wraps a nil object; it came from a \
+         constructor that failed or a pointer that was released"
+    );
+}
+
+#[test]
+fn unavailable_api() {
+    let _strict = Strict::set(true);
+    let scratch = Scratch::new("available");
+    let vm = scratch.vm();
+    let available = Class::get("VZVirtualMachine")
+        .unwrap()
+        .instance_method(sel!(stopWithCompletionHandler:))
+        .is_some();
+    if available {
+        // The machine never started, so the stop fails through its completion handler.
+        vm.stop(|_| {}).unwrap();
+    } else {
+        assert!(strict_panic(|| vm.stop(|_| {})).starts_with(
+            "VZVirtualMachine::stop needs macOS 12, and this host's Virtualization.framework \
+             lacks -[VZVirtualMachine stopWithCompletionHandler:]"
+        ));
+    }
+}
@@ -0,0 +1,239 @@
+//! Teardown registry bookkeeping against `FakeVm`s: a machine stays registered while its run
+//! goes on, and is forgotten once it stops, once it is dropped, which the registry does not
+//! prevent, or once its guard is dropped. The policy sequence runs against fakes whose guest
+//! stops, ignores the request or hangs, and an ignored test raises `SIGTERM` in a child process.
+//!
+//! Tests run in parallel and share the registry, so each only looks for its own machine.
+
+#![cfg(target_os = "macos")]
+
+extern crate virtualization_rs;
+
+use virtualization_rs::fake_vm::{FakeOp, FakeReply, FakeVm};
+use virtualization_rs::teardown::{self, TeardownGuard, TeardownPolicy};
+use virtualization_rs::virtualization::error::CompletionOutcome;
+use virtualization_rs::virtualization::handle::{VirtualMachineHandle, WeakHandle};
+use virtualization_rs::virtualization::lifecycle::StopReason;
+use virtualization_rs::virtualization::virtual_machine::{VZVirtualMachineState, VmId};
+
+use std::io::{BufRead, BufReader};
+use std::os::unix::process::ExitStatusExt;
+use std::process::{Command, Stdio};
+use std::sync::mpsc::channel;
+use std::thread;
+use std::time::{Duration, Instant};
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The grace period of [`policy`], long enough to tell a force stop after it from one before.
+const GRACE: Duration = Duration::from_millis(500);
+
+/// Set for [`sigterm_child`] by [`sigterm_tears_registered_machines_down`].
+const CHILD_ENV: &str = "VIRTUALIZATION_RS_TEST_TEARDOWN_CHILD";
+const CHILD_READY: &str = "teardown child registered";
+
+fn is_registered(id: VmId) -> bool {
+    teardown::registered().contains(&id)
+}
+
+/// Waits up to [`TIMEOUT`] for the registry to forget `id`, since the end of a run reaches it
+/// after the completion handler of the stop.
+fn forgotten(id: VmId) -> bool {
+    let deadline = Instant::now() + TIMEOUT;
+    while is_registered(id) {
+        if Instant::now() >= deadline {
+            return false;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    true
+}
+
+/// Runs `op` on `vm` and waits for it to succeed.
+fn succeeds<F>(vm: &FakeVm, op: F)
+where
+    F: FnOnce(&FakeVm, Box<dyn FnOnce(CompletionOutcome) + Send>),
+{
+    let (sender, outcome) = channel();
+    op(
+        vm,
+        Box::new(move |outcome| {
+            let _ = sender.send(matches!(outcome, CompletionOutcome::Success(_)));
+        }),
+    );
+    assert_eq!(outcome.recv_timeout(TIMEOUT), Ok(true));
+}
+
+fn policy() -> TeardownPolicy {
+    TeardownPolicy {
+        request_stop_timeout: GRACE,
+        force_stop_timeout: Duration::from_secs(1),
+    }
+}
+
+/// Starts `vm` and shuts it down with [`policy`]; returns whether it stopped, and how long that
+/// took.
+fn shut_down(vm: &FakeVm) -> (bool, Duration) {
+    succeeds(vm, |vm, done| vm.start(done).unwrap());
+    let started = Instant::now();
+    let stopped = teardown::shutdown(vm, &policy());
+    (stopped, started.elapsed())
+}
+
+#[test]
+fn a_machine_is_forgotten_once_its_run_ends() {
+    let vm = FakeVm::new("teardown-stopped");
+    let _guard = TeardownGuard::register(&vm, TeardownPolicy::default()).unwrap();
+    assert!(is_registered(vm.vm_id()));
+
+    succeeds(&vm, |vm, done| vm.start(done).unwrap());
+    assert!(is_registered(vm.vm_id()));
+    succeeds(&vm, |vm, done| vm.stop(done).unwrap());
+    assert!(forgotten(vm.vm_id()));
+
+    // Registering again covers the next run.
+    let _guard = TeardownGuard::register(&vm, TeardownPolicy::default()).unwrap();
+    succeeds(&vm, |vm, done| vm.start(done).unwrap());
+    assert!(is_registered(vm.vm_id()));
+    vm.guest_shutdown();
+    assert!(forgotten(vm.vm_id()));
+}
+
+#[test]
+fn the_registry_does_not_keep_a_machine_alive() {
+    let vm = FakeVm::new("teardown-dropped");
+    let id = vm.vm_id();
+    let weak = vm.downgrade();
+    let guard = TeardownGuard::register(&vm, TeardownPolicy::default()).unwrap();
+    succeeds(&vm, |vm, done| vm.start(done).unwrap());
+    let queue = vm.queue().clone();
+    let clone = vm.clone();
+    drop(vm);
+    assert!(is_registered(id));
+    drop(clone);
+    // The blocks that answered the start hold the machine until they return.
+    queue.exec_sync(|| ());
+    assert!(weak.upgrade().is_none());
+    assert!(!is_registered(id));
+    drop(guard);
+}
+
+#[test]
+fn dropping_the_guard_unregisters() {
+    let vm = FakeVm::new("teardown-guard");
+    let guard = TeardownGuard::register(&vm, TeardownPolicy::default()).unwrap();
+    let other = TeardownGuard::register(&vm, TeardownPolicy::default()).unwrap();
+    drop(guard);
+    // The other registration still covers it.
+    assert!(is_registered(vm.vm_id()));
+    drop(other);
+    assert!(!is_registered(vm.vm_id()));
+}
+
+#[test]
+fn a_guest_that_stops_is_not_forced() {
+    let vm = FakeVm::new("teardown-cooperative").latency(FakeOp::RequestStop, GRACE / 10);
+    let (stopped, took) = shut_down(&vm);
+    assert!(stopped);
+    assert!(took < GRACE, "{:?}", took);
+    // The force stop armed for the end of the grace period was withdrawn.
+    thread::sleep(GRACE * 2);
+    assert_eq!(vm.calls(), [FakeOp::Start, FakeOp::RequestStop]);
+    assert!(matches!(
+        vm.last_stop_reason(),
+        Some(StopReason::HostRequested)
+    ));
+}
+
+#[test]
+fn a_guest_that_ignores_the_request_is_forced_after_the_grace_period() {
+    let vm = FakeVm::new("teardown-ignored").reply(FakeOp::RequestStop, FakeReply::Hang);
+    let (stopped, took) = shut_down(&vm);
+    assert!(stopped);
+    assert!(took >= GRACE, "{:?}", took);
+    assert_eq!(
+        vm.calls(),
+        [FakeOp::Start, FakeOp::RequestStop, FakeOp::Stop]
+    );
+    assert_eq!(
+        vm.peek_state(),
+        VZVirtualMachineState::VZVirtualMachineStateStopped
+    );
+    assert!(matches!(vm.last_stop_reason(), Some(StopReason::Forced)));
+}
+
+#[test]
+fn a_refused_request_is_forced_right_away() {
+    let vm = FakeVm::new("teardown-refused").reply(FakeOp::RequestStop, FakeReply::Cancel);
+    let (stopped, took) = shut_down(&vm);
+    assert!(stopped);
+    assert!(took < GRACE, "{:?}", took);
+    assert_eq!(
+        vm.calls(),
+        [FakeOp::Start, FakeOp::RequestStop, FakeOp::Stop]
+    );
+}
+
+#[test]
+fn a_machine_that_does_not_stop_is_given_up_on() {
+    let vm = FakeVm::new("teardown-hung")
+        .reply(FakeOp::RequestStop, FakeReply::Hang)
+        .reply(FakeOp::Stop, FakeReply::Hang);
+    let (stopped, took) = shut_down(&vm);
+    assert!(!stopped);
+    let policy = policy();
+    assert!(
+        took >= policy.request_stop_timeout + policy.force_stop_timeout,
+        "{:?}",
+        took
+    );
+}
+
+/// Run by [`sigterm_tears_registered_machines_down`] in a child process; does nothing when run
+/// directly.
+#[test]
+#[ignore]
+fn sigterm_child() {
+    if std::env::var_os(CHILD_ENV).is_none() {
+        return;
+    }
+    let vm = FakeVm::new("teardown-sigterm");
+    let _guard = TeardownGuard::register(&vm, policy()).unwrap();
+    succeeds(&vm, |vm, done| vm.start(done).unwrap());
+    let _observation = vm
+        .on_first_transition_to(VZVirtualMachineState::VZVirtualMachineStateStopped, || {
+            println!("stopped")
+        });
+    println!("{}", CHILD_READY);
+    unsafe { libc::raise(libc::SIGTERM) };
+    // The teardown thread re-raises the signal once the machine stopped, which ends the process.
+    thread::sleep(TIMEOUT);
+    panic!("SIGTERM did not end the process");
+}
+
+/// Slow, and kills a process with `SIGTERM`.
+#[test]
+#[ignore]
+fn sigterm_tears_registered_machines_down() {
+    let mut child = Command::new(std::env::current_exe().unwrap())
+        .args(["sigterm_child", "--exact", "--ignored", "--nocapture"])
+        .env(CHILD_ENV, "1")
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let lines: Vec<String> = BufReader::new(child.stdout.take().unwrap())
+        .lines()
+        .map_while(Result::ok)
+        .collect();
+    let status = child.wait().unwrap();
+    assert_eq!(status.signal(), Some(libc::SIGTERM), "{}", status);
+    let ready = lines
+        .iter()
+        .position(|line| line == CHILD_READY)
+        .unwrap_or_else(|| panic!("the child did not register: {:?}", lines));
+    assert!(
+        lines[ready..].iter().any(|line| line == "stopped"),
+        "{:?}",
+        lines
+    );
+}
@@ -0,0 +1,232 @@
+//! A timeline must keep its events in order, also when recorded from several threads, and export
+//! them as Chrome trace-event JSON: byte for byte like the golden file for a fixed boot, and
+//! following the trace-event schema for a recorded one.
+
+#![cfg(target_os = "macos")]
+
+extern crate virtualization_rs;
+
+use virtualization_rs::timeline::{self, TimelineEvent, TimelineEventKind, TimelineHandle};
+use virtualization_rs::virtualization::boot_loader::VZLinuxBootLoaderBuilder;
+use virtualization_rs::virtualization::virtual_machine::{
+    VZVirtualMachine, VZVirtualMachineConfigurationBuilder, VZVirtualMachineState,
+};
+
+use std::fs::{self, File};
+use std::io::Write;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+use serde_json::Value;
+
+const GOLDEN: &str = include_str!("data/timeline_trace.json");
+
+fn event(micros: u64, kind: TimelineEventKind) -> TimelineEvent {
+    TimelineEvent {
+        at: Duration::from_micros(micros),
+        kind,
+    }
+}
+
+/// A boot that reaches the login prompt, then a pause that never completes and a failed stop.
+fn boot() -> Vec<TimelineEvent> {
+    use TimelineEventKind::*;
+    use VZVirtualMachineState::*;
+    vec![
+        event(0, Requested { operation: "start" }),
+        event(
+            150,
+            StateChanged {
+                state: VZVirtualMachineStateStarting,
+            },
+        ),
+        event(
+            41_250,
+            StateChanged {
+                state: VZVirtualMachineStateRunning,
+            },
+        ),
+        event(
+            41_300,
+            Completed {
+                operation: "start",
+                outcome: "success",
+            },
+        ),
+        event(
+            1_850_000,
+            Milestone {
+                name: "login:".to_string(),
+            },
+        ),
+        event(
+            2_400_500,
+            Mark {
+                label: "cloud-init \"done\"".to_string(),
+            },
+        ),
+        event(3_000_000, Requested { operation: "pause" }),
+        event(3_100_000, Requested { operation: "stop" }),
+        event(
+            3_350_000,
+            Completed {
+                operation: "stop",
+                outcome: "failed",
+            },
+        ),
+        event(
+            3_360_000,
+            StateChanged {
+                state: VZVirtualMachineStateStopped,
+            },
+        ),
+    ]
+}
+
+/// Checks the fields chrome://tracing and Perfetto need, all events being of process `pid`, and
+/// returns the trace events.
+fn assert_schema(trace: &str, pid: u64) -> Vec<Value> {
+    let root: Value = serde_json::from_str(trace).unwrap();
+    assert_eq!(root["displayTimeUnit"], "ms");
+    let events = root["traceEvents"].as_array().unwrap().clone();
+    let mut last_ts = 0;
+    for event in &events {
+        let object = event.as_object().unwrap();
+        assert!(object["name"].is_string(), "{}", event);
+        assert!(object["cat"].is_string(), "{}", event);
+        assert_eq!(object["pid"], pid, "{}", event);
+        assert!(object["tid"].is_u64(), "{}", event);
+        let ts = object["ts"].as_u64().unwrap();
+        match object["ph"].as_str().unwrap() {
+            "M" => assert!(object["args"]["name"].is_string(), "{}", event),
+            "X" => {
+                assert!(object["dur"].is_u64(), "{}", event);
+                assert!(ts >= last_ts, "{}", event);
+                last_ts = ts;
+            }
+            "i" => {
+                assert_eq!(object["s"], "t", "{}", event);
+                assert!(ts >= last_ts, "{}", event);
+                last_ts = ts;
+            }
+            ph => panic!("unexpected phase {}", ph),
+        }
+    }
+    events
+}
+
+#[test]
+fn trace_matches_golden_file() {
+    let trace = timeline::chrome_trace(&boot());
+    assert_eq!(trace, GOLDEN);
+    assert_schema(&trace, 1);
+}
+
+#[test]
+fn empty_trace_is_valid() {
+    let events = assert_schema(&timeline::chrome_trace(&[]), 1);
+    assert!(events.iter().all(|event| event["ph"] == "M"));
+}
+
+#[test]
+fn marks_keep_their_order() {
+    let timeline = TimelineHandle::new();
+    thread::scope(|s| {
+        for t in 0..4 {
+            let timeline = &timeline;
+            s.spawn(move || {
+                for i in 0..500 {
+                    timeline.mark(format!("{} {}", t, i));
+                }
+            });
+        }
+    });
+    let events = timeline.events();
+    assert_eq!(events.len(), 2000);
+    assert!(events.windows(2).all(|pair| pair[0].at <= pair[1].at));
+    for t in 0..4 {
+        let prefix = format!("{} ", t);
+        let mine: Vec<usize> = events
+            .iter()
+            .filter_map(|event| match &event.kind {
+                TimelineEventKind::Mark { label } => label.strip_prefix(&prefix),
+                _ => None,
+            })
+            .map(|i| i.parse().unwrap())
+            .collect();
+        assert_eq!(mine, (0..500).collect::<Vec<_>>());
+    }
+}
+
+#[test]
+fn vm_records_operations_and_states() {
+    let dir =
+        std::env::temp_dir().join(format!("virtualization-rs-timeline-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    for name in &["vmlinuz", "initrd"] {
+        File::create(dir.join(name))
+            .unwrap()
+            .write_all(&[0x5a; 4096])
+            .unwrap();
+    }
+    let boot_loader = VZLinuxBootLoaderBuilder::new()
+        .kernel_url(dir.join("vmlinuz").to_str().unwrap())
+        .initial_ramdisk_url(dir.join("initrd").to_str().unwrap())
+        .command_line("console=hvc0")
+        .build()
+        .unwrap();
+    let conf = VZVirtualMachineConfigurationBuilder::new()
+        .boot_loader(boot_loader)
+        .cpu_count(1)
+        .memory_size(512 * 1024 * 1024)
+        .build();
+    let vm = VZVirtualMachine::new_with_qos(conf, "timeline-test", None);
+    let timeline = vm.record_timeline();
+
+    let (tx, rx) = mpsc::channel();
+    vm.start(move |outcome| tx.send(outcome.is_success()).unwrap())
+        .unwrap();
+    rx.recv_timeout(Duration::from_secs(30)).unwrap();
+    // Let the last state changes reach the observer.
+    vm.queue().exec_sync(|| ());
+    timeline.mark("done");
+    let _ = fs::remove_dir_all(&dir);
+
+    let events = timeline.events();
+    let kinds: Vec<&TimelineEventKind> = events.iter().map(|event| &event.kind).collect();
+    assert_eq!(
+        kinds.first(),
+        Some(&&TimelineEventKind::Requested { operation: "start" }),
+        "{:#?}",
+        events
+    );
+    assert!(
+        kinds.iter().any(|kind| matches!(
+            kind,
+            TimelineEventKind::Completed {
+                operation: "start",
+                ..
+            }
+        )),
+        "{:#?}",
+        events
+    );
+    assert_eq!(
+        kinds.last(),
+        Some(&&TimelineEventKind::Mark {
+            label: "done".to_string()
+        })
+    );
+    assert!(vm.record_timeline().events().len() >= events.len());
+    assert_eq!(timeline.vm(), Some(vm.vm_id()));
+    let trace = assert_schema(&timeline.chrome_trace(), vm.vm_id().get());
+    let process = trace
+        .iter()
+        .find(|event| event["name"] == "process_name")
+        .unwrap();
+    assert_eq!(
+        process["args"]["name"],
+        format!("{} (timeline-test)", vm.vm_id())
+    );
+}
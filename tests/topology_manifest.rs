@@ -0,0 +1,233 @@
+//! Keyed storage and network devices must come out in the same order, and with the same manifest,
+//! whatever order they are added in.
+
+#![cfg(target_os = "macos")]
+
+extern crate virtualization_rs;
+
+use virtualization_rs::virtualization::network_device::{
+    VZMACAddress, VZNATNetworkDeviceAttachment, VZVirtioNetworkDeviceConfiguration,
+};
+use virtualization_rs::virtualization::storage_device::{
+    VZDiskImageStorageDeviceAttachmentBuilder, VZUSBMassStorageDeviceConfiguration,
+    VZVirtioBlockDeviceConfiguration,
+};
+use virtualization_rs::virtualization::topology::{TopologyEntry, TopologyManifest};
+use virtualization_rs::virtualization::virtual_machine::VZVirtualMachineConfigurationBuilder;
+
+use std::fs::{self, File};
+use std::path::PathBuf;
+
+/// Disk images, removed on drop.
+struct Images(PathBuf);
+
+impl Images {
+    fn new(name: &str) -> Images {
+        let path = std::env::temp_dir().join(format!(
+            "virtualization-rs-topology-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).unwrap();
+        Images(path)
+    }
+
+    fn image(&self, name: &str) -> String {
+        let path = self.0.join(format!("{}.img", name));
+        if !path.exists() {
+            File::create(&path).unwrap().set_len(1 << 20).unwrap();
+        }
+        path.to_str().unwrap().to_string()
+    }
+}
+
+impl Drop for Images {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+/// How a device is added to the builder.
+#[derive(Clone, Copy)]
+enum Add {
+    Storage(Option<&'static str>, &'static str),
+    Usb(Option<&'static str>, &'static str),
+    Network(Option<&'static str>, &'static str),
+}
+
+fn add(
+    builder: VZVirtualMachineConfigurationBuilder,
+    images: &Images,
+    add: Add,
+) -> VZVirtualMachineConfigurationBuilder {
+    let attachment = |name| {
+        VZDiskImageStorageDeviceAttachmentBuilder::new()
+            .path(images.image(name))
+            .build()
+            .unwrap_or_else(|e| panic!("{}", e))
+    };
+    match add {
+        Add::Storage(key, name) => {
+            let device = VZVirtioBlockDeviceConfiguration::new(attachment(name));
+            match key {
+                Some(key) => builder.storage_device_keyed(key, device),
+                None => builder.storage_device(device),
+            }
+        }
+        Add::Usb(key, name) => {
+            let device = VZUSBMassStorageDeviceConfiguration::new(attachment(name));
+            match key {
+                Some(key) => builder.storage_device_keyed(key, device),
+                None => builder.storage_device(device),
+            }
+        }
+        Add::Network(key, mac) => {
+            let mut device =
+                VZVirtioNetworkDeviceConfiguration::new(VZNATNetworkDeviceAttachment::new());
+            device
+                .set_mac_address(VZMACAddress::init_with_string(mac).unwrap())
+                .unwrap();
+            match key {
+                Some(key) => builder.network_device_keyed(key, device),
+                None => builder.network_device(device),
+            }
+        }
+    }
+}
+
+fn manifest(images: &Images, adds: &[Add]) -> TopologyManifest {
+    let builder = adds
+        .iter()
+        .fold(VZVirtualMachineConfigurationBuilder::new(), |builder, a| {
+            add(builder, images, *a)
+        });
+    builder.build_with_manifest().1
+}
+
+fn keys(entries: &[TopologyEntry]) -> Vec<Option<&str>> {
+    entries.iter().map(|e| e.key.as_deref()).collect()
+}
+
+/// Every ordering of `items`.
+fn permutations<T: Clone>(items: &[T]) -> Vec<Vec<T>> {
+    if items.len() <= 1 {
+        return vec![items.to_vec()];
+    }
+    let mut all = Vec::new();
+    for i in 0..items.len() {
+        let mut rest = items.to_vec();
+        let first = rest.remove(i);
+        for mut tail in permutations(&rest) {
+            tail.insert(0, first.clone());
+            all.push(tail);
+        }
+    }
+    all
+}
+
+#[test]
+fn keyed_devices_are_sorted_by_key() {
+    let images = Images::new("sorted");
+    let manifest = manifest(
+        &images,
+        &[
+            Add::Storage(Some("system"), "system"),
+            Add::Usb(Some("installer"), "installer"),
+            Add::Storage(Some("cidata"), "cidata"),
+            Add::Network(Some("primary"), "02:00:00:00:00:01"),
+            Add::Network(Some("backup"), "02:00:00:00:00:02"),
+        ],
+    );
+    assert_eq!(
+        keys(&manifest.storage),
+        [Some("cidata"), Some("installer"), Some("system")]
+    );
+    assert_eq!(keys(&manifest.network), [Some("backup"), Some("primary")]);
+    assert_eq!(
+        manifest.storage[1].class,
+        "VZUSBMassStorageDeviceConfiguration"
+    );
+    assert_eq!(
+        manifest.network[0].identity.as_deref(),
+        Some("02:00:00:00:00:02")
+    );
+}
+
+#[test]
+fn manifest_is_identical_across_insertion_orders() {
+    let images = Images::new("shuffled");
+    let devices = [
+        Add::Storage(Some("system"), "system"),
+        Add::Usb(Some("installer"), "installer"),
+        Add::Storage(Some("cidata"), "cidata"),
+        Add::Network(Some("primary"), "02:00:00:00:00:01"),
+        Add::Network(Some("backup"), "02:00:00:00:00:02"),
+    ];
+    let expected = manifest(&images, &devices).to_string();
+    for order in permutations(&devices) {
+        assert_eq!(manifest(&images, &order).to_string(), expected);
+    }
+}
+
+#[test]
+fn unkeyed_devices_follow_in_insertion_order() {
+    let images = Images::new("unkeyed");
+    let keyed = [
+        Add::Storage(Some("system"), "system"),
+        Add::Storage(Some("cidata"), "cidata"),
+    ];
+    let unkeyed = [Add::Storage(None, "scratch"), Add::Usb(None, "usb")];
+    let expected = manifest(&images, &[keyed[0], unkeyed[0], keyed[1], unkeyed[1]]).to_string();
+    // Keyed devices may move around freely; unkeyed ones keep their relative order.
+    for order in permutations(&keyed) {
+        let adds = [order[0], unkeyed[0], unkeyed[1], order[1]];
+        assert_eq!(manifest(&images, &adds).to_string(), expected);
+    }
+    let manifest = manifest(&images, &[unkeyed[1], keyed[0], unkeyed[0], keyed[1]]);
+    assert_eq!(
+        keys(&manifest.storage),
+        [Some("cidata"), Some("system"), None, None]
+    );
+    assert_eq!(
+        manifest.storage[2].class,
+        "VZUSBMassStorageDeviceConfiguration"
+    );
+}
+
+#[test]
+fn a_key_added_again_replaces_its_device() {
+    let images = Images::new("replaced");
+    let manifest = manifest(
+        &images,
+        &[
+            Add::Storage(Some("system"), "old"),
+            Add::Storage(Some("system"), "new"),
+        ],
+    );
+    assert_eq!(manifest.storage.len(), 1);
+    assert!(manifest.storage[0]
+        .identity
+        .as_deref()
+        .unwrap()
+        .ends_with("new.img"));
+}
+
+#[test]
+fn storage_devices_replaces_keyed_devices() {
+    let images = Images::new("reset");
+    let builder = add(
+        VZVirtualMachineConfigurationBuilder::new(),
+        &images,
+        Add::Storage(Some("system"), "system"),
+    );
+    let attachment = VZDiskImageStorageDeviceAttachmentBuilder::new()
+        .path(images.image("only"))
+        .build()
+        .unwrap_or_else(|e| panic!("{}", e));
+    let (_, manifest) = builder
+        .storage_devices(vec![VZVirtioBlockDeviceConfiguration::new(attachment)])
+        .build_with_manifest();
+    assert_eq!(manifest.storage.len(), 1);
+    assert_eq!(manifest.storage[0].key, None);
+}
@@ -0,0 +1,489 @@
+//! Builds configurations from the `test_support` fixtures and validates them with
+//! `validateWithError:`, which checks a configuration against the host without starting a virtual
+//! machine. Needs neither the virtualization entitlement nor hardware virtualization, so it runs
+//! on any Mac; the boot loaders point at garbage files, which validate but would not boot.
+
+#![cfg(target_os = "macos")]
+
+extern crate objc;
+extern crate virtualization_rs;
+
+use virtualization_rs::base::{DispatchQueue, NSFileHandle, NSInteger};
+use virtualization_rs::features::HostCapabilities;
+use virtualization_rs::identity::{IdentityError, VmIdentity};
+use virtualization_rs::test_support::{self, TempDir};
+use virtualization_rs::virtualization::directory_sharing::{
+    VZSharedDirectory, VZSingleDirectoryShare, VZVirtioFileSystemDeviceConfiguration,
+};
+use virtualization_rs::virtualization::memory_device::VZVirtioTraditionalMemoryBalloonDeviceConfiguration;
+use virtualization_rs::virtualization::network_device::{
+    VZMACAddress, VZNATNetworkDeviceAttachment, VZVirtioNetworkDeviceConfiguration,
+};
+use virtualization_rs::virtualization::serial_port::{
+    VZFileHandleSerialPortAttachmentBuilder, VZVirtioConsoleDeviceSerialPortConfiguration,
+};
+use virtualization_rs::virtualization::socket_device::VZVirtioSocketDeviceConfiguration;
+use virtualization_rs::virtualization::storage_device::{
+    VZDiskBlockDeviceStorageDeviceAttachment, VZDiskImageCachingMode,
+    VZDiskImageStorageDeviceAttachmentBuilder, VZDiskImageSynchronizationMode,
+    VZDiskSynchronizationMode, VZStorageDeviceAttachment, VZStorageDeviceConfiguration,
+    VZVirtioBlockDeviceConfiguration,
+};
+use virtualization_rs::virtualization::validation::{self, Culprit, Located, VerboseBuildError};
+use virtualization_rs::virtualization::virtual_machine::{
+    VZVirtualMachine, VZVirtualMachineConfiguration,
+};
+
+use std::fs::File;
+use std::process::Command;
+
+use objc::{msg_send, sel, sel_impl};
+
+fn assert_invalid(conf: &VZVirtualMachineConfiguration) {
+    let error = match conf.validate_with_error() {
+        Ok(_) => panic!("the configuration validated:\n{}", conf.describe()),
+        Err(error) => error,
+    };
+    assert_eq!(error.operation(), "validate virtual machine configuration");
+}
+
+#[test]
+fn minimal_linux_configuration_validates() {
+    let dir = TempDir::new("validation-linux");
+    let conf = test_support::minimal_linux_config(&dir);
+    let resources = conf
+        .effective_resources()
+        .unwrap_or_else(|e| panic!("{}", e));
+    assert!(!resources.cpu_count.was_clamped);
+    assert!(!resources.memory_size.was_clamped);
+    assert_eq!(resources.cpu_count.effective, 1);
+}
+
+#[test]
+fn headless_devices_validate() {
+    let dir = TempDir::new("validation-devices");
+    let image = dir.disk_image("disk.img", 64 * 1024 * 1024);
+    let attachment = VZDiskImageStorageDeviceAttachmentBuilder::new()
+        .path(image.to_str().unwrap())
+        .read_only(false)
+        .build()
+        .unwrap_or_else(|e| panic!("{}", e));
+    let serial = VZFileHandleSerialPortAttachmentBuilder::new()
+        .file_handle_for_reading(NSFileHandle::file_handle_with_standard_input())
+        .file_handle_for_writing(NSFileHandle::new())
+        .build();
+    let share = VZSingleDirectoryShare::new(
+        VZSharedDirectory::new(dir.path().to_str().unwrap(), true).unwrap(),
+    );
+    let conf = test_support::minimal_builder()
+        .boot_loader(test_support::garbage_linux_boot_loader(&dir))
+        .storage_devices(vec![VZVirtioBlockDeviceConfiguration::new(attachment)])
+        .network_devices(vec![VZVirtioNetworkDeviceConfiguration::new(
+            VZNATNetworkDeviceAttachment::new(),
+        )])
+        .serial_ports(vec![VZVirtioConsoleDeviceSerialPortConfiguration::new(
+            serial,
+        )])
+        .socket_devices(vec![VZVirtioSocketDeviceConfiguration::new()])
+        .memory_balloon_devices(vec![
+            VZVirtioTraditionalMemoryBalloonDeviceConfiguration::new(),
+        ])
+        .directory_sharing_devices(vec![VZVirtioFileSystemDeviceConfiguration::new(
+            "share", share,
+        )
+        .unwrap_or_else(|e| panic!("{}", e))])
+        .build();
+    assert!(conf
+        .validate_with_error()
+        .unwrap_or_else(|e| panic!("{}\n{}", e, conf.describe())));
+}
+
+#[test]
+fn minimal_efi_configuration_validates() {
+    if !HostCapabilities::detect().supports_class("VZEFIBootLoader") {
+        println!("minimal_efi_configuration_validates skipped: no VZEFIBootLoader before macOS 13");
+        return;
+    }
+    let dir = TempDir::new("validation-efi");
+    let conf = test_support::minimal_efi_config(&dir);
+    assert!(conf
+        .validate_with_error()
+        .unwrap_or_else(|e| panic!("{}\n{}", e, conf.describe())));
+}
+
+#[test]
+fn an_identity_is_not_applied_to_a_network_device_in_use() {
+    if !HostCapabilities::detect().supports_class("VZGenericMachineIdentifier") {
+        println!(
+            "an_identity_is_not_applied_to_a_network_device_in_use skipped: no generic machine \
+             identifiers before macOS 13"
+        );
+        return;
+    }
+    let dir = TempDir::new("validation-identity-frozen");
+    let identity = match VmIdentity::generate(dir.path()) {
+        Ok(identity) => identity,
+        Err(_) => panic!("could not generate an identity"),
+    };
+    let network = VZVirtioNetworkDeviceConfiguration::new(VZNATNetworkDeviceAttachment::new());
+    let conf = test_support::minimal_builder()
+        .boot_loader(test_support::garbage_linux_boot_loader(&dir))
+        .network_devices(vec![network.clone()])
+        .build();
+    let _vm = VZVirtualMachine::new(conf, DispatchQueue::new("validation-identity-frozen"));
+
+    let builder = test_support::minimal_builder().network_devices(vec![network]);
+    match identity.apply(builder) {
+        Err(IdentityError::Frozen(error)) => assert_eq!(
+            error.setter,
+            "VZVirtualMachineConfigurationBuilder::set_first_network_device_mac"
+        ),
+        Err(_) => panic!("apply failed for another reason"),
+        Ok(_) => panic!("apply changed the MAC address of a device in use"),
+    }
+}
+
+/// The `synchronizationMode` the framework reports for `attachment`.
+fn synchronization_mode<T: VZStorageDeviceAttachment>(attachment: &T) -> NSInteger {
+    unsafe { msg_send![attachment.id(), synchronizationMode] }
+}
+
+fn assert_disk_validates<T: VZStorageDeviceAttachment>(dir: &TempDir, attachment: T) {
+    let conf = test_support::minimal_builder()
+        .boot_loader(test_support::garbage_linux_boot_loader(dir))
+        .storage_devices(vec![VZVirtioBlockDeviceConfiguration::new(attachment)])
+        .build();
+    assert!(conf
+        .validate_with_error()
+        .unwrap_or_else(|e| panic!("{}\n{}", e, conf.describe())));
+}
+
+#[test]
+fn each_disk_image_synchronization_mode_round_trips() {
+    let dir = TempDir::new("validation-image-modes");
+    let image = dir.disk_image("disk.img", 64 * 1024 * 1024);
+    // The raw values of the macOS 14 SDK headers.
+    let modes = [
+        (VZDiskImageSynchronizationMode::full(), 1),
+        (VZDiskImageSynchronizationMode::fsync(), 2),
+        (VZDiskImageSynchronizationMode::none(), 3),
+    ];
+    for (mode, raw) in modes {
+        let attachment = VZDiskImageStorageDeviceAttachmentBuilder::new()
+            .path(image.to_str().unwrap())
+            .read_only(true)
+            .caching_mode(VZDiskImageCachingMode::automatic())
+            .synchronization_mode(mode)
+            .build()
+            .unwrap_or_else(|e| panic!("{:?}: {}", mode, e));
+        assert_eq!(synchronization_mode(&attachment), raw, "{:?}", mode);
+        assert_disk_validates(&dir, attachment);
+    }
+}
+
+/// A RAM disk with no file system, detached when dropped.
+struct RamDisk(String);
+
+impl RamDisk {
+    /// Attaches 1 MiB, or `None` where `hdiutil` cannot.
+    fn attach() -> Option<RamDisk> {
+        let output = Command::new("hdiutil")
+            .args(["attach", "-nomount", "ram://2048"])
+            .output()
+            .ok()?;
+        let device = String::from_utf8(output.stdout).ok()?.trim().to_string();
+        if !output.status.success() || !device.starts_with("/dev/disk") {
+            return None;
+        }
+        Some(RamDisk(device))
+    }
+}
+
+impl Drop for RamDisk {
+    fn drop(&mut self) {
+        let _ = Command::new("hdiutil")
+            .args(["detach", "-force", &self.0])
+            .output();
+    }
+}
+
+#[test]
+fn each_disk_synchronization_mode_round_trips() {
+    let test = "each_disk_synchronization_mode_round_trips";
+    if !HostCapabilities::detect().supports_class("VZDiskBlockDeviceStorageDeviceAttachment") {
+        println!(
+            "{} skipped: no block device attachments before macOS 14",
+            test
+        );
+        return;
+    }
+    let disk = match RamDisk::attach() {
+        Some(disk) => disk,
+        None => {
+            println!("{} skipped: hdiutil could not attach a RAM disk", test);
+            return;
+        }
+    };
+    let dir = TempDir::new("validation-disk-modes");
+    // The raw values of the macOS 14 SDK headers, unlike those of the disk image modes.
+    let modes = [
+        (VZDiskSynchronizationMode::full(), 0),
+        (VZDiskSynchronizationMode::none(), 1),
+    ];
+    for (mode, raw) in modes {
+        let file = match File::open(&disk.0) {
+            Ok(file) => file,
+            Err(e) => {
+                println!("{} skipped: cannot open {}: {}", test, disk.0, e);
+                return;
+            }
+        };
+        let attachment = VZDiskBlockDeviceStorageDeviceAttachment::new(file, true, mode)
+            .unwrap_or_else(|e| panic!("{:?}: {}", mode, e));
+        assert_eq!(synchronization_mode(&attachment), raw, "{:?}", mode);
+        assert_disk_validates(&dir, attachment);
+    }
+}
+
+#[test]
+fn configuration_without_boot_loader_is_invalid() {
+    assert_invalid(&test_support::minimal_builder().build());
+}
+
+#[test]
+fn cpu_count_above_maximum_is_invalid() {
+    let dir = TempDir::new("validation-cpus");
+    let maximum = HostCapabilities::detect().maximum_cpu_count;
+    let conf = test_support::minimal_builder()
+        .cpu_count(maximum + 1)
+        .boot_loader(test_support::garbage_linux_boot_loader(&dir))
+        .build();
+    assert_invalid(&conf);
+}
+
+#[test]
+fn memory_size_below_minimum_is_invalid() {
+    let dir = TempDir::new("validation-memory");
+    let minimum = VZVirtualMachineConfiguration::minimum_allowed_memory_size();
+    let conf = test_support::minimal_builder()
+        .memory_size(minimum - 1024 * 1024)
+        .boot_loader(test_support::garbage_linux_boot_loader(&dir))
+        .build();
+    assert_invalid(&conf);
+}
+
+#[test]
+fn copy_is_independent_of_the_original() {
+    let dir = TempDir::new("validation-copy");
+    let original = test_support::minimal_linux_config(&dir);
+    let memory_size = original.memory_size();
+    let mut copy = original.copy();
+    copy.set_memory_size(memory_size + 1024 * 1024 * 1024)
+        .unwrap();
+    copy.set_cpu_count(2).unwrap();
+    let attachment = VZDiskImageStorageDeviceAttachmentBuilder::new()
+        .path(dir.disk_image("disk.img", 1024 * 1024).to_str().unwrap())
+        .read_only(true)
+        .build()
+        .unwrap_or_else(|e| panic!("{}", e));
+    copy.replace_storage_devices(vec![
+        Box::new(VZVirtioBlockDeviceConfiguration::new(attachment))
+            as Box<dyn VZStorageDeviceConfiguration>,
+    ])
+    .unwrap();
+
+    for conf in &[&original, &copy] {
+        let valid = conf.validate_with_error();
+        assert!(valid.unwrap_or_else(|e| panic!("{}\n{}", e, conf.describe())));
+    }
+    assert_eq!(original.memory_size(), memory_size);
+    assert_eq!(original.cpu_count(), 1);
+    assert!(!original.describe().contains("storage_devices"));
+    assert_eq!(copy.memory_size(), memory_size + 1024 * 1024 * 1024);
+    assert_eq!(copy.cpu_count(), 2);
+    assert!(copy.describe().contains("storage_devices"));
+}
+
+#[test]
+fn dropping_a_copy_leaves_the_original() {
+    let dir = TempDir::new("validation-copy-drop");
+    let original = test_support::minimal_linux_config(&dir);
+    for _ in 0..16 {
+        drop(original.copy().copy());
+    }
+    let described = original.describe();
+    assert!(original
+        .validate_with_error()
+        .unwrap_or_else(|e| panic!("{}\n{}", e, described)));
+    assert_eq!(original.copy().describe(), described);
+}
+
+#[test]
+fn copy_of_a_frozen_configuration_is_not_frozen() {
+    let dir = TempDir::new("validation-copy-frozen");
+    let original = test_support::minimal_linux_config(&dir);
+    let queue = DispatchQueue::new("validation-copy-frozen");
+    let _vm = VZVirtualMachine::new(original.clone(), queue.id());
+    assert!(original.is_frozen());
+    let mut frozen = original.clone();
+    let error = frozen.set_cpu_count(2).unwrap_err();
+    assert_eq!(error.setter, "VZVirtualMachineConfiguration::set_cpu_count");
+
+    let mut copy = original.copy();
+    assert!(!copy.is_frozen());
+    copy.set_cpu_count(2).unwrap();
+    assert_eq!(copy.cpu_count(), 2);
+    assert_eq!(original.cpu_count(), 1);
+}
+
+#[test]
+fn replaced_devices_are_not_frozen_with_the_configuration() {
+    let dir = TempDir::new("validation-replaced-frozen");
+    let network = || VZVirtioNetworkDeviceConfiguration::new(VZNATNetworkDeviceAttachment::new());
+    let (mut replaced, mut kept) = (network(), network());
+    let conf = test_support::minimal_builder()
+        .boot_loader(test_support::garbage_linux_boot_loader(&dir))
+        .network_devices(vec![replaced.clone()])
+        .network_devices(vec![kept.clone()])
+        .build();
+    let queue = DispatchQueue::new("validation-replaced-frozen");
+    let _vm = VZVirtualMachine::new(conf, queue.id());
+
+    let mac = VZMACAddress::random_locally_administered_address;
+    replaced.set_mac_address(mac()).unwrap();
+    let error = kept.set_mac_address(mac()).unwrap_err();
+    assert_eq!(
+        error.setter,
+        "VZVirtioNetworkDeviceConfiguration::set_mac_address"
+    );
+}
+
+/// Device counts of the categories `validation::locate` walks in the tests below: three storage
+/// devices, one network device, two serial ports and one entropy device.
+const COUNTS: [usize; 4] = [3, 1, 2, 1];
+const STORAGE: usize = 0;
+const SERIAL: usize = 2;
+const ENTROPY: usize = 3;
+
+/// `validation::locate` over [`COUNTS`], with the configuration failing whenever `broken` holds
+/// for the device prefixes set.
+fn locate(broken: impl Fn(&[usize]) -> bool) -> (Located<&'static str>, usize) {
+    validation::locate(&COUNTS, |lengths| {
+        if broken(lengths) {
+            Err("invalid")
+        } else {
+            Ok(())
+        }
+    })
+}
+
+fn device(category: usize, index: usize) -> Located<&'static str> {
+    Located::Device {
+        category,
+        index,
+        error: "invalid",
+    }
+}
+
+#[test]
+fn a_broken_first_disk_is_found() {
+    let (located, validations) = locate(|lengths| lengths[STORAGE] > 0);
+    assert_eq!(located, device(STORAGE, 0));
+    // Without devices, then with the first disk.
+    assert_eq!(validations, 2);
+}
+
+#[test]
+fn a_broken_last_disk_is_found() {
+    let (located, validations) = locate(|lengths| lengths[STORAGE] > 2);
+    assert_eq!(located, device(STORAGE, 2));
+    assert_eq!(validations, 4);
+}
+
+#[test]
+fn a_broken_serial_attachment_is_found_after_the_categories_before_it() {
+    let (located, validations) = locate(|lengths| lengths[SERIAL] > 1);
+    assert_eq!(located, device(SERIAL, 1));
+    assert_eq!(validations, 1 + 3 + 1 + 2);
+}
+
+#[test]
+fn a_category_that_must_not_be_empty_is_filled_first() {
+    // Invalid without an entropy device, and with the second disk.
+    let (located, _) = locate(|lengths| lengths[ENTROPY] == 0 || lengths[STORAGE] > 1);
+    assert_eq!(located, device(STORAGE, 1));
+}
+
+#[test]
+fn categories_that_must_not_be_empty_together_are_filled_first() {
+    let (located, _) =
+        locate(|lengths| lengths[ENTROPY] == 0 || lengths[SERIAL] == 0 || lengths[SERIAL] > 1);
+    assert_eq!(located, device(SERIAL, 1));
+}
+
+#[test]
+fn a_configuration_invalid_without_devices_blames_the_base() {
+    let (located, validations) = locate(|_| true);
+    assert_eq!(located, Located::Base("invalid"));
+    // Without devices, with the first of each category alone, then with all of them.
+    assert_eq!(validations, 1 + COUNTS.len() + 1);
+}
+
+#[test]
+fn a_valid_configuration_is_not_reproduced() {
+    let (located, validations) = locate(|_| false);
+    assert_eq!(located, Located::NotReproduced);
+    assert_eq!(validations, 1 + COUNTS.iter().sum::<usize>());
+}
+
+#[test]
+fn verbose_build_of_a_valid_configuration_validates_once() {
+    let dir = TempDir::new("validation-verbose");
+    let conf = test_support::minimal_builder()
+        .boot_loader(test_support::garbage_linux_boot_loader(&dir))
+        .validated_build_verbose()
+        .unwrap_or_else(|e| panic!("{}", e));
+    assert!(conf.validate_with_error().unwrap());
+}
+
+#[test]
+fn verbose_build_names_the_extra_memory_balloon() {
+    // The framework supports one memory balloon device, and the builder knows it before
+    // validating.
+    let dir = TempDir::new("validation-verbose-balloon");
+    let builder = || {
+        test_support::minimal_builder()
+            .boot_loader(test_support::garbage_linux_boot_loader(&dir))
+            .memory_balloon_devices(vec![
+                VZVirtioTraditionalMemoryBalloonDeviceConfiguration::new(),
+                VZVirtioTraditionalMemoryBalloonDeviceConfiguration::new(),
+            ])
+    };
+    assert_invalid(&builder().build());
+    let error = builder()
+        .validated_build_verbose()
+        .err()
+        .expect("validated with two memory balloon devices");
+    let exceeded = match error {
+        VerboseBuildError::TooManyDevices(exceeded) => exceeded,
+        other => panic!("expected too many devices, got {}", other),
+    };
+    assert_eq!(exceeded.category, "memory_balloon_devices");
+    assert_eq!(exceeded.configured, 2);
+    assert_eq!(
+        exceeded.to_string(),
+        "memory_balloon_devices: 2 configured, the framework allows at most 1"
+    );
+}
+
+#[test]
+fn verbose_build_without_boot_loader_blames_the_base() {
+    let error = test_support::minimal_builder()
+        .validated_build_verbose()
+        .err()
+        .expect("validated without a boot loader");
+    match error {
+        VerboseBuildError::Invalid(failure) => assert_eq!(failure.culprit, Culprit::Base),
+        other => panic!("expected a validation failure, got {}", other),
+    }
+}
@@ -0,0 +1,194 @@
+//! Input injection: every printable ASCII character maps to a US key code, and a view without a
+//! virtual machine refuses input with an error instead of crashing.
+//!
+//! Runs without the test harness, on the main thread the view requires. Typing into a Linux
+//! desktop guest runs only with `VIRTUALIZATION_RS_TEST_DESKTOP_KERNEL`,
+//! `VIRTUALIZATION_RS_TEST_DESKTOP_INITRD` and `VIRTUALIZATION_RS_TEST_DESKTOP_DISK` set, and
+//! prints why it is skipped otherwise.
+
+extern crate virtualization_rs;
+
+#[cfg(target_os = "macos")]
+mod tests {
+    use virtualization_rs::base::DispatchQueue;
+    use virtualization_rs::features::HostCapabilities;
+    use virtualization_rs::runtime::MainQueuePump;
+    use virtualization_rs::virtualization::boot_loader::VZLinuxBootLoaderBuilder;
+    use virtualization_rs::virtualization::graphics_device::{
+        VZVirtioGraphicsDeviceConfiguration, VZVirtioGraphicsScanoutConfiguration,
+    };
+    use virtualization_rs::virtualization::input_devices::{self, GuestOS};
+    use virtualization_rs::virtualization::storage_device::{
+        VZDiskImageStorageDeviceAttachmentBuilder, VZVirtioBlockDeviceConfiguration,
+    };
+    use virtualization_rs::virtualization::view::{
+        keycode, keystroke_for, InjectError, KeyStroke, MouseButtons, VZVirtualMachineView,
+    };
+    use virtualization_rs::virtualization::virtual_machine::{
+        VZVirtualMachine, VZVirtualMachineConfigurationBuilder,
+    };
+
+    use std::cell::Cell;
+    use std::collections::HashMap;
+    use std::rc::Rc;
+    use std::time::Duration;
+
+    fn stroke(keycode: u16, shift: bool) -> Option<KeyStroke> {
+        Some(KeyStroke { keycode, shift })
+    }
+
+    pub fn printable_ascii_maps_to_keys() {
+        let mut characters_of_key: HashMap<u16, Vec<char>> = HashMap::new();
+        for c in (' '..='~').chain(vec!['\n', '\t']) {
+            let stroke = keystroke_for(c).unwrap_or_else(|| panic!("{:?} has no key", c));
+            characters_of_key.entry(stroke.keycode).or_default().push(c);
+        }
+        // Each character key types two characters, one with Shift; whitespace types one.
+        for (keycode, characters) in &characters_of_key {
+            let expected = match *keycode {
+                keycode::RETURN | keycode::TAB | keycode::SPACE => 1,
+                _ => 2,
+            };
+            assert_eq!(characters.len(), expected, "{:?}", characters);
+        }
+
+        assert_eq!(keystroke_for('a'), stroke(0x00, false));
+        assert_eq!(keystroke_for('A'), stroke(0x00, true));
+        assert_eq!(keystroke_for('z'), stroke(0x06, false));
+        assert_eq!(keystroke_for('1'), stroke(0x12, false));
+        assert_eq!(keystroke_for('!'), stroke(0x12, true));
+        assert_eq!(keystroke_for('5'), stroke(0x17, false));
+        assert_eq!(keystroke_for('6'), stroke(0x16, false));
+        assert_eq!(keystroke_for('|'), stroke(0x2A, true));
+        assert_eq!(keystroke_for('"'), stroke(0x27, true));
+        assert_eq!(keystroke_for('~'), stroke(0x32, true));
+        assert_eq!(keystroke_for('\n'), stroke(keycode::RETURN, false));
+        assert_eq!(keystroke_for('\t'), stroke(keycode::TAB, false));
+        assert_eq!(keystroke_for(' '), stroke(keycode::SPACE, false));
+
+        for &c in &['\r', '\u{7f}', '\u{1b}', '\u{e9}', '\u{20ac}', '\u{1f4a5}'] {
+            assert_eq!(keystroke_for(c), None, "{:?}", c);
+        }
+    }
+
+    pub fn injection_without_a_machine_fails() {
+        let view = VZVirtualMachineView::new();
+        assert_eq!(
+            view.inject_key_event(keycode::RETURN, true),
+            Err(InjectError::NoVirtualMachine)
+        );
+        assert_eq!(
+            view.inject_key_event(keycode::SHIFT, true),
+            Err(InjectError::NoVirtualMachine)
+        );
+        assert_eq!(
+            view.inject_mouse_event(10.0, 10.0, MouseButtons::LEFT),
+            Err(InjectError::NoVirtualMachine)
+        );
+        assert_eq!(
+            view.type_text("ls\n", Duration::from_millis(0)),
+            Err(InjectError::NoVirtualMachine)
+        );
+        // Checked before any key is sent.
+        let error = view
+            .type_text("caf\u{e9}", Duration::from_millis(0))
+            .unwrap_err();
+        assert_eq!(error, InjectError::UnmappedCharacter('\u{e9}'));
+        assert_eq!(
+            error.to_string(),
+            "'\u{e9}' has no key on the US keyboard layout"
+        );
+        assert_eq!(
+            InjectError::NoVirtualMachine.to_string(),
+            "the view has no virtual machine to send input to"
+        );
+    }
+
+    pub fn types_into_a_desktop_guest() {
+        let var = |name: &str| std::env::var(format!("VIRTUALIZATION_RS_TEST_DESKTOP_{}", name));
+        let (kernel, initrd, disk) = match (var("KERNEL"), var("INITRD"), var("DISK")) {
+            (Ok(kernel), Ok(initrd), Ok(disk)) => (kernel, initrd, disk),
+            _ => {
+                println!(
+                    "types_into_a_desktop_guest skipped: VIRTUALIZATION_RS_TEST_DESKTOP_KERNEL, \
+                     _INITRD or _DISK unset"
+                );
+                return;
+            }
+        };
+        let boot_seconds = var("BOOT_SECS").map_or(60, |s| s.parse().unwrap());
+
+        let boot_loader = VZLinuxBootLoaderBuilder::new()
+            .kernel_url(kernel)
+            .initial_ramdisk_url(initrd)
+            .command_line("root=/dev/vda rw")
+            .build()
+            .unwrap();
+        let disk = VZDiskImageStorageDeviceAttachmentBuilder::new()
+            .path(disk)
+            .read_only(false)
+            .build()
+            .unwrap();
+        let input =
+            input_devices::recommended_for(GuestOS::LinuxDesktop, &HostCapabilities::detect());
+        let conf = VZVirtualMachineConfigurationBuilder::new()
+            .boot_loader(boot_loader)
+            .cpu_count(2)
+            .memory_size_gib(2)
+            .storage_devices(vec![VZVirtioBlockDeviceConfiguration::new(disk)])
+            .graphics_devices(vec![VZVirtioGraphicsDeviceConfiguration::new(vec![
+                VZVirtioGraphicsScanoutConfiguration::new(1280, 800),
+            ])])
+            .keyboards(input.keyboards)
+            .pointing_devices(input.pointing)
+            .build();
+        conf.validate_with_error().unwrap();
+
+        // The view needs its machine on the main queue.
+        let vm = VZVirtualMachine::new(conf, DispatchQueue::main().id());
+        let view = VZVirtualMachineView::new();
+        view.set_virtual_machine(&vm);
+
+        let started = Rc::new(Cell::new(None));
+        let outcome = started.clone();
+        vm.start(move |o| outcome.set(Some(o.is_success())))
+            .unwrap();
+        MainQueuePump::pump_until(Duration::from_secs(30), || started.get().is_some());
+        assert_eq!(started.get(), Some(true), "the guest did not start");
+
+        // Long enough for the desktop to show a focused terminal, e.g. one started at login.
+        MainQueuePump::pump(Duration::from_secs(boot_seconds));
+        view.inject_mouse_event(640.0, 400.0, MouseButtons::NONE)
+            .unwrap();
+        view.type_text(
+            "echo typed by virtualization-rs > /tmp/virtualization-rs-typed\n",
+            Duration::from_millis(30),
+        )
+        .unwrap();
+        MainQueuePump::pump(Duration::from_secs(2));
+    }
+}
+
+fn main() {
+    #[cfg(target_os = "macos")]
+    {
+        let tests: &[(&str, fn())] = &[
+            (
+                "printable_ascii_maps_to_keys",
+                tests::printable_ascii_maps_to_keys,
+            ),
+            (
+                "injection_without_a_machine_fails",
+                tests::injection_without_a_machine_fails,
+            ),
+            (
+                "types_into_a_desktop_guest",
+                tests::types_into_a_desktop_guest,
+            ),
+        ];
+        for (name, test) in tests {
+            test();
+            println!("test {} ... ok", name);
+        }
+    }
+}
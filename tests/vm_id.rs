@@ -0,0 +1,116 @@
+//! Every virtual machine gets its own `VmId`, shared by its clones, and is named by it, with its
+//! label, in `Debug` and errors.
+
+#![cfg(target_os = "macos")]
+
+extern crate virtualization_rs;
+
+use virtualization_rs::base::DispatchQueue;
+use virtualization_rs::virtualization::boot_loader::VZLinuxBootLoaderBuilder;
+use virtualization_rs::virtualization::lifecycle::Lifecycle;
+use virtualization_rs::virtualization::virtual_machine::{
+    VZVirtualMachine, VZVirtualMachineConfiguration, VZVirtualMachineConfigurationBuilder,
+};
+
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::PathBuf;
+
+/// A scratch directory with kernel and initrd files that validate but do not boot.
+struct Scratch(PathBuf);
+
+impl Scratch {
+    fn new(name: &str) -> Scratch {
+        let path = std::env::temp_dir().join(format!(
+            "virtualization-rs-vm-id-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).unwrap();
+        for name in &["vmlinuz", "initrd"] {
+            File::create(path.join(name))
+                .unwrap()
+                .write_all(&[0x5a; 4096])
+                .unwrap();
+        }
+        Scratch(path)
+    }
+
+    fn config(&self) -> VZVirtualMachineConfiguration {
+        let boot_loader = VZLinuxBootLoaderBuilder::new()
+            .kernel_url(self.0.join("vmlinuz").to_str().unwrap())
+            .initial_ramdisk_url(self.0.join("initrd").to_str().unwrap())
+            .command_line("console=hvc0")
+            .build()
+            .unwrap();
+        VZVirtualMachineConfigurationBuilder::new()
+            .boot_loader(boot_loader)
+            .cpu_count(1)
+            .memory_size(512 * 1024 * 1024)
+            .build()
+    }
+}
+
+impl Drop for Scratch {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+#[test]
+fn ids_are_unique_and_increasing() {
+    let scratch = Scratch::new("unique");
+    let queue = DispatchQueue::new("vm-id-test");
+    let first = VZVirtualMachine::new(scratch.config(), queue.id());
+    let second = VZVirtualMachine::new_labeled(scratch.config(), queue.id(), "web");
+    assert!(first.vm_id() < second.vm_id());
+    assert_eq!(first.clone().vm_id(), first.vm_id());
+    assert_eq!(first.on_queue(&queue).vm_id(), first.vm_id());
+
+    assert_eq!(first.label(), None);
+    assert_eq!(first.display_name(), format!("vm-{}", first.vm_id().get()));
+    assert_eq!(second.label(), Some("web"));
+    assert_eq!(second.display_name(), format!("{} (web)", second.vm_id()));
+    let qos = VZVirtualMachine::new_with_qos(scratch.config(), "db", None);
+    assert_eq!(qos.label(), Some("db"));
+}
+
+#[test]
+fn debug_names_the_machine() {
+    let scratch = Scratch::new("debug");
+    let queue = DispatchQueue::new("vm-id-test");
+    let vm = VZVirtualMachine::new_labeled(scratch.config(), queue.id(), "web");
+    let debug = format!("{:?}", vm);
+    assert!(
+        debug.contains(&format!("id: VmId({})", vm.vm_id().get())),
+        "{}",
+        debug
+    );
+    assert!(debug.contains("label: Some(\"web\")"), "{}", debug);
+    assert!(
+        debug.contains(&format!("ptr: {:?}", unsafe { vm.id() })),
+        "{}",
+        debug
+    );
+    assert!(
+        debug.contains(&format!("lifecycle: {:?}", Lifecycle::Created)),
+        "{}",
+        debug
+    );
+}
+
+#[test]
+fn errors_name_the_machine() {
+    let scratch = Scratch::new("errors");
+    let queue = DispatchQueue::new("vm-id-test");
+    let vm = VZVirtualMachine::new_labeled(scratch.config(), queue.id(), "web");
+    // A machine that never started cannot be asked to stop.
+    let target = vm.clone();
+    let error = vm
+        .queue()
+        .exec_sync(move || unsafe { target.request_stop_with_error() })
+        .unwrap_err();
+    assert_eq!(error.resource(), Some(vm.display_name().as_str()));
+    assert!(error.to_string().contains(&vm.display_name()), "{}", error);
+}
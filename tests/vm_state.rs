@@ -0,0 +1,85 @@
+//! Virtual machine states against the raw values of `VZVirtualMachine.h`: every known value maps
+//! to its state and back, its name round-trips through `Display` and `FromStr`, and values out of
+//! range are kept as `Unknown`, which never equals a known state.
+
+#![cfg(target_os = "macos")]
+
+extern crate virtualization_rs;
+
+use virtualization_rs::base::NSInteger;
+use virtualization_rs::virtualization::virtual_machine::ParseStateError;
+use virtualization_rs::virtualization::virtual_machine::VZVirtualMachineState::{self, *};
+
+/// The values of the macOS 14 SDK headers.
+const SDK: &[(NSInteger, VZVirtualMachineState, &str)] = &[
+    (0, VZVirtualMachineStateStopped, "stopped"),
+    (1, VZVirtualMachineStateRunning, "running"),
+    (2, VZVirtualMachineStatePaused, "paused"),
+    (3, VZVirtualMachineStateError, "error"),
+    (4, VZVirtualMachineStateStarting, "starting"),
+    (5, VZVirtualMachineStatePausing, "pausing"),
+    (6, VZVirtualMachineStateResuming, "resuming"),
+    (7, VZVirtualMachineStateStopping, "stopping"),
+    (8, VZVirtualMachineStateSaving, "saving"),
+    (9, VZVirtualMachineStateRestoring, "restoring"),
+];
+
+const OUT_OF_RANGE: &[NSInteger] = &[-1, 10, 42, NSInteger::MIN, NSInteger::MAX];
+
+#[test]
+fn every_known_value_maps_both_ways() {
+    for &(raw, state, _) in SDK {
+        assert_eq!(VZVirtualMachineState::from_raw(raw), state, "{}", raw);
+        assert_eq!(state.raw(), raw, "{:?}", state);
+    }
+}
+
+#[test]
+fn every_known_name_round_trips() {
+    for &(_, state, name) in SDK {
+        assert_eq!(state.to_string(), name);
+        assert_eq!(name.parse::<VZVirtualMachineState>(), Ok(state));
+    }
+}
+
+#[test]
+fn out_of_range_values_are_unknown() {
+    for &raw in OUT_OF_RANGE {
+        let state = VZVirtualMachineState::from_raw(raw);
+        assert_eq!(state, Unknown(raw));
+        assert_eq!(state.raw(), raw);
+        assert_eq!(state.to_string(), format!("unknown({})", raw));
+        assert_eq!(
+            state.to_string().parse::<VZVirtualMachineState>(),
+            Ok(state)
+        );
+        for &(_, known, _) in SDK {
+            assert_ne!(state, known);
+        }
+    }
+}
+
+#[test]
+fn an_unknown_name_of_a_known_value_is_the_known_state() {
+    assert_eq!(
+        "unknown(1)".parse::<VZVirtualMachineState>(),
+        Ok(VZVirtualMachineStateRunning)
+    );
+}
+
+#[test]
+fn other_names_are_refused() {
+    for name in [
+        "",
+        "Running",
+        "unknown()",
+        "unknown(x)",
+        "unknown(1",
+        "stopped ",
+    ] {
+        assert_eq!(
+            name.parse::<VZVirtualMachineState>(),
+            Err(ParseStateError(name.to_string()))
+        );
+    }
+}
@@ -0,0 +1,85 @@
+//! Vsock addressing: which ports are accepted, how endpoints and connection errors render, and
+//! the guest-side templates filled in with a port.
+
+#![cfg(target_os = "macos")]
+
+extern crate libc;
+extern crate virtualization_rs;
+
+use virtualization_rs::base::NSError;
+use virtualization_rs::virtualization::error::VZError;
+use virtualization_rs::virtualization::vsock::{
+    self, Endpoint, ParseVsockPortError, VsockPort, VsockPortError, GUEST_CID, HOST_CID,
+};
+
+use std::convert::TryFrom;
+
+fn port(port: u32) -> VsockPort {
+    VsockPort::new(port).unwrap()
+}
+
+#[test]
+fn zero_and_the_wildcard_are_refused() {
+    assert_eq!(VsockPort::new(0), Err(VsockPortError::Zero));
+    assert_eq!(VsockPort::new(u32::MAX), Err(VsockPortError::Any));
+    assert_eq!(VsockPort::try_from(0), Err(VsockPortError::Zero));
+    assert_eq!(VsockPort::new(1).map(VsockPort::get), Ok(1));
+    assert_eq!(u32::from(port(u32::MAX - 1)), u32::MAX - 1);
+    assert_eq!(VsockPortError::Zero.to_string(), "invalid vsock port 0");
+}
+
+#[test]
+fn ports_below_1024_are_reserved() {
+    assert!(port(1).is_reserved());
+    assert!(port(1023).is_reserved());
+    assert!(!port(1024).is_reserved());
+}
+
+#[test]
+fn ports_parse_from_text() {
+    assert_eq!("1024".parse(), Ok(port(1024)));
+    assert_eq!(
+        "0".parse::<VsockPort>(),
+        Err(ParseVsockPortError::Invalid(VsockPortError::Zero))
+    );
+    let error = "ssh".parse::<VsockPort>().unwrap_err();
+    assert_eq!(error.to_string(), "invalid vsock port: \"ssh\"");
+}
+
+#[test]
+fn endpoints_render_cid_and_port() {
+    assert_eq!(Endpoint::guest(port(1024)).to_string(), "vsock 3:1024");
+    assert_eq!(Endpoint::host(port(5000)).to_string(), "vsock 2:5000");
+    assert_eq!(Endpoint::guest(port(1)).cid, GUEST_CID);
+    assert_eq!(Endpoint::host(port(1)).cid, HOST_CID);
+}
+
+#[test]
+fn connection_errors_name_the_endpoint() {
+    let error =
+        Endpoint::guest(port(1024)).connect_error(VZError(NSError::posix(libc::ECONNRESET)));
+    assert_eq!(error.operation(), "connect to");
+    assert_eq!(error.resource(), Some("vsock 3:1024"));
+    let message = error.to_string();
+    assert!(
+        message.starts_with("failed to connect to vsock 3:1024: "),
+        "{}",
+        message
+    );
+}
+
+#[test]
+fn guest_templates_take_the_port() {
+    assert_eq!(
+        vsock::guest_instructions(vsock::GUEST_SOCAT_LISTENER, port(1234)),
+        "socat VSOCK-LISTEN:1234,reuseaddr,fork EXEC:/bin/cat"
+    );
+    let unit = vsock::guest_instructions(vsock::GUEST_SYSTEMD_SOCKET, port(1234));
+    assert!(
+        unit.contains("ListenStream=vsock:4294967295:1234\n"),
+        "{}",
+        unit
+    );
+    assert!(!unit.contains(vsock::PORT_PLACEHOLDER));
+    assert!(vsock::GUEST_SYSTEMD_SERVICE.contains("StandardInput=socket"));
+}